@@ -0,0 +1,160 @@
+//! バケツの属性に関するモジュール。
+//!
+//! 属性は`Bucket`とは別に、構成管理用の Raft クラスタに登録され、実行中に変更することができる。
+use frugalos_core::erasure_coding::ErasureCoding;
+use libfrugalos::consistency::ReadConsistency;
+use libfrugalos::entity::bucket::BucketId;
+
+use {ErrorKind, Result};
+
+/// バケツの属性。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketAttributes {
+    /// バケツの ID。
+    pub bucket_id: BucketId,
+
+    /// 読み込み専用かどうか。
+    ///
+    /// `true`の場合には、バケツの全てのセグメントで、オブジェクトの書き込みおよび削除が拒否される。
+    /// データの移行中や、ハードウェアの縮退時等に使用することを想定している。
+    pub read_only: bool,
+
+    /// リクエストのデフォルトのデッドライン(ミリ秒)。
+    ///
+    /// クライアントがデッドラインを指定しなかったリクエストに適用される。
+    /// `None`の場合には、サーバ全体のデフォルト値が使われる。
+    ///
+    /// NOTE: RPC のリクエストには常にデッドラインが含まれるので、この値が適用されるのは、
+    /// `deadline`クエリパラメータを省略した HTTP のリクエストのみとなる。
+    #[serde(default)]
+    pub default_deadline_ms: Option<u64>,
+
+    /// 読み込み時のデフォルトの整合性。
+    ///
+    /// クライアントが整合性を指定しなかったリクエストに適用される。
+    /// `None`の場合には、サーバ全体のデフォルト値が使われる。
+    #[serde(default)]
+    pub default_consistency: Option<ReadConsistency>,
+
+    /// バケツが保持可能なオブジェクトの最大数。
+    ///
+    /// バケツのセグメント数で均等に分割され、各セグメントの MDS で適用される。
+    /// `None`の場合には上限は設けられない。
+    #[serde(default)]
+    pub max_objects: Option<u64>,
+
+    /// バケツが保持可能なオブジェクトの、論理的な大きさ(バイト)の合計の最大値。
+    ///
+    /// `max_objects`と同様に、セグメント毎に分割して適用される。
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+
+    /// オブジェクトの Erasure Coding に使用する実装。
+    ///
+    /// 新たに書き込まれるオブジェクトにのみ適用される。
+    /// 断片のヘッダには符号化に使われた実装が記録されているので、変更後も既存のオブジェクトは読み込み可能。
+    /// `None`の場合には既定の実装(`jerasure_rs_vand`、チェックサムなし)が使われる。
+    #[serde(default)]
+    pub erasure_coding: Option<ErasureCoding>,
+
+    /// 各セグメントの MDS の Raft クラスタで、ウィットネスとして動作させるメンバの数。
+    ///
+    /// ウィットネスは投票には参加するが、自ら選挙を始めることはないのでリーダには選ばれず、
+    /// フォロワーとしての読み込み要求にも応答しない。
+    /// その代わりに、オブジェクトのメタデータを保持しないので、他のメンバよりも少ないメモリで動作する。
+    /// セグメントのメンバ(セグメントテーブル上の順序)の末尾から選ばれ、
+    /// ウィットネス以外のメンバが常に過半数となるように、メンバ数に応じて切り詰められる。
+    ///
+    /// 変更は、各サーバでセグメントの構成が次に更新された時(サーバの再起動を含む)に反映される。
+    #[serde(default)]
+    pub mds_witnesses: usize,
+}
+impl BucketAttributes {
+    /// 全ての属性が既定値の場合に`true`を返す。
+    pub fn is_default(&self) -> bool {
+        !self.read_only
+            && self.default_deadline_ms.is_none()
+            && self.default_consistency.is_none()
+            && self.max_objects.is_none()
+            && self.max_bytes.is_none()
+            && self.erasure_coding.is_none()
+            && self.mds_witnesses == 0
+    }
+
+    /// 属性の値が妥当かどうかを検証する。
+    pub fn validate(&self) -> Result<()> {
+        track_assert_ne!(
+            self.default_deadline_ms,
+            Some(0),
+            ErrorKind::InvalidInput,
+            "The default deadline must be positive: bucket={:?}",
+            self.bucket_id
+        );
+        if let Some(ReadConsistency::Subset(n)) = self.default_consistency {
+            track_assert_ne!(
+                n,
+                0,
+                ErrorKind::InvalidInput,
+                "The subset size of the default consistency must be positive: bucket={:?}",
+                self.bucket_id
+            );
+        }
+        if let Some(coding) = self.erasure_coding {
+            track_assert!(
+                coding.is_valid(),
+                ErrorKind::InvalidInput,
+                "The backend {:?} does not support the checksum {:?}: bucket={:?}",
+                coding.backend.name(),
+                coding.checksum.name(),
+                self.bucket_id
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use frugalos_core::erasure_coding::{ErasureCodingBackend, ErasureCodingChecksum};
+
+    use super::*;
+
+    #[test]
+    fn validate_works() {
+        let mut attributes = BucketAttributes {
+            bucket_id: "foo".to_owned(),
+            ..Default::default()
+        };
+        assert!(attributes.is_default());
+        assert!(attributes.validate().is_ok());
+
+        attributes.max_objects = Some(0);
+        assert!(!attributes.is_default());
+        attributes.max_objects = None;
+
+        attributes.default_deadline_ms = Some(30_000);
+        attributes.default_consistency = Some(ReadConsistency::Subset(2));
+        assert!(!attributes.is_default());
+        assert!(attributes.validate().is_ok());
+
+        attributes.default_deadline_ms = Some(0);
+        assert!(attributes.validate().is_err());
+
+        attributes.default_deadline_ms = None;
+        attributes.default_consistency = Some(ReadConsistency::Subset(0));
+        assert!(attributes.validate().is_err());
+
+        attributes.default_consistency = None;
+        attributes.erasure_coding = Some(ErasureCoding {
+            backend: ErasureCodingBackend::RustReedSolomon,
+            checksum: ErasureCodingChecksum::None,
+        });
+        assert!(attributes.validate().is_ok());
+
+        attributes.erasure_coding = Some(ErasureCoding {
+            backend: ErasureCodingBackend::RustReedSolomon,
+            checksum: ErasureCodingChecksum::Crc32,
+        });
+        assert!(attributes.validate().is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! 構成管理用クラスタの状態のバックアップ。
+//!
+//! バックアップには、サーバ・デバイス・バケツ・セグメントテーブル、および各種シーケンス番号の採番状況が含まれる。
+//! 構成管理用のノード群が全て失われた場合でも、バックアップから新しいクラスタを構築し直すことで、
+//! 生き残ったデータノード群の構成を引き継ぐことができる。
+use bytecodec::{DecodeExt, EncodeExt};
+use byteorder::{BigEndian, ByteOrder};
+use libfrugalos::entity::bucket::Bucket;
+use libfrugalos::entity::device::Device;
+use libfrugalos::entity::server::{Server, ServerId};
+
+use machine::Snapshot;
+use protobuf;
+use {ErrorKind, Result};
+
+const MAGIC: [u8; 8] = *b"FRGLCONF";
+const FORMAT_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 8 + 4 + 8;
+
+/// 構成管理用クラスタのバックアップ。
+#[derive(Debug, Clone)]
+pub struct ConfigBackup {
+    revision: u64,
+    snapshot: Snapshot,
+}
+impl ConfigBackup {
+    pub(crate) fn new(revision: u64, snapshot: Snapshot) -> Self {
+        ConfigBackup { revision, snapshot }
+    }
+
+    /// バックアップ取得時点での、構成管理用の Raft ログのコミット位置。
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// 登録済みのサーバ一覧を返す。
+    pub fn servers(&self) -> &[Server] {
+        &self.snapshot.servers
+    }
+
+    /// 登録済みのデバイス一覧を返す。
+    pub fn devices(&self) -> &[Device] {
+        &self.snapshot.devices
+    }
+
+    /// 登録済みのバケツ一覧を返す。
+    pub fn buckets(&self) -> &[Bucket] {
+        &self.snapshot.buckets
+    }
+
+    /// ファイルに保存するためのバイト列に変換する。
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let snapshot =
+            track!(protobuf::snapshot_encoder().encode_into_bytes(self.snapshot.clone()))?;
+        let mut bytes = vec![0; HEADER_SIZE];
+        bytes[..8].copy_from_slice(&MAGIC[..]);
+        BigEndian::write_u32(&mut bytes[8..12], FORMAT_VERSION);
+        BigEndian::write_u64(&mut bytes[12..HEADER_SIZE], self.revision);
+        bytes.extend_from_slice(&snapshot);
+        Ok(bytes)
+    }
+
+    /// `to_bytes`で生成されたバイト列から復元する。
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        track_assert!(
+            bytes.len() >= HEADER_SIZE && bytes[..8] == MAGIC[..],
+            ErrorKind::InvalidInput,
+            "Not a frugalos config backup"
+        );
+        let version = BigEndian::read_u32(&bytes[8..12]);
+        track_assert_eq!(
+            version,
+            FORMAT_VERSION,
+            ErrorKind::InvalidInput,
+            "Unsupported backup format"
+        );
+        let revision = BigEndian::read_u64(&bytes[12..HEADER_SIZE]);
+        let snapshot =
+            track!(protobuf::snapshot_decoder().decode_from_bytes(&bytes[HEADER_SIZE..]))?;
+        Ok(ConfigBackup { revision, snapshot })
+    }
+
+    /// `local`だけをメンバとする新しいクラスタの初期状態に変換する。
+    ///
+    /// `local`が既に登録済みのサーバであれば、そのシーケンス番号を引き継いでアドレスのみを更新し、
+    /// そうでなければ新たなシーケンス番号を採番して登録する。
+    /// `excluded`に含まれるサーバ(失われた構成管理用ノード等)は、サーバ一覧から取り除かれる。
+    /// ただし、デバイスから参照されているサーバを取り除くことはできない。
+    pub(crate) fn into_snapshot(
+        self,
+        local: &mut Server,
+        excluded: &[ServerId],
+    ) -> Result<Snapshot> {
+        let mut snapshot = self.snapshot;
+        for id in excluded {
+            track_assert_ne!(
+                *id,
+                local.id,
+                ErrorKind::InvalidInput,
+                "Cannot exclude the local server"
+            );
+            track_assert!(
+                snapshot.servers.iter().any(|s| s.id == *id),
+                ErrorKind::InvalidInput,
+                "No such server: {:?}",
+                id
+            );
+            let is_referred = snapshot.devices.iter().any(|d| match *d {
+                Device::Virtual(_) => false,
+                Device::Memory(ref d) => d.server == *id,
+                Device::File(ref d) => d.server == *id,
+            });
+            track_assert!(
+                !is_referred,
+                ErrorKind::InvalidInput,
+                "The server {:?} is referred by some devices",
+                id
+            );
+            snapshot.servers.retain(|s| s.id != *id);
+            snapshot.failure_domains.retain(|d| d.server_id != *id);
+        }
+
+        if let Some(server) = snapshot.servers.iter_mut().find(|s| s.id == local.id) {
+            local.seqno = server.seqno;
+            *server = local.clone();
+        } else {
+            local.seqno = snapshot.next_seqno.server;
+            snapshot.next_seqno.server += 1;
+            snapshot.servers.push(local.clone());
+        }
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libfrugalos::entity::device::{MemoryDevice, Weight};
+    use trackable::result::TestResult;
+
+    fn server(id: &str, seqno: u32) -> Server {
+        let mut server = Server::new(id.to_owned(), ([127, 0, 0, 1], 14278).into());
+        server.seqno = seqno;
+        server
+    }
+
+    fn backup() -> ConfigBackup {
+        let mut snapshot = Snapshot::initial(server("config0", 0));
+        snapshot.servers.push(server("config1", 1));
+        snapshot.servers.push(server("data0", 2));
+        snapshot.next_seqno.server = 3;
+        snapshot.devices.push(Device::Memory(MemoryDevice {
+            id: "dev0".to_owned(),
+            seqno: 0,
+            weight: Weight::Auto,
+            server: "data0".to_owned(),
+            capacity: 1 << 30,
+        }));
+        ConfigBackup::new(10, snapshot)
+    }
+
+    #[test]
+    fn backup_roundtrip_works() -> TestResult {
+        let bytes = track!(backup().to_bytes())?;
+        let decoded = track!(ConfigBackup::from_bytes(&bytes))?;
+        assert_eq!(decoded.revision(), 10);
+        assert_eq!(decoded.servers().len(), 3);
+        assert_eq!(decoded.devices().len(), 1);
+
+        assert!(ConfigBackup::from_bytes(&bytes[1..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn into_snapshot_works() -> TestResult {
+        // 登録済みのサーバは、シーケンス番号を引き継ぐ
+        let mut local = server("data0", 0);
+        local.host = [127, 0, 0, 2].into();
+        let snapshot = track!(backup().into_snapshot(&mut local, &["config0".to_owned()]))?;
+        assert_eq!(local.seqno, 2);
+        assert_eq!(snapshot.servers.len(), 2);
+        let restored = snapshot.servers.iter().find(|s| s.id == "data0").unwrap();
+        assert_eq!(restored.seqno, 2);
+        assert_eq!(restored.host, local.host);
+
+        // 新規のサーバには、新たなシーケンス番号が採番される
+        let mut local = server("config2", 0);
+        let snapshot = track!(backup().into_snapshot(&mut local, &[]))?;
+        assert_eq!(local.seqno, 3);
+        assert_eq!(snapshot.next_seqno.server, 4);
+
+        // デバイスから参照されているサーバは取り除けない
+        let mut local = server("config2", 0);
+        assert!(backup()
+            .into_snapshot(&mut local, &["data0".to_owned()])
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,30 @@
+//! デバイスの作成時に測定された性能の基準値。
+//!
+//! 基準値はデバイスを持つサーバから報告され、構成管理用の Raft クラスタで複製される。
+//! 測定結果が閾値を満たさなかったデバイスは、退役中のデバイスと同様にセグメントの配置先から除外される。
+//! 複製された状態のみを参照するので、全てのノードで同じセグメントテーブルが構築される。
+use libfrugalos::entity::device::DeviceId;
+
+/// デバイスの作成時に測定された性能の基準値。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceBaseline {
+    /// デバイスの ID。
+    pub device_id: DeviceId,
+
+    /// 書き込みのスループット(バイト/秒)。
+    pub write_bytes_per_second: u64,
+
+    /// 読み込みのスループット(バイト/秒)。
+    pub read_bytes_per_second: u64,
+
+    /// 一回の書き込み(同期を含む)にかかった時間の最大値(マイクロ秒単位)。
+    pub max_write_latency_micros: u64,
+
+    /// 一回の読み込みにかかった時間の最大値(マイクロ秒単位)。
+    pub max_read_latency_micros: u64,
+
+    /// 測定結果が、測定したサーバで設定された閾値を満たしたかどうか。
+    ///
+    /// `false`の場合には、そのデバイスはセグメントの配置先から除外される。
+    pub passed: bool,
+}
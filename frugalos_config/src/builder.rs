@@ -1,37 +1,69 @@
 use libfrugalos::entity::bucket::Bucket;
 use libfrugalos::entity::device::{Device, DeviceId, SegmentAllocationPolicy, VirtualDevice};
+use libfrugalos::entity::server::ServerId;
 use rendezvous_hash::{Capacity, IdNode, WeightedNode};
 use rendezvous_hash::{DefaultNodeHasher, RendezvousNodes};
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
 use machine::{DeviceGroup, Segment, SegmentTable};
+use topology::{self, DomainKey, FailureDomain};
+use usage::DeviceUsage;
 use {ErrorKind, Result};
 
 type BucketNo = u32;
 type DeviceNo = u32;
 type Devices = BTreeMap<DeviceId, Device>;
+type DeviceUsages = BTreeMap<DeviceId, DeviceUsage>;
+type FailureDomains = BTreeMap<ServerId, FailureDomain>;
 type SegmentNo = u16;
 type HashRing = RendezvousNodes<WeightedNode<IdNode<DeviceNo>>, DefaultNodeHasher>;
 
 #[derive(Debug)]
 pub struct SegmentTableBuilder<'a> {
     devices: &'a Devices,
+    usages: Option<&'a DeviceUsages>,
+    failure_domains: Option<&'a FailureDomains>,
 }
 impl<'a> SegmentTableBuilder<'a> {
     pub fn new(devices: &'a Devices) -> Self {
-        SegmentTableBuilder { devices }
+        SegmentTableBuilder {
+            devices,
+            usages: None,
+            failure_domains: None,
+        }
+    }
+    /// 物理デバイスの重みを、報告済みの使用量に応じて調整するようにする。
+    ///
+    /// 使用量が報告されていないデバイスの重みはそのまま使われる。
+    pub fn usages(mut self, usages: &'a DeviceUsages) -> Self {
+        self.usages = Some(usages);
+        self
+    }
+    /// 同じセグメントのメンバが、できるだけ別々の障害ドメインに配置されるようにする。
+    ///
+    /// バケツ配下のどのサーバにも障害ドメインが設定されていない場合には、従来通りの配置となる。
+    pub fn failure_domains(mut self, failure_domains: &'a FailureDomains) -> Self {
+        self.failure_domains = Some(failure_domains);
+        self
     }
     pub fn build(&self, bucket: &Bucket) -> Result<SegmentTable> {
+        let failure_domains = self
+            .failure_domains
+            .filter(|d| topology::is_labeled(self.devices, bucket.device(), d));
         let segments_builder = SegmentsBuilder {
             bucket_no: bucket.seqno(),
             root: &self.devices[bucket.device()],
             devices: self.devices,
+            usages: self.usages,
+            failure_domains,
             segment_count: bucket.segment_count(),
             device_group_size: bucket.device_group_size(),
 
             device_states: HashMap::new(),
             segment_owners: HashMap::new(),
             gathered_segments: HashMap::new(),
+            zone_members: HashMap::new(),
+            rack_members: HashMap::new(),
         };
         let segments = track!(segments_builder.build())?;
         Ok(SegmentTable {
@@ -45,6 +77,8 @@ struct SegmentsBuilder<'a> {
     bucket_no: BucketNo,
     root: &'a Device,
     devices: &'a Devices,
+    usages: Option<&'a DeviceUsages>,
+    failure_domains: Option<&'a FailureDomains>,
     segment_count: u16,
     device_group_size: u8,
 
@@ -54,6 +88,10 @@ struct SegmentsBuilder<'a> {
     segment_owners: HashMap<SegmentNo, HashSet<DeviceNo>>,
 
     gathered_segments: HashMap<SegmentNo, DeviceNo>,
+
+    // 障害ドメイン毎の、該当セグメントに割り当て済みのメンバ数
+    zone_members: HashMap<(SegmentNo, String), usize>,
+    rack_members: HashMap<(SegmentNo, DomainKey), usize>,
 }
 impl<'a> SegmentsBuilder<'a> {
     pub fn build(mut self) -> Result<Vec<Segment>> {
@@ -80,7 +118,10 @@ impl<'a> SegmentsBuilder<'a> {
                 members.push(allocated_device);
             }
             let segment = Segment {
-                groups: vec![DeviceGroup { members }],
+                groups: vec![DeviceGroup {
+                    members,
+                    generation: 0,
+                }],
             };
             segments.push(segment);
         }
@@ -125,6 +166,18 @@ impl<'a> SegmentsBuilder<'a> {
             let child = self.get_device(child_no);
             track!(self.allocate_segment_slot(key, child))
         } else {
+            if self.failure_domains.is_some() {
+                for domain in &self.device_states[&device.seqno()].domains {
+                    *self
+                        .zone_members
+                        .entry((key.segment_no, domain.zone.clone()))
+                        .or_insert(0) += 1;
+                    *self
+                        .rack_members
+                        .entry((key.segment_no, domain.clone()))
+                        .or_insert(0) += 1;
+                }
+            }
             Ok(device.seqno())
         }
     }
@@ -135,43 +188,40 @@ impl<'a> SegmentsBuilder<'a> {
             .map_or(false, |g| g.contains(&device_no))
     }
     fn select_scatter_slot(&mut self, key: SlotKey, parent: &VirtualDevice) -> DeviceNo {
-        let ring = self.get_ring(parent.seqno);
-        let child = ring
-            .calc_candidates(&key)
-            .find(|item| {
-                let device_no = *item.node;
-                let d = &self.device_states[&device_no];
-                d.allocated <= d.capacity && !self.is_same_device_group(key.segment_no, device_no)
+        let candidates = self.candidates(key, parent);
+        self.domain_tiers(key.segment_no, candidates)
+            .into_iter()
+            .filter_map(|tier| {
+                let child = tier.iter().cloned().find(|&device_no| {
+                    let d = &self.device_states[&device_no];
+                    d.allocated <= d.capacity
+                        && !self.is_same_device_group(key.segment_no, device_no)
+                });
+                if child.is_some() {
+                    child
+                } else {
+                    // FIXME: この場合(i.e., capacity over)の割当方式を少し検討したいかも
+                    tier.iter()
+                        .cloned()
+                        .find(|&device_no| !self.is_same_device_group(key.segment_no, device_no))
+                }
             })
-            .map(|item| *item.node);
-        if let Some(child) = child {
-            child
-        } else {
-            // FIXME: この場合(i.e., capacity over)の割当方式を少し検討したいかも
-            ring.calc_candidates(&key)
-                .find(|item| {
-                    let device_no = *item.node;
-                    !self.is_same_device_group(key.segment_no, device_no)
-                })
-                .map(|item| *item.node)
-                .expect("Never fails")
-        }
+            .next()
+            .expect("Never fails")
     }
     fn select_neutral_slot(&mut self, key: SlotKey, parent: &VirtualDevice) -> DeviceNo {
-        let ring = self.get_ring(parent.seqno);
-        let child = ring
-            .calc_candidates(&key)
-            .find(|item| {
-                let d = &self.device_states[&*item.node];
-                d.allocated <= d.capacity
-            })
-            .map(|item| *item.node);
+        let candidates = self.candidates(key, parent);
+        let candidates = self.preferred_candidates(key.segment_no, candidates);
+        let child = candidates.iter().cloned().find(|device_no| {
+            let d = &self.device_states[device_no];
+            d.allocated <= d.capacity
+        });
         if let Some(child) = child {
             child
         } else {
             // NOTE: gather経由の場合等にここに来ることがある
             // FIXME: この場合の割当方式を少し検討したいかも
-            *ring.calc_candidates(&key).nth(0).expect("Never fails").node
+            candidates[0]
         }
     }
     fn select_gather_slot(&mut self, key: SlotKey, parent: &VirtualDevice) -> DeviceNo {
@@ -193,10 +243,9 @@ impl<'a> SegmentsBuilder<'a> {
         // Note that it takes O(#slot log #slot) for each call.
         let mut minimum_ratio = usize::max_value();
         let mut ratio_map = HashMap::new();
-        let ring = self.get_ring(parent.seqno);
-        let candidates: Vec<_> = ring.calc_candidates(&key).collect();
-        for item in candidates.iter() {
-            let device_no = *item.node;
+        let candidates = self.candidates(key, parent);
+        let candidates = self.preferred_candidates(key.segment_no, candidates);
+        for &device_no in candidates.iter() {
             let device_state = &self.device_states[&device_no];
             let ratio = if device_state.capacity > 0 {
                 1_000_000 * device_state.allocated / device_state.capacity
@@ -208,14 +257,76 @@ impl<'a> SegmentsBuilder<'a> {
         }
         candidates
             .iter()
-            .find(|item| {
-                let id = *item.node;
-                *ratio_map.get(&id).expect("never fails") == minimum_ratio
-            })
-            .map(|item| *item.node)
+            .cloned()
+            .find(|id| *ratio_map.get(id).expect("never fails") == minimum_ratio)
             .expect("at least one node has the minimum ratio")
     }
 
+    /// `parent`の子デバイスを、`key`に対する優先順に並べて返す。
+    fn candidates(&self, key: SlotKey, parent: &VirtualDevice) -> Vec<DeviceNo> {
+        self.get_ring(parent.seqno)
+            .calc_candidates(&key)
+            .map(|item| *item.node)
+            .collect()
+    }
+
+    /// 障害ドメインを考慮して、候補を優先度の高い順に分ける。
+    ///
+    /// 障害ドメインが有効な場合、先頭の要素は、このセグメントのメンバが最も少ないゾーン(およびラック)を
+    /// 含む子デバイスのみからなる。最後の要素は常に全ての候補となる。
+    fn domain_tiers(&self, segment_no: SegmentNo, candidates: Vec<DeviceNo>) -> Vec<Vec<DeviceNo>> {
+        if self.failure_domains.is_none() {
+            return vec![candidates];
+        }
+        let scores = candidates
+            .iter()
+            .map(|&device_no| self.domain_score(segment_no, device_no))
+            .collect::<Vec<_>>();
+        let minimum = scores.iter().filter_map(|s| *s).min();
+        let preferred = candidates
+            .iter()
+            .zip(scores.iter())
+            .filter(|&(_, score)| minimum.is_some() && *score == minimum)
+            .map(|(&device_no, _)| device_no)
+            .collect::<Vec<_>>();
+        if preferred.is_empty() || preferred.len() == candidates.len() {
+            vec![candidates]
+        } else {
+            vec![preferred, candidates]
+        }
+    }
+    fn preferred_candidates(
+        &self,
+        segment_no: SegmentNo,
+        candidates: Vec<DeviceNo>,
+    ) -> Vec<DeviceNo> {
+        self.domain_tiers(segment_no, candidates)
+            .into_iter()
+            .next()
+            .expect("Never fails")
+    }
+
+    /// 子デバイス配下の障害ドメインのうち、該当セグメントのメンバが最も少ないものの(ゾーン, ラック)毎のメンバ数を返す。
+    fn domain_score(&self, segment_no: SegmentNo, device_no: DeviceNo) -> Option<(usize, usize)> {
+        self.device_states[&device_no]
+            .domains
+            .iter()
+            .map(|domain| {
+                let zone = self
+                    .zone_members
+                    .get(&(segment_no, domain.zone.clone()))
+                    .cloned()
+                    .unwrap_or(0);
+                let rack = self
+                    .rack_members
+                    .get(&(segment_no, domain.clone()))
+                    .cloned()
+                    .unwrap_or(0);
+                (zone, rack)
+            })
+            .min()
+    }
+
     #[allow(clippy::mut_from_ref)]
     fn get_device<'b, 'c>(&'b self, device_no: DeviceNo) -> &'c Device {
         // NOTE: 現状のRustの借用チェックの制約を回避するためのワークアラウンド
@@ -241,6 +352,7 @@ impl<'a> SegmentsBuilder<'a> {
             allocated: 0,
             capacity: 0,
             ring: HashRing::default(),
+            domains: BTreeSet::new(),
             device,
         };
         match *device {
@@ -251,6 +363,8 @@ impl<'a> SegmentsBuilder<'a> {
                     self.init_device_states(c, states);
                     let child_weight = states[&c.seqno()].weight;
                     total_weight += child_weight;
+                    let child_domains = states[&c.seqno()].domains.clone();
+                    state.domains.extend(child_domains);
                     state.ring.insert(WeightedNode::new(
                         IdNode::new(c.seqno()),
                         Capacity::new(child_weight as f64).expect("Never fails"),
@@ -258,11 +372,20 @@ impl<'a> SegmentsBuilder<'a> {
                 }
                 state.weight = d.weight.calculate(total_weight);
             }
-            Device::Memory(ref d) => state.weight = d.weight(),
-            Device::File(ref d) => state.weight = d.weight(),
+            Device::Memory(ref d) => state.weight = self.physical_weight(&d.id, d.weight()),
+            Device::File(ref d) => state.weight = self.physical_weight(&d.id, d.weight()),
+        }
+        if let Some(domains) = self.failure_domains {
+            state.domains.extend(topology::domain_key(device, domains));
         }
         states.insert(device.seqno(), state);
     }
+    fn physical_weight(&self, id: &DeviceId, weight: u64) -> u64 {
+        match self.usages.and_then(|usages| usages.get(id)) {
+            Some(usage) => usage.adjust_weight(weight),
+            None => weight,
+        }
+    }
     fn assign_capacities(
         &self,
         slots: usize,
@@ -311,6 +434,9 @@ struct DeviceState<'a> {
 
     ring: HashRing,
 
+    // 配下の物理デバイスが属する障害ドメインの集合(障害ドメインが有効な場合のみ)
+    domains: BTreeSet<DomainKey>,
+
     device: &'a Device,
 }
 
@@ -320,8 +446,10 @@ mod tests {
     use builder::SegmentTableBuilder;
     use libfrugalos::entity::bucket::{Bucket, DispersedBucket};
     use libfrugalos::entity::device::{Device, DeviceId, SegmentAllocationPolicy};
-    use std::collections::HashMap;
-    use test_util::build_device_tree;
+    use std::collections::{BTreeMap, HashMap};
+    use test_util::{assign_distinct_servers, build_device_tree};
+    use topology::FailureDomain;
+    use usage::DeviceUsage;
 
     fn get_bucket_8_4(segment_count: u32, root_device_id: DeviceId) -> Bucket {
         Bucket::Dispersed(DispersedBucket {
@@ -447,4 +575,80 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn segment_table_builder_prefers_devices_with_free_space() -> Result<()> {
+        let (devices, root_device_id) =
+            build_device_tree(&[8], SegmentAllocationPolicy::ScatterIfPossible);
+        let mut usages = BTreeMap::new();
+        usages.insert(
+            "dev1".to_owned(),
+            DeviceUsage {
+                device_id: "dev1".to_owned(),
+                capacity_bytes: 1000,
+                usage_bytes: 900,
+            },
+        );
+        let builder = SegmentTableBuilder::new(&devices).usages(&usages);
+        let bucket = get_bucket_4_1(200, root_device_id);
+        let segment_table = builder.build(&bucket)?;
+
+        let mut frequency = HashMap::new();
+        for segment in &segment_table.segments {
+            for seqno in &segment.groups[0].members {
+                *frequency.entry(*seqno).or_insert(0) += 1;
+            }
+        }
+        let full_device = devices["dev1"].seqno();
+        for (seqno, count) in &frequency {
+            if *seqno != full_device {
+                assert!(frequency[&full_device] < *count, "{:?}", frequency);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn segment_table_builder_spreads_members_across_racks() -> Result<()> {
+        let (mut devices, root_device_id) =
+            build_device_tree(&[24], SegmentAllocationPolicy::ScatterIfPossible);
+        assign_distinct_servers(&mut devices);
+        let mut domains = BTreeMap::new();
+        let mut seqno_to_rack = HashMap::new();
+        for device in devices.values() {
+            if let Some(server) = device.server() {
+                let rack = format!("rack{}", device.seqno() % 3);
+                seqno_to_rack.insert(device.seqno(), rack.clone());
+                domains.insert(
+                    server.clone(),
+                    FailureDomain {
+                        server_id: server.clone(),
+                        zone: None,
+                        rack: Some(rack),
+                    },
+                );
+            }
+        }
+        let builder = SegmentTableBuilder::new(&devices).failure_domains(&domains);
+        let bucket = Bucket::Dispersed(DispersedBucket {
+            id: "bucket_id".to_string(),
+            seqno: 44,
+            device: root_device_id,
+            segment_count: 100,
+            tolerable_faults: 2,
+            data_fragment_count: 4,
+        });
+        let segment_table = builder.build(&bucket)?;
+
+        // 6 メンバが 3 ラックに 2 つずつ配置される
+        for segment in &segment_table.segments {
+            let mut frequency = HashMap::new();
+            for seqno in &segment.groups[0].members {
+                *frequency.entry(&seqno_to_rack[seqno]).or_insert(0) += 1;
+            }
+            assert_eq!(frequency.len(), 3, "{:?}", frequency);
+            assert!(frequency.values().all(|&n| n == 2), "{:?}", frequency);
+        }
+        Ok(())
+    }
 }
@@ -6,23 +6,44 @@ use cannyls::device::Device;
 use fibers::{Executor, Spawn, ThreadPoolExecutor};
 use fibers_rpc::client::{
     ClientServiceBuilder as RpcServiceBuilder, ClientServiceHandle as RpcServiceHandle,
+    Options as RpcOptions,
 };
 use fibers_rpc::server::ServerBuilder as RpcServerBuilder;
+use fibers_rpc::Call;
+use frugalos_core::rpc_auth;
 use frugalos_raft::{self, RaftIo};
 use futures::{Async, Future, Poll, Stream};
-use libfrugalos::client::config::Client;
-use libfrugalos::entity::server::Server;
+use libfrugalos;
+use libfrugalos::entity::bucket::BucketId;
+use libfrugalos::entity::device::DeviceId;
+use libfrugalos::entity::server::{Server, ServerId};
+use libfrugalos::schema::config::{DeleteServerRpc, GetLeaderRpc, PutServerRpc};
 use prometrics::metrics::MetricBuilder;
 use raftlog::ReplicatedLog;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use slog::Logger;
 use std::fs;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+use attribute::BucketAttributes;
+use backup::ConfigBackup;
 use config::server_to_frugalos_raft_node;
+use decommission::DeviceDecommission;
 use machine::Snapshot;
+use maintenance::MaintenanceWindows;
 use protobuf;
+use rebalance::{RebalanceOptions, RebalancePlan};
+use relayout::{BucketRelayout, RelayoutParameters, RelayoutedSegment};
+use schema::{
+    DecommissionDevicesRpc, ExportBackupRpc, FinishSegmentRelayoutRpc, ListBucketRelayoutsRpc,
+    ListDecommissionsRpc, PlanChangeRpc, PlanRebalanceRpc, PutBucketAttributesRpc,
+    PutFailureDomainRpc, PutMaintenanceWindowsRpc, RelayoutBucketRpc,
+};
+use simulation::{ChangePlan, ProposedChange};
+use topology::FailureDomain;
 use {Error, ErrorKind, Result};
 
 const LOCAL_DATA_FILE_NAME: &str = "local.dat";
@@ -39,6 +60,16 @@ pub fn load_local_server_info<P: AsRef<Path>>(data_dir: P) -> Result<Server> {
     Ok(server)
 }
 
+/// データディレクトリ内の、クラスタ構成管理用のファイル群のパスを返す。
+///
+/// ファイルが実際に存在するかどうかは確認しない。
+pub fn local_data_files<P: AsRef<Path>>(data_dir: P) -> Vec<PathBuf> {
+    vec![
+        data_dir.as_ref().join(LOCAL_DATA_FILE_NAME),
+        data_dir.as_ref().join(CLUSTER_DATA_FILE_NAME),
+    ]
+}
+
 /// ローカルサーバの情報を削除する。
 pub fn delete_local_server_info<P: AsRef<Path>>(data_dir: P) -> Result<()> {
     track!(fs::remove_dir_all(&data_dir).map_err(Error::from))?;
@@ -140,6 +171,419 @@ pub fn create<P: AsRef<Path>>(logger: &Logger, mut local: Server, data_dir: P) -
 
     // 自分だけを含むRaftクラスタを作成
     local.seqno = 0;
+    let snapshot = Snapshot::initial(local.clone());
+    track!(bootstrap(logger, local, data_dir, snapshot))?;
+
+    info!(logger, "[FINISH] create");
+    Ok(())
+}
+
+/// バックアップから、自分だけを含むRaftクラスタを新たに構築する。
+///
+/// 構成管理用のノード群が全て失われた場合に使用する。
+/// 失われたノード群は`excluded_servers`に指定して、新しいクラスタのメンバから取り除く必要がある。
+/// それ以外の登録済みのサーバは、クラスタ構成のデータファイル(`cluster.lusf`)を削除した上で再起動すれば、
+/// 新しいクラスタのメンバとして追加される。
+pub fn restore<P: AsRef<Path>, Q: AsRef<Path>>(
+    logger: &Logger,
+    mut local: Server,
+    data_dir: P,
+    backup_file: Q,
+    excluded_servers: &[ServerId],
+) -> Result<()> {
+    info!(
+        logger,
+        "[START] restore: {}",
+        dump!(
+            local,
+            data_dir.as_ref(),
+            backup_file.as_ref(),
+            excluded_servers
+        )
+    );
+
+    // 既にクラスタに参加済みではないかをチェック
+    track!(assert_to_be_newbie(logger, &data_dir))?;
+
+    let bytes = track!(fs::read(backup_file.as_ref()).map_err(Error::from))?;
+    let backup = track!(ConfigBackup::from_bytes(&bytes))?;
+    info!(
+        logger,
+        "Backup is loaded: {}",
+        dump!(
+            backup.revision(),
+            backup.servers().len(),
+            backup.devices().len(),
+            backup.buckets().len()
+        )
+    );
+    let snapshot = track!(backup.into_snapshot(&mut local, excluded_servers))?;
+    track!(bootstrap(logger, local, data_dir, snapshot))?;
+
+    info!(logger, "[FINISH] restore");
+    Ok(())
+}
+
+/// 構成管理用クラスタの現在の状態を、バックアップとして`backup_file`に保存する。
+///
+/// 一貫した状態を得るために、`contact_server`経由でリーダを特定して、その状態を取得する。
+pub fn backup<P: AsRef<Path>>(
+    logger: &Logger,
+    contact_server: SocketAddr,
+    backup_file: P,
+) -> Result<ConfigBackup> {
+    info!(
+        logger,
+        "[START] backup: {}",
+        dump!(contact_server, backup_file.as_ref())
+    );
+
+    let bytes = track!(call_leader::<ExportBackupRpc, _>(
+        logger,
+        contact_server,
+        ()
+    ))?;
+    let backup = track!(ConfigBackup::from_bytes(&bytes))?;
+
+    // 書き込み途中のファイルが残らないように、一時ファイルに書き込んでから置き換える
+    let tmp_file = backup_file.as_ref().with_extension("tmp");
+    track!(fs::write(&tmp_file, &bytes).map_err(Error::from))?;
+    track!(fs::rename(&tmp_file, backup_file.as_ref()).map_err(Error::from))?;
+
+    info!(
+        logger,
+        "[FINISH] backup: {}",
+        dump!(
+            backup.revision(),
+            backup.servers().len(),
+            backup.devices().len(),
+            backup.buckets().len()
+        )
+    );
+    Ok(backup)
+}
+
+/// セグメントの配置の偏りを均すための移動計画を取得する。
+///
+/// 最新の構成に基づいた計画を得るために、`contact_server`経由でリーダを特定して、そこで計画を作成する。
+/// 計画は報告されるだけで、実際の移動は行われない。
+pub fn plan_rebalance(
+    logger: &Logger,
+    contact_server: SocketAddr,
+    options: RebalanceOptions,
+) -> Result<RebalancePlan> {
+    info!(
+        logger,
+        "[START] plan_rebalance: {}",
+        dump!(contact_server, options)
+    );
+
+    let plan = track!(call_leader::<PlanRebalanceRpc, _>(
+        logger,
+        contact_server,
+        options
+    ))?;
+
+    info!(
+        logger,
+        "[FINISH] plan_rebalance: {}",
+        dump!(
+            plan.buckets.len(),
+            plan.buckets.iter().map(|b| b.moves.len()).sum::<usize>(),
+            plan.unused_devices.len()
+        )
+    );
+    Ok(plan)
+}
+
+/// 構成の変更を実際には適用せずに、その影響の見積もりを取得する。
+///
+/// 最新の構成に基づいた見積もりを得るために、`contact_server`経由でリーダを特定して、そこで見積もる。
+pub fn plan_change(
+    logger: &Logger,
+    contact_server: SocketAddr,
+    change: ProposedChange,
+) -> Result<ChangePlan> {
+    info!(
+        logger,
+        "[START] plan_change: {}",
+        dump!(contact_server, change)
+    );
+
+    let plan = track!(call_leader::<PlanChangeRpc, _>(
+        logger,
+        contact_server,
+        change
+    ))?;
+
+    info!(
+        logger,
+        "[FINISH] plan_change: {}",
+        dump!(
+            plan.satisfiable,
+            plan.buckets.len(),
+            plan.moved_slots,
+            plan.moved_bytes
+        )
+    );
+    Ok(plan)
+}
+
+/// サーバの障害ドメインを設定する。
+///
+/// 要求は、`contact_server`から取得したリーダに送られる。
+pub fn put_failure_domain(
+    logger: &Logger,
+    contact_server: SocketAddr,
+    domain: FailureDomain,
+) -> Result<FailureDomain> {
+    info!(
+        logger,
+        "[START] put_failure_domain: {}",
+        dump!(contact_server, domain)
+    );
+
+    let domain = track!(call_leader::<PutFailureDomainRpc, _>(
+        logger,
+        contact_server,
+        domain
+    ))?;
+
+    info!(logger, "[FINISH] put_failure_domain: {}", dump!(domain));
+    Ok(domain)
+}
+
+/// バケツの属性を設定する。
+///
+/// 要求は、`contact_server`から取得したリーダに送られる。
+pub fn put_bucket_attributes(
+    logger: &Logger,
+    contact_server: SocketAddr,
+    attributes: BucketAttributes,
+) -> Result<BucketAttributes> {
+    info!(
+        logger,
+        "[START] put_bucket_attributes: {}",
+        dump!(contact_server, attributes)
+    );
+
+    let attributes = track!(call_leader::<PutBucketAttributesRpc, _>(
+        logger,
+        contact_server,
+        attributes
+    ))?;
+
+    info!(
+        logger,
+        "[FINISH] put_bucket_attributes: {}",
+        dump!(attributes)
+    );
+    Ok(attributes)
+}
+
+/// メンテナンスウィンドウを設定する。
+///
+/// 要求は、`contact_server`から取得したリーダに送られる。
+pub fn put_maintenance_windows(
+    logger: &Logger,
+    contact_server: SocketAddr,
+    windows: MaintenanceWindows,
+) -> Result<MaintenanceWindows> {
+    info!(
+        logger,
+        "[START] put_maintenance_windows: {}",
+        dump!(contact_server, windows)
+    );
+
+    let windows = track!(call_leader::<PutMaintenanceWindowsRpc, _>(
+        logger,
+        contact_server,
+        windows
+    ))?;
+
+    info!(
+        logger,
+        "[FINISH] put_maintenance_windows: {}",
+        dump!(windows)
+    );
+    Ok(windows)
+}
+
+/// バケツの再配置を開始する。
+///
+/// 要求は、`contact_server`から取得したリーダに送られる。
+/// 再配置自体は各セグメントの MDS によって非同期に進められ、その進捗は`list_bucket_relayouts`で確認できる。
+pub fn relayout_bucket(
+    logger: &Logger,
+    contact_server: SocketAddr,
+    bucket_id: BucketId,
+    params: RelayoutParameters,
+) -> Result<BucketRelayout> {
+    info!(
+        logger,
+        "[START] relayout_bucket: {}",
+        dump!(contact_server, bucket_id, params)
+    );
+
+    let relayout = track!(call_leader::<RelayoutBucketRpc, _>(
+        logger,
+        contact_server,
+        (bucket_id, params)
+    ))?;
+
+    info!(logger, "[FINISH] relayout_bucket: {}", dump!(relayout));
+    Ok(relayout)
+}
+
+/// 実行中のバケツの再配置の一覧を取得する。
+///
+/// 最新の状態を得るために、`contact_server`経由でリーダを特定して、そこから取得する。
+pub fn list_bucket_relayouts(
+    logger: &Logger,
+    contact_server: SocketAddr,
+) -> Result<Vec<BucketRelayout>> {
+    track!(call_leader::<ListBucketRelayoutsRpc, _>(
+        logger,
+        contact_server,
+        ()
+    ))
+}
+
+/// セグメントの再配置の完了を、構成管理用クラスタのリーダに通知する。
+///
+/// 上の関数群とは異なり、実行中のデーモンの RPC サービスを用いる非同期版。
+pub fn finish_segment_relayout(
+    rpc_service: RpcServiceHandle,
+    contact_server: SocketAddr,
+    segment: RelayoutedSegment,
+) -> impl Future<Item = (), Error = Error> {
+    call_leader_async::<FinishSegmentRelayoutRpc, _>(rpc_service, contact_server, segment)
+}
+
+/// デバイスの退役を、構成管理用クラスタのリーダに要求する。
+///
+/// `finish_segment_relayout`と同様に、実行中のデーモンの RPC サービスを用いる非同期版。
+pub fn decommission_devices(
+    rpc_service: RpcServiceHandle,
+    contact_server: SocketAddr,
+    device_ids: Vec<DeviceId>,
+) -> impl Future<Item = Vec<DeviceDecommission>, Error = Error> {
+    call_leader_async::<DecommissionDevicesRpc, _>(rpc_service, contact_server, device_ids)
+}
+
+/// 退役中のデバイスの状態の一覧を、構成管理用クラスタのリーダから取得する。
+pub fn list_decommissions(
+    rpc_service: RpcServiceHandle,
+    contact_server: SocketAddr,
+) -> impl Future<Item = Vec<DeviceDecommission>, Error = Error> {
+    call_leader_async::<ListDecommissionsRpc, _>(rpc_service, contact_server, ())
+}
+
+/// `call_leader`および`call_leader_async`で呼び出す RPC。
+trait LeaderCall: Call {
+    /// サーバ側で`rpc_auth::add_call_handler`を使ってハンドラが登録されているかどうか。
+    ///
+    /// `true`の場合には、クラスタトークンが登録されていればトークン付きで呼び出す。
+    const AUTHENTICATED: bool;
+}
+impl LeaderCall for ExportBackupRpc {
+    const AUTHENTICATED: bool = true;
+}
+impl LeaderCall for PlanRebalanceRpc {
+    const AUTHENTICATED: bool = false;
+}
+impl LeaderCall for PlanChangeRpc {
+    const AUTHENTICATED: bool = false;
+}
+impl LeaderCall for PutFailureDomainRpc {
+    const AUTHENTICATED: bool = true;
+}
+impl LeaderCall for PutBucketAttributesRpc {
+    const AUTHENTICATED: bool = true;
+}
+impl LeaderCall for PutMaintenanceWindowsRpc {
+    const AUTHENTICATED: bool = true;
+}
+impl LeaderCall for RelayoutBucketRpc {
+    const AUTHENTICATED: bool = true;
+}
+impl LeaderCall for ListBucketRelayoutsRpc {
+    const AUTHENTICATED: bool = false;
+}
+impl LeaderCall for FinishSegmentRelayoutRpc {
+    const AUTHENTICATED: bool = true;
+}
+impl LeaderCall for DecommissionDevicesRpc {
+    const AUTHENTICATED: bool = true;
+}
+impl LeaderCall for ListDecommissionsRpc {
+    const AUTHENTICATED: bool = false;
+}
+
+/// `contact_server`経由で構成管理用クラスタのリーダを特定して、そのリーダに`C`を発行する。
+///
+/// RPC サービスを一時的に用意して、応答が得られるまで待機する。
+fn call_leader<C, T>(logger: &Logger, contact_server: SocketAddr, request: C::Req) -> Result<T>
+where
+    C: LeaderCall<Res = libfrugalos::Result<T>>,
+    C::Req: Serialize + DeserializeOwned + Send + 'static,
+    C::ReqEncoder: Default,
+    C::ResDecoder: Default,
+    T: Send + 'static,
+{
+    let mut executor = track!(ThreadPoolExecutor::new().map_err(Error::from))?;
+    let rpc_service = RpcServiceBuilder::new()
+        .logger(logger.clone())
+        .finish(executor.handle());
+    let rpc_service_handle = rpc_service.handle();
+    executor.spawn(rpc_service.map_err(|e| panic!("{}", e)));
+
+    let future = call_leader_async::<C, T>(rpc_service_handle, contact_server, request);
+    let monitor = executor.spawn_monitor(future);
+    let result = track!(executor.run_fiber(monitor).map_err(Error::from))?;
+    track!(result.map_err(Error::from))
+}
+
+/// `call_leader`の非同期版で、実行中のデーモンの RPC サービスを用いる。
+fn call_leader_async<C, T>(
+    rpc_service: RpcServiceHandle,
+    contact_server: SocketAddr,
+    request: C::Req,
+) -> impl Future<Item = T, Error = Error>
+where
+    C: LeaderCall<Res = libfrugalos::Result<T>>,
+    C::Req: Serialize + DeserializeOwned + Send + 'static,
+    C::ReqEncoder: Default,
+    C::ResDecoder: Default,
+{
+    GetLeaderRpc::client(&rpc_service)
+        .call(contact_server, ())
+        .map_err(|e| track!(Error::from(e)))
+        .and_then(|result| result.map_err(|e| track!(Error::from(e))))
+        .and_then(move |leader| {
+            let response = if C::AUTHENTICATED {
+                rpc_auth::call::<C>(&rpc_service, leader, request)
+            } else {
+                rpc_auth::call_with_token::<C>(
+                    &rpc_service,
+                    leader,
+                    None,
+                    request,
+                    RpcOptions::default(),
+                )
+            };
+            response.map_err(|e| track!(Error::from(e)))
+        })
+        .and_then(|result| result.map_err(|e| track!(Error::from(e))))
+}
+
+/// `snapshot`を初期状態とする、`local`だけを含むRaftクラスタを生成する。
+fn bootstrap<P: AsRef<Path>>(
+    logger: &Logger,
+    local: Server,
+    data_dir: P,
+    snapshot: Snapshot,
+) -> Result<()> {
     let node = server_to_frugalos_raft_node(&local);
 
     let mut executor = track!(ThreadPoolExecutor::new().map_err(Error::from))?;
@@ -166,7 +610,7 @@ pub fn create<P: AsRef<Path>>(logger: &Logger, mut local: Server, data_dir: P) -
     executor.spawn(rpc_service.map_err(move |e| panic!("Error: {}", e)));
 
     // クラスタ構成に自サーバを登録
-    let monitor = executor.spawn_monitor(CreateCluster::new(logger.clone(), rlog, local.clone()));
+    let monitor = executor.spawn_monitor(CreateCluster::new(logger.clone(), rlog, snapshot));
     let result = track!(executor.run_fiber(monitor).map_err(Error::from))?;
     track!(result.map_err(Error::from))?;
 
@@ -177,8 +621,6 @@ pub fn create<P: AsRef<Path>>(logger: &Logger, mut local: Server, data_dir: P) -
 
     // ローカルにも情報を保存
     track!(save_local_server_info(data_dir, local))?;
-
-    info!(logger, "[FINISH] create");
     Ok(())
 }
 
@@ -203,10 +645,13 @@ pub fn join<P: AsRef<Path>>(
     let rpc_service = RpcServiceBuilder::new()
         .logger(logger.clone())
         .finish(executor.handle());
-    let client = Client::new(contact_server, rpc_service.handle());
+    let rpc_service_handle = rpc_service.handle();
     executor.spawn(rpc_service.map_err(|e| panic!("{}", e)));
 
-    let monitor = executor.spawn_monitor(client.put_server(local.clone()));
+    let future = rpc_auth::call::<PutServerRpc>(&rpc_service_handle, contact_server, local.clone())
+        .map_err(|e| track!(Error::from(e)))
+        .and_then(|result| result.map_err(|e| track!(Error::from(e))));
+    let monitor = executor.spawn_monitor(future);
     let result = track!(executor.run_fiber(monitor).map_err(Error::from))?;
     let joined = track!(result.map_err(Error::from))?;
     info!(
@@ -242,10 +687,14 @@ pub fn leave<P: AsRef<Path>>(
     let rpc_service = RpcServiceBuilder::new()
         .logger(logger.clone())
         .finish(executor.handle());
-    let client = Client::new(contact_server, rpc_service.handle());
+    let rpc_service_handle = rpc_service.handle();
     executor.spawn(rpc_service.map_err(|e| panic!("{}", e)));
 
-    let monitor = executor.spawn_monitor(client.delete_server(local.id.clone()));
+    let future =
+        rpc_auth::call::<DeleteServerRpc>(&rpc_service_handle, contact_server, local.id.clone())
+            .map_err(|e| track!(Error::from(e)))
+            .and_then(|result| result.map_err(|e| track!(Error::from(e))));
+    let monitor = executor.spawn_monitor(future);
     let result = track!(executor.run_fiber(monitor).map_err(Error::from))?;
     let left = track!(result.map_err(Error::from))?;
     info!(
@@ -264,14 +713,14 @@ pub fn leave<P: AsRef<Path>>(
 struct CreateCluster {
     logger: Logger,
     rlog: ReplicatedLog<RaftIo>,
-    local: Server,
+    snapshot: Option<Snapshot>,
 }
 impl CreateCluster {
-    pub fn new(logger: Logger, rlog: ReplicatedLog<RaftIo>, local: Server) -> Self {
+    pub fn new(logger: Logger, rlog: ReplicatedLog<RaftIo>, snapshot: Snapshot) -> Self {
         CreateCluster {
             logger,
             rlog,
-            local,
+            snapshot: Some(snapshot),
         }
     }
 }
@@ -289,7 +738,7 @@ impl Future for CreateCluster {
                     entry: LogEntry::Noop { .. },
                     index,
                 } => {
-                    let snapshot = Snapshot::initial(self.local.clone());
+                    let snapshot = self.snapshot.take().expect("Never fails");
                     let snapshot =
                         track!(protobuf::snapshot_encoder().encode_into_bytes(snapshot))?;
                     track!(self.rlog.install_snapshot(index + 1, snapshot))?;
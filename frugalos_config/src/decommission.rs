@@ -0,0 +1,235 @@
+//! デバイスを安全に取り外すための、デバイスの退役。
+//!
+//! 退役中のデバイスは、以降に構築される全てのセグメントテーブルの配置先から除外される。
+//! 退役を開始すると、退役中のデバイスをメンバに含むセグメントが、それらを除いた配置へと再配置され
+//! (`relayout`モジュールを参照)、断片は MDS によって新しい配置にコピーされる。
+//! 再配置中のバケツは、その再配置が完了した時点で改めて退役の対象となる。
+//!
+//! 全てのセグメントが新しい配置に切り替わると、デバイスはどのセグメントからも参照されなくなり、
+//! 取り外しても良い状態(`DeviceDecommission::ready`)となる。
+//!
+//! NOTE: https://github.com/frugalos/frugalos/issues/208 のため、仮想デバイスの子の一覧は更新できない。
+//! そのため、退役したデバイス自体はデバイスツリーに残り、退役中の一覧によって配置先から除外され続ける。
+use libfrugalos::entity::bucket::BucketId;
+use libfrugalos::entity::device::{Device, DeviceId};
+use std::collections::{BTreeMap, BTreeSet};
+
+use machine::{Segment, SegmentTable};
+use {ErrorKind, Result};
+
+type Devices = BTreeMap<DeviceId, Device>;
+
+/// 退役中のデバイスの状態。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceDecommission {
+    /// デバイスの ID。
+    pub device_id: DeviceId,
+
+    /// このデバイスをメンバに含むセグメントの数。
+    ///
+    /// 再配置中のセグメントでは、変更前の配置も数に含まれる。
+    pub remaining_segments: u64,
+
+    /// `remaining_segments`が 0 となり、デバイスを取り外しても良い状態かどうか。
+    pub ready: bool,
+}
+
+/// 退役させるデバイスとして`id`が妥当かどうかを検証する。
+///
+/// 断片を保持し得るのは物理デバイスのみなので、仮想デバイスは指定できない。
+#[allow(clippy::ptr_arg)]
+pub fn validate_device(devices: &Devices, id: &DeviceId) -> Result<()> {
+    let device = track_assert_some!(
+        devices.get(id),
+        ErrorKind::InvalidInput,
+        "No such device: {:?}",
+        id
+    );
+    track_assert!(
+        !device.is_virtual(),
+        ErrorKind::InvalidInput,
+        "Virtual devices cannot be decommissioned: {:?}",
+        id
+    );
+    Ok(())
+}
+
+/// 退役中のデバイスを除いた、配置先となり得るデバイス群を返す。
+///
+/// 退役中のデバイスは仮想デバイスの子の一覧から取り除かれる。
+/// その結果として子を持たなくなった仮想デバイスも、同様にその親の子の一覧から取り除かれる
+/// (バケツのルートデバイスとして参照されている可能性があるので、一覧自体からは削除しない)。
+pub fn placeable_devices(devices: &Devices, decommissioned: &BTreeSet<DeviceId>) -> Devices {
+    let mut devices = devices.clone();
+    if decommissioned.is_empty() {
+        return devices;
+    }
+    let mut excluded = decommissioned.clone();
+    loop {
+        let mut emptied = Vec::new();
+        for device in devices.values_mut() {
+            if let Device::Virtual(ref mut d) = *device {
+                let was_empty = d.children.is_empty();
+                d.children.retain(|c| !excluded.contains(c));
+                if !was_empty && d.children.is_empty() {
+                    emptied.push(d.id.clone());
+                }
+            }
+        }
+        if emptied.is_empty() {
+            break;
+        }
+        excluded.extend(emptied);
+    }
+    devices
+}
+
+/// `segment`のいずれかのグループが、`device_nos`に含まれるデバイスをメンバに持つかどうかを返す。
+pub fn uses_devices(segment: &Segment, device_nos: &BTreeSet<u32>) -> bool {
+    segment
+        .groups
+        .iter()
+        .any(|g| g.members.iter().any(|m| device_nos.contains(m)))
+}
+
+/// `decommissioned`の各デバイスの状態を返す。
+pub fn statuses<'a, I>(
+    decommissioned: I,
+    devices: &Devices,
+    segment_tables: &BTreeMap<BucketId, SegmentTable>,
+) -> Vec<DeviceDecommission>
+where
+    I: IntoIterator<Item = &'a DeviceId>,
+{
+    decommissioned
+        .into_iter()
+        .map(|id| {
+            let remaining_segments = devices.get(id).map_or(0, |d| {
+                let device_nos = Some(d.seqno()).into_iter().collect();
+                segment_tables
+                    .values()
+                    .flat_map(|t| t.segments.iter())
+                    .filter(|s| uses_devices(s, &device_nos))
+                    .count() as u64
+            });
+            DeviceDecommission {
+                device_id: id.clone(),
+                remaining_segments,
+                ready: remaining_segments == 0,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use libfrugalos::entity::device::SegmentAllocationPolicy;
+
+    use super::*;
+    use machine::DeviceGroup;
+    use test_util::build_device_tree;
+
+    fn segment(groups: &[&[u32]]) -> Segment {
+        Segment {
+            groups: groups
+                .iter()
+                .enumerate()
+                .map(|(i, members)| DeviceGroup {
+                    members: members.to_vec(),
+                    generation: i as u8,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn placeable_devices_works() {
+        let (devices, root) =
+            build_device_tree(&[2, 2], SegmentAllocationPolicy::ScatterIfPossible);
+        let children = |devices: &Devices, id: &DeviceId| match devices[id] {
+            Device::Virtual(ref d) => d.children.clone(),
+            _ => panic!(),
+        };
+        let racks = children(&devices, &root).into_iter().collect::<Vec<_>>();
+        let rack0_leaves = children(&devices, &racks[0])
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        // 退役中のデバイスがなければ、そのまま
+        let placeable = placeable_devices(&devices, &BTreeSet::new());
+        assert_eq!(children(&placeable, &racks[0]).len(), 2);
+
+        // 物理デバイスは親の子の一覧から取り除かれる
+        let decommissioned = Some(rack0_leaves[0].clone()).into_iter().collect();
+        let placeable = placeable_devices(&devices, &decommissioned);
+        assert_eq!(placeable.len(), devices.len());
+        assert_eq!(
+            children(&placeable, &racks[0]),
+            Some(rack0_leaves[1].clone()).into_iter().collect()
+        );
+        assert_eq!(children(&placeable, &root).len(), 2);
+
+        // 子がなくなった仮想デバイスも取り除かれる
+        let decommissioned = rack0_leaves.iter().cloned().collect();
+        let placeable = placeable_devices(&devices, &decommissioned);
+        assert!(children(&placeable, &racks[0]).is_empty());
+        assert_eq!(
+            children(&placeable, &root),
+            Some(racks[1].clone()).into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn validate_device_works() {
+        let (devices, root) = build_device_tree(&[2], SegmentAllocationPolicy::ScatterIfPossible);
+        let leaf = devices
+            .values()
+            .find(|d| !d.is_virtual())
+            .map(|d| d.id().clone())
+            .unwrap();
+        assert!(validate_device(&devices, &leaf).is_ok());
+        assert!(validate_device(&devices, &root).is_err());
+        assert!(validate_device(&devices, &"unknown".to_owned()).is_err());
+    }
+
+    #[test]
+    fn statuses_works() {
+        let (devices, _) = build_device_tree(&[4], SegmentAllocationPolicy::ScatterIfPossible);
+        let leaves = devices
+            .values()
+            .filter(|d| !d.is_virtual())
+            .map(|d| (d.id().clone(), d.seqno()))
+            .collect::<Vec<_>>();
+        let (n0, n1, n2) = (leaves[0].1, leaves[1].1, leaves[2].1);
+
+        let mut tables = BTreeMap::new();
+        tables.insert(
+            "foo".to_owned(),
+            SegmentTable {
+                bucket_id: "foo".to_owned(),
+                segments: vec![
+                    segment(&[&[n0, n1]]),
+                    // 再配置中のセグメントでは、変更前の配置も数える
+                    segment(&[&[n1, n2], &[n0, n1]]),
+                ],
+            },
+        );
+        let decommissioned = vec![leaves[0].0.clone(), leaves[3].0.clone()];
+        let statuses = statuses(&decommissioned, &devices, &tables);
+        assert_eq!(
+            statuses,
+            vec![
+                DeviceDecommission {
+                    device_id: leaves[0].0.clone(),
+                    remaining_segments: 2,
+                    ready: false,
+                },
+                DeviceDecommission {
+                    device_id: leaves[3].0.clone(),
+                    remaining_segments: 0,
+                    ready: true,
+                },
+            ]
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use bytecodec;
 use cannyls;
 use fibers::sync::oneshot::MonitorError;
+use fibers_rpc;
 use libfrugalos;
 use raftlog;
 use std;
@@ -36,6 +37,11 @@ impl From<raftlog::Error> for Error {
         kind.takes_over(f).into()
     }
 }
+impl From<fibers_rpc::Error> for Error {
+    fn from(f: fibers_rpc::Error) -> Self {
+        ErrorKind::Other.takes_over(f).into()
+    }
+}
 impl From<bytecodec::Error> for Error {
     fn from(f: bytecodec::Error) -> Self {
         ErrorKind::InvalidInput.takes_over(f).into()
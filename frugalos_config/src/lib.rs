@@ -9,6 +9,7 @@ extern crate cannyls;
 extern crate fibers;
 extern crate fibers_rpc;
 extern crate fibers_tasque;
+extern crate frugalos_core;
 extern crate futures;
 extern crate libfrugalos;
 extern crate prometrics;
@@ -17,6 +18,9 @@ extern crate protobuf_codec;
 extern crate frugalos_raft;
 extern crate raftlog;
 extern crate rendezvous_hash;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 #[macro_use]
 extern crate slog;
 #[macro_use]
@@ -28,22 +32,45 @@ macro_rules! dump {
     }
 }
 
+pub use self::attribute::BucketAttributes;
+pub use self::backup::ConfigBackup;
 pub use self::error::{Error, ErrorKind};
+pub use baseline::DeviceBaseline;
+pub use decommission::DeviceDecommission;
 pub use machine::DeviceGroup;
+pub use maintenance::{MaintenanceWindow, MaintenanceWindows};
+pub use rebalance::{
+    BucketRebalance, BucketRebalancePlan, DeviceLoad, RebalanceOptions, RebalancePlan, SegmentMove,
+};
+pub use relayout::{BucketRelayout, RelayoutParameters, RelayoutedSegment};
 pub use rpc::RpcServer;
 pub use service::{Event, Service, ServiceHandle};
+pub use simulation::{BucketChangePlan, ChangePlan, ProposedChange};
+pub use topology::FailureDomain;
+pub use usage::DeviceUsage;
 
 pub mod cluster;
+pub mod schema;
 
+mod attribute;
+mod backup;
+mod baseline;
 mod builder;
 mod config;
+mod decommission;
 mod error;
 mod machine;
+mod maintenance;
 mod protobuf;
+mod rebalance;
+mod relayout;
 mod rpc;
 mod service;
+mod simulation;
 #[cfg(test)]
 mod test_util;
+mod topology;
+mod usage;
 
 /// クレート固有の`Result`型。
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -2,6 +2,14 @@ use libfrugalos::entity::bucket::{Bucket, BucketId};
 use libfrugalos::entity::device::{Device, DeviceId};
 use libfrugalos::entity::server::{Server, ServerId};
 
+use attribute::BucketAttributes;
+use baseline::DeviceBaseline;
+use maintenance::MaintenanceWindows;
+use rebalance::BucketRebalance;
+use relayout::{BucketRelayout, RelayoutedSegment};
+use topology::FailureDomain;
+use usage::DeviceUsage;
+
 #[derive(Debug, Clone)]
 pub enum Command {
     PutBucket { bucket: Bucket },
@@ -10,6 +18,15 @@ pub enum Command {
     DeleteDevice { id: DeviceId },
     PutServer { server: Server },
     DeleteServer { id: ServerId },
+    PutDeviceUsages { usages: Vec<DeviceUsage> },
+    PutFailureDomain { domain: FailureDomain },
+    PutBucketAttributes { attributes: BucketAttributes },
+    RelayoutBucket { bucket: Bucket },
+    FinishSegmentRelayout { segment: RelayoutedSegment },
+    DecommissionDevices { device_ids: Vec<DeviceId> },
+    RebalanceBucket { rebalance: BucketRebalance },
+    PutMaintenanceWindows { windows: MaintenanceWindows },
+    PutDeviceBaseline { baseline: DeviceBaseline },
 }
 
 #[derive(Debug, Clone)]
@@ -19,6 +36,13 @@ pub struct Snapshot {
     pub devices: Vec<Device>,
     pub servers: Vec<Server>,
     pub segment_tables: Vec<SegmentTable>,
+    pub device_usages: Vec<DeviceUsage>,
+    pub failure_domains: Vec<FailureDomain>,
+    pub bucket_attributes: Vec<BucketAttributes>,
+    pub relayouts: Vec<BucketRelayout>,
+    pub decommissioned_devices: Vec<DeviceId>,
+    pub maintenance_windows: MaintenanceWindows,
+    pub device_baselines: Vec<DeviceBaseline>,
 }
 impl Snapshot {
     pub fn initial(server: Server) -> Self {
@@ -32,6 +56,13 @@ impl Snapshot {
             devices: Vec::new(),
             servers: vec![server],
             segment_tables: Vec::new(),
+            device_usages: Vec::new(),
+            failure_domains: Vec::new(),
+            bucket_attributes: Vec::new(),
+            relayouts: Vec::new(),
+            decommissioned_devices: Vec::new(),
+            maintenance_windows: MaintenanceWindows::default(),
+            device_baselines: Vec::new(),
         }
     }
 }
@@ -57,7 +88,7 @@ impl SegmentTable {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Segment {
     pub groups: Vec<DeviceGroup>,
 }
@@ -65,8 +96,26 @@ pub struct Segment {
 /// デバイスグループ。
 ///
 /// 同一セグメントに属するメンバ群、を表現している。
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DeviceGroup {
     /// 同一セグメントに属するデバイス群のシーケンス番号。
     pub members: Vec<u32>,
+
+    /// デバイスグループの世代。
+    ///
+    /// セグメントが再配置される度に一つ進む(`relayout`モジュールを参照)。
+    pub generation: u8,
+}
+impl DeviceGroup {
+    /// `member_no`番目のメンバの、ノード ID に埋め込まれるメンバ番号を返す。
+    ///
+    /// 上位 3 ビットが世代で、下位 5 ビットがグループ内での位置となる。
+    /// 世代が 0 の場合には、グループ内での位置そのもの(再配置機能の導入前と同じ値)となる。
+    pub fn node_member_no(&self, member_no: usize) -> u8 {
+        if self.generation == 0 {
+            member_no as u8
+        } else {
+            (self.generation << 5) | (member_no as u8 & 0x1F)
+        }
+    }
 }
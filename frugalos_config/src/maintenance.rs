@@ -0,0 +1,103 @@
+//! メンテナンスウィンドウに関するモジュール。
+//!
+//! ウィンドウは構成管理用の Raft クラスタに登録されるので、全てのサーバで同じ時間帯が使われる。
+//! 実行中に変更することができ、変更は各サーバのバックグラウンド処理に即座に反映される。
+use {ErrorKind, Result};
+
+/// UTC からの時差(分)の上限。
+const MAX_UTC_OFFSET_MINUTES: i32 = 24 * 60;
+
+/// リペアや segment_gc、再配置等の負荷の高いバックグラウンド処理を実行する時間帯の設定。
+///
+/// ウィンドウが一つも指定されていない場合には、これらの処理は常に実行される。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindows {
+    /// メンテナンスウィンドウの一覧。
+    ///
+    /// 現在時刻がいずれかのウィンドウに含まれている間だけ、バックグラウンド処理が実行される。
+    #[serde(default)]
+    pub windows: Vec<MaintenanceWindow>,
+
+    /// スケジュールを解釈する際に用いる UTC からの時差(分)。
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+}
+impl MaintenanceWindows {
+    /// ウィンドウが一つも指定されておらず、時差も既定値の場合に`true`を返す。
+    pub fn is_default(&self) -> bool {
+        self.windows.is_empty() && self.utc_offset_minutes == 0
+    }
+
+    /// 設定の値が妥当かどうかを検証する。
+    ///
+    /// NOTE: スケジュールの書式は、各サーバでの適用時(`frugalos_segment::MaintenanceSchedule`)に検証される
+    pub fn validate(&self) -> Result<()> {
+        track_assert!(
+            -MAX_UTC_OFFSET_MINUTES < self.utc_offset_minutes
+                && self.utc_offset_minutes < MAX_UTC_OFFSET_MINUTES,
+            ErrorKind::InvalidInput,
+            "The UTC offset is out of range: {}",
+            self.utc_offset_minutes
+        );
+        for w in &self.windows {
+            track_assert_eq!(
+                w.schedule.split_whitespace().count(),
+                5,
+                ErrorKind::InvalidInput,
+                "A cron schedule must have five fields: {:?}",
+                w.schedule
+            );
+            track_assert_ne!(
+                w.duration_minutes,
+                0,
+                ErrorKind::InvalidInput,
+                "The duration of a maintenance window must be positive: {:?}",
+                w
+            );
+        }
+        Ok(())
+    }
+}
+
+/// 一つのメンテナンスウィンドウ。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    /// ウィンドウの開始時刻を表す cron 形式のスケジュール(分 時 日 月 曜日)。
+    ///
+    /// 例えば`"0 2 * * 1-5"`は、平日の午前二時を表す。
+    pub schedule: String,
+
+    /// ウィンドウの継続時間(分)。
+    pub duration_minutes: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_works() {
+        let mut windows = MaintenanceWindows::default();
+        assert!(windows.is_default());
+        assert!(windows.validate().is_ok());
+
+        windows.windows.push(MaintenanceWindow {
+            schedule: "0 2 * * 1-5".to_owned(),
+            duration_minutes: 60,
+        });
+        windows.utc_offset_minutes = 9 * 60;
+        assert!(!windows.is_default());
+        assert!(windows.validate().is_ok());
+
+        windows.utc_offset_minutes = 24 * 60;
+        assert!(windows.validate().is_err());
+
+        windows.utc_offset_minutes = 0;
+        windows.windows[0].duration_minutes = 0;
+        assert!(windows.validate().is_err());
+
+        windows.windows[0].duration_minutes = 60;
+        windows.windows[0].schedule = "0 2 * *".to_owned();
+        assert!(windows.validate().is_err());
+    }
+}
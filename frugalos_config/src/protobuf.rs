@@ -1,19 +1,30 @@
 use bytecodec::{DecodeExt, EncodeExt, ErrorKind, Result, SizedEncode};
+use frugalos_core::erasure_coding::{ErasureCoding, ErasureCodingBackend, ErasureCodingChecksum};
+use libfrugalos::consistency::ReadConsistency;
 use libfrugalos::entity::bucket::{Bucket, DispersedBucket, MetadataBucket, ReplicatedBucket};
 use libfrugalos::entity::device::{
     Device, FileDevice, MemoryDevice, SegmentAllocationPolicy, VirtualDevice, Weight,
 };
 use libfrugalos::entity::server::Server;
-use protobuf_codec::field::branch::{Branch2, Branch3, Branch6};
-use protobuf_codec::field::num::{F1, F2, F3, F4, F5, F6};
+use protobuf_codec::field::branch::{Branch2, Branch3, Branch8};
+use protobuf_codec::field::num::{
+    F1, F10, F11, F12, F13, F14, F15, F2, F3, F4, F5, F6, F7, F8, F9,
+};
 use protobuf_codec::message::{MessageDecode, MessageEncode};
 use protobuf_codec::scalar::{
-    DoubleDecoder, DoubleEncoder, StringDecoder, StringEncoder, Uint32Decoder, Uint32Encoder,
-    Uint64Decoder, Uint64Encoder,
+    BoolDecoder, BoolEncoder, DoubleDecoder, DoubleEncoder, Sint32Decoder, Sint32Encoder,
+    StringDecoder, StringEncoder, Uint32Decoder, Uint32Encoder, Uint64Decoder, Uint64Encoder,
 };
 use trackable::error::ErrorKindExt;
 
+use attribute::BucketAttributes;
+use baseline::DeviceBaseline;
 use machine::{Command, DeviceGroup, NextSeqNo, Segment, SegmentTable, Snapshot};
+use maintenance::{MaintenanceWindow, MaintenanceWindows};
+use rebalance::{BucketRebalance, SegmentMove};
+use relayout::{BucketRelayout, RelayoutedSegment};
+use topology::FailureDomain;
+use usage::DeviceUsage;
 
 //
 // https://github.com/frugalos/frugalos/blob/master/frugalos_config/schema/config.proto
@@ -345,22 +356,126 @@ pub fn weight_encoder() -> impl SizedEncode<Item = Weight> + MessageEncode<Item
 // https://github.com/frugalos/frugalos/blob/master/frugalos_config/schema/state.proto
 //
 pub fn command_decoder() -> impl MessageDecode<Item = Command> {
-    let base = protobuf_message_decoder![(
-        required_oneof,
-        (F1, put_bucket_decoder(), message),
-        (F2, delete_bucket_decoder(), message),
-        (F3, put_device_decoder(), message),
-        (F4, delete_device_decoder(), message),
-        (F5, put_server_decoder(), message),
-        (F6, delete_server_decoder(), message)
-    )];
-    base.map(|x| match x {
-        Branch6::A(bucket) => Command::PutBucket { bucket },
-        Branch6::B(id) => Command::DeleteBucket { id },
-        Branch6::C(device) => Command::PutDevice { device },
-        Branch6::D(id) => Command::DeleteDevice { id },
-        Branch6::E(server) => Command::PutServer { server },
-        Branch6::F(id) => Command::DeleteServer { id },
+    // NOTE: `protobuf_codec`の`oneof`は最大で 8 つの分岐しか扱えないので、
+    // 9 番目以降のコマンドは`oneof`の外のフィールドとしてデコードする (ワイヤ上の表現は同じ)
+    let base = protobuf_message_decoder![
+        (
+            oneof,
+            (F1, put_bucket_decoder(), message),
+            (F2, delete_bucket_decoder(), message),
+            (F3, put_device_decoder(), message),
+            (F4, delete_device_decoder(), message),
+            (F5, put_server_decoder(), message),
+            (F6, delete_server_decoder(), message),
+            (F7, put_device_usages_decoder(), message),
+            (F8, failure_domain_decoder(), message)
+        ),
+        (F9, bucket_attributes_decoder(), message),
+        (F10, put_bucket_decoder(), message),
+        (F11, relayouted_segment_decoder(), message),
+        (F12, decommission_devices_decoder(), message),
+        (F13, bucket_rebalance_decoder(), message),
+        (F14, maintenance_windows_decoder(), message),
+        (F15, device_baseline_decoder(), message)
+    ];
+    base.try_map(|x| -> Result<_> {
+        let command = match x {
+            (Some(Branch8::A(bucket)), None, None, None, None, None, None, None) => {
+                Command::PutBucket { bucket }
+            }
+            (Some(Branch8::B(id)), None, None, None, None, None, None, None) => {
+                Command::DeleteBucket { id }
+            }
+            (Some(Branch8::C(device)), None, None, None, None, None, None, None) => {
+                Command::PutDevice { device }
+            }
+            (Some(Branch8::D(id)), None, None, None, None, None, None, None) => {
+                Command::DeleteDevice { id }
+            }
+            (Some(Branch8::E(server)), None, None, None, None, None, None, None) => {
+                Command::PutServer { server }
+            }
+            (Some(Branch8::F(id)), None, None, None, None, None, None, None) => {
+                Command::DeleteServer { id }
+            }
+            (Some(Branch8::G(usages)), None, None, None, None, None, None, None) => {
+                Command::PutDeviceUsages { usages }
+            }
+            (Some(Branch8::H(domain)), None, None, None, None, None, None, None) => {
+                Command::PutFailureDomain { domain }
+            }
+            (None, Some(attributes), None, None, None, None, None, None) => {
+                Command::PutBucketAttributes { attributes }
+            }
+            (None, None, Some(bucket), None, None, None, None, None) => {
+                Command::RelayoutBucket { bucket }
+            }
+            (None, None, None, Some(segment), None, None, None, None) => {
+                Command::FinishSegmentRelayout { segment }
+            }
+            (None, None, None, None, Some(device_ids), None, None, None) => {
+                Command::DecommissionDevices { device_ids }
+            }
+            (None, None, None, None, None, Some(rebalance), None, None) => {
+                Command::RebalanceBucket { rebalance }
+            }
+            (None, None, None, None, None, None, Some(windows), None) => {
+                Command::PutMaintenanceWindows { windows }
+            }
+            (None, None, None, None, None, None, None, Some(baseline)) => {
+                Command::PutDeviceBaseline { baseline }
+            }
+            (None, None, None, None, None, None, None, None) => {
+                track_panic!(ErrorKind::InvalidInput, "No command")
+            }
+            _ => track_panic!(ErrorKind::InvalidInput, "Multiple commands"),
+        };
+        Ok(command)
+    })
+}
+
+pub fn decommission_devices_decoder() -> impl MessageDecode<Item = Vec<String>> {
+    protobuf_message_decoder![(F1, StringDecoder::new(), repeated)]
+}
+
+pub fn bucket_rebalance_decoder() -> impl MessageDecode<Item = BucketRebalance> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, segment_move_decoder(), repeated_message)
+    ];
+    base.map(|(bucket_id, moves)| BucketRebalance { bucket_id, moves })
+}
+
+pub fn segment_move_decoder() -> impl MessageDecode<Item = SegmentMove> {
+    let base = protobuf_message_decoder![
+        (F1, Uint32Decoder::new()),
+        (F2, StringDecoder::new()),
+        (F3, StringDecoder::new())
+    ];
+    base.try_map(|(segment, from, to)| -> Result<_> {
+        track_assert!(segment <= 0xFFFF, ErrorKind::InvalidInput);
+        Ok(SegmentMove {
+            segment: segment as u16,
+            from,
+            to,
+        })
+    })
+}
+
+pub fn relayouted_segment_decoder() -> impl MessageDecode<Item = RelayoutedSegment> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, Uint32Decoder::new()),
+        (F3, Uint32Decoder::new())
+    ];
+    base.try_map(|(bucket_id, segment_no, generation)| -> Result<_> {
+        track_assert!(segment_no <= 0xFFFF, ErrorKind::InvalidInput);
+        track_assert!(generation <= 0xFF, ErrorKind::InvalidInput);
+        Ok(RelayoutedSegment {
+            bucket_id,
+            segment_no: segment_no as u16,
+            generation: generation as u8,
+        })
     })
 }
 
@@ -388,23 +503,172 @@ pub fn delete_server_decoder() -> impl MessageDecode<Item = String> {
     protobuf_message_decoder![(F1, StringDecoder::new())]
 }
 
-pub fn command_encoder() -> impl SizedEncode<Item = Command> + MessageEncode<Item = Command> {
-    let base = protobuf_message_encoder![(
-        required_oneof,
-        (F1, put_bucket_encoder(), message),
-        (F2, delete_bucket_encoder(), message),
-        (F3, put_device_encoder(), message),
-        (F4, delete_device_encoder(), message),
-        (F5, put_server_encoder(), message),
-        (F6, delete_server_encoder(), message)
-    )];
+pub fn put_device_usages_decoder() -> impl MessageDecode<Item = Vec<DeviceUsage>> {
+    protobuf_message_decoder![(F1, device_usage_decoder(), repeated_message)]
+}
+
+pub fn command_encoder() -> impl MessageEncode<Item = Command> {
+    // NOTE: `command_decoder`と同様に、9 番目以降のコマンドは`oneof`の外のフィールドとして扱う
+    let base = protobuf_message_encoder![
+        (
+            oneof,
+            (F1, put_bucket_encoder(), message),
+            (F2, delete_bucket_encoder(), message),
+            (F3, put_device_encoder(), message),
+            (F4, delete_device_encoder(), message),
+            (F5, put_server_encoder(), message),
+            (F6, delete_server_encoder(), message),
+            (F7, put_device_usages_encoder(), unsized_message),
+            (F8, failure_domain_encoder(), message)
+        ),
+        (F9, bucket_attributes_encoder(), message),
+        (F10, put_bucket_encoder(), message),
+        (F11, relayouted_segment_encoder(), message),
+        (F12, decommission_devices_encoder(), unsized_message),
+        (F13, bucket_rebalance_encoder(), unsized_message),
+        (F14, maintenance_windows_encoder(), unsized_message),
+        (F15, device_baseline_encoder(), message)
+    ];
     base.map_from(|x: Command| match x {
-        Command::PutBucket { bucket } => Branch6::A(bucket),
-        Command::DeleteBucket { id } => Branch6::B(id),
-        Command::PutDevice { device } => Branch6::C(device),
-        Command::DeleteDevice { id } => Branch6::D(id),
-        Command::PutServer { server } => Branch6::E(server),
-        Command::DeleteServer { id } => Branch6::F(id),
+        Command::PutBucket { bucket } => (
+            Some(Branch8::A(bucket)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ),
+        Command::DeleteBucket { id } => (
+            Some(Branch8::B(id)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ),
+        Command::PutDevice { device } => (
+            Some(Branch8::C(device)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ),
+        Command::DeleteDevice { id } => (
+            Some(Branch8::D(id)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ),
+        Command::PutServer { server } => (
+            Some(Branch8::E(server)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ),
+        Command::DeleteServer { id } => (
+            Some(Branch8::F(id)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ),
+        Command::PutDeviceUsages { usages } => (
+            Some(Branch8::G(usages)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ),
+        Command::PutFailureDomain { domain } => (
+            Some(Branch8::H(domain)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        ),
+        Command::PutBucketAttributes { attributes } => {
+            (None, Some(attributes), None, None, None, None, None, None)
+        }
+        Command::RelayoutBucket { bucket } => {
+            (None, None, Some(bucket), None, None, None, None, None)
+        }
+        Command::FinishSegmentRelayout { segment } => {
+            (None, None, None, Some(segment), None, None, None, None)
+        }
+        Command::DecommissionDevices { device_ids } => {
+            (None, None, None, None, Some(device_ids), None, None, None)
+        }
+        Command::RebalanceBucket { rebalance } => {
+            (None, None, None, None, None, Some(rebalance), None, None)
+        }
+        Command::PutMaintenanceWindows { windows } => {
+            (None, None, None, None, None, None, Some(windows), None)
+        }
+        Command::PutDeviceBaseline { baseline } => {
+            (None, None, None, None, None, None, None, Some(baseline))
+        }
+    })
+}
+
+pub fn decommission_devices_encoder() -> impl MessageEncode<Item = Vec<String>> {
+    protobuf_message_encoder![(F1, StringEncoder::new(), repeated)]
+}
+
+pub fn bucket_rebalance_encoder() -> impl MessageEncode<Item = BucketRebalance> {
+    let base = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, segment_move_encoder(), repeated_message)
+    ];
+    base.map_from(|x: BucketRebalance| (x.bucket_id, x.moves))
+}
+
+pub fn segment_move_encoder(
+) -> impl SizedEncode<Item = SegmentMove> + MessageEncode<Item = SegmentMove> {
+    let base = protobuf_message_encoder![
+        (F1, Uint32Encoder::new()),
+        (F2, StringEncoder::new()),
+        (F3, StringEncoder::new())
+    ];
+    base.map_from(|x: SegmentMove| (u32::from(x.segment), x.from, x.to))
+}
+
+pub fn relayouted_segment_encoder(
+) -> impl SizedEncode<Item = RelayoutedSegment> + MessageEncode<Item = RelayoutedSegment> {
+    let base = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, Uint32Encoder::new()),
+        (F3, Uint32Encoder::new())
+    ];
+    base.map_from(|x: RelayoutedSegment| {
+        (
+            x.bucket_id,
+            u32::from(x.segment_no),
+            u32::from(x.generation),
+        )
     })
 }
 
@@ -432,23 +696,316 @@ pub fn delete_server_encoder() -> impl SizedEncode<Item = String> + MessageEncod
     protobuf_message_encoder![(F1, StringEncoder::new())]
 }
 
+pub fn put_device_usages_encoder() -> impl MessageEncode<Item = Vec<DeviceUsage>> {
+    protobuf_message_encoder![(F1, device_usage_encoder(), repeated_message)]
+}
+
+pub fn device_usage_decoder() -> impl MessageDecode<Item = DeviceUsage> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, Uint64Decoder::new()),
+        (F3, Uint64Decoder::new())
+    ];
+    base.map(|x| DeviceUsage {
+        device_id: x.0,
+        capacity_bytes: x.1,
+        usage_bytes: x.2,
+    })
+}
+
+pub fn device_usage_encoder(
+) -> impl SizedEncode<Item = DeviceUsage> + MessageEncode<Item = DeviceUsage> {
+    let base = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, Uint64Encoder::new()),
+        (F3, Uint64Encoder::new())
+    ];
+    base.map_from(|x: DeviceUsage| (x.device_id, x.capacity_bytes, x.usage_bytes))
+}
+
+pub fn device_baseline_decoder() -> impl MessageDecode<Item = DeviceBaseline> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, Uint64Decoder::new()),
+        (F3, Uint64Decoder::new()),
+        (F4, Uint64Decoder::new()),
+        (F5, Uint64Decoder::new()),
+        (F6, BoolDecoder::new())
+    ];
+    base.map(|x| DeviceBaseline {
+        device_id: x.0,
+        write_bytes_per_second: x.1,
+        read_bytes_per_second: x.2,
+        max_write_latency_micros: x.3,
+        max_read_latency_micros: x.4,
+        passed: x.5,
+    })
+}
+
+pub fn device_baseline_encoder(
+) -> impl SizedEncode<Item = DeviceBaseline> + MessageEncode<Item = DeviceBaseline> {
+    let base = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, Uint64Encoder::new()),
+        (F3, Uint64Encoder::new()),
+        (F4, Uint64Encoder::new()),
+        (F5, Uint64Encoder::new()),
+        (F6, BoolEncoder::new())
+    ];
+    base.map_from(|x: DeviceBaseline| {
+        (
+            x.device_id,
+            x.write_bytes_per_second,
+            x.read_bytes_per_second,
+            x.max_write_latency_micros,
+            x.max_read_latency_micros,
+            x.passed,
+        )
+    })
+}
+
+pub fn failure_domain_decoder() -> impl MessageDecode<Item = FailureDomain> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, StringDecoder::new()),
+        (F3, StringDecoder::new())
+    ];
+    let non_empty = |s: String| if s.is_empty() { None } else { Some(s) };
+    base.map(move |x| FailureDomain {
+        server_id: x.0,
+        zone: non_empty(x.1),
+        rack: non_empty(x.2),
+    })
+}
+
+pub fn failure_domain_encoder(
+) -> impl SizedEncode<Item = FailureDomain> + MessageEncode<Item = FailureDomain> {
+    let base = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, StringEncoder::new()),
+        (F3, StringEncoder::new())
+    ];
+    base.map_from(|x: FailureDomain| {
+        (
+            x.server_id,
+            x.zone.unwrap_or_default(),
+            x.rack.unwrap_or_default(),
+        )
+    })
+}
+
+pub fn maintenance_windows_decoder() -> impl MessageDecode<Item = MaintenanceWindows> {
+    let base = protobuf_message_decoder![
+        (F1, maintenance_window_decoder(), repeated_message),
+        (F2, Sint32Decoder::new())
+    ];
+    base.map(|(windows, utc_offset_minutes)| MaintenanceWindows {
+        windows,
+        utc_offset_minutes,
+    })
+}
+
+pub fn maintenance_window_decoder() -> impl MessageDecode<Item = MaintenanceWindow> {
+    let base = protobuf_message_decoder![(F1, StringDecoder::new()), (F2, Uint32Decoder::new())];
+    base.map(|(schedule, duration_minutes)| MaintenanceWindow {
+        schedule,
+        duration_minutes,
+    })
+}
+
+pub fn maintenance_windows_encoder() -> impl MessageEncode<Item = MaintenanceWindows> {
+    let base = protobuf_message_encoder![
+        (F1, maintenance_window_encoder(), repeated_message),
+        (F2, Sint32Encoder::new())
+    ];
+    base.map_from(|x: MaintenanceWindows| (x.windows, x.utc_offset_minutes))
+}
+
+pub fn maintenance_window_encoder(
+) -> impl SizedEncode<Item = MaintenanceWindow> + MessageEncode<Item = MaintenanceWindow> {
+    let base = protobuf_message_encoder![(F1, StringEncoder::new()), (F2, Uint32Encoder::new())];
+    base.map_from(|x: MaintenanceWindow| (x.schedule, x.duration_minutes))
+}
+
+pub fn bucket_attributes_decoder() -> impl MessageDecode<Item = BucketAttributes> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, BoolDecoder::new()),
+        (F3, Uint64Decoder::new()),
+        (F4, Uint32Decoder::new()),
+        (F5, Uint32Decoder::new()),
+        (F6, Uint64Decoder::new()),
+        (F7, Uint64Decoder::new()),
+        (F8, storage_attributes_decoder(), message)
+    ];
+    base.map(|x| {
+        let (erasure_coding, mds_witnesses) = x.7.unwrap_or_default();
+        BucketAttributes {
+            bucket_id: x.0,
+            read_only: x.1,
+            default_deadline_ms: if x.2 == 0 { None } else { Some(x.2) },
+            default_consistency: read_consistency_from_kind(x.3, x.4),
+            max_objects: x.5.checked_sub(1),
+            max_bytes: x.6.checked_sub(1),
+            erasure_coding,
+            mds_witnesses,
+        }
+    })
+}
+
+pub fn bucket_attributes_encoder(
+) -> impl SizedEncode<Item = BucketAttributes> + MessageEncode<Item = BucketAttributes> {
+    let base = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, BoolEncoder::new()),
+        (F3, Uint64Encoder::new()),
+        (F4, Uint32Encoder::new()),
+        (F5, Uint32Encoder::new()),
+        (F6, Uint64Encoder::new()),
+        (F7, Uint64Encoder::new()),
+        (F8, storage_attributes_encoder(), message)
+    ];
+    base.map_from(|x: BucketAttributes| {
+        let (kind, subset) = read_consistency_to_kind(x.default_consistency.as_ref());
+        // 上限は「値 + 1」で表現し、0 は上限が設けられていないことを表す
+        let limit = |max: Option<u64>| max.map_or(0, |max| max.saturating_add(1));
+        (
+            x.bucket_id,
+            x.read_only,
+            x.default_deadline_ms.unwrap_or(0),
+            kind,
+            subset,
+            limit(x.max_objects),
+            limit(x.max_bytes),
+            if x.erasure_coding.is_none() && x.mds_witnesses == 0 {
+                None
+            } else {
+                Some((x.erasure_coding, x.mds_witnesses))
+            },
+        )
+    })
+}
+
+// バケツの属性のうち、オブジェクトやメタデータの保存方法に関するもの(Erasure Coding の実装と MDS のウィットネスの数)。
+//
+// NOTE: `protobuf_codec`のメッセージは最大で 8 つのフィールドしか扱えないので、一つのメッセージにまとめている
+pub fn storage_attributes_decoder() -> impl MessageDecode<Item = (Option<ErasureCoding>, usize)> {
+    let base = protobuf_message_decoder![
+        (F1, erasure_coding_decoder(), message),
+        (F2, Uint64Decoder::new())
+    ];
+    base.map(|(coding, witnesses)| (coding.and_then(|x| x), witnesses as usize))
+}
+
+pub fn storage_attributes_encoder() -> impl SizedEncode<Item = (Option<ErasureCoding>, usize)>
+       + MessageEncode<Item = (Option<ErasureCoding>, usize)> {
+    let base = protobuf_message_encoder![
+        (F1, erasure_coding_encoder(), message),
+        (F2, Uint64Encoder::new())
+    ];
+    base.map_from(|(coding, witnesses): (Option<ErasureCoding>, usize)| (coding, witnesses as u64))
+}
+
+// NOTE: 未知のバックエンドおよびチェックサムは、未設定(既定の実装)として扱う
+pub fn erasure_coding_decoder() -> impl MessageDecode<Item = Option<ErasureCoding>> {
+    let base = protobuf_message_decoder![(F1, Uint32Decoder::new()), (F2, Uint32Decoder::new())];
+    base.map(|(backend, checksum)| {
+        let backend = match backend {
+            0 => ErasureCodingBackend::JerasureRsVand,
+            1 => ErasureCodingBackend::JerasureRsCauchy,
+            2 => ErasureCodingBackend::IsaLRsVand,
+            3 => ErasureCodingBackend::IsaLRsCauchy,
+            4 => ErasureCodingBackend::RustReedSolomon,
+            _ => return None,
+        };
+        let checksum = match checksum {
+            0 => ErasureCodingChecksum::None,
+            1 => ErasureCodingChecksum::Crc32,
+            2 => ErasureCodingChecksum::Md5,
+            _ => return None,
+        };
+        Some(ErasureCoding { backend, checksum })
+    })
+}
+
+pub fn erasure_coding_encoder(
+) -> impl SizedEncode<Item = ErasureCoding> + MessageEncode<Item = ErasureCoding> {
+    let base = protobuf_message_encoder![(F1, Uint32Encoder::new()), (F2, Uint32Encoder::new())];
+    base.map_from(|x: ErasureCoding| {
+        let backend = match x.backend {
+            ErasureCodingBackend::JerasureRsVand => 0,
+            ErasureCodingBackend::JerasureRsCauchy => 1,
+            ErasureCodingBackend::IsaLRsVand => 2,
+            ErasureCodingBackend::IsaLRsCauchy => 3,
+            ErasureCodingBackend::RustReedSolomon => 4,
+        };
+        let checksum = match x.checksum {
+            ErasureCodingChecksum::None => 0,
+            ErasureCodingChecksum::Crc32 => 1,
+            ErasureCodingChecksum::Md5 => 2,
+        };
+        (backend, checksum)
+    })
+}
+
+// `ReadConsistencyKind`の値と、`SUBSET`の場合のノード数から`ReadConsistency`を復元する。
+// 未知の値は未設定として扱う。
+fn read_consistency_from_kind(kind: u32, subset: u32) -> Option<ReadConsistency> {
+    match kind {
+        1 => Some(ReadConsistency::Consistent),
+        2 => Some(ReadConsistency::Stale),
+        3 => Some(ReadConsistency::Quorum),
+        4 => Some(ReadConsistency::Subset(subset as usize)),
+        _ => None,
+    }
+}
+
+fn read_consistency_to_kind(consistency: Option<&ReadConsistency>) -> (u32, u32) {
+    match consistency {
+        None => (0, 0),
+        Some(ReadConsistency::Consistent) => (1, 0),
+        Some(ReadConsistency::Stale) => (2, 0),
+        Some(ReadConsistency::Quorum) => (3, 0),
+        Some(ReadConsistency::Subset(n)) => (4, *n as u32),
+    }
+}
+
 pub fn snapshot_decoder() -> impl MessageDecode<Item = Snapshot> {
     let base = protobuf_message_decoder![
         (F1, next_seqno_decoder(), message),
         (F2, bucket_decoder(), repeated_message),
         (F3, device_decoder(), repeated_message),
         (F4, server_decoder(), repeated_message),
-        (F5, segment_table_decoder(), repeated_message)
+        (F5, segment_table_decoder(), repeated_message),
+        (F6, device_usage_decoder(), repeated_message),
+        (F7, failure_domain_decoder(), repeated_message),
+        (F8, bucket_attributes_decoder(), repeated_message)
+    ];
+    // NOTE: 内側のメッセージのフィールド数が上限に達しているので、新しい項目は外側のメッセージに追加する
+    let base = protobuf_message_decoder![
+        (F1, base, required_message),
+        (F2, bucket_relayout_decoder(), repeated_message),
+        (F3, StringDecoder::new(), repeated),
+        (F4, maintenance_windows_decoder(), message),
+        (F5, device_baseline_decoder(), repeated_message)
     ];
-    let base = protobuf_message_decoder![(F1, base, required_message)];
 
-    base.map(|x| Snapshot {
-        next_seqno: x.0.unwrap_or_else(Default::default),
-        buckets: x.1,
-        devices: x.2,
-        servers: x.3,
-        segment_tables: x.4,
-    })
+    base.map(
+        |(x, relayouts, decommissioned_devices, maintenance_windows, device_baselines)| Snapshot {
+            next_seqno: x.0.unwrap_or_else(Default::default),
+            buckets: x.1,
+            devices: x.2,
+            servers: x.3,
+            segment_tables: x.4,
+            device_usages: x.5,
+            failure_domains: x.6,
+            bucket_attributes: x.7,
+            relayouts,
+            decommissioned_devices,
+            maintenance_windows: maintenance_windows.unwrap_or_default(),
+            device_baselines,
+        },
+    )
 }
 
 pub fn snapshot_encoder() -> impl MessageEncode<Item = Snapshot> {
@@ -457,21 +1014,77 @@ pub fn snapshot_encoder() -> impl MessageEncode<Item = Snapshot> {
         (F2, bucket_encoder(), repeated_message),
         (F3, device_encoder(), repeated_message),
         (F4, server_encoder(), repeated_message),
-        (F5, segment_table_encoder(), repeated_unsized_message)
+        (F5, segment_table_encoder(), repeated_unsized_message),
+        (F6, device_usage_encoder(), repeated_message),
+        (F7, failure_domain_encoder(), repeated_message),
+        (F8, bucket_attributes_encoder(), repeated_message)
+    ];
+    let base = protobuf_message_encoder![
+        (F1, base, required_unsized_message),
+        (F2, bucket_relayout_encoder(), repeated_message),
+        (F3, StringEncoder::new(), repeated),
+        (F4, maintenance_windows_encoder(), unsized_message),
+        (F5, device_baseline_encoder(), repeated_message)
     ];
-    let base = protobuf_message_encoder![(F1, base, required_unsized_message)];
 
     base.map_from(|x: Snapshot| {
         (
-            x.next_seqno,
-            x.buckets,
-            x.devices,
-            x.servers,
-            x.segment_tables,
+            (
+                x.next_seqno,
+                x.buckets,
+                x.devices,
+                x.servers,
+                x.segment_tables,
+                x.device_usages,
+                x.failure_domains,
+                x.bucket_attributes,
+            ),
+            x.relayouts,
+            x.decommissioned_devices,
+            // 既定値の場合には省略する(機能を有効にしていないクラスタのスナップショットを変えないため)
+            if x.maintenance_windows.is_default() {
+                None
+            } else {
+                Some(x.maintenance_windows)
+            },
+            x.device_baselines,
         )
     })
 }
 
+pub fn bucket_relayout_decoder() -> impl MessageDecode<Item = BucketRelayout> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, bucket_decoder(), required_message),
+        (F3, Uint32Decoder::new(), packed)
+    ];
+    base.try_map(|(bucket_id, previous, pending)| -> Result<_> {
+        let mut pending_segments = Vec::with_capacity(pending.len());
+        for segment_no in pending {
+            track_assert!(segment_no <= 0xFFFF, ErrorKind::InvalidInput);
+            pending_segments.push(segment_no as u16);
+        }
+        Ok(BucketRelayout {
+            bucket_id,
+            previous,
+            pending_segments,
+        })
+    })
+}
+
+pub fn bucket_relayout_encoder(
+) -> impl SizedEncode<Item = BucketRelayout> + MessageEncode<Item = BucketRelayout> {
+    let base = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, bucket_encoder(), required_message),
+        (F3, Uint32Encoder::new(), packed)
+    ];
+    base.map_from(|x: BucketRelayout| {
+        let pending = x.pending_segments.into_iter().map(u32::from).collect();
+        (x.bucket_id, x.previous, pending)
+    })
+}
+
 pub fn next_seqno_decoder() -> impl MessageDecode<Item = NextSeqNo> {
     let base = protobuf_message_decoder![
         (F1, Uint32Decoder::new()),
@@ -525,13 +1138,25 @@ pub fn segment_encoder() -> impl MessageEncode<Item = Segment> {
 }
 
 pub fn device_group_decoder() -> impl MessageDecode<Item = DeviceGroup> {
-    let base = protobuf_message_decoder![(F1, Uint32Decoder::new(), packed)];
-    base.map(|x| DeviceGroup { members: x })
+    let base = protobuf_message_decoder![
+        (F1, Uint32Decoder::new(), packed),
+        (F2, Uint32Decoder::new())
+    ];
+    base.try_map(|(members, generation)| -> Result<_> {
+        track_assert!(generation <= 0xFF, ErrorKind::InvalidInput);
+        Ok(DeviceGroup {
+            members,
+            generation: generation as u8,
+        })
+    })
 }
 
 pub fn device_group_encoder() -> impl MessageEncode<Item = DeviceGroup> {
-    let base = protobuf_message_encoder![(F1, Uint32Encoder::new(), packed)];
-    base.map_from(|x: DeviceGroup| x.members)
+    let base = protobuf_message_encoder![
+        (F1, Uint32Encoder::new(), packed),
+        (F2, Uint32Encoder::new())
+    ];
+    base.map_from(|x: DeviceGroup| (x.members, u32::from(x.generation)))
 }
 
 #[cfg(test)]
@@ -557,4 +1182,292 @@ mod tests {
         ];
         track_try_unwrap!(command_decoder().decode_from_bytes(&input));
     }
+
+    #[test]
+    fn put_failure_domain_command_works() {
+        let domain = FailureDomain {
+            server_id: "srv0".to_owned(),
+            zone: Some("zone0".to_owned()),
+            rack: None,
+        };
+        let command = Command::PutFailureDomain {
+            domain: domain.clone(),
+        };
+        let bytes = track_try_unwrap!(command_encoder().encode_into_bytes(command));
+        match track_try_unwrap!(command_decoder().decode_from_bytes(&bytes)) {
+            Command::PutFailureDomain { domain: decoded } => assert_eq!(decoded, domain),
+            c => panic!("Unexpected command: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn put_bucket_attributes_command_works() {
+        let attributes = BucketAttributes {
+            bucket_id: "bucket0".to_owned(),
+            read_only: true,
+            ..Default::default()
+        };
+        let command = Command::PutBucketAttributes {
+            attributes: attributes.clone(),
+        };
+        let bytes = track_try_unwrap!(command_encoder().encode_into_bytes(command));
+        // `oneof`の 9 番目の分岐としてエンコードされる
+        assert_eq!(bytes[0], (9 << 3) | 2);
+        match track_try_unwrap!(command_decoder().decode_from_bytes(&bytes)) {
+            Command::PutBucketAttributes {
+                attributes: decoded,
+            } => assert_eq!(decoded, attributes),
+            c => panic!("Unexpected command: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn put_maintenance_windows_command_works() {
+        let windows = MaintenanceWindows {
+            windows: vec![MaintenanceWindow {
+                schedule: "0 22 * * 1-5".to_owned(),
+                duration_minutes: 240,
+            }],
+            utc_offset_minutes: 9 * 60,
+        };
+        let command = Command::PutMaintenanceWindows {
+            windows: windows.clone(),
+        };
+        let bytes = track_try_unwrap!(command_encoder().encode_into_bytes(command));
+        assert_eq!(bytes[0], (14 << 3) | 2);
+        match track_try_unwrap!(command_decoder().decode_from_bytes(&bytes)) {
+            Command::PutMaintenanceWindows { windows: decoded } => assert_eq!(decoded, windows),
+            c => panic!("Unexpected command: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn put_device_baseline_command_works() {
+        let baseline = DeviceBaseline {
+            device_id: "dev0".to_owned(),
+            write_bytes_per_second: 200 * 1024 * 1024,
+            read_bytes_per_second: 500 * 1024 * 1024,
+            max_write_latency_micros: 12_000,
+            max_read_latency_micros: 3_000,
+            passed: false,
+        };
+        let command = Command::PutDeviceBaseline {
+            baseline: baseline.clone(),
+        };
+        let bytes = track_try_unwrap!(command_encoder().encode_into_bytes(command));
+        assert_eq!(bytes[0], (15 << 3) | 2);
+        match track_try_unwrap!(command_decoder().decode_from_bytes(&bytes)) {
+            Command::PutDeviceBaseline { baseline: decoded } => assert_eq!(decoded, baseline),
+            c => panic!("Unexpected command: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn relayout_commands_work() {
+        use libfrugalos::entity::bucket::ReplicatedBucket;
+
+        let bucket = Bucket::Replicated(ReplicatedBucket {
+            id: "bucket0".to_owned(),
+            seqno: 1,
+            device: "root".to_owned(),
+            segment_count: 4,
+            tolerable_faults: 2,
+        });
+        let command = Command::RelayoutBucket { bucket };
+        let bytes = track_try_unwrap!(command_encoder().encode_into_bytes(command));
+        assert_eq!(bytes[0], (10 << 3) | 2);
+        match track_try_unwrap!(command_decoder().decode_from_bytes(&bytes)) {
+            Command::RelayoutBucket { bucket } => assert_eq!(bucket.id(), "bucket0"),
+            c => panic!("Unexpected command: {:?}", c),
+        }
+
+        let segment = RelayoutedSegment {
+            bucket_id: "bucket0".to_owned(),
+            segment_no: 3,
+            generation: 7,
+        };
+        let command = Command::FinishSegmentRelayout {
+            segment: segment.clone(),
+        };
+        let bytes = track_try_unwrap!(command_encoder().encode_into_bytes(command));
+        assert_eq!(bytes[0], (11 << 3) | 2);
+        match track_try_unwrap!(command_decoder().decode_from_bytes(&bytes)) {
+            Command::FinishSegmentRelayout { segment: decoded } => assert_eq!(decoded, segment),
+            c => panic!("Unexpected command: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn decommission_devices_command_works() {
+        let device_ids = vec!["dev0".to_owned(), "dev1".to_owned()];
+        let command = Command::DecommissionDevices {
+            device_ids: device_ids.clone(),
+        };
+        let bytes = track_try_unwrap!(command_encoder().encode_into_bytes(command));
+        assert_eq!(bytes[0], (12 << 3) | 2);
+        match track_try_unwrap!(command_decoder().decode_from_bytes(&bytes)) {
+            Command::DecommissionDevices {
+                device_ids: decoded,
+            } => {
+                assert_eq!(decoded, device_ids)
+            }
+            c => panic!("Unexpected command: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn rebalance_bucket_command_works() {
+        let rebalance = BucketRebalance {
+            bucket_id: "bucket0".to_owned(),
+            moves: vec![
+                SegmentMove {
+                    segment: 3,
+                    from: "dev0".to_owned(),
+                    to: "dev2".to_owned(),
+                },
+                SegmentMove {
+                    segment: 0xFFFF,
+                    from: "dev1".to_owned(),
+                    to: "dev3".to_owned(),
+                },
+            ],
+        };
+        let command = Command::RebalanceBucket {
+            rebalance: rebalance.clone(),
+        };
+        let bytes = track_try_unwrap!(command_encoder().encode_into_bytes(command));
+        assert_eq!(bytes[0], (13 << 3) | 2);
+        match track_try_unwrap!(command_decoder().decode_from_bytes(&bytes)) {
+            Command::RebalanceBucket { rebalance: decoded } => assert_eq!(decoded, rebalance),
+            c => panic!("Unexpected command: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn snapshot_with_relayouts_works() {
+        use libfrugalos::entity::bucket::ReplicatedBucket;
+
+        let server = Server::new("srv0".to_owned(), ([127, 0, 0, 1], 14278).into());
+        let mut snapshot = Snapshot::initial(server);
+        snapshot.segment_tables.push(SegmentTable {
+            bucket_id: "bucket0".to_owned(),
+            segments: vec![Segment {
+                groups: vec![
+                    DeviceGroup {
+                        members: vec![3, 4],
+                        generation: 1,
+                    },
+                    DeviceGroup {
+                        members: vec![0, 1, 2],
+                        generation: 0,
+                    },
+                ],
+            }],
+        });
+        snapshot.relayouts.push(BucketRelayout {
+            bucket_id: "bucket0".to_owned(),
+            previous: Bucket::Replicated(ReplicatedBucket {
+                id: "bucket0".to_owned(),
+                seqno: 0,
+                device: "root".to_owned(),
+                segment_count: 1,
+                tolerable_faults: 2,
+            }),
+            pending_segments: vec![0],
+        });
+        snapshot.decommissioned_devices.push("dev0".to_owned());
+        let bytes = track_try_unwrap!(snapshot_encoder().encode_into_bytes(snapshot.clone()));
+        let decoded = track_try_unwrap!(snapshot_decoder().decode_from_bytes(&bytes));
+        assert_eq!(
+            decoded.segment_tables[0].segments,
+            snapshot.segment_tables[0].segments
+        );
+        assert_eq!(decoded.relayouts.len(), 1);
+        assert_eq!(decoded.relayouts[0].pending_segments, vec![0]);
+        assert_eq!(decoded.decommissioned_devices, vec!["dev0".to_owned()]);
+        assert!(decoded.maintenance_windows.is_default());
+
+        snapshot.maintenance_windows = MaintenanceWindows {
+            windows: vec![MaintenanceWindow {
+                schedule: "0 2 * * *".to_owned(),
+                duration_minutes: 60,
+            }],
+            utc_offset_minutes: -5 * 60,
+        };
+        let bytes = track_try_unwrap!(snapshot_encoder().encode_into_bytes(snapshot.clone()));
+        let decoded = track_try_unwrap!(snapshot_decoder().decode_from_bytes(&bytes));
+        assert_eq!(decoded.maintenance_windows, snapshot.maintenance_windows);
+        assert!(decoded.device_baselines.is_empty());
+
+        snapshot.device_baselines.push(DeviceBaseline {
+            device_id: "dev1".to_owned(),
+            write_bytes_per_second: 1024,
+            read_bytes_per_second: 2048,
+            max_write_latency_micros: 1_500_000,
+            max_read_latency_micros: 10,
+            passed: false,
+        });
+        let bytes = track_try_unwrap!(snapshot_encoder().encode_into_bytes(snapshot.clone()));
+        let decoded = track_try_unwrap!(snapshot_decoder().decode_from_bytes(&bytes));
+        assert_eq!(decoded.device_baselines, snapshot.device_baselines);
+    }
+
+    #[test]
+    fn bucket_attributes_with_defaults_works() {
+        for consistency in vec![
+            None,
+            Some(ReadConsistency::Consistent),
+            Some(ReadConsistency::Stale),
+            Some(ReadConsistency::Quorum),
+            Some(ReadConsistency::Subset(3)),
+        ] {
+            let attributes = BucketAttributes {
+                bucket_id: "bucket0".to_owned(),
+                read_only: false,
+                default_deadline_ms: Some(60_000),
+                default_consistency: consistency,
+                max_objects: Some(0),
+                max_bytes: Some(1 << 40),
+                erasure_coding: None,
+                mds_witnesses: 1,
+            };
+            let bytes = track_try_unwrap!(
+                bucket_attributes_encoder().encode_into_bytes(attributes.clone())
+            );
+            let decoded = track_try_unwrap!(bucket_attributes_decoder().decode_from_bytes(&bytes));
+            assert_eq!(decoded, attributes);
+        }
+    }
+
+    #[test]
+    fn bucket_attributes_with_erasure_coding_works() {
+        for &backend in ErasureCodingBackend::all() {
+            let attributes = BucketAttributes {
+                bucket_id: "bucket0".to_owned(),
+                erasure_coding: Some(ErasureCoding {
+                    backend,
+                    checksum: ErasureCodingChecksum::None,
+                }),
+                ..Default::default()
+            };
+            let bytes = track_try_unwrap!(
+                bucket_attributes_encoder().encode_into_bytes(attributes.clone())
+            );
+            let decoded = track_try_unwrap!(bucket_attributes_decoder().decode_from_bytes(&bytes));
+            assert_eq!(decoded, attributes);
+        }
+
+        let attributes = BucketAttributes {
+            bucket_id: "bucket0".to_owned(),
+            erasure_coding: Some(ErasureCoding {
+                backend: ErasureCodingBackend::JerasureRsCauchy,
+                checksum: ErasureCodingChecksum::Md5,
+            }),
+            ..Default::default()
+        };
+        let bytes =
+            track_try_unwrap!(bucket_attributes_encoder().encode_into_bytes(attributes.clone()));
+        let decoded = track_try_unwrap!(bucket_attributes_decoder().decode_from_bytes(&bytes));
+        assert_eq!(decoded, attributes);
+    }
 }
@@ -0,0 +1,568 @@
+//! セグメントの配置の偏りを調べ、デバイス間で均すための移動計画を立てるモジュール。
+//!
+//! 各バケツについて、ルートデバイス配下の物理デバイスの重みに比例した理想的な割当数と、
+//! 現在のセグメントテーブルでの割当数を比較し、偏りを小さくするようなセグメントの移動を列挙する。
+//! `RebalanceOptions::include_unused_devices`が有効な場合には、どのバケツからも参照されていない
+//! 物理デバイス(e.g., 後から追加されたサーバのデバイス)も移動先の候補に含める。
+//! 移動によって、同じセグメントのメンバが一つの障害ドメイン(`topology`モジュールを参照)に偏ることはない。
+//!
+//! 計画は報告されるだけだが、一つのバケツ分の移動(`BucketRebalance`)をコマンドとして発行すると、
+//! 対象のセグメントは移動後のメンバ群へと再配置される(`relayout`モジュールを参照)。
+//! 移動先のデバイスがバケツのルートデバイス配下にあるとは限らないので、移動後のセグメントテーブルは
+//! ルートデバイスから構築し直したものとは一致しない
+//! (https://github.com/frugalos/frugalos/issues/208 のため、デバイスの子の一覧は更新できない)。
+use libfrugalos::entity::bucket::{Bucket, BucketId};
+use libfrugalos::entity::device::{Device, DeviceId};
+use libfrugalos::entity::server::ServerId;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use machine::SegmentTable;
+use topology::{self, DomainKey, FailureDomain};
+use {ErrorKind, Result};
+
+/// 移動計画の作成方法の指定。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RebalanceOptions {
+    /// 一つのバケツで移動対象とする割当の最大割合(`0.0`から`1.0`の範囲)。
+    ///
+    /// 一度に大量の断片が移動することを防ぐために使用する。
+    pub max_move_ratio: f64,
+
+    /// どのバケツからも参照されていない物理デバイスを、移動先の候補に含めるかどうか。
+    pub include_unused_devices: bool,
+}
+impl Default for RebalanceOptions {
+    fn default() -> Self {
+        RebalanceOptions {
+            max_move_ratio: 0.1,
+            include_unused_devices: true,
+        }
+    }
+}
+
+/// セグメントの移動計画。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RebalancePlan {
+    /// バケツ毎の移動計画。
+    pub buckets: Vec<BucketRebalancePlan>,
+
+    /// どのバケツからも参照されていない物理デバイスの一覧。
+    pub unused_devices: Vec<DeviceId>,
+}
+
+/// バケツ単位の移動計画。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BucketRebalancePlan {
+    /// バケツの ID。
+    pub bucket_id: BucketId,
+
+    /// バケツ全体の割当数(セグメント数とメンバ数の積)。
+    pub slots: usize,
+
+    /// 各デバイスの割当状況。
+    pub devices: Vec<DeviceLoad>,
+
+    /// 移動するセグメントの一覧。
+    pub moves: Vec<SegmentMove>,
+}
+
+/// デバイスの割当状況。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceLoad {
+    /// デバイスの ID。
+    pub device_id: DeviceId,
+
+    /// デバイスの重み。
+    pub weight: u64,
+
+    /// 現在の割当数。
+    pub current: usize,
+
+    /// 重みに比例して割り当てた場合の割当数。
+    pub ideal: f64,
+
+    /// 計画通りに移動した後の割当数。
+    pub planned: usize,
+}
+
+/// セグメントの一つのメンバの移動。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentMove {
+    /// セグメント番号。
+    pub segment: u16,
+
+    /// 移動元のデバイスの ID。
+    pub from: DeviceId,
+
+    /// 移動先のデバイスの ID。
+    pub to: DeviceId,
+}
+
+/// 一つのバケツについて、実際に行うセグメントの移動。
+///
+/// 構成管理用の Raft クラスタでコマンドとして複製され、適用時に`moved_members`で検証される。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketRebalance {
+    /// バケツの ID。
+    pub bucket_id: BucketId,
+
+    /// 移動するセグメントのメンバの一覧。
+    pub moves: Vec<SegmentMove>,
+}
+
+/// 全てのバケツの移動計画を作成する。
+///
+/// `excluded`に含まれるデバイス(e.g., 退役中のデバイス)は、移動先の候補にならない。
+/// メタデータ用のバケツと再配置中のバケツは対象外となる。
+pub(crate) fn plan(
+    buckets: &BTreeMap<BucketId, Bucket>,
+    devices: &BTreeMap<DeviceId, Device>,
+    segment_tables: &BTreeMap<BucketId, SegmentTable>,
+    failure_domains: &BTreeMap<ServerId, FailureDomain>,
+    excluded: &BTreeSet<DeviceId>,
+    options: &RebalanceOptions,
+) -> RebalancePlan {
+    let mut used = BTreeSet::new();
+    for b in buckets.values() {
+        collect_leaves(devices, b.device(), &mut used);
+    }
+    let unused_devices = devices
+        .values()
+        .filter(|d| !d.is_virtual() && !used.contains(d.id()) && !excluded.contains(d.id()))
+        .map(|d| d.id().clone())
+        .collect::<Vec<_>>();
+    let domains = devices
+        .values()
+        .filter_map(|d| Some((d.id().clone(), topology::domain_key(d, failure_domains)?)))
+        .collect::<BTreeMap<_, _>>();
+
+    let seqno_to_id = devices
+        .values()
+        .map(|d| (d.seqno(), d.id().clone()))
+        .collect::<HashMap<_, _>>();
+    let buckets = buckets
+        .values()
+        .filter_map(|b| {
+            if let Bucket::Metadata(_) = *b {
+                return None;
+            }
+            let table = segment_tables.get(b.id())?;
+            if table.segments.iter().any(|s| s.groups.len() != 1) {
+                return None;
+            }
+            let mut candidates = BTreeSet::new();
+            collect_leaves(devices, b.device(), &mut candidates);
+            if options.include_unused_devices {
+                candidates.extend(unused_devices.iter().cloned());
+            }
+            candidates.retain(|id| !excluded.contains(id));
+            let segments = table
+                .segments
+                .iter()
+                .map(|s| {
+                    s.groups[0]
+                        .members
+                        .iter()
+                        .filter_map(|seqno| seqno_to_id.get(seqno).cloned())
+                        .collect()
+                })
+                .collect::<Vec<Vec<_>>>();
+            let weights = candidates
+                .into_iter()
+                .map(|id| {
+                    let weight = device_weight(&devices[&id]);
+                    (id, weight)
+                })
+                .collect();
+            Some(plan_bucket(
+                b.id().clone(),
+                &segments,
+                weights,
+                &domains,
+                options.max_move_ratio,
+            ))
+        })
+        .collect();
+    RebalancePlan {
+        buckets,
+        unused_devices,
+    }
+}
+
+/// 一つのバケツの移動計画を作成する。
+///
+/// 割当数が理想値を最も上回っているデバイスから、最も下回っているデバイスへと、
+/// 移動によって両者の差が縮まる限り一つずつメンバを移動させる
+/// (以下の制約によって移動できない場合には、次に上回っているデバイスや、次に下回っているデバイスを試す)。
+/// 同じセグメントの複数のメンバが、一つのデバイスに集まるような移動は行わない。
+/// また、移動先の障害ドメインに属するメンバ数が、移動元の障害ドメインに属していたメンバ数を超えるような移動も行わない
+/// (i.e., 一つの障害ドメインの故障で失われるメンバ数が、移動によって増えることはない)。
+fn plan_bucket(
+    bucket_id: BucketId,
+    segments: &[Vec<DeviceId>],
+    weights: BTreeMap<DeviceId, u64>,
+    domains: &BTreeMap<DeviceId, DomainKey>,
+    max_move_ratio: f64,
+) -> BucketRebalancePlan {
+    let mut segments = segments.to_vec();
+    let slots = segments.iter().map(Vec::len).sum::<usize>();
+    let total_weight = weights.values().sum::<u64>();
+
+    let mut devices = weights
+        .into_iter()
+        .map(|(device_id, weight)| {
+            let current = segments
+                .iter()
+                .flat_map(|s| s.iter())
+                .filter(|d| **d == device_id)
+                .count();
+            let ideal = if total_weight == 0 {
+                0.0
+            } else {
+                slots as f64 * weight as f64 / total_weight as f64
+            };
+            DeviceLoad {
+                device_id,
+                weight,
+                current,
+                ideal,
+                planned: current,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let max_moves = (slots as f64 * max_move_ratio.clamp(0.0, 1.0)).floor() as usize;
+    let mut moves = Vec::new();
+    while moves.len() < max_moves {
+        let surplus = |d: &DeviceLoad| d.planned as f64 - d.ideal;
+        let mut order = (0..devices.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| {
+            surplus(&devices[b])
+                .partial_cmp(&surplus(&devices[a]))
+                .expect("Never fails")
+        });
+        let selected = order.iter().find_map(|&from| {
+            order.iter().rev().find_map(|&to| {
+                if surplus(&devices[from]) - surplus(&devices[to]) <= 1.0 {
+                    return None;
+                }
+                let (from_id, to_id) = (&devices[from].device_id, &devices[to].device_id);
+                let segment = segments.iter().position(|s| {
+                    s.contains(from_id)
+                        && !s.contains(to_id)
+                        && keeps_domain_spread(s, from_id, to_id, domains)
+                })?;
+                Some((from, to, segment))
+            })
+        });
+        let (from, to, segment) = match selected {
+            None => break,
+            Some(x) => x,
+        };
+
+        let member = segments[segment]
+            .iter_mut()
+            .find(|d| **d == devices[from].device_id)
+            .expect("Never fails");
+        *member = devices[to].device_id.clone();
+        devices[from].planned -= 1;
+        devices[to].planned += 1;
+        moves.push(SegmentMove {
+            segment: segment as u16,
+            from: devices[from].device_id.clone(),
+            to: devices[to].device_id.clone(),
+        });
+    }
+    BucketRebalancePlan {
+        bucket_id,
+        slots,
+        devices,
+        moves,
+    }
+}
+
+/// セグメントのメンバ`from`を`to`に移動しても、障害ドメイン毎のメンバ数の最大値が増えないかどうかを返す。
+///
+/// 障害ドメインの分からないデバイス同士は、別々のドメインに属するものとして扱う。
+fn keeps_domain_spread(
+    members: &[DeviceId],
+    from: &DeviceId,
+    to: &DeviceId,
+    domains: &BTreeMap<DeviceId, DomainKey>,
+) -> bool {
+    let to_domain = match domains.get(to) {
+        None => return true,
+        Some(d) => d,
+    };
+    if domains.get(from) == Some(to_domain) {
+        return true;
+    }
+    let count = |domain: Option<&DomainKey>| {
+        members
+            .iter()
+            .filter(|m| domain.is_some() && domains.get(*m) == domain)
+            .count()
+    };
+    count(Some(to_domain)) < count(domains.get(from)).max(1)
+}
+
+/// `rebalance`の移動を適用した後の、対象セグメントのメンバ(デバイスのシーケンス番号)の一覧を返す。
+///
+/// コマンドの適用時にも呼ばれるので、結果は複製された状態のみから決まる必要がある。
+/// 移動先は`excluded`に含まれない物理デバイスである必要があり、移動元は対象セグメントの現在のメンバである必要がある。
+/// 再配置中のセグメントは移動できない。
+pub(crate) fn moved_members(
+    rebalance: &BucketRebalance,
+    table: &SegmentTable,
+    devices: &BTreeMap<DeviceId, Device>,
+    excluded: &BTreeSet<DeviceId>,
+) -> Result<BTreeMap<u16, Vec<u32>>> {
+    track_assert!(
+        !rebalance.moves.is_empty(),
+        ErrorKind::InvalidInput,
+        "No moves are specified"
+    );
+    let seqno = |id: &DeviceId| -> Result<u32> {
+        let device = track_assert_some!(
+            devices.get(id),
+            ErrorKind::InvalidInput,
+            "No such device: {:?}",
+            id
+        );
+        Ok(device.seqno())
+    };
+
+    let mut moved = BTreeMap::new();
+    for m in &rebalance.moves {
+        let segment = track_assert_some!(
+            table.segments.get(m.segment as usize),
+            ErrorKind::InvalidInput,
+            "No such segment: {}",
+            m.segment
+        );
+        track_assert_eq!(
+            segment.groups.len(),
+            1,
+            ErrorKind::InvalidInput,
+            "The segment is being relayouted: {}",
+            m.segment
+        );
+        let to = track_assert_some!(
+            devices.get(&m.to),
+            ErrorKind::InvalidInput,
+            "No such device: {:?}",
+            m.to
+        );
+        track_assert!(
+            !to.is_virtual() && !excluded.contains(&m.to),
+            ErrorKind::InvalidInput,
+            "The device cannot be a destination: {:?}",
+            m.to
+        );
+
+        let members = moved
+            .entry(m.segment)
+            .or_insert_with(|| segment.groups[0].members.clone());
+        let (from, to) = (track!(seqno(&m.from))?, to.seqno());
+        track_assert!(
+            !members.contains(&to),
+            ErrorKind::InvalidInput,
+            "The device is already a member of the segment: {}",
+            dump!(m.segment, m.to)
+        );
+        let member = track_assert_some!(
+            members.iter_mut().find(|d| **d == from),
+            ErrorKind::InvalidInput,
+            "The device is not a member of the segment: {}",
+            dump!(m.segment, m.from)
+        );
+        *member = to;
+    }
+    Ok(moved)
+}
+
+fn collect_leaves(
+    devices: &BTreeMap<DeviceId, Device>,
+    id: &DeviceId,
+    leaves: &mut BTreeSet<DeviceId>,
+) {
+    match devices.get(id) {
+        Some(Device::Virtual(d)) => {
+            for c in &d.children {
+                collect_leaves(devices, c, leaves);
+            }
+        }
+        Some(_) => {
+            leaves.insert(id.clone());
+        }
+        None => {}
+    }
+}
+
+fn device_weight(device: &Device) -> u64 {
+    match *device {
+        Device::Virtual(_) => 0,
+        Device::Memory(ref d) => d.weight(),
+        Device::File(ref d) => d.weight(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weights(entries: &[(&str, u64)]) -> BTreeMap<DeviceId, u64> {
+        entries.iter().map(|&(id, w)| (id.to_owned(), w)).collect()
+    }
+
+    fn segments(entries: &[&[&str]]) -> Vec<Vec<DeviceId>> {
+        entries
+            .iter()
+            .map(|s| s.iter().map(|d| (*d).to_owned()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn plan_bucket_moves_segments_to_new_devices() {
+        let segments = segments(&[&["a", "b"], &["a", "b"], &["a", "b"], &["a", "b"]]);
+        let weights = weights(&[("a", 1), ("b", 1), ("c", 1), ("d", 1)]);
+
+        let plan = plan_bucket(
+            "foo".to_owned(),
+            &segments,
+            weights.clone(),
+            &BTreeMap::new(),
+            1.0,
+        );
+        assert_eq!(plan.slots, 8);
+        assert_eq!(plan.moves.len(), 4);
+        for d in &plan.devices {
+            assert_eq!(d.planned, 2);
+            assert_eq!(d.ideal, 2.0);
+        }
+        let mut moved = segments.clone();
+        for m in &plan.moves {
+            let member = moved[m.segment as usize]
+                .iter_mut()
+                .find(|d| **d == m.from)
+                .unwrap();
+            *member = m.to.clone();
+        }
+        for s in &moved {
+            assert_ne!(s[0], s[1]);
+        }
+
+        // 移動数の上限
+        let plan = plan_bucket("foo".to_owned(), &segments, weights, &BTreeMap::new(), 0.25);
+        assert_eq!(plan.moves.len(), 2);
+    }
+
+    #[test]
+    fn plan_bucket_keeps_balanced_placement() {
+        let segments = segments(&[&["a", "b"], &["b", "c"], &["c", "a"]]);
+        let plan = plan_bucket(
+            "foo".to_owned(),
+            &segments,
+            weights(&[("a", 1), ("b", 1), ("c", 1)]),
+            &BTreeMap::new(),
+            1.0,
+        );
+        assert!(plan.moves.is_empty());
+        assert!(plan.devices.iter().all(|d| d.current == 2));
+    }
+
+    #[test]
+    fn plan_bucket_keeps_failure_domains_spread() {
+        let rack = |name: &str| DomainKey {
+            zone: String::new(),
+            rack: name.to_owned(),
+        };
+        // "c" と "d" は "a" と同じラックにあるので、"a" と "b" を持つセグメントの移動先にはならない
+        let domains = vec![
+            ("a".to_owned(), rack("r0")),
+            ("b".to_owned(), rack("r1")),
+            ("c".to_owned(), rack("r1")),
+            ("d".to_owned(), rack("r1")),
+        ]
+        .into_iter()
+        .collect();
+        let segments = segments(&[&["a", "b"], &["a", "b"]]);
+        let plan = plan_bucket(
+            "foo".to_owned(),
+            &segments,
+            weights(&[("a", 1), ("b", 1), ("c", 1), ("d", 1)]),
+            &domains,
+            1.0,
+        );
+        assert!(!plan.moves.is_empty());
+        for m in &plan.moves {
+            assert_eq!(m.from, "b");
+        }
+    }
+
+    #[test]
+    fn moved_members_works() {
+        use libfrugalos::entity::device::SegmentAllocationPolicy;
+        use machine::{DeviceGroup, Segment};
+        use test_util::build_device_tree;
+
+        let (devices, root) = build_device_tree(&[3], SegmentAllocationPolicy::ScatterIfPossible);
+        let leaves = devices
+            .values()
+            .filter(|d| !d.is_virtual())
+            .map(|d| (d.id().clone(), d.seqno()))
+            .collect::<Vec<_>>();
+        let group = |members: Vec<u32>| DeviceGroup {
+            members,
+            generation: 0,
+        };
+        let table = SegmentTable {
+            bucket_id: "foo".to_owned(),
+            segments: vec![
+                Segment {
+                    groups: vec![group(vec![leaves[0].1, leaves[1].1])],
+                },
+                Segment {
+                    groups: vec![group(vec![leaves[1].1]), group(vec![leaves[0].1])],
+                },
+            ],
+        };
+        let rebalance = |segment, from: usize, to: &DeviceId| BucketRebalance {
+            bucket_id: "foo".to_owned(),
+            moves: vec![SegmentMove {
+                segment,
+                from: leaves[from].0.clone(),
+                to: to.clone(),
+            }],
+        };
+        let excluded = BTreeSet::new();
+
+        let moved = track_try_unwrap!(moved_members(
+            &rebalance(0, 0, &leaves[2].0),
+            &table,
+            &devices,
+            &excluded
+        ));
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[&0], vec![leaves[2].1, leaves[1].1]);
+
+        // 既存のメンバ、仮想デバイス、除外されたデバイスへは移動できない
+        assert!(
+            moved_members(&rebalance(0, 0, &leaves[1].0), &table, &devices, &excluded).is_err()
+        );
+        assert!(moved_members(&rebalance(0, 0, &root), &table, &devices, &excluded).is_err());
+        let excluded = Some(leaves[2].0.clone()).into_iter().collect();
+        assert!(
+            moved_members(&rebalance(0, 0, &leaves[2].0), &table, &devices, &excluded).is_err()
+        );
+
+        // 再配置中のセグメントや、メンバでないデバイスからは移動できない
+        let excluded = BTreeSet::new();
+        assert!(
+            moved_members(&rebalance(1, 1, &leaves[2].0), &table, &devices, &excluded).is_err()
+        );
+        assert!(
+            moved_members(&rebalance(0, 2, &leaves[2].0), &table, &devices, &excluded).is_err()
+        );
+    }
+}
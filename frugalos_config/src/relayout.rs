@@ -0,0 +1,245 @@
+//! 既存のバケツの耐障害性の設定(許容故障数やデータ断片数)を変更するための、セグメントの再配置。
+//!
+//! バケツの ID やバージョン、メタデータを維持したまま設定を変更するために、
+//! 各セグメントには新しい設定に従って構築されたデバイスグループ(世代が一つ進んだもの)が追加され、
+//! 変更前のデバイスグループと並んで保持される(`Segment::groups`の先頭が新しい配置で、二番目が変更前の配置)。
+//!
+//! 再配置中のセグメントでは、変更前のメンバ群が MDS を担当し続ける一方で、新しく保存されるオブジェクトの内容は
+//! 新しい配置に書き込まれ、既存のオブジェクトの内容は MDS のリーダが新しい配置にコピーしていく。
+//! コピーが完了したセグメントは、MDS のメンバ群を新しい配置に切り替えた上で`FinishSegmentRelayout`コマンドを発行し、
+//! 全てのセグメントが切り替わった時点で、バケツの再配置は完了となる。
+//!
+//! NOTE: 変更できるのは耐障害性の設定のみで、バケツの ID の変更(リネーム)はサポートしていない。
+//! バケツの ID は MDS のノード ID や各 API の対象の指定に使われているので、
+//! ID を変えたい場合には、従来通り新しいバケツを作成してオブジェクトをコピーする必要がある。
+use libfrugalos::entity::bucket::{Bucket, BucketId};
+
+use {ErrorKind, Result};
+
+/// デバイスグループの世代の数。
+///
+/// ノード ID のメンバ番号の上位 3 ビットに世代を埋め込むので、世代は 0 から 7 までを循環する。
+pub const GENERATIONS: u8 = 8;
+
+/// 再配置可能なバケツの、デバイスグループの大きさの上限。
+///
+/// ノード ID のメンバ番号の下位 5 ビットに収まる必要がある。
+pub const MAX_DEVICE_GROUP_SIZE: usize = 32;
+
+/// `generation`の次の世代を返す。
+pub fn next_generation(generation: u8) -> u8 {
+    (generation + 1) % GENERATIONS
+}
+
+/// 再配置後のバケツの設定。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayoutParameters {
+    /// 変更後の許容故障数。
+    ///
+    /// `None`の場合には変更されない。
+    pub tolerable_faults: Option<u32>,
+
+    /// 変更後のデータ断片数(`dispersed`のバケツのみ)。
+    ///
+    /// `None`の場合には変更されない。
+    pub data_fragment_count: Option<u32>,
+}
+
+/// 実行中のバケツの再配置。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BucketRelayout {
+    /// 再配置中のバケツの ID。
+    pub bucket_id: BucketId,
+
+    /// 再配置前のバケツの設定。
+    pub previous: Bucket,
+
+    /// まだ新しい配置に切り替わっていないセグメントの番号の一覧(昇順)。
+    pub pending_segments: Vec<u16>,
+}
+impl BucketRelayout {
+    /// 全てのセグメントが新しい配置に切り替わったかどうかを返す。
+    pub fn is_finished(&self) -> bool {
+        self.pending_segments.is_empty()
+    }
+}
+
+/// 新しい配置への切り替えが完了したセグメント。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayoutedSegment {
+    /// バケツの ID。
+    pub bucket_id: BucketId,
+
+    /// セグメントの番号。
+    pub segment_no: u16,
+
+    /// 切り替え先のデバイスグループの世代。
+    pub generation: u8,
+}
+
+/// 現在のバケツの設定に`params`を適用して、再配置後のバケツの設定を作成する。
+///
+/// ID やシーケンス番号、ルートデバイス、セグメント数は引き継がれる。
+pub fn relayout_bucket(current: &Bucket, params: &RelayoutParameters) -> Result<Bucket> {
+    let mut bucket = current.clone();
+    match bucket {
+        Bucket::Metadata(_) => {
+            track_panic!(
+                ErrorKind::InvalidInput,
+                "Metadata buckets cannot be relayouted (they have no contents to be moved)"
+            );
+        }
+        Bucket::Replicated(ref mut b) => {
+            track_assert!(
+                params.data_fragment_count.is_none(),
+                ErrorKind::InvalidInput,
+                "Replicated buckets have no data fragments"
+            );
+            b.tolerable_faults = params.tolerable_faults.unwrap_or(b.tolerable_faults);
+        }
+        Bucket::Dispersed(ref mut b) => {
+            b.tolerable_faults = params.tolerable_faults.unwrap_or(b.tolerable_faults);
+            b.data_fragment_count = params.data_fragment_count.unwrap_or(b.data_fragment_count);
+            track_assert_ne!(b.data_fragment_count, 0, ErrorKind::InvalidInput);
+        }
+    }
+    track!(validate_relayout(current, &bucket))?;
+    Ok(bucket)
+}
+
+/// `current`から`next`への再配置が可能かどうかを検証する。
+///
+/// コマンドの適用時にも呼ばれるので、結果は二つの設定のみから決まる必要がある。
+pub fn validate_relayout(current: &Bucket, next: &Bucket) -> Result<()> {
+    let (kind, faults, fragments) = layout(next);
+    let (current_kind, current_faults, current_fragments) = layout(current);
+    track_assert_ne!(
+        kind,
+        0,
+        ErrorKind::InvalidInput,
+        "Metadata buckets cannot be relayouted"
+    );
+    track_assert_eq!(
+        kind,
+        current_kind,
+        ErrorKind::InvalidInput,
+        "The kind of a bucket cannot be changed"
+    );
+    track_assert!(
+        (faults, fragments) != (current_faults, current_fragments),
+        ErrorKind::InvalidInput,
+        "No parameters to be changed"
+    );
+    track_assert_eq!(next.id(), current.id(), ErrorKind::InvalidInput);
+    track_assert_eq!(next.seqno(), current.seqno(), ErrorKind::InvalidInput);
+    track_assert_eq!(next.device(), current.device(), ErrorKind::InvalidInput);
+    track_assert_eq!(
+        next.segment_count(),
+        current.segment_count(),
+        ErrorKind::InvalidInput
+    );
+    for bucket in &[current, next] {
+        track!(validate_group_size(bucket))?;
+    }
+    Ok(())
+}
+
+/// `bucket`のデバイスグループの大きさが、再配置可能な範囲に収まっているかどうかを検証する。
+pub fn validate_group_size(bucket: &Bucket) -> Result<()> {
+    // NOTE: 変更前のグループが大きいと、世代 0 のメンバ番号が他の世代のものと衝突し得る
+    track_assert!(
+        bucket.device_group_size() as usize <= MAX_DEVICE_GROUP_SIZE,
+        ErrorKind::InvalidInput,
+        "Too many members: {}",
+        bucket.device_group_size()
+    );
+    Ok(())
+}
+
+// バケツの種類(0: metadata, 1: replicated, 2: dispersed)と、許容故障数、データ断片数の組を返す。
+fn layout(bucket: &Bucket) -> (u8, u32, u32) {
+    match *bucket {
+        Bucket::Metadata(ref b) => (0, b.tolerable_faults, 0),
+        Bucket::Replicated(ref b) => (1, b.tolerable_faults, 0),
+        Bucket::Dispersed(ref b) => (2, b.tolerable_faults, b.data_fragment_count),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libfrugalos::entity::bucket::{DispersedBucket, MetadataBucket, ReplicatedBucket};
+
+    use super::*;
+
+    fn dispersed() -> Bucket {
+        Bucket::Dispersed(DispersedBucket {
+            id: "foo".to_owned(),
+            seqno: 3,
+            device: "root".to_owned(),
+            segment_count: 10,
+            tolerable_faults: 2,
+            data_fragment_count: 4,
+        })
+    }
+
+    fn params(faults: Option<u32>, fragments: Option<u32>) -> RelayoutParameters {
+        RelayoutParameters {
+            tolerable_faults: faults,
+            data_fragment_count: fragments,
+        }
+    }
+
+    #[test]
+    fn relayout_bucket_works() -> Result<()> {
+        let bucket = track!(relayout_bucket(&dispersed(), &params(Some(4), Some(8))))?;
+        match bucket {
+            Bucket::Dispersed(ref b) => {
+                assert_eq!(b.id, "foo");
+                assert_eq!(b.seqno, 3);
+                assert_eq!(b.device, "root");
+                assert_eq!(b.segment_count, 10);
+                assert_eq!(b.tolerable_faults, 4);
+                assert_eq!(b.data_fragment_count, 8);
+            }
+            _ => panic!(),
+        }
+
+        // 指定されなかった値は引き継がれる
+        let bucket = track!(relayout_bucket(&dispersed(), &params(Some(1), None)))?;
+        assert_eq!(bucket.device_group_size(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn relayout_bucket_rejects_invalid_parameters() {
+        assert!(relayout_bucket(&dispersed(), &params(None, None)).is_err());
+        assert!(relayout_bucket(&dispersed(), &params(Some(2), Some(4))).is_err());
+        assert!(relayout_bucket(&dispersed(), &params(None, Some(0))).is_err());
+        assert!(relayout_bucket(&dispersed(), &params(Some(8), Some(30))).is_err());
+
+        let replicated = Bucket::Replicated(ReplicatedBucket {
+            id: "foo".to_owned(),
+            seqno: 0,
+            device: "root".to_owned(),
+            segment_count: 10,
+            tolerable_faults: 2,
+        });
+        assert!(relayout_bucket(&replicated, &params(Some(1), None)).is_ok());
+        assert!(relayout_bucket(&replicated, &params(None, Some(4))).is_err());
+
+        let metadata = Bucket::Metadata(MetadataBucket {
+            id: "foo".to_owned(),
+            seqno: 0,
+            device: "root".to_owned(),
+            segment_count: 10,
+            tolerable_faults: 2,
+        });
+        assert!(relayout_bucket(&metadata, &params(Some(1), None)).is_err());
+    }
+
+    #[test]
+    fn next_generation_works() {
+        assert_eq!(next_generation(0), 1);
+        assert_eq!(next_generation(GENERATIONS - 1), 0);
+    }
+}
@@ -1,12 +1,28 @@
 use fibers_rpc::server::{HandleCall, Reply, ServerBuilder as RpcServerBuilder};
+use frugalos_core::rpc_auth;
 use futures::Future;
 use libfrugalos::entity::bucket::{Bucket, BucketId};
 use libfrugalos::entity::device::{Device, DeviceId};
 use libfrugalos::entity::server::{Server, ServerId};
 use libfrugalos::schema::config as spec;
 
+use attribute::BucketAttributes;
+use baseline::DeviceBaseline;
 use error::to_rpc_error;
+use maintenance::MaintenanceWindows;
+use rebalance::RebalanceOptions;
+use relayout::{RelayoutParameters, RelayoutedSegment};
+use schema::{
+    DecommissionDevicesRpc, ExportBackupRpc, FinishSegmentRelayoutRpc, GetMaintenanceWindowsRpc,
+    ListBucketAttributesRpc, ListBucketRelayoutsRpc, ListDecommissionsRpc, ListDeviceBaselinesRpc,
+    ListDeviceUsagesRpc, ListFailureDomainsRpc, PlanChangeRpc, PlanRebalanceRpc,
+    PutBucketAttributesRpc, PutDeviceBaselineRpc, PutDeviceUsagesRpc, PutFailureDomainRpc,
+    PutMaintenanceWindowsRpc, RelayoutBucketRpc,
+};
 use service::ServiceHandle;
+use simulation::ProposedChange;
+use topology::FailureDomain;
+use usage::DeviceUsage;
 
 /// RPC サーバ。
 #[derive(Debug, Clone)]
@@ -15,22 +31,42 @@ pub struct RpcServer {
 }
 impl RpcServer {
     /// RPCハンドラ群を登録する。
+    ///
+    /// 構成を変更するRPCとバックアップの取得には、クラスタトークンによる認証が必要となる。
     pub fn register(service: ServiceHandle, builder: &mut RpcServerBuilder) {
         let this = RpcServer { service };
 
         builder.add_call_handler::<spec::GetLeaderRpc, _>(this.clone());
         builder.add_call_handler::<spec::ListServersRpc, _>(this.clone());
         builder.add_call_handler::<spec::GetServerRpc, _>(this.clone());
-        builder.add_call_handler::<spec::PutServerRpc, _>(this.clone());
-        builder.add_call_handler::<spec::DeleteServerRpc, _>(this.clone());
+        rpc_auth::add_call_handler::<spec::PutServerRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<spec::DeleteServerRpc, _>(builder, this.clone());
         builder.add_call_handler::<spec::ListDevicesRpc, _>(this.clone());
         builder.add_call_handler::<spec::GetDeviceRpc, _>(this.clone());
-        builder.add_call_handler::<spec::PutDeviceRpc, _>(this.clone());
-        builder.add_call_handler::<spec::DeleteDeviceRpc, _>(this.clone());
+        rpc_auth::add_call_handler::<spec::PutDeviceRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<spec::DeleteDeviceRpc, _>(builder, this.clone());
         builder.add_call_handler::<spec::ListBucketsRpc, _>(this.clone());
         builder.add_call_handler::<spec::GetBucketRpc, _>(this.clone());
-        builder.add_call_handler::<spec::PutBucketRpc, _>(this.clone());
-        builder.add_call_handler::<spec::DeleteBucketRpc, _>(this.clone());
+        rpc_auth::add_call_handler::<spec::PutBucketRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<spec::DeleteBucketRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<ExportBackupRpc, _>(builder, this.clone());
+        builder.add_call_handler::<PlanRebalanceRpc, _>(this.clone());
+        builder.add_call_handler::<PlanChangeRpc, _>(this.clone());
+        rpc_auth::add_call_handler::<PutDeviceUsagesRpc, _>(builder, this.clone());
+        builder.add_call_handler::<ListDeviceUsagesRpc, _>(this.clone());
+        rpc_auth::add_call_handler::<PutFailureDomainRpc, _>(builder, this.clone());
+        builder.add_call_handler::<ListFailureDomainsRpc, _>(this.clone());
+        rpc_auth::add_call_handler::<PutBucketAttributesRpc, _>(builder, this.clone());
+        builder.add_call_handler::<ListBucketAttributesRpc, _>(this.clone());
+        rpc_auth::add_call_handler::<RelayoutBucketRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<FinishSegmentRelayoutRpc, _>(builder, this.clone());
+        builder.add_call_handler::<ListBucketRelayoutsRpc, _>(this.clone());
+        rpc_auth::add_call_handler::<DecommissionDevicesRpc, _>(builder, this.clone());
+        builder.add_call_handler::<ListDecommissionsRpc, _>(this.clone());
+        rpc_auth::add_call_handler::<PutMaintenanceWindowsRpc, _>(builder, this.clone());
+        builder.add_call_handler::<GetMaintenanceWindowsRpc, _>(this.clone());
+        rpc_auth::add_call_handler::<PutDeviceBaselineRpc, _>(builder, this.clone());
+        builder.add_call_handler::<ListDeviceBaselinesRpc, _>(this.clone());
     }
 }
 impl HandleCall<spec::GetLeaderRpc> for RpcServer {
@@ -143,3 +179,187 @@ impl HandleCall<spec::DeleteBucketRpc> for RpcServer {
         )
     }
 }
+impl HandleCall<ExportBackupRpc> for RpcServer {
+    fn handle_call(&self, _: ()) -> Reply<ExportBackupRpc> {
+        Reply::future(
+            self.service
+                .export_backup()
+                .and_then(|backup| track!(backup.to_bytes()))
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<PlanRebalanceRpc> for RpcServer {
+    fn handle_call(&self, options: RebalanceOptions) -> Reply<PlanRebalanceRpc> {
+        Reply::future(
+            self.service
+                .plan_rebalance(options)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<PlanChangeRpc> for RpcServer {
+    fn handle_call(&self, change: ProposedChange) -> Reply<PlanChangeRpc> {
+        Reply::future(
+            self.service
+                .plan_change(change)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<PutDeviceUsagesRpc> for RpcServer {
+    fn handle_call(&self, usages: Vec<DeviceUsage>) -> Reply<PutDeviceUsagesRpc> {
+        Reply::future(
+            self.service
+                .put_device_usages(usages)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<ListDeviceUsagesRpc> for RpcServer {
+    fn handle_call(&self, _: ()) -> Reply<ListDeviceUsagesRpc> {
+        Reply::future(
+            self.service
+                .list_device_usages()
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<PutFailureDomainRpc> for RpcServer {
+    fn handle_call(&self, domain: FailureDomain) -> Reply<PutFailureDomainRpc> {
+        Reply::future(
+            self.service
+                .put_failure_domain(domain)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<ListFailureDomainsRpc> for RpcServer {
+    fn handle_call(&self, _: ()) -> Reply<ListFailureDomainsRpc> {
+        Reply::future(
+            self.service
+                .list_failure_domains()
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<PutBucketAttributesRpc> for RpcServer {
+    fn handle_call(&self, attributes: BucketAttributes) -> Reply<PutBucketAttributesRpc> {
+        Reply::future(
+            self.service
+                .put_bucket_attributes(attributes)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<ListBucketAttributesRpc> for RpcServer {
+    fn handle_call(&self, _: ()) -> Reply<ListBucketAttributesRpc> {
+        Reply::future(
+            self.service
+                .list_bucket_attributes()
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<RelayoutBucketRpc> for RpcServer {
+    fn handle_call(
+        &self,
+        (bucket_id, params): (BucketId, RelayoutParameters),
+    ) -> Reply<RelayoutBucketRpc> {
+        Reply::future(
+            self.service
+                .relayout_bucket(bucket_id, params)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<FinishSegmentRelayoutRpc> for RpcServer {
+    fn handle_call(&self, segment: RelayoutedSegment) -> Reply<FinishSegmentRelayoutRpc> {
+        Reply::future(
+            self.service
+                .finish_segment_relayout(segment)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<ListBucketRelayoutsRpc> for RpcServer {
+    fn handle_call(&self, _: ()) -> Reply<ListBucketRelayoutsRpc> {
+        Reply::future(
+            self.service
+                .list_bucket_relayouts()
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<DecommissionDevicesRpc> for RpcServer {
+    fn handle_call(&self, device_ids: Vec<DeviceId>) -> Reply<DecommissionDevicesRpc> {
+        Reply::future(
+            self.service
+                .decommission_devices(device_ids)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<ListDecommissionsRpc> for RpcServer {
+    fn handle_call(&self, _: ()) -> Reply<ListDecommissionsRpc> {
+        Reply::future(
+            self.service
+                .list_decommissions()
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<PutMaintenanceWindowsRpc> for RpcServer {
+    fn handle_call(&self, windows: MaintenanceWindows) -> Reply<PutMaintenanceWindowsRpc> {
+        Reply::future(
+            self.service
+                .put_maintenance_windows(windows)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<GetMaintenanceWindowsRpc> for RpcServer {
+    fn handle_call(&self, _: ()) -> Reply<GetMaintenanceWindowsRpc> {
+        Reply::future(
+            self.service
+                .get_maintenance_windows()
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<PutDeviceBaselineRpc> for RpcServer {
+    fn handle_call(&self, baseline: DeviceBaseline) -> Reply<PutDeviceBaselineRpc> {
+        Reply::future(
+            self.service
+                .put_device_baseline(baseline)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<ListDeviceBaselinesRpc> for RpcServer {
+    fn handle_call(&self, _: ()) -> Reply<ListDeviceBaselinesRpc> {
+        Reply::future(
+            self.service
+                .list_device_baselines()
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
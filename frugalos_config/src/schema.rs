@@ -0,0 +1,335 @@
+//! 構成管理用のノード間、およびそれらを操作するコマンドでのみ利用される RPC のスキーマ定義。
+//!
+//! ここで定義する RPC の ID には`0x0203_0000`以降を利用する。
+use bytecodec::bincode_codec::{BincodeDecoder, BincodeEncoder};
+use fibers_rpc::{Call, ProcedureId};
+use libfrugalos::Result;
+
+use libfrugalos::entity::bucket::BucketId;
+use libfrugalos::entity::device::DeviceId;
+
+use attribute::BucketAttributes;
+use baseline::DeviceBaseline;
+use decommission::DeviceDecommission;
+use maintenance::MaintenanceWindows;
+use rebalance::{RebalanceOptions, RebalancePlan};
+use relayout::{BucketRelayout, RelayoutParameters, RelayoutedSegment};
+use simulation::{ChangePlan, ProposedChange};
+use topology::FailureDomain;
+use usage::DeviceUsage;
+
+/// 構成管理用クラスタの現在の状態を、バックアップとして取得する RPC。
+///
+/// 応答は`ConfigBackup::to_bytes`でバイト列に変換されたバックアップ。
+/// 一貫した状態を返すために、リーダ以外のノードへの要求は`NotLeader`エラーとなる。
+#[derive(Debug)]
+pub struct ExportBackupRpc;
+impl Call for ExportBackupRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_0000);
+    const NAME: &'static str = "frugalos.config.export_backup";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Vec<u8>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// セグメントの配置の偏りを均すための移動計画を取得する RPC。
+///
+/// 計画は報告されるだけで、実際の移動は行われない。
+#[derive(Debug)]
+pub struct PlanRebalanceRpc;
+impl Call for PlanRebalanceRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_0001);
+    const NAME: &'static str = "frugalos.config.plan_rebalance";
+
+    type Req = RebalanceOptions;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<RebalancePlan>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 各サーバが、自身の持つデバイスの使用量を構成管理用クラスタに登録するための RPC。
+///
+/// 登録は Raft を経由するので、要求はリーダに送る必要がある。
+#[derive(Debug)]
+pub struct PutDeviceUsagesRpc;
+impl Call for PutDeviceUsagesRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_0002);
+    const NAME: &'static str = "frugalos.config.put_device_usages";
+
+    type Req = Vec<DeviceUsage>;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<()>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 登録済みのデバイスの使用量の一覧を取得する RPC。
+#[derive(Debug)]
+pub struct ListDeviceUsagesRpc;
+impl Call for ListDeviceUsagesRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_0003);
+    const NAME: &'static str = "frugalos.config.list_device_usages";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Vec<DeviceUsage>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// サーバの障害ドメイン(ゾーンおよびラック)を設定する RPC。
+///
+/// 設定は Raft を経由するので、要求はリーダに送る必要がある。
+#[derive(Debug)]
+pub struct PutFailureDomainRpc;
+impl Call for PutFailureDomainRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_0004);
+    const NAME: &'static str = "frugalos.config.put_failure_domain";
+
+    type Req = FailureDomain;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<FailureDomain>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 設定済みの障害ドメインの一覧を取得する RPC。
+#[derive(Debug)]
+pub struct ListFailureDomainsRpc;
+impl Call for ListFailureDomainsRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_0005);
+    const NAME: &'static str = "frugalos.config.list_failure_domains";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Vec<FailureDomain>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// バケツの属性を設定する RPC。
+///
+/// 設定は Raft を経由するので、要求はリーダに送る必要がある。
+#[derive(Debug)]
+pub struct PutBucketAttributesRpc;
+impl Call for PutBucketAttributesRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_0006);
+    const NAME: &'static str = "frugalos.config.put_bucket_attributes";
+
+    type Req = BucketAttributes;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<BucketAttributes>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 設定済みのバケツの属性の一覧を取得する RPC。
+///
+/// 全ての属性が既定値のバケツは含まれない。
+#[derive(Debug)]
+pub struct ListBucketAttributesRpc;
+impl Call for ListBucketAttributesRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_0007);
+    const NAME: &'static str = "frugalos.config.list_bucket_attributes";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Vec<BucketAttributes>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 構成の変更を実際には適用せずに、その影響の見積もりを取得する RPC。
+///
+/// 最新の構成に基づいて見積もるために、要求はリーダに送る必要がある。
+#[derive(Debug)]
+pub struct PlanChangeRpc;
+impl Call for PlanChangeRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_0008);
+    const NAME: &'static str = "frugalos.config.plan_change";
+
+    type Req = ProposedChange;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<ChangePlan>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// バケツの再配置を開始する RPC。
+///
+/// 再配置は Raft を経由して開始されるので、要求はリーダに送る必要がある。
+#[derive(Debug)]
+pub struct RelayoutBucketRpc;
+impl Call for RelayoutBucketRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_0009);
+    const NAME: &'static str = "frugalos.config.relayout_bucket";
+
+    type Req = (BucketId, RelayoutParameters);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<BucketRelayout>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// セグメントの再配置の完了を記録する RPC。
+///
+/// 要求はリーダに送る必要がある。
+/// 各セグメントの MDS のリーダが、新しい配置への切り替えを終えた後に発行する。
+#[derive(Debug)]
+pub struct FinishSegmentRelayoutRpc;
+impl Call for FinishSegmentRelayoutRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_000A);
+    const NAME: &'static str = "frugalos.config.finish_segment_relayout";
+
+    type Req = RelayoutedSegment;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<()>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 実行中のバケツの再配置の一覧を取得する RPC。
+#[derive(Debug)]
+pub struct ListBucketRelayoutsRpc;
+impl Call for ListBucketRelayoutsRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_000B);
+    const NAME: &'static str = "frugalos.config.list_bucket_relayouts";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Vec<BucketRelayout>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// デバイスの退役を開始する RPC。
+///
+/// 退役は Raft を経由して開始されるので、要求はリーダに送る必要がある。
+#[derive(Debug)]
+pub struct DecommissionDevicesRpc;
+impl Call for DecommissionDevicesRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_000C);
+    const NAME: &'static str = "frugalos.config.decommission_devices";
+
+    type Req = Vec<DeviceId>;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Vec<DeviceDecommission>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 退役中のデバイスの状態の一覧を取得する RPC。
+///
+/// 最新の状態を得るために、要求はリーダに送る必要がある。
+#[derive(Debug)]
+pub struct ListDecommissionsRpc;
+impl Call for ListDecommissionsRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_000D);
+    const NAME: &'static str = "frugalos.config.list_decommissions";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Vec<DeviceDecommission>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// メンテナンスウィンドウを設定する RPC。
+///
+/// 設定は Raft を経由するので、要求はリーダに送る必要がある。
+#[derive(Debug)]
+pub struct PutMaintenanceWindowsRpc;
+impl Call for PutMaintenanceWindowsRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_000E);
+    const NAME: &'static str = "frugalos.config.put_maintenance_windows";
+
+    type Req = MaintenanceWindows;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<MaintenanceWindows>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 設定済みのメンテナンスウィンドウを取得する RPC。
+#[derive(Debug)]
+pub struct GetMaintenanceWindowsRpc;
+impl Call for GetMaintenanceWindowsRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_000F);
+    const NAME: &'static str = "frugalos.config.get_maintenance_windows";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<MaintenanceWindows>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 各サーバが、作成したデバイスの性能の基準値を構成管理用クラスタに登録するための RPC。
+///
+/// 登録は Raft を経由するので、要求はリーダに送る必要がある。
+#[derive(Debug)]
+pub struct PutDeviceBaselineRpc;
+impl Call for PutDeviceBaselineRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_0010);
+    const NAME: &'static str = "frugalos.config.put_device_baseline";
+
+    type Req = DeviceBaseline;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<DeviceBaseline>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 登録済みのデバイスの性能の基準値の一覧を取得する RPC。
+#[derive(Debug)]
+pub struct ListDeviceBaselinesRpc;
+impl Call for ListDeviceBaselinesRpc {
+    const ID: ProcedureId = ProcedureId(0x0203_0011);
+    const NAME: &'static str = "frugalos.config.list_device_baselines";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Vec<DeviceBaseline>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
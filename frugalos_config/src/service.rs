@@ -4,6 +4,7 @@ use fibers::sync::{mpsc, oneshot};
 use fibers::Spawn;
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
 use fibers_rpc::server::ServerBuilder as RpcServerBuilder;
+use frugalos_core::cluster_feature::{self, ClusterFeature};
 use frugalos_raft::{self, RaftIo};
 use futures::{Async, Future, Poll, Stream};
 use libfrugalos::entity::bucket::{Bucket, BucketId, BucketSummary};
@@ -12,17 +13,28 @@ use libfrugalos::entity::server::{Server, ServerId, ServerSummary};
 use raftlog::log::{LogEntry, LogIndex, ProposalId};
 use raftlog::{self, ReplicatedLog};
 use slog::Logger;
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::mem;
 use std::net::SocketAddr;
 use std::path::Path;
+use trackable::error::ErrorKindExt;
 
+use attribute::BucketAttributes;
+use backup::ConfigBackup;
+use baseline::DeviceBaseline;
 use builder::SegmentTableBuilder;
 use cluster;
 use config::server_to_frugalos_raft_node;
-use machine::{Command, DeviceGroup, NextSeqNo, SegmentTable, Snapshot};
+use decommission::{self, DeviceDecommission};
+use machine::{Command, DeviceGroup, NextSeqNo, Segment, SegmentTable, Snapshot};
+use maintenance::MaintenanceWindows;
 use protobuf;
+use rebalance::{self, BucketRebalance, RebalanceOptions, RebalancePlan};
+use relayout::{self, BucketRelayout, RelayoutParameters, RelayoutedSegment};
 use rpc;
+use simulation::{self, ChangePlan, ClusterState, ProposedChange};
+use topology::{self, FailureDomain};
+use usage::DeviceUsage;
 use {Error, ErrorKind, Result};
 
 type RaftEvent = raftlog::Event;
@@ -49,6 +61,13 @@ pub struct Service {
     devices: BTreeMap<DeviceId, Device>,
     servers: BTreeMap<ServerId, Server>,
     segment_tables: BTreeMap<BucketId, SegmentTable>,
+    device_usages: BTreeMap<DeviceId, DeviceUsage>,
+    failure_domains: BTreeMap<ServerId, FailureDomain>,
+    bucket_attributes: BTreeMap<BucketId, BucketAttributes>,
+    relayouts: BTreeMap<BucketId, BucketRelayout>,
+    decommissioned: BTreeSet<DeviceId>,
+    maintenance_windows: MaintenanceWindows,
+    device_baselines: BTreeMap<DeviceId, DeviceBaseline>,
 
     next_seqno: NextSeqNo,
     events: VecDeque<Event>,
@@ -96,6 +115,13 @@ impl Service {
             devices: BTreeMap::new(),
             servers: BTreeMap::new(),
             segment_tables: BTreeMap::new(),
+            device_usages: BTreeMap::new(),
+            failure_domains: BTreeMap::new(),
+            bucket_attributes: BTreeMap::new(),
+            relayouts: BTreeMap::new(),
+            decommissioned: BTreeSet::new(),
+            maintenance_windows: MaintenanceWindows::default(),
+            device_baselines: BTreeMap::new(),
 
             next_seqno: NextSeqNo::default(),
             events: VecDeque::new(),
@@ -166,9 +192,9 @@ impl Service {
 
                     let leader = track!(frugalos_raft::NodeId::from_raft_node_id(leader))?;
                     for reply in self.leader_waiters.drain(..) {
-                        reply.exit(Ok(leader.addr));
+                        reply.exit(Ok(leader.current_addr()));
                     }
-                    self.leader = Some(leader.addr);
+                    self.leader = Some(leader.current_addr());
                 }
 
                 if let raftlog::Event::RoleChanged {
@@ -200,6 +226,31 @@ impl Service {
             Command::DeleteDevice { id } => self.handle_delete_device(proposal_id, id),
             Command::PutBucket { bucket } => self.handle_put_bucket(proposal_id, bucket),
             Command::DeleteBucket { id } => self.handle_delete_bucket(proposal_id, &id),
+            Command::PutDeviceUsages { usages } => {
+                self.handle_put_device_usages(proposal_id, usages)
+            }
+            Command::PutFailureDomain { domain } => {
+                self.handle_put_failure_domain(proposal_id, domain)
+            }
+            Command::PutBucketAttributes { attributes } => {
+                self.handle_put_bucket_attributes(proposal_id, attributes)
+            }
+            Command::RelayoutBucket { bucket } => self.handle_relayout_bucket(proposal_id, bucket),
+            Command::FinishSegmentRelayout { segment } => {
+                self.handle_finish_segment_relayout(proposal_id, segment)
+            }
+            Command::DecommissionDevices { device_ids } => {
+                self.handle_decommission_devices(proposal_id, device_ids)
+            }
+            Command::RebalanceBucket { rebalance } => {
+                self.handle_rebalance_bucket(proposal_id, rebalance)
+            }
+            Command::PutMaintenanceWindows { windows } => {
+                self.handle_put_maintenance_windows(proposal_id, windows)
+            }
+            Command::PutDeviceBaseline { baseline } => {
+                self.handle_put_device_baseline(proposal_id, baseline)
+            }
         }
         Ok(())
     }
@@ -242,6 +293,7 @@ impl Service {
                 None
             } else {
                 info!(self.logger, "Server is deleted: {}", dump!(id, server));
+                self.failure_domains.remove(&id);
                 self.events.push_back(Event::DeleteServer(server.clone()));
                 Some(server)
             }
@@ -339,6 +391,9 @@ impl Service {
                 None
             } else {
                 info!(self.logger, "Device is deleted: {}", dump!(id, device));
+                self.device_usages.remove(&id);
+                self.device_baselines.remove(&id);
+                self.decommissioned.remove(&id);
                 self.events.push_back(Event::DeleteDevice(device.clone()));
                 Some(device)
             }
@@ -351,6 +406,150 @@ impl Service {
             reply.exit(Ok(deleted))
         }
     }
+    fn handle_put_device_usages(&mut self, proposal_id: ProposalId, usages: Vec<DeviceUsage>) {
+        // NOTE: 使用量は今後構築されるセグメントテーブルにのみ反映され、既存のテーブルは変更しない
+        for usage in usages {
+            match self.devices.get(&usage.device_id) {
+                Some(d) if !d.is_virtual() => {
+                    self.device_usages.insert(usage.device_id.clone(), usage);
+                }
+                _ => {
+                    warn!(
+                        self.logger,
+                        "Ignores the usage of an unknown or virtual device: {}",
+                        dump!(proposal_id, usage)
+                    );
+                }
+            }
+        }
+        if let Some(Proposal::PutDeviceUsages { reply, .. }) =
+            self.pop_committed_proposal(proposal_id)
+        {
+            reply.exit(Ok(()));
+        }
+    }
+    fn handle_put_failure_domain(&mut self, proposal_id: ProposalId, domain: FailureDomain) {
+        // NOTE: 障害ドメインは今後構築されるセグメントテーブルにのみ反映され、既存のテーブルは変更しない
+        if !self.servers.contains_key(&domain.server_id) {
+            warn!(
+                self.logger,
+                "The failure domain refers to undefined server: {}",
+                dump!(proposal_id, domain)
+            );
+            if let Some(Proposal::PutFailureDomain { reply, .. }) =
+                self.pop_committed_proposal(proposal_id)
+            {
+                let e = ErrorKind::InvalidInput
+                    .cause(format!("Undefined server: {:?}", domain.server_id));
+                reply.exit(Err(track!(e.into())));
+            }
+            return;
+        }
+        info!(self.logger, "Failure domain is updated: {:?}", domain);
+        if domain.is_empty() {
+            self.failure_domains.remove(&domain.server_id);
+        } else {
+            self.failure_domains
+                .insert(domain.server_id.clone(), domain.clone());
+        }
+        if let Some(Proposal::PutFailureDomain { reply, .. }) =
+            self.pop_committed_proposal(proposal_id)
+        {
+            reply.exit(Ok(domain));
+        }
+    }
+    fn handle_put_bucket_attributes(
+        &mut self,
+        proposal_id: ProposalId,
+        attributes: BucketAttributes,
+    ) {
+        if !self.buckets.contains_key(&attributes.bucket_id) {
+            warn!(
+                self.logger,
+                "The attributes refer to undefined bucket: {}",
+                dump!(proposal_id, attributes)
+            );
+            if let Some(Proposal::PutBucketAttributes { reply, .. }) =
+                self.pop_committed_proposal(proposal_id)
+            {
+                let e = ErrorKind::InvalidInput
+                    .cause(format!("Undefined bucket: {:?}", attributes.bucket_id));
+                reply.exit(Err(track!(e.into())));
+            }
+            return;
+        }
+        info!(
+            self.logger,
+            "Bucket attributes are updated: {:?}", attributes
+        );
+        if attributes.is_default() {
+            self.bucket_attributes.remove(&attributes.bucket_id);
+        } else {
+            self.bucket_attributes
+                .insert(attributes.bucket_id.clone(), attributes.clone());
+        }
+        self.events
+            .push_back(Event::PutBucketAttributes(attributes.clone()));
+        if let Some(Proposal::PutBucketAttributes { reply, .. }) =
+            self.pop_committed_proposal(proposal_id)
+        {
+            reply.exit(Ok(attributes));
+        }
+    }
+    fn handle_put_maintenance_windows(
+        &mut self,
+        proposal_id: ProposalId,
+        windows: MaintenanceWindows,
+    ) {
+        info!(
+            self.logger,
+            "Maintenance windows are updated: {:?}", windows
+        );
+        self.maintenance_windows = windows.clone();
+        self.events
+            .push_back(Event::PutMaintenanceWindows(windows.clone()));
+        if let Some(Proposal::PutMaintenanceWindows { reply, .. }) =
+            self.pop_committed_proposal(proposal_id)
+        {
+            reply.exit(Ok(windows));
+        }
+    }
+    fn handle_put_device_baseline(&mut self, proposal_id: ProposalId, baseline: DeviceBaseline) {
+        // NOTE: 基準値は今後構築されるセグメントテーブルにのみ反映され、既存のテーブルは変更しない
+        let result = match self.devices.get(&baseline.device_id) {
+            Some(d) if !d.is_virtual() => {
+                if baseline.passed {
+                    info!(self.logger, "Device baseline is recorded: {:?}", baseline);
+                } else {
+                    warn!(
+                        self.logger,
+                        "Device failed the precheck and is excluded from placement: {:?}", baseline
+                    );
+                }
+                self.device_baselines
+                    .insert(baseline.device_id.clone(), baseline.clone());
+                Ok(baseline)
+            }
+            _ => {
+                warn!(
+                    self.logger,
+                    "Ignores the baseline of an unknown or virtual device: {}",
+                    dump!(proposal_id, baseline)
+                );
+                Err(track!(ErrorKind::InvalidInput
+                    .cause(format!(
+                        "Unknown or virtual device: {:?}",
+                        baseline.device_id
+                    ))
+                    .into()))
+            }
+        };
+        if let Some(Proposal::PutDeviceBaseline { reply, .. }) =
+            self.pop_committed_proposal(proposal_id)
+        {
+            reply.exit(result);
+        }
+    }
     fn handle_put_bucket(&mut self, proposal_id: ProposalId, mut bucket: Bucket) {
         // TODO: 最低限`MetadataBucket`は更新可能にする
         if self.buckets.contains_key(bucket.id()) {
@@ -392,6 +591,8 @@ impl Service {
         let deleted = if let Some(bucket) = self.buckets.remove(id) {
             info!(self.logger, "Bucket is deleted: {}", dump!(id, bucket));
             self.delete_segment_table(&bucket);
+            self.bucket_attributes.remove(id);
+            self.relayouts.remove(id);
             self.events.push_back(Event::DeleteBucket(bucket.clone()));
             Some(bucket)
         } else {
@@ -403,6 +604,511 @@ impl Service {
             reply.exit(Ok(deleted))
         }
     }
+    fn handle_relayout_bucket(&mut self, proposal_id: ProposalId, bucket: Bucket) {
+        let result = track!(self.start_relayout(bucket));
+        match result {
+            Err(ref e) => warn!(
+                self.logger,
+                "Cannot relayout the bucket: {}",
+                dump!(proposal_id, e)
+            ),
+            Ok(ref relayout) => info!(
+                self.logger,
+                "Bucket relayout is started: {}",
+                dump!(proposal_id, relayout)
+            ),
+        }
+        if let Some(Proposal::RelayoutBucket { reply, .. }) =
+            self.pop_committed_proposal(proposal_id)
+        {
+            reply.exit(result);
+        }
+    }
+    fn start_relayout(&mut self, bucket: Bucket) -> Result<BucketRelayout> {
+        track_assert!(
+            !self.relayouts.contains_key(bucket.id()),
+            ErrorKind::InvalidInput,
+            "The bucket is already being relayouted: {:?}",
+            bucket.id()
+        );
+        let previous = track_assert_some!(
+            self.buckets.get(bucket.id()).cloned(),
+            ErrorKind::InvalidInput,
+            "No such bucket: {:?}",
+            bucket.id()
+        );
+        track!(relayout::validate_relayout(&previous, &bucket))?;
+        track!(self.relayout_segments(previous, bucket, true, |_| true))
+    }
+    /// 退役中のデバイスをメンバに含む`bucket_id`のセグメントを、それらを除いた配置に再配置する。
+    ///
+    /// 該当するセグメントがない場合には`None`を返す。
+    #[allow(clippy::ptr_arg)]
+    fn start_decommission_relayout(
+        &mut self,
+        bucket_id: &BucketId,
+    ) -> Result<Option<BucketRelayout>> {
+        track_assert!(
+            !self.relayouts.contains_key(bucket_id),
+            ErrorKind::InvalidInput,
+            "The bucket is already being relayouted: {:?}",
+            bucket_id
+        );
+        let bucket = track_assert_some!(
+            self.buckets.get(bucket_id).cloned(),
+            ErrorKind::InvalidInput,
+            "No such bucket: {:?}",
+            bucket_id
+        );
+        let device_nos = self.decommissioned_device_nos();
+        let is_target = |s: &Segment| decommission::uses_devices(s, &device_nos);
+        let has_targets = self
+            .segment_tables
+            .get(bucket_id)
+            .map_or(false, |t| t.segments.iter().any(&is_target));
+        if !has_targets {
+            return Ok(None);
+        }
+        track!(relayout::validate_group_size(&bucket))?;
+        let relayout = track!(self.relayout_segments(bucket.clone(), bucket, false, is_target))?;
+        Ok(Some(relayout))
+    }
+    /// `bucket`のセグメントのうち`is_target`を満たすものに、`bucket`から構築し直した配置のデバイスグループを追加する。
+    fn relayout_segments<F>(
+        &mut self,
+        previous: Bucket,
+        bucket: Bucket,
+        is_bucket_changed: bool,
+        is_target: F,
+    ) -> Result<BucketRelayout>
+    where
+        F: Fn(&Segment) -> bool,
+    {
+        // NOTE: 構築結果はデバイス群等の(複製された)状態のみから決まるので、全てのサーバで一致する
+        let devices = self.placeable_devices();
+        let new_table = track!(SegmentTableBuilder::new(&devices)
+            .usages(&self.device_usages)
+            .failure_domains(&self.failure_domains)
+            .build(&bucket))?;
+        let old_table = track_assert_some!(
+            self.segment_tables.get(bucket.id()).cloned(),
+            ErrorKind::Other,
+            "No segment table: {:?}",
+            bucket.id()
+        );
+        track_assert_eq!(
+            old_table.segments.len(),
+            new_table.segments.len(),
+            ErrorKind::Other
+        );
+
+        let mut new_members = BTreeMap::new();
+        for (segment_no, (old, new)) in old_table
+            .segments
+            .iter()
+            .zip(new_table.segments)
+            .enumerate()
+        {
+            if is_target(old) {
+                let new_group = track_assert_some!(new.groups.into_iter().next(), ErrorKind::Other);
+                new_members.insert(segment_no as u16, new_group.members);
+            }
+        }
+        track!(self.switch_segments(previous, bucket, is_bucket_changed, new_members))
+    }
+    /// `new_members`に含まれるセグメントに、そのメンバ群からなる新しい世代のデバイスグループを追加する。
+    ///
+    /// `is_bucket_changed`が`false`の場合には、対象外のセグメントの構成を保つために`PutBucket`イベントは発行しない
+    /// (バケツが登録し直されると、全てのセグメントの構成が必要となるため)。
+    fn switch_segments(
+        &mut self,
+        previous: Bucket,
+        bucket: Bucket,
+        is_bucket_changed: bool,
+        mut new_members: BTreeMap<u16, Vec<u32>>,
+    ) -> Result<BucketRelayout> {
+        let old_table = track_assert_some!(
+            self.segment_tables.get(bucket.id()).cloned(),
+            ErrorKind::Other,
+            "No segment table: {:?}",
+            bucket.id()
+        );
+        let mut table = SegmentTable::new(bucket.id().clone());
+        let mut pending_segments = Vec::new();
+        for (segment_no, old) in old_table.segments.into_iter().enumerate() {
+            let members = match new_members.remove(&(segment_no as u16)) {
+                None => {
+                    table.segments.push(old);
+                    continue;
+                }
+                Some(members) => members,
+            };
+            let old_group = track_assert_some!(old.groups.into_iter().next(), ErrorKind::Other);
+            let new_group = DeviceGroup {
+                members,
+                generation: relayout::next_generation(old_group.generation),
+            };
+            table.segments.push(Segment {
+                groups: vec![new_group, old_group],
+            });
+            pending_segments.push(segment_no as u16);
+        }
+        track_assert!(
+            new_members.is_empty(),
+            ErrorKind::Other,
+            "No such segments: {:?}",
+            new_members.keys().collect::<Vec<_>>()
+        );
+        track_assert!(!pending_segments.is_empty(), ErrorKind::Other);
+
+        let relayout = BucketRelayout {
+            bucket_id: bucket.id().clone(),
+            previous,
+            pending_segments,
+        };
+        self.events
+            .push_back(Event::PutBucketRelayout(relayout.clone()));
+        if is_bucket_changed {
+            self.events.push_back(Event::PutBucket(bucket.clone()));
+        }
+        for &segment_no in &relayout.pending_segments {
+            self.events.push_back(Event::PatchSegment {
+                bucket_no: bucket.seqno(),
+                segment_no,
+                groups: table.segments[segment_no as usize].groups.clone(),
+            });
+        }
+        self.relayouts.insert(bucket.id().clone(), relayout.clone());
+        self.segment_tables.insert(bucket.id().clone(), table);
+        self.buckets.insert(bucket.id().clone(), bucket);
+
+        // NOTE: セグメント更新は重い処理なので、常にスナップショットを取る
+        track!(self.take_snapshot())?;
+        Ok(relayout)
+    }
+    fn handle_finish_segment_relayout(
+        &mut self,
+        proposal_id: ProposalId,
+        segment: RelayoutedSegment,
+    ) {
+        let result = track!(self.finish_segment_relayout(
+            &segment.bucket_id,
+            segment.segment_no,
+            segment.generation
+        ));
+        if let Err(ref e) = result {
+            warn!(
+                self.logger,
+                "Cannot finish the segment relayout: {}",
+                dump!(proposal_id, segment, e)
+            );
+        }
+        if let Some(Proposal::FinishSegmentRelayout { reply, .. }) =
+            self.pop_committed_proposal(proposal_id)
+        {
+            reply.exit(result);
+        }
+    }
+    #[allow(clippy::ptr_arg)]
+    fn finish_segment_relayout(
+        &mut self,
+        bucket_id: &BucketId,
+        segment_no: u16,
+        generation: u8,
+    ) -> Result<()> {
+        let bucket_no = track_assert_some!(
+            self.buckets.get(bucket_id).map(Bucket::seqno),
+            ErrorKind::InvalidInput,
+            "No such bucket: {:?}",
+            bucket_id
+        );
+        let segment = track_assert_some!(
+            self.segment_tables
+                .get_mut(bucket_id)
+                .and_then(|t| t.segments.get_mut(segment_no as usize)),
+            ErrorKind::InvalidInput,
+            "No such segment: {:?}",
+            (bucket_id, segment_no)
+        );
+        let is_pending = self
+            .relayouts
+            .get(bucket_id)
+            .map_or(false, |r| r.pending_segments.contains(&segment_no));
+        if !is_pending {
+            // NOTE: 完了通知は再送されうるので、切り替え済みのセグメントに対しては何もしない
+            track_assert_eq!(
+                segment.groups[0].generation,
+                generation,
+                ErrorKind::InvalidInput
+            );
+            return Ok(());
+        }
+        track_assert_eq!(
+            segment.groups[0].generation,
+            generation,
+            ErrorKind::InvalidInput
+        );
+
+        segment.groups.truncate(1);
+        self.events.push_back(Event::PatchSegment {
+            bucket_no,
+            segment_no,
+            groups: segment.groups.clone(),
+        });
+
+        let finished = {
+            let relayout = self.relayouts.get_mut(bucket_id).expect("Never fails");
+            relayout.pending_segments.retain(|&n| n != segment_no);
+            relayout.is_finished()
+        };
+        info!(
+            self.logger,
+            "Segment relayout is finished: {}",
+            dump!(bucket_id, segment_no, generation, finished)
+        );
+        if finished {
+            self.relayouts.remove(bucket_id);
+
+            // 再配置中に退役が開始されたデバイスが残っていれば、続けて移し替える
+            match track!(self.start_decommission_relayout(bucket_id)) {
+                Err(e) => warn!(
+                    self.logger,
+                    "Cannot relayout the bucket for decommissioning: {}",
+                    dump!(bucket_id, e)
+                ),
+                Ok(Some(relayout)) => info!(
+                    self.logger,
+                    "Bucket relayout for decommissioning is started: {}",
+                    dump!(relayout)
+                ),
+                Ok(None) => {}
+            }
+        }
+        Ok(())
+    }
+    fn handle_decommission_devices(&mut self, proposal_id: ProposalId, device_ids: Vec<DeviceId>) {
+        let result = track!(self.decommission_devices(&device_ids));
+        match result {
+            Err(ref e) => warn!(
+                self.logger,
+                "Cannot decommission the devices: {}",
+                dump!(proposal_id, device_ids, e)
+            ),
+            Ok(ref statuses) => info!(
+                self.logger,
+                "Devices are being decommissioned: {}",
+                dump!(proposal_id, statuses)
+            ),
+        }
+        if let Some(Proposal::DecommissionDevices { reply, .. }) =
+            self.pop_committed_proposal(proposal_id)
+        {
+            reply.exit(result);
+        }
+    }
+    fn decommission_devices(&mut self, device_ids: &[DeviceId]) -> Result<Vec<DeviceDecommission>> {
+        // NOTE: 検証に失敗した場合には、状態を一切変更しない
+        track!(self.validate_decommission(device_ids))?;
+        self.decommissioned.extend(device_ids.iter().cloned());
+
+        let bucket_ids = self
+            .buckets
+            .keys()
+            .filter(|id| !self.relayouts.contains_key(*id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for bucket_id in bucket_ids {
+            match track!(self.start_decommission_relayout(&bucket_id)) {
+                Err(e) => warn!(
+                    self.logger,
+                    "Cannot relayout the bucket for decommissioning: {}",
+                    dump!(bucket_id, e)
+                ),
+                Ok(Some(relayout)) => info!(
+                    self.logger,
+                    "Bucket relayout for decommissioning is started: {}",
+                    dump!(relayout)
+                ),
+                Ok(None) => {}
+            }
+        }
+        track!(self.take_snapshot())?;
+        Ok(decommission::statuses(
+            device_ids,
+            &self.devices,
+            &self.segment_tables,
+        ))
+    }
+    fn handle_rebalance_bucket(&mut self, proposal_id: ProposalId, rebalance: BucketRebalance) {
+        let result = track!(self.rebalance_bucket(&rebalance));
+        match result {
+            Err(ref e) => warn!(
+                self.logger,
+                "Cannot rebalance the bucket: {}",
+                dump!(proposal_id, rebalance, e)
+            ),
+            Ok(ref relayout) => info!(
+                self.logger,
+                "Bucket rebalance is started: {}",
+                dump!(proposal_id, rebalance.moves.len(), relayout)
+            ),
+        }
+        if let Some(Proposal::RebalanceBucket { reply, .. }) =
+            self.pop_committed_proposal(proposal_id)
+        {
+            reply.exit(result.map(|_| Some(rebalance)));
+        }
+    }
+    fn rebalance_bucket(&mut self, rebalance: &BucketRebalance) -> Result<BucketRelayout> {
+        let (bucket, new_members) = track!(self.validate_rebalance(rebalance))?;
+        track!(self.switch_segments(bucket.clone(), bucket, false, new_members))
+    }
+    /// `rebalance`の移動が可能かどうかを検証し、対象のバケツと移動後のセグメントのメンバ群を返す。
+    ///
+    /// コマンドの適用時にも呼ばれるので、結果は複製された状態のみから決まる必要がある。
+    fn validate_rebalance(
+        &self,
+        rebalance: &BucketRebalance,
+    ) -> Result<(Bucket, BTreeMap<u16, Vec<u32>>)> {
+        let bucket_id = &rebalance.bucket_id;
+        track_assert!(
+            !self.relayouts.contains_key(bucket_id),
+            ErrorKind::InvalidInput,
+            "The bucket is already being relayouted: {:?}",
+            bucket_id
+        );
+        let bucket = track_assert_some!(
+            self.buckets.get(bucket_id).cloned(),
+            ErrorKind::InvalidInput,
+            "No such bucket: {:?}",
+            bucket_id
+        );
+        if let Bucket::Metadata(_) = bucket {
+            track_panic!(
+                ErrorKind::InvalidInput,
+                "Metadata buckets cannot be rebalanced: {:?}",
+                bucket_id
+            );
+        }
+        track!(relayout::validate_group_size(&bucket))?;
+        let table = track_assert_some!(
+            self.segment_tables.get(bucket_id),
+            ErrorKind::InvalidInput,
+            "No segment table: {:?}",
+            bucket_id
+        );
+        let new_members = track!(rebalance::moved_members(
+            rebalance,
+            table,
+            &self.devices,
+            &self.decommissioned
+        ))?;
+        Ok((bucket, new_members))
+    }
+    /// 配置の偏りが最も大きい(移動数が最も多い)バケツについて、移動のコマンドを発行する。
+    ///
+    /// ローカルノードがリーダでない場合や、再配置中のバケツが`max_relayouts`個以上ある場合、
+    /// 移動の必要なバケツがない場合には、何もせずに`None`を返す。
+    fn start_rebalance(
+        &mut self,
+        options: &RebalanceOptions,
+        max_relayouts: usize,
+    ) -> Result<Option<BucketRebalance>> {
+        use raftlog::election::Role;
+        if self.rlog.local_node().role != Role::Leader || self.relayouts.len() >= max_relayouts {
+            return Ok(None);
+        }
+        for &feature in &[ClusterFeature::Relayout, ClusterFeature::Rebalance] {
+            track_assert!(
+                cluster_feature::is_enabled(feature),
+                ErrorKind::InvalidInput,
+                "The cluster feature {:?} is not enabled",
+                feature.name()
+            );
+        }
+
+        let plan = self.plan_rebalance(options);
+        let selected = plan
+            .buckets
+            .into_iter()
+            .filter(|b| !b.moves.is_empty())
+            .max_by_key(|b| b.moves.len());
+        let rebalance = match selected {
+            None => return Ok(None),
+            Some(b) => BucketRebalance {
+                bucket_id: b.bucket_id,
+                moves: b.moves,
+            },
+        };
+        track!(self.validate_rebalance(&rebalance))?;
+        Ok(Some(rebalance))
+    }
+    fn plan_rebalance(&self, options: &RebalanceOptions) -> RebalancePlan {
+        rebalance::plan(
+            &self.buckets,
+            &self.placeable_devices(),
+            &self.segment_tables,
+            &self.failure_domains,
+            &self.decommissioned,
+            options,
+        )
+    }
+    /// `device_ids`を退役させた後も、全てのバケツを配置できることを検証する。
+    ///
+    /// コマンドの適用時にも呼ばれるので、結果は複製された状態のみから決まる必要がある。
+    fn validate_decommission(&self, device_ids: &[DeviceId]) -> Result<()> {
+        track_assert!(
+            !device_ids.is_empty(),
+            ErrorKind::InvalidInput,
+            "No devices are specified"
+        );
+        for id in device_ids {
+            track!(decommission::validate_device(&self.devices, id))?;
+        }
+
+        let mut decommissioned = self.excluded_devices();
+        decommissioned.extend(device_ids.iter().cloned());
+        let devices = decommission::placeable_devices(&self.devices, &decommissioned);
+        let builder = SegmentTableBuilder::new(&devices)
+            .usages(&self.device_usages)
+            .failure_domains(&self.failure_domains);
+        for bucket in self.buckets.values() {
+            if let Bucket::Metadata(_) = *bucket {
+                // NOTE: メタデータ用のバケツは断片を持たないので、再配置の対象外
+                continue;
+            }
+            track!(
+                topology::validate_bucket(bucket, &devices, &self.failure_domains),
+                "bucket={:?}",
+                bucket.id()
+            )?;
+            track!(builder.build(bucket), "bucket={:?}", bucket.id())?;
+        }
+        Ok(())
+    }
+    /// 退役中のデバイスと性能試験に失敗したデバイスを除いた、配置先となり得るデバイス群を返す。
+    fn placeable_devices(&self) -> BTreeMap<DeviceId, Device> {
+        decommission::placeable_devices(&self.devices, &self.excluded_devices())
+    }
+    /// 配置先から除外するデバイス(退役中のものと、性能試験に失敗したもの)の集合を返す。
+    fn excluded_devices(&self) -> BTreeSet<DeviceId> {
+        let mut excluded = self.decommissioned.clone();
+        excluded.extend(
+            self.device_baselines
+                .values()
+                .filter(|b| !b.passed)
+                .map(|b| b.device_id.clone()),
+        );
+        excluded
+    }
+    /// 退役中のデバイスのシーケンス番号の集合を返す。
+    fn decommissioned_device_nos(&self) -> BTreeSet<u32> {
+        self.decommissioned
+            .iter()
+            .filter_map(|id| self.devices.get(id))
+            .map(Device::seqno)
+            .collect()
+    }
     fn handle_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
         // TODO: 以下が成立しないケースにも対応する (proposalsの中身を調整するだけ)
         track_assert_eq!(self.proposals.len(), 0, ErrorKind::Other);
@@ -427,11 +1133,48 @@ impl Service {
             .into_iter()
             .map(|s| (s.id.clone(), s))
             .collect();
-        self.segment_tables = snapshot
-            .segment_tables
+        let old_segment_tables = mem::replace(
+            &mut self.segment_tables,
+            snapshot
+                .segment_tables
+                .into_iter()
+                .map(|s| (s.bucket_id.clone(), s))
+                .collect(),
+        );
+        let old_relayouts = mem::replace(
+            &mut self.relayouts,
+            snapshot
+                .relayouts
+                .into_iter()
+                .map(|r| (r.bucket_id.clone(), r))
+                .collect(),
+        );
+        self.decommissioned = snapshot.decommissioned_devices.into_iter().collect();
+        self.device_usages = snapshot
+            .device_usages
+            .into_iter()
+            .map(|u| (u.device_id.clone(), u))
+            .collect();
+        self.failure_domains = snapshot
+            .failure_domains
             .into_iter()
-            .map(|s| (s.bucket_id.clone(), s))
+            .map(|d| (d.server_id.clone(), d))
             .collect();
+        self.device_baselines = snapshot
+            .device_baselines
+            .into_iter()
+            .map(|b| (b.device_id.clone(), b))
+            .collect();
+        let old_maintenance_windows =
+            mem::replace(&mut self.maintenance_windows, snapshot.maintenance_windows);
+        let old_bucket_attributes = mem::replace(
+            &mut self.bucket_attributes,
+            snapshot
+                .bucket_attributes
+                .into_iter()
+                .map(|a| (a.bucket_id.clone(), a))
+                .collect(),
+        );
         info!(
             self.logger,
             "Snapshot is loaded: {}",
@@ -459,20 +1202,54 @@ impl Service {
             }
             self.events.push_back(Event::PutDevice(d.clone()));
         }
+        for r in self.relayouts.values() {
+            if !old_relayouts.contains_key(&r.bucket_id) {
+                self.events.push_back(Event::PutBucketRelayout(r.clone()));
+            }
+        }
+        // NOTE: セグメントの構成はバケツの属性(MDS のウィットネスの数等)を参照するので、属性の後に通知する
+        let mut segment_events = Vec::new();
         for b in self.buckets.values() {
             if old_buckets.contains_key(b.id()) {
-                info!(self.logger, "The bucket {:?} already exists", b.id());
-                continue;
+                let old_segments = old_segment_tables.get(b.id()).map(|t| &t.segments);
+                if old_segments == Some(&self.segment_tables[b.id()].segments) {
+                    info!(self.logger, "The bucket {:?} already exists", b.id());
+                    continue;
+                }
+                // NOTE: 再配置によってセグメントテーブルが変わっている場合には、バケツの設定も
+                //       変わっている可能性があるので、全てのセグメントと共に登録し直す
+                info!(self.logger, "The bucket {:?} is relayouted", b.id());
             }
             self.events.push_back(Event::PutBucket(b.clone()));
             for (segment_no, segment) in self.segment_tables[b.id()].segments.iter().enumerate() {
-                self.events.push_back(Event::PatchSegment {
+                segment_events.push(Event::PatchSegment {
                     bucket_no: b.seqno(),
                     segment_no: segment_no as u16,
                     groups: segment.groups.clone(),
                 });
             }
         }
+        for (id, old) in &old_bucket_attributes {
+            if !self.bucket_attributes.contains_key(id) && self.buckets.contains_key(id) {
+                let attributes = BucketAttributes {
+                    bucket_id: old.bucket_id.clone(),
+                    ..Default::default()
+                };
+                self.events
+                    .push_back(Event::PutBucketAttributes(attributes));
+            }
+        }
+        for a in self.bucket_attributes.values() {
+            if old_bucket_attributes.get(&a.bucket_id) != Some(a) {
+                self.events.push_back(Event::PutBucketAttributes(a.clone()));
+            }
+        }
+        self.events.extend(segment_events);
+        if old_maintenance_windows != self.maintenance_windows {
+            self.events.push_back(Event::PutMaintenanceWindows(
+                self.maintenance_windows.clone(),
+            ));
+        }
 
         track!(self.sync_servers())?;
         Ok(())
@@ -483,16 +1260,26 @@ impl Service {
         }
         self.snapshot_reduction = SNAPSHOT_THRESHOLD;
 
-        let snapshot = Snapshot {
+        let snapshot = self.current_snapshot();
+        let snapshot = track!(protobuf::snapshot_encoder().encode_into_bytes(snapshot))?;
+        track!(self.rlog.install_snapshot(self.next_commit_index, snapshot))?;
+        Ok(())
+    }
+    fn current_snapshot(&self) -> Snapshot {
+        Snapshot {
             next_seqno: self.next_seqno.clone(),
             buckets: self.buckets.values().cloned().collect(),
             devices: self.devices.values().cloned().collect(),
             servers: self.servers.values().cloned().collect(),
             segment_tables: self.segment_tables.values().cloned().collect(),
-        };
-        let snapshot = track!(protobuf::snapshot_encoder().encode_into_bytes(snapshot))?;
-        track!(self.rlog.install_snapshot(self.next_commit_index, snapshot))?;
-        Ok(())
+            device_usages: self.device_usages.values().cloned().collect(),
+            failure_domains: self.failure_domains.values().cloned().collect(),
+            bucket_attributes: self.bucket_attributes.values().cloned().collect(),
+            relayouts: self.relayouts.values().cloned().collect(),
+            decommissioned_devices: self.decommissioned.iter().cloned().collect(),
+            maintenance_windows: self.maintenance_windows.clone(),
+            device_baselines: self.device_baselines.values().cloned().collect(),
+        }
     }
     fn handle_request(&mut self, request: Request) -> Result<()> {
         info!(self.logger, "Request: {:?}", request);
@@ -559,6 +1346,18 @@ impl Service {
             }
             Request::GetBucket { id, reply } => reply.exit(Ok(self.buckets.get(&id).cloned())),
             Request::PutBucket { bucket, reply } => {
+                if !self.buckets.contains_key(bucket.id())
+                    && self.devices.contains_key(bucket.device())
+                {
+                    if let Err(e) = track!(topology::validate_bucket(
+                        &bucket,
+                        &self.placeable_devices(),
+                        &self.failure_domains
+                    )) {
+                        reply.exit(Err(e));
+                        return Ok(());
+                    }
+                }
                 let command = Command::PutBucket { bucket };
                 match track!(self.propose_command(command)) {
                     Err(e) => reply.exit(Err(e)),
@@ -578,9 +1377,322 @@ impl Service {
                     }
                 }
             }
+            Request::ExportBackup { reply } => {
+                use raftlog::election::Role;
+                if self.rlog.local_node().role == Role::Leader {
+                    let revision = self.next_commit_index.as_u64().saturating_sub(1);
+                    reply.exit(Ok(ConfigBackup::new(revision, self.current_snapshot())));
+                } else {
+                    let e =
+                        ErrorKind::NotLeader.cause("Backups can only be exported from the leader");
+                    reply.exit(Err(track!(Error::from(e))));
+                }
+            }
+            Request::PutDeviceUsages { usages, reply } => {
+                if let Err(e) = track!(self.check_config_extensions()) {
+                    reply.exit(Err(e));
+                    return Ok(());
+                }
+                let command = Command::PutDeviceUsages { usages };
+                match track!(self.propose_command(command)) {
+                    Err(e) => reply.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::PutDeviceUsages { proposal_id, reply };
+                        self.proposals.push_back(proposal);
+                    }
+                }
+            }
+            Request::ListDeviceUsages { reply } => {
+                reply.exit(Ok(self.device_usages.values().cloned().collect()));
+            }
+            Request::PutFailureDomain { domain, reply } => {
+                if let Err(e) = track!(self.check_config_extensions()) {
+                    reply.exit(Err(e));
+                    return Ok(());
+                }
+                let command = Command::PutFailureDomain { domain };
+                match track!(self.propose_command(command)) {
+                    Err(e) => reply.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::PutFailureDomain { proposal_id, reply };
+                        self.proposals.push_back(proposal);
+                    }
+                }
+            }
+            Request::ListFailureDomains { reply } => {
+                reply.exit(Ok(self.failure_domains.values().cloned().collect()));
+            }
+            Request::PutBucketAttributes { attributes, reply } => {
+                if !self.buckets.contains_key(&attributes.bucket_id) {
+                    let e = ErrorKind::InvalidInput
+                        .cause(format!("No such bucket: {:?}", attributes.bucket_id));
+                    reply.exit(Err(track!(Error::from(e))));
+                    return Ok(());
+                }
+                if let Err(e) = track!(self.check_config_extensions())
+                    .and_then(|()| track!(attributes.validate()))
+                    .and_then(|()| track!(self.check_erasure_coding(&attributes)))
+                    .and_then(|()| track!(self.check_mds_witnesses(&attributes)))
+                {
+                    reply.exit(Err(e));
+                    return Ok(());
+                }
+                let command = Command::PutBucketAttributes { attributes };
+                match track!(self.propose_command(command)) {
+                    Err(e) => reply.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::PutBucketAttributes { proposal_id, reply };
+                        self.proposals.push_back(proposal);
+                    }
+                }
+            }
+            Request::ListBucketAttributes { reply } => {
+                reply.exit(Ok(self.bucket_attributes.values().cloned().collect()));
+            }
+            Request::PutMaintenanceWindows { windows, reply } => {
+                if let Err(e) = track!(self.check_maintenance_windows())
+                    .and_then(|()| track!(windows.validate()))
+                {
+                    reply.exit(Err(e));
+                    return Ok(());
+                }
+                let command = Command::PutMaintenanceWindows { windows };
+                match track!(self.propose_command(command)) {
+                    Err(e) => reply.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::PutMaintenanceWindows { proposal_id, reply };
+                        self.proposals.push_back(proposal);
+                    }
+                }
+            }
+            Request::GetMaintenanceWindows { reply } => {
+                reply.exit(Ok(self.maintenance_windows.clone()));
+            }
+            Request::PutDeviceBaseline { baseline, reply } => {
+                if let Err(e) = track!(self.check_device_baselines()) {
+                    reply.exit(Err(e));
+                    return Ok(());
+                }
+                let command = Command::PutDeviceBaseline { baseline };
+                match track!(self.propose_command(command)) {
+                    Err(e) => reply.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::PutDeviceBaseline { proposal_id, reply };
+                        self.proposals.push_back(proposal);
+                    }
+                }
+            }
+            Request::ListDeviceBaselines { reply } => {
+                reply.exit(Ok(self.device_baselines.values().cloned().collect()));
+            }
+            Request::RelayoutBucket {
+                bucket_id,
+                params,
+                reply,
+            } => {
+                let bucket = match track!(self.relayouted_bucket(&bucket_id, &params)) {
+                    Err(e) => {
+                        reply.exit(Err(e));
+                        return Ok(());
+                    }
+                    Ok(bucket) => bucket,
+                };
+                let command = Command::RelayoutBucket { bucket };
+                match track!(self.propose_command(command)) {
+                    Err(e) => reply.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::RelayoutBucket { proposal_id, reply };
+                        self.proposals.push_back(proposal);
+                    }
+                }
+            }
+            Request::FinishSegmentRelayout { segment, reply } => {
+                let command = Command::FinishSegmentRelayout { segment };
+                match track!(self.propose_command(command)) {
+                    Err(e) => reply.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::FinishSegmentRelayout { proposal_id, reply };
+                        self.proposals.push_back(proposal);
+                    }
+                }
+            }
+            Request::ListBucketRelayouts { reply } => {
+                reply.exit(Ok(self.relayouts.values().cloned().collect()));
+            }
+            Request::DecommissionDevices { device_ids, reply } => {
+                if let Err(e) = track!(self.check_decommission(&device_ids)) {
+                    reply.exit(Err(e));
+                    return Ok(());
+                }
+                let command = Command::DecommissionDevices { device_ids };
+                match track!(self.propose_command(command)) {
+                    Err(e) => reply.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::DecommissionDevices { proposal_id, reply };
+                        self.proposals.push_back(proposal);
+                    }
+                }
+            }
+            Request::ListDecommissions { reply } => {
+                reply.exit(Ok(decommission::statuses(
+                    &self.decommissioned,
+                    &self.devices,
+                    &self.segment_tables,
+                )));
+            }
+            Request::PlanRebalance { options, reply } => {
+                reply.exit(Ok(self.plan_rebalance(&options)));
+            }
+            Request::StartRebalance {
+                options,
+                max_relayouts,
+                reply,
+            } => {
+                let rebalance = match track!(self.start_rebalance(&options, max_relayouts)) {
+                    Err(e) => {
+                        reply.exit(Err(e));
+                        return Ok(());
+                    }
+                    Ok(None) => {
+                        reply.exit(Ok(None));
+                        return Ok(());
+                    }
+                    Ok(Some(rebalance)) => rebalance,
+                };
+                let command = Command::RebalanceBucket { rebalance };
+                match track!(self.propose_command(command)) {
+                    Err(e) => reply.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::RebalanceBucket { proposal_id, reply };
+                        self.proposals.push_back(proposal);
+                    }
+                }
+            }
+            Request::PlanChange { change, reply } => {
+                let devices = self.placeable_devices();
+                let state = ClusterState {
+                    buckets: &self.buckets,
+                    devices: &devices,
+                    servers: &self.servers,
+                    segment_tables: &self.segment_tables,
+                    usages: &self.device_usages,
+                    failure_domains: &self.failure_domains,
+                    next_seqno: &self.next_seqno,
+                };
+                reply.exit(track!(simulation::plan(&state, change)));
+            }
+        }
+        Ok(())
+    }
+    fn check_erasure_coding(&self, attributes: &BucketAttributes) -> Result<()> {
+        if attributes.erasure_coding.is_none() {
+            return Ok(());
         }
+        track_assert!(
+            cluster_feature::is_enabled(ClusterFeature::BucketErasureCoding),
+            ErrorKind::InvalidInput,
+            "The cluster feature {:?} is not enabled",
+            ClusterFeature::BucketErasureCoding.name()
+        );
+        let is_dispersed = match self.buckets.get(&attributes.bucket_id) {
+            Some(Bucket::Dispersed(_)) => true,
+            _ => false,
+        };
+        track_assert!(
+            is_dispersed,
+            ErrorKind::InvalidInput,
+            "The erasure coding can be specified only for dispersed buckets: {:?}",
+            attributes.bucket_id
+        );
+        Ok(())
+    }
+    // 古いバージョンのサーバは、デバイスの使用量や障害ドメイン、バケツの属性のコマンドをデコードできない
+    fn check_config_extensions(&self) -> Result<()> {
+        track_assert!(
+            cluster_feature::is_enabled(ClusterFeature::ConfigExtensions),
+            ErrorKind::InvalidInput,
+            "The cluster feature {:?} is not enabled",
+            ClusterFeature::ConfigExtensions.name()
+        );
+        Ok(())
+    }
+    fn check_maintenance_windows(&self) -> Result<()> {
+        track_assert!(
+            cluster_feature::is_enabled(ClusterFeature::MaintenanceWindows),
+            ErrorKind::InvalidInput,
+            "The cluster feature {:?} is not enabled",
+            ClusterFeature::MaintenanceWindows.name()
+        );
         Ok(())
     }
+    fn check_device_baselines(&self) -> Result<()> {
+        track_assert!(
+            cluster_feature::is_enabled(ClusterFeature::DeviceBaselines),
+            ErrorKind::InvalidInput,
+            "The cluster feature {:?} is not enabled",
+            ClusterFeature::DeviceBaselines.name()
+        );
+        Ok(())
+    }
+    fn check_mds_witnesses(&self, attributes: &BucketAttributes) -> Result<()> {
+        let current = self
+            .bucket_attributes
+            .get(&attributes.bucket_id)
+            .map_or(0, |a| a.mds_witnesses);
+        if attributes.mds_witnesses == current {
+            return Ok(());
+        }
+        track_assert!(
+            cluster_feature::is_enabled(ClusterFeature::MdsWitness),
+            ErrorKind::InvalidInput,
+            "The cluster feature {:?} is not enabled",
+            ClusterFeature::MdsWitness.name()
+        );
+        Ok(())
+    }
+    fn check_decommission(&self, device_ids: &[DeviceId]) -> Result<()> {
+        for &feature in &[ClusterFeature::Relayout, ClusterFeature::Decommission] {
+            track_assert!(
+                cluster_feature::is_enabled(feature),
+                ErrorKind::InvalidInput,
+                "The cluster feature {:?} is not enabled",
+                feature.name()
+            );
+        }
+        track!(self.validate_decommission(device_ids))
+    }
+    #[allow(clippy::ptr_arg)]
+    fn relayouted_bucket(
+        &self,
+        bucket_id: &BucketId,
+        params: &RelayoutParameters,
+    ) -> Result<Bucket> {
+        track_assert!(
+            cluster_feature::is_enabled(ClusterFeature::Relayout),
+            ErrorKind::InvalidInput,
+            "The cluster feature {:?} is not enabled",
+            ClusterFeature::Relayout.name()
+        );
+        track_assert!(
+            !self.relayouts.contains_key(bucket_id),
+            ErrorKind::InvalidInput,
+            "The bucket is already being relayouted: {:?}",
+            bucket_id
+        );
+        let current = track_assert_some!(
+            self.buckets.get(bucket_id),
+            ErrorKind::InvalidInput,
+            "No such bucket: {:?}",
+            bucket_id
+        );
+        let bucket = track!(relayout::relayout_bucket(current, params))?;
+        track!(topology::validate_bucket(
+            &bucket,
+            &self.placeable_devices(),
+            &self.failure_domains
+        ))?;
+        Ok(bucket)
+    }
     fn propose_command(&mut self, command: Command) -> Result<ProposalId> {
         info!(self.logger, "Propose: {}", dump!(command));
         let command = track!(protobuf::command_encoder().encode_into_bytes(command))?;
@@ -639,16 +1751,34 @@ impl Service {
         // TODO: 登録数が多くなるとそこそこ時間が掛かる可能性があるので
         //       別スレッドで実行した方が良いかもしれない.
 
-        let _old_table = self
+        if self.relayouts.contains_key(bucket_id) {
+            // NOTE: 再配置中のセグメントテーブルは、再配置の完了まで変更しない
+            warn!(
+                self.logger,
+                "The bucket is being relayouted; its segment table is not updated: {:?}", bucket_id
+            );
+            return;
+        }
+        let old_table = self
             .segment_tables
             .remove(bucket_id)
             .unwrap_or_else(|| SegmentTable::new(bucket_id.clone()));
         {
             let bucket = &self.buckets[bucket_id];
-            let builder = SegmentTableBuilder::new(&self.devices);
+            let devices = self.placeable_devices();
+            let builder = SegmentTableBuilder::new(&devices)
+                .usages(&self.device_usages)
+                .failure_domains(&self.failure_domains);
 
             // TODO: error handling
-            let new_table = track_try_unwrap!(builder.build(bucket));
+            let mut new_table = track_try_unwrap!(builder.build(bucket));
+
+            // 既存のセグメントの世代は引き継ぐ
+            for (old, new) in old_table.segments.iter().zip(new_table.segments.iter_mut()) {
+                if let (Some(old), Some(new)) = (old.groups.get(0), new.groups.get_mut(0)) {
+                    new.generation = old.generation;
+                }
+            }
 
             // TODO: 新旧の差分を取って云々
             for (segment_no, segment) in new_table.segments.iter().enumerate() {
@@ -728,6 +1858,9 @@ pub enum Event {
         segment_no: u16,
         groups: Vec<DeviceGroup>,
     },
+    PutBucketAttributes(BucketAttributes),
+    PutBucketRelayout(BucketRelayout),
+    PutMaintenanceWindows(MaintenanceWindows),
 }
 
 #[derive(Debug)]
@@ -780,6 +1913,76 @@ enum Request {
         id: BucketId,
         reply: Reply<Option<Bucket>>,
     },
+    ExportBackup {
+        reply: Reply<ConfigBackup>,
+    },
+    PlanRebalance {
+        options: RebalanceOptions,
+        reply: Reply<RebalancePlan>,
+    },
+    StartRebalance {
+        options: RebalanceOptions,
+        max_relayouts: usize,
+        reply: Reply<Option<BucketRebalance>>,
+    },
+    PlanChange {
+        change: ProposedChange,
+        reply: Reply<ChangePlan>,
+    },
+    PutDeviceUsages {
+        usages: Vec<DeviceUsage>,
+        reply: Reply<()>,
+    },
+    ListDeviceUsages {
+        reply: Reply<Vec<DeviceUsage>>,
+    },
+    PutFailureDomain {
+        domain: FailureDomain,
+        reply: Reply<FailureDomain>,
+    },
+    ListFailureDomains {
+        reply: Reply<Vec<FailureDomain>>,
+    },
+    PutBucketAttributes {
+        attributes: BucketAttributes,
+        reply: Reply<BucketAttributes>,
+    },
+    ListBucketAttributes {
+        reply: Reply<Vec<BucketAttributes>>,
+    },
+    PutMaintenanceWindows {
+        windows: MaintenanceWindows,
+        reply: Reply<MaintenanceWindows>,
+    },
+    GetMaintenanceWindows {
+        reply: Reply<MaintenanceWindows>,
+    },
+    PutDeviceBaseline {
+        baseline: DeviceBaseline,
+        reply: Reply<DeviceBaseline>,
+    },
+    ListDeviceBaselines {
+        reply: Reply<Vec<DeviceBaseline>>,
+    },
+    RelayoutBucket {
+        bucket_id: BucketId,
+        params: RelayoutParameters,
+        reply: Reply<BucketRelayout>,
+    },
+    FinishSegmentRelayout {
+        segment: RelayoutedSegment,
+        reply: Reply<()>,
+    },
+    ListBucketRelayouts {
+        reply: Reply<Vec<BucketRelayout>>,
+    },
+    DecommissionDevices {
+        device_ids: Vec<DeviceId>,
+        reply: Reply<Vec<DeviceDecommission>>,
+    },
+    ListDecommissions {
+        reply: Reply<Vec<DeviceDecommission>>,
+    },
 }
 type Reply<T> = oneshot::Monitored<T, Error>;
 
@@ -809,6 +2012,42 @@ enum Proposal {
         proposal_id: ProposalId,
         reply: Reply<Option<Bucket>>,
     },
+    PutDeviceUsages {
+        proposal_id: ProposalId,
+        reply: Reply<()>,
+    },
+    PutFailureDomain {
+        proposal_id: ProposalId,
+        reply: Reply<FailureDomain>,
+    },
+    PutBucketAttributes {
+        proposal_id: ProposalId,
+        reply: Reply<BucketAttributes>,
+    },
+    RelayoutBucket {
+        proposal_id: ProposalId,
+        reply: Reply<BucketRelayout>,
+    },
+    FinishSegmentRelayout {
+        proposal_id: ProposalId,
+        reply: Reply<()>,
+    },
+    DecommissionDevices {
+        proposal_id: ProposalId,
+        reply: Reply<Vec<DeviceDecommission>>,
+    },
+    RebalanceBucket {
+        proposal_id: ProposalId,
+        reply: Reply<Option<BucketRebalance>>,
+    },
+    PutMaintenanceWindows {
+        proposal_id: ProposalId,
+        reply: Reply<MaintenanceWindows>,
+    },
+    PutDeviceBaseline {
+        proposal_id: ProposalId,
+        reply: Reply<DeviceBaseline>,
+    },
 }
 impl Proposal {
     pub fn id(&self) -> ProposalId {
@@ -819,6 +2058,15 @@ impl Proposal {
             Proposal::DeleteDevice { proposal_id, .. } => proposal_id,
             Proposal::PutBucket { proposal_id, .. } => proposal_id,
             Proposal::DeleteBucket { proposal_id, .. } => proposal_id,
+            Proposal::PutDeviceUsages { proposal_id, .. } => proposal_id,
+            Proposal::PutFailureDomain { proposal_id, .. } => proposal_id,
+            Proposal::PutBucketAttributes { proposal_id, .. } => proposal_id,
+            Proposal::RelayoutBucket { proposal_id, .. } => proposal_id,
+            Proposal::FinishSegmentRelayout { proposal_id, .. } => proposal_id,
+            Proposal::DecommissionDevices { proposal_id, .. } => proposal_id,
+            Proposal::RebalanceBucket { proposal_id, .. } => proposal_id,
+            Proposal::PutMaintenanceWindows { proposal_id, .. } => proposal_id,
+            Proposal::PutDeviceBaseline { proposal_id, .. } => proposal_id,
         }
     }
 }
@@ -948,4 +2196,230 @@ impl ServiceHandle {
         let _ = self.request_tx.send(request);
         response
     }
+
+    /// クラスタの現在の状態をバックアップとして取得する。
+    ///
+    /// ローカルノードがリーダでない場合には`ErrorKind::NotLeader`エラーとなる。
+    pub fn export_backup(&self) -> impl Future<Item = ConfigBackup, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::ExportBackup { reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// セグメントの配置の偏りを均すための移動計画を作成する。
+    ///
+    /// 計画は報告されるだけで、実際の移動は行われない(`rebalance`モジュールのドキュメントを参照)。
+    pub fn plan_rebalance(
+        &self,
+        options: RebalanceOptions,
+    ) -> impl Future<Item = RebalancePlan, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::PlanRebalance { options, reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// 移動計画のうち、一つのバケツ分の移動を実際に開始する。
+    ///
+    /// 移動は再配置として非同期に進められ、その進捗は`list_bucket_relayouts`で確認できる。
+    /// ローカルノードがリーダでない場合や、再配置中のバケツが`max_relayouts`個以上ある場合、
+    /// 移動の必要なバケツがない場合には、何もせずに`None`を返す。
+    pub fn start_rebalance(
+        &self,
+        options: RebalanceOptions,
+        max_relayouts: usize,
+    ) -> impl Future<Item = Option<BucketRebalance>, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::StartRebalance {
+            options,
+            max_relayouts,
+            reply,
+        };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// 構成の変更を実際には適用せずに、その影響を見積もる。
+    ///
+    /// 見積もりの方法は`simulation`モジュールのドキュメントを参照。
+    pub fn plan_change(
+        &self,
+        change: ProposedChange,
+    ) -> impl Future<Item = ChangePlan, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::PlanChange { change, reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// デバイスの使用量を登録する。
+    ///
+    /// 登録された使用量は、以降に構築されるセグメントテーブルでの配置先の選択に使われる。
+    pub fn put_device_usages(
+        &self,
+        usages: Vec<DeviceUsage>,
+    ) -> impl Future<Item = (), Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::PutDeviceUsages { usages, reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// 登録済みのデバイスの使用量の一覧を返す。
+    pub fn list_device_usages(&self) -> impl Future<Item = Vec<DeviceUsage>, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::ListDeviceUsages { reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// サーバの障害ドメインを設定する。
+    ///
+    /// ゾーンとラックの両方が未設定の場合には、そのサーバの障害ドメインは削除される。
+    /// 設定された障害ドメインは、以降に構築されるセグメントテーブルでの配置先の選択に使われる。
+    pub fn put_failure_domain(
+        &self,
+        domain: FailureDomain,
+    ) -> impl Future<Item = FailureDomain, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::PutFailureDomain { domain, reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// 設定済みの障害ドメインの一覧を返す。
+    pub fn list_failure_domains(&self) -> impl Future<Item = Vec<FailureDomain>, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::ListFailureDomains { reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// バケツの属性を設定する。
+    ///
+    /// 全ての属性が既定値の場合には、そのバケツの属性の設定は削除される。
+    pub fn put_bucket_attributes(
+        &self,
+        attributes: BucketAttributes,
+    ) -> impl Future<Item = BucketAttributes, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::PutBucketAttributes { attributes, reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// 設定済みのバケツの属性の一覧を返す。
+    pub fn list_bucket_attributes(
+        &self,
+    ) -> impl Future<Item = Vec<BucketAttributes>, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::ListBucketAttributes { reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// メンテナンスウィンドウを設定する。
+    ///
+    /// 設定は全てのサーバで共有され、各サーバのバックグラウンド処理に即座に反映される。
+    pub fn put_maintenance_windows(
+        &self,
+        windows: MaintenanceWindows,
+    ) -> impl Future<Item = MaintenanceWindows, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::PutMaintenanceWindows { windows, reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// 設定済みのメンテナンスウィンドウを返す。
+    pub fn get_maintenance_windows(&self) -> impl Future<Item = MaintenanceWindows, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::GetMaintenanceWindows { reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// デバイスの作成時に測定された性能の基準値を登録する。
+    ///
+    /// 閾値を満たさなかったデバイスは、以降に構築されるセグメントテーブルで配置先から除外される。
+    pub fn put_device_baseline(
+        &self,
+        baseline: DeviceBaseline,
+    ) -> impl Future<Item = DeviceBaseline, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::PutDeviceBaseline { baseline, reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// 登録済みのデバイスの性能の基準値の一覧を返す。
+    pub fn list_device_baselines(&self) -> impl Future<Item = Vec<DeviceBaseline>, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::ListDeviceBaselines { reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// バケツの再配置を開始する。
+    ///
+    /// バケツの ID やバージョン、メタデータは維持されたまま、各セグメントが`params`に従った新しい配置に移される。
+    /// 結果のフューチャは、再配置が開始された時点で完了する(進捗は`list_bucket_relayouts`で確認できる)。
+    pub fn relayout_bucket(
+        &self,
+        bucket_id: BucketId,
+        params: RelayoutParameters,
+    ) -> impl Future<Item = BucketRelayout, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::RelayoutBucket {
+            bucket_id,
+            params,
+            reply,
+        };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// セグメントの再配置の完了を記録し、セグメントの構成を新しい配置のみにする。
+    ///
+    /// 既に完了済みのセグメントに対しては何もしない。
+    pub fn finish_segment_relayout(
+        &self,
+        segment: RelayoutedSegment,
+    ) -> impl Future<Item = (), Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::FinishSegmentRelayout { segment, reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// 実行中のバケツの再配置の一覧を返す。
+    pub fn list_bucket_relayouts(&self) -> impl Future<Item = Vec<BucketRelayout>, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::ListBucketRelayouts { reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// デバイスの退役を開始する。
+    ///
+    /// 以降、`device_ids`のデバイスは配置先から除外され、それらをメンバに含むセグメントは再配置される。
+    /// 結果のフューチャは、再配置が開始された時点で完了する(進捗は`list_decommissions`で確認できる)。
+    pub fn decommission_devices(
+        &self,
+        device_ids: Vec<DeviceId>,
+    ) -> impl Future<Item = Vec<DeviceDecommission>, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::DecommissionDevices { device_ids, reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
+
+    /// 退役中のデバイスの状態の一覧を返す。
+    pub fn list_decommissions(&self) -> impl Future<Item = Vec<DeviceDecommission>, Error = Error> {
+        let (reply, response) = Response::new();
+        let request = Request::ListDecommissions { reply };
+        let _ = self.request_tx.send(request);
+        response
+    }
 }
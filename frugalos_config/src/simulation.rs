@@ -0,0 +1,552 @@
+//! 構成の変更を実際には適用せずに、その影響を見積もるためのモジュール。
+//!
+//! 提案された変更(サーバ・デバイス・バケツの追加や削除)を現在の構成の複製に適用し、
+//! 影響を受けるバケツのセグメントテーブルを構築し直して、現在のテーブルと比較する。
+//! その結果として、移動するセグメントのメンバ、再複製が必要となるデータ量の見積もり、
+//! および変更後もバケツの配置の制約(e.g., EC の断片を異なるデバイスに分散できること)を満たせるかどうかを報告する。
+//!
+//! NOTE: https://github.com/frugalos/frugalos/issues/208 のため、現状では既存のデバイスの更新はクラスタに拒否される。
+//! シミュレーション上は更新を許すが、その旨を警告として報告する。
+use libfrugalos::entity::bucket::{Bucket, BucketId};
+use libfrugalos::entity::device::{Device, DeviceId};
+use libfrugalos::entity::server::{Server, ServerId};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use builder::SegmentTableBuilder;
+use machine::{NextSeqNo, SegmentTable};
+use rebalance::SegmentMove;
+use topology::{self, FailureDomain};
+use usage::DeviceUsage;
+use {ErrorKind, Result};
+
+/// 提案された構成の変更。
+///
+/// 変更は、サーバの追加、デバイスの追加、バケツの追加、バケツの削除、デバイスの削除、サーバの削除、の順に適用される。
+/// 各一覧の中では、記載された順に適用される。
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProposedChange {
+    /// 追加(または更新)するサーバの一覧。
+    #[serde(default)]
+    pub put_servers: Vec<Server>,
+
+    /// 削除するサーバの ID の一覧。
+    #[serde(default)]
+    pub delete_servers: Vec<ServerId>,
+
+    /// 追加(または更新)するデバイスの一覧。
+    ///
+    /// 通し番号は無視され、適用時に採番し直される。
+    #[serde(default)]
+    pub put_devices: Vec<Device>,
+
+    /// 削除するデバイスの ID の一覧。
+    #[serde(default)]
+    pub delete_devices: Vec<DeviceId>,
+
+    /// 追加するバケツの一覧。
+    ///
+    /// 通し番号とセグメント数は、実際の追加時と同様に決定される。
+    #[serde(default)]
+    pub put_buckets: Vec<Bucket>,
+
+    /// 削除するバケツの ID の一覧。
+    #[serde(default)]
+    pub delete_buckets: Vec<BucketId>,
+}
+
+/// 構成の変更の影響の見積もり。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangePlan {
+    /// 影響を受ける全てのバケツが、変更後も配置の制約を満たせる場合に`true`となる。
+    pub satisfiable: bool,
+
+    /// 移動するメンバの総数。
+    pub moved_slots: usize,
+
+    /// 再複製が必要となるデータ量の見積もり(バイト単位)の総和。
+    pub moved_bytes: u64,
+
+    /// 影響を受けるバケツ毎の見積もり。
+    pub buckets: Vec<BucketChangePlan>,
+
+    /// 削除されるバケツの ID の一覧。
+    pub deleted_buckets: Vec<BucketId>,
+
+    /// 見積もりの前提に関する警告の一覧。
+    pub warnings: Vec<String>,
+}
+
+/// バケツ単位の影響の見積もり。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BucketChangePlan {
+    /// バケツの ID。
+    pub bucket_id: BucketId,
+
+    /// 変更によって新たに追加されるバケツの場合に`true`となる。
+    pub is_new: bool,
+
+    /// 変更後もセグメントテーブルを構築できる場合に`true`となる。
+    pub satisfiable: bool,
+
+    /// セグメントテーブルを構築できない理由。
+    pub error: Option<String>,
+
+    /// 変更後のバケツ全体の割当数(セグメント数とメンバ数の積)。
+    pub slots: usize,
+
+    /// 移動するセグメントのメンバの一覧。
+    pub moves: Vec<SegmentMove>,
+
+    /// 再複製が必要となるデータ量の見積もり(バイト単位)。
+    ///
+    /// 移動元のデバイスの使用量を、そのデバイスへの割当数で等分した値の和。
+    /// 使用量が報告されていないデバイスからの移動は`0`として数える。
+    pub moved_bytes: u64,
+}
+
+/// シミュレーションの基点となる、現在の構成。
+#[derive(Debug)]
+pub(crate) struct ClusterState<'a> {
+    pub buckets: &'a BTreeMap<BucketId, Bucket>,
+    pub devices: &'a BTreeMap<DeviceId, Device>,
+    pub servers: &'a BTreeMap<ServerId, Server>,
+    pub segment_tables: &'a BTreeMap<BucketId, SegmentTable>,
+    pub usages: &'a BTreeMap<DeviceId, DeviceUsage>,
+    pub failure_domains: &'a BTreeMap<ServerId, FailureDomain>,
+    pub next_seqno: &'a NextSeqNo,
+}
+
+/// 提案された変更を現在の構成の複製に適用し、その影響を見積もる。
+///
+/// 変更自体が不正な場合(e.g., 未定義のサーバを参照するデバイスの追加)には`ErrorKind::InvalidInput`エラーとなる。
+pub(crate) fn plan(state: &ClusterState, change: ProposedChange) -> Result<ChangePlan> {
+    let mut buckets = state.buckets.clone();
+    let mut devices = state.devices.clone();
+    let mut servers = state.servers.clone();
+    let mut next_seqno = state.next_seqno.clone();
+    let mut changed_devices = BTreeSet::new();
+    let mut new_buckets = BTreeSet::new();
+    let mut warnings = Vec::new();
+
+    for server in change.put_servers {
+        servers.insert(server.id.clone(), server);
+    }
+    for mut device in change.put_devices {
+        if let Some(server) = device.server() {
+            track_assert!(
+                servers.contains_key(server),
+                ErrorKind::InvalidInput,
+                "The device {:?} refers to undefined server: {:?}",
+                device.id(),
+                server
+            );
+        }
+        if let Device::Virtual(ref d) = device {
+            for c in &d.children {
+                track_assert!(
+                    devices.contains_key(c),
+                    ErrorKind::InvalidInput,
+                    "The virtual device {:?} includes unknown device as a child: {:?}",
+                    d.id,
+                    c
+                );
+            }
+        }
+        if let Some(old) = devices.get(device.id()) {
+            warnings.push(format!(
+                "Updating the existing device {:?} is currently rejected by the cluster \
+                 (see https://github.com/frugalos/frugalos/issues/208)",
+                device.id()
+            ));
+            device.set_seqno(old.seqno());
+        } else {
+            device.set_seqno(next_seqno.device);
+            next_seqno.device += 1;
+        }
+        changed_devices.insert(device.id().clone());
+        devices.insert(device.id().clone(), device);
+    }
+    for mut bucket in change.put_buckets {
+        track_assert!(
+            !buckets.contains_key(bucket.id()),
+            ErrorKind::InvalidInput,
+            "Cannot update the existing bucket: {:?}",
+            bucket.id()
+        );
+        track_assert!(
+            devices.contains_key(bucket.device()),
+            ErrorKind::InvalidInput,
+            "The bucket {:?} refers to undefined device: {:?}",
+            bucket.id(),
+            bucket.device()
+        );
+        bucket.fix_segment_count(devices.len()); // `Service::handle_put_bucket`に合わせる
+        bucket.set_seqno(next_seqno.bucket);
+        next_seqno.bucket += 1;
+        new_buckets.insert(bucket.id().clone());
+        buckets.insert(bucket.id().clone(), bucket);
+    }
+    let mut deleted_buckets = Vec::new();
+    for id in change.delete_buckets {
+        track_assert!(
+            buckets.remove(&id).is_some(),
+            ErrorKind::InvalidInput,
+            "No such bucket: {:?}",
+            id
+        );
+        new_buckets.remove(&id);
+        if state.buckets.contains_key(&id) {
+            deleted_buckets.push(id);
+        }
+    }
+    for id in change.delete_devices {
+        track_assert!(
+            devices.contains_key(&id),
+            ErrorKind::InvalidInput,
+            "No such device: {:?}",
+            id
+        );
+        let referrer = buckets.values().find(|b| {
+            let mut tree = BTreeSet::new();
+            collect_tree(&devices, b.device(), &mut tree);
+            tree.contains(&id)
+        });
+        if let Some(b) = referrer {
+            track_panic!(
+                ErrorKind::InvalidInput,
+                "The device {:?} is referred to by the bucket {:?}",
+                id,
+                b.id()
+            );
+        }
+        devices.remove(&id);
+        changed_devices.insert(id);
+    }
+    for id in change.delete_servers {
+        track_assert!(
+            servers.remove(&id).is_some(),
+            ErrorKind::InvalidInput,
+            "No such server: {:?}",
+            id
+        );
+        let referrer = devices.values().find(|d| d.server() == Some(&id));
+        if let Some(d) = referrer {
+            track_panic!(
+                ErrorKind::InvalidInput,
+                "The server {:?} is referred to by the device {:?}",
+                id,
+                d.id()
+            );
+        }
+    }
+
+    let old_ids = seqno_to_id(state.devices);
+    let new_ids = seqno_to_id(&devices);
+    let slot_bytes = estimate_slot_bytes(state, &old_ids);
+    let builder = SegmentTableBuilder::new(&devices)
+        .usages(state.usages)
+        .failure_domains(state.failure_domains);
+    let mut plans = Vec::new();
+    for bucket in buckets.values() {
+        let is_new = new_buckets.contains(bucket.id());
+        let mut tree = BTreeSet::new();
+        collect_tree(&devices, bucket.device(), &mut tree);
+        if !is_new && tree.is_disjoint(&changed_devices) {
+            continue;
+        }
+
+        let slots = bucket.segment_count() as usize * bucket.device_group_size() as usize;
+        let built = track!(topology::validate_bucket(
+            bucket,
+            &devices,
+            state.failure_domains
+        ))
+        .and_then(|()| track!(builder.build(bucket)));
+        let table = match built {
+            Err(e) => {
+                plans.push(BucketChangePlan {
+                    bucket_id: bucket.id().clone(),
+                    is_new,
+                    satisfiable: false,
+                    error: Some(e.to_string()),
+                    slots,
+                    moves: Vec::new(),
+                    moved_bytes: 0,
+                });
+                continue;
+            }
+            Ok(table) => table,
+        };
+        let moves = match state.segment_tables.get(bucket.id()) {
+            Some(current) if !is_new => {
+                diff_members(&members(current, &old_ids), &members(&table, &new_ids))
+            }
+            _ => Vec::new(),
+        };
+        let moved_bytes = moves
+            .iter()
+            .map(|m| slot_bytes.get(&m.from).cloned().unwrap_or(0))
+            .sum();
+        plans.push(BucketChangePlan {
+            bucket_id: bucket.id().clone(),
+            is_new,
+            satisfiable: true,
+            error: None,
+            slots,
+            moves,
+            moved_bytes,
+        });
+    }
+
+    Ok(ChangePlan {
+        satisfiable: plans.iter().all(|b| b.satisfiable),
+        moved_slots: plans.iter().map(|b| b.moves.len()).sum(),
+        moved_bytes: plans.iter().map(|b| b.moved_bytes).sum(),
+        buckets: plans,
+        deleted_buckets,
+        warnings,
+    })
+}
+
+/// セグメント毎に、その位置が変わったメンバを列挙する。
+///
+/// メンバの位置は断片の番号に対応するため、同じデバイスが別の位置に移った場合も移動として扱う。
+fn diff_members(current: &[Vec<DeviceId>], planned: &[Vec<DeviceId>]) -> Vec<SegmentMove> {
+    let mut moves = Vec::new();
+    for (segment, (c, p)) in current.iter().zip(planned.iter()).enumerate() {
+        for (from, to) in c.iter().zip(p.iter()) {
+            if from != to {
+                moves.push(SegmentMove {
+                    segment: segment as u16,
+                    from: from.clone(),
+                    to: to.clone(),
+                });
+            }
+        }
+    }
+    moves
+}
+
+fn members(table: &SegmentTable, ids: &HashMap<u32, DeviceId>) -> Vec<Vec<DeviceId>> {
+    table
+        .segments
+        .iter()
+        .map(|s| {
+            s.groups
+                .iter()
+                .flat_map(|g| g.members.iter())
+                .filter_map(|seqno| ids.get(seqno).cloned())
+                .collect()
+        })
+        .collect()
+}
+
+/// デバイス毎に、一つの割当あたりのデータ量を見積もる。
+fn estimate_slot_bytes(
+    state: &ClusterState,
+    ids: &HashMap<u32, DeviceId>,
+) -> HashMap<DeviceId, u64> {
+    let mut slots = HashMap::new();
+    for table in state.segment_tables.values() {
+        for id in members(table, ids).into_iter().flatten() {
+            *slots.entry(id).or_insert(0u64) += 1;
+        }
+    }
+    slots
+        .into_iter()
+        .filter_map(|(id, n)| {
+            let usage = state.usages.get(&id)?;
+            Some((id, usage.usage_bytes / n))
+        })
+        .collect()
+}
+
+fn seqno_to_id(devices: &BTreeMap<DeviceId, Device>) -> HashMap<u32, DeviceId> {
+    devices
+        .values()
+        .map(|d| (d.seqno(), d.id().clone()))
+        .collect()
+}
+
+/// `id`をルートとする木に含まれる全てのデバイス(仮想デバイスを含む)を集める。
+fn collect_tree(
+    devices: &BTreeMap<DeviceId, Device>,
+    id: &DeviceId,
+    tree: &mut BTreeSet<DeviceId>,
+) {
+    if !tree.insert(id.clone()) {
+        return;
+    }
+    if let Some(Device::Virtual(d)) = devices.get(id) {
+        for c in &d.children {
+            collect_tree(devices, c, tree);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libfrugalos::entity::bucket::DispersedBucket;
+    use libfrugalos::entity::device::{MemoryDevice, SegmentAllocationPolicy, Weight};
+
+    use super::*;
+    use test_util::build_device_tree;
+
+    struct Fixture {
+        buckets: BTreeMap<BucketId, Bucket>,
+        devices: BTreeMap<DeviceId, Device>,
+        servers: BTreeMap<ServerId, Server>,
+        segment_tables: BTreeMap<BucketId, SegmentTable>,
+        usages: BTreeMap<DeviceId, DeviceUsage>,
+        failure_domains: BTreeMap<ServerId, FailureDomain>,
+        next_seqno: NextSeqNo,
+    }
+    impl Fixture {
+        fn new(policy: SegmentAllocationPolicy) -> (Self, DeviceId) {
+            let (devices, root) = build_device_tree(&[6], policy);
+            let bucket = bucket("foo", root.clone(), 4, 1);
+            let table = SegmentTableBuilder::new(&devices).build(&bucket).unwrap();
+            let usages = devices
+                .values()
+                .filter(|d| !d.is_virtual())
+                .map(|d| {
+                    let usage = DeviceUsage {
+                        device_id: d.id().clone(),
+                        capacity_bytes: 1 << 30,
+                        usage_bytes: 1000,
+                    };
+                    (d.id().clone(), usage)
+                })
+                .collect();
+            let server = Server::new("dummy".to_owned(), "127.0.0.1:14278".parse().unwrap());
+            let fixture = Fixture {
+                next_seqno: NextSeqNo {
+                    bucket: 1,
+                    device: devices.len() as u32,
+                    server: 1,
+                },
+                buckets: Some((bucket.id().clone(), bucket)).into_iter().collect(),
+                devices,
+                servers: Some((server.id.clone(), server)).into_iter().collect(),
+                segment_tables: Some(("foo".to_owned(), table)).into_iter().collect(),
+                usages,
+                failure_domains: BTreeMap::new(),
+            };
+            (fixture, root)
+        }
+
+        fn plan(&self, change: ProposedChange) -> Result<ChangePlan> {
+            let state = ClusterState {
+                buckets: &self.buckets,
+                devices: &self.devices,
+                servers: &self.servers,
+                segment_tables: &self.segment_tables,
+                usages: &self.usages,
+                failure_domains: &self.failure_domains,
+                next_seqno: &self.next_seqno,
+            };
+            plan(&state, change)
+        }
+    }
+
+    fn bucket(id: &str, root: DeviceId, data: u32, parity: u32) -> Bucket {
+        Bucket::Dispersed(DispersedBucket {
+            id: id.to_owned(),
+            seqno: 0,
+            device: root,
+            segment_count: 8,
+            tolerable_faults: parity,
+            data_fragment_count: data,
+        })
+    }
+
+    fn memory_device(id: &str) -> Device {
+        Device::Memory(MemoryDevice {
+            id: id.to_owned(),
+            seqno: 0,
+            weight: Weight::Auto,
+            server: "dummy".to_owned(),
+            capacity: 1 << 30,
+        })
+    }
+
+    #[test]
+    fn plan_reports_moves_to_added_devices() {
+        let (fixture, root) = Fixture::new(SegmentAllocationPolicy::ScatterIfPossible);
+        let mut new_root = fixture.devices[&root].clone();
+        if let Device::Virtual(ref mut d) = new_root {
+            d.children.insert("new0".to_owned());
+            d.children.insert("new1".to_owned());
+        }
+        let change = ProposedChange {
+            put_devices: vec![memory_device("new0"), memory_device("new1"), new_root],
+            ..Default::default()
+        };
+
+        let plan = fixture.plan(change).unwrap();
+        assert!(plan.satisfiable);
+        assert_eq!(plan.warnings.len(), 1); // ルートデバイスの更新
+        assert_eq!(plan.buckets.len(), 1);
+        let b = &plan.buckets[0];
+        assert!(!b.is_new);
+        assert_eq!(b.slots, 8 * 5);
+        assert!(!b.moves.is_empty());
+        assert!(b.moves.iter().all(|m| m.from != m.to));
+        assert_eq!(plan.moved_slots, b.moves.len());
+        assert!(plan.moved_bytes > 0);
+    }
+
+    #[test]
+    fn plan_reports_unsatisfiable_buckets() {
+        let (fixture, root) = Fixture::new(SegmentAllocationPolicy::Scatter);
+
+        // 6 デバイスには 4+1 は配置できるが 6+2 は配置できない
+        let change = ProposedChange {
+            put_buckets: vec![bucket("bar", root.clone(), 4, 1), bucket("baz", root, 6, 2)],
+            ..Default::default()
+        };
+        let plan = fixture.plan(change).unwrap();
+        assert!(!plan.satisfiable);
+        assert_eq!(plan.moved_slots, 0);
+        let results = plan
+            .buckets
+            .iter()
+            .map(|b| (&b.bucket_id[..], b.is_new, b.satisfiable))
+            .collect::<Vec<_>>();
+        assert_eq!(results, [("bar", true, true), ("baz", true, false)]);
+        assert!(plan.buckets[1].error.is_some());
+    }
+
+    #[test]
+    fn plan_rejects_invalid_changes() {
+        let (fixture, root) = Fixture::new(SegmentAllocationPolicy::ScatterIfPossible);
+
+        // バケツから参照されているデバイスは削除できない
+        let change = ProposedChange {
+            delete_devices: vec![root.clone()],
+            ..Default::default()
+        };
+        assert!(fixture.plan(change).is_err());
+
+        // バケツと一緒に削除する場合は問題ない
+        let change = ProposedChange {
+            delete_buckets: vec!["foo".to_owned()],
+            delete_devices: vec![root],
+            ..Default::default()
+        };
+        let plan = fixture.plan(change).unwrap();
+        assert_eq!(plan.deleted_buckets, ["foo"]);
+        assert!(plan.buckets.is_empty());
+
+        // 未定義のサーバは参照できない
+        let mut device = memory_device("new0");
+        if let Device::Memory(ref mut d) = device {
+            d.server = "unknown".to_owned();
+        }
+        let change = ProposedChange {
+            put_devices: vec![device],
+            ..Default::default()
+        };
+        assert!(fixture.plan(change).is_err());
+    }
+}
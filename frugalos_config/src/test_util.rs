@@ -15,6 +15,15 @@ pub(crate) fn build_device_tree(
     (result, root_device_id)
 }
 
+/// Assigns a distinct server `server{seqno}` to each leaf device.
+pub(crate) fn assign_distinct_servers(devices: &mut BTreeMap<DeviceId, Device>) {
+    for device in devices.values_mut() {
+        if let Device::Memory(ref mut d) = *device {
+            d.server = format!("server{}", d.seqno);
+        }
+    }
+}
+
 fn build_device_tree_dfs<'a>(
     numbers_of_children: &[u32],
     policy: SegmentAllocationPolicy,
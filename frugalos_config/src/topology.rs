@@ -0,0 +1,192 @@
+//! サーバの障害ドメイン(ゾーンおよびラック)に関するモジュール。
+//!
+//! 障害ドメインは、サーバ毎にラベルとして構成管理用の Raft クラスタに登録される。
+//! いずれかのサーバに障害ドメインが設定されている場合、セグメントテーブルの構築時には、
+//! 同じセグメントのメンバができるだけ別々のゾーンおよびラックに配置されるようになる。
+//!
+//! ラックが設定されていないサーバは、そのサーバ単体で一つのラックを構成するものとして扱われる。
+//! また、ゾーンが設定されていないサーバは、全て同じ(無名の)ゾーンに属するものとして扱われる。
+use libfrugalos::entity::bucket::Bucket;
+use libfrugalos::entity::device::{Device, DeviceId};
+use libfrugalos::entity::server::ServerId;
+use std::collections::{BTreeMap, BTreeSet};
+
+use {ErrorKind, Result};
+
+/// サーバの障害ドメイン。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailureDomain {
+    /// サーバの ID。
+    pub server_id: ServerId,
+
+    /// サーバが属するゾーン。
+    pub zone: Option<String>,
+
+    /// サーバが属するラック。
+    pub rack: Option<String>,
+}
+impl FailureDomain {
+    /// ゾーンもラックも設定されていない場合に`true`を返す。
+    pub fn is_empty(&self) -> bool {
+        self.zone.is_none() && self.rack.is_none()
+    }
+}
+
+/// 配置の計算で使用する障害ドメインのキー(ゾーン、ラック)。
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct DomainKey {
+    pub zone: String,
+    pub rack: String,
+}
+impl DomainKey {
+    fn new(server_id: &ServerId, domain: Option<&FailureDomain>) -> Self {
+        let zone = domain.and_then(|d| d.zone.clone()).unwrap_or_default();
+        let rack = domain
+            .and_then(|d| d.rack.clone())
+            .unwrap_or_else(|| format!("server:{}", server_id));
+        DomainKey { zone, rack }
+    }
+}
+
+type FailureDomains = BTreeMap<ServerId, FailureDomain>;
+
+/// 物理デバイスが属する障害ドメインのキーを返す。
+///
+/// 仮想デバイスの場合には`None`が返される。
+pub(crate) fn domain_key(device: &Device, domains: &FailureDomains) -> Option<DomainKey> {
+    let server = device.server()?;
+    Some(DomainKey::new(server, domains.get(server)))
+}
+
+/// `root`配下の物理デバイスのいずれかに、障害ドメインが設定されているかどうかを判定する。
+pub(crate) fn is_labeled(
+    devices: &BTreeMap<DeviceId, Device>,
+    root: &DeviceId,
+    domains: &FailureDomains,
+) -> bool {
+    let mut leaves = Vec::new();
+    collect_leaves(devices, root, &mut leaves);
+    leaves
+        .iter()
+        .filter_map(|d| d.server())
+        .any(|s| domains.contains_key(s))
+}
+
+/// バケツの耐障害性の設定が、現在の障害ドメインの構成で満たせるかどうかを検証する。
+///
+/// 同じセグメントのメンバをラック間でできるだけ均等に配置した場合に、
+/// 一つのラックの故障で失われるメンバ数が、バケツの許容する故障数以下であることを確認する。
+/// 障害ドメインが一つも設定されていない場合には、検証は行わない。
+pub(crate) fn validate_bucket(
+    bucket: &Bucket,
+    devices: &BTreeMap<DeviceId, Device>,
+    domains: &FailureDomains,
+) -> Result<()> {
+    if !is_labeled(devices, bucket.device(), domains) {
+        return Ok(());
+    }
+    let mut leaves = Vec::new();
+    collect_leaves(devices, bucket.device(), &mut leaves);
+    let racks = leaves
+        .iter()
+        .filter_map(|d| domain_key(d, domains))
+        .collect::<BTreeSet<_>>()
+        .len();
+    track_assert_ne!(racks, 0, ErrorKind::InvalidInput);
+
+    let members = bucket.device_group_size() as usize;
+    let members_per_rack = members.div_ceil(racks);
+    let tolerable_faults = tolerable_faults(bucket) as usize;
+    track_assert!(
+        members_per_rack <= tolerable_faults,
+        ErrorKind::InvalidInput,
+        "A single rack failure loses {} of {} members, but the bucket {:?} tolerates only {} faults \
+         (racks={})",
+        members_per_rack,
+        members,
+        bucket.id(),
+        tolerable_faults,
+        racks
+    );
+    Ok(())
+}
+
+fn tolerable_faults(bucket: &Bucket) -> u32 {
+    match *bucket {
+        Bucket::Metadata(ref b) => b.tolerable_faults,
+        Bucket::Replicated(ref b) => b.tolerable_faults,
+        Bucket::Dispersed(ref b) => b.tolerable_faults,
+    }
+}
+
+fn collect_leaves<'a>(
+    devices: &'a BTreeMap<DeviceId, Device>,
+    id: &DeviceId,
+    leaves: &mut Vec<&'a Device>,
+) {
+    match devices.get(id) {
+        Some(Device::Virtual(d)) => {
+            for c in &d.children {
+                collect_leaves(devices, c, leaves);
+            }
+        }
+        Some(d) => leaves.push(d),
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libfrugalos::entity::bucket::DispersedBucket;
+    use libfrugalos::entity::device::SegmentAllocationPolicy;
+
+    use super::*;
+    use test_util::{assign_distinct_servers, build_device_tree};
+
+    fn bucket(root: DeviceId, data_fragment_count: u32, tolerable_faults: u32) -> Bucket {
+        Bucket::Dispersed(DispersedBucket {
+            id: "bucket_id".to_owned(),
+            seqno: 0,
+            device: root,
+            segment_count: 10,
+            tolerable_faults,
+            data_fragment_count,
+        })
+    }
+
+    fn label_racks(devices: &BTreeMap<DeviceId, Device>, racks: usize) -> FailureDomains {
+        devices
+            .values()
+            .filter_map(|d| d.server())
+            .enumerate()
+            .map(|(i, server)| {
+                let domain = FailureDomain {
+                    server_id: server.clone(),
+                    zone: None,
+                    rack: Some(format!("rack{}", i % racks)),
+                };
+                (server.clone(), domain)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn validate_bucket_works() {
+        let (mut devices, root) =
+            build_device_tree(&[12], SegmentAllocationPolicy::ScatterIfPossible);
+        assign_distinct_servers(&mut devices);
+
+        // 障害ドメインが設定されていなければ検証しない
+        let unlabeled = BTreeMap::new();
+        assert!(validate_bucket(&bucket(root.clone(), 8, 4), &devices, &unlabeled).is_ok());
+
+        // 3 ラックに 12 メンバ: 一ラックあたり 4 メンバ
+        let domains = label_racks(&devices, 3);
+        assert!(validate_bucket(&bucket(root.clone(), 8, 4), &devices, &domains).is_ok());
+        assert!(validate_bucket(&bucket(root.clone(), 9, 3), &devices, &domains).is_err());
+
+        // 2 ラックでは 8+4 は満たせない
+        let domains = label_racks(&devices, 2);
+        assert!(validate_bucket(&bucket(root, 8, 4), &devices, &domains).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+//! デバイスの使用量と、それを考慮したセグメント配置用の重みの計算。
+//!
+//! 使用量は各サーバから定期的に報告され、構成管理用の Raft クラスタで複製される。
+//! セグメントテーブルの構築時には、報告済みの使用量に応じて物理デバイスの重みを減らすことで、
+//! 空き容量の多いデバイスが優先的に選ばれるようにする。
+//! 複製された状態のみを参照するので、全てのノードで同じセグメントテーブルが構築される。
+use libfrugalos::entity::device::DeviceId;
+
+/// 重みの計算で使用する空き容量の割合の下限(千分率)。
+///
+/// 満杯のデバイスの重みが 0 になり、配置先の候補から完全に外れることを防ぐ。
+const MIN_FREE_PERMILLE: u64 = 10;
+
+/// デバイスの使用量。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceUsage {
+    /// デバイスの ID。
+    pub device_id: DeviceId,
+
+    /// データ領域の容量(バイト単位)。
+    pub capacity_bytes: u64,
+
+    /// データ領域の使用量(バイト単位)。
+    pub usage_bytes: u64,
+}
+impl DeviceUsage {
+    /// データ領域の空き容量の割合を千分率で返す。
+    ///
+    /// 容量が 0 の場合には、空き容量が無いものとして扱う。
+    pub fn free_permille(&self) -> u64 {
+        if self.capacity_bytes == 0 {
+            return 0;
+        }
+        let free = self.capacity_bytes.saturating_sub(self.usage_bytes);
+        (u128::from(free) * 1000 / u128::from(self.capacity_bytes)) as u64
+    }
+
+    /// セグメント配置に使う重みを、空き容量の割合に応じて調整する。
+    ///
+    /// 調整後の重みが 0 になることはない(`weight`自体が 0 の場合を除く)。
+    pub fn adjust_weight(&self, weight: u64) -> u64 {
+        if weight == 0 {
+            return 0;
+        }
+        let permille = self.free_permille().max(MIN_FREE_PERMILLE);
+        let adjusted = u128::from(weight) * u128::from(permille) / 1000;
+        (adjusted as u64).max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(capacity_bytes: u64, usage_bytes: u64) -> DeviceUsage {
+        DeviceUsage {
+            device_id: "dev0".to_owned(),
+            capacity_bytes,
+            usage_bytes,
+        }
+    }
+
+    #[test]
+    fn adjust_weight_works() {
+        assert_eq!(usage(1000, 0).free_permille(), 1000);
+        assert_eq!(usage(1000, 250).free_permille(), 750);
+        assert_eq!(usage(1000, 2000).free_permille(), 0);
+        assert_eq!(usage(0, 0).free_permille(), 0);
+
+        assert_eq!(usage(1000, 0).adjust_weight(1 << 30), 1 << 30);
+        assert_eq!(usage(1000, 500).adjust_weight(1000), 500);
+        assert_eq!(usage(1000, 1000).adjust_weight(1000), 10);
+        assert_eq!(usage(1000, 1000).adjust_weight(1), 1);
+        assert_eq!(usage(1000, 0).adjust_weight(0), 0);
+    }
+}
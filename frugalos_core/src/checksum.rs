@@ -0,0 +1,303 @@
+//! チェックサム・ハッシュのアルゴリズムのレジストリ.
+//!
+//! 断片のチェックサムや内容の検証など、各クレートで利用するアルゴリズムはここで一元的に定義する.
+//! 新しいアルゴリズムを追加する場合には、`ChecksumAlgorithm`にバリアントを追加して、
+//! 各メソッドと`Hasher`に対応する実装を加えれば、全てのクレートから利用可能になる.
+//!
+//! 現在は、Raft のログエントリと断片の末尾に付与するチェックサム(いずれも Adler-32 に固定)と、
+//! HTTP API でのオブジェクトの内容の検証(`X-Frugalos-Content-Digest`ヘッダ)で使われている.
+//! 後者では、書き込み時にはクライアントが任意のアルゴリズムを指定でき、
+//! 取得時に返すダイジェストのアルゴリズムは設定(`http_server.content_digest`)で選択する.
+//!
+//! アルゴリズムの識別子(`name`)は設定ファイルや永続化されたダイジェストにも現れるので、
+//! 一度定義したものを変更してはいけない.
+use adler32::RollingAdler32;
+use crc::crc32::{self, CASTAGNOLI_TABLE};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use sha2::{Digest as Sha2Digest, Sha256};
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+use xxhash_rust::xxh3::Xxh3;
+
+/// チェックサム・ハッシュのアルゴリズム.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumAlgorithm {
+    /// Adler-32.
+    ///
+    /// 断片の末尾に付与されるチェックサムに使われている.
+    Adler32,
+
+    /// CRC-32C (Castagnoli).
+    Crc32c,
+
+    /// XXH3 (64 ビット).
+    Xxh3,
+
+    /// SHA-256.
+    Sha256,
+}
+impl ChecksumAlgorithm {
+    /// 利用可能な全てのアルゴリズム.
+    pub const ALL: [ChecksumAlgorithm; 4] = [
+        ChecksumAlgorithm::Adler32,
+        ChecksumAlgorithm::Crc32c,
+        ChecksumAlgorithm::Xxh3,
+        ChecksumAlgorithm::Sha256,
+    ];
+
+    /// アルゴリズムの識別子を返す.
+    ///
+    /// serde でのシリアライズ結果と同じ値になる.
+    pub fn name(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Adler32 => "adler32",
+            ChecksumAlgorithm::Crc32c => "crc32c",
+            ChecksumAlgorithm::Xxh3 => "xxh3",
+            ChecksumAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// ダイジェストのバイト数を返す.
+    pub fn digest_len(self) -> usize {
+        match self {
+            ChecksumAlgorithm::Adler32 | ChecksumAlgorithm::Crc32c => 4,
+            ChecksumAlgorithm::Xxh3 => 8,
+            ChecksumAlgorithm::Sha256 => 32,
+        }
+    }
+
+    /// 暗号学的ハッシュ関数かどうかを返す.
+    ///
+    /// 内容の同一性をダイジェストの一致のみで判定する用途(重複排除等)には、暗号学的ハッシュ関数を使うこと.
+    pub fn is_cryptographic(self) -> bool {
+        self == ChecksumAlgorithm::Sha256
+    }
+
+    /// 逐次的にダイジェストを計算するための`Hasher`を生成する.
+    pub fn hasher(self) -> Hasher {
+        let inner = match self {
+            ChecksumAlgorithm::Adler32 => HasherInner::Adler32(RollingAdler32::new()),
+            ChecksumAlgorithm::Crc32c => HasherInner::Crc32c(0),
+            ChecksumAlgorithm::Xxh3 => HasherInner::Xxh3(Box::new(Xxh3::new())),
+            ChecksumAlgorithm::Sha256 => HasherInner::Sha256(Sha256::new()),
+        };
+        Hasher(inner)
+    }
+
+    /// `data`のダイジェストを計算する.
+    pub fn digest(self, data: &[u8]) -> Digest {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finish()
+    }
+}
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+impl FromStr for ChecksumAlgorithm {
+    type Err = ParseChecksumError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ChecksumAlgorithm::ALL
+            .iter()
+            .cloned()
+            .find(|a| a.name() == s)
+            .ok_or_else(|| ParseChecksumError(format!("Unknown checksum algorithm: {:?}", s)))
+    }
+}
+
+/// ダイジェストを逐次的に計算する.
+pub struct Hasher(HasherInner);
+impl Hasher {
+    /// `data`をダイジェストの計算対象に追加する.
+    pub fn update(&mut self, data: &[u8]) {
+        match self.0 {
+            HasherInner::Adler32(ref mut h) => h.update_buffer(data),
+            HasherInner::Crc32c(ref mut h) => *h = crc32::update(*h, &CASTAGNOLI_TABLE, data),
+            HasherInner::Xxh3(ref mut h) => h.update(data),
+            HasherInner::Sha256(ref mut h) => h.update(data),
+        }
+    }
+
+    /// ダイジェストを返す.
+    pub fn finish(self) -> Digest {
+        let (algorithm, bytes) = match self.0 {
+            HasherInner::Adler32(h) => (ChecksumAlgorithm::Adler32, be_bytes_u32(h.hash())),
+            HasherInner::Crc32c(h) => (ChecksumAlgorithm::Crc32c, be_bytes_u32(h)),
+            HasherInner::Xxh3(h) => (ChecksumAlgorithm::Xxh3, h.digest().to_be_bytes().to_vec()),
+            HasherInner::Sha256(h) => (ChecksumAlgorithm::Sha256, h.finalize().to_vec()),
+        };
+        Digest { algorithm, bytes }
+    }
+}
+impl fmt::Debug for Hasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hasher({})", self.0.algorithm())
+    }
+}
+
+enum HasherInner {
+    Adler32(RollingAdler32),
+    Crc32c(u32),
+    Xxh3(Box<Xxh3>),
+    Sha256(Sha256),
+}
+impl HasherInner {
+    fn algorithm(&self) -> ChecksumAlgorithm {
+        match *self {
+            HasherInner::Adler32(_) => ChecksumAlgorithm::Adler32,
+            HasherInner::Crc32c(_) => ChecksumAlgorithm::Crc32c,
+            HasherInner::Xxh3(_) => ChecksumAlgorithm::Xxh3,
+            HasherInner::Sha256(_) => ChecksumAlgorithm::Sha256,
+        }
+    }
+}
+
+fn be_bytes_u32(n: u32) -> Vec<u8> {
+    n.to_be_bytes().to_vec()
+}
+
+/// アルゴリズムの種類を伴ったダイジェスト.
+///
+/// 文字列としては`{アルゴリズムの識別子}:{ダイジェストの16進数表記}`の形式で表現され、
+/// serde でもその文字列としてシリアライズされる.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Digest {
+    algorithm: ChecksumAlgorithm,
+    bytes: Vec<u8>,
+}
+impl Digest {
+    /// ダイジェストを計算したアルゴリズムを返す.
+    pub fn algorithm(&self) -> ChecksumAlgorithm {
+        self.algorithm
+    }
+
+    /// ダイジェストのバイト列を返す.
+    ///
+    /// 整数値を結果とするアルゴリズムの場合には、ビッグエンディアンで表現される.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// ダイジェストの16進数表記を返す.
+    pub fn to_hex(&self) -> String {
+        self.bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// `data`のダイジェストがこのダイジェストと一致するかどうかを返す.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        self.algorithm.digest(data) == *self
+    }
+}
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.to_hex())
+    }
+}
+impl FromStr for Digest {
+    type Err = ParseChecksumError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = s.splitn(2, ':');
+        let algorithm: ChecksumAlgorithm = tokens.next().unwrap_or("").parse()?;
+        let hex = tokens
+            .next()
+            .ok_or_else(|| ParseChecksumError(format!("Missing digest value: {:?}", s)))?;
+        if hex.len() != algorithm.digest_len() * 2 || !hex.is_ascii() {
+            return Err(ParseChecksumError(format!(
+                "Invalid {} digest: {:?}",
+                algorithm, s
+            )));
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| ParseChecksumError(format!("Invalid digest {:?}: {}", s, e)))?;
+        Ok(Digest { algorithm, bytes })
+    }
+}
+impl Serialize for Digest {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+impl<'de> Deserialize<'de> for Digest {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// アルゴリズムの識別子やダイジェストの文字列表現が不正な場合のエラー.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseChecksumError(String);
+impl fmt::Display for ParseChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl error::Error for ParseChecksumError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+
+    #[test]
+    fn digest_works() {
+        let data = b"123456789";
+        assert_eq!(ChecksumAlgorithm::Adler32.digest(data).to_hex(), "091e01de");
+        assert_eq!(ChecksumAlgorithm::Crc32c.digest(data).to_hex(), "e3069283");
+        assert_eq!(
+            ChecksumAlgorithm::Sha256.digest(b"abc").to_hex(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        for &algorithm in &ChecksumAlgorithm::ALL {
+            let digest = algorithm.digest(data);
+            assert_eq!(digest.as_bytes().len(), algorithm.digest_len());
+
+            // 分割して計算しても、結果は変わらない
+            let mut hasher = algorithm.hasher();
+            hasher.update(&data[..4]);
+            hasher.update(&data[4..]);
+            assert_eq!(hasher.finish(), digest);
+            assert!(digest.matches(data));
+            assert!(!digest.matches(b"12345678"));
+        }
+    }
+
+    #[test]
+    fn digest_string_representation_works() -> Result<(), Box<dyn std::error::Error>> {
+        for &algorithm in &ChecksumAlgorithm::ALL {
+            assert_eq!(algorithm.name().parse::<ChecksumAlgorithm>()?, algorithm);
+            assert_eq!(
+                serde_yaml::to_string(&algorithm)?
+                    .trim_start_matches("---\n")
+                    .trim(),
+                algorithm.name()
+            );
+
+            let digest = algorithm.digest(b"foo");
+            let s = digest.to_string();
+            assert!(s.starts_with(&format!("{}:", algorithm.name())));
+            assert_eq!(s.parse::<Digest>()?, digest);
+            let yaml = serde_yaml::to_string(&digest)?;
+            assert_eq!(serde_yaml::from_str::<Digest>(&yaml)?, digest);
+        }
+        assert!("md5".parse::<ChecksumAlgorithm>().is_err());
+        assert!("crc32c".parse::<Digest>().is_err());
+        assert!("crc32c:1234".parse::<Digest>().is_err());
+        assert!("crc32c:zzzzzzzz".parse::<Digest>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+//! クラスタ内の時刻を扱うためのユーティリティ.
+//!
+//! 各サーバの壁時計(`SystemTime`)は同期されている保証がないため、
+//! 時刻に依存する判断を行う際には、このモジュールの関数を使ってズレを把握すること.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// `SystemTime` を UNIX エポックからの経過ミリ秒に変換する.
+///
+/// エポックより前の時刻は `0` として扱う.
+pub fn to_unix_millis(time: SystemTime) -> u64 {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+}
+
+/// UNIX エポックからの経過ミリ秒を `SystemTime` に変換する.
+pub fn from_unix_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// ピアとの時計のズレの推定値.
+///
+/// 正の値はピアの時計が進んでいることを、負の値は遅れていることを表す.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ClockSkew {
+    millis: i64,
+}
+impl ClockSkew {
+    /// 問い合わせの送信時刻・ピアが返した時刻・応答の受信時刻からズレを推定する.
+    ///
+    /// 往復にかかった時間の中間点でピアが時刻を読み取ったと仮定している
+    /// (NTP と同様の推定方法).
+    pub fn estimate(sent_at: SystemTime, remote: SystemTime, received_at: SystemTime) -> Self {
+        let sent_at = to_unix_millis(sent_at) as i64;
+        let received_at = to_unix_millis(received_at) as i64;
+        let remote = to_unix_millis(remote) as i64;
+        let midpoint = sent_at + (received_at - sent_at) / 2;
+        ClockSkew {
+            millis: remote - midpoint,
+        }
+    }
+
+    /// ミリ秒単位の(符号付き)ズレを返す.
+    pub fn as_millis(&self) -> i64 {
+        self.millis
+    }
+
+    /// ズレの絶対値を返す.
+    pub fn magnitude(&self) -> Duration {
+        Duration::from_millis(self.millis.abs() as u64)
+    }
+
+    /// ズレの絶対値が `threshold` を超えているかどうかを返す.
+    pub fn exceeds(&self, threshold: Duration) -> bool {
+        self.magnitude() > threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_millis_roundtrip_works() {
+        let time = from_unix_millis(1_234_567);
+        assert_eq!(to_unix_millis(time), 1_234_567);
+        assert_eq!(to_unix_millis(UNIX_EPOCH), 0);
+    }
+
+    #[test]
+    fn estimate_skew_works() {
+        let sent_at = from_unix_millis(10_000);
+        let received_at = from_unix_millis(10_200);
+
+        // ピアの時計が 400ms 進んでいる
+        let skew = ClockSkew::estimate(sent_at, from_unix_millis(10_500), received_at);
+        assert_eq!(skew.as_millis(), 400);
+        assert!(skew.exceeds(Duration::from_millis(300)));
+        assert!(!skew.exceeds(Duration::from_millis(400)));
+
+        // ピアの時計が 2s 遅れている
+        let skew = ClockSkew::estimate(sent_at, from_unix_millis(8_100), received_at);
+        assert_eq!(skew.as_millis(), -2_000);
+        assert_eq!(skew.magnitude(), Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,128 @@
+//! 古いバージョンのサーバが解釈できない形式のデータの生成を制御するための、クラスタ単位の機能フラグ.
+//!
+//! Raft のコマンドやスナップショットに新しいフィールドや分岐を追加すると、
+//! 古いバージョンのサーバはそれを無視したり、別のコマンドとして解釈したりしてしまい、状態が食い違う.
+//! そのため、そのような形式を生成する機能は、ここで有効にされるまで使用できないようにしておく.
+//!
+//! フラグはプロセス全体で共有され、デーモンの起動時と設定の再読み込み時に`set_enabled_features`で登録される.
+//! 運用者は、クラスタ内の全てのサーバを対応するバージョンに更新した後で、全てのサーバの設定で同じ機能を有効にすること.
+//! 一度有効にして新しい形式のデータが生成された後は、古いバージョンに戻すことはできない.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::RwLock;
+
+lazy_static! {
+    static ref ENABLED: RwLock<BTreeSet<ClusterFeature>> = RwLock::new(BTreeSet::new());
+}
+
+/// 新しい形式のデータを必要とする機能.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClusterFeature {
+    /// MDS の、複数のオブジェクトを一つのコマンドで削除する`DeleteObjects`コマンド.
+    DeleteObjects,
+
+    /// MDS の、上書きされた過去のバージョンの保持.
+    HistoryRetention,
+
+    /// MDS の、既存のオブジェクトへの追記.
+    Append,
+
+    /// 構成管理の、デバイスの使用量や障害ドメイン、バケツの属性(読み込み専用等)を扱うコマンドとスナップショット.
+    ConfigExtensions,
+
+    /// MDS の、オブジェクトの大きさを伴う書き込み RPC と、コマンドの適用時の割り当て量の確認.
+    Quota,
+
+    /// 構成管理と MDS の、既存のバケツのセグメントを新しい配置に移し替える(再配置する)コマンドとスナップショット.
+    Relayout,
+
+    /// 構成管理の、バケツの属性による Erasure Coding の実装の選択.
+    ///
+    /// 古いバージョンのサーバは選択を無視して既定の実装で符号化・復号してしまうので、全てのサーバの更新後に有効にすること.
+    BucketErasureCoding,
+
+    /// 構成管理の、デバイスを退役させて配置先から除外するコマンドとスナップショット.
+    ///
+    /// 退役対象のセグメントは再配置によって移し替えられるので、`Relayout`も有効にしておく必要がある.
+    Decommission,
+
+    /// 構成管理の、セグメントのメンバを別のデバイスに移し替えて配置の偏りを均すコマンド.
+    ///
+    /// 移し替えは再配置によって行われるので、`Relayout`も有効にしておく必要がある.
+    Rebalance,
+
+    /// 構成管理の、バケツの属性による MDS のウィットネスの指定と、ウィットネスが取得する MDS のスナップショット.
+    ///
+    /// ウィットネスはオブジェクトのメタデータを保持しないので、全てのサーバの更新後に有効にすること.
+    MdsWitness,
+
+    /// 構成管理の、全てのサーバで共有されるメンテナンスウィンドウを扱うコマンドとスナップショット.
+    MaintenanceWindows,
+
+    /// 構成管理の、デバイスの作成時に測定された性能の基準値を扱うコマンドとスナップショット.
+    ///
+    /// 基準値が閾値を満たさなかったデバイスは配置先から除外されるので、全てのサーバの更新後に有効にすること.
+    DeviceBaselines,
+
+    /// MDS の、削除されたオブジェクトを猶予期間の間だけ保持する(削除を取り消せるようにする)コマンドとスナップショット.
+    Tombstones,
+}
+impl ClusterFeature {
+    /// 設定ファイルで使われる名前を返す.
+    pub fn name(self) -> &'static str {
+        match self {
+            ClusterFeature::DeleteObjects => "delete_objects",
+            ClusterFeature::HistoryRetention => "history_retention",
+            ClusterFeature::Append => "append",
+            ClusterFeature::ConfigExtensions => "config_extensions",
+            ClusterFeature::Quota => "quota",
+            ClusterFeature::Relayout => "relayout",
+            ClusterFeature::BucketErasureCoding => "bucket_erasure_coding",
+            ClusterFeature::Decommission => "decommission",
+            ClusterFeature::Rebalance => "rebalance",
+            ClusterFeature::MdsWitness => "mds_witness",
+            ClusterFeature::MaintenanceWindows => "maintenance_windows",
+            ClusterFeature::DeviceBaselines => "device_baselines",
+            ClusterFeature::Tombstones => "tombstones",
+        }
+    }
+}
+impl fmt::Display for ClusterFeature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// 有効な機能の一覧を登録する.
+///
+/// 一覧に含まれない機能は無効となる.
+pub fn set_enabled_features<I>(features: I)
+where
+    I: IntoIterator<Item = ClusterFeature>,
+{
+    *ENABLED.write().unwrap_or_else(|e| e.into_inner()) = features.into_iter().collect();
+}
+
+/// 指定された機能が有効かどうかを返す.
+pub fn is_enabled(feature: ClusterFeature) -> bool {
+    ENABLED
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .contains(&feature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_enabled_features_works() {
+        set_enabled_features(vec![ClusterFeature::Append]);
+        assert!(is_enabled(ClusterFeature::Append));
+        assert!(!is_enabled(ClusterFeature::DeleteObjects));
+
+        set_enabled_features(Vec::new());
+        assert!(!is_enabled(ClusterFeature::Append));
+    }
+}
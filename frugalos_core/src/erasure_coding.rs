@@ -0,0 +1,149 @@
+//! バケツ毎に選択される Erasure Coding の実装に関する型定義.
+//!
+//! 選択は構成管理用の Raft クラスタにバケツの属性として登録され、全てのサーバで共有される.
+//! 断片のヘッダには符号化に使われた実装が記録されるので、選択を変更しても既存の断片は復号できる.
+
+/// Erasure Coding のバックエンド.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErasureCodingBackend {
+    /// liberasurecode の Jerasure (Vandermonde 行列による Reed-Solomon 符号).
+    ///
+    /// 選択機能の導入以前から使われている既定値.
+    #[default]
+    JerasureRsVand,
+
+    /// liberasurecode の Jerasure (Cauchy 行列による Reed-Solomon 符号).
+    JerasureRsCauchy,
+
+    /// liberasurecode の ISA-L (Vandermonde 行列による Reed-Solomon 符号).
+    ///
+    /// 実行環境に ISA-L (`libisal`) がインストールされている必要がある.
+    IsaLRsVand,
+
+    /// liberasurecode の ISA-L (Cauchy 行列による Reed-Solomon 符号).
+    ///
+    /// 実行環境に ISA-L (`libisal`) がインストールされている必要がある.
+    IsaLRsCauchy,
+
+    /// Rust で実装された Reed-Solomon 符号 (`reed-solomon-erasure`).
+    ///
+    /// liberasurecode に依存しないが、チェックサムには対応していない.
+    RustReedSolomon,
+}
+impl ErasureCodingBackend {
+    /// 設定や CLI で使われる名前を返す.
+    pub fn name(self) -> &'static str {
+        match self {
+            ErasureCodingBackend::JerasureRsVand => "jerasure_rs_vand",
+            ErasureCodingBackend::JerasureRsCauchy => "jerasure_rs_cauchy",
+            ErasureCodingBackend::IsaLRsVand => "isa_l_rs_vand",
+            ErasureCodingBackend::IsaLRsCauchy => "isa_l_rs_cauchy",
+            ErasureCodingBackend::RustReedSolomon => "rust_reed_solomon",
+        }
+    }
+
+    /// 名前に対応するバックエンドを返す.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::all().iter().cloned().find(|b| b.name() == name)
+    }
+
+    /// 全てのバックエンドを返す.
+    pub fn all() -> &'static [Self] {
+        &[
+            ErasureCodingBackend::JerasureRsVand,
+            ErasureCodingBackend::JerasureRsCauchy,
+            ErasureCodingBackend::IsaLRsVand,
+            ErasureCodingBackend::IsaLRsCauchy,
+            ErasureCodingBackend::RustReedSolomon,
+        ]
+    }
+}
+
+/// Erasure Coding の復号時にデータの検証に用いるチェックサム.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErasureCodingChecksum {
+    /// チェックサムを用いない(既定値).
+    #[default]
+    None,
+
+    /// CRC32.
+    Crc32,
+
+    /// MD5.
+    Md5,
+}
+impl ErasureCodingChecksum {
+    /// 設定や CLI で使われる名前を返す.
+    pub fn name(self) -> &'static str {
+        match self {
+            ErasureCodingChecksum::None => "none",
+            ErasureCodingChecksum::Crc32 => "crc32",
+            ErasureCodingChecksum::Md5 => "md5",
+        }
+    }
+
+    /// 名前に対応するチェックサムを返す.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            ErasureCodingChecksum::None,
+            ErasureCodingChecksum::Crc32,
+            ErasureCodingChecksum::Md5,
+        ]
+        .iter()
+        .cloned()
+        .find(|c| c.name() == name)
+    }
+}
+
+/// バケツで使用する Erasure Coding の実装.
+///
+/// 既定値は、選択機能の導入以前と同じ実装となる.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ErasureCoding {
+    /// バックエンド.
+    #[serde(default)]
+    pub backend: ErasureCodingBackend,
+
+    /// チェックサム.
+    #[serde(default)]
+    pub checksum: ErasureCodingChecksum,
+}
+impl ErasureCoding {
+    /// 組み合わせが妥当かどうかを返す.
+    pub fn is_valid(&self) -> bool {
+        !(self.backend == ErasureCodingBackend::RustReedSolomon
+            && self.checksum != ErasureCodingChecksum::None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_work() {
+        for &backend in ErasureCodingBackend::all() {
+            assert_eq!(
+                ErasureCodingBackend::from_name(backend.name()),
+                Some(backend)
+            );
+        }
+        assert_eq!(ErasureCodingBackend::from_name("foo"), None);
+        assert_eq!(
+            ErasureCodingChecksum::from_name("crc32"),
+            Some(ErasureCodingChecksum::Crc32)
+        );
+    }
+
+    #[test]
+    fn is_valid_works() {
+        assert!(ErasureCoding::default().is_valid());
+        let coding = ErasureCoding {
+            backend: ErasureCodingBackend::RustReedSolomon,
+            checksum: ErasureCodingChecksum::Md5,
+        };
+        assert!(!coding.is_valid());
+    }
+}
@@ -0,0 +1,193 @@
+//! ハイブリッド論理時計(Hybrid Logical Clock).
+//!
+//! 壁時計の時刻と論理カウンタを組み合わせたタイムスタンプを発行する.
+//! サーバ間で時計がズレていても、因果関係のあるイベント同士の順序が逆転しないため、
+//! セグメントを跨いだイベントの順序付けに利用できる.
+//!
+//! 参考: "Logical Physical Clocks and Consistent Snapshots in Globally Distributed Databases"
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use clock;
+
+const LOGICAL_BITS: u32 = 16;
+const LOGICAL_MASK: u64 = (1 << LOGICAL_BITS) - 1;
+const MAX_PHYSICAL: u64 = (1 << (64 - LOGICAL_BITS)) - 1;
+
+/// ハイブリッド論理時計のタイムスタンプ.
+///
+/// 上位 48 ビットが UNIX エポックからの経過ミリ秒、下位 16 ビットが論理カウンタ.
+/// `u64` としての大小関係がそのままタイムスタンプの順序になる.
+///
+/// `0` (`Default`) は「タイムスタンプが不明」であることを表す.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HybridTimestamp(u64);
+impl HybridTimestamp {
+    /// 物理時刻(ミリ秒)と論理カウンタからタイムスタンプを生成する.
+    ///
+    /// 物理時刻が 48 ビットに収まらない場合には切り詰められる.
+    pub fn new(physical_millis: u64, logical: u16) -> Self {
+        let physical = physical_millis & MAX_PHYSICAL;
+        HybridTimestamp((physical << LOGICAL_BITS) | u64::from(logical))
+    }
+
+    /// `as_u64` で得た値からタイムスタンプを復元する.
+    pub fn from_u64(n: u64) -> Self {
+        HybridTimestamp(n)
+    }
+
+    /// 永続化や送信に使う `u64` 表現を返す.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// 物理時刻部分(UNIX エポックからの経過ミリ秒)を返す.
+    pub fn physical_millis(self) -> u64 {
+        self.0 >> LOGICAL_BITS
+    }
+
+    /// 論理カウンタ部分を返す.
+    pub fn logical(self) -> u16 {
+        (self.0 & LOGICAL_MASK) as u16
+    }
+
+    /// タイムスタンプが不明(`0`)かどうかを返す.
+    pub fn is_unknown(self) -> bool {
+        self.0 == 0
+    }
+
+    /// 直後のタイムスタンプを返す.
+    ///
+    /// 論理カウンタが溢れる場合には物理時刻を 1 ミリ秒進める.
+    fn succ(self) -> Self {
+        HybridTimestamp(self.0 + 1)
+    }
+}
+impl fmt::Display for HybridTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.physical_millis(), self.logical())
+    }
+}
+
+/// ハイブリッド論理時計.
+///
+/// 発行されるタイムスタンプは単調増加し、`update` で観測した他の時計の
+/// タイムスタンプよりも必ず大きくなる.
+///
+/// 複製したインスタンスは同じ時計を共有する.
+#[derive(Debug, Clone, Default)]
+pub struct HybridClock {
+    last: Arc<Mutex<HybridTimestamp>>,
+}
+impl HybridClock {
+    /// 新しい `HybridClock` を生成する.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ローカルなイベント(あるいは送信)用のタイムスタンプを発行する.
+    pub fn now(&self) -> HybridTimestamp {
+        self.now_at(physical_now())
+    }
+
+    /// 他の時計が発行したタイムスタンプを観測し、受信イベント用のタイムスタンプを発行する.
+    ///
+    /// 以降にこの時計が発行するタイムスタンプは `remote` より大きくなる.
+    pub fn update(&self, remote: HybridTimestamp) -> HybridTimestamp {
+        self.update_at(remote, physical_now())
+    }
+
+    /// 最後に発行したタイムスタンプを返す.
+    pub fn last(&self) -> HybridTimestamp {
+        *self.lock()
+    }
+
+    fn now_at(&self, physical: HybridTimestamp) -> HybridTimestamp {
+        let mut last = self.lock();
+        *last = if physical > *last {
+            physical
+        } else {
+            last.succ()
+        };
+        *last
+    }
+
+    fn update_at(&self, remote: HybridTimestamp, physical: HybridTimestamp) -> HybridTimestamp {
+        let mut last = self.lock();
+        let latest = ::std::cmp::max(*last, remote);
+        *last = if physical > latest {
+            physical
+        } else {
+            latest.succ()
+        };
+        *last
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HybridTimestamp> {
+        // 保持しているのは `Copy` な値のみなので、poison されていても問題ない
+        self.last.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn physical_now() -> HybridTimestamp {
+    HybridTimestamp::new(clock::to_unix_millis(SystemTime::now()), 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(physical: u64, logical: u16) -> HybridTimestamp {
+        HybridTimestamp::new(physical, logical)
+    }
+
+    #[test]
+    fn timestamp_works() {
+        let t = ts(1_234_567, 89);
+        assert_eq!(t.physical_millis(), 1_234_567);
+        assert_eq!(t.logical(), 89);
+        assert_eq!(HybridTimestamp::from_u64(t.as_u64()), t);
+        assert_eq!(t.to_string(), "1234567.89");
+
+        assert!(ts(10, 0) < ts(10, 1));
+        assert!(ts(10, 65_535) < ts(11, 0));
+        assert_eq!(ts(10, 65_535).succ(), ts(11, 0));
+        assert!(HybridTimestamp::default().is_unknown());
+    }
+
+    #[test]
+    fn now_is_monotonic() {
+        let clock = HybridClock::new();
+        assert_eq!(clock.now_at(ts(100, 0)), ts(100, 0));
+
+        // 物理時刻が進まない(あるいは戻る)間は論理カウンタが進む
+        assert_eq!(clock.now_at(ts(100, 0)), ts(100, 1));
+        assert_eq!(clock.now_at(ts(90, 0)), ts(100, 2));
+
+        // 物理時刻が進めば論理カウンタはリセットされる
+        assert_eq!(clock.now_at(ts(101, 0)), ts(101, 0));
+        assert_eq!(clock.last(), ts(101, 0));
+    }
+
+    #[test]
+    fn update_works() {
+        let clock = HybridClock::new();
+        assert_eq!(clock.now_at(ts(100, 0)), ts(100, 0));
+
+        // 時計が進んでいるサーバのタイムスタンプを観測した
+        assert_eq!(clock.update_at(ts(500, 3), ts(101, 0)), ts(500, 4));
+        assert_eq!(clock.now_at(ts(102, 0)), ts(500, 5));
+
+        // 古いタイムスタンプの観測では戻らない
+        assert_eq!(clock.update_at(ts(50, 0), ts(103, 0)), ts(500, 6));
+
+        // 物理時刻が追いつけば物理時刻に従う
+        assert_eq!(clock.update_at(ts(500, 9), ts(600, 0)), ts(600, 0));
+
+        // 共有されている時計にも反映される
+        let cloned = clock.clone();
+        assert_eq!(cloned.now_at(ts(600, 0)), ts(600, 1));
+        assert_eq!(clock.last(), ts(600, 1));
+    }
+}
@@ -1,13 +1,39 @@
 //! Frugal shared utilities.
 #![allow(clippy::new_ret_no_self)]
+extern crate adler32;
+extern crate bytecodec;
+extern crate crc;
+extern crate fibers_rpc;
+extern crate futures;
+#[cfg(feature = "grpc")]
+extern crate grpcio;
+#[macro_use]
+extern crate lazy_static;
+extern crate libfrugalos;
+extern crate prometrics;
 extern crate rustracing;
 extern crate rustracing_jaeger;
 extern crate serde;
-#[cfg(test)]
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate serde_yaml;
+extern crate sha2;
+#[macro_use]
 extern crate trackable;
+extern crate url;
+extern crate xxhash_rust;
 
+pub mod checksum;
+pub mod clock;
+pub mod cluster_feature;
+pub mod erasure_coding;
+pub mod hlc;
+pub mod logging;
+pub mod memory;
+pub mod metrics;
+pub mod prometheus;
+pub mod rpc_auth;
 pub mod serde_ext;
+pub mod task_dump;
 pub mod tracer;
@@ -0,0 +1,16 @@
+//! ログの構造化フィールドで使われるキー。
+//!
+//! JSON 形式でログを出力した場合に、モジュールを跨いで同じ ID を同じキーで検索できるように、
+//! ID はメッセージ中に埋め込まずに、これらのキーを使ったフィールドとして出力すること。
+
+/// バケツ ID。
+pub const BUCKET: &str = "bucket";
+
+/// バケツ内のセグメント番号。
+pub const SEGMENT: &str = "segment";
+
+/// オブジェクト ID。
+pub const OBJECT_ID: &str = "object_id";
+
+/// Raft ノードのローカル ID。
+pub const NODE_ID: &str = "node_id";
@@ -0,0 +1,232 @@
+//! サブシステム毎のメモリ使用量を集計するための仕組み.
+//!
+//! 各構成要素(e.g., 同期用のキュー群、MDS のマシン、キャッシュ)は`MemoryTracker`を保持して、
+//! 自身が保持しているデータのおおよそのバイト数を報告する.
+//! 報告値はサブシステム毎に合算され、`frugalos_memory_usage_bytes`ゲージとして公開される.
+//!
+//! 値はあくまで見積もり(コンテナのオーバーヘッド等は概算)で、プロセスの実際の使用量とは一致しない.
+//!
+//! また、`set_budget`で予算を設定すると、合計が予算を超えている間は`is_over_budget`が`true`を返す.
+//! 予算はソフトリミットで、リペアや segment_gc のように、後回しにできる処理がこれを参照して実行を控える.
+use prometrics::metrics::{Gauge, GaugeBuilder};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+lazy_static! {
+    static ref USAGES: Mutex<BTreeMap<&'static str, SubsystemUsage>> = Mutex::new(BTreeMap::new());
+    static ref BUDGET_GAUGE: Option<Gauge> = GaugeBuilder::new("budget_bytes")
+        .namespace("frugalos")
+        .subsystem("memory")
+        .help("Soft limit of the estimated memory usage (0 means unlimited)")
+        .default_registry()
+        .finish()
+        .ok();
+}
+
+// 合計値は`USAGES`のロックを保持した状態でのみ更新される
+static TOTAL_BYTES: AtomicU64 = AtomicU64::new(0);
+static BUDGET_BYTES: AtomicU64 = AtomicU64::new(0);
+
+// `MemoryTracker::set_approximately`が報告を省略する差の下限
+const APPROXIMATION_MIN_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug)]
+struct SubsystemUsage {
+    bytes: u64,
+    gauge: Option<Gauge>,
+}
+impl SubsystemUsage {
+    fn new(subsystem: &'static str) -> Self {
+        let gauge = GaugeBuilder::new("usage_bytes")
+            .namespace("frugalos")
+            .subsystem("memory")
+            .help("Estimated memory usage")
+            .label("subsystem", subsystem)
+            .default_registry()
+            .finish()
+            .ok();
+        SubsystemUsage { bytes: 0, gauge }
+    }
+}
+
+/// あるサブシステムのメモリ使用量を報告するためのオブジェクト.
+///
+/// 同じサブシステムに属する複数のインスタンスの報告値は合算される.
+/// 破棄されると、そのインスタンスの報告値は合計から差し引かれる.
+#[derive(Debug)]
+pub struct MemoryTracker {
+    subsystem: &'static str,
+    bytes: AtomicU64,
+}
+impl MemoryTracker {
+    /// `subsystem`(e.g., `"synchronizer_queues"`)のメモリ使用量を報告するためのインスタンスを生成する.
+    ///
+    /// 初期値は 0 となる.
+    pub fn new(subsystem: &'static str) -> Self {
+        MemoryTracker {
+            subsystem,
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// このインスタンスが保持しているデータのバイト数を報告する.
+    pub fn set(&self, bytes: u64) {
+        if self.bytes.load(Ordering::SeqCst) == bytes {
+            return;
+        }
+        let mut usages = lock_usages();
+        let old = self.bytes.swap(bytes, Ordering::SeqCst);
+        if old == bytes {
+            return;
+        }
+        let usage = usages
+            .entry(self.subsystem)
+            .or_insert_with(|| SubsystemUsage::new(self.subsystem));
+        usage.bytes = usage.bytes - old + bytes;
+        if let Some(ref gauge) = usage.gauge {
+            gauge.set(usage.bytes as f64);
+        }
+        let total = TOTAL_BYTES.load(Ordering::SeqCst) - old + bytes;
+        TOTAL_BYTES.store(total, Ordering::SeqCst);
+    }
+
+    /// `set`と同様だが、前回の報告値との差が小さい場合には報告を省略する.
+    ///
+    /// 報告にはグローバルなロックが必要なので、コミット毎のように頻繁に値が変わる箇所ではこちらを用いる.
+    /// 差が前回の報告値の 1% と 64KiB の大きい方に満たない場合に省略される(0 は常に報告される).
+    pub fn set_approximately(&self, bytes: u64) {
+        let last = self.bytes.load(Ordering::SeqCst);
+        let threshold = cmp::max(last / 100, APPROXIMATION_MIN_BYTES);
+        let diff = if last < bytes {
+            bytes - last
+        } else {
+            last - bytes
+        };
+        if bytes != 0 && diff < threshold {
+            return;
+        }
+        self.set(bytes);
+    }
+
+    /// 最後に報告したバイト数を返す.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::SeqCst)
+    }
+}
+impl Drop for MemoryTracker {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
+
+/// メモリ使用量の予算(バイト数)を設定する.
+///
+/// 0 の場合には予算を設けない.
+pub fn set_budget(bytes: u64) {
+    BUDGET_BYTES.store(bytes, Ordering::SeqCst);
+    if let Some(ref gauge) = *BUDGET_GAUGE {
+        gauge.set(bytes as f64);
+    }
+}
+
+/// メモリ使用量の予算を返す(0 は予算なしを意味する).
+pub fn budget() -> u64 {
+    BUDGET_BYTES.load(Ordering::SeqCst)
+}
+
+/// 全てのサブシステムのメモリ使用量の合計を返す.
+pub fn total_bytes() -> u64 {
+    TOTAL_BYTES.load(Ordering::SeqCst)
+}
+
+/// メモリ使用量の合計が予算を超えているかどうかを返す.
+pub fn is_over_budget() -> bool {
+    let budget = budget();
+    budget != 0 && total_bytes() > budget
+}
+
+/// 現在の合計に`additional`バイトを加えても予算内に収まるかどうかを返す.
+///
+/// 大きなデータを新たに確保する処理が、開始前に呼び出すことを想定している.
+pub fn fits_in_budget(additional: u64) -> bool {
+    let budget = budget();
+    budget == 0 || total_bytes().saturating_add(additional) <= budget
+}
+
+/// サブシステム毎のメモリ使用量を、サブシステムの名前順に返す.
+pub fn usages() -> BTreeMap<String, u64> {
+    lock_usages()
+        .iter()
+        .map(|(&subsystem, usage)| (subsystem.to_owned(), usage.bytes))
+        .collect()
+}
+
+fn lock_usages() -> MutexGuard<'static, BTreeMap<&'static str, SubsystemUsage>> {
+    USAGES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(subsystem: &str) -> u64 {
+        usages().get(subsystem).cloned().unwrap_or(0)
+    }
+
+    #[test]
+    fn memory_tracker_works() {
+        let foo = MemoryTracker::new("test_foo");
+        let bar = MemoryTracker::new("test_foo");
+        foo.set(100);
+        bar.set(20);
+        assert_eq!(usage("test_foo"), 120);
+
+        foo.set(30);
+        assert_eq!(foo.bytes(), 30);
+        assert_eq!(usage("test_foo"), 50);
+
+        // 破棄されたインスタンスの報告値は差し引かれる
+        drop(bar);
+        assert_eq!(usage("test_foo"), 30);
+    }
+
+    #[test]
+    fn set_approximately_works() {
+        let tracker = MemoryTracker::new("test_approximately");
+        tracker.set_approximately(1_000_000);
+        assert_eq!(tracker.bytes(), 1_000_000);
+
+        // 差が小さい場合には報告されない
+        tracker.set_approximately(1_000_100);
+        assert_eq!(tracker.bytes(), 1_000_000);
+        assert_eq!(usage("test_approximately"), 1_000_000);
+
+        tracker.set_approximately(2_000_000);
+        assert_eq!(tracker.bytes(), 2_000_000);
+
+        // 0 は常に報告される
+        tracker.set(10);
+        tracker.set_approximately(0);
+        assert_eq!(usage("test_approximately"), 0);
+    }
+
+    #[test]
+    fn budget_works() {
+        let tracker = MemoryTracker::new("test_budget");
+        tracker.set(10);
+        set_budget(0);
+        assert!(!is_over_budget());
+
+        set_budget(1);
+        assert!(is_over_budget());
+
+        set_budget(total_bytes() + 1_000_000);
+        assert!(!is_over_budget());
+        assert!(fits_in_budget(1_000));
+        assert!(!fits_in_budget(u64::max_value()));
+        set_budget(0);
+        assert!(fits_in_budget(u64::max_value()));
+    }
+}
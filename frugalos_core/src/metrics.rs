@@ -0,0 +1,121 @@
+//! バケツ毎・ノード毎のメトリクスを扱うための仕組み.
+//!
+//! バケツやノードを対象とするメトリクスには、`MetricLabels`を使って一貫した`bucket`ラベルと`node`ラベルを付与する.
+//!
+//! また、セグメントのクライアントのように、設定の更新の度に作り直されるオブジェクトがメトリクスを生成すると、
+//! 同じ名前とラベルを持つメトリクスが prometrics のレジストリに重複して登録されてしまう.
+//! これを避けるために、そのようなメトリクス群は`get_or_create`関数を経由して生成する.
+use prometrics::metrics::MetricBuilder;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// バケツ ID を保持するラベルの名前.
+pub const BUCKET_LABEL: &str = "bucket";
+
+/// ノード ID を保持するラベルの名前.
+pub const NODE_LABEL: &str = "node";
+
+lazy_static! {
+    static ref REGISTRY: Mutex<HashMap<(TypeId, MetricLabels), Box<dyn Any + Send>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// メトリクスの対象となるバケツとノード.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct MetricLabels {
+    bucket: Option<String>,
+    node: Option<String>,
+}
+impl MetricLabels {
+    /// バケツを対象とするラベルの組を生成する.
+    pub fn bucket(bucket: &str) -> Self {
+        MetricLabels {
+            bucket: Some(bucket.to_owned()),
+            node: None,
+        }
+    }
+
+    /// 対象のノードを設定したラベルの組を返す.
+    pub fn node(mut self, node: &str) -> Self {
+        self.node = Some(node.to_owned());
+        self
+    }
+
+    /// `builder`に`bucket`ラベルと`node`ラベルを付与する.
+    ///
+    /// 値が設定されていないラベルは付与されない.
+    pub fn apply<'a>(&self, builder: &'a mut MetricBuilder) -> &'a mut MetricBuilder {
+        if let Some(ref bucket) = self.bucket {
+            builder.label(BUCKET_LABEL, bucket);
+        }
+        if let Some(ref node) = self.node {
+            builder.label(NODE_LABEL, node);
+        }
+        builder
+    }
+}
+
+/// `labels`に対応する`T`型のメトリクス群を返す.
+///
+/// 同じ型とラベルの組に対する最初の呼び出しでのみ`f`が呼ばれ、以降は生成済みのメトリクス群の複製が返される.
+/// メトリクスの実体は複製間で共有される.
+pub fn get_or_create<T, F, E>(labels: &MetricLabels, f: F) -> Result<T, E>
+where
+    T: Any + Clone + Send,
+    F: FnOnce(&MetricLabels) -> Result<T, E>,
+{
+    let key = (TypeId::of::<T>(), labels.clone());
+    if let Some(metrics) = lookup::<T>(&key) {
+        return Ok(metrics);
+    }
+
+    // NOTE: `f`の中で`get_or_create`が呼ばれても良いように、生成中はロックを保持しない
+    let metrics = f(labels)?;
+    let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    let entry = registry.entry(key).or_insert_with(|| Box::new(metrics));
+    Ok(entry.downcast_ref::<T>().expect("Never fails").clone())
+}
+
+fn lookup<T: Any + Clone>(key: &(TypeId, MetricLabels)) -> Option<T> {
+    let registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    registry
+        .get(key)
+        .and_then(|m| m.downcast_ref::<T>())
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometrics::metrics::Counter;
+
+    #[derive(Clone)]
+    struct TestMetrics(Counter);
+
+    fn create(labels: &MetricLabels) -> Result<TestMetrics, ()> {
+        let mut builder = MetricBuilder::new();
+        labels.apply(&mut builder);
+        Ok(TestMetrics(
+            builder.counter("test_total").finish().map_err(|_| ())?,
+        ))
+    }
+
+    #[test]
+    fn get_or_create_works() {
+        let labels = MetricLabels::bucket("get_or_create_works").node("0");
+        let a = get_or_create(&labels, create).unwrap();
+        a.0.increment();
+
+        // 同じラベルの組に対しては、生成済みのメトリクスが返される
+        let b = get_or_create(&labels, |_| -> Result<TestMetrics, ()> {
+            panic!("should not be called")
+        })
+        .unwrap();
+        assert_eq!(b.0.value(), 1.0);
+
+        // ラベルが異なる場合には、新たに生成される
+        let c = get_or_create(&labels.clone().node("1"), create).unwrap();
+        assert_eq!(c.0.value(), 0.0);
+    }
+}
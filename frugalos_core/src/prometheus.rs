@@ -0,0 +1,135 @@
+//! Prometheus 向けのメトリクスの設定.
+//!
+//! 設定はプロセス全体で共有され、デーモンの起動時に`set_config`で登録される.
+//! メトリクスの生成時には、登録済みの設定が参照される.
+//!
+//! ヒストグラムは`PrometheusConfig::configure_histogram`を経由して生成することで、
+//! バケツを設定から変更できるようになる.
+use prometrics::metrics::{Histogram, HistogramBuilder};
+use prometrics::Result;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/// レイテンシを計測するヒストグラムのデフォルトのバケツ(秒).
+pub const DEFAULT_LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0,
+];
+
+lazy_static! {
+    static ref CONFIG: RwLock<PrometheusConfig> = RwLock::new(PrometheusConfig::default());
+}
+
+/// Prometheus 向けのメトリクスの設定.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PrometheusConfig {
+    /// ヒストグラム毎のバケツの上限値の一覧.
+    ///
+    /// キーは名前空間とサブシステムを含むメトリクスの名前 (e.g., `frugalos_segment_request_duration_seconds`).
+    /// 指定がないヒストグラムには、それぞれのデフォルトのバケツが使われる.
+    #[serde(default)]
+    pub histogram_buckets: BTreeMap<String, Vec<f64>>,
+}
+impl PrometheusConfig {
+    /// `name`のヒストグラムに設定されたバケツを返す.
+    ///
+    /// 設定されていない場合には`default`が返される.
+    pub fn buckets_or<'a>(&'a self, name: &str, default: &'a [f64]) -> &'a [f64] {
+        self.histogram_buckets
+            .get(name)
+            .map_or(default, |buckets| &buckets[..])
+    }
+
+    /// 設定に従ってバケツを付与した上で、`builder`からヒストグラムを生成する.
+    ///
+    /// `name`は名前空間とサブシステムを含むメトリクスの名前で、設定の参照に使われる.
+    /// 設定がない場合には`DEFAULT_LATENCY_BUCKETS`が使われるので、`builder`にはバケツを追加しておく必要はない.
+    /// レジストリへの登録の有無は`builder`の設定に従う.
+    pub fn configure_histogram(
+        &self,
+        name: &str,
+        builder: &mut HistogramBuilder,
+    ) -> Result<Histogram> {
+        self.configure_histogram_or(name, DEFAULT_LATENCY_BUCKETS, builder)
+    }
+
+    /// `configure_histogram`と同様だが、設定がない場合には`default`のバケツが使われる.
+    pub fn configure_histogram_or(
+        &self,
+        name: &str,
+        default: &[f64],
+        builder: &mut HistogramBuilder,
+    ) -> Result<Histogram> {
+        for &bucket in self.buckets_or(name, default) {
+            builder.bucket(bucket);
+        }
+        builder.finish()
+    }
+}
+
+/// プロセス全体で使われる設定を登録する.
+///
+/// 登録前に生成されたメトリクスには反映されない.
+pub fn set_config(config: PrometheusConfig) {
+    *CONFIG.write().unwrap_or_else(|e| e.into_inner()) = config;
+}
+
+/// 登録済みの設定を返す.
+pub fn config() -> PrometheusConfig {
+    CONFIG.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_or_works() {
+        let mut config = PrometheusConfig::default();
+        config
+            .histogram_buckets
+            .insert("foo_seconds".to_owned(), vec![0.1, 1.0]);
+        assert_eq!(
+            config.buckets_or("foo_seconds", DEFAULT_LATENCY_BUCKETS),
+            &[0.1, 1.0]
+        );
+        assert_eq!(
+            config.buckets_or("bar_seconds", DEFAULT_LATENCY_BUCKETS),
+            DEFAULT_LATENCY_BUCKETS
+        );
+    }
+
+    #[test]
+    fn configure_histogram_works() {
+        let mut config = PrometheusConfig::default();
+        config.histogram_buckets.insert(
+            "configure_histogram_works_seconds".to_owned(),
+            vec![0.1, 1.0],
+        );
+
+        let histogram = config
+            .configure_histogram(
+                "configure_histogram_works_seconds",
+                HistogramBuilder::new("configure_histogram_works_seconds").label("foo", "bar"),
+            )
+            .unwrap();
+        assert_eq!(upper_bounds(&histogram), [0.1, 1.0]);
+
+        // 設定がない場合はデフォルトのバケツが使われる
+        let histogram = config
+            .configure_histogram(
+                "configure_histogram_works_default_seconds",
+                &mut HistogramBuilder::new("configure_histogram_works_default_seconds"),
+            )
+            .unwrap();
+        assert_eq!(upper_bounds(&histogram), DEFAULT_LATENCY_BUCKETS);
+    }
+
+    fn upper_bounds(histogram: &Histogram) -> Vec<f64> {
+        histogram
+            .buckets()
+            .iter()
+            .map(|b| b.upper_bound())
+            .filter(|b| b.is_finite())
+            .collect()
+    }
+}
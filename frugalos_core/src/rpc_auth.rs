@@ -0,0 +1,331 @@
+//! RPC の呼び出しに認証情報(トークン)を載せるための仕組み.
+//!
+//! `libfrugalos`で定義されている RPC のメッセージには認証情報を載せる場所が無いので、
+//! 要求にトークンを付加した`Authenticated<C>`(ないし`AuthenticatedCast<C>`)という別の RPC を用意している.
+//! これらの RPC の ID は、元の RPC の ID の最上位ビットを立てたものとなる.
+//!
+//! サーバ間の RPC (構成管理の更新系、MDS、セグメント)では、トークンとしてクラスタ内で共有する
+//! クラスタトークンを使う. クラスタトークンが設定されている場合には、
+//! クライアントは`call`等を通して常にトークン付きの RPC を発行し、サーバは`add_call_handler`等で登録された
+//! ハンドラで、トークンを伴わない(従来の ID での)呼び出しと、トークンが一致しない呼び出しを拒否する.
+//!
+//! クラスタトークンはプロセス全体で共有され、起動時に`set_cluster_token`で登録される.
+//! 運用者は、クラスタ内の全てのサーバを対応するバージョンに更新した後で、全てのサーバの設定に同じトークンを追加すること.
+//!
+//! なお、Raft の RPC と、`cannyls_rpc`によるデバイスの RPC は対象外である.
+use bytecodec::bincode_codec::{BincodeDecoder, BincodeEncoder};
+use fibers_rpc::client::{ClientServiceHandle, Options};
+use fibers_rpc::server::{HandleCall, HandleCast, NoReply, Reply, ServerBuilder};
+use fibers_rpc::{Call, Cast, ProcedureId};
+use futures::Future;
+use libfrugalos;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::RwLock;
+use trackable::error::ErrorKindExt;
+
+/// トークン付きの RPC の ID で立てられるビット.
+pub const AUTHENTICATED_ID_FLAG: u32 = 0x8000_0000;
+
+lazy_static! {
+    static ref CLUSTER_TOKEN: RwLock<Option<String>> = RwLock::new(None);
+}
+
+/// クラスタトークンを登録する.
+///
+/// `None`を指定した場合には、サーバ間の RPC の認証は行われない.
+pub fn set_cluster_token(token: Option<String>) {
+    *CLUSTER_TOKEN.write().unwrap_or_else(|e| e.into_inner()) = token;
+}
+
+/// クラスタトークンが登録されているかどうかを返す.
+pub fn is_cluster_token_required() -> bool {
+    CLUSTER_TOKEN
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .is_some()
+}
+
+/// `token`が、登録されているクラスタトークンと一致するかどうかを返す.
+///
+/// クラスタトークンが登録されていない場合には常に`false`となる.
+pub fn is_cluster_token(token: &str) -> bool {
+    CLUSTER_TOKEN
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map_or(false, |expected| {
+            constant_time_eq(expected.as_bytes(), token.as_bytes())
+        })
+}
+
+/// 二つのバイト列が等しいかどうかを、内容に依存しない時間で判定する.
+///
+/// トークンの比較に使う. 長さが異なる場合には即座に`false`を返すので、長さは秘密にできない.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// `C`の要求にトークンを付加した RPC.
+///
+/// 要求は`(トークン, 元の要求)`の組で、応答は`C`と同じ.
+pub struct Authenticated<C>(PhantomData<C>);
+impl<C> Call for Authenticated<C>
+where
+    C: Call,
+    C::Req: Serialize + DeserializeOwned + Send + 'static,
+{
+    const ID: ProcedureId = ProcedureId(C::ID.0 | AUTHENTICATED_ID_FLAG);
+    const NAME: &'static str = C::NAME;
+
+    type Req = (String, C::Req);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = C::Res;
+    type ResDecoder = C::ResDecoder;
+    type ResEncoder = C::ResEncoder;
+}
+
+/// `C`の通知にトークンを付加した RPC.
+pub struct AuthenticatedCast<C>(PhantomData<C>);
+impl<C> Cast for AuthenticatedCast<C>
+where
+    C: Cast,
+    C::Notification: Serialize + DeserializeOwned + Send + 'static,
+{
+    const ID: ProcedureId = ProcedureId(C::ID.0 | AUTHENTICATED_ID_FLAG);
+    const NAME: &'static str = C::NAME;
+
+    type Notification = (String, C::Notification);
+    type Decoder = BincodeDecoder<Self::Notification>;
+    type Encoder = BincodeEncoder<Self::Notification>;
+}
+
+/// 認証に失敗した場合の応答を生成するためのトレイト.
+pub trait FromRpcError {
+    /// `e`をエラーとする応答を生成する.
+    fn from_rpc_error(e: libfrugalos::Error) -> Self;
+}
+impl<T> FromRpcError for libfrugalos::Result<T> {
+    fn from_rpc_error(e: libfrugalos::Error) -> Self {
+        Err(e)
+    }
+}
+
+fn unauthenticated_error() -> libfrugalos::Error {
+    libfrugalos::ErrorKind::InvalidInput
+        .cause("Unauthenticated RPC call: the cluster token is missing or wrong")
+        .into()
+}
+
+/// トークンを伴わない呼び出しを、クラスタトークンが登録されていない場合にのみ受け付けるハンドラ.
+#[derive(Debug, Clone)]
+pub struct ClusterOnly<H>(H);
+impl<C, H> HandleCall<C> for ClusterOnly<H>
+where
+    C: Call,
+    C::Res: FromRpcError,
+    H: HandleCall<C>,
+{
+    fn handle_call(&self, request: C::Req) -> Reply<C> {
+        if is_cluster_token_required() {
+            return Reply::done(C::Res::from_rpc_error(track!(unauthenticated_error())));
+        }
+        self.0.handle_call(request)
+    }
+}
+impl<C, H> HandleCast<C> for ClusterOnly<H>
+where
+    C: Cast,
+    H: HandleCast<C>,
+{
+    fn handle_cast(&self, notification: C::Notification) -> NoReply {
+        if is_cluster_token_required() {
+            return NoReply::done();
+        }
+        self.0.handle_cast(notification)
+    }
+}
+
+/// トークン付きの呼び出しを、トークンがクラスタトークンと一致する場合にのみ受け付けるハンドラ.
+///
+/// クラスタトークンが登録されていない場合には、トークンの値に関わらず受け付ける.
+#[derive(Debug, Clone)]
+pub struct WithClusterToken<H>(H);
+impl<C, H> HandleCall<Authenticated<C>> for WithClusterToken<H>
+where
+    C: Call,
+    C::Req: Serialize + DeserializeOwned + Send + 'static,
+    C::Res: FromRpcError,
+    H: HandleCall<C>,
+{
+    fn handle_call(&self, (token, request): (String, C::Req)) -> Reply<Authenticated<C>> {
+        if is_cluster_token_required() && !is_cluster_token(&token) {
+            return Reply::done(C::Res::from_rpc_error(track!(unauthenticated_error())));
+        }
+        Reply::future(self.0.handle_call(request))
+    }
+}
+impl<C, H> HandleCast<AuthenticatedCast<C>> for WithClusterToken<H>
+where
+    C: Cast,
+    C::Notification: Serialize + DeserializeOwned + Send + 'static,
+    H: HandleCast<C>,
+{
+    fn handle_cast(&self, (token, notification): (String, C::Notification)) -> NoReply {
+        if is_cluster_token_required() && !is_cluster_token(&token) {
+            return NoReply::done();
+        }
+        self.0.handle_cast(notification)
+    }
+}
+
+/// サーバ間の RPC のハンドラを、トークンを伴わない呼び出し用とトークン付きの呼び出し用の両方に登録する.
+pub fn add_call_handler<C, H>(builder: &mut ServerBuilder, handler: H)
+where
+    C: Call,
+    C::Req: Serialize + DeserializeOwned + Send + 'static,
+    C::ReqDecoder: Default,
+    C::ResEncoder: Default,
+    C::Res: FromRpcError,
+    H: HandleCall<C> + Clone,
+{
+    builder.add_call_handler::<C, _>(ClusterOnly(handler.clone()));
+    builder.add_call_handler::<Authenticated<C>, _>(WithClusterToken(handler));
+}
+
+/// サーバ間の通知のハンドラを、トークンを伴わない通知用とトークン付きの通知用の両方に登録する.
+pub fn add_cast_handler<C, H>(builder: &mut ServerBuilder, handler: H)
+where
+    C: Cast,
+    C::Notification: Serialize + DeserializeOwned + Send + 'static,
+    C::Decoder: Default,
+    H: HandleCast<C> + Clone,
+{
+    builder.add_cast_handler::<C, _>(ClusterOnly(handler.clone()));
+    builder.add_cast_handler::<AuthenticatedCast<C>, _>(WithClusterToken(handler));
+}
+
+/// RPC の応答を表す`Future`.
+pub type Response<T> = Box<dyn Future<Item = T, Error = ::fibers_rpc::Error> + Send + 'static>;
+
+/// サーバ間の RPC を呼び出す.
+///
+/// クラスタトークンが登録されている場合にはトークン付きの RPC を、そうでなければ`C`をそのまま呼び出す.
+pub fn call<C>(
+    service: &ClientServiceHandle,
+    server: SocketAddr,
+    request: C::Req,
+) -> Response<C::Res>
+where
+    C: Call,
+    C::Req: Serialize + DeserializeOwned + Send + 'static,
+    C::ReqEncoder: Default,
+    C::ResDecoder: Default,
+{
+    call_with_options::<C>(service, server, request, Options::default())
+}
+
+/// `call`と同様だが、RPC のオプションを指定できる.
+pub fn call_with_options<C>(
+    service: &ClientServiceHandle,
+    server: SocketAddr,
+    request: C::Req,
+    options: Options,
+) -> Response<C::Res>
+where
+    C: Call,
+    C::Req: Serialize + DeserializeOwned + Send + 'static,
+    C::ReqEncoder: Default,
+    C::ResDecoder: Default,
+{
+    let token = CLUSTER_TOKEN
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    call_with_token::<C>(service, server, token, request, options)
+}
+
+/// トークンを明示的に指定して RPC を呼び出す.
+///
+/// `token`が`None`の場合には、`C`をそのまま呼び出す.
+pub fn call_with_token<C>(
+    service: &ClientServiceHandle,
+    server: SocketAddr,
+    token: Option<String>,
+    request: C::Req,
+    options: Options,
+) -> Response<C::Res>
+where
+    C: Call,
+    C::Req: Serialize + DeserializeOwned + Send + 'static,
+    C::ReqEncoder: Default,
+    C::ResDecoder: Default,
+{
+    if let Some(token) = token {
+        let mut client = Authenticated::<C>::client(service);
+        *client.options_mut() = options;
+        Box::new(client.call(server, (token, request)))
+    } else {
+        let mut client = C::client(service);
+        *client.options_mut() = options;
+        Box::new(client.call(server, request))
+    }
+}
+
+/// サーバ間の通知を送る.
+///
+/// クラスタトークンが登録されている場合にはトークン付きの通知を、そうでなければ`C`をそのまま送る.
+pub fn cast<C>(
+    service: &ClientServiceHandle,
+    server: SocketAddr,
+    notification: C::Notification,
+) -> Result<(), ::fibers_rpc::Error>
+where
+    C: Cast,
+    C::Notification: Serialize + DeserializeOwned + Send + 'static,
+    C::Encoder: Default,
+{
+    let token = CLUSTER_TOKEN
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    if let Some(token) = token {
+        AuthenticatedCast::<C>::client(service).cast(server, (token, notification))
+    } else {
+        C::client(service).cast(server, notification)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_time_eq_works() {
+        assert!(constant_time_eq(b"foo", b"foo"));
+        assert!(!constant_time_eq(b"foo", b"fob"));
+        assert!(!constant_time_eq(b"foo", b"fooo"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn cluster_token_works() {
+        set_cluster_token(None);
+        assert!(!is_cluster_token_required());
+        assert!(!is_cluster_token("secret"));
+
+        set_cluster_token(Some("secret".to_owned()));
+        assert!(is_cluster_token_required());
+        assert!(is_cluster_token("secret"));
+        assert!(!is_cluster_token("secreT"));
+
+        set_cluster_token(None);
+    }
+}
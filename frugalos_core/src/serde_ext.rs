@@ -1,5 +1,27 @@
 //! Extensions for serde.
 
+/// A module for serializing/deserializing an `Option<Duration>` as milliseconds.
+pub mod option_duration_millis {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::Duration;
+
+    use super::duration_millis::{from_millis, to_millis};
+
+    pub fn serialize<S>(value: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        value.as_ref().map(to_millis).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<u64>::deserialize(deserializer).map(|v| v.map(from_millis))
+    }
+}
+
 /// A module for serializing/deserializing a `Duration` as milliseconds.
 pub mod duration_millis {
     use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -28,6 +50,210 @@ pub mod duration_millis {
     }
 }
 
+/// Helpers for keeping configuration files compatible across versions.
+///
+/// A `ConfigSchema` describes how the layout of a configuration has evolved:
+///
+/// - renamed fields are listed in `aliases` and are moved to their current names before deserialization,
+/// - fields that are still accepted but will be removed are listed in `deprecations`.
+///
+/// Both kinds of fields are reported as `ConfigWarning`s together with unknown fields
+/// (e.g., typo'd names), so that callers can surface them when loading the configuration
+/// instead of silently ignoring them.
+pub mod evolution {
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use serde_yaml::{self, Mapping, Value};
+    use std::fmt;
+
+    /// A warning found while loading a configuration.
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum ConfigWarning {
+        /// The field at the path is not a part of the configuration and was ignored.
+        UnknownField(String),
+
+        /// The field at the path has been renamed to `replacement`.
+        ///
+        /// If both names are specified, the value of `replacement` is used.
+        RenamedField {
+            /// The path of the old field.
+            path: String,
+
+            /// The path of the current field.
+            replacement: String,
+        },
+
+        /// The field at the path is deprecated and will be removed in a future version.
+        DeprecatedField {
+            /// The path of the field.
+            path: String,
+
+            /// Why the field is deprecated, or what to use instead.
+            note: String,
+        },
+    }
+    impl fmt::Display for ConfigWarning {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                ConfigWarning::UnknownField(ref path) => write!(f, "Unknown field: {}", path),
+                ConfigWarning::RenamedField {
+                    ref path,
+                    ref replacement,
+                } => write!(
+                    f,
+                    "Field {} has been renamed; use {} instead",
+                    path, replacement
+                ),
+                ConfigWarning::DeprecatedField { ref path, ref note } => {
+                    write!(f, "Field {} is deprecated: {}", path, note)
+                }
+            }
+        }
+    }
+
+    /// Describes the history of a configuration layout.
+    ///
+    /// Paths are dot-separated field names from the root of the document (e.g., `"frugalos.daemon.executor_threads"`).
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct ConfigSchema {
+        /// Pairs of an old path and the current path of renamed fields.
+        pub aliases: &'static [(&'static str, &'static str)],
+
+        /// Pairs of a path and a note of deprecated fields.
+        pub deprecations: &'static [(&'static str, &'static str)],
+    }
+    impl ConfigSchema {
+        /// Deserializes `value` as `T` after resolving the aliases,
+        /// and returns the warnings found in `value`.
+        ///
+        /// Unknown fields are detected by comparing `value` with the re-serialized result,
+        /// so they are also found inside `#[serde(flatten)]` fields.
+        pub fn deserialize<T>(
+            &self,
+            mut value: Value,
+        ) -> Result<(T, Vec<ConfigWarning>), serde_yaml::Error>
+        where
+            T: DeserializeOwned + Serialize,
+        {
+            let mut warnings = Vec::new();
+            for &(path, note) in self.deprecations {
+                if lookup(&value, path).is_some() {
+                    warnings.push(ConfigWarning::DeprecatedField {
+                        path: path.to_owned(),
+                        note: note.to_owned(),
+                    });
+                }
+            }
+            for &(old, new) in self.aliases {
+                if let Some(v) = take(&mut value, old) {
+                    if lookup(&value, new).is_none() {
+                        insert(&mut value, new, v);
+                    }
+                    warnings.push(ConfigWarning::RenamedField {
+                        path: old.to_owned(),
+                        replacement: new.to_owned(),
+                    });
+                }
+            }
+
+            let config: T = serde_yaml::from_value(value.clone())?;
+            let known = serde_yaml::to_value(&config)?;
+            let mut unknowns = Vec::new();
+            collect_unknown_fields(&value, &known, "", &mut unknowns);
+            unknowns.sort();
+            warnings.extend(unknowns.into_iter().map(ConfigWarning::UnknownField));
+            Ok((config, warnings))
+        }
+    }
+
+    fn key(name: &str) -> Value {
+        Value::String(name.to_owned())
+    }
+
+    fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+        path.split('.')
+            .try_fold(value, |v, name| v.as_mapping()?.get(&key(name)))
+    }
+
+    fn take(value: &mut Value, path: &str) -> Option<Value> {
+        let (parent, name) = match path.rfind('.') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => ("", path),
+        };
+        let mut v = value;
+        for n in parent.split('.').filter(|n| !n.is_empty()) {
+            v = match *v {
+                Value::Mapping(ref mut m) => m.get_mut(&key(n))?,
+                _ => return None,
+            };
+        }
+        match *v {
+            Value::Mapping(ref mut m) => m.remove(&key(name)),
+            _ => None,
+        }
+    }
+
+    fn insert(value: &mut Value, path: &str, new: Value) {
+        // A non-mapping value is left as is to let the deserialization report the error
+        if let Value::Mapping(ref mut m) = *value {
+            match path.find('.') {
+                None => {
+                    m.insert(key(path), new);
+                }
+                Some(i) => {
+                    let k = key(&path[..i]);
+                    if m.get(&k).is_none() {
+                        m.insert(k.clone(), Value::Mapping(Mapping::new()));
+                    }
+                    if let Some(child) = m.get_mut(&k) {
+                        insert(child, &path[i + 1..], new);
+                    }
+                }
+            }
+        }
+    }
+
+    fn collect_unknown_fields(
+        input: &Value,
+        known: &Value,
+        path: &str,
+        unknowns: &mut Vec<String>,
+    ) {
+        match (input, known) {
+            (Value::Mapping(input), Value::Mapping(known)) => {
+                for (k, v) in input {
+                    let name = match *k {
+                        Value::String(ref s) => s.clone(),
+                        ref other => serde_yaml::to_string(other)
+                            .map(|s| s.trim_start_matches("---").trim().to_owned())
+                            .unwrap_or_default(),
+                    };
+                    let child = if path.is_empty() {
+                        name
+                    } else {
+                        format!("{}.{}", path, name)
+                    };
+                    match known.get(k) {
+                        None => unknowns.push(child),
+                        Some(known) => collect_unknown_fields(v, known, &child, unknowns),
+                    }
+                }
+            }
+            (Value::Sequence(input), Value::Sequence(known)) => {
+                for (i, (v, known)) in input.iter().zip(known.iter()).enumerate() {
+                    let child = if path.is_empty() {
+                        i.to_string()
+                    } else {
+                        format!("{}.{}", path, i)
+                    };
+                    collect_unknown_fields(v, known, &child, unknowns);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -91,4 +317,72 @@ duration: 24
 
         Ok(())
     }
+
+    #[derive(PartialEq, Debug, Default, Serialize, Deserialize)]
+    struct Inner {
+        #[serde(default)]
+        pub timeout_secs: u64,
+    }
+
+    #[derive(PartialEq, Debug, Default, Serialize, Deserialize)]
+    struct Outer {
+        #[serde(default)]
+        pub threads: usize,
+
+        #[serde(default)]
+        pub legacy: bool,
+
+        #[serde(flatten)]
+        pub inner: Inner,
+    }
+
+    #[test]
+    fn config_schema_works() -> Result<(), Box<dyn std::error::Error>> {
+        use self::evolution::{ConfigSchema, ConfigWarning};
+
+        let schema = ConfigSchema {
+            aliases: &[
+                ("root.timeout", "root.timeout_secs"),
+                ("root.thread", "root.threads"),
+            ],
+            deprecations: &[("root.legacy", "it has no effect")],
+        };
+        let yaml = r#"---
+root:
+  timeout: 10
+  thread: 1
+  threads: 3
+  legacy: true
+  timeout_millis: 10000
+  unknown:
+    foo: bar
+"#;
+        let value = serde_yaml::from_str(yaml)?;
+        let (config, warnings): (::std::collections::BTreeMap<String, Outer>, _) =
+            schema.deserialize(value)?;
+        let outer = &config["root"];
+        assert_eq!(outer.threads, 3);
+        assert_eq!(outer.inner.timeout_secs, 10);
+        assert_eq!(
+            warnings,
+            vec![
+                ConfigWarning::DeprecatedField {
+                    path: "root.legacy".to_owned(),
+                    note: "it has no effect".to_owned(),
+                },
+                ConfigWarning::RenamedField {
+                    path: "root.timeout".to_owned(),
+                    replacement: "root.timeout_secs".to_owned(),
+                },
+                ConfigWarning::RenamedField {
+                    path: "root.thread".to_owned(),
+                    replacement: "root.threads".to_owned(),
+                },
+                // `#[serde(flatten)]`されたフィールドに紛れた未知のフィールドも検出される
+                ConfigWarning::UnknownField("root.timeout_millis".to_owned()),
+                ConfigWarning::UnknownField("root.unknown".to_owned()),
+            ]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,223 @@
+//! デーモン内で長時間動作する`Future`群の状態を一覧するための仕組み.
+//!
+//! スレッドダンプの fibers 版に相当するもので、セグメントが停滞している場合の調査等に利用する.
+//!
+//! 各`Future`は`TaskTracker`を保持して、ポーリングの度に自身の状態(現在のタスクの種類やキューの長さ)を
+//! 報告する. 報告された状態は`dump`関数で取得できる.
+//!
+//! また、外部(e.g., ウォッチドッグ)から`request_cancel`関数でタスクに処理の中断を要求することもできる.
+//! 要求に応じるかどうかは、各タスクの実装次第となる.
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref REGISTRY: Mutex<BTreeMap<u64, Arc<Mutex<TaskEntry>>>> = Mutex::new(BTreeMap::new());
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+struct TaskEntry {
+    kind: &'static str,
+    name: String,
+    state: &'static str,
+    state_changed_at: Instant,
+    progressed_at: Instant,
+    queues: BTreeMap<&'static str, usize>,
+    cancel_requested: bool,
+}
+
+/// あるタスクの状態のスナップショット.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TaskSnapshot {
+    /// タスクの識別子.
+    pub id: u64,
+
+    /// タスクの種類 (e.g., `"mds_node"`).
+    pub kind: String,
+
+    /// 同じ種類のタスクを区別するための名前 (e.g., ノードID).
+    pub name: String,
+
+    /// 現在の状態.
+    pub state: String,
+
+    /// 現在の状態に遷移してからの経過時間(ミリ秒).
+    pub state_elapsed_millis: u64,
+
+    /// 最後に進捗が報告されてからの経過時間(ミリ秒).
+    ///
+    /// 状態の遷移も進捗として扱われる.
+    pub progress_elapsed_millis: u64,
+
+    /// タスクが保持しているキュー群の長さ.
+    pub queues: BTreeMap<String, usize>,
+}
+
+/// タスクの状態を報告するためのオブジェクト.
+///
+/// 破棄されると、そのタスクはダンプの対象から外れる.
+#[derive(Debug)]
+pub struct TaskTracker {
+    id: u64,
+    entry: Arc<Mutex<TaskEntry>>,
+}
+impl TaskTracker {
+    /// 新しいタスクを登録する.
+    ///
+    /// 初期状態は`"Init"`となる.
+    pub fn new(kind: &'static str, name: &str) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        let now = Instant::now();
+        let entry = Arc::new(Mutex::new(TaskEntry {
+            kind,
+            name: name.to_owned(),
+            state: "Init",
+            state_changed_at: now,
+            progressed_at: now,
+            queues: BTreeMap::new(),
+            cancel_requested: false,
+        }));
+        if let Ok(mut registry) = REGISTRY.lock() {
+            registry.insert(id, entry.clone());
+        }
+        TaskTracker { id, entry }
+    }
+
+    /// タスクの現在の状態を報告する.
+    ///
+    /// 状態が変化していない場合には、経過時間はリセットされない.
+    pub fn set_state(&self, state: &'static str) {
+        if let Ok(mut entry) = self.entry.lock() {
+            if entry.state != state {
+                let now = Instant::now();
+                entry.state = state;
+                entry.state_changed_at = now;
+                entry.progressed_at = now;
+            }
+        }
+    }
+
+    /// 現在の状態のまま、処理が進んだことを報告する.
+    pub fn report_progress(&self) {
+        if let Ok(mut entry) = self.entry.lock() {
+            entry.progressed_at = Instant::now();
+        }
+    }
+
+    /// 処理の中断が要求されているかどうかを返す.
+    ///
+    /// 要求は一度取り出されるとクリアされる.
+    pub fn take_cancel_request(&self) -> bool {
+        self.entry
+            .lock()
+            .map(|mut entry| std::mem::take(&mut entry.cancel_requested))
+            .unwrap_or(false)
+    }
+
+    /// タスクが保持しているキューの長さを報告する.
+    pub fn set_queue_len(&self, queue: &'static str, len: usize) {
+        if let Ok(mut entry) = self.entry.lock() {
+            entry.queues.insert(queue, len);
+        }
+    }
+}
+impl Drop for TaskTracker {
+    fn drop(&mut self) {
+        if let Ok(mut registry) = REGISTRY.lock() {
+            registry.remove(&self.id);
+        }
+    }
+}
+
+/// 登録されている全てのタスクの状態を、種類と名前の順に並べて返す.
+pub fn dump() -> Vec<TaskSnapshot> {
+    let entries = REGISTRY
+        .lock()
+        .map(|registry| {
+            registry
+                .iter()
+                .map(|(&id, entry)| (id, entry.clone()))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let mut tasks = entries
+        .into_iter()
+        .filter_map(|(id, entry)| entry.lock().ok().map(|e| e.snapshot(id)))
+        .collect::<Vec<_>>();
+    tasks.sort_by(|a, b| (&a.kind, &a.name).cmp(&(&b.kind, &b.name)));
+    tasks
+}
+
+/// 指定されたタスクに処理の中断を要求する.
+///
+/// タスクが存在しない場合には`false`を返す.
+pub fn request_cancel(id: u64) -> bool {
+    let entry = REGISTRY
+        .lock()
+        .ok()
+        .and_then(|registry| registry.get(&id).cloned());
+    if let Some(entry) = entry {
+        if let Ok(mut entry) = entry.lock() {
+            entry.cancel_requested = true;
+            return true;
+        }
+    }
+    false
+}
+
+impl TaskEntry {
+    fn snapshot(&self, id: u64) -> TaskSnapshot {
+        TaskSnapshot {
+            id,
+            kind: self.kind.to_owned(),
+            name: self.name.clone(),
+            state: self.state.to_owned(),
+            state_elapsed_millis: duration_to_millis(self.state_changed_at.elapsed()),
+            progress_elapsed_millis: duration_to_millis(self.progressed_at.elapsed()),
+            queues: self
+                .queues
+                .iter()
+                .map(|(&k, &v)| (k.to_owned(), v))
+                .collect(),
+        }
+    }
+}
+
+fn duration_to_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(name: &str) -> Option<TaskSnapshot> {
+        dump()
+            .into_iter()
+            .find(|t| t.kind == "test_task" && t.name == name)
+    }
+
+    #[test]
+    fn task_tracker_works() {
+        let tracker = TaskTracker::new("test_task", "foo");
+        assert_eq!(find("foo").map(|t| t.state), Some("Init".to_owned()));
+
+        tracker.set_state("Running");
+        tracker.set_queue_len("pending", 3);
+        let task = find("foo").unwrap();
+        assert_eq!(task.state, "Running");
+        assert_eq!(task.queues.get("pending"), Some(&3));
+
+        assert!(!tracker.take_cancel_request());
+        assert!(request_cancel(task.id));
+        assert!(tracker.take_cancel_request());
+        assert!(!tracker.take_cancel_request());
+
+        drop(tracker);
+        assert!(!request_cancel(task.id));
+        assert_eq!(find("foo"), None);
+    }
+}
@@ -0,0 +1,322 @@
+//! Distributed Tracing 関連の機能を提供する create.
+//!
+//! # tail-based sampling
+//!
+//! 通常のサンプリング(head sampling)では、リクエストの開始時にトレースするかどうかが決まるので、
+//! 稀にしか発生しない遅いリクエストのトレースは、ほとんど報告されない。
+//!
+//! `TailSampler`を用いた場合には、全てのリクエストのスパンが生成され、
+//! スパンの受信側の`TailSampling`がリクエスト毎にそれらを保留しておく。
+//! リクエストの起点のスパン(`ThreadLocalTracer::span`で開始されたもの)が終了した時点で、
+//! head sampling で選ばれていたか、所要時間が閾値以上であれば、保留していたトレース全体が報告される。
+use rustracing::sampler::{NullSampler, Sampler};
+use rustracing::tag::{StdTag, Tag, TagValue};
+use rustracing_jaeger::span::{CandidateSpan, FinishedSpan, SpanContextState};
+use rustracing_jaeger::{Span, Tracer};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use trackable::error::{ErrorKind, TrackableError};
+
+pub mod otlp;
+
+/// リクエストの起点のスパンに付与される、head sampling の判定結果を表すタグの名前。
+///
+/// `TailSampler`を用いている場合にのみ付与される。
+pub const HEAD_SAMPLED_TAG: &str = "sampling.head";
+
+/// 一つのトレースについて保留しておくスパンの最大数。
+const MAX_PENDING_SPANS_PER_TRACE: usize = 512;
+
+thread_local! {
+    static TRACER: RefCell<Option<Tracer>> = RefCell::new(None);
+
+    // 直前に`TailSampler`が行った head sampling の判定結果
+    static HEAD_SAMPLED: Cell<Option<bool>> = Cell::new(None);
+}
+
+/// A tracer containing a thread local `Tracer`.
+#[derive(Debug, Clone)]
+pub struct ThreadLocalTracer {
+    tracer: Arc<Mutex<Tracer>>,
+}
+
+impl ThreadLocalTracer {
+    /// Returns a new `ThreadLocalTracer`.
+    pub fn new(tracer: Tracer) -> Self {
+        Self {
+            tracer: Arc::new(Mutex::new(tracer)),
+        }
+    }
+
+    /// Returns a new `Span` applying the specified function.
+    pub fn span<F>(&self, f: F) -> Span
+    where
+        F: FnOnce(&Tracer) -> Span,
+    {
+        TRACER.with(|local_tracer| {
+            if local_tracer.borrow().is_none() {
+                if let Ok(global_tracer) = self.tracer.try_lock() {
+                    *local_tracer.borrow_mut() = Some(global_tracer.clone());
+                }
+            }
+            if let Some(ref t) = *local_tracer.borrow() {
+                HEAD_SAMPLED.with(|h| h.set(None));
+                let mut span = f(t);
+                if let Some(sampled) = HEAD_SAMPLED.with(Cell::take) {
+                    span.set_tag(|| Tag::new(HEAD_SAMPLED_TAG, sampled));
+                }
+                span
+            } else {
+                Span::inactive()
+            }
+        })
+    }
+}
+
+/// tail-based sampling 用の`Sampler`。
+///
+/// 全てのスパンをサンプリング対象とした上で、内側の`Sampler`による判定結果を、
+/// `ThreadLocalTracer::span`経由でリクエストの起点のスパンにタグとして記録する。
+#[derive(Debug, Clone)]
+pub struct TailSampler<S>(pub S);
+impl<S> Sampler<SpanContextState> for TailSampler<S>
+where
+    S: Sampler<SpanContextState>,
+{
+    fn is_sampled(&self, span: &CandidateSpan) -> bool {
+        let sampled = self.0.is_sampled(span);
+        HEAD_SAMPLED.with(|h| h.set(Some(sampled)));
+        true
+    }
+}
+
+/// tail-based sampling の設定。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TailSamplingConfig {
+    /// tail-based sampling を行うかどうか。
+    ///
+    /// 有効な場合には、サンプリングレートに関わらず全てのリクエストのスパンが生成される。
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// この時間以上かかったリクエストは、head sampling の結果に関わらずトレース全体が報告される。
+    #[serde(
+        rename = "latency_threshold_millis",
+        default = "default_tail_sampling_latency_threshold",
+        with = "::serde_ext::duration_millis"
+    )]
+    pub latency_threshold: Duration,
+
+    /// 完了待ちのスパンを保留しておくトレースの最大数。
+    ///
+    /// 超過した場合には、最も古いトレースのスパンが破棄される。
+    #[serde(default = "default_tail_sampling_max_pending_traces")]
+    pub max_pending_traces: usize,
+}
+impl Default for TailSamplingConfig {
+    fn default() -> Self {
+        TailSamplingConfig {
+            enabled: false,
+            latency_threshold: default_tail_sampling_latency_threshold(),
+            max_pending_traces: default_tail_sampling_max_pending_traces(),
+        }
+    }
+}
+
+fn default_tail_sampling_latency_threshold() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_tail_sampling_max_pending_traces() -> usize {
+    4096
+}
+
+/// スパンの受信側で、tail-based sampling を行うためのバッファ。
+///
+/// `TailSampler`を用いたトレーサが生成したスパンを受け取り、報告すべきものを返す。
+pub struct TailSampling {
+    config: TailSamplingConfig,
+    pending: HashMap<String, Vec<FinishedSpan>>,
+    pending_order: VecDeque<String>,
+}
+impl TailSampling {
+    /// 新しい`TailSampling`インスタンスを生成する。
+    pub fn new(config: TailSamplingConfig) -> Self {
+        TailSampling {
+            config,
+            pending: HashMap::new(),
+            pending_order: VecDeque::new(),
+        }
+    }
+
+    /// 終了したスパンを受け取り、報告すべきスパン群を返す。
+    ///
+    /// リクエストの起点以外のスパンは、起点のスパンが終了するまで保留される。
+    pub fn observe(&mut self, span: FinishedSpan) -> Vec<FinishedSpan> {
+        let trace_id = span.context().state().trace_id().to_string();
+        let head_sampled = span
+            .tags()
+            .iter()
+            .find(|t| t.name() == HEAD_SAMPLED_TAG)
+            .map(|t| match *t.value() {
+                TagValue::Boolean(sampled) => sampled,
+                _ => false,
+            });
+        let head_sampled = if let Some(head_sampled) = head_sampled {
+            head_sampled
+        } else {
+            self.hold(trace_id, span);
+            return Vec::new();
+        };
+
+        let mut spans = self.pending.remove(&trace_id).unwrap_or_default();
+        self.pending_order.retain(|id| *id != trace_id);
+        let elapsed = span
+            .finish_time()
+            .duration_since(span.start_time())
+            .unwrap_or_default();
+        if head_sampled || elapsed >= self.config.latency_threshold {
+            spans.push(span);
+            spans
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// 完了待ちのスパンを保持しているトレースの数を返す。
+    pub fn pending_traces(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn hold(&mut self, trace_id: String, span: FinishedSpan) {
+        if !self.pending.contains_key(&trace_id) {
+            if self.pending_order.len() >= self.config.max_pending_traces {
+                if let Some(oldest) = self.pending_order.pop_front() {
+                    self.pending.remove(&oldest);
+                }
+            }
+            self.pending_order.push_back(trace_id.clone());
+        }
+        let spans = self.pending.entry(trace_id).or_default();
+        if spans.len() < MAX_PENDING_SPANS_PER_TRACE {
+            spans.push(span);
+        }
+    }
+}
+
+/// An extension of `Span`.
+pub trait SpanExt {
+    /// Logs the specified error into the given span.
+    fn log_error<K: ErrorKind>(&mut self, e: &TrackableError<K>);
+}
+
+impl SpanExt for Span {
+    fn log_error<K: ErrorKind>(&mut self, e: &TrackableError<K>) {
+        self.set_tag(StdTag::error);
+        self.log(|log| {
+            let kind = format!("{:?}", e.kind());
+            log.error().kind(kind).message(e.to_string());
+        })
+    }
+}
+
+/// Returns a tracer which samples nothing.
+pub fn make_null_tracer() -> ThreadLocalTracer {
+    let (tracer, _) = rustracing_jaeger::Tracer::new(NullSampler);
+    ThreadLocalTracer::new(tracer)
+}
+
+#[cfg(test)]
+mod tests {
+    use rustracing::sampler::{AllSampler, NullSampler};
+    use std::thread;
+
+    use super::*;
+
+    fn make_tail_sampling_tracer<S>(
+        head: S,
+    ) -> (ThreadLocalTracer, rustracing_jaeger::span::SpanReceiver)
+    where
+        S: Sampler<SpanContextState> + Send + Sync + 'static,
+    {
+        let (tracer, span_rx) = Tracer::new(TailSampler(head));
+        (ThreadLocalTracer::new(tracer), span_rx)
+    }
+
+    #[test]
+    fn tail_sampling_reports_slow_traces() {
+        let (tracer, span_rx) = make_tail_sampling_tracer(NullSampler);
+        let mut tail = TailSampling::new(TailSamplingConfig {
+            enabled: true,
+            latency_threshold: Duration::from_millis(50),
+            max_pending_traces: 16,
+        });
+
+        // 速いリクエストは報告されない
+        {
+            let root = tracer.span(|t| t.span("fast").start());
+            let _child = root.child("child", |span| span.start());
+        }
+        let mut reported = Vec::new();
+        while let Ok(span) = span_rx.try_recv() {
+            reported.extend(tail.observe(span));
+        }
+        assert!(reported.is_empty());
+        assert_eq!(tail.pending_traces(), 0);
+
+        // 遅いリクエストは、トレース全体が報告される
+        {
+            let root = tracer.span(|t| t.span("slow").start());
+            let _child = root.child("child", |span| span.start());
+            thread::sleep(Duration::from_millis(60));
+        }
+        while let Ok(span) = span_rx.try_recv() {
+            reported.extend(tail.observe(span));
+        }
+        let operations = reported
+            .iter()
+            .map(|s| s.operation_name().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(operations, vec!["child", "slow"]);
+    }
+
+    #[test]
+    fn tail_sampling_reports_head_sampled_traces() {
+        let (tracer, span_rx) = make_tail_sampling_tracer(AllSampler);
+        let mut tail = TailSampling::new(TailSamplingConfig {
+            enabled: true,
+            latency_threshold: Duration::from_secs(60),
+            max_pending_traces: 16,
+        });
+        {
+            let root = tracer.span(|t| t.span("fast").start());
+            let _child = root.child("child", |span| span.start());
+        }
+        let mut reported = Vec::new();
+        while let Ok(span) = span_rx.try_recv() {
+            reported.extend(tail.observe(span));
+        }
+        assert_eq!(reported.len(), 2);
+    }
+
+    #[test]
+    fn tail_sampling_limits_pending_traces() {
+        let mut tail = TailSampling::new(TailSamplingConfig {
+            enabled: true,
+            latency_threshold: Duration::from_secs(60),
+            max_pending_traces: 2,
+        });
+
+        // 起点のスパンが終了しないトレースは、上限までしか保留されない
+        let (tracer, span_rx) = Tracer::new(AllSampler);
+        for _ in 0..3 {
+            let _span = tracer.span("orphan").start();
+        }
+        while let Ok(span) = span_rx.try_recv() {
+            assert!(tail.observe(span).is_empty());
+        }
+        assert_eq!(tail.pending_traces(), 2);
+    }
+}
@@ -0,0 +1,841 @@
+//! トレースのスパンを、OTLP (OpenTelemetry Protocol) で collector に送信するためのモジュール。
+//!
+//! Jaeger agent を経由せずに、OpenTelemetry collector 等に直接スパンを送れるようにする。
+//! HTTP で JSON を送る方式(`http/json`)と、gRPC で protobuf を送る方式(`grpc`)に対応している。
+//! ただし`grpc`を使うには、`grpc` feature を有効にしてビルドする必要がある。
+//!
+//! 送信は専用のスレッドで行われるので、collector の応答が遅くてもスパンの受信は滞らない。
+//! 送信待ちのバッチが上限に達した場合や、送信に失敗した場合には、スパンは再送されずに破棄される
+//! (Jaeger agent への送信と同様)。
+use prometrics::metrics::{Counter, MetricBuilder};
+use rustracing::tag::TagValue;
+use rustracing_jaeger::span::FinishedSpan;
+use serde::Serializer;
+use serde_json;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use trackable::error::{BoxError, ErrorKindExt, Failed, Failure};
+use url::Url;
+
+/// スパンの送信元として collector に伝えるサービス名。
+const SERVICE_NAME: &str = "frugalos";
+
+/// 一度の要求で送信するスパンの最大数。
+pub const MAX_SPANS_PER_REQUEST: usize = 512;
+
+/// OTLP によるスパンの送信に関する設定。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OtlpExporterConfig {
+    /// コレクタの URL (e.g., `http://127.0.0.1:4318`)。
+    ///
+    /// `http/json`でパスが省略された場合には`/v1/traces`が使われる。
+    /// `grpc`ではパスは使われず、ポートが省略された場合には`4317`が使われる。
+    pub endpoint: String,
+
+    /// 送信に使うプロトコル。
+    #[serde(default)]
+    pub protocol: OtlpProtocol,
+
+    /// 一回の送信のタイムアウト時間。
+    #[serde(
+        rename = "timeout_millis",
+        default = "default_otlp_timeout",
+        with = "::serde_ext::duration_millis"
+    )]
+    pub timeout: Duration,
+
+    /// 送信待ちにしておけるバッチの最大数。
+    ///
+    /// collector の応答が遅く、これを超えた場合には、新たに報告されたスパンは破棄される。
+    #[serde(default = "default_otlp_max_pending_batches")]
+    pub max_pending_batches: usize,
+}
+
+/// OTLP の送信プロトコル。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OtlpProtocol {
+    /// HTTP 上で JSON 形式のメッセージを送信する。
+    #[serde(rename = "http/json")]
+    HttpJson,
+
+    /// gRPC で protobuf 形式のメッセージを送信する。
+    ///
+    /// `grpc` feature を有効にしてビルドされていない場合には、`OtlpReporter`の生成に失敗する。
+    #[serde(rename = "grpc")]
+    Grpc,
+}
+impl Default for OtlpProtocol {
+    fn default() -> Self {
+        OtlpProtocol::HttpJson
+    }
+}
+
+fn default_otlp_timeout() -> Duration {
+    Duration::from_secs(3)
+}
+
+fn default_otlp_max_pending_batches() -> usize {
+    16
+}
+
+/// OTLP でスパンを送信する。
+///
+/// 実際の送信は、生成時に起動される専用のスレッドで行われる。
+#[derive(Debug, Clone)]
+pub struct OtlpReporter {
+    tx: SyncSender<Vec<FinishedSpan>>,
+    dropped_spans: Counter,
+}
+impl OtlpReporter {
+    /// 新しい`OtlpReporter`インスタンスを生成する。
+    pub fn new(config: &OtlpExporterConfig) -> Result<Self, Failure> {
+        let transport = track!(Transport::new(config))?;
+        let dropped_spans = track!(dropped_spans_counter("queue_full"))?;
+        let failed_spans = track!(dropped_spans_counter("export_failed"))?;
+        let (tx, rx) = mpsc::sync_channel::<Vec<FinishedSpan>>(config.max_pending_batches);
+        track!(thread::Builder::new()
+            .name("otlp_reporter".to_owned())
+            .spawn(move || {
+                while let Ok(spans) = rx.recv() {
+                    for chunk in spans.chunks(MAX_SPANS_PER_REQUEST) {
+                        if transport.send(chunk).is_err() {
+                            failed_spans.add_u64(chunk.len() as u64);
+                        }
+                    }
+                }
+            })
+            .map_err(failed))?;
+        Ok(OtlpReporter { tx, dropped_spans })
+    }
+
+    /// スパン群を送信待ちのキューに入れる。
+    ///
+    /// キューが一杯の場合には、スパン群は送信されずに破棄される。
+    pub fn report(&self, spans: Vec<FinishedSpan>) {
+        match self.tx.try_send(spans) {
+            Ok(()) => {}
+            Err(TrySendError::Full(spans)) | Err(TrySendError::Disconnected(spans)) => {
+                self.dropped_spans.add_u64(spans.len() as u64);
+            }
+        }
+    }
+}
+
+fn dropped_spans_counter(reason: &str) -> Result<Counter, Failure> {
+    track!(MetricBuilder::new()
+        .namespace("frugalos")
+        .subsystem("otlp")
+        .counter("dropped_spans_total")
+        .help("Number of spans dropped without being exported")
+        .label("reason", reason)
+        .default_registry()
+        .finish()
+        .map_err(|e| Failed.takes_over(e)))
+}
+
+fn failed<E: Into<BoxError>>(e: E) -> Failure {
+    Failed.cause(e)
+}
+
+/// スパンの送信方式。
+enum Transport {
+    Http(HttpTransport),
+    #[cfg(feature = "grpc")]
+    Grpc(grpc::GrpcTransport),
+}
+impl Transport {
+    fn new(config: &OtlpExporterConfig) -> Result<Self, Failure> {
+        let url = track!(Url::parse(&config.endpoint)
+            .map_err(|e| failed(format!("{}: {:?}", e, config.endpoint))))?;
+        track_assert_eq!(
+            url.scheme(),
+            "http",
+            Failed,
+            "Unsupported OTLP endpoint scheme (TLS is not supported): {:?}",
+            config.endpoint
+        );
+        match config.protocol {
+            OtlpProtocol::HttpJson => {
+                let transport = track!(HttpTransport::new(&url, config.timeout))?;
+                Ok(Transport::Http(transport))
+            }
+            OtlpProtocol::Grpc => track!(Self::grpc(&url, config.timeout)),
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    fn grpc(url: &Url, timeout: Duration) -> Result<Self, Failure> {
+        let transport = track!(grpc::GrpcTransport::new(url, timeout))?;
+        Ok(Transport::Grpc(transport))
+    }
+
+    #[cfg(not(feature = "grpc"))]
+    fn grpc(_url: &Url, _timeout: Duration) -> Result<Self, Failure> {
+        track_panic!(
+            Failed,
+            "OTLP over gRPC requires frugalos to be built with the `grpc` feature"
+        );
+    }
+
+    fn send(&self, spans: &[FinishedSpan]) -> Result<(), Failure> {
+        match *self {
+            Transport::Http(ref t) => track!(t.send(spans)),
+            #[cfg(feature = "grpc")]
+            Transport::Grpc(ref t) => track!(t.send(spans)),
+        }
+    }
+}
+
+/// OTLP/HTTP で JSON 形式のスパンを送信する。
+#[derive(Debug)]
+struct HttpTransport {
+    host: String,
+    port: u16,
+    path: String,
+    timeout: Duration,
+}
+impl HttpTransport {
+    fn new(url: &Url, timeout: Duration) -> Result<Self, Failure> {
+        let host = track_assert_some!(
+            url.host_str(),
+            Failed,
+            "No host in the OTLP endpoint: {}",
+            url
+        );
+        let port = url.port_or_known_default().unwrap_or(80);
+        let path = match url.path() {
+            "" | "/" => "/v1/traces".to_owned(),
+            path => path.to_owned(),
+        };
+        Ok(HttpTransport {
+            host: host.to_owned(),
+            port,
+            path,
+            timeout,
+        })
+    }
+
+    fn send(&self, spans: &[FinishedSpan]) -> Result<(), Failure> {
+        let body = track!(serde_json::to_vec(&encode_spans(spans)).map_err(failed))?;
+        track!(self.post(&body))
+    }
+
+    fn post(&self, body: &[u8]) -> Result<(), Failure> {
+        let addr = track!((self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(failed))?
+        .next();
+        let addr = track_assert_some!(
+            addr,
+            Failed,
+            "Cannot resolve the OTLP endpoint: {}:{}",
+            self.host,
+            self.port
+        );
+        let mut stream = track!(TcpStream::connect_timeout(&addr, self.timeout).map_err(failed))?;
+        track!(stream.set_read_timeout(Some(self.timeout)).map_err(failed))?;
+        track!(stream.set_write_timeout(Some(self.timeout)).map_err(failed))?;
+
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.host,
+            self.port,
+            body.len()
+        );
+        track!(stream.write_all(head.as_bytes()).map_err(failed))?;
+        track!(stream.write_all(body).map_err(failed))?;
+        track!(stream.flush().map_err(failed))?;
+
+        let mut status_line = String::new();
+        track!(BufReader::new(stream)
+            .read_line(&mut status_line)
+            .map_err(failed))?;
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok());
+        let status = track_assert_some!(
+            status,
+            Failed,
+            "Malformed response from the OTLP endpoint: {:?}",
+            status_line
+        );
+        track_assert!(
+            200 <= status && status < 300,
+            Failed,
+            "The OTLP endpoint rejected spans: {:?}",
+            status_line.trim_end()
+        );
+        Ok(())
+    }
+}
+
+// 以降は、OTLP の`ExportTraceServiceRequest`の表現
+//
+// JSON では、ID は16進数表記、64ビット整数は10進数の文字列で表す。
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportTraceServiceRequest {
+    resource_spans: Vec<ResourceSpans>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceSpans {
+    resource: Resource,
+    scope_spans: Vec<ScopeSpans>,
+}
+
+#[derive(Debug, Serialize)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Debug, Serialize)]
+struct ScopeSpans {
+    scope: Scope,
+    spans: Vec<OtlpSpan>,
+}
+
+#[derive(Debug, Serialize)]
+struct Scope {
+    name: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OtlpSpan {
+    #[serde(serialize_with = "serialize_trace_id")]
+    trace_id: (u64, u64),
+    #[serde(serialize_with = "serialize_span_id")]
+    span_id: u64,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_parent_span_id"
+    )]
+    parent_span_id: Option<u64>,
+    name: String,
+    kind: u8,
+    #[serde(serialize_with = "serialize_decimal")]
+    start_time_unix_nano: u64,
+    #[serde(serialize_with = "serialize_decimal")]
+    end_time_unix_nano: u64,
+    attributes: Vec<KeyValue>,
+    events: Vec<Event>,
+    status: Status,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Event {
+    #[serde(serialize_with = "serialize_decimal")]
+    time_unix_nano: u64,
+    name: String,
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    code: u8,
+}
+
+#[derive(Debug, Serialize)]
+struct KeyValue {
+    key: String,
+    value: AnyValue,
+}
+
+#[derive(Debug, Serialize)]
+enum AnyValue {
+    #[serde(rename = "stringValue")]
+    String(String),
+    #[serde(rename = "boolValue")]
+    Bool(bool),
+    #[serde(rename = "intValue")]
+    Int(#[serde(serialize_with = "serialize_decimal")] i64),
+    #[serde(rename = "doubleValue")]
+    Double(f64),
+}
+
+fn serialize_trace_id<S: Serializer>(id: &(u64, u64), serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:016x}{:016x}", id.0, id.1))
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn serialize_span_id<S: Serializer>(id: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:016x}", id))
+}
+
+fn serialize_parent_span_id<S: Serializer>(
+    id: &Option<u64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match *id {
+        Some(ref id) => serialize_span_id(id, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_decimal<S: Serializer, T: ::std::fmt::Display>(
+    n: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(n)
+}
+
+// `Span.SpanKind`の値
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+const SPAN_KIND_CLIENT: u8 = 3;
+const SPAN_KIND_PRODUCER: u8 = 4;
+const SPAN_KIND_CONSUMER: u8 = 5;
+
+// `Status.StatusCode`の値
+const STATUS_CODE_UNSET: u8 = 0;
+const STATUS_CODE_ERROR: u8 = 2;
+
+fn encode_spans(spans: &[FinishedSpan]) -> ExportTraceServiceRequest {
+    ExportTraceServiceRequest {
+        resource_spans: vec![ResourceSpans {
+            resource: Resource {
+                attributes: vec![KeyValue {
+                    key: "service.name".to_owned(),
+                    value: AnyValue::String(SERVICE_NAME.to_owned()),
+                }],
+            },
+            scope_spans: vec![ScopeSpans {
+                scope: Scope { name: SERVICE_NAME },
+                spans: spans.iter().map(encode_span).collect(),
+            }],
+        }],
+    }
+}
+
+fn encode_span(span: &FinishedSpan) -> OtlpSpan {
+    let state = span.context().state();
+    let trace_id = state.trace_id();
+    let mut kind = SPAN_KIND_INTERNAL;
+    let mut status = STATUS_CODE_UNSET;
+    let attributes = span
+        .tags()
+        .iter()
+        .map(|t| {
+            match (t.name(), t.value()) {
+                ("span.kind", &TagValue::String(ref v)) => {
+                    kind = match v.as_ref() {
+                        "server" => SPAN_KIND_SERVER,
+                        "client" => SPAN_KIND_CLIENT,
+                        "producer" => SPAN_KIND_PRODUCER,
+                        "consumer" => SPAN_KIND_CONSUMER,
+                        _ => SPAN_KIND_INTERNAL,
+                    };
+                }
+                ("error", &TagValue::Boolean(true)) => status = STATUS_CODE_ERROR,
+                _ => {}
+            }
+            let value = match *t.value() {
+                TagValue::String(ref v) => AnyValue::String(v.to_string()),
+                TagValue::Boolean(v) => AnyValue::Bool(v),
+                TagValue::Integer(v) => AnyValue::Int(v),
+                TagValue::Float(v) => AnyValue::Double(v),
+            };
+            KeyValue {
+                key: t.name().to_owned(),
+                value,
+            }
+        })
+        .collect();
+    let events = span
+        .logs()
+        .iter()
+        .map(|log| {
+            let name = log
+                .fields()
+                .iter()
+                .find(|f| f.name() == "event")
+                .map_or("log", |f| f.value())
+                .to_owned();
+            let attributes = log
+                .fields()
+                .iter()
+                .map(|f| KeyValue {
+                    key: f.name().to_owned(),
+                    value: AnyValue::String(f.value().to_owned()),
+                })
+                .collect();
+            Event {
+                time_unix_nano: unix_nanos(log.time()),
+                name,
+                attributes,
+            }
+        })
+        .collect();
+    OtlpSpan {
+        trace_id: (trace_id.high, trace_id.low),
+        span_id: state.span_id(),
+        parent_span_id: span.references().first().map(|r| r.span().span_id()),
+        name: span.operation_name().to_owned(),
+        kind,
+        start_time_unix_nano: unix_nanos(span.start_time()),
+        end_time_unix_nano: unix_nanos(span.finish_time()),
+        attributes,
+        events,
+        status: Status { code: status },
+    }
+}
+
+fn unix_nanos(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+/// gRPC での送信。
+///
+/// OTLP の protobuf 定義から生成されたコードは持たないので、メッセージは手でエンコードしている。
+#[cfg(feature = "grpc")]
+mod grpc {
+    use grpcio::{
+        self, CallOption, ChannelBuilder, Client, EnvBuilder, GrpcSlice, Marshaller, MessageReader,
+        Method, MethodType,
+    };
+    use rustracing_jaeger::span::FinishedSpan;
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use trackable::error::{Failed, Failure};
+    use url::Url;
+
+    use super::{
+        encode_spans, failed, AnyValue, Event, ExportTraceServiceRequest, KeyValue, OtlpSpan,
+    };
+
+    /// ポートが省略された場合に使われる、OTLP/gRPC の標準のポート番号。
+    const DEFAULT_PORT: u16 = 4317;
+
+    const EXPORT_METHOD: Method<Vec<u8>, Vec<u8>> = Method {
+        ty: MethodType::Unary,
+        name: "/opentelemetry.proto.collector.trace.v1.TraceService/Export",
+        req_mar: Marshaller {
+            ser: ser_bytes,
+            de: de_bytes,
+        },
+        resp_mar: Marshaller {
+            ser: ser_bytes,
+            de: de_bytes,
+        },
+    };
+
+    pub struct GrpcTransport {
+        client: Client,
+        timeout: Duration,
+    }
+    impl GrpcTransport {
+        pub fn new(url: &Url, timeout: Duration) -> Result<Self, Failure> {
+            let host = track_assert_some!(
+                url.host_str(),
+                Failed,
+                "No host in the OTLP endpoint: {}",
+                url
+            );
+            let port = url.port().unwrap_or(DEFAULT_PORT);
+            let env = Arc::new(EnvBuilder::new().cq_count(1).name_prefix("otlp").build());
+            let channel = ChannelBuilder::new(env).connect(&format!("{}:{}", host, port));
+            Ok(GrpcTransport {
+                client: Client::new(channel),
+                timeout,
+            })
+        }
+
+        pub fn send(&self, spans: &[FinishedSpan]) -> Result<(), Failure> {
+            let request = encode_request(&encode_spans(spans));
+            let option = CallOption::default().timeout(self.timeout);
+            track!(self
+                .client
+                .unary_call(&EXPORT_METHOD, &request, option)
+                .map_err(failed))?;
+            Ok(())
+        }
+    }
+
+    #[allow(clippy::ptr_arg)]
+    fn ser_bytes(bytes: &Vec<u8>, buf: &mut GrpcSlice) -> grpcio::Result<()> {
+        *buf = GrpcSlice::from(&bytes[..]);
+        Ok(())
+    }
+
+    fn de_bytes(mut reader: MessageReader) -> grpcio::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(reader.len());
+        reader
+            .read_to_end(&mut bytes)
+            .map_err(|e| grpcio::Error::Codec(Box::new(e)))?;
+        Ok(bytes)
+    }
+
+    /// `ExportTraceServiceRequest`を protobuf 形式にエンコードする。
+    pub fn encode_request(request: &ExportTraceServiceRequest) -> Vec<u8> {
+        let mut w = ProtobufWriter::default();
+        for resource_spans in &request.resource_spans {
+            w.message(1, |w| {
+                w.message(1, |w| {
+                    for kv in &resource_spans.resource.attributes {
+                        w.message(1, |w| encode_key_value(w, kv));
+                    }
+                });
+                for scope_spans in &resource_spans.scope_spans {
+                    w.message(2, |w| {
+                        w.message(1, |w| w.string(1, scope_spans.scope.name));
+                        for span in &scope_spans.spans {
+                            w.message(2, |w| encode_span(w, span));
+                        }
+                    });
+                }
+            });
+        }
+        w.buf
+    }
+
+    fn encode_span(w: &mut ProtobufWriter, span: &OtlpSpan) {
+        let mut trace_id = Vec::with_capacity(16);
+        trace_id.extend_from_slice(&span.trace_id.0.to_be_bytes());
+        trace_id.extend_from_slice(&span.trace_id.1.to_be_bytes());
+        w.bytes(1, &trace_id);
+        w.bytes(2, &span.span_id.to_be_bytes());
+        if let Some(parent_span_id) = span.parent_span_id {
+            w.bytes(4, &parent_span_id.to_be_bytes());
+        }
+        w.string(5, &span.name);
+        w.varint_field(6, u64::from(span.kind));
+        w.fixed64(7, span.start_time_unix_nano);
+        w.fixed64(8, span.end_time_unix_nano);
+        for kv in &span.attributes {
+            w.message(9, |w| encode_key_value(w, kv));
+        }
+        for event in &span.events {
+            w.message(11, |w| encode_event(w, event));
+        }
+        w.message(15, |w| w.varint_field(3, u64::from(span.status.code)));
+    }
+
+    fn encode_event(w: &mut ProtobufWriter, event: &Event) {
+        w.fixed64(1, event.time_unix_nano);
+        w.string(2, &event.name);
+        for kv in &event.attributes {
+            w.message(3, |w| encode_key_value(w, kv));
+        }
+    }
+
+    fn encode_key_value(w: &mut ProtobufWriter, kv: &KeyValue) {
+        w.string(1, &kv.key);
+        w.message(2, |w| match kv.value {
+            AnyValue::String(ref v) => w.string(1, v),
+            AnyValue::Bool(v) => w.varint_field(2, u64::from(v)),
+            AnyValue::Int(v) => w.varint_field(3, v as u64),
+            AnyValue::Double(v) => w.fixed64(4, v.to_bits()),
+        });
+    }
+
+    /// protobuf のフィールドを順に書き込むためのバッファ。
+    #[derive(Default)]
+    struct ProtobufWriter {
+        buf: Vec<u8>,
+    }
+    impl ProtobufWriter {
+        fn varint(&mut self, mut n: u64) {
+            while n >= 0x80 {
+                self.buf.push((n as u8) | 0x80);
+                n >>= 7;
+            }
+            self.buf.push(n as u8);
+        }
+
+        fn key(&mut self, field: u32, wire_type: u32) {
+            self.varint(u64::from(field << 3 | wire_type));
+        }
+
+        fn varint_field(&mut self, field: u32, n: u64) {
+            self.key(field, 0);
+            self.varint(n);
+        }
+
+        fn fixed64(&mut self, field: u32, n: u64) {
+            self.key(field, 1);
+            self.buf.extend_from_slice(&n.to_le_bytes());
+        }
+
+        fn bytes(&mut self, field: u32, bytes: &[u8]) {
+            self.key(field, 2);
+            self.varint(bytes.len() as u64);
+            self.buf.extend_from_slice(bytes);
+        }
+
+        fn string(&mut self, field: u32, s: &str) {
+            self.bytes(field, s.as_bytes());
+        }
+
+        fn message<F>(&mut self, field: u32, f: F)
+        where
+            F: FnOnce(&mut ProtobufWriter),
+        {
+            let mut inner = ProtobufWriter::default();
+            f(&mut inner);
+            self.bytes(field, &inner.buf);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use rustracing::sampler::AllSampler;
+    use rustracing::tag::{StdTag, Tag};
+    use rustracing_jaeger::Tracer;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    fn make_spans() -> Vec<FinishedSpan> {
+        let (tracer, span_rx) = Tracer::new(AllSampler);
+        {
+            let root = tracer
+                .span("put_object")
+                .tag(Tag::new("object.id", "foo"))
+                .start();
+            let mut child = root.child("put_fragment", |span| {
+                span.tag(StdTag::span_kind("client"))
+                    .tag(Tag::new("lump.bytes", 10i64))
+                    .start()
+            });
+            child.set_tag(StdTag::error);
+            child.log(|log| {
+                log.error().message("Cannot connect");
+            });
+        }
+        let mut spans = Vec::new();
+        while let Ok(span) = span_rx.try_recv() {
+            spans.push(span);
+        }
+        spans
+    }
+
+    fn make_config(endpoint: &str) -> OtlpExporterConfig {
+        OtlpExporterConfig {
+            endpoint: endpoint.to_owned(),
+            protocol: OtlpProtocol::HttpJson,
+            timeout: Duration::from_secs(5),
+            max_pending_batches: 1,
+        }
+    }
+
+    fn http_transport(endpoint: &str) -> Result<HttpTransport, Failure> {
+        match track!(Transport::new(&make_config(endpoint)))? {
+            Transport::Http(t) => Ok(t),
+            #[cfg(feature = "grpc")]
+            Transport::Grpc(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn encode_spans_works() -> TestResult {
+        let spans = make_spans();
+        let json = track!(serde_json::to_value(&encode_spans(&spans)).map_err(failed))?;
+        let encoded = &json["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(encoded.as_array().map(Vec::len), Some(2));
+
+        let child = &encoded[0];
+        let root = &encoded[1];
+        assert_eq!(child["name"], "put_fragment");
+        assert_eq!(child["kind"], SPAN_KIND_CLIENT);
+        assert_eq!(child["status"]["code"], STATUS_CODE_ERROR);
+        assert_eq!(child["parentSpanId"], root["spanId"]);
+        assert_eq!(child["traceId"], root["traceId"]);
+        assert_eq!(child["traceId"].as_str().map(str::len), Some(32));
+        assert_eq!(child["spanId"].as_str().map(str::len), Some(16));
+        assert!(child["startTimeUnixNano"].is_string());
+        assert_eq!(child["events"][0]["name"], "error");
+        assert!(child["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|a| a["key"] == "lump.bytes" && a["value"]["intValue"] == "10"));
+
+        assert_eq!(root["name"], "put_object");
+        assert_eq!(root["kind"], SPAN_KIND_INTERNAL);
+        assert!(root.get("parentSpanId").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn http_transport_new_works() -> TestResult {
+        let transport = track!(http_transport("http://collector:4318"))?;
+        assert_eq!(transport.host, "collector");
+        assert_eq!(transport.port, 4318);
+        assert_eq!(transport.path, "/v1/traces");
+
+        let transport = track!(http_transport("http://collector/otlp/traces"))?;
+        assert_eq!(transport.port, 80);
+        assert_eq!(transport.path, "/otlp/traces");
+
+        assert!(http_transport("https://collector:4318").is_err());
+        assert!(http_transport("collector:4318").is_err());
+        Ok(())
+    }
+
+    #[cfg(not(feature = "grpc"))]
+    #[test]
+    fn grpc_requires_feature() {
+        let mut config = make_config("http://collector:4317");
+        config.protocol = OtlpProtocol::Grpc;
+        assert!(OtlpReporter::new(&config).is_err());
+    }
+
+    #[cfg(feature = "grpc")]
+    #[test]
+    fn encode_protobuf_request_works() {
+        let spans = make_spans();
+        let bytes = grpc::encode_request(&encode_spans(&spans));
+
+        // resource_spans (field 1, length-delimited)
+        assert_eq!(bytes[0], 0x0a);
+        let contains = |needle: &[u8]| bytes.windows(needle.len()).any(|w| w == needle);
+        // Span.name (field 5)
+        assert!(contains(b"\x2a\x0cput_fragment"));
+        assert!(contains(b"\x2a\x0aput_object"));
+        // Span.span_id (field 2, 8 bytes)
+        let span_id = spans[0].context().state().span_id().to_be_bytes();
+        let mut field = vec![0x12, 0x08];
+        field.extend_from_slice(&span_id);
+        assert!(contains(&field));
+    }
+
+    #[test]
+    fn otlp_reporter_report_works() -> TestResult {
+        let listener = track!(TcpListener::bind("127.0.0.1:0").map_err(failed))?;
+        let addr = track!(listener.local_addr().map_err(failed))?;
+        let collector = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !String::from_utf8_lossy(&request).contains("put_object") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        // 送信は別スレッドで行われるので、`report`は collector の応答を待たない
+        let endpoint = format!("http://{}", addr);
+        let reporter = track!(OtlpReporter::new(&make_config(&endpoint)))?;
+        reporter.report(make_spans());
+
+        let request = collector.join().unwrap();
+        assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Type: application/json\r\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+//! バケツへのアクセスの可否を、実行中に切り替えるためのモジュール.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use trackable::error::ErrorKindExt;
+
+use {ErrorKind, Result};
+
+/// バケツへの書き込みが許可されているかどうか.
+///
+/// `Clone`されたインスタンス間で状態が共有されるので、
+/// `set_read_only`による変更は、そのバケツの全てのセグメントのクライアントおよび MDS のノードに即座に反映される.
+#[derive(Debug, Clone, Default)]
+pub struct AccessMode {
+    read_only: Arc<AtomicBool>,
+}
+impl AccessMode {
+    /// 読み込み専用かどうかを返す.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// 読み込み専用とするかどうかを設定する.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
+    }
+
+    /// 書き込みが許可されていない場合には`ErrorKind::ReadOnly`エラーを返す.
+    pub fn check_writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            let e = ErrorKind::ReadOnly.cause("The bucket is read-only");
+            return Err(track!(e).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_mode_works() {
+        let mode = AccessMode::default();
+        assert!(!mode.is_read_only());
+        assert!(mode.check_writable().is_ok());
+
+        let shared = mode.clone();
+        shared.set_read_only(true);
+        assert!(mode.is_read_only());
+        let e = mode.check_writable().err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ReadOnly);
+    }
+}
@@ -0,0 +1,246 @@
+//! コミットされたオブジェクトの変更(追加と削除)の履歴.
+//!
+//! 変更の位置には、その変更を含むコマンドがコミットされたログの位置(= `ObjectVersion`)を用いる.
+//! 位置は全てのノードで共通なので、履歴の取得を途中から再開する際にはノードを区別する必要はない.
+use libfrugalos::entity::object::{ObjectId, ObjectVersion};
+use std::cmp;
+use std::collections::VecDeque;
+
+/// オブジェクトの変更の種類.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ObjectChangeKind {
+    /// オブジェクトが保存(ないし追記、復元)された.
+    Put,
+
+    /// オブジェクトが削除された.
+    Delete,
+}
+
+/// コミットされたオブジェクトの変更.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectChange {
+    /// 変更を含むコマンドがコミットされた位置.
+    pub position: ObjectVersion,
+
+    /// 変更の種類.
+    pub kind: ObjectChangeKind,
+
+    /// 変更されたオブジェクトの ID.
+    pub object_id: ObjectId,
+
+    /// 保存されたオブジェクトのバージョン、ないし削除されたオブジェクトのバージョン.
+    pub version: ObjectVersion,
+}
+
+/// 変更履歴の取得結果.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectChanges {
+    /// 指定位置よりも後にコミットされた変更群(位置の昇順).
+    pub changes: Vec<ObjectChange>,
+
+    /// 次に取得する際に指定する位置.
+    pub next: ObjectVersion,
+
+    /// 指定位置の直後からの変更の一部が、既に履歴から失われているかどうか.
+    ///
+    /// `true`の場合には、オブジェクトの一覧を取得し直すなどして、同期をやり直す必要がある.
+    pub lost: bool,
+}
+
+/// ノードが保持する、直近のオブジェクトの変更履歴.
+///
+/// 保持する変更の数は`capacity`までで、それを超えた場合には古いものから破棄される.
+#[derive(Debug)]
+pub(crate) struct ChangeLog {
+    changes: VecDeque<ObjectChange>,
+    capacity: usize,
+
+    // この位置よりも後の変更は全て履歴に含まれている
+    complete_after: ObjectVersion,
+
+    // 最後に記録した(変更を伴わないものも含む)コミットの位置
+    last_position: ObjectVersion,
+}
+impl ChangeLog {
+    pub fn new(capacity: usize) -> Self {
+        ChangeLog {
+            changes: VecDeque::new(),
+            capacity,
+            complete_after: ObjectVersion(0),
+            last_position: ObjectVersion(0),
+        }
+    }
+
+    /// `position`でコミットされたコマンドによる変更群を記録する.
+    pub fn record<I>(&mut self, position: ObjectVersion, changes: I)
+    where
+        I: IntoIterator<Item = (ObjectChangeKind, ObjectId, ObjectVersion)>,
+    {
+        for (kind, object_id, version) in changes {
+            if self.changes.len() >= self.capacity {
+                match self.changes.pop_front() {
+                    Some(c) => self.complete_after = c.position,
+                    None => {
+                        // 容量が0の場合は、何も保持しない
+                        self.complete_after = position;
+                        continue;
+                    }
+                }
+            }
+            self.changes.push_back(ObjectChange {
+                position,
+                kind,
+                object_id,
+                version,
+            });
+        }
+        self.last_position = cmp::max(self.last_position, position);
+    }
+
+    /// スナップショットからの復元等によって、`next_position`よりも前の変更が分からなくなったことを記録する.
+    pub fn reset(&mut self, next_position: ObjectVersion) {
+        self.changes.clear();
+        let position = ObjectVersion(next_position.0.saturating_sub(1));
+        self.complete_after = position;
+        self.last_position = position;
+    }
+
+    /// `after`よりも後にコミットされた変更を、最大`max`個まで返す.
+    ///
+    /// `after`が`None`の場合には、変更を含まず、現在の位置のみを返す.
+    pub fn changes_after(&self, after: Option<ObjectVersion>, max: usize) -> ObjectChanges {
+        let after = if let Some(after) = after {
+            after
+        } else {
+            return ObjectChanges {
+                changes: Vec::new(),
+                next: self.last_position,
+                lost: false,
+            };
+        };
+        let start = match self
+            .changes
+            .binary_search_by_key(&(after.0 + 1), |c| c.position.0)
+        {
+            Ok(mut i) => {
+                // 同じ位置の変更が複数ある場合には、その先頭から返す
+                while i > 0 && self.changes[i - 1].position == self.changes[i].position {
+                    i -= 1;
+                }
+                i
+            }
+            Err(i) => i,
+        };
+        let mut changes = self
+            .changes
+            .iter()
+            .skip(start)
+            .take(max)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // 一つのコマンドによる変更群が途中で分割されないようにする
+        if let Some(last) = changes.last().map(|c| c.position) {
+            let rest = self
+                .changes
+                .iter()
+                .skip(start + changes.len())
+                .take_while(|c| c.position == last)
+                .cloned()
+                .collect::<Vec<_>>();
+            changes.extend(rest);
+        }
+        let next = if start + changes.len() < self.changes.len() {
+            changes.last().map_or(after, |c| c.position)
+        } else {
+            cmp::max(after, self.last_position)
+        };
+        ObjectChanges {
+            changes,
+            next,
+            lost: after < self.complete_after,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(id: &str, version: u64) -> (ObjectChangeKind, ObjectId, ObjectVersion) {
+        (ObjectChangeKind::Put, id.to_owned(), ObjectVersion(version))
+    }
+
+    fn delete(id: &str, version: u64) -> (ObjectChangeKind, ObjectId, ObjectVersion) {
+        (
+            ObjectChangeKind::Delete,
+            id.to_owned(),
+            ObjectVersion(version),
+        )
+    }
+
+    fn ids(changes: &ObjectChanges) -> Vec<(ObjectChangeKind, &str)> {
+        changes
+            .changes
+            .iter()
+            .map(|c| (c.kind, c.object_id.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn change_log_works() {
+        let mut log = ChangeLog::new(10);
+        log.record(ObjectVersion(1), vec![put("foo", 1)]);
+        log.record(ObjectVersion(2), vec![]);
+        log.record(ObjectVersion(3), vec![put("bar", 3)]);
+        log.record(ObjectVersion(4), vec![delete("foo", 1), delete("bar", 3)]);
+
+        let current = log.changes_after(None, 10);
+        assert!(current.changes.is_empty());
+        assert_eq!(current.next, ObjectVersion(4));
+
+        let changes = log.changes_after(Some(ObjectVersion(0)), 10);
+        assert_eq!(
+            ids(&changes),
+            vec![
+                (ObjectChangeKind::Put, "foo"),
+                (ObjectChangeKind::Put, "bar"),
+                (ObjectChangeKind::Delete, "foo"),
+                (ObjectChangeKind::Delete, "bar"),
+            ]
+        );
+        assert_eq!(changes.next, ObjectVersion(4));
+        assert!(!changes.lost);
+
+        // 同じ位置の変更は分割されない
+        let changes = log.changes_after(Some(ObjectVersion(1)), 2);
+        assert_eq!(ids(&changes).len(), 3);
+        assert_eq!(changes.next, ObjectVersion(4));
+
+        let changes = log.changes_after(Some(ObjectVersion(0)), 1);
+        assert_eq!(ids(&changes), vec![(ObjectChangeKind::Put, "foo")]);
+        assert_eq!(changes.next, ObjectVersion(1));
+
+        let changes = log.changes_after(Some(ObjectVersion(4)), 10);
+        assert!(changes.changes.is_empty());
+        assert_eq!(changes.next, ObjectVersion(4));
+    }
+
+    #[test]
+    fn change_log_reports_lost_changes() {
+        let mut log = ChangeLog::new(2);
+        log.record(ObjectVersion(1), vec![put("foo", 1)]);
+        log.record(ObjectVersion(2), vec![put("bar", 2)]);
+        log.record(ObjectVersion(3), vec![put("baz", 3)]);
+
+        assert!(log.changes_after(Some(ObjectVersion(0)), 10).lost);
+        assert!(!log.changes_after(Some(ObjectVersion(1)), 10).lost);
+
+        log.reset(ObjectVersion(10));
+        let changes = log.changes_after(Some(ObjectVersion(3)), 10);
+        assert!(changes.lost);
+        assert!(changes.changes.is_empty());
+        assert_eq!(changes.next, ObjectVersion(9));
+        assert!(!log.changes_after(Some(ObjectVersion(9)), 10).lost);
+    }
+}
@@ -6,13 +6,37 @@ use protobuf;
 use {ErrorKind, Result};
 
 pub fn encode_machine(machine: &Machine) -> Result<Vec<u8>> {
-    let snapshot = machine.to_snapshot();
+    let snapshot = (
+        machine.to_snapshot(),
+        machine.to_tombstones(),
+        machine.to_sizes(),
+        machine.to_history(),
+        machine.to_mtimes(),
+        machine.to_delete_jobs(),
+        machine.relayout().cloned(),
+        (machine.to_pending_parts(), machine.is_witness()),
+    );
     let bytes = track!(protobuf::snapshot_encoder().encode_into_bytes(snapshot))?;
     Ok(bytes)
 }
 
-pub fn decode_machine(snapshot: &[u8]) -> Result<Machine> {
+/// スナップショットからマシンを復元する.
+///
+/// `witness`が`true`の場合、ないしウィットネスのマシンのスナップショットの場合には、
+/// 復元されたマシンはウィットネスとして動作する(`Machine::set_witness`を参照).
+pub fn decode_machine(snapshot: &[u8], witness: bool) -> Result<Machine> {
     track_assert!(!snapshot.is_empty(), ErrorKind::InvalidInput);
-    let snapshot = track!(protobuf::snapshot_decoder().decode_from_bytes(&snapshot))?;
-    Ok(Machine::from_snapshot(snapshot))
+    let (snapshot, tombstones, sizes, history, mtimes, jobs, relayout, (pending_parts, stripped)) =
+        track!(protobuf::snapshot_decoder().decode_from_bytes(&snapshot))?;
+    let mut machine = Machine::from_snapshot(snapshot)
+        .with_tombstones(tombstones)
+        .with_sizes(sizes)
+        .with_history(history, mtimes)
+        .with_delete_jobs(jobs)
+        .with_relayout(relayout)
+        .with_pending_parts(pending_parts);
+    if witness || stripped {
+        machine.set_witness();
+    }
+    Ok(machine)
 }
@@ -1,8 +1,13 @@
 //! mds の設定を定義しているcrate。
 
+use libfrugalos::time::Seconds;
+use std::collections::BTreeMap;
 use std::ops::Range;
+
 use std::time::Duration;
 
+use {ErrorKind, Result};
+
 /// `frugalos_mds` の設定。
 ///
 /// 以下の理由で現時点では module 毎に設定を struct で分けていない。
@@ -54,11 +59,167 @@ pub struct FrugalosMdsConfig {
     #[serde(default = "default_snapshot_threshold_max")]
     pub snapshot_threshold_max: usize,
 
+    /// 前回のスナップショット以降にコミットされたコマンドの合計サイズ(バイト単位)が、この値を超えた場合にもスナップショットを取る.
+    ///
+    /// エントリ数による閾値に達する前に、大きなコマンドでログが肥大化して再起動時間が延びることを防ぐ.
+    /// `null` を指定した場合には、サイズによる閾値は使われない.
+    #[serde(default = "default_snapshot_threshold_bytes")]
+    pub snapshot_threshold_bytes: Option<u64>,
+
     /// リーダー不在状況でオブジェクトが古くなりすぎているか否かを決める閾値の上限(この値を含む).
     ///
     /// この設定値の1単位は `node_polling_interval` である点に注意。
     #[serde(default = "default_staled_object_threshold")]
     pub staled_object_threshold: usize,
+
+    /// 削除されたオブジェクトを復元可能な状態で保持しておく期間(秒単位)。
+    ///
+    /// 指定された場合には、削除されたオブジェクトの実データは、この期間が過ぎるまで削除されない。
+    /// 指定されていない場合には、オブジェクトは即座に削除される。
+    ///
+    /// クラスタ単位の機能`tombstones`が有効になるまでは、指定されていても即座に削除される。
+    #[serde(rename = "tombstone_retention_secs", default)]
+    pub tombstone_retention: Option<Seconds>,
+
+    /// 猶予期間が過ぎた削除済みオブジェクトの破棄を、リーダが提案する間隔。
+    ///
+    /// 破棄は Raft のコマンドとして全ノードで同じように適用されるので、
+    /// 間隔を長くするほど、一度に破棄される削除済みオブジェクトの数が増え、ログのエントリ数が減る。
+    /// 猶予期間を過ぎた削除済みオブジェクトは、破棄されるまでスナップショットに含まれ続ける。
+    #[serde(
+        rename = "tombstone_gc_interval_millis",
+        default = "default_tombstone_gc_interval",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub tombstone_gc_interval: Duration,
+
+    /// 新しく追加されたノードが、起動前に既存のメンバからスナップショットを直接取得するかどうか。
+    ///
+    /// 有効な場合には、Raft 経由でログを同期するよりも早く、他のメンバに追い付くことができる。
+    #[serde(default)]
+    pub fetch_snapshot_from_peers: bool,
+
+    /// 既存のメンバからスナップショットを取得する際の、メンバ毎のタイムアウト。
+    ///
+    /// ウィットネスのスナップショットを読み込んだノードが、リーダから完全なスナップショットを取得し直す際にも使われ、
+    /// 取得に失敗した場合には、この期間を置いてから再試行する。
+    #[serde(
+        rename = "fetch_snapshot_timeout_millis",
+        default = "default_fetch_snapshot_timeout",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub fetch_snapshot_timeout: Duration,
+
+    /// リーダが`Consistent`な読み込みに、過半数のメンバへの確認なしで応答できる期間。
+    ///
+    /// 過半数のメンバからハートビートへの応答を得る度に、その送信時刻から、この期間だけ延長される。
+    /// Raft の選挙タイムアウト(`FRUGALOS_RAFT_MIN_TIMEOUT`)よりも十分に短くする必要があり、
+    /// それ以上の値を指定した場合には、設定の読み込みに失敗する。
+    /// 0 の場合には、全ての読み込みでハートビートによる確認が行われる。
+    #[serde(
+        rename = "consistent_read_lease_millis",
+        default = "default_consistent_read_lease",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub consistent_read_lease: Duration,
+
+    /// バージョン管理を行うバケツの ID から、上書きされたバージョンを保持する期間(秒単位)へのマップ。
+    ///
+    /// 保持期間中の過去のバージョンは、バージョン番号ないし時刻を指定して取得できる。
+    /// 保持期間の変更は、変更後に上書きされたバージョンにのみ適用される。
+    /// クラスタ単位の機能フラグ`history_retention`が有効でない場合には無視される。
+    #[serde(rename = "version_retention_secs", default)]
+    pub version_retention: BTreeMap<String, Seconds>,
+
+    /// 接頭辞指定での削除ジョブが、一度のコマンドで削除するオブジェクトの最大数。
+    #[serde(default = "default_delete_job_batch_size")]
+    pub delete_job_batch_size: usize,
+
+    /// 変更の監視のために、各ノードが保持するオブジェクトの変更の最大数。
+    ///
+    /// 監視の再開位置がこれよりも古い場合には、変更の一部が失われたものとして扱われる。
+    #[serde(default = "default_change_log_capacity")]
+    pub change_log_capacity: usize,
+
+    /// サーバ内のリーダ数の偏りを、定期的に解消するかどうか。
+    ///
+    /// 有効な場合には、リーダ数が期待値よりも多いサーバが、超過分のリーダ権を他のサーバに譲る。
+    /// リーダ権の委譲は再選出を伴うため、デフォルトでは無効。
+    #[serde(default = "default_leadership_balance")]
+    pub leadership_balance: bool,
+
+    /// リーダ数の偏りを確認する間隔。
+    ///
+    /// サーバ間で確認の時刻が揃わないように、実際の間隔はこの値の 0.5 倍から 1.5 倍の範囲でばらつく。
+    #[serde(
+        rename = "leadership_balance_interval_millis",
+        default = "default_leadership_balance_interval",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub leadership_balance_interval: Duration,
+
+    /// リーダ数の期待値を、どれだけ超えた場合にリーダ権を譲るかを決める閾値。
+    ///
+    /// 小さくするほどリーダ数は均等になるが、リーダの移動(再選出)が増える。
+    #[serde(default = "default_leadership_balance_slack")]
+    pub leadership_balance_slack: usize,
+
+    /// ウィットネスがスナップショットを取る際の閾値。
+    ///
+    /// ウィットネスはログを短く保つために、他のメンバよりも頻繁にスナップショットを取得する。
+    /// どのメンバがウィットネスとなるかは、構成管理用クラスタのバケツの属性(`mds_witnesses`)で指定する。
+    #[serde(default = "default_witness_snapshot_threshold")]
+    pub witness_snapshot_threshold: usize,
+
+    /// サーバ内で、同時に起動処理を行うノードの最大数。
+    ///
+    /// 起動処理は、Raft のログ(スナップショットを含む)のロードが完了するまでを指す。
+    /// 0 の場合には制限を設けない。
+    #[serde(default = "default_node_startup_concurrency")]
+    pub node_startup_concurrency: usize,
+
+    /// デバイス毎の、同時に起動処理を行うノードの最大数。
+    ///
+    /// 同じデバイス上のノードのログのロードは、デバイスのキューで競合するので、
+    /// 全てのノードを一斉に起動するよりも、少数ずつ起動した方が、最初のノードが利用可能になるまでの時間が短くなる。
+    /// 0 の場合には制限を設けない。
+    #[serde(default = "default_node_startup_concurrency_per_device")]
+    pub node_startup_concurrency_per_device: usize,
+
+    /// ノードの起動処理(Raft のログのロード)の完了を待つ最大時間。
+    ///
+    /// この時間が経過してもログのロードが完了しないノードは、起動処理が完了したものとして扱われ、
+    /// 後続のノードの起動が開始される(ロード自体はそのまま継続される)。
+    /// リーダの認識は待たないので、他のサーバのメンバの起動状況には左右されない。
+    #[serde(
+        rename = "node_startup_timeout_millis",
+        default = "default_node_startup_timeout",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub node_startup_timeout: Duration,
+
+    /// セグメントの再配置ジョブが、一度にコピーするオブジェクトの最大数。
+    ///
+    /// 一度のコピーが終わる毎に、進捗がコマンドとして記録される。
+    #[serde(default = "default_relayout_batch_size")]
+    pub relayout_batch_size: usize,
+
+    /// セグメントの再配置ジョブの、開始後および凍結後の待機時間。
+    ///
+    /// 開始後は、全てのクライアントが新しい配置に書き込むようになるまでの猶予で、
+    /// 凍結後は、凍結前にコミットされた書き込みの内容が保存されるまでの猶予となる。
+    #[serde(
+        rename = "relayout_grace_period_millis",
+        default = "default_relayout_grace_period",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub relayout_grace_period: Duration,
+
+    /// スナップショットを保存する際に、一つの lump に格納する最大バイト数。
+    ///
+    /// 指定されていない場合には、環境変数`RAFT_IO_MAX_LUMP_DATA_SIZE`の値、ないし lump の最大サイズが使われる。
+    #[serde(default)]
+    pub snapshot_chunk_size: Option<usize>,
 }
 
 impl FrugalosMdsConfig {
@@ -71,6 +232,21 @@ impl FrugalosMdsConfig {
             end: self.snapshot_threshold_max,
         }
     }
+
+    /// Raft の最小選挙タイムアウト時間に照らして、設定値を検証する.
+    ///
+    /// `consistent_read_lease`が`min_election_timeout`以上の場合には、
+    /// 旧リーダのリース期間中に新しいリーダが選出され得るので、エラーとなる.
+    pub fn validate(&self, min_election_timeout: Duration) -> Result<()> {
+        track_assert!(
+            self.consistent_read_lease < min_election_timeout,
+            ErrorKind::InvalidInput,
+            "`consistent_read_lease_millis` ({:?}) must be shorter than the minimum raft election timeout ({:?})",
+            self.consistent_read_lease,
+            min_election_timeout
+        );
+        Ok(())
+    }
 }
 
 impl Default for FrugalosMdsConfig {
@@ -84,7 +260,26 @@ impl Default for FrugalosMdsConfig {
             reelection_threshold: default_reelection_threshold(),
             snapshot_threshold_min: default_snapshot_threshold_min(),
             snapshot_threshold_max: default_snapshot_threshold_max(),
+            snapshot_threshold_bytes: default_snapshot_threshold_bytes(),
             staled_object_threshold: default_staled_object_threshold(),
+            tombstone_retention: None,
+            tombstone_gc_interval: default_tombstone_gc_interval(),
+            fetch_snapshot_from_peers: false,
+            fetch_snapshot_timeout: default_fetch_snapshot_timeout(),
+            consistent_read_lease: default_consistent_read_lease(),
+            version_retention: BTreeMap::new(),
+            delete_job_batch_size: default_delete_job_batch_size(),
+            change_log_capacity: default_change_log_capacity(),
+            leadership_balance: default_leadership_balance(),
+            leadership_balance_interval: default_leadership_balance_interval(),
+            leadership_balance_slack: default_leadership_balance_slack(),
+            witness_snapshot_threshold: default_witness_snapshot_threshold(),
+            node_startup_concurrency: default_node_startup_concurrency(),
+            node_startup_concurrency_per_device: default_node_startup_concurrency_per_device(),
+            node_startup_timeout: default_node_startup_timeout(),
+            relayout_batch_size: default_relayout_batch_size(),
+            relayout_grace_period: default_relayout_grace_period(),
+            snapshot_chunk_size: None,
         }
     }
 }
@@ -121,6 +316,66 @@ fn default_snapshot_threshold_max() -> usize {
     10_500
 }
 
+fn default_snapshot_threshold_bytes() -> Option<u64> {
+    Some(64 * 1024 * 1024)
+}
+
 fn default_staled_object_threshold() -> usize {
     50
 }
+
+fn default_tombstone_gc_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_delete_job_batch_size() -> usize {
+    1000
+}
+
+fn default_change_log_capacity() -> usize {
+    10_000
+}
+
+fn default_fetch_snapshot_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_consistent_read_lease() -> Duration {
+    Duration::from_millis(500)
+}
+
+fn default_leadership_balance() -> bool {
+    false
+}
+
+fn default_leadership_balance_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_leadership_balance_slack() -> usize {
+    1
+}
+
+fn default_witness_snapshot_threshold() -> usize {
+    1_000
+}
+
+fn default_node_startup_concurrency() -> usize {
+    64
+}
+
+fn default_node_startup_concurrency_per_device() -> usize {
+    8
+}
+
+fn default_node_startup_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_relayout_batch_size() -> usize {
+    100
+}
+
+fn default_relayout_grace_period() -> Duration {
+    Duration::from_secs(60)
+}
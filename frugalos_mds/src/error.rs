@@ -11,6 +11,8 @@ use std::{io, net, num, string};
 use trackable::error::TrackableError;
 use trackable::error::{ErrorKind as TrackableErrorKind, ErrorKindExt};
 
+use QuotaUsage;
+
 /// 発生し得るエラーの種類.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorKind {
@@ -25,6 +27,20 @@ pub enum ErrorKind {
     /// リーダ以外に対して要求が発行された.
     NotLeader,
 
+    /// 要求の期限までに処理が完了しなかった.
+    Timeout,
+
+    /// 書き込みによって割り当て量を超えてしまう.
+    ///
+    /// 値は、書き込みが拒否された時点の使用量.
+    QuotaExceeded(QuotaUsage),
+
+    /// セグメントの再配置の最終段階のため、オブジェクトの変更が一時的に凍結されている.
+    Frozen,
+
+    /// バケツが読み込み専用のため、オブジェクトの変更が拒否された.
+    ReadOnly,
+
     /// その他のエラー.
     Other,
 }
@@ -39,9 +55,8 @@ impl From<libfrugalos::Error> for Error {
             libfrugalos::ErrorKind::InvalidInput => ErrorKind::InvalidInput,
             libfrugalos::ErrorKind::NotLeader => ErrorKind::NotLeader,
             libfrugalos::ErrorKind::Unexpected(v) => ErrorKind::Unexpected(v),
-            libfrugalos::ErrorKind::Unavailable
-            | libfrugalos::ErrorKind::Timeout
-            | libfrugalos::ErrorKind::Other => ErrorKind::Other,
+            libfrugalos::ErrorKind::Timeout => ErrorKind::Timeout,
+            libfrugalos::ErrorKind::Unavailable | libfrugalos::ErrorKind::Other => ErrorKind::Other,
         };
         kind.takes_over(f).into()
     }
@@ -124,7 +139,16 @@ pub fn to_rpc_error(e: Error) -> libfrugalos::Error {
     let kind = match *e.kind() {
         ErrorKind::InvalidInput => libfrugalos::ErrorKind::InvalidInput,
         ErrorKind::NotLeader => libfrugalos::ErrorKind::NotLeader,
+        ErrorKind::Timeout => libfrugalos::ErrorKind::Timeout,
         ErrorKind::Unexpected(v) => libfrugalos::ErrorKind::Unexpected(v),
+        // NOTE: `libfrugalos`には割り当て量の超過を表す種類が無いので、
+        // 要求自体は妥当で、削除等の後であれば成功し得ることを示す`Unavailable`を使う.
+        // `PutObjectSizedRpc`の応答では、超過は`QuotaUsage`を伴う専用の値として返される.
+        ErrorKind::QuotaExceeded(_) => libfrugalos::ErrorKind::Unavailable,
+        // 凍結は再配置の完了と共に解除されるので、時間を置いて再試行すれば成功し得る
+        ErrorKind::Frozen => libfrugalos::ErrorKind::Unavailable,
+        // 読み込み専用は運用者によって解除されるので、凍結と同様に扱う
+        ErrorKind::ReadOnly => libfrugalos::ErrorKind::Unavailable,
         ErrorKind::Other => libfrugalos::ErrorKind::Other,
     };
     kind.takes_over(e).into()
@@ -36,18 +36,29 @@ extern crate serde_derive;
 #[macro_use]
 extern crate trackable;
 
+pub use access::AccessMode;
+pub use change::{ObjectChange, ObjectChangeKind, ObjectChanges};
 pub use config::FrugalosMdsConfig;
 pub use error::{Error, ErrorKind};
-pub use node::{Event, Node};
-pub use service::{Service, ServiceHandle};
+pub use node::{DeleteJobState, DeleteJobStatus, Event, Node};
+pub use precondition::Precondition;
+pub use quota::{Quota, QuotaUsage, SharedQuota};
+pub use revision::RevisionSelector;
+pub use service::{LeaderStatus, Service, ServiceHandle};
 
+mod access;
+mod change;
 mod codec;
 mod config;
 mod error;
 #[allow(missing_docs)]
 pub mod machine;
 mod node;
+pub mod precondition;
 mod protobuf;
+mod quota;
+mod revision;
+pub mod schema;
 mod server;
 mod service;
 
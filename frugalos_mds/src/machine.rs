@@ -1,12 +1,30 @@
 //! Raftに発行されたコマンド列を処理する状態機械.
 #![allow(clippy::ptr_arg)]
+use byteorder::{BigEndian, ByteOrder};
 use libfrugalos::entity::object::{Metadata, ObjectId, ObjectPrefix, ObjectSummary, ObjectVersion};
 use libfrugalos::expect::Expect;
 use libfrugalos::time::Seconds;
 use patricia_tree::PatriciaMap;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::ops::Range;
 
-use {Error, Result};
+use change::ObjectChangeKind;
+use node::{DeleteJobState, DeleteJobStatus};
+use {Error, ErrorKind, Precondition, Quota, QuotaUsage, Result, RevisionSelector};
+
+// `approximate_memory_bytes`で用いる、エントリ毎のおおよそのバイト数(ID を含む)
+const OBJECT_ENTRY_BYTES: u64 = 128;
+const TOMBSTONE_ENTRY_BYTES: u64 = 160;
+const HISTORY_ENTRY_BYTES: u64 = 192;
+
+// 保持しておく終了済みの削除ジョブの最大数
+const MAX_FINISHED_DELETE_JOBS: usize = 100;
+
+// ウィットネスのマシンで、破棄したデータの代わりに保持する目印
+//
+// 空ではなく`ObjectParts`としてもデコードできない値なので、
+// データの内容によって結果が変わるコマンド(追記等)の処理結果は、全データを保持するノードと一致する
+const WITNESS_PLACEHOLDER_DATA: &[u8] = b"\0";
 
 /// ノードの状態を管理するための状態機械.
 #[derive(Debug, Clone, Default)]
@@ -17,12 +35,74 @@ pub struct Machine {
     //   二つを分けた方がメモリ消費量が抑えられると期待されるため
     id_to_version: PatriciaMap<ObjectVersion>,
     id_to_data: HashMap<ObjectId, Vec<u8>>,
+
+    // オブジェクトの論理的な大きさ(バイト)
+    //
+    // 大きさが不明(ないし0)のオブジェクトはエントリを持たない
+    id_to_size: HashMap<ObjectId, u64>,
+
+    // `id_to_size`の値の合計
+    total_bytes: u64,
+
+    // 削除猶予期間中のオブジェクト群
+    tombstones: HashMap<ObjectId, Tombstone>,
+
+    // 削除猶予期間中のオブジェクトを、猶予期間の終了時刻順に並べた索引
+    //
+    // 破棄の対象を探す際に、全ての削除済みオブジェクトを走査しないようにするために使う
+    tombstone_expirations: BTreeSet<(u64, ObjectId)>,
+
+    // バージョン管理されているオブジェクトの、上書きされた過去のバージョン群(古い順)
+    history: HashMap<ObjectId, Vec<Revision>>,
+
+    // 過去のバージョンを、保持期間の終了時刻順に並べた索引
+    history_expirations: BTreeSet<(u64, ObjectId)>,
+
+    // オブジェクトの最新のバージョンが保存された時刻(UNIXエポックからのミリ秒)
+    //
+    // 時刻が不明なオブジェクト(タイムスタンプ導入前に保存されたもの等)はエントリを持たない
+    id_to_mtime: HashMap<ObjectId, u64>,
+
+    // 内容の保存の完了を待っている追記部分群(バージョン順)
+    pending_parts: BTreeMap<ObjectVersion, PendingPart>,
+
+    // 直前のコマンドの処理によって不要となった、追記部分ないし過去のバージョン群
+    released_parts: Vec<ObjectVersion>,
+
+    // 直前のコマンドの処理によるオブジェクトの変更群
+    changes: Vec<(ObjectChangeKind, ObjectId, ObjectVersion)>,
+
+    // コミット位置(= バージョン)とタイムスタンプの対応
+    commit_clock: CommitClock,
+
+    // 接頭辞指定での削除ジョブ群(実行中のものと、直近に終了したもの)
+    delete_jobs: BTreeMap<u64, DeleteJob>,
+
+    // セグメントの再配置ジョブ(実行中のものか、直近に終了したもの)
+    relayout: Option<RelayoutJob>,
+
+    // ウィットネスとして、`ObjectParts`以外のオブジェクトのデータを破棄するかどうか
+    witness: bool,
 }
 impl Machine {
     pub fn new() -> Self {
         Machine {
             id_to_version: PatriciaMap::new(),
             id_to_data: HashMap::new(),
+            id_to_size: HashMap::new(),
+            total_bytes: 0,
+            tombstones: HashMap::new(),
+            tombstone_expirations: BTreeSet::new(),
+            history: HashMap::new(),
+            history_expirations: BTreeSet::new(),
+            id_to_mtime: HashMap::new(),
+            pending_parts: BTreeMap::new(),
+            released_parts: Vec::new(),
+            changes: Vec::new(),
+            commit_clock: CommitClock::default(),
+            delete_jobs: BTreeMap::new(),
+            relayout: None,
+            witness: false,
         }
     }
     pub fn from_snapshot(snapshot: Snapshot) -> Self {
@@ -39,14 +119,139 @@ impl Machine {
                 Machine {
                     id_to_version,
                     id_to_data,
+                    id_to_size: HashMap::new(),
+                    total_bytes: 0,
+                    tombstones: HashMap::new(),
+                    tombstone_expirations: BTreeSet::new(),
+                    history: HashMap::new(),
+                    history_expirations: BTreeSet::new(),
+                    id_to_mtime: HashMap::new(),
+                    pending_parts: BTreeMap::new(),
+                    released_parts: Vec::new(),
+                    changes: Vec::new(),
+                    commit_clock: CommitClock::default(),
+                    delete_jobs: BTreeMap::new(),
+                    relayout: None,
+                    witness: false,
                 }
             }
             Snapshot::Patricia(id_to_version) => Machine {
                 id_to_version,
                 id_to_data: HashMap::new(),
+                id_to_size: HashMap::new(),
+                total_bytes: 0,
+                tombstones: HashMap::new(),
+                tombstone_expirations: BTreeSet::new(),
+                history: HashMap::new(),
+                history_expirations: BTreeSet::new(),
+                id_to_mtime: HashMap::new(),
+                pending_parts: BTreeMap::new(),
+                released_parts: Vec::new(),
+                changes: Vec::new(),
+                commit_clock: CommitClock::default(),
+                delete_jobs: BTreeMap::new(),
+                relayout: None,
+                witness: false,
             },
         }
     }
+    /// スナップショットから復元したマシンに、削除猶予期間中のオブジェクト群を設定する.
+    pub fn with_tombstones(mut self, tombstones: Vec<(ObjectId, Tombstone)>) -> Self {
+        self.tombstone_expirations = tombstones
+            .iter()
+            .map(|(id, t)| (t.expires_at, id.clone()))
+            .collect();
+        self.tombstones = tombstones.into_iter().collect();
+        self
+    }
+    /// スナップショットから復元したマシンに、オブジェクトの大きさを設定する.
+    pub fn with_sizes(mut self, sizes: Vec<(ObjectId, u64)>) -> Self {
+        self.id_to_size = sizes.into_iter().filter(|&(_, size)| size > 0).collect();
+        self.total_bytes = self.id_to_size.values().sum();
+        self
+    }
+    /// スナップショットから復元したマシンに、過去のバージョン群と更新時刻を設定する.
+    pub fn with_history(
+        mut self,
+        history: Vec<(ObjectId, Revision)>,
+        mtimes: Vec<(ObjectId, u64)>,
+    ) -> Self {
+        for (id, revision) in history {
+            self.history_expirations
+                .insert((revision.expires_at, id.clone()));
+            self.history.entry(id).or_default().push(revision);
+        }
+        self.id_to_mtime = mtimes.into_iter().collect();
+        self
+    }
+    /// スナップショットから復元したマシンに、削除ジョブ群を設定する.
+    pub fn with_delete_jobs(mut self, jobs: Vec<DeleteJob>) -> Self {
+        self.delete_jobs = jobs.into_iter().map(|j| (j.status.job_id, j)).collect();
+        self
+    }
+    pub fn to_delete_jobs(&self) -> Vec<DeleteJob> {
+        self.delete_jobs.values().cloned().collect()
+    }
+    /// スナップショットから復元したマシンに、セグメントの再配置ジョブを設定する.
+    pub fn with_relayout(mut self, job: Option<RelayoutJob>) -> Self {
+        self.relayout = job;
+        self
+    }
+    /// スナップショットから復元したマシンに、内容の保存を待っている追記部分群を設定する.
+    pub fn with_pending_parts(mut self, parts: Vec<PendingPart>) -> Self {
+        self.pending_parts = parts.into_iter().map(|p| (p.version, p)).collect();
+        self
+    }
+    /// マシンをウィットネスとして動作させる.
+    ///
+    /// ウィットネスのマシンは、オブジェクトのバージョンや大きさ等は全データを保持するノードと同様に管理するが、
+    /// メタデータとして保存されたデータ(追記されたオブジェクトの`ObjectParts`を除く)は保持しない.
+    /// 既に保持しているデータ(削除猶予期間中のものや過去のバージョンを含む)も、この時点で破棄される.
+    ///
+    /// 一度ウィットネスになったマシンは、全データを保持するマシンに戻ることはできない.
+    pub fn set_witness(&mut self) {
+        self.witness = true;
+        for data in self.id_to_data.values_mut() {
+            Self::strip_data(data);
+        }
+        for tombstone in self.tombstones.values_mut() {
+            Self::strip_data(&mut tombstone.data);
+        }
+        for revision in self.history.values_mut().flat_map(|rs| rs.iter_mut()) {
+            Self::strip_data(&mut revision.data);
+        }
+    }
+    /// マシンがウィットネスとして動作しているかどうかを返す.
+    pub fn is_witness(&self) -> bool {
+        self.witness
+    }
+    pub fn to_pending_parts(&self) -> Vec<PendingPart> {
+        self.pending_parts.values().cloned().collect()
+    }
+    pub fn to_history(&self) -> Vec<(ObjectId, Revision)> {
+        self.history
+            .iter()
+            .flat_map(|(id, revisions)| revisions.iter().map(move |r| (id.clone(), r.clone())))
+            .collect()
+    }
+    pub fn to_mtimes(&self) -> Vec<(ObjectId, u64)> {
+        self.id_to_mtime
+            .iter()
+            .map(|(id, &mtime)| (id.clone(), mtime))
+            .collect()
+    }
+    pub fn to_sizes(&self) -> Vec<(ObjectId, u64)> {
+        self.id_to_size
+            .iter()
+            .map(|(id, &size)| (id.clone(), size))
+            .collect()
+    }
+    pub fn to_tombstones(&self) -> Vec<(ObjectId, Tombstone)> {
+        self.tombstones
+            .iter()
+            .map(|(id, t)| (id.clone(), t.clone()))
+            .collect()
+    }
     pub fn to_snapshot(&self) -> Snapshot {
         if self.id_to_data.is_empty() {
             Snapshot::Patricia(self.id_to_version.clone())
@@ -70,28 +275,522 @@ impl Machine {
     pub fn is_empty(&self) -> bool {
         self.id_to_version.is_empty()
     }
+    /// オブジェクトの数と、論理的な大きさの合計を返す.
+    ///
+    /// 削除猶予期間中のオブジェクトや、上書きされた過去のバージョンは含まれない.
+    /// 大きさが記録されていないオブジェクトの分は、記録されているものの平均値で見積もられる.
+    pub fn usage(&self, quota: Quota) -> QuotaUsage {
+        let unsized_objects = (self.len() - self.id_to_size.len()) as u64;
+        QuotaUsage {
+            objects: self.len() as u64,
+            bytes: self
+                .total_bytes
+                .saturating_add(unsized_objects.saturating_mul(self.average_size())),
+            unsized_objects,
+            quota,
+        }
+    }
+    /// 大きさが`size`のオブジェクトを`object_id`として保存しても、割り当て量を超えないかどうかを確認する.
+    ///
+    /// 上書きによって使用量が増えない場合には、既に割り当て量を超えていても保存可能とする.
+    pub fn check_quota(&self, quota: Quota, object_id: &ObjectId, size: u64) -> Result<()> {
+        let usage = self.usage(quota);
+        let exists = self.id_to_version.contains_key(object_id);
+        let old_size = match self.id_to_size.get(object_id) {
+            Some(&size) => size,
+            None if exists => self.average_size(),
+            None => 0,
+        };
+        if let Some(max) = quota.max_objects {
+            track_assert!(
+                exists || usage.objects < max,
+                ErrorKind::QuotaExceeded(usage),
+                "Too many objects: max={}",
+                max
+            );
+        }
+        if let Some(max) = quota.max_bytes {
+            let bytes = (usage.bytes - old_size).saturating_add(size);
+            track_assert!(
+                size <= old_size || bytes <= max,
+                ErrorKind::QuotaExceeded(usage),
+                "Too many bytes: max={}, requested={}",
+                max,
+                bytes
+            );
+        }
+        Ok(())
+    }
+    /// `version`の位置でコミットされたコマンドのタイムスタンプ(UNIXエポックからの秒数)を記録する.
+    ///
+    /// 記録は`Precondition::IfUnmodifiedSince`の評価に使われる.
+    /// スナップショットには含まれず、ノード毎に内容が異なり得るので、
+    /// 評価はリーダが提案前に行う(`resolve_precondition`を参照).
+    pub fn record_commit(&mut self, version: ObjectVersion, unix_secs: u64) {
+        self.commit_clock.record(version, unix_secs);
+    }
+    /// 前提条件を、現在のバージョンを指定する`Expect`による条件に変換する.
+    ///
+    /// `Expect`以外の条件の評価にはノードのローカルな情報(コミット時刻の記録)が必要で、
+    /// そのままコマンドに含めるとノード間で評価結果が食い違い得る.
+    /// そのためリーダは提案前にここで条件を評価し、満たす場合には評価時点のバージョンに
+    /// 一致することを条件とするコマンドを提案する. 評価からコミットまでの間にオブジェクトが
+    /// 更新された場合には、コマンドの適用時に全ノードで一様に失敗する.
+    ///
+    /// 条件を満たさない場合には`ErrorKind::Unexpected`が返される.
+    pub fn resolve_precondition(
+        &self,
+        object_id: &ObjectId,
+        precondition: Precondition,
+    ) -> Result<Precondition> {
+        if let Precondition::Expect(_) = precondition {
+            return Ok(precondition);
+        }
+        track!(self.check_precondition(object_id, &precondition))?;
+        let expect = match self.id_to_version.get(object_id) {
+            Some(&version) => Expect::IfMatch(vec![version]),
+            None => Expect::None,
+        };
+        Ok(expect.into())
+    }
+    /// オブジェクトを保存する.
+    ///
+    /// `size`はオブジェクトの論理的な大きさ(バイト)で、不明な場合は0を指定する.
     pub fn put(
         &mut self,
         object_id: ObjectId,
         metadata: Metadata,
-        expect: &Expect,
+        size: u64,
+        expect: &Precondition,
     ) -> Result<Option<ObjectVersion>> {
-        track!(self.check_version(&object_id, &expect))?;
-        if metadata.data.is_empty() {
-            self.id_to_data.remove(&object_id);
+        track!(self.check_precondition(&object_id, expect))?;
+        let mut data = metadata.data;
+        if self.witness {
+            Self::strip_data(&mut data);
+        }
+        let old_data = if data.is_empty() {
+            self.id_to_data.remove(&object_id)
         } else {
-            self.id_to_data.insert(object_id.clone(), metadata.data);
+            self.id_to_data.insert(object_id.clone(), data)
+        };
+        self.release_parts(old_data);
+        self.take_size(&object_id);
+        self.id_to_mtime.remove(&object_id);
+        if size > 0 {
+            self.id_to_size.insert(object_id.clone(), size);
+            self.total_bytes += size;
         }
+        self.changes
+            .push((ObjectChangeKind::Put, object_id.clone(), metadata.version));
         Ok(self.id_to_version.insert(object_id, metadata.version))
     }
+    /// バージョン管理されているオブジェクトを保存する.
+    ///
+    /// `put`と異なり、上書きされたバージョンは`now`(UNIXエポックからのミリ秒)から`retention`の間、
+    /// 過去のバージョンとして保持され、`get_revision`で取得可能となる.
+    #[allow(clippy::too_many_arguments)]
+    pub fn put_versioned(
+        &mut self,
+        object_id: ObjectId,
+        metadata: Metadata,
+        size: u64,
+        expect: &Precondition,
+        now: u64,
+        retention: Seconds,
+    ) -> Result<Option<ObjectVersion>> {
+        track!(self.check_precondition(&object_id, expect))?;
+        if let Some(version) = self.id_to_version.get(&object_id).cloned() {
+            let expires_at = now + retention.0 * 1000;
+            let revision = Revision {
+                version,
+                data: self.id_to_data.remove(&object_id).unwrap_or_default(),
+                size: self.take_size(&object_id),
+                modified_at: self.id_to_mtime.get(&object_id).cloned().unwrap_or(0),
+                deleted_at: 0,
+                expires_at,
+            };
+            self.history
+                .entry(object_id.clone())
+                .or_default()
+                .push(revision);
+            self.history_expirations
+                .insert((expires_at, object_id.clone()));
+        }
+        let old = track!(self.put(object_id.clone(), metadata, size, &Expect::Any.into()))?;
+        self.id_to_mtime.insert(object_id, now);
+        Ok(old)
+    }
+    /// `put`で保存したオブジェクトの保存時刻(UNIXエポックからのミリ秒)を記録する.
+    ///
+    /// 記録は`list_by_time_range`で使われる. `now`が0の場合には時刻不明として扱う.
+    pub fn record_put_time(&mut self, object_id: ObjectId, now: u64) {
+        if now > 0 && self.id_to_version.contains_key(&object_id) {
+            self.id_to_mtime.insert(object_id, now);
+        }
+    }
+    /// 既存のオブジェクトに追記する部分のために、`version`を予約する.
+    ///
+    /// 予約された部分はまだオブジェクトの内容には含まれず、内容の保存後に`commit_part`を呼び出すことで追記される.
+    /// `now`(UNIXエポックからのミリ秒)から`put_content_timeout`が過ぎても追記されなかった部分は破棄され、
+    /// `take_released_parts`の結果に含まれるようになる.
+    /// 結果は、予約時点のオブジェクトのバージョン.
+    pub fn reserve_part(
+        &mut self,
+        object_id: &ObjectId,
+        version: ObjectVersion,
+        size: u64,
+        expect: &Precondition,
+        put_content_timeout: Seconds,
+        now: u64,
+    ) -> Result<ObjectVersion> {
+        self.expire_pending_parts(now);
+        track!(self.check_precondition(object_id, expect))?;
+        let current = track_assert_some!(
+            self.id_to_version.get(object_id).cloned(),
+            ErrorKind::InvalidInput,
+            "No such object: {:?}",
+            object_id
+        );
+        let parts = track!(self.object_parts(object_id))?;
+        let pending = self
+            .pending_parts
+            .values()
+            .filter(|p| p.object_id == *object_id)
+            .count();
+        track_assert!(
+            parts.len() + pending < ObjectParts::MAX_PARTS,
+            ErrorKind::InvalidInput,
+            "Too many parts: object_id={:?}, parts={}, pending={}",
+            object_id,
+            parts.len(),
+            pending
+        );
+        let part = PendingPart {
+            object_id: object_id.clone(),
+            version,
+            size,
+            put_content_timeout,
+            expires_at: now.saturating_add(put_content_timeout.0.saturating_mul(1000)),
+        };
+        self.pending_parts.insert(version, part);
+        Ok(current)
+    }
+    /// `reserve_part`で予約した部分を、オブジェクトの末尾に追記する.
+    ///
+    /// オブジェクトのバージョンは`version`に更新され、それまでの部分は`ObjectParts`としてオブジェクトのデータに記録される.
+    /// 保存時刻は`now`(UNIXエポックからのミリ秒)に更新される. `now`が0の場合には時刻不明として扱う.
+    ///
+    /// 予約後にオブジェクトが上書きや削除、あるいは後から予約された部分の追記によって更新されている場合には、
+    /// 部分は追記されずに破棄され、`ErrorKind::Unexpected`が返される.
+    /// 結果は、追記前のオブジェクトのバージョンと、予約時に指定された内容の修復までの猶予時間の組.
+    pub fn commit_part(
+        &mut self,
+        object_id: &ObjectId,
+        version: ObjectVersion,
+        now: u64,
+    ) -> Result<(ObjectVersion, Seconds)> {
+        self.expire_pending_parts(now);
+        let part = track_assert_some!(
+            self.pending_parts.remove(&version),
+            ErrorKind::InvalidInput,
+            "No such pending part (or already expired): object_id={:?}, version={:?}",
+            object_id,
+            version
+        );
+        if part.object_id != *object_id {
+            self.pending_parts.insert(version, part);
+            track_panic!(
+                ErrorKind::InvalidInput,
+                "The part {:?} is not reserved for the object {:?}",
+                version,
+                object_id
+            );
+        }
+        let current = self.id_to_version.get(object_id).cloned();
+        let parts = match current {
+            Some(current) if current < version => self.object_parts(object_id).ok(),
+            _ => None,
+        };
+        let (current, mut parts) = match (current, parts) {
+            (Some(current), Some(parts)) => (current, parts),
+            _ => {
+                // 予約した部分の内容は、もう参照されることはない
+                self.released_parts.push(version);
+                track_panic!(
+                    ErrorKind::Unexpected(current),
+                    "The object has been updated since the part was reserved: object_id={:?}, part={:?}",
+                    object_id,
+                    version
+                );
+            }
+        };
+
+        // 一度も追記されていないオブジェクトの大きさは、記録されていれば全体の大きさと等しい
+        let last_size = match parts.last_size {
+            None if parts.parts.is_empty() => self.id_to_size.get(object_id).cloned(),
+            size => size,
+        };
+        parts.parts.push(ObjectPart {
+            version: current,
+            size: last_size,
+        });
+        parts.last_size = Some(part.size);
+        self.id_to_data.insert(object_id.clone(), parts.encode());
+        self.id_to_version.insert(object_id.clone(), version);
+        if let Some(total) = self.id_to_size.get_mut(object_id) {
+            *total += part.size;
+            self.total_bytes += part.size;
+        }
+        if now > 0 {
+            self.id_to_mtime.insert(object_id.clone(), now);
+        } else {
+            self.id_to_mtime.remove(object_id);
+        }
+        self.changes
+            .push((ObjectChangeKind::Put, object_id.clone(), version));
+        Ok((current, part.put_content_timeout))
+    }
+    /// `now`(UNIXエポックからのミリ秒)の時点で内容の保存期限が過ぎている、予約された追記部分を破棄する.
+    ///
+    /// 破棄された部分は`take_released_parts`の結果に含まれるようになる.
+    pub fn expire_pending_parts(&mut self, now: u64) {
+        let expired = self
+            .pending_parts
+            .values()
+            .filter(|p| p.expires_at <= now)
+            .map(|p| p.version)
+            .collect::<Vec<_>>();
+        for version in expired {
+            self.pending_parts.remove(&version);
+            self.released_parts.push(version);
+        }
+    }
+    /// `now`(UNIXエポックからのミリ秒)の時点で内容の保存期限が過ぎている、予約された追記部分があるかどうか.
+    pub fn has_expired_pending_parts(&self, now: u64) -> bool {
+        self.pending_parts.values().any(|p| p.expires_at <= now)
+    }
+    /// バージョン管理されているオブジェクトを削除する.
+    ///
+    /// 過去のバージョンを保持しているオブジェクトの場合には、削除されたバージョンも
+    /// `now`(UNIXエポックからのミリ秒)に削除されたものとして過去のバージョンに加えられ、
+    /// 直前の過去のバージョンと同じ期間だけ保持される.
+    /// そうでない場合には`delete`と同様に破棄される.
+    ///
+    /// 結果は、削除されたバージョンと、それが過去のバージョンとして保持されたかどうかの組.
+    pub fn delete_versioned(
+        &mut self,
+        object_id: &ObjectId,
+        expect: &Precondition,
+        now: u64,
+    ) -> Result<Option<(ObjectVersion, bool)>> {
+        track!(self.check_precondition(object_id, expect))?;
+        if let Some(version) = self.retire_to_history(object_id, now) {
+            return Ok(Some((version, true)));
+        }
+        let old = track!(self.delete(object_id, &Expect::Any.into()))?;
+        Ok(old.map(|version| (version, false)))
+    }
+    /// 過去のバージョンを保持しているオブジェクトかどうか.
+    pub fn has_history(&self, object_id: &ObjectId) -> bool {
+        self.history.contains_key(object_id)
+    }
     pub fn delete(
         &mut self,
         object_id: &ObjectId,
-        expect: &Expect,
+        expect: &Precondition,
     ) -> Result<Option<ObjectVersion>> {
-        track!(self.check_version(object_id, &expect))?;
-        self.id_to_data.remove(object_id);
-        Ok(self.id_to_version.remove(object_id))
+        track!(self.check_precondition(object_id, expect))?;
+        let data = self.id_to_data.remove(object_id);
+        self.release_parts(data);
+        self.take_size(object_id);
+        self.drop_history(object_id);
+        let old = self.id_to_version.remove(object_id);
+        if let Some(version) = old {
+            self.changes
+                .push((ObjectChangeKind::Delete, object_id.clone(), version));
+        }
+        Ok(old)
+    }
+    /// オブジェクトを削除済みとして、`expires_at`(UNIXエポックからのミリ秒)まで保持する.
+    ///
+    /// 結果は、削除されたオブジェクトのバージョンと、
+    /// 同じIDで保持されていた古い削除済みオブジェクトのバージョン(もしあれば)の組.
+    pub fn tombstone(
+        &mut self,
+        object_id: &ObjectId,
+        expect: &Precondition,
+        expires_at: u64,
+    ) -> Result<Option<(ObjectVersion, Option<ObjectVersion>)>> {
+        track!(self.check_precondition(object_id, expect))?;
+        let version = if let Some(version) = self.id_to_version.remove(object_id) {
+            version
+        } else {
+            return Ok(None);
+        };
+        let modified_at = self.id_to_mtime.get(object_id).cloned().unwrap_or(0);
+        self.drop_history(object_id);
+        let tombstone = Tombstone {
+            version,
+            data: self.id_to_data.remove(object_id).unwrap_or_default(),
+            size: self.take_size(object_id),
+            modified_at,
+            expires_at,
+        };
+        self.tombstone_expirations
+            .insert((expires_at, object_id.clone()));
+        self.changes
+            .push((ObjectChangeKind::Delete, object_id.clone(), version));
+        let replaced = self.tombstones.insert(object_id.clone(), tombstone);
+        let replaced = replaced.map(|t| {
+            if t.expires_at != expires_at {
+                self.tombstone_expirations
+                    .remove(&(t.expires_at, object_id.clone()));
+            }
+            self.release_parts(Some(t.data));
+            t.version
+        });
+        Ok(Some((version, replaced)))
+    }
+    /// 削除猶予期間中のオブジェクトを復元する.
+    ///
+    /// 削除済みオブジェクトが無い場合には、`delete_versioned`で過去のバージョンに加えられた
+    /// 削除時のバージョンが、保持期間中であれば復元される.
+    /// 同じIDのオブジェクトが既に存在する場合にはエラーとなる.
+    /// `now`(UNIXエポックからのミリ秒)の時点で猶予期間が過ぎている場合には何もしない.
+    pub fn undelete(&mut self, object_id: &ObjectId, now: u64) -> Result<Option<ObjectVersion>> {
+        track!(self.check_version(object_id, &Expect::None))?;
+        match self.tombstones.get(object_id) {
+            Some(t) if now < t.expires_at => {}
+            _ => return Ok(self.restore_from_history(object_id, now)),
+        }
+        let tombstone = self.tombstones.remove(object_id).expect("Never fails");
+        self.tombstone_expirations
+            .remove(&(tombstone.expires_at, object_id.clone()));
+        if !tombstone.data.is_empty() {
+            self.id_to_data.insert(object_id.clone(), tombstone.data);
+        }
+        if tombstone.size > 0 {
+            self.id_to_size.insert(object_id.clone(), tombstone.size);
+            self.total_bytes += tombstone.size;
+        }
+        if tombstone.modified_at > 0 {
+            self.id_to_mtime
+                .insert(object_id.clone(), tombstone.modified_at);
+        }
+        self.id_to_version
+            .insert(object_id.clone(), tombstone.version);
+        self.changes
+            .push((ObjectChangeKind::Put, object_id.clone(), tombstone.version));
+        Ok(Some(tombstone.version))
+    }
+    /// `now`(UNIXエポックからのミリ秒)の時点で猶予期間が過ぎている削除済みオブジェクトを破棄する.
+    ///
+    /// 破棄されたオブジェクトのバージョンが、猶予期間の終了時刻順に返される.
+    pub fn purge_tombstones(&mut self, now: u64) -> Vec<ObjectVersion> {
+        let mut purged = Vec::new();
+        while self
+            .tombstone_expirations
+            .first()
+            .is_some_and(|&(expires_at, _)| expires_at <= now)
+        {
+            let (_, id) = self.tombstone_expirations.pop_first().expect("Never fails");
+            if let Some(t) = self.tombstones.remove(&id) {
+                self.release_parts(Some(t.data));
+                purged.push(t.version);
+            }
+        }
+        purged
+    }
+    /// `now`(UNIXエポックからのミリ秒)の時点で保持期間が過ぎている過去のバージョンを破棄する.
+    ///
+    /// 結果は破棄されたバージョン群.
+    pub fn purge_history(&mut self, now: u64) -> Vec<ObjectVersion> {
+        let mut purged = Vec::new();
+        while self
+            .history_expirations
+            .first()
+            .is_some_and(|&(expires_at, _)| expires_at <= now)
+        {
+            let (_, id) = self.history_expirations.pop_first().expect("Never fails");
+            let revisions = if let Some(revisions) = self.history.remove(&id) {
+                revisions
+            } else {
+                continue;
+            };
+            let (expired, alive): (Vec<_>, Vec<_>) =
+                revisions.into_iter().partition(|r| r.expires_at <= now);
+            for r in expired {
+                self.release_parts(Some(r.data));
+                purged.push(r.version);
+            }
+            if !alive.is_empty() {
+                self.history.insert(id, alive);
+            }
+        }
+        purged
+    }
+    /// `now`(UNIXエポックからのミリ秒)の時点で保持期間が過ぎている過去のバージョンがあるかどうか.
+    pub fn has_expired_history(&self, now: u64) -> bool {
+        self.history_expirations
+            .first()
+            .is_some_and(|&(expires_at, _)| expires_at <= now)
+    }
+    /// 保持されている過去のバージョン一覧を返す.
+    pub fn to_history_versions(&self) -> Vec<ObjectVersion> {
+        self.history
+            .values()
+            .flat_map(|revisions| revisions.iter().map(|r| r.version))
+            .collect()
+    }
+    /// `now`(UNIXエポックからのミリ秒)の時点で猶予期間が過ぎている削除済みオブジェクトがあるかどうか.
+    pub fn has_expired_tombstones(&self, now: u64) -> bool {
+        self.tombstone_expirations
+            .first()
+            .is_some_and(|&(expires_at, _)| expires_at <= now)
+    }
+    /// 削除猶予期間中のオブジェクトの数を返す.
+    pub fn tombstone_len(&self) -> usize {
+        self.tombstones.len()
+    }
+    /// マシンが消費しているメモリのおおよそのバイト数を返す.
+    ///
+    /// コマンドの処理毎に呼び出せるように、全エントリを走査せずに、エントリ数から見積もる.
+    pub fn approximate_memory_bytes(&self) -> u64 {
+        self.id_to_version.len() as u64 * OBJECT_ENTRY_BYTES
+            + self.tombstones.len() as u64 * TOMBSTONE_ENTRY_BYTES
+            + self.history.len() as u64 * HISTORY_ENTRY_BYTES
+    }
+    /// 削除猶予期間中のオブジェクトのバージョン一覧を返す.
+    pub fn to_tombstoned_versions(&self) -> Vec<ObjectVersion> {
+        self.tombstones.values().map(|t| t.version).collect()
+    }
+    /// 追記されたオブジェクト(削除猶予期間中のものや過去のバージョンを含む)を構成する、
+    /// 最新以外の部分のバージョン一覧を返す.
+    ///
+    /// 内容の保存を待っている、予約された追記部分のバージョンも含まれる.
+    pub fn to_part_versions(&self) -> Vec<ObjectVersion> {
+        self.id_to_data
+            .values()
+            .chain(self.tombstones.values().map(|t| &t.data))
+            .chain(
+                self.history
+                    .values()
+                    .flat_map(|rs| rs.iter().map(|r| &r.data)),
+            )
+            .filter_map(|data| ObjectParts::decode(data))
+            .flat_map(|parts| parts.versions().collect::<Vec<_>>())
+            .chain(self.pending_parts.keys().cloned())
+            .collect()
+    }
+    /// 直前までのコマンドの処理によって不要となった、追記部分ないし過去のバージョン群を取り出す.
+    ///
+    /// これらの部分の実データは、最新のバージョンと同様に削除する必要がある.
+    pub fn take_released_parts(&mut self) -> Vec<ObjectVersion> {
+        ::std::mem::take(&mut self.released_parts)
+    }
+    /// 前回の呼び出し以降のコマンドの処理による、オブジェクトの変更群を取り出す.
+    pub fn take_changes(&mut self) -> Vec<(ObjectChangeKind, ObjectId, ObjectVersion)> {
+        ::std::mem::take(&mut self.changes)
     }
     pub fn delete_version(
         &mut self,
@@ -105,7 +804,12 @@ impl Machine {
 
         if let Some(owner_id) = owner_id {
             let owner_id: ObjectId = track!(String::from_utf8(owner_id).map_err(Error::from))?;
-            self.id_to_data.remove(&owner_id);
+            let data = self.id_to_data.remove(&owner_id);
+            self.release_parts(data);
+            self.take_size(&owner_id);
+            self.drop_history(&owner_id);
+            self.changes
+                .push((ObjectChangeKind::Delete, owner_id.clone(), object_version));
             Ok(self.id_to_version.remove(&owner_id))
         } else {
             Ok(None)
@@ -115,11 +819,250 @@ impl Machine {
         let mut versions = Vec::new();
         for (object_id, version) in self.id_to_version.split_by_prefix(&object_prefix.0) {
             let id = track!(String::from_utf8(object_id).map_err(Error::from))?;
-            let _ = self.id_to_data.remove(&id);
+            let data = self.id_to_data.remove(&id);
+            self.release_parts(data);
+            self.take_size(&id);
+            self.drop_history(&id);
+            self.changes.push((ObjectChangeKind::Delete, id, version));
             versions.push(version);
         }
         Ok(versions)
     }
+    /// 接頭辞指定での削除ジョブを開始する.
+    ///
+    /// `version`はジョブを開始したコマンドのコミット位置で、これ以降に保存されたオブジェクトは
+    /// 接頭辞に一致してもジョブによって削除されることはない.
+    /// `retention`が指定された場合には、オブジェクトを即座に削除せずに削除猶予期間中とする.
+    pub fn start_delete_job(
+        &mut self,
+        job_id: u64,
+        prefix: ObjectPrefix,
+        retention: Option<Seconds>,
+        version: ObjectVersion,
+    ) {
+        let total = self.object_ids_by_prefix(&prefix).len() as u64;
+        let state = if total == 0 {
+            DeleteJobState::Completed
+        } else {
+            DeleteJobState::Running
+        };
+        let job = DeleteJob {
+            status: DeleteJobStatus {
+                job_id,
+                prefix,
+                state,
+                total,
+                processed: 0,
+                deleted: 0,
+            },
+            started_at: version,
+            retention,
+            finished_at: if total == 0 { Some(version) } else { None },
+        };
+        self.delete_jobs.insert(job_id, job);
+        self.forget_finished_delete_jobs();
+    }
+    /// 実行中の削除ジョブの対象のうち、まだ削除されていないオブジェクトの ID 一覧を返す.
+    pub fn delete_job_targets(&self, job_id: u64) -> Vec<ObjectId> {
+        let job = match self.delete_jobs.get(&job_id) {
+            Some(job) if job.status.state == DeleteJobState::Running => job,
+            _ => return Vec::new(),
+        };
+        let mut copy = self.id_to_version.clone();
+        copy.split_by_prefix(&job.status.prefix.0)
+            .into_iter()
+            .filter(|&(_, version)| version < job.started_at)
+            .filter_map(|(id, _)| String::from_utf8(id).ok())
+            .collect()
+    }
+    /// 削除ジョブの一つのバッチとして、指定されたオブジェクト群を削除する.
+    ///
+    /// ジョブの開始後に保存されたオブジェクトや、存在しないオブジェクトは無視される.
+    /// ジョブが実行中ではない場合には何もしない.
+    /// 削除猶予期間の起点は`now`(UNIXエポックからのミリ秒).
+    ///
+    /// 結果は、実データを即座に削除すべきオブジェクトのバージョン群で、
+    /// 猶予期間中のオブジェクトで置き換えられたものも含まれる.
+    pub fn delete_job_batch(
+        &mut self,
+        job_id: u64,
+        object_ids: &[ObjectId],
+        now: u64,
+    ) -> Vec<ObjectVersion> {
+        let (started_at, retention) = match self.delete_jobs.get(&job_id) {
+            Some(job) if job.status.state == DeleteJobState::Running => {
+                (job.started_at, job.retention)
+            }
+            _ => return Vec::new(),
+        };
+        let mut deleted = 0;
+        let mut released = Vec::new();
+        for id in object_ids {
+            if !self.id_to_version.get(id).is_some_and(|&v| v < started_at) {
+                continue;
+            }
+            deleted += 1;
+            let expect = Expect::Any.into();
+            if let Some(retention) = retention {
+                let expires_at = now + retention.0 * 1000;
+                if let Ok(Some((_, Some(replaced)))) = self.tombstone(id, &expect, expires_at) {
+                    released.push(replaced);
+                }
+            } else if let Ok(Some(version)) = self.delete(id, &expect) {
+                released.push(version);
+            }
+        }
+        let job = self.delete_jobs.get_mut(&job_id).expect("Never fails");
+        job.status.processed += object_ids.len() as u64;
+        job.status.deleted += deleted;
+        released
+    }
+    /// 削除ジョブを終了する.
+    ///
+    /// `cancelled`が`true`の場合には、利用者によって中断されたものとして扱う.
+    /// ジョブが実行中ではない場合には何もしない.
+    pub fn finish_delete_job(&mut self, job_id: u64, cancelled: bool, version: ObjectVersion) {
+        if let Some(job) = self.delete_jobs.get_mut(&job_id) {
+            if job.status.state != DeleteJobState::Running {
+                return;
+            }
+            job.status.state = if cancelled {
+                DeleteJobState::Cancelled
+            } else {
+                DeleteJobState::Completed
+            };
+            job.finished_at = Some(version);
+        }
+        self.forget_finished_delete_jobs();
+    }
+    /// 削除ジョブの進捗を返す.
+    pub fn delete_job_status(&self, job_id: u64) -> Option<DeleteJobStatus> {
+        self.delete_jobs.get(&job_id).map(|j| j.status.clone())
+    }
+    /// 実行中の削除ジョブの ID 一覧を返す.
+    pub fn running_delete_jobs(&self) -> Vec<u64> {
+        self.delete_jobs
+            .values()
+            .filter(|j| j.status.state == DeleteJobState::Running)
+            .map(|j| j.status.job_id)
+            .collect()
+    }
+    // 全ノードで同じ結果となるように、終了位置の古いものから忘れる.
+    fn forget_finished_delete_jobs(&mut self) {
+        let mut finished: Vec<_> = self
+            .delete_jobs
+            .values()
+            .filter_map(|j| j.finished_at.map(|v| (v, j.status.job_id)))
+            .collect();
+        finished.sort();
+        let excess = finished.len().saturating_sub(MAX_FINISHED_DELETE_JOBS);
+        for (_, job_id) in finished.into_iter().take(excess) {
+            self.delete_jobs.remove(&job_id);
+        }
+    }
+    /// セグメントの再配置ジョブを開始する.
+    ///
+    /// 同じ世代への再配置ジョブが既に存在する場合には何もしない.
+    pub fn start_relayout(&mut self, generation: u8, version: ObjectVersion) -> Result<()> {
+        if let Some(ref job) = self.relayout {
+            if job.generation == generation {
+                return Ok(());
+            }
+            track_assert_eq!(
+                job.state,
+                RelayoutState::Finished,
+                ErrorKind::InvalidInput,
+                "Another relayout job is running: {:?}",
+                job
+            );
+        }
+        self.relayout = Some(RelayoutJob {
+            generation,
+            started_at: version,
+            cursor: ObjectVersion(0),
+            copied: 0,
+            state: RelayoutState::Copying,
+        });
+        Ok(())
+    }
+    /// 再配置ジョブの進捗を記録する.
+    ///
+    /// 内容のコピーが完了した位置は後退しない.
+    pub fn record_relayout_progress(
+        &mut self,
+        generation: u8,
+        cursor: ObjectVersion,
+        copied: u64,
+    ) -> Result<()> {
+        let job = track!(self.relayout_job_mut(generation))?;
+        track_assert_ne!(job.state, RelayoutState::Finished, ErrorKind::InvalidInput);
+        if job.cursor < cursor {
+            job.cursor = cursor;
+            job.copied = copied;
+        }
+        Ok(())
+    }
+    /// 再配置ジョブを凍結状態にする.
+    ///
+    /// 凍結中は、オブジェクトを変更するコマンドは全て拒否される.
+    pub fn freeze_relayout(&mut self, generation: u8) -> Result<()> {
+        let job = track!(self.relayout_job_mut(generation))?;
+        if job.state == RelayoutState::Copying {
+            job.state = RelayoutState::Frozen;
+        }
+        Ok(())
+    }
+    /// 再配置ジョブを終了し、凍結状態を解除する.
+    pub fn finish_relayout(&mut self, generation: u8) -> Result<()> {
+        let job = track!(self.relayout_job_mut(generation))?;
+        job.state = RelayoutState::Finished;
+        Ok(())
+    }
+    /// 実行中ないし直近に終了した再配置ジョブを返す.
+    pub fn relayout(&self) -> Option<&RelayoutJob> {
+        self.relayout.as_ref()
+    }
+    /// 再配置ジョブによって、オブジェクトの変更が凍結されているかどうかを返す.
+    pub fn is_frozen(&self) -> bool {
+        self.relayout
+            .as_ref()
+            .is_some_and(|j| j.state == RelayoutState::Frozen)
+    }
+    /// 内容を保持する必要のあるバージョン(最新以外の追記部分や過去のバージョン、削除猶予期間中のものを含む)のうち、
+    /// `cursor`よりも新しいものを昇順に返す.
+    pub fn content_versions_after(&self, cursor: ObjectVersion) -> Vec<ObjectVersion> {
+        let mut versions = self.to_versions();
+        versions.extend(self.to_part_versions());
+        versions.extend(self.to_history_versions());
+        versions.extend(self.to_tombstoned_versions());
+        versions.retain(|&v| v > cursor);
+        versions.sort();
+        versions.dedup();
+        versions
+    }
+    fn relayout_job_mut(&mut self, generation: u8) -> Result<&mut RelayoutJob> {
+        let job = track_assert_some!(
+            self.relayout.as_mut(),
+            ErrorKind::InvalidInput,
+            "No relayout job"
+        );
+        track_assert_eq!(
+            job.generation,
+            generation,
+            ErrorKind::InvalidInput,
+            "Unexpected relayout generation"
+        );
+        Ok(job)
+    }
+    /// 接頭辞に一致するオブジェクトの ID 一覧を返す.
+    pub fn object_ids_by_prefix(&self, object_prefix: &ObjectPrefix) -> Vec<ObjectId> {
+        // NOTE: 現在の`PatriciaMap`には接頭辞を指定した走査がないので、複製を分割する
+        let mut copy = self.id_to_version.clone();
+        copy.split_by_prefix(&object_prefix.0)
+            .keys()
+            .filter_map(|id| String::from_utf8(id).ok())
+            .collect()
+    }
     pub fn get(&self, object_id: &ObjectId, expect: &Expect) -> Result<Option<Metadata>> {
         track!(self.check_version(object_id, &expect))?;
         Ok(self.id_to_version.get(object_id).cloned().map(|version| {
@@ -127,10 +1070,67 @@ impl Machine {
             Metadata { version, data }
         }))
     }
+    /// `selector`で指定された、最新ないし過去のバージョンのオブジェクトを返す.
+    ///
+    /// 時刻が指定された場合には、その時点(秒単位)で最新だったバージョンが返される.
+    /// 更新時刻が不明なバージョンは、指定時刻以前から存在していたものとして扱われる.
+    pub fn get_revision(
+        &self,
+        object_id: &ObjectId,
+        selector: RevisionSelector,
+        expect: &Expect,
+    ) -> Result<Option<Metadata>> {
+        let current = self.id_to_version.get(object_id).map(|&version| {
+            let modified_at = self.id_to_mtime.get(object_id).cloned().unwrap_or(0);
+            (version, self.get_data(object_id), modified_at, 0)
+        });
+        let history = self
+            .history
+            .get(object_id)
+            .into_iter()
+            .flat_map(|revisions| revisions.iter())
+            .map(|r| (r.version, r.data.clone(), r.modified_at, r.deleted_at));
+        let mut candidates = history.chain(current);
+        let found = match selector {
+            RevisionSelector::Version(v) => candidates.find(|&(version, ..)| version == v),
+            RevisionSelector::AsOf(unix_secs) => candidates
+                .rfind(|&(_, _, modified_at, _)| modified_at / 1000 <= unix_secs)
+                // 指定時刻には既に削除されていた
+                .filter(|&(.., deleted_at)| deleted_at == 0 || unix_secs < deleted_at / 1000),
+        };
+        let found = found.map(|(version, data, ..)| Metadata { version, data });
+        track!(expect
+            .validate(found.as_ref().map(|m| m.version))
+            .map_err(Error::from))?;
+        Ok(found)
+    }
     pub fn head(&self, object_id: &ObjectId, expect: &Expect) -> Result<Option<ObjectVersion>> {
         track!(self.check_version(object_id, &expect))?;
         Ok(self.id_to_version.get(object_id).cloned())
     }
+    /// 最新のバージョンが`range`(UNIXエポックからのミリ秒)の期間内に保存されたオブジェクト群を、
+    /// 保存時刻順に返す.
+    ///
+    /// 保存時刻が不明なオブジェクトは含まれない.
+    pub fn list_by_time_range(&self, range: Range<u64>) -> Vec<ObjectSummary> {
+        let mut list = self
+            .id_to_mtime
+            .iter()
+            .filter(|&(_, mtime)| range.contains(mtime))
+            .filter_map(|(id, &mtime)| {
+                let version = *self.id_to_version.get(id)?;
+                Some((
+                    mtime,
+                    ObjectSummary {
+                        id: id.clone(),
+                        version,
+                    },
+                ))
+            })
+            .collect::<Vec<_>>();
+        list.sort_by_key(|&(mtime, ref summary)| (mtime, summary.version));
+        list.into_iter().map(|(_, summary)| summary).collect()
+    }
     pub fn to_summaries(&self) -> Vec<ObjectSummary> {
         self.id_to_version
             .iter()
@@ -172,6 +1172,122 @@ impl Machine {
             .validate(self.id_to_version.get(object_id).cloned())
             .map_err(Error::from)
     }
+    fn check_precondition(&self, object_id: &ObjectId, precondition: &Precondition) -> Result<()> {
+        let version = self.id_to_version.get(object_id).cloned();
+        precondition.validate(version, |v| self.commit_clock.modified_at(v))
+    }
+    fn release_parts(&mut self, data: Option<Vec<u8>>) {
+        if let Some(parts) = data.as_ref().and_then(|data| ObjectParts::decode(data)) {
+            self.released_parts.extend(parts.versions());
+        }
+    }
+    // 追記先のオブジェクトの、最新以外の部分の一覧を返す.
+    fn object_parts(&self, object_id: &ObjectId) -> Result<ObjectParts> {
+        match self.id_to_data.get(object_id) {
+            None => Ok(ObjectParts::default()),
+            Some(data) => Ok(track_assert_some!(
+                ObjectParts::decode(data),
+                ErrorKind::InvalidInput,
+                "Cannot append to an object whose content is stored in metadata: {:?}",
+                object_id
+            )),
+        }
+    }
+    /// 現在のバージョンを、`now`(UNIXエポックからのミリ秒)に削除されたものとして過去のバージョンに移す.
+    ///
+    /// 保持期間は、直前の過去のバージョンのものを引き継ぐ.
+    /// 過去のバージョンを保持していないオブジェクトの場合には何もせずに`None`を返す.
+    fn retire_to_history(&mut self, object_id: &ObjectId, now: u64) -> Option<ObjectVersion> {
+        let retention = {
+            let last = self.history.get(object_id)?.last()?;
+            let superseded_at = if last.deleted_at != 0 {
+                last.deleted_at
+            } else {
+                *self.id_to_mtime.get(object_id)?
+            };
+            last.expires_at.saturating_sub(superseded_at)
+        };
+        let version = self.id_to_version.remove(object_id)?;
+        let expires_at = now + retention;
+        let revision = Revision {
+            version,
+            data: self.id_to_data.remove(object_id).unwrap_or_default(),
+            size: self.take_size(object_id),
+            modified_at: self.id_to_mtime.remove(object_id).unwrap_or(0),
+            deleted_at: now,
+            expires_at,
+        };
+        self.history
+            .entry(object_id.clone())
+            .or_default()
+            .push(revision);
+        self.history_expirations
+            .insert((expires_at, object_id.clone()));
+        self.changes
+            .push((ObjectChangeKind::Delete, object_id.clone(), version));
+        Some(version)
+    }
+    /// `retire_to_history`で過去のバージョンに移された削除時のバージョンを、
+    /// `now`(UNIXエポックからのミリ秒)の時点で保持期間中であれば、現在のバージョンに戻す.
+    fn restore_from_history(&mut self, object_id: &ObjectId, now: u64) -> Option<ObjectVersion> {
+        {
+            let last = self.history.get(object_id)?.last()?;
+            if last.deleted_at == 0 || last.expires_at <= now {
+                return None;
+            }
+        }
+        let revision = {
+            let revisions = self.history.get_mut(object_id).expect("Never fails");
+            revisions.pop().expect("Never fails")
+        };
+        if self.history.get(object_id).is_some_and(|r| r.is_empty()) {
+            self.history.remove(object_id);
+        }
+        // NOTE: `history_expirations`の対応する要素は、`purge_history`で読み飛ばされるので残しておく
+
+        if !revision.data.is_empty() {
+            self.id_to_data.insert(object_id.clone(), revision.data);
+        }
+        if revision.size > 0 {
+            self.id_to_size.insert(object_id.clone(), revision.size);
+            self.total_bytes += revision.size;
+        }
+        if revision.modified_at > 0 {
+            self.id_to_mtime
+                .insert(object_id.clone(), revision.modified_at);
+        }
+        self.id_to_version
+            .insert(object_id.clone(), revision.version);
+        self.changes
+            .push((ObjectChangeKind::Put, object_id.clone(), revision.version));
+        Some(revision.version)
+    }
+    fn drop_history(&mut self, object_id: &ObjectId) {
+        self.id_to_mtime.remove(object_id);
+        for r in self.history.remove(object_id).unwrap_or_default() {
+            self.release_parts(Some(r.data));
+            self.released_parts.push(r.version);
+        }
+    }
+    // 大きさが記録されているオブジェクトの平均の大きさ.
+    fn average_size(&self) -> u64 {
+        if self.id_to_size.is_empty() {
+            0
+        } else {
+            self.total_bytes / self.id_to_size.len() as u64
+        }
+    }
+    fn take_size(&mut self, object_id: &ObjectId) -> u64 {
+        let size = self.id_to_size.remove(object_id).unwrap_or(0);
+        self.total_bytes -= size;
+        size
+    }
+    // `ObjectParts`以外の空ではないデータを、目印に置き換える
+    fn strip_data(data: &mut Vec<u8>) {
+        if !data.is_empty() && ObjectParts::decode(data).is_none() {
+            *data = WITNESS_PLACEHOLDER_DATA.to_vec();
+        }
+    }
     fn get_data(&self, object_id: &ObjectId) -> Vec<u8> {
         self.id_to_data
             .get(object_id)
@@ -185,7 +1301,17 @@ pub enum Command {
     Put {
         object_id: ObjectId,
         userdata: Vec<u8>,
-        expect: Expect,
+
+        // オブジェクトの論理的な大きさ(バイト). 不明な場合は0.
+        size: u64,
+        expect: Precondition,
+
+        // バージョン管理されているバケツの場合に、上書きされるバージョンを保持する期間.
+        // 保持期間の起点はコマンドのタイムスタンプ.
+        history_retention: Option<Seconds>,
+
+        // 適用時に確認する割り当て量. 提案時にリーダが適用していたもの.
+        quota: Quota,
 
         // 現在時刻を起点とした秒単位の尺.
         // 絶対時刻ではので、ノード再起動時等に大幅にズレる可能性はあるが、
@@ -196,7 +1322,7 @@ pub enum Command {
     },
     Delete {
         object_id: ObjectId,
-        expect: Expect,
+        expect: Precondition,
     },
     DeleteByVersion {
         object_version: ObjectVersion,
@@ -208,6 +1334,407 @@ pub enum Command {
     DeleteByPrefix {
         prefix: ObjectPrefix,
     },
+
+    // 接頭辞指定での削除ジョブの開始.
+    // ジョブの対象は、このコマンドのコミット位置よりも前に保存されたオブジェクト群.
+    // 削除猶予期間が指定された場合には、オブジェクトを即座に削除せずに猶予期間中とする.
+    StartDeleteJob {
+        job_id: u64,
+        prefix: ObjectPrefix,
+        retention: Option<Seconds>,
+    },
+
+    // 接頭辞指定での削除ジョブが、一度に削除するオブジェクト群.
+    // 削除猶予期間の起点はコマンドのタイムスタンプ.
+    DeleteObjects {
+        job_id: u64,
+        object_ids: Vec<ObjectId>,
+    },
+
+    // 接頭辞指定での削除ジョブの終了.
+    FinishDeleteJob {
+        job_id: u64,
+        cancelled: bool,
+    },
+
+    // 削除猶予期間の起点はコマンドのタイムスタンプ.
+    Tombstone {
+        object_id: ObjectId,
+        expect: Precondition,
+        retention: Seconds,
+    },
+    Undelete {
+        object_id: ObjectId,
+    },
+    PurgeTombstones,
+
+    // 追記部分のためのバージョンの予約. バージョンはコマンドのコミット位置.
+    //
+    // 内容の保存後に`CommitAppend`で追記されるまでは、オブジェクトの内容には含まれない.
+    Append {
+        object_id: ObjectId,
+        expect: Precondition,
+        size: u64,
+        put_content_timeout: Seconds,
+    },
+
+    // 内容の保存が完了した、予約済みの追記部分の追記.
+    CommitAppend {
+        object_id: ObjectId,
+        version: ObjectVersion,
+    },
+
+    // セグメントの再配置ジョブの操作.
+    Relayout(RelayoutCommand),
+}
+
+impl Command {
+    /// 削除ジョブに関するコマンドかどうかを返す.
+    pub fn is_delete_job_command(&self) -> bool {
+        match *self {
+            Command::StartDeleteJob { .. }
+            | Command::DeleteObjects { .. }
+            | Command::FinishDeleteJob { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// 追記に関するコマンドかどうかを返す.
+    pub fn is_append_command(&self) -> bool {
+        match *self {
+            Command::Append { .. } | Command::CommitAppend { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// オブジェクト群を変更し得るコマンドかどうかを返す.
+    ///
+    /// 再配置ジョブの凍結中は、これらのコマンドは拒否される.
+    pub fn is_mutation(&self) -> bool {
+        match *self {
+            Command::FinishDeleteJob { .. } | Command::Relayout(_) => false,
+            _ => true,
+        }
+    }
+}
+
+/// セグメントの再配置ジョブを操作するコマンド.
+///
+/// いずれも再配置先のデバイスグループの世代を伴い、世代が一致しないジョブには適用されない.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayoutCommand {
+    // ジョブの開始. 既存のオブジェクトの内容のコピーが始まる.
+    Start {
+        generation: u8,
+    },
+
+    // `cursor`以下のバージョンの内容のコピーが完了した.
+    Progress {
+        generation: u8,
+        cursor: ObjectVersion,
+        copied: u64,
+    },
+
+    // オブジェクトの変更を凍結して、最後のコピーとメンバの切り替えを行う.
+    Freeze {
+        generation: u8,
+    },
+
+    // メンバの切り替えが完了した. 凍結が解除される.
+    Finish {
+        generation: u8,
+    },
+}
+
+/// 追記されたオブジェクトを構成する部分のうち、最新以外のもの(古い順)と、最新の部分の大きさ.
+///
+/// オブジェクトの内容は、これらの部分の後ろに最新のバージョンの部分を連結したものとなる.
+/// MDS上では、オブジェクトのデータとしてエンコードされた形式で保持される.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectParts {
+    /// 最新以外の部分(古い順).
+    pub parts: Vec<ObjectPart>,
+
+    /// 最新の部分の大きさ(バイト).
+    ///
+    /// 大きさが記録されていない場合には`None`となる.
+    pub last_size: Option<u64>,
+}
+impl ObjectParts {
+    /// 一つのオブジェクトを構成できる部分(最新のものを含む)の最大数.
+    ///
+    /// これを超えて追記するには、オブジェクト全体を書き直して部分を一つにまとめる必要がある.
+    pub const MAX_PARTS: usize = 1024;
+
+    // 部分毎の大きさを持たない、初期の形式
+    const MAGIC_V1: &'static [u8; 8] = b"\0FRGPRTS";
+    const MAGIC: &'static [u8; 8] = b"\0FRGPRT2";
+    const UNKNOWN_SIZE: u64 = u64::MAX;
+
+    /// オブジェクトのデータをデコードする.
+    ///
+    /// データが`ObjectParts`をエンコードしたものではない場合には`None`が返される.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.starts_with(Self::MAGIC_V1) {
+            let chunks = data[Self::MAGIC_V1.len()..].chunks_exact(8);
+            if !chunks.remainder().is_empty() {
+                return None;
+            }
+            let parts = chunks
+                .map(|x| ObjectPart {
+                    version: ObjectVersion(BigEndian::read_u64(x)),
+                    size: None,
+                })
+                .collect();
+            return Some(ObjectParts {
+                parts,
+                last_size: None,
+            });
+        }
+        if !data.starts_with(Self::MAGIC) || data.len() < Self::MAGIC.len() + 8 {
+            return None;
+        }
+        let data = &data[Self::MAGIC.len()..];
+        let last_size = Self::decode_size(&data[..8]);
+        let chunks = data[8..].chunks_exact(16);
+        if !chunks.remainder().is_empty() {
+            return None;
+        }
+        let parts = chunks
+            .map(|x| ObjectPart {
+                version: ObjectVersion(BigEndian::read_u64(&x[..8])),
+                size: Self::decode_size(&x[8..]),
+            })
+            .collect();
+        Some(ObjectParts { parts, last_size })
+    }
+
+    /// オブジェクトのデータとして保存するための形式にエンコードする.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Self::MAGIC.to_vec();
+        let mut buf = [0; 8];
+        BigEndian::write_u64(&mut buf, self.last_size.unwrap_or(Self::UNKNOWN_SIZE));
+        data.extend_from_slice(&buf);
+        for part in &self.parts {
+            BigEndian::write_u64(&mut buf, part.version.0);
+            data.extend_from_slice(&buf);
+            BigEndian::write_u64(&mut buf, part.size.unwrap_or(Self::UNKNOWN_SIZE));
+            data.extend_from_slice(&buf);
+        }
+        data
+    }
+
+    /// 最新のものを含む部分の数を返す.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.parts.len() + 1
+    }
+
+    /// 最新以外の部分のバージョンを古い順に返す.
+    pub fn versions<'a>(&'a self) -> impl Iterator<Item = ObjectVersion> + 'a {
+        self.parts.iter().map(|p| p.version)
+    }
+
+    fn decode_size(buf: &[u8]) -> Option<u64> {
+        let size = BigEndian::read_u64(buf);
+        if size == Self::UNKNOWN_SIZE {
+            None
+        } else {
+            Some(size)
+        }
+    }
+}
+
+/// 追記されたオブジェクトを構成する部分.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectPart {
+    /// 部分の内容のバージョン.
+    pub version: ObjectVersion,
+
+    /// 部分の大きさ(バイト). 記録されていない場合は`None`.
+    pub size: Option<u64>,
+}
+
+/// 内容の保存の完了を待っている、予約された追記部分.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingPart {
+    /// 追記先のオブジェクト.
+    pub object_id: ObjectId,
+
+    /// 部分の内容のバージョン(予約コマンドのコミット位置).
+    pub version: ObjectVersion,
+
+    /// 部分の大きさ(バイト).
+    pub size: u64,
+
+    /// 追記後に内容の修復を行うまでの猶予時間.
+    pub put_content_timeout: Seconds,
+
+    /// この時刻(UNIXエポックからのミリ秒)までに追記されなかった場合には破棄される.
+    pub expires_at: u64,
+}
+
+/// 暗号化されて保存されたオブジェクトの、鍵に関する情報.
+///
+/// MDS上では、オブジェクトのデータとしてエンコードされた形式で保持される.
+/// 鍵そのものは含まれず、読み込み時には`key_id`を元に鍵を取得する.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectEncryption {
+    /// 暗号化に使われた鍵のID.
+    pub key_id: String,
+
+    /// 鍵の取り違えを検出するための、鍵から導出された検査値.
+    pub key_check: [u8; 8],
+}
+impl ObjectEncryption {
+    const MAGIC: &'static [u8; 8] = b"\0FRGENCR";
+
+    /// オブジェクトのデータをデコードする.
+    ///
+    /// データが`ObjectEncryption`をエンコードしたものではない場合には`None`が返される.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if !data.starts_with(Self::MAGIC) || data.len() < Self::MAGIC.len() + 8 {
+            return None;
+        }
+        let data = &data[Self::MAGIC.len()..];
+        let mut key_check = [0; 8];
+        key_check.copy_from_slice(&data[..8]);
+        let key_id = String::from_utf8(data[8..].to_vec()).ok()?;
+        Some(ObjectEncryption { key_id, key_check })
+    }
+
+    /// オブジェクトのデータとして保存するための形式にエンコードする.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Self::MAGIC.to_vec();
+        data.extend_from_slice(&self.key_check);
+        data.extend_from_slice(self.key_id.as_bytes());
+        data
+    }
+}
+
+/// コミット位置とタイムスタンプ(秒単位)の対応を、秒が変わる毎に一つずつ記録したもの.
+///
+/// バージョンはコマンドのコミット位置なので、これを辿ればオブジェクトの更新時刻が秒単位で分かる.
+/// メモリ使用量を抑えるために、古いものから順に破棄される.
+#[derive(Debug, Clone, Default)]
+struct CommitClock {
+    // (その秒に最初にコミットされた位置, UNIXエポックからの秒数)
+    entries: VecDeque<(ObjectVersion, u64)>,
+}
+impl CommitClock {
+    // 一秒毎にコミットがあった場合で、一時間分
+    const MAX_ENTRIES: usize = 3600;
+
+    fn record(&mut self, version: ObjectVersion, unix_secs: u64) {
+        if unix_secs == 0 {
+            // タイムスタンプを持たない古いコマンド
+            return;
+        }
+        if self.entries.back().is_some_and(|&(_, s)| s >= unix_secs) {
+            return;
+        }
+        if self.entries.len() >= Self::MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((version, unix_secs));
+    }
+
+    /// `version`の位置でコミットされたコマンドのタイムスタンプ(の上限)を返す.
+    fn modified_at(&self, version: ObjectVersion) -> Option<u64> {
+        match self.entries.partition_point(|&(v, _)| v <= version) {
+            0 => {
+                // 記録よりも前にコミットされたので、最も古い記録の時刻以前であることだけが分かる
+                self.entries.front().map(|&(_, s)| s)
+            }
+            n => Some(self.entries[n - 1].1),
+        }
+    }
+}
+
+/// 削除猶予期間中のオブジェクト.
+///
+/// 猶予期間中は`undelete`で復元可能で、その間はオブジェクトの実データも削除されない.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tombstone {
+    pub version: ObjectVersion,
+    pub data: Vec<u8>,
+
+    // オブジェクトの論理的な大きさ(バイト). 不明な場合は0.
+    pub size: u64,
+
+    // 削除前の保存時刻(UNIXエポックからのミリ秒). 不明な場合は0.
+    pub modified_at: u64,
+
+    // 猶予期間の終了時刻(UNIXエポックからのミリ秒).
+    pub expires_at: u64,
+}
+
+/// バージョン管理されているオブジェクトの、上書きされた過去のバージョン.
+///
+/// 保持期間中は`get_revision`で取得可能で、その間はオブジェクトの実データも削除されない.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Revision {
+    pub version: ObjectVersion,
+    pub data: Vec<u8>,
+
+    // オブジェクトの論理的な大きさ(バイト). 不明な場合は0.
+    pub size: u64,
+
+    // このバージョンが保存された時刻(UNIXエポックからのミリ秒). 不明な場合は0.
+    pub modified_at: u64,
+
+    // このバージョンが削除された時刻(UNIXエポックからのミリ秒). 上書きされたバージョンの場合は0.
+    pub deleted_at: u64,
+
+    // 保持期間の終了時刻(UNIXエポックからのミリ秒).
+    pub expires_at: u64,
+}
+
+/// 接頭辞指定での削除ジョブ.
+///
+/// ジョブの状態は全ノードで複製されるので、リーダが交代した場合にも失われることはない.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeleteJob {
+    pub status: DeleteJobStatus,
+
+    // ジョブを開始したコマンドのコミット位置.
+    pub started_at: ObjectVersion,
+
+    // 削除猶予期間. `None`の場合はオブジェクトを即座に削除する.
+    pub retention: Option<Seconds>,
+
+    // ジョブを終了したコマンドのコミット位置. 実行中の場合は`None`.
+    pub finished_at: Option<ObjectVersion>,
+}
+
+/// セグメントの再配置ジョブ.
+///
+/// 変更前のメンバ群のリーダが、既存のオブジェクトの内容を新しい配置にコピーしていき、
+/// 全てのコピーが終わった時点でMDSのメンバ群を新しい配置のものに切り替える.
+/// ジョブの状態は全ノードで複製されるので、リーダが交代した場合にも途中から再開される.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayoutJob {
+    // 再配置先のデバイスグループの世代.
+    pub generation: u8,
+
+    // ジョブを開始したコマンドのコミット位置.
+    pub started_at: ObjectVersion,
+
+    // このバージョン以下の内容は、全てコピー済み.
+    pub cursor: ObjectVersion,
+
+    // コピー済みのバージョンの数.
+    pub copied: u64,
+
+    pub state: RelayoutState,
+}
+
+/// セグメントの再配置ジョブの状態.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayoutState {
+    Copying,
+    Frozen,
+    Finished,
 }
 
 #[derive(Debug)]
@@ -254,7 +1781,7 @@ mod tests {
     fn setup_metadata(machine: &mut Machine, metadata_size: usize, kind: MetadataKind) {
         for n in 0..metadata_size {
             let (id, meta) = make_metadata(n, kind);
-            machine.put(id, meta, &Expect::None).unwrap();
+            machine.put(id, meta, 0, &Expect::None.into()).unwrap();
         }
     }
 
@@ -266,7 +1793,7 @@ mod tests {
                 version,
                 data: vec![0x01, 0x02],
             };
-            machine.put(id, meta, &Expect::None).unwrap();
+            machine.put(id, meta, 0, &Expect::None.into()).unwrap();
         });
     }
 
@@ -278,7 +1805,7 @@ mod tests {
 
         let (id, meta) = make_metadata(1, MetadataKind::MUSIC);
 
-        assert!(machine.put(id, meta, &Expect::None)?.is_none());
+        assert!(machine.put(id, meta, 0, &Expect::None.into())?.is_none());
 
         assert_eq!(machine.len(), 1);
 
@@ -293,11 +1820,11 @@ mod tests {
 
         let (id, meta) = make_metadata(1, MetadataKind::MUSIC);
 
-        machine.put(id.clone(), meta.clone(), &Expect::None)?;
+        machine.put(id.clone(), meta.clone(), 0, &Expect::None.into())?;
 
         // すでにバージョンが1つ以上ある
         assert!(machine
-            .put(id.clone(), meta.clone(), &Expect::None)
+            .put(id.clone(), meta.clone(), 0, &Expect::None.into())
             .is_err());
 
         // バージョンが異なる
@@ -305,7 +1832,8 @@ mod tests {
             .put(
                 id.clone(),
                 meta.clone(),
-                &Expect::IfMatch(vec![UNKNOWN_OBJECT_VERSION])
+                0,
+                &Expect::IfMatch(vec![UNKNOWN_OBJECT_VERSION]).into()
             )
             .is_err());
 
@@ -314,7 +1842,8 @@ mod tests {
             .put(
                 id.clone(),
                 meta.clone(),
-                &Expect::IfNoneMatch(vec![DEFAULT_OBJECT_VERSION])
+                0,
+                &Expect::IfNoneMatch(vec![DEFAULT_OBJECT_VERSION]).into()
             )
             .is_err());
 
@@ -351,7 +1880,7 @@ mod tests {
         assert_eq!(machine.len(), metadata_size);
 
         assert!(machine
-            .delete(&make_object_id(0, MetadataKind::MUSIC), &Expect::Any)?
+            .delete(&make_object_id(0, MetadataKind::MUSIC), &Expect::Any.into())?
             .is_some());
 
         assert_eq!(machine.len(), metadata_size - 1);
@@ -371,7 +1900,7 @@ mod tests {
         assert!(machine
             .delete(
                 &make_object_id(metadata_size + 30, MetadataKind::MUSIC),
-                &Expect::Any
+                &Expect::Any.into()
             )?
             .is_none());
 
@@ -391,7 +1920,7 @@ mod tests {
         assert!(machine
             .delete(
                 &make_object_id(0, MetadataKind::MUSIC),
-                &Expect::IfMatch(vec![])
+                &Expect::IfMatch(vec![]).into()
             )
             .is_err());
 
@@ -399,7 +1928,7 @@ mod tests {
         assert!(machine
             .delete(
                 &make_object_id(0, MetadataKind::MUSIC),
-                &Expect::IfMatch(vec![UNKNOWN_OBJECT_VERSION])
+                &Expect::IfMatch(vec![UNKNOWN_OBJECT_VERSION]).into()
             )
             .is_err());
 
@@ -407,13 +1936,79 @@ mod tests {
         assert!(machine
             .delete(
                 &make_object_id(0, MetadataKind::MUSIC),
-                &Expect::IfNoneMatch(vec![DEFAULT_OBJECT_VERSION])
+                &Expect::IfNoneMatch(vec![DEFAULT_OBJECT_VERSION]).into()
             )
             .is_err());
 
         Ok(())
     }
 
+    #[test]
+    fn preconditions_are_evaluated_against_current_object() -> TestResult {
+        let mut machine = Machine::new();
+        let id = make_object_id(0, MetadataKind::MUSIC);
+        let put = |machine: &mut Machine, version, precondition: &Precondition| {
+            machine.record_commit(ObjectVersion(version), 100 + version);
+            let metadata = Metadata {
+                version: ObjectVersion(version),
+                data: vec![],
+            };
+            machine.put(id.clone(), metadata, 0, precondition)
+        };
+
+        put(&mut machine, 1, &Expect::None.into())?;
+        let range = Precondition::IfMatchRange {
+            from: ObjectVersion(2),
+            to: ObjectVersion(5),
+        };
+        assert!(put(&mut machine, 2, &range).is_err());
+        put(
+            &mut machine,
+            3,
+            &Precondition::IfNewerThan(ObjectVersion(0)),
+        )?;
+        put(&mut machine, 4, &range)?;
+        assert!(put(
+            &mut machine,
+            5,
+            &Precondition::IfNewerThan(ObjectVersion(4))
+        )
+        .is_err());
+
+        // バージョン 4 は時刻 104 にコミットされている
+        assert!(put(&mut machine, 6, &Precondition::IfUnmodifiedSince(103)).is_err());
+        put(&mut machine, 7, &Precondition::IfUnmodifiedSince(104))?;
+        assert!(machine
+            .delete(&id, &Precondition::IfUnmodifiedSince(106))
+            .is_err());
+        assert_eq!(
+            machine.delete(&id, &Precondition::IfUnmodifiedSince(107))?,
+            Some(ObjectVersion(7))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn commit_clock_works() {
+        let mut clock = CommitClock::default();
+        assert_eq!(clock.modified_at(ObjectVersion(1)), None);
+
+        clock.record(ObjectVersion(10), 0); // タイムスタンプ無し
+        clock.record(ObjectVersion(11), 100);
+        clock.record(ObjectVersion(12), 100);
+        clock.record(ObjectVersion(13), 101);
+        assert_eq!(clock.entries.len(), 2);
+        assert_eq!(clock.modified_at(ObjectVersion(5)), Some(100));
+        assert_eq!(clock.modified_at(ObjectVersion(12)), Some(100));
+        assert_eq!(clock.modified_at(ObjectVersion(20)), Some(101));
+
+        for i in 0..CommitClock::MAX_ENTRIES as u64 {
+            clock.record(ObjectVersion(20 + i), 200 + i);
+        }
+        assert_eq!(clock.entries.len(), CommitClock::MAX_ENTRIES);
+        assert_eq!(clock.modified_at(ObjectVersion(13)), Some(200));
+    }
+
     #[test]
     fn it_deletes_matched_objects_by_version() -> TestResult {
         let mut machine = Machine::new();
@@ -442,7 +2037,9 @@ mod tests {
 
         let (id, meta) = make_metadata(1, MetadataKind::LYRIC);
 
-        assert!(machine.put(id.clone(), meta, &Expect::None)?.is_none());
+        assert!(machine
+            .put(id.clone(), meta, 0, &Expect::None.into())?
+            .is_none());
 
         assert_eq!(machine.len(), music_metadata_size + lyric_metadata_size);
 
@@ -457,6 +2054,752 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn it_tombstones_and_undeletes_object() -> TestResult {
+        let mut machine = Machine::new();
+        setup_metadata(&mut machine, 2, MetadataKind::MUSIC);
+        let id = make_object_id(0, MetadataKind::MUSIC);
+        machine.record_put_time(id.clone(), 500);
+
+        assert_eq!(
+            machine.tombstone(&id, &Expect::Any.into(), 1000)?,
+            Some((DEFAULT_OBJECT_VERSION, None))
+        );
+        assert_eq!(machine.len(), 1);
+        assert!(machine.get(&id, &Expect::Any)?.is_none());
+        assert!(machine.list_by_time_range(0..u64::MAX).is_empty());
+        assert_eq!(
+            machine.to_tombstoned_versions(),
+            vec![DEFAULT_OBJECT_VERSION]
+        );
+
+        // 猶予期間中であれば、削除前の保存時刻と共に復元できる
+        assert_eq!(machine.undelete(&id, 999)?, Some(DEFAULT_OBJECT_VERSION));
+        assert_eq!(machine.len(), 2);
+        let metadata = machine.get(&id, &Expect::Any)?.unwrap();
+        assert_eq!(metadata.data, vec![0x01, 0x02]);
+        assert!(machine.to_tombstoned_versions().is_empty());
+        let list = machine.list_by_time_range(500..501);
+        assert_eq!(
+            list.iter().map(|s| s.id.clone()).collect::<Vec<_>>(),
+            vec![id.clone()]
+        );
+
+        // 猶予期間を過ぎたものは復元できない
+        machine.tombstone(&id, &Expect::Any.into(), 1000)?;
+        assert_eq!(machine.undelete(&id, 1000)?, None);
+        assert!(!machine.has_expired_tombstones(999));
+        assert!(machine.has_expired_tombstones(1000));
+        assert_eq!(machine.purge_tombstones(1000), vec![DEFAULT_OBJECT_VERSION]);
+        assert!(machine.to_tombstoned_versions().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_enforces_quota() -> TestResult {
+        let mut machine = Machine::new();
+        let quota = Quota {
+            max_objects: Some(2),
+            max_bytes: Some(100),
+        };
+        let (id0, meta0) = make_metadata(0, MetadataKind::MUSIC);
+        let (id1, meta1) = make_metadata(1, MetadataKind::MUSIC);
+        let id2 = make_object_id(2, MetadataKind::MUSIC);
+
+        machine.check_quota(quota, &id0, 60)?;
+        machine.put(id0.clone(), meta0.clone(), 60, &Expect::None.into())?;
+        assert!(machine.check_quota(quota, &id1, 41).is_err());
+        machine.check_quota(quota, &id1, 40)?;
+        machine.put(id1.clone(), meta1.clone(), 40, &Expect::None.into())?;
+        assert_eq!(
+            machine.usage(quota),
+            QuotaUsage {
+                objects: 2,
+                bytes: 100,
+                unsized_objects: 0,
+                quota
+            }
+        );
+
+        // 数の上限に達していても、既存のオブジェクトの上書きは可能
+        let e = machine.check_quota(quota, &id2, 0).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::QuotaExceeded(machine.usage(quota)));
+        machine.check_quota(quota, &id0, 50)?;
+        assert!(machine.check_quota(quota, &id0, 61).is_err());
+
+        // 削除猶予期間中のオブジェクトは使用量に含まれず、復元時に戻る
+        machine.tombstone(&id0, &Expect::Any.into(), 1000)?;
+        assert_eq!(machine.usage(quota).objects, 1);
+        assert_eq!(machine.usage(quota).bytes, 40);
+        machine.undelete(&id0, 999)?;
+        assert_eq!(machine.usage(quota).bytes, 100);
+
+        // 大きさはスナップショットから復元できる
+        let restored = Machine::from_snapshot(machine.to_snapshot()).with_sizes(machine.to_sizes());
+        assert_eq!(restored.usage(quota), machine.usage(quota));
+
+        machine.delete(&id1, &Expect::Any.into())?;
+        assert_eq!(machine.usage(quota).bytes, 60);
+
+        // 大きさが記録されていないオブジェクトの分は、平均値で見積もられる
+        let restored = Machine::from_snapshot(machine.to_snapshot());
+        assert_eq!(restored.usage(quota).unsized_objects, 1);
+        assert_eq!(restored.usage(quota).bytes, 0);
+        machine.put(id1.clone(), meta1.clone(), 0, &Expect::None.into())?;
+        let usage = machine.usage(quota);
+        assert_eq!(
+            (usage.objects, usage.bytes, usage.unsized_objects),
+            (2, 120, 1)
+        );
+        assert!(machine.check_quota(quota, &id2, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn it_records_changes() -> TestResult {
+        let mut machine = Machine::new();
+        let ids = (0..3)
+            .map(|i| make_object_id(i, MetadataKind::MUSIC))
+            .collect::<Vec<_>>();
+        for (i, id) in ids.iter().enumerate() {
+            let metadata = Metadata {
+                version: ObjectVersion(i as u64),
+                data: vec![],
+            };
+            machine.put(id.clone(), metadata, 0, &Expect::None.into())?;
+        }
+        assert_eq!(machine.take_changes().len(), 3);
+
+        // 存在しないオブジェクトの削除は変更として扱われない
+        machine.delete(&ids[0], &Expect::Any.into())?;
+        machine.delete(&ids[0], &Expect::Any.into())?;
+        machine.tombstone(&ids[1], &Expect::Any.into(), 1_000)?;
+        machine.undelete(&ids[1], 0)?;
+        machine.delete_version(ObjectVersion(2))?;
+        assert_eq!(
+            machine.take_changes(),
+            vec![
+                (ObjectChangeKind::Delete, ids[0].clone(), ObjectVersion(0)),
+                (ObjectChangeKind::Delete, ids[1].clone(), ObjectVersion(1)),
+                (ObjectChangeKind::Put, ids[1].clone(), ObjectVersion(1)),
+                (ObjectChangeKind::Delete, ids[2].clone(), ObjectVersion(2)),
+            ]
+        );
+        assert!(machine.take_changes().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn it_lists_objects_by_time_range() -> TestResult {
+        let mut machine = Machine::new();
+        let ids = (0..4)
+            .map(|i| make_object_id(i, MetadataKind::MUSIC))
+            .collect::<Vec<_>>();
+        for (i, id) in ids.iter().enumerate() {
+            let metadata = Metadata {
+                version: ObjectVersion(i as u64),
+                data: vec![],
+            };
+            machine.put(id.clone(), metadata, 0, &Expect::None.into())?;
+        }
+        machine.record_put_time(ids[0].clone(), 3_000);
+        machine.record_put_time(ids[1].clone(), 1_000);
+        machine.record_put_time(ids[2].clone(), 2_000);
+
+        // 保存時刻が不明なオブジェクトや、存在しないオブジェクトは含まれない
+        machine.record_put_time(ids[3].clone(), 0);
+        machine.record_put_time("unknown".to_owned(), 1_500);
+
+        let list = machine.list_by_time_range(1_000..3_000);
+        let versions = list.iter().map(|s| s.version.0).collect::<Vec<_>>();
+        assert_eq!(versions, vec![1, 2]);
+
+        // 削除されたオブジェクトは含まれず、上書きされたオブジェクトは新しい保存時刻で扱われる
+        machine.delete(&ids[1], &Expect::Any.into())?;
+        let metadata = Metadata {
+            version: ObjectVersion(4),
+            data: vec![],
+        };
+        machine.put(ids[2].clone(), metadata, 0, &Expect::Any.into())?;
+        machine.record_put_time(ids[2].clone(), 4_000);
+        let list = machine.list_by_time_range(0..u64::MAX);
+        let versions = list.iter().map(|s| s.version.0).collect::<Vec<_>>();
+        assert_eq!(versions, vec![0, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn it_keeps_overwritten_versions_of_versioned_object() -> TestResult {
+        let mut machine = Machine::new();
+        let id = make_object_id(0, MetadataKind::MUSIC);
+        let metadata = |version: u64| Metadata {
+            version: ObjectVersion(version),
+            data: vec![version as u8],
+        };
+        let retention = Seconds(10);
+        machine.put_versioned(
+            id.clone(),
+            metadata(1),
+            0,
+            &Expect::None.into(),
+            1_000,
+            retention,
+        )?;
+        machine.put_versioned(
+            id.clone(),
+            metadata(2),
+            0,
+            &Expect::Any.into(),
+            5_000,
+            retention,
+        )?;
+        machine.put_versioned(
+            id.clone(),
+            metadata(3),
+            0,
+            &Expect::Any.into(),
+            9_000,
+            retention,
+        )?;
+        assert_eq!(machine.len(), 1);
+        assert!(machine.take_released_parts().is_empty());
+
+        let get = |m: &Machine, s| {
+            m.get_revision(&id, s, &Expect::Any)
+                .expect("Never fails")
+                .map(|m| m.version.0)
+        };
+        assert_eq!(
+            get(&machine, RevisionSelector::Version(ObjectVersion(2))),
+            Some(2)
+        );
+        assert_eq!(
+            get(&machine, RevisionSelector::Version(ObjectVersion(4))),
+            None
+        );
+        assert_eq!(get(&machine, RevisionSelector::AsOf(0)), None);
+        assert_eq!(get(&machine, RevisionSelector::AsOf(1)), Some(1));
+        assert_eq!(get(&machine, RevisionSelector::AsOf(6)), Some(2));
+        assert_eq!(get(&machine, RevisionSelector::AsOf(100)), Some(3));
+
+        // スナップショットから復元しても、過去のバージョンは保持される
+        let restored = Machine::from_snapshot(machine.to_snapshot())
+            .with_history(machine.to_history(), machine.to_mtimes());
+        assert_eq!(get(&restored, RevisionSelector::AsOf(6)), Some(2));
+
+        // 保持期間が過ぎたものから破棄される
+        assert!(!machine.has_expired_history(14_999));
+        assert!(machine.has_expired_history(15_000));
+        assert_eq!(machine.purge_history(15_000), vec![ObjectVersion(1)]);
+        assert_eq!(machine.to_history_versions(), vec![ObjectVersion(2)]);
+
+        // 取得したバージョンに対して条件が検査される
+        let selector = RevisionSelector::AsOf(6);
+        assert!(machine
+            .get_revision(&id, selector, &Expect::Version(ObjectVersion(2)))
+            .is_ok());
+        assert!(machine
+            .get_revision(&id, selector, &Expect::Version(ObjectVersion(3)))
+            .is_err());
+        assert!(machine.get_revision(&id, selector, &Expect::None).is_err());
+
+        // 削除されたバージョンも、直前の過去のバージョンと同じ期間だけ保持される
+        let deleted = machine.delete_versioned(&id, &Expect::Any.into(), 16_000)?;
+        assert_eq!(deleted, Some((ObjectVersion(3), true)));
+        assert_eq!(machine.len(), 0);
+        assert!(machine.take_released_parts().is_empty());
+        assert_eq!(
+            machine.to_history_versions(),
+            vec![ObjectVersion(2), ObjectVersion(3)]
+        );
+        assert_eq!(get(&machine, RevisionSelector::AsOf(12)), Some(3));
+        assert_eq!(get(&machine, RevisionSelector::AsOf(16)), None);
+        assert_eq!(
+            get(&machine, RevisionSelector::Version(ObjectVersion(3))),
+            Some(3)
+        );
+
+        // 保持期間中であれば、削除されたバージョンを復元できる
+        assert_eq!(machine.undelete(&id, 17_000)?, Some(ObjectVersion(3)));
+        assert_eq!(machine.len(), 1);
+        assert_eq!(machine.to_history_versions(), vec![ObjectVersion(2)]);
+        assert_eq!(get(&machine, RevisionSelector::AsOf(100)), Some(3));
+
+        machine.delete_versioned(&id, &Expect::Any.into(), 18_000)?;
+        assert_eq!(machine.purge_history(19_000), vec![ObjectVersion(2)]);
+        assert_eq!(machine.to_history_versions(), vec![ObjectVersion(3)]);
+        assert_eq!(machine.undelete(&id, 28_000)?, None);
+        assert_eq!(machine.purge_history(28_000), vec![ObjectVersion(3)]);
+        assert!(machine.to_history_versions().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn it_doesnt_undelete_overwritten_object() -> TestResult {
+        let mut machine = Machine::new();
+        setup_metadata(&mut machine, 1, MetadataKind::MUSIC);
+        let (id, _) = make_metadata(0, MetadataKind::MUSIC);
+        machine.tombstone(&id, &Expect::Any.into(), 1000)?;
+
+        let metadata = Metadata {
+            version: ObjectVersion(10),
+            data: Vec::new(),
+        };
+        machine.put(id.clone(), metadata, 0, &Expect::Any.into())?;
+        assert!(machine.undelete(&id, 0).is_err());
+
+        // 再度削除すると、古い削除済みオブジェクトは置き換えられる
+        assert_eq!(
+            machine.tombstone(&id, &Expect::Any.into(), 2000)?,
+            Some((ObjectVersion(10), Some(DEFAULT_OBJECT_VERSION)))
+        );
+        assert_eq!(machine.undelete(&id, 1500)?, Some(ObjectVersion(10)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn it_purges_tombstones_in_expiration_order() -> TestResult {
+        let mut machine = Machine::new();
+        setup_metadata(&mut machine, 3, MetadataKind::MUSIC);
+        let ids = (0..3)
+            .map(|i| make_object_id(i, MetadataKind::MUSIC))
+            .collect::<Vec<_>>();
+        machine.tombstone(&ids[0], &Expect::Any.into(), 3000)?;
+        machine.tombstone(&ids[1], &Expect::Any.into(), 1000)?;
+        machine.tombstone(&ids[2], &Expect::Any.into(), 2000)?;
+        assert_eq!(machine.tombstone_len(), 3);
+
+        // 復元されたものは破棄の対象から外れる
+        machine.undelete(&ids[2], 0)?;
+        assert_eq!(machine.tombstone_len(), 2);
+        assert_eq!(machine.purge_tombstones(2500).len(), 1);
+        assert!(!machine.has_expired_tombstones(2999));
+        assert_eq!(machine.tombstone_len(), 1);
+
+        // スナップショットから復元した場合にも索引が再構築される
+        let mut restored =
+            Machine::from_snapshot(machine.to_snapshot()).with_tombstones(machine.to_tombstones());
+        assert!(restored.has_expired_tombstones(3000));
+        assert_eq!(restored.purge_tombstones(3000).len(), 1);
+        assert_eq!(restored.tombstone_len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn approximate_memory_bytes_works() -> TestResult {
+        let mut machine = Machine::new();
+        assert_eq!(machine.approximate_memory_bytes(), 0);
+
+        setup_metadata(&mut machine, 3, MetadataKind::MUSIC);
+        assert_eq!(machine.approximate_memory_bytes(), 3 * OBJECT_ENTRY_BYTES);
+
+        let id = make_object_id(0, MetadataKind::MUSIC);
+        machine.tombstone(&id, &Expect::Any.into(), 1000)?;
+        assert_eq!(
+            machine.approximate_memory_bytes(),
+            2 * OBJECT_ENTRY_BYTES + TOMBSTONE_ENTRY_BYTES
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn object_encryption_encoding_works() {
+        let encryption = ObjectEncryption {
+            key_id: "key-2019".to_owned(),
+            key_check: [1, 2, 3, 4, 5, 6, 7, 8],
+        };
+        let data = encryption.encode();
+        assert_eq!(ObjectEncryption::decode(&data), Some(encryption));
+        assert_eq!(ObjectParts::decode(&data), None);
+        assert_eq!(
+            ObjectEncryption::decode(&ObjectParts::default().encode()),
+            None
+        );
+        assert_eq!(ObjectEncryption::decode(b"foo"), None);
+    }
+
+    #[test]
+    fn it_appends_parts_to_object() -> TestResult {
+        let mut machine = Machine::new();
+        let id = "log".to_owned();
+        let metadata = Metadata {
+            version: ObjectVersion(1),
+            data: Vec::new(),
+        };
+        machine.put(id.clone(), metadata, 10, &Expect::None.into())?;
+        machine.record_put_time(id.clone(), 1_000);
+
+        // 予約しただけでは、オブジェクトは変わらない
+        let expect = Precondition::from(Expect::IfMatch(vec![ObjectVersion(1)]));
+        let timeout = Seconds(60);
+        assert_eq!(
+            machine.reserve_part(&id, ObjectVersion(2), 5, &expect, timeout, 2_000)?,
+            ObjectVersion(1)
+        );
+        assert_eq!(
+            machine.reserve_part(&id, ObjectVersion(3), 3, &expect, timeout, 2_000)?,
+            ObjectVersion(1)
+        );
+        assert_eq!(machine.head(&id, &Expect::Any)?, Some(ObjectVersion(1)));
+        assert_eq!(
+            machine.to_part_versions(),
+            vec![ObjectVersion(2), ObjectVersion(3)]
+        );
+
+        // 後から予約した部分が先に追記されると、先に予約した部分は破棄される
+        assert_eq!(
+            machine.commit_part(&id, ObjectVersion(3), 3_000)?,
+            (ObjectVersion(1), timeout)
+        );
+        assert!(machine.commit_part(&id, ObjectVersion(2), 3_000).is_err());
+        assert_eq!(machine.take_released_parts(), vec![ObjectVersion(2)]);
+        assert!(machine.commit_part(&id, ObjectVersion(3), 3_000).is_err());
+
+        // 追記すると保存時刻と大きさも更新される
+        let list = machine.list_by_time_range(3_000..4_000);
+        assert_eq!(
+            list.iter().map(|s| s.version).collect::<Vec<_>>(),
+            vec![ObjectVersion(3)]
+        );
+        assert!(machine.list_by_time_range(0..3_000).is_empty());
+        assert_eq!(machine.usage(Quota::default()).bytes, 13);
+        let metadata = machine.get(&id, &Expect::Any)?.unwrap();
+        assert_eq!(metadata.version, ObjectVersion(3));
+        assert_eq!(
+            ObjectParts::decode(&metadata.data),
+            Some(ObjectParts {
+                parts: vec![ObjectPart {
+                    version: ObjectVersion(1),
+                    size: Some(10),
+                }],
+                last_size: Some(3),
+            })
+        );
+
+        // 期限までに追記されなかった部分は破棄される
+        machine.reserve_part(
+            &id,
+            ObjectVersion(4),
+            1,
+            &Expect::Any.into(),
+            Seconds(1),
+            4_000,
+        )?;
+        machine.reserve_part(
+            &id,
+            ObjectVersion(5),
+            2,
+            &Expect::Any.into(),
+            timeout,
+            4_500,
+        )?;
+        assert!(machine.has_expired_pending_parts(5_000));
+        machine.expire_pending_parts(5_000);
+        assert_eq!(machine.take_released_parts(), vec![ObjectVersion(4)]);
+        assert!(machine.commit_part(&id, ObjectVersion(4), 5_000).is_err());
+        machine.commit_part(&id, ObjectVersion(5), 5_000)?;
+        assert_eq!(
+            machine.to_part_versions(),
+            vec![ObjectVersion(1), ObjectVersion(3)]
+        );
+
+        // 存在しないオブジェクトやメタデータに内容を持つオブジェクトには追記できない
+        assert!(machine
+            .reserve_part(
+                &"foo".to_owned(),
+                ObjectVersion(6),
+                1,
+                &Expect::Any.into(),
+                timeout,
+                0
+            )
+            .is_err());
+        setup_metadata(&mut machine, 1, MetadataKind::MUSIC);
+        let (music, _) = make_metadata(0, MetadataKind::MUSIC);
+        assert!(machine
+            .reserve_part(&music, ObjectVersion(6), 1, &Expect::Any.into(), timeout, 0)
+            .is_err());
+
+        // 予約後に上書きされた場合も、予約した部分は破棄される
+        machine.reserve_part(
+            &id,
+            ObjectVersion(7),
+            1,
+            &Expect::Any.into(),
+            timeout,
+            6_000,
+        )?;
+        let metadata = Metadata {
+            version: ObjectVersion(8),
+            data: Vec::new(),
+        };
+        machine.put(id.clone(), metadata, 1, &Expect::Any.into())?;
+        assert_eq!(
+            machine.take_released_parts(),
+            vec![ObjectVersion(1), ObjectVersion(3)]
+        );
+        assert!(machine.commit_part(&id, ObjectVersion(7), 6_000).is_err());
+        assert_eq!(machine.take_released_parts(), vec![ObjectVersion(7)]);
+        assert!(machine.to_part_versions().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn witness_drops_metadata() -> TestResult {
+        let mut machine = Machine::new();
+        setup_metadata(&mut machine, 2, MetadataKind::MUSIC);
+        let (deleted, _) = make_metadata(1, MetadataKind::MUSIC);
+        machine.tombstone(&deleted, &Expect::Any.into(), 60_000)?;
+        machine.set_witness();
+        assert!(machine.is_witness());
+
+        // 既存のデータも、その後に保存されたデータも保持しない
+        let (id, _) = make_metadata(0, MetadataKind::MUSIC);
+        let metadata = machine.get(&id, &Expect::Any)?.unwrap();
+        assert_eq!(metadata.version, DEFAULT_OBJECT_VERSION);
+        assert_eq!(metadata.data, WITNESS_PLACEHOLDER_DATA);
+        let (id, metadata) = make_metadata(2, MetadataKind::LYRIC);
+        machine.put(id.clone(), metadata, 0, &Expect::None.into())?;
+        assert_eq!(
+            machine.get(&id, &Expect::Any)?.unwrap().data,
+            WITNESS_PLACEHOLDER_DATA
+        );
+        assert_eq!(
+            machine.undelete(&deleted, 2_000)?,
+            Some(DEFAULT_OBJECT_VERSION)
+        );
+        assert_eq!(
+            machine.get(&deleted, &Expect::Any)?.unwrap().data,
+            WITNESS_PLACEHOLDER_DATA
+        );
+
+        // 追記の可否や、追記されたオブジェクトの部分は、全データを保持する場合と変わらない
+        assert!(machine
+            .reserve_part(
+                &id,
+                ObjectVersion(10),
+                1,
+                &Expect::Any.into(),
+                Seconds(60),
+                0
+            )
+            .is_err());
+        let log = "log".to_owned();
+        let metadata = Metadata {
+            version: ObjectVersion(11),
+            data: Vec::new(),
+        };
+        machine.put(log.clone(), metadata, 1, &Expect::None.into())?;
+        machine.reserve_part(
+            &log,
+            ObjectVersion(12),
+            1,
+            &Expect::Any.into(),
+            Seconds(60),
+            0,
+        )?;
+        machine.commit_part(&log, ObjectVersion(12), 0)?;
+        assert_eq!(machine.to_part_versions(), vec![ObjectVersion(11)]);
+        Ok(())
+    }
+
+    #[test]
+    fn object_parts_are_bounded() -> TestResult {
+        let mut machine = Machine::new();
+        let id = "log".to_owned();
+        let metadata = Metadata {
+            version: ObjectVersion(1),
+            data: Vec::new(),
+        };
+        machine.put(id.clone(), metadata, 0, &Expect::None.into())?;
+        for i in 1..ObjectParts::MAX_PARTS as u64 {
+            let version = ObjectVersion(i + 1);
+            machine.reserve_part(&id, version, 1, &Expect::Any.into(), Seconds(60), 0)?;
+            machine.commit_part(&id, version, 0)?;
+        }
+        let version = ObjectVersion(ObjectParts::MAX_PARTS as u64 + 1);
+        assert!(machine
+            .reserve_part(&id, version, 1, &Expect::Any.into(), Seconds(60), 0)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn object_parts_encoding_works() {
+        let parts = ObjectParts {
+            parts: vec![
+                ObjectPart {
+                    version: ObjectVersion(1),
+                    size: None,
+                },
+                ObjectPart {
+                    version: ObjectVersion(2),
+                    size: Some(10),
+                },
+            ],
+            last_size: Some(0),
+        };
+        assert_eq!(ObjectParts::decode(&parts.encode()), Some(parts));
+
+        // 大きさを持たない初期の形式もデコードできる
+        let mut data = b"\0FRGPRTS".to_vec();
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 7]);
+        assert_eq!(
+            ObjectParts::decode(&data),
+            Some(ObjectParts {
+                parts: vec![ObjectPart {
+                    version: ObjectVersion(7),
+                    size: None,
+                }],
+                last_size: None,
+            })
+        );
+        assert_eq!(ObjectParts::decode(b"\0FRGPRT2"), None);
+    }
+
+    #[test]
+    fn resolve_precondition_works() -> TestResult {
+        let mut machine = Machine::new();
+        let id = "foo".to_owned();
+        machine.record_commit(ObjectVersion(1), 100);
+        let metadata = Metadata {
+            version: ObjectVersion(1),
+            data: Vec::new(),
+        };
+        machine.put(id.clone(), metadata, 0, &Expect::None.into())?;
+
+        // `Expect`による条件はそのまま
+        let expect = Precondition::from(Expect::IfNoneMatch(vec![ObjectVersion(3)]));
+        assert_eq!(machine.resolve_precondition(&id, expect.clone())?, expect);
+
+        // それ以外の条件は、現在のバージョンに一致することを条件とするものに変換される
+        let resolved = machine.resolve_precondition(&id, Precondition::IfUnmodifiedSince(100))?;
+        assert_eq!(
+            resolved,
+            Precondition::from(Expect::IfMatch(vec![ObjectVersion(1)]))
+        );
+        assert!(machine
+            .resolve_precondition(&id, Precondition::IfUnmodifiedSince(99))
+            .is_err());
+        assert!(machine
+            .resolve_precondition(&id, Precondition::IfNewerThan(ObjectVersion(1)))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn delete_jobs_work() -> TestResult {
+        let mut machine = Machine::new();
+        setup_metadata(&mut machine, 3, MetadataKind::MUSIC);
+        let ids = (0..3)
+            .map(|i| make_object_id(i, MetadataKind::MUSIC))
+            .collect::<Vec<_>>();
+        let prefix = ObjectPrefix("music:".to_owned());
+        machine.start_delete_job(1, prefix.clone(), Some(Seconds(60)), ObjectVersion(10));
+        assert_eq!(machine.running_delete_jobs(), vec![1]);
+        assert_eq!(machine.delete_job_targets(1).len(), 3);
+
+        // ジョブの開始後に保存されたオブジェクトは削除されない
+        let metadata = Metadata {
+            version: ObjectVersion(11),
+            data: Vec::new(),
+        };
+        machine.put(ids[0].clone(), metadata, 0, &Expect::Any.into())?;
+        assert_eq!(machine.delete_job_targets(1).len(), 2);
+        let released = machine.delete_job_batch(1, &ids[..2], 1000);
+        assert!(released.is_empty());
+        assert_eq!(machine.len(), 2);
+
+        // 削除猶予期間が指定されたジョブでは、オブジェクトは猶予期間中となる
+        assert_eq!(machine.tombstone_len(), 1);
+        assert_eq!(
+            machine.undelete(&ids[1], 1000)?,
+            Some(DEFAULT_OBJECT_VERSION)
+        );
+
+        let status = machine.delete_job_status(1).unwrap();
+        assert_eq!((status.total, status.processed, status.deleted), (3, 2, 1));
+
+        // スナップショットから復元した場合にも、ジョブの状態は引き継がれる
+        let mut restored = Machine::from_snapshot(machine.to_snapshot())
+            .with_delete_jobs(machine.to_delete_jobs());
+        assert_eq!(restored.delete_job_status(1), Some(status));
+        restored.finish_delete_job(1, true, ObjectVersion(12));
+        assert_eq!(
+            restored.delete_job_status(1).unwrap().state,
+            DeleteJobState::Cancelled
+        );
+        assert!(restored.running_delete_jobs().is_empty());
+
+        // 終了したジョブのバッチは無視される
+        assert!(restored.delete_job_batch(1, &ids, 2000).is_empty());
+        assert_eq!(restored.len(), 3);
+
+        // 対象が存在しないジョブは即座に完了する
+        restored.start_delete_job(2, ObjectPrefix("foo".to_owned()), None, ObjectVersion(13));
+        assert_eq!(
+            restored.delete_job_status(2).unwrap().state,
+            DeleteJobState::Completed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn relayout_job_works() -> TestResult {
+        let mut machine = Machine::new();
+        setup_music_metadata_by_versions(
+            &mut machine,
+            vec![ObjectVersion(3), ObjectVersion(5), ObjectVersion(8)],
+        );
+        assert!(machine.relayout().is_none());
+        assert!(!machine.is_frozen());
+
+        track!(machine.start_relayout(1, ObjectVersion(10)))?;
+        assert_eq!(
+            machine.content_versions_after(ObjectVersion(0)),
+            vec![ObjectVersion(3), ObjectVersion(5), ObjectVersion(8)]
+        );
+
+        // 進捗は後退しない
+        track!(machine.record_relayout_progress(1, ObjectVersion(5), 2))?;
+        track!(machine.record_relayout_progress(1, ObjectVersion(3), 1))?;
+        let job = machine.relayout().cloned().unwrap();
+        assert_eq!(job.cursor, ObjectVersion(5));
+        assert_eq!(job.copied, 2);
+        assert_eq!(
+            machine.content_versions_after(job.cursor),
+            vec![ObjectVersion(8)]
+        );
+
+        // 世代が一致しないコマンドは拒否される
+        assert!(machine.freeze_relayout(2).is_err());
+        assert!(machine.start_relayout(2, ObjectVersion(11)).is_err());
+
+        // 同じ世代の開始は冪等
+        track!(machine.start_relayout(1, ObjectVersion(12)))?;
+        assert_eq!(machine.relayout().unwrap().started_at, ObjectVersion(10));
+
+        track!(machine.freeze_relayout(1))?;
+        assert!(machine.is_frozen());
+
+        let restored = Machine::from_snapshot(machine.to_snapshot())
+            .with_relayout(machine.relayout().cloned());
+        assert!(restored.is_frozen());
+
+        track!(machine.finish_relayout(1))?;
+        assert!(!machine.is_frozen());
+        assert!(machine
+            .record_relayout_progress(1, ObjectVersion(8), 3)
+            .is_err());
+
+        // 終了後は次の世代の再配置を開始できる
+        track!(machine.start_relayout(2, ObjectVersion(20)))?;
+        assert_eq!(machine.relayout().unwrap().cursor, ObjectVersion(0));
+        Ok(())
+    }
+
     #[test]
     fn it_doesnt_delete_non_matched_objects_by_prefix() -> TestResult {
         let mut machine = Machine::new();
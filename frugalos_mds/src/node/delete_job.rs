@@ -0,0 +1,240 @@
+use libfrugalos::entity::object::{ObjectId, ObjectPrefix};
+use raftlog::log::LogIndex;
+use std::collections::{BTreeMap, VecDeque};
+
+use machine::Command;
+
+/// 接頭辞指定での削除ジョブの状態.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeleteJobState {
+    /// 実行中.
+    Running,
+
+    /// 対象のオブジェクトを全て処理し終えた.
+    Completed,
+
+    /// 利用者によって中断された.
+    Cancelled,
+}
+
+/// 接頭辞指定での削除ジョブの進捗.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeleteJobStatus {
+    /// ジョブの ID.
+    pub job_id: u64,
+
+    /// 削除対象のオブジェクトの接頭辞.
+    pub prefix: ObjectPrefix,
+
+    /// ジョブの状態.
+    pub state: DeleteJobState,
+
+    /// ジョブの開始時点で、接頭辞に一致したオブジェクトの数.
+    pub total: u64,
+
+    /// 削除を試みたオブジェクトの数.
+    pub processed: u64,
+
+    /// 実際に削除されたオブジェクトの数.
+    ///
+    /// ジョブの開始後に他の操作で削除されたオブジェクトは含まれない.
+    pub deleted: u64,
+}
+
+#[derive(Debug)]
+struct PendingJob {
+    // まだ削除を提案していないオブジェクト群.
+    remaining: VecDeque<ObjectId>,
+
+    // 提案中のコマンドのコミット位置と、それに含まれるオブジェクト群.
+    inflight: Option<(LogIndex, Vec<ObjectId>)>,
+}
+
+/// リーダが、実行中の削除ジョブのコマンドを順に提案するための状態.
+///
+/// 一度のコマンドで全てを削除する`DeleteByPrefix`とは異なり、対象のオブジェクトを
+/// バッチに分けて順に削除し、全てを処理し終えたらジョブの完了を提案する.
+/// 次のバッチは、前のバッチの削除によって発行されたイベントが全て取り出され
+/// 実データの削除が始まってから提案されるので、大量のオブジェクトを削除する場合にも
+/// 削除待ちのイベントが溜まり続けることはない.
+///
+/// ジョブ自体の状態と進捗は`Machine`で複製されており、ここではリーダのローカルな
+/// 作業状態のみを保持する. リーダが交代した場合には、新たなリーダが
+/// マシンの状態から未処理のオブジェクト群を求めて処理を引き継ぐ.
+#[derive(Debug, Default)]
+pub(crate) struct DeleteJobs {
+    jobs: BTreeMap<u64, PendingJob>,
+}
+impl DeleteJobs {
+    /// 実行中のジョブの処理を開始(ないし再開)する.
+    ///
+    /// `targets`は、ジョブの対象のうち、まだ削除されていないオブジェクト群.
+    /// 既に処理中のジョブの場合には何もしない.
+    pub fn resume(&mut self, job_id: u64, targets: Vec<ObjectId>) {
+        self.jobs.entry(job_id).or_insert_with(|| PendingJob {
+            remaining: targets.into(),
+            inflight: None,
+        });
+    }
+
+    /// ジョブの処理を止める.
+    ///
+    /// ジョブの終了がコミットされた場合に呼び出される.
+    pub fn forget(&mut self, job_id: u64) {
+        self.jobs.remove(&job_id);
+    }
+
+    /// 全てのジョブの処理を止める.
+    ///
+    /// リーダではなくなった場合に呼び出される.
+    pub fn clear(&mut self) {
+        self.jobs.clear();
+    }
+
+    /// 次に提案するコマンドを取り出す.
+    ///
+    /// 対象のオブジェクトが残っているジョブでは次のバッチの削除が、
+    /// 全てを処理し終えたジョブでは完了が提案される.
+    /// `propose`には、コマンドを提案してそのコミット位置を返す関数を指定する.
+    /// 提案に失敗した場合には、バッチはジョブに戻される.
+    pub fn next_proposal<F, E>(&mut self, batch_size: usize, mut propose: F) -> Result<(), E>
+    where
+        F: FnMut(Command) -> Result<LogIndex, E>,
+    {
+        let (job_id, job) =
+            if let Some(x) = self.jobs.iter_mut().find(|(_, j)| j.inflight.is_none()) {
+                x
+            } else {
+                return Ok(());
+            };
+        let job_id = *job_id;
+        let n = batch_size.max(1).min(job.remaining.len());
+        let batch: Vec<_> = job.remaining.drain(..n).collect();
+        let command = if batch.is_empty() {
+            Command::FinishDeleteJob {
+                job_id,
+                cancelled: false,
+            }
+        } else {
+            Command::DeleteObjects {
+                job_id,
+                object_ids: batch.clone(),
+            }
+        };
+        match propose(command) {
+            Ok(index) => {
+                job.inflight = Some((index, batch));
+                Ok(())
+            }
+            Err(e) => {
+                for id in batch.into_iter().rev() {
+                    job.remaining.push_front(id);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// `index`の位置で、提案したコマンドがコミットされたことを通知する.
+    pub fn committed(&mut self, index: LogIndex) {
+        for job in self.jobs.values_mut() {
+            if job.inflight.as_ref().is_some_and(|&(i, _)| i == index) {
+                job.inflight = None;
+            }
+        }
+    }
+
+    /// `next_commit`よりも前の位置に提案されたまま、コミットされなかったバッチをジョブに戻す.
+    ///
+    /// 提案したエントリが、リーダの交代等によって別のエントリに置き換えられた場合が該当する.
+    pub fn requeue_lost_batches(&mut self, next_commit: LogIndex) {
+        for job in self.jobs.values_mut() {
+            let is_lost = job.inflight.as_ref().is_some_and(|&(i, _)| i < next_commit);
+            if !is_lost {
+                continue;
+            }
+            let (_, batch) = job.inflight.take().expect("Never fails");
+            for id in batch.into_iter().rev() {
+                job.remaining.push_front(id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(n: usize) -> Vec<ObjectId> {
+        (0..n).map(|i| format!("foo/{}", i)).collect()
+    }
+
+    fn batch_of(command: Command) -> Vec<ObjectId> {
+        match command {
+            Command::DeleteObjects { job_id, object_ids } => {
+                assert_eq!(job_id, 1);
+                object_ids
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn delete_jobs_work() {
+        let mut jobs = DeleteJobs::default();
+        jobs.resume(1, ids(5));
+
+        // 既に処理中のジョブは再開されない
+        jobs.resume(1, ids(1));
+
+        // 提案に失敗した場合にはバッチが戻される
+        let result = jobs.next_proposal(2, |_| Err(()));
+        assert!(result.is_err());
+        let mut proposed = Vec::new();
+        let _ = jobs.next_proposal(2, |command| -> Result<_, ()> {
+            proposed = batch_of(command);
+            Ok(LogIndex::new(10))
+        });
+        assert_eq!(proposed, vec!["foo/0".to_owned(), "foo/1".to_owned()]);
+
+        // 提案中のバッチがある間は、次のバッチは提案されない
+        let _ = jobs.next_proposal(2, |_| -> Result<_, ()> { panic!() });
+
+        // コミットされなかったバッチは戻される
+        jobs.requeue_lost_batches(LogIndex::new(11));
+        let _ = jobs.next_proposal(3, |command| -> Result<_, ()> {
+            assert_eq!(batch_of(command).len(), 3);
+            Ok(LogIndex::new(20))
+        });
+        jobs.committed(LogIndex::new(20));
+
+        let _ = jobs.next_proposal(3, |command| -> Result<_, ()> {
+            assert_eq!(
+                batch_of(command),
+                vec!["foo/3".to_owned(), "foo/4".to_owned()]
+            );
+            Ok(LogIndex::new(21))
+        });
+        jobs.committed(LogIndex::new(21));
+
+        // 全てを処理し終えたら、ジョブの完了が提案される
+        let _ = jobs.next_proposal(3, |command| -> Result<_, ()> {
+            match command {
+                Command::FinishDeleteJob { job_id, cancelled } => {
+                    assert_eq!(job_id, 1);
+                    assert!(!cancelled);
+                }
+                other => panic!("unexpected command: {:?}", other),
+            }
+            Ok(LogIndex::new(22))
+        });
+        jobs.committed(LogIndex::new(22));
+        jobs.forget(1);
+        let _ = jobs.next_proposal(3, |_| -> Result<_, ()> { panic!() });
+
+        // リーダでなくなった場合には、全てのジョブの処理を止める
+        jobs.resume(2, ids(3));
+        jobs.clear();
+        let _ = jobs.next_proposal(3, |_| -> Result<_, ()> { panic!() });
+    }
+}
@@ -1,6 +1,7 @@
 use fibers::sync::{mpsc, oneshot};
+use fibers::time::timer;
 use futures::future::Either;
-use futures::{self, Future};
+use futures::{self, Async, Future, Poll};
 use libfrugalos::consistency::ReadConsistency;
 use libfrugalos::entity::node::RemoteNodeId;
 use libfrugalos::entity::object::{
@@ -9,10 +10,13 @@ use libfrugalos::entity::object::{
 use libfrugalos::expect::Expect;
 use libfrugalos::time::Seconds;
 use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Instant;
+use trackable::error::ErrorKindExt;
 
-use super::{Reply, Request};
-use Error;
+use super::{DeleteJobStatus, Reply, Request};
+use {Error, ErrorKind, ObjectChanges, Precondition, QuotaUsage, RevisionSelector};
 
 macro_rules! future_try {
     ($e:expr) => {
@@ -23,13 +27,56 @@ macro_rules! future_try {
     };
 }
 
+/// ノードのリーダ状態.
+///
+/// ノード自身が更新し、`Service`がサーバ内のリーダ数の偏りやリーダの確定状況を把握するために参照する.
+#[derive(Debug, Default)]
+pub(crate) struct Leadership {
+    is_leader: AtomicBool,
+    members: AtomicUsize,
+    has_leader: AtomicBool,
+}
+impl Leadership {
+    pub(crate) fn update(&self, is_leader: bool, members: usize) {
+        self.is_leader.store(is_leader, Ordering::SeqCst);
+        self.members.store(members, Ordering::SeqCst);
+    }
+    pub(crate) fn is_leader(&self) -> bool {
+        self.is_leader.load(Ordering::SeqCst)
+    }
+
+    /// ノードが属するクラスタのリーダが確定しているかどうかを設定する.
+    pub(crate) fn set_has_leader(&self, has_leader: bool) {
+        self.has_leader.store(has_leader, Ordering::SeqCst);
+    }
+
+    /// ノードが属するクラスタのリーダが確定しているかどうかを返す.
+    ///
+    /// リーダの選出後、その任期の最初のエントリがコミットされた時点で確定したとみなす.
+    pub(crate) fn has_leader(&self) -> bool {
+        self.has_leader.load(Ordering::SeqCst)
+    }
+
+    /// ノードが属するクラスタのメンバ数を返す(不明な場合は`0`).
+    pub(crate) fn members(&self) -> usize {
+        self.members.load(Ordering::SeqCst)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct NodeHandle {
     request_tx: mpsc::Sender<Request>,
+    leadership: Arc<Leadership>,
 }
 impl NodeHandle {
     pub(crate) fn new(request_tx: mpsc::Sender<Request>) -> Self {
-        NodeHandle { request_tx }
+        NodeHandle {
+            request_tx,
+            leadership: Arc::default(),
+        }
+    }
+    pub(crate) fn leadership(&self) -> &Arc<Leadership> {
+        &self.leadership
     }
     pub fn stop(&self, reply: Reply<()>) {
         let _ = self.request_tx.send(Request::Stop(reply));
@@ -43,6 +90,16 @@ impl NodeHandle {
     pub fn start_reelection(&self) {
         let _ = self.request_tx.send(Request::StartElection);
     }
+    /// リーダである場合には、他のメンバにリーダとなるよう推薦する.
+    ///
+    /// リーダでない場合には何もしない.
+    pub fn resign_leadership(&self) {
+        let _ = self.request_tx.send(Request::ResignLeadership);
+    }
+    /// 停止に備えて、リーダ権を他のメンバに譲り、以降リーダに選ばれても引き受けないようにする.
+    pub fn drain(&self) {
+        let _ = self.request_tx.send(Request::Drain);
+    }
     pub fn get_leader(
         &self,
         started_at: Instant,
@@ -51,7 +108,7 @@ impl NodeHandle {
         let request = Request::GetLeader(started_at, monitored);
         future_try!(self.request_tx.send(request));
         let future = monitor
-            .map(|node| (node.addr, node.local_id.to_string()))
+            .map(|node| (node.current_addr(), node.local_id.to_string()))
             .map_err(|e| track!(Error::from(e)));
         Either::A(future)
     }
@@ -63,6 +120,33 @@ impl NodeHandle {
         Either::A(future)
     }
 
+    /// 最新のバージョンが`range`(UNIXエポックからのミリ秒)の期間内に保存されたオブジェクトの一覧を返す.
+    pub fn list_objects_by_time_range(
+        &self,
+        range: Range<u64>,
+    ) -> impl Future<Item = Vec<ObjectSummary>, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::ListByTimeRange(range, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+
+    /// `after`よりも後にコミットされたオブジェクトの変更を、最大`max`個(一つのコマンドによる変更群は分割されない)返す.
+    ///
+    /// `after`が`None`の場合には、現在の位置のみを返す.
+    pub fn watch(
+        &self,
+        after: Option<ObjectVersion>,
+        max: usize,
+    ) -> impl Future<Item = ObjectChanges, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::Watch(after, max, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+
     pub fn latest_version(&self) -> impl Future<Item = Option<ObjectSummary>, Error = Error> {
         let (monitored, monitor) = oneshot::monitor();
         let request = Request::LatestVersion(monitored);
@@ -81,6 +165,26 @@ impl NodeHandle {
         Either::A(future)
     }
 
+    /// このノード(= セグメント)の使用量と割り当て量を取得する.
+    pub fn quota_usage(&self) -> impl Future<Item = QuotaUsage, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::QuotaUsage(monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+
+    /// このノードの状態のリーダからの遅れが、`max_lag`エントリ以内かどうかを確認する.
+    ///
+    /// 遅れが大きい場合や、リーダが不明な場合には`ErrorKind::NotLeader`エラーとなる.
+    pub fn check_lag(&self, max_lag: u64) -> impl Future<Item = (), Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::CheckLag(max_lag, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+
     pub fn get_object(
         &self,
         object_id: ObjectId,
@@ -95,6 +199,20 @@ impl NodeHandle {
         Either::A(future)
     }
 
+    pub fn get_object_revision(
+        &self,
+        object_id: ObjectId,
+        selector: RevisionSelector,
+        expect: Expect,
+        consistency: ReadConsistency,
+    ) -> impl Future<Item = Option<Metadata>, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::GetRevision(object_id, selector, expect, consistency, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+
     pub fn head_object(
         &self,
         object_id: ObjectId,
@@ -111,11 +229,27 @@ impl NodeHandle {
     pub fn delete_object(
         &self,
         object_id: ObjectId,
-        expect: Expect,
+        expect: Precondition,
+        started_at: Instant,
+        deadline: Option<Instant>,
+    ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::Delete(object_id, expect, started_at, deadline, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(WithDeadline::new(future, deadline))
+    }
+
+    /// 削除猶予期間中のオブジェクトを復元する.
+    ///
+    /// 結果は復元されたオブジェクトのバージョン(復元可能なものがなかった場合は`None`).
+    pub fn undelete_object(
+        &self,
+        object_id: ObjectId,
         started_at: Instant,
     ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
         let (monitored, monitor) = oneshot::monitor();
-        let request = Request::Delete(object_id, expect, started_at, monitored);
+        let request = Request::Undelete(object_id, started_at, monitored);
         future_try!(self.request_tx.send(request));
         let future = monitor.map_err(|e| track!(Error::from(e)));
         Either::A(future)
@@ -177,6 +311,39 @@ impl NodeHandle {
         */
     }
 
+    /// 接頭辞に一致するオブジェクト群を、バッチに分けて削除するジョブを開始する.
+    ///
+    /// 結果はジョブの ID.
+    pub fn start_delete_job(&self, prefix: ObjectPrefix) -> impl Future<Item = u64, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::StartDeleteJob(prefix, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+
+    pub fn get_delete_job(
+        &self,
+        job_id: u64,
+    ) -> impl Future<Item = Option<DeleteJobStatus>, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::GetDeleteJob(job_id, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+
+    pub fn cancel_delete_job(
+        &self,
+        job_id: u64,
+    ) -> impl Future<Item = Option<DeleteJobStatus>, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::CancelDeleteJob(job_id, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+
     pub fn delete_by_prefix(
         &self,
         prefix: ObjectPrefix,
@@ -188,19 +355,58 @@ impl NodeHandle {
         Either::A(future)
     }
 
+    /// オブジェクトを保存する.
+    ///
+    /// `deadline`が指定された場合には、それまでに Raft へのコミットが完了しなければ
+    /// `ErrorKind::Timeout`で失敗する(ただし、提案済みのコマンドが後からコミットされることはあり得る).
+    ///
+    /// `size`はオブジェクトの論理的な大きさ(バイト)で、割り当て量の確認に使われる(不明な場合は0).
+    #[allow(clippy::too_many_arguments)]
     pub fn put_object(
         &self,
         object_id: ObjectId,
         body: Vec<u8>,
-        expect: Expect,
+        size: u64,
+        expect: Precondition,
         put_content_timeout: Seconds,
         started_at: Instant,
+        deadline: Option<Instant>,
     ) -> impl Future<Item = (ObjectVersion, Option<ObjectVersion>), Error = Error> {
         let (monitored, monitor) = oneshot::monitor();
         let request = Request::Put(
             object_id,
             body,
+            size,
+            expect,
+            put_content_timeout,
+            started_at,
+            deadline,
+            monitored,
+        );
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(WithDeadline::new(future, deadline))
+    }
+
+    /// 既存のオブジェクトに追記する、大きさが`size`の部分のためにバージョンを予約する.
+    ///
+    /// 部分の内容を保存した後で`commit_append`を呼び出すことで、オブジェクトに追記される.
+    /// `put_content_timeout`が過ぎても追記されなかった部分は破棄される.
+    ///
+    /// 結果は予約された部分のバージョンと、予約時点のオブジェクトのバージョンの組.
+    pub fn append_object(
+        &self,
+        object_id: ObjectId,
+        expect: Expect,
+        size: u64,
+        put_content_timeout: Seconds,
+        started_at: Instant,
+    ) -> impl Future<Item = (ObjectVersion, Option<ObjectVersion>), Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::Append(
+            object_id,
             expect,
+            size,
             put_content_timeout,
             started_at,
             monitored,
@@ -209,6 +415,55 @@ impl NodeHandle {
         let future = monitor.map_err(|e| track!(Error::from(e)));
         Either::A(future)
     }
+
+    /// `append_object`で予約した部分を、オブジェクトの末尾に追記する.
+    ///
+    /// 結果は追記前のオブジェクトのバージョン.
+    pub fn commit_append(
+        &self,
+        object_id: ObjectId,
+        version: ObjectVersion,
+        started_at: Instant,
+    ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let request = Request::CommitAppend(object_id, version, started_at, monitored);
+        future_try!(self.request_tx.send(request));
+        let future = monitor.map_err(|e| track!(Error::from(e)));
+        Either::A(future)
+    }
+}
+
+/// 期限を過ぎた時点で`ErrorKind::Timeout`を返す`Future`.
+#[derive(Debug)]
+struct WithDeadline<F> {
+    future: F,
+    timeout: Option<timer::Timeout>,
+}
+impl<F> WithDeadline<F> {
+    fn new(future: F, deadline: Option<Instant>) -> Self {
+        let timeout = deadline.map(|d| timer::timeout(d.saturating_duration_since(Instant::now())));
+        WithDeadline { future, timeout }
+    }
+}
+impl<F> Future for WithDeadline<F>
+where
+    F: Future<Error = Error>,
+{
+    type Item = F::Item;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(item) = track!(self.future.poll())? {
+            return Ok(Async::Ready(item));
+        }
+        let fired = self
+            .timeout
+            .poll()
+            .map_err(|e| Error::from(ErrorKind::Other.cause(e)))?;
+        if let Async::Ready(Some(())) = fired {
+            track_panic!(ErrorKind::Timeout, "Deadline exceeded");
+        }
+        Ok(Async::NotReady)
+    }
 }
 
 #[cfg(test)]
@@ -259,4 +514,32 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn it_fails_when_deadline_is_exceeded() -> TestResult {
+        let (handle, mut receiver) = make_handle();
+        let deadline = Instant::now() + Duration::from_millis(10);
+        let future = handle.put_object(
+            ObjectId::from("foo"),
+            Vec::new(),
+            0,
+            Precondition::default(),
+            Seconds(0),
+            Instant::now(),
+            Some(deadline),
+        );
+
+        // ノードが応答しないままでも、期限を過ぎれば失敗する
+        let e = fibers_global::execute(future).unwrap_err();
+        assert_eq!(*e.kind(), ErrorKind::Timeout);
+        assert!(Instant::now() >= deadline);
+
+        match receiver.poll().unwrap() {
+            Async::Ready(Some(Request::Put(_, _, _, _, _, _, Some(d), _))) => {
+                assert_eq!(d, deadline)
+            }
+            _ => panic!(),
+        }
+        Ok(())
+    }
 }
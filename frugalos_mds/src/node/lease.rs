@@ -0,0 +1,215 @@
+use raftlog::message::SequenceNumber;
+use std::collections::VecDeque;
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// 保留された時刻付きの読み込み要求群.
+type DeferredReads<T> = Vec<(Instant, T)>;
+
+/// リーダが`Consistent`な読み込みに応答するためのリース.
+///
+/// ネットワーク分断によって孤立した旧リーダは、新しいリーダが選出された後も
+/// 自分がリーダであると認識し続けるため、ローカルの状態のみを参照して応答すると
+/// 古いデータを返してしまう可能性がある.
+///
+/// そのため、読み込み要求を受け付けたリーダは、ハートビートを送信して過半数のメンバから
+/// 応答を得られるまで、その要求への応答を保留する.
+/// ハートビートの応答が得られた場合には、その送信時刻から`duration`の間はリースが有効となり、
+/// 以後の読み込み要求には(ハートビートを挟まずに)即座に応答することができる.
+///
+/// フォロワーは、最後にリーダからの通信を受信してから選挙タイムアウトが経過するまでは
+/// 新たな選挙を開始しないので、`duration`がその値よりも十分に短ければ、
+/// リースの有効期間中に他のノードがリーダとなることはない.
+#[derive(Debug)]
+pub(crate) struct ReadLease<T> {
+    duration: Duration,
+    expires_at: Option<Instant>,
+
+    // まだハートビートを送信していない読み込み要求群.
+    unsent: DeferredReads<T>,
+
+    // 応答待ちのハートビート(シーケンス番号と送信時刻)と、その応答を待っている読み込み要求群.
+    inflights: VecDeque<(SequenceNumber, Instant, DeferredReads<T>)>,
+}
+impl<T> ReadLease<T> {
+    /// 新しい`ReadLease`インスタンスを生成する.
+    ///
+    /// `duration`が0の場合には、リースは常に無効となり、全ての読み込み要求でハートビートが送信される.
+    pub fn new(duration: Duration) -> Self {
+        ReadLease {
+            duration,
+            expires_at: None,
+            unsent: Vec::new(),
+            inflights: VecDeque::new(),
+        }
+    }
+
+    /// リースが有効かどうかを判定する.
+    pub fn is_valid(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|t| now < t)
+    }
+
+    /// 読み込み要求を、リーダであることが確認できるまで保留する.
+    pub fn defer(&mut self, read: T, now: Instant) {
+        self.unsent.push((now, read));
+    }
+
+    /// 新たにハートビートを送信する必要があるかどうかを判定する.
+    pub fn needs_heartbeat(&self) -> bool {
+        !self.unsent.is_empty()
+    }
+
+    /// ハートビートが送信されたことを記録する.
+    ///
+    /// それまでに保留された読み込み要求は、このハートビートへの応答を待つことになる.
+    pub fn heartbeat_sent(&mut self, seq_no: SequenceNumber, now: Instant) {
+        let reads = mem::take(&mut self.unsent);
+        self.inflights.push_back((seq_no, now, reads));
+    }
+
+    /// 過半数のメンバから`ack`までのハートビートへの応答が得られたことを記録する.
+    ///
+    /// リースを延長し、応答を返しても良くなった読み込み要求群を返す.
+    pub fn heartbeat_acked(&mut self, ack: SequenceNumber) -> Vec<T> {
+        let mut confirmed = Vec::new();
+        while self.inflights.front().is_some_and(|x| x.0 <= ack) {
+            let (_, sent_at, reads) = self.inflights.pop_front().expect("Never fails");
+            if self.duration != Duration::from_secs(0) {
+                let expires_at = sent_at + self.duration;
+                if self.expires_at.is_none_or(|t| t < expires_at) {
+                    self.expires_at = Some(expires_at);
+                }
+            }
+            confirmed.extend(reads.into_iter().map(|(_, read)| read));
+        }
+        confirmed
+    }
+
+    /// 保留されてから`timeout`以上が経過した読み込み要求群を取り除いて返す.
+    pub fn take_expired(&mut self, now: Instant, timeout: Duration) -> Vec<T> {
+        let is_expired = |&(deferred_at, _): &(Instant, T)| {
+            now.saturating_duration_since(deferred_at) >= timeout
+        };
+        let mut expired = Vec::new();
+        for reads in self
+            .inflights
+            .iter_mut()
+            .map(|x| &mut x.2)
+            .chain(Some(&mut self.unsent))
+        {
+            let (xs, ys): (Vec<_>, Vec<_>) = reads.drain(..).partition(&is_expired);
+            *reads = ys;
+            expired.extend(xs.into_iter().map(|(_, read)| read));
+        }
+        self.inflights.retain(|x| !x.2.is_empty());
+        expired
+    }
+
+    /// リースを破棄し、保留中の読み込み要求群を全て返す.
+    ///
+    /// ノードがリーダでなくなった場合に呼び出される.
+    pub fn revoke(&mut self) -> Vec<T> {
+        self.expires_at = None;
+        let mut reads = Vec::new();
+        for (_, _, xs) in self.inflights.drain(..) {
+            reads.extend(xs.into_iter().map(|(_, read)| read));
+        }
+        reads.extend(self.unsent.drain(..).map(|(_, read)| read));
+        reads
+    }
+
+    /// 保留中の読み込み要求の数を返す.
+    pub fn len(&self) -> usize {
+        self.unsent.len() + self.inflights.iter().map(|x| x.2.len()).sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seq(n: u64) -> SequenceNumber {
+        SequenceNumber::new(n)
+    }
+
+    #[test]
+    fn acked_heartbeat_extends_lease() {
+        let now = Instant::now();
+        let mut lease = ReadLease::new(Duration::from_secs(1));
+        assert!(!lease.is_valid(now));
+
+        lease.defer("a", now);
+        lease.defer("b", now);
+        assert!(lease.needs_heartbeat());
+        lease.heartbeat_sent(seq(3), now);
+        assert!(!lease.needs_heartbeat());
+        assert_eq!(lease.len(), 2);
+
+        // 過半数の応答が得られるまでは、応答できない
+        assert!(lease.heartbeat_acked(seq(2)).is_empty());
+        assert!(!lease.is_valid(now));
+
+        assert_eq!(lease.heartbeat_acked(seq(3)), vec!["a", "b"]);
+        assert_eq!(lease.len(), 0);
+        assert!(lease.is_valid(now + Duration::from_millis(999)));
+        assert!(!lease.is_valid(now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn partitioned_leader_cannot_serve_reads() {
+        // 過半数と通信できていた間に得たリースが切れた後は、
+        // 分断されている限り、読み込みに応答することはない
+        let t0 = Instant::now();
+        let mut lease = ReadLease::new(Duration::from_secs(1));
+        lease.defer("before", t0);
+        lease.heartbeat_sent(seq(1), t0);
+        assert_eq!(lease.heartbeat_acked(seq(1)), vec!["before"]);
+
+        let t1 = t0 + Duration::from_secs(2);
+        assert!(!lease.is_valid(t1));
+        lease.defer("after", t1);
+        lease.heartbeat_sent(seq(2), t1);
+        assert!(lease.heartbeat_acked(seq(1)).is_empty());
+        assert!(!lease.is_valid(t1));
+
+        let timeout = Duration::from_secs(5);
+        assert!(lease
+            .take_expired(t1 + Duration::from_secs(4), timeout)
+            .is_empty());
+        assert_eq!(
+            lease.take_expired(t1 + Duration::from_secs(5), timeout),
+            vec!["after"]
+        );
+        assert_eq!(lease.len(), 0);
+
+        // 既にタイムアウトした要求は、後で応答が届いても返されない
+        assert!(lease.heartbeat_acked(seq(2)).is_empty());
+    }
+
+    #[test]
+    fn revoke_works() {
+        let now = Instant::now();
+        let mut lease = ReadLease::new(Duration::from_secs(1));
+        lease.defer(1, now);
+        lease.heartbeat_sent(seq(1), now);
+        lease.heartbeat_acked(seq(1));
+        lease.defer(2, now);
+        lease.heartbeat_sent(seq(2), now);
+        lease.defer(3, now);
+        assert!(lease.is_valid(now));
+
+        assert_eq!(lease.revoke(), vec![2, 3]);
+        assert!(!lease.is_valid(now));
+        assert_eq!(lease.len(), 0);
+    }
+
+    #[test]
+    fn zero_duration_disables_lease() {
+        let now = Instant::now();
+        let mut lease = ReadLease::new(Duration::from_secs(0));
+        lease.defer((), now);
+        lease.heartbeat_sent(seq(1), now);
+        assert_eq!(lease.heartbeat_acked(seq(1)).len(), 1);
+        assert!(!lease.is_valid(now));
+    }
+}
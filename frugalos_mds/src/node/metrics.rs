@@ -1,24 +1,21 @@
 //! This crate provides some helpers for metrics.
 
+use frugalos_core::prometheus;
 use prometrics::metrics::{Histogram, HistogramBuilder};
 
 use {Error, Result};
 
-/// Creates a histogram, which can be used as default.
-pub fn make_histogram(builder: &mut HistogramBuilder) -> Result<Histogram> {
-    builder
-        .bucket(0.0001)
-        .bucket(0.0005)
-        .bucket(0.001)
-        .bucket(0.005)
-        .bucket(0.01)
-        .bucket(0.05)
-        .bucket(0.1)
-        .bucket(0.5)
-        .bucket(1.0)
-        .bucket(5.0)
-        .bucket(10.0)
-        .default_registry()
-        .finish()
+/// Creates a histogram named `frugalos_mds_${name}`.
+///
+/// The buckets can be overridden by `PrometheusConfig`.
+pub fn make_histogram(name: &str) -> Result<Histogram> {
+    prometheus::config()
+        .configure_histogram(
+            &format!("frugalos_mds_{}", name),
+            HistogramBuilder::new(name)
+                .namespace("frugalos")
+                .subsystem("mds")
+                .default_registry(),
+        )
         .map_err(|e| track!(Error::from(e)))
 }
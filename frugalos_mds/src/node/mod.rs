@@ -1,5 +1,6 @@
 #![allow(clippy::module_inception)]
 use fibers::sync::oneshot::Monitored;
+use frugalos_core::hlc::HybridTimestamp;
 use frugalos_raft::NodeId;
 use libfrugalos::consistency::ReadConsistency;
 use libfrugalos::entity::object::{
@@ -11,15 +12,20 @@ use machine::Machine;
 use prometrics::metrics::{Counter, Histogram, MetricBuilder};
 use raftlog::log::LogIndex;
 use raftlog::log::ProposalId;
+use std::ops::Range;
 use std::time::Instant;
 use trackable::error::ErrorKindExt;
 
-use {Error, ErrorKind, Result};
+use {Error, ErrorKind, ObjectChanges, Precondition, QuotaUsage, Result, RevisionSelector};
 
+pub use self::delete_job::{DeleteJobState, DeleteJobStatus};
+pub(crate) use self::handle::Leadership;
 pub use self::handle::NodeHandle;
 pub use self::node::Node;
 
+mod delete_job;
 mod handle;
+mod lease;
 mod metrics;
 mod node;
 mod snapshot;
@@ -48,6 +54,14 @@ enum Proposal {
         ObjectPrefix,
         Reply<DeleteObjectsByPrefixSummary>,
     ),
+    StartDeleteJob(ProposalId, Instant, ProposalMetrics, u64, Reply<u64>),
+    CancelDeleteJob(
+        ProposalId,
+        Instant,
+        ProposalMetrics,
+        u64,
+        Reply<Option<DeleteJobStatus>>,
+    ),
 }
 impl Proposal {
     pub fn id(&self) -> ProposalId {
@@ -55,6 +69,8 @@ impl Proposal {
             Proposal::Put(id, ..) => id,
             Proposal::Delete(id, ..) => id,
             Proposal::DeleteByPrefix(id, ..) => id,
+            Proposal::StartDeleteJob(id, ..) => id,
+            Proposal::CancelDeleteJob(id, ..) => id,
         }
     }
     fn started_at(&self) -> Instant {
@@ -62,6 +78,8 @@ impl Proposal {
             Proposal::Put(_, at, ..) => at,
             Proposal::Delete(_, at, ..) => at,
             Proposal::DeleteByPrefix(_, at, ..) => at,
+            Proposal::StartDeleteJob(_, at, ..) => at,
+            Proposal::CancelDeleteJob(_, at, ..) => at,
         }
     }
     fn metrics(&self) -> &ProposalMetrics {
@@ -69,9 +87,14 @@ impl Proposal {
             Proposal::Put(_, _, ref metrics, ..) => metrics,
             Proposal::Delete(_, _, ref metrics, ..) => metrics,
             Proposal::DeleteByPrefix(_, _, ref metrics, ..) => metrics,
+            Proposal::StartDeleteJob(_, _, ref metrics, ..) => metrics,
+            Proposal::CancelDeleteJob(_, _, ref metrics, ..) => metrics,
         }
     }
-    pub fn notify_committed(self, old: &[ObjectVersion]) {
+    /// 提案がコミットされたことを通知する.
+    ///
+    /// `machine`は、コミットされたコマンドを適用した後の状態機械.
+    pub fn notify_committed(self, old: &[ObjectVersion], machine: &Machine) {
         let elapsed = prometrics::timestamp::duration_to_seconds(self.started_at().elapsed());
         self.metrics()
             .committed_proposal_duration_seconds
@@ -97,6 +120,12 @@ impl Proposal {
                     total: old.len() as u64,
                 }));
             }
+            Proposal::StartDeleteJob(_, _, _, job_id, monitored) => {
+                monitored.exit(Ok(job_id));
+            }
+            Proposal::CancelDeleteJob(_, _, _, job_id, monitored) => {
+                monitored.exit(Ok(machine.delete_job_status(job_id)));
+            }
         }
     }
     pub fn notify_rejected(self) {
@@ -124,6 +153,12 @@ impl Proposal {
             Proposal::DeleteByPrefix(_, _, _, _, monitored) => {
                 monitored.exit(Err(track!(e)));
             }
+            Proposal::StartDeleteJob(_, _, _, _, monitored) => {
+                monitored.exit(Err(track!(e)));
+            }
+            Proposal::CancelDeleteJob(_, _, _, _, monitored) => {
+                monitored.exit(Err(track!(e)));
+            }
         }
     }
 }
@@ -156,14 +191,13 @@ impl ProposalMetrics {
             .default_registry()
             .finish())?;
         let committed_proposal_duration_seconds = track!(metrics::make_histogram(
-            &mut builder.histogram("committed_proposal_duration_seconds")
+            "committed_proposal_duration_seconds"
         ))?;
         let rejected_proposal_duration_seconds = track!(metrics::make_histogram(
-            &mut builder.histogram("rejected_proposal_duration_seconds")
-        ))?;
-        let failed_proposal_duration_seconds = track!(metrics::make_histogram(
-            &mut builder.histogram("failed_proposal_duration_seconds")
+            "rejected_proposal_duration_seconds"
         ))?;
+        let failed_proposal_duration_seconds =
+            track!(metrics::make_histogram("failed_proposal_duration_seconds"))?;
         Ok(Self {
             committed_proposal_total,
             rejected_proposal_total,
@@ -179,10 +213,16 @@ impl ProposalMetrics {
 #[derive(Debug)]
 pub(crate) enum Request {
     StartElection,
+    ResignLeadership,
+    Drain,
     GetLeader(Instant, Reply<NodeId>),
     List(Reply<Vec<ObjectSummary>>),
+    ListByTimeRange(Range<u64>, Reply<Vec<ObjectSummary>>),
+    Watch(Option<ObjectVersion>, usize, Reply<ObjectChanges>),
     LatestVersion(Reply<Option<ObjectSummary>>),
     ObjectCount(Reply<u64>),
+    QuotaUsage(Reply<QuotaUsage>),
+    CheckLag(u64, Reply<()>),
     Get(
         ObjectId,
         Expect,
@@ -190,6 +230,13 @@ pub(crate) enum Request {
         Instant,
         Reply<Option<Metadata>>,
     ),
+    GetRevision(
+        ObjectId,
+        RevisionSelector,
+        Expect,
+        ReadConsistency,
+        Reply<Option<Metadata>>,
+    ),
     Head(
         ObjectId,
         Expect,
@@ -199,16 +246,42 @@ pub(crate) enum Request {
     Put(
         ObjectId,
         Vec<u8>,
+        u64,
+        Precondition,
+        Seconds,
+        Instant,
+        Option<Instant>,
+        Reply<(ObjectVersion, Option<ObjectVersion>)>,
+    ),
+    Append(
+        ObjectId,
         Expect,
+        u64,
         Seconds,
         Instant,
         Reply<(ObjectVersion, Option<ObjectVersion>)>,
     ),
-    Delete(ObjectId, Expect, Instant, Reply<Option<ObjectVersion>>),
+    CommitAppend(
+        ObjectId,
+        ObjectVersion,
+        Instant,
+        Reply<Option<ObjectVersion>>,
+    ),
+    Delete(
+        ObjectId,
+        Precondition,
+        Instant,
+        Option<Instant>,
+        Reply<Option<ObjectVersion>>,
+    ),
+    Undelete(ObjectId, Instant, Reply<Option<ObjectVersion>>),
     DeleteByVersion(ObjectVersion, Reply<Option<ObjectVersion>>),
     #[allow(dead_code)]
     DeleteByRange(ObjectVersion, ObjectVersion, Reply<Vec<ObjectSummary>>),
     DeleteByPrefix(ObjectPrefix, Reply<DeleteObjectsByPrefixSummary>),
+    StartDeleteJob(ObjectPrefix, Reply<u64>),
+    GetDeleteJob(u64, Reply<Option<DeleteJobStatus>>),
+    CancelDeleteJob(u64, Reply<Option<DeleteJobStatus>>),
     /// 停止待機状態から停止状態へと状態遷移する.
     Exit,
     /// 停止処理を開始する.
@@ -220,17 +293,32 @@ impl Request {
         match self {
             Request::GetLeader(_, tx) => tx.exit(Err(track!(e))),
             Request::List(tx) => tx.exit(Err(track!(e))),
+            Request::ListByTimeRange(_, tx) => tx.exit(Err(track!(e))),
+            Request::Watch(_, _, tx) => tx.exit(Err(track!(e))),
             Request::LatestVersion(tx) => tx.exit(Err(track!(e))),
             Request::ObjectCount(tx) => tx.exit(Err(track!(e))),
+            Request::QuotaUsage(tx) => tx.exit(Err(track!(e))),
+            Request::CheckLag(_, tx) => tx.exit(Err(track!(e))),
             Request::Get(_, _, _, _, tx) => tx.exit(Err(track!(e))),
+            Request::GetRevision(_, _, _, _, tx) => tx.exit(Err(track!(e))),
             Request::Head(_, _, _, tx) => tx.exit(Err(track!(e))),
-            Request::Put(_, _, _, _, _, tx) => tx.exit(Err(track!(e))),
-            Request::Delete(_, _, _, tx) => tx.exit(Err(track!(e))),
+            Request::Put(_, _, _, _, _, _, _, tx) => tx.exit(Err(track!(e))),
+            Request::Append(_, _, _, _, _, tx) => tx.exit(Err(track!(e))),
+            Request::CommitAppend(_, _, _, tx) => tx.exit(Err(track!(e))),
+            Request::Delete(_, _, _, _, tx) => tx.exit(Err(track!(e))),
+            Request::Undelete(_, _, tx) => tx.exit(Err(track!(e))),
             Request::DeleteByVersion(_, tx) => tx.exit(Err(track!(e))),
             Request::DeleteByRange(_, _, tx) => tx.exit(Err(track!(e))),
             Request::DeleteByPrefix(_, tx) => tx.exit(Err(track!(e))),
+            Request::StartDeleteJob(_, tx) => tx.exit(Err(track!(e))),
+            Request::GetDeleteJob(_, tx) => tx.exit(Err(track!(e))),
+            Request::CancelDeleteJob(_, tx) => tx.exit(Err(track!(e))),
             Request::Stop(tx) => tx.exit(Err(track!(e))),
-            Request::Exit | Request::TakeSnapshot | Request::StartElection => {}
+            Request::Exit
+            | Request::TakeSnapshot
+            | Request::StartElection
+            | Request::ResignLeadership
+            | Request::Drain => {}
         }
     }
 }
@@ -240,13 +328,24 @@ impl Request {
 #[allow(missing_docs)]
 pub enum Event {
     /// メタデータオブジェクトが追加された.
+    ///
+    /// `timestamp` はコマンドを提案したリーダが発行したハイブリッド論理時計のタイムスタンプ.
+    /// スナップショットから復元されたオブジェクトの場合は不明(`HybridTimestamp::default()`)となる.
     Putted {
         version: ObjectVersion,
         put_content_timeout: Seconds,
+        timestamp: HybridTimestamp,
     },
 
     /// メタデータオブジェクトが削除された.
-    Deleted { version: ObjectVersion },
+    ///
+    /// 削除猶予期間が設定されている場合には、猶予期間が過ぎた時点で発行される.
+    /// `overwritten` は、同じIDのオブジェクトの上書きによって不要になったかどうか.
+    Deleted {
+        version: ObjectVersion,
+        timestamp: HybridTimestamp,
+        overwritten: bool,
+    },
 
     FullSync {
         machine: Machine,
@@ -289,7 +388,7 @@ mod tests {
                 ObjectPrefix("abc".to_owned()),
                 monitored,
             );
-            proposal.notify_committed(&[ObjectVersion(1)]);
+            proposal.notify_committed(&[ObjectVersion(1)], &Machine::new());
             Ok(())
         }));
 
@@ -5,34 +5,54 @@ use fibers::sync::oneshot::Monitored;
 use fibers::time::timer;
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
 use fibers_tasque::{self, AsyncCall, TaskQueueExt};
-use frugalos_raft::{NodeId, RaftIo};
-use futures::{Async, Future, Poll, Stream};
+use frugalos_core::cluster_feature::{self, ClusterFeature};
+use frugalos_core::hlc::HybridTimestamp;
+use frugalos_core::memory::MemoryTracker;
+use frugalos_core::rpc_auth;
+use frugalos_core::task_dump::TaskTracker;
+use frugalos_raft::{self, NodeId, RaftIo};
+use futures::future::Either;
+use futures::{self, Async, Future, Poll, Stream};
 use libfrugalos::consistency::ReadConsistency;
-use libfrugalos::entity::object::{Metadata, ObjectVersion};
-use prometrics::metrics::{
-    Counter, CounterBuilder, Gauge, GaugeBuilder, Histogram, HistogramBuilder, MetricBuilder,
-};
+use libfrugalos::entity::object::{Metadata, ObjectId, ObjectVersion};
+use libfrugalos::expect::Expect;
+use libfrugalos::schema::mds::RecommendToLeaderRpc;
+use prometrics::metrics::{Counter, CounterBuilder, Gauge, GaugeBuilder, Histogram, MetricBuilder};
 use raftlog::cluster::{ClusterConfig, ClusterMembers};
 use raftlog::election::Role;
 use raftlog::log::{LogEntry, LogIndex, LogPosition};
 use raftlog::{self, ReplicatedLog};
+use rand;
 use slog::Logger;
+use std::cmp;
 use std::collections::VecDeque;
 use std::env;
+use std::mem;
 use std::ops::Range;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use trackable::error::ErrorKindExt;
 
+use super::delete_job::DeleteJobs;
+use super::lease::ReadLease;
 use super::metrics::make_histogram;
-use super::snapshot::SnapshotThreshold;
-use super::{Event, NodeHandle, Proposal, ProposalMetrics, Reply, Request, Seconds};
+use super::snapshot::{LocalLogSize, SnapshotThreshold};
+use super::{
+    DeleteJobState, Event, Leadership, NodeHandle, Proposal, ProposalMetrics, Reply, Request,
+    Seconds,
+};
+use change::ChangeLog;
 use codec;
 use config::FrugalosMdsConfig;
-use machine::{Command, Machine};
+use machine::{Command, Machine, RelayoutCommand, RelayoutJob};
 use protobuf;
-use {Error, ErrorKind, Result, ServiceHandle};
+use {AccessMode, Error, ErrorKind, Quota, Result, RevisionSelector, ServiceHandle, SharedQuota};
 
 type RaftEvent = raftlog::Event;
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+
+/// ドレイン中に、リーダに選ばれたノードがリーダ権を辞退する回数の上限.
+const MAX_DRAIN_RESIGNATIONS: usize = 3;
 
 /// proposal キューが長すぎる(リーダーが重い)と判断する基準となる閾値。
 #[derive(Debug)]
@@ -71,11 +91,101 @@ impl LeaderWaitingTimeout {
 #[derive(Debug)]
 struct ReElectionThreshold(usize);
 
+/// ドレイン中にリーダ権を辞退した回数.
+///
+/// ドレイン中でない場合は `None` になる.
+#[derive(Debug, Default)]
+struct DrainResignations(Option<usize>);
+
+impl DrainResignations {
+    /// ドレインを開始する. 既に開始済みの場合には何もしない.
+    fn start(&mut self) {
+        if self.0.is_none() {
+            self.0 = Some(0);
+        }
+    }
+
+    /// リーダ権を辞退すべきかどうかを判定し、辞退する場合には回数を数える.
+    ///
+    /// ドレイン中でない場合や、自ノードが唯一の投票者である場合、回数が上限に達している場合には `false` を返す.
+    fn try_resign(&mut self, sole_voter: bool) -> bool {
+        if let Some(ref mut n) = self.0 {
+            if !sole_voter && *n < MAX_DRAIN_RESIGNATIONS {
+                *n += 1;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// ウィットネスとして設定されていないノードが、ウィットネスのスナップショットを読み込んだ場合の復帰状況.
+///
+/// ウィットネスのスナップショットにはオブジェクトのデータが含まれていないので、
+/// ローカルのウィットネスの状態を破棄して、リーダから完全なスナップショットを取得し直す.
+/// 復帰が完了する(取得したスナップショットに追い付く)までの間は、ウィットネスとして動作する
+/// (リーダにならず、フォロワーとしての読み込みにも応答しない).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WitnessDemotion {
+    /// 復帰中ではない.
+    None,
+
+    /// `retry_at`以降に、リーダから完全なスナップショットを取得する.
+    Fetching { retry_at: Instant },
+
+    /// 取得したスナップショットを適用済みで、`head`より前のコミット済みのコマンドは既に反映されている.
+    CatchingUp { head: LogIndex },
+}
+impl WitnessDemotion {
+    /// リーダから完全なスナップショットを取得中かどうか.
+    fn is_fetching(&self) -> bool {
+        matches!(self, WitnessDemotion::Fetching { .. })
+    }
+
+    /// スナップショットの取得を開始すべきかどうか.
+    fn should_fetch(&self, now: Instant) -> bool {
+        match *self {
+            WitnessDemotion::Fetching { retry_at } => retry_at <= now,
+            _ => false,
+        }
+    }
+
+    /// 取得したスナップショット(`head`より前のエントリを含む)を適用できるかどうか.
+    ///
+    /// 既に適用済みのエントリよりも古いスナップショットは、その間のエントリを再現できないので適用できない.
+    fn accepts(&self, head: LogIndex, next_commit: LogIndex) -> bool {
+        self.is_fetching() && next_commit <= head
+    }
+
+    /// コミットされたエントリの適用を省略すべきかどうかを判定する.
+    ///
+    /// 取得したスナップショットに追い付いた時点で、復帰が完了する.
+    fn skips(&mut self, commit: LogIndex) -> bool {
+        if let WitnessDemotion::CatchingUp { head } = *self {
+            if commit < head {
+                return true;
+            }
+            *self = WitnessDemotion::None;
+        }
+        false
+    }
+}
+
+/// リーダであることの確認を待っている`Consistent`な読み込み要求.
+#[derive(Debug)]
+enum PendingRead {
+    Get(ObjectId, Expect, Instant, Reply<Option<Metadata>>),
+    GetRevision(ObjectId, RevisionSelector, Expect, Reply<Option<Metadata>>),
+    Head(ObjectId, Expect, Reply<Option<ObjectVersion>>),
+}
+
 #[derive(Clone)]
 struct Metrics {
     objects: Gauge,
+    tombstones: Gauge,
     snapshots_total: Counter,
     snapshot_bytes_total: Counter,
+    overwritten_objects_total: Counter,
     snapshot_encoding_duration_seconds: Histogram,
     snapshot_decoding_duration_seconds: Histogram,
     proposal_queue_len: Gauge,
@@ -92,6 +202,13 @@ impl Metrics {
             .label("role", "Follower")
             .default_registry()
             .finish())?;
+        let tombstones = track!(GaugeBuilder::new("tombstones")
+            .namespace("frugalos")
+            .subsystem("mds")
+            .label("node", &node)
+            .help("Number of deleted objects retained for undeletion")
+            .default_registry()
+            .finish())?;
         let proposal_queue_len = track!(GaugeBuilder::new("proposal_queue_len")
             .namespace("frugalos")
             .subsystem("mds")
@@ -107,30 +224,26 @@ impl Metrics {
             .subsystem("mds")
             .default_registry()
             .finish())?;
-        let snapshot_encoding_duration_seconds = track!(make_histogram(
-            &mut HistogramBuilder::new("snapshot_encoding_duration_seconds")
-                .namespace("frugalos")
-                .subsystem("mds")
-        ))?;
-        let snapshot_decoding_duration_seconds = track!(make_histogram(
-            &mut HistogramBuilder::new("snapshot_decoding_duration_seconds")
-                .namespace("frugalos")
-                .subsystem("mds")
-        ))?;
-        let get_request_duration_seconds = track!(make_histogram(
-            &mut HistogramBuilder::new("get_request_duration_seconds")
-                .namespace("frugalos")
-                .subsystem("mds")
-        ))?;
-        let leader_waiting_duration_seconds = track!(make_histogram(
-            &mut HistogramBuilder::new("leader_waiting_duration_seconds")
-                .namespace("frugalos")
-                .subsystem("mds")
-        ))?;
+        let overwritten_objects_total = track!(CounterBuilder::new("overwritten_objects_total")
+            .namespace("frugalos")
+            .subsystem("mds")
+            .label("node", &node)
+            .help("Number of objects superseded by newer versions with the same ID")
+            .default_registry()
+            .finish())?;
+        let snapshot_encoding_duration_seconds =
+            track!(make_histogram("snapshot_encoding_duration_seconds"))?;
+        let snapshot_decoding_duration_seconds =
+            track!(make_histogram("snapshot_decoding_duration_seconds"))?;
+        let get_request_duration_seconds = track!(make_histogram("get_request_duration_seconds"))?;
+        let leader_waiting_duration_seconds =
+            track!(make_histogram("leader_waiting_duration_seconds"))?;
         Ok(Metrics {
             objects,
+            tombstones,
             snapshots_total,
             snapshot_bytes_total,
+            overwritten_objects_total,
             snapshot_encoding_duration_seconds,
             snapshot_decoding_duration_seconds,
             proposal_queue_len,
@@ -196,9 +309,11 @@ pub struct Node {
     leader_waiting_timeout: LeaderWaitingTimeout,
     request_rx: mpsc::Receiver<Request>,
     proposals: VecDeque<Proposal>,
-    local_log_size: usize,
+    local_log_size: LocalLogSize,
     /// 次に snapshot を取得する閾値.
     snapshot_threshold: SnapshotThreshold,
+    /// snapshot を取得する、前回の snapshot 以降にコミットされたコマンドの合計サイズの閾値.
+    snapshot_threshold_bytes: Option<u64>,
     next_commit: LogIndex,
     last_commit: Option<LogIndex>,
     events: VecDeque<Event>,
@@ -207,13 +322,23 @@ pub struct Node {
     proposal_metrics: ProposalMetrics,
     ready_snapshot: Option<AsyncCall<Result<(LogIndex, Vec<u8>)>>>,
     decoding_snapshot: Option<AsyncCall<Result<(LogPosition, Machine, Vec<ObjectVersion>)>>>,
+    fetching_full_snapshot: Option<BoxFuture<(LogPosition, Machine, Vec<ObjectVersion>)>>,
+    fetch_snapshot_timeout: Duration,
+    demotion: WitnessDemotion,
     polling_timer: timer::Timeout,
     polling_timer_interval: Duration,
     phase: Phase,
     // 停止中の状態を管理するための変数.
     // `Request::Stop` を受け取り、かつ、スナップショットの取得を開始した時にだけ `Some` になる.
     stopping: Option<Stopping>,
+    drain_resignations: DrainResignations,
     rpc_service: RpcServiceHandle,
+    // `Service`と共有するリーダ状態.
+    leadership: Arc<Leadership>,
+    // ウィットネスとして動作するかどうか(バケツの属性`mds_witnesses`を参照).
+    //
+    // ウィットネスは選挙を始めないのでリーダにはならず、マシンはメタデータのデータを保持しない.
+    witness: bool,
 
     // 整合性保証のレベルを変更するための変数群
     // リーダーが決定した場合に `rounds` はリセットされる。
@@ -226,9 +351,46 @@ pub struct Node {
     reelection_threshold: ReElectionThreshold,
     commit_timeout: Option<usize>,
     commit_timeout_threshold: usize,
+
+    // 削除猶予期間. `None` の場合は削除時に即座にオブジェクトを破棄する.
+    tombstone_retention: Option<Seconds>,
+    // このノード(= セグメント)に適用される割り当て量.
+    quota: SharedQuota,
+    // このノードが属するバケツへのアクセスの可否. 読み込み専用の間はオブジェクトの変更を拒否する.
+    access: AccessMode,
+    // 上書きされたバージョンの保持期間. `None` の場合はバージョン管理を行わない.
+    version_retention: Option<Seconds>,
+    // 接頭辞指定での削除ジョブ群と、一度に削除するオブジェクトの最大数.
+    delete_jobs: DeleteJobs,
+    delete_job_batch_size: usize,
+    // 変更の監視に応答するための、直近のオブジェクトの変更履歴.
+    change_log: ChangeLog,
+    // 猶予期間が過ぎたオブジェクトの破棄を最後に提案したログインデックス.
+    purge_proposed_at: Option<LogIndex>,
+    // 猶予期間が過ぎたオブジェクトの破棄の提案間隔と、次に提案を検討する時刻.
+    tombstone_gc_interval: Duration,
+    next_tombstone_gc: Instant,
+
+    // `Consistent`な読み込みに応答するためのリースと、その確認を待っている要求群.
+    // 確認に`read_confirm_timeout`以上かかった要求は失敗させる.
+    read_lease: ReadLease<PendingRead>,
+    read_confirm_timeout: Duration,
+
+    // タスクダンプ用に状態を報告するためのオブジェクト.
+    task: TaskTracker,
+    // マシンのメモリ使用量を報告するためのオブジェクト.
+    memory: MemoryTracker,
+    // Raft のログ(スナップショットを含む)のロードが完了したかどうか.
+    log_loaded: bool,
 }
 impl Node {
     /// 新しい`Node`インスタンスを生成する.
+    ///
+    /// `quota`はこのノードが属するセグメントの割り当て量で、実行中に変更され得る.
+    /// `access`はこのノードが属するバケツへのアクセスの可否で、読み込み専用の間はオブジェクトの変更を拒否する.
+    /// `version_retention`が指定された場合には、上書きされたバージョンをその期間だけ保持する.
+    /// `witness`が`true`の場合には、ウィットネスとして動作する(バケツの属性`mds_witnesses`を参照).
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         logger: Logger,
         config: &FrugalosMdsConfig,
@@ -237,11 +399,23 @@ impl Node {
         cluster: ClusterMembers,
         io: RaftIo,
         rpc_service: RpcServiceHandle,
+        quota: SharedQuota,
+        access: AccessMode,
+        version_retention: Option<Seconds>,
+        witness: bool,
     ) -> Result<Self> {
         let (request_tx, request_rx) = mpsc::channel();
         let node_handle = NodeHandle::new(request_tx.clone());
+        let leadership = node_handle.leadership().clone();
         track!(service.add_node(node_id, node_handle))?;
 
+        // ウィットネスは、フォロワーや候補者のタイムアウトで選挙を始めない
+        io.timer().set_witness(witness);
+        let mut machine = Machine::new();
+        if witness {
+            machine.set_witness();
+        }
+
         let metric_builder = MetricBuilder::new();
         let rlog = track!(ReplicatedLog::new(
             node_id.to_raft_node_id(),
@@ -251,7 +425,16 @@ impl Node {
         ))?;
 
         // For backward compatibility
-        let snapshot_threshold = config.snapshot_threshold();
+        let snapshot_threshold = if witness {
+            // ウィットネスはログを短く保つ
+            let threshold = config.witness_snapshot_threshold;
+            Range {
+                start: threshold,
+                end: threshold,
+            }
+        } else {
+            config.snapshot_threshold()
+        };
         let snapshot_threshold_range = env::var("FRUGALOS_SNAPSHOT_THRESHOLD")
             .ok()
             .and_then(|v| v.parse().map(|v| Range { start: v, end: v }).ok())
@@ -274,8 +457,10 @@ impl Node {
             LeaderWaitingTimeout::new(config.leader_waiting_timeout_threshold);
         info!(
             logger,
-            "Thresholds: snapshot={}, reelection={}, queue={}, commit_timeout={}, leader_waiting={}, staled_object={}",
+            "Thresholds: witness={}, snapshot={}, snapshot_bytes={:?}, reelection={}, queue={}, commit_timeout={}, leader_waiting={}, staled_object={}",
+            witness,
             snapshot_threshold,
+            config.snapshot_threshold_bytes,
             reelection_threshold.0,
             large_queue_threshold.0,
             config.commit_timeout_threshold,
@@ -285,6 +470,7 @@ impl Node {
 
         let metrics = track!(Metrics::new(&node_id))?;
         let proposal_metrics = track!(ProposalMetrics::new())?;
+        let task = TaskTracker::new("mds_node", &node_id.local_id.to_string());
         Ok(Node {
             logger,
             service,
@@ -296,28 +482,56 @@ impl Node {
             leader_waiting_timeout,
             request_rx,
             proposals: VecDeque::new(),
-            local_log_size: 0,
+            local_log_size: LocalLogSize::default(),
             snapshot_threshold,
+            snapshot_threshold_bytes: config.snapshot_threshold_bytes,
             next_commit: LogIndex::new(0),
             last_commit: None,
             events: VecDeque::new(),
-            machine: Machine::new(),
+            machine,
             metrics,
             proposal_metrics,
             ready_snapshot: None,
             decoding_snapshot: None,
+            fetching_full_snapshot: None,
+            fetch_snapshot_timeout: config.fetch_snapshot_timeout,
+            demotion: WitnessDemotion::None,
             polling_timer: timer::timeout(config.node_polling_interval),
             polling_timer_interval: config.node_polling_interval,
             phase: Phase::Running,
             stopping: None,
+            drain_resignations: DrainResignations::default(),
             large_queue_rounds: 0,
             large_queue_threshold,
             reelection_threshold,
             commit_timeout: None,
             commit_timeout_threshold: config.commit_timeout_threshold,
             rpc_service,
+            leadership,
+            witness,
             staled_object_rounds: 0,
             staled_object_threshold: config.staled_object_threshold,
+            tombstone_retention: config.tombstone_retention,
+            quota,
+            access,
+            version_retention,
+            delete_jobs: DeleteJobs::default(),
+            delete_job_batch_size: config.delete_job_batch_size,
+            // ウィットネスは変更の監視に応答しないので、履歴を保持しない
+            change_log: ChangeLog::new(if witness {
+                0
+            } else {
+                config.change_log_capacity
+            }),
+            purge_proposed_at: None,
+            tombstone_gc_interval: config.tombstone_gc_interval,
+            next_tombstone_gc: Instant::now() + config.tombstone_gc_interval,
+            read_lease: ReadLease::new(config.consistent_read_lease),
+            read_confirm_timeout: config.node_polling_interval
+                * config.leader_waiting_timeout_threshold as u32,
+            task,
+            memory: MemoryTracker::new("mds_machines"),
+            log_loaded: false,
         })
     }
 
@@ -326,19 +540,118 @@ impl Node {
         self.next_commit
     }
 
+    /// このノードが認識している現在のリーダを返す.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    /// Raft のログ(スナップショットを含む)のロードが完了したかどうかを返す.
+    ///
+    /// `raftlog`はロードの完了を直接は通知しないので、ロード後にしか発生しないイベント
+    /// (役割や任期の変更、ログのコミット)を最初に受け取った時点で完了したものとみなす.
+    pub fn is_log_loaded(&self) -> bool {
+        self.log_loaded
+    }
+
+    /// このノードがリーダかどうかを返す.
+    pub fn is_leader(&self) -> bool {
+        self.rlog.local_node().role == Role::Leader
+    }
+
+    /// コミット済みのログが全て適用された状態のマシンと、その時点の next_commit を返す.
+    ///
+    /// スナップショットのロード中や、コミット済みのログの適用が追いついていない場合には、
+    /// マシンが最新の状態を反映していないので`None`を返す.
+    /// (不完全なマシンを元に FullSync を行うと、必要なオブジェクトまで削除されてしまう)
+    pub fn synced_machine(&self) -> Option<(&Machine, LogIndex)> {
+        if self.phase != Phase::Running
+            || self.leader.is_none()
+            || self.decoding_snapshot.is_some()
+            || self.rlog.is_snapshot_installing()
+        {
+            return None;
+        }
+        let committed_tail = self.rlog.local_history().committed_tail().index;
+        if self.next_commit < committed_tail {
+            return None;
+        }
+        Some((&self.machine, self.next_commit))
+    }
+
+    /// 実行中ないし直近に終了した、セグメントの再配置ジョブを返す.
+    pub fn relayout_job(&self) -> Option<&RelayoutJob> {
+        self.machine.relayout()
+    }
+
+    /// 再配置ジョブを操作するコマンドを提案する.
+    ///
+    /// リーダのみが提案可能で、コマンドがコミットされたかどうかは`relayout_job`の結果から判断する.
+    /// 古いバージョンのノードはコマンドをデコードできないので、`ClusterFeature::Relayout`が有効な場合にのみ提案できる.
+    pub fn propose_relayout(&mut self, command: RelayoutCommand) -> Result<LogIndex> {
+        track_assert!(
+            cluster_feature::is_enabled(ClusterFeature::Relayout),
+            ErrorKind::InvalidInput,
+            "The cluster feature `{}` is not enabled",
+            ClusterFeature::Relayout
+        );
+        track!(self.check_leader())?;
+        info!(self.logger, "Proposes a relayout command: {:?}", command);
+        let command = track!(self.encode_command(Command::Relayout(command)))?;
+        let proposal_id = track!(self.rlog.propose_command(command))?;
+        Ok(proposal_id.index)
+    }
+
+    /// Raftのメンバ構成を`members`に変更することを提案する.
+    ///
+    /// リーダのみが提案可能で、変更の完了は`has_members`で確認する.
+    pub fn propose_members(&mut self, members: &[NodeId]) -> Result<()> {
+        track!(self.check_leader())?;
+        let members = members
+            .iter()
+            .map(NodeId::to_raft_node_id)
+            .collect::<ClusterMembers>();
+        if members == *self.rlog.cluster_config().new_members() {
+            return Ok(());
+        }
+        info!(
+            self.logger,
+            "Proposes a new raft cluster configuration: {:?}", members
+        );
+        track!(self.rlog.propose_config(members))?;
+        Ok(())
+    }
+
+    /// Raftのメンバ構成が`members`のみから成る状態に移行済みかどうかを返す.
+    pub fn has_members(&self, members: &[NodeId]) -> bool {
+        let members = members
+            .iter()
+            .map(NodeId::to_raft_node_id)
+            .collect::<ClusterMembers>();
+        let config = self.rlog.cluster_config();
+        *config.new_members() == members && config.members().all(|m| members.contains(m))
+    }
+
+    /// 再配置ジョブがコピーする必要のある、`cursor`よりも新しいバージョン群を昇順に返す.
+    pub fn relayout_versions_after(&self, cursor: ObjectVersion) -> Vec<ObjectVersion> {
+        self.machine.content_versions_after(cursor)
+    }
+
     #[allow(clippy::cognitive_complexity)]
     fn handle_request(&mut self, request: Request) {
         // NOTE: 整合性を保証したいので、更新系の要求を処理できるのはリーダのみとする.
         //       Get と Head はクライアントが指定した整合性に従う.
-        // TODO: リースないしハートビートを使って、leaderであることを保証する (READ時)
+        //       `Consistent` の場合には、リースないしハートビートを使ってリーダであることを確認する.
         match request {
             Request::GetLeader(_, _)
+            | Request::CheckLag(_, _)
             | Request::Get(_, _, _, _, _)
+            | Request::GetRevision(_, _, _, _, _)
             | Request::Head(_, _, _, _)
             | Request::Exit
             | Request::Stop(_)
             | Request::TakeSnapshot
-            | Request::StartElection => {}
+            | Request::StartElection
+            | Request::Drain => {}
             _ => {
                 if let Err(e) = self.check_leader() {
                     request.failed(e);
@@ -346,12 +659,59 @@ impl Node {
                 }
             }
         }
+        match request {
+            Request::Put(..)
+            | Request::Append(..)
+            | Request::CommitAppend(..)
+            | Request::Delete(..)
+            | Request::Undelete(..)
+            | Request::DeleteByVersion(..)
+            | Request::DeleteByRange(..)
+            | Request::DeleteByPrefix(..)
+            | Request::StartDeleteJob(..)
+                if self.machine.is_frozen() =>
+            {
+                // NOTE: 提案済みのコマンドは適用時にも拒否されるので、ここでの確認は早期の失敗のため
+                let e = ErrorKind::Frozen.cause("The segment is being relayouted");
+                request.failed(track!(Error::from(e)));
+                return;
+            }
+            Request::Put(..)
+            | Request::Append(..)
+            | Request::Delete(..)
+            | Request::Undelete(..)
+            | Request::DeleteByVersion(..)
+            | Request::DeleteByPrefix(..)
+            | Request::StartDeleteJob(..)
+                if self.access.is_read_only() =>
+            {
+                // NOTE: 読み込み専用になる前に予約された追記部分の確定(`CommitAppend`)や、
+                //       実行中の削除ジョブの継続は、既に受け付けた要求の一部なので拒否しない.
+                //       読み込み専用かどうかはノード毎の状態なので、適用時ではなく提案前に確認する.
+                let e = ErrorKind::ReadOnly.cause("The bucket is read-only");
+                request.failed(track!(Error::from(e)));
+                return;
+            }
+            _ => {}
+        }
 
         match request {
             Request::StartElection => {
                 info!(self.logger, "Re-election is required");
                 self.rlog.start_election();
             }
+            Request::ResignLeadership => {
+                // NOTE: リーダでない場合は `check_leader` で弾かれている.
+                info!(self.logger, "Resigns the leadership");
+                self.start_reelection();
+            }
+            Request::Drain => {
+                info!(self.logger, "Starts draining");
+                self.drain_resignations.start();
+                if self.rlog.local_node().role == Role::Leader {
+                    self.resign_for_drain();
+                }
+            }
             Request::GetLeader(started_at, monitored) => {
                 // TODO: debugレベルにする
                 info!(self.logger, "GetLeader: {:?}", self.leader);
@@ -379,30 +739,84 @@ impl Node {
                 let list = self.machine.to_summaries();
                 monitored.exit(Ok(list));
             }
+            Request::ListByTimeRange(range, monitored) => {
+                let list = self.machine.list_by_time_range(range);
+                monitored.exit(Ok(list));
+            }
+            Request::Watch(after, max, monitored) => {
+                let changes = self.change_log.changes_after(after, max);
+                monitored.exit(Ok(changes));
+            }
             Request::LatestVersion(monitored) => {
                 let latest = self.machine.latest_version();
                 monitored.exit(Ok(latest));
             }
             Request::ObjectCount(monitored) => monitored.exit(Ok(self.machine.len() as u64)),
+            Request::QuotaUsage(monitored) => {
+                monitored.exit(Ok(self.machine.usage(self.quota.get())))
+            }
+            Request::CheckLag(max_lag, monitored) => monitored.exit(self.check_lag(max_lag)),
             Request::Get(object_id, expect, consistency, started_at, monitored) => {
-                let result = self.check_leader_if_needed(&consistency);
-                let elapsed = prometrics::timestamp::duration_to_seconds(started_at.elapsed());
-                self.metrics.get_request_duration_seconds.observe(elapsed);
-                monitored.exit(result.and_then(|()| self.machine.get(&object_id, &expect)));
+                let read = PendingRead::Get(object_id, expect, started_at, monitored);
+                self.handle_read(read, &consistency);
+            }
+            Request::GetRevision(object_id, selector, expect, consistency, monitored) => {
+                let read = PendingRead::GetRevision(object_id, selector, expect, monitored);
+                self.handle_read(read, &consistency);
             }
             Request::Head(object_id, expect, consistency, monitored) => {
-                let result = self.check_leader_if_needed(&consistency);
-                monitored.exit(result.and_then(|()| self.machine.head(&object_id, &expect)));
+                let read = PendingRead::Head(object_id, expect, monitored);
+                self.handle_read(read, &consistency);
             }
-            Request::Put(object_id, data, expect, put_content_timeout, started_at, monitored) => {
+            Request::Put(
+                object_id,
+                data,
+                size,
+                expect,
+                put_content_timeout,
+                started_at,
+                deadline,
+                monitored,
+            ) => {
+                if let Err(e) = track!(check_deadline(deadline)) {
+                    monitored.exit(Err(e));
+                    return;
+                }
+                // NOTE: 大きさを伴う書き込みは、機能が有効になるまで行われないので、割り当て量も適用しない
+                let quota = if cluster_feature::is_enabled(ClusterFeature::Quota) {
+                    self.quota.get()
+                } else {
+                    Quota::default()
+                };
+                if let Err(e) = track!(self.machine.check_quota(quota, &object_id, size)) {
+                    monitored.exit(Err(e));
+                    return;
+                }
+                let expect = match track!(self.machine.resolve_precondition(&object_id, expect)) {
+                    Err(e) => {
+                        monitored.exit(Err(e));
+                        return;
+                    }
+                    Ok(expect) => expect,
+                };
                 let command = Command::Put {
                     object_id,
                     userdata: data,
+                    size,
                     expect,
                     put_content_timeout,
+                    // 古いバージョンのサーバは`history_retention`を無視してしまうので、機能が有効な場合にのみ指定する
+                    history_retention: if cluster_feature::is_enabled(
+                        ClusterFeature::HistoryRetention,
+                    ) {
+                        self.version_retention
+                    } else {
+                        None
+                    },
+                    // 同時に提案された書き込みによる超過を防ぐため、適用時にも全ノードで確認する
+                    quota,
                 };
-                let result = track!(protobuf::command_encoder().encode_into_bytes(command))
-                    .map_err(Error::from)
+                let result = track!(self.encode_command(command))
                     .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
                 match result {
                     Err(e) => monitored.exit(Err(e)),
@@ -417,14 +831,116 @@ impl Node {
                     }
                 }
             }
-            Request::Delete(object_id, expect, started_at, monitored) => {
-                let command = Command::Delete { object_id, expect };
-                let result = track!(protobuf::command_encoder().encode_into_bytes(command))
-                    .map_err(Error::from)
+            Request::Append(
+                object_id,
+                expect,
+                size,
+                put_content_timeout,
+                started_at,
+                monitored,
+            ) => {
+                if let Err(e) = track!(check_append_enabled()) {
+                    monitored.exit(Err(e));
+                    return;
+                }
+                let command = Command::Append {
+                    object_id,
+                    expect: expect.into(),
+                    size,
+                    put_content_timeout,
+                };
+                let result = track!(self.encode_command(command))
                     .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
                 match result {
                     Err(e) => monitored.exit(Err(e)),
                     Ok(proposal_id) => {
+                        // NOTE: 結果の型が同じなので、書き込みと同じ種類の提案として扱う
+                        let proposal = Proposal::Put(
+                            proposal_id,
+                            started_at,
+                            self.proposal_metrics.clone(),
+                            monitored,
+                        );
+                        self.push_proposal(proposal);
+                    }
+                }
+            }
+            Request::CommitAppend(object_id, version, started_at, monitored) => {
+                if let Err(e) = track!(check_append_enabled()) {
+                    monitored.exit(Err(e));
+                    return;
+                }
+                let command = Command::CommitAppend { object_id, version };
+                let result = track!(self.encode_command(command))
+                    .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
+                match result {
+                    Err(e) => monitored.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        // NOTE: 結果の型が同じなので、削除と同じ種類の提案として扱う
+                        let proposal = Proposal::Delete(
+                            proposal_id,
+                            started_at,
+                            self.proposal_metrics.clone(),
+                            monitored,
+                        );
+                        self.push_proposal(proposal);
+                    }
+                }
+            }
+            Request::Delete(object_id, expect, started_at, deadline, monitored) => {
+                if let Err(e) = track!(check_deadline(deadline)) {
+                    monitored.exit(Err(e));
+                    return;
+                }
+                let expect = match track!(self.machine.resolve_precondition(&object_id, expect)) {
+                    Err(e) => {
+                        monitored.exit(Err(e));
+                        return;
+                    }
+                    Ok(expect) => expect,
+                };
+                // 古いバージョンのノードは`Tombstone`コマンドをデコードできないので、機能が有効な場合にのみ使用する
+                let retention = if cluster_feature::is_enabled(ClusterFeature::Tombstones) {
+                    self.tombstone_retention
+                } else {
+                    None
+                };
+                let command = if let Some(retention) = retention {
+                    Command::Tombstone {
+                        object_id,
+                        expect,
+                        retention,
+                    }
+                } else {
+                    Command::Delete { object_id, expect }
+                };
+                let result = track!(self.encode_command(command))
+                    .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
+                match result {
+                    Err(e) => monitored.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::Delete(
+                            proposal_id,
+                            started_at,
+                            self.proposal_metrics.clone(),
+                            monitored,
+                        );
+                        self.push_proposal(proposal);
+                    }
+                }
+            }
+            Request::Undelete(object_id, started_at, monitored) => {
+                if let Err(e) = track!(self.check_tombstones_enabled()) {
+                    monitored.exit(Err(e));
+                    return;
+                }
+                let command = Command::Undelete { object_id };
+                let result = track!(self.encode_command(command))
+                    .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
+                match result {
+                    Err(e) => monitored.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        // NOTE: 結果の型が同じなので、削除と同じ種類の提案として扱う
                         let proposal = Proposal::Delete(
                             proposal_id,
                             started_at,
@@ -437,8 +953,7 @@ impl Node {
             }
             Request::DeleteByVersion(object_version, monitored) => {
                 let command = Command::DeleteByVersion { object_version };
-                let result = track!(protobuf::command_encoder().encode_into_bytes(command))
-                    .map_err(Error::from)
+                let result = track!(self.encode_command(command))
                     .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
                 match result {
                     Err(e) => monitored.exit(Err(e)),
@@ -463,8 +978,7 @@ impl Node {
                     version_from,
                     version_to,
                 };
-                let result = track!(protobuf::command_encoder().encode_into_bytes(command))
-                    .map_err(Error::from)
+                let result = track!(self.encode_command(command))
                     .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
 
                 match result {
@@ -480,8 +994,7 @@ impl Node {
                 let command = Command::DeleteByPrefix {
                     prefix: prefix.clone(),
                 };
-                let result = track!(protobuf::command_encoder().encode_into_bytes(command))
-                    .map_err(Error::from)
+                let result = track!(self.encode_command(command))
                     .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
 
                 match result {
@@ -498,6 +1011,78 @@ impl Node {
                     }
                 }
             }
+            Request::StartDeleteJob(prefix, monitored) => {
+                if !cluster_feature::is_enabled(ClusterFeature::DeleteObjects) {
+                    let e = ErrorKind::InvalidInput.cause(format!(
+                        "The cluster feature `{}` is not enabled",
+                        ClusterFeature::DeleteObjects
+                    ));
+                    monitored.exit(Err(track!(Error::from(e))));
+                    return;
+                }
+                let job_id = rand::random();
+                let command = Command::StartDeleteJob {
+                    job_id,
+                    prefix: prefix.clone(),
+                    // 削除猶予期間中のオブジェクトはスナップショットに含まれるので、機能が有効な場合にのみ指定する
+                    retention: if cluster_feature::is_enabled(ClusterFeature::Tombstones) {
+                        self.tombstone_retention
+                    } else {
+                        None
+                    },
+                };
+                let result = track!(self.encode_command(command))
+                    .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
+                match result {
+                    Err(e) => monitored.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        info!(
+                            self.logger,
+                            "Proposes a delete job: job_id={}, prefix={:?}", job_id, prefix
+                        );
+                        let proposal = Proposal::StartDeleteJob(
+                            proposal_id,
+                            Instant::now(),
+                            self.proposal_metrics.clone(),
+                            job_id,
+                            monitored,
+                        );
+                        self.push_proposal(proposal);
+                    }
+                }
+            }
+            Request::GetDeleteJob(job_id, monitored) => {
+                monitored.exit(Ok(self.machine.delete_job_status(job_id)));
+            }
+            Request::CancelDeleteJob(job_id, monitored) => {
+                let running = self
+                    .machine
+                    .delete_job_status(job_id)
+                    .map(|s| s.state == DeleteJobState::Running);
+                if running != Some(true) {
+                    monitored.exit(Ok(self.machine.delete_job_status(job_id)));
+                    return;
+                }
+                let command = Command::FinishDeleteJob {
+                    job_id,
+                    cancelled: true,
+                };
+                let result = track!(self.encode_command(command))
+                    .and_then(|c| track!(self.rlog.propose_command(c)).map_err(Error::from));
+                match result {
+                    Err(e) => monitored.exit(Err(e)),
+                    Ok(proposal_id) => {
+                        let proposal = Proposal::CancelDeleteJob(
+                            proposal_id,
+                            Instant::now(),
+                            self.proposal_metrics.clone(),
+                            job_id,
+                            monitored,
+                        );
+                        self.push_proposal(proposal);
+                    }
+                }
+            }
             Request::Stop(monitored) => {
                 if self.phase == Phase::Running {
                     info!(self.logger, "Starts stopping the node");
@@ -537,6 +1122,10 @@ impl Node {
         }
     }
     fn take_snapshot(&mut self) -> Result<bool> {
+        if let WitnessDemotion::CatchingUp { .. } = self.demotion {
+            // マシンはローカルでコミット済みのエントリよりも先の状態を含んでいるので、追い付くまではスナップショットを取らない
+            return Ok(false);
+        }
         let commit = if let Some(commit) = self.last_commit {
             if commit.as_u64() == 0 {
                 // FIXME: `raftlog`のバグで、この状態でsnapshotを取得すると再起動時に
@@ -548,12 +1137,15 @@ impl Node {
             return Ok(false);
         };
         if !self.rlog.is_snapshot_installing() && self.ready_snapshot.is_none() {
-            self.local_log_size = 0;
+            let log_size = self.local_log_size;
+            self.local_log_size.reset();
             self.snapshot_threshold.refresh();
             info!(
                 self.logger,
-                "Starts taking snapshot: objects={}, next_threshold={}",
+                "Starts taking snapshot: objects={}, log_entries={}, log_bytes={}, next_threshold={}",
                 self.machine.len(),
+                log_size.entries,
+                log_size.bytes,
                 self.snapshot_threshold.value()
             );
 
@@ -597,6 +1189,32 @@ impl Node {
             self.commit_timeout = Some(self.commit_timeout_threshold);
         }
     }
+    fn report_task_state(&self) {
+        let state = if self.decoding_snapshot.is_some() {
+            "DecodingSnapshot"
+        } else if self.ready_snapshot.is_some() {
+            "EncodingSnapshot"
+        } else if self.rlog.is_snapshot_installing() {
+            "InstallingSnapshot"
+        } else if self.phase != Phase::Running {
+            "Stopping"
+        } else if self.rlog.local_node().role == Role::Leader {
+            "Leader"
+        } else if self.leader.is_some() {
+            "Follower"
+        } else {
+            "NoLeader"
+        };
+        self.task.set_state(state);
+        self.task
+            .set_queue_len("raft_proposals", self.rlog.proposal_queue_len());
+        self.task.set_queue_len("proposals", self.proposals.len());
+        self.task
+            .set_queue_len("leader_waitings", self.leader_waitings.len());
+        self.task
+            .set_queue_len("pending_reads", self.read_lease.len());
+        self.task.set_queue_len("events", self.events.len());
+    }
     fn check_leader(&self) -> Result<()> {
         track_assert_eq!(
             self.rlog.local_node().role,
@@ -605,10 +1223,109 @@ impl Node {
         );
         Ok(())
     }
+    /// 削除の取り消しを提案可能かどうかを確認する.
+    ///
+    /// 古いバージョンのノードは`Undelete`コマンドをデコードできないので、`ClusterFeature::Tombstones`が有効で、
+    /// かつ削除猶予期間が設定されている(削除されたオブジェクトが保持されている)場合にのみ提案できる.
+    fn check_tombstones_enabled(&self) -> Result<()> {
+        track_assert!(
+            cluster_feature::is_enabled(ClusterFeature::Tombstones),
+            ErrorKind::InvalidInput,
+            "The cluster feature `{}` is not enabled",
+            ClusterFeature::Tombstones
+        );
+        track_assert!(
+            self.tombstone_retention.is_some(),
+            ErrorKind::InvalidInput,
+            "Deleted objects are not retained (`tombstone_retention` is not set)"
+        );
+        Ok(())
+    }
+    /// 読み込みの直前に、このノードがリーダとして応答して良いかを確認する.
+    ///
+    /// 自分の任期中に(`Noop`の)コミットが済んでいない場合には、
+    /// 前任のリーダがコミットしたエントリを全て適用済みとは限らないのでエラーとする.
+    fn check_leader_for_read(&self) -> Result<()> {
+        track!(self.check_leader())?;
+        track_assert_eq!(self.leader, Some(self.node_id), ErrorKind::NotLeader);
+        Ok(())
+    }
+    /// 投票権を持つメンバが、このノードのみかどうか.
+    ///
+    /// その場合には、他のノードがリーダになることはないので、ハートビートによる確認は不要となる.
+    fn is_sole_voter(&self) -> bool {
+        let local = &self.rlog.local_node().id;
+        self.rlog.cluster_config().members().all(|m| m == local)
+    }
+    fn handle_read(&mut self, read: PendingRead, consistency: &ReadConsistency) {
+        if *consistency != ReadConsistency::Consistent {
+            let result = self.check_leader_if_needed(consistency);
+            self.reply_read(read, result);
+            return;
+        }
+        if let Err(e) = track!(self.check_leader_for_read()) {
+            self.reply_read(read, Err(e));
+            return;
+        }
+        let now = Instant::now();
+        if self.is_sole_voter() || self.read_lease.is_valid(now) {
+            self.reply_read(read, Ok(()));
+        } else {
+            self.read_lease.defer(read, now);
+        }
+    }
+    fn reply_read(&self, read: PendingRead, result: Result<()>) {
+        match read {
+            PendingRead::Get(object_id, expect, started_at, monitored) => {
+                let elapsed = prometrics::timestamp::duration_to_seconds(started_at.elapsed());
+                self.metrics.get_request_duration_seconds.observe(elapsed);
+                monitored.exit(result.and_then(|()| self.machine.get(&object_id, &expect)));
+            }
+            PendingRead::GetRevision(object_id, selector, expect, monitored) => {
+                monitored.exit(
+                    result.and_then(|()| self.machine.get_revision(&object_id, selector, &expect)),
+                );
+            }
+            PendingRead::Head(object_id, expect, monitored) => {
+                monitored.exit(result.and_then(|()| self.machine.head(&object_id, &expect)));
+            }
+        }
+    }
+    fn send_read_heartbeat_if_needed(&mut self) {
+        if !self.read_lease.needs_heartbeat() {
+            return;
+        }
+        match track!(self.rlog.heartbeat()) {
+            Ok(seq_no) => self.read_lease.heartbeat_sent(seq_no, Instant::now()),
+            Err(e) => {
+                let e = Error::from(e);
+                for read in self.read_lease.revoke() {
+                    self.reply_read(read, Err(e.clone()));
+                }
+            }
+        }
+    }
+    fn confirm_pending_reads(&mut self) {
+        if self.read_lease.len() == 0 {
+            return;
+        }
+        let ack = self.rlog.last_heartbeat_ack();
+        for read in self.read_lease.heartbeat_acked(ack) {
+            let result = track!(self.check_leader_for_read());
+            self.reply_read(read, result);
+        }
+    }
+    fn revoke_read_lease(&mut self, reason: &str) {
+        for read in self.read_lease.revoke() {
+            let e = ErrorKind::NotLeader.cause(reason.to_owned());
+            self.reply_read(read, Err(track!(e).into()));
+        }
+    }
     fn check_leader_if_needed(&self, consistency: &ReadConsistency) -> Result<()> {
         match consistency {
             ReadConsistency::Stale | ReadConsistency::Quorum | ReadConsistency::Subset(_) => {
-                if self.is_staled_object_visible() {
+                // ウィットネスはフォロワーとしての読み込みには応答しない
+                if !self.acts_as_witness() && self.is_staled_object_visible() {
                     Ok(())
                 } else {
                     self.check_leader()
@@ -617,14 +1334,68 @@ impl Node {
             ReadConsistency::Consistent => self.check_leader(),
         }
     }
+    /// このノードが適用済みのログが、リーダのコミット済みログから`max_lag`エントリ以内かどうかを確認する.
+    ///
+    /// フォロワーはリーダから最後に通知されたコミット位置を基準とするので、
+    /// リーダと通信できていない間の遅れは検出できない.
+    /// そのため、リーダが不在になってからの時間についての確認(`is_staled_object_visible`)も合わせて行う.
+    fn check_lag(&self, max_lag: u64) -> Result<()> {
+        track_assert!(
+            !self.acts_as_witness(),
+            ErrorKind::NotLeader,
+            "This node is a witness"
+        );
+        track_assert!(
+            self.is_staled_object_visible(),
+            ErrorKind::NotLeader,
+            "The leader is unknown"
+        );
+        let history = self.rlog.local_history();
+        let committed = cmp::max(
+            self.rlog.io().leader_committed_tail(),
+            history.committed_tail().index,
+        );
+        let lag = committed
+            .as_u64()
+            .saturating_sub(history.consumed_tail().index.as_u64());
+        track_assert!(
+            lag <= max_lag,
+            ErrorKind::NotLeader,
+            "Too far behind the leader: lag={}, max_lag={}",
+            lag,
+            max_lag
+        );
+        Ok(())
+    }
     fn handle_raft_event(&mut self, event: RaftEvent) -> Result<()> {
         use raftlog::Event as E;
         trace!(self.logger, "New raft event: {:?}", event);
+        match event {
+            E::RoleChanged { .. } | E::TermChanged { .. } | E::Committed { .. }
+                if !self.log_loaded =>
+            {
+                info!(self.logger, "Raft log is loaded");
+                self.log_loaded = true;
+            }
+            _ => {}
+        }
         match event {
             E::RoleChanged { new_role } => {
                 info!(self.logger, "New raft role: {:?}", new_role);
                 let role = format!("{:?}", new_role);
                 track!(self.metrics.objects.labels_mut().insert("role", &role))?;
+                let members = self.rlog.cluster_config().primary_members().len();
+                self.leadership.update(new_role == Role::Leader, members);
+                // ウィットネスは選挙を始めないが、ウィットネスになる前に始めた選挙で選ばれることはあり得る
+                if new_role == Role::Leader && self.acts_as_witness() {
+                    info!(self.logger, "Resigns the leadership as a witness");
+                    self.start_reelection();
+                }
+                if new_role != Role::Leader {
+                    self.revoke_read_lease("No longer the leader");
+                    // ジョブ自体はマシンに複製されており、新たなリーダが引き継ぐ
+                    self.delete_jobs.clear();
+                }
             }
             E::TermChanged { new_ballot } => {
                 info!(
@@ -632,6 +1403,7 @@ impl Node {
                     "New raft election term: ballot={:?}", new_ballot
                 );
                 self.leader = None;
+                self.leadership.set_has_leader(false);
             }
             E::Committed { index, entry } => track!(self.handle_committed(index, entry))?,
             E::SnapshotLoaded { new_head, snapshot } => {
@@ -644,9 +1416,12 @@ impl Node {
                 let logger = self.logger.clone();
                 let started_at = Instant::now();
                 let metrics = self.metrics.clone();
+                let witness = self.witness;
                 let future = fibers_tasque::DefaultCpuTaskQueue.async_call(move || {
-                    let machine = track!(codec::decode_machine(&snapshot))?;
-                    let versions = machine.to_versions();
+                    let machine = track!(codec::decode_machine(&snapshot, witness))?;
+                    let mut versions = machine.to_versions();
+                    versions.extend(machine.to_part_versions());
+                    versions.extend(machine.to_history_versions());
                     info!(logger, "Snapshot decoded: {} bytes", snapshot.len());
                     let elapsed = prometrics::timestamp::duration_to_seconds(started_at.elapsed());
                     metrics.snapshot_decoding_duration_seconds.observe(elapsed);
@@ -669,6 +1444,19 @@ impl Node {
         Ok(())
     }
     fn handle_committed(&mut self, commit: LogIndex, entry: LogEntry) -> Result<()> {
+        let catching_up = self.demotion != WitnessDemotion::None;
+        if self.demotion.skips(commit) {
+            // リーダから取得したスナップショットに既に含まれているので、コマンドは適用しない
+            track!(self.handle_skipped_commit(commit, entry))?;
+            return Ok(());
+        }
+        if catching_up && self.demotion == WitnessDemotion::None {
+            info!(
+                self.logger,
+                "Caught up with the full snapshot: commit={:?}", commit
+            );
+            self.rlog.io().timer().set_witness(self.witness);
+        }
         track_assert_eq!(self.next_commit, commit, ErrorKind::InvalidInput);
         self.next_commit = commit + 1;
 
@@ -693,6 +1481,10 @@ impl Node {
         }
 
         // エントリ毎の処理を実施
+        let entry_bytes = match entry {
+            LogEntry::Command { ref command, .. } => command.len(),
+            _ => 0,
+        };
         match entry {
             LogEntry::Noop { .. } => {
                 let leader = track!(NodeId::from_raft_node_id(
@@ -706,15 +1498,43 @@ impl Node {
                     "New leader is elected: {:?} (commit:{:?})", leader, commit
                 );
                 self.leader = Some(leader);
+                self.leadership.set_has_leader(true);
+                if leader == self.node_id {
+                    // 以前のリーダが実行していた削除ジョブを引き継ぐ
+                    for job_id in self.machine.running_delete_jobs() {
+                        let targets = self.machine.delete_job_targets(job_id);
+                        self.delete_jobs.resume(job_id, targets);
+                    }
+                    self.resign_for_drain();
+                }
             }
             LogEntry::Command { command, .. } => {
                 self.commit_timeout = None;
-                let command = track!(protobuf::command_decoder().decode_from_bytes(&command))?;
-                let result = track!(self.handle_command(commit, command));
+                let (command, timestamp) =
+                    track!(protobuf::command_decoder().decode_from_bytes(&command))?;
+                // リーダ以外のノードも、以降に発行するタイムスタンプがリーダのものより大きくなるようにする
+                self.service.clock().update(timestamp);
+                self.machine.record_commit(
+                    ObjectVersion(commit.as_u64()),
+                    timestamp.physical_millis() / 1000,
+                );
+                let overwritten = matches!(command, Command::Put { .. });
+                let result = track!(self.handle_command(commit, command, timestamp));
+                let changes = self.machine.take_changes();
+                self.change_log
+                    .record(ObjectVersion(commit.as_u64()), changes);
+                // 上書きや削除によって不要になった追記部分や過去のバージョンの実データも削除する
+                for version in self.machine.take_released_parts() {
+                    self.events.push_back(Event::Deleted {
+                        version,
+                        timestamp,
+                        overwritten,
+                    });
+                }
                 if let Some(proposal) = proposal {
                     match result {
                         Err(e) => proposal.notify_error(e),
-                        Ok(old) => proposal.notify_committed(&old),
+                        Ok(old) => proposal.notify_committed(&old, &self.machine),
                     }
                 }
             }
@@ -723,23 +1543,86 @@ impl Node {
 
         // スナップショットの処理
         self.last_commit = Some(commit);
-        self.local_log_size += 1;
-        if self.local_log_size > self.snapshot_threshold.value() {
+        self.local_log_size.append(entry_bytes);
+        if self
+            .local_log_size
+            .exceeds(&self.snapshot_threshold, self.snapshot_threshold_bytes)
+        {
             track!(self.take_snapshot())?;
         }
         Ok(())
     }
-    fn handle_command(&mut self, commit: LogIndex, command: Command) -> Result<Vec<ObjectVersion>> {
+    fn handle_skipped_commit(&mut self, commit: LogIndex, entry: LogEntry) -> Result<()> {
+        while let Some(next) = self.proposals.pop_front() {
+            if commit < next.id().index {
+                self.proposals.push_front(next);
+                break;
+            }
+            warn!(self.logger, "This proposal is rejected: {:?}", next.id());
+            next.notify_rejected();
+        }
+        match entry {
+            LogEntry::Noop { .. } => {
+                let leader = track!(NodeId::from_raft_node_id(
+                    &self.rlog.local_node().ballot.voted_for
+                ))?;
+                for x in self.leader_waitings.drain(..) {
+                    x.exit(Ok(leader));
+                }
+                self.leader = Some(leader);
+                self.leadership.set_has_leader(true);
+            }
+            LogEntry::Command { .. } => {}
+            LogEntry::Config { config, .. } => self.handle_config(commit, &config),
+        }
+        self.last_commit = Some(commit);
+        Ok(())
+    }
+    fn encode_command(&self, command: Command) -> Result<Vec<u8>> {
+        // コマンドの順序をセグメント間で比較できるように、提案時にタイムスタンプを付与する
+        let timestamp = self.service.clock().now();
+        track!(protobuf::command_encoder()
+            .encode_into_bytes((command, timestamp))
+            .map_err(Error::from))
+    }
+    fn handle_command(
+        &mut self,
+        commit: LogIndex,
+        command: Command,
+        timestamp: HybridTimestamp,
+    ) -> Result<Vec<ObjectVersion>> {
+        track_assert!(
+            !(self.machine.is_frozen() && command.is_mutation()),
+            ErrorKind::Frozen,
+            "The segment is being relayouted"
+        );
         match command {
             Command::Put {
                 object_id,
                 userdata: data,
+                size,
                 put_content_timeout,
                 expect,
+                history_retention,
+                quota,
             } => {
+                if !quota.is_unlimited() {
+                    track!(self.machine.check_quota(quota, &object_id, size))?;
+                }
                 let version = ObjectVersion(commit.as_u64());
                 let metadata = Metadata { version, data };
-                let old = track!(self.machine.put(object_id, metadata, &expect))?;
+                let old = if let Some(retention) = history_retention {
+                    // NOTE: 全ノードで同じ結果となるように、保持期間の起点には提案時のタイムスタンプを用いる
+                    let now = timestamp.physical_millis();
+                    track!(self
+                        .machine
+                        .put_versioned(object_id, metadata, size, &expect, now, retention))?
+                } else {
+                    let old = track!(self.machine.put(object_id.clone(), metadata, size, &expect))?;
+                    self.machine
+                        .record_put_time(object_id, timestamp.physical_millis());
+                    old
+                };
                 if let Some(old) = old {
                     track_assert!(
                         old < version,
@@ -748,30 +1631,53 @@ impl Node {
                         old,
                         version
                     );
-                    self.events.push_back(Event::Deleted { version: old });
+                    self.metrics.overwritten_objects_total.increment();
+                    // 過去のバージョンとして保持される場合には、実データは保持期間が過ぎてから削除される
+                    if history_retention.is_none() {
+                        self.events.push_back(Event::Deleted {
+                            version: old,
+                            timestamp,
+                            overwritten: true,
+                        });
+                    }
                 }
                 self.events.push_back(Event::Putted {
                     version,
                     put_content_timeout,
+                    timestamp,
                 });
-                self.metrics.objects.set(self.machine.len() as f64);
+                self.update_machine_metrics();
 
                 Ok(old.into_iter().collect())
             }
             Command::Delete { object_id, expect } => {
-                let old = track!(self.machine.delete(&object_id, &expect))?;
-                if let Some(version) = old {
-                    self.events.push_back(Event::Deleted { version });
-                }
-                self.metrics.objects.set(self.machine.len() as f64);
+                // NOTE: 全ノードで同じ結果となるように、保持期間の起点には提案時のタイムスタンプを用いる
+                let now = timestamp.physical_millis();
+                let old = track!(self.machine.delete_versioned(&object_id, &expect, now))?;
+                let old = old.map(|(version, retained)| {
+                    // 過去のバージョンとして保持される場合には、実データは保持期間が過ぎてから削除される
+                    if !retained {
+                        self.events.push_back(Event::Deleted {
+                            version,
+                            timestamp,
+                            overwritten: false,
+                        });
+                    }
+                    version
+                });
+                self.update_machine_metrics();
                 Ok(old.into_iter().collect())
             }
             Command::DeleteByVersion { object_version } => {
                 let old = track!(self.machine.delete_version(object_version))?;
                 if let Some(version) = old {
-                    self.events.push_back(Event::Deleted { version });
+                    self.events.push_back(Event::Deleted {
+                        version,
+                        timestamp,
+                        overwritten: false,
+                    });
                 }
-                self.metrics.objects.set(self.machine.len() as f64);
+                self.update_machine_metrics();
                 Ok(old.into_iter().collect())
             }
             // 現時点ではDeleteByRangeに到達することはない。
@@ -790,16 +1696,227 @@ impl Node {
             Command::DeleteByPrefix { prefix } => {
                 let deleted = track!(self.machine.delete_by_prefix(&prefix))?;
 
-                deleted
-                    .iter()
-                    .for_each(|&version| self.events.push_back(Event::Deleted { version }));
+                deleted.iter().for_each(|&version| {
+                    self.events.push_back(Event::Deleted {
+                        version,
+                        timestamp,
+                        overwritten: false,
+                    })
+                });
 
-                self.metrics.objects.set(self.machine.len() as f64);
+                self.update_machine_metrics();
 
                 Ok(deleted)
             }
+            Command::StartDeleteJob {
+                job_id,
+                prefix,
+                retention,
+            } => {
+                let version = ObjectVersion(commit.as_u64());
+                self.machine
+                    .start_delete_job(job_id, prefix, retention, version);
+                let running = self
+                    .machine
+                    .delete_job_status(job_id)
+                    .is_some_and(|s| s.state == DeleteJobState::Running);
+                if running && self.leader == Some(self.node_id) {
+                    let targets = self.machine.delete_job_targets(job_id);
+                    self.delete_jobs.resume(job_id, targets);
+                }
+                Ok(Vec::new())
+            }
+            Command::DeleteObjects { job_id, object_ids } => {
+                // NOTE: 全ノードで同じ結果となるように、猶予期間の起点には提案時のタイムスタンプを用いる
+                let deleted =
+                    self.machine
+                        .delete_job_batch(job_id, &object_ids, timestamp.physical_millis());
+                for &version in &deleted {
+                    self.events.push_back(Event::Deleted {
+                        version,
+                        timestamp,
+                        overwritten: false,
+                    });
+                }
+                self.delete_jobs.committed(commit);
+                self.update_machine_metrics();
+                Ok(deleted)
+            }
+            Command::FinishDeleteJob { job_id, cancelled } => {
+                self.machine
+                    .finish_delete_job(job_id, cancelled, ObjectVersion(commit.as_u64()));
+                self.delete_jobs.forget(job_id);
+                Ok(Vec::new())
+            }
+            Command::Tombstone {
+                object_id,
+                expect,
+                retention,
+            } => {
+                // NOTE: 全ノードで同じ結果となるように、猶予期間の起点には提案時のタイムスタンプを用いる
+                let now = timestamp.physical_millis();
+                if self.machine.has_history(&object_id) {
+                    // 過去のバージョンを持つオブジェクトは、削除時のバージョンも履歴として保持される
+                    let old = track!(self.machine.delete_versioned(&object_id, &expect, now))?;
+                    self.update_machine_metrics();
+                    return Ok(old.map(|(version, _)| version).into_iter().collect());
+                }
+                let expires_at = now + retention.0 * 1000;
+                let result = track!(self.machine.tombstone(&object_id, &expect, expires_at))?;
+                let old = result.map(|(version, replaced)| {
+                    // 置き換えられた古い削除済みオブジェクトは、もう復元されることはない
+                    if let Some(version) = replaced {
+                        self.events.push_back(Event::Deleted {
+                            version,
+                            timestamp,
+                            overwritten: false,
+                        });
+                    }
+                    version
+                });
+                self.update_machine_metrics();
+                Ok(old.into_iter().collect())
+            }
+            Command::Undelete { object_id } => {
+                let restored = track!(self
+                    .machine
+                    .undelete(&object_id, timestamp.physical_millis()))?;
+                self.update_machine_metrics();
+                Ok(restored.into_iter().collect())
+            }
+            Command::PurgeTombstones => {
+                // 期限切れの予約された追記部分は`take_released_parts`経由で削除される
+                self.machine
+                    .expire_pending_parts(timestamp.physical_millis());
+                let mut purged = self.machine.purge_tombstones(timestamp.physical_millis());
+                purged.extend(self.machine.purge_history(timestamp.physical_millis()));
+                self.update_machine_metrics();
+                for &version in &purged {
+                    self.events.push_back(Event::Deleted {
+                        version,
+                        timestamp,
+                        overwritten: false,
+                    });
+                }
+                Ok(purged)
+            }
+            Command::Append {
+                object_id,
+                expect,
+                size,
+                put_content_timeout,
+            } => {
+                // NOTE: 内容はまだ保存されていないので、`Event::Putted`は追記(`CommitAppend`)の適用時に発行する
+                let version = ObjectVersion(commit.as_u64());
+                let now = timestamp.physical_millis();
+                let old = track!(self.machine.reserve_part(
+                    &object_id,
+                    version,
+                    size,
+                    &expect,
+                    put_content_timeout,
+                    now
+                ))?;
+                Ok(vec![old])
+            }
+            Command::CommitAppend { object_id, version } => {
+                let now = timestamp.physical_millis();
+                let (old, put_content_timeout) =
+                    track!(self.machine.commit_part(&object_id, version, now))?;
+                self.events.push_back(Event::Putted {
+                    version,
+                    put_content_timeout,
+                    timestamp,
+                });
+                self.update_machine_metrics();
+                Ok(vec![old])
+            }
+            Command::Relayout(command) => {
+                info!(self.logger, "Relayout command is committed: {:?}", command);
+                match command {
+                    RelayoutCommand::Start { generation } => {
+                        let version = ObjectVersion(commit.as_u64());
+                        track!(self.machine.start_relayout(generation, version))?;
+                    }
+                    RelayoutCommand::Progress {
+                        generation,
+                        cursor,
+                        copied,
+                    } => {
+                        track!(self
+                            .machine
+                            .record_relayout_progress(generation, cursor, copied))?;
+                    }
+                    RelayoutCommand::Freeze { generation } => {
+                        track!(self.machine.freeze_relayout(generation))?;
+                    }
+                    RelayoutCommand::Finish { generation } => {
+                        track!(self.machine.finish_relayout(generation))?;
+                    }
+                }
+                Ok(Vec::new())
+            }
         }
     }
+    /// 猶予期間が過ぎた削除済みオブジェクト(ないし過去のバージョンや、期限切れの予約された追記部分)があれば、
+    /// その破棄を提案する.
+    ///
+    /// 提案は`tombstone_gc_interval`毎に高々一度だけ行われる.
+    /// 実際に破棄されるのは提案がコミットされた時点であり、
+    /// その際に発行される `Event::Deleted` を受けて実データが削除される.
+    fn propose_purge_tombstones_if_needed(&mut self) -> Result<()> {
+        if self.check_leader().is_err()
+            || self.machine.is_frozen()
+            || Instant::now() < self.next_tombstone_gc
+            || !is_purge_enabled()
+        {
+            return Ok(());
+        }
+        if let Some(index) = self.purge_proposed_at {
+            if self.next_commit <= index {
+                // 前回の提案がまだコミットされていない
+                return Ok(());
+            }
+        }
+        let now = self.service.clock().now().physical_millis();
+        if !self.machine.has_expired_tombstones(now)
+            && !self.machine.has_expired_history(now)
+            && !self.machine.has_expired_pending_parts(now)
+        {
+            return Ok(());
+        }
+        let command = track!(self.encode_command(Command::PurgeTombstones))?;
+        let proposal_id = track!(self.rlog.propose_command(command))?;
+        self.purge_proposed_at = Some(proposal_id.index);
+        self.next_tombstone_gc = Instant::now() + self.tombstone_gc_interval;
+        Ok(())
+    }
+    /// 実行中の削除ジョブがあれば、次のバッチの削除ないしジョブの完了を提案する.
+    ///
+    /// 前のバッチの削除によって発行されたイベントが、まだ取り出されていない場合には提案しない.
+    fn propose_delete_batch_if_needed(&mut self) -> Result<()> {
+        if self.check_leader().is_err() || self.machine.is_frozen() || !self.events.is_empty() {
+            return Ok(());
+        }
+        self.delete_jobs.requeue_lost_batches(self.next_commit);
+        let mut jobs = mem::take(&mut self.delete_jobs);
+        let result = jobs.next_proposal(self.delete_job_batch_size, |command| {
+            let command = track!(self.encode_command(command))?;
+            let proposal_id = track!(self.rlog.propose_command(command))?;
+            Ok(proposal_id.index)
+        });
+        self.delete_jobs = jobs;
+        result
+    }
+    fn update_machine_metrics(&self) {
+        self.metrics.objects.set(self.machine.len() as f64);
+        self.metrics
+            .tombstones
+            .set(self.machine.tombstone_len() as f64);
+        // コミット毎に呼ばれるので、グローバルなロックを取らないように小さな変化は報告を省略する
+        self.memory
+            .set_approximately(self.machine.approximate_memory_bytes());
+    }
     fn handle_config(&mut self, commit: LogIndex, config: &ClusterConfig) {
         info!(
             self.logger,
@@ -813,17 +1930,164 @@ impl Node {
     fn is_staled_object_visible(&self) -> bool {
         self.leader.is_some() || self.staled_object_rounds <= self.staled_object_threshold
     }
+    /// ドレイン中であれば、リーダ権を他のノードに譲る.
+    ///
+    /// 他のノードがリーダになれない状況で選出と辞退を繰り返さないように、辞退の回数には上限を設ける.
+    fn resign_for_drain(&mut self) {
+        let sole_voter = self.is_sole_voter();
+        if !self.drain_resignations.try_resign(sole_voter) {
+            return;
+        }
+        info!(self.logger, "Resigns the leadership for draining");
+        self.start_reelection();
+    }
+    /// ウィットネスとして動作すべきかどうか.
+    ///
+    /// ウィットネスとして設定されていなくても、完全なスナップショットに追い付くまでの間はウィットネスとして動作する.
+    fn acts_as_witness(&self) -> bool {
+        self.witness || self.demotion != WitnessDemotion::None
+    }
+    fn start_demotion(&mut self) {
+        self.demotion = WitnessDemotion::Fetching {
+            retry_at: Instant::now(),
+        };
+        self.fetching_full_snapshot = None;
+        self.rlog.io().timer().set_witness(true);
+        if self.is_leader() {
+            info!(self.logger, "Resigns the leadership as a witness");
+            self.start_reelection();
+        }
+    }
+    fn cancel_demotion(&mut self) {
+        if self.demotion != WitnessDemotion::None {
+            info!(self.logger, "Cancels the demotion from a witness");
+            self.demotion = WitnessDemotion::None;
+            self.fetching_full_snapshot = None;
+            self.rlog.io().timer().set_witness(self.witness);
+        }
+    }
+    /// リーダから完全なスナップショットを取得して、デコードする.
+    ///
+    /// リーダが不明な場合には何もしない(次回のポーリング時に再試行される).
+    fn fetch_full_snapshot(&mut self) {
+        let leader = match self.leader {
+            Some(leader) if leader != self.node_id => leader,
+            _ => return,
+        };
+        info!(
+            self.logger,
+            "Fetches a full snapshot from the leader: {:?}", leader
+        );
+        let logger = self.logger.clone();
+        let future = frugalos_raft::fetch_log_prefix(
+            &self.rpc_service,
+            &leader,
+            self.fetch_snapshot_timeout,
+        )
+        .map_err(|e| track!(Error::from(e)))
+        .and_then(move |prefix| {
+            let prefix = if let Some(prefix) = prefix {
+                prefix
+            } else {
+                let e = ErrorKind::Other.cause("The leader has no snapshot");
+                return Either::A(futures::failed(track!(e).into()));
+            };
+            let future = fibers_tasque::DefaultCpuTaskQueue.async_call(move || {
+                let machine = track!(codec::decode_machine(&prefix.snapshot, false))?;
+                track_assert!(
+                    !machine.is_witness(),
+                    ErrorKind::Other,
+                    "The snapshot of the leader is a witness's one"
+                );
+                let mut versions = machine.to_versions();
+                versions.extend(machine.to_part_versions());
+                versions.extend(machine.to_history_versions());
+                info!(
+                    logger,
+                    "Full snapshot decoded: {} bytes",
+                    prefix.snapshot.len()
+                );
+                Ok((prefix.tail, machine, versions))
+            });
+            Either::B(future.map_err(Error::from).and_then(|result| result))
+        });
+        self.fetching_full_snapshot = Some(Box::new(future));
+    }
+    fn poll_demotion(&mut self) {
+        if self.fetching_full_snapshot.is_none() && self.demotion.should_fetch(Instant::now()) {
+            self.fetch_full_snapshot();
+        }
+        let (new_head, machine, versions) = match self.fetching_full_snapshot.poll() {
+            Err(e) => {
+                warn!(self.logger, "Cannot fetch a full snapshot: {}", e);
+                self.retry_demotion();
+                return;
+            }
+            Ok(Async::Ready(Some(fetched))) => fetched,
+            Ok(_) => return,
+        };
+        self.fetching_full_snapshot = None;
+        if !self.demotion.accepts(new_head.index, self.next_commit) {
+            warn!(
+                self.logger,
+                "The full snapshot is older than the local state: new_head={:?}, next_commit={:?}",
+                new_head,
+                self.next_commit
+            );
+            self.retry_demotion();
+            return;
+        }
+        info!(
+            self.logger,
+            "The witness state is replaced with a full snapshot: new_head={:?}", new_head
+        );
+        self.load_machine(new_head, machine, versions);
+        self.demotion = WitnessDemotion::CatchingUp {
+            head: new_head.index,
+        };
+    }
+    fn retry_demotion(&mut self) {
+        self.fetching_full_snapshot = None;
+        self.demotion = WitnessDemotion::Fetching {
+            retry_at: Instant::now() + self.fetch_snapshot_timeout,
+        };
+    }
+    fn load_machine(
+        &mut self,
+        new_head: LogPosition,
+        machine: Machine,
+        versions: Vec<ObjectVersion>,
+    ) {
+        // Synchronizer takes care of load control, so this setting is no longer necessary.
+        // The default value of delay is 0, which means no delay happens by this.
+        let delay = env::var("FRUGALOS_SNAPSHOT_REPAIR_DELAY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        self.events.reserve_exact(machine.len());
+        self.events
+            .extend(versions.into_iter().map(|version| Event::Putted {
+                version,
+                put_content_timeout: Seconds(delay),
+                timestamp: HybridTimestamp::default(),
+            }));
+        self.next_commit = new_head.index;
+        self.change_log
+            .reset(ObjectVersion(new_head.index.as_u64()));
+        self.machine = machine;
+        self.update_machine_metrics();
+    }
     fn start_reelection(&mut self) {
         let members = self.rlog.cluster_config().primary_members();
         let local = self.rlog.local_node();
         for m in members.iter().filter(|n| **n != local.id) {
             let m = track!(NodeId::from_raft_node_id(&m)); // TODO:
             if let Ok(m) = m {
-                let client = ::libfrugalos::client::mds::Client::new(
-                    (m.addr, m.local_id.to_string()),
-                    self.rpc_service.clone(),
+                let _ = rpc_auth::cast::<RecommendToLeaderRpc>(
+                    &self.rpc_service,
+                    m.current_addr(),
+                    m.local_id.to_string(),
                 );
-                client.recommend_to_leader();
             }
         }
     }
@@ -846,6 +2110,44 @@ impl Drop for Node {
         }
     }
 }
+/// 要求の期限が過ぎていないかを確認する.
+///
+/// 期限を過ぎた要求は、Raft に提案しても呼び出し元が結果を受け取れないので、提案する前に失敗させる.
+fn check_deadline(deadline: Option<Instant>) -> Result<()> {
+    if let Some(deadline) = deadline {
+        track_assert!(
+            Instant::now() < deadline,
+            ErrorKind::Timeout,
+            "Deadline exceeded before proposing"
+        );
+    }
+    Ok(())
+}
+
+/// 追記のコマンドを提案可能かどうかを確認する.
+///
+/// 古いバージョンのノードは追記のコマンドをデコードできないので、`ClusterFeature::Append`が有効な場合にのみ提案できる.
+fn check_append_enabled() -> Result<()> {
+    track_assert!(
+        cluster_feature::is_enabled(ClusterFeature::Append),
+        ErrorKind::InvalidInput,
+        "The cluster feature `{}` is not enabled",
+        ClusterFeature::Append
+    );
+    Ok(())
+}
+
+/// `PurgeTombstones`コマンドを提案可能かどうかを返す.
+///
+/// このコマンドは削除猶予期間中のオブジェクトに加えて、保持期間を過ぎた過去のバージョンと
+/// 内容の保存を待っている追記部分の破棄にも使われるので、それらの機能のいずれかが有効な場合にのみ提案できる.
+/// いずれも無効な場合には、破棄の対象となる状態は生成されない.
+fn is_purge_enabled() -> bool {
+    cluster_feature::is_enabled(ClusterFeature::Tombstones)
+        || cluster_feature::is_enabled(ClusterFeature::HistoryRetention)
+        || cluster_feature::is_enabled(ClusterFeature::Append)
+}
+
 impl Stream for Node {
     type Item = Event;
     type Error = Error;
@@ -886,6 +2188,16 @@ impl Stream for Node {
                 }
             }
 
+            // 削除猶予期間チェック
+            if let Err(e) = track!(self.propose_purge_tombstones_if_needed()) {
+                warn!(self.logger, "Cannot purge tombstones: {}", e);
+            }
+
+            // 削除ジョブの進行
+            if let Err(e) = track!(self.propose_delete_batch_if_needed()) {
+                warn!(self.logger, "Cannot propose a delete batch: {}", e);
+            }
+
             // リーダ待機チェック
             self.leader_waiting_timeout.decrement();
             if self.leader_waiting_timeout.is_expired() && !self.leader_waitings.is_empty() {
@@ -893,6 +2205,22 @@ impl Stream for Node {
                 self.clear_leader_waitings();
             }
 
+            // 読み込み確認待ちチェック
+            let expired = self
+                .read_lease
+                .take_expired(Instant::now(), self.read_confirm_timeout);
+            if !expired.is_empty() {
+                warn!(
+                    self.logger,
+                    "Cannot confirm the leadership for {} reads (timeout)",
+                    expired.len()
+                );
+            }
+            for read in expired {
+                let e = ErrorKind::NotLeader.cause("Leadership confirmation timeout");
+                self.reply_read(read, Err(track!(e).into()));
+            }
+
             // 可視性チェック
             if self.leader.is_some() {
                 self.staled_object_rounds = 0;
@@ -900,6 +2228,7 @@ impl Stream for Node {
                 self.staled_object_rounds += 1;
             }
         }
+        self.report_task_state();
 
         match track!(self.decoding_snapshot.poll().map_err(Error::from))? {
             Async::NotReady => return Ok(Async::NotReady),
@@ -907,24 +2236,24 @@ impl Stream for Node {
             Async::Ready(Some(result)) => {
                 let (new_head, machine, versions) = track!(result)?;
                 info!(self.logger, "Snapshot decoded: new_head={:?}", new_head);
-                // Synchronizer takes care of load control, so this setting is no longer necessary.
-                // The default value of delay is 0, which means no delay happens by this.
-                let delay = env::var("FRUGALOS_SNAPSHOT_REPAIR_DELAY")
-                    .ok()
-                    .and_then(|v| v.parse().ok())
-                    .unwrap_or(0);
-                self.events.reserve_exact(machine.len());
-                self.events
-                    .extend(versions.into_iter().map(|version| Event::Putted {
-                        version,
-                        put_content_timeout: Seconds(delay),
-                    }));
-                self.next_commit = new_head.index;
-                self.machine = machine;
-                self.metrics.objects.set(self.machine.len() as f64);
+                if machine.is_witness() && !self.witness {
+                    // オブジェクトのデータは失われているので、リーダから完全なスナップショットを取得し直す.
+                    // それまでの間は、コミット済みのエントリをウィットネスのマシンに適用し続ける.
+                    warn!(
+                        self.logger,
+                        "Loaded a snapshot of a witness; fetches a full snapshot from the leader"
+                    );
+                    self.start_demotion();
+                } else {
+                    self.cancel_demotion();
+                }
+                self.load_machine(new_head, machine, versions);
                 self.decoding_snapshot = None;
             }
         }
+        if self.demotion.is_fetching() {
+            self.poll_demotion();
+        }
         if let Async::Ready(Some(result)) = track!(self.ready_snapshot.poll().map_err(Error::from))?
         {
             info!(self.logger, "Snapshot readied");
@@ -944,6 +2273,7 @@ impl Stream for Node {
             let request = polled.expect("Never fails");
             self.handle_request(request);
         }
+        self.send_read_heartbeat_if_needed();
 
         while let Async::Ready(polled) = track!(self.rlog.poll())? {
             if let Some(event) = polled {
@@ -967,6 +2297,7 @@ impl Stream for Node {
                 );
             }
         }
+        self.confirm_pending_reads();
 
         // FIXME: もっと適切な場所に移動
         if self.phase == Phase::Stopped {
@@ -989,6 +2320,76 @@ impl Stream for Node {
 mod tests {
     use super::*;
 
+    #[test]
+    fn check_deadline_works() {
+        assert!(check_deadline(None).is_ok());
+        assert!(check_deadline(Some(Instant::now() + Duration::from_secs(10))).is_ok());
+
+        let e = check_deadline(Some(Instant::now())).unwrap_err();
+        assert_eq!(*e.kind(), ErrorKind::Timeout);
+    }
+
+    #[test]
+    fn drain_resignations_works() {
+        // ドレイン中でなければ辞退しない
+        let mut resignations = DrainResignations::default();
+        assert!(!resignations.try_resign(false));
+
+        resignations.start();
+        for _ in 0..MAX_DRAIN_RESIGNATIONS {
+            assert!(resignations.try_resign(false));
+        }
+        // 上限に達した後は、リーダに選ばれてもそのまま引き受ける
+        assert!(!resignations.try_resign(false));
+
+        // 再度ドレインが要求されても、回数はリセットされない
+        resignations.start();
+        assert!(!resignations.try_resign(false));
+
+        // 他にリーダになれるノードがない場合は辞退しない
+        let mut resignations = DrainResignations::default();
+        resignations.start();
+        assert!(!resignations.try_resign(true));
+        assert!(resignations.try_resign(false));
+    }
+
+    #[test]
+    fn witness_demotion_works() {
+        let now = Instant::now();
+        let mut demotion = WitnessDemotion::None;
+        assert!(!demotion.is_fetching());
+        assert!(!demotion.should_fetch(now));
+        assert!(!demotion.skips(LogIndex::new(0)));
+
+        // ウィットネスのスナップショットを読み込んだ: 完全なスナップショットを取得するまではウィットネスとして動作する
+        demotion = WitnessDemotion::Fetching { retry_at: now };
+        assert!(demotion.is_fetching());
+        assert!(demotion.should_fetch(now));
+        assert!(!demotion.skips(LogIndex::new(10)));
+
+        // 適用済みのエントリよりも古いスナップショットは適用できない
+        assert!(!demotion.accepts(LogIndex::new(9), LogIndex::new(10)));
+        assert!(demotion.accepts(LogIndex::new(10), LogIndex::new(10)));
+        assert!(demotion.accepts(LogIndex::new(15), LogIndex::new(10)));
+
+        // 再試行は指定時刻まで待つ
+        let later = now + Duration::from_secs(10);
+        demotion = WitnessDemotion::Fetching { retry_at: later };
+        assert!(!demotion.should_fetch(now));
+        assert!(demotion.should_fetch(later));
+
+        // スナップショットに含まれるエントリの適用は省略し、追い付いた時点で完了する
+        demotion = WitnessDemotion::CatchingUp {
+            head: LogIndex::new(15),
+        };
+        assert!(!demotion.is_fetching());
+        assert!(!demotion.accepts(LogIndex::new(20), LogIndex::new(10)));
+        assert!(demotion.skips(LogIndex::new(10)));
+        assert!(demotion.skips(LogIndex::new(14)));
+        assert!(!demotion.skips(LogIndex::new(15)));
+        assert_eq!(demotion, WitnessDemotion::None);
+    }
+
     #[test]
     fn leader_waiting_timeout_works() {
         let mut timeout = LeaderWaitingTimeout::new(3);
@@ -69,6 +69,35 @@ impl fmt::Display for SnapshotThreshold {
     }
 }
 
+/// The size of the local log which has been committed since the last snapshot.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LocalLogSize {
+    /// The number of committed entries.
+    pub entries: usize,
+    /// The total size of committed commands in bytes.
+    pub bytes: u64,
+}
+
+impl LocalLogSize {
+    /// Records a committed entry whose payload is `bytes` long.
+    pub fn append(&mut self, bytes: usize) {
+        self.entries += 1;
+        self.bytes += bytes as u64;
+    }
+
+    /// Resets the size after a snapshot is taken.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Returns `true` if either the number of entries or the total bytes exceeds the thresholds.
+    ///
+    /// `threshold_bytes` of `None` disables the byte-based threshold.
+    pub fn exceeds(&self, threshold: &SnapshotThreshold, threshold_bytes: Option<u64>) -> bool {
+        self.entries > threshold.value() || threshold_bytes.is_some_and(|t| self.bytes > t)
+    }
+}
+
 /// Fills up the specified seed with `src`. `seed` must be `[u8; 32]`.
 fn fill_rng_seed(seed: &mut [u8], src: &str) {
     let len = src.len();
@@ -129,6 +158,28 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn local_log_size_works() -> TestResult {
+        let threshold = track!(SnapshotThreshold::new(
+            GOOD_SEED,
+            Range { start: 2, end: 2 }
+        ))?;
+        let mut size = LocalLogSize::default();
+        size.append(10);
+        size.append(0);
+        assert!(!size.exceeds(&threshold, None));
+        assert!(!size.exceeds(&threshold, Some(10)));
+        assert!(size.exceeds(&threshold, Some(9)));
+
+        size.append(0);
+        assert_eq!(size.entries, 3);
+        assert!(size.exceeds(&threshold, None));
+
+        size.reset();
+        assert_eq!(size, LocalLogSize::default());
+        Ok(())
+    }
+
     #[test]
     fn fill_rng_seed_works() {
         let mut seed = [0u8; 32];
@@ -0,0 +1,123 @@
+//! 書き込み系の操作に指定可能な前提条件.
+//!
+//! `libfrugalos::expect::Expect`では表現できない、バージョンの範囲や更新時刻による条件も扱う.
+//! `Expect`以外の条件はリーダが提案前に評価し、評価時点のバージョンを指定する`Expect`に変換してから
+//! Raftのコマンドに含める(`Machine::resolve_precondition`を参照).
+//! 変換後の条件はコマンドの適用時に状態機械の中で評価されるので、評価と更新の間に他の操作が割り込むことはない.
+use libfrugalos::entity::object::ObjectVersion;
+use libfrugalos::expect::Expect;
+
+use {Error, ErrorKind, Result};
+
+/// 操作対象のオブジェクトが満たすべき前提条件.
+///
+/// 条件を満たさない場合には`ErrorKind::Unexpected`が返される(値はオブジェクトの実際のバージョン).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Precondition {
+    /// 従来の`Expect`による条件.
+    Expect(Expect),
+
+    /// オブジェクトのバージョンが`from <= version < to`の範囲に含まれる場合にのみ適用可能.
+    IfMatchRange {
+        /// 範囲の始点(含む).
+        from: ObjectVersion,
+
+        /// 範囲の終点(含まない).
+        to: ObjectVersion,
+    },
+
+    /// オブジェクトのバージョンが指定のものよりも新しい場合にのみ適用可能.
+    IfNewerThan(ObjectVersion),
+
+    /// オブジェクトが指定時刻(UNIXエポックからの秒数)以降に更新されていない場合にのみ適用可能.
+    ///
+    /// 更新時刻は、オブジェクトを更新したコマンドのタイムスタンプ(秒単位)である.
+    /// MDSが保持している更新時刻の記録よりも前の時刻が指定された場合には、
+    /// 更新されたかどうかを判別できないので、条件を満たさないものとして扱われる.
+    IfUnmodifiedSince(u64),
+}
+impl Precondition {
+    /// オブジェクトのバージョンと更新時刻が、条件を満たすかどうかを検証する.
+    ///
+    /// `modified_at`は、オブジェクトの更新時刻(UNIXエポックからの秒数)の上限を返す関数.
+    /// 更新時刻が不明な場合には`None`を返す.
+    pub fn validate<F>(&self, version: Option<ObjectVersion>, modified_at: F) -> Result<()>
+    where
+        F: FnOnce(ObjectVersion) -> Option<u64>,
+    {
+        match *self {
+            Precondition::Expect(ref expect) => {
+                track!(expect.validate(version).map_err(Error::from))?
+            }
+            Precondition::IfMatchRange { from, to } => track_assert!(
+                version.is_some_and(|v| from <= v && v < to),
+                ErrorKind::Unexpected(version)
+            ),
+            Precondition::IfNewerThan(base) => track_assert!(
+                version.is_some_and(|v| v > base),
+                ErrorKind::Unexpected(version)
+            ),
+            Precondition::IfUnmodifiedSince(since) => track_assert!(
+                version
+                    .and_then(modified_at)
+                    .is_some_and(|modified_at| modified_at <= since),
+                ErrorKind::Unexpected(version)
+            ),
+        }
+        Ok(())
+    }
+}
+impl Default for Precondition {
+    fn default() -> Self {
+        Precondition::Expect(Expect::Any)
+    }
+}
+impl From<Expect> for Precondition {
+    fn from(f: Expect) -> Self {
+        Precondition::Expect(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_works() {
+        let v = Some(ObjectVersion(10));
+        let unknown = |_| None;
+        let at = |secs| move |_| Some(secs);
+
+        assert!(Precondition::from(Expect::Any).validate(v, unknown).is_ok());
+        assert!(Precondition::from(Expect::None)
+            .validate(v, unknown)
+            .is_err());
+
+        let range = Precondition::IfMatchRange {
+            from: ObjectVersion(10),
+            to: ObjectVersion(20),
+        };
+        assert!(range.validate(v, unknown).is_ok());
+        assert!(range.validate(Some(ObjectVersion(20)), unknown).is_err());
+        assert!(range.validate(None, unknown).is_err());
+
+        let newer = Precondition::IfNewerThan(ObjectVersion(9));
+        assert!(newer.validate(v, unknown).is_ok());
+        assert!(newer.validate(Some(ObjectVersion(9)), unknown).is_err());
+        assert!(newer.validate(None, unknown).is_err());
+
+        let since = Precondition::IfUnmodifiedSince(100);
+        assert!(since.validate(v, at(100)).is_ok());
+        assert!(since.validate(v, at(101)).is_err());
+        assert!(since.validate(v, unknown).is_err());
+        assert!(since.validate(None, at(0)).is_err());
+    }
+
+    #[test]
+    fn unexpected_version_is_reported() {
+        let e = Precondition::IfNewerThan(ObjectVersion(9))
+            .validate(Some(ObjectVersion(3)), |_| None)
+            .unwrap_err();
+        assert_eq!(*e.kind(), ErrorKind::Unexpected(Some(ObjectVersion(3))));
+    }
+}
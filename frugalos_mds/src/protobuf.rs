@@ -2,84 +2,213 @@
 #![allow(missing_docs)]
 use bytecodec::fixnum::{U64beDecoder, U64beEncoder};
 use bytecodec::{DecodeExt, EncodeExt, SizedEncode};
+use byteorder::{BigEndian, ByteOrder};
+use frugalos_core::hlc::HybridTimestamp;
 use libfrugalos::entity::object::{Metadata, ObjectPrefix, ObjectVersion};
 use libfrugalos::expect::Expect;
 use libfrugalos::time::Seconds;
 use patricia_tree::node::{NodeDecoder, NodeEncoder};
-use protobuf_codec::field::branch::{Branch2, Branch3, Branch5};
-use protobuf_codec::field::num::{F1, F2, F3, F4, F5};
+use protobuf_codec::field::branch::{Branch2, Branch3, Branch4, Branch6, Branch8};
+use protobuf_codec::field::num::{F1, F10, F11, F12, F2, F3, F4, F5, F6, F7, F8, F9};
 use protobuf_codec::message::{MessageDecode, MessageEncode};
 use protobuf_codec::scalar::{
-    BytesDecoder, BytesEncoder, CustomBytesDecoder, CustomBytesEncoder, StringDecoder,
-    StringEncoder, Uint64Decoder, Uint64Encoder,
+    BoolDecoder, BoolEncoder, BytesDecoder, BytesEncoder, CustomBytesDecoder, CustomBytesEncoder,
+    StringDecoder, StringEncoder, Uint64Decoder, Uint64Encoder,
 };
+use trackable::error::ErrorKindExt;
 
-use machine::{Command, Snapshot};
+use machine::{
+    Command, DeleteJob, PendingPart, RelayoutCommand, RelayoutJob, RelayoutState, Revision,
+    Snapshot, Tombstone,
+};
+use {DeleteJobState, DeleteJobStatus, Precondition, Quota};
 
-pub fn command_decoder() -> impl MessageDecode<Item = Command> {
-    let base = protobuf_message_decoder![(
-        required_oneof,
-        (F1, put_command_decoder(), message),
-        (F2, delete_command_decoder(), message),
-        (F3, delete_version_command_decoder(), message),
-        (F4, delete_by_range_command_decoder(), message),
-        (F5, delete_by_prefix_command_decoder(), message)
-    )];
-    base.map(|x| match x {
-        Branch5::A(x) => Command::Put {
+/// コマンドと、それを提案したリーダが発行したタイムスタンプの組.
+///
+/// タイムスタンプを持たない古いコマンドをデコードした場合には、
+/// 不明なタイムスタンプ(`HybridTimestamp::default()`)となる.
+pub type TimestampedCommand = (Command, HybridTimestamp);
+
+pub fn command_decoder() -> impl MessageDecode<Item = TimestampedCommand> {
+    // NOTE: `protobuf_codec`の`oneof`は最大で 8 つの分岐しか扱えないので、
+    // 削除ジョブと再配置ジョブ、追記のコマンドは`oneof`の外のフィールドとしてデコードする (ワイヤ上の表現は同じ)
+    let base = protobuf_message_decoder![
+        (F6, Uint64Decoder::new()),
+        (
+            oneof,
+            (F1, put_command_decoder(), message),
+            (F2, delete_command_decoder(), message),
+            (F3, delete_version_command_decoder(), message),
+            (F4, delete_by_range_command_decoder(), message),
+            (F5, delete_by_prefix_command_decoder(), message),
+            (F7, tombstone_command_decoder(), message),
+            (F8, undelete_command_decoder(), message),
+            (F9, empty_decoder(), message)
+        ),
+        (F10, delete_job_command_decoder(), message),
+        (F11, relayout_command_decoder(), message),
+        (F12, append_command_decoder(), message)
+    ];
+    base.try_map(
+        |(timestamp, command, job, relayout, append)| -> bytecodec::Result<_> {
+            let command = match (command, job, relayout, append) {
+                (Some(command), None, None, None) => command_from_branch(command),
+                (None, Some(job), None, None) => job,
+                (None, None, Some(relayout), None) => Command::Relayout(relayout),
+                (None, None, None, Some(append)) => append,
+                (None, None, None, None) => {
+                    track_panic!(bytecodec::ErrorKind::InvalidInput, "No command")
+                }
+                _ => track_panic!(bytecodec::ErrorKind::InvalidInput, "Multiple commands"),
+            };
+            Ok((command, HybridTimestamp::from_u64(timestamp)))
+        },
+    )
+}
+
+fn command_from_branch(x: CommandBranch) -> Command {
+    match x {
+        Branch8::A(x) => Command::Put {
             object_id: x.0,
             userdata: x.1,
+            size: x.4,
             expect: x.2,
             put_content_timeout: Seconds(x.3),
+            history_retention: if x.5 == 0 { None } else { Some(Seconds(x.5)) },
+            quota: x.6.unwrap_or_default(),
         },
-        Branch5::B(x) => Command::Delete {
+        Branch8::B(x) => Command::Delete {
             object_id: x.0,
             expect: x.1,
         },
-        Branch5::C(x) => Command::DeleteByVersion {
+        Branch8::C(x) => Command::DeleteByVersion {
             object_version: ObjectVersion(x),
         },
-        Branch5::D(x) => Command::DeleteByRange {
+        Branch8::D(x) => Command::DeleteByRange {
             version_from: ObjectVersion(x.0),
             version_to: ObjectVersion(x.1),
         },
-        Branch5::E(x) => Command::DeleteByPrefix {
+        Branch8::E(x) => Command::DeleteByPrefix {
             prefix: ObjectPrefix(x),
         },
+        Branch8::F(x) => Command::Tombstone {
+            object_id: x.0,
+            expect: x.1,
+            retention: Seconds(x.2),
+        },
+        Branch8::G(x) => Command::Undelete { object_id: x },
+        Branch8::H(()) => Command::PurgeTombstones,
+    }
+}
+
+pub fn command_encoder(
+) -> impl SizedEncode<Item = TimestampedCommand> + MessageEncode<Item = TimestampedCommand> {
+    // NOTE: `Oneof` のデコーダは、後続に oneof 以外のフィールドが現れるとデコード済みの値を
+    // 捨ててしまうため、タイムスタンプは oneof よりも前にエンコードする.
+    // 削除ジョブと再配置ジョブ、追記のコマンドは oneof と同時には現れないので、後ろに置いても問題はない.
+    let base = protobuf_message_encoder![
+        (F6, Uint64Encoder::new()),
+        (
+            oneof,
+            (F1, put_command_encoder(), message),
+            (F2, delete_command_encoder(), message),
+            (F3, delete_version_command_encoder(), message),
+            (F4, delete_by_range_command_encoder(), message),
+            (F5, delete_by_prefix_command_encoder(), message),
+            (F7, tombstone_command_encoder(), message),
+            (F8, undelete_command_encoder(), message),
+            (F9, empty_encoder(), message)
+        ),
+        (F10, delete_job_command_encoder(), message),
+        (F11, relayout_command_encoder(), message),
+        (F12, append_command_encoder(), message)
+    ];
+    base.map_from(|(command, timestamp): TimestampedCommand| {
+        let timestamp = timestamp.as_u64();
+        match command {
+            Command::Relayout(relayout) => (timestamp, None, None, Some(relayout), None),
+            command if command.is_delete_job_command() => {
+                (timestamp, None, Some(command), None, None)
+            }
+            command if command.is_append_command() => (timestamp, None, None, None, Some(command)),
+            command => (
+                timestamp,
+                Some(command_into_branch(command)),
+                None,
+                None,
+                None,
+            ),
+        }
     })
 }
 
-pub fn command_encoder() -> impl SizedEncode<Item = Command> + MessageEncode<Item = Command> {
-    let base = protobuf_message_encoder![(
-        required_oneof,
-        (F1, put_command_encoder(), message),
-        (F2, delete_command_encoder(), message),
-        (F3, delete_version_command_encoder(), message),
-        (F4, delete_by_range_command_encoder(), message),
-        (F5, delete_by_prefix_command_encoder(), message)
-    )];
-    base.map_from(|x: Command| match x {
+fn command_into_branch(x: Command) -> CommandBranch {
+    match x {
         Command::Put {
             object_id,
             userdata,
+            size,
             expect,
             put_content_timeout,
-        } => Branch5::A((object_id, userdata, expect, put_content_timeout.0)),
-        Command::Delete { object_id, expect } => Branch5::B((object_id, expect)),
-        Command::DeleteByVersion { object_version } => Branch5::C(object_version.0),
+            history_retention,
+            quota,
+        } => Branch8::A((
+            object_id,
+            userdata,
+            expect,
+            put_content_timeout.0,
+            size,
+            history_retention.map_or(0, |r| r.0),
+            if quota.is_unlimited() {
+                None
+            } else {
+                Some(quota)
+            },
+        )),
+        Command::Delete { object_id, expect } => Branch8::B((object_id, expect)),
+        Command::DeleteByVersion { object_version } => Branch8::C(object_version.0),
         Command::DeleteByRange {
             version_from,
             version_to,
-        } => Branch5::D((version_from.0, version_to.0)),
-        Command::DeleteByPrefix { prefix } => Branch5::E(prefix.0),
-    })
+        } => Branch8::D((version_from.0, version_to.0)),
+        Command::DeleteByPrefix { prefix } => Branch8::E(prefix.0),
+        Command::Tombstone {
+            object_id,
+            expect,
+            retention,
+        } => Branch8::F((object_id, expect, retention.0)),
+        Command::Undelete { object_id } => Branch8::G(object_id),
+        Command::PurgeTombstones => Branch8::H(()),
+        Command::StartDeleteJob { .. }
+        | Command::DeleteObjects { .. }
+        | Command::FinishDeleteJob { .. }
+        | Command::Append { .. }
+        | Command::CommitAppend { .. } => unreachable!(),
+    }
 }
 
+type CommandBranch = Branch8<
+    PutCommand,
+    DeleteCommand,
+    DeleteVersionCommand,
+    DeleteByRangeCommand,
+    DeleteByPrefixCommand,
+    TombstoneCommand,
+    UndeleteCommand,
+    (),
+>;
+
+// 五番目の要素はオブジェクトの大きさで、導入前のコマンドでは0となる.
+// 六番目の要素は過去のバージョンの保持期間(秒単位)で、バージョン管理されていない場合は0.
+// 最後の要素は適用時に確認する割り当て量で、上限が設けられていない場合は`None`.
+//
+// フィールド番号 5 は欠番. 古いサーバが書き込みとして解釈してしまわないように、
+// 追記は書き込みの変種ではなく、独立したコマンド(`append_command_decoder`)として表現する.
 #[allow(dead_code)]
-pub type PutCommand = (String, Vec<u8>, Expect, u64);
+pub type PutCommand = (String, Vec<u8>, Precondition, u64, u64, u64, Option<Quota>);
 
 #[allow(dead_code)]
-pub type DeleteCommand = (String, Expect);
+pub type DeleteCommand = (String, Precondition);
 
 #[allow(dead_code)]
 pub type DeleteVersionCommand = u64;
@@ -90,14 +219,23 @@ pub type DeleteByRangeCommand = (u64, u64);
 #[allow(dead_code)]
 pub type DeleteByPrefixCommand = String;
 
+#[allow(dead_code)]
+pub type TombstoneCommand = (String, Precondition, u64);
+
+#[allow(dead_code)]
+pub type UndeleteCommand = String;
+
 pub fn put_command_decoder() -> impl MessageDecode<Item = PutCommand> {
     let base = protobuf_message_decoder![
         (F1, StringDecoder::new()),
         (F2, BytesDecoder::new()),
-        (F3, expect_decoder(), message),
-        (F4, Uint64Decoder::new())
+        (F3, precondition_decoder(), message),
+        (F4, Uint64Decoder::new()),
+        (F6, Uint64Decoder::new()),
+        (F7, Uint64Decoder::new()),
+        (F8, quota_decoder(), message)
     ];
-    base.map(|x| (x.0, x.1, x.2.unwrap_or(Expect::Any), x.3))
+    base.map(|x| (x.0, x.1, x.2.unwrap_or_default(), x.3, x.4, x.5, x.6))
 }
 
 pub fn put_command_encoder(
@@ -105,22 +243,44 @@ pub fn put_command_encoder(
     protobuf_message_encoder![
         (F1, StringEncoder::new()),
         (F2, BytesEncoder::new()),
-        (F3, expect_encoder(), required_unsized_message),
-        (F4, Uint64Encoder::new())
+        (F3, precondition_encoder(), required_unsized_message),
+        (F4, Uint64Encoder::new()),
+        (F6, Uint64Encoder::new()),
+        (F7, Uint64Encoder::new()),
+        (F8, quota_encoder(), message)
     ]
 }
 
+// 上限は「値 + 1」で表現し、0 は上限が設けられていないことを表す.
+pub fn quota_decoder() -> impl MessageDecode<Item = Quota> {
+    let base = protobuf_message_decoder![(F1, Uint64Decoder::new()), (F2, Uint64Decoder::new())];
+    base.map(|(max_objects, max_bytes)| Quota {
+        max_objects: max_objects.checked_sub(1),
+        max_bytes: max_bytes.checked_sub(1),
+    })
+}
+
+pub fn quota_encoder() -> impl SizedEncode<Item = Quota> + MessageEncode<Item = Quota> {
+    let base = protobuf_message_encoder![(F1, Uint64Encoder::new()), (F2, Uint64Encoder::new())];
+    base.map_from(|quota: Quota| {
+        let encode = |max: Option<u64>| max.map_or(0, |max| max.saturating_add(1));
+        (encode(quota.max_objects), encode(quota.max_bytes))
+    })
+}
+
 pub fn delete_command_decoder() -> impl MessageDecode<Item = DeleteCommand> {
-    let base =
-        protobuf_message_decoder![(F1, StringDecoder::new()), (F2, expect_decoder(), message)];
-    base.map(|x| (x.0, x.1.unwrap_or(Expect::Any)))
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, precondition_decoder(), message)
+    ];
+    base.map(|x| (x.0, x.1.unwrap_or_default()))
 }
 
 pub fn delete_command_encoder(
 ) -> impl SizedEncode<Item = DeleteCommand> + MessageEncode<Item = DeleteCommand> {
     protobuf_message_encoder![
         (F1, StringEncoder::new()),
-        (F2, expect_encoder(), required_unsized_message)
+        (F2, precondition_encoder(), required_unsized_message)
     ]
 }
 
@@ -152,36 +312,336 @@ pub fn delete_by_prefix_command_encoder(
     protobuf_message_encoder![(F1, StringEncoder::new())]
 }
 
-pub fn expect_decoder() -> impl MessageDecode<Item = Expect> {
+// 削除ジョブのコマンド(`Command::StartDeleteJob`、`Command::DeleteObjects`および`Command::FinishDeleteJob`).
+//
+// 削除猶予期間(秒単位)が0の場合には、オブジェクトを即座に削除する.
+// コマンド全体の大きさを事前に計算できるように、オブジェクト群は一つのバイト列にエンコードする.
+pub fn delete_job_command_decoder() -> impl MessageDecode<Item = Command> {
+    let start = protobuf_message_decoder![
+        (F1, Uint64Decoder::new()),
+        (F2, StringDecoder::new()),
+        (F3, Uint64Decoder::new())
+    ];
+    let batch = protobuf_message_decoder![(F1, Uint64Decoder::new()), (F2, BytesDecoder::new())];
+    let finish = protobuf_message_decoder![(F1, Uint64Decoder::new()), (F2, BoolDecoder::new())];
+    let base = protobuf_message_decoder![(
+        required_oneof,
+        (F1, start, message),
+        (F2, batch, message),
+        (F3, finish, message)
+    )];
+    base.try_map(|x| -> bytecodec::Result<_> {
+        Ok(match x {
+            Branch3::A((job_id, prefix, retention)) => Command::StartDeleteJob {
+                job_id,
+                prefix: ObjectPrefix(prefix),
+                retention: if retention == 0 {
+                    None
+                } else {
+                    Some(Seconds(retention))
+                },
+            },
+            Branch3::B((job_id, ids)) => Command::DeleteObjects {
+                job_id,
+                object_ids: track!(decode_object_ids(&ids))?,
+            },
+            Branch3::C((job_id, cancelled)) => Command::FinishDeleteJob { job_id, cancelled },
+        })
+    })
+}
+
+pub fn delete_job_command_encoder(
+) -> impl SizedEncode<Item = Command> + MessageEncode<Item = Command> {
+    let start = protobuf_message_encoder![
+        (F1, Uint64Encoder::new()),
+        (F2, StringEncoder::new()),
+        (F3, Uint64Encoder::new())
+    ];
+    let batch = protobuf_message_encoder![(F1, Uint64Encoder::new()), (F2, BytesEncoder::new())];
+    let finish = protobuf_message_encoder![(F1, Uint64Encoder::new()), (F2, BoolEncoder::new())];
+    let base = protobuf_message_encoder![(
+        required_oneof,
+        (F1, start, message),
+        (F2, batch, message),
+        (F3, finish, message)
+    )];
+    base.map_from(|x: Command| match x {
+        Command::StartDeleteJob {
+            job_id,
+            prefix,
+            retention,
+        } => Branch3::A((job_id, prefix.0, retention.map_or(0, |r| r.0))),
+        Command::DeleteObjects { job_id, object_ids } => {
+            Branch3::B((job_id, encode_object_ids(&object_ids)))
+        }
+        Command::FinishDeleteJob { job_id, cancelled } => Branch3::C((job_id, cancelled)),
+        _ => unreachable!(),
+    })
+}
+
+// 追記のコマンド(`Command::Append`および`Command::CommitAppend`).
+//
+// 古いサーバはこのフィールドを知らず、コマンドのデコードに失敗するので、
+// `ClusterFeature::Append`が有効な場合にのみ提案される.
+pub fn append_command_decoder() -> impl MessageDecode<Item = Command> {
+    let reserve = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, precondition_decoder(), message),
+        (F3, Uint64Decoder::new()),
+        (F4, Uint64Decoder::new())
+    ];
+    let commit = protobuf_message_decoder![(F1, StringDecoder::new()), (F2, Uint64Decoder::new())];
+    let base = protobuf_message_decoder![(
+        required_oneof,
+        (F1, reserve, message),
+        (F2, commit, message)
+    )];
+    base.map(|x| match x {
+        Branch2::A((object_id, expect, size, put_content_timeout)) => Command::Append {
+            object_id,
+            expect: expect.unwrap_or_default(),
+            size,
+            put_content_timeout: Seconds(put_content_timeout),
+        },
+        Branch2::B((object_id, version)) => Command::CommitAppend {
+            object_id,
+            version: ObjectVersion(version),
+        },
+    })
+}
+
+pub fn append_command_encoder() -> impl SizedEncode<Item = Command> + MessageEncode<Item = Command>
+{
+    let reserve = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, precondition_encoder(), required_unsized_message),
+        (F3, Uint64Encoder::new()),
+        (F4, Uint64Encoder::new())
+    ];
+    let commit = protobuf_message_encoder![(F1, StringEncoder::new()), (F2, Uint64Encoder::new())];
+    let base = protobuf_message_encoder![(
+        required_oneof,
+        (F1, reserve, message),
+        (F2, commit, message)
+    )];
+    base.map_from(|x: Command| match x {
+        Command::Append {
+            object_id,
+            expect,
+            size,
+            put_content_timeout,
+        } => Branch2::A((object_id, expect, size, put_content_timeout.0)),
+        Command::CommitAppend { object_id, version } => Branch2::B((object_id, version.0)),
+        _ => unreachable!(),
+    })
+}
+
+// 再配置ジョブのコマンド(`Command::Relayout`).
+//
+// いずれの分岐も、先頭のフィールドは再配置先のデバイスグループの世代.
+pub fn relayout_command_decoder() -> impl MessageDecode<Item = RelayoutCommand> {
+    let progress = protobuf_message_decoder![
+        (F1, Uint64Decoder::new()),
+        (F2, Uint64Decoder::new()),
+        (F3, Uint64Decoder::new())
+    ];
+    let base = protobuf_message_decoder![(
+        required_oneof,
+        (
+            F1,
+            protobuf_message_decoder![(F1, Uint64Decoder::new())],
+            message
+        ),
+        (F2, progress, message),
+        (
+            F3,
+            protobuf_message_decoder![(F1, Uint64Decoder::new())],
+            message
+        ),
+        (
+            F4,
+            protobuf_message_decoder![(F1, Uint64Decoder::new())],
+            message
+        )
+    )];
+    base.try_map(|x| -> bytecodec::Result<_> {
+        Ok(match x {
+            Branch4::A(generation) => RelayoutCommand::Start {
+                generation: track!(decode_generation(generation))?,
+            },
+            Branch4::B((generation, cursor, copied)) => RelayoutCommand::Progress {
+                generation: track!(decode_generation(generation))?,
+                cursor: ObjectVersion(cursor),
+                copied,
+            },
+            Branch4::C(generation) => RelayoutCommand::Freeze {
+                generation: track!(decode_generation(generation))?,
+            },
+            Branch4::D(generation) => RelayoutCommand::Finish {
+                generation: track!(decode_generation(generation))?,
+            },
+        })
+    })
+}
+
+pub fn relayout_command_encoder(
+) -> impl SizedEncode<Item = RelayoutCommand> + MessageEncode<Item = RelayoutCommand> {
+    let progress = protobuf_message_encoder![
+        (F1, Uint64Encoder::new()),
+        (F2, Uint64Encoder::new()),
+        (F3, Uint64Encoder::new())
+    ];
+    let base = protobuf_message_encoder![(
+        required_oneof,
+        (
+            F1,
+            protobuf_message_encoder![(F1, Uint64Encoder::new())],
+            message
+        ),
+        (F2, progress, message),
+        (
+            F3,
+            protobuf_message_encoder![(F1, Uint64Encoder::new())],
+            message
+        ),
+        (
+            F4,
+            protobuf_message_encoder![(F1, Uint64Encoder::new())],
+            message
+        )
+    )];
+    base.map_from(|x: RelayoutCommand| match x {
+        RelayoutCommand::Start { generation } => Branch4::A(u64::from(generation)),
+        RelayoutCommand::Progress {
+            generation,
+            cursor,
+            copied,
+        } => Branch4::B((u64::from(generation), cursor.0, copied)),
+        RelayoutCommand::Freeze { generation } => Branch4::C(u64::from(generation)),
+        RelayoutCommand::Finish { generation } => Branch4::D(u64::from(generation)),
+    })
+}
+
+fn decode_generation(generation: u64) -> bytecodec::Result<u8> {
+    track_assert!(
+        generation <= u64::from(u8::max_value()),
+        bytecodec::ErrorKind::InvalidInput,
+        "Too large generation: {}",
+        generation
+    );
+    Ok(generation as u8)
+}
+
+// 各 ID を、長さ(32bit ビッグエンディアン)とバイト列の組として連結する.
+fn encode_object_ids(ids: &[String]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for id in ids {
+        let mut len = [0; 4];
+        BigEndian::write_u32(&mut len, id.len() as u32);
+        bytes.extend_from_slice(&len);
+        bytes.extend_from_slice(id.as_bytes());
+    }
+    bytes
+}
+
+fn decode_object_ids(mut bytes: &[u8]) -> bytecodec::Result<Vec<String>> {
+    let mut ids = Vec::new();
+    while !bytes.is_empty() {
+        track_assert!(bytes.len() >= 4, bytecodec::ErrorKind::InvalidInput);
+        let len = BigEndian::read_u32(bytes) as usize;
+        track_assert!(bytes.len() >= 4 + len, bytecodec::ErrorKind::InvalidInput);
+        let id = track!(String::from_utf8(bytes[4..4 + len].to_vec())
+            .map_err(|e| bytecodec::ErrorKind::InvalidInput.cause(e)))?;
+        ids.push(id);
+        bytes = &bytes[4 + len..];
+    }
+    Ok(ids)
+}
+
+pub fn tombstone_command_decoder() -> impl MessageDecode<Item = TombstoneCommand> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, precondition_decoder(), message),
+        (F3, Uint64Decoder::new())
+    ];
+    base.map(|x| (x.0, x.1.unwrap_or_default(), x.2))
+}
+
+pub fn tombstone_command_encoder(
+) -> impl SizedEncode<Item = TombstoneCommand> + MessageEncode<Item = TombstoneCommand> {
+    protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, precondition_encoder(), required_unsized_message),
+        (F3, Uint64Encoder::new())
+    ]
+}
+
+pub fn undelete_command_decoder() -> impl MessageDecode<Item = UndeleteCommand> {
+    protobuf_message_decoder![(F1, StringDecoder::new())]
+}
+
+pub fn undelete_command_encoder(
+) -> impl SizedEncode<Item = UndeleteCommand> + MessageEncode<Item = UndeleteCommand> {
+    protobuf_message_encoder![(F1, StringEncoder::new())]
+}
+
+// `Expect`で表現できる条件は、従来と同じ形式でエンコードされる.
+// F4 から F6 は古いノードでは`Expect::Any`と解釈されてしまうので、提案するコマンドには含めない.
+// (リーダが提案前に`Expect`に変換する. `Machine::resolve_precondition`を参照)
+pub fn precondition_decoder() -> impl MessageDecode<Item = Precondition> {
     let base = protobuf_message_decoder![(
         oneof,
         (F1, versions_decoder(), message),
         (F2, versions_decoder(), message),
-        (F3, empty_decoder(), message)
+        (F3, empty_decoder(), message),
+        (F4, version_range_decoder(), message),
+        (F5, Uint64Decoder::new()),
+        (F6, Uint64Decoder::new())
     )];
     base.map(|x| match x {
-        Some(Branch3::A(versions)) => Expect::IfMatch(versions),
-        Some(Branch3::B(versions)) => Expect::IfNoneMatch(versions),
-        Some(Branch3::C(_)) => Expect::None,
-        None => Expect::Any,
+        Some(Branch6::A(versions)) => Precondition::Expect(Expect::IfMatch(versions)),
+        Some(Branch6::B(versions)) => Precondition::Expect(Expect::IfNoneMatch(versions)),
+        Some(Branch6::C(_)) => Precondition::Expect(Expect::None),
+        Some(Branch6::D((from, to))) => Precondition::IfMatchRange {
+            from: ObjectVersion(from),
+            to: ObjectVersion(to),
+        },
+        Some(Branch6::E(version)) => Precondition::IfNewerThan(ObjectVersion(version)),
+        Some(Branch6::F(since)) => Precondition::IfUnmodifiedSince(since),
+        None => Precondition::Expect(Expect::Any),
     })
 }
 
-pub fn expect_encoder() -> impl MessageEncode<Item = Expect> {
+pub fn precondition_encoder() -> impl MessageEncode<Item = Precondition> {
     let base = protobuf_message_encoder![(
         oneof,
         (F1, versions_encoder(), unsized_message),
         (F2, versions_encoder(), unsized_message),
-        (F3, empty_encoder(), message)
+        (F3, empty_encoder(), message),
+        (F4, version_range_encoder(), message),
+        (F5, Uint64Encoder::new()),
+        (F6, Uint64Encoder::new())
     )];
-    base.map_from(|x: Expect| match x {
-        Expect::IfMatch(versions) => Some(Branch3::A(versions)),
-        Expect::IfNoneMatch(versions) => Some(Branch3::B(versions)),
-        Expect::None => Some(Branch3::C(())),
-        Expect::Any => None,
+    base.map_from(|x: Precondition| match x {
+        Precondition::Expect(Expect::IfMatch(versions)) => Some(Branch6::A(versions)),
+        Precondition::Expect(Expect::IfNoneMatch(versions)) => Some(Branch6::B(versions)),
+        Precondition::Expect(Expect::None) => Some(Branch6::C(())),
+        Precondition::Expect(Expect::Any) => None,
+        Precondition::IfMatchRange { from, to } => Some(Branch6::D((from.0, to.0))),
+        Precondition::IfNewerThan(version) => Some(Branch6::E(version.0)),
+        Precondition::IfUnmodifiedSince(since) => Some(Branch6::F(since)),
     })
 }
 
+pub fn version_range_decoder() -> impl MessageDecode<Item = (u64, u64)> {
+    protobuf_message_decoder![(F1, Uint64Decoder::new()), (F2, Uint64Decoder::new())]
+}
+
+pub fn version_range_encoder(
+) -> impl SizedEncode<Item = (u64, u64)> + MessageEncode<Item = (u64, u64)> {
+    protobuf_message_encoder![(F1, Uint64Encoder::new()), (F2, Uint64Encoder::new())]
+}
+
 pub fn versions_decoder() -> impl MessageDecode<Item = Vec<ObjectVersion>> {
     // FIXME: `collect()`を呼ばなくて済むようにする
     let base = protobuf_message_decoder![(F1, Uint64Decoder::new(), packed)];
@@ -201,35 +661,370 @@ pub fn empty_encoder() -> impl SizedEncode<Item = ()> + MessageEncode<Item = ()>
     protobuf_message_encoder![]
 }
 
-pub fn snapshot_decoder() -> impl MessageDecode<Item = Snapshot> {
+/// スナップショットと、削除猶予期間中のオブジェクト群、オブジェクトの大きさ、
+/// 過去のバージョン群、バージョン管理されているオブジェクトの更新時刻、削除ジョブ群、再配置ジョブ、
+/// および内容の保存を待っている追記部分群とウィットネスのスナップショットかどうかの組.
+///
+/// 削除猶予期間(ないし大きさ等)の導入前に作成されたスナップショットをデコードした場合には、
+/// 該当する要素は空となる.
+pub type SnapshotWithTombstones = (
+    Snapshot,
+    Vec<(String, Tombstone)>,
+    Vec<(String, u64)>,
+    Vec<(String, Revision)>,
+    Vec<(String, u64)>,
+    Vec<DeleteJob>,
+    Option<RelayoutJob>,
+    (Vec<PendingPart>, bool),
+);
+
+pub fn snapshot_decoder() -> impl MessageDecode<Item = SnapshotWithTombstones> {
     let patricia =
         CustomBytesDecoder::new(NodeDecoder::new(U64beDecoder::new().map(ObjectVersion)));
-    let base = protobuf_message_decoder![(
-        required_oneof,
-        (F1, objects_decoder(), message),
-        (F2, patricia)
-    )];
-    base.map(|x| match x {
-        Branch2::A(x) => Snapshot::Assoc(x),
-        Branch2::B(x) => Snapshot::Patricia(x.into()),
-    })
+    let base = protobuf_message_decoder![
+        (F3, tombstones_decoder(), message),
+        (F4, sizes_decoder(), message),
+        (F5, history_decoder(), message),
+        (F6, sizes_decoder(), message),
+        (F7, delete_jobs_decoder(), message),
+        (F8, relayout_job_decoder(), message),
+        (F9, pending_parts_and_witness_decoder(), message),
+        (
+            required_oneof,
+            (F1, objects_decoder(), message),
+            (F2, patricia)
+        )
+    ];
+    base.map(
+        |(tombstones, sizes, history, mtimes, jobs, relayout, pending, x)| {
+            let snapshot = match x {
+                Branch2::A(x) => Snapshot::Assoc(x),
+                Branch2::B(x) => Snapshot::Patricia(x.into()),
+            };
+            (
+                snapshot,
+                tombstones.unwrap_or_default(),
+                sizes.unwrap_or_default(),
+                history.unwrap_or_default(),
+                mtimes.unwrap_or_default(),
+                jobs.unwrap_or_default(),
+                relayout,
+                pending.unwrap_or_default(),
+            )
+        },
+    )
 }
 
-pub fn snapshot_encoder() -> impl MessageEncode<Item = Snapshot> {
+pub fn snapshot_encoder() -> impl MessageEncode<Item = SnapshotWithTombstones> {
     let patricia = CustomBytesEncoder::new(
         NodeEncoder::new(U64beEncoder::new().map_from(|v: ObjectVersion| v.0)).pre_encode(),
     );
-    let base = protobuf_message_encoder![(
-        required_oneof,
-        (F1, objects_encoder(), unsized_message),
-        (F2, patricia)
-    )];
-    base.map_from(|x: Snapshot| match x {
-        Snapshot::Assoc(x) => Branch2::A(x),
-        Snapshot::Patricia(x) => Branch2::B(x.into()),
+    // NOTE: コマンドのタイムスタンプと同様に、oneof 以外のフィールドは oneof よりも前にエンコードする.
+    let base = protobuf_message_encoder![
+        (F3, tombstones_encoder(), unsized_message),
+        (F4, sizes_encoder(), unsized_message),
+        (F5, history_encoder(), unsized_message),
+        (F6, sizes_encoder(), unsized_message),
+        (F7, delete_jobs_encoder(), unsized_message),
+        (F8, relayout_job_encoder(), message),
+        (F9, pending_parts_and_witness_encoder(), unsized_message),
+        (
+            required_oneof,
+            (F1, objects_encoder(), unsized_message),
+            (F2, patricia)
+        )
+    ];
+    base.map_from(
+        |(x, tombstones, sizes, history, mtimes, jobs, relayout, pending): SnapshotWithTombstones| {
+            let snapshot = match x {
+                Snapshot::Assoc(x) => Branch2::A(x),
+                Snapshot::Patricia(x) => Branch2::B(x.into()),
+            };
+            // NOTE: 削除猶予期間中のオブジェクトは`ClusterFeature::Tombstones`が有効な場合にのみ生成されるので、
+            //       空の場合にはフィールド自体を省略して、古いバージョンのノードと同じ形式にする
+            let tombstones = if tombstones.is_empty() {
+                None
+            } else {
+                Some(tombstones)
+            };
+            (
+                tombstones,
+                Some(sizes),
+                Some(history),
+                Some(mtimes),
+                Some(jobs),
+                relayout,
+                Some(pending),
+                snapshot,
+            )
+        },
+    )
+}
+
+// 状態は 0 がコピー中、1 が凍結中、2 が終了済み.
+pub fn relayout_job_decoder() -> impl MessageDecode<Item = RelayoutJob> {
+    let base = protobuf_message_decoder![
+        (F1, Uint64Decoder::new()),
+        (F2, Uint64Decoder::new()),
+        (F3, Uint64Decoder::new()),
+        (F4, Uint64Decoder::new()),
+        (F5, Uint64Decoder::new())
+    ];
+    base.try_map(|x| -> bytecodec::Result<_> {
+        let state = match x.4 {
+            0 => RelayoutState::Copying,
+            1 => RelayoutState::Frozen,
+            2 => RelayoutState::Finished,
+            n => track_panic!(bytecodec::ErrorKind::InvalidInput, "Unknown state: {}", n),
+        };
+        Ok(RelayoutJob {
+            generation: track!(decode_generation(x.0))?,
+            started_at: ObjectVersion(x.1),
+            cursor: ObjectVersion(x.2),
+            copied: x.3,
+            state,
+        })
     })
 }
 
+pub fn relayout_job_encoder(
+) -> impl SizedEncode<Item = RelayoutJob> + MessageEncode<Item = RelayoutJob> {
+    let base = protobuf_message_encoder![
+        (F1, Uint64Encoder::new()),
+        (F2, Uint64Encoder::new()),
+        (F3, Uint64Encoder::new()),
+        (F4, Uint64Encoder::new()),
+        (F5, Uint64Encoder::new())
+    ];
+    base.map_from(|j: RelayoutJob| {
+        let state = match j.state {
+            RelayoutState::Copying => 0,
+            RelayoutState::Frozen => 1,
+            RelayoutState::Finished => 2,
+        };
+        (
+            u64::from(j.generation),
+            j.started_at.0,
+            j.cursor.0,
+            j.copied,
+            state,
+        )
+    })
+}
+
+pub fn delete_jobs_decoder() -> impl MessageDecode<Item = Vec<DeleteJob>> {
+    let progress = protobuf_message_decoder![
+        (F1, Uint64Decoder::new()),
+        (F2, Uint64Decoder::new()),
+        (F3, Uint64Decoder::new())
+    ];
+    let job = protobuf_message_decoder![
+        (F1, Uint64Decoder::new()),
+        (F2, StringDecoder::new()),
+        (F3, Uint64Decoder::new()),
+        (F4, progress, message),
+        (F5, Uint64Decoder::new()),
+        (F6, Uint64Decoder::new()),
+        (F7, Uint64Decoder::new())
+    ];
+    let job = job.try_map(|x| -> bytecodec::Result<_> {
+        let state = match x.2 {
+            0 => DeleteJobState::Running,
+            1 => DeleteJobState::Completed,
+            2 => DeleteJobState::Cancelled,
+            n => track_panic!(bytecodec::ErrorKind::InvalidInput, "Unknown state: {}", n),
+        };
+        let (total, processed, deleted) = x.3.unwrap_or_default();
+        Ok(DeleteJob {
+            status: DeleteJobStatus {
+                job_id: x.0,
+                prefix: ObjectPrefix(x.1),
+                state,
+                total,
+                processed,
+                deleted,
+            },
+            started_at: ObjectVersion(x.4),
+            retention: if x.5 == 0 { None } else { Some(Seconds(x.5)) },
+            finished_at: if state == DeleteJobState::Running {
+                None
+            } else {
+                Some(ObjectVersion(x.6))
+            },
+        })
+    });
+    protobuf_message_decoder![(F1, job, repeated_message)]
+}
+
+pub fn delete_jobs_encoder() -> impl MessageEncode<Item = Vec<DeleteJob>> {
+    let progress = protobuf_message_encoder![
+        (F1, Uint64Encoder::new()),
+        (F2, Uint64Encoder::new()),
+        (F3, Uint64Encoder::new())
+    ];
+    let job = protobuf_message_encoder![
+        (F1, Uint64Encoder::new()),
+        (F2, StringEncoder::new()),
+        (F3, Uint64Encoder::new()),
+        (F4, progress, message),
+        (F5, Uint64Encoder::new()),
+        (F6, Uint64Encoder::new()),
+        (F7, Uint64Encoder::new())
+    ];
+    let job = job.map_from(|j: DeleteJob| {
+        let state = match j.status.state {
+            DeleteJobState::Running => 0,
+            DeleteJobState::Completed => 1,
+            DeleteJobState::Cancelled => 2,
+        };
+        (
+            j.status.job_id,
+            j.status.prefix.0,
+            state,
+            Some((j.status.total, j.status.processed, j.status.deleted)),
+            j.started_at.0,
+            j.retention.map_or(0, |r| r.0),
+            j.finished_at.map_or(0, |v| v.0),
+        )
+    });
+    protobuf_message_encoder![(F1, job, repeated_message)]
+}
+
+pub fn sizes_decoder() -> impl MessageDecode<Item = Vec<(String, u64)>> {
+    let size = protobuf_message_decoder![(F1, StringDecoder::new()), (F2, Uint64Decoder::new())];
+    protobuf_message_decoder![(F1, size, repeated_message)]
+}
+
+pub fn sizes_encoder() -> impl MessageEncode<Item = Vec<(String, u64)>> {
+    let size = protobuf_message_encoder![(F1, StringEncoder::new()), (F2, Uint64Encoder::new())];
+    protobuf_message_encoder![(F1, size, repeated_message)]
+}
+
+pub fn tombstones_decoder() -> impl MessageDecode<Item = Vec<(String, Tombstone)>> {
+    let tombstone = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, Uint64Decoder::new()),
+        (F3, BytesDecoder::new()),
+        (F4, Uint64Decoder::new()),
+        (F5, Uint64Decoder::new()),
+        (F6, Uint64Decoder::new())
+    ];
+    let tombstone = tombstone.map(|x| {
+        let tombstone = Tombstone {
+            version: ObjectVersion(x.1),
+            data: x.2,
+            size: x.4,
+            modified_at: x.5,
+            expires_at: x.3,
+        };
+        (x.0, tombstone)
+    });
+    protobuf_message_decoder![(F1, tombstone, repeated_message)]
+}
+
+pub fn tombstones_encoder() -> impl MessageEncode<Item = Vec<(String, Tombstone)>> {
+    let tombstone = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, Uint64Encoder::new()),
+        (F3, BytesEncoder::new()),
+        (F4, Uint64Encoder::new()),
+        (F5, Uint64Encoder::new()),
+        (F6, Uint64Encoder::new())
+    ];
+    let tombstone = tombstone.map_from(|(id, t): (String, Tombstone)| {
+        (id, t.version.0, t.data, t.expires_at, t.size, t.modified_at)
+    });
+    protobuf_message_encoder![(F1, tombstone, repeated_message)]
+}
+
+// NOTE: `protobuf_codec`のメッセージは最大で 8 つのフィールドしか扱えないので、
+// ウィットネスかどうかは、追記部分群と同じメッセージに含める
+pub fn pending_parts_and_witness_decoder() -> impl MessageDecode<Item = (Vec<PendingPart>, bool)> {
+    let part = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, Uint64Decoder::new()),
+        (F3, Uint64Decoder::new()),
+        (F4, Uint64Decoder::new()),
+        (F5, Uint64Decoder::new())
+    ];
+    let part = part.map(|x| PendingPart {
+        object_id: x.0,
+        version: ObjectVersion(x.1),
+        size: x.2,
+        put_content_timeout: Seconds(x.3),
+        expires_at: x.4,
+    });
+    protobuf_message_decoder![(F1, part, repeated_message), (F2, BoolDecoder::new())]
+}
+
+pub fn pending_parts_and_witness_encoder() -> impl MessageEncode<Item = (Vec<PendingPart>, bool)> {
+    let part = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, Uint64Encoder::new()),
+        (F3, Uint64Encoder::new()),
+        (F4, Uint64Encoder::new()),
+        (F5, Uint64Encoder::new())
+    ];
+    let part = part.map_from(|p: PendingPart| {
+        (
+            p.object_id,
+            p.version.0,
+            p.size,
+            p.put_content_timeout.0,
+            p.expires_at,
+        )
+    });
+    protobuf_message_encoder![(F1, part, repeated_message), (F2, BoolEncoder::new())]
+}
+
+pub fn history_decoder() -> impl MessageDecode<Item = Vec<(String, Revision)>> {
+    let revision = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, Uint64Decoder::new()),
+        (F3, BytesDecoder::new()),
+        (F4, Uint64Decoder::new()),
+        (F5, Uint64Decoder::new()),
+        (F6, Uint64Decoder::new()),
+        (F7, Uint64Decoder::new())
+    ];
+    let revision = revision.map(|x| {
+        let revision = Revision {
+            version: ObjectVersion(x.1),
+            data: x.2,
+            size: x.3,
+            modified_at: x.4,
+            expires_at: x.5,
+            deleted_at: x.6,
+        };
+        (x.0, revision)
+    });
+    protobuf_message_decoder![(F1, revision, repeated_message)]
+}
+
+pub fn history_encoder() -> impl MessageEncode<Item = Vec<(String, Revision)>> {
+    let revision = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, Uint64Encoder::new()),
+        (F3, BytesEncoder::new()),
+        (F4, Uint64Encoder::new()),
+        (F5, Uint64Encoder::new()),
+        (F6, Uint64Encoder::new()),
+        (F7, Uint64Encoder::new())
+    ];
+    let revision = revision.map_from(|(id, r): (String, Revision)| {
+        (
+            id,
+            r.version.0,
+            r.data,
+            r.size,
+            r.modified_at,
+            r.expires_at,
+            r.deleted_at,
+        )
+    });
+    protobuf_message_encoder![(F1, revision, repeated_message)]
+}
+
 pub fn objects_decoder() -> impl MessageDecode<Item = Vec<(String, Metadata)>> {
     let map = protobuf_message_decoder![
         (F1, StringDecoder::new()),
@@ -258,3 +1053,289 @@ pub fn metadata_encoder() -> impl SizedEncode<Item = Metadata> + MessageEncode<I
     let base = protobuf_message_encoder![(F1, Uint64Encoder::new()), (F2, BytesEncoder::new())];
     base.map_from(|x: Metadata| (x.version.0, x.data))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use patricia_tree::PatriciaMap;
+    use protobuf_codec::field::branch::Branch5;
+    use trackable::result::TestResult;
+
+    #[test]
+    fn command_with_timestamp_works() -> TestResult {
+        let command = Command::Delete {
+            object_id: "foo".to_owned(),
+            expect: Expect::Any.into(),
+        };
+        let timestamp = HybridTimestamp::new(1_500_000_000_000, 3);
+        let bytes = track!(command_encoder().encode_into_bytes((command, timestamp)))?;
+        let (decoded, decoded_timestamp) = track!(command_decoder().decode_from_bytes(&bytes))?;
+        assert_eq!(decoded_timestamp, timestamp);
+        match decoded {
+            Command::Delete { object_id, .. } => assert_eq!(object_id, "foo"),
+            other => panic!("unexpected command: {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn command_without_timestamp_is_decodable() -> TestResult {
+        // タイムスタンプ導入前のコマンドは F6 を含まない
+        let mut legacy_encoder = protobuf_message_encoder![(
+            required_oneof,
+            (F1, put_command_encoder(), message),
+            (F2, delete_command_encoder(), message),
+            (F3, delete_version_command_encoder(), message),
+            (F4, delete_by_range_command_encoder(), message),
+            (F5, delete_by_prefix_command_encoder(), message)
+        )];
+        let bytes = track!(legacy_encoder.encode_into_bytes(Branch5::C(10)))?;
+        let (decoded, timestamp) = track!(command_decoder().decode_from_bytes(&bytes))?;
+        assert!(timestamp.is_unknown());
+        match decoded {
+            Command::DeleteByVersion { object_version } => {
+                assert_eq!(object_version, ObjectVersion(10))
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn delete_job_commands_are_not_decodable_by_legacy_nodes() -> TestResult {
+        // 古いノードが、削除ジョブや追記のコマンドを接頭辞指定での削除や書き込み等と解釈してしまわないことを確認する
+        let commands = vec![
+            Command::DeleteObjects {
+                job_id: 1,
+                object_ids: vec!["foo/1".to_owned()],
+            },
+            Command::Append {
+                object_id: "foo".to_owned(),
+                expect: Expect::Any.into(),
+                size: 10,
+                put_content_timeout: Seconds(30),
+            },
+            Command::CommitAppend {
+                object_id: "foo".to_owned(),
+                version: ObjectVersion(5),
+            },
+        ];
+        let timestamp = HybridTimestamp::new(1_500_000_000_000, 0);
+        for command in commands {
+            let bytes = track!(command_encoder().encode_into_bytes((command, timestamp)))?;
+            let mut legacy_decoder = protobuf_message_decoder![(
+                required_oneof,
+                (F1, put_command_decoder(), message),
+                (F2, delete_command_decoder(), message),
+                (F3, delete_version_command_decoder(), message),
+                (F4, delete_by_range_command_decoder(), message),
+                (F5, delete_by_prefix_command_decoder(), message)
+            )];
+            assert!(legacy_decoder.decode_from_bytes(&bytes).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn tombstone_commands_work() -> TestResult {
+        let timestamp = HybridTimestamp::new(1_500_000_000_000, 0);
+        let commands = vec![
+            Command::Tombstone {
+                object_id: "foo".to_owned(),
+                expect: Expect::IfMatch(vec![ObjectVersion(3)]).into(),
+                retention: Seconds(60),
+            },
+            Command::Undelete {
+                object_id: "foo".to_owned(),
+            },
+            Command::PurgeTombstones,
+            Command::Append {
+                object_id: "foo".to_owned(),
+                expect: Expect::IfMatch(vec![ObjectVersion(4)]).into(),
+                size: 1024,
+                put_content_timeout: Seconds(30),
+            },
+            Command::CommitAppend {
+                object_id: "foo".to_owned(),
+                version: ObjectVersion(5),
+            },
+            Command::DeleteByPrefix {
+                prefix: ObjectPrefix("foo/".to_owned()),
+            },
+            Command::StartDeleteJob {
+                job_id: 1,
+                prefix: ObjectPrefix("foo/".to_owned()),
+                retention: Some(Seconds(60)),
+            },
+            Command::DeleteObjects {
+                job_id: 1,
+                object_ids: vec!["foo/1".to_owned(), String::new(), "foo/2".to_owned()],
+            },
+            Command::FinishDeleteJob {
+                job_id: 1,
+                cancelled: true,
+            },
+            Command::Relayout(RelayoutCommand::Start { generation: 1 }),
+            Command::Relayout(RelayoutCommand::Progress {
+                generation: 1,
+                cursor: ObjectVersion(100),
+                copied: 30,
+            }),
+            Command::Relayout(RelayoutCommand::Freeze { generation: 1 }),
+            Command::Relayout(RelayoutCommand::Finish { generation: 7 }),
+        ];
+        for command in commands {
+            let expected = format!("{:?}", command);
+            let bytes = track!(command_encoder().encode_into_bytes((command, timestamp)))?;
+            let (decoded, _) = track!(command_decoder().decode_from_bytes(&bytes))?;
+            assert_eq!(format!("{:?}", decoded), expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn preconditions_work() -> TestResult {
+        let timestamp = HybridTimestamp::new(1_500_000_000_000, 0);
+        let preconditions = vec![
+            Precondition::Expect(Expect::None),
+            Precondition::IfMatchRange {
+                from: ObjectVersion(3),
+                to: ObjectVersion(10),
+            },
+            Precondition::IfNewerThan(ObjectVersion(5)),
+            Precondition::IfUnmodifiedSince(1_500_000_000),
+        ];
+        for precondition in preconditions {
+            let command = Command::Put {
+                object_id: "foo".to_owned(),
+                userdata: vec![1, 2, 3],
+                size: 1024,
+                expect: precondition.clone(),
+                put_content_timeout: Seconds(30),
+                history_retention: Some(Seconds(60)),
+                quota: Quota {
+                    max_objects: Some(0),
+                    max_bytes: None,
+                },
+            };
+            let bytes = track!(command_encoder().encode_into_bytes((command, timestamp)))?;
+            let (decoded, _) = track!(command_decoder().decode_from_bytes(&bytes))?;
+            match decoded {
+                Command::Put {
+                    expect,
+                    size,
+                    history_retention,
+                    quota,
+                    ..
+                } => {
+                    assert_eq!(expect, precondition);
+                    assert_eq!(size, 1024);
+                    assert_eq!(history_retention, Some(Seconds(60)));
+                    assert_eq!(quota.max_objects, Some(0));
+                    assert_eq!(quota.max_bytes, None);
+                }
+                other => panic!("unexpected command: {:?}", other),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_with_tombstones_works() -> TestResult {
+        let mut patricia = PatriciaMap::new();
+        patricia.insert("foo", ObjectVersion(1));
+        let tombstone = Tombstone {
+            version: ObjectVersion(2),
+            data: vec![1, 2, 3],
+            size: 30,
+            modified_at: 1_300_000_000_000,
+            expires_at: 1_500_000_000_000,
+        };
+        let revision = Revision {
+            version: ObjectVersion(0),
+            data: vec![],
+            size: 20,
+            modified_at: 1_400_000_000_000,
+            deleted_at: 1_450_000_000_000,
+            expires_at: 1_600_000_000_000,
+        };
+        let job = DeleteJob {
+            status: DeleteJobStatus {
+                job_id: 1,
+                prefix: ObjectPrefix("foo/".to_owned()),
+                state: DeleteJobState::Cancelled,
+                total: 10,
+                processed: 5,
+                deleted: 4,
+            },
+            started_at: ObjectVersion(3),
+            retention: Some(Seconds(60)),
+            finished_at: Some(ObjectVersion(8)),
+        };
+        let relayout = RelayoutJob {
+            generation: 3,
+            started_at: ObjectVersion(5),
+            cursor: ObjectVersion(7),
+            copied: 2,
+            state: RelayoutState::Frozen,
+        };
+        let pending = PendingPart {
+            object_id: "foo".to_owned(),
+            version: ObjectVersion(9),
+            size: 100,
+            put_content_timeout: Seconds(30),
+            expires_at: 1_700_000_000_000,
+        };
+        let snapshot = (
+            Snapshot::Patricia(patricia),
+            vec![("bar".to_owned(), tombstone.clone())],
+            vec![("foo".to_owned(), 10)],
+            vec![("foo".to_owned(), revision.clone())],
+            vec![("foo".to_owned(), 1_500_000_000_000)],
+            vec![job.clone()],
+            Some(relayout.clone()),
+            (vec![pending.clone()], true),
+        );
+        let bytes = track!(snapshot_encoder().encode_into_bytes(snapshot))?;
+        let (decoded, tombstones, sizes, history, mtimes, jobs, decoded_relayout, pending_parts) =
+            track!(snapshot_decoder().decode_from_bytes(&bytes))?;
+        match decoded {
+            Snapshot::Patricia(x) => assert_eq!(x.get("foo"), Some(&ObjectVersion(1))),
+            other => panic!("unexpected snapshot: {:?}", other),
+        }
+        assert_eq!(tombstones, vec![("bar".to_owned(), tombstone)]);
+        assert_eq!(sizes, vec![("foo".to_owned(), 10)]);
+        assert_eq!(history, vec![("foo".to_owned(), revision)]);
+        assert_eq!(mtimes, vec![("foo".to_owned(), 1_500_000_000_000)]);
+        assert_eq!(jobs, vec![job]);
+        assert_eq!(decoded_relayout, Some(relayout));
+        assert_eq!(pending_parts, (vec![pending], true));
+
+        // 削除猶予期間の導入前のスナップショットもデコードできる
+        let mut legacy_encoder = protobuf_message_encoder![(
+            required_oneof,
+            (F1, objects_encoder(), unsized_message),
+            (F2, StringEncoder::<String>::new())
+        )];
+        let bytes = track!(legacy_encoder.encode_into_bytes(Branch2::A(vec![(
+            "foo".to_owned(),
+            Metadata {
+                version: ObjectVersion(1),
+                data: vec![1],
+            }
+        )])))?;
+        let (decoded, tombstones, sizes, history, _, jobs, relayout, pending_parts) =
+            track!(snapshot_decoder().decode_from_bytes(&bytes))?;
+        match decoded {
+            Snapshot::Assoc(x) => assert_eq!(x.len(), 1),
+            other => panic!("unexpected snapshot: {:?}", other),
+        }
+        assert!(tombstones.is_empty());
+        assert!(sizes.is_empty());
+        assert!(history.is_empty());
+        assert!(jobs.is_empty());
+        assert!(relayout.is_none());
+        assert_eq!(pending_parts, (Vec::new(), false));
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! オブジェクトの数と大きさに関する割り当て量(quota).
+//!
+//! 割り当て量は、構成管理用の Raft クラスタにバケツの属性として登録され、
+//! バケツのセグメント数で均等に分割されて MDS のノード(= セグメント)毎に適用される.
+//!
+//! 書き込みの提案時にはリーダが確認し、クラスタ単位の機能フラグ`quota`が有効な場合には、
+//! 提案時の割り当て量がコマンドに含められ、コマンドの適用時にも全てのノードで同じように確認される.
+//! そのため、同時に発行された書き込みによって超過することはない.
+use atomic_immut::AtomicImmut;
+use std::ops::Add;
+use std::sync::Arc;
+
+/// 割り当て量.
+///
+/// `None`の項目には上限が設けられない.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    /// 保持可能なオブジェクトの最大数.
+    #[serde(default)]
+    pub max_objects: Option<u64>,
+
+    /// 保持可能なオブジェクトの、論理的な大きさ(バイト)の合計の最大値.
+    ///
+    /// 大きさを伴わない書き込み(追記を含む)は、大きさが0のものとして扱われる.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+}
+impl Quota {
+    /// 上限が一つも設けられていないかどうかを返す.
+    pub fn is_unlimited(&self) -> bool {
+        self.max_objects.is_none() && self.max_bytes.is_none()
+    }
+
+    /// バケツ全体に対する割り当て量を、`segments`個のセグメントに均等に分割する.
+    ///
+    /// 割り切れない場合には切り上げるので、セグメント毎の上限の合計は元の上限以上となる.
+    pub fn split(&self, segments: u16) -> Self {
+        let segments = u64::from(segments.max(1));
+        let split = |max: u64| max / segments + (max % segments).min(1);
+        Quota {
+            max_objects: self.max_objects.map(split),
+            max_bytes: self.max_bytes.map(split),
+        }
+    }
+}
+
+/// 実行中に変更可能な、ノード(= セグメント)の割り当て量.
+///
+/// `Clone`されたインスタンス間で状態が共有されるので、
+/// `set`による変更は、以降の書き込みの提案に即座に反映される.
+#[derive(Clone, Default)]
+pub struct SharedQuota {
+    inner: Arc<AtomicImmut<Quota>>,
+}
+impl SharedQuota {
+    /// 割り当て量を設定する.
+    pub fn set(&self, quota: Quota) {
+        self.inner.store(quota);
+    }
+
+    /// 現在の割り当て量を返す.
+    pub fn get(&self) -> Quota {
+        *self.inner.load()
+    }
+}
+impl Add for Quota {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        let add = |x: Option<u64>, y: Option<u64>| x.and_then(|x| y.map(|y| x.saturating_add(y)));
+        Quota {
+            max_objects: add(self.max_objects, other.max_objects),
+            max_bytes: add(self.max_bytes, other.max_bytes),
+        }
+    }
+}
+
+/// 使用量と、その時点で適用されている割り当て量.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    /// オブジェクトの数.
+    pub objects: u64,
+
+    /// オブジェクトの論理的な大きさ(バイト)の合計.
+    ///
+    /// 大きさが記録されていないオブジェクト(大きさの記録の導入前に保存されたもの等)の分は、
+    /// 大きさが記録されているオブジェクトの平均値で見積もられる.
+    pub bytes: u64,
+
+    /// 大きさが記録されていないオブジェクトの数.
+    ///
+    /// 0 より大きい場合には、`bytes`は見積もりを含む.
+    pub unsized_objects: u64,
+
+    /// 割り当て量.
+    pub quota: Quota,
+}
+impl Add for QuotaUsage {
+    type Output = Self;
+
+    /// 二つのセグメントの使用量を合算する.
+    ///
+    /// 割り当て量は、両方に上限が設けられている項目のみ合算される.
+    fn add(self, other: Self) -> Self {
+        QuotaUsage {
+            objects: self.objects + other.objects,
+            bytes: self.bytes + other.bytes,
+            unsized_objects: self.unsized_objects + other.unsized_objects,
+            quota: self.quota + other.quota,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_works() {
+        let quota = Quota {
+            max_objects: Some(10),
+            max_bytes: None,
+        };
+        let split = quota.split(3);
+        assert_eq!(split.max_objects, Some(4));
+        assert_eq!(split.max_bytes, None);
+        assert_eq!(quota.split(0), quota);
+
+        let total = split + split + split;
+        assert_eq!(total.max_objects, Some(12));
+        assert!(total.max_bytes.is_none());
+        assert!(Quota::default().is_unlimited());
+    }
+
+    #[test]
+    fn shared_quota_works() {
+        let quota = SharedQuota::default();
+        let shared = quota.clone();
+        shared.set(Quota {
+            max_objects: Some(10),
+            max_bytes: None,
+        });
+        assert_eq!(quota.get().max_objects, Some(10));
+    }
+}
@@ -0,0 +1,17 @@
+//! バージョン管理されているバケツの、過去のバージョンの指定方法.
+//!
+//! 上書きされたバージョンは、MDS の設定(`version_retention_secs`)で指定された期間だけ保持される.
+//! この機能はクラスタ単位の機能フラグ`history_retention`が有効な場合にのみ使用される.
+//! 過去のバージョンを持つオブジェクトが削除された場合には、削除時のバージョンも過去のバージョンとして
+//! 同じ期間だけ保持され、その間は復元(undelete)できる.
+use libfrugalos::entity::object::ObjectVersion;
+
+/// 取得するオブジェクトのバージョンの指定.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RevisionSelector {
+    /// 指定のバージョン.
+    Version(ObjectVersion),
+
+    /// 指定時刻(UNIXエポックからの秒数)の時点で最新だったバージョン.
+    AsOf(u64),
+}
@@ -0,0 +1,376 @@
+//! `libfrugalos::schema::mds` に定義されていない MDS の RPC のスキーマ定義。
+//!
+//! ID は`frugalos`クレートや`frugalos_segment`クレートの`schema`モジュールで定義されているものと
+//! 衝突しないように`0x0202_0000`以降を利用する。
+use bytecodec::bincode_codec::{BincodeDecoder, BincodeEncoder};
+use fibers_rpc::{Call, ProcedureId};
+use libfrugalos::entity::object::{Metadata, ObjectId, ObjectSummary, ObjectVersion};
+use libfrugalos::schema::mds::{ObjectRequest, PrefixRequest, PutObjectRequest};
+use libfrugalos::Result;
+use std::time::Duration;
+
+use {DeleteJobStatus, ObjectChanges, Precondition, QuotaUsage, RevisionSelector};
+
+/// 削除猶予期間中のオブジェクトを復元する RPC。
+///
+/// 応答は復元されたオブジェクトのバージョン。
+/// 要求の`expect`と`consistency`は無視される。
+#[derive(Debug)]
+pub struct UndeleteObjectRpc;
+impl Call for UndeleteObjectRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_0000);
+    const NAME: &'static str = "frugalos.mds.object.undelete";
+
+    type Req = ObjectRequest;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Option<ObjectVersion>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 既存のオブジェクトに追記する部分のために、バージョンを予約する RPC。
+///
+/// 追記する内容自体はセグメントのストレージに保存されるので、要求の`metadata`は無視される。
+/// 組の二番目の要素は追記する部分の大きさ。
+/// 応答は予約された部分のバージョンと、予約時点のオブジェクトのバージョンの組。
+/// 内容の保存後に`CommitAppendRpc`を呼び出すまでは、オブジェクトには追記されない。
+#[derive(Debug)]
+pub struct AppendObjectRpc;
+impl Call for AppendObjectRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_0001);
+    const NAME: &'static str = "frugalos.mds.object.append";
+
+    type Req = (PutObjectRequest, u64);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<(ObjectVersion, Option<ObjectVersion>)>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 前提条件付きでオブジェクトを保存する RPC。
+///
+/// 要求の`expect`は無視され、代わりに組の二番目の要素の前提条件が使われる。
+/// 応答は`PutObjectRpc`と同様。
+#[derive(Debug)]
+pub struct PutObjectIfRpc;
+impl Call for PutObjectIfRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_0002);
+    const NAME: &'static str = "frugalos.mds.object.put_if";
+
+    type Req = (PutObjectRequest, Precondition);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<(ObjectVersion, Option<ObjectVersion>)>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 前提条件付きでオブジェクトを削除する RPC。
+///
+/// 要求の`expect`と`consistency`は無視され、代わりに組の二番目の要素の前提条件が使われる。
+/// 応答は`DeleteObjectRpc`と同様。
+#[derive(Debug)]
+pub struct DeleteObjectIfRpc;
+impl Call for DeleteObjectIfRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_0003);
+    const NAME: &'static str = "frugalos.mds.object.delete_if";
+
+    type Req = (ObjectRequest, Precondition);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Option<ObjectVersion>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 期限付きでオブジェクトを保存する RPC。
+///
+/// 組の三番目の要素は、要求を受信してから処理を諦めるまでの時間。
+/// 期限内に MDS へのコミットが完了しなかった場合には`ErrorKind::Timeout`が返される。
+/// それ以外は`PutObjectIfRpc`と同様。
+#[derive(Debug)]
+pub struct PutObjectWithinRpc;
+impl Call for PutObjectWithinRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_0004);
+    const NAME: &'static str = "frugalos.mds.object.put_within";
+
+    type Req = (PutObjectRequest, Precondition, Duration);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<(ObjectVersion, Option<ObjectVersion>)>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 期限付きでオブジェクトを削除する RPC。
+///
+/// 期限の扱いは`PutObjectWithinRpc`と、それ以外は`DeleteObjectIfRpc`と同様。
+#[derive(Debug)]
+pub struct DeleteObjectWithinRpc;
+impl Call for DeleteObjectWithinRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_0005);
+    const NAME: &'static str = "frugalos.mds.object.delete_within";
+
+    type Req = (ObjectRequest, Precondition, Duration);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Option<ObjectVersion>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// リーダからの遅れが許容範囲内のノードで、オブジェクトの一覧を取得する RPC。
+///
+/// 組の二番目の要素は、許容する遅れ(適用済みのログのエントリ数)。
+/// 要求を受けたノードの遅れがそれを超えている場合には`ErrorKind::NotLeader`が返される。
+#[derive(Debug)]
+pub struct ListObjectsWithinLagRpc;
+impl Call for ListObjectsWithinLagRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_0006);
+    const NAME: &'static str = "frugalos.mds.object.list_within_lag";
+
+    type Req = (String, u64);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Vec<ObjectSummary>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// リーダからの遅れが許容範囲内のノードで、オブジェクトを取得する RPC。
+///
+/// 要求の`consistency`は無視され、常に`ReadConsistency::Stale`として扱われる。
+/// 遅れの扱いは`ListObjectsWithinLagRpc`と同様。
+#[derive(Debug)]
+pub struct GetObjectWithinLagRpc;
+impl Call for GetObjectWithinLagRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_0007);
+    const NAME: &'static str = "frugalos.mds.object.get_within_lag";
+
+    type Req = (ObjectRequest, u64);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Option<Metadata>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// リーダからの遅れが許容範囲内のノードで、オブジェクトのバージョンを取得する RPC。
+///
+/// `GetObjectWithinLagRpc`の HEAD 版。
+#[derive(Debug)]
+pub struct HeadObjectWithinLagRpc;
+impl Call for HeadObjectWithinLagRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_0008);
+    const NAME: &'static str = "frugalos.mds.object.head_within_lag";
+
+    type Req = (ObjectRequest, u64);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Option<ObjectVersion>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// `PutObjectSizedRpc`の応答。
+///
+/// 割り当て量を超えるために保存されなかった場合には`Err`となり、その時点の使用量が含まれる。
+pub type PutObjectSizedResult =
+    ::std::result::Result<(ObjectVersion, Option<ObjectVersion>), QuotaUsage>;
+
+/// オブジェクトの大きさを伴って、オブジェクトを保存する RPC。
+///
+/// 組の四番目の要素はオブジェクトの論理的な大きさ(バイト)で、割り当て量の確認に使われる。
+/// 三番目の要素が指定された場合の扱いは`PutObjectWithinRpc`と、それ以外は`PutObjectIfRpc`と同様。
+#[derive(Debug)]
+pub struct PutObjectSizedRpc;
+impl Call for PutObjectSizedRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_0009);
+    const NAME: &'static str = "frugalos.mds.object.put_sized";
+
+    type Req = (PutObjectRequest, Precondition, Option<Duration>, u64);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<PutObjectSizedResult>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// ノード(= セグメント)の使用量と割り当て量を取得する RPC。
+///
+/// 要求はノードの ID。
+#[derive(Debug)]
+pub struct GetQuotaUsageRpc;
+impl Call for GetQuotaUsageRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_000A);
+    const NAME: &'static str = "frugalos.mds.quota_usage.get";
+
+    type Req = String;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<QuotaUsage>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// バージョン管理されているバケツで、過去のバージョンのオブジェクトを取得する RPC。
+///
+/// 要求の`expect`は無視される。
+/// 指定のバージョンが保持されていない場合や、指定時刻にオブジェクトが存在しなかった場合の応答は`None`。
+#[derive(Debug)]
+pub struct GetObjectRevisionRpc;
+impl Call for GetObjectRevisionRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_000B);
+    const NAME: &'static str = "frugalos.mds.object.get_revision";
+
+    type Req = (ObjectRequest, RevisionSelector);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Option<Metadata>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 接頭辞に一致するオブジェクト群をバッチに分けて削除するジョブを開始する RPC。
+///
+/// 応答はジョブの ID。ジョブはリーダ上で実行されるので、要求はリーダに送る必要がある。
+#[derive(Debug)]
+pub struct StartDeleteJobRpc;
+impl Call for StartDeleteJobRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_000C);
+    const NAME: &'static str = "frugalos.mds.delete_job.start";
+
+    type Req = PrefixRequest;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<u64>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 削除ジョブの進捗を取得する RPC。
+///
+/// 要求はノードの ID とジョブの ID。ジョブが存在しない場合の応答は`None`。
+#[derive(Debug)]
+pub struct GetDeleteJobRpc;
+impl Call for GetDeleteJobRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_000D);
+    const NAME: &'static str = "frugalos.mds.delete_job.get";
+
+    type Req = (String, u64);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Option<DeleteJobStatus>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 削除ジョブを中断する RPC。
+///
+/// 応答は中断後のジョブの進捗。既に削除されたオブジェクトは元に戻らない。
+#[derive(Debug)]
+pub struct CancelDeleteJobRpc;
+impl Call for CancelDeleteJobRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_000E);
+    const NAME: &'static str = "frugalos.mds.delete_job.cancel";
+
+    type Req = (String, u64);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Option<DeleteJobStatus>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 最新のバージョンが指定期間内に保存されたオブジェクトの一覧を、保存時刻順に取得する RPC。
+///
+/// 要求はノードの ID と、期間の始点(含む)と終点(含まない)。時刻は UNIX エポックからのミリ秒。
+/// 保存時刻が記録されていないオブジェクトは含まれない。
+#[derive(Debug)]
+pub struct ListObjectsByTimeRangeRpc;
+impl Call for ListObjectsByTimeRangeRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_000F);
+    const NAME: &'static str = "frugalos.mds.object.list_by_time_range";
+
+    type Req = (String, u64, u64);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Vec<ObjectSummary>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// オブジェクトの変更履歴を取得する RPC。
+///
+/// 要求はノードの ID と、取得を開始する位置(この位置の変更は含まない)、および取得する変更の最大数。
+/// 位置が`None`の場合には、変更を含まずに現在の位置のみが返される。
+#[derive(Debug)]
+pub struct WatchObjectsRpc;
+impl Call for WatchObjectsRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_0010);
+    const NAME: &'static str = "frugalos.mds.object.watch";
+
+    type Req = (String, Option<ObjectVersion>, u64);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<ObjectChanges>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// `AppendObjectRpc`で予約した部分を、オブジェクトの末尾に追記する RPC。
+///
+/// 要求はノードの ID、オブジェクトの ID および予約された部分のバージョンの組。
+/// 応答は追記前のオブジェクトのバージョン。
+#[derive(Debug)]
+pub struct CommitAppendRpc;
+impl Call for CommitAppendRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_0012);
+    const NAME: &'static str = "frugalos.mds.object.commit_append";
+
+    type Req = (String, ObjectId, ObjectVersion);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Option<ObjectVersion>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 指定されたノードのスナップショットを取得する RPC。
+///
+/// 全ノードを対象とする`take_snapshot`とは異なり、要求で指定された ID のノードのみが対象となる。
+/// スナップショットの取得は非同期に行われるため、応答は取得の完了を意味しない。
+#[derive(Debug)]
+pub struct TakeSnapshotRpc;
+impl Call for TakeSnapshotRpc {
+    const ID: ProcedureId = ProcedureId(0x0202_0011);
+    const NAME: &'static str = "frugalos.mds.node.take_snapshot";
+
+    type Req = String;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<()>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
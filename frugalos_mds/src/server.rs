@@ -1,18 +1,28 @@
 use fibers_rpc::server::{
     HandleCall, HandleCast, NoReply, Reply, ServerBuilder as RpcServerBuilder,
 };
+use frugalos_core::rpc_auth;
 use frugalos_core::tracer::{SpanExt, ThreadLocalTracer};
 use frugalos_raft::LocalNodeId;
 use futures::Future;
+use libfrugalos::consistency::ReadConsistency;
+use libfrugalos::entity::object::{ObjectId, ObjectVersion};
 use libfrugalos::schema::mds as rpc;
 use rustracing::tag::{StdTag, Tag};
 use rustracing_jaeger::span::Span;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use trackable::error::ErrorKindExt;
 
 use error::to_rpc_error;
 use node::NodeHandle;
-use {Error, ErrorKind, Result, ServiceHandle};
+use schema::{
+    AppendObjectRpc, CancelDeleteJobRpc, CommitAppendRpc, DeleteObjectIfRpc, DeleteObjectWithinRpc,
+    GetDeleteJobRpc, GetObjectRevisionRpc, GetObjectWithinLagRpc, GetQuotaUsageRpc,
+    HeadObjectWithinLagRpc, ListObjectsByTimeRangeRpc, ListObjectsWithinLagRpc, PutObjectIfRpc,
+    PutObjectSizedRpc, PutObjectWithinRpc, StartDeleteJobRpc, TakeSnapshotRpc, UndeleteObjectRpc,
+    WatchObjectsRpc,
+};
+use {Error, ErrorKind, Precondition, Result, RevisionSelector, ServiceHandle};
 
 macro_rules! rpc_try {
     ($expr:expr) => {
@@ -55,18 +65,37 @@ impl Server {
         tracer: ThreadLocalTracer,
     ) {
         let this = Server { service, tracer };
-        builder.add_cast_handler::<rpc::RecommendToLeaderRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::GetLeaderRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::ListObjectsRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::GetObjectRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::HeadObjectRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::PutObjectRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::DeleteObjectRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::GetLatestVersionRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::GetObjectCountRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::DeleteObjectByVersionRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::DeleteObjectsByRangeRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::DeleteObjectsByPrefixRpc, _>(this.clone());
+        rpc_auth::add_cast_handler::<rpc::RecommendToLeaderRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<rpc::GetLeaderRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<rpc::ListObjectsRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<rpc::GetObjectRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<rpc::HeadObjectRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<ListObjectsWithinLagRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<ListObjectsByTimeRangeRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<WatchObjectsRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<GetObjectWithinLagRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<GetObjectRevisionRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<HeadObjectWithinLagRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<rpc::PutObjectRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<PutObjectIfRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<PutObjectWithinRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<PutObjectSizedRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<AppendObjectRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<CommitAppendRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<rpc::DeleteObjectRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<DeleteObjectIfRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<DeleteObjectWithinRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<UndeleteObjectRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<rpc::GetLatestVersionRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<rpc::GetObjectCountRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<GetQuotaUsageRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<rpc::DeleteObjectByVersionRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<rpc::DeleteObjectsByRangeRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<rpc::DeleteObjectsByPrefixRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<StartDeleteJobRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<GetDeleteJobRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<CancelDeleteJobRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<TakeSnapshotRpc, _>(builder, this.clone());
     }
 
     fn get_node(&self, node: LocalNodeId) -> Result<NodeHandle> {
@@ -133,6 +162,14 @@ impl HandleCall<rpc::GetObjectCountRpc> for Server {
     }
 }
 
+impl HandleCall<GetQuotaUsageRpc> for Server {
+    fn handle_call(&self, node_id: String) -> Reply<GetQuotaUsageRpc> {
+        let node_id = rpc_try!(node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(node.quota_usage().map_err(to_rpc_error).then(Ok))
+    }
+}
+
 impl HandleCall<rpc::GetObjectRpc> for Server {
     fn handle_call(&self, request: rpc::ObjectRequest) -> Reply<rpc::GetObjectRpc> {
         let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
@@ -164,6 +201,92 @@ impl HandleCall<rpc::HeadObjectRpc> for Server {
         )
     }
 }
+impl HandleCall<ListObjectsWithinLagRpc> for Server {
+    fn handle_call(&self, (node_id, max_lag): (String, u64)) -> Reply<ListObjectsWithinLagRpc> {
+        let node_id = rpc_try!(node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        let future = node
+            .check_lag(max_lag)
+            .and_then(move |()| node.list_objects());
+        Reply::future(future.map_err(to_rpc_error).then(Ok))
+    }
+}
+impl HandleCall<ListObjectsByTimeRangeRpc> for Server {
+    fn handle_call(
+        &self,
+        (node_id, start, end): (String, u64, u64),
+    ) -> Reply<ListObjectsByTimeRangeRpc> {
+        let node_id = rpc_try!(node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.list_objects_by_time_range(start..end)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<WatchObjectsRpc> for Server {
+    fn handle_call(
+        &self,
+        (node_id, after, max): (String, Option<ObjectVersion>, u64),
+    ) -> Reply<WatchObjectsRpc> {
+        let node_id = rpc_try!(node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.watch(after, max as usize)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<GetObjectWithinLagRpc> for Server {
+    fn handle_call(
+        &self,
+        (request, max_lag): (rpc::ObjectRequest, u64),
+    ) -> Reply<GetObjectWithinLagRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        let started_at = Instant::now();
+        let future = node.check_lag(max_lag).and_then(move |()| {
+            node.get_object(
+                request.object_id,
+                request.expect,
+                ReadConsistency::Stale,
+                started_at,
+            )
+        });
+        Reply::future(future.map_err(to_rpc_error).then(Ok))
+    }
+}
+impl HandleCall<GetObjectRevisionRpc> for Server {
+    fn handle_call(
+        &self,
+        (request, selector): (rpc::ObjectRequest, RevisionSelector),
+    ) -> Reply<GetObjectRevisionRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        let future = node.get_object_revision(
+            request.object_id,
+            selector,
+            request.expect,
+            request.consistency.unwrap_or_default(),
+        );
+        Reply::future(future.map_err(to_rpc_error).then(Ok))
+    }
+}
+impl HandleCall<HeadObjectWithinLagRpc> for Server {
+    fn handle_call(
+        &self,
+        (request, max_lag): (rpc::ObjectRequest, u64),
+    ) -> Reply<HeadObjectWithinLagRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        let future = node.check_lag(max_lag).and_then(move |()| {
+            node.head_object(request.object_id, request.expect, ReadConsistency::Stale)
+        });
+        Reply::future(future.map_err(to_rpc_error).then(Ok))
+    }
+}
 impl HandleCall<rpc::PutObjectRpc> for Server {
     fn handle_call(&self, request: rpc::PutObjectRequest) -> Reply<rpc::PutObjectRpc> {
         let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
@@ -172,7 +295,104 @@ impl HandleCall<rpc::PutObjectRpc> for Server {
             node.put_object(
                 request.object_id,
                 request.metadata,
+                0,
+                request.expect.into(),
+                request.put_content_timeout.into(),
+                Instant::now(),
+                None,
+            )
+            .map_err(to_rpc_error)
+            .then(Ok),
+        )
+    }
+}
+impl HandleCall<PutObjectIfRpc> for Server {
+    fn handle_call(
+        &self,
+        (request, precondition): (rpc::PutObjectRequest, Precondition),
+    ) -> Reply<PutObjectIfRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.put_object(
+                request.object_id,
+                request.metadata,
+                0,
+                precondition,
+                request.put_content_timeout.into(),
+                Instant::now(),
+                None,
+            )
+            .map_err(to_rpc_error)
+            .then(Ok),
+        )
+    }
+}
+impl HandleCall<PutObjectWithinRpc> for Server {
+    fn handle_call(
+        &self,
+        (request, precondition, timeout): (rpc::PutObjectRequest, Precondition, Duration),
+    ) -> Reply<PutObjectWithinRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        let started_at = Instant::now();
+        Reply::future(
+            node.put_object(
+                request.object_id,
+                request.metadata,
+                0,
+                precondition,
+                request.put_content_timeout.into(),
+                started_at,
+                Some(started_at + timeout),
+            )
+            .map_err(to_rpc_error)
+            .then(Ok),
+        )
+    }
+}
+impl HandleCall<PutObjectSizedRpc> for Server {
+    fn handle_call(
+        &self,
+        (request, precondition, timeout, size): (
+            rpc::PutObjectRequest,
+            Precondition,
+            Option<Duration>,
+            u64,
+        ),
+    ) -> Reply<PutObjectSizedRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        let started_at = Instant::now();
+        let future = node
+            .put_object(
+                request.object_id,
+                request.metadata,
+                size,
+                precondition,
+                request.put_content_timeout.into(),
+                started_at,
+                timeout.map(|t| started_at + t),
+            )
+            .then(|result| match result {
+                Ok(v) => Ok(Ok(Ok(v))),
+                Err(e) => match *e.kind() {
+                    ErrorKind::QuotaExceeded(usage) => Ok(Ok(Err(usage))),
+                    _ => Ok(Err(to_rpc_error(e))),
+                },
+            });
+        Reply::future(future)
+    }
+}
+impl HandleCall<AppendObjectRpc> for Server {
+    fn handle_call(&self, (request, size): (rpc::PutObjectRequest, u64)) -> Reply<AppendObjectRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.append_object(
+                request.object_id,
                 request.expect,
+                size,
                 request.put_content_timeout.into(),
                 Instant::now(),
             )
@@ -181,12 +401,76 @@ impl HandleCall<rpc::PutObjectRpc> for Server {
         )
     }
 }
+impl HandleCall<CommitAppendRpc> for Server {
+    fn handle_call(
+        &self,
+        (node_id, object_id, version): (String, ObjectId, ObjectVersion),
+    ) -> Reply<CommitAppendRpc> {
+        let node_id = rpc_try!(node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.commit_append(object_id, version, Instant::now())
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
 impl HandleCall<rpc::DeleteObjectRpc> for Server {
     fn handle_call(&self, request: rpc::ObjectRequest) -> Reply<rpc::DeleteObjectRpc> {
         let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
         let node = rpc_try!(self.get_node(node_id));
         Reply::future(
-            node.delete_object(request.object_id, request.expect, Instant::now())
+            node.delete_object(
+                request.object_id,
+                request.expect.into(),
+                Instant::now(),
+                None,
+            )
+            .map_err(to_rpc_error)
+            .then(Ok),
+        )
+    }
+}
+impl HandleCall<DeleteObjectIfRpc> for Server {
+    fn handle_call(
+        &self,
+        (request, precondition): (rpc::ObjectRequest, Precondition),
+    ) -> Reply<DeleteObjectIfRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.delete_object(request.object_id, precondition, Instant::now(), None)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<DeleteObjectWithinRpc> for Server {
+    fn handle_call(
+        &self,
+        (request, precondition, timeout): (rpc::ObjectRequest, Precondition, Duration),
+    ) -> Reply<DeleteObjectWithinRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        let started_at = Instant::now();
+        Reply::future(
+            node.delete_object(
+                request.object_id,
+                precondition,
+                started_at,
+                Some(started_at + timeout),
+            )
+            .map_err(to_rpc_error)
+            .then(Ok),
+        )
+    }
+}
+impl HandleCall<UndeleteObjectRpc> for Server {
+    fn handle_call(&self, request: rpc::ObjectRequest) -> Reply<UndeleteObjectRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.undelete_object(request.object_id, Instant::now())
                 .map_err(to_rpc_error)
                 .then(Ok),
         )
@@ -227,3 +511,40 @@ impl HandleCall<rpc::DeleteObjectsByPrefixRpc> for Server {
         )
     }
 }
+impl HandleCall<StartDeleteJobRpc> for Server {
+    fn handle_call(&self, request: rpc::PrefixRequest) -> Reply<StartDeleteJobRpc> {
+        let node_id = rpc_try!(request.node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.start_delete_job(request.prefix)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<GetDeleteJobRpc> for Server {
+    fn handle_call(&self, (node_id, job_id): (String, u64)) -> Reply<GetDeleteJobRpc> {
+        let node_id = rpc_try!(node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(node.get_delete_job(job_id).map_err(to_rpc_error).then(Ok))
+    }
+}
+impl HandleCall<TakeSnapshotRpc> for Server {
+    fn handle_call(&self, node_id: String) -> Reply<TakeSnapshotRpc> {
+        let node_id = rpc_try!(node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        node.take_snapshot();
+        Reply::done(Ok(()))
+    }
+}
+impl HandleCall<CancelDeleteJobRpc> for Server {
+    fn handle_call(&self, (node_id, job_id): (String, u64)) -> Reply<CancelDeleteJobRpc> {
+        let node_id = rpc_try!(node_id.parse().map_err(Error::from));
+        let node = rpc_try!(self.get_node(node_id));
+        Reply::future(
+            node.cancel_delete_job(job_id)
+                .map_err(to_rpc_error)
+                .then(Ok),
+        )
+    }
+}
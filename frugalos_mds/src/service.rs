@@ -1,9 +1,11 @@
 use atomic_immut::AtomicImmut;
 use fibers::sync::{mpsc, oneshot};
 use fibers_rpc::server::ServerBuilder as RpcServerBuilder;
+use frugalos_core::hlc::HybridClock;
 use frugalos_core::tracer::ThreadLocalTracer;
 use frugalos_raft::{LocalNodeId, NodeId};
 use futures::{Async, Future, Poll, Stream};
+use rand::{self, Rng};
 use slog::Logger;
 use std::collections::HashMap;
 use std::fmt;
@@ -24,6 +26,7 @@ type Nodes = Arc<AtomicImmut<HashMap<LocalNodeId, NodeHandle>>>;
 #[derive(Debug)]
 pub struct Service {
     logger: Logger,
+    clock: HybridClock,
     command_tx: mpsc::Sender<Command>,
     command_rx: mpsc::Receiver<Command>,
     state: ServiceState,
@@ -39,6 +42,7 @@ impl Service {
         let (command_tx, command_rx) = mpsc::channel();
         let this = Service {
             logger: logger.clone(),
+            clock: HybridClock::new(),
             command_tx,
             command_rx,
             state: ServiceState::Running { logger, nodes },
@@ -51,6 +55,7 @@ impl Service {
     pub fn handle(&self) -> ServiceHandle {
         ServiceHandle {
             nodes: self.state.nodes(),
+            clock: self.clock.clone(),
             command_tx: self.command_tx.clone(),
         }
     }
@@ -127,6 +132,79 @@ impl Service {
         };
     }
 
+    /// 各ノードのリーダ権を他のサーバ上のノードに譲るよう要求する.
+    ///
+    /// サーバを停止する前に呼び出すことで、リーダ不在の期間を短くすることができる.
+    pub fn resign_leaderships(&mut self) {
+        for (id, node) in self.state.nodes().load().iter() {
+            info!(self.logger, "Sends resigning leadership request: {:?}", id);
+            node.resign_leadership();
+        }
+    }
+
+    /// 停止に先立って、各ノードをドレイン状態にする.
+    ///
+    /// ドレイン状態のノードはリーダ権を他のサーバ上のノードに譲り、以降リーダに選ばれても直ちに辞退する.
+    /// `stop`の前に呼び出して、停止時のクライアントのエラーを減らすために利用する.
+    pub fn drain(&mut self) {
+        for (id, node) in self.state.nodes().load().iter() {
+            info!(self.logger, "Sends draining request: {:?}", id);
+            node.drain();
+        }
+    }
+
+    /// 指定されたノードのリーダ権を他のサーバ上のノードに譲るよう要求する.
+    ///
+    /// ノードが存在しない場合には何もしない.
+    pub fn resign_leadership(&mut self, id: LocalNodeId) {
+        if let Some(node) = self.state.nodes().load().get(&id) {
+            info!(self.logger, "Sends resigning leadership request: {:?}", id);
+            node.resign_leadership();
+        }
+    }
+
+    /// サーバ内のリーダ数が期待値よりも多い場合に、超過分のノードのリーダ権を他のサーバ上のノードに譲るよう要求する.
+    ///
+    /// 各ノードがリーダとなる期待値は、そのノードが属するクラスタのメンバ数の逆数であり、
+    /// サーバ内の全ノードでの期待値の合計(切り上げ)に`slack`を加えた数を超えたリーダ権を譲る.
+    /// 全てのサーバで定期的に呼び出すことで、サーバ間のリーダ数の偏りが緩和される.
+    ///
+    /// リーダが確定していないノードが一つでもある場合には、障害や再選出の最中である可能性が高いので、
+    /// 更なる再選出を誘発しないように何もしない.
+    ///
+    /// リーダ権の委譲を要求したノードの数を返す.
+    pub fn balance_leaderships(&mut self, slack: usize) -> usize {
+        if !self.state.is_running() {
+            return 0;
+        }
+        let nodes = self.state.nodes().load();
+        if nodes.values().any(|node| !node.leadership().has_leader()) {
+            debug!(
+                self.logger,
+                "Skips balancing leaderships: some clusters have no leader"
+            );
+            return 0;
+        }
+        let leaderships = nodes
+            .iter()
+            .map(|(id, node)| {
+                let leadership = node.leadership();
+                (*id, leadership.is_leader(), leadership.members())
+            })
+            .collect::<Vec<_>>();
+        let resigned = leaders_to_resign(leaderships, slack);
+        for id in &resigned {
+            if let Some(node) = nodes.get(id) {
+                info!(
+                    self.logger,
+                    "Sends resigning leadership request for balancing: {:?}", id
+                );
+                node.resign_leadership();
+            }
+        }
+        resigned.len()
+    }
+
     /// スナップショットを取得する.
     pub fn take_snapshot(&mut self) {
         for (id, node) in self.state.nodes().load().iter() {
@@ -175,19 +253,55 @@ impl Future for Service {
     }
 }
 
+/// リーダ数の偏りを解消するために、リーダ権を譲るべきノードを返す.
+///
+/// `leaderships`の各要素は、ノードの ID とリーダかどうか、およびクラスタのメンバ数.
+/// メンバ数が不明ないし一つしかないノードは、リーダ権を譲れないので対象外となる.
+///
+/// 特定のノードばかりがリーダ権を譲ることにならないように、対象は超過分のリーダの中から無作為に選ばれる.
+fn leaders_to_resign(
+    mut leaderships: Vec<(LocalNodeId, bool, usize)>,
+    slack: usize,
+) -> Vec<LocalNodeId> {
+    leaderships.retain(|&(_, _, members)| members > 1);
+    let expected = leaderships
+        .iter()
+        .map(|&(_, _, members)| 1.0 / members as f64)
+        .sum::<f64>()
+        .ceil() as usize;
+    let mut leaders = leaderships
+        .into_iter()
+        .filter(|&(_, is_leader, _)| is_leader)
+        .map(|(id, _, _)| id)
+        .collect::<Vec<_>>();
+    rand::thread_rng().shuffle(&mut leaders);
+    let excess = leaders.len().saturating_sub(expected + slack);
+    leaders.into_iter().take(excess).collect()
+}
+
 #[derive(Debug)]
 enum Command {
     AddNode(LocalNodeId, NodeHandle),
     RemoveNode(LocalNodeId),
 }
 
+/// サーバ内の MDS ノードのリーダの確定状況.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LeaderStatus {
+    /// サーバ内のノードの数.
+    pub nodes: usize,
+
+    /// 属するクラスタのリーダが確定しているノードの数.
+    pub nodes_with_leader: usize,
+}
+
 /// `Service`を操作するためのハンドル.
 ///
-/// `Service`に対する操作はクレート内で閉じているため、
-/// 利用者に公開されているメソッドは存在しない.
+/// `Service`に対する操作は、状態の参照を除いてクレート内で閉じている.
 #[derive(Debug, Clone)]
 pub struct ServiceHandle {
     nodes: Nodes,
+    clock: HybridClock,
     command_tx: mpsc::Sender<Command>,
 }
 impl ServiceHandle {
@@ -215,6 +329,25 @@ impl ServiceHandle {
     pub(crate) fn nodes(&self) -> Arc<HashMap<LocalNodeId, NodeHandle>> {
         self.nodes.load()
     }
+
+    /// サーバ内のノードのリーダの確定状況を返す.
+    pub fn leader_status(&self) -> LeaderStatus {
+        let nodes = self.nodes();
+        LeaderStatus {
+            nodes: nodes.len(),
+            nodes_with_leader: nodes
+                .values()
+                .filter(|node| node.leadership().has_leader())
+                .count(),
+        }
+    }
+
+    /// サーバ内の全ノードで共有されるハイブリッド論理時計を返す.
+    ///
+    /// 同じ時計を使うことで、セグメントを跨いだイベントの順序付けが可能になる.
+    pub(crate) fn clock(&self) -> &HybridClock {
+        &self.clock
+    }
 }
 
 // ノード群の管理は `ServiceState` の責務.
@@ -352,4 +485,42 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn leaders_to_resign_works() {
+        let id = |i: u8| LocalNodeId::new([0, 0, 0, 0, 0, 0, i]);
+
+        // 3 メンバのクラスタが 6 つ: 期待値は 2
+        let leaderships = (0..6).map(|i| (id(i), i < 5, 3)).collect::<Vec<_>>();
+        let resigned = leaders_to_resign(leaderships.clone(), 0);
+        assert_eq!(resigned.len(), 3);
+        assert!(resigned
+            .iter()
+            .all(|id| leaderships.contains(&(*id, true, 3))));
+        assert_eq!(leaders_to_resign(leaderships.clone(), 1).len(), 2);
+        assert!(leaders_to_resign(leaderships, 3).is_empty());
+
+        // 単独のメンバやメンバ数が不明なノードは対象外
+        let leaderships = vec![(id(0), true, 1), (id(1), true, 0), (id(2), true, 3)];
+        assert!(leaders_to_resign(leaderships, 0).is_empty());
+    }
+
+    #[test]
+    fn drain_works() -> TestResult {
+        let mut node = TestNodeForStop::new("1000a00.0@127.0.0.1:14278");
+        let (tracer, _) = rustracing_jaeger::Tracer::new(NullSampler);
+        let tracer = ThreadLocalTracer::new(tracer);
+        let logger = Logger::root(Discard, o!());
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut rpc_server_builder = RpcServerBuilder::new(addr);
+        let mut service = track!(Service::new(logger, &mut rpc_server_builder, tracer))?;
+        track!(service.handle().add_node(node.node_id, node.handle()))?;
+        track!(service.poll())?;
+        service.drain();
+        match node.rx.poll() {
+            Ok(Async::Ready(Some(Request::Drain))) => {}
+            _ => panic!("The node should receive a draining request"),
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,59 @@
+//! ノードのアドレスの変換表.
+//!
+//! `NodeId`に含まれるアドレスはクラスタ構成に登録された時点のものであり、
+//! Raftのノード識別子の一部になっているため後から変更することができない.
+//! サーバのIPアドレスが変わった場合には、この変換表に「登録済みのアドレス」から
+//! 「現在のアドレス」への対応を追加することで、通信先だけを切り替える.
+use atomic_immut::AtomicImmut;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+lazy_static! {
+    static ref ADDR_TABLE: AtomicImmut<HashMap<SocketAddr, SocketAddr>> =
+        AtomicImmut::new(HashMap::new());
+}
+
+/// 登録済みのアドレス`registered`を持つサーバの、現在のアドレスを設定する.
+///
+/// `current`が`registered`と等しい場合には、対応が削除される.
+pub fn set_current_addr(registered: SocketAddr, current: SocketAddr) {
+    ADDR_TABLE.update(|table| {
+        let mut table = table.clone();
+        if registered == current {
+            table.remove(&registered);
+        } else {
+            table.insert(registered, current);
+        }
+        table
+    });
+}
+
+/// 登録済みのアドレス`registered`に対応する、現在のアドレスを返す.
+///
+/// 対応が設定されていない場合には`registered`がそのまま返される.
+pub fn current_addr(registered: SocketAddr) -> SocketAddr {
+    ADDR_TABLE
+        .load()
+        .get(&registered)
+        .cloned()
+        .unwrap_or(registered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addr_table_works() {
+        // 変換表はプロセス全体で共有されるので、他のテストが使わない(文書用の)アドレスを使う
+        let registered: SocketAddr = "192.0.2.1:3000".parse().unwrap();
+        let current: SocketAddr = "192.0.2.2:3000".parse().unwrap();
+        assert_eq!(current_addr(registered), registered);
+
+        set_current_addr(registered, current);
+        assert_eq!(current_addr(registered), current);
+
+        set_current_addr(registered, registered);
+        assert_eq!(current_addr(registered), registered);
+    }
+}
@@ -0,0 +1,302 @@
+//! 既存のメンバからスナップショットを取得して、新しく追加されたノードを素早く立ち上げるための仕組み.
+//!
+//! 通常、新しいメンバはリーダから`InstallSnapshot`もしくは`AppendEntries`を受け取ることで
+//! 他のメンバに追い付くが、ログが長いセグメントでは時間がかかり、その間は冗長度が下がった状態が続く.
+//!
+//! `BootstrapLogPrefix`を使うと、Raftノードの起動前に、他のメンバが保持している最新のスナップショットを
+//! RPC経由で直接取得してローカルストレージに保存できるので、起動後はその地点からログの同期を開始できる.
+//! 転送されるスナップショットにはチェックサムが付与されており、破損していた場合には保存されない.
+use cannyls::device::DeviceHandle;
+use fibers_rpc::client::ClientServiceHandle;
+use fibers_rpc::Call;
+use futures::{Async, Future, Poll};
+use raftlog::log::LogPrefix;
+use raftlog::{Error, ErrorKind};
+use slog::Logger;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use trackable::error::ErrorKindExt;
+
+use protobuf;
+use rpc;
+use storage::{SaveLogPrefix, Storage};
+use util::Phase3;
+use {LocalNodeId, NodeId, StorageMetrics};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+
+/// 他のノードが保持している最新のスナップショットを取得する.
+///
+/// ノードがスナップショットを保持していない場合には`None`が返される.
+pub fn fetch_log_prefix(
+    rpc_service: &ClientServiceHandle,
+    peer: &NodeId,
+    timeout: Duration,
+) -> impl Future<Item = Option<LogPrefix>, Error = Error> {
+    let mut client = rpc::FetchLogPrefixRpc::client(rpc_service);
+    client.options_mut().timeout = Some(timeout);
+    client
+        .call(peer.current_addr(), peer.local_id.to_string())
+        .map_err(|e| ErrorKind::Other.takes_over(e).into())
+        .and_then(|bytes| {
+            if bytes.is_empty() {
+                Ok(None)
+            } else {
+                track!(protobuf::decode_log_prefix(&bytes)).map(Some)
+            }
+        })
+}
+
+/// 新しく追加されたノードのために、既存のメンバからスナップショットを取得して保存する`Future`.
+///
+/// ローカルに既にノードの状態(投票状況やログ)が存在する場合には、新しいノードではないので何もしない.
+/// スナップショットを持たずにログの接尾部分のみを持つノードに、古いスナップショットを上書きしてしまうことを避けるため、
+/// スナップショットの有無だけでは判定しない.
+/// 全てのメンバからの取得に失敗した場合も、エラーにはせずに通常のRaftによる同期に任せる.
+///
+/// 結果は、スナップショットを保存したかどうかを示す.
+pub struct BootstrapLogPrefix {
+    logger: Logger,
+    storage: Storage,
+    rpc_service: ClientServiceHandle,
+    peers: VecDeque<NodeId>,
+    timeout: Duration,
+    phase: Phase3<BoxFuture<bool>, BoxFuture<Option<LogPrefix>>, SaveLogPrefix>,
+    started_at: Instant,
+}
+impl BootstrapLogPrefix {
+    /// 新しい`BootstrapLogPrefix`インスタンスを生成する.
+    ///
+    /// `peers`には、取得元の候補となるメンバを優先度順に指定する.
+    /// `timeout`は、各メンバからの取得にかける時間の上限.
+    pub fn new(
+        logger: Logger,
+        node_id: LocalNodeId,
+        device: DeviceHandle,
+        rpc_service: ClientServiceHandle,
+        peers: Vec<NodeId>,
+        timeout: Duration,
+    ) -> Self {
+        let storage = Storage::new(logger.clone(), node_id, device, StorageMetrics::new());
+        let phase = Phase3::A(storage.has_local_state());
+        let peers = peers
+            .into_iter()
+            .filter(|peer| peer.local_id != node_id)
+            .collect();
+        BootstrapLogPrefix {
+            logger,
+            storage,
+            rpc_service,
+            peers,
+            timeout,
+            phase,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// 取得したスナップショットを保存する際の、lump毎の最大バイト数を設定する.
+    ///
+    /// 詳細は`Storage::set_log_prefix_chunk_size`を参照.
+    pub fn set_log_prefix_chunk_size(&mut self, size: usize) {
+        self.storage.set_log_prefix_chunk_size(size);
+    }
+
+    fn fetch_next(&mut self) -> Option<BoxFuture<Option<LogPrefix>>> {
+        let peer = self.peers.pop_front()?;
+        info!(self.logger, "[START] FetchLogPrefix: {}", dump!(peer));
+        let logger = self.logger.clone();
+        let future =
+            fetch_log_prefix(&self.rpc_service, &peer, self.timeout).then(
+                move |result| match result {
+                    Ok(prefix) => {
+                        if prefix.is_none() {
+                            info!(logger, "[FINISH] FetchLogPrefix: Not Found");
+                        }
+                        Ok(prefix)
+                    }
+                    Err(e) => {
+                        warn!(logger, "[FAILED] FetchLogPrefix: {}", dump!(peer, e));
+                        Ok(None)
+                    }
+                },
+            );
+        Some(Box::new(future))
+    }
+}
+impl Future for BootstrapLogPrefix {
+    type Item = bool;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(phase) = track!(self.phase.poll())? {
+            let next = match phase {
+                Phase3::A(true) => {
+                    info!(
+                        self.logger,
+                        "The local raft state already exists: skips bootstrapping"
+                    );
+                    return Ok(Async::Ready(false));
+                }
+                Phase3::A(false) | Phase3::B(None) => {
+                    if let Some(future) = self.fetch_next() {
+                        Phase3::B(future)
+                    } else {
+                        info!(
+                            self.logger,
+                            "No peers have a log prefix: falls back to the ordinary raft synchronization"
+                        );
+                        return Ok(Async::Ready(false));
+                    }
+                }
+                Phase3::B(Some(prefix)) => {
+                    info!(
+                        self.logger,
+                        "[FINISH] FetchLogPrefix: {}",
+                        dump!(prefix.tail, prefix.config, prefix.snapshot.len())
+                    );
+                    Phase3::C(self.storage.install_log_prefix(prefix))
+                }
+                Phase3::C(()) => {
+                    info!(
+                        self.logger,
+                        "The log prefix is bootstrapped: {}",
+                        dump!(self.started_at.elapsed())
+                    );
+                    return Ok(Async::Ready(true));
+                }
+            };
+            self.phase = next;
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers_global;
+    use fibers_rpc::client::ClientService;
+    use raftlog::cluster::ClusterConfig;
+    use raftlog::election::Term;
+    use raftlog::log::{LogEntry, LogIndex, LogPosition, LogSuffix};
+    use slog::Discard;
+    use std::collections::BTreeSet;
+    use trackable::result::TestResult;
+
+    use super::*;
+    use test_util::{make_proposals, run_test_with_storage, wait_for, System};
+
+    fn rpc_service() -> ClientServiceHandle {
+        let rpc_service = ClientService::new(fibers_global::handle());
+        let handle = rpc_service.handle();
+        fibers_global::spawn(rpc_service.map_err(|e| panic!("{}", e)));
+        handle
+    }
+
+    #[test]
+    fn bootstrap_without_peers_works() -> TestResult {
+        let node_id = LocalNodeId::new([0, 1, 2, 3, 4, 5, 6]);
+        run_test_with_storage(node_id, |(_storage, device)| {
+            let future = BootstrapLogPrefix::new(
+                Logger::root(Discard, o!()),
+                node_id,
+                device.handle(),
+                rpc_service(),
+                Vec::new(),
+                Duration::from_secs(1),
+            );
+            assert!(!track!(wait_for(future))?);
+
+            let lump_id = node_id.to_log_prefix_index_lump_id();
+            assert!(wait_for(device.handle().request().head(lump_id))?.is_none());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn bootstrap_skips_existing_log_prefix() -> TestResult {
+        let node_id = LocalNodeId::new([0, 1, 2, 3, 4, 5, 7]);
+        run_test_with_storage(node_id, |(mut storage, device)| {
+            let prefix = LogPrefix {
+                tail: LogPosition {
+                    prev_term: Term::new(1),
+                    index: LogIndex::new(10),
+                },
+                config: ClusterConfig::new(BTreeSet::new()),
+                snapshot: vec![1, 2, 3],
+            };
+            track!(wait_for(storage.install_log_prefix(prefix)))?;
+
+            // 到達不能なメンバを指定しても、問い合わせずに終了する
+            let peer = "0102030405060a.0@127.0.0.1:1".parse()?;
+            let future = BootstrapLogPrefix::new(
+                Logger::root(Discard, o!()),
+                node_id,
+                device.handle(),
+                rpc_service(),
+                vec![peer],
+                Duration::from_secs(1),
+            );
+            assert!(!track!(wait_for(future))?);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn bootstrap_skips_existing_log_suffix() -> TestResult {
+        let node_id = LocalNodeId::new([0, 1, 2, 3, 4, 5, 8]);
+        run_test_with_storage(node_id, |(mut storage, device)| {
+            // スナップショットを持たずに、ログの接尾部分のみを持つノード
+            let suffix = LogSuffix {
+                head: LogPosition {
+                    prev_term: Term::new(0),
+                    index: LogIndex::new(0),
+                },
+                entries: vec![LogEntry::Noop { term: Term::new(1) }],
+            };
+            track!(wait_for(storage.save_log_suffix(&suffix)))?;
+
+            let peer = "0102030405060a.0@127.0.0.1:1".parse()?;
+            let future = BootstrapLogPrefix::new(
+                Logger::root(Discard, o!()),
+                node_id,
+                device.handle(),
+                rpc_service(),
+                vec![peer],
+                Duration::from_secs(1),
+            );
+            assert!(!track!(wait_for(future))?);
+
+            let lump_id = node_id.to_log_prefix_index_lump_id();
+            assert!(wait_for(device.handle().request().head(lump_id))?.is_none());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn bootstrap_fetches_log_prefix_from_peer() -> TestResult {
+        let mut system = track!(System::new())?;
+        track!(system.boot(3))?;
+        let leader = track!(system.select_leader())?;
+        track!(system.bulk_propose(leader, make_proposals(10)))?;
+        track!(system.take_snapshot(leader, LogIndex::new(5), vec![1, 2, 3]))?;
+        let peer = system.get_node_id(leader).expect("Never fails");
+
+        let node_id = LocalNodeId::new([0, 1, 2, 3, 4, 5, 9]);
+        run_test_with_storage(node_id, |(storage, device)| {
+            let future = BootstrapLogPrefix::new(
+                Logger::root(Discard, o!()),
+                node_id,
+                device.handle(),
+                system.rpc_service(),
+                vec![peer],
+                Duration::from_secs(5),
+            );
+            assert!(track!(wait_for(future))?);
+
+            let prefix = track!(wait_for(storage.load_log_prefix()))?;
+            let prefix = prefix.expect("The log prefix should be bootstrapped");
+            assert_eq!(prefix.tail.index, LogIndex::new(5));
+            assert_eq!(prefix.snapshot, vec![1, 2, 3]);
+            Ok(())
+        })
+    }
+}
@@ -3,7 +3,6 @@
 //! ストレージとしては`cannyls`を、RPCとしては`fibers_rpc`を使用している.
 #![warn(missing_docs)]
 #![allow(clippy::new_ret_no_self)]
-extern crate adler32;
 extern crate atomic_immut;
 extern crate bytecodec;
 extern crate byteorder;
@@ -12,7 +11,10 @@ extern crate fibers;
 #[cfg(test)]
 extern crate fibers_global;
 extern crate fibers_rpc;
+extern crate frugalos_core;
 extern crate futures;
+#[macro_use]
+extern crate lazy_static;
 extern crate prometrics;
 #[macro_use]
 extern crate protobuf_codec;
@@ -39,12 +41,16 @@ pub mod future_impls {
     pub use timer::Timeout;
 }
 
+pub use addr_table::{current_addr, set_current_addr};
+pub use bootstrap::{fetch_log_prefix, BootstrapLogPrefix};
 pub use node::{LocalNodeId, NodeId};
 pub use raft_io::RaftIo;
 pub use rpc::{Mailer, RpcMetrics, Service, ServiceHandle};
 pub use storage::{ClearLog, Storage, StorageMetrics};
 pub use timer::Timer;
 
+mod addr_table;
+mod bootstrap;
 mod node;
 mod protobuf;
 mod raft_io;
@@ -72,4 +78,20 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn witness_is_never_elected() -> TestResult {
+        let mut system = track!(System::new())?;
+        track!(system.boot_with_witnesses(3, 1))?;
+        let leader = track!(system.select_leader())?;
+
+        // ウィットネス(末尾のノード)は選挙を始めないのでリーダにはならない
+        assert_ne!(leader, 2);
+
+        // ウィットネスも投票には参加するので、コマンドは受理される
+        let command = track!(system.propose(leader, b"foo".to_vec()))?;
+        assert_eq!(command, b"foo");
+
+        Ok(())
+    }
 }
@@ -10,6 +10,8 @@ use std::str::FromStr;
 use std::u32;
 use trackable::error::ErrorKindExt;
 
+use addr_table;
+
 const LUMP_TYPE_BALLOT: u8 = 0;
 const LUMP_TYPE_LOG_ENTRY: u8 = 1;
 const LUMP_TYPE_LOG_PREFIX_INDEX: u8 = 2;
@@ -226,6 +228,14 @@ impl NodeId {
     pub fn from_raft_node_id(id: &RaftNodeId) -> Result<Self> {
         id.as_str().parse()
     }
+
+    /// ノードと通信する際に使うべき、現在のアドレスを返す.
+    ///
+    /// サーバのIPアドレスが変わっている場合には`addr`とは異なる値となる
+    /// (詳細は`set_current_addr`を参照).
+    pub fn current_addr(&self) -> SocketAddr {
+        addr_table::current_addr(self.addr)
+    }
 }
 impl fmt::Display for NodeId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -1,8 +1,7 @@
 //! https://github.com/frugalos/frugalos_raft/blob/master/schema/raft.proto
 #![allow(missing_docs)]
-use adler32;
 use bytecodec::{self, Decode, DecodeExt, EncodeExt, SizedEncode};
-use byteorder::{BigEndian, ByteOrder};
+use frugalos_core::checksum::ChecksumAlgorithm;
 use protobuf_codec::field::num::{F1, F2};
 use protobuf_codec::scalar::{Uint64Decoder, Uint64Encoder};
 use raftlog::election::Ballot;
@@ -12,6 +11,11 @@ use raftlog_protobuf;
 use std::ops::Range;
 use trackable::error::ErrorKindExt;
 
+/// 永続化されるエントリの末尾に付与されるチェックサムのアルゴリズム.
+///
+/// 既存のログとの互換性のために、変更してはいけない.
+const ENTRY_CHECKSUM: ChecksumAlgorithm = ChecksumAlgorithm::Adler32;
+
 pub fn decode_ballot(buf: &[u8]) -> Result<Ballot> {
     track!(decode_from_bytes(
         buf,
@@ -80,9 +84,13 @@ where
     track_assert!(bytes.len() >= 5, raftlog::ErrorKind::InvalidInput);
     let (payload, trailer) = bytes.split_at(bytes.len() - 5);
 
-    let checksum = adler32::adler32(payload).expect("Never fails");
-    let expected = BigEndian::read_u32(trailer);
-    track_assert_eq!(checksum, expected, raftlog::ErrorKind::InvalidInput);
+    let checksum = ENTRY_CHECKSUM.digest(payload);
+    let expected = &trailer[..checksum.as_bytes().len()];
+    track_assert_eq!(
+        checksum.as_bytes(),
+        expected,
+        raftlog::ErrorKind::InvalidInput
+    );
 
     track!(decoder.decode_from_bytes(payload)).map_err(into_raftlog_error)
 }
@@ -94,9 +102,9 @@ where
     let mut bytes = track!(encoder.encode_into_bytes(item)).map_err(into_raftlog_error)?;
 
     // append checksum
-    let checksum = adler32::adler32(&bytes[..]).expect("Never fails");
+    let checksum = ENTRY_CHECKSUM.digest(&bytes[..]);
     let mut trailer = [0; 5];
-    BigEndian::write_u32(&mut trailer[..], checksum);
+    trailer[..checksum.as_bytes().len()].copy_from_slice(checksum.as_bytes());
     bytes.extend_from_slice(&trailer[..]);
     Ok(bytes)
 }
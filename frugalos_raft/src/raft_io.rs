@@ -18,6 +18,7 @@ pub struct RaftIo {
     storage: Storage,
     mailer: Mailer,
     timer: Timer,
+    leader_committed_tail: LogIndex,
 }
 impl RaftIo {
     /// 新しい`RaftIo`インスタンスを生成する.
@@ -28,7 +29,7 @@ impl RaftIo {
         timer: Timer,
     ) -> Result<Self> {
         let node_id = storage.node_id();
-        track!(service.add_node(node_id, &mailer))?;
+        track!(service.add_node(node_id, &mailer, storage.device()))?;
         Ok(RaftIo {
             logger: storage.logger(),
             node_id,
@@ -36,8 +37,24 @@ impl RaftIo {
             storage,
             mailer,
             timer,
+            leader_committed_tail: LogIndex::new(0),
         })
     }
+
+    /// リーダから受信した`AppendEntriesCall`に含まれていた、コミット済みログの終端の最大値を返す.
+    ///
+    /// フォロワーが、自身の状態がリーダからどの程度遅れているかを見積もるために使われる.
+    /// まだ受信していない場合には`0`となる.
+    pub fn leader_committed_tail(&self) -> LogIndex {
+        self.leader_committed_tail
+    }
+
+    /// Raft の各ロールのタイムアウトを生成するタイマーを返す.
+    ///
+    /// ウィットネスとしての設定(`Timer::set_witness`)は、返されたタイマーと共有される.
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
 }
 impl Io for RaftIo {
     type SaveBallot = storage::SaveBallot;
@@ -46,9 +63,16 @@ impl Io for RaftIo {
     type LoadLog = storage::LoadLog;
     type Timeout = Timeout;
     fn try_recv_message(&mut self) -> Result<Option<Message>> {
-        self.mailer
+        let message = track!(self
+            .mailer
             .try_recv_message()
-            .map_err(|e| ErrorKind::Other.takes_over(e).into())
+            .map_err(|e| ErrorKind::Other.takes_over(e)))?;
+        if let Some(Message::AppendEntriesCall(ref m)) = message {
+            if self.leader_committed_tail < m.committed_log_tail {
+                self.leader_committed_tail = m.committed_log_tail;
+            }
+        }
+        Ok(message)
     }
     fn send_message(&mut self, message: Message) {
         let node = match message.header().destination.as_str().parse() {
@@ -95,7 +95,7 @@ impl Mailer {
             }
         }
 
-        let client = RpcClient::new(destination.addr, &self.rpc_service);
+        let client = RpcClient::new(destination.current_addr(), &self.rpc_service);
         client.send_rpc_message(message);
     }
 }
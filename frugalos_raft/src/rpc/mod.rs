@@ -1,4 +1,5 @@
-use fibers_rpc::{Cast, ProcedureId};
+use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder, Utf8Decoder, Utf8Encoder};
+use fibers_rpc::{Call, Cast, ProcedureId};
 use raftlog::message::{
     AppendEntriesCall, AppendEntriesReply, InstallSnapshotCast, RequestVoteCall, RequestVoteReply,
 };
@@ -11,7 +12,7 @@ use raftlog_protobuf::message::{
 
 pub use self::client::RpcClient;
 pub use self::mail::{Mailer, Metrics as RpcMetrics};
-pub use self::server::RpcServer;
+pub use self::server::{LogPrefixServer, RpcServer};
 pub use self::service::{Service, ServiceHandle};
 
 mod client;
@@ -68,3 +69,21 @@ impl Cast for InstallSnapshotCastRpc {
     type Encoder = InstallSnapshotCastEncoder;
     type Decoder = InstallSnapshotCastDecoder;
 }
+
+/// ノードが保持している最新のスナップショット(ログの接頭辞部分)を取得するためのRPC.
+///
+/// 要求は対象ノードの`LocalNodeId`の文字列表現.
+/// 応答はチェックサム付きで符号化された`LogPrefix`で、空の場合はスナップショットが存在しないことを示す.
+pub struct FetchLogPrefixRpc;
+impl Call for FetchLogPrefixRpc {
+    const ID: ProcedureId = ProcedureId(0x0100_0005);
+    const NAME: &'static str = "frugalos.raft.fetch_log_prefix";
+
+    type Req = String;
+    type ReqEncoder = Utf8Encoder;
+    type ReqDecoder = Utf8Decoder;
+
+    type Res = Vec<u8>;
+    type ResEncoder = BytesEncoder;
+    type ResDecoder = RemainingBytesDecoder;
+}
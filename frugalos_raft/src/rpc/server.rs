@@ -1,13 +1,17 @@
-use fibers_rpc::server::{HandleCast, NoReply};
+use fibers_rpc::server::{HandleCall, HandleCast, NoReply, Reply};
+use futures::Future;
 use prometrics::metrics::{Counter, MetricBuilder};
 use raftlog::message::{
     AppendEntriesCall, AppendEntriesReply, InstallSnapshotCast, Message, RequestVoteCall,
     RequestVoteReply,
 };
+use slog::Logger;
 
 use super::service::ServiceHandle;
+use protobuf;
 use rpc;
-use NodeId;
+use storage::Storage;
+use {LocalNodeId, NodeId, StorageMetrics};
 
 #[derive(Debug, Clone)]
 pub struct RpcServer {
@@ -66,6 +70,65 @@ impl HandleCast<rpc::InstallSnapshotCastRpc> for RpcServer {
     }
 }
 
+/// 他のノードからのスナップショットの取得要求を処理するためのサーバ.
+#[derive(Debug, Clone)]
+pub struct LogPrefixServer {
+    logger: Logger,
+    service: ServiceHandle,
+    storage_metrics: StorageMetrics,
+}
+impl LogPrefixServer {
+    pub fn new(logger: Logger, service: ServiceHandle) -> Self {
+        LogPrefixServer {
+            logger,
+            service,
+            storage_metrics: StorageMetrics::new(),
+        }
+    }
+}
+impl HandleCall<rpc::FetchLogPrefixRpc> for LogPrefixServer {
+    fn handle_call(&self, node: String) -> Reply<rpc::FetchLogPrefixRpc> {
+        let node_id: LocalNodeId = match node.parse() {
+            Err(e) => {
+                warn!(self.logger, "Malformed node id: {}", dump!(node, e));
+                return Reply::done(Vec::new());
+            }
+            Ok(id) => id,
+        };
+        let device = if let Some(device) = self.service.get_device(node_id) {
+            device
+        } else {
+            info!(self.logger, "No such node: {}", dump!(node_id));
+            return Reply::done(Vec::new());
+        };
+
+        let logger = self.logger.new(o!("node" => node_id.to_string()));
+        let storage = Storage::new(
+            logger.clone(),
+            node_id,
+            device,
+            self.storage_metrics.clone(),
+        );
+        let future = storage
+            .load_log_prefix()
+            .and_then(|prefix| match prefix {
+                None => Ok(Vec::new()),
+                Some(prefix) => track!(protobuf::encode_log_prefix(prefix)),
+            })
+            .then(move |result| match result {
+                Ok(bytes) => {
+                    info!(logger, "Sends the log prefix: {}", dump!(bytes.len()));
+                    Ok(bytes)
+                }
+                Err(e) => {
+                    warn!(logger, "Cannot load the log prefix: {}", e);
+                    Ok(Vec::new())
+                }
+            });
+        Reply::future(future)
+    }
+}
+
 /// Prometheus metrics.
 #[derive(Debug, Clone)]
 pub struct Metrics {
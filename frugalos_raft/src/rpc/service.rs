@@ -1,4 +1,5 @@
 use atomic_immut::AtomicImmut;
+use cannyls::device::DeviceHandle;
 use fibers::sync::mpsc;
 use fibers_rpc::server::ServerBuilder;
 use futures::{Async, Future, Poll, Stream};
@@ -8,11 +9,12 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use super::mail::{Mailbox, Mailer};
-use super::server::RpcServer;
+use super::server::{LogPrefixServer, RpcServer};
 use rpc;
 use LocalNodeId;
 
 type Nodes = Arc<AtomicImmut<HashMap<LocalNodeId, Mailbox>>>;
+type Devices = Arc<AtomicImmut<HashMap<LocalNodeId, DeviceHandle>>>;
 
 /// Raft用のサービス.
 ///
@@ -21,6 +23,7 @@ type Nodes = Arc<AtomicImmut<HashMap<LocalNodeId, Mailbox>>>;
 pub struct Service {
     logger: Logger,
     nodes: Nodes,
+    devices: Devices,
     command_tx: mpsc::Sender<Command>,
     command_rx: mpsc::Receiver<Command>,
 }
@@ -28,10 +31,12 @@ impl Service {
     /// 新しい`Service`インスタンスを生成する.
     pub fn new(logger: Logger, builder: &mut ServerBuilder) -> Self {
         let nodes = Arc::new(AtomicImmut::new(HashMap::new()));
+        let devices = Arc::new(AtomicImmut::new(HashMap::new()));
         let (command_tx, command_rx) = mpsc::channel();
         let this = Service {
             logger,
             nodes,
+            devices,
             command_tx,
             command_rx,
         };
@@ -40,6 +45,10 @@ impl Service {
         builder.add_cast_handler::<rpc::AppendEntriesCallRpc, _>(RpcServer::new(this.handle()));
         builder.add_cast_handler::<rpc::AppendEntriesReplyRpc, _>(RpcServer::new(this.handle()));
         builder.add_cast_handler::<rpc::InstallSnapshotCastRpc, _>(RpcServer::new(this.handle()));
+        builder.add_call_handler::<rpc::FetchLogPrefixRpc, _>(LogPrefixServer::new(
+            this.logger.clone(),
+            this.handle(),
+        ));
         this
     }
 
@@ -47,24 +56,33 @@ impl Service {
     pub fn handle(&self) -> ServiceHandle {
         ServiceHandle {
             nodes: self.nodes.clone(),
+            devices: self.devices.clone(),
             command_tx: self.command_tx.clone(),
         }
     }
 
     fn handle_command(&mut self, command: Command) {
         match command {
-            Command::AddNode(id, mailbox) => {
+            Command::AddNode(id, mailbox, device) => {
                 info!(self.logger, "Adds node: {}", dump!(id, mailbox));
 
                 let mut nodes = (&*self.nodes.load()).clone();
                 nodes.insert(id, mailbox);
                 self.nodes.store(nodes);
+
+                let mut devices = (&*self.devices.load()).clone();
+                devices.insert(id, device);
+                self.devices.store(devices);
             }
             Command::RemoveNode(id) => {
                 let mut nodes = (&*self.nodes.load()).clone();
                 let removed = nodes.remove(&id);
                 self.nodes.store(nodes);
 
+                let mut devices = (&*self.devices.load()).clone();
+                devices.remove(&id);
+                self.devices.store(devices);
+
                 info!(self.logger, "Removes node: {}", dump!(id, removed));
             }
         }
@@ -89,7 +107,7 @@ impl Future for Service {
 
 #[derive(Debug)]
 enum Command {
-    AddNode(LocalNodeId, Mailbox),
+    AddNode(LocalNodeId, Mailbox, DeviceHandle),
     RemoveNode(LocalNodeId),
 }
 
@@ -100,12 +118,18 @@ enum Command {
 #[derive(Debug, Clone)]
 pub struct ServiceHandle {
     nodes: Nodes,
+    devices: Devices,
     command_tx: mpsc::Sender<Command>,
 }
 impl ServiceHandle {
-    pub(crate) fn add_node(&self, id: LocalNodeId, mailer: &Mailer) -> Result<()> {
+    pub(crate) fn add_node(
+        &self,
+        id: LocalNodeId,
+        mailer: &Mailer,
+        device: DeviceHandle,
+    ) -> Result<()> {
         let mailbox = mailer.mailbox();
-        let command = Command::AddNode(id, mailbox);
+        let command = Command::AddNode(id, mailbox, device);
         if self.command_tx.send(command).is_err() {
             track_panic!(ErrorKind::Other, "Service down: {}", dump!(id));
         }
@@ -121,4 +145,7 @@ impl ServiceHandle {
     pub(crate) fn get_node(&self, id: LocalNodeId) -> Option<Mailbox> {
         self.nodes.load().get(&id).cloned()
     }
+    pub(crate) fn get_device(&self, id: LocalNodeId) -> Option<DeviceHandle> {
+        self.devices.load().get(&id).cloned()
+    }
 }
@@ -291,4 +291,82 @@ mod tests {
             Ok(())
         })
     }
+
+    #[test]
+    fn log_size_metrics_works() -> TestResult {
+        let node_id = LocalNodeId::new([0, 11, 222, 3, 44, 5, 68]);
+        run_test_with_storage(node_id, |(mut storage, _device)| {
+            storage.enable_log_size_metrics();
+            let term = Term::new(0);
+            let command = |size: usize| LogEntry::Command {
+                term,
+                command: vec![0; size],
+            };
+            let log_suffix = LogSuffix {
+                head: LogPosition {
+                    prev_term: term,
+                    index: LogIndex::new(0),
+                },
+                entries: vec![command(3), LogEntry::Noop { term }, command(5)],
+            };
+            wait_for(storage.save_log_suffix(&log_suffix))?;
+            let metrics = storage.handle.log_metrics.clone();
+            assert_eq!(metrics.log_suffix_entries.value(), 3.0);
+            assert_eq!(metrics.log_suffix_bytes.value(), 8.0);
+
+            let log_prefix = LogPrefix {
+                tail: LogPosition {
+                    prev_term: term,
+                    index: LogIndex::new(2),
+                },
+                config: ClusterConfig::new(BTreeSet::new()),
+                snapshot: vec![1; 10],
+            };
+            wait_for(storage.save_log_prefix(log_prefix))?;
+            assert_eq!(metrics.last_snapshot_index.value(), 2.0);
+            assert!(metrics.log_prefix_bytes.value() >= 10.0);
+
+            // スナップショットに含まれるエントリは、次の追記時に接尾部分から取り除かれる
+            let log_suffix = LogSuffix {
+                head: LogPosition {
+                    prev_term: term,
+                    index: LogIndex::new(3),
+                },
+                entries: vec![command(1)],
+            };
+            wait_for(storage.save_log_suffix(&log_suffix))?;
+            assert_eq!(metrics.log_suffix_entries.value(), 2.0);
+            assert_eq!(metrics.log_suffix_bytes.value(), 6.0);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn log_prefix_spanning_multiple_lumps_works() -> TestResult {
+        // スナップショットが複数のlumpに分割して保存され、元通りに読み込めることを確認する
+        let node_id = LocalNodeId::new([0, 11, 222, 3, 44, 5, 69]);
+        run_test_with_storage(node_id, |(mut storage, device)| {
+            storage.set_log_prefix_chunk_size(1024);
+            let term = Term::new(1);
+            let log_prefix = LogPrefix {
+                tail: LogPosition {
+                    prev_term: term,
+                    index: LogIndex::new(10),
+                },
+                config: ClusterConfig::new(BTreeSet::new()),
+                snapshot: (0..10_000).map(|i| i as u8).collect(),
+            };
+            wait_for(storage.save_log_prefix(log_prefix.clone()))?;
+            for index in 0..10 {
+                let lump_id = node_id.to_log_prefix_lump_id(index);
+                let result = wait_for(device.handle().request().head(lump_id))?;
+                assert!(result.is_some());
+            }
+
+            let loaded = wait_for(storage.load_log_prefix())?.expect("Never fails");
+            assert_eq!(loaded.tail, log_prefix.tail);
+            assert_eq!(loaded.snapshot, log_prefix.snapshot);
+            Ok(())
+        })
+    }
 }
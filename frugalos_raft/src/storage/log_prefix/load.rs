@@ -2,7 +2,8 @@ use cannyls::deadline::Deadline;
 use cannyls::lump::LumpData;
 use futures::{Async, Future, Poll};
 use raftlog::log::LogPrefix;
-use raftlog::Error;
+use raftlog::{Error, Result};
+use std::collections::VecDeque;
 use std::mem;
 use std::ops::Range;
 use std::time::Instant;
@@ -76,6 +77,9 @@ impl Future for LoadLogPrefix {
                             "[FINISH] LoadLogPrefix: {}",
                             dump!(prefix.tail, prefix.config, bytes.len())
                         );
+                        self.handle
+                            .log_metrics
+                            .set_log_prefix(prefix.tail.index, bytes.len());
                         let elapsed =
                             prometrics::timestamp::duration_to_seconds(self.started_at.elapsed());
                         self.metrics
@@ -148,21 +152,50 @@ struct LoadLogPrefixBytes {
     handle: Handle,
     prefix_index: Range<u64>,
     bytes: Vec<u8>,
-    future: Option<BoxFuture<Option<LumpData>>>,
+
+    // 読み込み中のlump群(インデックス順).
+    //
+    // 各lumpの読み込みは並行して行い、先頭から順に`bytes`に連結していく.
+    lumps: VecDeque<LoadLump>,
+    concurrency: usize,
 }
 impl LoadLogPrefixBytes {
     pub fn new(handle: Handle, prefix_index: Range<u64>) -> Self {
         assert!(prefix_index.start <= prefix_index.end);
+        let concurrency = super::io_concurrency();
         info!(
             handle.logger,
             "[START] LoadLogPrefixBytes: {}",
-            dump!(prefix_index)
+            dump!(prefix_index, concurrency)
         );
         LoadLogPrefixBytes {
             handle,
             prefix_index,
             bytes: Vec::new(),
-            future: None,
+            lumps: VecDeque::new(),
+            concurrency,
+        }
+    }
+
+    fn fill(&mut self) {
+        while self.lumps.len() < self.concurrency && self.prefix_index.start < self.prefix_index.end
+        {
+            let index = self.prefix_index.start;
+            let lump_id = self.handle.node_id.to_log_prefix_lump_id(index);
+            info!(
+                self.handle.logger,
+                "[PROGRESS] LoadLogPrefixBytes: {}",
+                dump!(index, lump_id)
+            );
+            let future = self
+                .handle
+                .device
+                .request()
+                .deadline(Deadline::Infinity)
+                .get(lump_id);
+            self.lumps
+                .push_back(LoadLump::Loading(into_box_future(future)));
+            self.prefix_index.start += 1;
         }
     }
 }
@@ -170,44 +203,56 @@ impl Future for LoadLogPrefixBytes {
     type Item = Option<Vec<u8>>;
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        while let Async::Ready(data) = track!(self.future.poll())? {
-            match data {
-                Some(Some(data)) => {
-                    self.bytes.extend_from_slice(data.as_bytes());
-                    self.prefix_index.start += 1;
-                    self.future = None;
-                }
-                Some(None) => {
-                    info!(self.handle.logger, "[FINISH] LoadLogPrefixBytes: None");
-                    return Ok(Async::Ready(None));
-                }
-                None if self.prefix_index.start == self.prefix_index.end => {
-                    let bytes = mem::replace(&mut self.bytes, Vec::new());
-                    info!(
-                        self.handle.logger,
-                        "[FINISH] LoadLogPrefixBytes: {}",
-                        dump!(bytes.len())
-                    );
-                    return Ok(Async::Ready(Some(bytes)));
-                }
-                None => {
-                    let index = self.prefix_index.start;
-                    let lump_id = self.handle.node_id.to_log_prefix_lump_id(index);
-                    info!(
-                        self.handle.logger,
-                        "[PROGRESS] LoadLogPrefixBytes: {}",
-                        dump!(index, lump_id)
-                    );
-                    let future = self
-                        .handle
-                        .device
-                        .request()
-                        .deadline(Deadline::Infinity)
-                        .get(lump_id);
-                    self.future = Some(into_box_future(future));
+        loop {
+            self.fill();
+            for lump in &mut self.lumps {
+                track!(lump.poll())?;
+            }
+
+            let mut progressed = false;
+            while let Some(LoadLump::Loaded(_)) = self.lumps.front() {
+                if let Some(LoadLump::Loaded(data)) = self.lumps.pop_front() {
+                    if let Some(data) = data {
+                        self.bytes.extend_from_slice(data.as_bytes());
+                        progressed = true;
+                    } else {
+                        info!(self.handle.logger, "[FINISH] LoadLogPrefixBytes: None");
+                        return Ok(Async::Ready(None));
+                    }
                 }
             }
+            if self.lumps.is_empty() && self.prefix_index.start == self.prefix_index.end {
+                let bytes = mem::take(&mut self.bytes);
+                info!(
+                    self.handle.logger,
+                    "[FINISH] LoadLogPrefixBytes: {}",
+                    dump!(bytes.len())
+                );
+                return Ok(Async::Ready(Some(bytes)));
+            }
+            if !progressed {
+                return Ok(Async::NotReady);
+            }
         }
-        Ok(Async::NotReady)
+    }
+}
+
+enum LoadLump {
+    Loading(BoxFuture<Option<LumpData>>),
+    Loaded(Option<LumpData>),
+}
+impl LoadLump {
+    fn poll(&mut self) -> Result<()> {
+        let data = if let LoadLump::Loading(ref mut future) = *self {
+            if let Async::Ready(data) = track!(future.poll())? {
+                data
+            } else {
+                return Ok(());
+            }
+        } else {
+            return Ok(());
+        };
+        *self = LoadLump::Loaded(data);
+        Ok(())
     }
 }
@@ -1,6 +1,36 @@
 pub use self::load::LoadLogPrefix;
 pub use self::save::SaveLogPrefix;
 
+use cannyls::lump::LumpData;
+use std::env;
+
 mod delete;
 mod load;
 mod save;
+
+/// スナップショットを構成するlump群を、並行して読み書きする際の最大並行数.
+///
+/// 環境変数`RAFT_IO_LOG_PREFIX_CONCURRENCY`で変更可能.
+/// (スナップショットの分割単位は`Storage::set_log_prefix_chunk_size`で変更可能)
+fn io_concurrency() -> usize {
+    env::var("RAFT_IO_LOG_PREFIX_CONCURRENCY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(4)
+        .max(1)
+}
+
+/// スナップショットの分割単位のデフォルト値.
+///
+/// 環境変数`RAFT_IO_MAX_LUMP_DATA_SIZE`が指定されていればその値、そうでなければlumpの最大サイズ.
+pub(crate) fn default_chunk_size() -> usize {
+    let size = env::var("RAFT_IO_MAX_LUMP_DATA_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(LumpData::MAX_SIZE);
+    clamp_chunk_size(size)
+}
+
+pub(crate) fn clamp_chunk_size(size: usize) -> usize {
+    size.max(1).min(LumpData::MAX_SIZE)
+}
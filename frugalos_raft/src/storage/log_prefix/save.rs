@@ -4,8 +4,6 @@ use fibers::sync::mpsc;
 use futures::{Async, Future, Poll};
 use raftlog::log::{LogIndex, LogPosition, LogPrefix};
 use raftlog::{Error, Result};
-use std::cmp;
-use std::env;
 use std::ops::Range;
 use std::time::Instant;
 
@@ -15,13 +13,6 @@ use super::load::LoadLogPrefixIndex;
 use protobuf;
 use util::Phase5;
 
-fn max_lump_data_size() -> usize {
-    env::var("RAFT_IO_MAX_LUMP_DATA_SIZE")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(LumpData::MAX_SIZE)
-}
-
 // #[derive(Debug)]
 pub struct SaveLogPrefix {
     handle: Handle,
@@ -36,6 +27,7 @@ pub struct SaveLogPrefix {
     old_prefix_index: Range<u64>,
     old_entries: Range<LogIndex>,
     new_head: LogPosition,
+    prefix_bytes: usize,
     event_tx: mpsc::Sender<Event>,
     started_at: Instant,
     metrics: StorageMetrics,
@@ -57,6 +49,7 @@ impl SaveLogPrefix {
             handle,
             phase,
             new_head: prefix.tail,
+            prefix_bytes: 0,
             prefix: Some(prefix),
             old_prefix_index: Range { start: 0, end: 0 },
             old_entries,
@@ -78,6 +71,7 @@ impl Future for SaveLogPrefix {
                     let prefix = self.prefix.take().expect("Never fails");
                     let future =
                         track!(SaveLogPrefixBytes::new(self.handle.clone(), index, prefix))?;
+                    self.prefix_bytes = future.prefix_bytes();
                     Phase5::B(future)
                 }
                 Phase5::B(prefix_index) => {
@@ -105,6 +99,9 @@ impl Future for SaveLogPrefix {
                         new_head: self.new_head,
                     };
                     let _ = self.event_tx.send(event);
+                    self.handle
+                        .log_metrics
+                        .set_log_prefix(self.new_head.index, self.prefix_bytes);
                     let elapsed =
                         prometrics::timestamp::duration_to_seconds(self.started_at.elapsed());
                     self.metrics
@@ -122,15 +119,27 @@ impl Future for SaveLogPrefix {
 // #[derive(Debug)]
 struct SaveLogPrefixBytes {
     handle: Handle,
-    current_index: u64,
     prefix_index: Range<u64>,
-    prefix_bytes: Vec<u8>,
-    future: Option<BoxFuture<bool>>,
+    bytes: Vec<u8>,
+    chunk_size: usize,
+
+    // 次に書き込みを開始するlumpのインデックス.
+    //
+    // lumpのデータは、書き込みの開始時に`bytes`から都度確保する.
+    next: u64,
+
+    // 書き込み中のlump群.
+    //
+    // インデックスの保存は全てのlumpの書き込み完了後に行われるので、書き込みは並行して行ってよい.
+    futures: Vec<BoxFuture<bool>>,
+    concurrency: usize,
 }
 impl SaveLogPrefixBytes {
     pub fn new(handle: Handle, old_index: Range<u64>, prefix: LogPrefix) -> Result<Self> {
-        let prefix_bytes = track!(protobuf::encode_log_prefix(prefix))?;
-        let lump_count = (prefix_bytes.len() + max_lump_data_size() - 1) / max_lump_data_size();
+        let bytes = track!(protobuf::encode_log_prefix(prefix))?;
+        let chunk_size = handle.log_prefix_chunk_size;
+        let concurrency = super::io_concurrency();
+        let lump_count = bytes.len().div_ceil(chunk_size);
         let prefix_index = Range {
             start: old_index.end,
             end: old_index.end + lump_count as u64,
@@ -138,53 +147,75 @@ impl SaveLogPrefixBytes {
         info!(
             handle.logger,
             "[START] SaveLogPrefixBytes: {}",
-            dump!(prefix_index, prefix_bytes.len())
+            dump!(prefix_index, bytes.len(), chunk_size, concurrency)
         );
         Ok(SaveLogPrefixBytes {
             handle,
-            current_index: prefix_index.start,
+            next: prefix_index.start,
             prefix_index,
-            prefix_bytes,
-            future: None,
+            bytes,
+            chunk_size,
+            futures: Vec::new(),
+            concurrency,
         })
     }
-}
-impl Future for SaveLogPrefixBytes {
-    type Item = Range<u64>;
-    type Error = Error;
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        while let Async::Ready(_) = track!(self.future.poll())? {
-            if self.current_index == self.prefix_index.end {
-                info!(self.handle.logger, "[FINISH] SaveLogPrefixBytes");
-                return Ok(Async::Ready(self.prefix_index.clone()));
-            }
 
-            let end = cmp::min(self.prefix_bytes.len(), max_lump_data_size());
-            let bytes = self.prefix_bytes.drain(0..end).collect::<Vec<_>>();
+    fn prefix_bytes(&self) -> usize {
+        self.bytes.len()
+    }
 
-            let index = self.current_index;
-            let lump_id = self.handle.node_id.to_log_prefix_lump_id(index);
-            info!(
-                self.handle.logger,
-                "[PROGRESS] SaveLogPrefixBytes: {}",
-                dump!(index, lump_id, bytes.len())
-            );
+    fn fill(&mut self) {
+        while self.futures.len() < self.concurrency && self.next < self.prefix_index.end {
+            let index = self.next;
+            self.next += 1;
 
+            let offset = (index - self.prefix_index.start) as usize * self.chunk_size;
+            let end = (offset + self.chunk_size).min(self.bytes.len());
             let data = self
                 .handle
                 .device
-                .allocate_lump_data_with_bytes(&bytes)
+                .allocate_lump_data_with_bytes(&self.bytes[offset..end])
                 .expect("Never fails");
+            let lump_id = self.handle.node_id.to_log_prefix_lump_id(index);
+            info!(
+                self.handle.logger,
+                "[PROGRESS] SaveLogPrefixBytes: {}",
+                dump!(index, lump_id, data.as_bytes().len())
+            );
             let future = self
                 .handle
                 .device
                 .request()
                 .deadline(Deadline::Infinity)
                 .put(lump_id, data);
-            self.current_index += 1;
-            self.future = Some(into_box_future(future));
+            self.futures.push(into_box_future(future));
+        }
+    }
+}
+impl Future for SaveLogPrefixBytes {
+    type Item = Range<u64>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            self.fill();
+            let mut i = 0;
+            let mut progressed = false;
+            while i < self.futures.len() {
+                if track!(self.futures[i].poll())?.is_ready() {
+                    self.futures.swap_remove(i);
+                    progressed = true;
+                } else {
+                    i += 1;
+                }
+            }
+            if self.futures.is_empty() && self.next == self.prefix_index.end {
+                info!(self.handle.logger, "[FINISH] SaveLogPrefixBytes");
+                return Ok(Async::Ready(self.prefix_index.clone()));
+            }
+            if !progressed {
+                return Ok(Async::NotReady);
+            }
         }
-        Ok(Async::NotReady)
     }
 }
 
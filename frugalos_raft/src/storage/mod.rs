@@ -1,10 +1,11 @@
 use cannyls;
+use cannyls::deadline::Deadline;
 use cannyls::device::DeviceHandle;
 use fibers::sync::mpsc;
 use futures::{Async, Future, Poll, Stream};
-use prometrics::metrics::{Histogram, HistogramBuilder, MetricBuilder};
+use prometrics::metrics::{Gauge, GaugeBuilder, Histogram, HistogramBuilder, MetricBuilder};
 use raftlog::election::Ballot;
-use raftlog::log::{LogIndex, LogPosition, LogPrefix, LogSuffix};
+use raftlog::log::{LogEntry, LogIndex, LogPosition, LogPrefix, LogSuffix};
 use raftlog::{Error, ErrorKind, Result};
 use slog::Logger;
 use std::sync::atomic::{self, AtomicUsize};
@@ -56,6 +57,9 @@ pub struct Storage {
     // というものは発生しない)
     log_suffix: LogSuffix,
 
+    // `log_suffix`に含まれるコマンドの合計バイト数.
+    log_suffix_bytes: u64,
+
     event_rx: mpsc::Receiver<Event>,
     event_tx: mpsc::Sender<Event>,
     phase: Phase,
@@ -75,8 +79,11 @@ impl Storage {
                 logger,
                 node_id,
                 device,
+                log_metrics: LogSizeMetrics::unregistered(),
+                log_prefix_chunk_size: log_prefix::default_chunk_size(),
             },
             log_suffix: LogSuffix::default(),
+            log_suffix_bytes: 0,
             event_rx,
             event_tx,
             phase: Phase::Started,
@@ -92,12 +99,61 @@ impl Storage {
         DeleteLog::new(&self.handle, self.event_tx.clone(), self.node_id())
     }
 
+    /// ログの接頭辞部分と接尾部分のサイズを、ノード毎のメトリクスとして公開する.
+    ///
+    /// 同じノードに対して複数の`Storage`が同時に存在し得るため(e.g., スナップショットの転送用)、
+    /// メトリクスの公開は、Raftノードが使用する`Storage`に対してのみ明示的に行う.
+    pub fn enable_log_size_metrics(&mut self) {
+        self.handle.log_metrics = LogSizeMetrics::new(self.handle.node_id);
+        self.update_log_suffix_metrics();
+    }
+
+    /// スナップショット(ログの接頭辞部分)を保存する際に、一つのlumpに格納する最大バイト数を設定する.
+    ///
+    /// 値は`1..=LumpData::MAX_SIZE`の範囲に丸められる.
+    /// デフォルト値は、環境変数`RAFT_IO_MAX_LUMP_DATA_SIZE`が指定されていればその値、
+    /// そうでなければ`LumpData::MAX_SIZE`.
+    pub fn set_log_prefix_chunk_size(&mut self, size: usize) {
+        self.handle.log_prefix_chunk_size = log_prefix::clamp_chunk_size(size);
+    }
+
     pub(crate) fn logger(&self) -> Logger {
         self.handle.logger.clone()
     }
     pub(crate) fn node_id(&self) -> LocalNodeId {
         self.handle.node_id
     }
+    pub(crate) fn device(&self) -> DeviceHandle {
+        self.handle.device.clone()
+    }
+
+    /// 永続化されているスナップショット(ログの接頭辞部分)を読み込む.
+    pub(crate) fn load_log_prefix(&self) -> LoadLogPrefix {
+        log_prefix::LoadLogPrefix::new(self)
+    }
+
+    /// ノードの状態(投票状況、ログの接頭辞部分および接尾部分)が一つでも永続化されているかどうかを確認する.
+    ///
+    /// 新しく追加されたノードかどうかを、Raftノードの起動前に判定するために使われる.
+    pub(crate) fn has_local_state(&self) -> BoxFuture<bool> {
+        let future = self
+            .handle
+            .device
+            .request()
+            .wait_for_running()
+            .deadline(Deadline::Immediate)
+            .list_range(self.handle.node_id.to_available_lump_id_range())
+            .map(|lump_ids| !lump_ids.is_empty());
+        into_box_future(future)
+    }
+
+    /// 他のノードから取得したスナップショットを保存する.
+    ///
+    /// `save_log_prefix`とは異なり、Raftノードの起動前に呼ばれることを想定しているので、
+    /// 初期化フェーズの状態は変更しない.
+    pub(crate) fn install_log_prefix(&mut self, prefix: LogPrefix) -> SaveLogPrefix {
+        log_prefix::SaveLogPrefix::new(self, prefix)
+    }
     #[cfg(test)]
     pub(crate) fn handle(&self) -> Handle {
         self.handle.clone()
@@ -263,6 +319,8 @@ impl Storage {
                 self.log_suffix.head.prev_term = new_head.prev_term;
                 self.log_suffix.entries.clear();
             }
+            self.log_suffix_bytes = entries_bytes(self.log_suffix.entries.iter());
+            self.update_log_suffix_metrics();
         }
         Ok(())
     }
@@ -274,6 +332,8 @@ impl Storage {
             dump!(suffix.head, suffix.entries.len())
         );
         self.log_suffix = suffix;
+        self.log_suffix_bytes = entries_bytes(self.log_suffix.entries.iter());
+        self.update_log_suffix_metrics();
         Ok(())
     }
     fn handle_log_suffix_deleted_event(&mut self) -> Result<()> {
@@ -284,6 +344,8 @@ impl Storage {
             dump!(self.log_suffix.head)
         );
         self.log_suffix = Default::default();
+        self.log_suffix_bytes = 0;
+        self.update_log_suffix_metrics();
         Ok(())
     }
     fn append_to_local_buffer(&mut self, suffix: &LogSuffix) -> Result<()> {
@@ -321,12 +383,34 @@ impl Storage {
         );
 
         // 末尾の余剰領域を削除(ロールバック)した上で、追記する
+        self.log_suffix_bytes -= entries_bytes(self.log_suffix.entries.iter().skip(offset));
+        self.log_suffix_bytes += entries_bytes(suffix.entries.iter().skip(entries_offset));
         self.log_suffix.entries.truncate(offset);
         self.log_suffix
             .entries
             .extend(suffix.entries.iter().skip(entries_offset).cloned());
+        self.update_log_suffix_metrics();
         Ok(())
     }
+    fn update_log_suffix_metrics(&self) {
+        let metrics = &self.handle.log_metrics;
+        metrics
+            .log_suffix_entries
+            .set(self.log_suffix.entries.len() as f64);
+        metrics.log_suffix_bytes.set(self.log_suffix_bytes as f64);
+    }
+}
+
+fn entries_bytes<'a, I>(entries: I) -> u64
+where
+    I: Iterator<Item = &'a LogEntry>,
+{
+    entries
+        .map(|e| match e {
+            LogEntry::Command { command, .. } => command.len() as u64,
+            LogEntry::Noop { .. } | LogEntry::Config { .. } => 0,
+        })
+        .sum()
 }
 
 #[derive(Debug, Clone)]
@@ -334,6 +418,8 @@ pub(crate) struct Handle {
     pub logger: Logger,
     pub node_id: LocalNodeId,
     pub device: DeviceHandle,
+    pub log_metrics: LogSizeMetrics,
+    pub log_prefix_chunk_size: usize,
 }
 
 #[derive(Debug)]
@@ -462,6 +548,65 @@ fn make_histogram(builder: &mut HistogramBuilder) -> Histogram {
         .expect("Never fails")
 }
 
+/// Per-node metrics for the sizes of the log of a Raft node.
+#[derive(Debug, Clone)]
+pub(crate) struct LogSizeMetrics {
+    pub(crate) log_prefix_bytes: Gauge,
+    pub(crate) log_suffix_entries: Gauge,
+    pub(crate) log_suffix_bytes: Gauge,
+    pub(crate) last_snapshot_index: Gauge,
+}
+impl LogSizeMetrics {
+    /// Makes a new `LogSizeMetrics` instance registered to the default registry.
+    fn new(node_id: LocalNodeId) -> Self {
+        let node = node_id.to_string();
+        let make_gauge = |name: &str, help: &str| {
+            GaugeBuilder::new(name)
+                .namespace("frugalos_raft")
+                .subsystem("storage")
+                .label("node", &node)
+                .help(help)
+                .default_registry()
+                .finish()
+                .expect("Never fails")
+        };
+        LogSizeMetrics {
+            log_prefix_bytes: make_gauge(
+                "log_prefix_bytes",
+                "Size of the latest snapshot (log prefix) in bytes",
+            ),
+            log_suffix_entries: make_gauge(
+                "log_suffix_entries",
+                "Number of entries in the log suffix",
+            ),
+            log_suffix_bytes: make_gauge(
+                "log_suffix_bytes",
+                "Total size of the commands in the log suffix in bytes",
+            ),
+            last_snapshot_index: make_gauge(
+                "last_snapshot_index",
+                "Log index at which the latest snapshot was taken",
+            ),
+        }
+    }
+
+    /// Makes a new `LogSizeMetrics` instance which is not exposed.
+    fn unregistered() -> Self {
+        let make_gauge = |name: &str| Gauge::new(name).expect("Never fails");
+        LogSizeMetrics {
+            log_prefix_bytes: make_gauge("log_prefix_bytes"),
+            log_suffix_entries: make_gauge("log_suffix_entries"),
+            log_suffix_bytes: make_gauge("log_suffix_bytes"),
+            last_snapshot_index: make_gauge("last_snapshot_index"),
+        }
+    }
+
+    pub(crate) fn set_log_prefix(&self, tail: LogIndex, bytes: usize) {
+        self.last_snapshot_index.set(tail.as_u64() as f64);
+        self.log_prefix_bytes.set(bytes as f64);
+    }
+}
+
 /// ログの接頭辞部分と接尾部分を削除する。
 pub enum ClearLog {
     /// ログを削除する。
@@ -70,10 +70,22 @@ impl System {
 
     /// 引数で指定されたノード数で raft クラスタを構成する。
     pub(crate) fn boot(&mut self, node_size: usize) -> raftlog::Result<()> {
+        track!(self.boot_with_witnesses(node_size, 0))
+    }
+
+    /// 引数で指定されたノード数で raft クラスタを構成する。
+    ///
+    /// 末尾の`witnesses`個のノードはウィットネスとして動作する。
+    pub(crate) fn boot_with_witnesses(
+        &mut self,
+        node_size: usize,
+        witnesses: usize,
+    ) -> raftlog::Result<()> {
         let mut nodes = Vec::new();
 
-        for _ in 0..node_size {
-            let (node_id, io, device, handle) = track!(self.make_node())?;
+        for i in 0..node_size {
+            let witness = i + witnesses >= node_size;
+            let (node_id, io, device, handle) = track!(self.make_node(witness))?;
             self.devices.push(device);
             self.handles.push(handle);
             self.members.insert(node_id.to_raft_node_id());
@@ -142,6 +154,36 @@ impl System {
         Ok(())
     }
 
+    /// 指定されたノードでスナップショットを取得し、それが保存されるまで待機する。
+    pub(crate) fn take_snapshot(
+        &mut self,
+        node: NodeIndex,
+        new_head: LogIndex,
+        snapshot: Vec<u8>,
+    ) -> raftlog::Result<()> {
+        track!(self.rlogs[node].install_snapshot(new_head, snapshot))?;
+        loop {
+            let (i, event) = track!(poll_event(&mut self.rlogs))?;
+            if let Event::SnapshotInstalled { .. } = event {
+                if i == node {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    pub(crate) fn rpc_service(&self) -> ClientServiceHandle {
+        self.rpc_service.clone()
+    }
+
+    pub(crate) fn get_node_id(&self, node: NodeIndex) -> Option<NodeId> {
+        self.handles.get(node).map(|handle| NodeId {
+            local_id: handle.node_id,
+            instance: 0,
+            addr: self.rpc_server_addr,
+        })
+    }
+
     pub fn get_handle(&self, node: NodeIndex) -> Option<Handle> {
         self.handles.get(node).cloned()
     }
@@ -156,8 +198,9 @@ impl System {
         self.devices.get(node).map(|device| device.handle())
     }
 
-    fn make_node(&mut self) -> raftlog::Result<(NodeId, RaftIo, Device, Handle)> {
+    fn make_node(&mut self, witness: bool) -> raftlog::Result<(NodeId, RaftIo, Device, Handle)> {
         let timer = Timer::new(Duration::from_millis(50), Duration::from_millis(300));
+        timer.set_witness(witness);
         let mailer = Mailer::new(fibers_global::handle(), self.rpc_service.clone(), None);
 
         let local_node_id = LocalNodeId::new([0, 0, 0, 0, 0, 0, self.node_seqno]);
@@ -1,8 +1,11 @@
 use fibers;
-use futures::{Future, Poll};
+use futures::{Async, Future, Poll};
 use raftlog::election::Role;
 use raftlog::{Error as RaftError, ErrorKind as RaftErrorKind};
 use rand::{self, Rng};
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use trackable::error::ErrorKindExt;
 
@@ -14,10 +17,13 @@ use trackable::error::ErrorKindExt;
 /// - `Role::Follower`: 常に最大タイムアウト時間
 /// - `Role::Leader`: 常に最小タイムアウト時間
 /// - `Role::Candidate`: 最小と最大の間のいずれかの値を無作為に選択
+///
+/// ただし、ウィットネスとして設定されている場合には、`Role::Follower`と`Role::Candidate`のタイムアウトは発生しない.
 #[derive(Debug, Clone)]
 pub struct Timer {
     min_timeout: Duration,
     max_timeout: Duration,
+    witness: Arc<AtomicBool>,
 }
 impl Timer {
     /// 新しい`Timer`インスタンスを生成する.
@@ -26,10 +32,52 @@ impl Timer {
         Timer {
             min_timeout,
             max_timeout,
+            witness: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    /// 環境変数で指定されたタイムアウト時間を用いて、新しい`Timer`インスタンスを生成する.
+    ///
+    /// 最小タイムアウト時間は`FRUGALOS_RAFT_MIN_TIMEOUT`、最大タイムアウト時間は`FRUGALOS_RAFT_MAX_TIMEOUT`で、
+    /// ミリ秒単位で指定する. 指定がない場合には、それぞれ 1 秒と 5 秒になる.
+    pub fn from_env() -> Self {
+        let timeout = |key, default| {
+            env::var(key)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .map_or(Duration::from_millis(default), Duration::from_millis)
+        };
+        Timer::new(
+            timeout("FRUGALOS_RAFT_MIN_TIMEOUT", 1000),
+            timeout("FRUGALOS_RAFT_MAX_TIMEOUT", 5 * 1000),
+        )
+    }
+
+    /// 最小タイムアウト時間を返す.
+    ///
+    /// リーダのハートビートの間隔であり、候補者が選挙を始めるまでの時間の下限でもある.
+    pub fn min_timeout(&self) -> Duration {
+        self.min_timeout
+    }
+
+    /// ウィットネスとして動作させるかどうかを設定する.
+    ///
+    /// ウィットネスのフォロワーはタイムアウトによって選挙を始めることがないので、
+    /// 投票には参加するが、自身がリーダに選ばれることはない.
+    /// 設定は、このインスタンスから`clone`された全てのインスタンスで共有され、次に生成されるタイムアウトから適用される.
+    pub fn set_witness(&self, witness: bool) {
+        self.witness.store(witness, Ordering::SeqCst);
+    }
+
+    /// ウィットネスとして設定されているかどうかを返す.
+    pub fn is_witness(&self) -> bool {
+        self.witness.load(Ordering::SeqCst)
+    }
+
     pub(crate) fn create_timeout(&self, role: Role) -> Timeout {
+        if self.is_witness() && role != Role::Leader {
+            return Timeout(None);
+        }
         let duration = match role {
             Role::Follower => self.max_timeout,
             Role::Candidate => {
@@ -41,7 +89,7 @@ impl Timer {
             Role::Leader => self.min_timeout,
         };
         let inner = fibers::time::timer::timeout(duration);
-        Timeout(inner)
+        Timeout(Some(inner))
     }
 }
 
@@ -52,15 +100,19 @@ fn duration_to_millis(duration: Duration) -> u64 {
 /// タイムアウトを表現した`Future`実装.
 ///
 /// `Timer`によって内部的に生成される.
+/// 中身が`None`の場合には、タイムアウトは発生しない.
 #[derive(Debug)]
-pub struct Timeout(fibers::time::timer::Timeout);
+pub struct Timeout(Option<fibers::time::timer::Timeout>);
 impl Future for Timeout {
     type Item = ();
     type Error = RaftError;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        track!(self
-            .0
-            .poll()
-            .map_err(|e| RaftErrorKind::Other.cause(e).into(),))
+        if let Some(ref mut inner) = self.0 {
+            track!(inner
+                .poll()
+                .map_err(|e| RaftErrorKind::Other.cause(e).into(),))
+        } else {
+            Ok(Async::NotReady)
+        }
     }
 }
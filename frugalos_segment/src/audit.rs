@@ -0,0 +1,336 @@
+//! オブジェクトの断片(フラグメント)の配置状況を監査するためのモジュール。
+//!
+//! 監査では各クラスタメンバに対して`head_lump`を発行するだけで、書き込みは一切行わない。
+use cannyls::deadline::Deadline;
+use cannyls::lump::LumpHeader;
+use cannyls_rpc::Client as CannyLsClient;
+use cannyls_rpc::DeviceId;
+use fibers_rpc::client::{ClientServiceHandle as RpcServiceHandle, Options as RpcOptions};
+use futures::{self, Future};
+use libfrugalos::entity::object::{ObjectId, ObjectVersion};
+use std::collections::HashMap;
+
+use config::ClusterMember;
+use util::BoxFuture;
+use Error;
+
+/// クラスタメンバが保持している断片の状態。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FragmentState {
+    /// 断片が正常に存在する。
+    Present,
+
+    /// 断片が存在しない。
+    Missing,
+
+    /// 断片は存在するが、サイズが他のメンバのものと一致しない。
+    ///
+    /// 古い(あるいは壊れた)断片が残っている可能性がある。
+    Stale,
+
+    /// メンバに問い合わせることができなかった。
+    Unreachable,
+}
+
+/// 一つのクラスタメンバに関する監査結果。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FragmentReport {
+    /// ノードの ID。
+    pub node: String,
+
+    /// デバイスの ID。
+    pub device: String,
+
+    /// 断片の状態。
+    pub state: FragmentState,
+
+    /// 断片のおおよそのサイズ(バイト単位)。
+    pub size: Option<u32>,
+
+    /// メンバへの問い合わせに失敗した場合のエラーメッセージ。
+    pub error: Option<String>,
+}
+
+/// オブジェクト単位の監査結果。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectAuditReport {
+    /// 監査対象となったオブジェクトのバージョン。
+    pub version: ObjectVersion,
+
+    /// 断片を保持しているべき各メンバの監査結果。
+    ///
+    /// メタデータのみを扱うセグメントの場合には空になる。
+    pub fragments: Vec<FragmentReport>,
+}
+impl ObjectAuditReport {
+    /// 全ての断片が正常に存在しているかどうかを返す。
+    pub fn is_healthy(&self) -> bool {
+        self.fragments
+            .iter()
+            .all(|f| f.state == FragmentState::Present)
+    }
+
+    /// 正常ではない断片の監査結果を返す。
+    pub fn problems(&self) -> impl Iterator<Item = &FragmentReport> {
+        self.fragments
+            .iter()
+            .filter(|f| f.state != FragmentState::Present)
+    }
+}
+
+/// 断片の監査結果から判定される、オブジェクトの可用性。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ObjectAvailability {
+    /// 全ての断片が正常に存在する。
+    Available,
+
+    /// 一部の断片が失われているが、残りの断片から復元可能である。
+    Degraded,
+
+    /// 復元に必要な数の断片が残っていない。
+    Lost,
+
+    /// 問い合わせに失敗したメンバがあるため、復元可能かどうかを判定できない。
+    Unknown,
+}
+
+impl ObjectAuditReport {
+    /// 復元に`required`個の断片が必要であるとして、オブジェクトの可用性を判定する。
+    ///
+    /// `Stale`な断片は復元には使えないものとして扱う。
+    pub fn availability(&self, required: usize) -> ObjectAvailability {
+        let count = |state| self.fragments.iter().filter(|f| f.state == state).count();
+        let present = count(FragmentState::Present);
+        let unreachable = count(FragmentState::Unreachable);
+        if present == self.fragments.len() {
+            ObjectAvailability::Available
+        } else if present >= required {
+            ObjectAvailability::Degraded
+        } else if present + unreachable >= required {
+            ObjectAvailability::Unknown
+        } else {
+            ObjectAvailability::Lost
+        }
+    }
+}
+
+/// セグメント内のオブジェクトの可用性の集計結果。
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AvailabilitySummary {
+    /// 調査したオブジェクトの数。
+    pub objects: u64,
+
+    /// 全ての断片が揃っているオブジェクトの数。
+    pub available: u64,
+
+    /// 復元可能だが、一部の断片が失われているオブジェクトの数。
+    pub degraded: u64,
+
+    /// 復元できないオブジェクトの数。
+    pub lost: u64,
+
+    /// 可用性を判定できなかったオブジェクトの数。
+    pub unknown: u64,
+
+    /// 復元できないオブジェクトの ID の一覧。
+    ///
+    /// 最大件数を超えた分は含まれない(件数自体は`lost`で分かる)。
+    pub lost_objects: Vec<ObjectId>,
+}
+impl AvailabilitySummary {
+    /// 一つのオブジェクトの判定結果を集計に加える。
+    ///
+    /// `lost_objects`には、最大で`max_lost_objects`個の ID が保持される。
+    pub fn add(&mut self, id: ObjectId, availability: ObjectAvailability, max_lost_objects: usize) {
+        self.objects += 1;
+        match availability {
+            ObjectAvailability::Available => self.available += 1,
+            ObjectAvailability::Degraded => self.degraded += 1,
+            ObjectAvailability::Unknown => self.unknown += 1,
+            ObjectAvailability::Lost => {
+                self.lost += 1;
+                if self.lost_objects.len() < max_lost_objects {
+                    self.lost_objects.push(id);
+                }
+            }
+        }
+    }
+
+    /// 他の集計結果を足し合わせる。
+    pub fn merge(&mut self, other: AvailabilitySummary, max_lost_objects: usize) {
+        self.objects += other.objects;
+        self.available += other.available;
+        self.degraded += other.degraded;
+        self.lost += other.lost;
+        self.unknown += other.unknown;
+        let room = max_lost_objects.saturating_sub(self.lost_objects.len());
+        self.lost_objects
+            .extend(other.lost_objects.into_iter().take(room));
+    }
+}
+
+/// `members`の各々に対して、`version`の断片が存在するかどうかを問い合わせる。
+pub(crate) fn audit_fragments(
+    members: Vec<ClusterMember>,
+    version: ObjectVersion,
+    deadline: Deadline,
+    rpc_service: RpcServiceHandle,
+    rpc_options: RpcOptions,
+) -> BoxFuture<ObjectAuditReport> {
+    let futures = members.into_iter().map(move |member| {
+        let client = CannyLsClient::new(member.node.current_addr(), rpc_service.clone());
+        let lump_id = member.make_lump_id(version);
+        let mut request = client.request();
+        request.rpc_options(rpc_options.clone());
+        request
+            .deadline(deadline)
+            .head_lump(DeviceId::new(member.device.clone()), lump_id)
+            .then(move |result| {
+                let result = result.map_err(|e| track!(Error::from(e)));
+                Ok(make_report(&member, result))
+            })
+    });
+    let future =
+        futures::future::join_all(futures.collect::<Vec<_>>()).map(move |mut fragments| {
+            mark_stale_fragments(&mut fragments);
+            ObjectAuditReport { version, fragments }
+        });
+    Box::new(future)
+}
+
+fn make_report(
+    member: &ClusterMember,
+    result: Result<Option<LumpHeader>, Error>,
+) -> FragmentReport {
+    let (state, size, error) = match result {
+        Ok(Some(header)) => (
+            FragmentState::Present,
+            Some(header.approximate_data_size),
+            None,
+        ),
+        Ok(None) => (FragmentState::Missing, None, None),
+        Err(e) => (FragmentState::Unreachable, None, Some(e.to_string())),
+    };
+    FragmentReport {
+        node: member.node.to_string(),
+        device: member.device.clone(),
+        state,
+        size,
+        error,
+    }
+}
+
+/// 多数派とはサイズが異なる断片を`Stale`とみなす。
+///
+/// 多数派が一意に定まらない場合には何もしない。
+fn mark_stale_fragments(fragments: &mut [FragmentReport]) {
+    let mut counts = HashMap::new();
+    for size in fragments.iter().filter_map(|f| f.size) {
+        *counts.entry(size).or_insert(0) += 1;
+    }
+    let max = if let Some(&max) = counts.values().max() {
+        max
+    } else {
+        return;
+    };
+    let mut majorities = counts.iter().filter(|&(_, &n)| n == max);
+    let majority = match (majorities.next(), majorities.next()) {
+        (Some((&size, _)), None) => size,
+        _ => return,
+    };
+    for f in fragments.iter_mut() {
+        if f.state == FragmentState::Present && f.size != Some(majority) {
+            f.state = FragmentState::Stale;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(node: &str, state: FragmentState, size: Option<u32>) -> FragmentReport {
+        FragmentReport {
+            node: node.to_owned(),
+            device: "dev".to_owned(),
+            state,
+            size,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn mark_stale_fragments_works() {
+        let mut fragments = vec![
+            report("a", FragmentState::Present, Some(1024)),
+            report("b", FragmentState::Present, Some(1024)),
+            report("c", FragmentState::Present, Some(512)),
+            report("d", FragmentState::Missing, None),
+        ];
+        mark_stale_fragments(&mut fragments);
+        let states = fragments.iter().map(|f| f.state).collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            vec![
+                FragmentState::Present,
+                FragmentState::Present,
+                FragmentState::Stale,
+                FragmentState::Missing
+            ]
+        );
+
+        let audit = ObjectAuditReport {
+            version: ObjectVersion(1),
+            fragments,
+        };
+        assert!(!audit.is_healthy());
+        let problems = audit
+            .problems()
+            .map(|f| f.node.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(problems, vec!["c", "d"]);
+
+        // 多数派が定まらない場合には変更しない
+        let mut fragments = vec![
+            report("a", FragmentState::Present, Some(1024)),
+            report("b", FragmentState::Present, Some(512)),
+        ];
+        mark_stale_fragments(&mut fragments);
+        assert!(fragments.iter().all(|f| f.state == FragmentState::Present));
+    }
+
+    #[test]
+    fn availability_works() {
+        use self::FragmentState::*;
+
+        let audit = |states: &[FragmentState]| ObjectAuditReport {
+            version: ObjectVersion(1),
+            fragments: states.iter().map(|&s| report("a", s, None)).collect(),
+        };
+        assert_eq!(
+            audit(&[Present, Present, Present]).availability(2),
+            ObjectAvailability::Available
+        );
+        assert_eq!(
+            audit(&[Present, Missing, Present]).availability(2),
+            ObjectAvailability::Degraded
+        );
+        assert_eq!(
+            audit(&[Present, Unreachable, Stale]).availability(2),
+            ObjectAvailability::Unknown
+        );
+        assert_eq!(
+            audit(&[Present, Missing, Stale]).availability(2),
+            ObjectAvailability::Lost
+        );
+        assert_eq!(audit(&[]).availability(0), ObjectAvailability::Available);
+
+        let mut summary = AvailabilitySummary::default();
+        summary.add(ObjectId::from("foo"), ObjectAvailability::Lost, 1);
+        summary.add(ObjectId::from("bar"), ObjectAvailability::Lost, 1);
+        summary.add(ObjectId::from("baz"), ObjectAvailability::Degraded, 1);
+        assert_eq!(summary.objects, 3);
+        assert_eq!(summary.lost, 2);
+        assert_eq!(summary.lost_objects, vec![ObjectId::from("foo")]);
+    }
+}
@@ -0,0 +1,193 @@
+//! 一つの読み込み要求が消費できる資源(復号用のメモリ、断片の取得要求の数、処理時間)を制限するための仕組み。
+//!
+//! 上限を超えた要求は`ErrorKind::BudgetExceeded`で失敗し、その旨がメトリクスに記録される。
+use fibers::time::timer::{self, Timeout};
+use futures::{Async, Future, Poll};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use trackable::error::ErrorKindExt;
+
+use config::RequestBudgetConfig;
+use metrics::RequestBudgetMetrics;
+use {Error, ErrorKind, Result};
+
+/// 要求毎の`RequestBudget`を生成する。
+#[derive(Debug, Clone)]
+pub(crate) struct RequestBudgets {
+    config: Arc<RequestBudgetConfig>,
+    metrics: RequestBudgetMetrics,
+}
+impl RequestBudgets {
+    pub fn new(config: RequestBudgetConfig, metrics: RequestBudgetMetrics) -> Self {
+        RequestBudgets {
+            config: Arc::new(config),
+            metrics,
+        }
+    }
+
+    /// 新しい要求用の`RequestBudget`を生成する。
+    pub fn start(&self) -> RequestBudget {
+        RequestBudget(Some(Arc::new(BudgetInner {
+            budgets: self.clone(),
+            fanout: AtomicUsize::new(0),
+            decode_bytes: AtomicU64::new(0),
+        })))
+    }
+
+    /// `future`の処理時間を制限する。
+    pub fn limit_duration<F>(&self, future: F) -> LimitDuration<F>
+    where
+        F: Future<Error = Error>,
+    {
+        LimitDuration {
+            future,
+            timeout: timer::timeout(self.config.max_duration),
+            budgets: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BudgetInner {
+    budgets: RequestBudgets,
+    fanout: AtomicUsize,
+    decode_bytes: AtomicU64,
+}
+
+/// 一つの要求が消費した資源の量。
+///
+/// `Clone`されたインスタンス間では、消費量が共有される。
+#[derive(Debug, Clone)]
+pub(crate) struct RequestBudget(Option<Arc<BudgetInner>>);
+impl RequestBudget {
+    /// 上限を持たないインスタンスを生成する。
+    ///
+    /// リペア等の、内部的な処理のための読み込みで使用される。
+    pub fn unlimited() -> Self {
+        RequestBudget(None)
+    }
+
+    /// `n`個の断片の取得要求の発行を記録する。
+    pub fn consume_fanout(&self, n: usize) -> Result<()> {
+        let inner = if let Some(ref inner) = self.0 {
+            inner
+        } else {
+            return Ok(());
+        };
+        let max = inner.budgets.config.max_fanout;
+        let fanout = inner
+            .fanout
+            .fetch_add(n, Ordering::SeqCst)
+            .saturating_add(n);
+        if fanout > max {
+            inner.budgets.metrics.fanout_exceeded_total.increment();
+            track_panic!(
+                ErrorKind::BudgetExceeded,
+                "Too many fragment requests: fanout={}, max_fanout={}",
+                fanout,
+                max
+            );
+        }
+        Ok(())
+    }
+
+    /// 復号のために`n`バイトの断片(または複製)を保持することを記録する。
+    pub fn consume_decode_bytes(&self, n: u64) -> Result<()> {
+        let inner = if let Some(ref inner) = self.0 {
+            inner
+        } else {
+            return Ok(());
+        };
+        let max = inner.budgets.config.max_decode_bytes;
+        let bytes = inner
+            .decode_bytes
+            .fetch_add(n, Ordering::SeqCst)
+            .saturating_add(n);
+        if bytes > max {
+            inner
+                .budgets
+                .metrics
+                .decode_bytes_exceeded_total
+                .increment();
+            track_panic!(
+                ErrorKind::BudgetExceeded,
+                "Too large decode buffer: bytes={}, max_decode_bytes={}",
+                bytes,
+                max
+            );
+        }
+        Ok(())
+    }
+}
+
+/// 処理時間の上限を超えた場合に、`ErrorKind::BudgetExceeded`で失敗する`Future`。
+#[derive(Debug)]
+pub(crate) struct LimitDuration<F> {
+    future: F,
+    timeout: Timeout,
+    budgets: RequestBudgets,
+}
+impl<F> Future for LimitDuration<F>
+where
+    F: Future<Error = Error>,
+{
+    type Item = F::Item;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(item) = track!(self.future.poll())? {
+            return Ok(Async::Ready(item));
+        }
+        if let Ok(Async::NotReady) = self.timeout.poll() {
+            return Ok(Async::NotReady);
+        }
+        self.budgets.metrics.duration_exceeded_total.increment();
+        let cause = format!(
+            "Request duration exceeded: max_duration={:?}",
+            self.budgets.config.max_duration
+        );
+        Err(track!(Error::from(ErrorKind::BudgetExceeded.cause(cause))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frugalos_core::metrics::MetricLabels;
+
+    fn budgets() -> RequestBudgets {
+        let config = RequestBudgetConfig {
+            max_decode_bytes: 100,
+            max_fanout: 3,
+            ..Default::default()
+        };
+        RequestBudgets::new(
+            config,
+            RequestBudgetMetrics::new(&MetricLabels::default()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn budget_is_shared_within_request() {
+        let budgets = budgets();
+        let budget = budgets.start();
+        assert!(budget.consume_fanout(2).is_ok());
+        assert!(budget.clone().consume_fanout(1).is_ok());
+        let e = budget.consume_fanout(1).err().unwrap();
+        assert!(matches!(*e.kind(), ErrorKind::BudgetExceeded));
+
+        assert!(budget.consume_decode_bytes(100).is_ok());
+        assert!(budget.consume_decode_bytes(1).is_err());
+
+        // 別の要求の消費量とは独立している
+        let budget = budgets.start();
+        assert!(budget.consume_fanout(3).is_ok());
+        assert!(budget.consume_decode_bytes(100).is_ok());
+    }
+
+    #[test]
+    fn unlimited_budget_never_fails() {
+        let budget = RequestBudget::unlimited();
+        assert!(budget.consume_fanout(usize::MAX).is_ok());
+        assert!(budget.consume_decode_bytes(u64::MAX).is_ok());
+    }
+}
@@ -0,0 +1,464 @@
+//! 読み込んだオブジェクトの内容をセグメント毎に保持するキャッシュ。
+//!
+//! オブジェクトの内容はバージョン毎に不変なので、バージョンをキーとしており無効化は不要。
+//!
+//! 単純な LRU では、一度しか読まれない大きなオブジェクトを順に走査されると、
+//! 頻繁に読まれるオブジェクトが全て追い出されてしまう。
+//! そのため TinyLFU 風の受け入れ方針を採用し、容量に空きがない場合には、
+//! 追加候補の推定アクセス頻度が追い出し対象(最も長く使われていないもの)よりも
+//! 高い場合にのみ追加する。
+use frugalos_core::memory::MemoryTracker;
+use libfrugalos::entity::object::ObjectVersion;
+use siphasher::sip::SipHasher13;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use config::{ContentCacheConfig, Storage};
+use metrics::ContentCacheMetrics;
+
+/// キャッシュの容量を決める、バケツの種類。
+#[allow(missing_docs)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheClass {
+    Replicated,
+    Dispersed,
+}
+impl CacheClass {
+    /// ストレージの種類に対応するクラスを返す。
+    ///
+    /// 内容がメタデータとして MDS に保存されるバケツは、キャッシュの対象外なので`None`となる。
+    pub fn from_storage(storage: &Storage) -> Option<Self> {
+        match *storage {
+            Storage::Metadata => None,
+            Storage::Replicated(_) => Some(CacheClass::Replicated),
+            Storage::Dispersed(_) => Some(CacheClass::Dispersed),
+        }
+    }
+}
+
+/// クラス毎の、セグメント当たりのキャッシュの容量(バイト)。
+///
+/// `Clone`されたインスタンス間で共有されるので、実行中に`set_capacity`で変更した値は
+/// 全てのセグメントのキャッシュに反映される(縮小した場合は、次のアクセス時に追い出される)。
+#[derive(Debug, Clone)]
+pub struct ContentCacheSizing {
+    replicated: Arc<AtomicU64>,
+    dispersed: Arc<AtomicU64>,
+}
+impl ContentCacheSizing {
+    /// 設定に従って、新しいインスタンスを生成する。
+    pub fn new(config: &ContentCacheConfig) -> Self {
+        ContentCacheSizing {
+            replicated: Arc::new(AtomicU64::new(config.replicated_capacity)),
+            dispersed: Arc::new(AtomicU64::new(config.dispersed_capacity)),
+        }
+    }
+
+    /// `class`のキャッシュの容量を返す。
+    pub fn capacity(&self, class: CacheClass) -> u64 {
+        self.counter(class).load(Ordering::SeqCst)
+    }
+
+    /// `class`のキャッシュの容量を変更する。`0`の場合にはキャッシュが無効となる。
+    pub fn set_capacity(&self, class: CacheClass, capacity: u64) {
+        self.counter(class).store(capacity, Ordering::SeqCst);
+    }
+
+    fn counter(&self, class: CacheClass) -> &AtomicU64 {
+        match class {
+            CacheClass::Replicated => &self.replicated,
+            CacheClass::Dispersed => &self.dispersed,
+        }
+    }
+}
+impl Default for ContentCacheSizing {
+    fn default() -> Self {
+        Self::new(&ContentCacheConfig::default())
+    }
+}
+
+/// セグメントのキャッシュの統計情報。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentCacheStats {
+    /// キャッシュから内容を返した回数。
+    pub hits: u64,
+
+    /// キャッシュに内容がなかった回数。
+    pub misses: u64,
+
+    /// 保持しているオブジェクトの数。
+    pub entries: u64,
+
+    /// 保持している内容の合計バイト数。
+    pub bytes: u64,
+
+    /// 容量を空けるために追い出したオブジェクトの数。
+    pub evictions: u64,
+
+    /// 推定アクセス頻度が低いために、追加しなかったオブジェクトの数。
+    pub rejections: u64,
+}
+impl ContentCacheStats {
+    /// ヒット率を返す。まだ一度もアクセスがない場合は`0.0`となる。
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// 一つのセグメントのキャッシュ。
+///
+/// `Clone`されたインスタンス間では内容が共有される。
+#[derive(Debug, Clone)]
+pub(crate) struct ContentCache {
+    class: Option<CacheClass>,
+    sizing: ContentCacheSizing,
+    max_entry_size: u64,
+    inner: Arc<Mutex<CacheInner>>,
+}
+impl ContentCache {
+    pub fn new(
+        class: Option<CacheClass>,
+        sizing: ContentCacheSizing,
+        config: &ContentCacheConfig,
+        metrics: ContentCacheMetrics,
+    ) -> Self {
+        let inner = CacheInner::new(config.frequency_sample_size, metrics);
+        ContentCache {
+            class,
+            sizing,
+            max_entry_size: config.max_entry_size,
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// `version`の内容がキャッシュにあれば、それを返す。
+    pub fn get(&self, version: ObjectVersion) -> Option<Vec<u8>> {
+        let class = self.class?;
+        let capacity = self.sizing.capacity(class);
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.shrink(capacity);
+        if capacity == 0 {
+            return None;
+        }
+        inner.get(version)
+    }
+
+    /// 読み込んだ`version`の内容を、受け入れ方針に従ってキャッシュに追加する。
+    pub fn insert(&self, version: ObjectVersion, content: &[u8]) {
+        let capacity = self.class.map_or(0, |c| self.sizing.capacity(c));
+        let size = content.len() as u64;
+        if capacity == 0 || size > self.max_entry_size || size > capacity {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.shrink(capacity);
+        inner.insert(version, content, capacity);
+    }
+
+    /// 保持している全ての内容を破棄する。
+    ///
+    /// 結果は破棄したオブジェクトの数。破棄したものは追い出し(`evictions`)としては数えない。
+    pub fn clear(&self) -> u64 {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.clear()
+    }
+
+    /// 統計情報を返す。
+    pub fn stats(&self) -> ContentCacheStats {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.stats.clone()
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    content: Vec<u8>,
+    last_access: u64,
+}
+
+#[derive(Debug)]
+struct CacheInner {
+    entries: HashMap<ObjectVersion, CacheEntry>,
+
+    // 最終アクセス時刻(論理時刻)から、バージョンへのマップ(先頭が追い出し対象)
+    lru: BTreeMap<u64, ObjectVersion>,
+    clock: u64,
+    sketch: FrequencySketch,
+    stats: ContentCacheStats,
+    metrics: ContentCacheMetrics,
+    memory: MemoryTracker,
+}
+impl CacheInner {
+    fn new(sample_size: u64, metrics: ContentCacheMetrics) -> Self {
+        CacheInner {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            sketch: FrequencySketch::new(sample_size),
+            stats: ContentCacheStats::default(),
+            metrics,
+            memory: MemoryTracker::new("content_cache"),
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn get(&mut self, version: ObjectVersion) -> Option<Vec<u8>> {
+        self.sketch.increment(version);
+        let now = self.tick();
+        if let Some(entry) = self.entries.get_mut(&version) {
+            self.lru.remove(&entry.last_access);
+            self.lru.insert(now, version);
+            entry.last_access = now;
+            self.stats.hits += 1;
+            self.metrics.hits_total.increment();
+            Some(entry.content.clone())
+        } else {
+            self.stats.misses += 1;
+            self.metrics.misses_total.increment();
+            None
+        }
+    }
+
+    fn insert(&mut self, version: ObjectVersion, content: &[u8], capacity: u64) {
+        if self.entries.contains_key(&version) {
+            return;
+        }
+        let size = content.len() as u64;
+        let candidate = self.sketch.estimate(version);
+        let mut victims = Vec::new();
+        let mut freed = 0;
+        for victim in self.lru.values() {
+            if self.stats.bytes - freed + size <= capacity {
+                break;
+            }
+            if self.sketch.estimate(*victim) >= candidate {
+                self.stats.rejections += 1;
+                return;
+            }
+            freed += self.entries[victim].content.len() as u64;
+            victims.push(*victim);
+        }
+        for victim in victims {
+            self.evict(victim);
+        }
+
+        let now = self.tick();
+        self.lru.insert(now, version);
+        self.entries.insert(
+            version,
+            CacheEntry {
+                content: content.to_owned(),
+                last_access: now,
+            },
+        );
+        self.stats.entries += 1;
+        self.stats.bytes += size;
+        self.report_memory();
+    }
+
+    fn shrink(&mut self, capacity: u64) {
+        while self.stats.bytes > capacity {
+            let victim = *self.lru.values().next().expect("Never fails");
+            self.evict(victim);
+        }
+        self.report_memory();
+    }
+
+    fn evict(&mut self, version: ObjectVersion) {
+        if let Some(entry) = self.entries.remove(&version) {
+            self.lru.remove(&entry.last_access);
+            self.stats.entries -= 1;
+            self.stats.bytes -= entry.content.len() as u64;
+            self.stats.evictions += 1;
+            self.metrics.evictions_total.increment();
+        }
+    }
+
+    fn clear(&mut self) -> u64 {
+        let entries = self.stats.entries;
+        self.entries.clear();
+        self.lru.clear();
+        self.stats.entries = 0;
+        self.stats.bytes = 0;
+        self.report_memory();
+        entries
+    }
+
+    fn report_memory(&self) {
+        let sketch_bytes = self.sketch.counters.len() as u64;
+        self.memory.set(self.stats.bytes + sketch_bytes);
+    }
+}
+
+/// アクセス頻度を推定するための Count-Min Sketch。
+///
+/// 古いアクセスの影響が残り続けないように、`sample_size`回の記録毎に全ての値を半分にする。
+#[derive(Debug)]
+struct FrequencySketch {
+    counters: Vec<u8>,
+    width: usize,
+    additions: u64,
+    sample_size: u64,
+}
+impl FrequencySketch {
+    const DEPTH: usize = 4;
+    const MAX_COUNT: u8 = 15;
+
+    fn new(sample_size: u64) -> Self {
+        let sample_size = sample_size.max(1);
+        let width = ((sample_size / 4) as usize).next_power_of_two().max(16);
+        FrequencySketch {
+            counters: vec![0; width * Self::DEPTH],
+            width,
+            additions: 0,
+            sample_size,
+        }
+    }
+
+    fn increment(&mut self, version: ObjectVersion) {
+        for i in 0..Self::DEPTH {
+            let j = self.index(version, i);
+            if self.counters[j] < Self::MAX_COUNT {
+                self.counters[j] += 1;
+            }
+        }
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            for c in &mut self.counters {
+                *c /= 2;
+            }
+            self.additions /= 2;
+        }
+    }
+
+    fn estimate(&self, version: ObjectVersion) -> u8 {
+        (0..Self::DEPTH)
+            .map(|i| self.counters[self.index(version, i)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn index(&self, version: ObjectVersion, row: usize) -> usize {
+        let mut hasher = SipHasher13::new_with_keys(row as u64, 0);
+        version.0.hash(&mut hasher);
+        row * self.width + (hasher.finish() as usize & (self.width - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use frugalos_core::metrics::MetricLabels;
+
+    use super::*;
+    use config::ReplicatedConfig;
+
+    fn cache(capacity: u64) -> ContentCache {
+        let config = ContentCacheConfig {
+            replicated_capacity: capacity,
+            max_entry_size: 100,
+            ..Default::default()
+        };
+        let sizing = ContentCacheSizing::new(&config);
+        let class = CacheClass::from_storage(&Storage::Replicated(ReplicatedConfig {
+            tolerable_faults: 1,
+        }));
+        let metrics =
+            ContentCacheMetrics::new(&MetricLabels::bucket("content_cache_test")).unwrap();
+        ContentCache::new(class, sizing, &config, metrics)
+    }
+
+    #[test]
+    fn content_cache_works() {
+        let cache = cache(30);
+        assert_eq!(cache.get(ObjectVersion(1)), None);
+        cache.insert(ObjectVersion(1), &[1; 10]);
+        assert_eq!(cache.get(ObjectVersion(1)), Some(vec![1; 10]));
+
+        // 上限を超える大きさのオブジェクトは追加されない
+        cache.insert(ObjectVersion(2), &[2; 101]);
+        assert_eq!(cache.get(ObjectVersion(2)), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        assert_eq!((stats.entries, stats.bytes), (1, 10));
+        assert_eq!(stats.hit_ratio(), 1.0 / 3.0);
+    }
+
+    #[test]
+    fn scans_do_not_evict_frequently_used_objects() {
+        let cache = cache(30);
+        for version in 0..3 {
+            for _ in 0..3 {
+                cache.get(ObjectVersion(version));
+            }
+            cache.insert(ObjectVersion(version), &[0; 10]);
+        }
+
+        // 一度しか読まれないオブジェクトは、頻繁に読まれているオブジェクトを追い出さない
+        for version in 100..200 {
+            assert_eq!(cache.get(ObjectVersion(version)), None);
+            cache.insert(ObjectVersion(version), &[0; 10]);
+        }
+        for version in 0..3 {
+            assert!(cache.get(ObjectVersion(version)).is_some());
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.evictions, 0);
+        assert_eq!(stats.rejections, 100);
+
+        // 頻繁に読まれるようになったオブジェクトは追加される
+        for _ in 0..10 {
+            cache.get(ObjectVersion(300));
+        }
+        cache.insert(ObjectVersion(300), &[0; 10]);
+        assert!(cache.get(ObjectVersion(300)).is_some());
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn capacity_can_be_changed_at_runtime() {
+        let cache = cache(30);
+        for version in 0..3 {
+            cache.insert(ObjectVersion(version), &[0; 10]);
+        }
+        assert_eq!(cache.stats().bytes, 30);
+
+        cache.sizing.set_capacity(CacheClass::Replicated, 15);
+        assert!(cache.get(ObjectVersion(2)).is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (1, 10, 2));
+
+        cache.sizing.set_capacity(CacheClass::Replicated, 0);
+        assert_eq!(cache.get(ObjectVersion(2)), None);
+        cache.insert(ObjectVersion(3), &[0; 10]);
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn clear_works() {
+        let cache = cache(30);
+        cache.insert(ObjectVersion(1), &[0; 10]);
+        cache.insert(ObjectVersion(2), &[0; 10]);
+        assert_eq!(cache.clear(), 2);
+        assert_eq!(cache.get(ObjectVersion(1)), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (0, 0, 0));
+
+        // 破棄した後も、通常通り追加できる
+        cache.insert(ObjectVersion(3), &[0; 10]);
+        assert!(cache.get(ObjectVersion(3)).is_some());
+    }
+}
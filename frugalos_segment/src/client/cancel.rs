@@ -0,0 +1,291 @@
+//! ストレージへの要求を、呼び出し元が結果を必要としなくなった時点で協調的に中断するための仕組み。
+//!
+//! `StorageClient`の読み込み系の各操作(get, get_range, head)は、それぞれ固有の`CancellationToken`を持つ。
+//! 操作の`Future`が完了前に破棄されるか、呼び出し元の期限(`Deadline::Within`)を過ぎた場合にはトークンが取り消される。
+//! 取り消し後は、応答待ちの cannyls への RPC 要求は破棄され、
+//! (hedged read やパリティ断片へのフォールバック等による)新たな要求も発行されなくなる。
+//!
+//! なお、既にストレージ側に届いている要求の処理を止めることはできない。
+//! また、MDS へのメタデータの登録後に行われる内容の書き込みは、途中で打ち切るとオブジェクトが読めなくなるので、
+//! 取り消しの対象とはしない。
+//!
+//! `Deadline::Within`は相対的な期間なので、再試行や読み込みの各段階で同じ値を渡すと、その度に期限が延びてしまう。
+//! そのため、操作の開始時に`OperationDeadline`で絶対時刻に変換し、各要求には残り時間を渡すようにしている。
+use cannyls::deadline::Deadline;
+use fibers::time::timer::{self, Timeout};
+use futures::{Async, Future, Poll};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use {Error, ErrorKind, Result};
+
+/// 一つの操作全体に対する期限。
+///
+/// `Deadline::Within`の場合には、生成された時点を起点とした絶対時刻として保持する。
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct OperationDeadline {
+    deadline: Deadline,
+    expires_at: Option<Instant>,
+}
+impl OperationDeadline {
+    /// 現在時刻を起点として、`deadline`を絶対時刻に変換する。
+    pub fn new(deadline: Deadline) -> Self {
+        let expires_at = if let Deadline::Within(d) = deadline {
+            // 期間が大きすぎて表現できない場合には、期限はないものとして扱う
+            Instant::now().checked_add(d)
+        } else {
+            None
+        };
+        OperationDeadline {
+            deadline,
+            expires_at,
+        }
+    }
+
+    /// 次に発行する要求に渡すべき期限(i.e., 残り時間)を返す。
+    ///
+    /// 既に期限を過ぎている場合には、長さ 0 の`Deadline::Within`が返される。
+    pub fn remaining(&self) -> Deadline {
+        match self.expires_at {
+            Some(t) => {
+                let now = Instant::now();
+                Deadline::Within(if t > now {
+                    t - now
+                } else {
+                    Duration::from_secs(0)
+                })
+            }
+            None => self.deadline,
+        }
+    }
+}
+
+/// 一つの操作に属する要求群の、取り消し状態。
+///
+/// `Clone`されたインスタンス間では、状態が共有される。
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CancellationToken(Arc<AtomicBool>);
+impl CancellationToken {
+    /// 取り消されていないトークンを生成する。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// トークンを取り消す。
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// トークンが取り消されているかどうかを返す。
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// 取り消されている場合には`ErrorKind::Cancelled`を返す。
+    ///
+    /// 新たな要求を発行する前に呼び出される。
+    pub fn check(&self) -> Result<()> {
+        track_assert!(
+            !self.is_cancelled(),
+            ErrorKind::Cancelled,
+            "The operation has been cancelled"
+        );
+        Ok(())
+    }
+
+    /// `future`を、トークンが取り消された時点で破棄されるようにする。
+    pub fn guard<F>(&self, future: F) -> Guarded<F>
+    where
+        F: Future<Error = Error>,
+    {
+        Guarded {
+            token: self.clone(),
+            future: Some(future),
+        }
+    }
+}
+
+/// `CancellationToken::guard`によって包まれた`Future`。
+pub(crate) struct Guarded<F> {
+    token: CancellationToken,
+    future: Option<F>,
+}
+impl<F> Future for Guarded<F>
+where
+    F: Future<Error = Error>,
+{
+    type Item = F::Item;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.token.is_cancelled() {
+            // 応答を待たずに、要求を破棄する
+            self.future = None;
+        }
+        let future = track_assert_some!(
+            self.future.as_mut(),
+            ErrorKind::Cancelled,
+            "The request has been cancelled"
+        );
+        track!(future.poll())
+    }
+}
+
+/// `StorageClient`の一つの操作を表す`Future`。
+///
+/// 完了前に破棄された場合や、期限を過ぎた場合には、その操作のトークンを取り消す。
+pub(crate) struct Cancellable<F> {
+    token: CancellationToken,
+    future: Option<F>,
+    expiry: Option<Timeout>,
+}
+impl<F> Cancellable<F>
+where
+    F: Future<Error = Error>,
+{
+    /// `deadline`が`Deadline::Within`の場合には、その期間を過ぎた時点で`future`を中断する。
+    ///
+    /// 操作全体の期限を守るために、`deadline`には`OperationDeadline::remaining`の値を渡すこと。
+    pub fn new(token: CancellationToken, future: F, deadline: Deadline) -> Self {
+        let expiry = if let Deadline::Within(d) = deadline {
+            Some(timer::timeout(d))
+        } else {
+            None
+        };
+        Cancellable {
+            token,
+            future: Some(future),
+            expiry,
+        }
+    }
+
+    fn cancel(&mut self) {
+        self.token.cancel();
+        self.future = None;
+    }
+}
+impl<F> Future for Cancellable<F>
+where
+    F: Future<Error = Error>,
+{
+    type Item = F::Item;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Ok(Async::Ready(Some(()))) = self.expiry.poll() {
+            self.expiry = None;
+            self.cancel();
+            track_panic!(ErrorKind::Cancelled, "Deadline exceeded");
+        }
+        let result = {
+            let future = track_assert_some!(
+                self.future.as_mut(),
+                ErrorKind::Cancelled,
+                "The operation has been cancelled"
+            );
+            track!(future.poll())
+        };
+        if let Ok(Async::NotReady) = result {
+        } else {
+            // 完了済みなので、破棄時にトークンを取り消す必要はない
+            self.future = None;
+        }
+        result
+    }
+}
+impl<F> Drop for Cancellable<F> {
+    fn drop(&mut self) {
+        if self.future.is_some() {
+            self.token.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fibers_global;
+    use futures::future;
+    use std::thread;
+    use trackable::result::TestResult;
+
+    #[test]
+    fn guard_works() -> TestResult {
+        let token = CancellationToken::new();
+        assert!(token.check().is_ok());
+        let result = fibers_global::execute(token.guard(future::ok::<_, Error>(1)));
+        assert_eq!(track!(result)?, 1);
+
+        token.cancel();
+        assert!(token.check().is_err());
+        let e = fibers_global::execute(token.guard(future::ok::<_, Error>(1)))
+            .err()
+            .unwrap();
+        assert!(matches!(*e.kind(), ErrorKind::Cancelled));
+        Ok(())
+    }
+
+    #[test]
+    fn cancellable_cancels_token_on_drop() -> TestResult {
+        let token = CancellationToken::new();
+        let future = Cancellable::new(
+            token.clone(),
+            future::empty::<(), Error>(),
+            Deadline::Infinity,
+        );
+        drop(future);
+        assert!(token.is_cancelled());
+
+        // 完了後に破棄されても、トークンは取り消されない
+        let token = CancellationToken::new();
+        let result = fibers_global::execute(Cancellable::new(
+            token.clone(),
+            future::ok::<_, Error>(()),
+            Deadline::Infinity,
+        ));
+        assert!(result.is_ok());
+        assert!(!token.is_cancelled());
+        Ok(())
+    }
+
+    #[test]
+    fn operation_deadline_works() {
+        let deadline = OperationDeadline::new(Deadline::Infinity);
+        assert_eq!(deadline.remaining(), Deadline::Infinity);
+
+        // 残り時間は、生成時に指定された期間を超えない
+        let deadline = OperationDeadline::new(Deadline::Within(Duration::from_secs(60)));
+        thread::sleep(Duration::from_millis(10));
+        match deadline.remaining() {
+            Deadline::Within(d) => assert!(d < Duration::from_secs(60)),
+            other => panic!("unexpected deadline: {:?}", other),
+        }
+
+        // 期限を過ぎた後は 0 となる
+        let deadline = OperationDeadline::new(Deadline::Within(Duration::from_millis(1)));
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(
+            deadline.remaining(),
+            Deadline::Within(Duration::from_secs(0))
+        );
+
+        // 表現できないほど大きな期間は、期限なしとして扱う
+        let deadline = OperationDeadline::new(Deadline::Within(Duration::from_secs(u64::MAX)));
+        assert_eq!(
+            deadline.remaining(),
+            Deadline::Within(Duration::from_secs(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn cancellable_cancels_token_on_deadline() -> TestResult {
+        let token = CancellationToken::new();
+        let inner = token.guard(future::empty::<(), Error>());
+        let deadline = Deadline::Within(Duration::from_millis(1));
+        let e = fibers_global::execute(Cancellable::new(token.clone(), inner, deadline))
+            .err()
+            .unwrap();
+        assert!(matches!(*e.kind(), ErrorKind::Cancelled));
+        assert!(token.is_cancelled());
+        Ok(())
+    }
+}
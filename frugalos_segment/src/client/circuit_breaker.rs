@@ -0,0 +1,304 @@
+//! ストレージの各メンバ(デバイス)毎のサーキットブレーカ。
+//!
+//! 読み込み要求が連続して失敗したメンバは、回路を開いて一定時間(クールダウン)の間、読み込み先の候補の末尾に回す。
+//! クールダウンが明けると半開状態となり、一つの要求だけを試験的に許可する。
+//! その要求が成功すれば回路を閉じて通常の状態に戻し、失敗すれば再び回路を開く。
+//!
+//! 末尾に回されたメンバも、他のメンバだけでは取得が完了しない場合には使用される。
+use frugalos_raft::NodeId;
+use slog::Logger;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+use config::{CircuitBreakerConfig, ClusterMember};
+use metrics::CircuitBreakerMetrics;
+
+/// サーキットブレーカの状態。
+#[allow(missing_docs)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    #[default]
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum Circuit {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { probe_started_at: Option<Instant> },
+}
+impl Circuit {
+    fn state(&self) -> CircuitState {
+        match *self {
+            Circuit::Closed { .. } => CircuitState::Closed,
+            Circuit::Open { .. } => CircuitState::Open,
+            Circuit::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+}
+impl Default for Circuit {
+    fn default() -> Self {
+        Circuit::Closed { failures: 0 }
+    }
+}
+
+/// メンバ毎のサーキットブレーカ群。
+///
+/// `Clone`されたインスタンス間では、同じ状態が共有される。
+#[derive(Debug, Clone)]
+pub(crate) struct CircuitBreakers {
+    logger: Logger,
+    config: Arc<CircuitBreakerConfig>,
+    metrics: CircuitBreakerMetrics,
+    circuits: Arc<Mutex<HashMap<(NodeId, String), Circuit>>>,
+}
+impl CircuitBreakers {
+    pub fn new(
+        logger: Logger,
+        config: CircuitBreakerConfig,
+        metrics: CircuitBreakerMetrics,
+    ) -> Self {
+        CircuitBreakers {
+            logger,
+            config: Arc::new(config),
+            metrics,
+            circuits: Arc::default(),
+        }
+    }
+
+    /// 回路が開いているメンバを、読み込み先の候補の末尾に移動する。
+    ///
+    /// 半開状態のメンバは、試験的な要求が未発行であれば通常通りに扱い、以降の要求に対しては回路が開いているものとして扱う。
+    /// それ以外のメンバの順序は維持される。
+    ///
+    /// 結果は、回路を通過できる(先頭に残された)メンバの数。
+    /// 半開状態の判定で状態が変わるので、同じメンバに対して一度の読み込みで複数回呼び出してはいけない。
+    pub fn sort(&self, candidates: &mut [ClusterMember]) -> usize {
+        if self.config.enabled {
+            self.sort_at(candidates, Instant::now())
+        } else {
+            candidates.len()
+        }
+    }
+
+    fn sort_at(&self, candidates: &mut [ClusterMember], now: Instant) -> usize {
+        let mut circuits = self.lock();
+        let mut passable = candidates.len();
+        candidates.sort_by_cached_key(|m| {
+            let blocked = circuits
+                .get_mut(&member_key(m))
+                .is_some_and(|circuit| !self.try_pass(m, circuit, now));
+            if blocked {
+                passable -= 1;
+            }
+            blocked
+        });
+        passable
+    }
+
+    /// `member`への要求を許可するかどうかを判定する。
+    fn try_pass(&self, member: &ClusterMember, circuit: &mut Circuit, now: Instant) -> bool {
+        match *circuit {
+            Circuit::Closed { .. } => true,
+            Circuit::Open { until } if now < until => false,
+            Circuit::HalfOpen {
+                probe_started_at: Some(t),
+            } if now < t + self.config.cooldown => false,
+            _ => {
+                let prev = circuit.state();
+                *circuit = Circuit::HalfOpen {
+                    probe_started_at: Some(now),
+                };
+                if prev != CircuitState::HalfOpen {
+                    self.on_transition(member, CircuitState::HalfOpen);
+                }
+                true
+            }
+        }
+    }
+
+    /// `member`への読み込み要求の結果を記録する。
+    pub fn record(&self, member: &ClusterMember, succeeded: bool) {
+        if self.config.enabled {
+            self.record_at(member, succeeded, Instant::now());
+        }
+    }
+
+    fn record_at(&self, member: &ClusterMember, succeeded: bool, now: Instant) {
+        let mut circuits = self.lock();
+        let circuit = circuits.entry(member_key(member)).or_default();
+        let next = match (&*circuit, succeeded) {
+            (&Circuit::Closed { failures: 0 }, true) => return,
+            (&Circuit::Closed { .. }, true) | (&Circuit::HalfOpen { .. }, true) => {
+                Circuit::Closed { failures: 0 }
+            }
+            (&Circuit::Closed { failures }, false)
+                if failures + 1 < self.config.failure_threshold =>
+            {
+                Circuit::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (&Circuit::Closed { .. }, false) | (&Circuit::HalfOpen { .. }, false) => {
+                Circuit::Open {
+                    until: now + self.config.cooldown,
+                }
+            }
+            // 回路が開く前に発行された要求の結果なので無視する
+            (&Circuit::Open { .. }, _) => return,
+        };
+        let prev = circuit.state();
+        *circuit = next;
+        if prev != circuit.state() {
+            self.on_transition(member, circuit.state());
+        }
+    }
+
+    /// 応答を待たずに破棄された`member`への読み込み要求を記録する。
+    ///
+    /// 半開状態であれば、次の試験的な要求を許可する。
+    pub fn record_cancelled(&self, member: &ClusterMember) {
+        if let Some(&mut Circuit::HalfOpen {
+            ref mut probe_started_at,
+        }) = self.lock().get_mut(&member_key(member))
+        {
+            *probe_started_at = None;
+        }
+    }
+
+    /// `member`の現在の状態を返す。
+    pub fn state(&self, member: &(NodeId, String)) -> CircuitState {
+        self.lock()
+            .get(member)
+            .map_or(CircuitState::Closed, Circuit::state)
+    }
+
+    fn on_transition(&self, member: &ClusterMember, state: CircuitState) {
+        match state {
+            CircuitState::Open => {
+                self.metrics.opened_total.increment();
+                warn!(
+                    self.logger,
+                    "Circuit opened: node={}, device={}", member.node, member.device
+                );
+            }
+            CircuitState::HalfOpen => {
+                self.metrics.half_opened_total.increment();
+                info!(
+                    self.logger,
+                    "Circuit half-opened: node={}, device={}", member.node, member.device
+                );
+            }
+            CircuitState::Closed => {
+                self.metrics.closed_total.increment();
+                info!(
+                    self.logger,
+                    "Circuit closed: node={}, device={}", member.node, member.device
+                );
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<(NodeId, String), Circuit>> {
+        self.circuits.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn member_key(member: &ClusterMember) -> (NodeId, String) {
+    (member.node, member.device.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frugalos_core::metrics::MetricLabels;
+    use frugalos_raft::LocalNodeId;
+    use slog::Discard;
+    use std::time::Duration;
+
+    fn member(n: u8) -> ClusterMember {
+        ClusterMember {
+            node: NodeId {
+                local_id: LocalNodeId::new([0, 0, 0, 0, 0, 0, n]),
+                instance: 0,
+                addr: ([127, 0, 0, 1], 80).into(),
+            },
+            device: format!("dev{}", n),
+        }
+    }
+
+    fn breakers() -> CircuitBreakers {
+        let config = CircuitBreakerConfig {
+            enabled: true,
+            failure_threshold: 2,
+            cooldown: Duration::from_secs(10),
+        };
+        let metrics = CircuitBreakerMetrics::new("test", &MetricLabels::default()).unwrap();
+        CircuitBreakers::new(Logger::root(Discard, o!()), config, metrics)
+    }
+
+    fn sorted(breakers: &CircuitBreakers, now: Instant) -> Vec<String> {
+        let mut candidates = vec![member(0), member(1), member(2)];
+        breakers.sort_at(&mut candidates, now);
+        candidates.into_iter().map(|m| m.device).collect()
+    }
+
+    #[test]
+    fn consecutive_failures_open_circuit() {
+        let now = Instant::now();
+        let breakers = breakers();
+
+        // 失敗が連続しなければ、回路は開かない
+        breakers.record_at(&member(0), false, now);
+        breakers.record_at(&member(0), true, now);
+        breakers.record_at(&member(0), false, now);
+        assert_eq!(
+            breakers.state(&member_key(&member(0))),
+            CircuitState::Closed
+        );
+        assert_eq!(sorted(&breakers, now), ["dev0", "dev1", "dev2"]);
+
+        breakers.record_at(&member(0), false, now);
+        assert_eq!(breakers.state(&member_key(&member(0))), CircuitState::Open);
+        assert_eq!(sorted(&breakers, now), ["dev1", "dev2", "dev0"]);
+
+        // 回路が開いているメンバは、通過できるメンバの数に含まれない
+        let mut candidates = vec![member(0), member(1), member(2)];
+        assert_eq!(breakers.sort_at(&mut candidates, now), 2);
+    }
+
+    #[test]
+    fn half_open_circuit_allows_single_probe() {
+        let now = Instant::now();
+        let breakers = breakers();
+        breakers.record_at(&member(0), false, now);
+        breakers.record_at(&member(0), false, now);
+
+        // クールダウンが明けると、一つの要求だけが許可される
+        let later = now + Duration::from_secs(10);
+        assert_eq!(sorted(&breakers, later), ["dev0", "dev1", "dev2"]);
+        assert_eq!(
+            breakers.state(&member_key(&member(0))),
+            CircuitState::HalfOpen
+        );
+        assert_eq!(sorted(&breakers, later), ["dev1", "dev2", "dev0"]);
+
+        // 試験的な要求が失敗すれば、再び回路が開く
+        breakers.record_at(&member(0), false, later);
+        assert_eq!(breakers.state(&member_key(&member(0))), CircuitState::Open);
+
+        // 試験的な要求が成功すれば、回路が閉じる
+        let later = later + Duration::from_secs(10);
+        assert_eq!(sorted(&breakers, later), ["dev0", "dev1", "dev2"]);
+        breakers.record_at(&member(0), true, later);
+        assert_eq!(
+            breakers.state(&member_key(&member(0))),
+            CircuitState::Closed
+        );
+        assert_eq!(sorted(&breakers, later), ["dev0", "dev1", "dev2"]);
+    }
+}
@@ -3,29 +3,48 @@ use cannyls::deadline::Deadline;
 use cannyls::lump::{LumpData, LumpHeader};
 use cannyls_rpc::Client as CannyLsClient;
 use cannyls_rpc::DeviceId;
-use ecpool::liberasurecode::LibErasureCoderBuilder;
-use ecpool::ErasureCoderPool;
 use fibers::time::timer;
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
+use frugalos_core::rpc_auth;
 use frugalos_core::tracer::SpanExt;
 use frugalos_raft::NodeId;
+use futures::future::Either;
 use futures::{self, Async, Future, Poll};
 use libfrugalos::entity::object::ObjectVersion;
+use prometrics::metrics::Counter;
 use rustracing::tag::{StdTag, Tag};
 use rustracing_jaeger::span::{Span, SpanHandle};
 use slog::Logger;
+use std::cmp;
 use std::mem;
+use std::ops::Range;
 use std::sync::Arc;
 use std::time::Duration;
 use trackable::error::ErrorKindExt;
 
-use client::ec::{build_ec, ErasureCoder};
-use client::storage::{append_checksum, verify_and_remove_checksum, MaybeFragment, PutAll};
+use bytes::Bytes;
+
+use audit::{audit_fragments, FragmentState, ObjectAuditReport, ObjectAvailability};
+use client::budget::RequestBudget;
+use client::cancel::CancellationToken;
+use client::circuit_breaker::CircuitBreakers;
+use client::ec::{
+    build_ec_with_coding, parse_replica, ErasureCoder, FragmentHeader, SharedErasureCoding,
+    REPLICA_MARKER,
+};
+use client::ec_pool::ErasureCodingPool;
+use client::health::{MemberHealth, MemberHealthTable};
+use client::storage::{
+    append_checksum, lump_data_with_checksum, slice_content, verify_and_remove_checksum,
+    ContentRange, Hedge, MaybeFragment, PutAll, PutDurability,
+};
 use config::{
-    CannyLsClientConfig, ClusterConfig, ClusterMember, DispersedClientConfig, DispersedConfig,
-    Participants,
+    CannyLsClientConfig, CircuitBreakerConfig, ClusterConfig, ClusterMember, DispersedClientConfig,
+    DispersedConfig, ErasureCoderConfig, FragmentFanOut, MemberHealthConfig, Participants,
 };
-use metrics::{DispersedClientMetrics, PutAllMetrics};
+use feature::{Feature, FeatureFlags};
+use metrics::{DispersedClientMetrics, FragmentFallbackMetrics, PutAllMetrics};
+use schema::{RepairObjectCast, RepairObjectRequest};
 use util::{BoxFuture, Phase};
 use {Error, ErrorKind, Result};
 
@@ -38,9 +57,14 @@ pub struct DispersedClient {
     client_config: DispersedClientConfig,
     data_fragments: usize,
     ec: ErasureCoder,
+    replication_threshold: u64,
     rpc_service: RpcServiceHandle,
+    health: MemberHealthTable,
+    features: FeatureFlags,
+    cancel: CancellationToken,
 }
 impl DispersedClient {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         logger: Logger,
         metrics: DispersedClientMetrics,
@@ -48,11 +72,21 @@ impl DispersedClient {
         config: DispersedConfig,
         client_config: DispersedClientConfig,
         rpc_service: RpcServiceHandle,
-        ec: Option<ErasureCoder>,
+        ec_config: &ErasureCoderConfig,
+        coding: SharedErasureCoding,
+        ec_pool: ErasureCodingPool,
+        member_health: MemberHealthConfig,
+        circuit_breaker: CircuitBreakerConfig,
+        features: FeatureFlags,
     ) -> Self {
         let parity_fragments = config.tolerable_faults as usize;
         let data_fragments = config.fragments as usize - parity_fragments;
-        let ec = ec.unwrap_or_else(|| build_ec(data_fragments, parity_fragments));
+        let ec = build_ec_with_coding(data_fragments, parity_fragments, coding).with_pool(ec_pool);
+        let breakers = CircuitBreakers::new(
+            logger.clone(),
+            circuit_breaker,
+            metrics.circuit_breaker.clone(),
+        );
         DispersedClient {
             logger,
             metrics,
@@ -60,10 +94,35 @@ impl DispersedClient {
             config,
             client_config,
             ec,
+            replication_threshold: ec_config.replication_threshold,
             data_fragments,
             rpc_service,
+            health: MemberHealthTable::new(member_health, breakers),
+            features,
+            cancel: CancellationToken::new(),
         }
     }
+    /// 以降に発行するストレージへの要求を、`token`が取り消された時点で中断するようにする。
+    pub(crate) fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+    /// `node`がこのストレージのメンバかどうかを返す。
+    pub fn has_member(&self, node: &NodeId) -> bool {
+        self.cluster.contains(node)
+    }
+    /// 各メンバの健全性を返す。
+    pub fn member_health(&self) -> Vec<MemberHealth> {
+        self.health.snapshot()
+    }
+    /// データおよびパリティを合わせた断片の合計数を返す。
+    pub fn fragments(&self) -> usize {
+        self.config.fragments() as usize
+    }
+    /// `content`を符号化せずに、全体の複製として格納すべきかどうかを判定する。
+    pub fn should_replicate(&self, content: &[u8]) -> bool {
+        (content.len() as u64) < self.replication_threshold
+    }
     pub fn get_fragment(
         self,
         local_node: NodeId,
@@ -96,6 +155,7 @@ impl DispersedClient {
         let future = CollectFragments::new(
             self.logger,
             self.data_fragments,
+            self.data_fragments,
             spares,
             version,
             Deadline::Infinity,
@@ -103,6 +163,11 @@ impl DispersedClient {
             self.rpc_service,
             Span::inactive().handle(),
             None,
+            None,
+            self.health,
+            RequestBudget::unlimited(),
+            self.cancel,
+            None,
         );
         ReconstructDispersedFragment {
             phase: Phase::A(future),
@@ -115,12 +180,27 @@ impl DispersedClient {
         version: ObjectVersion,
         deadline: Deadline,
         parent: SpanHandle,
+        budget: RequestBudget,
     ) -> BoxFuture<Vec<u8>> {
         let mut candidates = self
             .cluster
             .candidates(version)
             .cloned()
             .collect::<Vec<_>>();
+        // 先頭の`fragments`個のメンバが断片を保持しており、その内の先頭の`data_fragments`個はデータ断片である
+        let initial_fanout = match self.client_config.fan_out {
+            FragmentFanOut::Adaptive => self.data_fragments,
+            FragmentFanOut::All => self.config.fragments() as usize,
+        };
+        // 回路が開いているメンバは、他のメンバだけでは足りない場合を除いて、初回の要求先から外す
+        let passable = self.health.demote_open_circuits(&mut candidates);
+        let preferred_len = cmp::min(initial_fanout, candidates.len());
+        let passable = cmp::max(passable, preferred_len);
+        {
+            let (preferred, rest) = candidates[..passable].split_at_mut(preferred_len);
+            self.health.sort_by_health(preferred);
+            self.health.sort_by_health(rest);
+        }
         candidates.reverse();
 
         let span = parent.child("get_content", |span| {
@@ -129,9 +209,15 @@ impl DispersedClient {
                 .tag(Tag::new("storage.type", "dispersed"))
                 .start()
         });
-        let future = CollectFragments::new(
+        let hedge = if self.features.is_enabled(Feature::HedgedReads) {
+            Hedge::start(&self.client_config.hedge, &self.metrics.hedge)
+        } else {
+            None
+        };
+        let mut future = CollectFragments::new(
             self.logger,
             self.data_fragments,
+            initial_fanout,
             candidates,
             version,
             deadline,
@@ -139,13 +225,171 @@ impl DispersedClient {
             self.rpc_service,
             span.handle(),
             Some(timer::timeout(self.client_config.get_timeout)),
+            hedge,
+            self.health,
+            budget,
+            self.cancel,
+            Some(self.metrics.fallback.clone()),
         );
+        if self.features.is_enabled(Feature::ReadRepair) {
+            future.enable_read_repair(self.metrics.read_repairs_total.clone());
+        }
         Box::new(DispersedGet {
             phase: Phase::A(future),
             ec: self.ec.clone(),
             span,
         })
     }
+    /// オブジェクトの内、`range`の範囲のデータのみを取得する。
+    ///
+    /// 範囲を保持しているデータ断片のみを取得し、デコードを行わずに該当部分を切り出す。
+    /// 必要な断片のいずれかが取得できなかった場合には、`get`と同様にオブジェクト全体を復元してから切り出す。
+    ///
+    /// 断片の大きさを知るために、先頭のデータ断片は常に取得される。
+    pub fn get_range(
+        self,
+        version: ObjectVersion,
+        range: Range<u64>,
+        deadline: Deadline,
+        parent: SpanHandle,
+        budget: RequestBudget,
+    ) -> BoxFuture<ContentRange> {
+        // `put`時には、候補の先頭から順に断片が格納されている
+        let members = self
+            .cluster
+            .candidates(version)
+            .take(self.data_fragments)
+            .cloned()
+            .collect::<Vec<_>>();
+        let span = parent.child("get_content_range", |span| {
+            span.tag(StdTag::component(module_path!()))
+                .tag(Tag::new("object.version", version.0 as i64))
+                .tag(Tag::new("storage.type", "dispersed"))
+                .tag(Tag::new("range.start", range.start as i64))
+                .tag(Tag::new("range.end", range.end as i64))
+                .start()
+        });
+        let span_handle = span.handle();
+
+        let this = self.clone();
+        let requested = range.clone();
+        let fragment_budget = budget.clone();
+        let future = self
+            .get_data_fragment(&members[0], version, deadline, &span_handle, &budget)
+            .and_then(move |(header, fragment)| {
+                track_assert_eq!(header.index, 0, ErrorKind::Corrupted);
+                let range = cmp::min(range.start, header.object_size)
+                    ..cmp::min(range.end, header.object_size);
+                let indices = header.covering_fragments(&range);
+                track_assert!(
+                    indices.end <= members.len(),
+                    ErrorKind::Corrupted,
+                    "header={:?}, members={}",
+                    header,
+                    members.len()
+                );
+                let futures = indices
+                    .clone()
+                    .map(|i| {
+                        if i == header.index {
+                            Box::new(futures::finished((header, fragment.clone())))
+                        } else {
+                            this.get_data_fragment(
+                                &members[i],
+                                version,
+                                deadline,
+                                &span_handle,
+                                &fragment_budget,
+                            )
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let object_size = header.object_size;
+                Ok(
+                    futures::future::join_all(futures).and_then(move |fragments| {
+                        let mut content = Vec::with_capacity((range.end - range.start) as usize);
+                        for (i, (header, fragment)) in indices.zip(fragments) {
+                            track_assert_eq!(header.index, i, ErrorKind::Corrupted);
+                            content.extend_from_slice(header.slice(&fragment, &range));
+                        }
+                        Ok(ContentRange {
+                            content,
+                            object_size,
+                        })
+                    }),
+                )
+            })
+            .flatten();
+
+        let logger = self.logger.clone();
+        let future = future.or_else(move |e| {
+            if let ErrorKind::BudgetExceeded | ErrorKind::Cancelled = *e.kind() {
+                return Either::A(futures::failed(track!(e)));
+            }
+            debug!(
+                logger,
+                "Cannot read the range from data fragments (fallback to full decoding): {}", e
+            );
+            let span_handle = span.handle();
+            let future = self
+                .get(version, deadline, span_handle, budget)
+                .map(move |content| slice_content(content, &requested));
+            Either::B(future)
+        });
+        Box::new(future)
+    }
+    fn get_data_fragment(
+        &self,
+        member: &ClusterMember,
+        version: ObjectVersion,
+        deadline: Deadline,
+        parent: &SpanHandle,
+        budget: &RequestBudget,
+    ) -> BoxFuture<(FragmentHeader, Vec<u8>)> {
+        if let Err(e) = track!(self.cancel.check()) {
+            return Box::new(futures::failed(e));
+        }
+        if let Err(e) = track!(budget.consume_fanout(1)) {
+            return Box::new(futures::failed(e));
+        }
+        let budget = budget.clone();
+        let client = CannyLsClient::new(member.node.current_addr(), self.rpc_service.clone());
+        let lump_id = member.make_lump_id(version);
+        let mut span = parent.child("get_fragment", |span| {
+            span.tag(StdTag::component(module_path!()))
+                .tag(StdTag::span_kind("client"))
+                .tag(StdTag::peer_ip(member.node.current_addr().ip()))
+                .tag(StdTag::peer_port(member.node.current_addr().port()))
+                .tag(Tag::new("device", member.device.clone()))
+                .tag(Tag::new("lump", format!("{:?}", lump_id)))
+                .start()
+        });
+        let mut request = client.request();
+        request.rpc_options(self.client_config.cannyls.rpc_options());
+        let future = request
+            .deadline(deadline)
+            .get_lump(DeviceId::new(member.device.clone()), lump_id)
+            .map_err(|e| track!(Error::from(e)))
+            .and_then(move |fragment| {
+                let mut fragment = track_assert_some!(
+                    fragment,
+                    ErrorKind::Corrupted,
+                    "No such fragment: lump_id={:?}",
+                    lump_id
+                );
+                track!(budget.consume_decode_bytes(fragment.len() as u64))?;
+                track!(verify_and_remove_checksum(&mut fragment))?;
+                let header = track!(FragmentHeader::parse(&fragment))?;
+                Ok((header, fragment))
+            })
+            .then(move |result| {
+                if let Err(ref e) = result {
+                    span.log_error(e);
+                }
+                result
+            });
+        Box::new(self.cancel.guard(future))
+    }
     pub fn head(
         self,
         version: ObjectVersion,
@@ -174,15 +418,99 @@ impl DispersedClient {
             &self.client_config,
             span.handle(),
             Some(timer::timeout(self.client_config.head_timeout)),
+            self.cancel,
         ))
     }
+    pub fn audit(self, version: ObjectVersion, deadline: Deadline) -> BoxFuture<ObjectAuditReport> {
+        // 先頭の`fragments`個のメンバが一つずつ断片を保持しており、それ以降のメンバは断片を持たない
+        let fragments = self.config.fragments() as usize;
+        let members = self
+            .cluster
+            .candidates(version)
+            .take(fragments)
+            .cloned()
+            .collect();
+        audit_fragments(
+            members,
+            version,
+            deadline,
+            self.rpc_service,
+            self.client_config.cannyls.rpc_options(),
+        )
+    }
+    /// `version`の断片の有無を調べて、オブジェクトの可用性を判定する。
+    ///
+    /// 小さなオブジェクトは符号化されずに、全体の複製として各メンバに格納されている。
+    /// そのため、データ断片の数に満たない断片しか残っていない場合には、残っている断片の一つを取得し、
+    /// それが複製であれば、一つでも残っていれば復元可能なものとして判定し直す。
+    pub fn survey(
+        self,
+        version: ObjectVersion,
+        deadline: Deadline,
+    ) -> BoxFuture<ObjectAvailability> {
+        let data_fragments = self.data_fragments;
+        let members = self
+            .cluster
+            .candidates(version)
+            .cloned()
+            .collect::<Vec<_>>();
+        let rpc_service = self.rpc_service.clone();
+        let rpc_options = self.client_config.cannyls.rpc_options();
+        let future = self.audit(version, deadline).and_then(
+            move |report| -> BoxFuture<ObjectAvailability> {
+                let availability = report.availability(data_fragments);
+                match availability {
+                    ObjectAvailability::Available | ObjectAvailability::Degraded => {
+                        return Box::new(futures::finished(availability));
+                    }
+                    ObjectAvailability::Lost | ObjectAvailability::Unknown => {}
+                }
+                let member = report
+                    .fragments
+                    .iter()
+                    .zip(members)
+                    .find(|&(f, _)| f.state == FragmentState::Present)
+                    .map(|(_, m)| m);
+                let member = if let Some(member) = member {
+                    member
+                } else {
+                    return Box::new(futures::finished(availability));
+                };
+                let client = CannyLsClient::new(member.node.current_addr(), rpc_service);
+                let mut request = client.request();
+                request.rpc_options(rpc_options);
+                let future = request
+                    .deadline(deadline)
+                    .get_lump(
+                        DeviceId::new(member.device.clone()),
+                        member.make_lump_id(version),
+                    )
+                    .then(move |result| -> Result<ObjectAvailability> {
+                        let is_replica = match result {
+                            Ok(Some(mut fragment)) => {
+                                verify_and_remove_checksum(&mut fragment).is_ok()
+                                    && parse_replica(&fragment).is_some()
+                            }
+                            _ => false,
+                        };
+                        if is_replica {
+                            Ok(report.availability(1))
+                        } else {
+                            Ok(availability)
+                        }
+                    });
+                Box::new(future)
+            },
+        );
+        Box::new(future)
+    }
     pub fn put(
         self,
         version: ObjectVersion,
-        content: Vec<u8>,
+        content: Bytes,
         deadline: Deadline,
         parent: SpanHandle,
-    ) -> BoxFuture<()> {
+    ) -> BoxFuture<PutDurability> {
         let span = parent.child("put_content", |span| {
             span.tag(StdTag::component(module_path!()))
                 .tag(Tag::new("object.version", version.0 as i64))
@@ -197,6 +525,17 @@ impl DispersedClient {
             .ec
             .encode(content)
             .map_err(|e| track!(Error::from(e)))
+            .and_then(|fragments| {
+                // NOTE: `reed-solomon-erasure`による実装では断片の末尾にチェックサムの分の領域が確保済みなので、
+                // チェックサムの付与による断片の再割り当て(複製)は発生しない
+                fragments
+                    .into_iter()
+                    .map(|mut fragment| {
+                        append_checksum(&mut fragment);
+                        track!(LumpData::new(fragment).map_err(Error::from))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
             .then(move |result| {
                 if let Err(ref e) = result {
                     child.set_tag(StdTag::error);
@@ -206,6 +545,45 @@ impl DispersedClient {
                 }
                 result
             });
+        self.put_fragments(version, Box::new(future), deadline, span)
+    }
+    /// 符号化を行わずに、オブジェクト全体の複製を各断片の代わりに格納する。
+    ///
+    /// 各複製は断片と同じメンバに配置されるので、リペアや削除は通常の断片と同様に行われる。
+    pub fn put_replicas(
+        self,
+        version: ObjectVersion,
+        content: Bytes,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> BoxFuture<PutDurability> {
+        let span = parent.child("put_content", |span| {
+            span.tag(StdTag::component(module_path!()))
+                .tag(Tag::new("object.version", version.0 as i64))
+                .tag(Tag::new("storage.type", "dispersed_replica"))
+                .start()
+        });
+        // NOTE: 複製の作成とチェックサムの計算は一度だけ行い、各メンバにはその領域を共有するデータを送る
+        let replica = match track!(lump_data_with_checksum(&[&REPLICA_MARKER[..], &content])) {
+            Ok(replica) => replica,
+            Err(e) => return Box::new(futures::failed(e)),
+        };
+        let replicas = vec![replica; self.config.fragments() as usize];
+        self.put_fragments(
+            version,
+            Box::new(futures::finished(replicas)),
+            deadline,
+            span,
+        )
+    }
+    /// チェックサムが付与済みの断片群を、各メンバに格納する。
+    fn put_fragments(
+        self,
+        version: ObjectVersion,
+        fragments: BoxFuture<Vec<LumpData>>,
+        deadline: Deadline,
+        span: Span,
+    ) -> BoxFuture<PutDurability> {
         Box::new(DispersedPut {
             // NOTE: 他のメトリクスを追加するタイミングで `DispersedPut` 用の metrics に変更する
             metrics: self.metrics.put_all,
@@ -214,8 +592,9 @@ impl DispersedClient {
             deadline,
             cannyls_config: self.client_config.cannyls.clone(),
             data_fragments: self.data_fragments,
+            wait_all: !self.features.is_enabled(Feature::QuorumPutAck),
             rpc_service: self.rpc_service,
-            phase: Phase::A(Box::new(future)),
+            phase: Phase::A(fragments),
             parent: span,
         })
     }
@@ -228,17 +607,19 @@ pub struct DispersedPut {
     deadline: Deadline,
     cannyls_config: CannyLsClientConfig,
     data_fragments: usize,
+    wait_all: bool,
     rpc_service: RpcServiceHandle,
-    phase: Phase<BoxFuture<Vec<Vec<u8>>>, PutAll>,
+    phase: Phase<BoxFuture<Vec<LumpData>>, PutAll>,
     parent: Span,
 }
 impl Future for DispersedPut {
-    type Item = ();
+    type Item = PutDurability;
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         while let Async::Ready(phase) = track!(self.phase.poll().map_err(Error::from))? {
             let next = match phase {
                 Phase::A(fragments) => {
+                    // NOTE: MDS にはメタデータが登録済みなので、断片の格納要求は取り消さない
                     let parent = self.parent.handle();
                     let version = self.version;
                     let deadline = self.deadline;
@@ -248,28 +629,19 @@ impl Future for DispersedPut {
                         .cluster
                         .candidates(self.version)
                         .zip(fragments.into_iter())
-                        .map(move |(m, mut content)| {
-                            append_checksum(&mut content);
-                            let client = CannyLsClient::new(m.node.addr, rpc_service.clone());
+                        .map(move |(m, data)| {
+                            let client =
+                                CannyLsClient::new(m.node.current_addr(), rpc_service.clone());
                             let mut request = client.request();
                             request.rpc_options(cannyls_config.rpc_options());
 
                             let device_id = m.device.clone();
                             let lump_id = m.make_lump_id(version);
-                            let data = match track!(LumpData::new(content)) {
-                                Ok(data) => data,
-                                Err(error) => {
-                                    let future: BoxFuture<_> =
-                                        Box::new(futures::failed(Error::from(error)));
-                                    return future;
-                                }
-                            };
-
                             let mut span = parent.child("put_fragment", |span| {
                                 span.tag(StdTag::component(module_path!()))
                                     .tag(StdTag::span_kind("client"))
-                                    .tag(StdTag::peer_ip(m.node.addr.ip()))
-                                    .tag(StdTag::peer_port(m.node.addr.port()))
+                                    .tag(StdTag::peer_ip(m.node.current_addr().ip()))
+                                    .tag(StdTag::peer_port(m.node.current_addr().port()))
                                     .tag(Tag::new("node", m.node.local_id.to_string()))
                                     .tag(Tag::new("device.id", device_id.clone()))
                                     .tag(Tag::new("lump.id", lump_id.to_string()))
@@ -292,14 +664,17 @@ impl Future for DispersedPut {
                             );
                             future
                         });
-                    Phase::B(track!(PutAll::new(
-                        self.metrics.clone(),
-                        futures,
-                        self.data_fragments
-                    ))?)
+                    Phase::B(
+                        track!(PutAll::new(
+                            self.metrics.clone(),
+                            futures,
+                            self.data_fragments
+                        ))?
+                        .wait_all(self.wait_all),
+                    )
                 }
-                Phase::B(()) => {
-                    return Ok(Async::Ready(()));
+                Phase::B(durability) => {
+                    return Ok(Async::Ready(durability));
                 }
             };
             self.phase = next;
@@ -310,7 +685,7 @@ impl Future for DispersedPut {
 
 pub struct DispersedGet {
     phase: Phase<CollectFragments, BoxFuture<Vec<u8>>>,
-    ec: ErasureCoderPool<LibErasureCoderBuilder>,
+    ec: ErasureCoder,
     span: Span,
 }
 impl Future for DispersedGet {
@@ -320,6 +695,9 @@ impl Future for DispersedGet {
         while let Async::Ready(phase) = track!(self.phase.poll().map_err(Error::from))? {
             let next = match phase {
                 Phase::A(fragments) => {
+                    if let Some(content) = fragments.first().and_then(|f| parse_replica(f)) {
+                        return Ok(Async::Ready(content.to_owned()));
+                    }
                     let mut child = self.span.child("ec_decode", |span| {
                         span.tag(StdTag::component(module_path!()))
                             .tag(Tag::new(
@@ -328,6 +706,18 @@ impl Future for DispersedGet {
                             ))
                             .start()
                     });
+                    if child.is_sampled() {
+                        // 復号に失敗した場合の調査用に、どの断片から復号しようとしたかを残しておく
+                        let headers = fragments
+                            .iter()
+                            .map(|f| match FragmentHeader::parse(f) {
+                                Ok(h) => format!("{}:{}:{}", h.index, h.size, h.object_size),
+                                Err(_) => "corrupted".to_owned(),
+                            })
+                            .collect::<Vec<_>>();
+                        child.set_tag(|| Tag::new("fragments.count", fragments.len() as i64));
+                        child.set_tag(|| Tag::new("fragments.headers", headers.join(",")));
+                    }
                     let future: BoxFuture<_> = Box::new(
                         self.ec
                             .decode(fragments)
@@ -356,6 +746,26 @@ struct CollectFragments {
     logger: Logger,
     futures: Vec<BoxFuture<Option<Vec<u8>>>>,
     fragments: Vec<Vec<u8>>,
+
+    // `futures`の各要素が、hedged read による追加の要求かどうか
+    hedged: Vec<bool>,
+
+    // `futures`の各要素の要求先
+    requested: Vec<Option<ClusterMember>>,
+
+    // 断片が存在しないか、破損していたメンバ
+    missing: Vec<ClusterMember>,
+
+    // 欠損した断片のリペアを依頼する場合には、その回数を記録するメトリクス
+    read_repair: Option<Counter>,
+
+    // `fragments`の内、hedged read による追加の要求で得られたものの数
+    hedged_fragments: usize,
+    hedge: Option<Hedge>,
+
+    health: MemberHealthTable,
+    budget: RequestBudget,
+    cancel: CancellationToken,
     data_fragments: usize,
     spares: Vec<ClusterMember>,
     version: ObjectVersion,
@@ -379,12 +789,20 @@ struct CollectFragments {
 
     /// How long to wait before aborting the next get operation.
     next_timeout_duration: Duration,
+
+    // 最初に読み込み要求を発行する断片の数
+    initial_fanout: usize,
+
+    // 最初の読み込み要求群を発行済みかどうか(以降の要求は、失敗やタイムアウトを補うためのもの)
+    started: bool,
+    fallback_metrics: Option<FragmentFallbackMetrics>,
 }
 impl CollectFragments {
     #[allow(clippy::too_many_arguments)]
     fn new(
         logger: Logger,
         data_fragments: usize,
+        initial_fanout: usize,
         candidates: Vec<ClusterMember>,
         version: ObjectVersion,
         deadline: Deadline,
@@ -392,6 +810,11 @@ impl CollectFragments {
         rpc_service: RpcServiceHandle,
         parent: SpanHandle,
         timeout: Option<timer::Timeout>,
+        hedge: Option<Hedge>,
+        health: MemberHealthTable,
+        budget: RequestBudget,
+        cancel: CancellationToken,
+        fallback_metrics: Option<FragmentFallbackMetrics>,
     ) -> Self {
         // rand::thread_rng().shuffle(&mut candidates);
         let dummy: BoxFuture<_> = Box::new(futures::finished(None));
@@ -399,6 +822,15 @@ impl CollectFragments {
             logger: logger.clone(),
             futures: vec![dummy],
             fragments: Vec::new(),
+            hedged: vec![false],
+            requested: vec![None],
+            missing: Vec::new(),
+            read_repair: None,
+            hedged_fragments: 0,
+            hedge,
+            health,
+            budget,
+            cancel,
             data_fragments,
             spares: candidates,
             version,
@@ -408,10 +840,22 @@ impl CollectFragments {
             parent,
             timeout,
             next_timeout_duration: client_config.get_timeout,
+            initial_fanout,
+            started: false,
+            fallback_metrics,
         }
     }
     fn fill_shortage_from_spare(&mut self, mut force: bool) -> Result<()> {
-        while force || self.futures.len() + self.fragments.len() < self.data_fragments {
+        let is_fallback = self.started;
+        let timed_out = force;
+        self.started = true;
+        let required = if is_fallback {
+            self.data_fragments
+        } else {
+            let initial_fanout = cmp::min(self.initial_fanout, self.spares.len());
+            cmp::max(initial_fanout, self.data_fragments)
+        };
+        while force || self.futures.len() + self.fragments.len() < required {
             force = false;
 
             let m = track!(self
@@ -426,47 +870,146 @@ impl CollectFragments {
                                );
                                Error::from(ErrorKind::Corrupted.cause(cause))
                            }))?;
+            match self.fallback_metrics {
+                Some(ref metrics) if is_fallback && timed_out => metrics.timeout_total.increment(),
+                Some(ref metrics) if is_fallback => metrics.failure_total.increment(),
+                _ => {}
+            }
+            track!(self.request_fragment(m, false))?;
+        }
+        Ok(())
+    }
+    fn request_fragment(&mut self, m: ClusterMember, hedged: bool) -> Result<()> {
+        track!(self.cancel.check())?;
+        track!(self.budget.consume_fanout(1))?;
+        let client = CannyLsClient::new(m.node.current_addr(), self.rpc_service.clone());
+        let lump_id = m.make_lump_id(self.version);
+        debug!(
+            self.logger,
+            "[CollectFragments({},{},{}/{})] candidate={:?}, lump_id={:?}",
+            self.spares.len(),
+            self.futures.len(),
+            self.fragments.len(),
+            self.data_fragments,
+            m.node,
+            lump_id
+        );
+        let mut span = self.parent.child("collect_fragment", |span| {
+            span.tag(StdTag::component(module_path!()))
+                .tag(StdTag::span_kind("client"))
+                .tag(StdTag::peer_ip(m.node.current_addr().ip()))
+                .tag(StdTag::peer_port(m.node.current_addr().port()))
+                .tag(Tag::new("device", m.device.clone()))
+                .tag(Tag::new("lump", format!("{:?}", lump_id)))
+                .start()
+        });
 
-            let client = CannyLsClient::new(m.node.addr, self.rpc_service.clone());
-            let lump_id = m.make_lump_id(self.version);
-            debug!(
-                self.logger,
-                "[CollectFragments({},{},{}/{})] candidate={:?}, lump_id={:?}",
-                self.spares.len(),
-                self.futures.len(),
-                self.fragments.len(),
-                self.data_fragments,
-                m.node,
-                lump_id
-            );
-            let mut span = self.parent.child("collect_fragment", |span| {
-                span.tag(StdTag::component(module_path!()))
-                    .tag(StdTag::span_kind("client"))
-                    .tag(StdTag::peer_ip(m.node.addr.ip()))
-                    .tag(StdTag::peer_port(m.node.addr.port()))
-                    .tag(Tag::new("device", m.device.clone()))
-                    .tag(Tag::new("lump", format!("{:?}", lump_id)))
-                    .start()
-            });
-
-            let mut request = client.request();
-            request.rpc_options(self.cannyls_config.rpc_options());
+        let mut request = client.request();
+        request.rpc_options(self.cannyls_config.rpc_options());
 
-            let future = request
-                .deadline(self.deadline)
-                .get_lump(DeviceId::new(m.device), lump_id)
-                .then(move |result| {
-                    if let Err(ref e) = result {
-                        span.log_error(e);
-                    }
-                    result
-                });
-            let future: BoxFuture<_> = Box::new(future.map_err(|e| track!(Error::from(e))));
-            self.futures.push(future);
-        }
+        let future = request
+            .deadline(self.deadline)
+            .get_lump(DeviceId::new(m.device.clone()), lump_id);
+        let future = self.health.track(&m, future).then(move |result| {
+            match result {
+                Err(ref e) => span.log_error(e),
+                Ok(None) => span.set_tag(|| Tag::new("fragment.found", false)),
+                Ok(Some(ref f)) => span.set_tag(|| Tag::new("fragment.bytes", f.len() as i64)),
+            }
+            result
+        });
+        let future = self
+            .cancel
+            .guard(future.map_err(|e| track!(Error::from(e))));
+        let future: BoxFuture<_> = Box::new(future);
+        self.futures.push(future);
+        self.hedged.push(hedged);
+        self.requested.push(Some(m));
         Ok(())
     }
+    /// 取得の完了時に、欠損ないし破損していた断片のリペアを、それを保持すべきノードに依頼するようにする。
+    fn enable_read_repair(&mut self, metrics: Counter) {
+        self.read_repair = Some(metrics);
+    }
+    fn request_read_repairs(&self) {
+        let metrics = if let Some(ref metrics) = self.read_repair {
+            metrics
+        } else {
+            return;
+        };
+        for m in &self.missing {
+            let request = RepairObjectRequest {
+                node: m.node.local_id.to_string(),
+                version: self.version,
+            };
+            let result = rpc_auth::cast::<RepairObjectCast>(
+                &self.rpc_service,
+                m.node.current_addr(),
+                request,
+            );
+            if let Err(e) = result {
+                warn!(
+                    self.logger,
+                    "[CollectFragments] Cannot request a read repair: node={:?}, error={}",
+                    m.node,
+                    e
+                );
+            } else {
+                metrics.increment();
+            }
+        }
+    }
+    /// hedged read の待ち時間が経過していれば、予備の候補に追加の要求を発行する。
+    ///
+    /// 要求を発行した場合には`true`を返す。
+    fn hedge_if_needed(&mut self) -> Result<bool> {
+        let extra_requests = self.hedge.as_mut().map_or(0, Hedge::poll_extra_requests);
+        let mut issued = 0;
+        while issued < extra_requests {
+            if let Some(m) = self.spares.pop() {
+                track!(self.request_fragment(m, true))?;
+                issued += 1;
+            } else {
+                break;
+            }
+        }
+        if issued == 0 {
+            return Ok(false);
+        }
+        debug!(
+            self.logger,
+            "[CollectFragments] Issued {} hedged requests", issued
+        );
+        if let Some(ref hedge) = self.hedge {
+            hedge.on_triggered();
+        }
+        Ok(true)
+    }
+    fn on_completed(&self) {
+        if self.hedged_fragments > 0 {
+            if let Some(ref hedge) = self.hedge {
+                hedge.on_won();
+            }
+        }
+        self.request_read_repairs();
+    }
 }
+/// 復元に必要な数の断片が揃っていれば、それらを取り出す。
+///
+/// hedged read 等によって複数の応答が同時に届いた場合には、必要な数を超えることもあるので、
+/// 余分な断片は捨てる。
+fn take_enough_fragments(
+    fragments: &mut Vec<Vec<u8>>,
+    data_fragments: usize,
+) -> Option<Vec<Vec<u8>>> {
+    if fragments.len() < data_fragments {
+        return None;
+    }
+    let mut fragments = mem::take(fragments);
+    fragments.truncate(data_fragments);
+    Some(fragments)
+}
+
 impl Future for CollectFragments {
     type Item = Vec<Vec<u8>>;
     type Error = Error;
@@ -477,6 +1020,8 @@ impl Future for CollectFragments {
                 match track!(self.futures[i].poll()) {
                     Err(e) => {
                         self.futures.swap_remove(i);
+                        self.hedged.swap_remove(i);
+                        self.requested.swap_remove(i);
                         debug!(self.logger, "[CollectFragments] Error: {}", e);
                         track!(self.fill_shortage_from_spare(false), "Last error: {}", e)?;
                     }
@@ -485,23 +1030,43 @@ impl Future for CollectFragments {
                     }
                     Ok(Async::Ready(fragment)) => {
                         self.futures.swap_remove(i);
+                        let hedged = self.hedged.swap_remove(i);
+                        let member = self.requested.swap_remove(i);
                         if let Some(mut fragment) = fragment {
+                            track!(self.budget.consume_decode_bytes(fragment.len() as u64))?;
                             if let Err(e) = track!(verify_and_remove_checksum(&mut fragment)) {
                                 // TODO: Add protection for log overflow
                                 warn!(self.logger, "[CollectFragments] Corrupted fragment: {}", e);
+                                self.missing.extend(member);
                                 track!(self.fill_shortage_from_spare(false))?;
+                            } else if parse_replica(&fragment).is_some() {
+                                // オブジェクト全体の複製が得られたので、他の断片は不要
+                                if hedged {
+                                    self.hedged_fragments += 1;
+                                }
+                                self.on_completed();
+                                return Ok(Async::Ready(vec![fragment]));
                             } else {
+                                if hedged {
+                                    self.hedged_fragments += 1;
+                                }
                                 self.fragments.push(fragment);
                             }
                         } else {
                             debug!(self.logger, "[CollectFragments] NotFound");
+                            self.missing.extend(member);
                             track!(self.fill_shortage_from_spare(false))?;
                         }
                     }
                 }
             }
-            if self.fragments.len() == self.data_fragments {
-                return Ok(Async::Ready(mem::replace(&mut self.fragments, Vec::new())));
+            if let Some(fragments) = take_enough_fragments(&mut self.fragments, self.data_fragments)
+            {
+                self.on_completed();
+                return Ok(Async::Ready(fragments));
+            }
+            if track!(self.hedge_if_needed())? {
+                continue;
             }
             if let Ok(Async::Ready(Some(()))) = self.timeout.poll() {
                 // TODO: ログは出さなくする(かわりにprometheusを使う)
@@ -533,7 +1098,7 @@ pub struct ReconstructDispersedFragment {
     phase: Phase<CollectFragments, BoxFuture<Vec<u8>>>,
 
     /// A thread pool of encoders(by erasure code)
-    ec: ErasureCoderPool<LibErasureCoderBuilder>,
+    ec: ErasureCoder,
 
     /// The index of a focusing node.
     /// None represents that there is no missing index.
@@ -551,7 +1116,15 @@ impl Future for ReconstructDispersedFragment {
 
         while let Async::Ready(phase) = track!(self.phase.poll().map_err(Error::from))? {
             let next = match phase {
-                Phase::A(fragments) => {
+                Phase::A(mut fragments) => {
+                    if fragments
+                        .first()
+                        .is_some_and(|f| parse_replica(f).is_some())
+                    {
+                        // 複製はどの位置でも同じ内容なので、そのまま使用する
+                        let replica = fragments.swap_remove(0);
+                        return Ok(Async::Ready(MaybeFragment::Fragment(replica)));
+                    }
                     let future = self.ec.reconstruct(missing_index, fragments);
                     let future: BoxFuture<_> = Box::new(future.map_err(|e| track!(Error::from(e))));
                     Phase::B(future)
@@ -583,15 +1156,17 @@ impl DispersedHead {
         client_config: &DispersedClientConfig,
         parent: SpanHandle,
         timeout: Option<timer::Timeout>,
+        cancel: CancellationToken,
     ) -> Self {
         let futures = candidates.iter().map(move |cluster_member| {
-            let client = CannyLsClient::new(cluster_member.node.addr, rpc_service.clone());
+            let client =
+                CannyLsClient::new(cluster_member.node.current_addr(), rpc_service.clone());
             let lump_id = cluster_member.make_lump_id(version);
             let mut span = parent.child("dispersed_head", |span| {
                 span.tag(StdTag::component(module_path!()))
                     .tag(StdTag::span_kind("client"))
-                    .tag(StdTag::peer_ip(cluster_member.node.addr.ip()))
-                    .tag(StdTag::peer_port(cluster_member.node.addr.port()))
+                    .tag(StdTag::peer_ip(cluster_member.node.current_addr().ip()))
+                    .tag(StdTag::peer_port(cluster_member.node.current_addr().port()))
                     .tag(Tag::new("device", cluster_member.device.clone()))
                     .tag(Tag::new("lump", format!("{:?}", lump_id)))
                     .start()
@@ -608,7 +1183,8 @@ impl DispersedHead {
                     }
                     result
                 });
-            let future: BoxFuture<_> = Box::new(future.map_err(|e| track!(Error::from(e))));
+            let future = cancel.guard(future.map_err(|e| track!(Error::from(e))));
+            let future: BoxFuture<_> = Box::new(future);
             future
         });
         DispersedHead {
@@ -655,3 +1231,24 @@ impl Future for DispersedHead {
         Ok(Async::NotReady)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_enough_fragments_works() {
+        let mut fragments = vec![vec![0], vec![1]];
+        assert_eq!(take_enough_fragments(&mut fragments, 3), None);
+        assert_eq!(fragments.len(), 2);
+
+        // 必要な数を超えて集まった場合にも、必要な数だけを返す
+        fragments.push(vec![2]);
+        fragments.push(vec![3]);
+        assert_eq!(
+            take_enough_fragments(&mut fragments, 3),
+            Some(vec![vec![0], vec![1], vec![2]])
+        );
+        assert!(fragments.is_empty());
+    }
+}
@@ -1,15 +1,573 @@
 //! Functions and types related to erasure coding.
-use ecpool::liberasurecode::LibErasureCoderBuilder;
-use ecpool::ErasureCoderPool;
+use byteorder::{ByteOrder, LittleEndian};
+use ecpool::liberasurecode::{Backend, Checksum, LibErasureCoderBuilder};
+use ecpool::{BuildCoder, ErasureCode, Fragment, FragmentBuf};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use trackable::error::ErrorKindExt;
 
-/// ErasureCodingのエンコーダ・デコーダの型。
-pub type ErasureCoder = ErasureCoderPool<LibErasureCoderBuilder>;
+use client::ec_pool::ErasureCodingPool;
+use client::storage::CHECKSUM_TRAILER_SIZE;
+use config::{ErasureCoding, ErasureCodingBackend, ErasureCodingChecksum};
+use util::BoxFuture;
+use {Error, ErrorKind, Result};
+
+thread_local! {
+    // ワーカスレッド毎に、構築済みのエンコーダ・デコーダを保持しておく
+    static ERASURE_CODERS: RefCell<HashMap<CoderKey, Coder>> = RefCell::new(HashMap::new());
+}
+
+/// バケツで使用する Erasure Coding の実装。
+///
+/// 構成管理用クラスタでバケツの属性として設定される。
+/// `Clone`されたインスタンス間で状態が共有されるので、
+/// `set`による変更は、以降にそのバケツの全てのセグメントで行われる符号化に即座に反映される。
+///
+/// 復号は断片のヘッダに記録された実装で行われるので、変更前に保存されたオブジェクトも読み込める。
+#[derive(Debug, Clone, Default)]
+pub struct SharedErasureCoding {
+    inner: Arc<Mutex<ErasureCoding>>,
+}
+impl SharedErasureCoding {
+    /// 使用する実装を設定する。
+    pub fn set(&self, coding: ErasureCoding) {
+        *self.inner.lock().unwrap_or_else(|e| e.into_inner()) = coding;
+    }
+
+    /// 現在の実装を返す。
+    pub fn get(&self) -> ErasureCoding {
+        *self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// ErasureCodingのエンコーダ・デコーダ。
+///
+/// 符号化・復号は`ErasureCodingPool`のワーカスレッド上で実行される。
+#[derive(Debug, Clone)]
+pub struct ErasureCoder {
+    data_fragments: NonZeroUsize,
+    parity_fragments: NonZeroUsize,
+    coding: SharedErasureCoding,
+    pool: ErasureCodingPool,
+}
+impl ErasureCoder {
+    /// 指定のスレッドプールで処理を実行するインスタンスを返す。
+    pub fn with_pool(mut self, pool: ErasureCodingPool) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// データを断片群に符号化する。
+    ///
+    /// その時点でバケツに設定されている実装が使われる。
+    pub fn encode<T>(&self, data: T) -> BoxFuture<Vec<FragmentBuf>>
+    where
+        T: AsRef<[u8]> + Send + 'static,
+    {
+        let key = self.key(self.coding.get());
+        self.pool
+            .spawn(move || with_coder(key, |coder| coder.encode(data.as_ref())))
+    }
+
+    /// 断片群から元のデータを復号する。
+    pub fn decode<T>(&self, fragments: Vec<T>) -> BoxFuture<Vec<u8>>
+    where
+        T: AsRef<Fragment> + Send + 'static,
+    {
+        let key = self.decoder_key(&fragments);
+        self.pool.spawn(move || {
+            let fragments = fragments.iter().map(|f| f.as_ref()).collect::<Vec<_>>();
+            with_coder(key, |coder| coder.decode(&fragments))
+        })
+    }
+
+    /// 他の断片群から、`index`番目の断片を再構築する。
+    pub fn reconstruct<T>(&self, index: usize, fragments: Vec<T>) -> BoxFuture<Vec<u8>>
+    where
+        T: AsRef<Fragment> + Send + 'static,
+    {
+        let key = self.decoder_key(&fragments);
+        self.pool.spawn(move || {
+            let fragments = fragments.iter().map(|f| f.as_ref()).collect::<Vec<_>>();
+            with_coder(key, |coder| coder.reconstruct(index, &fragments))
+        })
+    }
+
+    fn key(&self, coding: ErasureCoding) -> CoderKey {
+        CoderKey {
+            data_fragments: self.data_fragments,
+            parity_fragments: self.parity_fragments,
+            coding,
+        }
+    }
+
+    // 断片群の符号化に使われた実装を、先頭の断片のヘッダから判別する。
+    // 判別できない場合には(liberasurecode が検証時にエラーとするので)現在の実装を使う。
+    fn decoder_key<T: AsRef<Fragment>>(&self, fragments: &[T]) -> CoderKey {
+        let coding = fragments
+            .first()
+            .and_then(|f| coding_of_fragment(f.as_ref()))
+            .unwrap_or_else(|| self.coding.get());
+        self.key(coding)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct CoderKey {
+    data_fragments: NonZeroUsize,
+    parity_fragments: NonZeroUsize,
+    coding: ErasureCoding,
+}
+
+enum Coder {
+    Lib(Box<dyn ErasureCode>),
+    Rust(RustReedSolomonCoder),
+}
+impl Coder {
+    fn build(key: CoderKey) -> Result<Self> {
+        let backend = match key.coding.backend {
+            ErasureCodingBackend::JerasureRsVand => Backend::JerasureRsVand,
+            ErasureCodingBackend::JerasureRsCauchy => Backend::JerasureRsCauchy,
+            ErasureCodingBackend::IsaLRsVand => Backend::IsaLRsVand,
+            ErasureCodingBackend::IsaLRsCauchy => Backend::IsaLRsCauchy,
+            ErasureCodingBackend::RustReedSolomon => {
+                let coder = track!(RustReedSolomonCoder::new(
+                    key.data_fragments.get(),
+                    key.parity_fragments.get()
+                ))?;
+                return Ok(Coder::Rust(coder));
+            }
+        };
+        let checksum = match key.coding.checksum {
+            ErasureCodingChecksum::None => Checksum::None,
+            ErasureCodingChecksum::Crc32 => Checksum::Crc32,
+            ErasureCodingChecksum::Md5 => Checksum::Md5,
+        };
+        let coder = track!(
+            LibErasureCoderBuilder::new(key.data_fragments, key.parity_fragments)
+                .backend(backend)
+                .checksum(checksum)
+                .build_coder()
+                .map_err(Error::from)
+        )?;
+        Ok(Coder::Lib(Box::new(coder)))
+    }
+
+    fn encode(&mut self, data: &[u8]) -> Result<Vec<FragmentBuf>> {
+        match self {
+            Coder::Lib(c) => track!(c.encode(data).map_err(Error::from)),
+            Coder::Rust(c) => track!(c.encode(data)),
+        }
+    }
+
+    fn decode(&mut self, fragments: &[&Fragment]) -> Result<Vec<u8>> {
+        match self {
+            Coder::Lib(c) => track!(c.decode(fragments).map_err(Error::from)),
+            Coder::Rust(c) => track!(c.decode(fragments)),
+        }
+    }
+
+    fn reconstruct(&mut self, index: usize, fragments: &[&Fragment]) -> Result<Vec<u8>> {
+        match self {
+            Coder::Lib(c) => track!(c.reconstruct(index, fragments).map_err(Error::from)),
+            Coder::Rust(c) => track!(c.reconstruct(index, fragments)),
+        }
+    }
+}
+
+fn with_coder<F, T>(key: CoderKey, f: F) -> Result<T>
+where
+    F: FnOnce(&mut Coder) -> Result<T>,
+{
+    ERASURE_CODERS.with(|coders| {
+        let mut coders = coders.borrow_mut();
+        if !coders.contains_key(&key) {
+            let coder = track!(Coder::build(key))?;
+            coders.insert(key, coder);
+        }
+        let coder = coders.get_mut(&key).expect("Never fails");
+        track!(f(coder))
+    })
+}
+
+/// `reed-solomon-erasure`による Reed-Solomon 符号の実装。
+///
+/// 断片には liberasurecode と同じ形式のヘッダが付与されるので、範囲読み込み等は他の実装と同様に行える
+/// (ただしチェックサムは記録されない)。
+struct RustReedSolomonCoder {
+    data_fragments: usize,
+    inner: ReedSolomon,
+}
+impl RustReedSolomonCoder {
+    fn new(data_fragments: usize, parity_fragments: usize) -> Result<Self> {
+        let inner = track!(ReedSolomon::new(data_fragments, parity_fragments)
+            .map_err(|e| Error::from(ErrorKind::Invalid.cause(format!("{:?}", e)))))?;
+        Ok(RustReedSolomonCoder {
+            data_fragments,
+            inner,
+        })
+    }
+
+    fn encode(&self, data: &[u8]) -> Result<Vec<FragmentBuf>> {
+        // NOTE: 空のデータの場合にも、長さ 1 の断片を作る(空の断片は符号化できない)
+        let size = ((data.len() + self.data_fragments - 1) / self.data_fragments).max(1);
+
+        // NOTE: 符号化は各断片のペイロード部分に対して直接行い、中間的なバッファへの複製を避ける
+        let mut fragments = (0..self.inner.total_shard_count())
+            .map(|index| make_fragment_buf(index, size, data.len() as u64))
+            .collect::<Vec<_>>();
+        for (fragment, chunk) in fragments.iter_mut().zip(data.chunks(size)) {
+            fragment[FRAGMENT_HEADER_SIZE..][..chunk.len()].copy_from_slice(chunk);
+        }
+        {
+            let mut shards = fragments
+                .iter_mut()
+                .map(|f| &mut f[FRAGMENT_HEADER_SIZE..])
+                .collect::<Vec<_>>();
+            track!(self.inner.encode(&mut shards).map_err(reed_solomon_error))?;
+        }
+        Ok(fragments)
+    }
+
+    fn decode(&self, fragments: &[&Fragment]) -> Result<Vec<u8>> {
+        let (mut shards, object_size) = track!(self.shards(fragments))?;
+        if shards[..self.data_fragments].iter().any(Option::is_none) {
+            track!(self
+                .inner
+                .reconstruct_data(&mut shards)
+                .map_err(reed_solomon_error))?;
+        }
+        let mut data = Vec::with_capacity(object_size as usize);
+        for shard in shards.into_iter().take(self.data_fragments) {
+            data.extend_from_slice(&shard.expect("Never fails"));
+        }
+        track_assert!(
+            data.len() as u64 >= object_size,
+            ErrorKind::Corrupted,
+            "Too short data: len={}, object_size={}",
+            data.len(),
+            object_size
+        );
+        data.truncate(object_size as usize);
+        Ok(data)
+    }
+
+    fn reconstruct(&self, index: usize, fragments: &[&Fragment]) -> Result<Vec<u8>> {
+        track_assert!(
+            index < self.inner.total_shard_count(),
+            ErrorKind::Invalid,
+            "Too large index: {}",
+            index
+        );
+        let (mut shards, object_size) = track!(self.shards(fragments))?;
+        track!(self
+            .inner
+            .reconstruct(&mut shards)
+            .map_err(reed_solomon_error))?;
+        let shard = shards[index].as_ref().expect("Never fails");
+        Ok(make_fragment(index, shard, object_size))
+    }
+
+    // 断片群のペイロードを、インデックスの位置に配置して返す
+    fn shards(&self, fragments: &[&Fragment]) -> Result<(Vec<Option<Vec<u8>>>, u64)> {
+        let mut shards = vec![None; self.inner.total_shard_count()];
+        let mut object_size = None;
+        for fragment in fragments {
+            let header = track!(FragmentHeader::parse(fragment))?;
+            track_assert!(
+                header.index < shards.len(),
+                ErrorKind::Corrupted,
+                "Too large fragment index: {:?}",
+                header
+            );
+            track_assert!(
+                object_size.map_or(true, |size| size == header.object_size),
+                ErrorKind::Corrupted,
+                "Inconsistent object size: {:?}",
+                header
+            );
+            object_size = Some(header.object_size);
+            let payload = &fragment[FRAGMENT_HEADER_SIZE..FRAGMENT_HEADER_SIZE + header.size];
+            shards[header.index] = Some(payload.to_vec());
+        }
+        let object_size = track_assert_some!(object_size, ErrorKind::Invalid, "No fragments");
+        Ok((shards, object_size))
+    }
+}
+
+fn reed_solomon_error(e: ::reed_solomon_erasure::Error) -> Error {
+    ErrorKind::Other.cause(format!("{:?}", e)).into()
+}
+
+fn make_fragment(index: usize, payload: &[u8], object_size: u64) -> FragmentBuf {
+    let mut fragment = make_fragment_buf(index, payload.len(), object_size);
+    fragment[FRAGMENT_HEADER_SIZE..].copy_from_slice(payload);
+    fragment
+}
+
+/// ヘッダのみが書き込まれた、長さ`payload_size`のペイロードを持つ断片を作る。
+///
+/// 格納時に末尾にチェックサムが付与されるので、その分の領域も予め確保しておく。
+fn make_fragment_buf(index: usize, payload_size: usize, object_size: u64) -> FragmentBuf {
+    let len = FRAGMENT_HEADER_SIZE + payload_size;
+    let mut fragment = Vec::with_capacity(len + CHECKSUM_TRAILER_SIZE);
+    fragment.resize(len, 0);
+    LittleEndian::write_u32(&mut fragment[0..4], index as u32);
+    LittleEndian::write_u32(&mut fragment[4..8], payload_size as u32);
+    LittleEndian::write_u64(&mut fragment[12..20], object_size);
+    fragment[20] = CHECKSUM_TYPE_NONE;
+    fragment[54] = RUST_REED_SOLOMON_BACKEND_ID;
+    LittleEndian::write_u32(&mut fragment[59..63], FRAGMENT_HEADER_MAGIC);
+    fragment
+}
 
 /// `ErasureCoder`を構築するための補助関数。
+///
+/// 構築された`ErasureCoder`は、既定の実装と、投入数に上限を持たない`ErasureCodingPool::unbounded()`を使用する。
 pub fn build_ec(data_fragments: usize, parity_fragments: usize) -> ErasureCoder {
-    let data_fragments = NonZeroUsize::new(data_fragments).expect("TODO: handle error");
-    let parity_fragments = NonZeroUsize::new(parity_fragments).expect("TODO: handle error");
-    let builder = LibErasureCoderBuilder::new(data_fragments, parity_fragments);
-    ErasureCoderPool::new(builder)
+    build_ec_with_coding(data_fragments, parity_fragments, Default::default())
+}
+
+/// バケツに設定された実装を用いる`ErasureCoder`を構築する。
+pub fn build_ec_with_coding(
+    data_fragments: usize,
+    parity_fragments: usize,
+    coding: SharedErasureCoding,
+) -> ErasureCoder {
+    ErasureCoder {
+        data_fragments: NonZeroUsize::new(data_fragments).expect("TODO: handle error"),
+        parity_fragments: NonZeroUsize::new(parity_fragments).expect("TODO: handle error"),
+        coding,
+        pool: ErasureCodingPool::unbounded(),
+    }
+}
+
+/// 断片の代わりにオブジェクト全体の複製を格納する場合に、その先頭に付与するマーカー。
+///
+/// 先頭の4バイトはliberasurecodeのヘッダでは断片のインデックスに該当し、
+/// 有効な断片がこの値を持つことはないので、通常の断片と取り違えることはない。
+pub(crate) const REPLICA_MARKER: [u8; 8] = [0xff, 0xff, 0xff, 0xff, b'R', b'E', b'P', b'L'];
+
+/// オブジェクト全体の複製を、断片の代わりに格納できる形式に変換する。
+pub fn make_replica(content: &[u8]) -> Vec<u8> {
+    let mut replica = Vec::with_capacity(REPLICA_MARKER.len() + content.len());
+    replica.extend_from_slice(&REPLICA_MARKER[..]);
+    replica.extend_from_slice(content);
+    replica
+}
+
+/// 断片がオブジェクト全体の複製である場合には、その中身を返す。
+pub fn parse_replica(fragment: &[u8]) -> Option<&[u8]> {
+    if fragment.starts_with(&REPLICA_MARKER[..]) {
+        Some(&fragment[REPLICA_MARKER.len()..])
+    } else {
+        None
+    }
+}
+
+/// liberasurecodeが各断片の先頭に付与するヘッダのサイズ。
+pub const FRAGMENT_HEADER_SIZE: usize = 80;
+
+/// liberasurecodeのヘッダであることを示すマジックナンバー。
+const FRAGMENT_HEADER_MAGIC: u32 = 0x0b0c_5ecc;
+
+/// `RustReedSolomonCoder`がヘッダに記録するバックエンドの ID。
+///
+/// liberasurecode が定義している ID(`ec_backend_id_t`)と重ならない値を用いる。
+const RUST_REED_SOLOMON_BACKEND_ID: u8 = 0x80;
+
+/// liberasurecode のヘッダでチェックサムなしを表す値(`CHKSUM_NONE`)。
+const CHECKSUM_TYPE_NONE: u8 = 1;
+
+/// 断片のヘッダから、その断片の符号化に使われた実装を判別する。
+fn coding_of_fragment(fragment: &[u8]) -> Option<ErasureCoding> {
+    if fragment.len() < FRAGMENT_HEADER_SIZE
+        || LittleEndian::read_u32(&fragment[59..63]) != FRAGMENT_HEADER_MAGIC
+    {
+        return None;
+    }
+    let backend = match fragment[54] {
+        1 => ErasureCodingBackend::JerasureRsVand,
+        2 => ErasureCodingBackend::JerasureRsCauchy,
+        4 => ErasureCodingBackend::IsaLRsVand,
+        7 => ErasureCodingBackend::IsaLRsCauchy,
+        RUST_REED_SOLOMON_BACKEND_ID => ErasureCodingBackend::RustReedSolomon,
+        _ => return None,
+    };
+    let checksum = match fragment[20] {
+        CHECKSUM_TYPE_NONE => ErasureCodingChecksum::None,
+        2 => ErasureCodingChecksum::Crc32,
+        3 => ErasureCodingChecksum::Md5,
+        _ => return None,
+    };
+    Some(ErasureCoding { backend, checksum })
+}
+
+/// liberasurecodeが付与する断片のヘッダの内、範囲読み込みに必要な部分。
+///
+/// 利用しているバックエンドは全て組織符号なので、
+/// `index`番目のデータ断片のペイロードには、元データの`index * size`バイト目からの`size`バイトが格納されている
+/// (末尾の断片はゼロ埋めされる)。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    /// 断片のインデックス。
+    pub index: usize,
+
+    /// ヘッダを除いた断片のペイロードのサイズ。
+    pub size: usize,
+
+    /// 元データのサイズ。
+    pub object_size: u64,
+}
+impl FragmentHeader {
+    /// 断片の先頭からヘッダを読み込む。
+    ///
+    /// NOTE: ヘッダはliberasurecodeが動作するホストのバイトオーダー(リトルエンディアンを想定)で書き込まれている
+    pub fn parse(fragment: &[u8]) -> Result<Self> {
+        track_assert!(
+            fragment.len() >= FRAGMENT_HEADER_SIZE,
+            ErrorKind::Corrupted,
+            "Too short fragment: len={}",
+            fragment.len()
+        );
+        let magic = LittleEndian::read_u32(&fragment[59..63]);
+        track_assert_eq!(magic, FRAGMENT_HEADER_MAGIC, ErrorKind::Corrupted);
+        let header = FragmentHeader {
+            index: LittleEndian::read_u32(&fragment[0..4]) as usize,
+            size: LittleEndian::read_u32(&fragment[4..8]) as usize,
+            object_size: LittleEndian::read_u64(&fragment[12..20]),
+        };
+        track_assert!(
+            fragment.len() >= FRAGMENT_HEADER_SIZE + header.size,
+            ErrorKind::Corrupted,
+            "Too short fragment: len={}, header={:?}",
+            fragment.len(),
+            header
+        );
+        Ok(header)
+    }
+
+    /// `range`の範囲のデータを保持しているデータ断片のインデックス群を返す。
+    ///
+    /// `range`は元データのサイズに収まっている必要がある。
+    pub fn covering_fragments(&self, range: &Range<u64>) -> Range<usize> {
+        if range.start >= range.end || self.size == 0 {
+            return 0..0;
+        }
+        let size = self.size as u64;
+        (range.start / size) as usize..((range.end - 1) / size + 1) as usize
+    }
+
+    /// 断片のペイロードの内、元データの`range`の範囲に該当する部分を返す。
+    pub fn slice<'a>(&self, fragment: &'a [u8], range: &Range<u64>) -> &'a [u8] {
+        let payload = &fragment[FRAGMENT_HEADER_SIZE..FRAGMENT_HEADER_SIZE + self.size];
+        let offset = (self.index * self.size) as u64;
+        let start = range.start.max(offset).min(offset + self.size as u64);
+        let end = range.end.min(offset + self.size as u64).max(start);
+        &payload[(start - offset) as usize..(end - offset) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(index: u32, size: u32, object_size: u64) -> Vec<u8> {
+        let mut fragment = vec![0; FRAGMENT_HEADER_SIZE + size as usize];
+        LittleEndian::write_u32(&mut fragment[0..4], index);
+        LittleEndian::write_u32(&mut fragment[4..8], size);
+        LittleEndian::write_u64(&mut fragment[12..20], object_size);
+        LittleEndian::write_u32(&mut fragment[59..63], FRAGMENT_HEADER_MAGIC);
+        for i in 0..size {
+            fragment[FRAGMENT_HEADER_SIZE + i as usize] = (index * size + i) as u8;
+        }
+        fragment
+    }
+
+    #[test]
+    fn fragment_header_works() {
+        let f = fragment(1, 16, 40);
+        let header = FragmentHeader::parse(&f).unwrap();
+        assert_eq!(
+            header,
+            FragmentHeader {
+                index: 1,
+                size: 16,
+                object_size: 40
+            }
+        );
+        assert_eq!(header.covering_fragments(&(0..40)), 0..3);
+        assert_eq!(header.covering_fragments(&(16..32)), 1..2);
+        assert_eq!(header.covering_fragments(&(15..17)), 0..2);
+        assert_eq!(header.covering_fragments(&(20..20)), 0..0);
+
+        assert_eq!(header.slice(&f, &(20..24)), &[20, 21, 22, 23]);
+        assert_eq!(header.slice(&f, &(30..100)), &[30, 31]);
+        assert!(header.slice(&f, &(0..10)).is_empty());
+
+        assert!(FragmentHeader::parse(&f[..FRAGMENT_HEADER_SIZE + 8]).is_err());
+        let mut broken = f.clone();
+        broken[60] = 0;
+        assert!(FragmentHeader::parse(&broken).is_err());
+    }
+
+    #[test]
+    fn shared_erasure_coding_works() {
+        let coding = SharedErasureCoding::default();
+        assert_eq!(coding.get(), ErasureCoding::default());
+
+        let shared = coding.clone();
+        let rust = ErasureCoding {
+            backend: ErasureCodingBackend::RustReedSolomon,
+            checksum: ErasureCodingChecksum::None,
+        };
+        shared.set(rust);
+        assert_eq!(coding.get(), rust);
+    }
+
+    #[test]
+    fn rust_reed_solomon_works() {
+        let coder = RustReedSolomonCoder::new(4, 2).unwrap();
+        let data = (0..100).map(|i| i as u8).collect::<Vec<_>>();
+        let fragments = coder.encode(&data).unwrap();
+        assert_eq!(fragments.len(), 6);
+
+        let rust = ErasureCoding {
+            backend: ErasureCodingBackend::RustReedSolomon,
+            checksum: ErasureCodingChecksum::None,
+        };
+        assert_eq!(coding_of_fragment(&fragments[0]), Some(rust));
+        let header = FragmentHeader::parse(&fragments[1]).unwrap();
+        assert_eq!(header.object_size, 100);
+        assert_eq!(header.slice(&fragments[1], &(30..34)), &[30, 31, 32, 33]);
+
+        // データ断片の一部が欠けていても復号できる
+        let available = fragments[1..5].iter().map(|f| &f[..]).collect::<Vec<_>>();
+        assert_eq!(coder.decode(&available).unwrap(), data);
+
+        let reconstructed = coder.reconstruct(0, &available).unwrap();
+        assert_eq!(reconstructed, fragments[0]);
+
+        let too_few = fragments[2..5].iter().map(|f| &f[..]).collect::<Vec<_>>();
+        assert!(coder.decode(&too_few).is_err());
+
+        let empty = coder.encode(&[]).unwrap();
+        let empty = empty.iter().map(|f| &f[..]).collect::<Vec<_>>();
+        assert_eq!(coder.decode(&empty[2..]).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn replica_works() {
+        let replica = make_replica(b"foo");
+        assert_eq!(parse_replica(&replica), Some(&b"foo"[..]));
+        assert!(FragmentHeader::parse(&replica).is_err());
+
+        let f = fragment(0, 16, 10);
+        assert_eq!(parse_replica(&f), None);
+        assert_eq!(parse_replica(&make_replica(&f)), Some(&f[..]));
+    }
 }
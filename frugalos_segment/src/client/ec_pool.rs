@@ -0,0 +1,258 @@
+//! Erasure Coding の符号化・復号を実行する専用のスレッドプール。
+//!
+//! 符号化・復号は CPU を長時間占有するため、fibers のスケジューラとは別のワーカスレッド群で実行する。
+//! 投入できる処理の数には上限があり、上限に達している場合には、設定に応じて空きができるまで待機するか、
+//! `ErrorKind::Busy`で即座に失敗する(上限なしにキューが伸び続けることはない)。
+use fibers::sync::oneshot;
+use fibers_tasque::{DefaultCpuTaskQueue, TaskQueueExt};
+use futures::Future;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tasque::{TaskQueue, TaskQueueBuilder};
+use trackable::error::ErrorKindExt;
+
+use config::{ErasureCodingPoolConfig, SaturationPolicy};
+use metrics::ErasureCodingPoolMetrics;
+use util::BoxFuture;
+use {Error, ErrorKind, Result};
+
+/// Erasure Coding 用のスレッドプール。
+///
+/// `Clone`されたインスタンス間では、ワーカスレッド群と投入数の上限が共有される。
+#[derive(Debug, Clone)]
+pub struct ErasureCodingPool {
+    inner: Arc<PoolInner>,
+}
+impl ErasureCodingPool {
+    /// 設定に従って、新しいスレッドプールを生成する。
+    pub fn new(config: &ErasureCodingPoolConfig) -> Result<Self> {
+        track_assert_ne!(config.max_queue_len, 0, ErrorKind::Invalid);
+        let mut builder = TaskQueueBuilder::new();
+        if config.worker_threads != 0 {
+            builder.worker_count(config.worker_threads);
+        }
+        let queue = builder
+            .metrics(|m| {
+                m.label("name", "frugalos_segment_ec");
+            })
+            .finish();
+        let metrics = track!(ErasureCodingPoolMetrics::new())?;
+        Ok(Self::with_queue(
+            Some(queue),
+            config.max_queue_len,
+            config.on_saturation,
+            Some(metrics),
+        ))
+    }
+
+    /// 投入数に上限を持たず、fibers のデフォルトの CPU 用キューで処理を実行するインスタンスを生成する。
+    ///
+    /// `build_ec`等の、プールを明示的に指定しない`ErasureCoder`で使用される。
+    pub fn unbounded() -> Self {
+        Self::with_queue(None, usize::MAX, SaturationPolicy::Wait, None)
+    }
+
+    fn with_queue(
+        queue: Option<TaskQueue>,
+        max_queue_len: usize,
+        on_saturation: SaturationPolicy,
+        metrics: Option<ErasureCodingPoolMetrics>,
+    ) -> Self {
+        ErasureCodingPool {
+            inner: Arc::new(PoolInner {
+                queue,
+                max_queue_len,
+                on_saturation,
+                metrics,
+                state: Mutex::default(),
+            }),
+        }
+    }
+
+    /// `f`をワーカスレッドで実行する。
+    ///
+    /// プールが飽和している場合には、空きができるまで待機するか`ErrorKind::Busy`で失敗する。
+    pub(crate) fn spawn<F, T>(&self, f: F) -> BoxFuture<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.clone();
+        let future = self.acquire().and_then(move |slot| pool.execute(slot, f));
+        Box::new(future)
+    }
+
+    fn acquire(&self) -> BoxFuture<Slot> {
+        let mut state = self.inner.lock();
+        if state.in_flight < self.inner.max_queue_len {
+            state.in_flight += 1;
+            if let Some(ref m) = self.inner.metrics {
+                m.in_flight.increment();
+            }
+            return Box::new(futures::finished(Slot(Some(self.clone()))));
+        }
+        match self.inner.on_saturation {
+            SaturationPolicy::Reject => {
+                if let Some(ref m) = self.inner.metrics {
+                    m.rejected_total.increment();
+                }
+                let cause = format!(
+                    "Erasure coding pool is saturated: max_queue_len={}",
+                    self.inner.max_queue_len
+                );
+                Box::new(futures::failed(track!(Error::from(
+                    ErrorKind::Busy.cause(cause)
+                ))))
+            }
+            SaturationPolicy::Wait => {
+                let (tx, rx) = oneshot::channel();
+                state.waiters.push_back(tx);
+                if let Some(ref m) = self.inner.metrics {
+                    m.waiting.increment();
+                }
+                Box::new(rx.map_err(|e| track!(Error::from(ErrorKind::Other.cause(e)))))
+            }
+        }
+    }
+
+    fn execute<F, T>(&self, slot: Slot, f: F) -> BoxFuture<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        // 枠は処理の完了と同時に(ワーカスレッド上で)解放する
+        let task = move || {
+            let result = f();
+            drop(slot);
+            result
+        };
+        let call = if let Some(ref queue) = self.inner.queue {
+            queue.async_call(task)
+        } else {
+            DefaultCpuTaskQueue.async_call(task)
+        };
+        let future = call.then(|result| match result {
+            Ok(result) => track!(result),
+            Err(e) => Err(track!(Error::from(ErrorKind::Other.cause(e)))),
+        });
+        Box::new(future)
+    }
+
+    /// 空いた枠を、待機中の処理があればそれに譲り、なければ解放する。
+    fn release(&self) {
+        let mut state = self.inner.lock();
+        while let Some(waiter) = state.waiters.pop_front() {
+            if let Some(ref m) = self.inner.metrics {
+                m.waiting.decrement();
+            }
+            match waiter.send(Slot(Some(self.clone()))) {
+                Ok(()) => return,
+                Err(e) => {
+                    // 待機していた要求は既に破棄されているので、次の候補に譲る
+                    // (ロックを保持したまま`release`が再帰的に呼ばれないようにする)
+                    let mut slot = e.0;
+                    slot.0 = None;
+                }
+            }
+        }
+        state.in_flight -= 1;
+        if let Some(ref m) = self.inner.metrics {
+            m.in_flight.decrement();
+        }
+    }
+
+    #[cfg(test)]
+    fn in_flight(&self) -> usize {
+        self.inner.lock().in_flight
+    }
+}
+
+#[derive(Debug)]
+struct PoolInner {
+    queue: Option<TaskQueue>,
+    max_queue_len: usize,
+    on_saturation: SaturationPolicy,
+    metrics: Option<ErasureCodingPoolMetrics>,
+    state: Mutex<PoolState>,
+}
+impl PoolInner {
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    in_flight: usize,
+    waiters: VecDeque<oneshot::Sender<Slot>>,
+}
+
+/// プールに投入された処理が占有している枠。
+///
+/// 破棄されると枠が解放される。
+#[derive(Debug)]
+struct Slot(Option<ErasureCodingPool>);
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(pool) = self.0.take() {
+            pool.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers_global;
+    use std::sync::mpsc;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    fn pool(on_saturation: SaturationPolicy) -> ErasureCodingPool {
+        let config = ErasureCodingPoolConfig {
+            worker_threads: 2,
+            max_queue_len: 1,
+            on_saturation,
+        };
+        ErasureCodingPool::new(&config).unwrap()
+    }
+
+    #[test]
+    fn saturated_pool_rejects_tasks() -> TestResult {
+        let pool = pool(SaturationPolicy::Reject);
+        let (tx, rx) = mpsc::channel::<()>();
+        let blocked = pool.spawn(move || {
+            let _ = rx.recv();
+            Ok(1)
+        });
+        let e = fibers_global::execute(pool.spawn(|| Ok(2))).err().unwrap();
+        assert!(matches!(*e.kind(), ErrorKind::Busy));
+
+        tx.send(()).unwrap();
+        assert_eq!(track!(fibers_global::execute(blocked))?, 1);
+        assert_eq!(track!(fibers_global::execute(pool.spawn(|| Ok(3))))?, 3);
+        assert_eq!(pool.in_flight(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn saturated_pool_waits_for_free_slot() -> TestResult {
+        let pool = pool(SaturationPolicy::Wait);
+        let (tx, rx) = mpsc::channel::<()>();
+        let blocked = pool.spawn(move || {
+            let _ = rx.recv();
+            Ok(1)
+        });
+        let cancelled = pool.spawn(|| Ok(2));
+        let waiting = pool.spawn(|| Ok(3));
+        assert_eq!(pool.inner.lock().waiters.len(), 2);
+
+        // 待機中に破棄された処理の枠は、次の処理に譲られる
+        drop(cancelled);
+        tx.send(()).unwrap();
+        assert_eq!(track!(fibers_global::execute(blocked))?, 1);
+        assert_eq!(track!(fibers_global::execute(waiting))?, 3);
+        assert_eq!(pool.in_flight(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+//! ストレージの各メンバへの読み込み要求の結果を記録し、読み込み先の選択に利用するための仕組み。
+//!
+//! 記録されたエラー率と応答時間を元に、健全で応答の速いメンバから順に読み込みを行うようにする。
+//! これにより、故障しかけているディスクや過負荷のノードへの要求を減らすことができる。
+//!
+//! 連続して失敗するメンバについては、更に`CircuitBreakers`によって一定時間候補の末尾に回される。
+use frugalos_raft::NodeId;
+use futures::{Async, Future, Poll};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use client::circuit_breaker::{CircuitBreakers, CircuitState};
+use config::{ClusterMember, MemberHealthConfig};
+
+/// 指数移動平均における、最新の観測値の重み。
+const EWMA_WEIGHT: f64 = 0.2;
+
+/// あるメンバの健全性。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberHealth {
+    /// ノードの ID。
+    pub node: String,
+
+    /// デバイスの ID。
+    pub device: String,
+
+    /// 記録された読み込み要求の数。
+    pub requests: u64,
+
+    /// 記録された読み込み要求の内、エラーとなったものの数。
+    pub errors: u64,
+
+    /// エラー率(指数移動平均)。
+    pub error_rate: f64,
+
+    /// 応答時間(指数移動平均、ミリ秒単位)。
+    ///
+    /// まだ応答が得られていない場合には`None`となる。
+    pub latency_millis: Option<f64>,
+
+    /// 読み込み先の候補から外されているかどうか。
+    pub ejected: bool,
+
+    /// サーキットブレーカの状態。
+    #[serde(default)]
+    pub circuit: CircuitState,
+}
+
+#[derive(Debug, Default)]
+struct MemberStats {
+    requests: u64,
+    errors: u64,
+    error_rate: f64,
+    latency_secs: Option<f64>,
+    ejected_until: Option<Instant>,
+}
+impl MemberStats {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|t| now < t)
+    }
+
+    /// 候補から外されていた期間が過ぎていれば、エラー率をリセットして通常の候補に戻す。
+    fn expire_ejection(&mut self, now: Instant) {
+        if self.ejected_until.is_some_and(|t| t <= now) {
+            self.ejected_until = None;
+            self.error_rate = 0.0;
+        }
+    }
+
+    fn record_latency(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        self.latency_secs = Some(
+            self.latency_secs
+                .map_or(secs, |x| x * (1.0 - EWMA_WEIGHT) + secs * EWMA_WEIGHT),
+        );
+    }
+
+    /// 並べ替えに用いるキー。
+    ///
+    /// 応答時間は2のべき乗(ミリ秒単位)毎の階級に丸めるので、
+    /// 同程度の応答時間のメンバ間では、本来の候補の順序が維持される。
+    fn sort_key(&self, now: Instant) -> (bool, u32) {
+        let latency_class = self
+            .latency_secs
+            .map_or(0, |secs| (secs * 1000.0 + 1.0).log2() as u32);
+        (self.is_ejected(now), latency_class)
+    }
+}
+
+/// メンバ毎の健全性の記録。
+///
+/// `Clone`されたインスタンス間では、同じ記録が共有される。
+#[derive(Debug, Clone)]
+pub(crate) struct MemberHealthTable {
+    config: Arc<MemberHealthConfig>,
+    members: Arc<Mutex<HashMap<(NodeId, String), MemberStats>>>,
+    breakers: CircuitBreakers,
+}
+impl MemberHealthTable {
+    pub fn new(config: MemberHealthConfig, breakers: CircuitBreakers) -> Self {
+        MemberHealthTable {
+            config: Arc::new(config),
+            members: Arc::default(),
+            breakers,
+        }
+    }
+
+    /// 健全で応答の速いメンバが先頭に来るように、読み込み先の候補を並べ替える。
+    ///
+    /// 健全性の考慮が無効になっている場合には、サーキットブレーカによる並べ替えのみを行う。
+    pub fn sort(&self, candidates: &mut [ClusterMember]) {
+        self.sort_by_health(candidates);
+        self.breakers.sort(candidates);
+    }
+
+    /// 回路が開いているメンバを候補の末尾に移動し、回路を通過できるメンバの数を返す。
+    ///
+    /// それ以外のメンバの順序は維持されるので、断片の位置に基づく優先順位を保ったまま、
+    /// 初回の要求先から回路が開いているメンバを除くために使える。
+    pub fn demote_open_circuits(&self, candidates: &mut [ClusterMember]) -> usize {
+        self.breakers.sort(candidates)
+    }
+
+    /// 健全性のみに基づいて、読み込み先の候補を並べ替える。
+    pub fn sort_by_health(&self, candidates: &mut [ClusterMember]) {
+        if self.config.enabled {
+            self.sort_at(candidates, Instant::now());
+        }
+    }
+
+    fn sort_at(&self, candidates: &mut [ClusterMember], now: Instant) {
+        let mut members = self.members.lock().unwrap_or_else(|e| e.into_inner());
+        for stats in members.values_mut() {
+            stats.expire_ejection(now);
+        }
+        candidates.sort_by_cached_key(|m| {
+            members
+                .get(&member_key(m))
+                .map_or((false, 0), |stats| stats.sort_key(now))
+        });
+    }
+
+    /// `member`への読み込み要求の結果を記録する。
+    ///
+    /// `elapsed`は要求の発行から結果が得られるまでの時間で、成功した場合にのみ応答時間として使われる。
+    pub fn record(&self, member: &ClusterMember, elapsed: Duration, succeeded: bool) {
+        self.record_at(member, elapsed, succeeded, Instant::now());
+        self.breakers.record(member, succeeded);
+    }
+
+    fn record_at(&self, member: &ClusterMember, elapsed: Duration, succeeded: bool, now: Instant) {
+        let mut members = self.members.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = members.entry(member_key(member)).or_default();
+        stats.expire_ejection(now);
+        stats.requests += 1;
+        if succeeded {
+            stats.error_rate *= 1.0 - EWMA_WEIGHT;
+            stats.record_latency(elapsed);
+        } else {
+            stats.errors += 1;
+            stats.error_rate = stats.error_rate * (1.0 - EWMA_WEIGHT) + EWMA_WEIGHT;
+            if !stats.is_ejected(now) && stats.error_rate >= self.config.ejection_threshold {
+                stats.ejected_until = Some(now + self.config.ejection_duration);
+            }
+        }
+    }
+
+    /// 応答を待たずに破棄された読み込み要求を記録する。
+    ///
+    /// 他のメンバの応答で取得が完了した場合等が該当する。
+    /// この場合の経過時間は実際の応答時間の下限値でしかないが、遅いメンバを見逃さないように記録しておく。
+    pub fn record_cancelled(&self, member: &ClusterMember, elapsed: Duration) {
+        {
+            let mut members = self.members.lock().unwrap_or_else(|e| e.into_inner());
+            let stats = members.entry(member_key(member)).or_default();
+            if stats
+                .latency_secs
+                .is_none_or(|secs| secs < elapsed.as_secs_f64())
+            {
+                stats.record_latency(elapsed);
+            }
+        }
+        self.breakers.record_cancelled(member);
+    }
+
+    /// `future`の結果を、`member`への読み込み要求の結果として記録するようにする。
+    pub fn track<F: Future>(&self, member: &ClusterMember, future: F) -> Tracked<F> {
+        Tracked {
+            table: self.clone(),
+            member: member.clone(),
+            started_at: Instant::now(),
+            future,
+            done: false,
+        }
+    }
+
+    /// 現在の各メンバの健全性を返す。
+    pub fn snapshot(&self) -> Vec<MemberHealth> {
+        let now = Instant::now();
+        let members = self.members.lock().unwrap_or_else(|e| e.into_inner());
+        let mut snapshot = members
+            .iter()
+            .map(|(key, stats)| MemberHealth {
+                node: key.0.to_string(),
+                device: key.1.clone(),
+                requests: stats.requests,
+                errors: stats.errors,
+                error_rate: stats.error_rate,
+                latency_millis: stats.latency_secs.map(|secs| secs * 1000.0),
+                ejected: stats.is_ejected(now),
+                circuit: self.breakers.state(key),
+            })
+            .collect::<Vec<_>>();
+        snapshot.sort_by(|a, b| (&a.node, &a.device).cmp(&(&b.node, &b.device)));
+        snapshot
+    }
+}
+
+fn member_key(member: &ClusterMember) -> (NodeId, String) {
+    (member.node, member.device.clone())
+}
+
+/// 結果を`MemberHealthTable`に記録する`Future`。
+pub(crate) struct Tracked<F> {
+    table: MemberHealthTable,
+    member: ClusterMember,
+    started_at: Instant,
+    future: F,
+    done: bool,
+}
+impl<F: Future> Future for Tracked<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = self.future.poll();
+        let succeeded = match result {
+            Ok(Async::NotReady) => return result,
+            Ok(Async::Ready(_)) => true,
+            Err(_) => false,
+        };
+        self.done = true;
+        self.table
+            .record(&self.member, self.started_at.elapsed(), succeeded);
+        result
+    }
+}
+impl<F> Drop for Tracked<F> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.table
+                .record_cancelled(&self.member, self.started_at.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::CircuitBreakerConfig;
+    use frugalos_core::metrics::MetricLabels;
+    use frugalos_raft::LocalNodeId;
+    use metrics::CircuitBreakerMetrics;
+    use slog::{Discard, Logger};
+
+    fn member(n: u8) -> ClusterMember {
+        ClusterMember {
+            node: NodeId {
+                local_id: LocalNodeId::new([0, 0, 0, 0, 0, 0, n]),
+                instance: 0,
+                addr: ([127, 0, 0, 1], 80).into(),
+            },
+            device: format!("dev{}", n),
+        }
+    }
+
+    fn table(config: MemberHealthConfig) -> MemberHealthTable {
+        let breakers = CircuitBreakers::new(
+            Logger::root(Discard, o!()),
+            CircuitBreakerConfig::default(),
+            CircuitBreakerMetrics::new("test", &MetricLabels::default()).unwrap(),
+        );
+        MemberHealthTable::new(config, breakers)
+    }
+
+    fn devices(members: &[ClusterMember]) -> Vec<&str> {
+        members.iter().map(|m| m.device.as_str()).collect()
+    }
+
+    #[test]
+    fn slow_members_are_moved_back() {
+        let now = Instant::now();
+        let table = table(MemberHealthConfig::default());
+        table.record_at(&member(0), Duration::from_millis(100), true, now);
+        table.record_at(&member(1), Duration::from_millis(2), true, now);
+        table.record_at(&member(2), Duration::from_millis(3), true, now);
+
+        // 同程度の応答時間のメンバ(`dev1`と`dev2`)の間では、元の順序が維持される
+        let mut candidates = vec![member(0), member(1), member(2), member(3)];
+        table.sort_at(&mut candidates, now);
+        assert_eq!(devices(&candidates), ["dev3", "dev1", "dev2", "dev0"]);
+    }
+
+    #[test]
+    fn unhealthy_members_are_ejected_temporarily() {
+        let now = Instant::now();
+        let config = MemberHealthConfig::default();
+        let duration = config.ejection_duration;
+        let table = table(config);
+        let elapsed = Duration::from_millis(1);
+
+        // 一度のエラーでは、候補から外されない
+        table.record_at(&member(0), elapsed, false, now);
+        let mut candidates = vec![member(0), member(1)];
+        table.sort_at(&mut candidates, now);
+        assert_eq!(devices(&candidates), ["dev0", "dev1"]);
+
+        for _ in 0..3 {
+            table.record_at(&member(0), elapsed, false, now);
+        }
+        table.sort_at(&mut candidates, now);
+        assert_eq!(devices(&candidates), ["dev1", "dev0"]);
+
+        let snapshot = table.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].requests, 4);
+        assert_eq!(snapshot[0].errors, 4);
+        assert!(snapshot[0].ejected);
+
+        // 一定時間が経過すれば、通常の候補に戻る
+        let later = now + duration;
+        let mut candidates = vec![member(0), member(1)];
+        table.sort_at(&mut candidates, later);
+        assert_eq!(devices(&candidates), ["dev0", "dev1"]);
+        table.record_at(&member(0), elapsed, false, later);
+        table.sort_at(&mut candidates, later);
+        assert_eq!(devices(&candidates), ["dev0", "dev1"]);
+    }
+
+    #[test]
+    fn disabled_table_does_not_sort() {
+        let config = MemberHealthConfig {
+            enabled: false,
+            ..Default::default()
+        };
+        let table = table(config);
+        table.record(&member(0), Duration::from_secs(10), true);
+        let mut candidates = vec![member(0), member(1)];
+        table.sort(&mut candidates);
+        assert_eq!(devices(&candidates), ["dev0", "dev1"]);
+    }
+}
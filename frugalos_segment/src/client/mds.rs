@@ -37,6 +37,8 @@
 //! 2. (リーダーが不明の場合は)クラスタ内のノードを順に過不足なく1度ずつ選択する
 //!
 //! 1, 2 どちらでもリーダーが判明している場合はリーダーを選ぶ。
+//! リーダーは、リダイレクトの応答から判明した時点でキャッシュされ、以降のリクエストは直接リーダーに送られる。
+//! キャッシュしたリーダーが `NotLeader` エラーを返した場合には、キャッシュは無効化される。
 //!
 //! 1 は初期の実装であり、乱択することでリクエスト量が均一にならされるメリットがある。
 //! 一方で、理屈上同一のノードを連続して選択し続け、ノードを変更すれば失敗しないケース
@@ -67,29 +69,51 @@
 use cannyls::deadline::Deadline;
 use fibers::time::timer;
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
+use fibers_rpc::Call as RpcCall;
+use frugalos_core::cluster_feature::{self, ClusterFeature};
+use frugalos_core::metrics::{get_or_create, MetricLabels};
+use frugalos_core::rpc_auth;
 use frugalos_core::tracer::SpanExt;
-use frugalos_mds::{Error as MdsError, ErrorKind as MdsErrorKind};
+use frugalos_mds::schema::{
+    AppendObjectRpc, CancelDeleteJobRpc, CommitAppendRpc, DeleteObjectIfRpc, DeleteObjectWithinRpc,
+    GetDeleteJobRpc, GetObjectRevisionRpc, GetObjectWithinLagRpc, GetQuotaUsageRpc,
+    HeadObjectWithinLagRpc, ListObjectsByTimeRangeRpc, ListObjectsWithinLagRpc, PutObjectIfRpc,
+    PutObjectSizedRpc, PutObjectWithinRpc, StartDeleteJobRpc, UndeleteObjectRpc, WatchObjectsRpc,
+};
+use frugalos_mds::{
+    DeleteJobStatus, Error as MdsError, ErrorKind as MdsErrorKind, ObjectChanges, Precondition,
+    QuotaUsage, RevisionSelector,
+};
 use frugalos_raft::{LocalNodeId, NodeId};
 use futures::future::Either;
 use futures::{Async, Future, Poll};
-use libfrugalos::client::mds::Client as RaftMdsClient;
+use libfrugalos;
 use libfrugalos::consistency::ReadConsistency;
 use libfrugalos::entity::node::RemoteNodeId;
 use libfrugalos::entity::object::{
     DeleteObjectsByPrefixSummary, Metadata, ObjectId, ObjectPrefix, ObjectSummary, ObjectVersion,
 };
 use libfrugalos::expect::Expect;
+use libfrugalos::schema::mds::{
+    self as mds_rpc, ObjectRequest, PrefixRequest, PutObjectRequest, RangeRequest, VersionRequest,
+};
 use libfrugalos::time::Seconds;
+use prometrics::metrics::{Counter, MetricBuilder};
 use rand::{self, thread_rng, Rng};
 use rustracing::tag::{StdTag, Tag};
 use rustracing_jaeger::span::{Span, SpanHandle};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use slog::Logger;
 use std::collections::hash_set::HashSet;
 use std::fmt::Debug;
+use std::net::SocketAddr;
 use std::ops::Range;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use trackable::error::ErrorKindExt;
 
+use super::metadata_cache::MetadataCache;
 use config::{ClusterConfig, MdsClientConfig, MdsRequestPolicy};
 use {Error, ErrorKind, ObjectValue, Result};
 
@@ -100,6 +124,10 @@ pub struct MdsClient {
     rpc_service: RpcServiceHandle,
     inner: Arc<Mutex<Inner>>,
     client_config: MdsClientConfig,
+    quorum_metrics: QuorumReadMetrics,
+    leader_metrics: LeaderCacheMetrics,
+    metadata_cache: Option<MetadataCache>,
+    metadata_cache_metrics: MetadataCacheMetrics,
 }
 impl MdsClient {
     pub fn new(
@@ -107,14 +135,28 @@ impl MdsClient {
         rpc_service: RpcServiceHandle,
         cluster_config: ClusterConfig,
         client_config: MdsClientConfig,
+        metric_labels: &MetricLabels,
     ) -> Self {
         // TODO: 以下のassertionは復活させたい
         // assert!(!config.members.is_empty());
+        let metadata_cache = if client_config.metadata_cache_capacity > 0 {
+            Some(MetadataCache::new(
+                client_config.metadata_cache_capacity,
+                client_config.metadata_cache_capacity_bytes,
+                client_config.metadata_cache_ttl,
+            ))
+        } else {
+            None
+        };
         MdsClient {
             logger,
             rpc_service,
             inner: Arc::new(Mutex::new(Inner::new(cluster_config))),
             client_config,
+            quorum_metrics: QuorumReadMetrics::new(metric_labels),
+            leader_metrics: LeaderCacheMetrics::new(metric_labels),
+            metadata_cache,
+            metadata_cache_metrics: MetadataCacheMetrics::new(metric_labels),
         }
     }
 
@@ -126,6 +168,7 @@ impl MdsClient {
         Request::new(self.clone(), parent, request)
     }
 
+    /// オブジェクトの一覧をリーダから取得する.
     pub fn list(&self) -> impl Future<Item = Vec<ObjectSummary>, Error = Error> {
         debug!(self.logger, "Starts LIST");
         let parent = Span::inactive().handle();
@@ -135,22 +178,190 @@ impl MdsClient {
         Request::new(self.clone(), parent, request)
     }
 
+    /// 指定された一貫性でオブジェクトの一覧を取得する.
+    ///
+    /// `ReadConsistency::Stale`が指定され、かつ`stale_read_max_lag`が設定されている場合にのみ、
+    /// 遅れが許容範囲内のフォロワーから取得する. それ以外の場合は`list`と同様にリーダから取得する.
+    pub fn list_with_consistency(
+        &self,
+        consistency: ReadConsistency,
+    ) -> impl Future<Item = Vec<ObjectSummary>, Error = Error> {
+        let max_lag = match (consistency, self.client_config.stale_read_max_lag) {
+            (ReadConsistency::Stale, Some(max_lag)) => max_lag,
+            _ => return Either::B(self.list()),
+        };
+        debug!(self.logger, "Starts LIST: {}", dump!(max_lag));
+        let parent = Span::inactive().handle();
+        let request =
+            RawRequestOnce::followers_first(RequestKind::Other, move |peer, rpc_service| {
+                let future = rpc_auth::call::<ListObjectsWithinLagRpc>(
+                    &rpc_service,
+                    peer.current_addr(),
+                    (peer.local_id.to_string(), max_lag),
+                )
+                .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+                .and_then(|result| result.map_err(MdsError::from))
+                .map(|list| (None, list));
+                Box::new(future)
+            });
+        Either::A(Request::new(self.clone(), parent, request))
+    }
+
+    /// 最新のバージョンが`range`(UNIXエポックからのミリ秒)の期間内に保存されたオブジェクトの一覧を、
+    /// 保存時刻順に取得する.
+    pub fn list_by_time_range(
+        &self,
+        range: Range<u64>,
+    ) -> impl Future<Item = Vec<ObjectSummary>, Error = Error> {
+        debug!(self.logger, "Starts LIST: range={:?}", range);
+        let parent = Span::inactive().handle();
+        let request = RawRequestOnce::new(RequestKind::Other, move |peer, rpc_service| {
+            let leader = (peer.current_addr(), peer.local_id.to_string());
+            let future = rpc_auth::call::<ListObjectsByTimeRangeRpc>(
+                &rpc_service,
+                peer.current_addr(),
+                (peer.local_id.to_string(), range.start, range.end),
+            )
+            .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+            .and_then(|result| result.map_err(MdsError::from))
+            .map(move |list| (Some(leader), list));
+            Box::new(future)
+        });
+        Request::new(self.clone(), parent, request)
+    }
+
+    /// `after`よりも後にコミットされたオブジェクトの変更を、最大`max`個取得する.
+    pub fn watch(
+        &self,
+        after: Option<ObjectVersion>,
+        max: u64,
+    ) -> impl Future<Item = ObjectChanges, Error = Error> {
+        let parent = Span::inactive().handle();
+        let request = RawRequestOnce::new(RequestKind::Other, move |peer, rpc_service| {
+            let leader = (peer.current_addr(), peer.local_id.to_string());
+            let future = rpc_auth::call::<WatchObjectsRpc>(
+                &rpc_service,
+                peer.current_addr(),
+                (peer.local_id.to_string(), after, max),
+            )
+            .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+            .and_then(|result| result.map_err(MdsError::from))
+            .map(move |changes| (Some(leader), changes));
+            Box::new(future)
+        });
+        // 他のクライアント経由の更新を、TTL の経過を待たずにメタデータのキャッシュに反映する
+        let cache = self.metadata_cache.clone();
+        Request::new(self.clone(), parent, request).inspect(move |changes| {
+            if let Some(ref cache) = cache {
+                if changes.lost {
+                    cache.clear();
+                } else {
+                    for change in &changes.changes {
+                        cache.invalidate(&change.object_id);
+                    }
+                }
+            }
+        })
+    }
+
+    /// `ReadConsistency::Stale`での参照を、遅れが`max_lag`以内のフォロワーに送る要求を生成する.
+    ///
+    /// `call`には、送信先のノードに対する`ObjectRequest`と`max_lag`が渡される.
+    #[allow(clippy::type_complexity)]
+    fn stale_read_request<V, F>(
+        &self,
+        kind: RequestKind,
+        id: ObjectId,
+        max_lag: u64,
+        call: F,
+    ) -> RawRequestOnce<impl Fn(&NodeId, RpcServiceHandle) -> BoxFuture<V>>
+    where
+        V: Send + 'static,
+        F: Fn(
+                RpcServiceHandle,
+                SocketAddr,
+                (ObjectRequest, u64),
+            ) -> Box<dyn Future<Item = V, Error = MdsError> + Send>
+            + Send
+            + 'static,
+    {
+        RawRequestOnce::followers_first(kind, move |peer, rpc_service| {
+            let request = ObjectRequest {
+                node_id: peer.local_id.to_string(),
+                object_id: id.clone(),
+                expect: Expect::Any,
+                consistency: Some(ReadConsistency::Stale),
+            };
+            let future = call(rpc_service, peer.current_addr(), (request, max_lag));
+            Box::new(future.map(|v| (None, v)))
+        })
+    }
+
+    /// オブジェクトを取得する.
+    ///
+    /// メタデータのキャッシュが有効な場合、`ReadConsistency::Stale`と`ReadConsistency::Subset`の参照には
+    /// キャッシュから応答することがある.
     pub fn get(
         &self,
         id: ObjectId,
         consistency: ReadConsistency,
         parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectValue>, Error = Error> {
+        let cache = match self.metadata_cache {
+            Some(ref cache) if is_cacheable(&consistency) => cache.clone(),
+            _ => return Either::A(self.get_from_mds(id, consistency, parent)),
+        };
+        if let Some(value) = cache.get(&id) {
+            self.metadata_cache_metrics.hits_total.increment();
+            return Either::B(Either::A(futures::future::ok(Some(value))));
+        }
+        self.metadata_cache_metrics.misses_total.increment();
+        let generation = cache.generation();
+        let future = self
+            .get_from_mds(id.clone(), consistency, parent)
+            .inspect(move |value| {
+                if let Some(ref value) = *value {
+                    let metadata = Some(value.content.clone());
+                    cache.insert(generation, id, value.version, metadata);
+                }
+            });
+        Either::B(Either::B(future))
+    }
+
+    fn get_from_mds(
+        &self,
+        id: ObjectId,
+        consistency: ReadConsistency,
+        parent: SpanHandle,
     ) -> impl Future<Item = Option<ObjectValue>, Error = Error> {
         debug!(self.logger, "Starts GET: id={:?}", id);
         let member_size = self.member_size();
         if let Err(e) = validate_consistency(consistency.clone(), member_size) {
             return Either::A(futures::future::err(track!(e)));
         }
+        if let (ReadConsistency::Stale, Some(max_lag)) =
+            (&consistency, self.client_config.stale_read_max_lag)
+        {
+            let request =
+                self.stale_read_request(RequestKind::Get, id, max_lag, |rpc_service, addr, req| {
+                    let future = rpc_auth::call::<GetObjectWithinLagRpc>(&rpc_service, addr, req)
+                        .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+                        .and_then(|result| result.map_err(MdsError::from))
+                        .map(|metadata| to_object_value((None, metadata)).1);
+                    Box::new(future)
+                });
+            return Either::B(Either::A(Request::new(self.clone(), parent, request)));
+        }
         let concurrency = match consistency {
             ReadConsistency::Quorum => Some(self.majority_size()),
             ReadConsistency::Subset(n) => Some(n),
             _ => None,
         };
+        let quorum_metrics = if consistency == ReadConsistency::Quorum {
+            Some(self.quorum_metrics.clone())
+        } else {
+            None
+        };
         let request = if let Some(concurrency) = concurrency {
             RequestOnce2::Parallel(ParallelRequestOnce::new(
                 RequestKind::Get,
@@ -171,7 +382,7 @@ impl MdsClient {
                             future
                         })
                         .collect();
-                    Box::new(GetLatestObject::new(futures))
+                    Box::new(GetLatestObject::new(futures, quorum_metrics.clone()))
                 },
             ))
         } else {
@@ -183,25 +394,110 @@ impl MdsClient {
                 Box::new(future)
             }))
         };
-        Either::B(Request::new(self.clone(), parent, request))
+        Either::B(Either::B(Request::new(self.clone(), parent, request)))
+    }
+
+    /// 過去のバージョンを含めて、`selector`で指定されたオブジェクトを取得する.
+    ///
+    /// 整合性は常に`ReadConsistency::Consistent`となる.
+    pub fn get_revision(
+        &self,
+        id: ObjectId,
+        selector: RevisionSelector,
+        expect: Expect,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectValue>, Error = Error> {
+        debug!(
+            self.logger,
+            "Starts GET (revision): id={:?}, selector={:?}, expect={:?}", id, selector, expect
+        );
+        let request = RawRequestOnce::new(RequestKind::Get, move |peer, rpc_service| {
+            let request = ObjectRequest {
+                node_id: peer.local_id.to_string(),
+                object_id: id.clone(),
+                expect: expect.clone(),
+                consistency: Some(ReadConsistency::Consistent),
+            };
+            let leader = (peer.current_addr(), peer.local_id.to_string());
+            let future = rpc_auth::call::<GetObjectRevisionRpc>(
+                &rpc_service,
+                peer.current_addr(),
+                (request, selector),
+            )
+            .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+            .and_then(|result| result.map_err(MdsError::from))
+            .map(move |metadata| to_object_value((Some(leader), metadata)));
+            Box::new(future)
+        });
+        Request::new(self.clone(), parent, request)
     }
 
+    /// オブジェクトのバージョンを取得する.
+    ///
+    /// キャッシュの扱いは`get`と同様.
     pub fn head(
         &self,
         id: ObjectId,
         consistency: ReadConsistency,
         parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
+        let cache = match self.metadata_cache {
+            Some(ref cache) if is_cacheable(&consistency) => cache.clone(),
+            _ => return Either::A(self.head_from_mds(id, consistency, parent)),
+        };
+        if let Some(version) = cache.version(&id) {
+            self.metadata_cache_metrics.hits_total.increment();
+            return Either::B(Either::A(futures::future::ok(Some(version))));
+        }
+        self.metadata_cache_metrics.misses_total.increment();
+        let generation = cache.generation();
+        let future = self
+            .head_from_mds(id.clone(), consistency, parent)
+            .inspect(move |version| {
+                if let Some(version) = *version {
+                    cache.insert(generation, id, version, None);
+                }
+            });
+        Either::B(Either::B(future))
+    }
+
+    fn head_from_mds(
+        &self,
+        id: ObjectId,
+        consistency: ReadConsistency,
+        parent: SpanHandle,
     ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
         debug!(self.logger, "Starts HEAD: id={:?}", id);
         let member_size = self.member_size();
         if let Err(e) = validate_consistency(consistency.clone(), member_size) {
             return Either::A(futures::future::err(track!(e)));
         }
+        if let (ReadConsistency::Stale, Some(max_lag)) =
+            (&consistency, self.client_config.stale_read_max_lag)
+        {
+            let request = self.stale_read_request(
+                RequestKind::Head,
+                id,
+                max_lag,
+                |rpc_service, addr, req| {
+                    let future = rpc_auth::call::<HeadObjectWithinLagRpc>(&rpc_service, addr, req)
+                        .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+                        .and_then(|result| result.map_err(MdsError::from));
+                    Box::new(future)
+                },
+            );
+            return Either::B(Either::A(Request::new(self.clone(), parent, request)));
+        }
         let concurrency = match consistency {
             ReadConsistency::Quorum => Some(self.majority_size()),
             ReadConsistency::Subset(n) => Some(n),
             _ => None,
         };
+        let quorum_metrics = if consistency == ReadConsistency::Quorum {
+            Some(self.quorum_metrics.clone())
+        } else {
+            None
+        };
         let request = if let Some(concurrency) = concurrency {
             RequestOnce2::Parallel(ParallelRequestOnce::new(
                 RequestKind::Head,
@@ -221,7 +517,7 @@ impl MdsClient {
                             future
                         })
                         .collect();
-                    Box::new(GetLatestObject::new(futures))
+                    Box::new(GetLatestObject::new(futures, quorum_metrics.clone()))
                 },
             ))
         } else {
@@ -233,16 +529,69 @@ impl MdsClient {
                 )
             }))
         };
-        Either::B(Request::new(self.clone(), parent, request))
+        Either::B(Either::B(Request::new(self.clone(), parent, request)))
     }
 
+    /// オブジェクトを削除する.
+    ///
+    /// `deadline`が`Deadline::Within`の場合には、その期間内に削除が完了しなければ失敗する.
     pub fn delete(
         &self,
         id: ObjectId,
-        expect: Expect,
+        expect: Precondition,
+        deadline: Deadline,
         parent: SpanHandle,
     ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
         debug!(self.logger, "Starts DELETE: id={:?}", id);
+        let target = Some(id.clone());
+        let expect = match (expect, deadline) {
+            (precondition, Deadline::Within(timeout)) => {
+                let request = RawRequestOnce::new(RequestKind::Other, move |peer, rpc_service| {
+                    let request = ObjectRequest {
+                        node_id: peer.local_id.to_string(),
+                        object_id: id.clone(),
+                        expect: Expect::Any,
+                        consistency: None,
+                    };
+                    let leader = (peer.current_addr(), peer.local_id.to_string());
+                    let future = rpc_auth::call::<DeleteObjectWithinRpc>(
+                        &rpc_service,
+                        peer.current_addr(),
+                        (request, precondition.clone(), timeout),
+                    )
+                    .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+                    .and_then(|result| result.map_err(MdsError::from))
+                    .map(move |version| (Some(leader), version));
+                    Box::new(future)
+                });
+                let request = Request::new(self.clone(), parent, request).within(timeout);
+                return Either::A(Either::A(self.invalidating(target, request)));
+            }
+            (Precondition::Expect(expect), _) => expect,
+            (precondition, _) => {
+                // NOTE: `Expect`で表現できない条件の場合にのみ、独自の RPC を使う
+                let request = RawRequestOnce::new(RequestKind::Other, move |peer, rpc_service| {
+                    let request = ObjectRequest {
+                        node_id: peer.local_id.to_string(),
+                        object_id: id.clone(),
+                        expect: Expect::Any,
+                        consistency: None,
+                    };
+                    let leader = (peer.current_addr(), peer.local_id.to_string());
+                    let future = rpc_auth::call::<DeleteObjectIfRpc>(
+                        &rpc_service,
+                        peer.current_addr(),
+                        (request, precondition.clone()),
+                    )
+                    .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+                    .and_then(|result| result.map_err(MdsError::from))
+                    .map(move |version| (Some(leader), version));
+                    Box::new(future)
+                });
+                let request = Request::new(self.clone(), parent, request);
+                return Either::A(Either::B(self.invalidating(target, request)));
+            }
+        };
         let request = SingleRequestOnce::new(RequestKind::Other, move |client| {
             Box::new(
                 client
@@ -250,7 +599,35 @@ impl MdsClient {
                     .map_err(MdsError::from),
             )
         });
-        Request::new(self.clone(), parent, request)
+        let request = Request::new(self.clone(), parent, request);
+        Either::B(self.invalidating(target, request))
+    }
+
+    pub fn undelete(
+        &self,
+        id: ObjectId,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
+        debug!(self.logger, "Starts UNDELETE: id={:?}", id);
+        let target = Some(id.clone());
+        let request = RawRequestOnce::new(RequestKind::Other, move |peer, rpc_service| {
+            let request = ObjectRequest {
+                node_id: peer.local_id.to_string(),
+                object_id: id.clone(),
+                expect: Expect::Any,
+                consistency: None,
+            };
+            // NOTE: リーダ以外に送った場合はエラーとなり、`Request` によって次の候補に再送される
+            let leader = (peer.current_addr(), peer.local_id.to_string());
+            let future =
+                rpc_auth::call::<UndeleteObjectRpc>(&rpc_service, peer.current_addr(), request)
+                    .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+                    .and_then(|result| result.map_err(MdsError::from))
+                    .map(move |version| (Some(leader), version));
+            Box::new(future)
+        });
+        let request = Request::new(self.clone(), parent, request);
+        self.invalidating(target, request)
     }
 
     pub fn delete_by_version(
@@ -266,7 +643,8 @@ impl MdsClient {
                     .map_err(MdsError::from),
             )
         });
-        Request::new(self.clone(), parent, request)
+        let request = Request::new(self.clone(), parent, request);
+        self.invalidating(None, request)
     }
 
     pub fn delete_by_range(
@@ -285,7 +663,8 @@ impl MdsClient {
                     .map_err(MdsError::from),
             )
         });
-        Request::new(self.clone(), parent, request)
+        let request = Request::new(self.clone(), parent, request);
+        self.invalidating(None, request)
     }
 
     pub fn delete_by_prefix(
@@ -301,35 +680,266 @@ impl MdsClient {
                     .map_err(MdsError::from),
             )
         });
+        let request = Request::new(self.clone(), parent, request);
+        self.invalidating(None, request)
+    }
+
+    /// 接頭辞に一致するオブジェクト群を、バッチに分けて削除するジョブをリーダ上で開始する.
+    ///
+    /// 結果はジョブの ID.
+    pub fn start_delete_job(
+        &self,
+        prefix: ObjectPrefix,
+        parent: SpanHandle,
+    ) -> impl Future<Item = u64, Error = Error> {
+        debug!(self.logger, "Starts DELETE job: prefix={:?}", prefix);
+        let request = RawRequestOnce::new(RequestKind::Other, move |peer, rpc_service| {
+            let request = PrefixRequest {
+                node_id: peer.local_id.to_string(),
+                prefix: prefix.clone(),
+            };
+            let leader = (peer.current_addr(), peer.local_id.to_string());
+            let future =
+                rpc_auth::call::<StartDeleteJobRpc>(&rpc_service, peer.current_addr(), request)
+                    .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+                    .and_then(|result| result.map_err(MdsError::from))
+                    .map(move |job_id| (Some(leader), job_id));
+            Box::new(future)
+        });
         Request::new(self.clone(), parent, request)
     }
 
+    /// 削除ジョブの進捗を返す.
+    ///
+    /// ジョブの状態はリーダにのみ保持されているので、リーダが交代した後は`None`となる.
+    pub fn get_delete_job_status(
+        &self,
+        job_id: u64,
+    ) -> impl Future<Item = Option<DeleteJobStatus>, Error = Error> {
+        let parent = Span::inactive().handle();
+        let request = RawRequestOnce::new(RequestKind::Other, move |peer, rpc_service| {
+            let leader = (peer.current_addr(), peer.local_id.to_string());
+            let future = rpc_auth::call::<GetDeleteJobRpc>(
+                &rpc_service,
+                peer.current_addr(),
+                (peer.local_id.to_string(), job_id),
+            )
+            .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+            .and_then(|result| result.map_err(MdsError::from))
+            .map(move |status| (Some(leader), status));
+            Box::new(future)
+        });
+        Request::new(self.clone(), parent, request)
+    }
+
+    /// 削除ジョブを中断する.
+    pub fn cancel_delete_job(
+        &self,
+        job_id: u64,
+    ) -> impl Future<Item = Option<DeleteJobStatus>, Error = Error> {
+        let parent = Span::inactive().handle();
+        let request = RawRequestOnce::new(RequestKind::Other, move |peer, rpc_service| {
+            let leader = (peer.current_addr(), peer.local_id.to_string());
+            let future = rpc_auth::call::<CancelDeleteJobRpc>(
+                &rpc_service,
+                peer.current_addr(),
+                (peer.local_id.to_string(), job_id),
+            )
+            .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+            .and_then(|result| result.map_err(MdsError::from))
+            .map(move |status| (Some(leader), status));
+            Box::new(future)
+        });
+        Request::new(self.clone(), parent, request)
+    }
+
+    /// オブジェクトを保存する.
+    ///
+    /// `size`はオブジェクトの論理的な大きさ(バイト)で、MDS での割り当て量の確認に使われる.
+    /// ただし、クラスタ単位の機能フラグ`quota`が有効でない場合には、古いバージョンの MDS でも
+    /// 扱える従来の RPC が使われ、大きさは MDS に伝えられない.
+    /// `deadline`が`Deadline::Within`の場合には、その期間内に保存が完了しなければ失敗する.
     pub fn put(
         &self,
         id: ObjectId,
         content: Vec<u8>,
-        expect: Expect,
+        size: u64,
+        expect: Precondition,
         deadline: Deadline,
         parent: SpanHandle,
     ) -> impl Future<Item = (ObjectVersion, bool), Error = Error> {
         debug!(self.logger, "Starts PUT: id={:?}", id);
+        let target = Some(id.clone());
         let put_content_timeout = Seconds(if let Deadline::Within(d) = deadline {
             d.as_secs() + self.client_config.put_content_timeout.0
         } else {
             self.client_config.put_content_timeout.0
         });
-        let request = SingleRequestOnce::new(RequestKind::Other, move |client| {
-            Box::new(
-                client
-                    .put_object(
-                        id.clone(),
-                        content.clone(),
-                        expect.clone(),
-                        put_content_timeout.into(),
-                    )
-                    .map(|(leader, (version, old))| (leader, (version, old.is_none())))
-                    .map_err(MdsError::from),
+        let timeout = if let Deadline::Within(timeout) = deadline {
+            Some(timeout)
+        } else {
+            None
+        };
+        let sized = cluster_feature::is_enabled(ClusterFeature::Quota);
+        let request = RawRequestOnce::new(RequestKind::Other, move |peer, rpc_service| {
+            let mut request = PutObjectRequest {
+                node_id: peer.local_id.to_string(),
+                object_id: id.clone(),
+                metadata: content.clone(),
+                expect: Expect::Any,
+                put_content_timeout: put_content_timeout.into(),
+            };
+            let leader = (peer.current_addr(), peer.local_id.to_string());
+            if !sized {
+                let addr = peer.current_addr();
+                let future: Box<
+                    dyn Future<Item = (ObjectVersion, Option<ObjectVersion>), Error = MdsError>
+                        + Send,
+                > = match (expect.clone(), timeout) {
+                    (precondition, Some(timeout)) => Box::new(
+                        rpc_auth::call::<PutObjectWithinRpc>(
+                            &rpc_service,
+                            addr,
+                            (request, precondition, timeout),
+                        )
+                        .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+                        .and_then(|result| result.map_err(MdsError::from)),
+                    ),
+                    (Precondition::Expect(expect), None) => {
+                        request.expect = expect;
+                        Box::new(
+                            rpc_auth::call::<mds_rpc::PutObjectRpc>(&rpc_service, addr, request)
+                                .map_err(|e| {
+                                    track!(MdsError::from(MdsErrorKind::Other.takes_over(e)))
+                                })
+                                .and_then(|result| result.map_err(MdsError::from)),
+                        )
+                    }
+                    (precondition, None) => Box::new(
+                        rpc_auth::call::<PutObjectIfRpc>(
+                            &rpc_service,
+                            addr,
+                            (request, precondition),
+                        )
+                        .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+                        .and_then(|result| result.map_err(MdsError::from)),
+                    ),
+                };
+                return Box::new(
+                    future.map(move |(version, old)| (Some(leader), (version, old.is_none()))),
+                );
+            }
+            let future = rpc_auth::call::<PutObjectSizedRpc>(
+                &rpc_service,
+                peer.current_addr(),
+                (request, expect.clone(), timeout, size),
             )
+            .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+            .and_then(|result| result.map_err(MdsError::from))
+            .and_then(|result| {
+                result.map_err(|usage| {
+                    let e = MdsErrorKind::QuotaExceeded(usage).cause("Quota exceeded");
+                    track!(MdsError::from(e))
+                })
+            })
+            .map(move |(version, old)| (Some(leader), (version, old.is_none())));
+            Box::new(future)
+        });
+        let request = Request::new(self.clone(), parent, request);
+        let request = if let Some(timeout) = timeout {
+            Either::A(request.within(timeout))
+        } else {
+            Either::B(request)
+        };
+        self.invalidating(target, request)
+    }
+
+    /// 既存のオブジェクトに追記する、大きさが`size`の部分のためにバージョンを予約する.
+    ///
+    /// 予約された部分は、内容を保存した後に`commit_append`を呼び出すことでオブジェクトに追記される.
+    /// 結果は、予約された部分のバージョンと予約時点のオブジェクトのバージョンの組.
+    pub fn append(
+        &self,
+        id: ObjectId,
+        expect: Expect,
+        size: u64,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = (ObjectVersion, Option<ObjectVersion>), Error = Error> {
+        debug!(self.logger, "Starts APPEND: id={:?}, size={}", id, size);
+        let target = Some(id.clone());
+        let put_content_timeout = Seconds(if let Deadline::Within(d) = deadline {
+            d.as_secs() + self.client_config.put_content_timeout.0
+        } else {
+            self.client_config.put_content_timeout.0
+        });
+        let request = RawRequestOnce::new(RequestKind::Other, move |peer, rpc_service| {
+            let request = PutObjectRequest {
+                node_id: peer.local_id.to_string(),
+                object_id: id.clone(),
+                metadata: Vec::new(),
+                expect: expect.clone(),
+                put_content_timeout: put_content_timeout.into(),
+            };
+            let leader = (peer.current_addr(), peer.local_id.to_string());
+            let future = rpc_auth::call::<AppendObjectRpc>(
+                &rpc_service,
+                peer.current_addr(),
+                (request, size),
+            )
+            .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+            .and_then(|result| result.map_err(MdsError::from))
+            .map(move |versions| (Some(leader), versions));
+            Box::new(future)
+        });
+        let request = Request::new(self.clone(), parent, request);
+        self.invalidating(target, request)
+    }
+
+    /// `append`で予約した部分を、オブジェクトの末尾に追記する.
+    ///
+    /// 予約後にオブジェクトが更新されていた場合には`ErrorKind::UnexpectedVersion`が返され、
+    /// 予約した部分は破棄される.
+    /// 結果は、追記前のオブジェクトのバージョン.
+    pub fn commit_append(
+        &self,
+        id: ObjectId,
+        version: ObjectVersion,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
+        debug!(
+            self.logger,
+            "Starts COMMIT_APPEND: id={:?}, version={:?}", id, version
+        );
+        let target = Some(id.clone());
+        let request = RawRequestOnce::new(RequestKind::Other, move |peer, rpc_service| {
+            let request = (peer.local_id.to_string(), id.clone(), version);
+            let leader = (peer.current_addr(), peer.local_id.to_string());
+            let future =
+                rpc_auth::call::<CommitAppendRpc>(&rpc_service, peer.current_addr(), request)
+                    .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+                    .and_then(|result| result.map_err(MdsError::from))
+                    .map(move |old| (Some(leader), old));
+            Box::new(future)
+        });
+        let request = Request::new(self.clone(), parent, request);
+        self.invalidating(target, request)
+    }
+
+    /// セグメントの使用量と割り当て量を返す.
+    pub fn quota_usage(&self) -> impl Future<Item = QuotaUsage, Error = Error> {
+        let parent = Span::inactive().handle();
+        let request = RawRequestOnce::new(RequestKind::Other, move |peer, rpc_service| {
+            let leader = (peer.current_addr(), peer.local_id.to_string());
+            let future = rpc_auth::call::<GetQuotaUsageRpc>(
+                &rpc_service,
+                peer.current_addr(),
+                peer.local_id.to_string(),
+            )
+            .map_err(|e| track!(MdsError::from(MdsErrorKind::Other.takes_over(e))))
+            .and_then(|result| result.map_err(MdsError::from))
+            .map(move |usage| (Some(leader), usage));
+            Box::new(future)
         });
         Request::new(self.clone(), parent, request)
     }
@@ -343,6 +953,29 @@ impl MdsClient {
         Request::new(self.clone(), parent, request)
     }
 
+    /// 更新系の要求の完了時(失敗した場合も含む)に、メタデータのキャッシュを無効化する.
+    ///
+    /// `id`が`None`の場合には、全てのエントリが無効化される.
+    fn invalidating<F>(
+        &self,
+        id: Option<ObjectId>,
+        future: F,
+    ) -> impl Future<Item = F::Item, Error = Error>
+    where
+        F: Future<Error = Error>,
+    {
+        let cache = self.metadata_cache.clone();
+        future.then(move |result| {
+            if let Some(cache) = cache {
+                match id {
+                    Some(ref id) => cache.invalidate(id),
+                    None => cache.clear(),
+                }
+            }
+            result
+        })
+    }
+
     fn timeout(&self, kind: RequestKind, max_retry: usize) -> RequestTimeout {
         match self.request_policy(&kind) {
             // for backward compatibility
@@ -363,7 +996,7 @@ impl MdsClient {
         }
     }
     fn majority_size(&self) -> usize {
-        ((self.member_size() as f64 / 2.0).ceil()) as usize
+        majority_of(self.member_size())
     }
     fn member_size(&self) -> usize {
         self.inner
@@ -373,9 +1006,26 @@ impl MdsClient {
             .members
             .len()
     }
+    /// このクライアントが現在リーダとして扱っているノードを返す.
+    ///
+    /// リーダが不明な場合に(リクエストの送信先として)暫定的に選ばれたノードも含まれる.
+    pub fn known_leader(&self) -> Option<NodeId> {
+        self.inner.lock().unwrap_or_else(|e| panic!("{}", e)).leader
+    }
     fn clear_leader(&self) {
         self.inner.lock().unwrap_or_else(|e| panic!("{}", e)).leader = None;
     }
+    /// `peers`のいずれかが`NotLeader`エラーを返した場合に呼び出される.
+    ///
+    /// キャッシュしているリーダが`peers`に含まれている場合には、それを無効化する.
+    fn invalidate_leader(&self, peers: &[NodeId]) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| panic!("{}", e));
+        if inner.leader.is_some_and(|leader| peers.contains(&leader)) {
+            debug!(self.logger, "Invalidate leader: {:?}", inner.leader);
+            inner.leader = None;
+            self.leader_metrics.invalidations_total.increment();
+        }
+    }
     fn set_leader(&self, leader: LocalNodeId) {
         // TODO: debugレベルにする
         info!(self.logger, "Set leader: {:?}", leader);
@@ -420,6 +1070,22 @@ impl MdsClient {
         if let Some(leader) = inner.leader {
             peers.insert(leader);
         }
+        // ウィットネスは読み込みに応答しないので、それ以外のメンバを優先する
+        let readables = inner
+            .config
+            .members
+            .iter()
+            .filter(|m| !inner.config.is_mds_witness(&m.node))
+            .count();
+        let mut tried = 0;
+        while peers.len() < required_peers && tried < readables {
+            let member = &inner.config.members[i % member_total];
+            if !inner.config.is_mds_witness(&member.node) {
+                peers.insert(member.node);
+                tried += 1;
+            }
+            i += 1;
+        }
         // NOTE: 上記 `track_assert!` によって無限ループしないことが保証されている
         while peers.len() < required_peers {
             peers.insert(inner.config.members[i % member_total].node);
@@ -427,12 +1093,49 @@ impl MdsClient {
         }
         Ok(peers)
     }
+    /// `attempt`回目(0 始まり)の要求の送信先として、フォロワーを優先して選択する。
+    ///
+    /// フォロワーを`from`から順に一度ずつ選び、全て選び終えた後はリーダを選ぶ。
+    /// ウィットネスは読み込みに応答しないので、候補から除外される。
+    fn follower_or_leader(&self, from: usize, attempt: usize) -> Result<NodeId> {
+        let inner = self.inner.lock().unwrap_or_else(|e| panic!("{}", e));
+        track_assert!(
+            !inner.config.members.is_empty(),
+            ErrorKind::Invalid,
+            "This cluster has no members"
+        );
+        let followers = inner
+            .config
+            .members
+            .iter()
+            .map(|m| m.node)
+            .filter(|node| inner.leader != Some(*node) && !inner.config.is_mds_witness(node))
+            .collect::<Vec<_>>();
+        let peer = match inner.leader {
+            Some(leader) if attempt >= followers.len() => leader,
+            None if followers.is_empty() => {
+                let members = &inner.config.members;
+                members[from.wrapping_add(attempt) % members.len()].node
+            }
+            _ => followers[from.wrapping_add(attempt) % followers.len()],
+        };
+        Ok(peer)
+    }
     fn leader(&self) -> NodeId {
         let mut inner = self.inner.lock().unwrap_or_else(|e| panic!("{}", e));
         if inner.leader.is_none() {
-            inner.leader = rand::thread_rng()
-                .choose(&inner.config.members)
-                .map(|m| m.node);
+            // ウィットネスはリーダにならないので、それ以外のメンバから選ぶ
+            let mut candidates = inner
+                .config
+                .members
+                .iter()
+                .map(|m| m.node)
+                .filter(|node| !inner.config.is_mds_witness(node))
+                .collect::<Vec<_>>();
+            if candidates.is_empty() {
+                candidates = inner.config.members.iter().map(|m| m.node).collect();
+            }
+            inner.leader = rand::thread_rng().choose(&candidates).cloned();
         }
         inner.leader.unwrap_or_else(|| unreachable!())
     }
@@ -453,6 +1156,143 @@ impl MdsClient {
 type BoxFuture<V> =
     Box<dyn Future<Item = (Option<RemoteNodeId>, V), Error = MdsError> + Send + 'static>;
 
+/// 単一の MDS ノードに RPC を発行するクライアント.
+///
+/// `libfrugalos::client::mds::Client`と同等だが、クラスタトークンが設定されている場合にはそれを付けて呼び出す.
+#[derive(Debug, Clone)]
+struct RaftMdsClient {
+    server: (SocketAddr, String),
+    rpc_service: RpcServiceHandle,
+}
+impl RaftMdsClient {
+    fn new(server: (SocketAddr, String), rpc_service: RpcServiceHandle) -> Self {
+        RaftMdsClient {
+            server,
+            rpc_service,
+        }
+    }
+
+    fn latest_version(
+        &self,
+    ) -> impl Future<Item = (Option<RemoteNodeId>, Option<ObjectSummary>), Error = libfrugalos::Error>
+    {
+        self.call::<mds_rpc::GetLatestVersionRpc, _>(self.server.1.clone())
+    }
+
+    fn list_objects(
+        &self,
+    ) -> impl Future<Item = (Option<RemoteNodeId>, Vec<ObjectSummary>), Error = libfrugalos::Error>
+    {
+        self.call::<mds_rpc::ListObjectsRpc, _>(self.server.1.clone())
+    }
+
+    fn object_count(
+        &self,
+    ) -> impl Future<Item = (Option<RemoteNodeId>, u64), Error = libfrugalos::Error> {
+        self.call::<mds_rpc::GetObjectCountRpc, _>(self.server.1.clone())
+    }
+
+    fn get_object(
+        &self,
+        object_id: ObjectId,
+        expect: Expect,
+        consistency: ReadConsistency,
+    ) -> impl Future<Item = (Option<RemoteNodeId>, Option<Metadata>), Error = libfrugalos::Error>
+    {
+        let request = self.object_request(object_id, expect, Some(consistency));
+        self.call::<mds_rpc::GetObjectRpc, _>(request)
+    }
+
+    fn head_object(
+        &self,
+        object_id: ObjectId,
+        expect: Expect,
+        consistency: ReadConsistency,
+    ) -> impl Future<Item = (Option<RemoteNodeId>, Option<ObjectVersion>), Error = libfrugalos::Error>
+    {
+        let request = self.object_request(object_id, expect, Some(consistency));
+        self.call::<mds_rpc::HeadObjectRpc, _>(request)
+    }
+
+    fn delete_object(
+        &self,
+        object_id: ObjectId,
+        expect: Expect,
+    ) -> impl Future<Item = (Option<RemoteNodeId>, Option<ObjectVersion>), Error = libfrugalos::Error>
+    {
+        let request = self.object_request(object_id, expect, None);
+        self.call::<mds_rpc::DeleteObjectRpc, _>(request)
+    }
+
+    fn delete_object_by_version(
+        &self,
+        object_version: ObjectVersion,
+    ) -> impl Future<Item = (Option<RemoteNodeId>, Option<ObjectVersion>), Error = libfrugalos::Error>
+    {
+        let request = VersionRequest {
+            node_id: self.server.1.clone(),
+            object_version,
+        };
+        self.call::<mds_rpc::DeleteObjectByVersionRpc, _>(request)
+    }
+
+    fn delete_by_range(
+        &self,
+        targets: Range<ObjectVersion>,
+    ) -> impl Future<Item = (Option<RemoteNodeId>, Vec<ObjectSummary>), Error = libfrugalos::Error>
+    {
+        let request = RangeRequest {
+            node_id: self.server.1.clone(),
+            targets,
+        };
+        self.call::<mds_rpc::DeleteObjectsByRangeRpc, _>(request)
+    }
+
+    fn delete_by_prefix(
+        &self,
+        prefix: ObjectPrefix,
+    ) -> impl Future<
+        Item = (Option<RemoteNodeId>, DeleteObjectsByPrefixSummary),
+        Error = libfrugalos::Error,
+    > {
+        let request = PrefixRequest {
+            node_id: self.server.1.clone(),
+            prefix,
+        };
+        self.call::<mds_rpc::DeleteObjectsByPrefixRpc, _>(request)
+    }
+
+    fn object_request(
+        &self,
+        object_id: ObjectId,
+        expect: Expect,
+        consistency: Option<ReadConsistency>,
+    ) -> ObjectRequest {
+        ObjectRequest {
+            node_id: self.server.1.clone(),
+            object_id,
+            expect,
+            consistency,
+        }
+    }
+
+    fn call<C, T>(&self, request: C::Req) -> impl Future<Item = T, Error = libfrugalos::Error>
+    where
+        C: RpcCall<Res = libfrugalos::Result<T>>,
+        C::Req: Serialize + DeserializeOwned + Send + 'static,
+        C::ReqEncoder: Default,
+        C::ResDecoder: Default,
+    {
+        rpc_auth::call::<C>(&self.rpc_service, self.server.0, request)
+            .map_err(|e| {
+                track!(libfrugalos::Error::from(
+                    libfrugalos::ErrorKind::Other.takes_over(e)
+                ))
+            })
+            .and_then(|result| result)
+    }
+}
+
 fn to_object_value(
     response: (Option<RemoteNodeId>, Option<Metadata>),
 ) -> (Option<RemoteNodeId>, Option<ObjectValue>) {
@@ -465,6 +1305,19 @@ fn to_object_value(
     )
 }
 
+/// `n` 台のノードからなるクラスタの過半数を返す。
+// 古い値を返すことが許容される(i.e., メタデータのキャッシュを使って良い)参照かどうか
+fn is_cacheable(consistency: &ReadConsistency) -> bool {
+    match *consistency {
+        ReadConsistency::Stale | ReadConsistency::Subset(_) => true,
+        ReadConsistency::Consistent | ReadConsistency::Quorum => false,
+    }
+}
+
+fn majority_of(n: usize) -> usize {
+    n / 2 + 1
+}
+
 fn validate_consistency(consistency: ReadConsistency, member_size: usize) -> Result<()> {
     if member_size == 0 {
         return track!(Err(ErrorKind::Invalid
@@ -489,8 +1342,8 @@ fn make_request_span(parent: &SpanHandle, peer: &NodeId) -> Span {
     parent.child("mds_request", |span| {
         span.tag(StdTag::component(module_path!()))
             .tag(StdTag::span_kind("client"))
-            .tag(StdTag::peer_ip(peer.addr.ip()))
-            .tag(StdTag::peer_port(peer.addr.port()))
+            .tag(StdTag::peer_ip(peer.current_addr().ip()))
+            .tag(StdTag::peer_port(peer.current_addr().port()))
             .tag(Tag::new("peer.node", peer.local_id.to_string()))
             .start()
     })
@@ -555,6 +1408,9 @@ pub struct Request<T: RequestOnce> {
     peers: Vec<NodeId>,
     timeout: RequestTimeout,
     future: Option<BoxFuture<T::Item>>,
+
+    // 呼び出し元の期限. 期限を過ぎた場合には、リトライせずに失敗する.
+    deadline: Option<timer::Timeout>,
 }
 impl<T> Request<T>
 where
@@ -572,8 +1428,15 @@ where
             peers: Vec::new(),
             timeout,
             future: None,
+            deadline: None,
         }
     }
+
+    /// 要求全体(リトライを含む)に期限を設定する.
+    pub fn within(mut self, duration: Duration) -> Self {
+        self.deadline = Some(timer::timeout(duration));
+        self
+    }
     fn request_once(&mut self) -> Result<()> {
         track_assert_ne!(self.max_retry, 0, ErrorKind::Busy);
         self.max_retry -= 1;
@@ -592,6 +1455,14 @@ where
     type Item = T::Item;
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(Some(())) = track!(self.deadline.poll().map_err(Error::from))? {
+            track_panic!(
+                ErrorKind::Busy,
+                "Deadline exceeded: peers={:?}, max_retry={}",
+                self.peers,
+                self.max_retry
+            );
+        }
         // It is possible to reduce processing time by making a request time out.
         // For example, there is a node where leader election has been completed but the leader has not been updated yet.
         while let Async::Ready(()) = track!(self.timeout.poll())? {
@@ -615,6 +1486,14 @@ where
                     return Err(
                         track!(ErrorKind::UnexpectedVersion { current }.takes_over(e)).into(),
                     );
+                } else if let MdsErrorKind::QuotaExceeded(_) = *e.kind() {
+                    // NOTE: リーダが判断した結果なので、再試行しても結果は変わらない
+                    return Err(track!(ErrorKind::QuotaExceeded.takes_over(e)).into());
+                } else if *e.kind() == MdsErrorKind::Timeout {
+                    // NOTE: 期限を過ぎているので、他のノードで再試行しても意味がない
+                    return Err(track!(ErrorKind::Busy.takes_over(e)).into());
+                } else if *e.kind() == MdsErrorKind::NotLeader {
+                    self.client.invalidate_leader(&self.peers);
                 } else {
                     self.client.clear_leader();
                 }
@@ -635,7 +1514,12 @@ where
             Ok(Async::Ready(Some((new_leader, v)))) => {
                 if let Some(leader) = new_leader {
                     let (_addr, local_node_id) = leader;
-                    self.client.set_leader(track!(local_node_id.parse())?);
+                    let local_node_id: LocalNodeId = track!(local_node_id.parse())?;
+                    if self.peers.iter().all(|p| p.local_id != local_node_id) {
+                        // 送信先がリーダではなく、リーダへリダイレクトされた
+                        self.client.leader_metrics.redirects_total.increment();
+                    }
+                    self.client.set_leader(local_node_id);
                 }
                 Ok(Async::Ready(v))
             }
@@ -734,7 +1618,7 @@ where
         let mut clients = Vec::new();
         for peer in &peers {
             let client = RaftMdsClient::new(
-                (peer.addr, peer.local_id.to_string()),
+                (peer.current_addr(), peer.local_id.to_string()),
                 client.rpc_service.clone(),
             );
             let span = make_request_span(parent, peer);
@@ -783,8 +1667,9 @@ where
         let request_policy = client.request_policy(&self.kind);
         let peer = client.next_peer(request_policy, self.from_peer);
         let mut span = make_request_span(parent, &peer);
+        span.set_tag(|| Tag::new("mds.attempt", self.from_peer as i64));
         let client = RaftMdsClient::new(
-            (peer.addr, peer.local_id.to_string()),
+            (peer.current_addr(), peer.local_id.to_string()),
             client.rpc_service.clone(),
         );
         let future = (self.f)(client);
@@ -799,6 +1684,77 @@ where
     }
 }
 
+/// `libfrugalos` のクライアントが提供していない RPC を単一の MDS に投げるための `Future` を生成する。
+///
+/// `SingleRequestOnce` とは異なり、リーダへのリダイレクトは行われない。
+struct RawRequestOnce<F> {
+    kind: RequestKind,
+    from_peer: usize,
+    f: F,
+
+    // リーダよりもフォロワーを優先して送信先に選ぶかどうか
+    followers_first: bool,
+    attempts: usize,
+}
+impl<F, V> RawRequestOnce<F>
+where
+    F: Fn(&NodeId, RpcServiceHandle) -> BoxFuture<V>,
+    V: Send + 'static,
+{
+    fn new(kind: RequestKind, f: F) -> Self {
+        let from_peer = thread_rng().gen();
+        Self {
+            kind,
+            from_peer,
+            f,
+            followers_first: false,
+            attempts: 0,
+        }
+    }
+
+    /// リーダの負荷を下げるために、フォロワーに優先して送信する要求を生成する。
+    ///
+    /// 全てのフォロワーで失敗した場合にのみ、リーダに送信される。
+    fn followers_first(kind: RequestKind, f: F) -> Self {
+        let mut this = Self::new(kind, f);
+        this.followers_first = true;
+        this
+    }
+}
+impl<F, V> RequestOnce for RawRequestOnce<F>
+where
+    F: Fn(&NodeId, RpcServiceHandle) -> BoxFuture<V>,
+    V: Send + 'static,
+{
+    type Item = V;
+    fn kind(&self) -> RequestKind {
+        self.kind
+    }
+    fn request_once(
+        &mut self,
+        client: &MdsClient,
+        parent: &SpanHandle,
+    ) -> Result<(Vec<NodeId>, BoxFuture<Self::Item>)> {
+        let peer = if self.followers_first {
+            self.attempts += 1;
+            track!(client.follower_or_leader(self.from_peer, self.attempts - 1))?
+        } else {
+            self.from_peer += 1;
+            let request_policy = client.request_policy(&self.kind);
+            client.next_peer(request_policy, self.from_peer)
+        };
+        let mut span = make_request_span(parent, &peer);
+        let future = (self.f)(&peer, client.rpc_service.clone());
+        let future = future.then(move |result| {
+            if let Err(ref e) = result {
+                span.log_error(e);
+            }
+            track!(result)
+        });
+        Ok((vec![peer], Box::new(future)))
+    }
+}
+
 /// `ObjectVersion` を取得できる型で実装するべきトレイト。
 ///
 /// HEAD と GET で `GetLatestObject` を共用するために利用される。
@@ -829,9 +1785,110 @@ where
     values.max_by_key(ContainObjectVersion::object_version)
 }
 
+/// `ReadConsistency::Quorum` による参照の挙動を観測するためのメトリクス。
+#[derive(Debug, Clone)]
+struct QuorumReadMetrics {
+    reads_total: Counter,
+    disagreements_total: Counter,
+    peer_failures_total: Counter,
+}
+impl QuorumReadMetrics {
+    fn new(labels: &MetricLabels) -> Self {
+        get_or_create(labels, |labels| -> Result<Self> {
+            let metric_builder = mds_client_metric_builder(labels);
+            Ok(QuorumReadMetrics {
+                reads_total: track!(metric_builder
+                    .counter("quorum_reads_total")
+                    .help("Number of MDS reads performed with the quorum consistency")
+                    .default_registry()
+                    .finish())?,
+                disagreements_total: track!(metric_builder
+                    .counter("quorum_read_disagreements_total")
+                    .help("Number of quorum reads whose peers returned different versions")
+                    .default_registry()
+                    .finish())?,
+                peer_failures_total: track!(metric_builder
+                    .counter("quorum_read_peer_failures_total")
+                    .help("Number of peer failures during quorum reads")
+                    .default_registry()
+                    .finish())?,
+            })
+        })
+        .expect("metric should be well-formed")
+    }
+}
+
+/// リーダのキャッシュの効果を観測するためのメトリクス。
+#[derive(Debug, Clone)]
+struct LeaderCacheMetrics {
+    /// リーダ以外に送信され、リーダへリダイレクトされたリクエストの数。
+    redirects_total: Counter,
+
+    /// `NotLeader`エラーによって、キャッシュしていたリーダが無効化された回数。
+    invalidations_total: Counter,
+}
+impl LeaderCacheMetrics {
+    fn new(labels: &MetricLabels) -> Self {
+        get_or_create(labels, |labels| -> Result<Self> {
+            let metric_builder = mds_client_metric_builder(labels);
+            Ok(LeaderCacheMetrics {
+                redirects_total: track!(metric_builder
+                    .counter("leader_redirects_total")
+                    .help("Number of MDS requests redirected to the leader")
+                    .default_registry()
+                    .finish())?,
+                invalidations_total: track!(metric_builder
+                    .counter("leader_invalidations_total")
+                    .help("Number of cached MDS leaders invalidated by NotLeader errors")
+                    .default_registry()
+                    .finish())?,
+            })
+        })
+        .expect("metric should be well-formed")
+    }
+}
+
+/// メタデータのキャッシュの効果を観測するためのメトリクス。
+#[derive(Debug, Clone)]
+struct MetadataCacheMetrics {
+    /// キャッシュから応答した GET/HEAD の数。
+    hits_total: Counter,
+
+    /// キャッシュになく、MDS に問い合わせた GET/HEAD の数。
+    misses_total: Counter,
+}
+impl MetadataCacheMetrics {
+    fn new(labels: &MetricLabels) -> Self {
+        get_or_create(labels, |labels| -> Result<Self> {
+            let metric_builder = mds_client_metric_builder(labels);
+            Ok(MetadataCacheMetrics {
+                hits_total: track!(metric_builder
+                    .counter("metadata_cache_hits_total")
+                    .help("Number of GET/HEAD requests answered from the metadata cache")
+                    .default_registry()
+                    .finish())?,
+                misses_total: track!(metric_builder
+                    .counter("metadata_cache_misses_total")
+                    .help("Number of GET/HEAD requests that missed the metadata cache")
+                    .default_registry()
+                    .finish())?,
+            })
+        })
+        .expect("metric should be well-formed")
+    }
+}
+
+fn mds_client_metric_builder(labels: &MetricLabels) -> MetricBuilder {
+    let mut builder = MetricBuilder::new();
+    builder.namespace("frugalos").subsystem("mds_client");
+    labels.apply(&mut builder);
+    builder
+}
+
 /// 複数ノードに同時に参照リクエストを投げ、最新の `ObjectVersion` を返してきたレスポンスを採用する。
 ///
 /// 可用性を優先するため、最新ではないオブジェクトを返すことを許容している。
+/// `ReadConsistency::Quorum` の場合には、各ノードの応答が食い違った回数がメトリクスとして記録される。
 struct GetLatestObject<V> {
     /// 存在しないオブジェクトを参照した回数。
     ///
@@ -846,14 +1903,32 @@ struct GetLatestObject<V> {
     ///
     /// `not_found_count` と `values` を比較した上で最終的な結果が決まる。
     values: Vec<(Option<RemoteNodeId>, V)>,
+
+    /// `ReadConsistency::Quorum` の場合にのみ指定される。
+    metrics: Option<QuorumReadMetrics>,
 }
-impl<V> GetLatestObject<V> {
-    fn new(futures: Vec<BoxFuture<Option<V>>>) -> Self {
+impl<V> GetLatestObject<V>
+where
+    V: ContainObjectVersion,
+{
+    fn new(futures: Vec<BoxFuture<Option<V>>>, metrics: Option<QuorumReadMetrics>) -> Self {
         Self {
             futures,
             not_found_count: 0,
             values: Vec::new(),
+            metrics,
+        }
+    }
+
+    /// ノード間で、オブジェクトの有無ないしバージョンが食い違っているかどうか。
+    fn is_disagreed(&self) -> bool {
+        if self.not_found_count > 0 {
+            return !self.values.is_empty();
         }
+        let mut versions = self.values.iter().map(|v| v.object_version());
+        versions
+            .next()
+            .is_some_and(|first| versions.any(|v| v != first))
     }
 }
 impl<V> Future for GetLatestObject<V>
@@ -868,6 +1943,10 @@ where
             match track!(self.futures[i].poll()) {
                 Err(e) => {
                     self.futures.swap_remove(i);
+                    if let Some(ref metrics) = self.metrics {
+                        // NOTE: 呼び出し元の `Request` によって、別のノード群に対して再試行される
+                        metrics.peer_failures_total.increment();
+                    }
                     return track!(Err(e));
                 }
                 Ok(Async::NotReady) => {
@@ -884,6 +1963,12 @@ where
             }
         }
         if self.futures.is_empty() {
+            if let Some(ref metrics) = self.metrics {
+                metrics.reads_total.increment();
+                if self.is_disagreed() {
+                    metrics.disagreements_total.increment();
+                }
+            }
             let values = self.values.drain(..);
             if self.not_found_count > values.len() {
                 return Ok(Async::Ready((None, None)));
@@ -901,6 +1986,15 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use fibers_global;
+    use fibers_rpc::client::ClientServiceBuilder as RpcServiceBuilder;
+    use futures;
+    use slog::Discard;
+    use std::collections::VecDeque;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use trackable::result::TestResult;
+
+    use config::ClusterMember;
 
     #[test]
     fn validate_consistency_works() {
@@ -915,4 +2009,169 @@ mod tests {
         assert!(validate_consistency(ReadConsistency::Subset(2), 1).is_err());
         assert!(validate_consistency(ReadConsistency::Subset(0), 1).is_err());
     }
+
+    #[test]
+    fn majority_of_works() {
+        assert_eq!(majority_of(1), 1);
+        assert_eq!(majority_of(2), 2);
+        assert_eq!(majority_of(3), 2);
+        assert_eq!(majority_of(4), 3);
+        assert_eq!(majority_of(5), 3);
+    }
+
+    fn found(version: u64) -> BoxFuture<Option<ObjectVersion>> {
+        Box::new(futures::finished((None, Some(ObjectVersion(version)))))
+    }
+
+    fn not_found() -> BoxFuture<Option<ObjectVersion>> {
+        Box::new(futures::finished((None, None)))
+    }
+
+    fn node(n: u8) -> NodeId {
+        NodeId {
+            local_id: LocalNodeId::new([0, 0, 0, 0, 0, 0, n]),
+            instance: 0,
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+        }
+    }
+
+    fn mds_client(size: u8) -> MdsClient {
+        let members = (0..size)
+            .map(|n| ClusterMember {
+                node: node(n),
+                device: n.to_string(),
+            })
+            .collect();
+        let rpc_service = RpcServiceBuilder::new().finish(fibers_global::handle());
+        MdsClient::new(
+            Logger::root(Discard, o!()),
+            rpc_service.handle(),
+            ClusterConfig {
+                members,
+                mds_witnesses: 0,
+            },
+            MdsClientConfig::default(),
+            &MetricLabels::default(),
+        )
+    }
+
+    /// 予め与えられた応答を順に返す`RequestOnce`。
+    struct ScriptedRequest(VecDeque<BoxFuture<u8>>);
+    impl RequestOnce for ScriptedRequest {
+        type Item = u8;
+        fn kind(&self) -> RequestKind {
+            RequestKind::Other
+        }
+        fn request_once(
+            &mut self,
+            client: &MdsClient,
+            _parent: &SpanHandle,
+        ) -> Result<(Vec<NodeId>, BoxFuture<Self::Item>)> {
+            let future = self.0.pop_front().expect("no more responses");
+            let peer = client.known_leader().unwrap_or_else(|| node(0));
+            Ok((vec![peer], future))
+        }
+    }
+
+    #[test]
+    fn follower_or_leader_prefers_followers() -> TestResult {
+        let client = mds_client(3);
+
+        // リーダが不明な場合には、全てのメンバが候補となる
+        let peers = track!((0..3)
+            .map(|i| client.follower_or_leader(0, i))
+            .collect::<Result<Vec<_>>>())?;
+        assert_eq!(peers, vec![node(0), node(1), node(2)]);
+
+        // リーダが判明している場合には、フォロワーを全て試した後にリーダが選ばれる
+        client.set_leader(node(1).local_id);
+        let peers = track!((0..4)
+            .map(|i| client.follower_or_leader(1, i))
+            .collect::<Result<Vec<_>>>())?;
+        assert_eq!(peers, vec![node(2), node(0), node(1), node(1)]);
+
+        // メンバがいない場合には、パニックせずにエラーとなる
+        let client = mds_client(0);
+        assert!(client.follower_or_leader(0, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn witnesses_are_not_chosen_for_reads() -> TestResult {
+        let client = mds_client(3);
+        client.inner.lock().unwrap().config.mds_witnesses = 1;
+
+        let peers = track!((0..3)
+            .map(|i| client.follower_or_leader(0, i))
+            .collect::<Result<Vec<_>>>())?;
+        assert_eq!(peers, vec![node(0), node(1), node(0)]);
+        assert_ne!(client.leader(), node(2));
+
+        client.set_leader(node(0).local_id);
+        let peers = track!((0..3)
+            .map(|i| client.follower_or_leader(0, i))
+            .collect::<Result<Vec<_>>>())?;
+        assert_eq!(peers, vec![node(1), node(0), node(0)]);
+
+        let peers = track!(client.next_peers(2, 2))?;
+        assert_eq!(peers, [node(0), node(1)].iter().cloned().collect());
+        Ok(())
+    }
+
+    #[test]
+    fn leader_is_cached_and_invalidated_by_not_leader() -> TestResult {
+        let client = mds_client(3);
+
+        // リダイレクト先のリーダがキャッシュされる
+        let redirected = (node(2).addr, node(2).local_id.to_string());
+        let responses: Vec<BoxFuture<u8>> =
+            vec![Box::new(futures::finished((Some(redirected), 1)))];
+        let request = ScriptedRequest(responses.into_iter().collect());
+        let future = Request::new(client.clone(), Span::inactive().handle(), request);
+        assert_eq!(track!(future.wait())?, 1);
+        assert_eq!(client.known_leader(), Some(node(2)));
+        assert_eq!(client.leader_metrics.redirects_total.value(), 1.0);
+
+        // `NotLeader`を返したリーダはキャッシュから外され、別のノードで再試行される
+        let responses: Vec<BoxFuture<u8>> = vec![
+            Box::new(futures::failed(MdsErrorKind::NotLeader.error().into())),
+            Box::new(futures::finished((None, 2))),
+        ];
+        let request = ScriptedRequest(responses.into_iter().collect());
+        let future = Request::new(client.clone(), Span::inactive().handle(), request);
+        assert_eq!(track!(future.wait())?, 2);
+        assert_eq!(client.leader_metrics.invalidations_total.value(), 1.0);
+        assert_eq!(client.leader_metrics.redirects_total.value(), 1.0);
+        Ok(())
+    }
+
+    #[test]
+    fn get_latest_object_counts_disagreements() -> TestResult {
+        let metrics = QuorumReadMetrics::new(&MetricLabels::bucket(
+            "get_latest_object_counts_disagreements",
+        ));
+
+        let future = GetLatestObject::new(vec![found(3), found(3)], Some(metrics.clone()));
+        assert_eq!(track!(future.wait())?.1, Some(ObjectVersion(3)));
+        assert_eq!(metrics.reads_total.value(), 1.0);
+        assert_eq!(metrics.disagreements_total.value(), 0.0);
+
+        let future = GetLatestObject::new(vec![found(3), found(5)], Some(metrics.clone()));
+        assert_eq!(track!(future.wait())?.1, Some(ObjectVersion(5)));
+        assert_eq!(metrics.disagreements_total.value(), 1.0);
+
+        let futures = vec![not_found(), found(5), not_found()];
+        let future = GetLatestObject::new(futures, Some(metrics.clone()));
+        assert_eq!(track!(future.wait())?.1, None);
+        assert_eq!(metrics.reads_total.value(), 3.0);
+        assert_eq!(metrics.disagreements_total.value(), 2.0);
+
+        let failed: BoxFuture<Option<ObjectVersion>> =
+            Box::new(futures::failed(MdsErrorKind::Other.error().into()));
+        let future = GetLatestObject::new(vec![failed, found(3)], Some(metrics.clone()));
+        assert!(future.wait().is_err());
+        assert_eq!(metrics.peer_failures_total.value(), 1.0);
+        assert_eq!(metrics.reads_total.value(), 3.0);
+        Ok(())
+    }
 }
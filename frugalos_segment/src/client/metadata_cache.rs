@@ -0,0 +1,327 @@
+//! MDS から取得したオブジェクトのメタデータを保持するキャッシュ。
+//!
+//! 頻繁に参照されるオブジェクトについて、MDS への問い合わせを減らすことが目的。
+//! 内容のキャッシュ(`ContentCache`)とは異なり、キーはオブジェクトの ID なので、
+//! オブジェクトが更新・削除された場合には無効化が必要となる。
+//!
+//! 無効化は以下の契機で行われる:
+//!
+//! 1. この`MdsClient`経由での更新・削除の完了時
+//! 2. この`MdsClient`経由で監視している変更履歴(`Watch`)に、オブジェクトの変更が含まれていた場合
+//! 3. エントリを追加してから TTL が経過した場合
+//!
+//! 他のクライアント経由の更新は 2 ないし 3 まで反映されないので、このキャッシュは
+//! 古い値が返ることを許容する`ReadConsistency::Stale`と`ReadConsistency::Subset`の参照にのみ使われる。
+//!
+//! キャッシュの大きさは、エントリ数とおおよその合計バイト数の両方で制限される。
+use frugalos_core::memory::MemoryTracker;
+use libfrugalos::entity::object::{ObjectId, ObjectVersion};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ObjectValue;
+
+// エントリ毎の、ID とメタデータ以外の部分(バージョンや索引等)のおおよそのバイト数
+const ENTRY_OVERHEAD_BYTES: u64 = 96;
+
+/// オブジェクトの ID から、最新のバージョンとメタデータへのキャッシュ。
+///
+/// `Clone`されたインスタンス間では内容が共有される。
+#[derive(Debug, Clone)]
+pub(crate) struct MetadataCache {
+    capacity: usize,
+    capacity_bytes: u64,
+    ttl: Duration,
+    inner: Arc<Mutex<CacheInner>>,
+}
+impl MetadataCache {
+    /// エントリ数が`capacity`以下、合計バイト数が`capacity_bytes`以下に制限されたキャッシュを生成する。
+    pub fn new(capacity: usize, capacity_bytes: u64, ttl: Duration) -> Self {
+        MetadataCache {
+            capacity,
+            capacity_bytes,
+            ttl,
+            inner: Arc::new(Mutex::new(CacheInner::new())),
+        }
+    }
+
+    /// `id`のメタデータがキャッシュにあれば、それを返す。
+    pub fn get(&self, id: &ObjectId) -> Option<ObjectValue> {
+        let now = Instant::now();
+        self.lock().lookup(id, now).and_then(|(version, metadata)| {
+            metadata.map(|content| ObjectValue { version, content })
+        })
+    }
+
+    /// `id`のバージョンがキャッシュにあれば、それを返す。
+    pub fn version(&self, id: &ObjectId) -> Option<ObjectVersion> {
+        let now = Instant::now();
+        self.lock().lookup(id, now).map(|(version, _)| version)
+    }
+
+    /// MDS への問い合わせを開始する前に呼び出し、結果を`insert`する際に渡す値を取得する。
+    ///
+    /// 問い合わせ中に無効化が行われた場合には、古い結果がキャッシュに追加されないようにするために使われる。
+    pub fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    /// MDS から取得した`id`のバージョンとメタデータを追加する。
+    ///
+    /// メタデータが`None`の場合(HEAD の結果)には、バージョンの参照にのみ使われる。
+    /// 一つで`capacity_bytes`を超えるエントリは追加されない。
+    pub fn insert(
+        &self,
+        generation: u64,
+        id: ObjectId,
+        version: ObjectVersion,
+        metadata: Option<Vec<u8>>,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        let expires_at = Instant::now() + self.ttl;
+        self.lock().insert(
+            generation,
+            id,
+            version,
+            metadata,
+            expires_at,
+            self.capacity,
+            self.capacity_bytes,
+        );
+    }
+
+    /// `id`のエントリを無効化する。
+    pub fn invalidate(&self, id: &ObjectId) {
+        self.lock().invalidate(Some(id));
+    }
+
+    /// 全てのエントリを無効化する。
+    pub fn clear(&self) {
+        self.lock().invalidate(None);
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<CacheInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    version: ObjectVersion,
+    metadata: Option<Vec<u8>>,
+    expires_at: Instant,
+    last_access: u64,
+}
+
+#[derive(Debug)]
+struct CacheInner {
+    entries: HashMap<ObjectId, CacheEntry>,
+
+    // 最終アクセス時刻(論理時刻)から、オブジェクトの ID へのマップ(先頭が追い出し対象)
+    lru: BTreeMap<u64, ObjectId>,
+    clock: u64,
+
+    // 無効化の度に増加する
+    generation: u64,
+
+    // 保持しているエントリのおおよその合計バイト数
+    bytes: u64,
+    memory: MemoryTracker,
+}
+impl CacheInner {
+    fn new() -> Self {
+        CacheInner {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            generation: 0,
+            bytes: 0,
+            memory: MemoryTracker::new("metadata_cache"),
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn lookup(&mut self, id: &ObjectId, now: Instant) -> Option<(ObjectVersion, Option<Vec<u8>>)> {
+        let expired = self.entries.get(id)?.expires_at <= now;
+        if expired {
+            self.remove(id);
+            return None;
+        }
+        let now = self.tick();
+        let entry = self.entries.get_mut(id).expect("Never fails");
+        self.lru.remove(&entry.last_access);
+        self.lru.insert(now, id.clone());
+        entry.last_access = now;
+        Some((entry.version, entry.metadata.clone()))
+    }
+
+    fn insert(
+        &mut self,
+        generation: u64,
+        id: ObjectId,
+        version: ObjectVersion,
+        metadata: Option<Vec<u8>>,
+        expires_at: Instant,
+        capacity: usize,
+        capacity_bytes: u64,
+    ) {
+        if generation != self.generation {
+            return;
+        }
+        let metadata = match self.entries.get(&id) {
+            // 並行する参照の結果が、新しいエントリを上書きしないようにする
+            Some(entry) if entry.version > version => return,
+            Some(entry) if entry.version == version && metadata.is_none() => entry.metadata.clone(),
+            _ => metadata,
+        };
+        self.remove(&id);
+        let bytes = entry_bytes(&id, &metadata);
+        if bytes > capacity_bytes {
+            return;
+        }
+        while self.entries.len() >= capacity || self.bytes + bytes > capacity_bytes {
+            let victim = self.lru.values().next().expect("Never fails").clone();
+            self.remove(&victim);
+        }
+        let now = self.tick();
+        self.lru.insert(now, id.clone());
+        self.bytes += bytes;
+        self.memory.set(self.bytes);
+        self.entries.insert(
+            id,
+            CacheEntry {
+                version,
+                metadata,
+                expires_at,
+                last_access: now,
+            },
+        );
+    }
+
+    fn invalidate(&mut self, id: Option<&ObjectId>) {
+        self.generation += 1;
+        if let Some(id) = id {
+            self.remove(id);
+        } else {
+            self.entries.clear();
+            self.lru.clear();
+            self.bytes = 0;
+            self.memory.set(0);
+        }
+    }
+
+    fn remove(&mut self, id: &ObjectId) {
+        if let Some(entry) = self.entries.remove(id) {
+            self.lru.remove(&entry.last_access);
+            self.bytes -= entry_bytes(id, &entry.metadata);
+            self.memory.set(self.bytes);
+        }
+    }
+}
+
+fn entry_bytes(id: &ObjectId, metadata: &Option<Vec<u8>>) -> u64 {
+    let metadata_bytes = metadata.as_ref().map_or(0, Vec::len);
+    (id.len() + metadata_bytes) as u64 + ENTRY_OVERHEAD_BYTES
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNLIMITED_BYTES: u64 = u64::max_value();
+
+    fn id(s: &str) -> ObjectId {
+        s.to_owned()
+    }
+
+    #[test]
+    fn metadata_cache_works() {
+        let cache = MetadataCache::new(2, UNLIMITED_BYTES, Duration::from_secs(60));
+        assert!(cache.get(&id("foo")).is_none());
+
+        let generation = cache.generation();
+        cache.insert(generation, id("foo"), ObjectVersion(1), Some(vec![1]));
+        cache.insert(generation, id("bar"), ObjectVersion(2), None);
+        let value = cache.get(&id("foo")).map(|v| (v.version, v.content));
+        assert_eq!(value, Some((ObjectVersion(1), vec![1])));
+
+        // HEAD の結果は、バージョンの参照にのみ使われる
+        assert!(cache.get(&id("bar")).is_none());
+        assert_eq!(cache.version(&id("bar")), Some(ObjectVersion(2)));
+
+        // 古いバージョンでは上書きされない
+        cache.insert(generation, id("foo"), ObjectVersion(0), Some(vec![0]));
+        assert_eq!(cache.version(&id("foo")), Some(ObjectVersion(1)));
+
+        // 容量を超えた場合には、最も長く使われていないものが追い出される
+        cache.insert(generation, id("baz"), ObjectVersion(3), None);
+        assert_eq!(cache.lock().entries.len(), 2);
+        assert_eq!(cache.version(&id("bar")), None);
+        assert_eq!(cache.version(&id("foo")), Some(ObjectVersion(1)));
+    }
+
+    #[test]
+    fn invalidation_works() {
+        let cache = MetadataCache::new(10, UNLIMITED_BYTES, Duration::from_secs(60));
+        let generation = cache.generation();
+        cache.insert(generation, id("foo"), ObjectVersion(1), Some(vec![1]));
+        cache.invalidate(&id("foo"));
+        assert_eq!(cache.version(&id("foo")), None);
+
+        // 無効化の前に開始された問い合わせの結果は追加されない
+        cache.insert(generation, id("foo"), ObjectVersion(1), Some(vec![1]));
+        assert_eq!(cache.version(&id("foo")), None);
+
+        let generation = cache.generation();
+        cache.insert(generation, id("foo"), ObjectVersion(2), Some(vec![2]));
+        cache.insert(generation, id("bar"), ObjectVersion(3), Some(vec![3]));
+        assert_eq!(cache.lock().bytes, 2 * (3 + 1 + ENTRY_OVERHEAD_BYTES));
+        cache.clear();
+        assert_eq!(cache.lock().entries.len(), 0);
+        assert_eq!(cache.lock().bytes, 0);
+    }
+
+    #[test]
+    fn capacity_bytes_works() {
+        let entry_bytes = 3 + 10 + ENTRY_OVERHEAD_BYTES;
+        let cache = MetadataCache::new(10, entry_bytes * 2, Duration::from_secs(60));
+        let generation = cache.generation();
+        cache.insert(generation, id("foo"), ObjectVersion(1), Some(vec![0; 10]));
+        cache.insert(generation, id("bar"), ObjectVersion(2), Some(vec![0; 10]));
+        assert_eq!(cache.lock().bytes, entry_bytes * 2);
+
+        // バイト数の上限を超える場合には、エントリ数に余裕があっても追い出される
+        cache.insert(generation, id("baz"), ObjectVersion(3), Some(vec![0; 10]));
+        assert_eq!(cache.lock().entries.len(), 2);
+        assert_eq!(cache.version(&id("foo")), None);
+
+        // 一つで上限を超えるエントリは追加されない
+        cache.insert(generation, id("qux"), ObjectVersion(4), Some(vec![0; 1000]));
+        assert_eq!(cache.version(&id("qux")), None);
+        assert_eq!(cache.lock().entries.len(), 2);
+    }
+
+    #[test]
+    fn expiration_works() {
+        let cache = MetadataCache::new(10, UNLIMITED_BYTES, Duration::from_secs(0));
+        let generation = cache.generation();
+        cache.insert(generation, id("foo"), ObjectVersion(1), Some(vec![1]));
+        assert!(cache.get(&id("foo")).is_none());
+        assert_eq!(cache.lock().entries.len(), 0);
+    }
+
+    #[test]
+    fn disabled_cache_works() {
+        let cache = MetadataCache::new(0, UNLIMITED_BYTES, Duration::from_secs(60));
+        let generation = cache.generation();
+        cache.insert(generation, id("foo"), ObjectVersion(1), Some(vec![1]));
+        assert_eq!(cache.lock().entries.len(), 0);
+    }
+}
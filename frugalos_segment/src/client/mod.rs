@@ -1,7 +1,14 @@
+use bytes::Bytes;
 use cannyls::deadline::Deadline;
-use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
+use fibers_rpc::client::{ClientServiceHandle as RpcServiceHandle, Options as RpcOptions};
+use frugalos_core::logging;
+use frugalos_core::metrics::MetricLabels;
+use frugalos_core::rpc_auth;
+use frugalos_mds::machine::{ObjectEncryption, ObjectParts};
+use frugalos_mds::{AccessMode, DeleteJobStatus, Precondition, QuotaUsage, RevisionSelector};
+use frugalos_raft::NodeId;
 use futures::future::Either;
-use futures::{self, Future};
+use futures::{self, Future, Stream};
 use libfrugalos::consistency::ReadConsistency;
 use libfrugalos::entity::object::{
     DeleteObjectsByPrefixSummary, ObjectId, ObjectPrefix, ObjectSummary, ObjectVersion,
@@ -11,24 +18,66 @@ use rustracing_jaeger::span::SpanHandle;
 use slog::Logger;
 use std::mem;
 use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
 
-use self::ec::ErasureCoder;
+use self::budget::RequestBudgets;
+use self::cache::{CacheClass, ContentCache, ContentCacheStats};
+use self::cancel::OperationDeadline;
+use self::health::MemberHealth;
 use self::mds::MdsClient;
-use self::storage::StorageClient;
-use config::ClientConfig;
-use {Error, ObjectValue, Result};
+use self::retry::RetryPolicy;
+use self::storage::{slice_content, PutDurability, StorageClient};
+use self::watch::Watch;
+use audit::{AvailabilitySummary, ObjectAuditReport};
+use config::{
+    ClientConfig, ClusterConfig, ClusterMember, CompactionConfig, RequestPriority,
+    RequestPriorityConfig, Storage,
+};
+use encryption::{ContentEncryption, ObjectKey};
+use maintenance::MaintenanceSchedule;
+use metrics::{observe_latency, ClientLatencyMetrics, ContentCacheMetrics, RequestBudgetMetrics};
+use repair::{NodeRepairResult, ObjectRepairSummary};
+use schema::{GetSegmentNodeStatusRpc, RepairObjectRequest, RepairObjectRpc};
+use status::{MemberStatus, SegmentNodeStatus};
+use util::BoxFuture;
+use {Error, ErrorKind, ObjectRange, ObjectValue, Result};
 
+pub(crate) mod budget;
+pub mod cache; // to re-export in frugalos_segment/src/lib.rs
+mod cancel;
+pub mod circuit_breaker; // to re-export in frugalos_segment/src/lib.rs
 mod dispersed_storage;
 pub mod ec; // to re-export in frugalos_segment/src/lib.rs
+pub mod ec_pool; // to re-export in frugalos_segment/src/lib.rs
+pub mod health; // to re-export in frugalos_segment/src/lib.rs
 mod mds;
+mod metadata_cache;
 mod replicated_storage;
+mod retry;
 pub mod storage; // TODO: private
+pub mod watch; // to re-export in frugalos_segment/src/lib.rs
 
 /// セグメントにアクセスるために使用するクライアント。
 #[derive(Clone)]
 pub struct Client {
-    logger: Logger,
+    pub(crate) logger: Logger,
+    pub(crate) metric_labels: MetricLabels,
     mds: MdsClient,
+    rpc_service: RpcServiceHandle,
+    pub(crate) cluster: Arc<ClusterConfig>,
+    // 再配置中の場合の、変更前のメンバ群
+    previous_cluster: Option<Arc<ClusterConfig>>,
+    request_priority: RequestPriorityConfig,
+    encryption: Option<ContentEncryption>,
+    retry: RetryPolicy,
+    budgets: RequestBudgets,
+    latency: ClientLatencyMetrics,
+    cache: ContentCache,
+    pub(crate) compaction: CompactionConfig,
+    pub(crate) maintenance: MaintenanceSchedule,
+    pub(crate) access: AccessMode,
     pub(crate) storage: StorageClient, // TODO: private
 }
 impl Client {
@@ -37,23 +86,180 @@ impl Client {
         logger: Logger,
         rpc_service: RpcServiceHandle,
         config: ClientConfig,
-        ec: Option<ErasureCoder>,
     ) -> Result<Self> {
+        let metric_labels = MetricLabels::bucket(&config.bucket_id);
+        // 再配置中は、MDS のメンバの切り替えが完了するまで変更前のメンバ群が MDS を担当する
+        let mds_cluster = config
+            .previous_layout
+            .as_ref()
+            .map_or_else(|| config.cluster.clone(), |p| p.cluster.clone());
         let mds = MdsClient::new(
             logger.clone(),
             rpc_service.clone(),
-            config.cluster.clone(),
+            mds_cluster,
             config.mds.clone(),
+            &metric_labels,
+        );
+        let cluster = Arc::new(config.cluster.clone());
+        let previous_cluster = config
+            .previous_layout
+            .as_ref()
+            .map(|p| Arc::new(p.cluster.clone()));
+        let request_priority = config.request_priority.clone();
+        let encryption = config.encryption.clone();
+        let compaction = config.compaction.clone();
+        let maintenance = config.maintenance.clone();
+        let access = config.access.clone();
+        let retry = RetryPolicy::new(config.retry.clone());
+        let budgets = RequestBudgets::new(
+            config.budget.clone(),
+            track!(RequestBudgetMetrics::new(&metric_labels))?,
+        );
+        let storage_label = match config.storage {
+            Storage::Metadata => "metadata",
+            Storage::Replicated(_) => "replicated",
+            Storage::Dispersed(_) => "dispersed",
+        };
+        let latency = track!(ClientLatencyMetrics::new(&metric_labels, storage_label))?;
+        let cache = ContentCache::new(
+            CacheClass::from_storage(&config.storage),
+            config.cache_sizing.clone(),
+            &config.content_cache,
+            track!(ContentCacheMetrics::new(&metric_labels))?,
         );
-        let storage = track!(StorageClient::new(logger.clone(), config, rpc_service, ec))?;
+        let storage = track!(StorageClient::new(
+            logger.clone(),
+            config,
+            rpc_service.clone()
+        ))?;
         Ok(Client {
             logger,
+            metric_labels,
             mds,
+            rpc_service,
+            cluster,
+            previous_cluster,
+            request_priority,
+            encryption,
+            retry,
+            budgets,
+            latency,
+            cache,
+            compaction,
+            maintenance,
+            access,
             storage,
         })
     }
 
+    /// 優先度クラスを考慮した上で、このセグメントへのリクエストに使うデッドラインを返す。
+    pub fn deadline(&self, priority: RequestPriority, requested: Deadline) -> Deadline {
+        self.request_priority.deadline(priority, requested)
+    }
+
+    /// MDS からオブジェクトのメタデータを取得する(失敗した場合は設定に従って再試行する)。
+    fn get_metadata(
+        &self,
+        id: ObjectId,
+        deadline: Deadline,
+        consistency: ReadConsistency,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectValue>, Error = Error> {
+        let mds = self.mds.clone();
+        self.retry.retry(&self.logger, deadline, move |_| {
+            mds.get(id.clone(), consistency.clone(), parent.clone())
+        })
+    }
+
+    /// MDS からオブジェクトのバージョンを取得する(失敗した場合は設定に従って再試行する)。
+    fn head_metadata(
+        &self,
+        id: ObjectId,
+        deadline: Deadline,
+        consistency: ReadConsistency,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
+        let mds = self.mds.clone();
+        self.retry.retry(&self.logger, deadline, move |_| {
+            mds.head(id.clone(), consistency.clone(), parent.clone())
+        })
+    }
+
+    /// ストレージからオブジェクトの内容を取得する(失敗した場合は設定に従って再試行する)。
+    ///
+    /// キャッシュに内容があれば、ストレージにはアクセスしない。
+    fn get_content(
+        &self,
+        object: ObjectValue,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Vec<u8>, Error = Error> {
+        let version = object.version;
+        if let Some(content) = self.cache.get(version) {
+            return Either::A(futures::future::ok(content));
+        }
+        let storage = self.storage.clone();
+        // NOTE: 再試行を含めた要求全体で消費量を数えるために、再試行の外で生成する
+        let budget = self.budgets.start();
+        let cache = self.cache.clone();
+        let deadline = OperationDeadline::new(deadline);
+        let future = self
+            .retry
+            .retry(&self.logger, deadline.remaining(), move |_| {
+                storage.clone().get(
+                    object.clone(),
+                    deadline.remaining(),
+                    parent.clone(),
+                    budget.clone(),
+                )
+            })
+            .inspect(move |content| cache.insert(version, content));
+        Either::B(observe_latency(&self.latency.get_storage, future))
+    }
+
+    /// このセグメントのキャッシュの統計情報を返す。
+    pub fn content_cache_stats(&self) -> ContentCacheStats {
+        self.cache.stats()
+    }
+
+    /// このセグメントのキャッシュの内容を全て破棄する。結果は破棄したオブジェクトの数。
+    pub fn flush_content_cache(&self) -> u64 {
+        self.cache.clear()
+    }
+
+    /// 暗号化されたオブジェクトであれば、その復号に使う鍵を返す。
+    ///
+    /// 鍵が取得できない場合や、暗号化に使われた鍵と異なる場合には、
+    /// 内容を読み込む前にエラーとする。
+    fn opening_key(&self, object: &ObjectValue) -> Result<Option<ObjectKey>> {
+        if self.storage.is_metadata() {
+            return Ok(None);
+        }
+        let encryption = match ObjectEncryption::decode(&object.content) {
+            None => return Ok(None),
+            Some(encryption) => encryption,
+        };
+        let result = match self.encryption {
+            None => Err(track!(Error::from(ErrorKind::Invalid.cause(format!(
+                "No key provider is configured: key_id={:?}",
+                encryption.key_id
+            ))))),
+            Some(ref e) => track!(e.opening_key(encryption)),
+        };
+        if let Err(ref e) = result {
+            crit!(
+                self.logger,
+                "Cannot decrypt the object: version={:?}, reason={}",
+                object.version,
+                e
+            );
+        }
+        result.map(Some)
+    }
+
     /// オブジェクトを取得する。
+    ///
+    /// 取得に消費できる資源は`RequestBudgetConfig`によって制限される。
     pub fn get(
         &self,
         id: ObjectId,
@@ -61,21 +267,129 @@ impl Client {
         consistency: ReadConsistency,
         parent: SpanHandle,
     ) -> impl Future<Item = Option<ObjectValue>, Error = Error> {
-        let storage = self.storage.clone();
-        self.mds
-            .get(id, consistency, parent.clone())
+        let this = self.clone();
+        let deadline = OperationDeadline::new(deadline);
+        let metadata = self.get_metadata(id, deadline.remaining(), consistency, parent.clone());
+        let future = observe_latency(&self.latency.get_mds, metadata)
+            .and_then(move |object| this.get_value(object, deadline.remaining(), parent));
+        observe_latency(&self.latency.get_total, self.budgets.limit_duration(future))
+    }
+
+    /// バージョン管理されているバケツで、`selector`で指定された過去のバージョンのオブジェクトを取得する。
+    ///
+    /// 最新のバージョンが指定された場合には、`get`と同様の結果となる。
+    /// `expect`は、`selector`で選択されたバージョンに対して検査される。
+    pub fn get_revision(
+        &self,
+        id: ObjectId,
+        selector: RevisionSelector,
+        expect: Expect,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectValue>, Error = Error> {
+        let this = self.clone();
+        let mds = self.mds.clone();
+        let parent0 = parent.clone();
+        let deadline = OperationDeadline::new(deadline);
+        let future = self
+            .retry
+            .retry(&self.logger, deadline.remaining(), move |_| {
+                mds.get_revision(id.clone(), selector, expect.clone(), parent0.clone())
+            })
+            .and_then(move |object| this.get_value(object, deadline.remaining(), parent));
+        self.budgets.limit_duration(future)
+    }
+
+    /// MDS から取得したメタデータを元に、オブジェクトの内容を読み込む。
+    fn get_value(
+        &self,
+        object: Option<ObjectValue>,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectValue>, Error = Error> {
+        if let Some(object) = object {
+            let version = object.version;
+            let key = match self.opening_key(&object) {
+                Err(e) => return Either::B(futures::future::err(track!(e))),
+                Ok(key) => key,
+            };
+            let logger = self.logger.clone();
+            let future = self
+                .get_content(object, deadline, parent)
+                .and_then(move |content| open_content(&logger, key, version, content))
+                .map(move |content| ObjectValue { version, content })
+                .map(Some);
+            Either::A(future)
+        } else {
+            Either::B(futures::future::ok(None))
+        }
+    }
+
+    /// オブジェクトの内、`range`で指定されたバイト範囲のみを取得する。
+    ///
+    /// 範囲がオブジェクトの末尾を超えている場合には、末尾までのデータが返される。
+    /// 結果にはオブジェクト全体のバイト数も含まれる。
+    /// 分散(erasure coding)されたオブジェクトの場合には、範囲を含む断片のみが読み込まれる。
+    pub fn get_range(
+        &self,
+        id: ObjectId,
+        range: Range<u64>,
+        deadline: Deadline,
+        consistency: ReadConsistency,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectRange>, Error = Error> {
+        let this = self.clone();
+        let deadline = OperationDeadline::new(deadline);
+        let future = self
+            .get_metadata(id, deadline.remaining(), consistency, parent.clone())
             .and_then(move |object| {
                 if let Some(object) = object {
                     let version = object.version;
-                    let future = storage
-                        .get(object, deadline, parent)
-                        .map(move |content| ObjectValue { version, content })
+                    let key = match this.opening_key(&object) {
+                        Err(e) => return Either::B(futures::future::err(track!(e))),
+                        Ok(key) => key,
+                    };
+                    let future = if key.is_some() {
+                        // 暗号文の一部だけでは復号できないので、全体を読み込む
+                        let logger = this.logger.clone();
+                        let future = this
+                            .get_content(object, deadline.remaining(), parent)
+                            .and_then(move |content| open_content(&logger, key, version, content))
+                            .map(move |content| slice_content(content, &range));
+                        Either::A(future)
+                    } else if let Some(content) = this.cache.get(version) {
+                        let content = slice_content(content, &range);
+                        Either::B(Either::A(futures::future::ok(content)))
+                    } else {
+                        let storage = this.storage.clone();
+                        // NOTE: `get_content`と同様に、再試行の間で消費量を共有する
+                        let budget = this.budgets.start();
+                        let future =
+                            this.retry
+                                .retry(&this.logger, deadline.remaining(), move |_| {
+                                    storage.clone().get_range(
+                                        object.clone(),
+                                        range.clone(),
+                                        deadline.remaining(),
+                                        parent.clone(),
+                                        budget.clone(),
+                                    )
+                                });
+                        Either::B(Either::B(future))
+                    };
+                    let future = future
+                        .map(move |r| ObjectRange {
+                            version,
+                            content: r.content,
+                            object_size: r.object_size,
+                        })
                         .map(Some);
                     Either::A(future)
                 } else {
                     Either::B(futures::future::ok(None))
                 }
-            })
+            });
+        self.budgets.limit_duration(future)
     }
 
     /// オブジェクトの存在確認を行う。
@@ -85,7 +399,7 @@ impl Client {
         consistency: ReadConsistency,
         parent: SpanHandle,
     ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
-        self.mds.head(id, consistency, parent)
+        self.head_metadata(id, Deadline::Infinity, consistency, parent)
     }
 
     /// オブジェクトの存在確認をストレージ側に問い合わせる。
@@ -97,12 +411,18 @@ impl Client {
         parent: SpanHandle,
     ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
         let storage = self.storage.clone();
-        self.mds
-            .head(id, consistency, parent.clone())
+        let retry = self.retry.clone();
+        let logger = self.logger.clone();
+        let deadline = OperationDeadline::new(deadline);
+        self.head_metadata(id, deadline.remaining(), consistency, parent.clone())
             .and_then(move |version| {
                 if let Some(version) = version {
-                    let future = storage
-                        .head(version, deadline, parent)
+                    let future = retry
+                        .retry(&logger, deadline.remaining(), move |_| {
+                            storage
+                                .clone()
+                                .head(version, deadline.remaining(), parent.clone())
+                        })
                         .map(move |()| Some(version));
                     Either::A(future)
                 } else {
@@ -111,72 +431,446 @@ impl Client {
             })
     }
 
+    /// オブジェクトの中身を、セグメント内の全ノードで即座に検証し、必要ならリペアする。
+    ///
+    /// 通常のリペアとは異なり、ノードの idleness の閾値に関わらず実行される。
+    /// オブジェクトが存在しない場合には`None`が返される。
+    pub fn repair_object(
+        &self,
+        id: ObjectId,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectRepairSummary>, Error = Error> {
+        let rpc_service = self.rpc_service.clone();
+        let cluster = self.cluster.clone();
+        self.mds
+            .head(id, ReadConsistency::Consistent, parent)
+            .and_then(move |version| {
+                if let Some(version) = version {
+                    let futures = cluster
+                        .members
+                        .iter()
+                        .map(|m| {
+                            let node = m.node.to_string();
+                            let request = RepairObjectRequest {
+                                node: m.node.local_id.to_string(),
+                                version,
+                            };
+                            rpc_auth::call::<RepairObjectRpc>(
+                                &rpc_service,
+                                m.node.current_addr(),
+                                request,
+                            )
+                            .then(move |result| {
+                                let result = match result {
+                                    Ok(Ok(outcome)) => Ok(outcome),
+                                    Ok(Err(e)) => Err(e.to_string()),
+                                    Err(e) => Err(e.to_string()),
+                                };
+                                Ok(NodeRepairResult::new(node, result))
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    let future = futures::future::join_all(futures)
+                        .map(move |nodes| Some(ObjectRepairSummary { version, nodes }));
+                    Either::A(future)
+                } else {
+                    Either::B(futures::future::ok(None))
+                }
+            })
+    }
+
+    /// オブジェクトの断片が、それを保持すべき全てのメンバに存在するかどうかを検査する。
+    ///
+    /// 書き込みは一切行わない。欠損や古い断片が見つかった場合でも、リペアは行われない。
+    /// オブジェクトが存在しない場合には`None`が返される。
+    pub fn verify(
+        &self,
+        id: ObjectId,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectAuditReport>, Error = Error> {
+        let storage = self.storage.clone();
+        self.mds
+            .head(id, ReadConsistency::Consistent, parent)
+            .and_then(move |version| {
+                if let Some(version) = version {
+                    Either::A(storage.audit(version, deadline).map(Some))
+                } else {
+                    Either::B(futures::future::ok(None))
+                }
+            })
+    }
+
+    /// セグメント内の全オブジェクトについて断片の有無を調べ、その可用性を集計する。
+    ///
+    /// MDS から取得したオブジェクト一覧の各バージョンを、最大で`concurrency`個ずつ並行して監査する。
+    /// `verify`と同様に、書き込みやリペアは一切行わない。
+    ///
+    /// 複製として格納された小さなオブジェクトは、複製が一つでも残っていれば復元可能なものとして扱う。
+    pub fn survey_availability(
+        &self,
+        deadline: Deadline,
+        concurrency: usize,
+        max_lost_objects: usize,
+    ) -> impl Future<Item = AvailabilitySummary, Error = Error> {
+        let storage = self.storage.clone();
+        self.mds.list().and_then(move |objects| {
+            futures::stream::iter_ok(objects)
+                .map(move |object| {
+                    storage
+                        .clone()
+                        .survey(object.version, deadline)
+                        .map(move |availability| (object.id, availability))
+                })
+                .buffer_unordered(concurrency.max(1))
+                .fold(
+                    AvailabilitySummary::default(),
+                    move |mut summary, (id, availability)| {
+                        summary.add(id, availability, max_lost_objects);
+                        Ok(summary) as Result<_>
+                    },
+                )
+        })
+    }
+
     /// オブジェクトを保存する。
+    ///
+    /// `expect`で指定された前提条件は、MDS 上でオブジェクトを更新する際にアトミックに評価される。
+    /// `deadline`が`Deadline::Within`の場合には、MDS 上での更新も同じ期限内に完了しなければ失敗する。
+    /// 一方で、MDS 上での更新後に行われる内容の書き込みは、期限を過ぎても取り消されない
+    /// (途中で打ち切ると、メタデータだけが存在する読み込めないオブジェクトが残ってしまうため)。
+    ///
+    /// `content`は符号化や各メンバへの送信の間で共有されるので、再試行の際にも内容全体が複製されることはない。
+    ///
+    /// 結果は、保存したオブジェクトのバージョン、新規に作成されたかどうか、および断片の書き込み状況。
+    /// 一部の断片の書き込みに失敗しても、必要な数の断片が書き込めていれば保存は成功となる。
     pub fn put(
         &self,
         id: ObjectId,
-        mut content: Vec<u8>,
+        mut content: Bytes,
         deadline: Deadline,
-        expect: Expect,
+        expect: Precondition,
         parent: SpanHandle,
-    ) -> impl Future<Item = (ObjectVersion, bool), Error = Error> {
-        // TODO: mdsにdeadlineを渡せるようにする
-        // (repairのトリガー時間の判断用)
+    ) -> impl Future<Item = (ObjectVersion, bool, PutDurability), Error = Error> {
+        if let Err(e) = track!(self.access.check_writable().map_err(Error::from)) {
+            return Either::A(futures::failed(e));
+        }
+        let size = content.len() as u64;
         let storage = self.storage.clone();
+        let key = match self.encryption {
+            Some(ref e) if !self.storage.is_metadata() => e.sealing_key(),
+            _ => Ok(None),
+        };
+        let key = match key {
+            Err(e) => return Either::A(futures::failed(track!(e))),
+            Ok(key) => key,
+        };
         let metadata = if self.storage.is_metadata() {
-            mem::replace(&mut content, Vec::new())
+            Vec::from(mem::replace(&mut content, Bytes::new()))
+        } else if let Some(ref key) = key {
+            key.encryption().encode()
         } else {
             Vec::new()
         };
         let object_id = id.clone();
         let logger = self.logger.clone();
+        let retry = self.retry.clone();
+        let latency = self.latency.clone();
 
         let mds = self.mds.clone();
+        let metadata_deadline = OperationDeadline::new(deadline);
         let expect_future = match expect {
-            Expect::Any => {
-                let f = mds
-                    .head(id.clone(), ReadConsistency::Consistent, parent.clone())
-                    .map(|version| version.map_or(Expect::None, |v| Expect::IfMatch(vec![v])));
+            Precondition::Expect(Expect::Any) => {
+                let f = self
+                    .head_metadata(
+                        id.clone(),
+                        metadata_deadline.remaining(),
+                        ReadConsistency::Consistent,
+                        parent.clone(),
+                    )
+                    .map(|version| version.map_or(Expect::None, |v| Expect::IfMatch(vec![v])))
+                    .map(Precondition::from);
                 Either::A(f)
             }
             _ => Either::B(futures::future::ok(expect)),
         };
 
-        expect_future.and_then(move |expect| {
-            mds.put(id, metadata, expect, deadline, parent.clone())
-                .and_then(move |(version, created)| {
-                    let mut tracking = PutFailureTracking::new(logger, object_id);
+        let total = self.latency.put_total.clone();
+        let future = expect_future.and_then(move |expect| {
+            let parent0 = parent.clone();
+            let put_metadata = retry.retry(&logger, metadata_deadline.remaining(), move |_| {
+                mds.put(
+                    id.clone(),
+                    metadata.clone(),
+                    size,
+                    expect.clone(),
+                    metadata_deadline.remaining(),
+                    parent0.clone(),
+                )
+            });
+            observe_latency(&latency.put_mds, put_metadata).and_then(move |(version, created)| {
+                let mut tracking = PutFailureTracking::new(logger.clone(), object_id);
+                let content = match key {
+                    None => content,
+                    Some(key) => match track!(key.seal(version, Vec::from(content))) {
+                        Ok(sealed) => Bytes::from(sealed),
+                        Err(e) => return Either::A(futures::failed(e)),
+                    },
+                };
+                // NOTE: メタデータは既に登録済みなので、元の期限のまま最後まで書き込みを行う
+                let put_content = retry.retry(&logger, deadline, move |_| {
                     storage
-                        .put(version, content, deadline, parent)
-                        .map(move |()| {
-                            tracking.complete();
-                            (version, created)
-                        })
-                })
-        })
+                        .clone()
+                        .put(version, content.clone(), deadline, parent.clone())
+                });
+                let future =
+                    observe_latency(&latency.put_storage, put_content).map(move |durability| {
+                        tracking.complete();
+                        (version, created, durability)
+                    });
+                Either::B(future)
+            })
+        });
+        Either::B(observe_latency(&total, future))
+    }
+
+    /// 既存のオブジェクトの末尾に`content`を追記する。
+    ///
+    /// 追記された内容は新しいバージョンの部分として保存され、その保存が完了した後に
+    /// MDS 上のオブジェクトの構成がアトミックに更新されるので、オブジェクト全体を書き直す必要はない。
+    /// ただし、部分の数が`ObjectParts::MAX_PARTS`に達している場合には、全体を一つの内容として書き直す。
+    /// ただし、内容をメタデータとして保持するバケツの場合には、全体を読み込んで書き直すことになる。
+    ///
+    /// オブジェクトが存在しない場合には、`content`を内容とするオブジェクトが新規に作成される。
+    /// `expect`には追記前のオブジェクトのバージョンに関する条件を指定でき、
+    /// `Expect::Any`以外の場合には、存在しないオブジェクトの新規作成にも適用される。
+    /// 結果は追記後のオブジェクトのバージョン。
+    ///
+    /// バケツの暗号化が有効な場合には、追記は常に`ErrorKind::Invalid`エラーとなる。
+    pub fn append(
+        &self,
+        id: ObjectId,
+        content: Vec<u8>,
+        deadline: Deadline,
+        expect: Expect,
+        parent: SpanHandle,
+    ) -> impl Future<Item = ObjectVersion, Error = Error> {
+        let this = self.clone();
+        if let Err(e) = track!(self.access.check_writable().map_err(Error::from)) {
+            return Either::A(Either::A(futures::failed(e)));
+        }
+        if self.encryption.as_ref().is_some_and(|e| e.is_enabled()) {
+            // NOTE: 暗号化されたオブジェクトは全体で一つの暗号文となっており、部分の一覧と共に鍵の情報を
+            //       記録する仕組みがないため、末尾に部分を足すだけの追記はできない
+            let e = ErrorKind::Invalid.cause("Cannot append to an object in an encrypted bucket");
+            return Either::A(Either::A(futures::failed(track!(Error::from(e)))));
+        }
+        if self.storage.is_metadata() {
+            let future = self
+                .get(
+                    id.clone(),
+                    deadline,
+                    ReadConsistency::Consistent,
+                    parent.clone(),
+                )
+                .and_then(move |object| {
+                    if let Err(e) = expect.validate(object.as_ref().map(|o| o.version)) {
+                        return Either::A(futures::failed(track!(Error::from(e))));
+                    }
+                    let (expect, mut whole) = match object {
+                        None => (Expect::None, Vec::new()),
+                        Some(o) => (Expect::IfMatch(vec![o.version]), o.content),
+                    };
+                    whole.extend_from_slice(&content);
+                    let future = this
+                        .put(id, whole.into(), deadline, expect.into(), parent)
+                        .map(|(version, _, _)| version);
+                    Either::B(future)
+                });
+            return Either::A(Either::B(future));
+        }
+
+        // NOTE: 追記同士は衝突しないので、前提条件が指定されていなければ、
+        // 予約から追記までの間に他の追記が割り込んだ場合には、改めて予約し直す
+        let content = Bytes::from(content);
+        let future = futures::future::loop_fn(1, move |attempt| {
+            let retriable = expect == Expect::Any && attempt < MAX_APPEND_ATTEMPTS;
+            this.append_once(
+                id.clone(),
+                content.clone(),
+                deadline,
+                expect.clone(),
+                parent.clone(),
+            )
+            .then(move |result| match result {
+                Err(ref e) if retriable && is_unexpected_version(e) => {
+                    Ok(futures::future::Loop::Continue(attempt + 1))
+                }
+                Err(e) => Err(track!(e)),
+                Ok(version) => Ok(futures::future::Loop::Break(version)),
+            })
+        });
+        Either::B(future)
+    }
+
+    /// 内容をストレージに保存するバケツで、一度だけ追記を試みる。
+    ///
+    /// 部分のバージョンを MDS で予約し、その内容をストレージに保存した後で、オブジェクトに追記する。
+    /// 部分の数が上限に達している場合には、追記する代わりに全体を一つの内容として書き直す。
+    fn append_once(
+        &self,
+        id: ObjectId,
+        content: Bytes,
+        deadline: Deadline,
+        expect: Expect,
+        parent: SpanHandle,
+    ) -> impl Future<Item = ObjectVersion, Error = Error> {
+        let this = self.clone();
+        self.mds
+            .get(id.clone(), ReadConsistency::Consistent, parent.clone())
+            .and_then(move |object| {
+                let object = if let Some(object) = object {
+                    object
+                } else {
+                    // 前提条件が指定されていなければ、新規に作成する
+                    let expect = if expect == Expect::Any {
+                        Expect::None
+                    } else {
+                        expect
+                    };
+                    let future = this
+                        .put(id, content, deadline, expect.into(), parent)
+                        .map(|(version, _, _)| version);
+                    return Either::A(Either::A(future));
+                };
+
+                let parts = ObjectParts::decode(&object.content).map_or(1, |p| p.len());
+                if parts >= ObjectParts::MAX_PARTS {
+                    if let Err(e) = expect.validate(Some(object.version)) {
+                        return Either::A(Either::B(Either::A(futures::failed(track!(
+                            Error::from(e)
+                        )))));
+                    }
+                    let future =
+                        this.compact_and_append(id, object.version, content, deadline, parent);
+                    return Either::A(Either::B(Either::B(future)));
+                }
+
+                let storage = this.storage.clone();
+                let mds = this.mds.clone();
+                let mut tracking = PutFailureTracking::new(this.logger.clone(), id.clone());
+                let size = content.len() as u64;
+                let future = this
+                    .mds
+                    .append(id.clone(), expect, size, deadline, parent.clone())
+                    .and_then(move |(version, _)| {
+                        storage
+                            .put(version, content, deadline, parent.clone())
+                            .and_then(move |_| mds.commit_append(id, version, parent))
+                            .map(move |_| {
+                                tracking.complete();
+                                version
+                            })
+                    });
+                Either::B(future)
+            })
+    }
+
+    /// 部分の数が上限に達したオブジェクトの全体を読み込み、`content`を連結した内容で書き直す。
+    ///
+    /// 書き直しはバージョン`version`に対する条件付きで行われるので、
+    /// その間に他の更新があった場合には`ErrorKind::UnexpectedVersion`となる。
+    fn compact_and_append(
+        &self,
+        id: ObjectId,
+        version: ObjectVersion,
+        content: Bytes,
+        deadline: Deadline,
+        parent: SpanHandle,
+    ) -> BoxFuture<ObjectVersion> {
+        let this = self.clone();
+        let future = self
+            .get(
+                id.clone(),
+                deadline,
+                ReadConsistency::Consistent,
+                parent.clone(),
+            )
+            .and_then(move |object| {
+                let mut whole = match object {
+                    Some(o) if o.version == version => o.content,
+                    object => {
+                        let current = object.map(|o| o.version);
+                        let e = ErrorKind::UnexpectedVersion { current }
+                            .cause("The object has been updated while compacting its parts");
+                        return Either::A(futures::failed(track!(Error::from(e))));
+                    }
+                };
+                whole.extend_from_slice(&content);
+                let expect = Expect::IfMatch(vec![version]);
+                let future = this
+                    .put(id, whole.into(), deadline, expect.into(), parent)
+                    .map(|(version, _, _)| version);
+                Either::B(future)
+            });
+        Box::new(future)
     }
 
     /// オブジェクトを削除する。
+    ///
+    /// 前提条件の扱いは`put`と同様。
     pub fn delete(
         &self,
         id: ObjectId,
-        _deadline: Deadline,
-        expect: Expect,
+        deadline: Deadline,
+        expect: Precondition,
         parent: SpanHandle,
     ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
-        // TODO: mdsにdeadlineを渡せるようにする
-        // (lump削除タイミングの決定用)
+        if let Err(e) = track!(self.access.check_writable().map_err(Error::from)) {
+            return Either::A(futures::failed(e));
+        }
         let mds = self.mds.clone();
+        let retry = self.retry.clone();
+        let logger = self.logger.clone();
         let expect_future = match expect {
-            Expect::Any => {
-                let f = mds
-                    .head(id.clone(), ReadConsistency::Consistent, parent.clone())
-                    .map(|version| version.map_or(Expect::None, |v| Expect::IfMatch(vec![v])));
+            Precondition::Expect(Expect::Any) => {
+                let f = self
+                    .head_metadata(
+                        id.clone(),
+                        deadline,
+                        ReadConsistency::Consistent,
+                        parent.clone(),
+                    )
+                    .map(|version| version.map_or(Expect::None, |v| Expect::IfMatch(vec![v])))
+                    .map(Precondition::from);
                 Either::A(f)
             }
             _ => Either::B(futures::future::ok(expect)),
         };
-        expect_future.and_then(move |expect| mds.delete(id, expect, parent))
+        let delete_mds = self.latency.delete_mds.clone();
+        let future = expect_future.and_then(move |expect| {
+            let future = retry.retry(&logger, deadline, move |_| {
+                mds.delete(id.clone(), expect.clone(), deadline, parent.clone())
+            });
+            observe_latency(&delete_mds, future)
+        });
+        Either::B(observe_latency(&self.latency.delete_total, future))
+    }
+
+    /// 削除猶予期間中のオブジェクトを復元する。
+    ///
+    /// MDS に削除猶予期間(`tombstone_retention_secs`)が設定されている場合にのみ有効で、
+    /// 復元されるのは最後に削除されたバージョンとなる。
+    /// 復元可能なオブジェクトが存在しない場合には`None`が返される。
+    pub fn undelete(
+        &self,
+        id: ObjectId,
+        parent: SpanHandle,
+    ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
+        if let Err(e) = track!(self.access.check_writable().map_err(Error::from)) {
+            return Either::A(futures::failed(e));
+        }
+        Either::B(self.mds.undelete(id, parent))
     }
 
     /// バージョン指定でオブジェクトを削除する。
@@ -186,7 +880,10 @@ impl Client {
         _deadline: Deadline,
         parent: SpanHandle,
     ) -> impl Future<Item = Option<ObjectVersion>, Error = Error> {
-        self.mds.delete_by_version(version, parent)
+        if let Err(e) = track!(self.access.check_writable().map_err(Error::from)) {
+            return Either::A(futures::failed(e));
+        }
+        Either::B(self.mds.delete_by_version(version, parent))
     }
 
     /// バージョンの範囲指定でオブジェクトを削除する。
@@ -196,7 +893,10 @@ impl Client {
         _deadline: Deadline,
         parent: SpanHandle,
     ) -> impl Future<Item = Vec<ObjectSummary>, Error = Error> {
-        self.mds.delete_by_range(targets, parent)
+        if let Err(e) = track!(self.access.check_writable().map_err(Error::from)) {
+            return Either::A(futures::failed(e));
+        }
+        Either::B(self.mds.delete_by_range(targets, parent))
     }
 
     /// IDの接頭辞指定でオブジェクトを削除する。
@@ -206,7 +906,45 @@ impl Client {
         _deadline: Deadline,
         parent: SpanHandle,
     ) -> impl Future<Item = DeleteObjectsByPrefixSummary, Error = Error> {
-        self.mds.delete_by_prefix(prefix, parent)
+        if let Err(e) = track!(self.access.check_writable().map_err(Error::from)) {
+            return Either::A(futures::failed(e));
+        }
+        Either::B(self.mds.delete_by_prefix(prefix, parent))
+    }
+
+    /// IDの接頭辞指定でオブジェクトを削除するジョブを開始する。
+    ///
+    /// `delete_by_prefix`とは異なり、オブジェクトはバッチに分けて削除され、
+    /// 実データの削除の進み具合に合わせて次のバッチが処理される。
+    /// 結果はジョブの ID で、`get_delete_job_status`で進捗を確認できる。
+    ///
+    /// ジョブの状態は MDS のクラスタ内で複製されるので、リーダが交代しても処理は継続される。
+    /// クラスタ機能`delete_objects`が有効になっていない場合にはエラーとなる。
+    pub fn start_delete_job(
+        &self,
+        prefix: ObjectPrefix,
+        parent: SpanHandle,
+    ) -> impl Future<Item = u64, Error = Error> {
+        if let Err(e) = track!(self.access.check_writable().map_err(Error::from)) {
+            return Either::A(futures::failed(e));
+        }
+        Either::B(self.mds.start_delete_job(prefix, parent))
+    }
+
+    /// 削除ジョブの進捗を取得する。
+    pub fn get_delete_job_status(
+        &self,
+        job_id: u64,
+    ) -> impl Future<Item = Option<DeleteJobStatus>, Error = Error> {
+        self.mds.get_delete_job_status(job_id)
+    }
+
+    /// 削除ジョブを中断する。
+    pub fn cancel_delete_job(
+        &self,
+        job_id: u64,
+    ) -> impl Future<Item = Option<DeleteJobStatus>, Error = Error> {
+        self.mds.cancel_delete_job(job_id)
     }
 
     /// 保存済みのオブジェクト一覧を取得する。
@@ -214,6 +952,38 @@ impl Client {
         self.mds.list()
     }
 
+    /// 指定された一貫性で、保存済みのオブジェクト一覧を取得する。
+    ///
+    /// `ReadConsistency::Stale`の場合には、遅れが許容範囲内のフォロワーから取得することがある。
+    pub fn list_with_consistency(
+        &self,
+        consistency: ReadConsistency,
+    ) -> impl Future<Item = Vec<ObjectSummary>, Error = Error> {
+        self.mds.list_with_consistency(consistency)
+    }
+
+    /// 最新のバージョンが`start`から`end`までの間(UNIXエポックからの秒数、`end`は含まない)に
+    /// 保存されたオブジェクトの一覧を、保存時刻順に取得する。
+    ///
+    /// セグメント全体を列挙せずに、最近追加されたオブジェクトを見つけるために使う。
+    /// 保存時刻が記録される以前に保存されたオブジェクトは含まれない。
+    pub fn list_by_time_range(
+        &self,
+        start: u64,
+        end: u64,
+    ) -> impl Future<Item = Vec<ObjectSummary>, Error = Error> {
+        let range = start.saturating_mul(1000)..end.saturating_mul(1000);
+        self.mds.list_by_time_range(range)
+    }
+
+    /// セグメント内のオブジェクトの変更(追加と削除)を監視するストリームを返す。
+    ///
+    /// `after`を指定した場合には、その位置よりも後にコミットされた変更から生成される。
+    /// `None`の場合には、監視を開始した時点以降の変更のみが生成される。
+    pub fn watch(&self, after: Option<ObjectVersion>) -> Watch {
+        Watch::new(self.mds.clone(), after)
+    }
+
     /// セグメント内の最新オブジェクトのバージョンを取得する。
     pub fn latest(&self) -> impl Future<Item = Option<ObjectSummary>, Error = Error> {
         self.mds.latest()
@@ -223,6 +993,143 @@ impl Client {
     pub fn object_count(&self) -> impl Future<Item = u64, Error = Error> {
         self.mds.object_count()
     }
+
+    /// セグメントの使用量と割り当て量を返す。
+    pub fn quota_usage(&self) -> impl Future<Item = QuotaUsage, Error = Error> {
+        self.mds.quota_usage()
+    }
+
+    /// このクライアントが認識している、セグメントの MDS のリーダを返す.
+    ///
+    /// まだ一度も MDS にリクエストを送信していない場合には`None`となる.
+    pub fn mds_leader(&self) -> Option<NodeId> {
+        self.mds.known_leader()
+    }
+
+    /// セグメントを構成するメンバの一覧を返す。
+    pub fn members(&self) -> &[ClusterMember] {
+        &self.cluster.members
+    }
+
+    /// `node`が属する配置のメンバ群を返す。
+    ///
+    /// 再配置中のセグメントの場合には、変更前の配置に属するノードもある。
+    pub(crate) fn cluster_of(&self, node: &NodeId) -> &ClusterConfig {
+        match self.previous_cluster {
+            Some(ref previous) if previous.contains(node) => previous,
+            _ => &self.cluster,
+        }
+    }
+
+    /// 指定のバージョンのオブジェクトのデータを保持するメンバの一覧を返す。
+    ///
+    /// `dispersed`の場合には断片の番号順に、`replicated`の場合には取得時の優先順位順に並ぶ。
+    /// メタデータ用のセグメントの場合には空となる。
+    ///
+    /// 実際にデータが保存されているかどうかは確認せず、配置の計算結果のみを返す。
+    pub fn data_members(&self, version: ObjectVersion) -> Vec<ClusterMember> {
+        let count = self.storage.fragments();
+        self.cluster
+            .candidates(version)
+            .take(count)
+            .cloned()
+            .collect()
+    }
+
+    /// セグメントの各メンバを保持するサーバに問い合わせて、それぞれの状態を返す。
+    ///
+    /// 問い合わせに失敗したメンバについては、`MemberStatus::error`にその理由が入る。
+    /// 障害時に応答しないメンバがあっても全体が止まらないように、
+    /// 各メンバへの問い合わせは`MEMBER_STATUS_TIMEOUT`で打ち切られる。
+    pub fn member_statuses(&self) -> impl Future<Item = Vec<MemberStatus>, Error = Error> {
+        let futures = self
+            .cluster
+            .members
+            .iter()
+            .map(|m| {
+                let member = m.clone();
+                let options = RpcOptions {
+                    timeout: Some(MEMBER_STATUS_TIMEOUT),
+                    ..Default::default()
+                };
+                rpc_auth::call_with_options::<GetSegmentNodeStatusRpc>(
+                    &self.rpc_service,
+                    m.node.current_addr(),
+                    m.node.local_id.to_string(),
+                    options,
+                )
+                .then(move |result| Ok(make_member_status(&member, result)))
+            })
+            .collect::<Vec<_>>();
+        futures::future::join_all(futures)
+    }
+
+    /// ストレージの各メンバへの読み込み要求のエラー率や応答時間を返す。
+    ///
+    /// これらの値は、オブジェクトの内容の読み込み先の選択に使われている。
+    pub fn member_health(&self) -> Vec<MemberHealth> {
+        self.storage.member_health()
+    }
+}
+
+/// メンバの状態を問い合わせる際の、メンバ毎のタイムアウト。
+const MEMBER_STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `member`への状態の問い合わせ結果から、`MemberStatus`を作る。
+///
+/// 通信自体の失敗(タイムアウトを含む)も、メンバ側での失敗も、`MemberStatus::error`として扱う。
+fn make_member_status<E, F>(
+    member: &ClusterMember,
+    result: std::result::Result<std::result::Result<SegmentNodeStatus, E>, F>,
+) -> MemberStatus
+where
+    E: ToString,
+    F: ToString,
+{
+    let (status, error) = match result {
+        Ok(Ok(status)) => (Some(status), None),
+        Ok(Err(e)) => (None, Some(e.to_string())),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    MemberStatus {
+        node: member.node.to_string(),
+        device: member.device.clone(),
+        status,
+        error,
+    }
+}
+
+/// `Expect::Any`での追記を、予約から追記までの間の他の追記との衝突によって試行し直す最大回数。
+const MAX_APPEND_ATTEMPTS: usize = 3;
+
+fn is_unexpected_version(e: &Error) -> bool {
+    if let ErrorKind::UnexpectedVersion { .. } = *e.kind() {
+        true
+    } else {
+        false
+    }
+}
+
+/// `key`が指定されている場合には、ストレージから読み込んだ`content`を復号する。
+fn open_content(
+    logger: &Logger,
+    key: Option<ObjectKey>,
+    version: ObjectVersion,
+    content: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let key = match key {
+        None => return Ok(content),
+        Some(key) => key,
+    };
+    key.open(version, content).map_err(|e| {
+        crit!(
+            logger,
+            "Cannot decrypt the object: version={:?}, reason={}",
+            version,
+            e
+        );
+        track!(e)
+    })
 }
 
 /// Put がアトミックではないため、ストレージへの保存に失敗した可能性を追跡する。
@@ -252,7 +1159,8 @@ impl Drop for PutFailureTracking {
         if !self.is_completed {
             warn!(
                 self.logger,
-                "A put operation might have failed: object_id={:?}", self.object_id
+                "A put operation might have failed";
+                logging::OBJECT_ID => &self.object_id
             );
         }
     }
@@ -293,11 +1201,11 @@ mod tests {
         // However, 5-secs is an ungrounded value.
         thread::sleep(time::Duration::from_secs(5));
 
-        let (object_version, _) = wait(client.put(
+        let (object_version, _, _) = wait(client.put(
             object_id.to_owned(),
-            expected.clone(),
+            Bytes::from(expected.clone()),
             Deadline::Infinity,
-            Expect::Any,
+            Expect::Any.into(),
             Span::inactive().handle(),
         ))?;
 
@@ -368,9 +1276,9 @@ mod tests {
 
         let _ = wait(client.put(
             object_id.clone(),
-            expected.clone(),
+            Bytes::from(expected.clone()),
             Deadline::Infinity,
-            Expect::Any,
+            Expect::Any.into(),
             Span::inactive().handle(),
         ))?;
 
@@ -386,7 +1294,7 @@ mod tests {
         let _ = wait(client.delete(
             object_id.clone(),
             Deadline::Infinity,
-            Expect::Any,
+            Expect::Any.into(),
             Span::inactive().handle(),
         ))?;
 
@@ -402,6 +1310,138 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn read_only_bucket_rejects_writes() -> TestResult {
+        let system = System::new(2, 1)?;
+        let access = AccessMode::default();
+        let client = system.make_segment_client_with(|c| c.access = access.clone())?;
+        access.set_read_only(true);
+
+        let e = wait(client.put(
+            "test_data".to_owned(),
+            Bytes::from(vec![0x03]),
+            Deadline::Infinity,
+            Expect::Any.into(),
+            Span::inactive().handle(),
+        ))
+        .err()
+        .unwrap();
+        assert!(matches!(*e.kind(), ErrorKind::ReadOnly));
+
+        let e = wait(client.delete(
+            "test_data".to_owned(),
+            Deadline::Infinity,
+            Expect::Any.into(),
+            Span::inactive().handle(),
+        ))
+        .err()
+        .unwrap();
+        assert!(matches!(*e.kind(), ErrorKind::ReadOnly));
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_bucket_rejects_writes_on_mds() -> TestResult {
+        let mut system = System::new(2, 1)?;
+        let (_members, _) = setup_system(&mut system, 3)?;
+
+        // クライアント側で確認されない場合でも、MDS のノードが書き込みを拒否する
+        let client = system.make_segment_client_with(|c| c.access = AccessMode::default())?;
+        system.access().set_read_only(true);
+        let result = wait(client.put(
+            "test_data".to_owned(),
+            Bytes::from(vec![0x03]),
+            Deadline::Infinity,
+            Expect::Any.into(),
+            Span::inactive().handle(),
+        ));
+        assert!(result.is_err());
+
+        system.access().set_read_only(false);
+        wait(client.put(
+            "test_data".to_owned(),
+            Bytes::from(vec![0x03]),
+            Deadline::Infinity,
+            Expect::Any.into(),
+            Span::inactive().handle(),
+        ))?;
+        Ok(())
+    }
+
+    #[test]
+    fn data_members_works() -> TestResult {
+        let mut system = System::new(2, 1)?;
+        let (_members, client) = setup_system(&mut system, 4)?;
+
+        // 余分なメンバは含まれず、取得先候補の先頭から断片の数だけ返される
+        let version = ObjectVersion(10);
+        let members = client.data_members(version);
+        assert_eq!(members.len(), 3);
+        let candidates = client
+            .cluster
+            .candidates(version)
+            .take(3)
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(members, candidates);
+        Ok(())
+    }
+
+    #[test]
+    fn small_objects_are_replicated() -> TestResult {
+        let data_fragments = 2;
+        let parity_fragments = 1;
+        let cluster_size = 3;
+        let mut system = System::new(data_fragments, parity_fragments)?;
+        system.erasure_coder.replication_threshold = 16;
+        let (members, client) = setup_system(&mut system, cluster_size)?;
+
+        thread::spawn(move || loop {
+            system.executor.run_once().unwrap();
+            thread::sleep(time::Duration::from_micros(100));
+        });
+
+        let expected = vec![0x03];
+        let object_id = "test_data".to_owned();
+
+        // wait until the segment becomes stable; for example, there is a raft leader.
+        // However, 5-secs is an ungrounded value.
+        thread::sleep(time::Duration::from_secs(5));
+
+        let (object_version, _, _) = wait(client.put(
+            object_id.clone(),
+            Bytes::from(expected.clone()),
+            Deadline::Infinity,
+            Expect::Any.into(),
+            Span::inactive().handle(),
+        ))?;
+
+        // Deletes all lumps except for one replica.
+        for (node_id, device_id, device_handle) in members.into_iter().skip(1) {
+            let lump_id = ClusterMember {
+                node: node_id,
+                device: device_id,
+            }
+            .make_lump_id(object_version);
+            let _ = wait(
+                device_handle
+                    .request()
+                    .delete(lump_id)
+                    .map_err(|e| track!(Error::from(e))),
+            )?;
+        }
+
+        let data = wait(client.get(
+            object_id.clone(),
+            Deadline::Infinity,
+            ReadConsistency::Consistent,
+            Span::inactive().handle(),
+        ))?;
+        assert_eq!(expected, data.unwrap().content);
+
+        Ok(())
+    }
+
     #[test]
     fn head_storage_work() -> TestResult {
         let data_fragments = 2;
@@ -423,11 +1463,11 @@ mod tests {
         // However, 5-secs is an ungrounded value.
         thread::sleep(time::Duration::from_secs(5));
 
-        let (object_version, _) = wait(client.put(
+        let (object_version, _, _) = wait(client.put(
             object_id.clone(),
-            expected.clone(),
+            Bytes::from(expected.clone()),
             Deadline::Infinity,
-            Expect::Any,
+            Expect::Any.into(),
             Span::inactive().handle(),
         ))?;
 
@@ -441,7 +1481,8 @@ mod tests {
         // delete (num of data_fragments) lumps
         let mut i = 0;
         for (node_id, device_id, _) in members {
-            let client = cannyls_rpc::Client::new(node_id.addr, rpc_service_handle.clone());
+            let client =
+                cannyls_rpc::Client::new(node_id.current_addr(), rpc_service_handle.clone());
             let cluster_member = ClusterMember {
                 node: node_id,
                 device: device_id.clone(),
@@ -476,4 +1517,40 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn make_member_status_works() {
+        let member = ClusterMember {
+            node: "00000000000001.0@127.0.0.1:14278".parse().unwrap(),
+            device: "dev".to_owned(),
+        };
+        let status = SegmentNodeStatus {
+            node: "00000000000001".to_owned(),
+            leader: None,
+            is_leader: false,
+            queues: Default::default(),
+            segment_gc: None,
+            relayout: None,
+        };
+
+        let ok: std::result::Result<std::result::Result<_, String>, String> =
+            Ok(Ok(status.clone()));
+        let s = make_member_status(&member, ok);
+        assert_eq!(s.node, member.node.to_string());
+        assert_eq!(s.device, "dev");
+        assert_eq!(s.status, Some(status));
+        assert_eq!(s.error, None);
+
+        // 応答がなかったメンバについては、理由だけを返す
+        let timeout: std::result::Result<std::result::Result<SegmentNodeStatus, String>, _> =
+            Err("timeout".to_owned());
+        let s = make_member_status(&member, timeout);
+        assert_eq!(s.status, None);
+        assert_eq!(s.error, Some("timeout".to_owned()));
+
+        let failed: std::result::Result<_, String> = Ok(Err("no such node".to_owned()));
+        let s = make_member_status(&member, failed);
+        assert_eq!(s.status, None);
+        assert_eq!(s.error, Some("no such node".to_owned()));
+    }
 }
@@ -1,21 +1,32 @@
 use cannyls::deadline::Deadline;
-use cannyls::lump::LumpData;
 use cannyls_rpc::Client as CannyLsClient;
 use cannyls_rpc::DeviceId;
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
 use frugalos_raft::NodeId;
 use futures::{Async, Future, Poll};
 use libfrugalos::entity::object::ObjectVersion;
+use slog::Logger;
 use std::sync::Arc;
 use trackable::error::ErrorKindExt;
 
-use client::storage::{append_checksum, verify_and_remove_checksum, PutAll};
+use bytes::Bytes;
+
+use audit::{audit_fragments, ObjectAuditReport};
+use client::budget::RequestBudget;
+use client::cancel::CancellationToken;
+use client::circuit_breaker::CircuitBreakers;
+use client::health::{MemberHealth, MemberHealthTable};
+use client::storage::{
+    lump_data_with_checksum, verify_and_remove_checksum, Hedge, PutAll, PutDurability,
+};
 use config::{
-    CannyLsClientConfig, ClusterConfig, ClusterMember, ReplicatedClientConfig, ReplicatedConfig,
+    CannyLsClientConfig, CircuitBreakerConfig, ClusterConfig, ClusterMember, MemberHealthConfig,
+    ReplicatedClientConfig, ReplicatedConfig,
 };
+use feature::{Feature, FeatureFlags};
 use metrics::ReplicatedClientMetrics;
 use util::BoxFuture;
-use {Error, ErrorKind};
+use {Error, ErrorKind, Result};
 
 #[derive(Debug, Clone)]
 pub struct ReplicatedClient {
@@ -24,33 +35,68 @@ pub struct ReplicatedClient {
     config: ReplicatedConfig,
     client_config: ReplicatedClientConfig,
     rpc_service: RpcServiceHandle,
+    health: MemberHealthTable,
+    features: FeatureFlags,
+    cancel: CancellationToken,
 }
 impl ReplicatedClient {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
+        logger: Logger,
         metrics: ReplicatedClientMetrics,
         cluster: ClusterConfig,
         config: ReplicatedConfig,
         client_config: ReplicatedClientConfig,
         rpc_service: RpcServiceHandle,
+        member_health: MemberHealthConfig,
+        circuit_breaker: CircuitBreakerConfig,
+        features: FeatureFlags,
     ) -> Self {
+        let breakers =
+            CircuitBreakers::new(logger, circuit_breaker, metrics.circuit_breaker.clone());
         ReplicatedClient {
             metrics,
             cluster: Arc::new(cluster),
             config,
             client_config,
             rpc_service,
+            health: MemberHealthTable::new(member_health, breakers),
+            features,
+            cancel: CancellationToken::new(),
         }
     }
+    /// 以降に発行するストレージへの要求を、`token`が取り消された時点で中断するようにする。
+    pub(crate) fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+    /// `node`がこのストレージのメンバかどうかを返す。
+    pub fn has_member(&self, node: &NodeId) -> bool {
+        self.cluster.contains(node)
+    }
+    /// オブジェクトの複製の数を返す。
+    pub fn replicas(&self) -> usize {
+        self.config.tolerable_faults as usize + 1
+    }
+    /// 各メンバの健全性を返す。
+    pub fn member_health(&self) -> Vec<MemberHealth> {
+        self.health.snapshot()
+    }
     pub fn get_fragment(
         self,
         _local_node: NodeId,
         version: ObjectVersion,
     ) -> GetReplicatedFragment {
         // TODO: `_local_node`は問い合わせ候補から外す(必ず失敗するので)
-        let future = self.get(version, Deadline::Infinity);
+        let future = self.get(version, Deadline::Infinity, RequestBudget::unlimited());
         GetReplicatedFragment(future)
     }
-    pub fn get(self, version: ObjectVersion, deadline: Deadline) -> BoxFuture<Vec<u8>> {
+    pub fn get(
+        self,
+        version: ObjectVersion,
+        deadline: Deadline,
+        budget: RequestBudget,
+    ) -> BoxFuture<Vec<u8>> {
         let replica = self.config.tolerable_faults as usize + 1;
         let mut candidates = self
             .cluster
@@ -58,17 +104,44 @@ impl ReplicatedClient {
             .take(replica)
             .cloned()
             .collect::<Vec<_>>();
+        self.health.sort(&mut candidates);
         candidates.reverse();
+        let hedge = if self.features.is_enabled(Feature::HedgedReads) {
+            Hedge::start(&self.client_config.hedge, &self.metrics.hedge)
+        } else {
+            None
+        };
         let future = ReplicatedGet {
             version,
             deadline,
             cannyls_config: self.client_config.cannyls.clone(),
             candidates,
             rpc_service: self.rpc_service,
-            future: Box::new(futures::finished(None)),
+            futures: Vec::new(),
+            last_error: None,
+            hedge,
+            health: self.health,
+            budget,
+            cancel: self.cancel,
         };
         Box::new(future)
     }
+    pub fn audit(self, version: ObjectVersion, deadline: Deadline) -> BoxFuture<ObjectAuditReport> {
+        let replica = self.config.tolerable_faults as usize + 1;
+        let members = self
+            .cluster
+            .candidates(version)
+            .take(replica)
+            .cloned()
+            .collect();
+        audit_fragments(
+            members,
+            version,
+            deadline,
+            self.rpc_service,
+            self.client_config.cannyls.rpc_options(),
+        )
+    }
     /// TODO 実装
     pub fn head(self, _version: ObjectVersion, _deadline: Deadline) -> BoxFuture<()> {
         Box::new(futures::future::ok(()))
@@ -76,42 +149,45 @@ impl ReplicatedClient {
     pub fn put(
         self,
         version: ObjectVersion,
-        mut content: Vec<u8>,
+        content: Bytes,
         deadline: Deadline,
-    ) -> BoxFuture<()> {
+    ) -> BoxFuture<PutDurability> {
         let rpc_service = self.rpc_service;
         let replica = self.config.tolerable_faults as usize + 1;
-        append_checksum(&mut content);
 
-        let data = match track!(LumpData::new(content)) {
+        // NOTE: データの領域は各メンバへの要求の間で共有されるので、内容の複製は一度だけで済む
+        let data = match track!(lump_data_with_checksum(&[&content])) {
             Ok(data) => data,
-            Err(error) => return Box::new(futures::failed(Error::from(error))),
+            Err(error) => return Box::new(futures::failed(error)),
         };
         let cannyls_config = self.client_config.cannyls.clone();
 
-        let futures = self
+        // NOTE: MDS にはメタデータが登録済みなので、複製の格納要求は取り消さない
+        let members = self
             .cluster
             .candidates(version)
             .take(replica)
-            .map(move |m| {
-                let client = CannyLsClient::new(m.node.addr, rpc_service.clone());
-                let mut request = client.request();
-                request.rpc_options(cannyls_config.rpc_options());
+            .collect::<Vec<_>>();
+        let futures = members.into_iter().map(move |m| {
+            let client = CannyLsClient::new(m.node.current_addr(), rpc_service.clone());
+            let mut request = client.request();
+            request.rpc_options(cannyls_config.rpc_options());
 
-                let device_id = DeviceId::new(m.device.clone());
-                let lump_id = m.make_lump_id(version);
-                let future: BoxFuture<_> = Box::new(
-                    request
-                        .deadline(deadline)
-                        .max_queue_len(cannyls_config.device_max_queue_len)
-                        .put_lump(device_id, lump_id, data.clone())
-                        .map(|_is_new| ())
-                        .map_err(|e| track!(Error::from(e))),
-                );
-                future
-            });
+            let device_id = DeviceId::new(m.device.clone());
+            let lump_id = m.make_lump_id(version);
+            let future: BoxFuture<_> = Box::new(
+                request
+                    .deadline(deadline)
+                    .max_queue_len(cannyls_config.device_max_queue_len)
+                    .put_lump(device_id, lump_id, data.clone())
+                    .map(|_is_new| ())
+                    .map_err(|e| track!(Error::from(e))),
+            );
+            future
+        });
+        let wait_all = !self.features.is_enabled(Feature::QuorumPutAck);
         let put_all = match track!(PutAll::new(self.metrics.put_all.clone(), futures, 1)) {
-            Ok(put_all) => put_all,
+            Ok(put_all) => put_all.wait_all(wait_all),
             Err(error) => return Box::new(futures::failed(error)),
         };
         Box::new(put_all)
@@ -123,48 +199,98 @@ pub struct ReplicatedGet {
     deadline: Deadline,
     cannyls_config: CannyLsClientConfig,
     candidates: Vec<ClusterMember>,
-    future: BoxFuture<Option<Vec<u8>>>,
+
+    // 応答待ちの要求群(hedged read による追加の要求かどうかと、その応答)
+    futures: Vec<(bool, BoxFuture<Option<Vec<u8>>>)>,
+    last_error: Option<Error>,
+    hedge: Option<Hedge>,
+    health: MemberHealthTable,
+    budget: RequestBudget,
+    cancel: CancellationToken,
     rpc_service: RpcServiceHandle,
 }
+impl ReplicatedGet {
+    fn request_next(&mut self, hedged: bool) -> Result<bool> {
+        let m = if let Some(m) = self.candidates.pop() {
+            m
+        } else {
+            return Ok(false);
+        };
+        track!(self.cancel.check())?;
+        track!(self.budget.consume_fanout(1))?;
+        let client = CannyLsClient::new(m.node.current_addr(), self.rpc_service.clone());
+        let mut request = client.request();
+        request.rpc_options(self.cannyls_config.rpc_options());
+
+        let lump_id = m.make_lump_id(self.version);
+        let future = request
+            .deadline(self.deadline)
+            .get_lump(DeviceId::new(m.device.clone()), lump_id);
+        let future = self.health.track(&m, future);
+        let future = self
+            .cancel
+            .guard(future.map_err(|e| track!(Error::from(e))));
+        self.futures.push((hedged, Box::new(future)));
+        Ok(true)
+    }
+}
 impl Future for ReplicatedGet {
     type Item = Vec<u8>;
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         loop {
-            match self.future.poll() {
-                Err(e) => {
-                    if self.candidates.is_empty() {
-                        return Err(track!(e));
+            let mut i = 0;
+            while i < self.futures.len() {
+                match self.futures[i].1.poll() {
+                    Err(e) => {
+                        self.futures.swap_remove(i);
+                        self.last_error = Some(e);
                     }
-                    self.future = Box::new(futures::finished(None))
-                }
-                Ok(Async::Ready(None)) => {
-                    let m = track!(self
-                        .candidates
-                        .pop()
-                        .ok_or_else(|| ErrorKind::Corrupted.error(),))?;
-                    let client = CannyLsClient::new(m.node.addr, self.rpc_service.clone());
-                    let mut request = client.request();
-                    request.rpc_options(self.cannyls_config.rpc_options());
-
-                    let lump_id = m.make_lump_id(self.version);
-                    let future = request
-                        .deadline(self.deadline)
-                        .get_lump(DeviceId::new(m.device), lump_id);
-                    self.future = Box::new(future.map_err(|e| track!(Error::from(e))));
-                }
-                Ok(Async::Ready(Some(mut content))) => {
-                    if let Err(e) = track!(verify_and_remove_checksum(&mut content)) {
-                        if self.candidates.is_empty() {
-                            return Err(track!(e));
+                    Ok(Async::Ready(None)) => {
+                        self.futures.swap_remove(i);
+                    }
+                    Ok(Async::Ready(Some(mut content))) => {
+                        let (hedged, _) = self.futures.swap_remove(i);
+                        track!(self.budget.consume_decode_bytes(content.len() as u64))?;
+                        if let Err(e) = track!(verify_and_remove_checksum(&mut content)) {
+                            self.last_error = Some(e);
+                        } else {
+                            if hedged {
+                                if let Some(ref hedge) = self.hedge {
+                                    hedge.on_won();
+                                }
+                            }
+                            return Ok(Async::Ready(content));
                         }
-                        self.future = Box::new(futures::finished(None));
-                    } else {
-                        return Ok(Async::Ready(content));
                     }
+                    Ok(Async::NotReady) => {
+                        i += 1;
+                    }
+                }
+            }
+            if self.futures.is_empty() {
+                if !track!(self.request_next(false))? {
+                    let e = self
+                        .last_error
+                        .take()
+                        .unwrap_or_else(|| ErrorKind::Corrupted.error().into());
+                    return Err(track!(e));
+                }
+                continue;
+            }
+
+            let extra_requests = self.hedge.as_mut().map_or(0, Hedge::poll_extra_requests);
+            let mut issued = 0;
+            while issued < extra_requests && track!(self.request_next(true))? {
+                issued += 1;
+            }
+            if issued > 0 {
+                if let Some(ref hedge) = self.hedge {
+                    hedge.on_triggered();
                 }
-                Ok(Async::NotReady) => break,
+                continue;
             }
+            break;
         }
         Ok(Async::NotReady)
     }
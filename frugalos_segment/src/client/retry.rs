@@ -0,0 +1,252 @@
+//! MDS やストレージへのリクエストを、クライアント側で再試行するための仕組み。
+//!
+//! `MdsClient`もリーダの交代時等にはノードを変えて再送を行うが、それでも失敗した場合
+//! (e.g., 選挙中でリーダが一時的に不在)には、呼び出し元にエラーが返されてしまう。
+//! ここでは、そのような一時的なエラーに対して、指数バックオフで待機した上で操作全体をやり直す。
+use cannyls::deadline::Deadline;
+use fibers::time::timer;
+use futures::{Async, Future, Poll};
+use rand::{self, Rng};
+use slog::Logger;
+use std::cmp;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use config::{RetryConfig, RetryableErrorKind};
+use {Error, ErrorKind};
+
+/// 再試行の方針。
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    config: Arc<RetryConfig>,
+}
+impl RetryPolicy {
+    /// 新しい`RetryPolicy`インスタンスを生成する。
+    pub fn new(config: RetryConfig) -> Self {
+        RetryPolicy {
+            config: Arc::new(config),
+        }
+    }
+
+    /// エラーが再試行の対象かどうかを判定する。
+    pub fn is_retryable(&self, e: &Error) -> bool {
+        let kind = match *e.kind() {
+            ErrorKind::Busy => RetryableErrorKind::Busy,
+            ErrorKind::Other => RetryableErrorKind::Other,
+            _ => return false,
+        };
+        self.config.retry_on.contains(&kind)
+    }
+
+    /// `retries`回目(1以上)の再試行までの待ち時間を返す。
+    pub fn backoff<R: Rng>(&self, retries: u32, rng: &mut R) -> Duration {
+        let factor = 2u32.saturating_pow(retries.saturating_sub(1));
+        let backoff = self
+            .config
+            .initial_backoff
+            .checked_mul(factor)
+            .map_or(self.config.max_backoff, |d| {
+                cmp::min(d, self.config.max_backoff)
+            });
+        if self.config.jitter {
+            let millis = backoff.as_millis() as u64;
+            Duration::from_millis(rng.gen_range(millis / 2, millis + 1))
+        } else {
+            backoff
+        }
+    }
+
+    /// `f`が生成する`Future`を、必要に応じて再試行しながら実行する。
+    ///
+    /// `f`の引数は、その試行が最後のものかどうか。
+    /// 最後の試行であれば、再試行に備えて要求の内容を複製しておく必要はない。
+    ///
+    /// `deadline`が`Deadline::Within`の場合には、その期間を超えてまで再試行は行わない。
+    pub fn retry<F, T>(&self, logger: &Logger, deadline: Deadline, f: F) -> Retry<F, T>
+    where
+        F: FnMut(bool) -> T,
+        T: Future<Error = Error>,
+    {
+        let give_up_at = if let Deadline::Within(d) = deadline {
+            Some(Instant::now() + d)
+        } else {
+            None
+        };
+        let mut this = Retry {
+            logger: logger.clone(),
+            policy: self.clone(),
+            f,
+            future: None,
+            backoff: None,
+            attempts: 0,
+            give_up_at,
+        };
+        this.start();
+        this
+    }
+}
+
+/// 再試行を行う`Future`。
+pub struct Retry<F, T> {
+    logger: Logger,
+    policy: RetryPolicy,
+    f: F,
+    future: Option<T>,
+    backoff: Option<timer::Timeout>,
+    attempts: usize,
+    give_up_at: Option<Instant>,
+}
+impl<F, T> Retry<F, T>
+where
+    F: FnMut(bool) -> T,
+    T: Future<Error = Error>,
+{
+    fn start(&mut self) {
+        self.attempts += 1;
+        let is_last = self.attempts >= self.policy.config.max_attempts;
+        self.future = Some((self.f)(is_last));
+    }
+
+    /// 次の再試行までの待ち時間を返す。再試行すべきでない場合には`None`を返す。
+    fn next_backoff(&self, e: &Error) -> Option<Duration> {
+        if self.attempts >= self.policy.config.max_attempts || !self.policy.is_retryable(e) {
+            return None;
+        }
+        let backoff = self
+            .policy
+            .backoff(self.attempts as u32, &mut rand::thread_rng());
+        if self
+            .give_up_at
+            .is_some_and(|t| Instant::now() + backoff >= t)
+        {
+            return None;
+        }
+        Some(backoff)
+    }
+}
+impl<F, T> Future for Retry<F, T>
+where
+    F: FnMut(bool) -> T,
+    T: Future<Error = Error>,
+{
+    type Item = T::Item;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Async::Ready(Some(())) = track!(self.backoff.poll().map_err(Error::from))? {
+                self.backoff = None;
+                self.start();
+            }
+            let result = match self.future {
+                None => return Ok(Async::NotReady),
+                Some(ref mut f) => f.poll(),
+            };
+            match result {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(item)) => return Ok(Async::Ready(item)),
+                Err(e) => {
+                    let backoff = match self.next_backoff(&e) {
+                        None => return Err(track!(e, "attempts={}", self.attempts)),
+                        Some(backoff) => backoff,
+                    };
+                    debug!(
+                        self.logger,
+                        "Retries after {:?}: attempts={}, reason={}", backoff, self.attempts, e
+                    );
+                    self.future = None;
+                    self.backoff = Some(timer::timeout(backoff));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fibers_global;
+    use futures;
+    use rand::{SeedableRng, StdRng};
+    use slog::Discard;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use trackable::error::ErrorKindExt;
+
+    fn policy(max_attempts: usize, jitter: bool) -> RetryPolicy {
+        RetryPolicy::new(RetryConfig {
+            max_attempts,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            jitter,
+            retry_on: vec![RetryableErrorKind::Busy],
+        })
+    }
+
+    #[test]
+    fn backoff_works() {
+        let mut rng = StdRng::from_seed([0; 32]);
+        let p = policy(10, false);
+        assert_eq!(p.backoff(1, &mut rng), Duration::from_millis(100));
+        assert_eq!(p.backoff(2, &mut rng), Duration::from_millis(200));
+        assert_eq!(p.backoff(3, &mut rng), Duration::from_millis(400));
+        assert_eq!(p.backoff(4, &mut rng), Duration::from_millis(500));
+        assert_eq!(p.backoff(100, &mut rng), Duration::from_millis(500));
+
+        let p = policy(10, true);
+        for _ in 0..100 {
+            let d = p.backoff(2, &mut rng);
+            assert!(Duration::from_millis(100) <= d && d <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn is_retryable_works() {
+        let p = policy(3, false);
+        assert!(p.is_retryable(&ErrorKind::Busy.error().into()));
+        assert!(!p.is_retryable(&ErrorKind::Other.error().into()));
+        assert!(!p.is_retryable(&ErrorKind::Invalid.error().into()));
+        let e = ErrorKind::UnexpectedVersion { current: None }.error();
+        assert!(!p.is_retryable(&e.into()));
+    }
+
+    #[test]
+    fn retry_works() {
+        let logger = Logger::root(Discard, o!());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let lasts = Arc::new(AtomicUsize::new(0));
+
+        // 二回失敗した後に成功する
+        let (c, l) = (calls.clone(), lasts.clone());
+        let future = policy(3, false).retry(&logger, Deadline::Infinity, move |is_last| {
+            if is_last {
+                l.fetch_add(1, Ordering::SeqCst);
+            }
+            if c.fetch_add(1, Ordering::SeqCst) < 2 {
+                futures::failed(ErrorKind::Busy.error().into())
+            } else {
+                futures::finished(10)
+            }
+        });
+        assert_eq!(fibers_global::execute(future).ok(), Some(10));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(lasts.load(Ordering::SeqCst), 1);
+
+        // 再試行の対象でないエラーの場合には、即座に失敗する
+        let c = calls.clone();
+        let future = policy(3, false).retry(&logger, Deadline::Infinity, move |_| {
+            c.fetch_add(1, Ordering::SeqCst);
+            futures::failed::<(), _>(ErrorKind::Invalid.error().into())
+        });
+        assert!(fibers_global::execute(future).is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        // 期限を超えてまで再試行は行わない
+        let c = calls.clone();
+        let deadline = Deadline::Within(Duration::from_millis(50));
+        let future = policy(3, false).retry(&logger, deadline, move |_| {
+            c.fetch_add(1, Ordering::SeqCst);
+            futures::failed::<(), _>(ErrorKind::Busy.error().into())
+        });
+        assert!(fibers_global::execute(future).is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+}
@@ -1,21 +1,32 @@
 #![allow(clippy::needless_pass_by_value)]
-use adler32;
-use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
 use cannyls::deadline::Deadline;
+use cannyls::lump::LumpData;
+use fibers::time::timer;
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
+use frugalos_core::checksum::ChecksumAlgorithm;
+use frugalos_core::metrics::MetricLabels;
+use frugalos_mds::machine::ObjectParts;
 use frugalos_raft::NodeId;
 use futures::future;
 use futures::{self, Async, Future, Poll};
 use libfrugalos::entity::object::ObjectVersion;
 use rustracing_jaeger::span::SpanHandle;
 use slog::Logger;
+use std::cmp;
+use std::iter;
+use std::ops::Range;
+use std::time::Duration;
 use trackable::error::ErrorKindExt;
 
+use audit::{ObjectAuditReport, ObjectAvailability};
+use client::budget::RequestBudget;
+use client::cancel::{Cancellable, CancellationToken};
 use client::dispersed_storage::{DispersedClient, ReconstructDispersedFragment};
-use client::ec::ErasureCoder;
+use client::health::MemberHealth;
 use client::replicated_storage::{GetReplicatedFragment, ReplicatedClient};
-use config::ClientConfig;
-use metrics::{DispersedClientMetrics, PutAllMetrics, ReplicatedClientMetrics};
+use config::{ClientConfig, HedgeConfig};
+use metrics::{DispersedClientMetrics, HedgeMetrics, PutAllMetrics, ReplicatedClientMetrics};
 use util::BoxFuture;
 use {Error, ErrorKind, ObjectValue, Result};
 
@@ -24,29 +35,53 @@ pub enum StorageClient {
     Metadata,
     Replicated(ReplicatedClient),
     Dispersed(DispersedClient),
+
+    /// 再配置中のセグメントのストレージ(新しい配置と変更前の配置の組)。
+    ///
+    /// 書き込みは新しい配置に対して行い、読み込みは新しい配置で失敗した場合に変更前の配置から行う。
+    Relayouting(Box<StorageClient>, Box<StorageClient>),
 }
 impl StorageClient {
     pub fn new(
         logger: Logger,
-        config: ClientConfig,
+        mut config: ClientConfig,
         rpc_service: RpcServiceHandle,
-        ec: Option<ErasureCoder>,
     ) -> Result<Self> {
         use config::Storage;
+        if let Some(previous) = config.previous_layout.take() {
+            let mut previous_config = config.clone();
+            previous_config.cluster = previous.cluster;
+            previous_config.storage = previous.storage;
+            let new = track!(StorageClient::new(
+                logger.clone(),
+                config,
+                rpc_service.clone()
+            ))?;
+            let previous = track!(StorageClient::new(logger, previous_config, rpc_service))?;
+            return Ok(StorageClient::Relayouting(
+                Box::new(new),
+                Box::new(previous),
+            ));
+        }
+        let labels = MetricLabels::bucket(&config.bucket_id);
         match config.storage {
             Storage::Metadata => Ok(StorageClient::Metadata),
             Storage::Replicated(c) => {
-                let metrics = track!(ReplicatedClientMetrics::new())?;
+                let metrics = track!(ReplicatedClientMetrics::new(&labels))?;
                 Ok(StorageClient::Replicated(ReplicatedClient::new(
+                    logger,
                     metrics,
                     config.cluster,
                     c,
                     config.replicated_client,
                     rpc_service,
+                    config.member_health,
+                    config.circuit_breaker,
+                    config.features,
                 )))
             }
             Storage::Dispersed(c) => {
-                let metrics = track!(DispersedClientMetrics::new())?;
+                let metrics = track!(DispersedClientMetrics::new(&labels))?;
                 Ok(StorageClient::Dispersed(DispersedClient::new(
                     logger,
                     metrics,
@@ -54,7 +89,12 @@ impl StorageClient {
                     c,
                     config.dispersed_client,
                     rpc_service,
-                    ec,
+                    &config.erasure_coder,
+                    config.coding,
+                    config.ec_pool,
+                    config.member_health,
+                    config.circuit_breaker,
+                    config.features,
                 )))
             }
         }
@@ -66,11 +106,70 @@ impl StorageClient {
             false
         }
     }
-    pub fn get_fragment(self, local_node: NodeId, version: ObjectVersion) -> GetFragment {
+    /// 一つのオブジェクトの内容を保持するメンバの数を返す。
+    ///
+    /// メタデータのみを扱うセグメントの場合には`0`となる。
+    pub fn fragments(&self) -> usize {
+        match *self {
+            StorageClient::Metadata => 0,
+            StorageClient::Replicated(ref c) => c.replicas(),
+            StorageClient::Dispersed(ref c) => c.fragments(),
+            StorageClient::Relayouting(ref new, _) => new.fragments(),
+        }
+    }
+    /// ストレージの各メンバの健全性を返す。
+    ///
+    /// メタデータのみを扱うセグメントの場合には空になる。
+    pub fn member_health(&self) -> Vec<MemberHealth> {
+        match *self {
+            StorageClient::Metadata => Vec::new(),
+            StorageClient::Replicated(ref c) => c.member_health(),
+            StorageClient::Dispersed(ref c) => c.member_health(),
+            StorageClient::Relayouting(ref new, ref previous) => {
+                let mut health = new.member_health();
+                health.extend(previous.member_health());
+                health
+            }
+        }
+    }
+    /// 再配置中の場合には、新しい配置と変更前の配置のストレージの組を返す。
+    pub fn relayouting(&self) -> Option<(&StorageClient, &StorageClient)> {
+        if let StorageClient::Relayouting(ref new, ref previous) = *self {
+            Some((new, previous))
+        } else {
+            None
+        }
+    }
+    /// 再配置中の場合には、`node`を含む方の配置のストレージを返す。
+    ///
+    /// ノードのローカルな修復等は、そのノードが属する配置の中で行う必要がある。
+    pub fn for_node(self, node: &NodeId) -> Self {
         match self {
-            StorageClient::Metadata => GetFragment::Failed(futures::failed(
-                ErrorKind::Other.cause("unreachable").into(),
-            )),
+            StorageClient::Relayouting(new, previous) => {
+                if previous.has_member(node) {
+                    previous.for_node(node)
+                } else {
+                    new.for_node(node)
+                }
+            }
+            this => this,
+        }
+    }
+    fn has_member(&self, node: &NodeId) -> bool {
+        match *self {
+            StorageClient::Metadata => false,
+            StorageClient::Replicated(ref c) => c.has_member(node),
+            StorageClient::Dispersed(ref c) => c.has_member(node),
+            StorageClient::Relayouting(ref new, ref previous) => {
+                new.has_member(node) || previous.has_member(node)
+            }
+        }
+    }
+    pub fn get_fragment(self, local_node: NodeId, version: ObjectVersion) -> GetFragment {
+        match self.for_node(&local_node) {
+            StorageClient::Metadata | StorageClient::Relayouting(..) => GetFragment::Failed(
+                futures::failed(ErrorKind::Other.cause("unreachable").into()),
+            ),
             StorageClient::Replicated(c) => {
                 GetFragment::Replicated(c.get_fragment(local_node, version))
             }
@@ -79,17 +178,148 @@ impl StorageClient {
             }
         }
     }
+    /// `budget`は、要求全体で消費できる資源の上限を表す。
     pub fn get(
         self,
         object: ObjectValue,
         deadline: Deadline,
         parent: SpanHandle,
+        budget: RequestBudget,
     ) -> BoxFuture<Vec<u8>> {
-        match self {
+        if let Some(parts) = self.object_parts(&object) {
+            return self.get_parts(parts, object.version, deadline, parent, budget);
+        }
+        self.cancellable(deadline, move |this| match this {
             StorageClient::Metadata => Box::new(futures::finished(object.content)),
-            StorageClient::Replicated(c) => c.get(object.version, deadline),
-            StorageClient::Dispersed(c) => c.get(object.version, deadline, parent),
+            StorageClient::Replicated(c) => c.get(object.version, deadline, budget),
+            StorageClient::Dispersed(c) => c.get(object.version, deadline, parent, budget),
+            StorageClient::Relayouting(new, previous) => with_fallback(*new, *previous, move |c| {
+                c.get(object.clone(), deadline, parent.clone(), budget.clone())
+            }),
+        })
+    }
+    pub fn get_range(
+        self,
+        object: ObjectValue,
+        range: Range<u64>,
+        deadline: Deadline,
+        parent: SpanHandle,
+        budget: RequestBudget,
+    ) -> BoxFuture<ContentRange> {
+        if let Some(parts) = self.object_parts(&object) {
+            return self.get_range_of_parts(parts, object.version, range, deadline, parent, budget);
         }
+        self.cancellable(deadline, move |this| match this {
+            StorageClient::Metadata => {
+                Box::new(futures::finished(slice_content(object.content, &range)))
+            }
+            StorageClient::Replicated(c) => Box::new(
+                c.get(object.version, deadline, budget)
+                    .map(move |content| slice_content(content, &range)),
+            ),
+            StorageClient::Dispersed(c) => {
+                c.get_range(object.version, range, deadline, parent, budget)
+            }
+            StorageClient::Relayouting(new, previous) => with_fallback(*new, *previous, move |c| {
+                c.get_range(
+                    object.clone(),
+                    range.clone(),
+                    deadline,
+                    parent.clone(),
+                    budget.clone(),
+                )
+            }),
+        })
+    }
+    /// 追記されたオブジェクトであれば、最新以外の部分の一覧を返す.
+    fn object_parts(&self, object: &ObjectValue) -> Option<ObjectParts> {
+        if self.is_metadata() {
+            // 内容そのものがメタデータとして保存されている
+            return None;
+        }
+        ObjectParts::decode(&object.content)
+    }
+    fn get_parts(
+        self,
+        parts: ObjectParts,
+        version: ObjectVersion,
+        deadline: Deadline,
+        parent: SpanHandle,
+        budget: RequestBudget,
+    ) -> BoxFuture<Vec<u8>> {
+        let futures = parts
+            .versions()
+            .chain(iter::once(version))
+            .map(|version| {
+                let part = ObjectValue {
+                    version,
+                    content: Vec::new(),
+                };
+                self.clone()
+                    .get(part, deadline, parent.clone(), budget.clone())
+            })
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(futures).map(|contents| contents.concat()))
+    }
+    /// 追記されたオブジェクトの`range`の範囲を、その範囲と重なる部分のみを取得して返す。
+    ///
+    /// 大きさが記録されていない部分(古い形式で追記されたもの)を含む場合には、
+    /// 各部分の位置が分からないので、全体を取得してから切り出す。
+    fn get_range_of_parts(
+        self,
+        parts: ObjectParts,
+        version: ObjectVersion,
+        range: Range<u64>,
+        deadline: Deadline,
+        parent: SpanHandle,
+        budget: RequestBudget,
+    ) -> BoxFuture<ContentRange> {
+        let sizes = parts
+            .parts
+            .iter()
+            .map(|p| (p.version, p.size))
+            .chain(iter::once((version, parts.last_size)))
+            .map(|(version, size)| size.map(|size| (version, size)))
+            .collect::<Option<Vec<_>>>();
+        let sizes = if let Some(sizes) = sizes {
+            sizes
+        } else {
+            return Box::new(
+                self.get_parts(parts, version, deadline, parent, budget)
+                    .map(move |content| slice_content(content, &range)),
+            );
+        };
+
+        let object_size = sizes.iter().map(|&(_, size)| size).sum();
+        let mut offset = 0;
+        let mut futures = Vec::new();
+        for (version, size) in sizes {
+            let start = cmp::max(range.start, offset);
+            let end = cmp::min(range.end, offset + size);
+            offset += size;
+            if start >= end {
+                continue;
+            }
+            let part = ObjectValue {
+                version,
+                content: Vec::new(),
+            };
+            let part_range = (start - (offset - size))..(end - (offset - size));
+            futures.push(self.clone().get_range(
+                part,
+                part_range,
+                deadline,
+                parent.clone(),
+                budget.clone(),
+            ));
+        }
+        Box::new(future::join_all(futures).map(move |ranges| {
+            let content = ranges.into_iter().flat_map(|r| r.content).collect();
+            ContentRange {
+                content,
+                object_size,
+            }
+        }))
     }
     pub fn head(
         self,
@@ -97,32 +327,134 @@ impl StorageClient {
         deadline: Deadline,
         parent: SpanHandle,
     ) -> BoxFuture<()> {
-        match self {
+        self.cancellable(deadline, move |this| match this {
             StorageClient::Metadata => Box::new(future::ok(())),
             StorageClient::Replicated(c) => c.head(version, deadline),
             StorageClient::Dispersed(c) => c.head(version, deadline, parent),
+            StorageClient::Relayouting(new, previous) => with_fallback(*new, *previous, move |c| {
+                c.head(version, deadline, parent.clone())
+            }),
+        })
+    }
+    /// `version`の断片を保持しているべき各メンバに、その有無を問い合わせる。
+    pub fn audit(self, version: ObjectVersion, deadline: Deadline) -> BoxFuture<ObjectAuditReport> {
+        match self {
+            StorageClient::Metadata => Box::new(future::ok(ObjectAuditReport {
+                version,
+                fragments: Vec::new(),
+            })),
+            StorageClient::Replicated(c) => c.audit(version, deadline),
+            StorageClient::Dispersed(c) => c.audit(version, deadline),
+            // 監査の対象は、今後も内容を保持し続ける新しい配置
+            StorageClient::Relayouting(new, _) => new.audit(version, deadline),
         }
     }
+    /// `version`の断片の有無を調べて、オブジェクトの可用性を判定する。
+    ///
+    /// メタデータのみを扱うセグメントの場合には、常に`Available`となる。
+    pub fn survey(
+        self,
+        version: ObjectVersion,
+        deadline: Deadline,
+    ) -> BoxFuture<ObjectAvailability> {
+        match self {
+            StorageClient::Metadata => Box::new(future::ok(ObjectAvailability::Available)),
+            StorageClient::Replicated(c) => Box::new(
+                c.audit(version, deadline)
+                    .map(|report| report.availability(1)),
+            ),
+            StorageClient::Dispersed(c) => c.survey(version, deadline),
+            StorageClient::Relayouting(new, _) => new.survey(version, deadline),
+        }
+    }
+    /// `version`の内容として`content`を書き込む。
+    ///
+    /// MDS にメタデータが登録された後に呼び出されるので、読み込みとは異なり、期限を過ぎても中断はしない。
+    /// `deadline`は、ストレージ側での要求の優先度の決定にのみ使われる。
     pub fn put(
         self,
         version: ObjectVersion,
-        content: Vec<u8>,
+        content: Bytes,
         deadline: Deadline,
         parent: SpanHandle,
-    ) -> BoxFuture<()> {
+    ) -> BoxFuture<PutDurability> {
         match self {
-            StorageClient::Metadata => Box::new(futures::finished(())),
+            StorageClient::Metadata => Box::new(futures::finished(PutDurability::default())),
             StorageClient::Replicated(c) => c.put(version, content, deadline),
-            StorageClient::Dispersed(c) => c.put(version, content, deadline, parent),
+            StorageClient::Dispersed(c) => {
+                if c.should_replicate(&content) {
+                    c.put_replicas(version, content, deadline, parent)
+                } else {
+                    c.put(version, content, deadline, parent)
+                }
+            }
+            StorageClient::Relayouting(new, _) => new.put(version, content, deadline, parent),
         }
     }
+    /// `f`が返す操作を、完了前に破棄されるか`deadline`を過ぎた時点で、ストレージへの要求ごと中断されるようにする。
+    fn cancellable<T, F>(self, deadline: Deadline, f: F) -> BoxFuture<T>
+    where
+        F: FnOnce(Self) -> BoxFuture<T>,
+        T: Send + 'static,
+    {
+        let token = CancellationToken::new();
+        let this = match self {
+            StorageClient::Metadata => StorageClient::Metadata,
+            StorageClient::Replicated(c) => {
+                StorageClient::Replicated(c.with_cancellation(token.clone()))
+            }
+            StorageClient::Dispersed(c) => {
+                StorageClient::Dispersed(c.with_cancellation(token.clone()))
+            }
+            // 各配置への要求は、それぞれ個別に中断される
+            StorageClient::Relayouting(new, previous) => StorageClient::Relayouting(new, previous),
+        };
+        Box::new(Cancellable::new(token, f(this), deadline))
+    }
+}
+
+/// `f`を新しい配置に対して実行し、失敗した場合には変更前の配置に対して実行し直す。
+///
+/// 再配置中のセグメントでは、既存のオブジェクトの内容はまだ新しい配置にコピーされていない可能性がある。
+fn with_fallback<T, F>(new: StorageClient, previous: StorageClient, f: F) -> BoxFuture<T>
+where
+    F: Fn(StorageClient) -> BoxFuture<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let future = f(new);
+    Box::new(future.or_else(move |e| -> BoxFuture<T> {
+        if let ErrorKind::Cancelled = *e.kind() {
+            return Box::new(futures::failed(e));
+        }
+        f(previous)
+    }))
+}
+
+/// オブジェクトの内容の書き込みにおける、冗長度の状況。
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PutDurability {
+    /// 書き込みに失敗した、もしくは書き込みの完了を確認できなかった断片(ないし複製)の数。
+    ///
+    /// 必要な数の書き込みが成功した時点で応答する場合(`QuorumPutAck`)には、
+    /// その時点で完了していない書き込みも、格納されている保証がないので失敗として数える。
+    ///
+    /// 書き込み自体は成功しているが、失敗した断片はリペアされるまで欠けたままとなる。
+    pub failed_fragments: usize,
+}
+impl PutDurability {
+    /// 一部の断片の書き込みに失敗し、冗長度が低下しているかどうかを返す。
+    pub fn is_degraded(&self) -> bool {
+        self.failed_fragments != 0
+    }
 }
 
 pub struct PutAll {
     metrics: PutAllMetrics,
     future: future::SelectAll<BoxFuture<()>>,
     ok_count: usize,
+    failed_count: usize,
     required_ok_count: usize,
+    wait_all: bool,
 }
 impl PutAll {
     pub fn new<I>(metrics: PutAllMetrics, futures: I, required_ok_count: usize) -> Result<Self>
@@ -142,18 +474,36 @@ impl PutAll {
             metrics,
             future,
             ok_count: 0,
+            failed_count: 0,
             required_ok_count,
+            wait_all: false,
         })
     }
+
+    /// `true`が指定された場合には、必要な数の書き込みが成功した後も、残りの書き込みの完了を待つ。
+    ///
+    /// 成功とみなす条件は変わらない。
+    pub fn wait_all(mut self, wait_all: bool) -> Self {
+        self.wait_all = wait_all;
+        self
+    }
+}
+impl PutAll {
+    fn durability(&self) -> PutDurability {
+        PutDurability {
+            failed_fragments: self.failed_count,
+        }
+    }
 }
 impl Future for PutAll {
-    type Item = ();
+    type Item = PutDurability;
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         loop {
             let remainings = match self.future.poll() {
                 Err((e, _, remainings)) => {
                     self.metrics.lost_fragments_total.increment();
+                    self.failed_count += 1;
                     if remainings.len() + self.ok_count < self.required_ok_count {
                         self.metrics.failures_total.increment();
                         return Err(track!(e));
@@ -162,16 +512,17 @@ impl Future for PutAll {
                 }
                 Ok(Async::Ready(((), _, remainings))) => {
                     self.ok_count += 1;
-                    if self.ok_count >= self.required_ok_count {
-                        // TODO: パラメータ化
-                        return Ok(Async::Ready(()));
+                    if self.ok_count >= self.required_ok_count && !self.wait_all {
+                        // 完了を待たない書き込みは、失敗したものとして報告する
+                        self.failed_count += remainings.len();
+                        return Ok(Async::Ready(self.durability()));
                     }
                     remainings
                 }
                 Ok(Async::NotReady) => break,
             };
             if remainings.is_empty() {
-                return Ok(Async::Ready(()));
+                return Ok(Async::Ready(self.durability()));
             }
             self.future = future::select_all(remainings);
         }
@@ -211,29 +562,150 @@ impl Future for GetFragment {
     }
 }
 
+/// 一つの取得操作における hedged read の状態。
+pub(crate) struct Hedge {
+    // 待ち時間が0の場合には`None`で、最初の確認時に追加の要求が必要となる
+    timer: Option<timer::Timeout>,
+    triggered: bool,
+    extra_requests: usize,
+    metrics: HedgeMetrics,
+}
+impl Hedge {
+    /// 取得操作の開始時に呼び出す。
+    ///
+    /// hedged read が無効な場合には`None`を返す。
+    pub(crate) fn start(config: &HedgeConfig, metrics: &HedgeMetrics) -> Option<Self> {
+        if !config.enabled || config.extra_requests == 0 {
+            return None;
+        }
+        let timer = if config.delay == Duration::from_secs(0) {
+            None
+        } else {
+            Some(timer::timeout(config.delay))
+        };
+        Some(Hedge {
+            timer,
+            triggered: false,
+            extra_requests: config.extra_requests,
+            metrics: metrics.clone(),
+        })
+    }
+
+    /// 待ち時間が経過した場合には、追加で発行すべき読み込み要求の数を返す。
+    ///
+    /// 追加の要求が必要になるのは、一つの取得操作につき一度だけである。
+    pub(crate) fn poll_extra_requests(&mut self) -> usize {
+        if self.triggered {
+            return 0;
+        }
+        let expired = match self.timer {
+            None => true,
+            Some(ref mut timer) => timer.poll().map_or(true, |x| x.is_ready()),
+        };
+        if !expired {
+            return 0;
+        }
+        self.timer = None;
+        self.triggered = true;
+        self.extra_requests
+    }
+
+    /// 追加の読み込み要求を発行したことを記録する。
+    pub(crate) fn on_triggered(&self) {
+        self.metrics.triggered_total.increment();
+    }
+
+    /// 追加の読み込み要求の応答を、取得結果に使用したことを記録する。
+    pub(crate) fn on_won(&self) {
+        self.metrics.won_total.increment();
+    }
+}
+
+/// `append_checksum`によって末尾に付与されるチェックサムのバイト数。
+///
+/// 末尾の形式は以下の通り:
+/// - 先頭の 4 バイト: 末尾より前の全データの Adler-32 チェックサム(ビッグエンディアン)
+/// - 残りの 1 バイト: 常に`0`(予約領域)
+pub(crate) const CHECKSUM_TRAILER_SIZE: usize = 5;
+
+/// 断片の末尾に付与するチェックサムのアルゴリズム。
+///
+/// 既に保存されている断片の検証にも使われるので、変更してはいけない。
+const FRAGMENT_CHECKSUM: ChecksumAlgorithm = ChecksumAlgorithm::Adler32;
+
 pub(crate) fn append_checksum(bytes: &mut Vec<u8>) {
-    let checksum = adler32::adler32(&bytes[..]).expect("Never fails");
-    let mut trailer = [0; 5]; // TODO: フォーマットを文書化
-    BigEndian::write_u32(&mut trailer[..], checksum);
+    let checksum = FRAGMENT_CHECKSUM.digest(&bytes[..]);
+    let mut trailer = [0; CHECKSUM_TRAILER_SIZE];
+    trailer[..checksum.as_bytes().len()].copy_from_slice(checksum.as_bytes());
     bytes.extend_from_slice(&trailer[..]);
 }
 
+/// `parts`を連結した上で末尾にチェックサムを付与した、ストレージに格納するためのデータを生成する。
+///
+/// 内容はチェックサムの分も含めて一度に確保された領域に一度だけ複製され、
+/// 生成されたデータの領域は`clone`しても共有されるので、同じ内容を複数のメンバに送る際にも再び複製されることはない。
+pub(crate) fn lump_data_with_checksum(parts: &[&[u8]]) -> Result<LumpData> {
+    let len = parts.iter().map(|p| p.len()).sum::<usize>();
+    let mut data =
+        track!(LumpData::aligned_allocate(len + CHECKSUM_TRAILER_SIZE).map_err(Error::from))?;
+    {
+        let bytes = data.as_bytes_mut();
+        let mut offset = 0;
+        for part in parts {
+            bytes[offset..offset + part.len()].copy_from_slice(part);
+            offset += part.len();
+        }
+        let checksum = FRAGMENT_CHECKSUM.digest(&bytes[..offset]);
+        let (checksum_bytes, padding) = bytes[offset..].split_at_mut(checksum.as_bytes().len());
+        checksum_bytes.copy_from_slice(checksum.as_bytes());
+        for b in padding {
+            *b = 0;
+        }
+    }
+    Ok(data)
+}
+
 pub(crate) fn verify_and_remove_checksum(bytes: &mut Vec<u8>) -> Result<()> {
-    track_assert!(bytes.len() >= 5, ErrorKind::Invalid);
-    let split_pos = bytes.len() - 5;
+    track_assert!(bytes.len() >= CHECKSUM_TRAILER_SIZE, ErrorKind::Invalid);
+    let split_pos = bytes.len() - CHECKSUM_TRAILER_SIZE;
 
-    let checksum = adler32::adler32(&bytes[..split_pos]).expect("Never fails");
-    let expected = BigEndian::read_u32(&bytes[split_pos..]);
-    track_assert_eq!(checksum, expected, ErrorKind::Invalid);
+    let checksum = FRAGMENT_CHECKSUM.digest(&bytes[..split_pos]);
+    let expected = &bytes[split_pos..split_pos + checksum.as_bytes().len()];
+    track_assert_eq!(checksum.as_bytes(), expected, ErrorKind::Invalid);
 
     bytes.truncate(split_pos);
     Ok(())
 }
 
+/// オブジェクトから切り出された範囲のデータ。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentRange {
+    /// 切り出されたデータ。
+    pub content: Vec<u8>,
+
+    /// 切り出し元のオブジェクト全体のバイト数。
+    pub object_size: u64,
+}
+
+/// `content`から`range`の範囲を切り出す。
+///
+/// 範囲が`content`の末尾を超える場合には、末尾までが返される。
+pub(crate) fn slice_content(mut content: Vec<u8>, range: &Range<u64>) -> ContentRange {
+    let object_size = content.len() as u64;
+    let end = cmp::min(range.end, object_size) as usize;
+    let start = cmp::min(range.start, end as u64) as usize;
+    content.truncate(end);
+    content.drain(..start);
+    ContentRange {
+        content,
+        object_size,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use config::{ClusterConfig, ClusterMember};
+    use config::{ClusterConfig, ClusterMember, FragmentFanOut};
     use rustracing_jaeger::Span;
     use test_util::tests::{setup_system, wait, System};
     use trackable::result::TestResult;
@@ -249,9 +721,40 @@ mod tests {
             .unwrap()
     }
 
+    #[test]
+    fn slice_content_works() {
+        let content = (0..10).collect::<Vec<u8>>();
+        assert_eq!(
+            slice_content(content.clone(), &(2..5)).content,
+            vec![2, 3, 4]
+        );
+        assert_eq!(
+            slice_content(content.clone(), &(8..100)).content,
+            vec![8, 9]
+        );
+
+        let range = slice_content(content, &(20..30));
+        assert!(range.content.is_empty());
+        assert_eq!(range.object_size, 10);
+    }
+
+    #[test]
+    fn lump_data_with_checksum_works() -> TestResult {
+        let data = track!(lump_data_with_checksum(&[b"foo", b"", b"bar"]))?;
+        assert_eq!(data.as_bytes().len(), 6 + CHECKSUM_TRAILER_SIZE);
+
+        let mut bytes = data.as_bytes().to_vec();
+        let mut expected = b"foobar".to_vec();
+        append_checksum(&mut expected);
+        assert_eq!(bytes, expected);
+        track!(verify_and_remove_checksum(&mut bytes))?;
+        assert_eq!(bytes, b"foobar");
+        Ok(())
+    }
+
     #[test]
     fn put_all_new_works() -> TestResult {
-        let metrics = track!(PutAllMetrics::new("test_client"))?;
+        let metrics = track!(PutAllMetrics::new("test_client", &MetricLabels::default()))?;
         let futures: Vec<BoxFuture<_>> = vec![];
         assert!(PutAll::new(metrics.clone(), futures.into_iter(), 2).is_err());
 
@@ -283,12 +786,66 @@ mod tests {
             Box::new(futures::future::ok(())),
             Box::new(futures::future::err(ErrorKind::Other.into())),
         ];
-        let metrics = track!(PutAllMetrics::new("test_client"))?;
+        let metrics = track!(PutAllMetrics::new("test_client", &MetricLabels::default()))?;
         let put = track!(PutAll::new(metrics, futures.into_iter(), 2))?;
         assert!(wait(put).is_err());
         Ok(())
     }
 
+    #[test]
+    fn put_all_reports_failed_fragments() -> TestResult {
+        let futures: Vec<BoxFuture<_>> = vec![
+            Box::new(futures::future::err(ErrorKind::Other.into())),
+            Box::new(futures::future::ok(())),
+            Box::new(futures::future::ok(())),
+        ];
+        let metrics = track!(PutAllMetrics::new("test_client", &MetricLabels::default()))?;
+        let put = track!(PutAll::new(metrics, futures.into_iter(), 2))?;
+        let durability = track!(wait(put))?;
+        assert!(durability.is_degraded());
+        assert_eq!(durability.failed_fragments, 1);
+
+        let futures: Vec<BoxFuture<_>> = vec![
+            Box::new(futures::future::ok(())),
+            Box::new(futures::future::ok(())),
+        ];
+        let metrics = track!(PutAllMetrics::new("test_client", &MetricLabels::default()))?;
+        let put = track!(PutAll::new(metrics, futures.into_iter(), 2))?;
+        assert!(!track!(wait(put))?.is_degraded());
+        Ok(())
+    }
+
+    #[test]
+    fn put_all_waits_for_all_writes_if_requested() -> TestResult {
+        let make_futures = || -> Vec<BoxFuture<_>> {
+            vec![
+                Box::new(futures::future::ok(())),
+                Box::new(futures::future::ok(())),
+                Box::new(futures::future::err(ErrorKind::Other.into())),
+            ]
+        };
+        let metrics = track!(PutAllMetrics::new("test_client", &MetricLabels::default()))?;
+        let put =
+            track!(PutAll::new(metrics.clone(), make_futures().into_iter(), 2))?.wait_all(true);
+        assert_eq!(track!(wait(put))?.failed_fragments, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn put_all_counts_unfinished_writes_as_failed() -> TestResult {
+        let futures: Vec<BoxFuture<_>> = vec![
+            Box::new(futures::future::ok(())),
+            Box::new(futures::future::ok(())),
+            Box::new(futures::future::empty()),
+        ];
+        let metrics = track!(PutAllMetrics::new("test_client", &MetricLabels::default()))?;
+        let put = track!(PutAll::new(metrics, futures.into_iter(), 2))?;
+        let durability = track!(wait(put))?;
+        assert!(durability.is_degraded());
+        assert_eq!(durability.failed_fragments, 1);
+        Ok(())
+    }
+
     #[test]
     fn put_all_fails_even_if_last_operation_succeeds() -> TestResult {
         let futures: Vec<BoxFuture<_>> = vec![
@@ -296,12 +853,116 @@ mod tests {
             Box::new(futures::future::err(ErrorKind::Other.into())),
             Box::new(futures::future::ok(())),
         ];
-        let metrics = track!(PutAllMetrics::new("test_client"))?;
+        let metrics = track!(PutAllMetrics::new("test_client", &MetricLabels::default()))?;
         let put = track!(PutAll::new(metrics, futures.into_iter(), 2))?;
         assert!(wait(put).is_err());
         Ok(())
     }
 
+    #[test]
+    fn hedge_works() -> TestResult {
+        let metrics = track!(HedgeMetrics::new("test_client", &MetricLabels::default()))?;
+        let mut config = HedgeConfig::default();
+        assert!(Hedge::start(&config, &metrics).is_none());
+
+        // 待ち時間が0の場合には、最初の確認時に追加の要求が必要となる
+        config.enabled = true;
+        config.delay = Duration::from_millis(0);
+        config.extra_requests = 2;
+        let mut hedge = Hedge::start(&config, &metrics).unwrap();
+        assert_eq!(hedge.poll_extra_requests(), 2);
+
+        // 追加の要求が必要になるのは一度だけ
+        assert_eq!(hedge.poll_extra_requests(), 0);
+
+        // 待ち時間が経過するまでは、追加の要求は不要
+        config.delay = Duration::from_secs(3600);
+        let mut hedge = Hedge::start(&config, &metrics).unwrap();
+        assert_eq!(hedge.poll_extra_requests(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn it_gets_data_with_hedged_reads() -> TestResult {
+        let mut system = System::new(4, 1)?;
+        let (_members, client) = setup_system(&mut system, 5)?;
+        let version = ObjectVersion(1);
+        let expected = vec![0x05; 1024];
+        wait(client.storage.clone().put(
+            version,
+            Bytes::from(expected.clone()),
+            Deadline::Infinity,
+            Span::inactive().handle(),
+        ))?;
+
+        // 待ち時間を 0 にして、最初の確認時に必ず追加の要求が発行されるようにする
+        // (必要な数を超える断片が同時に届いても、取得は成功する)
+        let hedged_client = system.make_segment_client_with(|config| {
+            config.dispersed_client.hedge = HedgeConfig {
+                enabled: true,
+                delay: Duration::from_millis(0),
+                extra_requests: 1,
+            };
+        })?;
+        let actual = wait(hedged_client.storage.get(
+            ObjectValue {
+                version,
+                content: expected.clone(),
+            },
+            Deadline::Infinity,
+            Span::inactive().handle(),
+            RequestBudget::unlimited(),
+        ))?;
+        assert_eq!(expected, actual);
+        Ok(())
+    }
+
+    #[test]
+    fn it_falls_back_to_parity_fragments() -> TestResult {
+        let mut system = System::new(4, 1)?;
+        let (members, client) = setup_system(&mut system, 5)?;
+        let version = ObjectVersion(1);
+        let expected = vec![0x07; 1024];
+        wait(client.storage.clone().put(
+            version,
+            Bytes::from(expected.clone()),
+            Deadline::Infinity,
+            Span::inactive().handle(),
+        ))?;
+
+        // 先頭のデータ断片を削除して、パリティ断片の読み込みが必要な状態にする
+        let first = system.cluster_config().candidates(version).next().cloned();
+        let first = track_assert_some!(first, ErrorKind::Other);
+        let device = members
+            .iter()
+            .find(|m| m.0 == first.node)
+            .map(|m| m.2.clone());
+        let device = track_assert_some!(device, ErrorKind::Other);
+        assert!(wait(
+            device
+                .request()
+                .delete(first.make_lump_id(version))
+                .map_err(Error::from)
+        )?);
+
+        for fan_out in [FragmentFanOut::Adaptive, FragmentFanOut::All] {
+            let client = system.make_segment_client_with(|config| {
+                config.dispersed_client.fan_out = fan_out;
+            })?;
+            let actual = wait(client.storage.get(
+                ObjectValue {
+                    version,
+                    content: expected.clone(),
+                },
+                Deadline::Infinity,
+                Span::inactive().handle(),
+                RequestBudget::unlimited(),
+            ))?;
+            assert_eq!(expected, actual);
+        }
+        Ok(())
+    }
+
     #[test]
     fn it_puts_data_correctly() -> TestResult {
         let data_fragments = 4;
@@ -315,7 +976,7 @@ mod tests {
 
         wait(storage_client.clone().put(
             version,
-            expected.clone(),
+            Bytes::from(expected.clone()),
             Deadline::Infinity,
             Span::inactive().handle(),
         ))?;
@@ -326,6 +987,7 @@ mod tests {
             },
             Deadline::Infinity,
             Span::inactive().handle(),
+            RequestBudget::unlimited(),
         ))?;
 
         assert_eq!(expected, actual);
@@ -361,7 +1023,7 @@ mod tests {
 
         wait(storage_client.clone().put(
             version,
-            expected.clone(),
+            Bytes::from(expected.clone()),
             Deadline::Infinity,
             Span::inactive().handle(),
         ))?;
@@ -408,7 +1070,7 @@ mod tests {
 
         wait(storage_client.clone().put(
             version,
-            expected.clone(),
+            Bytes::from(expected.clone()),
             Deadline::Infinity,
             Span::inactive().handle(),
         ))?;
@@ -0,0 +1,291 @@
+//! セグメント内のオブジェクトの変更を監視するためのストリーム。
+//!
+//! MDS のリーダが保持している変更履歴を定期的に取得し、コミットされた順に変更を生成する。
+use fibers::time::timer::{self, Timeout};
+use frugalos_mds::{ObjectChange, ObjectChanges};
+use futures::{Async, Future, Poll, Stream};
+use libfrugalos::entity::object::ObjectVersion;
+use std::cmp;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use super::mds::MdsClient;
+use {Error, ErrorKind};
+
+/// 一度の要求で取得する変更の最大数。
+const MAX_CHANGES_PER_REQUEST: u64 = 1000;
+
+/// 新しい変更がなかった場合に、次に取得を試みるまでの間隔のデフォルト値。
+const DEFAULT_POLLING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// セグメント内のオブジェクトの変更(追加と削除)を、コミットされた順に生成するストリーム。
+///
+/// 監視を中断した場合には、`position`の値を`Client::watch`に渡すことで、その続きから再開できる。
+/// 再開位置の直後からの変更の一部が MDS に保持されていなかった場合には、
+/// `ErrorKind::ChangesLost`のエラーで終了する。
+///
+/// これは無限ストリームであり、エラー時を除いて自発的に終了することはない。
+pub struct Watch {
+    mds: MdsClient,
+    position: Option<ObjectVersion>,
+    polling_interval: Duration,
+    max_polling_interval: Duration,
+    idle_interval: Duration,
+    changes: VecDeque<ObjectChange>,
+    fetching: Option<Box<dyn Future<Item = ObjectChanges, Error = Error> + Send + 'static>>,
+    timeout: Option<Timeout>,
+}
+impl Watch {
+    pub(crate) fn new(mds: MdsClient, after: Option<ObjectVersion>) -> Self {
+        Watch {
+            mds,
+            position: after,
+            polling_interval: DEFAULT_POLLING_INTERVAL,
+            max_polling_interval: DEFAULT_POLLING_INTERVAL,
+            idle_interval: DEFAULT_POLLING_INTERVAL,
+            changes: VecDeque::new(),
+            fetching: None,
+            timeout: None,
+        }
+    }
+
+    /// 新しい変更がなかった場合に、次に取得を試みるまでの間隔を設定する。
+    pub fn polling_interval(mut self, interval: Duration) -> Self {
+        self.polling_interval = interval;
+        self.max_polling_interval = cmp::max(self.max_polling_interval, interval);
+        self.idle_interval = interval;
+        self
+    }
+
+    /// 新しい変更がない状態が続いた場合の、取得間隔の上限を設定する。
+    ///
+    /// 変更がなかった場合の間隔は、`polling_interval`から始めて、連続する度に倍になり、この値で頭打ちとなる。
+    /// 変更を取得すると`polling_interval`に戻る。
+    /// デフォルトでは`polling_interval`と同じ値(i.e., 間隔を延ばさない)となる。
+    pub fn max_polling_interval(mut self, interval: Duration) -> Self {
+        self.max_polling_interval = cmp::max(self.polling_interval, interval);
+        self
+    }
+
+    /// 既に生成した変更の位置を返す。
+    ///
+    /// 監視の開始直後で、まだ MDS から現在の位置を取得していない場合には`None`となる。
+    /// なお、一つのコマンドによる変更群の途中で中断した場合には、再開時にその一部が重複して生成される。
+    pub fn position(&self) -> Option<ObjectVersion> {
+        self.changes
+            .front()
+            .map(|c| ObjectVersion(c.position.0 - 1))
+            .or(self.position)
+    }
+
+    fn handle_changes(&mut self, changes: ObjectChanges) -> Result<(), Error> {
+        if changes.lost {
+            track_panic!(
+                ErrorKind::ChangesLost,
+                "Some changes after {:?} are no longer retained by MDS",
+                self.position
+            );
+        }
+        if changes.changes.is_empty() {
+            self.timeout = Some(timer::timeout(self.idle_interval));
+            self.idle_interval = next_idle_interval(self.idle_interval, self.max_polling_interval);
+        } else {
+            self.idle_interval = self.polling_interval;
+        }
+        self.changes.extend(changes.changes);
+        self.position = Some(changes.next);
+        Ok(())
+    }
+}
+impl Stream for Watch {
+    type Item = ObjectChange;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(change) = self.changes.pop_front() {
+                return Ok(Async::Ready(Some(change)));
+            }
+            if let Some(mut future) = self.fetching.take() {
+                match track!(future.poll())? {
+                    Async::NotReady => {
+                        self.fetching = Some(future);
+                        return Ok(Async::NotReady);
+                    }
+                    Async::Ready(changes) => {
+                        track!(self.handle_changes(changes))?;
+                        continue;
+                    }
+                }
+            }
+            if let Some(mut timeout) = self.timeout.take() {
+                if let Async::NotReady = track!(timeout.poll().map_err(Error::from))? {
+                    self.timeout = Some(timeout);
+                    return Ok(Async::NotReady);
+                }
+            }
+            let future = self.mds.watch(self.position, MAX_CHANGES_PER_REQUEST);
+            self.fetching = Some(Box::new(future));
+        }
+    }
+}
+
+fn next_idle_interval(current: Duration, max: Duration) -> Duration {
+    cmp::min(current.checked_mul(2).unwrap_or(max), max)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use cannyls::deadline::Deadline;
+    use fibers_global;
+    use frugalos_mds::ObjectChangeKind;
+    use libfrugalos::expect::Expect;
+    use rustracing_jaeger::Span;
+    use std::thread;
+    use trackable::result::TestResult;
+
+    use super::*;
+    use test_util::tests::{setup_system, wait, System};
+    use Result;
+
+    fn next_change(watch: Watch) -> Result<(ObjectChange, Watch)> {
+        match fibers_global::execute(watch.into_future()) {
+            Ok((change, watch)) => Ok((change.expect("Never fails"), watch)),
+            Err((e, _)) => Err(track!(e)),
+        }
+    }
+
+    #[test]
+    fn watch_works() -> TestResult {
+        let mut system = System::new(2, 1)?;
+        let segment_size = system.fragments() as usize;
+        let (_members, client) = setup_system(&mut system, segment_size)?;
+        thread::spawn(move || loop {
+            system.executor.run_once().unwrap();
+            thread::sleep(Duration::from_micros(100));
+        });
+
+        // wait until the segment becomes stable (see `head_work_but_get_doesnt`)
+        thread::sleep(Duration::from_secs(5));
+
+        let put = |id: &str| {
+            wait(client.put(
+                id.to_owned(),
+                Bytes::from(vec![0x01]),
+                Deadline::Infinity,
+                Expect::Any.into(),
+                Span::inactive().handle(),
+            ))
+            .map(|(version, _, _)| version)
+        };
+        let foo = put("foo")?;
+        let bar = put("bar")?;
+        let deleted = wait(client.delete(
+            "foo".to_owned(),
+            Deadline::Infinity,
+            Expect::Any.into(),
+            Span::inactive().handle(),
+        ))?;
+        assert_eq!(deleted, Some(foo));
+
+        let mut watch = client
+            .watch(Some(ObjectVersion(0)))
+            .polling_interval(Duration::from_millis(10));
+        let mut changes = Vec::new();
+        for _ in 0..3 {
+            let (change, next) = next_change(watch)?;
+            changes.push(change);
+            watch = next;
+        }
+        let actual = changes
+            .iter()
+            .map(|c| (c.kind, c.object_id.as_str(), c.version))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actual,
+            vec![
+                (ObjectChangeKind::Put, "foo", foo),
+                (ObjectChangeKind::Put, "bar", bar),
+                (ObjectChangeKind::Delete, "foo", foo),
+            ]
+        );
+        assert!(watch.position() >= Some(changes[2].position));
+
+        // 途中の位置から再開できる
+        let watch = client.watch(Some(changes[0].position));
+        let (change, _) = next_change(watch)?;
+        assert_eq!(change, changes[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn watch_fails_if_changes_are_lost() -> TestResult {
+        let system = System::new(2, 1)?;
+        let client = system.make_segment_client()?;
+        let mut watch = client.watch(Some(ObjectVersion(3)));
+        let e = watch
+            .handle_changes(ObjectChanges {
+                changes: Vec::new(),
+                next: ObjectVersion(10),
+                lost: true,
+            })
+            .err()
+            .expect("Never fails");
+        assert!(matches!(*e.kind(), ErrorKind::ChangesLost));
+        assert_eq!(watch.position(), Some(ObjectVersion(3)));
+        Ok(())
+    }
+
+    #[test]
+    fn watch_backs_off_while_idle() -> TestResult {
+        let system = System::new(2, 1)?;
+        let client = system.make_segment_client()?;
+        let mut watch = client
+            .watch(None)
+            .polling_interval(Duration::from_secs(1))
+            .max_polling_interval(Duration::from_secs(3));
+        assert_eq!(watch.position(), None);
+
+        let empty = |next| ObjectChanges {
+            changes: Vec::new(),
+            next: ObjectVersion(next),
+            lost: false,
+        };
+        track!(watch.handle_changes(empty(5)))?;
+        assert!(watch.timeout.is_some());
+        assert_eq!(watch.idle_interval, Duration::from_secs(2));
+        assert_eq!(watch.position(), Some(ObjectVersion(5)));
+
+        track!(watch.handle_changes(empty(5)))?;
+        track!(watch.handle_changes(empty(5)))?;
+        assert_eq!(watch.idle_interval, Duration::from_secs(3));
+
+        // 変更を取得すると、間隔は元に戻る
+        let change = ObjectChange {
+            position: ObjectVersion(6),
+            kind: ObjectChangeKind::Put,
+            object_id: "foo".to_owned(),
+            version: ObjectVersion(6),
+        };
+        track!(watch.handle_changes(ObjectChanges {
+            changes: vec![change],
+            next: ObjectVersion(6),
+            lost: false,
+        }))?;
+        assert_eq!(watch.idle_interval, Duration::from_secs(1));
+        assert_eq!(watch.position(), Some(ObjectVersion(5)));
+        Ok(())
+    }
+
+    #[test]
+    fn next_idle_interval_works() {
+        let max = Duration::from_secs(5);
+        assert_eq!(
+            next_idle_interval(Duration::from_secs(1), max),
+            Duration::from_secs(2)
+        );
+        assert_eq!(next_idle_interval(Duration::from_secs(4), max), max);
+        assert_eq!(next_idle_interval(max, max), max);
+    }
+}
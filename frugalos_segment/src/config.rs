@@ -1,17 +1,36 @@
 //! セグメント構成に関係する構造体等。
 use byteorder::{BigEndian, ByteOrder};
+use cannyls::deadline::Deadline;
 use cannyls::lump::LumpId;
 use fibers_rpc::client::Options as RpcOptions;
+use frugalos_mds::AccessMode;
 use frugalos_raft::NodeId;
 use libfrugalos::entity::object::ObjectVersion;
 use libfrugalos::time::Seconds;
 use raftlog::cluster::ClusterMembers;
 use siphasher::sip::SipHasher;
+use std::cmp;
+use std::collections::BTreeMap;
 use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 
+pub use frugalos_core::erasure_coding::{
+    ErasureCoding, ErasureCodingBackend, ErasureCodingChecksum,
+};
+
+use client::cache::ContentCacheSizing;
+use client::ec::SharedErasureCoding;
+use client::ec_pool::ErasureCodingPool;
+use encryption::{ContentEncryption, EnvKeyProvider, FileKeyProvider, KeyProvider};
+use feature::{Feature, FeatureFlags};
+use maintenance::MaintenanceSchedule;
+
 // TODO: LumpIdの名前空間の使い方に関してWikiに記載する
 pub(crate) const LUMP_NAMESPACE_CONTENT: u8 = 1;
+pub(crate) const LUMP_NAMESPACE_QUEUE_SNAPSHOT: u8 = 2;
 
 /// Raftクラスタ(i.e., セグメント)内のメンバ情報。
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -40,6 +59,24 @@ pub(crate) fn make_lump_id(node: &NodeId, version: ObjectVersion) -> LumpId {
     LumpId::new(BigEndian::read_u128(&id[..]))
 }
 
+/// 対象ノードがオブジェクトの保存に使用する`LumpId`の範囲を返す。
+pub(crate) fn content_lump_id_range(node: &NodeId) -> Range<LumpId> {
+    let start = make_lump_id(node, ObjectVersion(0));
+    // NOTE: `id[7]`は使用されないので、そこを繰り上げた値が範囲の終端となる
+    let end = LumpId::new(start.as_u128() + (1 << 64));
+    Range { start, end }
+}
+
+/// 対象ノードの同期処理用キューの内容を保存する際に使用する`LumpId`を返す。
+///
+/// `make_lump_id`とは名前空間が異なるので、オブジェクトの`LumpId`と衝突することはない。
+pub(crate) fn make_queue_snapshot_lump_id(node: &NodeId) -> LumpId {
+    let mut id = [0; 16];
+    (&mut id[0..7]).copy_from_slice(node.local_id.as_slice());
+    id[0] = LUMP_NAMESPACE_QUEUE_SNAPSHOT;
+    LumpId::new(BigEndian::read_u128(&id[..]))
+}
+
 pub(crate) fn get_object_version_from_lump_id(lump_id: LumpId) -> ObjectVersion {
     let mut id = [0; 16];
     BigEndian::write_u128(&mut id, lump_id.as_u128());
@@ -145,6 +182,36 @@ pub struct MdsClientConfig {
     /// Request policy for mds head requests.
     #[serde(default)]
     pub head_request_policy: MdsRequestPolicy,
+
+    /// Maximum replication lag (in log entries) allowed for `ReadConsistency::Stale` reads.
+    ///
+    /// If set, stale GET/HEAD/LIST requests are served by followers whose applied log is within
+    /// this many entries of the leader's commit index, instead of by the leader.
+    #[serde(default)]
+    pub stale_read_max_lag: Option<u64>,
+
+    /// Maximum number of objects whose metadata is cached by the client of each segment
+    /// (`0` disables the cache).
+    ///
+    /// The cache is consulted only for `ReadConsistency::Stale` and `ReadConsistency::Subset` reads.
+    #[serde(default)]
+    pub metadata_cache_capacity: usize,
+
+    /// Maximum approximate total bytes of the metadata cached by the client of each segment.
+    ///
+    /// Least recently used entries are evicted when either this or `metadata_cache_capacity` is exceeded.
+    #[serde(default = "default_mds_client_metadata_cache_capacity_bytes")]
+    pub metadata_cache_capacity_bytes: u64,
+
+    /// How long a cached metadata entry may be served without asking MDS.
+    ///
+    /// Entries are also invalidated by writes through this client and by watched MDS events.
+    #[serde(
+        rename = "metadata_cache_ttl_millis",
+        default = "default_mds_client_metadata_cache_ttl",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub metadata_cache_ttl: Duration,
 }
 
 fn default_mds_client_request_timeout() -> Duration {
@@ -159,10 +226,22 @@ impl Default for MdsClientConfig {
             default_request_policy: Default::default(),
             get_request_policy: Default::default(),
             head_request_policy: Default::default(),
+            stale_read_max_lag: None,
+            metadata_cache_capacity: 0,
+            metadata_cache_capacity_bytes: default_mds_client_metadata_cache_capacity_bytes(),
+            metadata_cache_ttl: default_mds_client_metadata_cache_ttl(),
         }
     }
 }
 
+fn default_mds_client_metadata_cache_capacity_bytes() -> u64 {
+    4 * 1024 * 1024
+}
+
+fn default_mds_client_metadata_cache_ttl() -> Duration {
+    Duration::from_secs(1)
+}
+
 fn default_mds_client_put_content_timeout() -> Seconds {
     Seconds(60)
 }
@@ -187,6 +266,14 @@ pub struct DispersedClientConfig {
     )]
     pub head_timeout: Duration,
 
+    /// Configuration for hedged reads.
+    #[serde(default)]
+    pub hedge: HedgeConfig,
+
+    /// Which fragments to read on a get operation.
+    #[serde(default)]
+    pub fan_out: FragmentFanOut,
+
     /// Configuration for `CannyLsClient`.
     #[serde(flatten)]
     pub cannyls: CannyLsClientConfig,
@@ -197,11 +284,30 @@ impl Default for DispersedClientConfig {
         DispersedClientConfig {
             get_timeout: default_dispersed_client_get_timeout(),
             head_timeout: default_dispersed_client_head_timeout(),
+            hedge: Default::default(),
+            fan_out: Default::default(),
             cannyls: Default::default(),
         }
     }
 }
 
+/// 分散オブジェクトの取得時に、どの断片に読み込み要求を発行するか。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FragmentFanOut {
+    /// まずはデータ断片のみを読み込み、
+    /// いずれかの取得に失敗した(またはタイムアウトした)場合にだけパリティ断片を読み込む。
+    ///
+    /// データ断片のみが揃った場合には、復号の負荷も小さくなる。
+    #[default]
+    Adaptive,
+
+    /// 全ての断片に同時に読み込み要求を発行し、先に揃った断片群を使用する。
+    ///
+    /// ネットワーク帯域とディスク I/O を多く消費する代わりに、遅延の大きいメンバの影響を受けにくい。
+    All,
+}
+
 fn default_dispersed_client_get_timeout() -> Duration {
     Duration::from_secs(2)
 }
@@ -213,21 +319,687 @@ fn default_dispersed_client_head_timeout() -> Duration {
 /// Configuration for `ReplicatedClient`.
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, Default)]
 pub struct ReplicatedClientConfig {
+    /// Configuration for hedged reads.
+    #[serde(default)]
+    pub hedge: HedgeConfig,
+
     /// Configuration for `CannyLsClient`.
     #[serde(flatten)]
     pub cannyls: CannyLsClientConfig,
 }
 
+/// オブジェクトの取得時の hedged read に関する設定。
+///
+/// 有効な場合には、最初の読み込み要求を発行してから`delay`が経過しても取得が完了していなければ、
+/// 他のメンバにも(最大`extra_requests`個の)読み込み要求を発行して、先に届いた応答を使用する。
+/// 一部のディスクの遅延によるテールレイテンシの悪化を抑えられる代わりに、ディスク I/O は増加する。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HedgeConfig {
+    /// hedged read を行うかどうか。
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// 追加の読み込み要求を発行するまでの待ち時間。
+    #[serde(
+        rename = "delay_millis",
+        default = "default_hedge_delay",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub delay: Duration,
+
+    /// 追加で発行する読み込み要求の最大数。
+    #[serde(default = "default_hedge_extra_requests")]
+    pub extra_requests: usize,
+}
+impl Default for HedgeConfig {
+    fn default() -> Self {
+        HedgeConfig {
+            enabled: false,
+            delay: default_hedge_delay(),
+            extra_requests: default_hedge_extra_requests(),
+        }
+    }
+}
+
+fn default_hedge_delay() -> Duration {
+    Duration::from_millis(50)
+}
+
+fn default_hedge_extra_requests() -> usize {
+    1
+}
+
+/// ストレージの各メンバの健全性の追跡と、それに基づく読み込み先の選択に関する設定。
+///
+/// 各メンバへの読み込み要求のエラー率と応答時間は、指数移動平均で記録される。
+/// エラー率が`ejection_threshold`以上になったメンバは、`ejection_duration`の間は
+/// 読み込み先の候補の末尾に回される(他の候補で足りない場合にのみ使用される)。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemberHealthConfig {
+    /// 読み込み先の選択に、各メンバの健全性を考慮するかどうか。
+    ///
+    /// `false`の場合でも、健全性の記録自体は行われる。
+    #[serde(default = "default_member_health_enabled")]
+    pub enabled: bool,
+
+    /// メンバを候補から外す(後回しにする)エラー率の閾値(`0.0`から`1.0`の範囲)。
+    #[serde(default = "default_member_health_ejection_threshold")]
+    pub ejection_threshold: f64,
+
+    /// 候補から外したメンバを、再び通常の候補として扱うまでの時間。
+    #[serde(
+        rename = "ejection_duration_millis",
+        default = "default_member_health_ejection_duration",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub ejection_duration: Duration,
+}
+impl Default for MemberHealthConfig {
+    fn default() -> Self {
+        MemberHealthConfig {
+            enabled: default_member_health_enabled(),
+            ejection_threshold: default_member_health_ejection_threshold(),
+            ejection_duration: default_member_health_ejection_duration(),
+        }
+    }
+}
+
+fn default_member_health_enabled() -> bool {
+    true
+}
+
+fn default_member_health_ejection_threshold() -> f64 {
+    0.5
+}
+
+fn default_member_health_ejection_duration() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// ストレージの各メンバ(デバイス)毎のサーキットブレーカの設定。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitBreakerConfig {
+    /// サーキットブレーカを有効にするかどうか。
+    #[serde(default = "default_circuit_breaker_enabled")]
+    pub enabled: bool,
+
+    /// 回路を開く(メンバを読み込み先の候補の末尾に回す)までに許容する、連続した読み込みの失敗数。
+    #[serde(default = "default_circuit_breaker_failure_threshold")]
+    pub failure_threshold: u32,
+
+    /// 回路を開いてから、試験的な読み込み要求を許可するまでの時間。
+    ///
+    /// 試験的な要求の応答をこの時間以上待っている場合には、次の試験的な要求が許可される。
+    #[serde(
+        rename = "cooldown_millis",
+        default = "default_circuit_breaker_cooldown",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub cooldown: Duration,
+}
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            enabled: default_circuit_breaker_enabled(),
+            failure_threshold: default_circuit_breaker_failure_threshold(),
+            cooldown: default_circuit_breaker_cooldown(),
+        }
+    }
+}
+
+fn default_circuit_breaker_enabled() -> bool {
+    true
+}
+
+fn default_circuit_breaker_failure_threshold() -> u32 {
+    5
+}
+
+fn default_circuit_breaker_cooldown() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// 一つの読み込み要求が消費できる資源の上限。
+///
+/// 巨大なオブジェクトや極端な範囲指定を含む要求によって、ノード全体が不安定になることを防ぐ。
+/// 上限を超えた要求は`ErrorKind::BudgetExceeded`で失敗する。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestBudgetConfig {
+    /// 復号のためにメモリ上に保持する、断片(または複製)の合計バイト数の上限。
+    #[serde(default = "default_request_budget_max_decode_bytes")]
+    pub max_decode_bytes: u64,
+
+    /// 発行する断片の取得要求の数の上限。
+    ///
+    /// 再試行時や hedged read による追加の要求も含まれる。
+    #[serde(default = "default_request_budget_max_fanout")]
+    pub max_fanout: usize,
+
+    /// 要求の処理に掛けられる時間の上限。
+    ///
+    /// 要求毎に指定されるデッドラインとは異なり、再試行等を含めた全体の処理時間に適用される。
+    #[serde(
+        rename = "max_duration_millis",
+        default = "default_request_budget_max_duration",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub max_duration: Duration,
+}
+impl Default for RequestBudgetConfig {
+    fn default() -> Self {
+        RequestBudgetConfig {
+            max_decode_bytes: default_request_budget_max_decode_bytes(),
+            max_fanout: default_request_budget_max_fanout(),
+            max_duration: default_request_budget_max_duration(),
+        }
+    }
+}
+
+fn default_request_budget_max_decode_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_request_budget_max_fanout() -> usize {
+    256
+}
+
+fn default_request_budget_max_duration() -> Duration {
+    Duration::from_secs(600)
+}
+
+/// 読み込んだオブジェクトの内容を保持するキャッシュの設定。
+///
+/// キャッシュはセグメント毎に作られ、容量はバケツの種類毎に指定する。
+/// 容量は実行中に`ContentCacheSizing`を通して変更できる。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentCacheConfig {
+    /// 複製を保存するバケツで、セグメント当たりに使用するキャッシュの容量(バイト)。
+    ///
+    /// `0` の場合にはキャッシュを使用しない。
+    #[serde(rename = "replicated_capacity_bytes", default)]
+    pub replicated_capacity: u64,
+
+    /// Erasure Coding を用いるバケツで、セグメント当たりに使用するキャッシュの容量(バイト)。
+    ///
+    /// `0` の場合にはキャッシュを使用しない。
+    #[serde(rename = "dispersed_capacity_bytes", default)]
+    pub dispersed_capacity: u64,
+
+    /// このサイズ(バイト)を超えるオブジェクトはキャッシュしない。
+    #[serde(
+        rename = "max_entry_bytes",
+        default = "default_content_cache_max_entry_size"
+    )]
+    pub max_entry_size: u64,
+
+    /// アクセス頻度の推定に用いる標本の大きさ。
+    ///
+    /// この回数だけアクセスを記録する毎に、それまでの記録の重みを半分にする。
+    #[serde(default = "default_content_cache_frequency_sample_size")]
+    pub frequency_sample_size: u64,
+}
+impl Default for ContentCacheConfig {
+    fn default() -> Self {
+        ContentCacheConfig {
+            replicated_capacity: 0,
+            dispersed_capacity: 0,
+            max_entry_size: default_content_cache_max_entry_size(),
+            frequency_sample_size: default_content_cache_frequency_sample_size(),
+        }
+    }
+}
+
+fn default_content_cache_max_entry_size() -> u64 {
+    4 * 1024 * 1024
+}
+
+fn default_content_cache_frequency_sample_size() -> u64 {
+    100_000
+}
+
+/// リペアや segment_gc、再配置等の負荷の高いバックグラウンド処理を実行する時間帯(メンテナンスウィンドウ)の設定。
+///
+/// ウィンドウが一つも指定されていない場合には、これらの処理は常に実行される。
+/// 値は構成管理用クラスタで共有されているもの(`frugalos_config::MaintenanceWindows`)から作られる。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaintenanceConfig {
+    /// メンテナンスウィンドウの一覧。
+    ///
+    /// 現在時刻がいずれかのウィンドウに含まれている間だけ、バックグラウンド処理が実行される。
+    pub windows: Vec<MaintenanceWindowConfig>,
+
+    /// スケジュールを解釈する際に用いる UTC からの時差(分)。
+    pub utc_offset_minutes: i32,
+}
+
+/// 一つのメンテナンスウィンドウの設定。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaintenanceWindowConfig {
+    /// ウィンドウの開始時刻を表す cron 形式のスケジュール(分 時 日 月 曜日)。
+    ///
+    /// 例えば`"0 2 * * 1-5"`は、平日の午前二時を表す。
+    pub schedule: String,
+
+    /// ウィンドウの継続時間(分単位に切り上げられる)。
+    pub duration: Duration,
+}
+
+/// Erasure Coding の符号化・復号を実行する専用スレッドプールの設定。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErasureCodingPoolConfig {
+    /// ワーカスレッドの数。
+    ///
+    /// `0` の場合には CPU の数となる。
+    #[serde(default)]
+    pub worker_threads: usize,
+
+    /// プールに投入できる処理(実行中のものも含む)の数の上限。
+    #[serde(default = "default_ec_pool_max_queue_len")]
+    pub max_queue_len: usize,
+
+    /// 投入された処理の数が上限に達している場合の振る舞い。
+    #[serde(default)]
+    pub on_saturation: SaturationPolicy,
+}
+impl Default for ErasureCodingPoolConfig {
+    fn default() -> Self {
+        ErasureCodingPoolConfig {
+            worker_threads: 0,
+            max_queue_len: default_ec_pool_max_queue_len(),
+            on_saturation: SaturationPolicy::default(),
+        }
+    }
+}
+
+fn default_ec_pool_max_queue_len() -> usize {
+    1024
+}
+
+/// 処理の投入先が飽和している場合の振る舞い。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SaturationPolicy {
+    /// 空きができるまで待機する。
+    #[default]
+    Wait,
+
+    /// 待機せずに`ErrorKind::Busy`で失敗する。
+    Reject,
+}
+
+/// バケツ毎の Erasure Coding に関する設定。
+///
+/// 符号化に用いる実装(バックエンドやチェックサム)は、全てのサーバで一致している必要があるので、
+/// この設定ではなく、構成管理用クラスタにバケツの属性として登録する(`BucketAttributes::erasure_coding`)。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErasureCoderConfig {
+    /// このサイズ(バイト)未満のオブジェクトは符号化せずに、全体の複製を各断片の代わりに格納する。
+    ///
+    /// 小さなオブジェクトに対する符号化のオーバヘッドを避けるためのもので、
+    /// `0` の場合には全てのオブジェクトが符号化される。
+    /// 複製か断片かは読み込み時に判別されるので、既存のバケツの設定を変更しても問題はない。
+    #[serde(rename = "replication_threshold_bytes", default)]
+    pub replication_threshold: u64,
+}
+
+/// バケツ毎に使用する `ErasureCoder` の実装を登録しておくための設定。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErasureCodingConfig {
+    /// `buckets` に登録されていないバケツで使用される設定。
+    #[serde(flatten)]
+    pub default: ErasureCoderConfig,
+
+    /// バケツ ID から、そのバケツで使用する設定へのマップ。
+    #[serde(default)]
+    pub buckets: BTreeMap<String, ErasureCoderConfig>,
+}
+impl ErasureCodingConfig {
+    /// 指定されたバケツで使用する設定を返す。
+    pub fn coder_config(&self, bucket_id: &str) -> &ErasureCoderConfig {
+        self.buckets.get(bucket_id).unwrap_or(&self.default)
+    }
+}
+
+/// 各機能フラグの値。
+///
+/// 省略されたフラグには、既存の振る舞いを変えない値が使われる。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeatureFlagSet {
+    /// hedged read を行うかどうか(`HedgeConfig`でも有効にする必要がある)。
+    #[serde(default = "default_feature_enabled")]
+    pub hedged_reads: bool,
+
+    /// 復元に必要な数の書き込みが完了した時点で、書き込みの応答を返すかどうか。
+    #[serde(default = "default_feature_enabled")]
+    pub quorum_put_ack: bool,
+
+    /// 読み込み時に見つかった断片の欠損を、その場でリペアするかどうか。
+    #[serde(default)]
+    pub read_repair: bool,
+}
+impl FeatureFlagSet {
+    /// 指定された機能が有効かどうかを返す。
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::HedgedReads => self.hedged_reads,
+            Feature::QuorumPutAck => self.quorum_put_ack,
+            Feature::ReadRepair => self.read_repair,
+        }
+    }
+
+    /// 指定された機能を有効ないし無効にする。
+    pub fn set(&mut self, feature: Feature, enabled: bool) {
+        match feature {
+            Feature::HedgedReads => self.hedged_reads = enabled,
+            Feature::QuorumPutAck => self.quorum_put_ack = enabled,
+            Feature::ReadRepair => self.read_repair = enabled,
+        }
+    }
+}
+impl Default for FeatureFlagSet {
+    fn default() -> Self {
+        FeatureFlagSet {
+            hedged_reads: default_feature_enabled(),
+            quorum_put_ack: default_feature_enabled(),
+            read_repair: false,
+        }
+    }
+}
+
+fn default_feature_enabled() -> bool {
+    true
+}
+
+/// バケツ毎の機能フラグの初期値。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeatureFlagsConfig {
+    /// `buckets` に登録されていないバケツで使用される値。
+    #[serde(flatten)]
+    pub default: FeatureFlagSet,
+
+    /// バケツ ID から、そのバケツで使用する値へのマップ。
+    #[serde(default)]
+    pub buckets: BTreeMap<String, FeatureFlagSet>,
+}
+impl FeatureFlagsConfig {
+    /// 指定されたバケツの機能フラグの初期値を返す。
+    pub fn flags(&self, bucket_id: &str) -> &FeatureFlagSet {
+        self.buckets.get(bucket_id).unwrap_or(&self.default)
+    }
+}
+
+/// リクエストの優先度クラス。
+///
+/// 優先度はデバイスのキュー内での処理順序を決める `Deadline` に変換される。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RequestPriority {
+    /// 他のリクエストよりも先に処理される。
+    Realtime,
+
+    /// 指定されたデッドラインがそのまま使われる。
+    #[default]
+    Normal,
+
+    /// バッチ処理等の、遅延が許容されるリクエスト。
+    Bulk,
+}
+
+/// リクエストの優先度クラスを `Deadline` に対応付けるための設定。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestPriorityConfig {
+    /// `Bulk` クラスのリクエストに割り当てるデッドラインの下限。
+    ///
+    /// `Deadline::Infinity` を使うと、負荷が高い状況で処理が一向に進まなくなる可能性があるので、
+    /// 十分に長い有限の値を割り当てている。
+    #[serde(
+        rename = "bulk_deadline_millis",
+        default = "default_request_priority_bulk_deadline",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub bulk_deadline: Duration,
+}
+impl RequestPriorityConfig {
+    /// 優先度クラスを考慮した上で、実際にデバイスに渡すデッドラインを返す。
+    pub fn deadline(&self, priority: RequestPriority, requested: Deadline) -> Deadline {
+        match priority {
+            RequestPriority::Realtime => Deadline::Immediate,
+            RequestPriority::Normal => requested,
+            RequestPriority::Bulk => match requested {
+                Deadline::Within(d) => Deadline::Within(cmp::max(d, self.bulk_deadline)),
+                Deadline::Immediate => Deadline::Within(self.bulk_deadline),
+                Deadline::Infinity => Deadline::Infinity,
+            },
+        }
+    }
+}
+impl Default for RequestPriorityConfig {
+    fn default() -> Self {
+        RequestPriorityConfig {
+            bulk_deadline: default_request_priority_bulk_deadline(),
+        }
+    }
+}
+
+fn default_request_priority_bulk_deadline() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// 上書きされたオブジェクトの実データの削除に関する設定。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionConfig {
+    /// `true` の場合には、上書きによって不要になったバージョンの実データを、
+    /// 通常の削除キューを経由せずに即座に削除する。
+    #[serde(default)]
+    pub aggressive: bool,
+
+    /// 即座に削除する場合に、同時に実行する削除の最大数。
+    #[serde(default = "default_compaction_max_concurrency")]
+    pub max_concurrency: usize,
+}
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        CompactionConfig {
+            aggressive: false,
+            max_concurrency: default_compaction_max_concurrency(),
+        }
+    }
+}
+
+fn default_compaction_max_concurrency() -> usize {
+    16
+}
+
+/// 再試行の対象となるエラーの種類。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryableErrorKind {
+    /// `ErrorKind::Busy` (リーダの交代中や、MDS・デバイスが過負荷の場合等)。
+    Busy,
+
+    /// `ErrorKind::Other` (RPC の通信エラー等)。
+    Other,
+}
+
+/// MDS やストレージへのリクエストが失敗した場合の、クライアント側での再試行に関する設定。
+///
+/// 待ち時間は再試行の度に倍になり(指数バックオフ)、`max_backoff` を上限とする。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryConfig {
+    /// 最初の試行を含めた最大の試行回数。
+    ///
+    /// `1` の場合には再試行は行われない。
+    /// なお、書き込みの応答のみが失われた場合には、再試行時に前提条件を満たさなくなり
+    /// `ErrorKind::UnexpectedVersion` が返されることがある。
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: usize,
+
+    /// 最初の再試行までの待ち時間。
+    #[serde(
+        rename = "initial_backoff_millis",
+        default = "default_retry_initial_backoff",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub initial_backoff: Duration,
+
+    /// 再試行までの待ち時間の上限。
+    #[serde(
+        rename = "max_backoff_millis",
+        default = "default_retry_max_backoff",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub max_backoff: Duration,
+
+    /// `true` の場合には、待ち時間を `[backoff / 2, backoff]` の範囲でランダムに決める。
+    ///
+    /// 多数のクライアントの再試行が同時に発生して、負荷が集中することを避けるためのもの。
+    #[serde(default = "default_retry_jitter")]
+    pub jitter: bool,
+
+    /// 再試行の対象となるエラーの種類。
+    ///
+    /// これ以外のエラー(e.g., 前提条件を満たさない、入力が不正)は再試行されない。
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<RetryableErrorKind>,
+}
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: default_retry_max_attempts(),
+            initial_backoff: default_retry_initial_backoff(),
+            max_backoff: default_retry_max_backoff(),
+            jitter: default_retry_jitter(),
+            retry_on: default_retry_on(),
+        }
+    }
+}
+
+fn default_retry_max_attempts() -> usize {
+    1
+}
+
+fn default_retry_initial_backoff() -> Duration {
+    Duration::from_millis(100)
+}
+
+fn default_retry_max_backoff() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn default_retry_jitter() -> bool {
+    true
+}
+
+fn default_retry_on() -> Vec<RetryableErrorKind> {
+    vec![RetryableErrorKind::Busy]
+}
+
+/// 暗号化に用いる鍵の取得方法。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KeyProviderConfig {
+    /// 環境変数から鍵を読み込む(`EnvKeyProvider`)。
+    Env {
+        /// 環境変数名の接頭辞。
+        #[serde(default = "default_key_provider_env_prefix")]
+        prefix: String,
+    },
+
+    /// ディレクトリ内のファイルから鍵を読み込む(`FileKeyProvider`)。
+    File {
+        /// 鍵のファイルが置かれているディレクトリ。
+        dir: PathBuf,
+    },
+}
+impl KeyProviderConfig {
+    /// 設定に対応する`KeyProvider`を生成する。
+    pub fn build(&self) -> Arc<dyn KeyProvider> {
+        match *self {
+            KeyProviderConfig::Env { ref prefix } => Arc::new(EnvKeyProvider::new(prefix.clone())),
+            KeyProviderConfig::File { ref dir } => Arc::new(FileKeyProvider::new(dir.clone())),
+        }
+    }
+}
+impl Default for KeyProviderConfig {
+    fn default() -> Self {
+        KeyProviderConfig::Env {
+            prefix: default_key_provider_env_prefix(),
+        }
+    }
+}
+
+fn default_key_provider_env_prefix() -> String {
+    "FRUGALOS_ENCRYPTION_KEY_".to_owned()
+}
+
+/// バケツ毎の暗号化に関する設定。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketEncryptionConfig {
+    /// 新規に保存されるオブジェクトの暗号化に使う鍵の ID。
+    ///
+    /// 変更しても、既存のオブジェクトは保存時の鍵で復号されるので、
+    /// 古い鍵も`KeyProvider`から取得可能な状態にしておく必要がある。
+    pub key_id: String,
+}
+
+/// オブジェクトの内容の暗号化に関する設定。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    /// 鍵の取得方法。
+    #[serde(default)]
+    pub key_provider: KeyProviderConfig,
+
+    /// バケツ ID から、そのバケツの暗号化の設定へのマップ。
+    ///
+    /// 登録されていないバケツでは、新規に保存されるオブジェクトは暗号化されない。
+    /// 登録されたバケツでは、オブジェクトへの追記(append)は拒否される。
+    #[serde(default)]
+    pub buckets: BTreeMap<String, BucketEncryptionConfig>,
+}
+impl EncryptionConfig {
+    /// 指定されたバケツで使用する`ContentEncryption`を生成する。
+    pub fn content_encryption(&self, bucket_id: &str) -> ContentEncryption {
+        let key_id = self.buckets.get(bucket_id).map(|b| b.key_id.clone());
+        ContentEncryption::new(self.key_provider.build(), key_id)
+    }
+}
+
 // FIXME: rename (config.rs で定義されている struct は名前、責務、依存関係を整理した方がよい)
 /// クライアントがセグメントにアクセスする際に使用する構成情報。
 #[allow(missing_docs)]
 #[derive(Debug, Clone)]
 pub struct ClientConfig {
+    /// セグメントが属するバケツの ID。
+    ///
+    /// メトリクスの`bucket`ラベルに使われる。
+    pub bucket_id: String,
     pub cluster: ClusterConfig,
     pub dispersed_client: DispersedClientConfig,
     pub replicated_client: ReplicatedClientConfig,
     pub storage: Storage,
     pub mds: MdsClientConfig,
+    pub erasure_coder: ErasureCoderConfig,
+
+    /// バケツの属性で指定された、符号化に用いる実装。
+    pub coding: SharedErasureCoding,
+    pub request_priority: RequestPriorityConfig,
+    pub encryption: Option<ContentEncryption>,
+    pub compaction: CompactionConfig,
+    pub retry: RetryConfig,
+    pub member_health: MemberHealthConfig,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub budget: RequestBudgetConfig,
+    pub ec_pool: ErasureCodingPool,
+    pub content_cache: ContentCacheConfig,
+    pub cache_sizing: ContentCacheSizing,
+    pub maintenance: MaintenanceSchedule,
+    pub features: FeatureFlags,
+    pub access: AccessMode,
+
+    /// 再配置中のセグメントの場合の、変更前の配置。
+    ///
+    /// 再配置中は変更前のメンバ群が MDS を担当し、オブジェクトの内容は新しい配置に書き込まれる。
+    /// 内容の読み込みは、新しい配置で失敗した場合に変更前の配置から行われる。
+    pub previous_layout: Option<PreviousLayout>,
 }
 impl ClientConfig {
     /// 対象のセグメントに属しているメンバ一覧を返す。
@@ -240,13 +1012,46 @@ impl ClientConfig {
     }
 }
 
+/// 再配置中のセグメントの、変更前の配置。
+#[derive(Debug, Clone)]
+pub struct PreviousLayout {
+    /// 変更前のメンバ群。
+    pub cluster: ClusterConfig,
+
+    /// 変更前のストレージの設定。
+    pub storage: Storage,
+}
+
 /// セグメント(Raftクラスタ)の構成情報。
 #[allow(missing_docs)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClusterConfig {
     pub members: Vec<ClusterMember>,
+
+    /// MDS のウィットネスとして動作させるメンバの数(バケツの属性`mds_witnesses`の値)。
+    ///
+    /// `members`の末尾からこの数のメンバがウィットネスとなる。
+    /// ただし、ウィットネス以外のメンバが過半数となるように、メンバ数に応じて切り詰められる。
+    #[serde(default)]
+    pub mds_witnesses: usize,
 }
 impl ClusterConfig {
+    /// `node`がメンバに含まれているかどうかを返す。
+    pub fn contains(&self, node: &NodeId) -> bool {
+        self.members.iter().any(|m| m.node == *node)
+    }
+
+    /// `node`が MDS のウィットネスとして動作するメンバかどうかを返す。
+    pub fn is_mds_witness(&self, node: &NodeId) -> bool {
+        let majority = self.members.len() / 2 + 1;
+        let witnesses = cmp::min(
+            self.mds_witnesses,
+            self.members.len().saturating_sub(majority),
+        );
+        let start = self.members.len() - witnesses;
+        self.members[start..].iter().any(|m| m.node == *node)
+    }
+
     /// オブジェクトデータの取得先候補を優先順位が高い順に返す。
     pub fn candidates(&self, version: ObjectVersion) -> impl Iterator<Item = &ClusterMember> {
         let mut hasher = SipHasher::new();
@@ -410,7 +1215,10 @@ mod tests {
             members.push(make_member(n));
         }
 
-        ClusterConfig { members }
+        ClusterConfig {
+            members,
+            mds_witnesses: 0,
+        }
     }
 
     /// Collects all device names from `ClusterConfig`.
@@ -437,6 +1245,32 @@ mod tests {
         assert_eq!(candidates[4], "2");
     }
 
+    #[test]
+    fn is_mds_witness_works() {
+        let mut cluster = make_cluster(5);
+        assert!(cluster
+            .members
+            .iter()
+            .all(|m| !cluster.is_mds_witness(&m.node)));
+
+        cluster.mds_witnesses = 2;
+        let witnesses = cluster
+            .members
+            .iter()
+            .map(|m| cluster.is_mds_witness(&m.node))
+            .collect::<Vec<_>>();
+        assert_eq!(witnesses, vec![false, false, false, true, true]);
+
+        // ウィットネス以外のメンバが過半数となるように切り詰められる
+        cluster.mds_witnesses = 4;
+        let witnesses = cluster
+            .members
+            .iter()
+            .filter(|m| cluster.is_mds_witness(&m.node))
+            .count();
+        assert_eq!(witnesses, 2);
+    }
+
     #[test]
     fn participants_works() -> TestResult {
         let cluster_size = 5;
@@ -506,6 +1340,37 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    #[allow(clippy::inconsistent_digit_grouping)]
+    fn make_queue_snapshot_lump_id_works() -> TestResult {
+        use std::str::FromStr;
+
+        let node = NodeId::from_str("1000a00.0@127.0.0.1:14278")?;
+        let lump_id = make_queue_snapshot_lump_id(&node);
+
+        assert_eq!(lump_id.as_u128(), 2 << 120 | 0x100_0a00_00 << 64);
+        assert_ne!(lump_id, make_lump_id(&node, ObjectVersion(0)));
+
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::inconsistent_digit_grouping)]
+    fn content_lump_id_range_works() -> TestResult {
+        use std::str::FromStr;
+
+        let node = NodeId::from_str("1000a00.0@127.0.0.1:14278")?;
+        let range = content_lump_id_range(&node);
+        assert_eq!(range.start, make_lump_id(&node, ObjectVersion(0)));
+        assert!(range.contains(&make_lump_id(&node, ObjectVersion(u64::max_value()))));
+        assert!(!range.contains(&make_queue_snapshot_lump_id(&node)));
+
+        let other = NodeId::from_str("1000a01.0@127.0.0.1:14278")?;
+        assert!(!range.contains(&make_lump_id(&other, ObjectVersion(0))));
+
+        Ok(())
+    }
+
     #[test]
     fn get_object_version_from_lump_id_works() -> TestResult {
         #[allow(clippy::inconsistent_digit_grouping)]
@@ -517,4 +1382,31 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn request_priority_config_works() {
+        let config = RequestPriorityConfig {
+            bulk_deadline: Duration::from_secs(60),
+        };
+        let requested = Deadline::Within(Duration::from_secs(5));
+        assert_eq!(
+            config.deadline(RequestPriority::Realtime, requested),
+            Deadline::Immediate
+        );
+        assert_eq!(
+            config.deadline(RequestPriority::Normal, requested),
+            requested
+        );
+        assert_eq!(
+            config.deadline(RequestPriority::Bulk, requested),
+            Deadline::Within(Duration::from_secs(60))
+        );
+        assert_eq!(
+            config.deadline(
+                RequestPriority::Bulk,
+                Deadline::Within(Duration::from_secs(120))
+            ),
+            Deadline::Within(Duration::from_secs(120))
+        );
+    }
 }
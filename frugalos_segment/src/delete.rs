@@ -1,7 +1,8 @@
 use cannyls::deadline::Deadline;
 use cannyls::device::DeviceHandle;
 use frugalos_raft::NodeId;
-use futures::{Async, Future, Poll};
+use futures::future::Either;
+use futures::{self, Async, Future, Poll};
 use libfrugalos::entity::object::ObjectVersion;
 use slog::Logger;
 
@@ -55,3 +56,43 @@ impl Future for DeleteContent {
         }
     }
 }
+
+/// 上書きによって不要になったバージョンの実データを削除する`Future`.
+///
+/// 結果は、削除によって解放されたバイト数の概算値.
+pub(crate) struct CompactContent {
+    future: BoxFuture<u64>,
+}
+impl CompactContent {
+    pub fn new(device: &DeviceHandle, node_id: NodeId, version: ObjectVersion) -> Self {
+        let lump_id = config::make_lump_id(&node_id, version);
+        let device = device.clone();
+        let future = device
+            .request()
+            .deadline(Deadline::Infinity)
+            .head(lump_id)
+            .and_then(move |header| {
+                if let Some(header) = header {
+                    let size = u64::from(header.approximate_data_size);
+                    let future = device
+                        .request()
+                        .deadline(Deadline::Infinity)
+                        .delete(lump_id)
+                        .map(move |deleted| if deleted { size } else { 0 });
+                    Either::A(future)
+                } else {
+                    Either::B(futures::finished(0))
+                }
+            });
+        CompactContent {
+            future: into_box_future(future),
+        }
+    }
+}
+impl Future for CompactContent {
+    type Item = u64;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        track!(self.future.poll())
+    }
+}
@@ -0,0 +1,166 @@
+//! AES-256-GCM (NIST SP 800-38D) のラッパー.
+//!
+//! 暗号処理そのものは`aes-gcm`クレートに委ねている.
+//! 同クレートの実装は定数時間で動作し、認証タグの比較も定数時間で行われる.
+//! また、一度に暗号化可能なデータの大きさの上限(約 64GiB)を超えた場合には、
+//! カウンタが周回する前にエラーとなる.
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::aes::cipher::{generic_array::GenericArray, BlockEncrypt};
+use aes_gcm::aes::Aes256 as RawAes256;
+use aes_gcm::{Aes256Gcm as RawAes256Gcm, Nonce, Tag};
+
+/// 鍵のバイト長.
+pub const KEY_SIZE: usize = 32;
+
+/// nonce のバイト長.
+pub const NONCE_SIZE: usize = 12;
+
+/// 認証タグのバイト長.
+pub const TAG_SIZE: usize = 16;
+
+const BLOCK_SIZE: usize = 16;
+
+/// AES-256 のブロック暗号(暗号化方向のみ).
+///
+/// 鍵の検査値の計算にのみ使用される.
+#[derive(Clone)]
+pub struct Aes256(RawAes256);
+impl Aes256 {
+    /// 新しい`Aes256`インスタンスを生成する.
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Aes256(RawAes256::new(GenericArray::from_slice(key)))
+    }
+
+    /// `block`をその場で暗号化する.
+    pub fn encrypt_block(&self, block: &mut [u8; BLOCK_SIZE]) {
+        self.0.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+}
+
+/// AES-256-GCM による認証付き暗号.
+#[derive(Clone)]
+pub struct Aes256Gcm(RawAes256Gcm);
+impl Aes256Gcm {
+    /// 新しい`Aes256Gcm`インスタンスを生成する.
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Aes256Gcm(RawAes256Gcm::new(GenericArray::from_slice(key)))
+    }
+
+    /// `data`をその場で暗号化し、認証タグを返す.
+    ///
+    /// `data`が GCM で暗号化可能な大きさを超えている場合には`None`が返され、`data`は変更されない.
+    pub fn encrypt(
+        &self,
+        nonce: &[u8; NONCE_SIZE],
+        aad: &[u8],
+        data: &mut [u8],
+    ) -> Option<[u8; TAG_SIZE]> {
+        let tag = self
+            .0
+            .encrypt_in_place_detached(Nonce::from_slice(nonce), aad, data)
+            .ok()?;
+        let mut bytes = [0; TAG_SIZE];
+        bytes.copy_from_slice(&tag);
+        Some(bytes)
+    }
+
+    /// `data`をその場で復号する.
+    ///
+    /// 認証タグが一致しない場合には`false`が返され、`data`は変更されない.
+    pub fn decrypt(
+        &self,
+        nonce: &[u8; NONCE_SIZE],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8; TAG_SIZE],
+    ) -> bool {
+        self.0
+            .decrypt_in_place_detached(Nonce::from_slice(nonce), aad, data, Tag::from_slice(tag))
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn key(s: &str) -> [u8; KEY_SIZE] {
+        let mut key = [0; KEY_SIZE];
+        key.copy_from_slice(&hex(s));
+        key
+    }
+
+    fn nonce(s: &str) -> [u8; NONCE_SIZE] {
+        let mut nonce = [0; NONCE_SIZE];
+        nonce.copy_from_slice(&hex(s));
+        nonce
+    }
+
+    #[test]
+    fn aes256_works() {
+        // FIPS-197, Appendix C.3
+        let cipher = Aes256::new(&key(
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+        ));
+        let mut block = [0; BLOCK_SIZE];
+        block.copy_from_slice(&hex("00112233445566778899aabbccddeeff"));
+        cipher.encrypt_block(&mut block);
+        assert_eq!(block.to_vec(), hex("8ea2b7ca516745bfeafc49904b496089"));
+    }
+
+    #[test]
+    fn aes256_gcm_works() {
+        // "The Galois/Counter Mode of Operation (GCM)", Test Case 13 ~ 16
+        let zero_key = key(&"00".repeat(32));
+        let zero_nonce = nonce(&"00".repeat(12));
+        let gcm = Aes256Gcm::new(&zero_key);
+        let tag = gcm.encrypt(&zero_nonce, &[], &mut []).unwrap();
+        assert_eq!(tag.to_vec(), hex("530f8afbc74536b9a963b4f1c4cb738b"));
+
+        let mut data = vec![0; 16];
+        let tag = gcm.encrypt(&zero_nonce, &[], &mut data).unwrap();
+        assert_eq!(data, hex("cea7403d4d606b6e074ec5d3baf39d18"));
+        assert_eq!(tag.to_vec(), hex("d0d1c8a799996bf0265b98b5d48ab919"));
+
+        let gcm = Aes256Gcm::new(&key(
+            "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308",
+        ));
+        let nonce = nonce("cafebabefacedbaddecaf888");
+        let plaintext = hex(
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+             1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255",
+        );
+        let ciphertext = hex(
+            "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa\
+             8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662898015ad",
+        );
+        let mut data = plaintext.clone();
+        let tag = gcm.encrypt(&nonce, &[], &mut data).unwrap();
+        assert_eq!(data, ciphertext);
+        assert_eq!(tag.to_vec(), hex("b094dac5d93471bdec1a502270e3cc6c"));
+
+        let aad = hex("feedfacedeadbeeffeedfacedeadbeefabaddad2");
+        let mut data = plaintext[..60].to_vec();
+        let tag = gcm.encrypt(&nonce, &aad, &mut data).unwrap();
+        assert_eq!(data, &ciphertext[..60]);
+        assert_eq!(tag.to_vec(), hex("76fc6ece0f4e1768cddf8853bb2d551b"));
+
+        assert!(gcm.decrypt(&nonce, &aad, &mut data, &tag));
+        assert_eq!(data, &plaintext[..60]);
+
+        // 改竄されたデータは復号できない
+        let mut data = ciphertext[..60].to_vec();
+        data[0] ^= 1;
+        assert!(!gcm.decrypt(&nonce, &aad, &mut data, &tag));
+        let mut data = ciphertext[..60].to_vec();
+        assert!(!gcm.decrypt(&nonce, &aad[1..], &mut data, &tag));
+        assert_eq!(data, &ciphertext[..60]);
+    }
+}
@@ -0,0 +1,430 @@
+//! オブジェクトの内容の暗号化(at-rest encryption)に関する機能。
+//!
+//! 暗号化はバケツ単位で有効にでき、オブジェクトの内容は断片化(erasure coding)される前に
+//! AES-256-GCM で暗号化される。
+//! 使用した鍵の ID は MDS のオブジェクトのデータに `ObjectEncryption` として記録されるので、
+//! 後から鍵を切り替えても、既存のオブジェクトはそれぞれの鍵で復号できる。
+//!
+//! 鍵そのものは `KeyProvider` を通して取得する。
+//! 環境変数やファイルから読み込む実装を提供しているが、
+//! KMS 等の外部サービスを利用したい場合には、このトレイトを実装すればよい。
+//!
+//! ストレージ上の形式は以下の通り:
+//!
+//! ```text
+//! nonce(12バイト) || 暗号文 || 認証タグ(16バイト)
+//! ```
+//!
+//! 認証タグの計算には、オブジェクトのバージョンと鍵の ID が追加データとして含まれるので、
+//! 他のオブジェクトやバージョンの内容と取り違えた場合も検出される。
+//!
+//! なお、暗号化が有効なバケツでは、オブジェクトへの追記(append)は拒否される。
+//! 追記された部分は独立したバージョンとして保存されるが、その鍵の情報を MDS に記録する手段がないため。
+//! 暗号化が有効になる前に追記されたオブジェクトは、これまで通り読み込むことができる。
+//!
+//! 暗号処理には`aes-gcm`クレートを用いている。
+use frugalos_mds::machine::ObjectEncryption;
+use libfrugalos::entity::object::ObjectVersion;
+use rand::{self, RngCore};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use trackable::error::ErrorKindExt;
+
+use self::aes_gcm::{Aes256, Aes256Gcm, KEY_SIZE, NONCE_SIZE, TAG_SIZE};
+use {Error, ErrorKind, Result};
+
+mod aes_gcm;
+
+/// 鍵の検査値の計算に用いる平文ブロック。
+const KEY_CHECK_BLOCK: &[u8; 16] = b"frugalos-keychk\0";
+
+/// オブジェクトの暗号化に用いる 256 ビットの鍵。
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_SIZE]);
+impl EncryptionKey {
+    /// 新しい`EncryptionKey`インスタンスを生成する。
+    pub fn new(bytes: [u8; KEY_SIZE]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    /// 16進数で表現された鍵をパースする。
+    ///
+    /// 前後の空白は無視される。
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim();
+        track_assert_eq!(
+            hex.len(),
+            KEY_SIZE * 2,
+            ErrorKind::Invalid,
+            "An encryption key must be {} hexadecimal digits",
+            KEY_SIZE * 2
+        );
+        let mut bytes = [0; KEY_SIZE];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            let digits = track_assert_some!(
+                hex.get(i * 2..i * 2 + 2),
+                ErrorKind::Invalid,
+                "Non-ASCII encryption key"
+            );
+            *b = track!(u8::from_str_radix(digits, 16).map_err(|e| ErrorKind::Invalid.cause(e)))?;
+        }
+        Ok(EncryptionKey(bytes))
+    }
+
+    /// 鍵の取り違えを検出するための検査値を返す。
+    ///
+    /// 固定のブロックを暗号化したものなので、検査値から鍵が推測されることはない。
+    pub fn check_value(&self) -> [u8; 8] {
+        let mut block = *KEY_CHECK_BLOCK;
+        Aes256::new(&self.0).encrypt_block(&mut block);
+        let mut check = [0; 8];
+        check.copy_from_slice(&block[..8]);
+        check
+    }
+}
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // 鍵の中身がログ等に出力されないようにする
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+/// 鍵の ID から鍵を取得するためのトレイト。
+///
+/// オブジェクトの読み書きの度に呼び出されるので、
+/// 外部サービスに問い合わせる実装の場合には、結果をキャッシュすることが望ましい。
+pub trait KeyProvider: fmt::Debug + Send + Sync + 'static {
+    /// `key_id`に対応する鍵を返す。
+    ///
+    /// 鍵が存在しない場合には`None`を返す。
+    fn get_key(&self, key_id: &str) -> Result<Option<EncryptionKey>>;
+}
+
+/// 環境変数から鍵を読み込む`KeyProvider`。
+///
+/// 鍵の ID が`foo-1`で接頭辞が`FRUGALOS_ENCRYPTION_KEY_`の場合には、
+/// `FRUGALOS_ENCRYPTION_KEY_FOO_1`に 16 進数で表現された鍵が設定されている必要がある。
+#[derive(Debug, Clone)]
+pub struct EnvKeyProvider {
+    prefix: String,
+}
+impl EnvKeyProvider {
+    /// 新しい`EnvKeyProvider`インスタンスを生成する。
+    pub fn new(prefix: String) -> Self {
+        EnvKeyProvider { prefix }
+    }
+
+    /// `key_id`に対応する環境変数名を返す。
+    pub fn var_name(&self, key_id: &str) -> String {
+        let suffix = key_id
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        format!("{}{}", self.prefix, suffix)
+    }
+}
+impl KeyProvider for EnvKeyProvider {
+    fn get_key(&self, key_id: &str) -> Result<Option<EncryptionKey>> {
+        match env::var(self.var_name(key_id)) {
+            Ok(hex) => track!(EncryptionKey::from_hex(&hex)).map(Some),
+            Err(env::VarError::NotPresent) => Ok(None),
+            Err(e) => Err(track!(Error::from(ErrorKind::Invalid.cause(e)))),
+        }
+    }
+}
+
+/// ディレクトリ内のファイルから鍵を読み込む`KeyProvider`。
+///
+/// 鍵の ID と同じ名前のファイルに、16 進数で表現された鍵が書かれている必要がある。
+/// 一度読み込んだ鍵はキャッシュされる。
+#[derive(Debug)]
+pub struct FileKeyProvider {
+    dir: PathBuf,
+    cache: Mutex<HashMap<String, EncryptionKey>>,
+}
+impl FileKeyProvider {
+    /// 新しい`FileKeyProvider`インスタンスを生成する。
+    pub fn new(dir: PathBuf) -> Self {
+        FileKeyProvider {
+            dir,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+impl KeyProvider for FileKeyProvider {
+    fn get_key(&self, key_id: &str) -> Result<Option<EncryptionKey>> {
+        // ディレクトリ外のファイルが参照されないようにする
+        track_assert!(
+            !key_id.is_empty()
+                && !key_id.starts_with('.')
+                && key_id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)),
+            ErrorKind::Invalid,
+            "Invalid key ID: {:?}",
+            key_id
+        );
+        if let Some(key) = self.cache.lock().expect("Never fails").get(key_id) {
+            return Ok(Some(key.clone()));
+        }
+
+        let path = self.dir.join(key_id);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let hex = track!(fs::read_to_string(&path).map_err(|e| ErrorKind::Other.cause(e)); path)?;
+        let key = track!(EncryptionKey::from_hex(&hex); path)?;
+        self.cache
+            .lock()
+            .expect("Never fails")
+            .insert(key_id.to_owned(), key.clone());
+        Ok(Some(key))
+    }
+}
+
+/// 一つのオブジェクトの暗号化および復号に用いる鍵。
+#[derive(Clone)]
+pub struct ObjectKey {
+    encryption: ObjectEncryption,
+    cipher: Aes256Gcm,
+}
+impl ObjectKey {
+    /// MDS に記録される、鍵の情報を返す。
+    pub fn encryption(&self) -> &ObjectEncryption {
+        &self.encryption
+    }
+
+    /// `version`の内容として保存される`content`を暗号化する。
+    ///
+    /// `content`が AES-GCM で暗号化可能な大きさ(約 64GiB)を超えている場合にはエラーとなる。
+    pub fn seal(&self, version: ObjectVersion, mut content: Vec<u8>) -> Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let tag = track_assert_some!(
+            self.cipher
+                .encrypt(&nonce, &self.aad(version), &mut content),
+            ErrorKind::Invalid,
+            "Too large content to encrypt: version={:?}, len={}",
+            version,
+            content.len()
+        );
+
+        let mut sealed = Vec::with_capacity(NONCE_SIZE + content.len() + TAG_SIZE);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&content);
+        sealed.extend_from_slice(&tag);
+        Ok(sealed)
+    }
+
+    /// `seal`によって暗号化された`version`の内容を復号する。
+    pub fn open(&self, version: ObjectVersion, mut sealed: Vec<u8>) -> Result<Vec<u8>> {
+        track_assert!(
+            sealed.len() >= NONCE_SIZE + TAG_SIZE,
+            ErrorKind::Corrupted,
+            "Too short encrypted content: version={:?}, len={}",
+            version,
+            sealed.len()
+        );
+        let mut nonce = [0; NONCE_SIZE];
+        nonce.copy_from_slice(&sealed[..NONCE_SIZE]);
+        let mut tag = [0; TAG_SIZE];
+        tag.copy_from_slice(&sealed[sealed.len() - TAG_SIZE..]);
+
+        let tag_pos = sealed.len() - TAG_SIZE;
+        sealed.truncate(tag_pos);
+        let mut content = sealed.split_off(NONCE_SIZE);
+        track_assert!(
+            self.cipher
+                .decrypt(&nonce, &self.aad(version), &mut content, &tag),
+            ErrorKind::Corrupted,
+            "Failed to authenticate encrypted content: version={:?}, key_id={:?}",
+            version,
+            self.encryption.key_id
+        );
+        Ok(content)
+    }
+
+    fn aad(&self, version: ObjectVersion) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(8 + self.encryption.key_id.len());
+        aad.extend_from_slice(&version.0.to_be_bytes());
+        aad.extend_from_slice(self.encryption.key_id.as_bytes());
+        aad
+    }
+}
+
+/// バケツに対する暗号化の設定。
+///
+/// 暗号化が無効なバケツであっても、有効だった時期に保存されたオブジェクトを読み込めるように、
+/// `KeyProvider`は常に保持している。
+#[derive(Debug, Clone)]
+pub struct ContentEncryption {
+    provider: Arc<dyn KeyProvider>,
+    key_id: Option<String>,
+}
+impl ContentEncryption {
+    /// 新しい`ContentEncryption`インスタンスを生成する。
+    ///
+    /// `key_id`は新規に保存されるオブジェクトの暗号化に使う鍵で、`None`の場合には暗号化は行われない。
+    pub fn new(provider: Arc<dyn KeyProvider>, key_id: Option<String>) -> Self {
+        ContentEncryption { provider, key_id }
+    }
+
+    /// 新規に保存されるオブジェクトが暗号化されるかどうかを返す。
+    pub fn is_enabled(&self) -> bool {
+        self.key_id.is_some()
+    }
+
+    /// 新規に保存されるオブジェクトの暗号化に使う鍵を返す。
+    ///
+    /// 暗号化が無効な場合には`None`が返される。
+    pub fn sealing_key(&self) -> Result<Option<ObjectKey>> {
+        let key_id = match self.key_id {
+            None => return Ok(None),
+            Some(ref key_id) => key_id,
+        };
+        let key = track!(self.get_key(key_id))?;
+        let encryption = ObjectEncryption {
+            key_id: key_id.clone(),
+            key_check: key.check_value(),
+        };
+        Ok(Some(ObjectKey {
+            encryption,
+            cipher: Aes256Gcm::new(&key.0),
+        }))
+    }
+
+    /// `encryption`の情報と共に保存されたオブジェクトの復号に使う鍵を返す。
+    ///
+    /// `KeyProvider`が返した鍵が、暗号化に使われたものと異なる場合にはエラーとなる。
+    pub fn opening_key(&self, encryption: ObjectEncryption) -> Result<ObjectKey> {
+        let key = track!(self.get_key(&encryption.key_id))?;
+        track_assert_eq!(
+            key.check_value(),
+            encryption.key_check,
+            ErrorKind::Invalid,
+            "The key provided for {:?} differs from the one used for encryption",
+            encryption.key_id
+        );
+        Ok(ObjectKey {
+            encryption,
+            cipher: Aes256Gcm::new(&key.0),
+        })
+    }
+
+    fn get_key(&self, key_id: &str) -> Result<EncryptionKey> {
+        let key = track!(self.provider.get_key(key_id); key_id)?;
+        let key = track_assert_some!(
+            key,
+            ErrorKind::Invalid,
+            "No such encryption key: {:?}",
+            key_id
+        );
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct StaticKeyProvider(HashMap<String, EncryptionKey>);
+    impl KeyProvider for StaticKeyProvider {
+        fn get_key(&self, key_id: &str) -> Result<Option<EncryptionKey>> {
+            Ok(self.0.get(key_id).cloned())
+        }
+    }
+
+    fn encryption(keys: &[(&str, u8)], key_id: Option<&str>) -> ContentEncryption {
+        let keys = keys
+            .iter()
+            .map(|&(id, b)| (id.to_owned(), EncryptionKey::new([b; KEY_SIZE])))
+            .collect();
+        ContentEncryption::new(
+            Arc::new(StaticKeyProvider(keys)),
+            key_id.map(|id| id.to_owned()),
+        )
+    }
+
+    #[test]
+    fn seal_and_open_work() -> ::trackable::result::TestResult {
+        let encryption = encryption(&[("foo", 1)], Some("foo"));
+        let key = track!(encryption.sealing_key())?.unwrap();
+        assert_eq!(key.encryption().key_id, "foo");
+
+        let version = ObjectVersion(10);
+        let sealed = track!(key.seal(version, b"hello".to_vec()))?;
+        assert_eq!(sealed.len(), NONCE_SIZE + 5 + TAG_SIZE);
+        assert_ne!(&sealed[NONCE_SIZE..][..5], b"hello");
+
+        let opening = track!(encryption.opening_key(key.encryption().clone()))?;
+        assert_eq!(track!(opening.open(version, sealed.clone()))?, b"hello");
+
+        // 別のバージョンの内容としては復号できない
+        assert!(opening.open(ObjectVersion(11), sealed.clone()).is_err());
+
+        // 壊れた内容は復号できない
+        let mut corrupted = sealed;
+        corrupted[NONCE_SIZE] ^= 1;
+        assert!(opening.open(version, corrupted).is_err());
+        assert!(opening.open(version, vec![0; 10]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn key_mismatch_is_detected() -> ::trackable::result::TestResult {
+        let writer = encryption(&[("foo", 1)], Some("foo"));
+        let key = track!(writer.sealing_key())?.unwrap();
+
+        // 同じ ID で異なる鍵が返される
+        let reader = encryption(&[("foo", 2)], None);
+        assert!(reader.opening_key(key.encryption().clone()).is_err());
+
+        // 鍵が存在しない
+        let reader = encryption(&[], None);
+        assert!(reader.opening_key(key.encryption().clone()).is_err());
+
+        // 暗号化が無効でも、既存のオブジェクトは復号できる
+        let reader = encryption(&[("foo", 1)], None);
+        assert!(!reader.is_enabled());
+        assert!(track!(reader.sealing_key())?.is_none());
+        assert!(reader.opening_key(key.encryption().clone()).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn encryption_key_from_hex_works() {
+        let hex = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        let key = EncryptionKey::from_hex(&format!(" {}\n", hex)).unwrap();
+        assert_eq!(key.0[31], 0x1f);
+        assert!(EncryptionKey::from_hex(&hex[1..]).is_err());
+        assert!(EncryptionKey::from_hex(&hex.replace("1f", "zz")).is_err());
+    }
+
+    #[test]
+    fn env_key_provider_var_name_works() {
+        let provider = EnvKeyProvider::new("FRUGALOS_ENCRYPTION_KEY_".to_owned());
+        assert_eq!(
+            provider.var_name("foo-1.bar"),
+            "FRUGALOS_ENCRYPTION_KEY_FOO_1_BAR"
+        );
+    }
+
+    #[test]
+    fn file_key_provider_rejects_path_traversal() {
+        let provider = FileKeyProvider::new(PathBuf::from("/tmp"));
+        assert!(provider.get_key("../etc/passwd").is_err());
+        assert!(provider.get_key(".hidden").is_err());
+        assert!(provider.get_key("").is_err());
+    }
+}
@@ -15,10 +15,27 @@ use trackable::error::{ErrorKind as TrackableErrorKind, ErrorKindExt};
 #[allow(missing_docs)]
 #[derive(Debug, Clone)]
 pub enum ErrorKind {
-    UnexpectedVersion { current: Option<ObjectVersion> },
+    UnexpectedVersion {
+        current: Option<ObjectVersion>,
+    },
     Invalid,
     Busy,
     Corrupted,
+
+    /// 要求が`RequestBudgetConfig`で指定された資源の上限を超えた。
+    BudgetExceeded,
+
+    /// 書き込みによって、バケツ(セグメント)の割り当て量を超えてしまう。
+    QuotaExceeded,
+
+    /// バケツが読み込み専用に設定されているため、書き込みや削除が行えない。
+    ReadOnly,
+
+    /// 監視を再開しようとした位置の直後からの変更の一部が、MDS の変更履歴から失われている。
+    ChangesLost,
+
+    /// 呼び出し元の期限を過ぎたか、結果が不要になったために、ストレージへの要求が中断された。
+    Cancelled,
     Other,
 }
 impl TrackableErrorKind for ErrorKind {}
@@ -58,6 +75,11 @@ impl From<frugalos_mds::Error> for Error {
                     .takes_over(f)
                     .into()
             }
+            frugalos_mds::ErrorKind::QuotaExceeded(_) => {
+                ErrorKind::QuotaExceeded.takes_over(f).into()
+            }
+            frugalos_mds::ErrorKind::Frozen => ErrorKind::Busy.takes_over(f).into(),
+            frugalos_mds::ErrorKind::ReadOnly => ErrorKind::ReadOnly.takes_over(f).into(),
             _ => ErrorKind::Other.takes_over(f).into(),
         }
     }
@@ -0,0 +1,149 @@
+//! 新たに導入された(リスクを伴う)振る舞いを、バケツ毎に再デプロイなしで有効・無効にするための機能フラグ。
+//!
+//! 初期値は設定ファイルで指定し、実行中には管理用の RPC で切り替えられる。
+//! 実行中の変更はサーバ毎に保持され、再起動すると設定ファイルの値に戻る。
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use trackable::error::ErrorKindExt;
+
+use config::FeatureFlagSet;
+use {Error, ErrorKind};
+
+/// 機能フラグで切り替えられる振る舞い。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// 読み込みが遅延している場合に、予備のメンバにも追加の読み込み要求を発行する(hedged read)。
+    ///
+    /// 実際に行われるのは、クライアントの設定で hedged read が有効になっている場合のみ。
+    HedgedReads,
+
+    /// 書き込み時に、復元に必要な数の断片(ないし複製)の書き込みが完了した時点で応答を返す。
+    ///
+    /// 無効な場合には、全ての書き込みの完了を待ってから応答する(成功とみなす条件は変わらない)。
+    QuorumPutAck,
+
+    /// 分散オブジェクトの読み込み時に欠損ないし破損した断片を見つけた場合に、
+    /// その断片を保持すべきノードにリペアを依頼する。
+    ReadRepair,
+}
+impl Feature {
+    /// 全ての機能を返す。
+    pub fn all() -> [Feature; 3] {
+        [
+            Feature::HedgedReads,
+            Feature::QuorumPutAck,
+            Feature::ReadRepair,
+        ]
+    }
+
+    /// 設定ファイルや RPC で使われる名前を返す。
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::HedgedReads => "hedged_reads",
+            Feature::QuorumPutAck => "quorum_put_ack",
+            Feature::ReadRepair => "read_repair",
+        }
+    }
+
+    fn bit(self) -> usize {
+        1 << self as usize
+    }
+}
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+impl FromStr for Feature {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(&feature) = Feature::all().iter().find(|f| f.name() == s) {
+            Ok(feature)
+        } else {
+            let e = ErrorKind::Invalid.cause(format!("Unknown feature: {:?}", s));
+            Err(track!(Error::from(e)))
+        }
+    }
+}
+
+/// バケツの機能フラグの現在の値。
+///
+/// `Clone`されたインスタンス間で状態が共有されるので、
+/// `set`による変更は、そのバケツの全てのセグメントに即座に反映される。
+#[derive(Debug, Clone)]
+pub struct FeatureFlags {
+    bits: Arc<AtomicUsize>,
+}
+impl FeatureFlags {
+    /// `initial`を初期値として、新しいインスタンスを生成する。
+    pub fn new(initial: &FeatureFlagSet) -> Self {
+        let bits = Feature::all()
+            .iter()
+            .filter(|&&f| initial.is_enabled(f))
+            .fold(0, |bits, f| bits | f.bit());
+        FeatureFlags {
+            bits: Arc::new(AtomicUsize::new(bits)),
+        }
+    }
+
+    /// 指定された機能が有効かどうかを返す。
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        self.bits.load(Ordering::Relaxed) & feature.bit() != 0
+    }
+
+    /// 指定された機能を有効ないし無効にする。
+    pub fn set(&self, feature: Feature, enabled: bool) {
+        if enabled {
+            self.bits.fetch_or(feature.bit(), Ordering::SeqCst);
+        } else {
+            self.bits.fetch_and(!feature.bit(), Ordering::SeqCst);
+        }
+    }
+
+    /// 全ての機能の現在の値を返す。
+    pub fn snapshot(&self) -> FeatureFlagSet {
+        let mut flags = FeatureFlagSet::default();
+        for &feature in Feature::all().iter() {
+            flags.set(feature, self.is_enabled(feature));
+        }
+        flags
+    }
+}
+impl Default for FeatureFlags {
+    fn default() -> Self {
+        FeatureFlags::new(&FeatureFlagSet::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feature_flags_works() {
+        let flags = FeatureFlags::default();
+        assert!(flags.is_enabled(Feature::HedgedReads));
+        assert!(flags.is_enabled(Feature::QuorumPutAck));
+        assert!(!flags.is_enabled(Feature::ReadRepair));
+
+        let shared = flags.clone();
+        shared.set(Feature::ReadRepair, true);
+        shared.set(Feature::QuorumPutAck, false);
+        assert_eq!(
+            flags.snapshot(),
+            FeatureFlagSet {
+                hedged_reads: true,
+                quorum_put_ack: false,
+                read_repair: true,
+            }
+        );
+
+        for &feature in Feature::all().iter() {
+            assert_eq!(feature.name().parse::<Feature>().ok(), Some(feature));
+        }
+        assert!("foo".parse::<Feature>().is_err());
+    }
+}
@@ -3,8 +3,10 @@
 //! [Frugalos]: https://github.com/frugalos/frugalos
 #![warn(missing_docs)]
 #![allow(clippy::new_ret_no_self)]
-extern crate adler32;
+extern crate aes_gcm;
+extern crate bytecodec;
 extern crate byteorder;
+extern crate bytes;
 extern crate cannyls;
 extern crate cannyls_rpc;
 extern crate ecpool;
@@ -21,6 +23,7 @@ extern crate libfrugalos;
 extern crate prometrics;
 extern crate raftlog;
 extern crate rand;
+extern crate reed_solomon_erasure;
 extern crate rustracing;
 extern crate rustracing_jaeger;
 extern crate serde;
@@ -29,25 +32,50 @@ extern crate serde_derive;
 extern crate siphasher;
 #[macro_use]
 extern crate slog;
+extern crate tasque;
 #[macro_use]
 extern crate trackable;
 
-pub use client::ec::{build_ec, ErasureCoder};
+pub use audit::{
+    AvailabilitySummary, FragmentReport, FragmentState, ObjectAuditReport, ObjectAvailability,
+};
+pub use client::cache::{CacheClass, ContentCacheSizing, ContentCacheStats};
+pub use client::circuit_breaker::CircuitState;
+pub use client::ec::{build_ec, build_ec_with_coding, ErasureCoder, SharedErasureCoding};
+pub use client::ec_pool::ErasureCodingPool;
+pub use client::health::MemberHealth;
+pub use client::storage::PutDurability;
+pub use client::watch::Watch;
 pub use client::Client;
 pub use error::{Error, ErrorKind};
+pub use feature::{Feature, FeatureFlags};
+pub use frugalos_mds::AccessMode;
+pub use maintenance::MaintenanceSchedule;
+pub use relayout::{NotifyRelayoutFinished, RelayoutProgress, RelayoutTarget};
+pub use repair::{NodeRepairResult, ObjectRepairSummary, RepairOutcome};
+pub use segment_gc::{SegmentGcProgress, SegmentGcStatus};
 pub use service::{Service, ServiceHandle};
+pub use status::{MemberStatus, SegmentNodeStatus, SynchronizerQueues};
 
 pub mod config;
+pub mod encryption;
+pub mod schema;
 
+mod audit;
 mod client;
 mod delete;
 mod error;
+mod feature;
+mod maintenance;
 mod metrics;
 mod queue_executor;
+mod relayout;
 mod repair;
 mod rpc_server;
 mod segment_gc;
 mod service;
+mod startup;
+mod status;
 mod synchronizer;
 mod test_util;
 mod util;
@@ -65,6 +93,21 @@ pub struct ObjectValue {
     pub content: Vec<u8>,
 }
 
+/// オブジェクトの一部の範囲の値。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectRange {
+    /// バージョン番号。
+    pub version: libfrugalos::entity::object::ObjectVersion,
+
+    /// 範囲内の中身。
+    ///
+    /// 範囲がオブジェクトの末尾を超えている場合には、末尾までのデータとなる。
+    pub content: Vec<u8>,
+
+    /// オブジェクト全体のバイト数。
+    pub object_size: u64,
+}
+
 /// `frugalos_segment` の設定。
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FrugalosSegmentConfig {
@@ -77,6 +120,39 @@ pub struct FrugalosSegmentConfig {
     /// A configuration for `MdsClient`.
     #[serde(default)]
     pub mds_client: config::MdsClientConfig,
+    /// バケツ毎の Erasure Coding の実装の選択。
+    #[serde(default)]
+    pub erasure_coding: config::ErasureCodingConfig,
+    /// リクエストの優先度クラスとデッドラインの対応付け。
+    #[serde(default)]
+    pub request_priority: config::RequestPriorityConfig,
+    /// バケツ毎のオブジェクトの内容の暗号化。
+    #[serde(default)]
+    pub encryption: config::EncryptionConfig,
+    /// 上書きされたオブジェクトの実データの削除。
+    #[serde(default)]
+    pub compaction: config::CompactionConfig,
+    /// MDS やストレージへのリクエストの再試行。
+    #[serde(default)]
+    pub retry: config::RetryConfig,
+    /// ストレージの各メンバの健全性の追跡と、読み込み先の選択。
+    #[serde(default)]
+    pub member_health: config::MemberHealthConfig,
+    /// ストレージの各メンバ毎のサーキットブレーカ。
+    #[serde(default)]
+    pub circuit_breaker: config::CircuitBreakerConfig,
+    /// 一つの読み込み要求が消費できる資源の上限。
+    #[serde(default)]
+    pub budget: config::RequestBudgetConfig,
+    /// Erasure Coding 用のスレッドプール。
+    #[serde(default)]
+    pub ec_pool: config::ErasureCodingPoolConfig,
+    /// 読み込んだオブジェクトの内容のキャッシュ。
+    #[serde(default)]
+    pub content_cache: config::ContentCacheConfig,
+    /// バケツ毎の機能フラグの初期値。
+    #[serde(default)]
+    pub feature_flags: config::FeatureFlagsConfig,
 }
 
 impl Default for FrugalosSegmentConfig {
@@ -85,6 +161,17 @@ impl Default for FrugalosSegmentConfig {
             dispersed_client: Default::default(),
             replicated_client: Default::default(),
             mds_client: Default::default(),
+            erasure_coding: Default::default(),
+            request_priority: Default::default(),
+            encryption: Default::default(),
+            compaction: Default::default(),
+            retry: Default::default(),
+            member_health: Default::default(),
+            circuit_breaker: Default::default(),
+            budget: Default::default(),
+            ec_pool: Default::default(),
+            content_cache: Default::default(),
+            feature_flags: Default::default(),
         }
     }
 }
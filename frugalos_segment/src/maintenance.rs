@@ -0,0 +1,411 @@
+//! リペアや segment_gc のような負荷の高いバックグラウンド処理を、
+//! 指定された時間帯(メンテナンスウィンドウ)にのみ実行するための仕組み。
+//!
+//! ウィンドウは、開始時刻を表す cron 形式のスケジュールと、その継続時間の組で指定する。
+//! ウィンドウが一つも指定されていない場合には、常にウィンドウ内にあるものとして扱う。
+//!
+//! ウィンドウ自体は構成管理用クラスタで共有されており、変更される度に`MaintenanceSchedule::update`で反映される。
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use config::{MaintenanceConfig, MaintenanceWindowConfig};
+use {ErrorKind, Result};
+
+/// `MaintenanceSchedule::cached`の値がまだ計算されていないことを表す値。
+const NOT_CACHED: u64 = u64::MAX;
+
+/// cron 形式のスケジュール(分 時 日 月 曜日)。
+///
+/// 各フィールドでは`*`、数値、範囲(`1-5`)、刻み(`*/15`、`0-30/10`)とそのリスト(`1,3,5`)が使える。
+/// 曜日は`0`(または`7`)が日曜日となる。
+/// cron と同様に、日と曜日の両方が指定された場合には、どちらかに一致すれば良い。
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    restricts_days: bool,
+    restricts_weekdays: bool,
+}
+impl CronSchedule {
+    fn parse(s: &str) -> Result<Self> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        track_assert_eq!(
+            fields.len(),
+            5,
+            ErrorKind::Invalid,
+            "A cron schedule must have five fields: {:?}",
+            s
+        );
+        let mut weekdays = track!(parse_cron_field(fields[4], 0, 7))?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(CronSchedule {
+            minutes: track!(parse_cron_field(fields[0], 0, 59))?,
+            hours: track!(parse_cron_field(fields[1], 0, 23))?,
+            days: track!(parse_cron_field(fields[2], 1, 31))?,
+            months: track!(parse_cron_field(fields[3], 1, 12))?,
+            weekdays,
+            restricts_days: fields[2] != "*",
+            restricts_weekdays: fields[4] != "*",
+        })
+    }
+
+    fn matches(&self, t: &CivilTime) -> bool {
+        let bit = |set: u64, n: u32| set & (1 << n) != 0;
+        let day = bit(self.days, t.day);
+        let weekday = bit(self.weekdays, t.weekday);
+        let day_matches = if self.restricts_days && self.restricts_weekdays {
+            day || weekday
+        } else {
+            day && weekday
+        };
+        bit(self.minutes, t.minute)
+            && bit(self.hours, t.hour)
+            && bit(self.months, t.month)
+            && day_matches
+    }
+}
+
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut set = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.find('/') {
+            Some(i) => (&item[..i], track!(parse_cron_number(&item[i + 1..]))?),
+            None => (item, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            (
+                track!(parse_cron_number(&range[..i]))?,
+                track!(parse_cron_number(&range[i + 1..]))?,
+            )
+        } else {
+            let n = track!(parse_cron_number(range))?;
+            (n, n)
+        };
+        track_assert!(
+            min <= start && start <= end && end <= max && step > 0,
+            ErrorKind::Invalid,
+            "Invalid cron field: {:?} (must be in {}..={})",
+            field,
+            min,
+            max
+        );
+        for n in (start..=end).step_by(step as usize) {
+            set |= 1 << n;
+        }
+    }
+    Ok(set)
+}
+
+fn parse_cron_number(s: &str) -> Result<u32> {
+    let n = track_assert_some!(s.parse().ok(), ErrorKind::Invalid, "Not a number: {:?}", s);
+    Ok(n)
+}
+
+/// 分単位の暦上の時刻。
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CivilTime {
+    month: u32,
+    day: u32,
+    weekday: u32,
+    hour: u32,
+    minute: u32,
+}
+impl CivilTime {
+    /// エポックからの分数を、暦上の時刻に変換する。
+    fn from_epoch_minutes(minutes: i64) -> Self {
+        let days = minutes.div_euclid(24 * 60);
+        let minute_of_day = minutes.rem_euclid(24 * 60);
+
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+
+        CivilTime {
+            month: month as u32,
+            day: day as u32,
+            // 1970-01-01 は木曜日
+            weekday: (days + 4).rem_euclid(7) as u32,
+            hour: (minute_of_day / 60) as u32,
+            minute: (minute_of_day % 60) as u32,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MaintenanceWindow {
+    schedule: CronSchedule,
+    duration_minutes: i64,
+}
+impl MaintenanceWindow {
+    fn new(config: &MaintenanceWindowConfig) -> Result<Self> {
+        let schedule = track!(CronSchedule::parse(&config.schedule))?;
+        let duration_minutes = config.duration.as_secs().div_ceil(60) as i64;
+        track_assert!(
+            duration_minutes > 0,
+            ErrorKind::Invalid,
+            "The duration of a maintenance window must be positive: {:?}",
+            config
+        );
+        Ok(MaintenanceWindow {
+            schedule,
+            duration_minutes,
+        })
+    }
+
+    /// `minutes`(エポックからの分数)が、このウィンドウのいずれかの回に含まれるかどうかを返す。
+    fn contains(&self, minutes: i64) -> bool {
+        (0..self.duration_minutes).any(|i| {
+            self.schedule
+                .matches(&CivilTime::from_epoch_minutes(minutes - i))
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Windows {
+    windows: Vec<MaintenanceWindow>,
+    utc_offset_minutes: i64,
+}
+impl Windows {
+    fn new(config: &MaintenanceConfig) -> Result<Self> {
+        let windows = config
+            .windows
+            .iter()
+            .map(|w| track!(MaintenanceWindow::new(w)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Windows {
+            windows,
+            utc_offset_minutes: i64::from(config.utc_offset_minutes),
+        })
+    }
+
+    fn is_open_at(&self, utc_minutes: i64) -> bool {
+        if self.windows.is_empty() {
+            return true;
+        }
+        let local = utc_minutes + self.utc_offset_minutes;
+        self.windows.iter().any(|w| w.contains(local))
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    windows: Mutex<Windows>,
+    overridden: AtomicBool,
+
+    // 直近に判定した時刻(分)と結果(最下位ビット)
+    cached: AtomicU64,
+}
+
+/// 負荷の高いバックグラウンド処理を実行して良い時間帯かどうかを判定するためのオブジェクト。
+///
+/// `Clone`されたインスタンス間で状態が共有されるので、
+/// `update`や`set_override`による変更は、全てのセグメントのバックグラウンド処理に反映される。
+#[derive(Debug, Clone)]
+pub struct MaintenanceSchedule {
+    inner: Arc<Inner>,
+}
+impl MaintenanceSchedule {
+    /// 設定に従って、新しいインスタンスを生成する。
+    pub fn new(config: &MaintenanceConfig) -> Result<Self> {
+        let windows = track!(Windows::new(config))?;
+        Ok(MaintenanceSchedule {
+            inner: Arc::new(Inner {
+                windows: Mutex::new(windows),
+                overridden: AtomicBool::new(false),
+                cached: AtomicU64::new(NOT_CACHED),
+            }),
+        })
+    }
+
+    /// ウィンドウを`config`の内容に置き換える。
+    ///
+    /// `config`が不正な場合にはエラーを返し、現在のウィンドウは変更されない。
+    pub fn update(&self, config: &MaintenanceConfig) -> Result<()> {
+        let windows = track!(Windows::new(config))?;
+        let mut current = self.inner.windows.lock().unwrap_or_else(|e| e.into_inner());
+        *current = windows;
+        self.inner.cached.store(NOT_CACHED, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// 現在、負荷の高いバックグラウンド処理を実行して良いかどうかを返す。
+    ///
+    /// ウィンドウが指定されていない場合や、`set_override`で制限が解除されている場合には常に`true`となる。
+    pub fn is_open(&self) -> bool {
+        if self.is_overridden() {
+            return true;
+        }
+        let minutes = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 60;
+        let cached = self.inner.cached.load(Ordering::Relaxed);
+        if cached != NOT_CACHED && cached >> 1 == minutes {
+            return cached & 1 == 1;
+        }
+
+        // NOTE: `update`と競合して古いウィンドウでの結果がキャッシュされないように、ロックを保持したまま更新する
+        let windows = self.inner.windows.lock().unwrap_or_else(|e| e.into_inner());
+        let open = windows.is_open_at(minutes as i64);
+        self.inner
+            .cached
+            .store((minutes << 1) | u64::from(open), Ordering::Relaxed);
+        open
+    }
+
+    /// ウィンドウによる制限が解除されているかどうかを返す。
+    pub fn is_overridden(&self) -> bool {
+        self.inner.overridden.load(Ordering::SeqCst)
+    }
+
+    /// ウィンドウによる制限を解除(`true`)ないし再開(`false`)する。
+    ///
+    /// 緊急にリペアを進める必要がある場合等に用いる。
+    pub fn set_override(&self, overridden: bool) {
+        self.inner.overridden.store(overridden, Ordering::SeqCst);
+    }
+
+    fn is_open_at(&self, utc_minutes: i64) -> bool {
+        self.inner
+            .windows
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_open_at(utc_minutes)
+    }
+}
+impl Default for MaintenanceSchedule {
+    fn default() -> Self {
+        Self::new(&MaintenanceConfig::default()).expect("Never fails")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use trackable::result::TestResult;
+
+    // 2019-03-04 (月) 00:00 UTC
+    const MONDAY: i64 = 1_551_657_600 / 60;
+
+    fn window(schedule: &str, hours: u64) -> MaintenanceWindowConfig {
+        MaintenanceWindowConfig {
+            schedule: schedule.to_owned(),
+            duration: Duration::from_secs(hours * 60 * 60),
+        }
+    }
+
+    #[test]
+    fn civil_time_works() {
+        let t = CivilTime::from_epoch_minutes(MONDAY + 13 * 60 + 5);
+        assert_eq!(
+            t,
+            CivilTime {
+                month: 3,
+                day: 4,
+                weekday: 1,
+                hour: 13,
+                minute: 5
+            }
+        );
+        let t = CivilTime::from_epoch_minutes(0);
+        assert_eq!((t.month, t.day, t.weekday), (1, 1, 4));
+    }
+
+    #[test]
+    fn cron_schedule_works() -> TestResult {
+        let s = track!(CronSchedule::parse("*/15 1-3 * * 1-5"))?;
+        let at = |days: i64, hour: i64, minute: i64| {
+            CivilTime::from_epoch_minutes(MONDAY + days * 24 * 60 + hour * 60 + minute)
+        };
+        assert!(s.matches(&at(0, 1, 0)));
+        assert!(s.matches(&at(0, 3, 45)));
+        assert!(!s.matches(&at(0, 3, 46)));
+        assert!(!s.matches(&at(0, 4, 0)));
+        assert!(!s.matches(&at(5, 1, 0))); // 土曜日
+
+        // 日と曜日の両方が指定された場合には、どちらかに一致すれば良い
+        let s = track!(CronSchedule::parse("0 0 1,15 * 7"))?;
+        assert!(s.matches(&at(6, 0, 0))); // 日曜日
+        assert!(!s.matches(&at(7, 0, 0))); // 3/11 (月)
+        assert!(s.matches(&at(11, 0, 0))); // 3/15 (金)
+
+        assert!(CronSchedule::parse("0 0 * *").is_err());
+        assert!(CronSchedule::parse("60 0 * * *").is_err());
+        assert!(CronSchedule::parse("0 0 0 * *").is_err());
+        assert!(CronSchedule::parse("*/0 0 * * *").is_err());
+        assert!(CronSchedule::parse("a 0 * * *").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn maintenance_schedule_works() -> TestResult {
+        let schedule = track!(MaintenanceSchedule::new(&MaintenanceConfig::default()))?;
+        assert!(schedule.is_open());
+
+        // 毎日 22:00 から 4 時間 (日をまたぐ)
+        let config = MaintenanceConfig {
+            windows: vec![window("0 22 * * *", 4)],
+            utc_offset_minutes: 0,
+        };
+        let schedule = track!(MaintenanceSchedule::new(&config))?;
+        assert!(schedule.is_open_at(MONDAY));
+        assert!(schedule.is_open_at(MONDAY + 60 + 59));
+        assert!(!schedule.is_open_at(MONDAY + 2 * 60));
+        assert!(!schedule.is_open_at(MONDAY + 21 * 60 + 59));
+        assert!(schedule.is_open_at(MONDAY + 22 * 60));
+
+        // UTC+9 で毎日 02:00 から 1 時間
+        let config = MaintenanceConfig {
+            windows: vec![window("0 2 * * *", 1)],
+            utc_offset_minutes: 9 * 60,
+        };
+        let schedule = track!(MaintenanceSchedule::new(&config))?;
+        assert!(schedule.is_open_at(MONDAY + 17 * 60 + 30));
+        assert!(!schedule.is_open_at(MONDAY + 2 * 60 + 30));
+
+        // ウィンドウの更新 (クローン間で共有される)
+        let cloned = schedule.clone();
+        let config = MaintenanceConfig {
+            windows: vec![window("0 17 * * *", 1)],
+            utc_offset_minutes: 0,
+        };
+        track!(cloned.update(&config))?;
+        assert!(schedule.is_open_at(MONDAY + 17 * 60 + 30));
+        assert!(!schedule.is_open_at(MONDAY + 2 * 60 + 30));
+
+        // 不正な設定による更新は反映されない
+        let invalid = MaintenanceConfig {
+            windows: vec![window("0 24 * * *", 1)],
+            utc_offset_minutes: 0,
+        };
+        assert!(cloned.update(&invalid).is_err());
+        assert!(schedule.is_open_at(MONDAY + 17 * 60 + 30));
+
+        // ウィンドウが空の場合には常に実行可能
+        track!(cloned.update(&MaintenanceConfig::default()))?;
+        assert!(schedule.is_open_at(MONDAY + 2 * 60 + 30));
+
+        // 制限の解除
+        track!(cloned.update(&config))?;
+        cloned.set_override(true);
+        assert!(schedule.is_open());
+        Ok(())
+    }
+}
@@ -1,9 +1,24 @@
 //! Metrics for `frugalos_segment`.
+//!
+//! クライアントのメトリクスは、バケツ毎に`bucket`ラベルを付与して区別される.
 
-use prometrics::metrics::{Counter, CounterBuilder};
+use frugalos_core::metrics::{get_or_create, MetricLabels};
+use frugalos_core::prometheus;
+use futures::Future;
+use prometrics;
+use prometrics::metrics::{Counter, CounterBuilder, Gauge, GaugeBuilder, Histogram, MetricBuilder};
+use std::time::Instant;
 
 use Result;
 
+/// `labels`を付与したセグメント用の`MetricBuilder`を返す.
+fn metric_builder(labels: &MetricLabels) -> MetricBuilder {
+    let mut builder = MetricBuilder::new();
+    builder.namespace("frugalos").subsystem("segment");
+    labels.apply(&mut builder);
+    builder
+}
+
 #[derive(Debug, Clone)]
 pub struct PutAllMetrics {
     pub(crate) failures_total: Counter,
@@ -11,17 +26,16 @@ pub struct PutAllMetrics {
 }
 
 impl PutAllMetrics {
-    pub(crate) fn new(client_name: &'static str) -> Result<Self> {
-        let failures_total = track!(CounterBuilder::new("put_all_failures_total")
-            .namespace("frugalos")
-            .subsystem("segment")
+    pub(crate) fn new(client_name: &'static str, labels: &MetricLabels) -> Result<Self> {
+        let builder = metric_builder(labels);
+        let failures_total = track!(builder
+            .counter("put_all_failures_total")
             .help("Number of PutAll failures")
             .label("client", client_name)
             .default_registry()
             .finish())?;
-        let lost_fragments_total = track!(CounterBuilder::new("put_all_lost_fragments_total")
-            .namespace("frugalos")
-            .subsystem("segment")
+        let lost_fragments_total = track!(builder
+            .counter("put_all_lost_fragments_total")
             .help("Number of lost fragments")
             .label("client", client_name)
             .default_registry()
@@ -33,26 +47,399 @@ impl PutAllMetrics {
     }
 }
 
+/// Metrics for the content cache.
+///
+/// 同じバケツの全てのセグメントのキャッシュで共有される.
+#[derive(Debug, Clone)]
+pub struct ContentCacheMetrics {
+    /// キャッシュから内容を返した回数.
+    pub(crate) hits_total: Counter,
+
+    /// キャッシュに内容がなかった回数.
+    pub(crate) misses_total: Counter,
+
+    /// 容量を空けるために追い出したオブジェクトの数.
+    pub(crate) evictions_total: Counter,
+}
+
+impl ContentCacheMetrics {
+    pub(crate) fn new(labels: &MetricLabels) -> Result<Self> {
+        get_or_create(labels, |labels| {
+            let builder = metric_builder(labels);
+            Ok(ContentCacheMetrics {
+                hits_total: track!(builder
+                    .counter("content_cache_hits_total")
+                    .help("Number of reads served from the content cache")
+                    .default_registry()
+                    .finish())?,
+                misses_total: track!(builder
+                    .counter("content_cache_misses_total")
+                    .help("Number of reads that missed the content cache")
+                    .default_registry()
+                    .finish())?,
+                evictions_total: track!(builder
+                    .counter("content_cache_evictions_total")
+                    .help("Number of objects evicted from the content cache")
+                    .default_registry()
+                    .finish())?,
+            })
+        })
+    }
+}
+
+/// Metrics for hedged reads.
+#[derive(Debug, Clone)]
+pub struct HedgeMetrics {
+    /// 追加の読み込み要求が発行された取得操作の数.
+    pub(crate) triggered_total: Counter,
+
+    /// 追加の読み込み要求の応答が、取得結果に使用された取得操作の数.
+    pub(crate) won_total: Counter,
+}
+
+impl HedgeMetrics {
+    pub(crate) fn new(client_name: &'static str, labels: &MetricLabels) -> Result<Self> {
+        let builder = metric_builder(labels);
+        let triggered_total = track!(builder
+            .counter("hedge_triggered_total")
+            .help("Number of get operations that issued hedged requests")
+            .label("client", client_name)
+            .default_registry()
+            .finish())?;
+        let won_total = track!(builder
+            .counter("hedge_won_total")
+            .help("Number of get operations completed by hedged requests")
+            .label("client", client_name)
+            .default_registry()
+            .finish())?;
+        Ok(HedgeMetrics {
+            triggered_total,
+            won_total,
+        })
+    }
+}
+
+/// Metrics for circuit breakers.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerMetrics {
+    /// 回路が開いた回数.
+    pub(crate) opened_total: Counter,
+
+    /// 回路が半開状態(試験的な要求の許可)に移った回数.
+    pub(crate) half_opened_total: Counter,
+
+    /// 回路が閉じた(通常の状態に戻った)回数.
+    pub(crate) closed_total: Counter,
+}
+
+impl CircuitBreakerMetrics {
+    pub(crate) fn new(client_name: &'static str, labels: &MetricLabels) -> Result<Self> {
+        let builder = metric_builder(labels);
+        let transitions_total = |state| {
+            track!(builder
+                .counter("circuit_breaker_transitions_total")
+                .help("Number of circuit breaker state transitions")
+                .label("client", client_name)
+                .label("state", state)
+                .default_registry()
+                .finish())
+        };
+        Ok(CircuitBreakerMetrics {
+            opened_total: track!(transitions_total("open"))?,
+            half_opened_total: track!(transitions_total("half_open"))?,
+            closed_total: track!(transitions_total("closed"))?,
+        })
+    }
+}
+
+/// Metrics for request budgets.
+#[derive(Debug, Clone)]
+pub struct RequestBudgetMetrics {
+    /// 復号用のメモリの上限を超えた要求の数.
+    pub(crate) decode_bytes_exceeded_total: Counter,
+
+    /// 断片の取得要求の数の上限を超えた要求の数.
+    pub(crate) fanout_exceeded_total: Counter,
+
+    /// 処理時間の上限を超えた要求の数.
+    pub(crate) duration_exceeded_total: Counter,
+}
+
+impl RequestBudgetMetrics {
+    pub(crate) fn new(labels: &MetricLabels) -> Result<Self> {
+        get_or_create(labels, |labels| {
+            let builder = metric_builder(labels);
+            let exceeded_total = |budget| {
+                track!(builder
+                    .counter("request_budget_exceeded_total")
+                    .help("Number of requests that exceeded their budgets")
+                    .label("budget", budget)
+                    .default_registry()
+                    .finish())
+            };
+            Ok(RequestBudgetMetrics {
+                decode_bytes_exceeded_total: track!(exceeded_total("decode_bytes"))?,
+                fanout_exceeded_total: track!(exceeded_total("fanout"))?,
+                duration_exceeded_total: track!(exceeded_total("duration"))?,
+            })
+        })
+    }
+}
+
+/// Metrics for the erasure coding pool.
+#[derive(Debug, Clone)]
+pub struct ErasureCodingPoolMetrics {
+    /// プールに投入済みの(実行中のものも含む)処理の数.
+    pub(crate) in_flight: Gauge,
+
+    /// プールの空きを待機している処理の数.
+    pub(crate) waiting: Gauge,
+
+    /// プールが飽和していたために拒否された処理の数.
+    pub(crate) rejected_total: Counter,
+}
+
+impl ErasureCodingPoolMetrics {
+    pub(crate) fn new() -> Result<Self> {
+        Ok(ErasureCodingPoolMetrics {
+            in_flight: track!(GaugeBuilder::new("ec_pool_in_flight")
+                .namespace("frugalos")
+                .subsystem("segment")
+                .help("Number of erasure coding tasks submitted to the pool")
+                .default_registry()
+                .finish())?,
+            waiting: track!(GaugeBuilder::new("ec_pool_waiting")
+                .namespace("frugalos")
+                .subsystem("segment")
+                .help("Number of erasure coding tasks waiting for the pool")
+                .default_registry()
+                .finish())?,
+            rejected_total: track!(CounterBuilder::new("ec_pool_rejected_total")
+                .namespace("frugalos")
+                .subsystem("segment")
+                .help("Number of erasure coding tasks rejected by the saturated pool")
+                .default_registry()
+                .finish())?,
+        })
+    }
+}
+
+/// Metrics for the startup of segment nodes.
+#[derive(Debug, Clone)]
+pub struct NodeStartupMetrics {
+    /// 起動処理の開始を待機しているノードの数.
+    pub(crate) waiting: Gauge,
+
+    /// 起動処理中のノードの数.
+    pub(crate) starting: Gauge,
+
+    /// 起動処理が完了したノードの数.
+    pub(crate) started_total: Counter,
+
+    /// 起動処理がタイムアウトしたノードの数.
+    pub(crate) timeouts_total: Counter,
+
+    /// 起動処理の完了後に、リーダを認識したノードの数.
+    pub(crate) leader_recognized_total: Counter,
+}
+
+impl NodeStartupMetrics {
+    pub(crate) fn new() -> Result<Self> {
+        Ok(NodeStartupMetrics {
+            waiting: track!(GaugeBuilder::new("node_startup_waiting")
+                .namespace("frugalos")
+                .subsystem("segment")
+                .help("Number of nodes waiting to start up")
+                .default_registry()
+                .finish())?,
+            starting: track!(GaugeBuilder::new("node_startup_in_progress")
+                .namespace("frugalos")
+                .subsystem("segment")
+                .help("Number of nodes loading their raft logs")
+                .default_registry()
+                .finish())?,
+            started_total: track!(CounterBuilder::new("node_startup_completed_total")
+                .namespace("frugalos")
+                .subsystem("segment")
+                .help("Number of nodes which have loaded their raft logs (or timed out)")
+                .default_registry()
+                .finish())?,
+            timeouts_total: track!(CounterBuilder::new("node_startup_timeouts_total")
+                .namespace("frugalos")
+                .subsystem("segment")
+                .help("Number of nodes which have not loaded their raft logs within the startup timeout")
+                .default_registry()
+                .finish())?,
+            leader_recognized_total: track!(CounterBuilder::new(
+                "node_startup_leader_recognized_total"
+            )
+            .namespace("frugalos")
+            .subsystem("segment")
+            .help("Number of started nodes which have recognized a leader")
+            .default_registry()
+            .finish())?,
+        })
+    }
+}
+
+/// Metrics for fragment reads issued after the initial fan-out.
+#[derive(Debug, Clone)]
+pub struct FragmentFallbackMetrics {
+    /// 断片の取得失敗(未存在・破損を含む)を補うために発行された読み込み要求の数.
+    pub(crate) failure_total: Counter,
+
+    /// 断片の取得のタイムアウトにより発行された読み込み要求の数.
+    pub(crate) timeout_total: Counter,
+}
+
+impl FragmentFallbackMetrics {
+    pub(crate) fn new(labels: &MetricLabels) -> Result<Self> {
+        let builder = metric_builder(labels);
+        let fallback_reads_total = |reason| {
+            track!(builder
+                .counter("fragment_fallback_reads_total")
+                .help("Number of fragment reads issued to replace failed or slow reads")
+                .label("reason", reason)
+                .default_registry()
+                .finish())
+        };
+        Ok(FragmentFallbackMetrics {
+            failure_total: track!(fallback_reads_total("failure"))?,
+            timeout_total: track!(fallback_reads_total("timeout"))?,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DispersedClientMetrics {
     pub(crate) put_all: PutAllMetrics,
+    pub(crate) hedge: HedgeMetrics,
+    pub(crate) circuit_breaker: CircuitBreakerMetrics,
+    pub(crate) fallback: FragmentFallbackMetrics,
+
+    /// 読み込み時に見つかった断片の欠損について、リペアを依頼した回数.
+    pub(crate) read_repairs_total: Counter,
 }
 
 impl DispersedClientMetrics {
-    pub fn new() -> Result<Self> {
-        let put_all = track!(PutAllMetrics::new("dispersed_client"))?;
-        Ok(DispersedClientMetrics { put_all })
+    /// `labels`に対応するメトリクスを返す.
+    ///
+    /// 同じバケツのセグメントのクライアント間では、同じメトリクスが共有される.
+    pub fn new(labels: &MetricLabels) -> Result<Self> {
+        get_or_create(labels, |labels| {
+            let put_all = track!(PutAllMetrics::new("dispersed_client", labels))?;
+            let hedge = track!(HedgeMetrics::new("dispersed_client", labels))?;
+            let circuit_breaker = track!(CircuitBreakerMetrics::new("dispersed_client", labels))?;
+            let fallback = track!(FragmentFallbackMetrics::new(labels))?;
+            let read_repairs_total = track!(metric_builder(labels)
+                .counter("read_repairs_total")
+                .help("Number of repair requests for missing fragments found by get operations")
+                .label("client", "dispersed_client")
+                .default_registry()
+                .finish())?;
+            Ok(DispersedClientMetrics {
+                put_all,
+                hedge,
+                circuit_breaker,
+                fallback,
+                read_repairs_total,
+            })
+        })
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct ReplicatedClientMetrics {
     pub(crate) put_all: PutAllMetrics,
+    pub(crate) hedge: HedgeMetrics,
+    pub(crate) circuit_breaker: CircuitBreakerMetrics,
 }
 
 impl ReplicatedClientMetrics {
-    pub fn new() -> Result<Self> {
-        let put_all = track!(PutAllMetrics::new("replicated_client"))?;
-        Ok(ReplicatedClientMetrics { put_all })
+    /// `labels`に対応するメトリクスを返す.
+    ///
+    /// 同じバケツのセグメントのクライアント間では、同じメトリクスが共有される.
+    pub fn new(labels: &MetricLabels) -> Result<Self> {
+        get_or_create(labels, |labels| {
+            let put_all = track!(PutAllMetrics::new("replicated_client", labels))?;
+            let hedge = track!(HedgeMetrics::new("replicated_client", labels))?;
+            let circuit_breaker = track!(CircuitBreakerMetrics::new("replicated_client", labels))?;
+            Ok(ReplicatedClientMetrics {
+                put_all,
+                hedge,
+                circuit_breaker,
+            })
+        })
     }
 }
+
+/// Latency metrics for the operations of `Client`.
+///
+/// 各操作のヒストグラムは、MDS へのアクセス(`mds`)、ストレージへのアクセス(`storage`)、
+/// 操作全体(`total`)の三つのフェーズに分けて記録される.
+/// 失敗した操作も、完了までの時間が記録される.
+#[derive(Debug, Clone)]
+pub struct ClientLatencyMetrics {
+    pub(crate) get_mds: Histogram,
+    pub(crate) get_storage: Histogram,
+    pub(crate) get_total: Histogram,
+    pub(crate) put_mds: Histogram,
+    pub(crate) put_storage: Histogram,
+    pub(crate) put_total: Histogram,
+    pub(crate) delete_mds: Histogram,
+    pub(crate) delete_total: Histogram,
+}
+
+impl ClientLatencyMetrics {
+    /// `storage`はバケツの種類 (`metadata`, `replicated`, `dispersed`).
+    ///
+    /// バケツの種類は変わらないので、同じバケツのクライアント間では同じメトリクスが共有される.
+    pub(crate) fn new(labels: &MetricLabels, storage: &'static str) -> Result<Self> {
+        get_or_create(labels, |labels| {
+            let builder = metric_builder(labels);
+            let config = prometheus::config();
+            let histogram = |operation, phase| {
+                track!(config.configure_histogram(
+                    "frugalos_segment_request_duration_seconds",
+                    builder
+                        .histogram("request_duration_seconds")
+                        .help("Latency of segment client operations")
+                        .label("operation", operation)
+                        .label("phase", phase)
+                        .label("storage", storage)
+                        .default_registry()
+                ))
+            };
+            Ok(ClientLatencyMetrics {
+                get_mds: track!(histogram("get", "mds"))?,
+                get_storage: track!(histogram("get", "storage"))?,
+                get_total: track!(histogram("get", "total"))?,
+                put_mds: track!(histogram("put", "mds"))?,
+                put_storage: track!(histogram("put", "storage"))?,
+                put_total: track!(histogram("put", "total"))?,
+                delete_mds: track!(histogram("delete", "mds"))?,
+                delete_total: track!(histogram("delete", "total"))?,
+            })
+        })
+    }
+}
+
+/// `future`の完了までの時間を`histogram`に記録する.
+///
+/// 計測はこの関数の呼び出し時点から開始される.
+pub(crate) fn observe_latency<F>(
+    histogram: &Histogram,
+    future: F,
+) -> impl Future<Item = F::Item, Error = F::Error>
+where
+    F: Future,
+{
+    let histogram = histogram.clone();
+    let started_at = Instant::now();
+    future.then(move |result| {
+        histogram.observe(prometrics::timestamp::duration_to_seconds(
+            started_at.elapsed(),
+        ));
+        result
+    })
+}
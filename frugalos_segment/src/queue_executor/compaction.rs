@@ -0,0 +1,202 @@
+use cannyls::device::DeviceHandle;
+use frugalos_raft::NodeId;
+use futures::{Async, Future};
+use libfrugalos::entity::object::ObjectVersion;
+use prometrics::metrics::{Counter, MetricBuilder};
+use slog::Logger;
+use std::collections::VecDeque;
+
+use config::CompactionConfig;
+use delete::CompactContent;
+
+struct CompactionMetrics {
+    compacted_versions_total: Counter,
+    reclaimed_bytes_total: Counter,
+    failures_total: Counter,
+}
+impl CompactionMetrics {
+    fn new(metric_builder: &MetricBuilder) -> Self {
+        CompactionMetrics {
+            compacted_versions_total: metric_builder
+                .counter("compacted_versions_total")
+                .help("Number of overwritten versions deleted by compaction")
+                .default_registry()
+                .finish()
+                .expect("metric should be well-formed"),
+            reclaimed_bytes_total: metric_builder
+                .counter("compaction_reclaimed_bytes_total")
+                .help("Bytes reclaimed by compaction")
+                .default_registry()
+                .finish()
+                .expect("metric should be well-formed"),
+            failures_total: metric_builder
+                .counter("compaction_failures_total")
+                .help("Number of failed compaction deletions")
+                .default_registry()
+                .finish()
+                .expect("metric should be well-formed"),
+        }
+    }
+}
+
+/// 上書きによって不要になったバージョンの実データを、削除キューを経由せずに即座に削除する。
+///
+/// 同じ ID のオブジェクトが繰り返し上書きされる用途では、リペアより優先度の低い削除キューに
+/// 古い断片が溜まり続けてしまうので、それを避けるために使われる。
+/// 同時に実行される削除の数は `max_concurrency` までに制限され、それを超えた分は順番待ちとなる。
+pub(crate) struct Compaction {
+    logger: Logger,
+    node_id: NodeId,
+    device: DeviceHandle,
+    max_concurrency: usize,
+    pending: VecDeque<ObjectVersion>,
+    running: Vec<(ObjectVersion, CompactContent)>,
+    metrics: CompactionMetrics,
+}
+impl Compaction {
+    pub(crate) fn new(
+        logger: &Logger,
+        node_id: NodeId,
+        device: &DeviceHandle,
+        metric_builder: &MetricBuilder,
+        config: &CompactionConfig,
+    ) -> Self {
+        Compaction {
+            logger: logger.clone(),
+            node_id,
+            device: device.clone(),
+            max_concurrency: config.max_concurrency.max(1),
+            pending: VecDeque::new(),
+            running: Vec::new(),
+            metrics: CompactionMetrics::new(metric_builder),
+        }
+    }
+
+    pub(crate) fn push(&mut self, version: ObjectVersion) {
+        self.pending.push_back(version);
+    }
+
+    /// 削除が完了していないバージョン群を返す。
+    pub(crate) fn versions(&self) -> impl Iterator<Item = ObjectVersion> + '_ {
+        self.running
+            .iter()
+            .map(|&(version, _)| version)
+            .chain(self.pending.iter().cloned())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.running.len() + self.pending.len()
+    }
+
+    /// 実行中の削除を進め、空きがあれば順番待ちのものを開始する。
+    pub(crate) fn poll(&mut self) {
+        loop {
+            while self.running.len() < self.max_concurrency {
+                if let Some(version) = self.pending.pop_front() {
+                    let future = CompactContent::new(&self.device, self.node_id, version);
+                    self.running.push((version, future));
+                } else {
+                    break;
+                }
+            }
+
+            let mut finished = 0;
+            let mut i = 0;
+            while i < self.running.len() {
+                match self.running[i].1.poll() {
+                    Ok(Async::NotReady) => {
+                        i += 1;
+                        continue;
+                    }
+                    Ok(Async::Ready(bytes)) => {
+                        self.metrics.compacted_versions_total.increment();
+                        self.metrics.reclaimed_bytes_total.add_u64(bytes);
+                    }
+                    Err(e) => {
+                        // 残ってしまった断片は segment_gc で回収される
+                        let version = self.running[i].0;
+                        warn!(
+                            self.logger,
+                            "Cannot compact the superseded content: version={:?}, reason={}",
+                            version,
+                            e
+                        );
+                        self.metrics.failures_total.increment();
+                    }
+                }
+                self.running.swap_remove(i);
+                finished += 1;
+            }
+            if finished == 0 || self.pending.is_empty() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cannyls::deadline::Deadline;
+    use cannyls::device::DeviceBuilder;
+    use cannyls::nvm::MemoryNvm;
+    use cannyls::storage::StorageBuilder;
+    use slog::Discard;
+    use std::thread;
+    use std::time::Duration;
+    use trackable::result::TestResult;
+
+    use super::*;
+    use config::make_lump_id;
+    use test_util::tests::wait;
+
+    #[test]
+    fn compaction_deletes_superseded_contents() -> TestResult {
+        let nvm = MemoryNvm::new(vec![0; 1024 * 1024 * 4]);
+        let storage = track!(StorageBuilder::new().journal_region_ratio(0.05).create(nvm))?;
+        let device = DeviceBuilder::new().spawn(|| Ok(storage));
+        let handle = device.handle();
+        let node_id: NodeId = "0102030405060a.0@127.0.0.1:1".parse()?;
+
+        for i in 0..3 {
+            let lump_id = make_lump_id(&node_id, ObjectVersion(i));
+            let data = track!(handle.allocate_lump_data_with_bytes(&[0; 1024]))?;
+            let request = handle
+                .request()
+                .wait_for_running()
+                .deadline(Deadline::Immediate)
+                .put(lump_id, data);
+            track!(wait(request.map_err(From::from)))?;
+        }
+
+        let config = CompactionConfig {
+            aggressive: true,
+            max_concurrency: 2,
+        };
+        let mut compaction = Compaction::new(
+            &Logger::root(Discard, o!()),
+            node_id,
+            &handle,
+            &MetricBuilder::new(),
+            &config,
+        );
+        // 存在しないバージョンも含む
+        for i in 0..4 {
+            compaction.push(ObjectVersion(i));
+        }
+        assert_eq!(compaction.len(), 4);
+        while compaction.len() > 0 {
+            compaction.poll();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        for i in 0..3 {
+            let lump_id = make_lump_id(&node_id, ObjectVersion(i));
+            let request = handle.request().deadline(Deadline::Immediate).head(lump_id);
+            assert!(track!(wait(request.map_err(From::from)))?.is_none());
+        }
+        assert_eq!(compaction.metrics.compacted_versions_total.value(), 4.0);
+        assert!(compaction.metrics.reclaimed_bytes_total.value() >= 3.0 * 1024.0);
+        assert_eq!(compaction.metrics.failures_total.value(), 0.0);
+        Ok(())
+    }
+}
@@ -1,16 +1,20 @@
 use cannyls::device::DeviceHandle;
 use fibers::time::timer::{self, Timeout};
+use frugalos_core::task_dump::TaskTracker;
 use frugalos_mds::Event;
 use frugalos_raft::NodeId;
 use futures::{Async, Future, Poll, Stream};
 use libfrugalos::entity::object::ObjectVersion;
-use prometrics::metrics::Counter;
+use prometrics::metrics::{Counter, MetricBuilder};
 use slog::Logger;
 use std::cmp::{self, min, Reverse};
 use std::collections::{BTreeSet, BinaryHeap, VecDeque};
 use std::convert::Infallible;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
 
+use super::compaction::Compaction;
+use super::queue_metrics::QueueGauges;
+use config::CompactionConfig;
 use delete::DeleteContent;
 use repair::RepairPrepContent;
 use Error;
@@ -21,8 +25,9 @@ const DELETE_CONCURRENCY: usize = 16;
 #[derive(Debug, PartialOrd, Ord, PartialEq, Eq)]
 enum TodoItem {
     RepairContent {
-        start_time: SystemTime,
+        start_time: Instant,
         version: ObjectVersion,
+        enqueued_at: Instant,
     },
     DeleteContent {
         versions: Vec<ObjectVersion>,
@@ -31,18 +36,25 @@ enum TodoItem {
 impl TodoItem {
     pub fn new(event: &Event) -> Self {
         match *event {
-            Event::Deleted { version } => TodoItem::DeleteContent {
+            Event::Deleted { version, .. } => TodoItem::DeleteContent {
                 versions: vec![version],
             },
             Event::Putted {
                 version,
                 put_content_timeout,
+                ..
             } => {
                 // Wait for put_content_timeout.0 seconds, to avoid race condition with storage.put.
-                let start_time = SystemTime::now() + Duration::from_secs(put_content_timeout.0);
+                //
+                // NOTE: `put_content_timeout` はリーダーがコミットしたコマンドに含まれる相対時間なので、
+                // 待ち時間の計算には壁時計ではなく単調増加する `Instant` を使う.
+                // こうすることで、時計がズレているフォロワーが早すぎるタイミングで処理を始めることがなくなる.
+                let now = Instant::now();
+                let start_time = now + Duration::from_secs(put_content_timeout.0);
                 TodoItem::RepairContent {
                     start_time,
                     version,
+                    enqueued_at: now,
                 }
             }
             Event::FullSync { .. } => unreachable!(),
@@ -52,7 +64,12 @@ impl TodoItem {
         match *self {
             TodoItem::DeleteContent { .. } => None,
             TodoItem::RepairContent { start_time, .. } => {
-                start_time.duration_since(SystemTime::now()).ok()
+                let now = Instant::now();
+                if start_time > now {
+                    Some(start_time - now)
+                } else {
+                    None
+                }
             }
         }
     }
@@ -65,6 +82,16 @@ enum Task {
     Delete(DeleteContent),
     RepairPrep(RepairPrepContent),
 }
+impl Task {
+    fn name(&self) -> &'static str {
+        match *self {
+            Task::Idle => "Idle",
+            Task::Wait(_) => "Wait",
+            Task::Delete(_) => "Delete",
+            Task::RepairPrep(_) => "RepairPrep",
+        }
+    }
+}
 impl Future for Task {
     type Item = Option<ObjectVersion>;
     type Error = Error;
@@ -93,6 +120,10 @@ pub(crate) struct GeneralQueueExecutor {
     delete_queue: DeleteQueue,
     task: Task,
     repair_candidates: BTreeSet<ObjectVersion>,
+    tracker: TaskTracker,
+
+    // 有効な場合には、上書きによって不要になったバージョンは削除キューを経由せずに削除される.
+    compaction: Option<Compaction>,
 }
 
 impl GeneralQueueExecutor {
@@ -100,6 +131,7 @@ impl GeneralQueueExecutor {
         logger: &Logger,
         node_id: NodeId,
         device: &DeviceHandle,
+        metric_builder: &MetricBuilder,
         enqueued_repair_prep: &Counter,
         enqueued_delete: &Counter,
         dequeued_repair_prep: &Counter,
@@ -109,27 +141,123 @@ impl GeneralQueueExecutor {
             logger: logger.clone(),
             node_id,
             device: device.clone(),
-            repair_prep_queue: RepairPrepQueue::new(enqueued_repair_prep, dequeued_repair_prep),
-            delete_queue: DeleteQueue::new(enqueued_delete, dequeued_delete),
+            repair_prep_queue: RepairPrepQueue::new(
+                enqueued_repair_prep,
+                dequeued_repair_prep,
+                QueueGauges::new(metric_builder, "repair_prep"),
+            ),
+            delete_queue: DeleteQueue::new(
+                enqueued_delete,
+                dequeued_delete,
+                QueueGauges::new(metric_builder, "delete"),
+            ),
             task: Task::Idle,
             repair_candidates: BTreeSet::new(),
+            tracker: TaskTracker::new("general_queue_executor", &node_id.local_id.to_string()),
+            compaction: None,
         }
     }
+    /// 上書きによって不要になったバージョンを、即座に削除するようにする。
+    pub(crate) fn enable_compaction(
+        &mut self,
+        metric_builder: &MetricBuilder,
+        config: &CompactionConfig,
+    ) {
+        self.compaction = Some(Compaction::new(
+            &self.logger,
+            self.node_id,
+            &self.device,
+            metric_builder,
+            config,
+        ));
+    }
     pub(crate) fn push(&mut self, event: &Event) {
         match *event {
             Event::Putted { version, .. } => {
                 self.repair_prep_queue.push(TodoItem::new(event));
                 self.repair_candidates.insert(version);
             }
-            Event::Deleted { version } => {
+            Event::Deleted {
+                version,
+                overwritten,
+                ..
+            } => {
                 self.repair_candidates.remove(&version);
-                self.delete_queue.push(version);
+                match self.compaction {
+                    Some(ref mut compaction) if overwritten => compaction.push(version),
+                    _ => self.delete_queue.push(version),
+                }
             }
             Event::FullSync { .. } => {
                 unreachable!();
             }
         }
     }
+    /// 永続化されていたリペア準備対象のオブジェクトをキューに戻す。
+    ///
+    /// 再起動前に既に待ち時間は経過しているはずなので、待たずに処理対象となる。
+    pub(crate) fn push_recovered_repair_prep(&mut self, version: ObjectVersion) {
+        let now = Instant::now();
+        self.repair_prep_queue.push(TodoItem::RepairContent {
+            start_time: now,
+            version,
+            enqueued_at: now,
+        });
+        self.repair_candidates.insert(version);
+    }
+    /// 永続化されていた削除対象のオブジェクトをキューに戻す。
+    pub(crate) fn push_recovered_delete(&mut self, version: ObjectVersion) {
+        self.repair_candidates.remove(&version);
+        self.delete_queue.push(version);
+    }
+    /// キューに積まれているリペア準備対象と削除対象のオブジェクトのバージョンを返す。
+    ///
+    /// 処理中のタスクの対象は含まれない。
+    /// ただし、即座に削除される予定のバージョンは、完了していなければ削除対象に含まれる。
+    pub(crate) fn queued_versions(&self) -> (Vec<ObjectVersion>, Vec<ObjectVersion>) {
+        let repair_prep = self
+            .repair_prep_queue
+            .queue
+            .iter()
+            .filter_map(|item| match item.0 {
+                TodoItem::RepairContent { version, .. } => Some(version),
+                TodoItem::DeleteContent { .. } => None,
+            })
+            .filter(|version| self.repair_candidates.contains(version))
+            .collect();
+        let delete = self
+            .delete_queue
+            .deque
+            .iter()
+            .map(|&(version, _)| version)
+            .chain(self.compaction.iter().flat_map(Compaction::versions))
+            .collect();
+        (repair_prep, delete)
+    }
+    /// リペア準備、削除、即時削除の各キューの長さを返す。
+    pub(crate) fn queue_lengths(&self) -> (usize, usize, usize) {
+        (
+            self.repair_prep_queue.queue.len(),
+            self.delete_queue.deque.len(),
+            self.compaction.as_ref().map_or(0, Compaction::len),
+        )
+    }
+    /// キューの長さと待ち時間のメトリクスを更新する。
+    pub(crate) fn update_metrics(&self) {
+        self.repair_prep_queue.gauges.update();
+        self.delete_queue.gauges.update();
+    }
+    /// 現在のタスクとキューの長さを、タスクダンプ用に報告する。
+    pub(crate) fn report_task_state(&self) {
+        self.tracker.set_state(self.task.name());
+        self.tracker
+            .set_queue_len("repair_prep", self.repair_prep_queue.queue.len());
+        self.tracker
+            .set_queue_len("delete", self.delete_queue.deque.len());
+        if let Some(ref compaction) = self.compaction {
+            self.tracker.set_queue_len("compaction", compaction.len());
+        }
+    }
     /// pop を呼ぶ際には、self.Task は Task::Idle でなければならない。
     /// この関数を呼び出した場合、以下の条件に応じて挙動が変わる。
     /// 1. 待たなければいけない場合: 戻り値は None であり、self.task には Task::Wait がセットされる。
@@ -188,6 +316,9 @@ impl Stream for GeneralQueueExecutor {
     type Item = ObjectVersion;
     type Error = Infallible;
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(ref mut compaction) = self.compaction {
+            compaction.poll();
+        }
         while let Async::Ready(result) = self.task.poll().unwrap_or_else(|e| {
             // 同期処理のエラーは致命的ではないので、ログを出すだけに留める
             warn!(self.logger, "Task failure: {}", e);
@@ -234,23 +365,35 @@ struct RepairPrepQueue {
     queue: BinaryHeap<Reverse<TodoItem>>,
     enqueued: Counter,
     dequeued: Counter,
+    gauges: QueueGauges,
 }
 impl RepairPrepQueue {
-    fn new(enqueued_repair_prep: &Counter, dequeued_repair_prep: &Counter) -> Self {
+    fn new(
+        enqueued_repair_prep: &Counter,
+        dequeued_repair_prep: &Counter,
+        gauges: QueueGauges,
+    ) -> Self {
         Self {
             queue: BinaryHeap::new(),
             enqueued: enqueued_repair_prep.clone(),
             dequeued: dequeued_repair_prep.clone(),
+            gauges,
         }
     }
 }
 impl Queue<TodoItem, TodoItem> for RepairPrepQueue {
     fn push(&mut self, element: TodoItem) {
+        if let TodoItem::RepairContent { enqueued_at, .. } = element {
+            self.gauges.on_push(enqueued_at);
+        }
         self.queue.push(Reverse(element));
         self.enqueued.increment();
     }
     fn pop(&mut self) -> Option<TodoItem> {
         let result = self.queue.pop();
+        if let Some(Reverse(TodoItem::RepairContent { enqueued_at, .. })) = result {
+            self.gauges.on_pop(enqueued_at);
+        }
         if result.is_some() {
             self.dequeued.increment();
         }
@@ -264,22 +407,27 @@ impl Queue<TodoItem, TodoItem> for RepairPrepQueue {
 
 /// Delete 用のキュー。FIFO キューであり、効率のため、最大 DELETE_CONCURRENCY 個単位でまとめて pop できる。
 struct DeleteQueue {
-    deque: VecDeque<ObjectVersion>,
+    // 各要素は、オブジェクトのバージョンとキューに積まれた時刻の組.
+    deque: VecDeque<(ObjectVersion, Instant)>,
     enqueued: Counter,
     dequeued: Counter,
+    gauges: QueueGauges,
 }
 impl DeleteQueue {
-    fn new(enqueued_delete: &Counter, dequeued_delete: &Counter) -> Self {
+    fn new(enqueued_delete: &Counter, dequeued_delete: &Counter, gauges: QueueGauges) -> Self {
         Self {
             deque: VecDeque::new(),
             enqueued: enqueued_delete.clone(),
             dequeued: dequeued_delete.clone(),
+            gauges,
         }
     }
 }
 impl Queue<ObjectVersion, TodoItem> for DeleteQueue {
     fn push(&mut self, element: ObjectVersion) {
-        self.deque.push_back(element);
+        let now = Instant::now();
+        self.deque.push_back((element, now));
+        self.gauges.on_push(now);
         self.enqueued.increment();
     }
     /// Delete すべきオブジェクトがない場合は None を、ある場合は数個まとめた TodoItem を返す。
@@ -291,7 +439,11 @@ impl Queue<ObjectVersion, TodoItem> for DeleteQueue {
             return None;
         }
 
-        let versions: Vec<ObjectVersion> = self.deque.drain(..length).collect();
+        let mut versions = Vec::with_capacity(length);
+        for (version, enqueued_at) in self.deque.drain(..length) {
+            self.gauges.on_pop(enqueued_at);
+            versions.push(version);
+        }
         self.dequeued.add_u64(length as u64);
         if self.deque.capacity() > 32 && self.deque.len() < self.deque.capacity() / 2 {
             self.deque.shrink_to_fit();
@@ -303,9 +455,30 @@ impl Queue<ObjectVersion, TodoItem> for DeleteQueue {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use frugalos_core::hlc::HybridTimestamp;
     use libfrugalos::entity::object::ObjectVersion;
+    use libfrugalos::time::Seconds;
     use prometrics::metrics::MetricBuilder;
 
+    #[test]
+    fn repair_content_waits_for_put_content_timeout() {
+        let item = TodoItem::new(&Event::Putted {
+            version: ObjectVersion(1),
+            put_content_timeout: Seconds(0),
+            timestamp: HybridTimestamp::default(),
+        });
+        assert_eq!(item.wait_time(), None);
+
+        let item = TodoItem::new(&Event::Putted {
+            version: ObjectVersion(2),
+            put_content_timeout: Seconds(60),
+            timestamp: HybridTimestamp::default(),
+        });
+        let wait_time = item.wait_time().expect("should wait");
+        assert!(wait_time > Duration::from_secs(59));
+        assert!(wait_time <= Duration::from_secs(60));
+    }
+
     #[test]
     fn delete_queue_works() {
         // 乱雑な順番のリスト
@@ -313,7 +486,8 @@ mod tests {
         let metric_builder = MetricBuilder::new();
         let enqueued = metric_builder.counter("enqueued").finish().unwrap();
         let dequeued = metric_builder.counter("dequeued").finish().unwrap();
-        let mut queue = DeleteQueue::new(&enqueued, &dequeued);
+        let gauges = QueueGauges::new(&metric_builder, "delete");
+        let mut queue = DeleteQueue::new(&enqueued, &dequeued, gauges);
         for &version in &versions {
             queue.push(version);
         }
@@ -326,5 +500,8 @@ mod tests {
         // キューに突っ込んだ個数とキューから出した個数が等しい
         assert_eq!(enqueued.value() as usize, versions.len());
         assert_eq!(dequeued.value() as usize, versions.len());
+        // 全て取り出したのでキューは空
+        assert_eq!(queue.gauges.length(), 0.0);
+        assert_eq!(queue.gauges.oldest_item_age_seconds(), 0.0);
     }
 }
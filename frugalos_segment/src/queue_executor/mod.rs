@@ -1,2 +1,5 @@
+pub(crate) mod compaction;
 pub(crate) mod general_queue_executor;
+mod queue_metrics;
+pub(crate) mod queue_snapshot;
 pub(crate) mod repair_queue_executor;
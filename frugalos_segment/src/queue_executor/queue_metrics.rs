@@ -0,0 +1,111 @@
+use prometrics::metrics::{Gauge, MetricBuilder};
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// キューに積まれている要素数と、最も古い要素の待ち時間を報告するためのゲージ。
+///
+/// 要素が積まれた時刻を保持しておき、push/pop の度と、呼び出し側のタイマーで
+/// `update` が呼ばれた際にゲージを更新する。
+pub(crate) struct QueueGauges {
+    length: Gauge,
+    oldest_item_age_seconds: Gauge,
+    // 積まれた時刻毎の要素数(多重集合).
+    enqueued_at: BTreeMap<Instant, usize>,
+    len: usize,
+}
+impl QueueGauges {
+    pub(crate) fn new(metric_builder: &MetricBuilder, queue_type: &str) -> Self {
+        let length = metric_builder
+            .gauge("queue_length")
+            .help("Number of items in the queue")
+            .label("type", queue_type)
+            .default_registry()
+            .finish()
+            .expect("metric should be well-formed");
+        let oldest_item_age_seconds = metric_builder
+            .gauge("oldest_item_age_seconds")
+            .help("Time the oldest item in the queue has been waiting")
+            .label("type", queue_type)
+            .default_registry()
+            .finish()
+            .expect("metric should be well-formed");
+        Self {
+            length,
+            oldest_item_age_seconds,
+            enqueued_at: BTreeMap::new(),
+            len: 0,
+        }
+    }
+
+    /// `enqueued_at` に積まれた要素が追加されたことを記録する。
+    pub(crate) fn on_push(&mut self, enqueued_at: Instant) {
+        *self.enqueued_at.entry(enqueued_at).or_insert(0) += 1;
+        self.len += 1;
+        self.update();
+    }
+
+    /// `enqueued_at` に積まれた要素が取り出されたことを記録する。
+    pub(crate) fn on_pop(&mut self, enqueued_at: Instant) {
+        let remove = if let Some(count) = self.enqueued_at.get_mut(&enqueued_at) {
+            *count -= 1;
+            self.len -= 1;
+            *count == 0
+        } else {
+            false
+        };
+        if remove {
+            self.enqueued_at.remove(&enqueued_at);
+        }
+        self.update();
+    }
+
+    /// ゲージの値を現在の状態で更新する。
+    pub(crate) fn update(&self) {
+        self.length.set(self.len as f64);
+        let age = self.enqueued_at.keys().next().map_or(0.0, |t| {
+            prometrics::timestamp::duration_to_seconds(t.elapsed())
+        });
+        self.oldest_item_age_seconds.set(age);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn length(&self) -> f64 {
+        self.length.value()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn oldest_item_age_seconds(&self) -> f64 {
+        self.oldest_item_age_seconds.value()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn queue_gauges_work() {
+        let mut gauges = QueueGauges::new(&MetricBuilder::new(), "test");
+        let now = Instant::now();
+        let old = now - Duration::from_secs(10);
+
+        gauges.on_push(now);
+        gauges.on_push(old);
+        gauges.on_push(old);
+        assert_eq!(gauges.length(), 3.0);
+        assert!(gauges.oldest_item_age_seconds() >= 10.0);
+
+        gauges.on_pop(old);
+        assert_eq!(gauges.length(), 2.0);
+        assert!(gauges.oldest_item_age_seconds() >= 10.0);
+
+        gauges.on_pop(old);
+        assert_eq!(gauges.length(), 1.0);
+        assert!(gauges.oldest_item_age_seconds() < 10.0);
+
+        gauges.on_pop(now);
+        assert_eq!(gauges.length(), 0.0);
+        assert_eq!(gauges.oldest_item_age_seconds(), 0.0);
+    }
+}
@@ -0,0 +1,266 @@
+//! 同期処理用のキューの内容をデバイスに永続化するためのモジュール。
+//!
+//! キューはメモリ上にしか存在しないため、ノードが再起動すると未処理の
+//! リペアや削除は次の FullSync まで放置されてしまう。
+//! これを避けるために、キューの内容を定期的に専用の lump に保存しておき、
+//! `Synchronizer` の生成時に読み込んで復元する。
+use byteorder::{BigEndian, ByteOrder};
+use cannyls::deadline::Deadline;
+use cannyls::device::DeviceHandle;
+use cannyls::lump::{LumpData, LumpId};
+use fibers::time::timer::{self, Timeout};
+use frugalos_raft::NodeId;
+use futures::{Async, Future};
+use libfrugalos::entity::object::ObjectVersion;
+use slog::Logger;
+use std::time::Duration;
+
+use config;
+use util::{into_box_future, BoxFuture};
+use {Error, ErrorKind, Result};
+
+/// キューの内容を保存する間隔。
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// エンコード形式のバージョン。
+const FORMAT_VERSION: u8 = 1;
+
+/// ヘッダのサイズ(バージョン + 各キューの要素数)。
+const HEADER_SIZE: usize = 1 + 4 * 3;
+
+/// 一つの lump に保存できる要素数の上限。
+///
+/// これを超えた分は保存されないが、次の FullSync で回収される。
+const MAX_ITEMS: usize = (LumpData::MAX_SIZE - HEADER_SIZE) / 8;
+
+/// 保存対象となるキューの内容。
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct QueueSnapshot {
+    pub repair_prep: Vec<ObjectVersion>,
+    pub delete: Vec<ObjectVersion>,
+    pub repair: Vec<ObjectVersion>,
+}
+impl QueueSnapshot {
+    pub fn len(&self) -> usize {
+        self.repair_prep.len() + self.delete.len() + self.repair.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 要素数が `MAX_ITEMS` に収まるように切り詰める。
+    ///
+    /// 優先度の低いキュー(削除、リペア準備、リペアの順)から切り詰められる。
+    fn truncate(&mut self, max_items: usize) -> usize {
+        let mut excess = self.len().saturating_sub(max_items);
+        let truncated = excess;
+        for queue in &mut [&mut self.delete, &mut self.repair_prep, &mut self.repair] {
+            let n = ::std::cmp::min(excess, queue.len());
+            let len = queue.len() - n;
+            queue.truncate(len);
+            excess -= n;
+        }
+        truncated
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_SIZE + self.len() * 8];
+        bytes[0] = FORMAT_VERSION;
+        BigEndian::write_u32(&mut bytes[1..5], self.repair_prep.len() as u32);
+        BigEndian::write_u32(&mut bytes[5..9], self.delete.len() as u32);
+        BigEndian::write_u32(&mut bytes[9..13], self.repair.len() as u32);
+        let versions = self
+            .repair_prep
+            .iter()
+            .chain(self.delete.iter())
+            .chain(self.repair.iter());
+        for (chunk, version) in bytes[HEADER_SIZE..].chunks_mut(8).zip(versions) {
+            BigEndian::write_u64(chunk, version.0);
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        track_assert!(bytes.len() >= HEADER_SIZE, ErrorKind::Corrupted; bytes.len());
+        track_assert_eq!(bytes[0], FORMAT_VERSION, ErrorKind::Corrupted);
+        let repair_prep_len = BigEndian::read_u32(&bytes[1..5]) as usize;
+        let delete_len = BigEndian::read_u32(&bytes[5..9]) as usize;
+        let repair_len = BigEndian::read_u32(&bytes[9..13]) as usize;
+        let body = &bytes[HEADER_SIZE..];
+        track_assert_eq!(
+            body.len(),
+            (repair_prep_len + delete_len + repair_len) * 8,
+            ErrorKind::Corrupted
+        );
+        let mut versions = body
+            .chunks(8)
+            .map(|chunk| ObjectVersion(BigEndian::read_u64(chunk)));
+        Ok(QueueSnapshot {
+            repair_prep: versions.by_ref().take(repair_prep_len).collect(),
+            delete: versions.by_ref().take(delete_len).collect(),
+            repair: versions.take(repair_len).collect(),
+        })
+    }
+}
+
+/// `QueueSnapshot` の保存と読み込みを担当する。
+pub(crate) struct QueueSnapshotStore {
+    logger: Logger,
+    device: DeviceHandle,
+    lump_id: LumpId,
+    loading: Option<BoxFuture<Option<LumpData>>>,
+    saving: Option<BoxFuture<bool>>,
+    timeout: Timeout,
+    last_saved_is_empty: bool,
+}
+impl QueueSnapshotStore {
+    /// 新しい `QueueSnapshotStore` を生成し、保存済みのキューの読み込みを開始する。
+    pub fn new(logger: &Logger, node_id: NodeId, device: &DeviceHandle) -> Self {
+        let lump_id = config::make_queue_snapshot_lump_id(&node_id);
+        let loading = device.request().deadline(Deadline::Infinity).get(lump_id);
+        QueueSnapshotStore {
+            logger: logger.clone(),
+            device: device.clone(),
+            lump_id,
+            loading: Some(into_box_future(loading)),
+            saving: None,
+            timeout: timer::timeout(SAVE_INTERVAL),
+            last_saved_is_empty: false,
+        }
+    }
+
+    /// 保存済みのキューの読み込みが完了していたら、その内容を返す。
+    ///
+    /// 内容が返されるのは一度だけである。
+    /// 読み込みに失敗した場合には、警告を出して空のキューとして扱う。
+    pub fn poll_load(&mut self) -> Option<QueueSnapshot> {
+        let result = match self.loading.poll() {
+            Ok(Async::NotReady) | Ok(Async::Ready(None)) => return None,
+            Ok(Async::Ready(Some(data))) => data.and_then(|data| {
+                QueueSnapshot::decode(data.as_bytes())
+                    .map_err(|e| warn!(self.logger, "Cannot decode queue snapshot: {}", e))
+                    .ok()
+            }),
+            Err(e) => {
+                warn!(self.logger, "Cannot load queue snapshot: {}", e);
+                None
+            }
+        };
+        self.loading = None;
+        result
+    }
+
+    /// キューの内容を保存すべきタイミングかどうかを返す。
+    ///
+    /// 読み込みや前回の保存が完了していない間は `false` を返す。
+    pub fn poll_save_timing(&mut self) -> bool {
+        match self.saving.poll() {
+            Err(e) => {
+                warn!(self.logger, "Cannot save queue snapshot: {}", e);
+                self.saving = None;
+            }
+            Ok(Async::Ready(Some(_))) => {
+                self.saving = None;
+            }
+            Ok(Async::Ready(None)) | Ok(Async::NotReady) => {}
+        }
+        if self.loading.is_some() || self.saving.is_some() {
+            return false;
+        }
+        match self.timeout.poll() {
+            Ok(Async::NotReady) => false,
+            _ => {
+                self.timeout = timer::timeout(SAVE_INTERVAL);
+                true
+            }
+        }
+    }
+
+    /// キューの内容の保存を開始する。
+    pub fn save(&mut self, mut snapshot: QueueSnapshot) -> Result<()> {
+        if snapshot.is_empty() && self.last_saved_is_empty {
+            return Ok(());
+        }
+        let truncated = snapshot.truncate(MAX_ITEMS);
+        if truncated > 0 {
+            warn!(
+                self.logger,
+                "Too many items in queues; {} items are not saved", truncated
+            );
+        }
+        self.last_saved_is_empty = snapshot.is_empty();
+        let future = if snapshot.is_empty() {
+            into_box_future(
+                self.device
+                    .request()
+                    .deadline(Deadline::Infinity)
+                    .delete(self.lump_id),
+            )
+        } else {
+            let data = track!(self
+                .device
+                .allocate_lump_data_with_bytes(&snapshot.encode())
+                .map_err(Error::from))?;
+            into_box_future(
+                self.device
+                    .request()
+                    .deadline(Deadline::Infinity)
+                    .put(self.lump_id, data),
+            )
+        };
+        self.saving = Some(future);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trackable::result::TestResult;
+
+    fn versions(vs: &[u64]) -> Vec<ObjectVersion> {
+        vs.iter().cloned().map(ObjectVersion).collect()
+    }
+
+    #[test]
+    fn encode_and_decode_works() -> TestResult {
+        let snapshot = QueueSnapshot {
+            repair_prep: versions(&[3, 1, 2]),
+            delete: versions(&[10]),
+            repair: versions(&[7, 8]),
+        };
+        let decoded = track!(QueueSnapshot::decode(&snapshot.encode()))?;
+        assert_eq!(decoded, snapshot);
+
+        let empty = QueueSnapshot::default();
+        assert_eq!(track!(QueueSnapshot::decode(&empty.encode()))?, empty);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_rejects_corrupted_bytes() {
+        let snapshot = QueueSnapshot {
+            repair_prep: versions(&[1]),
+            delete: versions(&[2]),
+            repair: versions(&[3]),
+        };
+        let mut bytes = snapshot.encode();
+        bytes.pop();
+        assert!(QueueSnapshot::decode(&bytes).is_err());
+        assert!(QueueSnapshot::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn truncate_drops_low_priority_items_first() {
+        let mut snapshot = QueueSnapshot {
+            repair_prep: versions(&[1, 2]),
+            delete: versions(&[3, 4, 5]),
+            repair: versions(&[6]),
+        };
+        assert_eq!(snapshot.truncate(2), 4);
+        assert_eq!(snapshot.delete, versions(&[]));
+        assert_eq!(snapshot.repair_prep, versions(&[1]));
+        assert_eq!(snapshot.repair, versions(&[6]));
+    }
+}
@@ -1,15 +1,18 @@
 use cannyls::device::DeviceHandle;
+use frugalos_core::task_dump::TaskTracker;
 use frugalos_raft::NodeId;
 use futures::{Async, Future, Poll};
 use libfrugalos::entity::object::ObjectVersion;
 use libfrugalos::repair::RepairIdleness;
 use prometrics::metrics::{Counter, MetricBuilder};
 use slog::Logger;
-use std::collections::BTreeSet;
+use std::collections::BTreeMap;
 use std::convert::Infallible;
 use std::time::Instant;
 
+use super::queue_metrics::QueueGauges;
 use client::storage::StorageClient;
+use maintenance::MaintenanceSchedule;
 use repair::{RepairContent, RepairMetrics};
 use service::{RepairLock, ServiceHandle};
 use Error;
@@ -26,6 +29,12 @@ impl Task {
             Task::Repair(_, _) => false,
         }
     }
+    fn name(&self) -> &'static str {
+        match self {
+            Task::Idle => "Idle",
+            Task::Repair(_, _) => "Repair",
+        }
+    }
 }
 impl Future for Task {
     type Item = ();
@@ -33,7 +42,9 @@ impl Future for Task {
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         match *self {
             Task::Idle => Ok(Async::Ready(())),
-            Task::Repair(ref mut f, _) => track!(f.poll().map_err(Error::from)),
+            Task::Repair(ref mut f, _) => {
+                track!(f.poll().map_err(Error::from)).map(|a| a.map(|_| ()))
+            }
         }
     }
 }
@@ -46,13 +57,17 @@ pub(crate) struct RepairQueueExecutor {
     client: StorageClient,
     service_handle: ServiceHandle,
     task: Task,
-    queue: BTreeSet<ObjectVersion>,
+    // オブジェクトのバージョンから、キューに積まれた時刻へのマップ.
+    queue: BTreeMap<ObjectVersion, Instant>,
     // The idleness threshold for repair functionality.
     repair_idleness_threshold: RepairIdleness,
     last_not_idle: Instant,
     repair_metrics: RepairMetrics,
     enqueued_repair: Counter,
     dequeued_repair: Counter,
+    gauges: QueueGauges,
+    tracker: TaskTracker,
+    maintenance: MaintenanceSchedule,
 }
 impl RepairQueueExecutor {
     #[allow(clippy::too_many_arguments)]
@@ -65,6 +80,7 @@ impl RepairQueueExecutor {
         metric_builder: &MetricBuilder,
         enqueued_repair: &Counter,
         dequeued_repair: &Counter,
+        maintenance: MaintenanceSchedule,
     ) -> Self {
         RepairQueueExecutor {
             logger: logger.clone(),
@@ -73,26 +89,70 @@ impl RepairQueueExecutor {
             client: client.clone(),
             service_handle: service_handle.clone(),
             task: Task::Idle,
-            queue: BTreeSet::new(),
+            queue: BTreeMap::new(),
             repair_idleness_threshold: RepairIdleness::Disabled,
             last_not_idle: Instant::now(),
             repair_metrics: RepairMetrics::new(metric_builder),
             enqueued_repair: enqueued_repair.clone(),
             dequeued_repair: dequeued_repair.clone(),
+            gauges: QueueGauges::new(metric_builder, "repair"),
+            tracker: TaskTracker::new("repair_queue_executor", &node_id.local_id.to_string()),
+            maintenance,
         }
     }
     /// Pushes an element into this queue.
     pub(crate) fn push(&mut self, version: ObjectVersion) {
+        self.push_with_enqueued_at(version, Instant::now());
+    }
+    /// 一度取り出した要素を、積まれた時刻を保ったままキューに戻す。
+    fn push_with_enqueued_at(&mut self, version: ObjectVersion, enqueued_at: Instant) {
         // Insert version. Also, increment enqueued_repair if version was absent before insertion.
-        if self.queue.insert(version) {
+        if !self.queue.contains_key(&version) {
+            self.queue.insert(version, enqueued_at);
+            self.gauges.on_push(enqueued_at);
             self.enqueued_repair.increment();
         }
     }
-    fn pop(&mut self) -> Option<ObjectVersion> {
+    /// キューに積まれているリペア対象のオブジェクトのバージョンを返す。
+    ///
+    /// 処理中のタスクの対象は含まれない。
+    pub(crate) fn queued_versions(&self) -> Vec<ObjectVersion> {
+        self.queue.keys().cloned().collect()
+    }
+    /// キューに積まれているリペア対象の数を返す。
+    pub(crate) fn len(&self) -> usize {
+        self.queue.len()
+    }
+    /// キューの長さと待ち時間のメトリクスを更新する。
+    pub(crate) fn update_metrics(&self) {
+        self.gauges.update();
+    }
+    /// 現在のタスクとキューの長さを、タスクダンプ用に報告する。
+    pub(crate) fn report_task_state(&self) {
+        self.tracker.set_state(self.task.name());
+        self.tracker.set_queue_len("repair", self.queue.len());
+    }
+    /// 中断が要求されている場合には、実行中のリペアを中断して対象をキューに戻す。
+    fn handle_cancel_request(&mut self) {
+        if !self.tracker.take_cancel_request() {
+            return;
+        }
+        if let Task::Repair(ref repair, _) = self.task {
+            let version = repair.version();
+            warn!(
+                self.logger,
+                "The repair task is cancelled and requeued: version={:?}", version
+            );
+            self.task = Task::Idle;
+            self.push(version);
+        }
+    }
+    fn pop(&mut self) -> Option<(ObjectVersion, Instant)> {
         // Pick the minimum element, if queue is not empty.
-        let result = self.queue.iter().next().copied();
-        if let Some(version) = result {
+        let result = self.queue.iter().next().map(|(&v, &t)| (v, t));
+        if let Some((version, enqueued_at)) = result {
             self.queue.remove(&version);
+            self.gauges.on_pop(enqueued_at);
             self.dequeued_repair.increment();
         }
         result
@@ -112,6 +172,7 @@ impl Future for RepairQueueExecutor {
     type Item = Infallible; // This executor will never finish normally.
     type Error = Infallible;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.handle_cancel_request();
         if !self.task.is_sleeping() {
             self.last_not_idle = Instant::now();
             debug!(self.logger, "last_not_idle = {:?}", self.last_not_idle);
@@ -122,14 +183,18 @@ impl Future for RepairQueueExecutor {
             warn!(self.logger, "Task failure in RepairQueueExecutor: {}", e);
             Async::Ready(())
         }) {
+            if !self.task.is_sleeping() {
+                self.tracker.report_progress();
+            }
             self.task = Task::Idle;
             if let RepairIdleness::Threshold(repair_idleness_threshold_duration) =
                 self.repair_idleness_threshold
             {
-                if let Some(version) = self.pop() {
+                if let Some((version, enqueued_at)) = self.pop() {
                     let elapsed = self.last_not_idle.elapsed();
-                    if elapsed < repair_idleness_threshold_duration {
-                        self.push(version);
+                    // メンテナンスウィンドウ外では、新たなリペアは開始しない
+                    if elapsed < repair_idleness_threshold_duration || !self.maintenance.is_open() {
+                        self.push_with_enqueued_at(version, enqueued_at);
                         break;
                     } else {
                         let repair_lock = self.service_handle.acquire_repair_lock();
@@ -147,7 +212,7 @@ impl Future for RepairQueueExecutor {
                             );
                             self.last_not_idle = Instant::now();
                         } else {
-                            self.push(version);
+                            self.push_with_enqueued_at(version, enqueued_at);
                             break;
                         }
                     }
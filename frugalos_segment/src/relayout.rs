@@ -0,0 +1,385 @@
+//! MDS のリーダ上で、セグメントの再配置ジョブ(`frugalos_mds::machine::RelayoutJob`)を進めるための処理。
+//!
+//! ジョブの状態は MDS に複製されており、ここで保持するのはリーダ毎の一時的な状態のみである。
+//! リーダが交代した場合には、新しいリーダが複製された状態から処理を再開する。
+//!
+//! 処理の流れは以下の通り:
+//!
+//! 1. 変更前の配置のリーダが`Start`を提案する
+//! 2. 猶予期間(全てのクライアントが新しい配置に書き込むようになるまでの時間)の経過後、
+//!    既存のオブジェクトの内容をバージョンの昇順に新しい配置にコピーし、進捗を`Progress`として記録する
+//! 3. 残りが一バッチ分以下になったら`Freeze`を提案し、オブジェクトの変更を凍結する
+//! 4. 猶予期間の経過後、残りの内容をコピーして、MDS のメンバ群を新しい配置に切り替える
+//! 5. 切り替えが完了したら`Finish`を提案して凍結を解除し、構成管理用クラスタに完了を通知する
+//!
+//! 凍結中は、セグメントへの書き込みや削除は`Busy`として失敗する。
+//! 凍結前のコピーはメンテナンスウィンドウ内でのみ行い、凍結後は書き込みの停止を短くするために、ウィンドウに関わらず進める。
+use cannyls::deadline::Deadline;
+use cannyls::device::DeviceHandle;
+use frugalos_mds::machine::{RelayoutCommand, RelayoutJob, RelayoutState};
+use frugalos_mds::Node;
+use frugalos_raft::NodeId;
+use futures::future::{self, Either};
+use futures::{self, Async, Future, Stream};
+use libfrugalos::entity::object::ObjectVersion;
+use raftlog::log::LogIndex;
+use rustracing_jaeger::span::Span;
+use slog::Logger;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use client::budget::RequestBudget;
+use client::storage::StorageClient;
+use config::{content_lump_id_range, make_queue_snapshot_lump_id};
+use maintenance::MaintenanceSchedule;
+use util::BoxFuture;
+use {Error, ErrorKind, ObjectValue, Result};
+
+/// 同時にコピーするオブジェクトの数。
+const COPY_CONCURRENCY: usize = 8;
+
+/// コピーや完了通知に失敗した場合に、再試行するまでの待ち時間。
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 構成管理用クラスタに、セグメントの切り替えの完了を通知する関数。
+pub type NotifyRelayoutFinished =
+    Arc<dyn Fn() -> Box<dyn Future<Item = (), Error = Error> + Send> + Send + Sync>;
+
+/// セグメントの再配置先。
+#[derive(Clone)]
+pub struct RelayoutTarget {
+    /// 再配置先のデバイスグループの世代。
+    pub generation: u8,
+
+    /// 再配置後の MDS のメンバ群。
+    pub members: Vec<NodeId>,
+
+    /// 切り替えの完了を構成管理用クラスタに通知する関数。
+    ///
+    /// 通知は再送され得るので、冪等である必要がある。
+    pub notify_finished: NotifyRelayoutFinished,
+}
+
+/// セグメントの再配置の進捗。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayoutProgress {
+    /// 再配置先のデバイスグループの世代。
+    pub generation: u8,
+
+    /// ジョブの状態(`copying`、`frozen`、`finished`のいずれか)。
+    pub state: String,
+
+    /// このバージョン以下の内容は、全て新しい配置にコピー済み。
+    pub cursor: u64,
+
+    /// 新しい配置にコピーしたバージョンの数。
+    pub copied: u64,
+
+    /// このノードでの、直近のコピーないし通知の失敗理由。
+    pub last_error: Option<String>,
+}
+impl RelayoutProgress {
+    pub(crate) fn new(job: &RelayoutJob, last_error: Option<String>) -> Self {
+        let state = match job.state {
+            RelayoutState::Copying => "copying",
+            RelayoutState::Frozen => "frozen",
+            RelayoutState::Finished => "finished",
+        };
+        RelayoutProgress {
+            generation: job.generation,
+            state: state.to_owned(),
+            cursor: job.cursor.0,
+            copied: job.copied,
+            last_error,
+        }
+    }
+}
+
+/// 再配置ジョブを進めるためのオブジェクト。
+///
+/// 再配置中のセグメントの、変更前と変更後の両方の配置のノードが保持し、リーダになった場合にのみ処理を行う。
+pub(crate) struct RelayoutDriver {
+    logger: Logger,
+    target: RelayoutTarget,
+    new_storage: StorageClient,
+    previous_storage: StorageClient,
+    batch_size: usize,
+    grace_period: Duration,
+    maintenance: MaintenanceSchedule,
+
+    // 以下はリーダ毎の一時的な状態
+    ready_at: Option<Instant>,
+    proposed_at: Option<LogIndex>,
+    retry_at: Option<Instant>,
+    in_flight: Option<InFlight>,
+    notified: bool,
+    last_error: Option<String>,
+}
+impl RelayoutDriver {
+    /// `storage`は再配置中のセグメントのストレージで、そうでない場合には`None`を返す。
+    pub fn new(
+        logger: Logger,
+        target: RelayoutTarget,
+        storage: &StorageClient,
+        batch_size: usize,
+        grace_period: Duration,
+        maintenance: MaintenanceSchedule,
+    ) -> Option<Self> {
+        let (new_storage, previous_storage) = storage.relayouting()?;
+        Some(RelayoutDriver {
+            logger,
+            target,
+            new_storage: new_storage.clone(),
+            previous_storage: previous_storage.clone(),
+            batch_size,
+            grace_period,
+            maintenance,
+            ready_at: None,
+            proposed_at: None,
+            retry_at: None,
+            in_flight: None,
+            notified: false,
+            last_error: None,
+        })
+    }
+
+    /// このノードでの、直近の失敗理由を返す。
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.clone()
+    }
+
+    /// ジョブを一段階進める。
+    ///
+    /// ノードがリーダでない場合には何もしない(リーダ毎の状態は破棄される)。
+    pub fn poll(&mut self, node: &mut Node) {
+        if !node.is_leader() || node.synced_machine().is_none() {
+            self.reset();
+            return;
+        }
+        let result = track!(self.poll_in_flight(node)).and_then(|()| track!(self.step(node)));
+        if let Err(e) = result {
+            warn!(
+                self.logger,
+                "Relayout failed (retries after {:?}): {}", RETRY_INTERVAL, e
+            );
+            self.last_error = Some(e.to_string());
+            self.retry_at = Some(Instant::now() + RETRY_INTERVAL);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ready_at = None;
+        self.proposed_at = None;
+        self.retry_at = None;
+        self.in_flight = None;
+        self.notified = false;
+    }
+
+    fn poll_in_flight(&mut self, node: &mut Node) -> Result<()> {
+        match self.in_flight.take() {
+            None => {}
+            Some(InFlight::Copy(mut future)) => match track!(future.poll())? {
+                Async::NotReady => self.in_flight = Some(InFlight::Copy(future)),
+                Async::Ready((cursor, copied)) => {
+                    let total = node
+                        .relayout_job()
+                        .filter(|job| job.generation == self.target.generation)
+                        .map_or(0, |job| job.copied);
+                    let command = RelayoutCommand::Progress {
+                        generation: self.target.generation,
+                        cursor,
+                        copied: total + copied,
+                    };
+                    self.proposed_at = Some(track!(node.propose_relayout(command))?);
+                    self.last_error = None;
+                }
+            },
+            Some(InFlight::Notify(mut future)) => match track!(future.poll())? {
+                Async::NotReady => self.in_flight = Some(InFlight::Notify(future)),
+                Async::Ready(()) => {
+                    info!(
+                        self.logger,
+                        "Segment relayout is finished: generation={}", self.target.generation
+                    );
+                    self.notified = true;
+                    self.last_error = None;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, node: &mut Node) -> Result<()> {
+        if self.in_flight.is_some() {
+            return Ok(());
+        }
+        if let Some(index) = self.proposed_at {
+            if node.get_next_commit() <= index {
+                // 前回の提案がまだ適用されていない
+                return Ok(());
+            }
+            self.proposed_at = None;
+        }
+        if let Some(retry_at) = self.retry_at {
+            if Instant::now() < retry_at {
+                return Ok(());
+            }
+            self.retry_at = None;
+        }
+
+        let generation = self.target.generation;
+        let job = match node.relayout_job().cloned() {
+            Some(ref job)
+                if job.generation != generation && job.state != RelayoutState::Finished =>
+            {
+                track_panic!(
+                    ErrorKind::Other,
+                    "Another relayout job is running: {:?}",
+                    job
+                );
+            }
+            Some(job) if job.generation == generation => job,
+            _ => {
+                let command = RelayoutCommand::Start { generation };
+                self.proposed_at = Some(track!(node.propose_relayout(command))?);
+                return Ok(());
+            }
+        };
+
+        if job.state == RelayoutState::Finished {
+            if !self.notified {
+                self.in_flight = Some(InFlight::Notify((self.target.notify_finished)()));
+            }
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let ready_at = *self.ready_at.get_or_insert(now + self.grace_period);
+        if now < ready_at {
+            return Ok(());
+        }
+
+        if job.state == RelayoutState::Copying && !self.maintenance.is_open() {
+            // 凍結前のコピーは、メンテナンスウィンドウ内でのみ進める
+            return Ok(());
+        }
+        let mut versions = node.relayout_versions_after(job.cursor);
+        if job.state == RelayoutState::Copying && versions.len() <= self.batch_size {
+            // 残りは凍結後にコピーする(書き込みが続いていても、ジョブが終わるようにするため)
+            info!(
+                self.logger,
+                "Freezes the segment to copy the remaining contents: {}",
+                dump!(job, versions.len())
+            );
+            let command = RelayoutCommand::Freeze { generation };
+            self.proposed_at = Some(track!(node.propose_relayout(command))?);
+            // 凍結前に受け付けた書き込みが完了するまで待つ
+            self.ready_at = None;
+            return Ok(());
+        }
+        if !versions.is_empty() {
+            versions.truncate(self.batch_size);
+            self.in_flight = Some(InFlight::Copy(self.copy(versions)));
+            return Ok(());
+        }
+
+        // 全てのコピーが完了した
+        if node.has_members(&self.target.members) {
+            let command = RelayoutCommand::Finish { generation };
+            self.proposed_at = Some(track!(node.propose_relayout(command))?);
+        } else {
+            track!(node.propose_members(&self.target.members))?;
+        }
+        Ok(())
+    }
+
+    /// `versions`の内容を、変更前の配置から新しい配置にコピーする。
+    ///
+    /// 結果は、コピー済みになったバージョンの上限と、実際にコピーしたバージョンの数の組。
+    fn copy(&self, versions: Vec<ObjectVersion>) -> BoxFuture<(ObjectVersion, u64)> {
+        let cursor = *versions.last().expect("Never fails");
+        let new = self.new_storage.clone();
+        let previous = self.previous_storage.clone();
+        let future = futures::stream::iter_ok(versions)
+            .map(move |version| copy_content(new.clone(), previous.clone(), version))
+            .buffered(COPY_CONCURRENCY)
+            .fold(0, |copied, c| -> Result<u64> { Ok(copied + c as u64) })
+            .map(move |copied| (cursor, copied));
+        Box::new(future)
+    }
+}
+
+enum InFlight {
+    Copy(BoxFuture<(ObjectVersion, u64)>),
+    Notify(Box<dyn Future<Item = (), Error = Error> + Send>),
+}
+
+/// `version`の内容が新しい配置に揃っていなければ、変更前の配置から読み込んで書き込む。
+///
+/// 結果は、実際にコピーしたかどうか。
+/// 一部の断片の書き込みにでも失敗した場合には、カーソルを進めないようにエラーとする。
+fn copy_content(
+    new: StorageClient,
+    previous: StorageClient,
+    version: ObjectVersion,
+) -> BoxFuture<bool> {
+    // NOTE: 通常の要求よりも優先度を下げるために、期限は設けない
+    let deadline = Deadline::Infinity;
+    let future = new
+        .clone()
+        .audit(version, deadline)
+        .and_then(move |report| {
+            if report.is_healthy() {
+                return Either::A(future::ok(false));
+            }
+            let object = ObjectValue {
+                version,
+                content: Vec::new(),
+            };
+            let future = previous
+                .get(
+                    object,
+                    deadline,
+                    Span::inactive().handle(),
+                    RequestBudget::unlimited(),
+                )
+                .and_then(move |content| {
+                    new.put(version, content.into(), deadline, Span::inactive().handle())
+                })
+                .and_then(move |durability| {
+                    track_assert!(
+                        !durability.is_degraded(),
+                        ErrorKind::Other,
+                        "Cannot copy all fragments: version={:?}, failed_fragments={}",
+                        version,
+                        durability.failed_fragments
+                    );
+                    Ok(true)
+                });
+            Either::B(future)
+        });
+    Box::new(future)
+}
+
+/// 再配置によって不要になったノードが、ローカルのデバイスに保存していたデータを全て削除する。
+///
+/// ノードの停止後に呼び出す必要がある。
+pub(crate) fn delete_node_data(device: &DeviceHandle, node: NodeId) -> BoxFuture<()> {
+    let raft = device
+        .request()
+        .deadline(Deadline::Infinity)
+        .delete_range(node.local_id.to_available_lump_id_range());
+    let contents = device
+        .request()
+        .deadline(Deadline::Infinity)
+        .delete_range(content_lump_id_range(&node));
+    let queue = device
+        .request()
+        .deadline(Deadline::Infinity)
+        .delete(make_queue_snapshot_lump_id(&node));
+    Box::new(
+        raft.join3(contents, queue)
+            .map(|_| ())
+            .map_err(|e| track!(Error::from(e))),
+    )
+}
@@ -1,7 +1,7 @@
 use cannyls::deadline::Deadline;
 use cannyls::device::DeviceHandle;
-use cannyls::lump::LumpHeader;
-use client::storage::{GetFragment, MaybeFragment, StorageClient};
+use client::storage::{verify_and_remove_checksum, GetFragment, MaybeFragment, StorageClient};
+use frugalos_core::prometheus;
 use frugalos_raft::NodeId;
 use futures::{Async, Future, Poll};
 use libfrugalos::entity::object::ObjectVersion;
@@ -12,6 +12,9 @@ use std::time::Instant;
 use util::{into_box_future, BoxFuture, Phase3};
 use {config, Error};
 
+/// リペアの所要時間のヒストグラムのデフォルトのバケツ(秒).
+const REPAIR_DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
 #[derive(Clone)]
 pub(crate) struct RepairMetrics {
     pub(crate) repairs_success_total: Counter,
@@ -24,6 +27,7 @@ pub(crate) struct RepairMetrics {
 
 impl RepairMetrics {
     pub(crate) fn new(metric_builder: &MetricBuilder) -> Self {
+        let config = prometheus::config();
         RepairMetrics {
             repairs_success_total: metric_builder
                 .counter("repairs_success_total")
@@ -40,47 +44,32 @@ impl RepairMetrics {
                 .label("type", "repair")
                 .finish()
                 .expect("metric should be well-formed"),
-            repairs_durations_seconds_step_1: metric_builder
-                .histogram("repairs_durations_seconds_step_1")
-                .bucket(0.001)
-                .bucket(0.005)
-                .bucket(0.01)
-                .bucket(0.05)
-                .bucket(0.1)
-                .bucket(0.5)
-                .bucket(1.0)
-                .bucket(5.0)
-                .bucket(10.0)
-                .label("type", "repair")
-                .finish()
+            repairs_durations_seconds_step_1: config
+                .configure_histogram_or(
+                    "frugalos_synchronizer_repairs_durations_seconds_step_1",
+                    REPAIR_DURATION_BUCKETS,
+                    metric_builder
+                        .histogram("repairs_durations_seconds_step_1")
+                        .label("type", "repair"),
+                )
                 .expect("metric should be well-formed"),
-            repairs_durations_seconds_step_2: metric_builder
-                .histogram("repairs_durations_seconds_step_2")
-                .bucket(0.001)
-                .bucket(0.005)
-                .bucket(0.01)
-                .bucket(0.05)
-                .bucket(0.1)
-                .bucket(0.5)
-                .bucket(1.0)
-                .bucket(5.0)
-                .bucket(10.0)
-                .label("type", "repair")
-                .finish()
+            repairs_durations_seconds_step_2: config
+                .configure_histogram_or(
+                    "frugalos_synchronizer_repairs_durations_seconds_step_2",
+                    REPAIR_DURATION_BUCKETS,
+                    metric_builder
+                        .histogram("repairs_durations_seconds_step_2")
+                        .label("type", "repair"),
+                )
                 .expect("metric should be well-formed"),
-            repairs_durations_seconds: metric_builder
-                .histogram("repairs_durations_seconds")
-                .bucket(0.001)
-                .bucket(0.005)
-                .bucket(0.01)
-                .bucket(0.05)
-                .bucket(0.1)
-                .bucket(0.5)
-                .bucket(1.0)
-                .bucket(5.0)
-                .bucket(10.0)
-                .label("type", "repair")
-                .finish()
+            repairs_durations_seconds: config
+                .configure_histogram_or(
+                    "frugalos_synchronizer_repairs_durations_seconds",
+                    REPAIR_DURATION_BUCKETS,
+                    metric_builder
+                        .histogram("repairs_durations_seconds")
+                        .label("type", "repair"),
+                )
                 .expect("metric should be well-formed"),
         }
     }
@@ -124,6 +113,61 @@ impl Future for RepairPrepContent {
     }
 }
 
+/// オブジェクトの中身のリペアの結果。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RepairOutcome {
+    /// 中身が正常に存在していたので、リペアは不要だった。
+    Healthy,
+
+    /// 中身が存在しない(あるいは壊れていた)ので、リペアした。
+    Repaired,
+
+    /// このノードは対象オブジェクトの中身を保持する必要がない。
+    NotParticipant,
+}
+
+/// セグメント内の全ノードに対して行った、オブジェクト単位のリペアの結果。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectRepairSummary {
+    /// リペア対象となったオブジェクトのバージョン。
+    pub version: ObjectVersion,
+
+    /// 各ノードでのリペアの結果。
+    pub nodes: Vec<NodeRepairResult>,
+}
+
+/// 一つのノードでのリペアの結果。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeRepairResult {
+    /// ノードの ID。
+    pub node: String,
+
+    /// リペアの結果 (失敗した場合は`None`)。
+    pub outcome: Option<RepairOutcome>,
+
+    /// リペアに失敗した場合のエラーメッセージ。
+    pub error: Option<String>,
+}
+impl NodeRepairResult {
+    pub(crate) fn new<E: ToString>(
+        node: String,
+        result: ::std::result::Result<RepairOutcome, E>,
+    ) -> Self {
+        match result {
+            Ok(outcome) => NodeRepairResult {
+                node,
+                outcome: Some(outcome),
+                error: None,
+            },
+            Err(e) => NodeRepairResult {
+                node,
+                outcome: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
 // NOTE
 // ====
 //
@@ -145,7 +189,8 @@ pub(crate) struct RepairContent {
     device: DeviceHandle,
     started_at: Instant,
     repair_metrics: RepairMetrics,
-    phase: Phase3<BoxFuture<Option<LumpHeader>>, GetFragment, BoxFuture<bool>>,
+    // A: 手元の中身が正常かどうかの確認
+    phase: Phase3<BoxFuture<bool>, GetFragment, BoxFuture<bool>>,
 }
 impl RepairContent {
     pub fn new(
@@ -155,18 +200,94 @@ impl RepairContent {
         client: &StorageClient,
         repair_metrics: &RepairMetrics,
         version: ObjectVersion,
+    ) -> Self {
+        let lump_id = config::make_lump_id(&node_id, version);
+        let check = into_box_future(
+            device
+                .request()
+                .deadline(Deadline::Infinity)
+                .head(lump_id)
+                .map(|header| header.is_some()),
+        );
+        Self::with_check(
+            logger,
+            device,
+            node_id,
+            client,
+            repair_metrics,
+            version,
+            check,
+        )
+    }
+
+    /// リペア対象のオブジェクトのバージョンを返す。
+    pub fn version(&self) -> ObjectVersion {
+        self.version
+    }
+
+    /// 存在確認だけではなく、中身を読み込んでチェックサムの検証まで行う`RepairContent`を生成する。
+    ///
+    /// 中身が壊れていた場合には、存在しない場合と同様に他のノードから復元して上書きする。
+    pub fn with_verification(
+        logger: &Logger,
+        device: &DeviceHandle,
+        node_id: NodeId,
+        client: &StorageClient,
+        repair_metrics: &RepairMetrics,
+        version: ObjectVersion,
+    ) -> Self {
+        let lump_id = config::make_lump_id(&node_id, version);
+        let logger0 = logger.clone();
+        let check = into_box_future(
+            device
+                .request()
+                .deadline(Deadline::Infinity)
+                .get(lump_id)
+                .map(move |data| {
+                    data.map_or(false, |data| {
+                        let mut content = data.as_bytes().to_vec();
+                        if let Err(e) = track!(verify_and_remove_checksum(&mut content)) {
+                            warn!(
+                                logger0,
+                                "Corrupted content: version={:?}, error={}", version, e
+                            );
+                            false
+                        } else {
+                            true
+                        }
+                    })
+                }),
+        );
+        Self::with_check(
+            logger,
+            device,
+            node_id,
+            client,
+            repair_metrics,
+            version,
+            check,
+        )
+    }
+
+    fn with_check(
+        logger: &Logger,
+        device: &DeviceHandle,
+        node_id: NodeId,
+        client: &StorageClient,
+        repair_metrics: &RepairMetrics,
+        version: ObjectVersion,
+        check: BoxFuture<bool>,
     ) -> Self {
         let logger = logger.clone();
         let device = device.clone();
-        let lump_id = config::make_lump_id(&node_id, version);
         let started_at = Instant::now();
         debug!(
             logger,
-            "Starts checking content: version={:?}, lump_id={:?}", version, lump_id
+            "Starts checking content: version={:?}, lump_id={:?}",
+            version,
+            config::make_lump_id(&node_id, version)
         );
-        let phase = Phase3::A(into_box_future(
-            device.request().deadline(Deadline::Infinity).head(lump_id),
-        ));
+        let phase = Phase3::A(check);
         RepairContent {
             logger,
             node_id,
@@ -180,7 +301,7 @@ impl RepairContent {
     }
 }
 impl Future for RepairContent {
-    type Item = ();
+    type Item = RepairOutcome;
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         while let Async::Ready(phase) = track!(self.phase.poll().map_err(|e| {
@@ -188,12 +309,12 @@ impl Future for RepairContent {
             e
         }))? {
             let next = match phase {
-                Phase3::A(Some(_)) => {
+                Phase3::A(true) => {
                     debug!(self.logger, "The object {:?} already exists", self.version);
                     self.repair_metrics.repairs_unnecessary_total.increment();
-                    return Ok(Async::Ready(()));
+                    return Ok(Async::Ready(RepairOutcome::Healthy));
                 }
-                Phase3::A(None) => {
+                Phase3::A(false) => {
                     debug!(
                         self.logger,
                         "The object {:?} does not exist (try repairing)", self.version
@@ -216,7 +337,7 @@ impl Future for RepairContent {
                         self.node_id
                     );
                     self.repair_metrics.repairs_failure_total.increment();
-                    return Ok(Async::Ready(()));
+                    return Ok(Async::Ready(RepairOutcome::NotParticipant));
                 }
                 Phase3::B(MaybeFragment::Fragment(mut content)) => {
                     ::client::storage::append_checksum(&mut content); // TODO
@@ -255,7 +376,7 @@ impl Future for RepairContent {
                     self.repair_metrics
                         .repairs_durations_seconds
                         .observe(elapsed);
-                    return Ok(Async::Ready(()));
+                    return Ok(Async::Ready(RepairOutcome::Repaired));
                 }
             };
             self.phase = next;
@@ -263,3 +384,22 @@ impl Future for RepairContent {
         Ok(Async::NotReady)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_repair_result_works() {
+        let result = NodeRepairResult::new::<String>(
+            "0@127.0.0.1:14278".to_owned(),
+            Ok(RepairOutcome::Repaired),
+        );
+        assert_eq!(result.outcome, Some(RepairOutcome::Repaired));
+        assert!(result.error.is_none());
+
+        let result = NodeRepairResult::new("0@127.0.0.1:14278".to_owned(), Err("timeout"));
+        assert!(result.outcome.is_none());
+        assert_eq!(result.error, Some("timeout".to_owned()));
+    }
+}
@@ -1,7 +1,16 @@
-use fibers_rpc::server::{HandleCall, Reply, ServerBuilder as RpcServerBuilder};
+use fibers_rpc::server::{
+    HandleCall, HandleCast, NoReply, Reply, ServerBuilder as RpcServerBuilder,
+};
+use frugalos_core::rpc_auth;
+use frugalos_raft::LocalNodeId;
+use futures::Future;
+use libfrugalos;
 use libfrugalos::repair::RepairConfig;
 use libfrugalos::schema::frugalos as rpc;
-use ServiceHandle;
+use trackable::error::ErrorKindExt;
+
+use schema;
+use {Error, ErrorKind, ServiceHandle};
 
 #[derive(Clone)]
 pub struct RpcServer {
@@ -11,7 +20,14 @@ pub struct RpcServer {
 impl RpcServer {
     pub fn register(service_handle: ServiceHandle, builder: &mut RpcServerBuilder) {
         let this = RpcServer { service_handle };
-        builder.add_call_handler::<rpc::SetRepairConfigRpc, _>(this.clone());
+        rpc_auth::add_call_handler::<rpc::SetRepairConfigRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<schema::GetSegmentGcStatusRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<schema::StartSegmentGcRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<schema::StopSegmentGcRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<schema::RepairObjectRpc, _>(builder, this.clone());
+        rpc_auth::add_cast_handler::<schema::RepairObjectCast, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<schema::GetSegmentNodeStatusRpc, _>(builder, this.clone());
+        rpc_auth::add_call_handler::<schema::GetSegmentNodeStatusesRpc, _>(builder, this.clone());
     }
 }
 
@@ -21,3 +37,73 @@ impl HandleCall<rpc::SetRepairConfigRpc> for RpcServer {
         Reply::done(Ok(()))
     }
 }
+
+impl HandleCall<schema::GetSegmentGcStatusRpc> for RpcServer {
+    fn handle_call(&self, (): ()) -> Reply<schema::GetSegmentGcStatusRpc> {
+        let future = self.service_handle.segment_gc_statuses();
+        Reply::future(future.map_err(into_rpc_error).then(Ok))
+    }
+}
+
+impl HandleCall<schema::StartSegmentGcRpc> for RpcServer {
+    fn handle_call(&self, (): ()) -> Reply<schema::StartSegmentGcRpc> {
+        let future = self.service_handle.start_segment_gc();
+        Reply::future(future.map_err(into_rpc_error).then(Ok))
+    }
+}
+
+impl HandleCall<schema::StopSegmentGcRpc> for RpcServer {
+    fn handle_call(&self, (): ()) -> Reply<schema::StopSegmentGcRpc> {
+        let future = self.service_handle.stop_segment_gc();
+        Reply::future(future.map_err(into_rpc_error).then(Ok))
+    }
+}
+
+impl HandleCall<schema::RepairObjectRpc> for RpcServer {
+    fn handle_call(&self, req: schema::RepairObjectRequest) -> Reply<schema::RepairObjectRpc> {
+        let node: LocalNodeId = match req.node.parse() {
+            Ok(node) => node,
+            Err(e) => {
+                let e = ErrorKind::Invalid.takes_over(e);
+                return Reply::done(Err(into_rpc_error(track!(Error::from(e)))));
+            }
+        };
+        let future = self.service_handle.repair_object(node, req.version);
+        Reply::future(future.map_err(into_rpc_error).then(Ok))
+    }
+}
+
+impl HandleCast<schema::RepairObjectCast> for RpcServer {
+    fn handle_cast(&self, req: schema::RepairObjectRequest) -> NoReply {
+        if let Ok(node) = req.node.parse() {
+            // NOTE: 依頼元は結果を必要としないので、完了は待たない
+            let _ = self.service_handle.repair_object(node, req.version);
+        }
+        NoReply::done()
+    }
+}
+
+impl HandleCall<schema::GetSegmentNodeStatusRpc> for RpcServer {
+    fn handle_call(&self, node: String) -> Reply<schema::GetSegmentNodeStatusRpc> {
+        let node: LocalNodeId = match node.parse() {
+            Ok(node) => node,
+            Err(e) => {
+                let e = ErrorKind::Invalid.takes_over(e);
+                return Reply::done(Err(into_rpc_error(track!(Error::from(e)))));
+            }
+        };
+        let future = self.service_handle.node_status(node);
+        Reply::future(future.map_err(into_rpc_error).then(Ok))
+    }
+}
+
+impl HandleCall<schema::GetSegmentNodeStatusesRpc> for RpcServer {
+    fn handle_call(&self, (): ()) -> Reply<schema::GetSegmentNodeStatusesRpc> {
+        let future = self.service_handle.node_statuses();
+        Reply::future(future.map_err(into_rpc_error).then(Ok))
+    }
+}
+
+fn into_rpc_error(e: Error) -> libfrugalos::Error {
+    libfrugalos::ErrorKind::Other.takes_over(e).into()
+}
@@ -0,0 +1,140 @@
+//! セグメントの管理用 RPC のスキーマ定義。
+//!
+//! ID は`libfrugalos::schema`や`frugalos`クレートの`schema`モジュールで定義されているものと
+//! 衝突しないように`0x0201_0000`以降を利用する。
+use bytecodec::bincode_codec::{BincodeDecoder, BincodeEncoder};
+use fibers_rpc::{Call, Cast, ProcedureId};
+use libfrugalos::entity::object::ObjectVersion;
+use libfrugalos::Result;
+
+use {RepairOutcome, SegmentGcStatus, SegmentNodeStatus};
+
+/// サーバ上の各ノードの segment_gc の状態を取得する RPC。
+#[derive(Debug)]
+pub struct GetSegmentGcStatusRpc;
+impl Call for GetSegmentGcStatusRpc {
+    const ID: ProcedureId = ProcedureId(0x0201_0000);
+    const NAME: &'static str = "frugalos.segment.get_segment_gc_status";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Vec<SegmentGcStatus>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// サーバ上の各ノードで segment_gc (FullSync) を開始する RPC。
+///
+/// 応答は、新たに segment_gc を開始したノードの ID のリスト。
+#[derive(Debug)]
+pub struct StartSegmentGcRpc;
+impl Call for StartSegmentGcRpc {
+    const ID: ProcedureId = ProcedureId(0x0201_0001);
+    const NAME: &'static str = "frugalos.segment.start_segment_gc";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Vec<String>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// サーバ上の各ノードで実行中の segment_gc を中断する RPC。
+///
+/// 応答は、segment_gc を中断したノードの ID のリスト。
+#[derive(Debug)]
+pub struct StopSegmentGcRpc;
+impl Call for StopSegmentGcRpc {
+    const ID: ProcedureId = ProcedureId(0x0201_0002);
+    const NAME: &'static str = "frugalos.segment.stop_segment_gc";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Vec<String>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// ノードが保持するオブジェクトの中身を、即座に検証・リペアする RPC。
+///
+/// リペアキューを経由しないため、idleness の閾値に関わらず実行される。
+#[derive(Debug)]
+pub struct RepairObjectRpc;
+impl Call for RepairObjectRpc {
+    const ID: ProcedureId = ProcedureId(0x0201_0003);
+    const NAME: &'static str = "frugalos.segment.repair_object";
+
+    type Req = RepairObjectRequest;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<RepairOutcome>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// `RepairObjectRpc`と同様のリペアを、応答を待たずに依頼する RPC。
+///
+/// 読み込み時に断片の欠損を見つけたクライアントが、その断片を保持すべきノードに対して発行する。
+#[derive(Debug)]
+pub struct RepairObjectCast;
+impl Cast for RepairObjectCast {
+    const ID: ProcedureId = ProcedureId(0x0201_0004);
+    const NAME: &'static str = "frugalos.segment.repair_object_cast";
+
+    type Notification = RepairObjectRequest;
+    type Decoder = BincodeDecoder<Self::Notification>;
+    type Encoder = BincodeEncoder<Self::Notification>;
+}
+
+/// サーバ上のノードの状態を取得する RPC。
+///
+/// 要求はノードのローカル ID。
+#[derive(Debug)]
+pub struct GetSegmentNodeStatusRpc;
+impl Call for GetSegmentNodeStatusRpc {
+    const ID: ProcedureId = ProcedureId(0x0201_0005);
+    const NAME: &'static str = "frugalos.segment.get_node_status";
+
+    type Req = String;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<SegmentNodeStatus>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// サーバ上の全てのノードの状態を取得する RPC。
+///
+/// `Synchronizer`のキューの長さ等を、ノード毎に確認するために使われる。
+#[derive(Debug)]
+pub struct GetSegmentNodeStatusesRpc;
+impl Call for GetSegmentNodeStatusesRpc {
+    const ID: ProcedureId = ProcedureId(0x0201_0006);
+    const NAME: &'static str = "frugalos.segment.get_node_statuses";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Vec<SegmentNodeStatus>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// `RepairObjectRpc`と`RepairObjectCast`の要求。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepairObjectRequest {
+    /// リペアを行うローカルノードの ID。
+    pub node: String,
+
+    /// リペア対象のオブジェクトのバージョン。
+    pub version: ObjectVersion,
+}
@@ -14,6 +14,45 @@ use slog::Logger;
 use config;
 use Error;
 
+/// ノード毎の segment_gc の状態。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentGcStatus {
+    /// ノードのローカル ID。
+    pub node: String,
+
+    /// 実行中の segment_gc の進捗。
+    ///
+    /// segment_gc が実行されていない場合には`None`となる。
+    pub progress: Option<SegmentGcProgress>,
+}
+
+/// 実行中の segment_gc の進捗。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentGcProgress {
+    /// 走査対象となるバージョンの上限(このバージョン自体は含まない)。
+    pub object_version_limit: u64,
+
+    /// 走査済みのバージョンの数。
+    pub scanned_versions: u64,
+
+    /// 残りの走査対象のバージョンの数(実在しないバージョンも含む)。
+    pub remaining_versions: u64,
+
+    /// 今回の segment_gc で削除したオブジェクトの数。
+    pub deleted_objects: u64,
+}
+impl SegmentGcProgress {
+    fn new(object_version_limit: ObjectVersion, remaining: u64, deleted_objects: u64) -> Self {
+        let remaining_versions = std::cmp::min(remaining, object_version_limit.0);
+        SegmentGcProgress {
+            object_version_limit: object_version_limit.0,
+            scanned_versions: object_version_limit.0 - remaining_versions,
+            remaining_versions,
+            deleted_objects,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct SegmentGcMetrics {
     segment_gc_count: Counter,
@@ -46,6 +85,10 @@ impl SegmentGcMetrics {
 
 pub(crate) struct SegmentGc {
     future: Box<dyn Future<Item = (), Error = Error> + Send + 'static>,
+    object_version_limit: ObjectVersion,
+    metrics: SegmentGcMetrics,
+    // 開始時点での削除済みオブジェクト数 (`segment_gc_deleted_objects` は累積値なので)
+    deleted_objects_at_start: u64,
 }
 
 impl SegmentGc {
@@ -62,6 +105,12 @@ impl SegmentGc {
         let logger = logger.clone();
         info!(logger, "Starts segment_gc");
         segment_gc_metrics.segment_gc_count.increment();
+        // オブジェクトテーブルの作成中も進捗を返せるように、走査前の残数を設定しておく
+        segment_gc_metrics
+            .segment_gc_remaining
+            .set(object_version_limit.0 as f64);
+        let metrics = segment_gc_metrics.clone();
+        let deleted_objects_at_start = metrics.segment_gc_deleted_objects.value() as u64;
         let create_object_table = make_create_object_table(logger.clone(), machine);
 
         let logger = logger.clone();
@@ -84,7 +133,19 @@ impl SegmentGc {
             .map(move |()| info!(logger2, "SegmentGc objects done"));
 
         let future = Box::new(combined_future);
-        SegmentGc { future }
+        SegmentGc {
+            future,
+            object_version_limit,
+            metrics,
+            deleted_objects_at_start,
+        }
+    }
+    /// 現在の進捗を返す。
+    pub fn progress(&self) -> SegmentGcProgress {
+        let remaining = self.metrics.segment_gc_remaining.value() as u64;
+        let deleted_objects = (self.metrics.segment_gc_deleted_objects.value() as u64)
+            .saturating_sub(self.deleted_objects_at_start);
+        SegmentGcProgress::new(self.object_version_limit, remaining, deleted_objects)
     }
     /// Returns the `ObjectVersion`s of objects that should be deleted.
     fn compute_deleted_versions(
@@ -185,7 +246,13 @@ pub(self) fn make_create_object_table(
 }
 
 fn get_object_table(logger: &Logger, machine: &Machine) -> ObjectTable {
+    // 削除猶予期間中のオブジェクトは復元される可能性があるので、実データを残しておく
     let mut versions = machine.to_versions();
+    versions.extend(machine.to_tombstoned_versions());
+    // 追記されたオブジェクトの最新以外の部分も、オブジェクトの一部として残しておく
+    versions.extend(machine.to_part_versions());
+    // 保持期間中の過去のバージョンも同様
+    versions.extend(machine.to_history_versions());
     versions.sort_unstable();
     let objects_count = versions.len();
     debug!(
@@ -245,6 +312,7 @@ mod tests {
     use libfrugalos::expect::Expect;
     use segment_gc::{
         make_create_object_table, make_list_and_delete_content, ObjectTable, SegmentGc,
+        SegmentGcProgress,
     };
     use slog::{Discard, Logger};
     use std::{thread, time};
@@ -324,7 +392,12 @@ mod tests {
                 data: vec![],
             };
 
-            machine.put(format!("test-object-{}", i), metadata, &Expect::None)?;
+            machine.put(
+                format!("test-object-{}", i),
+                metadata,
+                0,
+                &Expect::None.into(),
+            )?;
         }
 
         let create_object_table = make_create_object_table(logger, machine);
@@ -353,4 +426,18 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn segment_gc_progress_works() {
+        let progress = SegmentGcProgress::new(ObjectVersion(100), 30, 5);
+        assert_eq!(progress.object_version_limit, 100);
+        assert_eq!(progress.scanned_versions, 70);
+        assert_eq!(progress.remaining_versions, 30);
+        assert_eq!(progress.deleted_objects, 5);
+
+        // 残数が上限を超えることはないが、念のため丸められる
+        let progress = SegmentGcProgress::new(ObjectVersion(10), 20, 0);
+        assert_eq!(progress.scanned_versions, 0);
+        assert_eq!(progress.remaining_versions, 10);
+    }
 }
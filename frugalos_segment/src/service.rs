@@ -2,14 +2,21 @@ use cannyls::device::DeviceHandle;
 use cannyls_rpc::Server as CannyLsRpcServer;
 use cannyls_rpc::{DeviceRegistry, DeviceRegistryHandle};
 use fibers::sync::mpsc;
+use fibers::sync::oneshot::{self, Monitor, Monitored};
+use fibers::time::timer::{self, Timeout};
 use fibers::Spawn;
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
 use fibers_rpc::server::ServerBuilder as RpcServerBuilder;
+use frugalos_core::logging;
+use frugalos_core::memory;
+use frugalos_core::metrics::MetricLabels;
 use frugalos_core::tracer::ThreadLocalTracer;
 use frugalos_mds::{
-    FrugalosMdsConfig, Node, Service as RaftMdsService, ServiceHandle as MdsHandle,
+    AccessMode, FrugalosMdsConfig, LeaderStatus, Node, Service as RaftMdsService,
+    ServiceHandle as MdsHandle, SharedQuota,
 };
 use frugalos_raft::{self, LocalNodeId, NodeId};
+use futures::future::{join_all, Either};
 use futures::{Async, Future, Poll, Stream};
 use raftlog::cluster::ClusterMembers;
 use slog::Logger;
@@ -19,8 +26,18 @@ use std::time::Duration;
 use trackable::error::ErrorKindExt;
 
 use client::storage::StorageClient;
+use config::CompactionConfig;
+use libfrugalos::entity::object::ObjectVersion;
 use libfrugalos::repair::{RepairConfig, RepairIdleness};
+use libfrugalos::time::Seconds;
+use maintenance::MaintenanceSchedule;
+use metrics::NodeStartupMetrics;
+use relayout::{self, RelayoutDriver, RelayoutProgress, RelayoutTarget};
+use repair::RepairOutcome;
 use rpc_server::RpcServer;
+use segment_gc::SegmentGcStatus;
+use startup::{StartupLimiter, StartupPermit, StartupProgress};
+use status::SegmentNodeStatus;
 use std::collections::HashMap;
 use synchronizer::Synchronizer;
 use {Client, Error, ErrorKind, Result};
@@ -40,6 +57,9 @@ pub struct Service<S> {
     // Senders of `SegmentNode`s
     segment_node_handles: HashMap<LocalNodeId, SegmentNodeHandle>,
     repair_concurrency: Arc<Mutex<RepairConcurrency>>,
+    // 後から追加されたノードにも適用するために保持しておく
+    repair_idleness_threshold: Option<RepairIdleness>,
+    startup: StartupLimiter,
 }
 impl<S> Service<S>
 where
@@ -59,6 +79,11 @@ where
         let device_registry = DeviceRegistry::new(logger.clone());
         let (command_tx, command_rx) = mpsc::channel();
         CannyLsRpcServer::new(device_registry.handle()).register(rpc);
+        let startup = StartupLimiter::new(
+            logger.clone(),
+            &mds_config,
+            Some(track!(NodeStartupMetrics::new())?),
+        );
 
         let service = Service {
             logger,
@@ -73,6 +98,8 @@ where
             mds_config,
             segment_node_handles: HashMap::new(),
             repair_concurrency: Arc::new(Mutex::new(RepairConcurrency::new())),
+            repair_idleness_threshold: None,
+            startup,
         };
 
         RpcServer::register(service.handle(), rpc);
@@ -103,11 +130,51 @@ where
         self.mds_service.take_snapshot();
     }
 
+    /// MDSのリーダ権を他のサーバに譲るよう要求する。
+    pub fn resign_leaderships(&mut self) {
+        self.mds_service.resign_leaderships();
+    }
+
+    /// 停止に備えて、ノード群をドレイン状態にする。
+    ///
+    /// MDS のリーダ権を他のサーバに譲り、新たなリペアの開始を止める。
+    /// 実行中のリペアの数は`repairs_in_flight`で確認できる。
+    pub fn drain(&mut self) {
+        self.mds_service.drain();
+        let mut lock = self
+            .repair_concurrency
+            .lock()
+            .unwrap_or_else(|e| panic!("Lock failed with error: {:?}", e));
+        lock.set_limit(0);
+    }
+
+    /// 実行中のリペアの数を返す。
+    pub fn repairs_in_flight(&self) -> u64 {
+        let lock = self
+            .repair_concurrency
+            .lock()
+            .unwrap_or_else(|e| panic!("Lock failed with error: {:?}", e));
+        lock.current_repair_threads
+    }
+
+    /// 指定されたノードのMDSのリーダ権を他のサーバに譲るよう要求する。
+    pub fn resign_leadership(&mut self, node: LocalNodeId) {
+        self.mds_service.resign_leadership(node);
+    }
+
+    /// サーバ内のMDSのリーダ数が偏っている場合に、超過分のリーダ権を他のサーバに譲るよう要求する。
+    ///
+    /// リーダ権の委譲を要求したノードの数を返す。
+    pub fn balance_leaderships(&mut self, slack: usize) -> usize {
+        self.mds_service.balance_leaderships(slack)
+    }
+
     /// repair_idleness_threshold の変更要求を発行する。
     #[allow(clippy::needless_pass_by_value)]
     pub fn set_repair_config(&mut self, repair_config: RepairConfig) {
         // TODO: handle RepairConfig's remaining field (segment_gc_concurrency_limit)
         if let Some(repair_idleness_threshold) = repair_config.repair_idleness_threshold {
+            self.repair_idleness_threshold = Some(repair_idleness_threshold);
             for (_, segment_node_handle) in self.segment_node_handles.iter() {
                 let command =
                     SegmentNodeCommand::SetRepairIdlenessThreshold(repair_idleness_threshold);
@@ -137,11 +204,28 @@ where
 
     fn handle_command(&mut self, command: Command) {
         match command {
-            Command::AddNode(node_id, device, client, cluster, config) => {
+            Command::AddNode(
+                logger,
+                metric_labels,
+                node_id,
+                device_id,
+                device,
+                client,
+                cluster,
+                config,
+                compaction,
+                maintenance,
+                quota,
+                access,
+                version_retention,
+                relayout,
+            ) => {
                 // TODO: error handling
-                let logger = self.logger.clone();
+                let logger = logger.new(o!(logging::NODE_ID => node_id.local_id.to_string()));
                 let logger0 = logger.clone();
                 let logger1 = logger.clone();
+                let logger2 = logger.clone();
+                let logger3 = logger.clone();
                 let service_handle = self.handle();
                 let local_id = node_id.local_id;
                 let spawner = self.spawner.clone();
@@ -149,6 +233,11 @@ where
                 let raft_service = self.raft_service.clone();
                 let mds_config = self.mds_config.clone();
                 let mds_service = self.mds_service.handle();
+                let bootstrap_config = mds_config.clone();
+                let bootstrap_cluster = cluster.clone();
+                let bootstrap_rpc_service = rpc_service.clone();
+                let witness = config.witness;
+                let removed_node = node_id;
                 // The sender (tx) and the receiver (rx) for SegmentNode.
                 // Rather than passing both tx and rx to SegmentNode's constructor
                 // and allow SegmentNode to make handles by cloning tx,
@@ -156,53 +245,170 @@ where
                 // That is because we need tx only in SegmentService.
                 let (segment_node_command_tx, segment_node_command_rx) = mpsc::channel();
                 // TODO: Remove a node from segment_node_handles when a SegmentNode terminates with an error
+                let segment_node_handle = SegmentNodeHandle(segment_node_command_tx);
+                if let Some(threshold) = self.repair_idleness_threshold {
+                    segment_node_handle
+                        .send(SegmentNodeCommand::SetRepairIdlenessThreshold(threshold));
+                }
                 self.segment_node_handles
-                    .insert(local_id, SegmentNodeHandle(segment_node_command_tx));
-                let future = device
-                    .map_err(|e| track!(e))
-                    .and_then(move |device| {
-                        // raft のログが不正な状態になった場合に強制削除するための対応
-                        // https://github.com/frugalos/frugalos/issues/157
-                        // https://github.com/frugalos/raftlog/issues/18
-                        let logger = logger1.new(o!("node" => local_id.to_string()));
-                        let future = if config.discard_former_log {
-                            let storage = frugalos_raft::Storage::new(
-                                logger,
-                                local_id,
-                                device.clone(),
-                                frugalos_raft::StorageMetrics::new(),
-                            );
-                            frugalos_raft::ClearLog::new(storage)
-                        } else {
-                            frugalos_raft::ClearLog::skip()
-                        };
-                        future.map(|_| device).map_err(|e| track!(Error::from(e)))
-                    })
-                    .and_then(move |device| {
-                        track!(SegmentNode::new(
-                            &logger0,
-                            spawner,
-                            rpc_service,
-                            raft_service,
-                            &mds_config,
-                            mds_service,
-                            node_id,
-                            device,
-                            service_handle,
-                            client,
-                            cluster,
-                            segment_node_command_rx
-                        ))
+                    .insert(local_id, segment_node_handle);
+                // ログのロードがデバイスのキューで競合しないように、起動処理の並列度を制限する
+                let future = self
+                    .startup
+                    .acquire(device_id)
+                    .and_then(move |permit| {
+                        device
+                            .map_err(|e| track!(e))
+                            .and_then(move |device| {
+                                // raft のログが不正な状態になった場合に強制削除するための対応
+                                // https://github.com/frugalos/frugalos/issues/157
+                                // https://github.com/frugalos/raftlog/issues/18
+                                let logger = logger1;
+                                let future = if config.discard_former_log {
+                                    let storage = frugalos_raft::Storage::new(
+                                        logger,
+                                        local_id,
+                                        device.clone(),
+                                        frugalos_raft::StorageMetrics::new(),
+                                    );
+                                    frugalos_raft::ClearLog::new(storage)
+                                } else {
+                                    frugalos_raft::ClearLog::skip()
+                                };
+                                future.map(|_| device).map_err(|e| track!(Error::from(e)))
+                            })
+                            .and_then(move |device| {
+                                // 新しく追加されたノードの場合には、既存のメンバからスナップショットを取得しておく
+                                let future = if bootstrap_config.fetch_snapshot_from_peers {
+                                    let peers = bootstrap_cluster
+                                        .iter()
+                                        .filter_map(|id| {
+                                            frugalos_raft::NodeId::from_raft_node_id(id).ok()
+                                        })
+                                        .collect();
+                                    let mut future = frugalos_raft::BootstrapLogPrefix::new(
+                                        logger2,
+                                        local_id,
+                                        device.clone(),
+                                        bootstrap_rpc_service,
+                                        peers,
+                                        bootstrap_config.fetch_snapshot_timeout,
+                                    );
+                                    if let Some(size) = bootstrap_config.snapshot_chunk_size {
+                                        future.set_log_prefix_chunk_size(size);
+                                    }
+                                    Either::A(future.map(|_| ()))
+                                } else {
+                                    Either::B(futures::finished(()))
+                                };
+                                future.map(|_| device).map_err(|e| track!(Error::from(e)))
+                            })
+                            .and_then(move |device| {
+                                track!(SegmentNode::new(
+                                    &logger0,
+                                    &metric_labels,
+                                    spawner,
+                                    rpc_service,
+                                    raft_service,
+                                    &mds_config,
+                                    mds_service,
+                                    node_id,
+                                    device,
+                                    service_handle,
+                                    client,
+                                    cluster,
+                                    &compaction,
+                                    maintenance,
+                                    quota,
+                                    access,
+                                    version_retention,
+                                    witness,
+                                    relayout,
+                                    segment_node_command_rx,
+                                    permit
+                                ))
+                            })
                     })
                     .map_err(move |e| crit!(logger, "Error: {}", e))
-                    .and_then(|node| node);
+                    .and_then(|node| node)
+                    .and_then(move |removed| {
+                        // 再配置によって不要になったノードのデータを削除する
+                        let device = if let Some(device) = removed {
+                            device
+                        } else {
+                            return Either::A(futures::finished(()));
+                        };
+                        let logger = logger3;
+                        let future =
+                            relayout::delete_node_data(&device, removed_node).then(move |result| {
+                                match result {
+                                    Ok(()) => info!(logger, "The data of the node is deleted"),
+                                    Err(e) => {
+                                        warn!(logger, "Cannot delete the data of the node: {}", e)
+                                    }
+                                }
+                                Ok(())
+                            });
+                        Either::B(future)
+                    });
                 self.spawner.spawn(future);
             }
+            Command::RemoveNode(node) => {
+                if let Some(segment_node_handle) = self.segment_node_handles.remove(&node) {
+                    info!(self.logger, "Removes the node: {:?}", node);
+                    segment_node_handle.send(SegmentNodeCommand::Remove);
+                } else {
+                    warn!(self.logger, "No such node to be removed: {:?}", node);
+                }
+            }
             Command::SetRepairConfig(repair_config) => {
                 self.set_repair_config(repair_config);
             }
+            Command::RepairObject(node, version, reply) => {
+                if let Some(segment_node_handle) = self.segment_node_handles.get(&node) {
+                    segment_node_handle.send(SegmentNodeCommand::RepairObject(version, reply));
+                } else {
+                    let e = ErrorKind::Invalid.cause(format!("No such node: {:?}", node));
+                    reply.exit(Err(track!(Error::from(e))));
+                }
+            }
+            Command::GetNodeStatus(node, reply) => {
+                if let Some(segment_node_handle) = self.segment_node_handles.get(&node) {
+                    segment_node_handle.send(SegmentNodeCommand::GetStatus(reply));
+                } else {
+                    let e = ErrorKind::Invalid.cause(format!("No such node: {:?}", node));
+                    reply.exit(Err(track!(Error::from(e))));
+                }
+            }
+            Command::GetNodeStatuses(reply) => {
+                reply.exit(Ok(self.broadcast(SegmentNodeCommand::GetStatus)));
+            }
+            Command::GetSegmentGcStatus(reply) => {
+                reply.exit(Ok(self.broadcast(SegmentNodeCommand::GetSegmentGcStatus)));
+            }
+            Command::StartSegmentGc(reply) => {
+                reply.exit(Ok(self.broadcast(SegmentNodeCommand::StartSegmentGc)));
+            }
+            Command::StopSegmentGc(reply) => {
+                reply.exit(Ok(self.broadcast(SegmentNodeCommand::StopSegmentGc)));
+            }
         }
     }
+
+    /// 全てのノードにコマンドを送信し、それぞれの応答を待つための`Monitor`群を返す。
+    fn broadcast<T, F>(&self, f: F) -> Vec<Monitor<T, Error>>
+    where
+        F: Fn(Monitored<T, Error>) -> SegmentNodeCommand,
+    {
+        self.segment_node_handles
+            .values()
+            .map(|segment_node_handle| {
+                let (monitored, monitor) = oneshot::monitor();
+                segment_node_handle.send(f(monitored));
+                monitor
+            })
+            .collect()
+    }
 }
 impl<S> Future for Service<S>
 where
@@ -247,6 +453,13 @@ pub struct ServiceHandle {
 impl ServiceHandle {
     // FIXME: 将来的には`client`と`cluster`は統合可能(前者から後者を引ける)
     /// サービスにノードを登録する。
+    ///
+    /// `quota`はノードが属するセグメントに適用される MDS の割り当て量(実行中に変更され得る)で、
+    /// `version_retention`はバージョン管理されているバケツの場合の、過去のバージョンの保持期間。
+    /// ノードは、`client`と共有するバケツへのアクセスの可否(読み込み専用かどうか)に従って書き込みを拒否する。
+    ///
+    /// セグメントが再配置中の場合には`relayout`に再配置先を指定する(ノードは変更前と変更後のどちらの配置に属していても良い)。
+    #[allow(clippy::too_many_arguments)]
     pub fn add_node(
         &self,
         node_id: NodeId,
@@ -255,24 +468,134 @@ impl ServiceHandle {
         cluster: ClusterMembers,
         // NOTE: "前回の状態"は raft だけに限らないので raft を意識しない
         discard_former_state: bool,
+        quota: SharedQuota,
+        version_retention: Option<Seconds>,
+        relayout: Option<RelayoutTarget>,
     ) -> Result<()> {
+        let cluster_config = client.cluster_of(&node_id);
         let raft_config = RaftConfig {
             discard_former_log: discard_former_state,
+            witness: cluster_config.is_mds_witness(&node_id),
         };
-        let command = Command::AddNode(node_id, device, client.storage, cluster, raft_config);
+        let device_id = cluster_config
+            .members
+            .iter()
+            .find(|m| m.node == node_id)
+            .map_or_else(String::new, |m| m.device.clone());
+        let command = Command::AddNode(
+            client.logger.clone(),
+            client.metric_labels.clone(),
+            node_id,
+            device_id,
+            device,
+            client.storage,
+            cluster,
+            raft_config,
+            client.compaction,
+            client.maintenance,
+            quota,
+            client.access,
+            version_retention,
+            relayout,
+        );
         track!(self
             .command_tx
             .send(command,)
             .map_err(|_| ErrorKind::Other.error(),))?;
         Ok(())
     }
+    /// 再配置によって不要になったノードを停止して、ローカルのデバイス上のデータを削除する。
+    pub fn remove_node(&self, node_id: NodeId) -> Result<()> {
+        let command = Command::RemoveNode(node_id.local_id);
+        track!(self
+            .command_tx
+            .send(command)
+            .map_err(|_| ErrorKind::Other.error()))?;
+        Ok(())
+    }
     /// repair_config の変更要求を発行する。
     pub fn set_repair_config(&self, repair_config: RepairConfig) {
         let command = Command::SetRepairConfig(repair_config);
         let _ = self.command_tx.send(command);
     }
+    /// ローカルの MDS ノードのリーダの確定状況を返す。
+    pub fn mds_leader_status(&self) -> LeaderStatus {
+        self.mds.leader_status()
+    }
+    /// ローカルノード`node`が保持する、指定バージョンのオブジェクトの中身を即座に検証・リペアする。
+    pub fn repair_object(
+        &self,
+        node: LocalNodeId,
+        version: ObjectVersion,
+    ) -> impl Future<Item = RepairOutcome, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let _ = self
+            .command_tx
+            .send(Command::RepairObject(node, version, monitored));
+        monitor.map_err(|e| track!(Error::from(e)))
+    }
+    /// ローカルノード`node`の状態を取得する。
+    pub fn node_status(
+        &self,
+        node: LocalNodeId,
+    ) -> impl Future<Item = SegmentNodeStatus, Error = Error> {
+        let (monitored, monitor) = oneshot::monitor();
+        let _ = self
+            .command_tx
+            .send(Command::GetNodeStatus(node, monitored));
+        monitor.map_err(|e| track!(Error::from(e)))
+    }
+    /// 全てのローカルノードの状態を取得する。
+    ///
+    /// 応答しなかったノード(e.g., 停止済み)の状態は含まれない。
+    pub fn node_statuses(&self) -> impl Future<Item = Vec<SegmentNodeStatus>, Error = Error> {
+        self.broadcast(Command::GetNodeStatuses)
+    }
+    /// 各ノードの segment_gc の状態を取得する。
+    pub fn segment_gc_statuses(&self) -> impl Future<Item = Vec<SegmentGcStatus>, Error = Error> {
+        self.broadcast(Command::GetSegmentGcStatus)
+    }
+    /// 各ノードで segment_gc (FullSync) を開始する。
+    ///
+    /// 結果として、新たに segment_gc を開始したノードの ID のリストが返される。
+    pub fn start_segment_gc(&self) -> impl Future<Item = Vec<String>, Error = Error> {
+        self.broadcast(Command::StartSegmentGc)
+            .map(|nodes| nodes.into_iter().flatten().collect())
+    }
+    /// 各ノードで実行中の segment_gc を中断する。
+    ///
+    /// 結果として、segment_gc を中断したノードの ID のリストが返される。
+    pub fn stop_segment_gc(&self) -> impl Future<Item = Vec<String>, Error = Error> {
+        self.broadcast(Command::StopSegmentGc)
+            .map(|nodes| nodes.into_iter().flatten().collect())
+    }
+    /// 全てのノードにコマンドを送信し、応答を集める。
+    ///
+    /// 応答しなかったノード(e.g., 停止済み)の結果は含まれない。
+    fn broadcast<T, F>(&self, f: F) -> impl Future<Item = Vec<T>, Error = Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(Monitored<Vec<Monitor<T, Error>>, Error>) -> Command,
+    {
+        let (monitored, monitor) = oneshot::monitor();
+        let _ = self.command_tx.send(f(monitored));
+        monitor
+            .map_err(|e| track!(Error::from(e)))
+            .and_then(|monitors| {
+                let futures = monitors
+                    .into_iter()
+                    .map(|monitor| monitor.then(|r| Ok(r.ok())));
+                join_all(futures)
+            })
+            .map(|results| results.into_iter().flatten().collect())
+    }
     /// Attempt to acquire repair lock.
+    ///
+    /// メモリ使用量が予算を超えている間は、並列数に関わらず取得できない。
     pub fn acquire_repair_lock(&self) -> Option<RepairLock> {
+        if memory::is_over_budget() {
+            return None;
+        }
         RepairLock::new(&self.repair_concurrency)
     }
 }
@@ -327,31 +650,61 @@ pub type CreateDeviceHandle = Box<dyn Future<Item = DeviceHandle, Error = Error>
 struct RaftConfig {
     /// true ならノード追加前に保存されていた Raft のログを破棄する。
     discard_former_log: bool,
+
+    /// true なら MDS のウィットネスとして動作する。
+    witness: bool,
 }
 
 #[allow(clippy::large_enum_variant)]
 enum Command {
     AddNode(
+        Logger,
+        MetricLabels,
         NodeId,
+        String,
         CreateDeviceHandle,
         StorageClient,
         ClusterMembers,
         RaftConfig,
+        CompactionConfig,
+        MaintenanceSchedule,
+        SharedQuota,
+        AccessMode,
+        Option<Seconds>,
+        Option<RelayoutTarget>,
     ),
+    RemoveNode(LocalNodeId),
     SetRepairConfig(RepairConfig),
+    RepairObject(LocalNodeId, ObjectVersion, Monitored<RepairOutcome, Error>),
+    GetNodeStatus(LocalNodeId, Monitored<SegmentNodeStatus, Error>),
+    GetNodeStatuses(Monitored<Vec<Monitor<SegmentNodeStatus, Error>>, Error>),
+    GetSegmentGcStatus(Monitored<Vec<Monitor<SegmentGcStatus, Error>>, Error>),
+    StartSegmentGc(Monitored<Vec<Monitor<Option<String>, Error>>, Error>),
+    StopSegmentGc(Monitored<Vec<Monitor<Option<String>, Error>>, Error>),
 }
 
 struct SegmentNode {
     logger: Logger,
+    node_id: NodeId,
     node: Node,
     synchronizer: Synchronizer,
+    // 再配置中のセグメントの場合に、リーダとしてジョブを進めるためのもの
+    relayout: Option<RelayoutDriver>,
+    // 削除要求を受けて停止した場合に、データを削除するために保持しておく
+    device: DeviceHandle,
+    removed: bool,
     segment_node_command_rx: mpsc::Receiver<SegmentNodeCommand>,
+    // Raft のログのロードが完了するまで保持される
+    startup: Option<(StartupPermit, Timeout)>,
+    // 枠の解放後、リーダを認識するまで保持される
+    awaiting_leader: Option<StartupProgress>,
 }
 impl SegmentNode {
     #[allow(clippy::too_many_arguments)]
     pub fn new<S>(
         // TODO: service: &Service<S>,
         logger: &Logger,
+        metric_labels: &MetricLabels,
         spawner: S,
         rpc_service: RpcServiceHandle,
         raft_service: frugalos_raft::ServiceHandle,
@@ -363,33 +716,31 @@ impl SegmentNode {
         service_handle: ServiceHandle,
         client: StorageClient,
         cluster: ClusterMembers,
+        compaction: &CompactionConfig,
+        maintenance: MaintenanceSchedule,
+        quota: SharedQuota,
+        access: AccessMode,
+        version_retention: Option<Seconds>,
+        witness: bool,
+        relayout: Option<RelayoutTarget>,
         segment_node_command_rx: mpsc::Receiver<SegmentNodeCommand>,
+        startup: StartupPermit,
     ) -> Result<Self>
     where
         S: Clone + Spawn + Send + 'static,
     {
-        let logger = logger.new(o!("node" => node_id.local_id.to_string()));
-
         // TODO: 正式な口を用意する
-        let min_timeout = env::var("FRUGALOS_RAFT_MIN_TIMEOUT")
-            .ok()
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(1000);
-        let max_timeout = env::var("FRUGALOS_RAFT_MAX_TIMEOUT")
-            .ok()
-            .and_then(|v| v.parse().ok())
-            .unwrap_or(5 * 1000);
-
-        let timer = frugalos_raft::Timer::new(
-            Duration::from_millis(min_timeout),
-            Duration::from_millis(max_timeout),
-        );
-        let storage = frugalos_raft::Storage::new(
+        let timer = frugalos_raft::Timer::from_env();
+        let mut storage = frugalos_raft::Storage::new(
             logger.clone(),
             node_id.local_id,
             device.clone(),
             frugalos_raft::StorageMetrics::new(),
         );
+        storage.enable_log_size_metrics();
+        if let Some(size) = mds_config.snapshot_chunk_size {
+            storage.set_log_prefix_chunk_size(size);
+        }
         let mailer = frugalos_raft::Mailer::new(
             spawner,
             rpc_service.clone(),
@@ -408,7 +759,11 @@ impl SegmentNode {
             node_id,
             cluster,
             io,
-            rpc_service
+            rpc_service,
+            quota,
+            access,
+            version_retention,
+            witness
         ))?;
 
         let full_sync_step = env::var("FRUGALOS_FULL_SYNC_STEP")
@@ -417,20 +772,40 @@ impl SegmentNode {
             .unwrap_or(100);
         info!(logger, "FullSync step: {}", full_sync_step);
 
+        let relayout = relayout.and_then(|target| {
+            RelayoutDriver::new(
+                logger.clone(),
+                target,
+                &client,
+                mds_config.relayout_batch_size,
+                mds_config.relayout_grace_period,
+                maintenance.clone(),
+            )
+        });
+        // NOTE: リペア等のローカルな処理は、ノードが属する配置の中で行う
         let synchronizer = Synchronizer::new(
             logger.clone(),
+            metric_labels,
             node_id,
-            device,
+            device.clone(),
             service_handle,
-            client,
+            client.for_node(&node_id),
             full_sync_step,
+            compaction,
+            maintenance,
         );
 
         Ok(SegmentNode {
-            logger,
+            logger: logger.clone(),
+            node_id,
             node,
             synchronizer,
+            relayout,
+            device,
+            removed: false,
             segment_node_command_rx,
+            startup: Some((startup, timer::timeout(mds_config.node_startup_timeout))),
+            awaiting_leader: None,
         })
     }
     fn run_once(&mut self) -> Result<bool> {
@@ -442,6 +817,9 @@ impl SegmentNode {
                 self.handle_command(command);
             }
         }
+        if self.removed {
+            return Ok(false);
+        }
         while let Async::Ready(event) = track!(self.node.poll())? {
             if let Some(event) = event {
                 self.synchronizer.handle_event(&event);
@@ -449,9 +827,52 @@ impl SegmentNode {
                 return Ok(false);
             }
         }
+        self.check_startup();
+        if let Some(ref mut relayout) = self.relayout {
+            relayout.poll(&mut self.node);
+        }
         track!(self.synchronizer.poll())?;
         Ok(true)
     }
+    /// Raft のログのロードが完了したか、タイムアウトした場合に、起動処理の枠を解放する。
+    ///
+    /// リーダの認識は他のサーバのメンバの起動状況に依存するので、枠の解放の条件には含めず、
+    /// 進捗のメトリクスとログのためにのみ、その後も待機する。
+    fn check_startup(&mut self) {
+        let timed_out = match self.startup {
+            None => false,
+            Some(_) if self.node.is_log_loaded() => false,
+            Some((_, ref mut timeout)) => match timeout.poll() {
+                Ok(Async::NotReady) => false,
+                _ => true,
+            },
+        };
+        if self.startup.is_some() && (timed_out || self.node.is_log_loaded()) {
+            let (permit, _) = self.startup.take().expect("Never fails");
+            if timed_out {
+                warn!(
+                    self.logger,
+                    "Node startup timed out while loading the raft log"
+                );
+            } else {
+                debug!(
+                    self.logger,
+                    "Raft log is loaded; releases the startup permit"
+                );
+            }
+            self.awaiting_leader = Some(permit.complete(timed_out));
+        }
+        if self.node.leader().is_some() {
+            if let Some(progress) = self.awaiting_leader.take() {
+                info!(
+                    self.logger,
+                    "Node started up: leader={:?}",
+                    self.node.leader()
+                );
+                progress.leader_recognized();
+            }
+        }
+    }
     #[allow(clippy::needless_pass_by_value)]
     fn handle_command(&mut self, command: SegmentNodeCommand) {
         match command {
@@ -459,11 +880,60 @@ impl SegmentNode {
                 self.synchronizer
                     .set_repair_idleness_threshold(idleness_threshold);
             }
+            SegmentNodeCommand::RepairObject(version, reply) => {
+                self.synchronizer.repair_object(version, reply);
+            }
+            SegmentNodeCommand::GetStatus(reply) => {
+                reply.exit(Ok(SegmentNodeStatus {
+                    node: self.node_id.local_id.to_string(),
+                    leader: self.node.leader().map(|n| n.to_string()),
+                    is_leader: self.node.is_leader(),
+                    queues: self.synchronizer.queues(),
+                    segment_gc: self.synchronizer.segment_gc_progress(),
+                    relayout: self.node.relayout_job().map(|job| {
+                        let last_error = self.relayout.as_ref().and_then(|r| r.last_error());
+                        RelayoutProgress::new(job, last_error)
+                    }),
+                }));
+            }
+            SegmentNodeCommand::GetSegmentGcStatus(reply) => {
+                reply.exit(Ok(SegmentGcStatus {
+                    node: self.node_id.local_id.to_string(),
+                    progress: self.synchronizer.segment_gc_progress(),
+                }));
+            }
+            SegmentNodeCommand::StartSegmentGc(reply) => {
+                let started = if let Some((machine, next_commit)) = self.node.synced_machine() {
+                    self.synchronizer.start_segment_gc(machine, next_commit)
+                } else {
+                    info!(
+                        self.logger,
+                        "Cannot start segment_gc because the machine is not up-to-date"
+                    );
+                    false
+                };
+                reply.exit(Ok(self.node_id_if(started)));
+            }
+            SegmentNodeCommand::StopSegmentGc(reply) => {
+                let stopped = self.synchronizer.stop_segment_gc();
+                reply.exit(Ok(self.node_id_if(stopped)));
+            }
+            SegmentNodeCommand::Remove => {
+                self.removed = true;
+            }
+        }
+    }
+    fn node_id_if(&self, condition: bool) -> Option<String> {
+        if condition {
+            Some(self.node_id.local_id.to_string())
+        } else {
+            None
         }
     }
 }
 impl Future for SegmentNode {
-    type Item = ();
+    /// 削除要求を受けて停止した場合には、データを削除する必要があるデバイス。
+    type Item = Option<DeviceHandle>;
     type Error = ();
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         match track!(self.run_once()) {
@@ -473,7 +943,12 @@ impl Future for SegmentNode {
             }
             Ok(false) => {
                 info!(self.logger, "Node stopped");
-                Ok(Async::Ready(()))
+                let device = if self.removed {
+                    Some(self.device.clone())
+                } else {
+                    None
+                };
+                Ok(Async::Ready(device))
             }
             Ok(true) => Ok(Async::NotReady),
         }
@@ -491,4 +966,10 @@ impl SegmentNodeHandle {
 
 enum SegmentNodeCommand {
     SetRepairIdlenessThreshold(RepairIdleness),
+    RepairObject(ObjectVersion, Monitored<RepairOutcome, Error>),
+    GetStatus(Monitored<SegmentNodeStatus, Error>),
+    GetSegmentGcStatus(Monitored<SegmentGcStatus, Error>),
+    StartSegmentGc(Monitored<Option<String>, Error>),
+    StopSegmentGc(Monitored<Option<String>, Error>),
+    Remove,
 }
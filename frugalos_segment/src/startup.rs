@@ -0,0 +1,293 @@
+//! セグメントのノード群の起動処理の並列度を制御するためのモジュール。
+//!
+//! サーバの起動時には、そのサーバが担当する全てのノードが一斉に追加されるが、
+//! 各ノードの Raft のログのロードは、同じデバイスのキューで競合するので、
+//! 全てを同時に開始すると、どのノードも全体の完了間際まで利用可能にならない。
+//! そのため、サーバ全体とデバイス毎に、同時に起動処理を行うノードの数を制限し、
+//! 起動処理が完了したノードから順に、後続のノードに枠を譲る。
+//!
+//! 枠は、ノードの Raft のログのロードが完了した時点(ないしタイムアウト時)に解放される。
+//! リーダの認識は、他のサーバのメンバの起動を待つ必要があり得るので枠の解放の条件とはせず、
+//! 進捗のメトリクス(`node_startup_leader_recognized_total`)にのみ反映する。
+use fibers::sync::oneshot;
+use frugalos_mds::FrugalosMdsConfig;
+use futures::Future;
+use slog::Logger;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use trackable::error::ErrorKindExt;
+
+use metrics::NodeStartupMetrics;
+use util::BoxFuture;
+use {Error, ErrorKind};
+
+/// ノードの起動処理の並列度を制限するためのオブジェクト。
+///
+/// `Clone`されたインスタンス間では、上限と起動中のノードの数が共有される。
+#[derive(Debug, Clone)]
+pub(crate) struct StartupLimiter {
+    inner: Arc<LimiterInner>,
+}
+impl StartupLimiter {
+    /// MDS の設定に従って、新しいインスタンスを生成する。
+    pub fn new(
+        logger: Logger,
+        config: &FrugalosMdsConfig,
+        metrics: Option<NodeStartupMetrics>,
+    ) -> Self {
+        let unlimited_if_zero = |n| if n == 0 { usize::max_value() } else { n };
+        StartupLimiter {
+            inner: Arc::new(LimiterInner {
+                logger,
+                concurrency: unlimited_if_zero(config.node_startup_concurrency),
+                concurrency_per_device: unlimited_if_zero(
+                    config.node_startup_concurrency_per_device,
+                ),
+                metrics,
+                state: Mutex::default(),
+            }),
+        }
+    }
+
+    /// `device`上のノードの起動処理を開始するための枠を取得する。
+    ///
+    /// 枠に空きがない場合には、先に要求されたノードから順に割り当てられる。
+    pub fn acquire(&self, device: String) -> BoxFuture<StartupPermit> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut state = self.inner.lock();
+            if state.requested == state.finished {
+                state.started_at = Instant::now();
+            }
+            state.requested += 1;
+            state.waiters.push_back((device, tx));
+            if let Some(ref m) = self.inner.metrics {
+                m.waiting.increment();
+            }
+            self.dispatch(&mut state);
+        }
+        Box::new(rx.map_err(|e| track!(Error::from(ErrorKind::Other.cause(e)))))
+    }
+
+    /// 空いている枠を、待機中のノードに先頭から順に割り当てる。
+    ///
+    /// デバイス毎の上限に達しているノードは飛ばし、後続の別デバイスのノードを先に起動する。
+    fn dispatch(&self, state: &mut LimiterState) {
+        let mut i = 0;
+        while i < state.waiters.len() && state.starting < self.inner.concurrency {
+            let starting = state
+                .starting_per_device
+                .get(&state.waiters[i].0)
+                .cloned()
+                .unwrap_or(0);
+            if starting >= self.inner.concurrency_per_device {
+                i += 1;
+                continue;
+            }
+
+            let (device, waiter) = state.waiters.remove(i).expect("Never fails");
+            if let Some(ref m) = self.inner.metrics {
+                m.waiting.decrement();
+            }
+            let permit = StartupPermit {
+                limiter: Some(self.clone()),
+                device: device.clone(),
+            };
+            match waiter.send(permit) {
+                Ok(()) => {
+                    state.starting += 1;
+                    *state.starting_per_device.entry(device).or_insert(0) += 1;
+                    if let Some(ref m) = self.inner.metrics {
+                        m.starting.increment();
+                    }
+                }
+                Err(e) => {
+                    // 待機していたノードは既に破棄されているので、起動処理は行われない
+                    // (ロックを保持したまま`release`が再帰的に呼ばれないようにする)
+                    let mut permit = e.0;
+                    permit.limiter = None;
+                    state.finished += 1;
+                }
+            }
+        }
+    }
+
+    /// `device`上のノードが使用していた枠を解放する。
+    fn release(&self, device: &str) {
+        let mut state = self.inner.lock();
+        state.starting -= 1;
+        let remains = {
+            let starting = state
+                .starting_per_device
+                .get_mut(device)
+                .expect("Never fails");
+            *starting -= 1;
+            *starting
+        };
+        if remains == 0 {
+            state.starting_per_device.remove(device);
+        }
+        state.finished += 1;
+        if let Some(ref m) = self.inner.metrics {
+            m.starting.decrement();
+        }
+
+        if state.finished == state.requested {
+            info!(
+                self.inner.logger,
+                "All nodes have started up: nodes={}, elapsed={:?}",
+                state.requested,
+                state.started_at.elapsed()
+            );
+        } else {
+            info!(
+                self.inner.logger,
+                "Node startup progress: {}/{} (starting={}, waiting={})",
+                state.finished,
+                state.requested,
+                state.starting,
+                state.waiters.len()
+            );
+        }
+        self.dispatch(&mut state);
+    }
+}
+
+#[derive(Debug)]
+struct LimiterInner {
+    logger: Logger,
+    concurrency: usize,
+    concurrency_per_device: usize,
+    metrics: Option<NodeStartupMetrics>,
+    state: Mutex<LimiterState>,
+}
+impl LimiterInner {
+    fn lock(&self) -> MutexGuard<'_, LimiterState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    starting: usize,
+    starting_per_device: HashMap<String, usize>,
+    waiters: VecDeque<(String, oneshot::Sender<StartupPermit>)>,
+
+    // 進捗の表示用(失敗したノードや、起動前に破棄されたノードも`finished`に含まれる)
+    requested: usize,
+    finished: usize,
+    started_at: Instant,
+}
+impl Default for LimiterState {
+    fn default() -> Self {
+        LimiterState {
+            starting: 0,
+            starting_per_device: HashMap::new(),
+            waiters: VecDeque::new(),
+            requested: 0,
+            finished: 0,
+            started_at: Instant::now(),
+        }
+    }
+}
+
+/// ノードの起動処理を行うための枠。
+///
+/// 破棄されると枠が解放される。
+#[derive(Debug)]
+pub(crate) struct StartupPermit {
+    limiter: Option<StartupLimiter>,
+    device: String,
+}
+impl StartupPermit {
+    /// 起動処理の完了を記録して、枠を解放する。
+    ///
+    /// `timed_out`はログのロードが完了する前にタイムアウトしたかどうか。
+    /// 返り値は、その後にリーダを認識したことを記録するために用いる。
+    pub fn complete(self, timed_out: bool) -> StartupProgress {
+        let metrics = self.limiter.as_ref().and_then(|l| l.inner.metrics.clone());
+        if let Some(ref m) = metrics {
+            m.started_total.increment();
+            if timed_out {
+                m.timeouts_total.increment();
+            }
+        }
+        StartupProgress { metrics }
+    }
+}
+
+/// 起動処理の枠を解放したノードが、リーダを認識したことを記録するためのオブジェクト。
+#[derive(Debug)]
+pub(crate) struct StartupProgress {
+    metrics: Option<NodeStartupMetrics>,
+}
+impl StartupProgress {
+    /// リーダを認識したことを記録する。
+    pub fn leader_recognized(self) {
+        if let Some(m) = self.metrics {
+            m.leader_recognized_total.increment();
+        }
+    }
+}
+impl Drop for StartupPermit {
+    fn drop(&mut self) {
+        if let Some(limiter) = self.limiter.take() {
+            limiter.release(&self.device);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers_global;
+    use slog::Discard;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    fn limiter(concurrency: usize, concurrency_per_device: usize) -> StartupLimiter {
+        let mut config = FrugalosMdsConfig::default();
+        config.node_startup_concurrency = concurrency;
+        config.node_startup_concurrency_per_device = concurrency_per_device;
+        StartupLimiter::new(Logger::root(Discard, o!()), &config, None)
+    }
+
+    #[test]
+    fn per_device_limit_works() -> TestResult {
+        let limiter = limiter(3, 1);
+        let a0 = limiter.acquire("a".to_owned());
+        let a1 = limiter.acquire("a".to_owned());
+        let b0 = limiter.acquire("b".to_owned());
+
+        // 同じデバイスのノードは待たされるが、別のデバイスのノードは先に起動できる
+        assert_eq!(limiter.inner.lock().waiters.len(), 1);
+        let a0 = track!(fibers_global::execute(a0))?;
+        let _b0 = track!(fibers_global::execute(b0))?;
+
+        a0.complete(false);
+        assert_eq!(limiter.inner.lock().waiters.len(), 0);
+        let _a1 = track!(fibers_global::execute(a1))?;
+        assert_eq!(limiter.inner.lock().finished, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn global_limit_works() -> TestResult {
+        let limiter = limiter(1, 0);
+        let a0 = limiter.acquire("a".to_owned());
+        let a1 = limiter.acquire("a".to_owned());
+        let b0 = limiter.acquire("b".to_owned());
+        let a0 = track!(fibers_global::execute(a0))?;
+        assert_eq!(limiter.inner.lock().waiters.len(), 2);
+
+        // 待機中に破棄されたノードの枠は、次のノードに譲られる
+        drop(a1);
+        drop(a0);
+        let _b0 = track!(fibers_global::execute(b0))?;
+        let state = limiter.inner.lock();
+        assert_eq!(state.starting, 1);
+        assert_eq!(state.finished, 2);
+        Ok(())
+    }
+}
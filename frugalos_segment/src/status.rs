@@ -0,0 +1,64 @@
+//! 管理用に、セグメントを構成する各ノードの内部状態を公開するための型群。
+use relayout::RelayoutProgress;
+use segment_gc::SegmentGcProgress;
+
+/// ノードの`Synchronizer`が保持するキューの長さ。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SynchronizerQueues {
+    /// 書き込み直後で、一定時間後にリペアが必要かどうかを確認する予定のオブジェクトの数。
+    pub repair_prep: usize,
+
+    /// 削除待ちのオブジェクトの数。
+    pub delete: usize,
+
+    /// 上書きによって不要になり、即座に削除される予定のオブジェクトの数。
+    pub compaction: usize,
+
+    /// リペア待ちのオブジェクトの数(リペアの積み残し)。
+    pub repair: usize,
+
+    /// 外部から要求され、実行中のリペアの数。
+    pub on_demand_repairs: usize,
+}
+
+/// ノード毎のセグメントの状態。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentNodeStatus {
+    /// ノードのローカル ID。
+    pub node: String,
+
+    /// このノードが認識している Raft のリーダ。
+    ///
+    /// 選挙中等でリーダが不明な場合には`None`となる。
+    pub leader: Option<String>,
+
+    /// このノード自身がリーダかどうか。
+    pub is_leader: bool,
+
+    /// `Synchronizer`のキューの長さ。
+    pub queues: SynchronizerQueues,
+
+    /// 実行中の segment_gc の進捗。
+    pub segment_gc: Option<SegmentGcProgress>,
+
+    /// 実行中ないし直近に終了した、セグメントの再配置の進捗。
+    pub relayout: Option<RelayoutProgress>,
+}
+
+/// セグメントのメンバの状態。
+///
+/// メンバを保持するサーバに問い合わせた結果で、応答がなかった場合には`error`にその理由が入る。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemberStatus {
+    /// ノード ID (アドレスを含む)。
+    pub node: String,
+
+    /// ノードが使用しているデバイスの ID。
+    pub device: String,
+
+    /// ノードの状態。
+    pub status: Option<SegmentNodeStatus>,
+
+    /// 状態を取得できなかった場合の理由。
+    pub error: Option<String>,
+}
@@ -1,19 +1,38 @@
 use cannyls::device::DeviceHandle;
+use fibers::sync::oneshot::Monitored;
+use fibers::time::timer::{self, Timeout};
+use frugalos_core::memory::{self, MemoryTracker};
+use frugalos_core::metrics::MetricLabels;
+use frugalos_core::task_dump::TaskTracker;
+use frugalos_mds::machine::Machine;
 use frugalos_mds::Event;
 use frugalos_raft::NodeId;
 use futures::{Async, Future, Poll, Stream};
 use libfrugalos::entity::object::ObjectVersion;
 use libfrugalos::repair::RepairIdleness;
-use prometrics::metrics::MetricBuilder;
+use prometrics::metrics::{Counter, MetricBuilder};
+use raftlog::log::LogIndex;
 use slog::Logger;
+use std::time::Duration;
 
 use client::storage::StorageClient;
+use config::CompactionConfig;
+use maintenance::MaintenanceSchedule;
 use queue_executor::general_queue_executor::GeneralQueueExecutor;
+use queue_executor::queue_snapshot::{QueueSnapshot, QueueSnapshotStore};
 use queue_executor::repair_queue_executor::RepairQueueExecutor;
-use segment_gc::{SegmentGc, SegmentGcMetrics};
+use repair::{RepairContent, RepairMetrics, RepairOutcome};
+use segment_gc::{SegmentGc, SegmentGcMetrics, SegmentGcProgress};
 use service::ServiceHandle;
+use status::SynchronizerQueues;
 use Error;
 
+/// キューの長さ等のメトリクスを定期的に更新する間隔.
+const QUEUE_METRICS_UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// メモリ使用量の見積もりに用いる、キューの要素毎のおおよそのバイト数.
+const QUEUE_ENTRY_BYTES: u64 = 64;
+
 // TODO: 起動直後の確認は`device.list()`の結果を使った方が効率的
 pub struct Synchronizer {
     logger: Logger,
@@ -28,20 +47,52 @@ pub struct Synchronizer {
     general_queue: GeneralQueueExecutor,
     // repair-only queue.
     repair_queue: RepairQueueExecutor,
+
+    // 再起動後もキューの内容を引き継ぐための永続化領域.
+    queue_snapshot_store: QueueSnapshotStore,
+    recovered_repair: Counter,
+    recovered_repair_prep: Counter,
+    recovered_delete: Counter,
+
+    queue_metrics_timer: Timeout,
+
+    // 外部から要求されたリペア (キューを経由せずに即座に実行される).
+    on_demand_repairs: Vec<Box<dyn Future<Item = (), Error = ()> + Send + 'static>>,
+    on_demand_repair_metrics: RepairMetrics,
+
+    // タスクダンプ用に状態を報告するためのオブジェクト.
+    tracker: TaskTracker,
+    // 前回報告した時点での segment_gc の進捗.
+    last_segment_gc_progress: Option<SegmentGcProgress>,
+
+    // segment_gc を進めて良い時間帯かどうかの判定に用いる.
+    maintenance: MaintenanceSchedule,
+
+    // キュー群と segment_gc が保持するマシンの複製の、メモリ使用量を報告するためのオブジェクト.
+    queue_memory: MemoryTracker,
+    segment_gc_memory: MemoryTracker,
 }
 impl Synchronizer {
+    /// メトリクスには、`metric_labels`のバケツと`node_id`がラベルとして付与される。
     pub fn new(
         logger: Logger,
+        metric_labels: &MetricLabels,
         node_id: NodeId,
         device: DeviceHandle,
         service_handle: ServiceHandle,
         client: StorageClient,
         segment_gc_step: u64,
+        compaction: &CompactionConfig,
+        maintenance: MaintenanceSchedule,
     ) -> Self {
-        let metric_builder = MetricBuilder::new()
+        let mut metric_builder = MetricBuilder::new();
+        metric_builder
             .namespace("frugalos")
-            .subsystem("synchronizer")
-            .clone();
+            .subsystem("synchronizer");
+        metric_labels
+            .clone()
+            .node(&node_id.to_string())
+            .apply(&mut metric_builder);
         // Metrics related to queue length
         let enqueued_repair = metric_builder
             .counter("enqueued_items")
@@ -73,16 +124,42 @@ impl Synchronizer {
             .label("type", "delete")
             .finish()
             .expect("metric should be well-formed");
+        // Metrics related to the persisted queues
+        let recovered_repair = metric_builder
+            .counter("recovered_items")
+            .help("Number of queued items recovered from the persisted queue snapshot")
+            .label("type", "repair")
+            .default_registry()
+            .finish()
+            .expect("metric should be well-formed");
+        let recovered_repair_prep = metric_builder
+            .counter("recovered_items")
+            .help("Number of queued items recovered from the persisted queue snapshot")
+            .label("type", "repair_prep")
+            .default_registry()
+            .finish()
+            .expect("metric should be well-formed");
+        let recovered_delete = metric_builder
+            .counter("recovered_items")
+            .help("Number of queued items recovered from the persisted queue snapshot")
+            .label("type", "delete")
+            .default_registry()
+            .finish()
+            .expect("metric should be well-formed");
 
-        let general_queue = GeneralQueueExecutor::new(
+        let mut general_queue = GeneralQueueExecutor::new(
             &logger,
             node_id,
             &device,
+            &metric_builder,
             &enqueued_repair_prep,
             &enqueued_delete,
             &dequeued_repair_prep,
             &dequeued_delete,
         );
+        if compaction.aggressive {
+            general_queue.enable_compaction(&metric_builder, compaction);
+        }
         let repair_queue = RepairQueueExecutor::new(
             &logger,
             node_id,
@@ -92,7 +169,10 @@ impl Synchronizer {
             &metric_builder,
             &enqueued_repair,
             &dequeued_repair,
+            maintenance.clone(),
         );
+        let queue_snapshot_store = QueueSnapshotStore::new(&logger, node_id, &device);
+        let on_demand_repair_metrics = RepairMetrics::new(&metric_builder);
         Synchronizer {
             logger,
             node_id,
@@ -104,6 +184,23 @@ impl Synchronizer {
 
             general_queue,
             repair_queue,
+
+            queue_snapshot_store,
+            recovered_repair,
+            recovered_repair_prep,
+            recovered_delete,
+
+            queue_metrics_timer: timer::timeout(QUEUE_METRICS_UPDATE_INTERVAL),
+
+            on_demand_repairs: Vec::new(),
+            on_demand_repair_metrics,
+
+            tracker: TaskTracker::new("synchronizer", &node_id.local_id.to_string()),
+            last_segment_gc_progress: None,
+            maintenance,
+
+            queue_memory: MemoryTracker::new("synchronizer_queues"),
+            segment_gc_memory: MemoryTracker::new("segment_gc"),
         }
     }
     pub fn handle_event(&mut self, event: &Event) {
@@ -127,22 +224,184 @@ impl Synchronizer {
                     ref machine,
                     next_commit,
                 } => {
-                    // If FullSync is not being processed now, this event lets the synchronizer to handle one.
-                    if self.segment_gc.is_none() {
-                        self.segment_gc = Some(SegmentGc::new(
-                            &self.logger,
-                            self.node_id,
-                            &self.device,
-                            machine.clone(),
-                            ObjectVersion(next_commit.as_u64()),
-                            self.segment_gc_metrics.clone(),
-                            self.segment_gc_step,
-                        ));
-                    }
+                    self.start_segment_gc(machine, next_commit);
                 }
             }
         }
     }
+    /// segment_gc を開始する。
+    ///
+    /// 既に実行中の場合(あるいはメタデータ専用のノードの場合)には何もせずに`false`を返す。
+    /// segment_gc はマシンの複製を保持するので、複製を加えるとメモリ使用量が予算を超える場合にも開始しない。
+    pub(crate) fn start_segment_gc(&mut self, machine: &Machine, next_commit: LogIndex) -> bool {
+        // If FullSync is not being processed now, this lets the synchronizer to handle one.
+        if self.client.is_metadata() || self.segment_gc.is_some() {
+            return false;
+        }
+        let machine_bytes = machine.approximate_memory_bytes();
+        if !memory::fits_in_budget(machine_bytes) {
+            warn!(
+                self.logger,
+                "Segment_gc is not started due to the memory budget: usage={}, machine={}, budget={}",
+                memory::total_bytes(),
+                machine_bytes,
+                memory::budget()
+            );
+            return false;
+        }
+        self.segment_gc_memory.set(machine_bytes);
+        self.segment_gc = Some(SegmentGc::new(
+            &self.logger,
+            self.node_id,
+            &self.device,
+            machine.clone(),
+            ObjectVersion(next_commit.as_u64()),
+            self.segment_gc_metrics.clone(),
+            self.segment_gc_step,
+        ));
+        true
+    }
+    /// 実行中の segment_gc を中断する。
+    ///
+    /// 実行中の segment_gc が存在しなかった場合には`false`を返す。
+    pub(crate) fn stop_segment_gc(&mut self) -> bool {
+        if self.segment_gc.take().is_none() {
+            return false;
+        }
+        info!(self.logger, "Segment_gc is cancelled");
+        self.segment_gc_metrics.reset();
+        self.segment_gc_memory.set(0);
+        true
+    }
+    /// 指定されたバージョンのオブジェクトの中身を即座に検証し、必要ならリペアする。
+    ///
+    /// リペアキューを経由しないので、idleness の閾値や並列数の制限を受けない。
+    pub(crate) fn repair_object(
+        &mut self,
+        version: ObjectVersion,
+        reply: Monitored<RepairOutcome, Error>,
+    ) {
+        if self.client.is_metadata() {
+            reply.exit(Ok(RepairOutcome::NotParticipant));
+            return;
+        }
+        info!(
+            self.logger,
+            "Starts on-demand repair: version={:?}", version
+        );
+        let logger = self.logger.clone();
+        let future = RepairContent::with_verification(
+            &self.logger,
+            &self.device,
+            self.node_id,
+            &self.client,
+            &self.on_demand_repair_metrics,
+            version,
+        )
+        .then(move |result| {
+            match result {
+                Ok(outcome) => info!(
+                    logger,
+                    "On-demand repair finished: version={:?}, outcome={:?}", version, outcome
+                ),
+                Err(ref e) => warn!(
+                    logger,
+                    "On-demand repair failed: version={:?}, error={}", version, e
+                ),
+            }
+            reply.exit(result);
+            Ok(())
+        });
+        self.on_demand_repairs.push(Box::new(future));
+    }
+    /// 実行中の segment_gc の進捗を返す。
+    pub(crate) fn segment_gc_progress(&self) -> Option<SegmentGcProgress> {
+        self.segment_gc.as_ref().map(SegmentGc::progress)
+    }
+    /// キュー群のメモリ使用量の見積もりを報告する。
+    fn report_queue_memory(&self) {
+        let queues = self.queues();
+        let entries = queues.repair_prep + queues.delete + queues.compaction + queues.repair;
+        self.queue_memory.set(entries as u64 * QUEUE_ENTRY_BYTES);
+    }
+    /// メモリ使用量が予算を超えている場合には、実行中の segment_gc を取り消す。
+    ///
+    /// 中断するだけではマシンの複製が解放されないので、取り消して複製を破棄する
+    /// (次回の segment_gc は、改めて最新のマシンから開始される)。
+    fn cancel_segment_gc_if_over_budget(&mut self) {
+        if self.segment_gc.is_none() || !memory::is_over_budget() {
+            return;
+        }
+        warn!(
+            self.logger,
+            "Segment_gc is cancelled due to the memory budget: usage={}, budget={}",
+            memory::total_bytes(),
+            memory::budget()
+        );
+        self.segment_gc = None;
+        self.segment_gc_metrics.reset();
+        self.segment_gc_memory.set(0);
+    }
+    /// 各キューの長さを返す。
+    pub(crate) fn queues(&self) -> SynchronizerQueues {
+        let (repair_prep, delete, compaction) = self.general_queue.queue_lengths();
+        SynchronizerQueues {
+            repair_prep,
+            delete,
+            compaction,
+            repair: self.repair_queue.len(),
+            on_demand_repairs: self.on_demand_repairs.len(),
+        }
+    }
+    fn restore_queues(&mut self, snapshot: QueueSnapshot) {
+        info!(
+            self.logger,
+            "Restores queues: repair_prep={}, delete={}, repair={}",
+            snapshot.repair_prep.len(),
+            snapshot.delete.len(),
+            snapshot.repair.len()
+        );
+        for version in snapshot.repair_prep {
+            self.general_queue.push_recovered_repair_prep(version);
+            self.recovered_repair_prep.increment();
+        }
+        for version in snapshot.delete {
+            self.general_queue.push_recovered_delete(version);
+            self.recovered_delete.increment();
+        }
+        for version in snapshot.repair {
+            self.repair_queue.push(version);
+            self.recovered_repair.increment();
+        }
+    }
+    fn report_task_state(&mut self) {
+        let state = if self.segment_gc.is_some() {
+            "SegmentGc"
+        } else {
+            "Running"
+        };
+        self.tracker.set_state(state);
+        let progress = self.segment_gc_progress();
+        if progress != self.last_segment_gc_progress {
+            self.tracker.report_progress();
+            self.last_segment_gc_progress = progress;
+        }
+        self.tracker
+            .set_queue_len("on_demand_repairs", self.on_demand_repairs.len());
+        self.general_queue.report_task_state();
+        self.repair_queue.report_task_state();
+    }
+    fn save_queues(&mut self) {
+        let (repair_prep, delete) = self.general_queue.queued_versions();
+        let snapshot = QueueSnapshot {
+            repair_prep,
+            delete,
+            repair: self.repair_queue.queued_versions(),
+        };
+        if let Err(e) = track!(self.queue_snapshot_store.save(snapshot)) {
+            warn!(self.logger, "Cannot save queues: {}", e);
+        }
+    }
     pub(crate) fn set_repair_idleness_threshold(
         &mut self,
         repair_idleness_threshold: RepairIdleness,
@@ -155,13 +414,46 @@ impl Future for Synchronizer {
     type Item = ();
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        while let Async::Ready(Some(())) = self.segment_gc.poll().unwrap_or_else(|e| {
-            warn!(self.logger, "Task failure: {}", e);
-            Async::Ready(Some(()))
-        }) {
-            // Full sync is done. Clearing the segment_gc field.
-            self.segment_gc = None;
-            self.segment_gc_metrics.reset();
+        if self.tracker.take_cancel_request() && self.stop_segment_gc() {
+            warn!(self.logger, "Segment_gc is cancelled by the watchdog");
+        }
+        if let Some(snapshot) = self.queue_snapshot_store.poll_load() {
+            self.restore_queues(snapshot);
+        }
+        if self.queue_snapshot_store.poll_save_timing() {
+            self.save_queues();
+        }
+        // 最も古い要素の待ち時間は push/pop がなくても変化するので、定期的に更新する
+        while let Ok(Async::Ready(())) = self.queue_metrics_timer.poll() {
+            self.general_queue.update_metrics();
+            self.repair_queue.update_metrics();
+            self.report_queue_memory();
+            self.queue_metrics_timer = timer::timeout(QUEUE_METRICS_UPDATE_INTERVAL);
+        }
+
+        // メモリ使用量が予算を超えている場合には segment_gc を取り消し、
+        // メンテナンスウィンドウ外の間は中断したままにしておく
+        // (`queue_metrics_timer` によって定期的に起こされるので、ウィンドウに入れば再開される)
+        self.cancel_segment_gc_if_over_budget();
+        if self.maintenance.is_open() {
+            while let Async::Ready(Some(())) = self.segment_gc.poll().unwrap_or_else(|e| {
+                warn!(self.logger, "Task failure: {}", e);
+                Async::Ready(Some(()))
+            }) {
+                // Full sync is done. Clearing the segment_gc field.
+                self.segment_gc = None;
+                self.segment_gc_metrics.reset();
+                self.segment_gc_memory.set(0);
+            }
+        }
+
+        let mut i = 0;
+        while i < self.on_demand_repairs.len() {
+            if let Ok(Async::NotReady) = self.on_demand_repairs[i].poll() {
+                i += 1;
+            } else {
+                self.on_demand_repairs.swap_remove(i);
+            }
         }
 
         if let Async::Ready(Some(version)) = self.general_queue.poll().unwrap_or_else(|e| {
@@ -173,6 +465,8 @@ impl Future for Synchronizer {
 
         // Never stops, never fails.
         self.repair_queue.poll().unwrap_or_else(Into::into);
+
+        self.report_task_state();
         Ok(Async::NotReady)
     }
 }
@@ -5,6 +5,7 @@ pub mod tests {
     use cannyls::storage::StorageBuilder;
     use cannyls_rpc;
     use cannyls_rpc::DeviceRegistryHandle;
+    use client::ec_pool::ErasureCodingPool;
     use client::Client;
     use config::*;
     use fibers::executor::Executor;
@@ -13,7 +14,7 @@ pub mod tests {
     use fibers_rpc::client::{ClientService, ClientServiceHandle};
     use fibers_rpc::server::ServerBuilder;
     use frugalos_core;
-    use frugalos_mds;
+    use frugalos_mds::{self, AccessMode};
     use frugalos_raft::{self, LocalNodeId, NodeId};
     use futures;
     use futures::future::Future;
@@ -74,7 +75,9 @@ pub mod tests {
         node_seqno: u8,
         device_no: u8,
         cluster_config: ClusterConfig,
+        access: AccessMode,
         pub executor: ThreadPoolExecutor,
+        pub erasure_coder: ErasureCoderConfig,
     }
 
     impl System {
@@ -121,8 +124,11 @@ pub mod tests {
                 device_no: 0,
                 cluster_config: ClusterConfig {
                     members: Vec::new(),
+                    mds_witnesses: 0,
                 },
+                access: AccessMode::default(),
                 executor,
+                erasure_coder: Default::default(),
             })
         }
 
@@ -131,6 +137,11 @@ pub mod tests {
             &self.cluster_config
         }
 
+        /// Returns the access mode shared by the nodes and the clients of this cluster.
+        pub fn access(&self) -> &AccessMode {
+            &self.access
+        }
+
         /// Returns the size of fragments(data_fragments + parity_fragments).
         pub fn fragments(&self) -> u8 {
             self.data_fragments + self.parity_fragments
@@ -162,6 +173,9 @@ pub mod tests {
                         client,
                         cluster.clone(),
                         false,
+                        frugalos_mds::SharedQuota::default(),
+                        None,
+                        None,
                     )
                     .unwrap();
             }
@@ -219,19 +233,41 @@ pub mod tests {
 
         /// Creates a new SegmentClient.
         pub fn make_segment_client(&self) -> Result<Client> {
-            Client::new(
-                self.logger(),
-                self.rpc_service_handle.clone(),
-                ClientConfig {
-                    cluster: self.cluster_config.clone(),
-                    dispersed_client: Default::default(),
-                    replicated_client: Default::default(),
-                    storage: self.make_dispersed_storage(),
-                    mds: MdsClientConfig::default(),
-                },
-                None,
-            )
-            .map_err(|e| track!(e))
+            self.make_segment_client_with(|_| {})
+        }
+
+        /// Creates a new SegmentClient with the configuration modified by `f`.
+        pub fn make_segment_client_with<F>(&self, f: F) -> Result<Client>
+        where
+            F: FnOnce(&mut ClientConfig),
+        {
+            let mut config = ClientConfig {
+                bucket_id: "test".to_owned(),
+                cluster: self.cluster_config.clone(),
+                dispersed_client: Default::default(),
+                replicated_client: Default::default(),
+                storage: self.make_dispersed_storage(),
+                mds: MdsClientConfig::default(),
+                erasure_coder: self.erasure_coder.clone(),
+                coding: Default::default(),
+                request_priority: Default::default(),
+                encryption: None,
+                compaction: Default::default(),
+                retry: Default::default(),
+                member_health: Default::default(),
+                circuit_breaker: Default::default(),
+                budget: Default::default(),
+                ec_pool: ErasureCodingPool::unbounded(),
+                content_cache: Default::default(),
+                cache_sizing: Default::default(),
+                maintenance: Default::default(),
+                features: Default::default(),
+                access: self.access.clone(),
+                previous_layout: None,
+            };
+            f(&mut config);
+            Client::new(self.logger(), self.rpc_service_handle.clone(), config)
+                .map_err(|e| track!(e))
         }
 
         /// Creates a new `NodeId`.
@@ -6,3 +6,23 @@
 
 # 作業ディレクトリパス
 WORK_DIR=/tmp/frugalos_it
+
+# 指定されたホストの全てが ready (`/v1/frugalos/readiness` が 200 を返す) になるまで待つ
+#
+# 使い方: wait_ready HOST... (最大で 60 秒待つ)
+wait_ready() {
+  for host in "$@"
+  do
+    for i in `seq 60`
+    do
+      if curl -sf http://$host/v1/frugalos/readiness > /dev/null; then
+        break
+      fi
+      if [ $i -eq 60 ]; then
+        echo "$host did not become ready" >&2
+        return 1
+      fi
+      sleep 1
+    done
+  done
+}
@@ -37,7 +37,7 @@ curl http://frugalos01/v1/buckets
 # Puts objects
 #
 it/scripts/gen_put_requests.sh frugalos03 live_archive_chunk 1 1000 $WORK_DIR/req.json
-sleep 5
+wait_ready frugalos01 frugalos02 frugalos03
 hb run -i $WORK_DIR/req.json | hb summary
 sleep 5
 hb run -i $WORK_DIR/req.json | hb summary
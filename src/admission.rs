@@ -0,0 +1,570 @@
+//! リクエストの受け付け制御(アドミッション制御)を行うためのモジュール。
+//!
+//! 過負荷時に全てのリクエストを受け付けると、各リクエストの処理が遅れてデッドラインの超過が連鎖する。
+//! ここでは、同時実行数とリクエストレートの上限を超えたリクエストを処理の開始前に拒否して、
+//! クライアントに`ErrorKind::Busy`(HTTP では`503 Service Unavailable`と`Retry-After`ヘッダ)を返す。
+//!
+//! 上限は、全てのバケツの合計に対するもの(`global`)と、バケツ毎のもの(`buckets`)を設定できる。
+//! 実行中のリクエスト数は、`frugalos_admission_inflight_requests`ゲージとして公開される。
+use fibers_http_server::{HandleRequest, Req, Res, Status};
+use futures::{Async, Future, Poll};
+use httpcodec::HeaderField;
+use prometrics::metrics::{Counter, Gauge, MetricBuilder};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use url::Url;
+
+use client::FrugalosClient;
+use http::{make_json_response, HttpResult};
+use {Error, ErrorKind, FrugalosAdmissionConfig, Result};
+
+/// 同時実行数を制限する単位となるリクエストの種類。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    /// オブジェクトの取得(GET と HEAD)。
+    Get,
+
+    /// オブジェクトの書き込み(PUT、追記、削除)。
+    Put,
+}
+impl RequestKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            RequestKind::Get => "get",
+            RequestKind::Put => "put",
+        }
+    }
+}
+
+/// 受け付けの上限。
+///
+/// 指定されていない項目は無制限となる。
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AdmissionLimits {
+    /// 同時に実行できる取得リクエストの数。
+    #[serde(default)]
+    pub max_inflight_gets: Option<usize>,
+
+    /// 同時に実行できる書き込みリクエストの数。
+    #[serde(default)]
+    pub max_inflight_puts: Option<usize>,
+
+    /// 一秒間に受け付けるリクエストの数(取得と書き込みの合計)。
+    ///
+    /// 一秒分までのバーストは許容される。
+    #[serde(default)]
+    pub max_requests_per_sec: Option<f64>,
+}
+impl AdmissionLimits {
+    fn max_inflight(&self, kind: RequestKind) -> Option<usize> {
+        match kind {
+            RequestKind::Get => self.max_inflight_gets,
+            RequestKind::Put => self.max_inflight_puts,
+        }
+    }
+}
+
+/// トークンバケットによるレート制限。
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        self.tokens = (self.tokens + duration_to_secs(elapsed) * self.rate).min(self.rate);
+        self.updated_at = now;
+    }
+
+    /// トークンが得られるまでの時間を返す(すぐに得られる場合は`None`)。
+    fn wait_time(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            None
+        } else if self.rate <= 0.0 {
+            Some(Duration::from_secs(1))
+        } else {
+            let secs = (1.0 - self.tokens) / self.rate;
+            Some(Duration::from_millis((secs * 1000.0).ceil() as u64))
+        }
+    }
+
+    fn consume(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+/// バケツ毎のメトリクス。
+#[derive(Debug)]
+struct BucketMetrics {
+    inflight_gets: Gauge,
+    inflight_puts: Gauge,
+    rejected_inflight: Counter,
+    rejected_rate: Counter,
+}
+impl BucketMetrics {
+    fn new(bucket_id: &str) -> Result<Self> {
+        let mut builder = MetricBuilder::new();
+        builder
+            .namespace("frugalos")
+            .subsystem("admission")
+            .label("bucket", bucket_id);
+        let inflight = |kind: RequestKind| {
+            track!(builder
+                .gauge("inflight_requests")
+                .help("Number of in-flight requests admitted by the admission controller")
+                .label("kind", kind.as_str())
+                .default_registry()
+                .finish())
+        };
+        let inflight_gets = inflight(RequestKind::Get)?;
+        let inflight_puts = inflight(RequestKind::Put)?;
+        let rejected = |reason: &str| {
+            track!(builder
+                .counter("rejected_requests_total")
+                .help("Number of requests rejected by the admission controller")
+                .label("reason", reason)
+                .default_registry()
+                .finish())
+        };
+        let rejected_inflight = rejected("inflight")?;
+        let rejected_rate = rejected("rate")?;
+        Ok(BucketMetrics {
+            inflight_gets,
+            inflight_puts,
+            rejected_inflight,
+            rejected_rate,
+        })
+    }
+
+    fn inflight(&self, kind: RequestKind) -> &Gauge {
+        match kind {
+            RequestKind::Get => &self.inflight_gets,
+            RequestKind::Put => &self.inflight_puts,
+        }
+    }
+}
+
+/// ある範囲(全体ないしバケツ)に対する受け付け状況。
+#[derive(Debug)]
+struct Limiter {
+    limits: AdmissionLimits,
+    inflight_gets: usize,
+    inflight_puts: usize,
+    rate: Option<TokenBucket>,
+}
+impl Limiter {
+    fn new(limits: AdmissionLimits, now: Instant) -> Self {
+        let rate = limits
+            .max_requests_per_sec
+            .map(|rate| TokenBucket::new(rate, now));
+        Limiter {
+            limits,
+            inflight_gets: 0,
+            inflight_puts: 0,
+            rate,
+        }
+    }
+
+    fn inflight_mut(&mut self, kind: RequestKind) -> &mut usize {
+        match kind {
+            RequestKind::Get => &mut self.inflight_gets,
+            RequestKind::Put => &mut self.inflight_puts,
+        }
+    }
+
+    /// 同時実行数の上限に達しているかどうか。
+    fn is_saturated(&mut self, kind: RequestKind) -> bool {
+        let max = self.limits.max_inflight(kind);
+        max.is_some_and(|max| *self.inflight_mut(kind) >= max)
+    }
+
+    fn wait_time(&mut self, now: Instant) -> Option<Duration> {
+        self.rate.as_mut().and_then(|rate| rate.wait_time(now))
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    global: Limiter,
+    buckets: HashMap<String, (Limiter, BucketMetrics)>,
+}
+
+/// リクエストの受け付け制御を行うためのコントローラ。
+#[derive(Debug, Clone)]
+pub struct AdmissionController {
+    config: Arc<FrugalosAdmissionConfig>,
+    state: Arc<Mutex<State>>,
+}
+impl AdmissionController {
+    /// 新しい`AdmissionController`インスタンスを生成する。
+    pub fn new(config: FrugalosAdmissionConfig) -> Self {
+        let state = State {
+            global: Limiter::new(config.global.clone(), Instant::now()),
+            buckets: HashMap::new(),
+        };
+        AdmissionController {
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// バケツ`bucket_id`に対する`kind`のリクエストの受け付けを試みる。
+    ///
+    /// 受け付けられた場合には、リクエストの処理が終わるまで、返された`AdmissionPermit`を保持すること。
+    /// 上限を超えている場合には`ErrorKind::Busy`を返す。
+    pub fn admit(&self, bucket_id: &str, kind: RequestKind) -> Result<AdmissionPermit> {
+        track!(self.acquire(bucket_id, kind, true))?;
+        Ok(AdmissionPermit {
+            controller: self.clone(),
+            bucket_id: bucket_id.to_owned(),
+            kind,
+        })
+    }
+
+    /// 受け付け枠を確保せずに、バケツ`bucket_id`に対する`kind`のリクエストが受け付け可能かどうかを判定する。
+    ///
+    /// リクエストボディを読み込む前に、過負荷時のリクエストを拒否するために使われる。
+    /// 判定後に枠が埋まることもあるため、処理の開始時には改めて`admit`を呼び出すこと。
+    pub fn check(&self, bucket_id: &str, kind: RequestKind) -> Result<()> {
+        track!(self.acquire(bucket_id, kind, false))
+    }
+
+    fn acquire(&self, bucket_id: &str, kind: RequestKind, reserve: bool) -> Result<()> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let State {
+            ref mut global,
+            ref mut buckets,
+        } = *state;
+        if !buckets.contains_key(bucket_id) {
+            let limits = self
+                .config
+                .buckets
+                .get(bucket_id)
+                .cloned()
+                .unwrap_or_default();
+            let metrics = track!(BucketMetrics::new(bucket_id))?;
+            buckets.insert(bucket_id.to_owned(), (Limiter::new(limits, now), metrics));
+        }
+        let (bucket, metrics) = buckets.get_mut(bucket_id).expect("Never fails");
+
+        if global.is_saturated(kind) || bucket.is_saturated(kind) {
+            metrics.rejected_inflight.increment();
+            track_panic!(
+                ErrorKind::Busy(self.config.retry_after),
+                "Too many in-flight requests: bucket={:?}, kind={:?}",
+                bucket_id,
+                kind
+            );
+        }
+        if let Some(wait_time) = global.wait_time(now).max(bucket.wait_time(now)) {
+            metrics.rejected_rate.increment();
+            track_panic!(
+                ErrorKind::Busy(wait_time),
+                "Request rate limit exceeded: bucket={:?}",
+                bucket_id
+            );
+        }
+        if !reserve {
+            return Ok(());
+        }
+        for limiter in &mut [global, bucket] {
+            if let Some(rate) = limiter.rate.as_mut() {
+                rate.consume();
+            }
+            *limiter.inflight_mut(kind) += 1;
+        }
+        metrics.inflight(kind).increment();
+        Ok(())
+    }
+
+    fn release(&self, bucket_id: &str, kind: RequestKind) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state.global.inflight_mut(kind) -= 1;
+        if let Some((bucket, metrics)) = state.buckets.get_mut(bucket_id) {
+            *bucket.inflight_mut(kind) -= 1;
+            metrics.inflight(kind).decrement();
+        }
+    }
+}
+
+/// 受け付けられたリクエストの処理中に保持される値。
+///
+/// 破棄された時点で、リクエストの処理が終わったものと見なされる。
+#[derive(Debug)]
+pub struct AdmissionPermit {
+    controller: AdmissionController,
+    bucket_id: String,
+    kind: RequestKind,
+}
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        self.controller.release(&self.bucket_id, self.kind);
+    }
+}
+
+fn duration_to_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1_000_000_000.0
+}
+
+type BucketSelector = Arc<dyn Fn(&Url) -> Option<String> + Send + Sync + 'static>;
+
+/// HTTP ハンドラをラップして、リクエストの処理前に受け付け制御を行う。
+///
+/// 上限を超えている場合には、リクエストボディを読み込む前(`handle_request_head`)に拒否する。
+///
+/// 存在しないバケツに対するリクエストは、受け付け制御の対象とせずにそのまま内側のハンドラに渡す
+/// (任意のバケツ名に対してメトリクスが作られることを避けるため)。
+pub struct WithAdmission<H> {
+    inner: H,
+    controller: AdmissionController,
+    kind: RequestKind,
+    bucket: BucketSelector,
+}
+impl<H: HandleRequest> WithAdmission<H> {
+    /// パスに含まれるバケツを対象とする`WithAdmission`インスタンスを生成する。
+    ///
+    /// 対象のハンドラのパスは`/v1/buckets/{bucket_id}/...`の形式である必要がある。
+    pub fn bucket(
+        inner: H,
+        controller: AdmissionController,
+        client: FrugalosClient,
+        kind: RequestKind,
+    ) -> Self {
+        Self::new(inner, controller, kind, move |url| {
+            let bucket_id = bucket_id_from_path(url.path()).to_owned();
+            client.segment_count(&bucket_id).map(|_| bucket_id)
+        })
+    }
+
+    /// リクエストの URL から対象のバケツを決定する`WithAdmission`インスタンスを生成する。
+    ///
+    /// `f`が`None`を返したリクエストは、受け付け制御の対象外となる。
+    pub fn new<F>(inner: H, controller: AdmissionController, kind: RequestKind, f: F) -> Self
+    where
+        F: Fn(&Url) -> Option<String> + Send + Sync + 'static,
+    {
+        WithAdmission {
+            inner,
+            controller,
+            kind,
+            bucket: Arc::new(f),
+        }
+    }
+}
+impl<H, T> HandleRequest for WithAdmission<H>
+where
+    H: HandleRequest<ResBody = HttpResult<T>>,
+{
+    const METHOD: &'static str = H::METHOD;
+    const PATH: &'static str = H::PATH;
+
+    type ReqBody = H::ReqBody;
+    type ResBody = H::ResBody;
+    type Decoder = H::Decoder;
+    type Encoder = H::Encoder;
+    type Reply = Admitted<H>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = match (self.bucket)(req.url()) {
+            None => return Admitted::Unchecked(self.inner.handle_request(req)),
+            Some(bucket_id) => bucket_id,
+        };
+
+        // `handle_request_head`での判定後に、他のリクエストによって枠が埋まっている場合がある
+        match track!(self.controller.admit(&bucket_id, self.kind)) {
+            Err(e) => Admitted::Rejected(Some(rejected_response(e))),
+            Ok(permit) => Admitted::Accepted {
+                future: self.inner.handle_request(req),
+                _permit: permit,
+            },
+        }
+    }
+
+    fn handle_request_head(&self, req: &Req<()>) -> Option<Res<Self::ResBody>> {
+        if let Some(bucket_id) = (self.bucket)(req.url()) {
+            if let Err(e) = track!(self.controller.check(&bucket_id, self.kind)) {
+                return Some(rejected_response(e));
+            }
+        }
+        self.inner.handle_request_head(req)
+    }
+}
+
+fn rejected_response<T>(e: Error) -> Res<HttpResult<T>> {
+    let retry_after = match *e.kind() {
+        ErrorKind::Busy(retry_after) => retry_after,
+        _ => Duration::from_secs(1),
+    };
+    let mut res = make_json_response(Status::ServiceUnavailable, Err(e));
+    add_retry_after_header(&mut res, retry_after);
+    res
+}
+
+/// 受け付け制御を経たリクエストの応答を返す`Future`。
+pub enum Admitted<H: HandleRequest> {
+    /// 受け付けられたリクエスト。
+    Accepted {
+        /// 内側のハンドラの応答。
+        future: H::Reply,
+        /// 応答が返るまで保持される。
+        _permit: AdmissionPermit,
+    },
+
+    /// 受け付け制御の対象外のリクエスト。
+    Unchecked(H::Reply),
+
+    /// 拒否されたリクエスト。
+    Rejected(Option<Res<H::ResBody>>),
+}
+impl<H: HandleRequest> Future for Admitted<H> {
+    type Item = Res<H::ResBody>;
+    type Error = <H::Reply as Future>::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self {
+            Admitted::Accepted { future, .. } => future.poll(),
+            Admitted::Unchecked(future) => future.poll(),
+            Admitted::Rejected(res) => Ok(Async::Ready(res.take().expect("Never fails"))),
+        }
+    }
+}
+
+/// `Retry-After`ヘッダを付与する(秒単位に切り上げる)。
+pub fn add_retry_after_header<T>(res: &mut Res<T>, retry_after: Duration) {
+    let mut secs = retry_after.as_secs();
+    if retry_after.subsec_nanos() > 0 || secs == 0 {
+        secs += 1;
+    }
+    res.header_mut()
+        .add_field(unsafe { HeaderField::new_unchecked("Retry-After", &secs.to_string()) });
+}
+
+fn bucket_id_from_path(path: &str) -> &str {
+    path.trim_start_matches('/').split('/').nth(2).unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn config(global: AdmissionLimits, bucket: AdmissionLimits) -> FrugalosAdmissionConfig {
+        let mut buckets = BTreeMap::new();
+        buckets.insert("limited".to_owned(), bucket);
+        FrugalosAdmissionConfig {
+            global,
+            buckets,
+            retry_after: Duration::from_millis(500),
+        }
+    }
+
+    #[test]
+    fn inflight_limit_works() {
+        let controller = AdmissionController::new(config(
+            AdmissionLimits {
+                max_inflight_puts: Some(3),
+                ..Default::default()
+            },
+            AdmissionLimits {
+                max_inflight_gets: Some(1),
+                ..Default::default()
+            },
+        ));
+
+        // バケツ毎の上限
+        let get = controller
+            .admit("limited", RequestKind::Get)
+            .expect("Never fails");
+        let e = controller
+            .admit("limited", RequestKind::Get)
+            .err()
+            .expect("Never fails");
+        assert_eq!(*e.kind(), ErrorKind::Busy(Duration::from_millis(500)));
+        assert!(controller.admit("other", RequestKind::Get).is_ok());
+        drop(get);
+        assert!(controller.admit("limited", RequestKind::Get).is_ok());
+
+        // 全体の上限
+        let puts = (0..3)
+            .map(|i| controller.admit(&format!("bucket{}", i), RequestKind::Put))
+            .collect::<Result<Vec<_>>>()
+            .expect("Never fails");
+        assert!(controller.admit("limited", RequestKind::Put).is_err());
+        assert!(controller.admit("limited", RequestKind::Get).is_ok());
+        drop(puts);
+        assert!(controller.admit("limited", RequestKind::Put).is_ok());
+    }
+
+    #[test]
+    fn check_does_not_reserve() {
+        let controller = AdmissionController::new(config(
+            AdmissionLimits::default(),
+            AdmissionLimits {
+                max_inflight_puts: Some(1),
+                max_requests_per_sec: Some(1.0),
+                ..Default::default()
+            },
+        ));
+        assert!(controller.check("limited", RequestKind::Put).is_ok());
+        assert!(controller.check("limited", RequestKind::Put).is_ok());
+
+        let _put = controller
+            .admit("limited", RequestKind::Put)
+            .expect("Never fails");
+        let e = controller
+            .check("limited", RequestKind::Put)
+            .err()
+            .expect("Never fails");
+        assert_eq!(*e.kind(), ErrorKind::Busy(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn rate_limit_works() {
+        let controller = AdmissionController::new(config(
+            AdmissionLimits::default(),
+            AdmissionLimits {
+                max_requests_per_sec: Some(2.0),
+                ..Default::default()
+            },
+        ));
+        assert!(controller.admit("limited", RequestKind::Get).is_ok());
+        assert!(controller.admit("limited", RequestKind::Put).is_ok());
+        let e = controller
+            .admit("limited", RequestKind::Get)
+            .err()
+            .expect("Never fails");
+        if let ErrorKind::Busy(retry_after) = *e.kind() {
+            assert!(retry_after > Duration::from_millis(0));
+            assert!(retry_after <= Duration::from_millis(500));
+        } else {
+            panic!("Unexpected error: {}", e);
+        }
+        assert!(controller.admit("other", RequestKind::Get).is_ok());
+    }
+
+    #[test]
+    fn token_bucket_works() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, now);
+        for _ in 0..10 {
+            assert_eq!(bucket.wait_time(now), None);
+            bucket.consume();
+        }
+        assert_eq!(bucket.wait_time(now), Some(Duration::from_millis(100)));
+        assert_eq!(bucket.wait_time(now + Duration::from_millis(100)), None);
+    }
+}
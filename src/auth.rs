@@ -0,0 +1,548 @@
+//! RPC と HTTP の API に対する認証と認可を行うためのモジュール。
+//!
+//! HTTP では、クライアントは`Authorization: Bearer <token>`ヘッダでトークンを提示する。
+//! トークンを提示しなかったクライアントは、匿名の主体(`Principal::Anonymous`)として扱われる。
+//!
+//! RPC のメッセージ(`libfrugalos`で定義されている)には認証情報を載せる場所が無いので、
+//! RPC では`frugalos_core::rpc_auth::Authenticated`で要求にトークンを付加した呼び出しを使う。
+//! 従来の(トークンを伴わない)呼び出しは匿名の主体として認可される。
+//!
+//! 設定でクラスタトークンが指定されている場合には、それを提示した RPC の呼び出しには全ての操作が許可される。
+//! サーバ間の RPC(構成管理の更新系、MDS、セグメント)は、クラスタトークンを伴う呼び出しのみが受け付けられる。
+//! なお、Raft の RPC とデバイスの RPC は認証の対象外なので、それらのポートへの到達はネットワーク側で制限すること。
+use atomic_immut::AtomicImmut;
+use fibers_http_server::{HandleRequest, Req, Res, Status};
+use frugalos_core::rpc_auth;
+use httpcodec::Header;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use url::Url;
+
+use http::{make_json_response, HttpResult};
+use {AuthorizerConfig, ErrorKind, FrugalosAuthConfig, Result};
+
+/// ACL 中で、匿名の主体を表す名前。
+pub const ANONYMOUS: &str = "anonymous";
+
+/// ACL 中で、全ての主体(匿名の主体を含む)を表す名前。
+pub const ANYONE: &str = "*";
+
+/// 操作を要求した主体。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Principal {
+    /// トークンを提示しなかった主体。
+    Anonymous,
+
+    /// トークンによって認証された主体。
+    Named(String),
+}
+impl Principal {
+    /// ACL 中で、この主体を表す名前を返す。
+    pub fn name(&self) -> &str {
+        match self {
+            Principal::Anonymous => ANONYMOUS,
+            Principal::Named(name) => name,
+        }
+    }
+}
+
+/// 操作の対象。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resource {
+    /// バケツ(およびその中のオブジェクト)。
+    Bucket(String),
+
+    /// 特定のバケツに属さない、クラスタやサーバの管理操作。
+    Cluster,
+}
+
+/// 操作に必要な権限。
+///
+/// 上位の権限は下位の権限を含む(`Admin` > `Write` > `Read`)。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    /// オブジェクトや状態の参照。
+    Read,
+
+    /// オブジェクトの作成、更新、削除。
+    Write,
+
+    /// バケツやクラスタの構成の変更。
+    Admin,
+}
+
+/// 操作の認証と認可を行うためのトレイト。
+///
+/// RPC と HTTP のサーバは、バケツやオブジェクトに対する全ての操作の実行前に、
+/// `authenticate`と`authorize`を順に呼び出す。
+/// どちらかがエラーを返した場合には、操作は実行されない。
+pub trait Authorizer: Send + Sync + 'static {
+    /// 提示されたトークンから主体を特定する。
+    ///
+    /// トークンが提示されなかった場合には`token`は`None`となる。
+    /// トークンが不正な場合には`ErrorKind::Unauthenticated`を返すこと。
+    fn authenticate(&self, token: Option<&str>) -> Result<Principal>;
+
+    /// 主体が対象に対する権限を持つかどうかを判定する。
+    ///
+    /// 権限を持たない場合には、匿名の主体に対しては`ErrorKind::Unauthenticated`を、
+    /// それ以外の主体に対しては`ErrorKind::PermissionDenied`を返すこと。
+    fn authorize(
+        &self,
+        principal: &Principal,
+        resource: &Resource,
+        permission: Permission,
+    ) -> Result<()>;
+}
+
+/// 全ての操作を許可する`Authorizer`。
+///
+/// 認可が設定されていない場合に使われる。
+#[derive(Debug, Default, Clone)]
+pub struct AllowAllAuthorizer;
+impl Authorizer for AllowAllAuthorizer {
+    fn authenticate(&self, _token: Option<&str>) -> Result<Principal> {
+        Ok(Principal::Anonymous)
+    }
+
+    fn authorize(&self, _: &Principal, _: &Resource, _: Permission) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// 事前に共有したトークンのいずれかを提示した主体にのみ、全ての操作を許可する`Authorizer`。
+#[derive(Debug, Clone)]
+pub struct StaticTokenAuthorizer {
+    // トークンと主体の名前の組
+    //
+    // トークンの比較に要する時間から内容を推測されないように、照合時には常に全ての組と定数時間で比較する
+    principals: Vec<(String, String)>,
+}
+impl StaticTokenAuthorizer {
+    /// 新しい`StaticTokenAuthorizer`インスタンスを生成する。
+    ///
+    /// `tokens`は、主体の名前からトークンへの対応。
+    pub fn new(tokens: &BTreeMap<String, String>) -> Result<Self> {
+        let mut principals: Vec<(String, String)> = Vec::new();
+        for (name, token) in tokens {
+            track_assert!(!token.is_empty(), ErrorKind::InvalidInput; name);
+            track_assert!(
+                name != ANONYMOUS && name != ANYONE,
+                ErrorKind::InvalidInput,
+                "Reserved principal name: {:?}",
+                name
+            );
+            if let Some((_, other)) = principals.iter().find(|(t, _)| t == token) {
+                track_panic!(
+                    ErrorKind::InvalidInput,
+                    "Principals {:?} and {:?} share the same token",
+                    other,
+                    name
+                );
+            }
+            principals.push((token.clone(), name.clone()));
+        }
+        Ok(StaticTokenAuthorizer { principals })
+    }
+}
+impl Authorizer for StaticTokenAuthorizer {
+    fn authenticate(&self, token: Option<&str>) -> Result<Principal> {
+        if let Some(token) = token {
+            let mut matched = None;
+            for (candidate, name) in &self.principals {
+                if rpc_auth::constant_time_eq(candidate.as_bytes(), token.as_bytes()) {
+                    matched = Some(name);
+                }
+            }
+            let name = track_assert_some!(matched, ErrorKind::Unauthenticated, "Unknown token");
+            Ok(Principal::Named(name.clone()))
+        } else {
+            Ok(Principal::Anonymous)
+        }
+    }
+
+    fn authorize(&self, principal: &Principal, _: &Resource, _: Permission) -> Result<()> {
+        track_assert_ne!(*principal, Principal::Anonymous, ErrorKind::Unauthenticated);
+        Ok(())
+    }
+}
+
+/// ある対象に対するアクセス制御リスト。
+///
+/// 各権限を持つ主体の名前を列挙する。
+/// 上位の権限のリストに含まれる主体は、下位の権限も持つ。
+///
+/// 名前には、`anonymous`(匿名の主体)と`*`(全ての主体)も指定できる。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketAcl {
+    /// 参照を許可する主体。
+    #[serde(default)]
+    pub read: Vec<String>,
+
+    /// 書き込みを許可する主体。
+    #[serde(default)]
+    pub write: Vec<String>,
+
+    /// 管理操作を許可する主体。
+    #[serde(default)]
+    pub admin: Vec<String>,
+}
+impl BucketAcl {
+    /// `principal`が`permission`を持つかどうかを判定する。
+    pub fn allows(&self, principal: &Principal, permission: Permission) -> bool {
+        let name = principal.name();
+        let contains = |list: &Vec<String>| list.iter().any(|n| n == name || n == ANYONE);
+        match permission {
+            Permission::Read => {
+                contains(&self.read) || contains(&self.write) || contains(&self.admin)
+            }
+            Permission::Write => contains(&self.write) || contains(&self.admin),
+            Permission::Admin => contains(&self.admin),
+        }
+    }
+}
+
+/// バケツ毎のアクセス制御リストに従って認可を行う`Authorizer`。
+///
+/// 主体の認証は`StaticTokenAuthorizer`と同様にトークンで行う。
+/// ACL が設定されていないバケツと、バケツに属さない操作には、デフォルトの ACL が使われる。
+#[derive(Debug, Clone)]
+pub struct BucketAclAuthorizer {
+    tokens: StaticTokenAuthorizer,
+    buckets: BTreeMap<String, BucketAcl>,
+    default: BucketAcl,
+}
+impl BucketAclAuthorizer {
+    /// 新しい`BucketAclAuthorizer`インスタンスを生成する。
+    pub fn new(
+        tokens: &BTreeMap<String, String>,
+        buckets: BTreeMap<String, BucketAcl>,
+        default: BucketAcl,
+    ) -> Result<Self> {
+        let tokens = track!(StaticTokenAuthorizer::new(tokens))?;
+        Ok(BucketAclAuthorizer {
+            tokens,
+            buckets,
+            default,
+        })
+    }
+
+    fn acl(&self, resource: &Resource) -> &BucketAcl {
+        match resource {
+            Resource::Bucket(bucket_id) => self.buckets.get(bucket_id).unwrap_or(&self.default),
+            Resource::Cluster => &self.default,
+        }
+    }
+}
+impl Authorizer for BucketAclAuthorizer {
+    fn authenticate(&self, token: Option<&str>) -> Result<Principal> {
+        track!(self.tokens.authenticate(token))
+    }
+
+    fn authorize(
+        &self,
+        principal: &Principal,
+        resource: &Resource,
+        permission: Permission,
+    ) -> Result<()> {
+        if self.acl(resource).allows(principal, permission) {
+            return Ok(());
+        }
+        let kind = if *principal == Principal::Anonymous {
+            ErrorKind::Unauthenticated
+        } else {
+            ErrorKind::PermissionDenied
+        };
+        track_panic!(
+            kind,
+            "{:?} is not allowed to {:?} {:?}",
+            principal.name(),
+            permission,
+            resource
+        );
+    }
+}
+
+/// 設定から組み込みの`Authorizer`を生成する。
+pub fn authorizer_from_config(config: &FrugalosAuthConfig) -> Result<Arc<dyn Authorizer>> {
+    Ok(match config.authorizer {
+        AuthorizerConfig::AllowAll => Arc::new(AllowAllAuthorizer),
+        AuthorizerConfig::StaticToken => {
+            Arc::new(track!(StaticTokenAuthorizer::new(&config.tokens))?)
+        }
+        AuthorizerConfig::BucketAcl {
+            ref buckets,
+            ref default,
+        } => Arc::new(track!(BucketAclAuthorizer::new(
+            &config.tokens,
+            buckets.clone(),
+            default.clone()
+        ))?),
+    })
+}
+
+/// RPC と HTTP のサーバで共有される`Authorizer`。
+///
+/// サーバの起動後に、使用する`Authorizer`を差し替えることができる。
+#[derive(Clone)]
+pub struct SharedAuthorizer(Arc<AtomicImmut<Arc<dyn Authorizer>>>);
+impl SharedAuthorizer {
+    /// 新しい`SharedAuthorizer`インスタンスを生成する。
+    pub fn new(authorizer: Arc<dyn Authorizer>) -> Self {
+        SharedAuthorizer(Arc::new(AtomicImmut::new(authorizer)))
+    }
+
+    /// 使用する`Authorizer`を差し替える。
+    pub fn set(&self, authorizer: Arc<dyn Authorizer>) {
+        self.0.store(authorizer);
+    }
+
+    /// `token`を提示した主体が、対象に対する権限を持つかどうかを判定する。
+    pub fn check(
+        &self,
+        token: Option<&str>,
+        resource: &Resource,
+        permission: Permission,
+    ) -> Result<()> {
+        let authorizer = self.0.load();
+        let principal = track!(authorizer.authenticate(token))?;
+        track!(authorizer.authorize(&principal, resource, permission))
+    }
+}
+impl ::std::fmt::Debug for SharedAuthorizer {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "SharedAuthorizer {{ .. }}")
+    }
+}
+
+type ResourceSelector = Arc<dyn Fn(&Url) -> Resource + Send + Sync + 'static>;
+
+/// HTTP ハンドラをラップして、リクエストの処理前に認可を行う。
+///
+/// 認可に失敗した場合には、リクエストボディを読み込まずに
+/// `401 Unauthorized`ないし`403 Forbidden`を返す。
+pub struct WithAuth<H> {
+    inner: H,
+    authorizer: SharedAuthorizer,
+    permission: Permission,
+    resource: ResourceSelector,
+}
+impl<H: HandleRequest> WithAuth<H> {
+    /// パスに含まれるバケツを対象とする`WithAuth`インスタンスを生成する。
+    ///
+    /// 対象のハンドラのパスは`/v1/buckets/{bucket_id}/...`の形式である必要がある。
+    pub fn bucket(inner: H, authorizer: SharedAuthorizer, permission: Permission) -> Self {
+        Self::new(inner, authorizer, permission, |url| {
+            Resource::Bucket(bucket_id_from_path(url.path()).to_owned())
+        })
+    }
+
+    /// クラスタを対象とする`WithAuth`インスタンスを生成する。
+    pub fn cluster(inner: H, authorizer: SharedAuthorizer, permission: Permission) -> Self {
+        Self::new(inner, authorizer, permission, |_| Resource::Cluster)
+    }
+
+    /// リクエストの URL から対象を決定する`WithAuth`インスタンスを生成する。
+    pub fn new<F>(inner: H, authorizer: SharedAuthorizer, permission: Permission, f: F) -> Self
+    where
+        F: Fn(&Url) -> Resource + Send + Sync + 'static,
+    {
+        WithAuth {
+            inner,
+            authorizer,
+            permission,
+            resource: Arc::new(f),
+        }
+    }
+}
+impl<H, T> HandleRequest for WithAuth<H>
+where
+    H: HandleRequest<ResBody = HttpResult<T>>,
+{
+    const METHOD: &'static str = H::METHOD;
+    const PATH: &'static str = H::PATH;
+
+    type ReqBody = H::ReqBody;
+    type ResBody = H::ResBody;
+    type Decoder = H::Decoder;
+    type Encoder = H::Encoder;
+    type Reply = H::Reply;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        self.inner.handle_request(req)
+    }
+
+    fn handle_request_head(&self, req: &Req<()>) -> Option<Res<Self::ResBody>> {
+        let resource = (self.resource)(req.url());
+        let token = get_bearer_token(&req.header());
+        let result = self.authorizer.check(
+            token.as_ref().map(String::as_str),
+            &resource,
+            self.permission,
+        );
+        if let Err(e) = track!(result) {
+            let status = if *e.kind() == ErrorKind::Unauthenticated {
+                Status::Unauthorized
+            } else {
+                Status::Forbidden
+            };
+            return Some(make_json_response(status, Err(e)));
+        }
+        self.inner.handle_request_head(req)
+    }
+}
+
+/// `Authorization: Bearer <token>`ヘッダからトークンを取り出す。
+fn get_bearer_token(header: &Header) -> Option<String> {
+    for field in header.fields() {
+        if field.name().eq_ignore_ascii_case("authorization") {
+            return parse_bearer_token(field.value());
+        }
+    }
+    None
+}
+
+/// `Authorization`の値から、Bearer トークンを取り出す。
+pub(crate) fn parse_bearer_token(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.len() > 7 && value.is_char_boundary(7) && value[..7].eq_ignore_ascii_case("bearer ") {
+        Some(value[7..].trim().to_owned())
+    } else {
+        None
+    }
+}
+
+fn bucket_id_from_path(path: &str) -> &str {
+    path.trim_start_matches('/').split('/').nth(2).unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens() -> BTreeMap<String, String> {
+        let mut tokens = BTreeMap::new();
+        tokens.insert("alice".to_owned(), "alice-token".to_owned());
+        tokens.insert("bob".to_owned(), "bob-token".to_owned());
+        tokens
+    }
+
+    fn bucket(id: &str) -> Resource {
+        Resource::Bucket(id.to_owned())
+    }
+
+    #[test]
+    fn static_token_authorizer_works() -> Result<()> {
+        let authorizer = track!(StaticTokenAuthorizer::new(&tokens()))?;
+        let auth = SharedAuthorizer::new(Arc::new(authorizer));
+
+        assert!(auth
+            .check(Some("alice-token"), &bucket("foo"), Permission::Admin)
+            .is_ok());
+        assert!(auth
+            .check(Some("bob-token"), &Resource::Cluster, Permission::Write)
+            .is_ok());
+
+        let e = auth
+            .check(Some("unknown"), &bucket("foo"), Permission::Read)
+            .err()
+            .expect("Never fails");
+        assert_eq!(*e.kind(), ErrorKind::Unauthenticated);
+
+        // 前方一致するだけのトークンは受け付けない
+        let e = auth
+            .check(Some("alice-toke"), &bucket("foo"), Permission::Read)
+            .err()
+            .expect("Never fails");
+        assert_eq!(*e.kind(), ErrorKind::Unauthenticated);
+
+        let e = auth
+            .check(None, &bucket("foo"), Permission::Read)
+            .err()
+            .expect("Never fails");
+        assert_eq!(*e.kind(), ErrorKind::Unauthenticated);
+        Ok(())
+    }
+
+    #[test]
+    fn static_token_authorizer_rejects_invalid_tokens() {
+        let mut tokens = tokens();
+        tokens.insert("carol".to_owned(), "alice-token".to_owned());
+        assert!(StaticTokenAuthorizer::new(&tokens).is_err());
+
+        let mut tokens = BTreeMap::new();
+        tokens.insert(ANONYMOUS.to_owned(), "token".to_owned());
+        assert!(StaticTokenAuthorizer::new(&tokens).is_err());
+    }
+
+    #[test]
+    fn bucket_acl_authorizer_works() -> Result<()> {
+        let mut buckets = BTreeMap::new();
+        buckets.insert(
+            "public".to_owned(),
+            BucketAcl {
+                read: vec![ANYONE.to_owned()],
+                write: vec!["bob".to_owned()],
+                admin: Vec::new(),
+            },
+        );
+        let default = BucketAcl {
+            read: Vec::new(),
+            write: Vec::new(),
+            admin: vec!["alice".to_owned()],
+        };
+        let authorizer = track!(BucketAclAuthorizer::new(&tokens(), buckets, default))?;
+        let auth = SharedAuthorizer::new(Arc::new(authorizer));
+
+        let check = |token, resource, permission| {
+            auth.check(token, &resource, permission)
+                .map_err(|e| e.kind().clone())
+        };
+        assert_eq!(check(None, bucket("public"), Permission::Read), Ok(()));
+        assert_eq!(
+            check(None, bucket("public"), Permission::Write),
+            Err(ErrorKind::Unauthenticated)
+        );
+        assert_eq!(
+            check(Some("bob-token"), bucket("public"), Permission::Write),
+            Ok(())
+        );
+        assert_eq!(
+            check(Some("bob-token"), bucket("public"), Permission::Admin),
+            Err(ErrorKind::PermissionDenied)
+        );
+        assert_eq!(
+            check(Some("alice-token"), bucket("public"), Permission::Write),
+            Err(ErrorKind::PermissionDenied)
+        );
+
+        // ACL が無いバケツにはデフォルトの ACL が使われる
+        assert_eq!(
+            check(Some("alice-token"), bucket("private"), Permission::Read),
+            Ok(())
+        );
+        assert_eq!(
+            check(Some("alice-token"), Resource::Cluster, Permission::Admin),
+            Ok(())
+        );
+        assert_eq!(
+            check(Some("bob-token"), bucket("private"), Permission::Read),
+            Err(ErrorKind::PermissionDenied)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_bearer_token_works() {
+        assert_eq!(parse_bearer_token("Bearer foo"), Some("foo".to_owned()));
+        assert_eq!(parse_bearer_token(" bearer  foo "), Some("foo".to_owned()));
+        assert_eq!(parse_bearer_token("Basic Zm9vOmJhcg=="), None);
+        assert_eq!(parse_bearer_token("Bearer"), None);
+    }
+
+    #[test]
+    fn bucket_id_from_path_works() {
+        assert_eq!(bucket_id_from_path("/v1/buckets/foo/objects/bar"), "foo");
+        assert_eq!(bucket_id_from_path("/v1/buckets/foo"), "foo");
+    }
+}
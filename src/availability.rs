@@ -0,0 +1,200 @@
+//! 大規模な障害の後に、各バケツのオブジェクトがどの程度読み出し可能かを調べるためのモジュール。
+//!
+//! MDS が保持するオブジェクト一覧と、各メンバに対する断片の有無の問い合わせを組み合わせて、
+//! オブジェクトを以下のいずれかに分類する:
+//!
+//! - `available`: 全ての断片が揃っている
+//! - `degraded`: 一部の断片が失われているが、残りの断片から復元可能
+//! - `lost`: 復元に必要な数の断片が残っていない
+//! - `unknown`: 応答しないメンバがあるため判定できない
+//!
+//! 調査では書き込みやリペアは一切行わないので、障害の直後でも状況を悪化させることなく実行できる。
+use cannyls::deadline::Deadline;
+use frugalos_segment::config::RequestPriority;
+use frugalos_segment::AvailabilitySummary;
+use futures::future::{self, Either};
+use futures::{Future, Stream};
+use libfrugalos::entity::bucket::{BucketId, BucketKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+use trackable::error::ErrorKindExt;
+
+use client::{BucketHandle, FrugalosClient};
+use operation::OperationHandle;
+use {Error, ErrorKind, Result};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+
+/// 各セグメント内で、並行して監査するオブジェクト数のデフォルト値。
+pub const DEFAULT_CONCURRENCY: usize = 16;
+
+/// バケツ毎に報告する、復元できないオブジェクトの ID の最大数のデフォルト値。
+pub const DEFAULT_MAX_LOST_OBJECTS: usize = 1000;
+
+/// 可用性の調査方法の指定。
+#[derive(Debug, Clone)]
+pub struct AvailabilityOptions {
+    /// 各セグメント内で、並行して監査するオブジェクト数。
+    pub concurrency: usize,
+
+    /// バケツ毎に報告する、復元できないオブジェクトの ID の最大数。
+    pub max_lost_objects: usize,
+
+    /// 各オブジェクトの監査の期限(未指定の場合はバケツのデフォルト値)。
+    pub deadline: Option<Deadline>,
+
+    /// 監査時のリクエストの優先度。
+    pub priority: RequestPriority,
+}
+impl Default for AvailabilityOptions {
+    fn default() -> Self {
+        AvailabilityOptions {
+            concurrency: DEFAULT_CONCURRENCY,
+            max_lost_objects: DEFAULT_MAX_LOST_OBJECTS,
+            deadline: None,
+            priority: RequestPriority::Bulk,
+        }
+    }
+}
+
+/// 調査できなかったセグメント。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentFailure {
+    /// セグメント番号。
+    pub segment: u16,
+
+    /// 失敗の原因。
+    pub error: String,
+}
+
+/// バケツ単位の可用性の調査結果。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BucketAvailability {
+    /// バケツの ID。
+    pub bucket_id: BucketId,
+
+    /// バケツのセグメント数。
+    pub segments: u16,
+
+    /// 調査できたセグメント内のオブジェクトの集計結果。
+    pub summary: AvailabilitySummary,
+
+    /// MDS から一覧を取得できない等の理由で、調査できなかったセグメントの一覧。
+    ///
+    /// これらのセグメント内のオブジェクトは`summary`に含まれないので、
+    /// 空でない場合には調査結果は不完全である。
+    pub failed_segments: Vec<SegmentFailure>,
+}
+
+/// 可用性の調査結果。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AvailabilityReport {
+    /// 調査を開始した時刻(UNIXエポックからの秒数)。
+    pub started_at: u64,
+
+    /// バケツ毎の調査結果。
+    pub buckets: Vec<BucketAvailability>,
+}
+
+/// 調査対象のバケツを決定する。
+///
+/// `bucket_ids`が空の場合には、メタデータ用のものを除く全てのバケツが対象となる。
+/// 明示的に指定されたバケツが存在しないか、メタデータ用のものである場合にはエラーとなる。
+pub fn select_buckets(
+    client: &FrugalosClient,
+    bucket_ids: &[BucketId],
+) -> Result<Vec<BucketHandle>> {
+    if bucket_ids.is_empty() {
+        let mut ids = client.bucket_ids();
+        ids.sort();
+        let buckets = ids
+            .iter()
+            .filter_map(|id| client.bucket(id).ok())
+            .filter(|b| !is_metadata(b))
+            .collect();
+        return Ok(buckets);
+    }
+    let mut buckets = Vec::with_capacity(bucket_ids.len());
+    for id in bucket_ids {
+        let bucket = track!(client.bucket(id))?;
+        track_assert!(
+            !is_metadata(&bucket),
+            ErrorKind::InvalidInput,
+            "The metadata bucket {:?} has no fragments to survey",
+            id
+        );
+        buckets.push(bucket);
+    }
+    Ok(buckets)
+}
+
+/// `buckets`の全セグメントの可用性を調査する。
+///
+/// セグメントは一つずつ順番に調査され、操作の進捗は調査済みのセグメント数で表される。
+/// 一部のセグメントの調査に失敗しても、調査全体は中断せずに`failed_segments`として報告する。
+pub fn survey_buckets(
+    buckets: Vec<BucketHandle>,
+    options: AvailabilityOptions,
+    operation: OperationHandle,
+) -> BoxFuture<AvailabilityReport> {
+    let report = AvailabilityReport {
+        started_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        buckets: buckets
+            .iter()
+            .map(|b| BucketAvailability {
+                bucket_id: b.id().clone(),
+                segments: b.segment_count(),
+                summary: AvailabilitySummary::default(),
+                failed_segments: Vec::new(),
+            })
+            .collect(),
+    };
+    let targets = buckets
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, b)| (0..b.segment_count()).map(move |s| (i, b.clone(), s)))
+        .collect::<Vec<_>>();
+    operation.set_total(targets.len() as u64);
+
+    let max_lost_objects = options.max_lost_objects;
+    let future = futures::stream::iter_ok(targets)
+        .and_then(move |(i, bucket, segment)| {
+            if operation.is_cancelled() {
+                let e = ErrorKind::Other.cause("The availability survey has been cancelled");
+                return Either::A(future::failed(e.into()));
+            }
+            let operation = operation.clone();
+            let future = bucket
+                .request()
+                .deadline(options.deadline)
+                .priority(options.priority)
+                .survey_availability(
+                    segment as usize,
+                    options.concurrency,
+                    options.max_lost_objects,
+                )
+                .then(move |result| {
+                    operation.add_done(1);
+                    Ok((i, segment, result))
+                });
+            Either::B(future)
+        })
+        .fold(report, move |mut report, (i, segment, result)| {
+            let bucket = &mut report.buckets[i];
+            match result {
+                Ok(summary) => bucket.summary.merge(summary, max_lost_objects),
+                Err(e) => bucket.failed_segments.push(SegmentFailure {
+                    segment,
+                    error: e.to_string(),
+                }),
+            }
+            Ok(report) as Result<_>
+        });
+    Box::new(future)
+}
+
+fn is_metadata(bucket: &BucketHandle) -> bool {
+    matches!(*bucket.kind(), BucketKind::Metadata)
+}
@@ -1,110 +1,321 @@
 #![allow(clippy::ptr_arg)]
+use atomic_immut::AtomicImmut;
+use cannyls::deadline::Deadline;
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
-use frugalos_segment::config::ClusterMember;
+use frugalos_core::logging;
+use frugalos_mds::SharedQuota;
+use frugalos_segment::config::{ClusterMember, ErasureCoderConfig, PreviousLayout, Storage};
+use frugalos_segment::encryption::ContentEncryption;
 use frugalos_segment::Client as Segment;
-use frugalos_segment::{self, ErasureCoder, FrugalosSegmentConfig};
-use libfrugalos::entity::bucket::Bucket as BucketConfig;
+use frugalos_segment::{
+    self, AccessMode, ContentCacheSizing, ErasureCodingPool, FeatureFlags, FrugalosSegmentConfig,
+    MaintenanceSchedule, SharedErasureCoding,
+};
+use libfrugalos::consistency::ReadConsistency;
+use libfrugalos::entity::bucket::{Bucket as BucketConfig, BucketId, BucketKind};
 use libfrugalos::entity::object::ObjectId;
 use siphasher;
 use slog::Logger;
-use std::iter;
+use std::sync::Arc;
+use std::time::Duration;
 
+use client::BucketDefaults;
 use Result;
 
 #[derive(Clone)]
 pub struct Bucket {
     logger: Logger,
+    bucket_id: BucketId,
     rpc_service: RpcServiceHandle,
-    ec: Option<ErasureCoder>,
-    storage_config: frugalos_segment::config::Storage,
+    kind: BucketKind,
+    storage_config: Storage,
     segment_config: FrugalosSegmentConfig,
+    erasure_coder: ErasureCoderConfig,
+    encryption: ContentEncryption,
+    ec_pool: ErasureCodingPool,
+    cache_sizing: ContentCacheSizing,
+    maintenance: MaintenanceSchedule,
+    features: FeatureFlags,
+    access: AccessMode,
+    request_defaults: RequestDefaults,
+    quota: SharedQuota,
+    coding: SharedErasureCoding,
     segments: Vec<Segment>,
 }
 impl Bucket {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         logger: Logger,
         rpc_service: RpcServiceHandle,
         config: &BucketConfig,
         segment_config: FrugalosSegmentConfig,
+        ec_pool: ErasureCodingPool,
+        cache_sizing: ContentCacheSizing,
+        maintenance: MaintenanceSchedule,
+        features: FeatureFlags,
+        access: AccessMode,
+        request_defaults: RequestDefaults,
+        quota: SharedQuota,
+        coding: SharedErasureCoding,
     ) -> Result<Self> {
-        let ec = match config {
-            BucketConfig::Metadata(_) => None,
-            BucketConfig::Replicated(_) => None,
-            BucketConfig::Dispersed(ref c) => Some(frugalos_segment::build_ec(
-                c.data_fragment_count as usize,
-                c.tolerable_faults as usize,
-            )),
-        };
-
-        let storage_config = match config {
-            BucketConfig::Metadata(_) => frugalos_segment::config::Storage::Metadata,
-            BucketConfig::Replicated(ref b) => {
-                let c = frugalos_segment::config::ReplicatedConfig {
-                    tolerable_faults: b.tolerable_faults as u8,
-                };
-                frugalos_segment::config::Storage::Replicated(c)
-            }
-            BucketConfig::Dispersed(ref b) => {
-                let c = frugalos_segment::config::DispersedConfig {
-                    tolerable_faults: b.tolerable_faults as u8,
-                    fragments: (b.tolerable_faults + b.data_fragment_count) as u8,
-                };
-                frugalos_segment::config::Storage::Dispersed(c)
-            }
-        };
+        let storage_config = storage_config(config);
 
+        let erasure_coder = segment_config
+            .erasure_coding
+            .coder_config(config.id())
+            .clone();
+        let encryption = segment_config.encryption.content_encryption(config.id());
         let client_config = frugalos_segment::config::ClientConfig {
+            bucket_id: config.id().clone(),
             cluster: frugalos_segment::config::ClusterConfig {
                 members: Vec::new(),
+                mds_witnesses: 0,
             },
             dispersed_client: segment_config.dispersed_client.clone(),
             replicated_client: segment_config.replicated_client.clone(),
             storage: storage_config.clone(),
             mds: segment_config.mds_client.clone(),
+            erasure_coder: erasure_coder.clone(),
+            coding: coding.clone(),
+            request_priority: segment_config.request_priority.clone(),
+            encryption: Some(encryption.clone()),
+            compaction: segment_config.compaction.clone(),
+            retry: segment_config.retry.clone(),
+            member_health: segment_config.member_health.clone(),
+            circuit_breaker: segment_config.circuit_breaker.clone(),
+            budget: segment_config.budget.clone(),
+            ec_pool: ec_pool.clone(),
+            content_cache: segment_config.content_cache.clone(),
+            cache_sizing: cache_sizing.clone(),
+            maintenance: maintenance.clone(),
+            features: features.clone(),
+            access: access.clone(),
+            previous_layout: None,
         };
-        let segment = track!(Segment::new(
-            logger.clone(),
-            rpc_service.clone(),
-            client_config,
-            ec.clone(),
-        ))?;
-        let segments = iter::repeat(segment)
-            .take(config.segment_count() as usize)
-            .collect();
+        let logger = logger.new(o!(logging::BUCKET => config.id().clone()));
+        let segments = (0..config.segment_count())
+            .map(|segment_no| {
+                track!(Segment::new(
+                    logger.new(o!(logging::SEGMENT => segment_no)),
+                    rpc_service.clone(),
+                    client_config.clone(),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
         Ok(Bucket {
             logger,
+            bucket_id: config.id().clone(),
             rpc_service,
-            ec,
+            kind: config.kind(),
             storage_config,
             segments,
             segment_config,
+            erasure_coder,
+            encryption,
+            ec_pool,
+            cache_sizing,
+            maintenance,
+            features,
+            access,
+            request_defaults,
+            quota,
+            coding,
         })
     }
-    pub fn update_segment(&mut self, segment_no: u16, members: Vec<ClusterMember>) -> Result<()> {
+    pub fn update_segment(
+        &mut self,
+        segment_no: u16,
+        members: Vec<ClusterMember>,
+        mds_witnesses: usize,
+        previous_layout: Option<PreviousLayout>,
+    ) -> Result<()> {
         let segment_config = frugalos_segment::config::ClientConfig {
-            cluster: frugalos_segment::config::ClusterConfig { members },
+            bucket_id: self.bucket_id.clone(),
+            cluster: frugalos_segment::config::ClusterConfig {
+                members,
+                mds_witnesses,
+            },
             dispersed_client: self.segment_config.dispersed_client.clone(),
             replicated_client: self.segment_config.replicated_client.clone(),
             storage: self.storage_config.clone(),
             mds: self.segment_config.mds_client.clone(),
+            erasure_coder: self.erasure_coder.clone(),
+            coding: self.coding.clone(),
+            request_priority: self.segment_config.request_priority.clone(),
+            encryption: Some(self.encryption.clone()),
+            compaction: self.segment_config.compaction.clone(),
+            retry: self.segment_config.retry.clone(),
+            member_health: self.segment_config.member_health.clone(),
+            circuit_breaker: self.segment_config.circuit_breaker.clone(),
+            budget: self.segment_config.budget.clone(),
+            ec_pool: self.ec_pool.clone(),
+            content_cache: self.segment_config.content_cache.clone(),
+            cache_sizing: self.cache_sizing.clone(),
+            maintenance: self.maintenance.clone(),
+            features: self.features.clone(),
+            access: self.access.clone(),
+            previous_layout,
         };
         let segment = track!(Segment::new(
-            self.logger.clone(),
+            self.logger.new(o!(logging::SEGMENT => segment_no)),
             self.rpc_service.clone(),
             segment_config,
-            self.ec.clone(),
         ))?;
         self.segments[segment_no as usize] = segment;
         Ok(())
     }
     pub fn get_segment(&self, id: &ObjectId) -> &Segment {
+        &self.segments[self.segment_index(id)]
+    }
+    /// オブジェクトが割り当てられるセグメントの番号を返す。
+    pub fn segment_index(&self, id: &ObjectId) -> usize {
         use std::hash::{Hash, Hasher};
         let mut hasher = siphasher::sip::SipHasher13::new();
         id.hash(&mut hasher);
-        let i = hasher.finish() as usize % self.segments.len();
-        &self.segments[i]
+        hasher.finish() as usize % self.segments.len()
     }
     pub fn segments(&self) -> &[Segment] {
         &self.segments
     }
+    pub fn kind(&self) -> &BucketKind {
+        &self.kind
+    }
+    pub fn features(&self) -> &FeatureFlags {
+        &self.features
+    }
+    pub fn access(&self) -> &AccessMode {
+        &self.access
+    }
+    pub fn request_defaults(&self) -> &RequestDefaults {
+        &self.request_defaults
+    }
+    /// 構成管理用クラスタでバケツの属性として設定された割り当て量を、セグメント毎に分割したもの。
+    ///
+    /// このサーバ上の、バケツの MDS ノード群と共有されている。
+    pub fn quota(&self) -> &SharedQuota {
+        &self.quota
+    }
+    /// 構成管理用クラスタでバケツの属性として設定された、Erasure Coding の実装。
+    pub fn coding(&self) -> &SharedErasureCoding {
+        &self.coding
+    }
+}
+#[cfg(test)]
+impl Bucket {
+    /// テスト用に、セグメントのメンバを持たない`config`のバケツを生成する。
+    ///
+    /// RPC のクライアントは起動されないので、MDS やストレージへの問い合わせは完了しない。
+    pub(crate) fn for_test(
+        config: &BucketConfig,
+        request_defaults: RequestDefaults,
+    ) -> Result<Self> {
+        use fibers::{Executor, InPlaceExecutor};
+        use fibers_rpc::client::ClientServiceBuilder as RpcServiceBuilder;
+        use frugalos_segment::config::ErasureCodingPoolConfig;
+        use slog::Discard;
+        use Error;
+
+        let executor = track!(InPlaceExecutor::new().map_err(Error::from))?;
+        let rpc_service = RpcServiceBuilder::new().finish(executor.handle());
+        let ec_pool = track!(ErasureCodingPool::new(&ErasureCodingPoolConfig {
+            worker_threads: 1,
+            ..ErasureCodingPoolConfig::default()
+        }))?;
+        track!(Bucket::new(
+            Logger::root(Discard, o!()),
+            rpc_service.handle(),
+            config,
+            FrugalosSegmentConfig::default(),
+            ec_pool,
+            ContentCacheSizing::default(),
+            MaintenanceSchedule::default(),
+            FeatureFlags::default(),
+            AccessMode::default(),
+            request_defaults,
+            SharedQuota::default(),
+            SharedErasureCoding::default(),
+        ))
+    }
+}
+
+/// バケツの設定に対応する、セグメントのストレージの設定を返す。
+pub fn storage_config(config: &BucketConfig) -> Storage {
+    match config {
+        BucketConfig::Metadata(_) => Storage::Metadata,
+        BucketConfig::Replicated(ref b) => {
+            let c = frugalos_segment::config::ReplicatedConfig {
+                tolerable_faults: b.tolerable_faults as u8,
+            };
+            Storage::Replicated(c)
+        }
+        BucketConfig::Dispersed(ref b) => {
+            let c = frugalos_segment::config::DispersedConfig {
+                tolerable_faults: b.tolerable_faults as u8,
+                fragments: (b.tolerable_faults + b.data_fragment_count) as u8,
+            };
+            Storage::Dispersed(c)
+        }
+    }
+}
+
+/// 構成管理用クラスタでバケツの属性として設定された、リクエストのデフォルト値。
+///
+/// `Clone`されたインスタンス間で状態が共有されるので、
+/// `set`による変更は、以降にそのバケツに対して作成されるリクエストに即座に反映される。
+///
+/// なお、RPC のリクエストは常にデッドラインを伴うので、デッドラインのデフォルト値は HTTP のリクエストにのみ適用される。
+#[derive(Clone)]
+pub struct RequestDefaults {
+    inner: Arc<AtomicImmut<(Option<Deadline>, Option<ReadConsistency>)>>,
+}
+impl RequestDefaults {
+    /// デフォルト値を設定する。
+    ///
+    /// `None`の項目には、クライアント全体のデフォルト値が使われる。
+    pub fn set(&self, deadline_ms: Option<u64>, consistency: Option<ReadConsistency>) {
+        let deadline = deadline_ms.map(|ms| Deadline::Within(Duration::from_millis(ms)));
+        self.inner.store((deadline, consistency));
+    }
+
+    /// クライアント全体のデフォルト値`base`に、このバケツのデフォルト値を適用した結果を返す。
+    pub fn apply(&self, base: &BucketDefaults) -> BucketDefaults {
+        let inner = self.inner.load();
+        BucketDefaults {
+            deadline: inner.0.unwrap_or(base.deadline),
+            consistency: inner.1.clone().unwrap_or_else(|| base.consistency.clone()),
+        }
+    }
+}
+impl Default for RequestDefaults {
+    fn default() -> Self {
+        RequestDefaults {
+            inner: Arc::new(AtomicImmut::new((None, None))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_defaults_works() {
+        let base = BucketDefaults::default();
+        let defaults = RequestDefaults::default();
+        assert_eq!(defaults.apply(&base), base);
+
+        let shared = defaults.clone();
+        shared.set(Some(60_000), None);
+        let applied = defaults.apply(&base);
+        assert_eq!(
+            applied.deadline,
+            Deadline::Within(Duration::from_millis(60_000))
+        );
+        assert_eq!(applied.consistency, base.consistency);
+
+        shared.set(None, Some(ReadConsistency::Stale));
+        let applied = defaults.apply(&base);
+        assert_eq!(applied.deadline, base.deadline);
+        assert_eq!(applied.consistency, ReadConsistency::Stale);
+    }
 }
@@ -0,0 +1,549 @@
+//! 特定のオブジェクトないしトレースに対するリクエストの処理過程を記録するためのモジュール。
+//!
+//! データパスの不具合の調査用に、対象のリクエストについて、
+//! 各ホップ(MDS への問い合わせ、断片の取得・保存、ErasureCoding の復号等)のスパンを丸ごと収集し、
+//! バグ報告に添付できる形式(`CaptureBundle`)で取り出せるようにする。
+//!
+//! 記録できるのはトレースされたリクエストのみである。
+//! 記録の開始時に`sample_all`を指定した場合に限り、その記録中はサンプリングレートの設定に関わらず
+//! 全てのリクエストがトレースされる(負荷が増えるので、必要な場合にのみ明示的に指定する)。
+//! 終了したスパンが対象のリクエストのものかどうかは、スパンの受信スレッド上で判定する。
+//! 子スパンは親スパンよりも先に終了するので、直近のトレースのスパンは一定数まで保留しておき、
+//! 対象と判明した時点でまとめて記録する。
+//!
+//! 記録されるのはスパンのタグとログのみで、オブジェクトの内容は含まれない。
+//! また、ピアのアドレスは記録毎の別名(`peer-0`等)に置き換えられる。
+use rustracing::sampler::Sampler;
+use rustracing::tag::TagValue;
+use rustracing_jaeger::span::{CandidateSpan, FinishedSpan, SpanContextState, TraceId};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use trackable::error::ErrorKindExt;
+
+use {ErrorKind, Result};
+
+/// 同時に保持できる記録の最大数。
+const MAX_CAPTURES: usize = 8;
+
+/// 一つの記録に含められるトレースの最大数。
+const MAX_TRACES_PER_CAPTURE: usize = 32;
+
+/// 一つのトレースに含められるスパンの最大数。
+const MAX_SPANS_PER_TRACE: usize = 512;
+
+/// 対象かどうか未確定のスパンを保留しておくトレースの最大数。
+const MAX_PENDING_TRACES: usize = 512;
+
+/// 記録期間が指定されなかった場合のデフォルト値。
+pub const DEFAULT_CAPTURE_DURATION: Duration = Duration::from_secs(10 * 60);
+
+/// 指定できる記録期間の最大値。
+pub const MAX_CAPTURE_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// 記録対象のリクエスト。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureTarget {
+    /// 指定のオブジェクトに対するリクエスト(`object.id`タグで判定する)。
+    ObjectId(String),
+
+    /// 指定のトレース ID を持つリクエスト。
+    TraceId(String),
+}
+impl CaptureTarget {
+    /// トレース ID を対象とするインスタンスを生成する。
+    ///
+    /// 表記揺れ(先頭のゼロ等)を吸収するために、ID は一度パースして正規化される。
+    pub fn trace_id(trace_id: &str) -> Result<Self> {
+        let id: TraceId = track!(trace_id
+            .parse()
+            .map_err(|e| ErrorKind::InvalidInput.takes_over(e)))?;
+        Ok(CaptureTarget::TraceId(id.to_string()))
+    }
+
+    fn matches(&self, trace_id: &str, span: &CapturedSpan) -> bool {
+        match *self {
+            CaptureTarget::ObjectId(ref id) => span.tags.get("object.id") == Some(id),
+            CaptureTarget::TraceId(ref id) => id == trace_id,
+        }
+    }
+}
+
+/// 記録されたスパン。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedSpan {
+    /// スパンの ID (16進数表記)。
+    pub span_id: String,
+
+    /// 親スパンの ID (16進数表記)。
+    pub parent_span_id: Option<String>,
+
+    /// 操作名(`get_object`, `collect_fragment`等)。
+    pub operation: String,
+
+    /// 開始時刻(UNIXエポックからのマイクロ秒)。
+    pub start_micros: u64,
+
+    /// 所要時間(マイクロ秒)。
+    pub duration_micros: u64,
+
+    /// スパンのタグ。
+    pub tags: BTreeMap<String, String>,
+
+    /// スパンのログ(開始時刻からの経過マイクロ秒と、そのフィールド群)。
+    pub logs: Vec<(u64, BTreeMap<String, String>)>,
+}
+impl CapturedSpan {
+    fn from_finished(span: &FinishedSpan) -> Self {
+        let start = span.start_time();
+        let tags = span
+            .tags()
+            .iter()
+            .map(|t| {
+                let value = match *t.value() {
+                    TagValue::String(ref v) => v.to_string(),
+                    TagValue::Boolean(v) => v.to_string(),
+                    TagValue::Integer(v) => v.to_string(),
+                    TagValue::Float(v) => v.to_string(),
+                };
+                (t.name().to_owned(), value)
+            })
+            .collect();
+        let logs = span
+            .logs()
+            .iter()
+            .map(|log| {
+                let fields = log
+                    .fields()
+                    .iter()
+                    .map(|f| (f.name().to_owned(), f.value().to_owned()))
+                    .collect();
+                (micros_between(start, log.time()), fields)
+            })
+            .collect();
+        CapturedSpan {
+            span_id: format!("{:x}", span.context().state().span_id()),
+            parent_span_id: span
+                .references()
+                .first()
+                .map(|r| format!("{:x}", r.span().span_id())),
+            operation: span.operation_name().to_owned(),
+            start_micros: micros_between(UNIX_EPOCH, start),
+            duration_micros: micros_between(start, span.finish_time()),
+            tags,
+            logs,
+        }
+    }
+}
+
+/// 記録された一つのリクエスト(トレース)。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedTrace {
+    /// トレース ID。
+    pub trace_id: String,
+
+    /// トレースに属するスパン群(終了した順)。
+    pub spans: Vec<CapturedSpan>,
+
+    /// `MAX_SPANS_PER_TRACE`を超えたために破棄されたスパンの数。
+    pub dropped_spans: usize,
+}
+
+/// バグ報告に添付するための記録結果。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureBundle {
+    /// 記録の ID。
+    pub capture_id: String,
+
+    /// 記録対象。
+    pub target: CaptureTarget,
+
+    /// 記録を開始した時刻(UNIXエポックからの秒数)。
+    pub started_at: u64,
+
+    /// 記録中は全てのリクエストをトレースするかどうか。
+    ///
+    /// `false`の場合は、サンプリングレートに従ってトレースされたリクエストのみが記録される。
+    pub sample_all: bool,
+
+    /// まだ記録中かどうか。
+    ///
+    /// 記録期間が過ぎるか、記録できるトレース数の上限に達すると`false`になる。
+    pub active: bool,
+
+    /// 記録されたリクエスト群(記録された順)。
+    pub traces: Vec<CapturedTrace>,
+}
+
+#[derive(Debug)]
+struct Capture {
+    bundle: CaptureBundle,
+    expires_at: Instant,
+
+    // 実際のピアのアドレスから別名への対応
+    aliases: HashMap<String, String>,
+}
+impl Capture {
+    fn is_active(&self, now: Instant) -> bool {
+        now < self.expires_at && self.bundle.traces.len() < MAX_TRACES_PER_CAPTURE
+    }
+
+    fn is_sampling_all(&self, now: Instant) -> bool {
+        self.bundle.sample_all && self.is_active(now)
+    }
+
+    fn trace_mut(&mut self, trace_id: &str) -> Option<&mut CapturedTrace> {
+        self.bundle
+            .traces
+            .iter_mut()
+            .find(|t| t.trace_id == trace_id)
+    }
+
+    fn add(&mut self, trace_id: &str, span: &CapturedSpan) {
+        let span = self.redact(span);
+        if self.trace_mut(trace_id).is_none() {
+            self.bundle.traces.push(CapturedTrace {
+                trace_id: trace_id.to_owned(),
+                spans: Vec::new(),
+                dropped_spans: 0,
+            });
+        }
+        let trace = self.trace_mut(trace_id).expect("Never fails");
+        if trace.spans.len() < MAX_SPANS_PER_TRACE {
+            trace.spans.push(span);
+        } else {
+            trace.dropped_spans += 1;
+        }
+    }
+
+    fn redact(&mut self, span: &CapturedSpan) -> CapturedSpan {
+        let mut span = span.clone();
+        span.tags.remove("peer.port");
+        for key in &["peer.ipv4", "peer.ipv6"] {
+            if let Some(addr) = span.tags.get_mut(*key) {
+                let next = self.aliases.len();
+                let alias = self
+                    .aliases
+                    .entry(addr.clone())
+                    .or_insert_with(|| format!("peer-{}", next))
+                    .clone();
+                *addr = alias;
+            }
+        }
+
+        // エラーメッセージ等に埋め込まれたアドレスも置き換える
+        for (_, fields) in &mut span.logs {
+            for value in fields.values_mut() {
+                for (addr, alias) in &self.aliases {
+                    if value.contains(addr.as_str()) {
+                        *value = value.replace(addr.as_str(), alias);
+                    }
+                }
+            }
+        }
+        span
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    captures: Vec<Capture>,
+    pending: HashMap<String, Vec<CapturedSpan>>,
+    pending_order: VecDeque<String>,
+    seqno: u64,
+}
+impl Inner {
+    fn hold(&mut self, trace_id: &str, span: CapturedSpan) {
+        if !self.pending.contains_key(trace_id) {
+            if self.pending_order.len() >= MAX_PENDING_TRACES {
+                if let Some(oldest) = self.pending_order.pop_front() {
+                    self.pending.remove(&oldest);
+                }
+            }
+            self.pending_order.push_back(trace_id.to_owned());
+        }
+        let spans = self.pending.entry(trace_id.to_owned()).or_default();
+        if spans.len() < MAX_SPANS_PER_TRACE {
+            spans.push(span);
+        }
+    }
+}
+
+/// 実行中の記録を管理するためのレジストリ。
+///
+/// 状態はこのサーバのメモリ上にのみ保持される。
+#[derive(Debug, Clone, Default)]
+pub struct RequestCaptures {
+    inner: Arc<Mutex<Inner>>,
+
+    // 登録されている記録の数(記録がない場合に、ロックを取らずに済ませるために使う)
+    registered: Arc<AtomicUsize>,
+}
+impl RequestCaptures {
+    /// 新しい`RequestCaptures`インスタンスを生成する。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `target`に対するリクエストの記録を開始し、その時点の状態を返す。
+    ///
+    /// 記録は`duration`が経過するまで続けられる。
+    /// `sample_all`が`true`の場合には、記録中は全てのリクエストがトレースされる。
+    /// 既に`MAX_CAPTURES`個の記録が保持されている場合にはエラーとなる。
+    pub fn start(
+        &self,
+        target: CaptureTarget,
+        duration: Duration,
+        sample_all: bool,
+    ) -> Result<CaptureBundle> {
+        let expires_at = track_assert_some!(
+            Instant::now().checked_add(duration),
+            ErrorKind::InvalidInput,
+            "Too long duration: {:?}",
+            duration
+        );
+        let mut inner = self.lock();
+        track_assert!(
+            inner.captures.len() < MAX_CAPTURES,
+            ErrorKind::InvalidInput,
+            "Too many captures; delete finished ones first: max={}",
+            MAX_CAPTURES
+        );
+        inner.seqno += 1;
+        let started_at = SystemTime::now();
+        let capture = Capture {
+            bundle: CaptureBundle {
+                capture_id: format!(
+                    "{:x}-{:x}",
+                    micros_between(UNIX_EPOCH, started_at),
+                    inner.seqno
+                ),
+                target,
+                started_at: micros_between(UNIX_EPOCH, started_at) / 1_000_000,
+                sample_all,
+                active: true,
+                traces: Vec::new(),
+            },
+            expires_at,
+            aliases: HashMap::new(),
+        };
+        let bundle = capture.bundle.clone();
+        inner.captures.push(capture);
+        self.registered
+            .store(inner.captures.len(), Ordering::SeqCst);
+        Ok(bundle)
+    }
+
+    /// 記録の現在の状態を返す。
+    pub fn get(&self, capture_id: &str) -> Result<CaptureBundle> {
+        let now = Instant::now();
+        let inner = self.lock();
+        let capture = track!(find_capture(&inner, capture_id))?;
+        let mut bundle = capture.bundle.clone();
+        bundle.active = capture.is_active(now);
+        Ok(bundle)
+    }
+
+    /// 記録を終了・破棄し、その最終的な状態を返す。
+    pub fn remove(&self, capture_id: &str) -> Result<CaptureBundle> {
+        let mut inner = self.lock();
+        track!(find_capture(&inner, capture_id))?;
+        let i = inner
+            .captures
+            .iter()
+            .position(|c| c.bundle.capture_id == capture_id)
+            .expect("Never fails");
+        let mut bundle = inner.captures.swap_remove(i).bundle;
+        bundle.active = false;
+        self.registered
+            .store(inner.captures.len(), Ordering::SeqCst);
+        if inner.captures.is_empty() {
+            inner.pending.clear();
+            inner.pending_order.clear();
+        }
+        Ok(bundle)
+    }
+
+    /// 記録中のものがあるかどうかを返す。
+    pub fn is_active(&self) -> bool {
+        if self.registered.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+        let now = Instant::now();
+        self.lock().captures.iter().any(|c| c.is_active(now))
+    }
+
+    /// `sample_all`を指定した記録中のものがあるかどうかを返す。
+    pub fn is_sampling_all(&self) -> bool {
+        if self.registered.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+        let now = Instant::now();
+        self.lock().captures.iter().any(|c| c.is_sampling_all(now))
+    }
+
+    /// 終了したスパンを受け取り、記録対象のリクエストのものであれば記録する。
+    pub fn observe(&self, span: &FinishedSpan) {
+        if !self.is_active() {
+            return;
+        }
+        let trace_id = span.context().state().trace_id().to_string();
+        let span = CapturedSpan::from_finished(span);
+        let now = Instant::now();
+
+        let mut inner = self.lock();
+        let Inner {
+            ref mut captures,
+            ref pending,
+            ..
+        } = *inner;
+        for capture in captures.iter_mut() {
+            if capture.trace_mut(&trace_id).is_some() {
+                // 記録期間の終了後に完了した、記録済みのリクエストの残りのスパン
+                capture.add(&trace_id, &span);
+            } else if capture.is_active(now) && capture.bundle.target.matches(&trace_id, &span) {
+                for held in pending.get(&trace_id).into_iter().flatten() {
+                    capture.add(&trace_id, held);
+                }
+                capture.add(&trace_id, &span);
+            }
+        }
+        inner.hold(&trace_id, span);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// `sample_all`を指定した記録中のものがある間は、全てのリクエストをサンプリングする`Sampler`。
+#[derive(Debug, Clone)]
+pub struct CaptureSampler(pub RequestCaptures);
+impl Sampler<SpanContextState> for CaptureSampler {
+    fn is_sampled(&self, _span: &CandidateSpan) -> bool {
+        self.0.is_sampling_all()
+    }
+}
+
+fn find_capture<'a>(inner: &'a Inner, capture_id: &str) -> Result<&'a Capture> {
+    let capture = inner
+        .captures
+        .iter()
+        .find(|c| c.bundle.capture_id == capture_id);
+    Ok(track_assert_some!(
+        capture,
+        ErrorKind::NotFound,
+        "No such capture: {:?}",
+        capture_id
+    ))
+}
+
+fn micros_between(from: SystemTime, to: SystemTime) -> u64 {
+    to.duration_since(from).unwrap_or_default().as_micros() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use rustracing::sampler::AllSampler;
+    use rustracing::tag::{StdTag, Tag};
+    use rustracing_jaeger::Tracer;
+    use std::net::{IpAddr, Ipv4Addr};
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    #[test]
+    fn capture_works() -> TestResult {
+        let captures = RequestCaptures::new();
+        let (tracer, span_rx) = Tracer::new(AllSampler);
+        assert!(!captures.is_active());
+
+        let capture = track!(captures.start(
+            CaptureTarget::ObjectId("foo".to_owned()),
+            DEFAULT_CAPTURE_DURATION,
+            false
+        ))?;
+        assert!(captures.is_active());
+        assert!(!captures.is_sampling_all());
+
+        let peer = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+        for object_id in &["foo", "bar"] {
+            let root = tracer.span("get_object").start();
+            {
+                let mut child = root.child("collect_fragment", |span| {
+                    span.tag(StdTag::peer_ip(peer))
+                        .tag(StdTag::peer_port(80))
+                        .start()
+                });
+                child.log(|log| {
+                    log.error().message("Cannot connect to 192.168.0.1:80");
+                });
+            }
+            let mut root = root;
+            root.set_tag(|| Tag::new("object.id", object_id.to_string()));
+        }
+        while let Ok(span) = span_rx.try_recv() {
+            captures.observe(&span);
+        }
+
+        let bundle = track!(captures.get(&capture.capture_id))?;
+        assert!(bundle.active);
+        assert_eq!(bundle.traces.len(), 1);
+
+        let spans = &bundle.traces[0].spans;
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].operation, "collect_fragment");
+        assert_eq!(spans[0].parent_span_id.as_ref(), Some(&spans[1].span_id));
+        assert_eq!(
+            spans[0].tags.get("peer.ipv4").map(|s| s.as_str()),
+            Some("peer-0")
+        );
+        assert!(!spans[0].tags.contains_key("peer.port"));
+        assert_eq!(
+            spans[0].logs[0].1.get("message").map(|s| s.as_str()),
+            Some("Cannot connect to peer-0:80")
+        );
+        assert_eq!(
+            spans[1].tags.get("object.id").map(|s| s.as_str()),
+            Some("foo")
+        );
+
+        let bundle = track!(captures.remove(&capture.capture_id))?;
+        assert!(!bundle.active);
+        assert!(!captures.is_active());
+        assert!(captures.get(&capture.capture_id).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sample_all_is_opt_in() -> TestResult {
+        let captures = RequestCaptures::new();
+        let target = CaptureTarget::ObjectId("foo".to_owned());
+        let capture = track!(captures.start(target.clone(), DEFAULT_CAPTURE_DURATION, true))?;
+        assert!(capture.sample_all);
+        assert!(captures.is_sampling_all());
+
+        track!(captures.remove(&capture.capture_id))?;
+        assert!(!captures.is_sampling_all());
+        Ok(())
+    }
+
+    #[test]
+    fn too_long_duration_is_rejected() {
+        let captures = RequestCaptures::new();
+        let target = CaptureTarget::ObjectId("foo".to_owned());
+        let duration = Duration::from_secs(u64::max_value());
+        assert!(captures.start(target, duration, false).is_err());
+        assert!(!captures.is_active());
+    }
+
+    #[test]
+    fn trace_id_target_is_normalized() -> TestResult {
+        let target = track!(CaptureTarget::trace_id("00000000000000abc"))?;
+        assert_eq!(target, CaptureTarget::TraceId("abc".to_owned()));
+        assert!(CaptureTarget::trace_id("xyz").is_err());
+        Ok(())
+    }
+}
@@ -2,10 +2,19 @@
 #![allow(clippy::needless_pass_by_value)]
 use atomic_immut::AtomicImmut;
 use cannyls::deadline::Deadline;
-use frugalos_segment::ObjectValue;
+use frugalos_mds::{Precondition, QuotaUsage, RevisionSelector};
+use frugalos_raft::NodeId;
+use frugalos_segment::config::{ClusterMember, RequestPriority};
+use frugalos_segment::Client as Segment;
+use frugalos_segment::{
+    AvailabilitySummary, FeatureFlags, ObjectAuditReport, ObjectRange, ObjectRepairSummary,
+    ObjectValue, PutDurability, Watch,
+};
+use frugalos_segment::{CacheClass, ContentCacheSizing, ContentCacheStats, MaintenanceSchedule};
+use frugalos_segment::{MemberHealth, MemberStatus};
 use futures::{self, Future};
 use libfrugalos::consistency::ReadConsistency;
-use libfrugalos::entity::bucket::BucketId;
+use libfrugalos::entity::bucket::{BucketId, BucketKind};
 use libfrugalos::entity::object::{
     DeleteObjectsByPrefixSummary, ObjectId, ObjectPrefix, ObjectSummary, ObjectVersion,
 };
@@ -15,25 +24,161 @@ use std::collections::HashMap;
 use std::fmt;
 use std::ops::Range;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use trackable::error::ErrorKindExt;
 
 use bucket::Bucket;
-use {Error, ErrorKind};
+use existence::{ExistenceFilters, ExistenceProbe};
+use operation::OperationHandle;
+use workload::{OperationKind, WorkloadRecorder};
+use {Error, ErrorKind, Result};
 
 type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
 
+/// frugalos のバケツに対する操作を発行するクライアント.
 #[derive(Clone)]
 pub struct FrugalosClient {
     buckets: Arc<AtomicImmut<HashMap<BucketId, Bucket>>>,
+    cache_sizing: ContentCacheSizing,
+    maintenance: MaintenanceSchedule,
+    recorder: Option<WorkloadRecorder>,
+    bucket_defaults: BucketDefaults,
+    existence: ExistenceFilters,
 }
 impl FrugalosClient {
-    pub(crate) fn new(buckets: Arc<AtomicImmut<HashMap<BucketId, Bucket>>>) -> Self {
-        FrugalosClient { buckets }
+    pub(crate) fn new(
+        buckets: Arc<AtomicImmut<HashMap<BucketId, Bucket>>>,
+        cache_sizing: ContentCacheSizing,
+        maintenance: MaintenanceSchedule,
+    ) -> Self {
+        FrugalosClient {
+            buckets,
+            cache_sizing,
+            maintenance,
+            recorder: None,
+            bucket_defaults: BucketDefaults::default(),
+            existence: ExistenceFilters::default(),
+        }
+    }
+    /// GET/HEAD/PUT/DELETE の各リクエストを`recorder`に記録するようにする。
+    pub(crate) fn with_recorder(mut self, recorder: Option<WorkloadRecorder>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+    /// GET/HEAD の際に、`filters`で存在しないことが確実なオブジェクトを MDS に問い合わせないようにする。
+    pub(crate) fn with_existence_filters(mut self, filters: ExistenceFilters) -> Self {
+        self.existence = filters;
+        self
+    }
+    pub(crate) fn existence_filters(&self) -> &ExistenceFilters {
+        &self.existence
     }
+    /// このクライアントから取得したハンドルで使用される、リクエストのデフォルト値を設定する.
+    pub fn with_bucket_defaults(mut self, defaults: BucketDefaults) -> Self {
+        self.bucket_defaults = defaults;
+        self
+    }
+    /// `bucket_id`のバケツを操作するためのハンドルを返す.
+    ///
+    /// ハンドルのデフォルト値には、バケツの属性として設定されたデフォルト値が優先して使われる.
+    ///
+    /// バケツが存在しない場合には`ErrorKind::NotFound`のエラーが返される.
+    pub fn bucket(&self, bucket_id: &str) -> Result<BucketHandle> {
+        let buckets = self.buckets.load();
+        let (kind, defaults) = if let Some(bucket) = buckets.get(bucket_id) {
+            let defaults = bucket.request_defaults().apply(&self.bucket_defaults);
+            (bucket.kind().clone(), defaults)
+        } else {
+            track_panic!(ErrorKind::NotFound, "No such bucket: {:?}", bucket_id);
+        };
+        Ok(BucketHandle {
+            client: self.clone(),
+            buckets,
+            bucket_id: bucket_id.to_owned(),
+            kind,
+            defaults,
+        })
+    }
+    /// `bucket_id`のバケツに対するリクエストを作成する.
+    ///
+    /// バケツが存在しない場合のエラーは、リクエストを発行した時点で返される.
     pub fn request(&self, bucket_id: BucketId) -> Request {
-        Request::new(self, bucket_id)
+        match self.bucket(&bucket_id) {
+            Ok(bucket) => bucket.request(),
+            Err(_) => Request::new(Err(bucket_id), self.bucket_defaults.clone()),
+        }
+    }
+    /// 存在するバケツの ID の一覧を返す.
+    pub fn bucket_ids(&self) -> Vec<BucketId> {
+        self.buckets.load().keys().cloned().collect()
+    }
+    /// バケツの各セグメントについて、MDS のリーダとして認識しているノードを返す.
+    pub fn segment_leaders(&self, bucket_id: &BucketId) -> Option<Vec<Option<NodeId>>> {
+        self.buckets
+            .load()
+            .get(bucket_id)
+            .map(|b| b.segments().iter().map(|s| s.mds_leader()).collect())
+    }
+    /// バケツの各セグメントのキャッシュの統計情報を返す.
+    pub fn content_cache_stats(&self, bucket_id: &BucketId) -> Option<Vec<ContentCacheStats>> {
+        self.buckets.load().get(bucket_id).map(|b| {
+            b.segments()
+                .iter()
+                .map(|s| s.content_cache_stats())
+                .collect()
+        })
+    }
+    /// バケツの各セグメントについて、このサーバが記録しているストレージの各メンバの健全性を返す.
+    ///
+    /// バケツが存在しない場合には`None`となる.
+    pub fn member_health(&self, bucket_id: &BucketId) -> Option<Vec<Vec<MemberHealth>>> {
+        self.buckets
+            .load()
+            .get(bucket_id)
+            .map(|b| b.segments().iter().map(|s| s.member_health()).collect())
+    }
+    /// バケツの全てのセグメントのキャッシュの内容を破棄する.
+    ///
+    /// 結果は破棄したオブジェクトの数で、バケツが存在しない場合には`None`となる.
+    pub fn flush_content_cache(&self, bucket_id: &BucketId) -> Option<u64> {
+        self.buckets
+            .load()
+            .get(bucket_id)
+            .map(|b| b.segments().iter().map(|s| s.flush_content_cache()).sum())
+    }
+    /// `class`の種類のバケツで使用する、セグメント当たりのキャッシュの容量を変更する.
+    pub fn set_content_cache_capacity(&self, class: CacheClass, capacity: u64) {
+        self.cache_sizing.set_capacity(class, capacity);
+    }
+    /// `class`の種類のバケツで使用する、セグメント当たりのキャッシュの容量を返す.
+    pub fn content_cache_capacity(&self, class: CacheClass) -> u64 {
+        self.cache_sizing.capacity(class)
     }
+    /// 現在、リペアや segment_gc 等の負荷の高いバックグラウンド処理を実行できる状態かどうかを返す.
+    pub fn is_maintenance_window_open(&self) -> bool {
+        self.maintenance.is_open()
+    }
+    /// メンテナンスウィンドウによるバックグラウンド処理の制限が解除されているかどうかを返す.
+    pub fn is_maintenance_window_overridden(&self) -> bool {
+        self.maintenance.is_overridden()
+    }
+    /// メンテナンスウィンドウによるバックグラウンド処理の制限を解除ないし再開する.
+    ///
+    /// 緊急にリペアを進めたい場合には、`true`を指定してウィンドウ外でも処理を実行させる.
+    pub fn set_maintenance_window_override(&self, overridden: bool) {
+        self.maintenance.set_override(overridden);
+    }
+    /// バケツの機能フラグを返す.
+    ///
+    /// 返り値に対する変更は、このサーバが受け付けるそのバケツへのリクエストに即座に反映される.
+    pub fn feature_flags(&self, bucket_id: &str) -> Result<FeatureFlags> {
+        if let Some(bucket) = self.buckets.load().get(bucket_id) {
+            Ok(bucket.features().clone())
+        } else {
+            track_panic!(ErrorKind::NotFound, "No such bucket: {:?}", bucket_id);
+        }
+    }
+    /// バケツのセグメント数を返す.
     pub fn segment_count(&self, bucket_id: &BucketId) -> Option<u16> {
         self.buckets
             .load()
@@ -47,105 +192,466 @@ impl fmt::Debug for FrugalosClient {
     }
 }
 
+/// バケツ毎のリクエストのデフォルト値.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketDefaults {
+    /// リクエストのデッドライン.
+    pub deadline: Deadline,
+
+    /// 読み込み時の整合性.
+    pub consistency: ReadConsistency,
+}
+impl Default for BucketDefaults {
+    fn default() -> Self {
+        BucketDefaults {
+            deadline: Deadline::Within(Duration::from_millis(5000)),
+            consistency: ReadConsistency::default(),
+        }
+    }
+}
+
+/// 特定のバケツを操作するためのハンドル.
+///
+/// 取得時点のバケツの構成を保持しているため、リクエスト毎にバケツを探す必要がなく、
+/// バケツの種類に合わない操作(e.g., メタデータ用バケツに対するストレージの検査)は発行前に拒否される.
+#[derive(Clone)]
+pub struct BucketHandle {
+    client: FrugalosClient,
+    buckets: Arc<HashMap<BucketId, Bucket>>,
+    bucket_id: BucketId,
+    kind: BucketKind,
+    defaults: BucketDefaults,
+}
+impl BucketHandle {
+    /// バケツの ID を返す.
+    pub fn id(&self) -> &BucketId {
+        &self.bucket_id
+    }
+    /// バケツの種類を返す.
+    pub fn kind(&self) -> &BucketKind {
+        &self.kind
+    }
+    /// このハンドルから発行するリクエストのデフォルト値を返す.
+    pub fn defaults(&self) -> &BucketDefaults {
+        &self.defaults
+    }
+    /// このハンドルから発行するリクエストのデフォルト値を変更する.
+    pub fn with_defaults(mut self, defaults: BucketDefaults) -> Self {
+        self.defaults = defaults;
+        self
+    }
+    /// バケツのセグメント数を返す.
+    pub fn segment_count(&self) -> u16 {
+        self.bucket().segments().len() as u16
+    }
+    /// セグメントを構成するメンバの一覧を返す.
+    pub fn segment_members(&self, segment: u16) -> Result<Vec<ClusterMember>> {
+        let segments = self.bucket().segments();
+        track_assert!(
+            (segment as usize) < segments.len(),
+            ErrorKind::InvalidInput,
+            "Too large segment number: {}",
+            segment
+        );
+        Ok(segments[segment as usize].members().to_vec())
+    }
+    /// オブジェクトが割り当てられるセグメントの番号を返す.
+    pub fn segment_of(&self, object_id: &ObjectId) -> u16 {
+        self.bucket().segment_index(object_id) as u16
+    }
+    /// 指定のバージョンのオブジェクトのデータを保持するメンバの一覧を返す.
+    ///
+    /// 並び順等は`frugalos_segment::Client::data_members`と同様.
+    pub fn data_members(&self, segment: u16, version: ObjectVersion) -> Result<Vec<ClusterMember>> {
+        let segments = self.bucket().segments();
+        track_assert!(
+            (segment as usize) < segments.len(),
+            ErrorKind::InvalidInput,
+            "Too large segment number: {}",
+            segment
+        );
+        Ok(segments[segment as usize].data_members(version))
+    }
+    /// デフォルト値が設定されたリクエストを作成する.
+    pub fn request(&self) -> Request {
+        Request::new(Ok(self.clone()), self.defaults.clone())
+    }
+    /// デフォルトの整合性でオブジェクトを取得する.
+    pub fn get(&self, object_id: ObjectId) -> BoxFuture<Option<ObjectValue>> {
+        self.request()
+            .get(object_id, self.defaults.consistency.clone())
+    }
+    /// デフォルトの整合性でオブジェクトのバージョンを取得する.
+    pub fn head(&self, object_id: ObjectId) -> BoxFuture<Option<ObjectVersion>> {
+        self.request()
+            .head(object_id, self.defaults.consistency.clone())
+    }
+    /// オブジェクトを保存する.
+    ///
+    /// 結果は`Request::put`と同様.
+    pub fn put(
+        &self,
+        object_id: ObjectId,
+        content: Vec<u8>,
+    ) -> BoxFuture<(ObjectVersion, bool, PutDurability)> {
+        self.request().put(object_id, content)
+    }
+    /// オブジェクトを削除する.
+    pub fn delete(&self, object_id: ObjectId) -> BoxFuture<Option<ObjectVersion>> {
+        self.request().delete(object_id)
+    }
+    /// セグメント内のオブジェクトの変更を監視するストリームを返す.
+    ///
+    /// `after`の意味は`frugalos_segment::Client::watch`と同様.
+    pub fn watch(&self, segment: usize, after: Option<ObjectVersion>) -> Result<Watch> {
+        let segments = self.bucket().segments();
+        track_assert!(
+            segment < segments.len(),
+            ErrorKind::InvalidInput,
+            "Too large segment number: {}",
+            segment
+        );
+        Ok(segments[segment].watch(after))
+    }
+    fn bucket(&self) -> &Bucket {
+        self.buckets
+            .get(&self.bucket_id)
+            .expect("Checked when the handle was created")
+    }
+    fn probe_existence(
+        &self,
+        object_id: &ObjectId,
+        consistency: &ReadConsistency,
+    ) -> ExistenceProbe {
+        let segment = self.bucket().segment_index(object_id);
+        self.client
+            .existence
+            .probe(&self.bucket_id, segment, object_id, consistency)
+    }
+    /// 保存しようとしているオブジェクトを存在フィルタに登録する.
+    ///
+    /// 保存の完了後に登録すると、完了直後の GET が「存在しない」と判定されてしまう可能性があるので、発行前に登録する.
+    fn insert_existence(&self, object_id: &ObjectId) {
+        let segment = self.bucket().segment_index(object_id);
+        self.client
+            .existence
+            .insert(&self.bucket_id, segment, object_id);
+    }
+    /// 実データをストレージに保持する種類のバケツかどうかを確認する.
+    fn check_storage(&self, operation: &str) -> Result<()> {
+        if let BucketKind::Metadata = self.kind {
+            track_panic!(
+                ErrorKind::InvalidInput,
+                "{} is not supported for the metadata bucket {:?}",
+                operation,
+                self.bucket_id
+            );
+        }
+        Ok(())
+    }
+}
+impl fmt::Debug for BucketHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BucketHandle")
+            .field("bucket_id", &self.bucket_id)
+            .field("kind", &self.kind)
+            .field("defaults", &self.defaults)
+            .finish()
+    }
+}
+
 macro_rules! try_get_bucket {
-    ($buckets:expr, $bucket_id:expr) => {
-        if let Some(bucket) = $buckets.get(&$bucket_id) {
-            bucket
-        } else {
-            let e = ErrorKind::NotFound
-                .cause(format!("No such bucket: {:?}", $bucket_id))
-                .into();
-            return Box::new(futures::failed(e));
+    ($request:expr) => {
+        match $request.bucket {
+            Ok(ref handle) => handle,
+            Err(ref bucket_id) => {
+                let e = ErrorKind::NotFound
+                    .cause(format!("No such bucket: {:?}", bucket_id))
+                    .into();
+                return Box::new(futures::failed(e));
+            }
         }
     };
+    ($request:expr, storage = $operation:expr) => {{
+        let handle = try_get_bucket!($request);
+        if let Err(e) = handle.check_storage($operation) {
+            return Box::new(futures::failed(track!(e)));
+        }
+        handle
+    }};
 }
 
-pub struct Request<'a> {
-    client: &'a FrugalosClient,
-    bucket_id: BucketId,
+pub struct Request {
+    bucket: ::std::result::Result<BucketHandle, BucketId>,
+    defaults: BucketDefaults,
     deadline: Deadline,
-    expect: Expect,
+    priority: RequestPriority,
+    expect: Precondition,
     parent: SpanHandle,
+    operation: Option<OperationHandle>,
 }
-impl<'a> Request<'a> {
-    pub fn new(client: &'a FrugalosClient, bucket_id: BucketId) -> Self {
+impl Request {
+    fn new(
+        bucket: ::std::result::Result<BucketHandle, BucketId>,
+        defaults: BucketDefaults,
+    ) -> Self {
         Request {
-            client,
-            bucket_id,
-            deadline: Deadline::Within(Duration::from_millis(5000)),
-            expect: Expect::Any,
+            bucket,
+            deadline: defaults.deadline,
+            defaults,
+            priority: RequestPriority::Normal,
+            expect: Precondition::default(),
             parent: Span::inactive().handle(),
+            operation: None,
         }
     }
-    pub fn deadline(&mut self, deadline: Deadline) -> &mut Self {
-        self.deadline = deadline;
+    /// リクエストのデッドラインを指定する.
+    ///
+    /// `None`が渡された場合は、バケツのデフォルト値が使われる.
+    pub fn deadline<D: Into<Option<Deadline>>>(&mut self, deadline: D) -> &mut Self {
+        self.deadline = deadline.into().unwrap_or(self.defaults.deadline);
+        self
+    }
+    #[cfg(all(test, feature = "grpc"))]
+    pub(crate) fn current_deadline(&self) -> Deadline {
+        self.deadline
+    }
+    /// リクエストの優先度クラスを指定する.
+    ///
+    /// 実際にデバイスに渡されるデッドラインは、優先度クラスに応じて調整される.
+    pub fn priority(&mut self, priority: RequestPriority) -> &mut Self {
+        self.priority = priority;
         self
     }
     pub fn expect(&mut self, expect: Expect) -> &mut Self {
-        self.expect = expect;
+        self.expect = expect.into();
+        self
+    }
+    /// `Expect`では表現できない前提条件を指定する.
+    ///
+    /// `expect`と同様に`put`と`delete`(`Expect`で表現できるものは`append`にも)に適用され、
+    /// 後から指定した方が優先される.
+    pub fn precondition(&mut self, precondition: Precondition) -> &mut Self {
+        self.expect = precondition;
         self
     }
     pub fn span(&mut self, span: &Span) -> &mut Self {
         self.parent = span.handle();
         self
     }
-    pub fn get(
+    /// 長時間操作として実行されるリクエストの場合に、進捗の報告先を指定する.
+    ///
+    /// 現在は`delete_by_prefix`のみが、完了したセグメントの数を進捗として報告する.
+    pub fn operation(&mut self, operation: &OperationHandle) -> &mut Self {
+        self.operation = Some(operation.clone());
+        self
+    }
+    pub fn get<C: Into<Option<ReadConsistency>>>(
         &self,
         object_id: ObjectId,
-        consistency: ReadConsistency,
+        consistency: C,
     ) -> BoxFuture<Option<ObjectValue>> {
-        let buckets = self.client.buckets.load();
-        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let consistency = self.consistency(consistency);
+        let handle = try_get_bucket!(self);
+        let probe = handle.probe_existence(&object_id, &consistency);
+        if probe.is_absent() {
+            let future = futures::finished(None);
+            return self.recorded(OperationKind::Get, object_id, future, |_| 0);
+        }
+        let bucket = handle.bucket();
         let segment = bucket.get_segment(&object_id);
-        let future = segment.get(object_id, self.deadline, consistency, self.parent.clone());
-        Box::new(future.map_err(|e| track!(Error::from(e))))
+        let future = segment.get(
+            object_id.clone(),
+            self.segment_deadline(segment),
+            consistency,
+            self.parent.clone(),
+        );
+        let future = future
+            .map_err(|e| track!(Error::from(e)))
+            .inspect(move |o| probe.observe(o.is_some()));
+        self.recorded(OperationKind::Get, object_id, future, |o| {
+            o.as_ref().map_or(0, |o| o.content.len() as u64)
+        })
     }
-    pub fn head(
+    pub fn get_revision(
         &self,
         object_id: ObjectId,
-        consistency: ReadConsistency,
+        selector: RevisionSelector,
+    ) -> BoxFuture<Option<ObjectValue>> {
+        let expect = match self.expect {
+            Precondition::Expect(ref expect) => expect.clone(),
+            _ => {
+                let e = ErrorKind::InvalidInput
+                    .cause("Only `Expect` preconditions are supported for revision reads");
+                return Box::new(futures::failed(track!(Error::from(e))));
+            }
+        };
+        let bucket = try_get_bucket!(self).bucket();
+        let segment = bucket.get_segment(&object_id);
+        let future = segment.get_revision(
+            object_id.clone(),
+            selector,
+            expect,
+            self.segment_deadline(segment),
+            self.parent.clone(),
+        );
+        let future = future.map_err(|e| track!(Error::from(e)));
+        self.recorded(OperationKind::Get, object_id, future, |o| {
+            o.as_ref().map_or(0, |o| o.content.len() as u64)
+        })
+    }
+    pub fn get_range<C: Into<Option<ReadConsistency>>>(
+        &self,
+        object_id: ObjectId,
+        range: Range<u64>,
+        consistency: C,
+    ) -> BoxFuture<Option<ObjectRange>> {
+        let consistency = self.consistency(consistency);
+        let handle = try_get_bucket!(self);
+        let probe = handle.probe_existence(&object_id, &consistency);
+        if probe.is_absent() {
+            return Box::new(futures::finished(None));
+        }
+        let bucket = handle.bucket();
+        let segment = bucket.get_segment(&object_id);
+        let future = segment.get_range(
+            object_id,
+            range,
+            self.segment_deadline(segment),
+            consistency,
+            self.parent.clone(),
+        );
+        let future = future
+            .map_err(|e| track!(Error::from(e)))
+            .inspect(move |o| probe.observe(o.is_some()));
+        Box::new(future)
+    }
+    pub fn head<C: Into<Option<ReadConsistency>>>(
+        &self,
+        object_id: ObjectId,
+        consistency: C,
     ) -> BoxFuture<Option<ObjectVersion>> {
-        let buckets = self.client.buckets.load();
-        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let consistency = self.consistency(consistency);
+        let handle = try_get_bucket!(self);
+        let probe = handle.probe_existence(&object_id, &consistency);
+        if probe.is_absent() {
+            let future = futures::finished(None);
+            return self.recorded(OperationKind::Head, object_id, future, |_| 0);
+        }
+        let bucket = handle.bucket();
         let segment = bucket.get_segment(&object_id);
-        let future = segment.head(object_id, consistency, self.parent.clone());
-        Box::new(future.map_err(|e| track!(Error::from(e))))
+        let future = segment.head(object_id.clone(), consistency, self.parent.clone());
+        let future = future
+            .map_err(|e| track!(Error::from(e)))
+            .inspect(move |o| probe.observe(o.is_some()));
+        self.recorded(OperationKind::Head, object_id, future, |_| 0)
     }
-    pub fn head_storage(
+    /// ストレージ上に実データが存在するかどうかも確認した上で、オブジェクトのバージョンを返す.
+    ///
+    /// メタデータ用バケツの場合は、実データも MDS に保持されているため`head`と等価.
+    pub fn head_storage<C: Into<Option<ReadConsistency>>>(
         &self,
         object_id: ObjectId,
-        consistency: ReadConsistency,
+        consistency: C,
     ) -> BoxFuture<Option<ObjectVersion>> {
-        let buckets = self.client.buckets.load();
-        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let consistency = self.consistency(consistency);
+        let handle = try_get_bucket!(self);
+        if let BucketKind::Metadata = *handle.kind() {
+            return self.head(object_id, consistency);
+        }
+        let bucket = handle.bucket();
+        let segment = bucket.get_segment(&object_id);
+        let future = segment.head_storage(
+            object_id,
+            self.segment_deadline(segment),
+            consistency,
+            self.parent.clone(),
+        );
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
+    pub fn verify(&self, object_id: ObjectId) -> BoxFuture<Option<ObjectAuditReport>> {
+        let bucket = try_get_bucket!(self, storage = "Verification").bucket();
+        let segment = bucket.get_segment(&object_id);
+        let future = segment.verify(
+            object_id,
+            self.segment_deadline(segment),
+            self.parent.clone(),
+        );
+        Box::new(future.map_err(|e| track!(Error::from(e))))
+    }
+    pub fn repair(&self, object_id: ObjectId) -> BoxFuture<Option<ObjectRepairSummary>> {
+        let bucket = try_get_bucket!(self, storage = "Repair").bucket();
         let segment = bucket.get_segment(&object_id);
-        let future =
-            segment.head_storage(object_id, self.deadline, consistency, self.parent.clone());
+        let future = segment.repair_object(object_id, self.parent.clone());
         Box::new(future.map_err(|e| track!(Error::from(e))))
     }
-    pub fn put(&self, object_id: ObjectId, content: Vec<u8>) -> BoxFuture<(ObjectVersion, bool)> {
-        let buckets = self.client.buckets.load();
-        let bucket = try_get_bucket!(buckets, self.bucket_id);
+    /// オブジェクトを保存する.
+    ///
+    /// 結果は、保存したオブジェクトのバージョン、新規に作成されたかどうか、および断片の書き込み状況.
+    pub fn put(
+        &self,
+        object_id: ObjectId,
+        content: Vec<u8>,
+    ) -> BoxFuture<(ObjectVersion, bool, PutDurability)> {
+        let handle = try_get_bucket!(self);
+        handle.insert_existence(&object_id);
+        let bucket = handle.bucket();
         let segment = bucket.get_segment(&object_id);
+        let size = content.len() as u64;
         let future = segment.put(
+            object_id.clone(),
+            content.into(),
+            self.segment_deadline(segment),
+            self.expect.clone(),
+            self.parent.clone(),
+        );
+        let future = future.map_err(|e| track!(Error::from(e)));
+        self.recorded(OperationKind::Put, object_id, future, move |_| size)
+    }
+    /// 既存のオブジェクトの末尾に`content`を追記する(存在しない場合は新規に作成される).
+    ///
+    /// `expect`の指定は無視される.
+    /// 暗号化が有効なバケツでは、追記は拒否される.
+    pub fn append(&self, object_id: ObjectId, content: Vec<u8>) -> BoxFuture<ObjectVersion> {
+        let handle = try_get_bucket!(self);
+        let bucket = handle.bucket();
+        let segment = bucket.get_segment(&object_id);
+        let expect = match self.expect {
+            Precondition::Expect(ref expect) => expect.clone(),
+            _ => {
+                let e = ErrorKind::InvalidInput
+                    .cause("Only `Expect` preconditions are supported for append");
+                return Box::new(futures::failed(track!(Error::from(e))));
+            }
+        };
+        handle.insert_existence(&object_id);
+        let future = segment.append(
             object_id,
             content,
-            self.deadline,
-            self.expect.clone(),
+            self.segment_deadline(segment),
+            expect,
             self.parent.clone(),
         );
         Box::new(future.map_err(|e| track!(Error::from(e))))
     }
     pub fn delete(&self, object_id: ObjectId) -> BoxFuture<Option<ObjectVersion>> {
-        let buckets = self.client.buckets.load();
-        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let bucket = try_get_bucket!(self).bucket();
         let segment = bucket.get_segment(&object_id);
         let future = segment.delete(
-            object_id,
-            self.deadline,
+            object_id.clone(),
+            self.segment_deadline(segment),
             self.expect.clone(),
             self.parent.clone(),
         );
+        let future = future.map_err(|e| track!(Error::from(e)));
+        self.recorded(OperationKind::Delete, object_id, future, |_| 0)
+    }
+    pub fn undelete(&self, object_id: ObjectId) -> BoxFuture<Option<ObjectVersion>> {
+        let handle = try_get_bucket!(self);
+        handle.insert_existence(&object_id);
+        let bucket = handle.bucket();
+        let segment = bucket.get_segment(&object_id);
+        let future = segment.undelete(object_id, self.parent.clone());
         Box::new(future.map_err(|e| track!(Error::from(e))))
     }
     pub fn delete_by_version(
@@ -153,12 +659,14 @@ impl<'a> Request<'a> {
         segment: usize,
         object_version: ObjectVersion,
     ) -> BoxFuture<Option<ObjectVersion>> {
-        let buckets = self.client.buckets.load();
-        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let bucket = try_get_bucket!(self).bucket();
         if segment < bucket.segments().len() {
             let segment = &bucket.segments()[segment];
-            let future =
-                segment.delete_by_version(object_version, self.deadline, self.parent.clone());
+            let future = segment.delete_by_version(
+                object_version,
+                self.segment_deadline(segment),
+                self.parent.clone(),
+            );
             Box::new(future.map_err(|e| track!(Error::from(e))))
         } else {
             let e = ErrorKind::InvalidInput.cause(format!("Too large segment number: {}", segment));
@@ -170,11 +678,14 @@ impl<'a> Request<'a> {
         segment: usize,
         targets: Range<ObjectVersion>,
     ) -> BoxFuture<Vec<ObjectSummary>> {
-        let buckets = self.client.buckets.load();
-        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let bucket = try_get_bucket!(self).bucket();
         if segment < bucket.segments().len() {
             let segment = &bucket.segments()[segment];
-            let future = segment.delete_by_range(targets, self.deadline, self.parent.clone());
+            let future = segment.delete_by_range(
+                targets,
+                self.segment_deadline(segment),
+                self.parent.clone(),
+            );
             Box::new(future.map_err(|e| track!(Error::from(e))))
         } else {
             let e = ErrorKind::InvalidInput.cause(format!("Too large segment number: {}", segment));
@@ -186,16 +697,28 @@ impl<'a> Request<'a> {
         &self,
         prefix: ObjectPrefix,
     ) -> BoxFuture<DeleteObjectsByPrefixSummary> {
-        let buckets = self.client.buckets.load();
-        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let bucket = try_get_bucket!(self).bucket();
         let mut futures = Vec::new();
+        if let Some(ref operation) = self.operation {
+            operation.set_total(bucket.segments().len() as u64);
+        }
 
         // どこかのセグメントで削除が失敗した場合に不整合が発生するがひとまず対応はしない。
         for segment in bucket.segments() {
+            let operation = self.operation.clone();
             futures.push(
                 segment
-                    .delete_by_prefix(prefix.clone(), self.deadline, self.parent.clone())
-                    .map_err(|e| track!(Error::from(e))),
+                    .delete_by_prefix(
+                        prefix.clone(),
+                        self.segment_deadline(segment),
+                        self.parent.clone(),
+                    )
+                    .map_err(|e| track!(Error::from(e)))
+                    .inspect(move |_| {
+                        if let Some(operation) = operation {
+                            operation.add_done(1);
+                        }
+                    }),
             );
         }
 
@@ -205,8 +728,7 @@ impl<'a> Request<'a> {
         }))
     }
     pub fn list(&self, segment: usize) -> BoxFuture<Vec<ObjectSummary>> {
-        let buckets = self.client.buckets.load();
-        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let bucket = try_get_bucket!(self).bucket();
         if segment < bucket.segments().len() {
             let future = bucket.segments()[segment].list();
             Box::new(future.map_err(|e| track!(Error::from(e))))
@@ -215,9 +737,64 @@ impl<'a> Request<'a> {
             Box::new(futures::failed(e.into()))
         }
     }
+    /// 指定された一貫性で、セグメント内のオブジェクトの一覧を返す.
+    ///
+    /// `list`とは異なり、`ReadConsistency::Stale`の場合にはフォロワーから取得することがある.
+    pub fn list_with_consistency(
+        &self,
+        segment: usize,
+        consistency: ReadConsistency,
+    ) -> BoxFuture<Vec<ObjectSummary>> {
+        let bucket = try_get_bucket!(self).bucket();
+        if segment < bucket.segments().len() {
+            let future = bucket.segments()[segment].list_with_consistency(consistency);
+            Box::new(future.map_err(|e| track!(Error::from(e))))
+        } else {
+            let e = ErrorKind::InvalidInput.cause(format!("Too large segment number: {}", segment));
+            Box::new(futures::failed(e.into()))
+        }
+    }
+    /// セグメント内で、最新のバージョンが`range`(UNIXエポックからの秒数)の期間内に保存された
+    /// オブジェクトの一覧を返す.
+    pub fn list_by_time_range(
+        &self,
+        segment: usize,
+        range: Range<u64>,
+    ) -> BoxFuture<Vec<ObjectSummary>> {
+        let bucket = try_get_bucket!(self).bucket();
+        if segment < bucket.segments().len() {
+            let future = bucket.segments()[segment].list_by_time_range(range.start, range.end);
+            Box::new(future.map_err(|e| track!(Error::from(e))))
+        } else {
+            let e = ErrorKind::InvalidInput.cause(format!("Too large segment number: {}", segment));
+            Box::new(futures::failed(e.into()))
+        }
+    }
+    /// セグメント内の全オブジェクトについて断片の有無を調べ、その可用性を集計する.
+    ///
+    /// 各オブジェクトの監査には、リクエストに指定された期限が適用される.
+    pub fn survey_availability(
+        &self,
+        segment: usize,
+        concurrency: usize,
+        max_lost_objects: usize,
+    ) -> BoxFuture<AvailabilitySummary> {
+        let bucket = try_get_bucket!(self, storage = "Availability survey").bucket();
+        if segment < bucket.segments().len() {
+            let segment = &bucket.segments()[segment];
+            let future = segment.survey_availability(
+                self.segment_deadline(segment),
+                concurrency,
+                max_lost_objects,
+            );
+            Box::new(future.map_err(|e| track!(Error::from(e))))
+        } else {
+            let e = ErrorKind::InvalidInput.cause(format!("Too large segment number: {}", segment));
+            Box::new(futures::failed(e.into()))
+        }
+    }
     pub fn latest(&self, segment: usize) -> BoxFuture<Option<ObjectSummary>> {
-        let buckets = self.client.buckets.load();
-        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let bucket = try_get_bucket!(self).bucket();
         if segment < bucket.segments().len() {
             let future = bucket.segments()[segment].latest();
             Box::new(future.map_err(|e| track!(Error::from(e))))
@@ -227,8 +804,7 @@ impl<'a> Request<'a> {
         }
     }
     pub fn object_count(&self, segment: usize) -> BoxFuture<u64> {
-        let buckets = self.client.buckets.load();
-        let bucket = try_get_bucket!(buckets, self.bucket_id);
+        let bucket = try_get_bucket!(self).bucket();
         if segment < bucket.segments().len() {
             let future = bucket.segments()[segment].object_count();
             Box::new(future.map_err(|e| track!(Error::from(e))))
@@ -237,4 +813,205 @@ impl<'a> Request<'a> {
             Box::new(futures::failed(e.into()))
         }
     }
+    pub fn member_statuses(&self, segment: usize) -> BoxFuture<Vec<MemberStatus>> {
+        let bucket = try_get_bucket!(self).bucket();
+        if segment < bucket.segments().len() {
+            let future = bucket.segments()[segment].member_statuses();
+            Box::new(future.map_err(|e| track!(Error::from(e))))
+        } else {
+            let e = ErrorKind::InvalidInput.cause(format!("Too large segment number: {}", segment));
+            Box::new(futures::failed(e.into()))
+        }
+    }
+    pub fn quota_usage(&self, segment: usize) -> BoxFuture<QuotaUsage> {
+        let bucket = try_get_bucket!(self).bucket();
+        if segment < bucket.segments().len() {
+            let future = bucket.segments()[segment].quota_usage();
+            Box::new(future.map_err(|e| track!(Error::from(e))))
+        } else {
+            let e = ErrorKind::InvalidInput.cause(format!("Too large segment number: {}", segment));
+            Box::new(futures::failed(e.into()))
+        }
+    }
+    fn consistency<C: Into<Option<ReadConsistency>>>(&self, consistency: C) -> ReadConsistency {
+        consistency
+            .into()
+            .unwrap_or_else(|| self.defaults.consistency.clone())
+    }
+    fn segment_deadline(&self, segment: &Segment) -> Deadline {
+        segment.deadline(self.priority, self.deadline)
+    }
+    fn recorded<T, F, G>(
+        &self,
+        kind: OperationKind,
+        object_id: ObjectId,
+        future: F,
+        size: G,
+    ) -> BoxFuture<T>
+    where
+        T: Send + 'static,
+        F: Future<Item = T, Error = Error> + Send + 'static,
+        G: FnOnce(&T) -> u64 + Send + 'static,
+    {
+        let recorder = match self
+            .bucket
+            .as_ref()
+            .ok()
+            .and_then(|b| b.client.recorder.clone())
+        {
+            Some(recorder) => recorder,
+            None => return Box::new(future),
+        };
+        let started_at = Instant::now();
+        Box::new(future.then(move |result| {
+            let size = result.as_ref().map(size).unwrap_or(0);
+            recorder.record(kind, &object_id, size, started_at, result.is_ok());
+            result
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libfrugalos::entity::bucket::{Bucket as BucketConfig, MetadataBucket, ReplicatedBucket};
+    use trackable::result::TestResult;
+
+    use bucket::RequestDefaults;
+
+    /// メタデータ用バケツ`meta`と、複製バケツ`repl`を持つクライアントを生成する。
+    fn client(meta_defaults: RequestDefaults) -> Result<FrugalosClient> {
+        let meta = BucketConfig::Metadata(MetadataBucket {
+            id: "meta".to_owned(),
+            seqno: 0,
+            device: "root".to_owned(),
+            segment_count: 1,
+            tolerable_faults: 2,
+        });
+        let repl = BucketConfig::Replicated(ReplicatedBucket {
+            id: "repl".to_owned(),
+            seqno: 0,
+            device: "root".to_owned(),
+            segment_count: 1,
+            tolerable_faults: 2,
+        });
+        let mut buckets = HashMap::new();
+        buckets.insert(
+            "meta".to_owned(),
+            track!(Bucket::for_test(&meta, meta_defaults))?,
+        );
+        buckets.insert(
+            "repl".to_owned(),
+            track!(Bucket::for_test(&repl, RequestDefaults::default()))?,
+        );
+        Ok(FrugalosClient::new(
+            Arc::new(AtomicImmut::new(buckets)),
+            ContentCacheSizing::default(),
+            MaintenanceSchedule::default(),
+        ))
+    }
+
+    fn within(ms: u64) -> Deadline {
+        Deadline::Within(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn metadata_bucket_rejects_storage_operations() -> TestResult {
+        let client = track!(client(RequestDefaults::default()))?;
+        let meta = track!(client.bucket("meta"))?;
+        match *meta.kind() {
+            BucketKind::Metadata => {}
+            _ => panic!(),
+        }
+
+        let e = meta
+            .request()
+            .verify("foo".to_owned())
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+        let e = meta
+            .request()
+            .repair("foo".to_owned())
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+        let e = meta
+            .request()
+            .survey_availability(0, 1, 10)
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+
+        let repl = track!(client.bucket("repl"))?;
+        assert!(repl.check_storage("Verification").is_ok());
+
+        // 存在しないバケツは、ハンドルの取得時に拒否される
+        let e = client.bucket("unknown").err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_bucket_head_storage_falls_back_to_head() -> TestResult {
+        let client = track!(client(RequestDefaults::default()))?;
+        // 空のフィルタが登録されているので、`head`であれば MDS に問い合わせずに`None`が返る
+        client.existence_filters().register_empty("meta", 1);
+
+        let meta = track!(client.bucket("meta"))?;
+        let version = track!(meta
+            .request()
+            .head_storage("foo".to_owned(), ReadConsistency::Stale)
+            .wait())?;
+        assert_eq!(version, None);
+        Ok(())
+    }
+
+    #[test]
+    fn bucket_handle_applies_defaults() -> TestResult {
+        let meta_defaults = RequestDefaults::default();
+        meta_defaults.set(Some(60_000), Some(ReadConsistency::Stale));
+        let client_defaults = BucketDefaults {
+            deadline: within(1000),
+            consistency: ReadConsistency::Consistent,
+        };
+        let client = track!(client(meta_defaults))?.with_bucket_defaults(client_defaults.clone());
+
+        // バケツの属性として設定されたデフォルト値が優先される
+        let meta = track!(client.bucket("meta"))?;
+        assert_eq!(meta.defaults().deadline, within(60_000));
+        assert_eq!(meta.defaults().consistency, ReadConsistency::Stale);
+
+        // 設定されていない場合には、クライアントのデフォルト値が使われる
+        let repl = track!(client.bucket("repl"))?;
+        assert_eq!(*repl.defaults(), client_defaults);
+
+        let mut request = meta.request();
+        assert_eq!(request.deadline, within(60_000));
+        assert_eq!(request.consistency(None), ReadConsistency::Stale);
+        assert_eq!(
+            request.consistency(ReadConsistency::Quorum),
+            ReadConsistency::Quorum
+        );
+        request.deadline(within(10));
+        assert_eq!(request.deadline, within(10));
+        request.deadline(None);
+        assert_eq!(request.deadline, within(60_000));
+
+        // デフォルトの整合性(Stale)が使われるので、存在フィルタにより MDS への問い合わせが省略される
+        client.existence_filters().register_empty("meta", 1);
+        assert_eq!(track!(meta.head("foo".to_owned()).wait())?, None);
+
+        let meta = meta.with_defaults(client_defaults.clone());
+        assert_eq!(*meta.defaults(), client_defaults);
+        assert_eq!(meta.request().deadline, within(1000));
+
+        // 存在しないバケツへのリクエストには、クライアントのデフォルト値が使われる
+        let request = client.request("unknown".to_owned());
+        assert_eq!(request.defaults, client_defaults);
+        Ok(())
+    }
 }
@@ -0,0 +1,198 @@
+//! サーバ間の時計のズレを監視するためのモジュール。
+//!
+//! frugalos は各サーバの時計が同期していることを前提にしていないが、
+//! 大きなズレは運用上の問題(ログの突き合わせが困難になる等)の原因になるので、
+//! 定期的に他のサーバの時刻を問い合わせて、ズレを警告およびメトリクスとして報告する。
+use fibers::time::timer::{self, Timeout};
+use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
+use fibers_rpc::Call;
+use frugalos_core::clock::{self, ClockSkew};
+use frugalos_raft;
+use futures::{Async, Future, Poll};
+use libfrugalos::entity::server::{Server, ServerId};
+use prometrics::metrics::{Counter, Gauge, MetricBuilder};
+use slog::Logger;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use schema::GetServerTimeRpc;
+use {Error, Result};
+
+type ProbeFuture = Box<dyn Future<Item = (ServerId, ClockSkew), Error = (ServerId, Error)> + Send>;
+
+/// 他のサーバとの時計のズレを定期的に計測する `Future`。
+///
+/// この `Future` が終了することはない。
+pub struct ClockSkewMonitor {
+    logger: Logger,
+    rpc_service: RpcServiceHandle,
+    local_server: ServerId,
+    peers: HashMap<ServerId, SocketAddr>,
+    skews: HashMap<ServerId, ClockSkew>,
+    interval: Duration,
+    warning_threshold: Duration,
+    timeout: Timeout,
+    probes: Vec<ProbeFuture>,
+    metrics: ClockSkewMetrics,
+}
+impl ClockSkewMonitor {
+    /// 新しい `ClockSkewMonitor` を生成する。
+    pub fn new(
+        logger: Logger,
+        rpc_service: RpcServiceHandle,
+        local_server: ServerId,
+        interval: Duration,
+        warning_threshold: Duration,
+    ) -> Result<Self> {
+        let metrics = track!(ClockSkewMetrics::new())?;
+        Ok(ClockSkewMonitor {
+            logger,
+            rpc_service,
+            local_server,
+            peers: HashMap::new(),
+            skews: HashMap::new(),
+            interval,
+            warning_threshold,
+            timeout: timer::timeout(interval),
+            probes: Vec::new(),
+            metrics,
+        })
+    }
+
+    /// 監視対象のサーバを追加(あるいは更新)する。
+    ///
+    /// 自サーバは監視対象にならない。
+    pub fn put_peer(&mut self, server: &Server) {
+        if server.id == self.local_server {
+            return;
+        }
+        self.peers.insert(server.id.clone(), server.addr());
+    }
+
+    /// 監視対象からサーバを取り除く。
+    pub fn delete_peer(&mut self, server: &ServerId) {
+        self.peers.remove(server);
+        self.skews.remove(server);
+        self.update_max_skew();
+    }
+
+    fn start_probes(&mut self) {
+        if !self.probes.is_empty() {
+            // 前回の問い合わせが終わっていないので、今回はスキップする
+            return;
+        }
+        for (id, &addr) in &self.peers {
+            let id = id.clone();
+            let addr = frugalos_raft::current_addr(addr);
+            let sent_at = SystemTime::now();
+            let future = GetServerTimeRpc::client(&self.rpc_service)
+                .call(addr, ())
+                .then(move |result| match result {
+                    Ok(remote) => {
+                        let skew = ClockSkew::estimate(
+                            sent_at,
+                            clock::from_unix_millis(remote),
+                            SystemTime::now(),
+                        );
+                        Ok((id, skew))
+                    }
+                    Err(e) => Err((id, track!(Error::from(e)))),
+                });
+            self.probes.push(Box::new(future));
+        }
+    }
+
+    fn handle_skew(&mut self, server: ServerId, skew: ClockSkew) {
+        if !self.peers.contains_key(&server) {
+            // 問い合わせ中に監視対象から外れた
+            return;
+        }
+        if skew.exceeds(self.warning_threshold) {
+            self.metrics.warnings.increment();
+            warn!(
+                self.logger,
+                "Clock skew exceeds the threshold: server={}, skew_millis={}, threshold={:?}",
+                server,
+                skew.as_millis(),
+                self.warning_threshold
+            );
+        }
+        self.skews.insert(server, skew);
+        self.update_max_skew();
+    }
+
+    fn update_max_skew(&mut self) {
+        let max = self
+            .skews
+            .values()
+            .map(ClockSkew::magnitude)
+            .max()
+            .unwrap_or_default();
+        self.metrics
+            .max_skew_seconds
+            .set(prometrics::timestamp::duration_to_seconds(max));
+    }
+}
+impl Future for ClockSkewMonitor {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while track!(self.timeout.poll().map_err(Error::from))?.is_ready() {
+            self.timeout = timer::timeout(self.interval);
+            self.start_probes();
+        }
+
+        let mut i = 0;
+        while i < self.probes.len() {
+            match self.probes[i].poll() {
+                Ok(Async::NotReady) => {
+                    i += 1;
+                    continue;
+                }
+                Ok(Async::Ready((server, skew))) => self.handle_skew(server, skew),
+                Err((server, e)) => {
+                    self.metrics.probe_failures.increment();
+                    debug!(
+                        self.logger,
+                        "Cannot get the time of the server: server={}, error={}", server, e
+                    );
+                }
+            }
+            let _ = self.probes.swap_remove(i);
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+struct ClockSkewMetrics {
+    max_skew_seconds: Gauge,
+    warnings: Counter,
+    probe_failures: Counter,
+}
+impl ClockSkewMetrics {
+    fn new() -> Result<Self> {
+        let mut builder = MetricBuilder::new();
+        builder.namespace("frugalos").subsystem("clock");
+        let max_skew_seconds = track!(builder
+            .gauge("max_skew_seconds")
+            .help("Maximum clock skew against the other servers")
+            .default_registry()
+            .finish())?;
+        let warnings = track!(builder
+            .counter("skew_warnings_total")
+            .help("Number of clock skews exceeding the warning threshold")
+            .default_registry()
+            .finish())?;
+        let probe_failures = track!(builder
+            .counter("probe_failures_total")
+            .help("Number of failures of getting the time of the other servers")
+            .default_registry()
+            .finish())?;
+        Ok(ClockSkewMetrics {
+            max_skew_seconds,
+            warnings,
+            probe_failures,
+        })
+    }
+}
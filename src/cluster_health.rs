@@ -0,0 +1,263 @@
+//! ローカルのノードが所属するセグメントの健全性を、まとめて調べるためのモジュール。
+//!
+//! RPC を受け付けたサーバ上にメンバを持つ全てのセグメントについて、各メンバの状態を問い合わせ、
+//! 以下のいずれかに該当するセグメントを劣化(degraded)しているものとして報告する:
+//!
+//! - `missing_members`: 状態を取得できないメンバがある
+//! - `no_leader`: Raft のリーダであると応答したメンバがない
+//! - `repair_backlog`: リペアの積み残しが閾値を超えているメンバがある
+//!
+//! 各サーバに対して実行した結果を合わせることで、クラスタ全体の状況を把握できる。
+use frugalos_segment::MemberStatus;
+use futures::{stream, Future, Stream};
+use libfrugalos::entity::bucket::BucketId;
+use std::net::SocketAddr;
+
+use client::FrugalosClient;
+use Error;
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+
+/// 並行して状態を問い合わせるセグメント数。
+const CONCURRENCY: usize = 32;
+
+/// リペアの積み残しの閾値のデフォルト値。
+pub const DEFAULT_REPAIR_BACKLOG_THRESHOLD: usize = 1000;
+
+/// セグメントが劣化していると判定された理由。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DegradationReason {
+    /// 状態を取得できないメンバがある。
+    MissingMembers,
+
+    /// Raft のリーダであると応答したメンバがない。
+    NoLeader,
+
+    /// リペアの積み残しが閾値を超えているメンバがある。
+    RepairBacklog,
+}
+
+/// 劣化しているセグメント。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DegradedSegment {
+    /// バケツの ID。
+    pub bucket_id: BucketId,
+
+    /// セグメント番号。
+    pub segment: u16,
+
+    /// 劣化していると判定された理由の一覧。
+    pub reasons: Vec<DegradationReason>,
+
+    /// 状態を取得できなかったメンバのノード ID の一覧。
+    pub missing_members: Vec<String>,
+
+    /// メンバのリペアの積み残しの最大値。
+    pub repair_backlog: usize,
+
+    /// 各メンバの状態。
+    pub members: Vec<MemberStatus>,
+}
+
+/// 健全性の調査結果。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClusterHealthReport {
+    /// 劣化しているセグメントが一つもない場合に`true`となる。
+    pub healthy: bool,
+
+    /// 調査したセグメントの数。
+    pub checked_segments: usize,
+
+    /// 判定に用いたリペアの積み残しの閾値。
+    pub repair_backlog_threshold: usize,
+
+    /// 劣化しているセグメントの一覧(バケツ ID とセグメント番号の順)。
+    pub degraded_segments: Vec<DegradedSegment>,
+}
+
+/// `local_addr`のサーバ上にメンバを持つ全てのセグメントの健全性を調べる。
+///
+/// 一部のメンバが応答しなくても調査全体は失敗せず、そのメンバは`missing_members`として報告される。
+pub fn check_local_segments(
+    client: &FrugalosClient,
+    local_addr: SocketAddr,
+    repair_backlog_threshold: usize,
+) -> BoxFuture<ClusterHealthReport> {
+    let targets = local_segments(client, local_addr);
+    let checked_segments = targets.len();
+    let client = client.clone();
+    let future = stream::iter_ok::<_, Error>(targets)
+        .map(move |(bucket_id, segment)| {
+            client
+                .request(bucket_id.clone())
+                .member_statuses(segment as usize)
+                .map(move |members| {
+                    check_segment(bucket_id, segment, members, repair_backlog_threshold)
+                })
+        })
+        .buffer_unordered(CONCURRENCY)
+        .filter_map(|degraded| degraded)
+        .collect()
+        .map(move |mut degraded_segments: Vec<DegradedSegment>| {
+            degraded_segments
+                .sort_by(|a, b| (&a.bucket_id, a.segment).cmp(&(&b.bucket_id, b.segment)));
+            ClusterHealthReport {
+                healthy: degraded_segments.is_empty(),
+                checked_segments,
+                repair_backlog_threshold,
+                degraded_segments,
+            }
+        });
+    Box::new(future)
+}
+
+/// `local_addr`のサーバ上にメンバを持つセグメントの一覧を返す。
+///
+/// サーバの IP アドレスが変わっている場合にも判定できるように、アドレスはアドレス表を通して比較する。
+fn local_segments(client: &FrugalosClient, local_addr: SocketAddr) -> Vec<(BucketId, u16)> {
+    let local_addr = frugalos_raft::current_addr(local_addr);
+    let mut bucket_ids = client.bucket_ids();
+    bucket_ids.sort();
+    let mut targets = Vec::new();
+    for bucket_id in bucket_ids {
+        let bucket = match client.bucket(&bucket_id) {
+            Err(_) => continue, // 調査の開始前に削除された
+            Ok(bucket) => bucket,
+        };
+        for segment in 0..bucket.segment_count() {
+            let is_local = bucket
+                .segment_members(segment)
+                .map(|members| members.iter().any(|m| m.node.current_addr() == local_addr))
+                .unwrap_or(false);
+            if is_local {
+                targets.push((bucket_id.clone(), segment));
+            }
+        }
+    }
+    targets
+}
+
+/// メンバの状態から、セグメントが劣化しているかどうかを判定する。
+///
+/// 劣化していない場合には`None`を返す。
+fn check_segment(
+    bucket_id: BucketId,
+    segment: u16,
+    members: Vec<MemberStatus>,
+    repair_backlog_threshold: usize,
+) -> Option<DegradedSegment> {
+    let missing_members = members
+        .iter()
+        .filter(|m| m.status.is_none())
+        .map(|m| m.node.clone())
+        .collect::<Vec<_>>();
+    let has_leader = members
+        .iter()
+        .filter_map(|m| m.status.as_ref())
+        .any(|s| s.is_leader);
+    let repair_backlog = members
+        .iter()
+        .filter_map(|m| m.status.as_ref())
+        .map(|s| s.queues.repair)
+        .max()
+        .unwrap_or(0);
+
+    let mut reasons = Vec::new();
+    if !missing_members.is_empty() {
+        reasons.push(DegradationReason::MissingMembers);
+    }
+    if !has_leader {
+        reasons.push(DegradationReason::NoLeader);
+    }
+    if repair_backlog > repair_backlog_threshold {
+        reasons.push(DegradationReason::RepairBacklog);
+    }
+    if reasons.is_empty() {
+        return None;
+    }
+    Some(DegradedSegment {
+        bucket_id,
+        segment,
+        reasons,
+        missing_members,
+        repair_backlog,
+        members,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use frugalos_segment::{SegmentNodeStatus, SynchronizerQueues};
+
+    use super::*;
+
+    fn member(node: &str, is_leader: bool, repair: usize) -> MemberStatus {
+        MemberStatus {
+            node: node.to_owned(),
+            device: "dev".to_owned(),
+            status: Some(SegmentNodeStatus {
+                node: node.to_owned(),
+                leader: Some("0".to_owned()),
+                is_leader,
+                queues: SynchronizerQueues {
+                    repair,
+                    ..SynchronizerQueues::default()
+                },
+                segment_gc: None,
+                relayout: None,
+            }),
+            error: None,
+        }
+    }
+
+    fn missing(node: &str) -> MemberStatus {
+        MemberStatus {
+            node: node.to_owned(),
+            device: "dev".to_owned(),
+            status: None,
+            error: Some("unreachable".to_owned()),
+        }
+    }
+
+    fn reasons(members: Vec<MemberStatus>) -> Vec<DegradationReason> {
+        check_segment("foo".to_owned(), 0, members, 10).map_or_else(Vec::new, |d| d.reasons)
+    }
+
+    #[test]
+    fn check_segment_works() {
+        use self::DegradationReason::*;
+
+        let healthy = vec![member("0", true, 0), member("1", false, 10)];
+        assert_eq!(check_segment("foo".to_owned(), 0, healthy, 10), None);
+
+        assert_eq!(
+            reasons(vec![member("0", true, 0), missing("1")]),
+            [MissingMembers]
+        );
+        assert_eq!(
+            reasons(vec![member("0", false, 0), member("1", false, 0)]),
+            [NoLeader]
+        );
+        assert_eq!(
+            reasons(vec![member("0", true, 0), member("1", false, 11)]),
+            [RepairBacklog]
+        );
+        assert_eq!(
+            reasons(vec![missing("0"), member("1", false, 11)]),
+            [MissingMembers, NoLeader, RepairBacklog]
+        );
+
+        let degraded = check_segment(
+            "foo".to_owned(),
+            3,
+            vec![missing("0"), member("1", true, 5)],
+            10,
+        )
+        .unwrap();
+        assert_eq!(degraded.segment, 3);
+        assert_eq!(degraded.missing_members, ["0"]);
+        assert_eq!(degraded.repair_backlog, 5);
+        assert_eq!(degraded.members.len(), 2);
+    }
+}
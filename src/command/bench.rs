@@ -0,0 +1,161 @@
+//! Definitions for frugalos bench
+use clap::{App, Arg, ArgMatches, SubCommand};
+use sloggers::Build;
+use sloggers::LoggerBuilder;
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+
+use command::rpc_addr;
+use command::{warn_config_warnings, FrugalosSubcommand};
+use frugalos_core::serde_ext::evolution::ConfigWarning;
+use workload::{self, ReplayOptions};
+use {Error, ErrorKind, Result};
+
+/// frugalos bench
+pub struct BenchCommand;
+
+static WORKLOAD: &str = "WORKLOAD";
+static BUCKET: &str = "BUCKET";
+static SPEED: &str = "SPEED";
+static CONCURRENCY: &str = "CONCURRENCY";
+static DEADLINE: &str = "DEADLINE";
+
+impl FrugalosSubcommand for BenchCommand {
+    fn get_subcommand<'a, 'b: 'a>(&self) -> App<'a, 'b> {
+        SubCommand::with_name("bench")
+            .about("Replays a recorded workload against a (test) cluster")
+            .arg(rpc_addr::get_arg())
+            .arg(
+                Arg::with_name(WORKLOAD)
+                    .help("Sets the workload file recorded by `workload_recorder`")
+                    .long("workload")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name(BUCKET)
+                    .help("Sets the bucket to which the requests are issued")
+                    .long("bucket")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name(SPEED)
+                    .help("Sets the replay speed multiplier")
+                    .long("speed")
+                    .takes_value(true)
+                    .default_value("1.0"),
+            )
+            .arg(
+                Arg::with_name(CONCURRENCY)
+                    .help("Sets the maximum number of in-flight requests")
+                    .long("concurrency")
+                    .takes_value(true)
+                    .default_value("64"),
+            )
+            .arg(
+                Arg::with_name(DEADLINE)
+                    .help("Sets the deadline of each request in milliseconds")
+                    .long("deadline")
+                    .takes_value(true)
+                    .default_value("5000"),
+            )
+    }
+
+    fn check_matches<'a>(&self, matches: &'a ArgMatches<'a>) -> Option<&'a ArgMatches<'a>> {
+        matches.subcommand_matches("bench")
+    }
+
+    fn handle_matches(
+        &self,
+        logger_builder: LoggerBuilder,
+        matches: &ArgMatches,
+        config_warnings: &[ConfigWarning],
+    ) {
+        let mut logger = track_try_unwrap!(logger_builder.build());
+        warn_config_warnings(&mut logger, config_warnings);
+        let rpc_addr = rpc_addr::from_matches(matches);
+        let workload = matches.value_of(WORKLOAD).expect("Never fails");
+        let bucket_id = matches.value_of(BUCKET).expect("Never fails").to_owned();
+        let options = track_try_unwrap!(Self::get_options_from_matches(matches));
+        let summary = track_try_unwrap!(workload::replay(
+            &logger, workload, rpc_addr, bucket_id, &options
+        ));
+
+        println!("elapsed: {:?}", summary.elapsed);
+        for (kind, stats) in &summary.stats {
+            let count = ::std::cmp::max(1, stats.count) as u32;
+            println!(
+                "{:?}: count={}, errors={}, mean_latency={:?}, recorded_mean_latency={:?}",
+                kind,
+                stats.count,
+                stats.errors,
+                stats.total_latency / count,
+                stats.recorded_latency / count
+            );
+        }
+
+        // NOTE: ログ出力(非同期)用に少し待機
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+impl BenchCommand {
+    fn get_options_from_matches(matches: &ArgMatches) -> Result<ReplayOptions> {
+        let speed: f64 = track!(matches
+            .value_of(SPEED)
+            .unwrap_or("1.0")
+            .parse()
+            .map_err(|_| invalid_arg("speed")))?;
+        track_assert!(speed > 0.0, ErrorKind::InvalidInput, "speed={}", speed);
+        let concurrency: usize = track!(matches
+            .value_of(CONCURRENCY)
+            .unwrap_or("64")
+            .parse()
+            .map_err(|_| invalid_arg("concurrency")))?;
+        track_assert!(concurrency > 0, ErrorKind::InvalidInput);
+        let deadline: u64 = track!(matches
+            .value_of(DEADLINE)
+            .unwrap_or("5000")
+            .parse()
+            .map_err(|_| invalid_arg("deadline")))?;
+        Ok(ReplayOptions {
+            speed,
+            concurrency,
+            deadline: Duration::from_millis(deadline),
+        })
+    }
+}
+
+fn invalid_arg(name: &str) -> Error {
+    ErrorKind::InvalidInput
+        .cause(format!("Invalid `--{}`", name))
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_options_from_matches_works() {
+        let command = BenchCommand;
+        let matches = App::new("frugalos-test")
+            .subcommand(command.get_subcommand())
+            .get_matches_from(vec![
+                "frugalos-test",
+                "bench",
+                "--workload",
+                "workload.dat",
+                "--bucket",
+                "foo",
+                "--speed",
+                "2.5",
+            ]);
+        let matches = command.check_matches(&matches).expect("Never fails");
+        let options = BenchCommand::get_options_from_matches(matches).unwrap();
+        assert_eq!(options.speed, 2.5);
+        assert_eq!(options.concurrency, 64);
+        assert_eq!(options.deadline, Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,246 @@
+//! Definitions for frugalos bucket-archive
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde_yaml;
+use slog::Logger;
+use sloggers::Build;
+use sloggers::LoggerBuilder;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use command::rpc_addr;
+use command::{warn_config_warnings, FrugalosSubcommand};
+use frugalos_core::serde_ext::evolution::ConfigWarning;
+use {Error, OperationState, OperationStatus, Result};
+
+/// frugalos bucket-archive
+pub struct BucketArchiveCommand;
+
+static BUCKET_ID: &str = "BUCKET_ID";
+static PATH: &str = "PATH";
+static OPERATION_ID: &str = "OPERATION_ID";
+static WAIT: &str = "WAIT";
+
+/// How often `--wait` polls the progress of the operation.
+const POLLING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Actions that can be performed by `frugalos bucket-archive`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BucketArchiveAction {
+    /// Starts exporting all objects in a bucket to a file.
+    Export {
+        bucket_id: String,
+        path: PathBuf,
+        wait: bool,
+    },
+    /// Starts importing objects from an exported file into a bucket.
+    Import {
+        bucket_id: String,
+        path: PathBuf,
+        wait: bool,
+    },
+    /// Shows the status of an export or import operation.
+    Status { operation_id: String },
+}
+
+impl FrugalosSubcommand for BucketArchiveCommand {
+    fn get_subcommand<'a, 'b: 'a>(&self) -> App<'a, 'b> {
+        let path_arg = Arg::with_name(PATH)
+            .help(
+                "A path relative to `operation.archive_dir` on the server that handles the request",
+            )
+            .index(2)
+            .required(true);
+        let wait_arg = Arg::with_name(WAIT)
+            .help("Waits until the operation finishes, reporting its progress")
+            .long("wait");
+        SubCommand::with_name("bucket-archive")
+            .about("Exports a bucket to a local file on a server, or imports one into a bucket")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("export")
+                    .about("Writes all objects in a bucket and their metadata to a file")
+                    .arg(rpc_addr::get_arg())
+                    .arg(Arg::with_name(BUCKET_ID).index(1).required(true))
+                    .arg(path_arg.clone())
+                    .arg(wait_arg.clone()),
+            )
+            .subcommand(
+                SubCommand::with_name("import")
+                    .about("Restores the objects in an exported file into an existing bucket")
+                    .arg(rpc_addr::get_arg())
+                    .arg(Arg::with_name(BUCKET_ID).index(1).required(true))
+                    .arg(path_arg)
+                    .arg(wait_arg),
+            )
+            .subcommand(
+                SubCommand::with_name("status")
+                    .about("Shows the progress of an export or import operation")
+                    .arg(rpc_addr::get_arg())
+                    .arg(Arg::with_name(OPERATION_ID).index(1).required(true)),
+            )
+    }
+
+    fn check_matches<'a>(&self, matches: &'a ArgMatches<'a>) -> Option<&'a ArgMatches<'a>> {
+        matches.subcommand_matches("bucket-archive")
+    }
+
+    fn handle_matches(
+        &self,
+        logger_builder: LoggerBuilder,
+        matches: &ArgMatches,
+        config_warnings: &[ConfigWarning],
+    ) {
+        let mut logger = track_try_unwrap!(logger_builder.build());
+        warn_config_warnings(&mut logger, config_warnings);
+        let (_, sub_matches) = matches.subcommand();
+        let sub_matches = sub_matches.expect("Never fails");
+        let rpc_addr = rpc_addr::from_matches(sub_matches);
+        let logger = logger.new(o!("rpc_addr" => rpc_addr.to_string()));
+        match Self::get_action_from_matches(matches) {
+            BucketArchiveAction::Export {
+                bucket_id,
+                path,
+                wait,
+            } => {
+                let operation_id = track_try_unwrap!(crate::daemon::export_bucket(
+                    &logger, rpc_addr, &bucket_id, &path
+                ));
+                println!("Started: operation_id={}", operation_id);
+                if wait {
+                    track_try_unwrap!(wait_operation(&logger, rpc_addr, &operation_id));
+                }
+            }
+            BucketArchiveAction::Import {
+                bucket_id,
+                path,
+                wait,
+            } => {
+                let operation_id = track_try_unwrap!(crate::daemon::import_bucket(
+                    &logger, rpc_addr, &bucket_id, &path
+                ));
+                println!("Started: operation_id={}", operation_id);
+                if wait {
+                    track_try_unwrap!(wait_operation(&logger, rpc_addr, &operation_id));
+                }
+            }
+            BucketArchiveAction::Status { operation_id } => {
+                let status = track_try_unwrap!(crate::daemon::get_operation(
+                    &logger,
+                    rpc_addr,
+                    &operation_id
+                ));
+                track_try_unwrap!(print_status(&status));
+            }
+        }
+
+        // NOTE: ログ出力(非同期)用に少し待機
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+impl BucketArchiveCommand {
+    fn get_action_from_matches(matches: &ArgMatches) -> BucketArchiveAction {
+        match matches.subcommand() {
+            ("export", Some(m)) => BucketArchiveAction::Export {
+                bucket_id: m.value_of(BUCKET_ID).expect("Never fails").to_owned(),
+                path: PathBuf::from(m.value_of(PATH).expect("Never fails")),
+                wait: m.is_present(WAIT),
+            },
+            ("import", Some(m)) => BucketArchiveAction::Import {
+                bucket_id: m.value_of(BUCKET_ID).expect("Never fails").to_owned(),
+                path: PathBuf::from(m.value_of(PATH).expect("Never fails")),
+                wait: m.is_present(WAIT),
+            },
+            (_, m) => BucketArchiveAction::Status {
+                operation_id: m
+                    .and_then(|m| m.value_of(OPERATION_ID))
+                    .expect("Never fails")
+                    .to_owned(),
+            },
+        }
+    }
+}
+
+/// Polls the status of the operation until it finishes.
+fn wait_operation(logger: &Logger, rpc_addr: SocketAddr, operation_id: &str) -> Result<()> {
+    loop {
+        let status = track!(crate::daemon::get_operation(logger, rpc_addr, operation_id))?;
+        if status.state != OperationState::Running {
+            return track!(print_status(&status));
+        }
+        match status.progress.total {
+            Some(total) => eprintln!("Progress: {}/{}", status.progress.done, total),
+            None => eprintln!("Progress: {}", status.progress.done),
+        }
+        thread::sleep(POLLING_INTERVAL);
+    }
+}
+
+fn print_status(status: &OperationStatus) -> Result<()> {
+    let output = track!(serde_yaml::to_string(status).map_err(Error::from))?;
+    println!("{}", output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::App;
+    use std::path::PathBuf;
+
+    use super::{BucketArchiveAction, BucketArchiveCommand};
+    use command::FrugalosSubcommand;
+
+    fn parse(args: Vec<&str>) -> BucketArchiveAction {
+        let command = BucketArchiveCommand;
+        let matches = App::new("frugalos-test")
+            .subcommand(command.get_subcommand())
+            .get_matches_from(args);
+        let matches = command
+            .check_matches(&matches)
+            .expect("bucket-archive should match");
+        BucketArchiveCommand::get_action_from_matches(matches)
+    }
+
+    #[test]
+    fn get_action_from_matches_works() {
+        assert_eq!(
+            parse(vec![
+                "frugalos-test",
+                "bucket-archive",
+                "export",
+                "foo",
+                "/tmp/foo.archive",
+                "--wait"
+            ]),
+            BucketArchiveAction::Export {
+                bucket_id: "foo".to_owned(),
+                path: PathBuf::from("/tmp/foo.archive"),
+                wait: true,
+            }
+        );
+        assert_eq!(
+            parse(vec![
+                "frugalos-test",
+                "bucket-archive",
+                "import",
+                "--rpc-addr",
+                "127.0.0.1:14278",
+                "bar",
+                "/tmp/foo.archive"
+            ]),
+            BucketArchiveAction::Import {
+                bucket_id: "bar".to_owned(),
+                path: PathBuf::from("/tmp/foo.archive"),
+                wait: false,
+            }
+        );
+        assert_eq!(
+            parse(vec!["frugalos-test", "bucket-archive", "status", "abc-1"]),
+            BucketArchiveAction::Status {
+                operation_id: "abc-1".to_owned()
+            }
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Definitions for frugalos bucket-relayout
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use frugalos_config::{self, RelayoutParameters};
+use sloggers::Build;
+use sloggers::LoggerBuilder;
+use trackable::error::ErrorKindExt;
+
+use command::rpc_addr;
+use command::{warn_config_warnings, FrugalosSubcommand};
+use frugalos_core::serde_ext::evolution::ConfigWarning;
+use {Error, ErrorKind, Result};
+
+/// frugalos bucket-relayout
+///
+/// The relayout itself is carried out by the MDS leader of each segment in the background.
+///
+/// Only the fault tolerance parameters can be changed; renaming a bucket is not supported.
+pub struct BucketRelayoutCommand;
+
+static BUCKET_ID: &str = "BUCKET_ID";
+static TOLERABLE_FAULTS: &str = "TOLERABLE_FAULTS";
+static DATA_FRAGMENT_COUNT: &str = "DATA_FRAGMENT_COUNT";
+
+impl FrugalosSubcommand for BucketRelayoutCommand {
+    fn get_subcommand<'a, 'b: 'a>(&self) -> App<'a, 'b> {
+        SubCommand::with_name("bucket-relayout")
+            .about(
+                "Changes the fault tolerance parameters of an existing bucket in place, \
+                 keeping its objects, versions and metadata",
+            )
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("start")
+                    .about(
+                        "Starts relayouting a bucket \
+                         (writes to each segment are briefly rejected while it switches)",
+                    )
+                    .arg(rpc_addr::get_arg())
+                    .arg(Arg::with_name(BUCKET_ID).index(1).required(true))
+                    .arg(
+                        Arg::with_name(TOLERABLE_FAULTS)
+                            .help("Sets the new tolerable faults of the bucket")
+                            .long("tolerable-faults")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name(DATA_FRAGMENT_COUNT)
+                            .help(
+                                "Sets the new data fragment count of the bucket \
+                                 (dispersed buckets only)",
+                            )
+                            .long("data-fragment-count")
+                            .takes_value(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("status")
+                    .about("Shows the segments that have not been switched yet")
+                    .arg(rpc_addr::get_arg()),
+            )
+    }
+
+    fn check_matches<'a>(&self, matches: &'a ArgMatches<'a>) -> Option<&'a ArgMatches<'a>> {
+        matches.subcommand_matches("bucket-relayout")
+    }
+
+    fn handle_matches(
+        &self,
+        logger_builder: LoggerBuilder,
+        matches: &ArgMatches,
+        config_warnings: &[ConfigWarning],
+    ) {
+        let mut logger = track_try_unwrap!(logger_builder.build());
+        warn_config_warnings(&mut logger, config_warnings);
+        if let Some(matches) = matches.subcommand_matches("start") {
+            let rpc_addr = rpc_addr::from_matches(matches);
+            let bucket_id = matches.value_of(BUCKET_ID).expect("Never fails");
+            let params = track_try_unwrap!(Self::get_params_from_matches(matches));
+            let relayout = track_try_unwrap!(frugalos_config::cluster::relayout_bucket(
+                &logger,
+                rpc_addr,
+                bucket_id.to_owned(),
+                params
+            ));
+            println!(
+                "Started: bucket={}, segments={}",
+                relayout.bucket_id,
+                relayout.pending_segments.len()
+            );
+        } else if let Some(matches) = matches.subcommand_matches("status") {
+            let rpc_addr = rpc_addr::from_matches(matches);
+            let relayouts = track_try_unwrap!(frugalos_config::cluster::list_bucket_relayouts(
+                &logger, rpc_addr
+            ));
+            if relayouts.is_empty() {
+                println!("No running relayouts");
+            }
+            for relayout in relayouts {
+                println!(
+                    "bucket={}, pending_segments={:?}",
+                    relayout.bucket_id, relayout.pending_segments
+                );
+            }
+        }
+
+        // NOTE: ログ出力(非同期)用に少し待機
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+impl BucketRelayoutCommand {
+    fn get_params_from_matches(matches: &ArgMatches) -> Result<RelayoutParameters> {
+        Ok(RelayoutParameters {
+            tolerable_faults: track!(parse_optional(matches, TOLERABLE_FAULTS))?,
+            data_fragment_count: track!(parse_optional(matches, DATA_FRAGMENT_COUNT))?,
+        })
+    }
+}
+
+fn parse_optional<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>> {
+    matches
+        .value_of(name)
+        .map(|s| {
+            s.parse().map_err(|_| {
+                Error::from(ErrorKind::InvalidInput.cause(format!("Invalid {}: {:?}", name, s)))
+            })
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::App;
+
+    use super::BucketRelayoutCommand;
+    use command::FrugalosSubcommand;
+
+    fn parse<F, T>(args: Vec<&str>, f: F) -> T
+    where
+        F: FnOnce(&clap::ArgMatches) -> T,
+    {
+        let command = BucketRelayoutCommand;
+        let matches = App::new("frugalos-test")
+            .subcommand(command.get_subcommand())
+            .get_matches_from(args);
+        let matches = command
+            .check_matches(&matches)
+            .and_then(|m| m.subcommand_matches("start"))
+            .expect("bucket-relayout start should match");
+        f(matches)
+    }
+
+    #[test]
+    fn get_params_from_matches_works() {
+        let params = parse(
+            vec![
+                "frugalos-test",
+                "bucket-relayout",
+                "start",
+                "foo",
+                "--tolerable-faults",
+                "4",
+            ],
+            |m| BucketRelayoutCommand::get_params_from_matches(m).unwrap(),
+        );
+        assert_eq!(params.tolerable_faults, Some(4));
+        assert_eq!(params.data_fragment_count, None);
+
+        let result = parse(
+            vec![
+                "frugalos-test",
+                "bucket-relayout",
+                "start",
+                "foo",
+                "--data-fragment-count",
+                "many",
+            ],
+            |m| BucketRelayoutCommand::get_params_from_matches(m),
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,132 @@
+//! Definitions for frugalos cluster-health
+use clap::{App, Arg, ArgMatches, SubCommand};
+use serde_yaml;
+use sloggers::Build;
+use sloggers::LoggerBuilder;
+use trackable::error::ErrorKindExt;
+
+use cluster_health::{ClusterHealthReport, DEFAULT_REPAIR_BACKLOG_THRESHOLD};
+use command::rpc_addr;
+use command::{warn_config_warnings, FrugalosSubcommand};
+use frugalos_core::serde_ext::evolution::ConfigWarning;
+use {Error, ErrorKind, Result};
+
+/// frugalos cluster-health
+pub struct ClusterHealthCommand;
+
+static REPAIR_BACKLOG_THRESHOLD: &str = "REPAIR_BACKLOG_THRESHOLD";
+static REPAIR_BACKLOG_THRESHOLD_LONG_ARG: &str = "repair-backlog-threshold";
+
+impl FrugalosSubcommand for ClusterHealthCommand {
+    fn get_subcommand<'a, 'b: 'a>(&self) -> App<'a, 'b> {
+        SubCommand::with_name("cluster-health")
+            .about(
+                "Reports the segments on a server that have missing members, \
+                 no leader or a large repair backlog",
+            )
+            .arg(rpc_addr::get_arg())
+            .arg(
+                Arg::with_name(REPAIR_BACKLOG_THRESHOLD)
+                    .long(REPAIR_BACKLOG_THRESHOLD_LONG_ARG)
+                    .takes_value(true)
+                    .help("Segments whose repair backlog exceeds this value are reported"),
+            )
+    }
+
+    fn check_matches<'a>(&self, matches: &'a ArgMatches<'a>) -> Option<&'a ArgMatches<'a>> {
+        matches.subcommand_matches("cluster-health")
+    }
+
+    fn handle_matches(
+        &self,
+        logger_builder: LoggerBuilder,
+        matches: &ArgMatches,
+        config_warnings: &[ConfigWarning],
+    ) {
+        let mut logger = track_try_unwrap!(logger_builder.build());
+        warn_config_warnings(&mut logger, config_warnings);
+        let rpc_addr = rpc_addr::from_matches(&matches);
+        let threshold = track_try_unwrap!(Self::get_threshold_from_matches(matches));
+        let logger = logger.new(o!("rpc_addr" => rpc_addr.to_string()));
+        let report = track_try_unwrap!(crate::daemon::get_cluster_health(
+            &logger, rpc_addr, threshold
+        ));
+        track_try_unwrap!(print_report(&report));
+
+        // NOTE: ログ出力(非同期)用に少し待機
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // 劣化しているセグメントがある場合は、スクリプトから判別できるように失敗扱いとする
+        if !report.healthy {
+            std::process::exit(1);
+        }
+    }
+}
+
+impl ClusterHealthCommand {
+    fn get_threshold_from_matches(matches: &ArgMatches) -> Result<usize> {
+        matches.value_of(REPAIR_BACKLOG_THRESHOLD).map_or(
+            Ok(DEFAULT_REPAIR_BACKLOG_THRESHOLD),
+            |s| {
+                s.parse().map_err(|_| {
+                    Error::from(
+                        ErrorKind::InvalidInput
+                            .cause("repair-backlog-threshold must be a non-negative integer"),
+                    )
+                })
+            },
+        )
+    }
+}
+
+fn print_report(report: &ClusterHealthReport) -> Result<()> {
+    let output = track!(serde_yaml::to_string(report).map_err(Error::from))?;
+    println!("{}", output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::App;
+
+    use super::ClusterHealthCommand;
+    use cluster_health::DEFAULT_REPAIR_BACKLOG_THRESHOLD;
+    use command::FrugalosSubcommand;
+    use Result;
+
+    fn parse(args: Vec<&str>) -> Result<usize> {
+        let command = ClusterHealthCommand;
+        let matches = App::new("frugalos-test")
+            .subcommand(command.get_subcommand())
+            .get_matches_from(args);
+        let matches = command
+            .check_matches(&matches)
+            .expect("cluster-health should match");
+        ClusterHealthCommand::get_threshold_from_matches(matches)
+    }
+
+    #[test]
+    fn get_threshold_from_matches_works() {
+        assert_eq!(
+            parse(vec!["frugalos-test", "cluster-health"]).unwrap(),
+            DEFAULT_REPAIR_BACKLOG_THRESHOLD
+        );
+        assert_eq!(
+            parse(vec![
+                "frugalos-test",
+                "cluster-health",
+                "--repair-backlog-threshold",
+                "10"
+            ])
+            .unwrap(),
+            10
+        );
+        assert!(parse(vec![
+            "frugalos-test",
+            "cluster-health",
+            "--repair-backlog-threshold",
+            "many"
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,521 @@
+//! Definitions for frugalos config
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use frugalos_config::{
+    self, BucketAttributes, FailureDomain, MaintenanceWindows, ProposedChange, RebalanceOptions,
+};
+use frugalos_core::erasure_coding::{ErasureCoding, ErasureCodingBackend, ErasureCodingChecksum};
+use frugalos_segment::MaintenanceSchedule;
+use libfrugalos::consistency::ReadConsistency;
+use libfrugalos::entity::server::Server;
+use serde_json;
+use sloggers::Build;
+use sloggers::LoggerBuilder;
+use std::env;
+use std::fs::File;
+use trackable::error::ErrorKindExt;
+
+use command::rpc_addr;
+use command::{warn_config_warnings, FrugalosSubcommand};
+use frugalos_core::serde_ext::evolution::ConfigWarning;
+use service;
+use {Error, ErrorKind, Result};
+
+/// frugalos config
+pub struct ConfigCommand;
+
+static FILE: &str = "FILE";
+static SERVER_ID: &str = "SERVER_ID";
+static SERVER_ADDR: &str = "SERVER_ADDR";
+static DATA_DIR: &str = "DATA_DIR";
+static EXCLUDE_SERVER: &str = "EXCLUDE_SERVER";
+static MAX_MOVE_RATIO: &str = "MAX_MOVE_RATIO";
+static EXCLUDE_UNUSED_DEVICES: &str = "EXCLUDE_UNUSED_DEVICES";
+static ZONE: &str = "ZONE";
+static RACK: &str = "RACK";
+static BUCKET_ID: &str = "BUCKET_ID";
+static READ_ONLY: &str = "READ_ONLY";
+static DEFAULT_DEADLINE: &str = "DEFAULT_DEADLINE";
+static DEFAULT_CONSISTENCY: &str = "DEFAULT_CONSISTENCY";
+static DEFAULT_SUBSET: &str = "DEFAULT_SUBSET";
+static MAX_OBJECTS: &str = "MAX_OBJECTS";
+static MAX_BYTES: &str = "MAX_BYTES";
+static ERASURE_CODING_BACKEND: &str = "ERASURE_CODING_BACKEND";
+static ERASURE_CODING_CHECKSUM: &str = "ERASURE_CODING_CHECKSUM";
+static MDS_WITNESSES: &str = "MDS_WITNESSES";
+
+impl FrugalosSubcommand for ConfigCommand {
+    fn get_subcommand<'a, 'b: 'a>(&self) -> App<'a, 'b> {
+        SubCommand::with_name("config")
+            .about("Backs up, restores or inspects the state of the configuration cluster")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("backup")
+                    .about("Saves the servers, devices and buckets held by the configuration cluster to a file")
+                    .arg(rpc_addr::get_arg())
+                    .arg(Arg::with_name(FILE).index(1).required(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("restore")
+                    .about(
+                        "Bootstraps a new configuration cluster from a backup file \
+                         (other surviving servers rejoin it after removing their `cluster.lusf`)",
+                    )
+                    .arg(Arg::with_name(FILE).index(1).required(true))
+                    .arg(
+                        Arg::with_name(SERVER_ID)
+                            .help("Sets the identifier of this server")
+                            .long("id")
+                            .takes_value(true)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name(SERVER_ADDR)
+                            .long("addr")
+                            .takes_value(true)
+                            .default_value(rpc_addr::default_rpc_server_bind_addr()),
+                    )
+                    .arg(
+                        Arg::with_name(DATA_DIR)
+                            .help(
+                                "Sets the data directory of this server \
+                                 (the default is the value of FRUGALOS_DATA_DIR environment variable)",
+                            )
+                            .long("data-dir")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name(EXCLUDE_SERVER)
+                            .help("Removes a lost server from the restored cluster")
+                            .long("exclude-server")
+                            .value_name("SERVER_ID")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("plan-rebalance")
+                    .about(
+                        "Prints a plan that evens out segment placement across devices \
+                         (dry-run; the plan is applied bucket by bucket by the background \
+                         rebalancer if `rebalancer.enabled` is set)",
+                    )
+                    .arg(rpc_addr::get_arg())
+                    .arg(
+                        Arg::with_name(MAX_MOVE_RATIO)
+                            .help("Sets the maximum ratio of segment members to be moved per bucket")
+                            .long("max-move-ratio")
+                            .takes_value(true)
+                            .default_value("0.1"),
+                    )
+                    .arg(
+                        Arg::with_name(EXCLUDE_UNUSED_DEVICES)
+                            .help("Does not move segments to devices that no bucket uses")
+                            .long("exclude-unused-devices"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("plan-change")
+                    .about(
+                        "Prints which segments would move and whether buckets remain placeable \
+                         if a proposed change of servers, devices and buckets were applied \
+                         (nothing is applied)",
+                    )
+                    .arg(rpc_addr::get_arg())
+                    .arg(
+                        Arg::with_name(FILE)
+                            .help("Sets the JSON file describing the proposed change")
+                            .index(1)
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("set-failure-domain")
+                    .about(
+                        "Sets the zone and rack of a server so that buckets created afterwards \
+                         spread segment members across them (omit both to clear the labels); \
+                         requires the `config_extensions` cluster feature",
+                    )
+                    .arg(rpc_addr::get_arg())
+                    .arg(
+                        Arg::with_name(SERVER_ID)
+                            .help("Sets the identifier of the server to be labeled")
+                            .long("id")
+                            .takes_value(true)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name(ZONE)
+                            .help("Sets the zone to which the server belongs")
+                            .long("zone")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name(RACK)
+                            .help("Sets the rack to which the server belongs")
+                            .long("rack")
+                            .takes_value(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("set-bucket-attributes")
+                    .about(
+                        "Sets the attributes of a bucket \
+                         (a read-only bucket rejects puts and deletes but keeps serving reads); \
+                         requires the `config_extensions` cluster feature",
+                    )
+                    .arg(rpc_addr::get_arg())
+                    .arg(
+                        Arg::with_name(BUCKET_ID)
+                            .help("Sets the identifier of the bucket")
+                            .long("bucket")
+                            .takes_value(true)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name(READ_ONLY)
+                            .help("Makes the bucket read-only or writable")
+                            .long("read-only")
+                            .takes_value(true)
+                            .possible_values(&["true", "false"])
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name(DEFAULT_DEADLINE)
+                            .help(
+                                "Sets the default deadline in milliseconds \
+                                 applied to HTTP requests that do not specify one \
+                                 (the server-wide default is used if omitted; \
+                                 RPC requests always carry their own deadline)",
+                            )
+                            .long("default-deadline")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name(DEFAULT_CONSISTENCY)
+                            .help(
+                                "Sets the default read consistency \
+                                 applied to requests that do not specify one \
+                                 (the server-wide default is used if omitted)",
+                            )
+                            .long("default-consistency")
+                            .takes_value(true)
+                            .possible_values(&["consistent", "stale", "quorum", "subset"]),
+                    )
+                    .arg(
+                        Arg::with_name(DEFAULT_SUBSET)
+                            .help("Sets the subset size used with `--default-consistency subset`")
+                            .long("default-subset")
+                            .takes_value(true)
+                            .default_value("1"),
+                    )
+                    .arg(
+                        Arg::with_name(MAX_OBJECTS)
+                            .help(
+                                "Sets the maximum number of objects the bucket can hold \
+                                 (unlimited if omitted)",
+                            )
+                            .long("max-objects")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name(MAX_BYTES)
+                            .help(
+                                "Sets the maximum total size in bytes of the objects \
+                                 the bucket can hold (unlimited if omitted)",
+                            )
+                            .long("max-bytes")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::with_name(ERASURE_CODING_BACKEND)
+                            .help(
+                                "Sets the erasure coding backend used for newly written objects \
+                                 of a dispersed bucket (`jerasure_rs_vand` if omitted; \
+                                 requires the `bucket_erasure_coding` cluster feature)",
+                            )
+                            .long("erasure-coding-backend")
+                            .takes_value(true)
+                            .possible_values(&[
+                                "jerasure_rs_vand",
+                                "jerasure_rs_cauchy",
+                                "isa_l_rs_vand",
+                                "isa_l_rs_cauchy",
+                                "rust_reed_solomon",
+                            ]),
+                    )
+                    .arg(
+                        Arg::with_name(ERASURE_CODING_CHECKSUM)
+                            .help(
+                                "Sets the checksum used with `--erasure-coding-backend` \
+                                 (not supported by `rust_reed_solomon`)",
+                            )
+                            .long("erasure-coding-checksum")
+                            .takes_value(true)
+                            .possible_values(&["none", "crc32", "md5"])
+                            .default_value("none"),
+                    )
+                    .arg(
+                        Arg::with_name(MDS_WITNESSES)
+                            .help(
+                                "Sets the number of MDS witnesses in each segment \
+                                 (requires the `mds_witness` cluster feature)",
+                            )
+                            .long("mds-witnesses")
+                            .takes_value(true)
+                            .default_value("0"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("set-maintenance-windows")
+                    .about(
+                        "Sets the maintenance windows shared by all servers, outside of which \
+                         repairs, segment_gc, rebalancing and relayout copies are not started \
+                         (an empty list of windows allows them at any time); \
+                         requires the `maintenance_windows` cluster feature",
+                    )
+                    .arg(rpc_addr::get_arg())
+                    .arg(
+                        Arg::with_name(FILE)
+                            .help(
+                                "Sets the JSON file describing the windows, e.g., \
+                                 `{\"windows\": [{\"schedule\": \"0 2 * * 1-5\", \
+                                 \"duration_minutes\": 180}], \"utc_offset_minutes\": 540}`",
+                            )
+                            .index(1)
+                            .required(true),
+                    ),
+            )
+    }
+
+    fn check_matches<'a>(&self, matches: &'a ArgMatches<'a>) -> Option<&'a ArgMatches<'a>> {
+        matches.subcommand_matches("config")
+    }
+
+    fn handle_matches(
+        &self,
+        logger_builder: LoggerBuilder,
+        matches: &ArgMatches,
+        config_warnings: &[ConfigWarning],
+    ) {
+        let mut logger = track_try_unwrap!(logger_builder.build());
+        warn_config_warnings(&mut logger, config_warnings);
+        if let Some(matches) = matches.subcommand_matches("backup") {
+            let rpc_addr = rpc_addr::from_matches(matches);
+            let file = matches.value_of(FILE).expect("Never fails");
+            let backup =
+                track_try_unwrap!(frugalos_config::cluster::backup(&logger, rpc_addr, file));
+            println!(
+                "Saved to {:?}: revision={}, servers={}, devices={}, buckets={}",
+                file,
+                backup.revision(),
+                backup.servers().len(),
+                backup.devices().len(),
+                backup.buckets().len()
+            );
+        } else if let Some(matches) = matches.subcommand_matches("restore") {
+            let file = matches.value_of(FILE).expect("Never fails");
+            let server = track_try_unwrap!(Self::get_server_from_matches(matches));
+            let data_dir = track_try_unwrap!(Self::get_data_dir_from_matches(matches));
+            let excluded = matches
+                .values_of(EXCLUDE_SERVER)
+                .into_iter()
+                .flatten()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            let logger = logger.new(o!("server" => format!("{}@{}", server.id, server.addr())));
+            track_try_unwrap!(frugalos_config::cluster::restore(
+                &logger, server, data_dir, file, &excluded
+            ));
+        } else if let Some(matches) = matches.subcommand_matches("plan-rebalance") {
+            let rpc_addr = rpc_addr::from_matches(matches);
+            let options = track_try_unwrap!(Self::get_rebalance_options_from_matches(matches));
+            let plan = track_try_unwrap!(frugalos_config::cluster::plan_rebalance(
+                &logger, rpc_addr, options
+            ));
+            let json = track_try_unwrap!(serde_json::to_string_pretty(&plan)
+                .map_err(|e| Error::from(ErrorKind::Other.cause(e))));
+            println!("{}", json);
+        } else if let Some(matches) = matches.subcommand_matches("plan-change") {
+            let rpc_addr = rpc_addr::from_matches(matches);
+            let file = matches.value_of(FILE).expect("Never fails");
+            let change = track_try_unwrap!(Self::read_proposed_change(file));
+            let plan = track_try_unwrap!(frugalos_config::cluster::plan_change(
+                &logger, rpc_addr, change
+            ));
+            let json = track_try_unwrap!(serde_json::to_string_pretty(&plan)
+                .map_err(|e| Error::from(ErrorKind::Other.cause(e))));
+            println!("{}", json);
+
+            // 制約を満たせないバケツがある場合は、スクリプトから判別できるように失敗扱いとする
+            if !plan.satisfiable {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                std::process::exit(1);
+            }
+        } else if let Some(matches) = matches.subcommand_matches("set-failure-domain") {
+            let rpc_addr = rpc_addr::from_matches(matches);
+            let domain = FailureDomain {
+                server_id: matches.value_of(SERVER_ID).expect("Never fails").to_owned(),
+                zone: matches.value_of(ZONE).map(ToOwned::to_owned),
+                rack: matches.value_of(RACK).map(ToOwned::to_owned),
+            };
+            let domain = track_try_unwrap!(frugalos_config::cluster::put_failure_domain(
+                &logger, rpc_addr, domain
+            ));
+            println!(
+                "Updated: server={}, zone={:?}, rack={:?}",
+                domain.server_id, domain.zone, domain.rack
+            );
+        } else if let Some(matches) = matches.subcommand_matches("set-bucket-attributes") {
+            let rpc_addr = rpc_addr::from_matches(matches);
+            let attributes = track_try_unwrap!(Self::get_bucket_attributes_from_matches(matches));
+            let attributes = track_try_unwrap!(frugalos_config::cluster::put_bucket_attributes(
+                &logger, rpc_addr, attributes
+            ));
+            println!(
+                "Updated: bucket={}, read_only={}, default_deadline_ms={:?}, default_consistency={:?}",
+                attributes.bucket_id,
+                attributes.read_only,
+                attributes.default_deadline_ms,
+                attributes.default_consistency
+            );
+        } else if let Some(matches) = matches.subcommand_matches("set-maintenance-windows") {
+            let rpc_addr = rpc_addr::from_matches(matches);
+            let file = matches.value_of(FILE).expect("Never fails");
+            let windows = track_try_unwrap!(Self::read_maintenance_windows(file));
+            let windows = track_try_unwrap!(frugalos_config::cluster::put_maintenance_windows(
+                &logger, rpc_addr, windows
+            ));
+            println!(
+                "Updated: windows={:?}, utc_offset_minutes={}",
+                windows.windows, windows.utc_offset_minutes
+            );
+        }
+
+        // NOTE: ログ出力(非同期)用に少し待機
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+impl ConfigCommand {
+    fn get_server_from_matches(matches: &ArgMatches) -> Result<Server> {
+        let id = matches.value_of(SERVER_ID).expect("Never fails");
+        let addr = matches.value_of(SERVER_ADDR).expect("Never fails");
+        let addr = track!(addr
+            .parse()
+            .map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e))))?;
+        Ok(Server::new(id.to_owned(), addr))
+    }
+
+    fn get_rebalance_options_from_matches(matches: &ArgMatches) -> Result<RebalanceOptions> {
+        let ratio = matches.value_of(MAX_MOVE_RATIO).expect("Never fails");
+        let max_move_ratio: f64 = track!(ratio
+            .parse()
+            .map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e))))?;
+        track_assert!(
+            (0.0..=1.0).contains(&max_move_ratio),
+            ErrorKind::InvalidInput,
+            "`--max-move-ratio` must be between 0.0 and 1.0: {}",
+            max_move_ratio
+        );
+        Ok(RebalanceOptions {
+            max_move_ratio,
+            include_unused_devices: !matches.is_present(EXCLUDE_UNUSED_DEVICES),
+        })
+    }
+
+    fn get_bucket_attributes_from_matches(matches: &ArgMatches) -> Result<BucketAttributes> {
+        let default_deadline_ms = if let Some(v) = matches.value_of(DEFAULT_DEADLINE) {
+            let ms: u64 = track!(v
+                .parse()
+                .map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e))))?;
+            Some(ms)
+        } else {
+            None
+        };
+        let default_consistency = match matches.value_of(DEFAULT_CONSISTENCY) {
+            None => None,
+            Some("consistent") => Some(ReadConsistency::Consistent),
+            Some("stale") => Some(ReadConsistency::Stale),
+            Some("quorum") => Some(ReadConsistency::Quorum),
+            Some(_) => {
+                let subset = matches.value_of(DEFAULT_SUBSET).expect("Never fails");
+                let n: usize = track!(subset
+                    .parse()
+                    .map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e))))?;
+                Some(ReadConsistency::Subset(n))
+            }
+        };
+        let parse_limit = |name| -> Result<Option<u64>> {
+            if let Some(v) = matches.value_of(name) {
+                let n: u64 = track!(v
+                    .parse()
+                    .map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e))))?;
+                Ok(Some(n))
+            } else {
+                Ok(None)
+            }
+        };
+        let erasure_coding = if let Some(v) = matches.value_of(ERASURE_CODING_BACKEND) {
+            let checksum = matches
+                .value_of(ERASURE_CODING_CHECKSUM)
+                .expect("Never fails");
+            Some(ErasureCoding {
+                backend: ErasureCodingBackend::from_name(v).expect("Never fails"),
+                checksum: ErasureCodingChecksum::from_name(checksum).expect("Never fails"),
+            })
+        } else {
+            None
+        };
+        let attributes = BucketAttributes {
+            bucket_id: matches.value_of(BUCKET_ID).expect("Never fails").to_owned(),
+            read_only: matches.value_of(READ_ONLY) == Some("true"),
+            default_deadline_ms,
+            default_consistency,
+            max_objects: track!(parse_limit(MAX_OBJECTS))?,
+            max_bytes: track!(parse_limit(MAX_BYTES))?,
+            erasure_coding,
+            mds_witnesses: track!(matches
+                .value_of(MDS_WITNESSES)
+                .expect("Never fails")
+                .parse()
+                .map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e))))?,
+        };
+        track!(attributes.validate())?;
+        Ok(attributes)
+    }
+
+    fn read_proposed_change(file: &str) -> Result<ProposedChange> {
+        let f = track!(File::open(file).map_err(Error::from), "file={:?}", file)?;
+        let change =
+            track!(serde_json::from_reader(f)
+                .map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e))))?;
+        Ok(change)
+    }
+
+    fn read_maintenance_windows(file: &str) -> Result<MaintenanceWindows> {
+        let f = track!(File::open(file).map_err(Error::from), "file={:?}", file)?;
+        let windows: MaintenanceWindows =
+            track!(serde_json::from_reader(f)
+                .map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e))))?;
+
+        // スケジュールの書式は構成管理用クラスタでは検証されないので、提案前にここで確認しておく
+        track!(MaintenanceSchedule::new(&service::maintenance_config(
+            &windows
+        )))?;
+        Ok(windows)
+    }
+
+    fn get_data_dir_from_matches(matches: &ArgMatches) -> Result<String> {
+        let data_dir = matches
+            .value_of(DATA_DIR)
+            .map(ToString::to_string)
+            .or_else(|| env::var("FRUGALOS_DATA_DIR").ok());
+        let data_dir = track_assert_some!(
+            data_dir,
+            ErrorKind::InvalidInput,
+            "`--data-dir` is required to restore the configuration cluster"
+        );
+        Ok(data_dir)
+    }
+}
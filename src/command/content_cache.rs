@@ -0,0 +1,56 @@
+//! Definitions for frugalos content-cache
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use sloggers::Build;
+use sloggers::LoggerBuilder;
+
+use command::rpc_addr;
+use command::{warn_config_warnings, FrugalosSubcommand};
+use frugalos_core::serde_ext::evolution::ConfigWarning;
+
+/// frugalos content-cache
+pub struct ContentCacheCommand;
+
+static BUCKET_ID: &str = "BUCKET_ID";
+
+impl FrugalosSubcommand for ContentCacheCommand {
+    fn get_subcommand<'a, 'b: 'a>(&self) -> App<'a, 'b> {
+        SubCommand::with_name("content-cache")
+            .about("Manages the in-memory content caches of a server")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("flush")
+                    .about(
+                        "Drops all cached contents of a bucket on a server \
+                         (the caches on other servers are kept)",
+                    )
+                    .arg(rpc_addr::get_arg())
+                    .arg(Arg::with_name(BUCKET_ID).index(1).required(true)),
+            )
+    }
+
+    fn check_matches<'a>(&self, matches: &'a ArgMatches<'a>) -> Option<&'a ArgMatches<'a>> {
+        matches.subcommand_matches("content-cache")
+    }
+
+    fn handle_matches(
+        &self,
+        logger_builder: LoggerBuilder,
+        matches: &ArgMatches,
+        config_warnings: &[ConfigWarning],
+    ) {
+        let mut logger = track_try_unwrap!(logger_builder.build());
+        warn_config_warnings(&mut logger, config_warnings);
+        if let Some(matches) = matches.subcommand_matches("flush") {
+            let rpc_addr = rpc_addr::from_matches(matches);
+            let bucket_id = matches.value_of(BUCKET_ID).expect("Never fails");
+            let logger = logger.new(o!("rpc_addr" => rpc_addr.to_string()));
+            let objects = track_try_unwrap!(crate::daemon::flush_content_cache(
+                &logger, rpc_addr, bucket_id
+            ));
+            println!("{}", objects);
+        }
+
+        // NOTE: ログ出力(非同期)用に少し待機
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
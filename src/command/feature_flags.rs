@@ -0,0 +1,184 @@
+//! Definitions for frugalos feature-flags
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use frugalos_segment::config::FeatureFlagSet;
+use frugalos_segment::Feature;
+use serde_yaml;
+use sloggers::Build;
+use sloggers::LoggerBuilder;
+
+use command::rpc_addr;
+use command::{warn_config_warnings, FrugalosSubcommand};
+use frugalos_core::serde_ext::evolution::ConfigWarning;
+use {Error, Result};
+
+/// frugalos feature-flags
+pub struct FeatureFlagsCommand;
+
+static BUCKET_ID: &str = "BUCKET_ID";
+static FEATURE: &str = "FEATURE";
+
+/// Actions that can be performed by `frugalos feature-flags`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FeatureFlagsAction {
+    /// Shows the current values of the feature flags of a bucket.
+    Get { bucket_id: String },
+    /// Enables or disables a feature of a bucket.
+    Set {
+        bucket_id: String,
+        feature: Feature,
+        enabled: bool,
+    },
+}
+
+impl FrugalosSubcommand for FeatureFlagsCommand {
+    fn get_subcommand<'a, 'b: 'a>(&self) -> App<'a, 'b> {
+        let feature_names = || Feature::all().iter().map(|f| f.name()).collect::<Vec<_>>();
+        let set_subcommand = |name, about| {
+            SubCommand::with_name(name)
+                .about(about)
+                .arg(rpc_addr::get_arg())
+                .arg(Arg::with_name(BUCKET_ID).index(1).required(true))
+                .arg(
+                    Arg::with_name(FEATURE)
+                        .index(2)
+                        .required(true)
+                        .possible_values(&feature_names()),
+                )
+        };
+        SubCommand::with_name("feature-flags")
+            .about(
+                "Shows or changes the feature flags of a bucket on a server \
+                 (changes are lost when the server restarts)",
+            )
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("get")
+                    .about("Shows the current values of the feature flags of a bucket")
+                    .arg(rpc_addr::get_arg())
+                    .arg(Arg::with_name(BUCKET_ID).index(1).required(true)),
+            )
+            .subcommand(set_subcommand("enable", "Enables a feature of a bucket"))
+            .subcommand(set_subcommand("disable", "Disables a feature of a bucket"))
+    }
+
+    fn check_matches<'a>(&self, matches: &'a ArgMatches<'a>) -> Option<&'a ArgMatches<'a>> {
+        matches.subcommand_matches("feature-flags")
+    }
+
+    fn handle_matches(
+        &self,
+        logger_builder: LoggerBuilder,
+        matches: &ArgMatches,
+        config_warnings: &[ConfigWarning],
+    ) {
+        let mut logger = track_try_unwrap!(logger_builder.build());
+        warn_config_warnings(&mut logger, config_warnings);
+        let (_, sub_matches) = matches.subcommand();
+        let sub_matches = sub_matches.expect("Never fails");
+        let rpc_addr = rpc_addr::from_matches(sub_matches);
+        let logger = logger.new(o!("rpc_addr" => rpc_addr.to_string()));
+        let flags = match Self::get_action_from_matches(matches) {
+            FeatureFlagsAction::Get { bucket_id } => track_try_unwrap!(
+                crate::daemon::get_feature_flags(&logger, rpc_addr, &bucket_id)
+            ),
+            FeatureFlagsAction::Set {
+                bucket_id,
+                feature,
+                enabled,
+            } => track_try_unwrap!(crate::daemon::set_feature_flag(
+                &logger, rpc_addr, &bucket_id, feature, enabled
+            )),
+        };
+        track_try_unwrap!(print_flags(&flags));
+
+        // NOTE: ログ出力(非同期)用に少し待機
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+impl FeatureFlagsCommand {
+    fn get_action_from_matches(matches: &ArgMatches) -> FeatureFlagsAction {
+        let (name, m) = matches.subcommand();
+        let m = m.expect("Never fails");
+        let bucket_id = m.value_of(BUCKET_ID).expect("Never fails").to_owned();
+        if name == "get" {
+            return FeatureFlagsAction::Get { bucket_id };
+        }
+        let feature = m
+            .value_of(FEATURE)
+            .expect("Never fails")
+            .parse()
+            .expect("Never fails");
+        FeatureFlagsAction::Set {
+            bucket_id,
+            feature,
+            enabled: name == "enable",
+        }
+    }
+}
+
+fn print_flags(flags: &FeatureFlagSet) -> Result<()> {
+    let output = track!(serde_yaml::to_string(flags).map_err(Error::from))?;
+    println!("{}", output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::App;
+    use frugalos_segment::Feature;
+
+    use super::{FeatureFlagsAction, FeatureFlagsCommand};
+    use command::FrugalosSubcommand;
+
+    fn parse(args: Vec<&str>) -> FeatureFlagsAction {
+        let command = FeatureFlagsCommand;
+        let matches = App::new("frugalos-test")
+            .subcommand(command.get_subcommand())
+            .get_matches_from(args);
+        let matches = command
+            .check_matches(&matches)
+            .expect("feature-flags should match");
+        FeatureFlagsCommand::get_action_from_matches(matches)
+    }
+
+    #[test]
+    fn get_action_from_matches_works() {
+        assert_eq!(
+            parse(vec!["frugalos-test", "feature-flags", "get", "foo"]),
+            FeatureFlagsAction::Get {
+                bucket_id: "foo".to_owned()
+            }
+        );
+        assert_eq!(
+            parse(vec![
+                "frugalos-test",
+                "feature-flags",
+                "enable",
+                "--rpc-addr",
+                "127.0.0.1:14278",
+                "foo",
+                "read_repair"
+            ]),
+            FeatureFlagsAction::Set {
+                bucket_id: "foo".to_owned(),
+                feature: Feature::ReadRepair,
+                enabled: true,
+            }
+        );
+        assert_eq!(
+            parse(vec![
+                "frugalos-test",
+                "feature-flags",
+                "disable",
+                "foo",
+                "quorum_put_ack"
+            ]),
+            FeatureFlagsAction::Set {
+                bucket_id: "foo".to_owned(),
+                feature: Feature::QuorumPutAck,
+                enabled: false,
+            }
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! Definitions for frugalos migrate-data-dir
+use clap::{App, Arg, ArgMatches, SubCommand};
+use sloggers::Build;
+use sloggers::LoggerBuilder;
+use std::env;
+use trackable::error::ErrorKindExt;
+
+use command::{warn_config_warnings, FrugalosSubcommand};
+use frugalos_core::serde_ext::evolution::ConfigWarning;
+use migration::MigrationPlan;
+use {Error, ErrorKind, Result};
+
+/// frugalos migrate-data-dir
+pub struct MigrateDataDirCommand;
+
+static DATA_DIR: &str = "DATA_DIR";
+static DEST_DATA_DIR: &str = "DEST_DATA_DIR";
+static MOVE_DEVICE: &str = "MOVE_DEVICE";
+static DRY_RUN: &str = "DRY_RUN";
+
+impl FrugalosSubcommand for MigrateDataDirCommand {
+    fn get_subcommand<'a, 'b: 'a>(&self) -> App<'a, 'b> {
+        SubCommand::with_name("migrate-data-dir")
+            .about("Relocates the data directory and device files of a stopped server")
+            .arg(
+                Arg::with_name(DATA_DIR)
+                    .help(
+                        "Sets the current data directory of this server \
+                         (the default is the value of FRUGALOS_DATA_DIR environment variable)",
+                    )
+                    .long("data-dir")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name(DEST_DATA_DIR)
+                    .help("Moves the files managed by frugalos in the data directory to this directory")
+                    .long("dest-data-dir")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name(MOVE_DEVICE)
+                    .help(
+                        "Moves a device file to a new path (e.g., `/mnt1/file0.lusf:/mnt2/file0.lusf`), \
+                         leaving a symbolic link at the registered path",
+                    )
+                    .long("move-device")
+                    .value_name("REGISTERED_PATH:NEW_PATH")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name(DRY_RUN)
+                    .help("Only shows the files to be moved")
+                    .long("dry-run"),
+            )
+    }
+
+    fn check_matches<'a>(&self, matches: &'a ArgMatches<'a>) -> Option<&'a ArgMatches<'a>> {
+        matches.subcommand_matches("migrate-data-dir")
+    }
+
+    fn handle_matches(
+        &self,
+        logger_builder: LoggerBuilder,
+        matches: &ArgMatches,
+        config_warnings: &[ConfigWarning],
+    ) {
+        let mut logger = track_try_unwrap!(logger_builder.build());
+        warn_config_warnings(&mut logger, config_warnings);
+        let plan = track_try_unwrap!(Self::get_plan_from_matches(matches));
+        if plan.moves().is_empty() {
+            println!("Nothing to migrate");
+            return;
+        }
+        for m in plan.moves() {
+            println!(
+                "{:?} -> {:?}{}",
+                m.src,
+                m.dest,
+                if m.leave_symlink { " (symlinked)" } else { "" }
+            );
+        }
+        if !matches.is_present(DRY_RUN) {
+            track_try_unwrap!(plan.execute(&logger));
+        }
+
+        // NOTE: ログ出力(非同期)用に少し待機
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+impl MigrateDataDirCommand {
+    fn get_plan_from_matches(matches: &ArgMatches) -> Result<MigrationPlan> {
+        let mut plan = MigrationPlan::new();
+        if let Some(dest) = matches.value_of(DEST_DATA_DIR) {
+            let src = track_assert_some!(
+                matches
+                    .value_of(DATA_DIR)
+                    .map(ToString::to_string)
+                    .or_else(|| env::var("FRUGALOS_DATA_DIR").ok()),
+                ErrorKind::InvalidInput,
+                "`--data-dir` is required to relocate the data directory"
+            );
+            track!(plan.relocate_data_dir(src, dest))?;
+        }
+        for value in matches.values_of(MOVE_DEVICE).into_iter().flatten() {
+            let (registered, dest) = track!(parse_device_move(value))?;
+            track!(plan.relocate_device(registered, dest))?;
+        }
+        Ok(plan)
+    }
+}
+
+fn parse_device_move(value: &str) -> Result<(&str, &str)> {
+    let mut tokens = value.splitn(2, ':');
+    match (tokens.next(), tokens.next()) {
+        (Some(registered), Some(dest)) if !registered.is_empty() && !dest.is_empty() => {
+            Ok((registered, dest))
+        }
+        _ => Err(track!(Error::from(ErrorKind::InvalidInput.cause(format!(
+            "Expected `REGISTERED_PATH:NEW_PATH`, but got {:?}",
+            value
+        ))))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_device_move_works() {
+        assert_eq!(
+            parse_device_move("/mnt1/file0.lusf:/mnt2/file0.lusf").ok(),
+            Some(("/mnt1/file0.lusf", "/mnt2/file0.lusf"))
+        );
+        assert!(parse_device_move("/mnt1/file0.lusf").is_err());
+        assert!(parse_device_move(":/mnt2/file0.lusf").is_err());
+    }
+}
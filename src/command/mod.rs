@@ -1,9 +1,21 @@
 //! A module for defining frugalos' subcommands.
 
 use clap::{App, ArgMatches};
+use frugalos_core::serde_ext::evolution::ConfigWarning;
 use sloggers::LoggerBuilder;
 
+pub mod bench;
+pub mod bucket_archive;
+pub mod bucket_relayout;
+pub mod cluster_health;
+pub mod config;
+pub mod content_cache;
+pub mod feature_flags;
+pub mod migrate_data_dir;
+pub mod object;
+pub mod repair;
 pub mod rpc_addr;
+pub mod segment_gc;
 pub mod set_repair_config;
 
 /// Trait for frugalos' subcommands.
@@ -19,16 +31,13 @@ pub trait FrugalosSubcommand {
         &self,
         logger_builder: LoggerBuilder,
         matches: &ArgMatches,
-        unknown_fields: &[String],
+        config_warnings: &[ConfigWarning],
     );
 }
 
-/// Emits warnings if there are unknown fields in parsing the config file.
-pub fn warn_if_there_are_unknown_fields(logger: &mut slog::Logger, unknown_fields: &[String]) {
-    if !unknown_fields.is_empty() {
-        warn!(
-            logger,
-            "The following unknown fields were passed:\n{:?}", unknown_fields
-        );
+/// Emits warnings found in parsing the config file (e.g., unknown fields).
+pub fn warn_config_warnings(logger: &mut slog::Logger, config_warnings: &[ConfigWarning]) {
+    for w in config_warnings {
+        warn!(logger, "{}", w);
     }
 }
@@ -0,0 +1,439 @@
+//! Definitions for frugalos object
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use libfrugalos::consistency::ReadConsistency;
+use libfrugalos::entity::object::ObjectVersion;
+use libfrugalos::expect::Expect;
+use serde::Serialize;
+use serde_yaml;
+use sloggers::Build;
+use sloggers::LoggerBuilder;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+
+use command::rpc_addr;
+use command::{warn_config_warnings, FrugalosSubcommand};
+use frugalos_core::serde_ext::evolution::ConfigWarning;
+use {Error, ErrorKind, Result};
+
+/// frugalos object
+pub struct ObjectCommand;
+
+static BUCKET_ID: &str = "BUCKET_ID";
+static OBJECT_ID: &str = "OBJECT_ID";
+static SEGMENT: &str = "SEGMENT";
+static DEADLINE: &str = "DEADLINE";
+static EXPECT: &str = "EXPECT";
+static CONSISTENCY: &str = "CONSISTENCY";
+static CHECK_STORAGE: &str = "CHECK_STORAGE";
+static INPUT: &str = "INPUT";
+static OUTPUT: &str = "OUTPUT";
+
+/// Options shared by the requests issued by `frugalos object`.
+#[derive(Debug, Clone)]
+struct RequestOptions {
+    deadline: Duration,
+    expect: Expect,
+    consistency: ReadConsistency,
+}
+
+/// Result of `frugalos object put`.
+#[derive(Debug, Serialize)]
+struct PutResult {
+    version: ObjectVersion,
+    created: bool,
+}
+
+impl FrugalosSubcommand for ObjectCommand {
+    fn get_subcommand<'a, 'b: 'a>(&self) -> App<'a, 'b> {
+        let object_subcommand = |name, about| {
+            SubCommand::with_name(name)
+                .about(about)
+                .arg(rpc_addr::get_arg())
+                .arg(Arg::with_name(BUCKET_ID).index(1).required(true))
+                .arg(Arg::with_name(OBJECT_ID).index(2).required(true))
+                .arg(
+                    Arg::with_name(DEADLINE)
+                        .help("Sets the deadline of the request in milliseconds")
+                        .long("deadline")
+                        .takes_value(true)
+                        .default_value("5000"),
+                )
+        };
+        let expect_arg = || {
+            Arg::with_name(EXPECT)
+                .help(
+                    "Sets the expected version of the object \
+                     (any, none, if-match:VERSION[,...] or if-none-match:VERSION[,...])",
+                )
+                .long("expect")
+                .takes_value(true)
+                .default_value("any")
+        };
+        let consistency_arg = || {
+            Arg::with_name(CONSISTENCY)
+                .help("Sets the read consistency (consistent, stale, quorum or subset[:N])")
+                .long("consistency")
+                .takes_value(true)
+        };
+        SubCommand::with_name("object")
+            .about("Issues object-level requests to a server via the native RPC")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                object_subcommand(
+                    "get",
+                    "Writes the content of an object to the standard output",
+                )
+                .arg(expect_arg())
+                .arg(consistency_arg())
+                .arg(
+                    Arg::with_name(OUTPUT)
+                        .help("Writes the content to the file instead of the standard output")
+                        .long("output")
+                        .takes_value(true),
+                ),
+            )
+            .subcommand(
+                object_subcommand(
+                    "put",
+                    "Puts an object whose content is read from the standard input",
+                )
+                .arg(expect_arg())
+                .arg(
+                    Arg::with_name(INPUT)
+                        .help("Reads the content from the file instead of the standard input")
+                        .long("input")
+                        .takes_value(true),
+                ),
+            )
+            .subcommand(object_subcommand("delete", "Deletes an object").arg(expect_arg()))
+            .subcommand(
+                object_subcommand("head", "Shows the version of an object")
+                    .arg(expect_arg())
+                    .arg(consistency_arg())
+                    .arg(
+                        Arg::with_name(CHECK_STORAGE)
+                            .help("Checks the existence of the object in the storage")
+                            .long("check-storage"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("list")
+                    .about("Lists the objects in a segment")
+                    .arg(rpc_addr::get_arg())
+                    .arg(Arg::with_name(BUCKET_ID).index(1).required(true))
+                    .arg(Arg::with_name(SEGMENT).index(2).required(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("locate")
+                    .about("Shows the segment of an object and the members holding its fragments")
+                    .arg(rpc_addr::get_arg())
+                    .arg(Arg::with_name(BUCKET_ID).index(1).required(true))
+                    .arg(Arg::with_name(OBJECT_ID).index(2).required(true))
+                    .arg(consistency_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("verify")
+                    .about("Audits the fragments of an object without repairing them")
+                    .arg(rpc_addr::get_arg())
+                    .arg(Arg::with_name(BUCKET_ID).index(1).required(true))
+                    .arg(Arg::with_name(OBJECT_ID).index(2).required(true)),
+            )
+    }
+
+    fn check_matches<'a>(&self, matches: &'a ArgMatches<'a>) -> Option<&'a ArgMatches<'a>> {
+        matches.subcommand_matches("object")
+    }
+
+    fn handle_matches(
+        &self,
+        logger_builder: LoggerBuilder,
+        matches: &ArgMatches,
+        config_warnings: &[ConfigWarning],
+    ) {
+        let mut logger = track_try_unwrap!(logger_builder.build());
+        warn_config_warnings(&mut logger, config_warnings);
+        let (name, sub_matches) = matches.subcommand();
+        let sub_matches = sub_matches.expect("Never fails");
+        let rpc_addr = rpc_addr::from_matches(sub_matches);
+        let logger = logger.new(o!("rpc_addr" => rpc_addr.to_string()));
+        track_try_unwrap!(Self::handle_action(&logger, rpc_addr, name, sub_matches));
+
+        // NOTE: ログ出力(非同期)用に少し待機
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+impl ObjectCommand {
+    fn handle_action(
+        logger: &slog::Logger,
+        rpc_addr: SocketAddr,
+        name: &str,
+        matches: &ArgMatches,
+    ) -> Result<()> {
+        let bucket_id = matches.value_of(BUCKET_ID).expect("Never fails");
+        if name == "list" {
+            let segment = track!(parse_arg::<u16>(matches, SEGMENT, "segment"))?;
+            let objects = track!(crate::daemon::list_objects(
+                logger, rpc_addr, bucket_id, segment
+            ))?;
+            return track!(print_yaml(&objects));
+        }
+
+        let object_id = matches.value_of(OBJECT_ID).expect("Never fails");
+        if name == "verify" {
+            let report = track!(crate::daemon::verify_object(
+                logger, rpc_addr, bucket_id, object_id
+            ))?;
+            return track!(print_yaml(&report));
+        }
+
+        if name == "locate" {
+            let consistency = if let Some(s) = matches.value_of(CONSISTENCY) {
+                Some(track!(parse_consistency(s))?)
+            } else {
+                None
+            };
+            let placement = track!(crate::daemon::get_object_placement(
+                logger,
+                rpc_addr,
+                bucket_id,
+                object_id,
+                consistency
+            ))?;
+            return track!(print_yaml(&placement));
+        }
+
+        let options = track!(Self::get_options_from_matches(matches))?;
+        match name {
+            "get" => {
+                let object = track!(crate::daemon::get_object(
+                    logger,
+                    rpc_addr,
+                    bucket_id,
+                    object_id,
+                    options.deadline,
+                    options.expect,
+                    options.consistency
+                ))?;
+                let (version, content) = track_assert_some!(
+                    object,
+                    ErrorKind::NotFound,
+                    "No such object: {:?}",
+                    object_id
+                );
+                info!(logger, "Got the object: version={}", version.0);
+                if let Some(path) = matches.value_of(OUTPUT) {
+                    let mut file = track!(File::create(path).map_err(Error::from))?;
+                    track!(file.write_all(&content).map_err(Error::from))?;
+                } else {
+                    let stdout = io::stdout();
+                    track!(stdout.lock().write_all(&content).map_err(Error::from))?;
+                }
+                Ok(())
+            }
+            "put" => {
+                let mut content = Vec::new();
+                if let Some(path) = matches.value_of(INPUT) {
+                    let mut file = track!(File::open(path).map_err(Error::from))?;
+                    track!(file.read_to_end(&mut content).map_err(Error::from))?;
+                } else {
+                    let stdin = io::stdin();
+                    track!(stdin.lock().read_to_end(&mut content).map_err(Error::from))?;
+                }
+                let (version, created) = track!(crate::daemon::put_object(
+                    logger,
+                    rpc_addr,
+                    bucket_id,
+                    object_id,
+                    content,
+                    options.deadline,
+                    options.expect
+                ))?;
+                track!(print_yaml(&PutResult { version, created }))
+            }
+            "delete" => {
+                let version = track!(crate::daemon::delete_object(
+                    logger,
+                    rpc_addr,
+                    bucket_id,
+                    object_id,
+                    options.deadline,
+                    options.expect
+                ))?;
+                track!(print_yaml(&version))
+            }
+            "head" => {
+                let version = track!(crate::daemon::head_object(
+                    logger,
+                    rpc_addr,
+                    bucket_id,
+                    object_id,
+                    options.deadline,
+                    options.expect,
+                    options.consistency,
+                    matches.is_present(CHECK_STORAGE)
+                ))?;
+                track!(print_yaml(&version))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn get_options_from_matches(matches: &ArgMatches) -> Result<RequestOptions> {
+        let deadline = track!(parse_arg::<u64>(matches, DEADLINE, "deadline"))?;
+        let expect = track!(parse_expect(matches.value_of(EXPECT).unwrap_or("any")))?;
+        let consistency = if let Some(s) = matches.value_of(CONSISTENCY) {
+            track!(parse_consistency(s))?
+        } else {
+            ReadConsistency::default()
+        };
+        Ok(RequestOptions {
+            deadline: Duration::from_millis(deadline),
+            expect,
+            consistency,
+        })
+    }
+}
+
+fn parse_arg<T: ::std::str::FromStr>(matches: &ArgMatches, arg: &str, name: &str) -> Result<T> {
+    let value = matches.value_of(arg).expect("Never fails");
+    value.parse().map_err(|_| invalid_arg(name))
+}
+
+/// `any`, `none`, `if-match:1,2`, `if-none-match:3`のような文字列から、期待するバージョンを取り出す。
+///
+/// バージョンは十進数で指定する。
+fn parse_expect(s: &str) -> Result<Expect> {
+    match s {
+        "any" => return Ok(Expect::Any),
+        "none" => return Ok(Expect::None),
+        _ => {}
+    }
+    let mut tokens = s.splitn(2, ':');
+    let kind = tokens.next().expect("Never fails");
+    let versions = track_assert_some!(tokens.next(), ErrorKind::InvalidInput, "expect={:?}", s);
+    let versions = track!(versions
+        .split(',')
+        .map(|v| v.trim().parse().map(ObjectVersion))
+        .collect::<::std::result::Result<Vec<_>, _>>()
+        .map_err(|_| invalid_arg("expect")))?;
+    match kind {
+        "if-match" => Ok(Expect::IfMatch(versions)),
+        "if-none-match" => Ok(Expect::IfNoneMatch(versions)),
+        _ => Err(invalid_arg("expect")),
+    }
+}
+
+/// `consistent`, `stale`, `quorum`, `subset`, `subset:2`のような文字列から、読み込みの一貫性を取り出す。
+///
+/// `subset`の要素数を省略した場合は`1`となる。
+fn parse_consistency(s: &str) -> Result<ReadConsistency> {
+    match s {
+        "consistent" => Ok(ReadConsistency::Consistent),
+        "stale" => Ok(ReadConsistency::Stale),
+        "quorum" => Ok(ReadConsistency::Quorum),
+        "subset" => Ok(ReadConsistency::Subset(1)),
+        _ if s.starts_with("subset:") => {
+            let n = track!(s["subset:".len()..]
+                .parse()
+                .map_err(|_| invalid_arg("consistency")))?;
+            Ok(ReadConsistency::Subset(n))
+        }
+        _ => Err(invalid_arg("consistency")),
+    }
+}
+
+fn print_yaml<T: Serialize>(value: &T) -> Result<()> {
+    let output = track!(serde_yaml::to_string(value).map_err(Error::from))?;
+    println!("{}", output);
+    Ok(())
+}
+
+fn invalid_arg(name: &str) -> Error {
+    ErrorKind::InvalidInput
+        .cause(format!("Invalid `--{}`", name))
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_expect_works() {
+        assert!(match parse_expect("any").unwrap() {
+            Expect::Any => true,
+            _ => false,
+        });
+        assert!(match parse_expect("none").unwrap() {
+            Expect::None => true,
+            _ => false,
+        });
+        assert!(match parse_expect("if-match:1,2").unwrap() {
+            Expect::IfMatch(v) => v == [ObjectVersion(1), ObjectVersion(2)],
+            _ => false,
+        });
+        assert!(match parse_expect("if-none-match:3").unwrap() {
+            Expect::IfNoneMatch(v) => v == [ObjectVersion(3)],
+            _ => false,
+        });
+        assert!(parse_expect("if-match").is_err());
+        assert!(parse_expect("if-match:foo").is_err());
+        assert!(parse_expect("unless:1").is_err());
+    }
+
+    #[test]
+    fn parse_consistency_works() {
+        assert_eq!(
+            parse_consistency("consistent").unwrap(),
+            ReadConsistency::Consistent
+        );
+        assert_eq!(parse_consistency("stale").unwrap(), ReadConsistency::Stale);
+        assert_eq!(
+            parse_consistency("quorum").unwrap(),
+            ReadConsistency::Quorum
+        );
+        assert_eq!(
+            parse_consistency("subset").unwrap(),
+            ReadConsistency::Subset(1)
+        );
+        assert_eq!(
+            parse_consistency("subset:2").unwrap(),
+            ReadConsistency::Subset(2)
+        );
+        assert!(parse_consistency("subset:").is_err());
+        assert!(parse_consistency("strong").is_err());
+    }
+
+    #[test]
+    fn get_options_from_matches_works() {
+        let command = ObjectCommand;
+        let matches = App::new("frugalos-test")
+            .subcommand(command.get_subcommand())
+            .get_matches_from(vec![
+                "frugalos-test",
+                "object",
+                "head",
+                "foo",
+                "bar",
+                "--deadline",
+                "100",
+                "--consistency",
+                "stale",
+            ]);
+        let matches = command.check_matches(&matches).expect("Never fails");
+        let (name, matches) = matches.subcommand();
+        assert_eq!(name, "head");
+        let options =
+            ObjectCommand::get_options_from_matches(matches.expect("Never fails")).unwrap();
+        assert_eq!(options.deadline, Duration::from_millis(100));
+        assert_eq!(options.consistency, ReadConsistency::Stale);
+        assert!(match options.expect {
+            Expect::Any => true,
+            _ => false,
+        });
+    }
+}
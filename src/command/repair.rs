@@ -0,0 +1,202 @@
+//! Definitions for frugalos repair
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use frugalos_segment::{SegmentNodeStatus, SynchronizerQueues};
+use serde::Serialize;
+use serde_yaml;
+use sloggers::Build;
+use sloggers::LoggerBuilder;
+
+use command::rpc_addr;
+use command::{warn_config_warnings, FrugalosSubcommand};
+use frugalos_core::serde_ext::evolution::ConfigWarning;
+use {Error, Result};
+
+/// frugalos repair
+///
+/// The repair idleness threshold and segment_gc (FullSync) are controlled by
+/// `frugalos set-repair-config` and `frugalos segment-gc` respectively.
+pub struct RepairCommand;
+
+static BUCKET_ID: &str = "BUCKET_ID";
+static OBJECT_ID: &str = "OBJECT_ID";
+
+/// Actions that can be performed by `frugalos repair`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RepairAction {
+    /// Repairs an object on all the nodes that hold it.
+    Object {
+        bucket_id: String,
+        object_id: String,
+    },
+    /// Shows the queue lengths of the synchronizer of each node.
+    Queues,
+}
+
+/// Output of `frugalos repair queues`.
+#[derive(Debug, Serialize)]
+struct QueueStats {
+    total: SynchronizerQueues,
+    nodes: Vec<NodeQueues>,
+}
+
+#[derive(Debug, Serialize)]
+struct NodeQueues {
+    node: String,
+    is_leader: bool,
+    queues: SynchronizerQueues,
+}
+
+impl FrugalosSubcommand for RepairCommand {
+    fn get_subcommand<'a, 'b: 'a>(&self) -> App<'a, 'b> {
+        SubCommand::with_name("repair")
+            .about("Repairs objects or shows the repair backlog of the nodes on a server")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("object")
+                    .about("Repairs an object immediately on all the nodes that hold it")
+                    .arg(rpc_addr::get_arg())
+                    .arg(Arg::with_name(BUCKET_ID).index(1).required(true))
+                    .arg(Arg::with_name(OBJECT_ID).index(2).required(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("queues")
+                    .about("Shows the queue lengths of the synchronizer of each node")
+                    .arg(rpc_addr::get_arg()),
+            )
+    }
+
+    fn check_matches<'a>(&self, matches: &'a ArgMatches<'a>) -> Option<&'a ArgMatches<'a>> {
+        matches.subcommand_matches("repair")
+    }
+
+    fn handle_matches(
+        &self,
+        logger_builder: LoggerBuilder,
+        matches: &ArgMatches,
+        config_warnings: &[ConfigWarning],
+    ) {
+        let mut logger = track_try_unwrap!(logger_builder.build());
+        warn_config_warnings(&mut logger, config_warnings);
+        let (_, sub_matches) = matches.subcommand();
+        let sub_matches = sub_matches.expect("Never fails");
+        let rpc_addr = rpc_addr::from_matches(sub_matches);
+        let logger = logger.new(o!("rpc_addr" => rpc_addr.to_string()));
+        match Self::get_action_from_matches(matches) {
+            RepairAction::Object {
+                bucket_id,
+                object_id,
+            } => {
+                let summary = track_try_unwrap!(crate::daemon::repair_object(
+                    &logger, rpc_addr, &bucket_id, &object_id
+                ));
+                track_try_unwrap!(print_yaml(&summary));
+            }
+            RepairAction::Queues => {
+                let statuses =
+                    track_try_unwrap!(crate::daemon::get_segment_node_statuses(&logger, rpc_addr));
+                track_try_unwrap!(print_yaml(&make_queue_stats(statuses)));
+            }
+        }
+
+        // NOTE: ログ出力(非同期)用に少し待機
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+impl RepairCommand {
+    fn get_action_from_matches(matches: &ArgMatches) -> RepairAction {
+        let (name, m) = matches.subcommand();
+        let m = m.expect("Never fails");
+        if name == "queues" {
+            return RepairAction::Queues;
+        }
+        RepairAction::Object {
+            bucket_id: m.value_of(BUCKET_ID).expect("Never fails").to_owned(),
+            object_id: m.value_of(OBJECT_ID).expect("Never fails").to_owned(),
+        }
+    }
+}
+
+/// Sorts the queue lengths of the nodes by their IDs and sums them up.
+fn make_queue_stats(mut statuses: Vec<SegmentNodeStatus>) -> QueueStats {
+    statuses.sort_by(|a, b| a.node.cmp(&b.node));
+    let mut total = SynchronizerQueues::default();
+    let nodes = statuses
+        .into_iter()
+        .map(|s| {
+            total.repair_prep += s.queues.repair_prep;
+            total.delete += s.queues.delete;
+            total.compaction += s.queues.compaction;
+            total.repair += s.queues.repair;
+            total.on_demand_repairs += s.queues.on_demand_repairs;
+            NodeQueues {
+                node: s.node,
+                is_leader: s.is_leader,
+                queues: s.queues,
+            }
+        })
+        .collect();
+    QueueStats { total, nodes }
+}
+
+fn print_yaml<T: Serialize>(value: &T) -> Result<()> {
+    let output = track!(serde_yaml::to_string(value).map_err(Error::from))?;
+    println!("{}", output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::App;
+
+    use super::*;
+
+    fn parse(args: Vec<&str>) -> RepairAction {
+        let command = RepairCommand;
+        let matches = App::new("frugalos-test")
+            .subcommand(command.get_subcommand())
+            .get_matches_from(args);
+        let matches = command
+            .check_matches(&matches)
+            .expect("repair should match");
+        RepairCommand::get_action_from_matches(matches)
+    }
+
+    #[test]
+    fn get_action_from_matches_works() {
+        assert_eq!(
+            parse(vec!["frugalos-test", "repair", "object", "foo", "bar"]),
+            RepairAction::Object {
+                bucket_id: "foo".to_owned(),
+                object_id: "bar".to_owned(),
+            }
+        );
+        assert_eq!(
+            parse(vec!["frugalos-test", "repair", "queues"]),
+            RepairAction::Queues
+        );
+    }
+
+    #[test]
+    fn make_queue_stats_works() {
+        let status = |node: &str, repair| SegmentNodeStatus {
+            node: node.to_owned(),
+            leader: None,
+            is_leader: false,
+            queues: SynchronizerQueues {
+                repair,
+                delete: 1,
+                ..SynchronizerQueues::default()
+            },
+            segment_gc: None,
+            relayout: None,
+        };
+        let stats = make_queue_stats(vec![status("1", 3), status("0", 2)]);
+        assert_eq!(stats.total.repair, 5);
+        assert_eq!(stats.total.delete, 2);
+        assert_eq!(
+            stats.nodes.iter().map(|n| &n.node[..]).collect::<Vec<_>>(),
+            ["0", "1"]
+        );
+    }
+}
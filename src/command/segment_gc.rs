@@ -0,0 +1,128 @@
+//! Definitions for frugalos segment-gc
+use clap::{App, Arg, ArgMatches, SubCommand};
+use serde_yaml;
+use sloggers::Build;
+use sloggers::LoggerBuilder;
+
+use command::rpc_addr;
+use command::{warn_config_warnings, FrugalosSubcommand};
+use frugalos_core::serde_ext::evolution::ConfigWarning;
+use Error;
+
+/// frugalos segment-gc
+pub struct SegmentGcCommand;
+
+static ACTION: &str = "ACTION";
+
+/// Actions that can be performed by `frugalos segment-gc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SegmentGcAction {
+    /// Shows the progress of segment_gc of each node.
+    Status,
+    /// Starts segment_gc (FullSync) on each node.
+    Start,
+    /// Cancels running segment_gc on each node.
+    Stop,
+}
+
+impl FrugalosSubcommand for SegmentGcCommand {
+    fn get_subcommand<'a, 'b: 'a>(&self) -> App<'a, 'b> {
+        SubCommand::with_name("segment-gc")
+            .about("Shows, starts or stops segment_gc (FullSync) of the nodes on a server")
+            .arg(rpc_addr::get_arg())
+            .arg(
+                Arg::with_name(ACTION)
+                    .index(1)
+                    .possible_values(&["status", "start", "stop"])
+                    .default_value("status"),
+            )
+    }
+
+    fn check_matches<'a>(&self, matches: &'a ArgMatches<'a>) -> Option<&'a ArgMatches<'a>> {
+        matches.subcommand_matches("segment-gc")
+    }
+
+    fn handle_matches(
+        &self,
+        logger_builder: LoggerBuilder,
+        matches: &ArgMatches,
+        config_warnings: &[ConfigWarning],
+    ) {
+        let mut logger = track_try_unwrap!(logger_builder.build());
+        warn_config_warnings(&mut logger, config_warnings);
+        let rpc_addr = rpc_addr::from_matches(&matches);
+        let action = Self::get_action_from_matches(matches);
+        let logger = logger.new(o!("rpc_addr" => rpc_addr.to_string()));
+        match action {
+            SegmentGcAction::Status => {
+                let statuses =
+                    track_try_unwrap!(crate::daemon::get_segment_gc_status(&logger, rpc_addr));
+                let output =
+                    track_try_unwrap!(serde_yaml::to_string(&statuses).map_err(Error::from));
+                println!("{}", output);
+            }
+            SegmentGcAction::Start => {
+                let nodes = track_try_unwrap!(crate::daemon::start_segment_gc(&logger, rpc_addr));
+                println!("Started: {:?}", nodes);
+            }
+            SegmentGcAction::Stop => {
+                let nodes = track_try_unwrap!(crate::daemon::stop_segment_gc(&logger, rpc_addr));
+                println!("Stopped: {:?}", nodes);
+            }
+        }
+
+        // NOTE: ログ出力(非同期)用に少し待機
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+impl SegmentGcCommand {
+    fn get_action_from_matches(matches: &ArgMatches) -> SegmentGcAction {
+        match matches.value_of(ACTION) {
+            Some("start") => SegmentGcAction::Start,
+            Some("stop") => SegmentGcAction::Stop,
+            _ => SegmentGcAction::Status,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::App;
+
+    use super::{SegmentGcAction, SegmentGcCommand};
+    use command::FrugalosSubcommand;
+
+    fn parse(args: Vec<&str>) -> SegmentGcAction {
+        let segment_gc_command = SegmentGcCommand;
+        let matches = App::new("frugalos-test")
+            .subcommand(segment_gc_command.get_subcommand())
+            .get_matches_from(args);
+        let matches = segment_gc_command
+            .check_matches(&matches)
+            .expect("segment-gc should match");
+        SegmentGcCommand::get_action_from_matches(&matches)
+    }
+
+    #[test]
+    fn get_action_from_matches_works() {
+        assert_eq!(
+            parse(vec!["frugalos-test", "segment-gc"]),
+            SegmentGcAction::Status
+        );
+        assert_eq!(
+            parse(vec!["frugalos-test", "segment-gc", "start"]),
+            SegmentGcAction::Start
+        );
+        assert_eq!(
+            parse(vec![
+                "frugalos-test",
+                "segment-gc",
+                "--rpc-addr",
+                "127.0.0.1:14278",
+                "stop"
+            ]),
+            SegmentGcAction::Stop
+        );
+    }
+}
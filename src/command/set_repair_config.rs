@@ -7,7 +7,8 @@ use std::time::Duration;
 use trackable::error::ErrorKindExt;
 
 use command::rpc_addr;
-use command::{warn_if_there_are_unknown_fields, FrugalosSubcommand};
+use command::{warn_config_warnings, FrugalosSubcommand};
+use frugalos_core::serde_ext::evolution::ConfigWarning;
 use {Error, ErrorKind};
 
 /// frugalos set-repair-config
@@ -49,10 +50,10 @@ impl FrugalosSubcommand for SetRepairConfigCommand {
         &self,
         logger_builder: LoggerBuilder,
         matches: &ArgMatches,
-        unknown_fields: &[String],
+        config_warnings: &[ConfigWarning],
     ) {
         let mut logger = track_try_unwrap!(logger_builder.build());
-        warn_if_there_are_unknown_fields(&mut logger, &unknown_fields);
+        warn_config_warnings(&mut logger, config_warnings);
         let rpc_addr = rpc_addr::from_matches(&matches);
         let repair_config = Self::get_repair_config_from_matches(matches);
         let logger = logger.new(o!("rpc_addr" => rpc_addr.to_string(),
@@ -76,7 +77,12 @@ impl SetRepairConfigCommand {
                 let duration_secs: f64 = track_try_unwrap!(str.parse().map_err(|_| Error::from(
                     ErrorKind::InvalidInput.cause("repair-idleness-threshold must be a float")
                 )));
-                // TODO check if duration_secs is non-negative
+                if duration_secs < 0.0 {
+                    track_try_unwrap!(Err::<(), _>(Error::from(
+                        ErrorKind::InvalidInput
+                            .cause("repair-idleness-threshold must be non-negative")
+                    )));
+                }
                 RepairIdleness::Threshold(Duration::from_millis((duration_secs * 1000.0) as u64))
             })
         }
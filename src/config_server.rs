@@ -2,48 +2,89 @@ use bytecodec::json_codec::{JsonDecoder, JsonEncoder};
 use bytecodec::null::NullDecoder;
 use fibers_http_server::{HandleRequest, Reply, Req, ServerBuilder as HttpServerBuilder, Status};
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
-use futures::Future;
+use fibers_rpc::Call;
+use frugalos_config::DeviceDecommission;
+use frugalos_core::rpc_auth;
+use futures::{self, Future};
 use httpcodec::{BodyDecoder, BodyEncoder};
+use libfrugalos;
 use libfrugalos::client::config::Client as ConfigRpcClient;
 use libfrugalos::entity::bucket::{Bucket, BucketSummary};
-use libfrugalos::entity::device::{Device, DeviceSummary};
+use libfrugalos::entity::device::{Device, DeviceId, DeviceSummary};
 use libfrugalos::entity::server::{Server, ServerSummary};
+use libfrugalos::schema::config::{PutBucketRpc, PutDeviceRpc, PutServerRpc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::net::SocketAddr;
 use url::Url;
 
+use auth::{Permission, SharedAuthorizer, WithAuth};
+use decommission;
 use http::{make_json_response, not_found, HttpResult};
-use {Error, Result};
+use operation::{OperationRegistry, OperationStatus};
+use {Error, ErrorKind, Result};
 
 #[derive(Clone)]
 pub struct ConfigServer {
     rpc_service: RpcServiceHandle,
     local_addr: SocketAddr,
+    authorizer: SharedAuthorizer,
+    operations: OperationRegistry,
 }
 impl ConfigServer {
-    pub fn new(rpc_service: RpcServiceHandle, local_addr: SocketAddr) -> Self {
+    pub fn new(
+        rpc_service: RpcServiceHandle,
+        local_addr: SocketAddr,
+        authorizer: SharedAuthorizer,
+        operations: OperationRegistry,
+    ) -> Self {
         ConfigServer {
             rpc_service,
             local_addr,
+            authorizer,
+            operations,
         }
     }
     pub fn register(self, builder: &mut HttpServerBuilder) -> Result<()> {
-        track!(builder.add_handler(ListServers(self.clone())))?;
-        track!(builder.add_handler(PutServer(self.clone())))?;
-        track!(builder.add_handler(GetServer(self.clone())))?;
-
-        track!(builder.add_handler(ListDevices(self.clone())))?;
-        track!(builder.add_handler(PutDevice(self.clone())))?;
-        track!(builder.add_handler(GetDevice(self.clone())))?;
-
-        track!(builder.add_handler(ListBuckets(self.clone())))?;
-        track!(builder.add_handler(PutBucket(self.clone())))?;
-        track!(builder.add_handler(GetBucket(self.clone())))?;
+        let read = Permission::Read;
+        let admin = Permission::Admin;
+        let auth = || self.authorizer.clone();
+        track!(builder.add_handler(WithAuth::cluster(ListServers(self.clone()), auth(), read)))?;
+        track!(builder.add_handler(WithAuth::cluster(PutServer(self.clone()), auth(), admin)))?;
+        track!(builder.add_handler(WithAuth::cluster(GetServer(self.clone()), auth(), read)))?;
+
+        track!(builder.add_handler(WithAuth::cluster(ListDevices(self.clone()), auth(), read)))?;
+        track!(builder.add_handler(WithAuth::cluster(PutDevice(self.clone()), auth(), admin)))?;
+        track!(builder.add_handler(WithAuth::cluster(GetDevice(self.clone()), auth(), read)))?;
+
+        // バケツの作成や更新はクラスタの構成の変更なので、クラスタに対する管理権限を要求する
+        track!(builder.add_handler(WithAuth::cluster(ListBuckets(self.clone()), auth(), read)))?;
+        track!(builder.add_handler(WithAuth::cluster(PutBucket(self.clone()), auth(), admin)))?;
+        track!(builder.add_handler(WithAuth::bucket(GetBucket(self.clone()), auth(), read)))?;
+
+        let start_decommission = StartDecommission(self.clone());
+        track!(builder.add_handler(WithAuth::cluster(start_decommission, auth(), admin)))?;
+        let list_decommissions = ListDecommissions(self.clone());
+        track!(builder.add_handler(WithAuth::cluster(list_decommissions, auth(), read)))?;
 
         Ok(())
     }
     fn client(&self) -> ConfigRpcClient {
         ConfigRpcClient::new(self.local_addr, self.rpc_service.clone())
     }
+
+    // 構成を変更する RPC は、クラスタトークンを付けて呼び出す必要がある
+    fn update<C, T>(&self, request: C::Req) -> impl Future<Item = T, Error = Error>
+    where
+        C: Call<Res = libfrugalos::Result<T>>,
+        C::Req: Serialize + DeserializeOwned + Send + 'static,
+        C::ReqEncoder: Default,
+        C::ResDecoder: Default,
+    {
+        rpc_auth::call::<C>(&self.rpc_service, self.local_addr, request)
+            .map_err(|e| track!(Error::from(e)))
+            .and_then(|result| track!(result.map_err(Error::from)))
+    }
 }
 
 struct ListServers(ConfigServer);
@@ -82,7 +123,7 @@ impl HandleRequest for PutServer {
 
     fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
         let server = req.into_body();
-        let future = self.0.client().put_server(server).then(|result| {
+        let future = self.0.update::<PutServerRpc, _>(server).then(|result| {
             let (status, body) = match track!(result) {
                 Err(e) => (Status::InternalServerError, Err(Error::from(e))),
                 Ok(v) => (Status::Ok, Ok(v)),
@@ -154,7 +195,7 @@ impl HandleRequest for PutDevice {
 
     fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
         let device = req.into_body();
-        let future = self.0.client().put_device(device).then(|result| {
+        let future = self.0.update::<PutDeviceRpc, _>(device).then(|result| {
             let (status, body) = match track!(result) {
                 Err(e) => (Status::InternalServerError, Err(Error::from(e))),
                 Ok(v) => (Status::Ok, Ok(v)),
@@ -226,7 +267,7 @@ impl HandleRequest for PutBucket {
 
     fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
         let bucket = req.into_body();
-        let future = self.0.client().put_bucket(bucket).then(|result| {
+        let future = self.0.update::<PutBucketRpc, _>(bucket).then(|result| {
             let (status, body) = match track!(result) {
                 Err(e) => (Status::InternalServerError, Err(Error::from(e))),
                 Ok(v) => (Status::Ok, Ok(v)),
@@ -262,6 +303,104 @@ impl HandleRequest for GetBucket {
     }
 }
 
+/// 指定されたデバイスの退役を開始する。
+///
+/// 退役の進捗と結果は、長時間操作として`/v1/operations/*`から参照できる。
+struct StartDecommission(ConfigServer);
+impl HandleRequest for StartDecommission {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/v1/decommissions";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<OperationStatus>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let devices = match track!(get_decommission_devices(&req.url())) {
+            Err(e) => {
+                return Box::new(futures::finished(make_json_response(
+                    Status::BadRequest,
+                    Err(e),
+                )));
+            }
+            Ok(devices) => devices,
+        };
+        let server = self.0.clone();
+        let future = decommission::start(
+            server.rpc_service.clone(),
+            server.local_addr,
+            devices.clone(),
+        )
+        .then(move |result| {
+            let initial = match track!(result) {
+                Err(e) => {
+                    return Ok(make_json_response(Status::InternalServerError, Err(e)));
+                }
+                Ok(initial) => initial,
+            };
+            let target = devices.join(",");
+            let rpc_service = server.rpc_service.clone();
+            let local_addr = server.local_addr;
+            let status = server
+                .operations
+                .start("decommission", target, move |operation| {
+                    decommission::watch(
+                        rpc_service,
+                        local_addr,
+                        devices,
+                        initial,
+                        decommission::DEFAULT_POLL_INTERVAL,
+                        operation,
+                    )
+                });
+            Ok(make_json_response(Status::Accepted, Ok(status)))
+        });
+        Box::new(future)
+    }
+}
+
+/// 退役中のデバイスの状態の一覧を返す。
+struct ListDecommissions(ConfigServer);
+impl HandleRequest for ListDecommissions {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/decommissions";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<Vec<DeviceDecommission>>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        let future =
+            decommission::list(self.0.rpc_service.clone(), self.0.local_addr).then(|result| {
+                let (status, body) = match track!(result) {
+                    Err(e) => (Status::InternalServerError, Err(e)),
+                    Ok(v) => (Status::Ok, Ok(v)),
+                };
+                Ok(make_json_response(status, body))
+            });
+        Box::new(future)
+    }
+}
+
+/// クエリの`device`から、退役させるデバイスの ID 群を取り出す。
+fn get_decommission_devices(url: &Url) -> Result<Vec<DeviceId>> {
+    let devices = url
+        .query_pairs()
+        .filter(|(k, _)| k == "device")
+        .map(|(_, v)| v.into_owned())
+        .collect::<Vec<_>>();
+    track_assert!(
+        !devices.is_empty(),
+        ErrorKind::InvalidInput,
+        "No `device` parameter"
+    );
+    Ok(devices)
+}
+
 fn get_id(url: &Url) -> String {
     url.path_segments()
         .expect("Never fails")
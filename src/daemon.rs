@@ -5,35 +5,81 @@ use fibers::sync::mpsc;
 use fibers::sync::oneshot;
 use fibers::time::timer;
 use fibers::{Executor, Spawn, ThreadPoolExecutor};
-use fibers_http_server::metrics::{MetricsHandler, WithMetrics};
+use fibers_http_server::metrics::WithMetrics;
 use fibers_http_server::{Server as HttpServer, ServerBuilder as HttpServerBuilder};
 use fibers_rpc;
 use fibers_rpc::client::{ClientService as RpcService, ClientServiceBuilder as RpcServiceBuilder};
 use fibers_rpc::server::ServerBuilder as RpcServerBuilder;
+use fibers_rpc::Call;
 use frugalos_config;
-use frugalos_core::tracer::ThreadLocalTracer;
+use frugalos_core::cluster_feature;
+use frugalos_core::logging;
+use frugalos_core::memory;
+use frugalos_core::prometheus;
+use frugalos_core::rpc_auth;
+use frugalos_core::tracer::{TailSampler, TailSampling, ThreadLocalTracer};
 use frugalos_raft;
+use frugalos_segment::config::FeatureFlagSet;
+use frugalos_segment::schema as segment_schema;
+use frugalos_segment::{
+    Feature, ObjectAuditReport, ObjectRepairSummary, SegmentGcStatus, SegmentNodeStatus,
+};
 use futures::{Async, Future, Poll, Stream};
 use libfrugalos;
+use libfrugalos::consistency::ReadConsistency;
+use libfrugalos::entity::object::{ObjectSummary, ObjectVersion};
+use libfrugalos::expect::Expect;
 use prometrics;
 use rustracing::sampler::{PassiveSampler, ProbabilisticSampler, Sampler};
 use rustracing_jaeger;
 use rustracing_jaeger::span::SpanContextState;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use slog::{self, Drain, Logger};
 use std::mem;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
 use std::time::Duration;
 use trackable::error::ErrorKindExt;
 
+use admission::AdmissionController;
+use auth::{self, Authorizer, SharedAuthorizer};
+use capture::{CaptureSampler, RequestCaptures};
+use clock::ClockSkewMonitor;
+use cluster_health::ClusterHealthReport;
 use config_server::ConfigServer;
+use discovery::{resolve_local_addr, ServerDiscovery};
+use event_sink::{EventForwarders, EventSink};
+use existence::{self, ExistenceFilters};
+#[cfg(feature = "grpc")]
+use grpc;
+#[cfg(feature = "grpc")]
+use grpcio;
+use health::{DefaultDeviceHealthProbe, DeviceHealthMonitor};
 use libfrugalos::repair::RepairConfig;
+use lifecycle::{Lifecycle, LifecyclePhase, ShutdownEvent, ShutdownTimers, TerminationSignal};
+use metrics::FrugalosMetricsHandler;
+use operation::{OperationRegistry, OperationStatus};
+use placement::ObjectPlacement;
+use readiness::{HealthzHandler, ReadinessHandler, ReadyzHandler};
 use recovery::prepare_recovery;
+use reload::{restart_required_fields, LogLevel, ReloadOutcome, ReloadSignal, ReloadableConfig};
 use rpc_server::RpcServer;
-use server::{spawn_report_spans_thread, Server};
+use schema;
+use server::{spawn_report_spans_thread, Server, SpanExporter};
 use service;
+use warmup::ConnectionWarmup;
+use watchdog::Watchdog;
+#[cfg(feature = "web-ui")]
+use web_ui::WebUi;
+use workload::WorkloadRecorder;
 use {Error, ErrorKind, FrugalosConfig, FrugalosDaemonConfig, Result};
 
+/// ドレイン中に、実行中のリペアが終わったかどうかを確認する間隔。
+const REPAIR_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Frugalosの各種機能を提供するためのデーモン。
 pub struct FrugalosDaemon {
     logger: Logger,
@@ -43,15 +89,31 @@ pub struct FrugalosDaemon {
     rpc_service: RpcService,
     executor: ThreadPoolExecutor,
     command_rx: mpsc::Receiver<DaemonCommand>,
+    lifecycle: Lifecycle,
+    event_forwarders: EventForwarders,
+    authorizer: SharedAuthorizer,
+    config: FrugalosConfig,
+    config_file: Option<ConfigFile>,
+    log_level: LogLevel,
+    #[cfg(feature = "grpc")]
+    grpc_server: Option<grpcio::Server>,
 }
 impl FrugalosDaemon {
     /// Creates a new `FrugalosDaemon`.
     pub fn new(logger: &Logger, config: FrugalosConfig) -> Result<Self> {
+        // メトリクスの生成前に、ヒストグラムのバケツ等の設定を登録しておく
+        prometheus::set_config(config.prometheus.clone());
+        // サーバ間の RPC を発行・受け付ける前に、クラスタトークンを登録しておく
+        rpc_auth::set_cluster_token(config.auth.cluster_token.clone());
+
         let cloned_config = config.clone();
         let data_dir = config.data_dir;
         let http_addr = config.http_server.bind_addr;
+        // ログレベルは設定の再読み込みで変更できるように、ここで絞り込む
+        let log_level = LogLevel::new(config.loglevel);
         let logger = Logger::root(
-            slog::Duplicate::new(logger.clone(), track!(LogMetrics::new())?).fuse(),
+            slog::Duplicate::new(log_level.filter(logger.clone()), track!(LogMetrics::new())?)
+                .fuse(),
             o!(),
         );
 
@@ -59,7 +121,14 @@ impl FrugalosDaemon {
 
         let server = track!(frugalos_config::cluster::load_local_server_info(&data_dir))?;
 
-        let rpc_addr = server.addr();
+        let rpc_addr = track!(resolve_local_addr(&config.discovery, &server))?;
+        if rpc_addr != server.addr() {
+            info!(
+                logger,
+                "Uses the resolved address instead of the registered one: {}",
+                dump!(rpc_addr, server.addr())
+            );
+        }
         let mut http_server_builder = HttpServerBuilder::new(http_addr);
         http_server_builder.logger(logger.clone());
 
@@ -85,16 +154,59 @@ impl FrugalosDaemon {
             executor.handle(),
         ))?;
 
+        // `sample_all`付きのリクエストの記録中は、全てのリクエストをトレースする
+        let captures = RequestCaptures::new();
         let sampler = Sampler::<SpanContextState>::or(
             PassiveSampler,
             track!(ProbabilisticSampler::new(config.daemon.sampling_rate)
                 .map_err(|e| ErrorKind::InvalidInput.takes_over(e)))?,
-        );
-        let (tracer, span_rx) = rustracing_jaeger::Tracer::new(sampler);
-        spawn_report_spans_thread(span_rx);
+        )
+        .or(CaptureSampler(captures.clone()));
+        let (tracer, span_rx, tail_sampling) = if config.daemon.tail_sampling.enabled {
+            let (tracer, span_rx) = rustracing_jaeger::Tracer::new(TailSampler(sampler));
+            let tail_sampling = TailSampling::new(config.daemon.tail_sampling.clone());
+            (tracer, span_rx, Some(tail_sampling))
+        } else {
+            let (tracer, span_rx) = rustracing_jaeger::Tracer::new(sampler);
+            (tracer, span_rx, None)
+        };
+        let exporter = track!(SpanExporter::new(&config.tracing))?;
+        spawn_report_spans_thread(span_rx, captures.clone(), tail_sampling, exporter);
         let tracer = ThreadLocalTracer::new(tracer);
 
-        let service = track!(service::Service::new(
+        let clock_skew_monitor = track!(ClockSkewMonitor::new(
+            logger.clone(),
+            rpc_service.handle(),
+            server.id.clone(),
+            config.daemon.clock_skew_check_interval,
+            config.daemon.clock_skew_warning_threshold,
+        ))?;
+        let server_discovery = track!(ServerDiscovery::new(logger.clone(), &config.discovery))?;
+        let lifecycle = Lifecycle::new();
+        let connection_warmup = track!(ConnectionWarmup::new(
+            logger.clone(),
+            rpc_service.handle(),
+            server.id.clone(),
+            lifecycle.clone(),
+            &config.daemon.warmup,
+        ))?;
+        let device_health_monitor = if config.device.health_check {
+            let probe = DefaultDeviceHealthProbe::new(&config.device.health_check_command);
+            Some(track!(DeviceHealthMonitor::new(
+                logger.clone(),
+                Arc::new(probe),
+                config.device.health_check_interval,
+                config.device.health_quarantine_threshold,
+            ))?)
+        } else {
+            None
+        };
+        let watchdog = track!(Watchdog::new(logger.clone(), config.watchdog.clone()))?;
+        let watchdog_logger = logger.clone();
+        executor.handle().spawn(watchdog.map_err(move |e| {
+            error!(watchdog_logger, "Watchdog terminated abnormally: {}", e);
+        }));
+        let mut service = track!(service::Service::new(
             logger.clone(),
             executor.handle(),
             raft_service,
@@ -103,28 +215,135 @@ impl FrugalosDaemon {
             rpc_service.handle(),
             config.mds,
             config.segment,
+            config.device,
             recovery_request,
+            clock_skew_monitor,
+            server_discovery,
+            connection_warmup,
+            device_health_monitor,
             tracer.clone(),
         ))?;
+        service.set_repair_config(config.repair.to_repair_config());
+        track!(service.set_rebalancer_config(&config.rebalancer))?;
+        memory::set_budget(config.memory.budget_bytes);
+        cluster_feature::set_enabled_features(config.cluster_features.iter().cloned());
 
         let (command_tx, command_rx) = mpsc::channel();
 
-        let client = service.client();
-        RpcServer::register(
+        let recorder = track!(WorkloadRecorder::from_config(
+            &logger,
+            &config.workload_recorder
+        ))?;
+        let client = service
+            .client()
+            .with_recorder(recorder)
+            .with_existence_filters(ExistenceFilters::default());
+        track!(existence::spawn_updaters(
+            &logger,
+            &client,
+            &config.existence_filter,
+            &executor.handle(),
+        ))?;
+        let event_forwarders = track!(EventForwarders::new(
+            logger.clone(),
+            client.clone(),
+            config.event_sink.clone(),
+        ))?;
+        // 長時間操作は、HTTP と RPC のどちらから開始されたものも同じ API で追跡できるようにする
+        let operations = OperationRegistry::new(config.operation.clone());
+        let authorizer = SharedAuthorizer::new(track!(auth::authorizer_from_config(&config.auth))?);
+        // 受け付けの上限は HTTP と RPC のリクエストの合計に適用する
+        let admission = AdmissionController::new(config.admission.clone());
+        #[cfg_attr(not(feature = "grpc"), allow(unused_variables))]
+        let rpc_server = RpcServer::register(
             client.clone(),
+            server.addr(),
             FrugalosDaemonHandle { command_tx },
             &mut rpc_server_builder,
             tracer.clone(),
+            operations.clone(),
+            authorizer.clone(),
+            admission.clone(),
+            config.operation.archive_dir.clone(),
+        );
+        // gRPC の API は、RPC と同じ認可・受け付け制御の下で処理する
+        #[cfg(feature = "grpc")]
+        let grpc_server = if let Some(addr) = cloned_config.grpc_server.bind_addr {
+            Some(track!(grpc::build(
+                addr,
+                &cloned_config.grpc_server,
+                rpc_server,
+                executor.handle(),
+            ))?)
+        } else {
+            None
+        };
+        #[cfg(not(feature = "grpc"))]
+        track_assert!(
+            cloned_config.grpc_server.bind_addr.is_none(),
+            ErrorKind::InvalidInput,
+            "The gRPC server is not available: frugalos was built without the `grpc` feature"
         );
 
-        let server = Server::new(logger.clone(), cloned_config, client, tracer.clone());
+        let server = track!(Server::new(
+            logger.clone(),
+            cloned_config.clone(),
+            client,
+            tracer.clone(),
+            operations.clone(),
+            captures,
+            authorizer.clone(),
+            admission,
+        ))?;
+        let upload_gc_logger = logger.clone();
+        executor
+            .handle()
+            .spawn(server.upload_gc().map_err(move |e| {
+                error!(upload_gc_logger, "Upload GC terminated abnormally: {}", e);
+            }));
+        let slo_refresher_logger = logger.clone();
+        executor
+            .handle()
+            .spawn(server.slo_refresher().map_err(move |e| {
+                error!(
+                    slo_refresher_logger,
+                    "SLO refresher terminated abnormally: {}", e
+                );
+            }));
+        let operation_runner_logger = logger.clone();
+        executor
+            .handle()
+            .spawn(server.operation_runner().map_err(move |e| {
+                error!(
+                    operation_runner_logger,
+                    "Operation runner terminated abnormally: {}", e
+                );
+            }));
         track!(server.register(&mut http_server_builder))?;
 
-        track!(http_server_builder.add_handler(WithMetrics::new(MetricsHandler)))?;
+        let metrics_handler = track!(FrugalosMetricsHandler::new())?;
+        track!(http_server_builder.add_handler(WithMetrics::new(metrics_handler)))?;
+
+        let readiness_probe =
+            track!(service.readiness_probe(lifecycle.clone(), &cloned_config.daemon.readiness))?;
+        track!(http_server_builder.add_handler(HealthzHandler(lifecycle.clone())))?;
+        track!(http_server_builder.add_handler(ReadyzHandler(readiness_probe.clone())))?;
+        track!(http_server_builder.add_handler(ReadinessHandler(readiness_probe)))?;
 
-        let config_server = ConfigServer::new(rpc_service.handle(), rpc_addr);
+        let config_server = ConfigServer::new(
+            rpc_service.handle(),
+            rpc_addr,
+            authorizer.clone(),
+            operations,
+        );
         track!(config_server.register(&mut http_server_builder))?;
 
+        #[cfg(feature = "web-ui")]
+        track!(
+            WebUi::new(rpc_service.handle(), rpc_addr, authorizer.clone())
+                .register(&mut http_server_builder)
+        )?;
+
         Ok(FrugalosDaemon {
             logger: logger.clone(),
             service,
@@ -133,9 +352,46 @@ impl FrugalosDaemon {
             rpc_service,
             executor,
             command_rx,
+            lifecycle,
+            event_forwarders,
+            authorizer,
+            config: cloned_config,
+            config_file: None,
+            log_level,
+            #[cfg(feature = "grpc")]
+            grpc_server,
         })
     }
 
+    /// 設定の読み込み元のファイルを指定する。
+    ///
+    /// 指定された場合には、SIGHUP の受信時や RPC での要求時に、このファイルから設定を再読み込みする。
+    /// `loaded`はファイルから読み込んだままの(コマンドライン引数で上書きする前の)設定で、
+    /// 再読み込み時に、ファイル側で変更された項目を検出するために使われる。
+    pub fn set_config_file<P: Into<PathBuf>>(&mut self, path: P, loaded: FrugalosConfig) {
+        self.config_file = Some(ConfigFile {
+            path: path.into(),
+            loaded,
+        });
+    }
+
+    /// バケツ内のオブジェクトの変更イベントの転送先を追加する。
+    ///
+    /// 設定ファイルで指定できる組み込みの転送先以外を使う場合に利用する。
+    /// 転送は`run`の呼び出し時に開始される。
+    pub fn add_event_sink<S: EventSink>(&mut self, bucket_id: &str, sink: S) -> Result<()> {
+        track!(self
+            .event_forwarders
+            .add(bucket_id.to_owned(), Box::new(sink)))
+    }
+
+    /// RPC と HTTP の API の認可に使う`Authorizer`を設定する。
+    ///
+    /// 設定ファイルで指定できる組み込みの方式以外で認可を行う場合に利用する。
+    pub fn set_authorizer<A: Authorizer>(&mut self, authorizer: A) {
+        self.authorizer.set(Arc::new(authorizer));
+    }
+
     fn register_prometheus_metrics(&self) -> Result<()> {
         prometrics::default_registry()
             .register(prometrics::metrics::ProcessMetricsCollector::new());
@@ -168,10 +424,33 @@ impl FrugalosDaemon {
     /// この呼び出しはブロッキングするので注意。
     pub fn run(mut self, config: FrugalosDaemonConfig) -> Result<()> {
         track!(self.register_prometheus_metrics())?;
+        self.event_forwarders.spawn(&self.executor.handle());
 
+        let termination_signal = if config.graceful_shutdown_on_sigterm {
+            info!(self.logger, "Graceful shutdown on SIGTERM is enabled");
+            Some(TerminationSignal::install())
+        } else {
+            None
+        };
+        let reload_signal = if self.config_file.is_some() {
+            Some(ReloadSignal::install())
+        } else {
+            None
+        };
+        self.config.daemon = config.clone();
+        // gRPC サーバは`run`から戻る(executor が停止する)まで保持する
+        #[cfg(feature = "grpc")]
+        let _grpc_server = self.grpc_server.map(|mut server| {
+            server.start();
+            server
+        });
         let runner = DaemonRunner {
             logger: self.logger.clone(),
             config,
+            frugalos_config: self.config,
+            config_file: self.config_file,
+            log_level: self.log_level,
+            reload_signal,
             service: self.service,
             rpc_server: self.rpc_server_builder.finish(self.executor.handle()),
             http_server: StoppableHttpServer::new(
@@ -181,6 +460,8 @@ impl FrugalosDaemon {
             command_rx: self.command_rx,
             stop_notifications: Vec::new(),
             do_stop: false,
+            lifecycle: self.lifecycle,
+            shutdown: ShutdownTimers::new(termination_signal),
         };
 
         let monitor = self.executor.handle().spawn_monitor(runner);
@@ -189,9 +470,21 @@ impl FrugalosDaemon {
     }
 }
 
+/// 設定の読み込み元のファイルと、最後に読み込んだ内容。
+#[derive(Debug)]
+struct ConfigFile {
+    path: PathBuf,
+    loaded: FrugalosConfig,
+}
+
 struct DaemonRunner {
     logger: Logger,
     config: FrugalosDaemonConfig,
+    // コマンドライン引数による上書きを含む、現在の設定
+    frugalos_config: FrugalosConfig,
+    config_file: Option<ConfigFile>,
+    log_level: LogLevel,
+    reload_signal: Option<ReloadSignal>,
     service: service::Service<ThreadPoolExecutorHandle>,
     http_server: StoppableHttpServer,
     rpc_server: fibers_rpc::server::Server<ThreadPoolExecutorHandle>,
@@ -199,30 +492,178 @@ struct DaemonRunner {
     command_rx: mpsc::Receiver<DaemonCommand>,
     stop_notifications: Vec<oneshot::Monitored<(), Error>>,
     do_stop: bool,
+    lifecycle: Lifecycle,
+    shutdown: ShutdownTimers,
 }
 impl DaemonRunner {
     fn handle_command(&mut self, command: DaemonCommand) {
         match command {
             DaemonCommand::StopDaemon { reply } => {
-                info!(
-                    self.logger,
-                    "Begins stopping and waits for a while({:?})", self.config.stop_waiting_time
-                );
-                self.http_server.stop(self.config.stop_waiting_time);
-                self.service.stop();
+                self.start_stopping();
+                self.stop_notifications.push(reply);
+            }
+            DaemonCommand::DrainDaemon { reply } => {
+                self.start_draining();
                 self.stop_notifications.push(reply);
             }
             DaemonCommand::TakeSnapshot => {
                 self.service.take_snapshot();
             }
+            DaemonCommand::ReloadConfig { reply } => {
+                reply.exit(track!(self.reload_config()));
+            }
         }
     }
+
+    /// 設定ファイルを読み込み直して、実行中に反映可能な項目を適用する。
+    fn reload_config(&mut self) -> Result<ReloadOutcome> {
+        let config_file = track_assert_some!(
+            self.config_file.as_mut(),
+            ErrorKind::InvalidInput,
+            "The daemon was started without a configuration file"
+        );
+        let path = config_file.path.clone();
+        let (new_config, warnings) = track!(FrugalosConfig::from_yaml(&path))?;
+        for warning in warnings {
+            warn!(self.logger, "{}", warning);
+        }
+
+        // 設定ファイル上で変更された項目だけを、現在の設定に反映する
+        let previous = ReloadableConfig::from_config(&config_file.loaded);
+        let loaded = ReloadableConfig::from_config(&new_config);
+        let current = ReloadableConfig::from_config(&self.frugalos_config);
+        let reloadable = current.merge_changes(&previous, &loaded);
+        let mut outcome = ReloadOutcome {
+            changed: previous.changed_fields(&loaded),
+            restart_required: restart_required_fields(&config_file.loaded, &new_config),
+        };
+        config_file.loaded = new_config;
+
+        if current.loglevel != reloadable.loglevel && !self.log_level.set(reloadable.loglevel) {
+            // 起動時よりも詳細なログは、ロガー自体が捨ててしまう
+            outcome.changed.retain(|f| f != "loglevel");
+            outcome.restart_required.push("loglevel".to_owned());
+        }
+        // ドレイン中に止めたリペアを再開させないようにする
+        let draining = match self.lifecycle.phase() {
+            LifecyclePhase::Draining | LifecyclePhase::Stopping => true,
+            LifecyclePhase::WarmingUp | LifecyclePhase::Running => false,
+        };
+        if current.repair != reloadable.repair && !draining {
+            self.service
+                .set_repair_config(reloadable.repair.to_repair_config());
+        }
+        if current.memory != reloadable.memory {
+            memory::set_budget(reloadable.memory.budget_bytes);
+        }
+        if current.cluster_features != reloadable.cluster_features {
+            cluster_feature::set_enabled_features(reloadable.cluster_features.iter().cloned());
+        }
+        if current.prometheus != reloadable.prometheus {
+            prometheus::set_config(reloadable.prometheus.clone());
+        }
+        self.config.stop_waiting_time = reloadable.stop_waiting_time;
+        self.config.leadership_drain_time = reloadable.leadership_drain_time;
+        self.config.shutdown_grace_period = reloadable.shutdown_grace_period;
+        reloadable.apply_to(&mut self.frugalos_config);
+
+        info!(
+            self.logger,
+            "Reloaded the configuration file: path={:?}, changed={:?}, restart_required={:?}",
+            path,
+            outcome.changed,
+            outcome.restart_required
+        );
+        Ok(outcome)
+    }
+
+    /// graceful shutdown を開始する.
+    ///
+    /// readiness を not-ready にして、リーダ権を他のサーバに譲り、新たなリペアの開始を止める.
+    /// 実際の停止処理は `leadership_drain_time` が経過し、かつ、実行中のリペアが全て終わってから開始される.
+    fn start_draining(&mut self) {
+        match self.lifecycle.phase() {
+            LifecyclePhase::WarmingUp | LifecyclePhase::Running => {}
+            LifecyclePhase::Draining | LifecyclePhase::Stopping => return,
+        }
+        info!(
+            self.logger,
+            "Begins draining leaderships and waits for a while({:?})",
+            self.config.leadership_drain_time
+        );
+        self.lifecycle.set_phase(LifecyclePhase::Draining);
+        self.service.drain();
+        self.shutdown.start_draining(
+            self.config.leadership_drain_time,
+            self.config.shutdown_grace_period,
+        );
+    }
+
+    fn start_stopping(&mut self) {
+        if self.lifecycle.phase() == LifecyclePhase::Stopping {
+            return;
+        }
+        info!(
+            self.logger,
+            "Begins stopping and waits for a while({:?})", self.config.stop_waiting_time
+        );
+        self.lifecycle.set_phase(LifecyclePhase::Stopping);
+        self.shutdown.cancel_draining();
+        self.http_server.stop(self.config.stop_waiting_time);
+        self.service.stop();
+    }
 }
 impl Future for DaemonRunner {
     type Item = ();
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(Some(event)) = track!(self.shutdown.poll())? {
+            match event {
+                ShutdownEvent::Terminated => {
+                    info!(self.logger, "Received SIGTERM");
+                    self.start_draining();
+                }
+                ShutdownEvent::Drained => {
+                    let repairs = self.service.repairs_in_flight();
+                    if repairs == 0 {
+                        self.start_stopping();
+                    } else {
+                        info!(
+                            self.logger,
+                            "Waits for {} in-flight repairs to finish before stopping", repairs
+                        );
+                        self.shutdown.postpone_drained(REPAIR_CHECK_INTERVAL);
+                    }
+                }
+                ShutdownEvent::GracePeriodExpired => {
+                    warn!(
+                        self.logger,
+                        "The shutdown grace period({:?}) has expired; exits forcibly",
+                        self.config.shutdown_grace_period
+                    );
+                    for reply in self.stop_notifications.drain(..) {
+                        reply.exit(Ok(()));
+                    }
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+        while let Some(signal) = self.reload_signal.as_mut() {
+            if track!(signal.poll())?.is_not_ready() {
+                break;
+            }
+            info!(
+                self.logger,
+                "Received SIGHUP; reloads the configuration file"
+            );
+            if let Err(e) = track!(self.reload_config()) {
+                error!(
+                    self.logger,
+                    "Failed to reload the configuration file: {}", e
+                );
+            }
+        }
         let do_stop = track!(self.http_server.poll())?.is_ready() && self.do_stop;
         if do_stop {
             return Ok(Async::Ready(()));
@@ -257,11 +698,45 @@ impl FrugalosDaemonHandle {
         StopDaemon(reply_rx)
     }
 
+    /// ドレインした上で停止する。
+    ///
+    /// SIGTERM による graceful shutdown と同様に、リーダ権の移譲と実行中のリペアの完了を待ってから停止する。
+    pub fn drain(&self) -> impl Future<Item = (), Error = Error> {
+        let (reply_tx, reply_rx) = oneshot::monitor();
+        let command = DaemonCommand::DrainDaemon { reply: reply_tx };
+        let _ = self.command_tx.send(command);
+        StopDaemon(reply_rx)
+    }
+
     /// スナップショット取得を依頼する。
     pub fn take_snapshot(&self) {
         let command = DaemonCommand::TakeSnapshot;
         let _ = self.command_tx.send(command);
     }
+
+    /// 設定ファイルの再読み込みを依頼する。
+    pub fn reload_config(&self) -> impl Future<Item = ReloadOutcome, Error = Error> {
+        let (reply_tx, reply_rx) = oneshot::monitor();
+        let command = DaemonCommand::ReloadConfig { reply: reply_tx };
+        let _ = self.command_tx.send(command);
+        reply_rx.map_err(|e| {
+            e.unwrap_or_else(|| {
+                ErrorKind::Other
+                    .cause("Monitoring channel disconnected")
+                    .into()
+            })
+        })
+    }
+}
+#[cfg(test)]
+impl FrugalosDaemonHandle {
+    /// テスト用に、どのデーモンにも接続されていないハンドルを生成する。
+    ///
+    /// 送られたコマンドは全て破棄される。
+    pub(crate) fn detached() -> Self {
+        let (command_tx, _) = mpsc::channel();
+        FrugalosDaemonHandle { command_tx }
+    }
 }
 
 #[derive(Debug)]
@@ -269,7 +744,13 @@ enum DaemonCommand {
     StopDaemon {
         reply: oneshot::Monitored<(), Error>,
     },
+    DrainDaemon {
+        reply: oneshot::Monitored<(), Error>,
+    },
     TakeSnapshot,
+    ReloadConfig {
+        reply: oneshot::Monitored<ReloadOutcome, Error>,
+    },
 }
 
 #[derive(Debug)]
@@ -370,20 +851,20 @@ impl Drain for LogMetrics {
 pub fn stop(logger: &Logger, rpc_addr: SocketAddr) -> Result<()> {
     info!(logger, "Starts stopping the frugalos server");
 
-    let mut executor = track!(ThreadPoolExecutor::with_thread_count(1).map_err(Error::from))?;
-    let rpc_service = RpcServiceBuilder::new()
-        .logger(logger.clone())
-        .finish(executor.handle());
-    let rpc_service_handle = rpc_service.handle();
-    executor.spawn(rpc_service.map_err(|e| panic!("{}", e)));
+    track!(call_rpc::<libfrugalos::schema::frugalos::StopRpc, _>(
+        logger,
+        rpc_addr,
+        ()
+    ))?;
 
-    let client = libfrugalos::client::frugalos::Client::new(rpc_addr, rpc_service_handle);
-    let fiber = executor.spawn_monitor(client.stop());
-    track!(executor
-        .run_fiber(fiber)
-        .unwrap()
-        .map_err(|e| e.unwrap_or_else(|| panic!("monitoring channel disconnected"))))?;
+    info!(logger, "The frugalos server has stopped");
+    Ok(())
+}
 
+/// 指定されたアドレスを使用しているfrugalosプロセスを、ドレインしてから停止させる。
+pub fn drain(logger: &Logger, rpc_addr: SocketAddr) -> Result<()> {
+    info!(logger, "Starts draining the frugalos server");
+    track!(call_rpc::<schema::DrainDaemonRpc, _>(logger, rpc_addr, ()))?;
     info!(logger, "The frugalos server has stopped");
     Ok(())
 }
@@ -392,19 +873,7 @@ pub fn stop(logger: &Logger, rpc_addr: SocketAddr) -> Result<()> {
 pub fn take_snapshot(logger: &Logger, rpc_addr: SocketAddr) -> Result<()> {
     info!(logger, "Starts taking snapshot");
 
-    let mut executor = track!(ThreadPoolExecutor::with_thread_count(1).map_err(Error::from))?;
-    let rpc_service = RpcServiceBuilder::new()
-        .logger(logger.clone())
-        .finish(executor.handle());
-    let rpc_service_handle = rpc_service.handle();
-    executor.spawn(rpc_service.map_err(|e| panic!("{}", e)));
-
-    let client = libfrugalos::client::frugalos::Client::new(rpc_addr, rpc_service_handle);
-    let fiber = executor.spawn_monitor(client.take_snapshot());
-    track!(executor
-        .run_fiber(fiber)
-        .unwrap()
-        .map_err(|e| e.unwrap_or_else(|| panic!("monitoring channel disconnected"))))?;
+    track!(call_rpc::<libfrugalos::schema::frugalos::TakeSnapshotRpc, _>(logger, rpc_addr, ()))?;
 
     info!(logger, "The frugalos server has taken snapshot");
     Ok(())
@@ -418,6 +887,372 @@ pub fn set_repair_config(
 ) -> Result<()> {
     info!(logger, "Starts setting repair_idleness_threshold");
 
+    track!(call_rpc::<
+        libfrugalos::schema::frugalos::SetRepairConfigRpc,
+        _,
+    >(logger, rpc_addr, repair_config))?;
+
+    info!(
+        logger,
+        "The frugalos server has set repair_idleness_threshold"
+    );
+    Ok(())
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスから、各ノードの segment_gc の状態を取得する。
+pub fn get_segment_gc_status(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+) -> Result<Vec<SegmentGcStatus>> {
+    track!(call_segment_gc_rpc::<
+        segment_schema::GetSegmentGcStatusRpc,
+        _,
+    >(logger, rpc_addr))
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスで segment_gc を開始する。
+///
+/// 新たに segment_gc を開始したノードの ID のリストを返す。
+pub fn start_segment_gc(logger: &Logger, rpc_addr: SocketAddr) -> Result<Vec<String>> {
+    let nodes = track!(call_segment_gc_rpc::<segment_schema::StartSegmentGcRpc, _>(
+        logger, rpc_addr
+    ))?;
+    info!(
+        logger,
+        "The frugalos server has started segment_gc: nodes={:?}", nodes
+    );
+    Ok(nodes)
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスで実行中の segment_gc を中断する。
+///
+/// segment_gc を中断したノードの ID のリストを返す。
+pub fn stop_segment_gc(logger: &Logger, rpc_addr: SocketAddr) -> Result<Vec<String>> {
+    let nodes = track!(call_segment_gc_rpc::<segment_schema::StopSegmentGcRpc, _>(
+        logger, rpc_addr
+    ))?;
+    info!(
+        logger,
+        "The frugalos server has stopped segment_gc: nodes={:?}", nodes
+    );
+    Ok(nodes)
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスから、各ノードの状態(`Synchronizer`のキューの長さ等)を取得する。
+pub fn get_segment_node_statuses(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+) -> Result<Vec<SegmentNodeStatus>> {
+    track!(call_rpc::<segment_schema::GetSegmentNodeStatusesRpc, _>(
+        logger,
+        rpc_addr,
+        ()
+    ))
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスを経由して、オブジェクトを保持する全ノードで即座にリペアを行う。
+///
+/// オブジェクトが存在しない場合には`None`を返す。
+pub fn repair_object(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+    bucket_id: &str,
+    object_id: &str,
+) -> Result<Option<ObjectRepairSummary>> {
+    let request = (bucket_id.to_owned(), object_id.to_owned());
+    let summary = track!(call_rpc::<schema::RepairObjectRpc, _>(
+        logger, rpc_addr, request
+    ))?;
+    let logger = logger.new(o!(
+        logging::BUCKET => bucket_id.to_owned(),
+        logging::OBJECT_ID => object_id.to_owned()
+    ));
+    if let Some(ref summary) = summary {
+        for node in &summary.nodes {
+            if let Some(outcome) = node.outcome {
+                info!(
+                    logger,
+                    "Repair finished: {}",
+                    dump!(summary.version, outcome);
+                    "node" => &node.node
+                );
+            } else {
+                warn!(
+                    logger,
+                    "Repair failed: {}",
+                    dump!(summary.version, node.error);
+                    "node" => &node.node
+                );
+            }
+        }
+    } else {
+        info!(logger, "The object does not exist: nothing to repair");
+    }
+    Ok(summary)
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスで、バケツ内の全オブジェクトの書き出しを開始する。
+///
+/// `path`は、そのプロセスが動作しているサーバ上の、`operation.archive_dir`からの相対パス。
+/// 開始された長時間操作の ID を返す。
+pub fn export_bucket(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+    bucket_id: &str,
+    path: &Path,
+) -> Result<String> {
+    let request = (bucket_id.to_owned(), path.to_string_lossy().into_owned());
+    let operation_id = track!(call_rpc::<schema::ExportBucketRpc, _>(
+        logger, rpc_addr, request
+    ))?;
+    info!(
+        logger,
+        "The frugalos server has started exporting the bucket: operation_id={:?}", operation_id
+    );
+    Ok(operation_id)
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスで、`export_bucket`で書き出したファイルからの復元を開始する。
+///
+/// `path`は、そのプロセスが動作しているサーバ上の、`operation.archive_dir`からの相対パス。
+/// 開始された長時間操作の ID を返す。
+pub fn import_bucket(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+    bucket_id: &str,
+    path: &Path,
+) -> Result<String> {
+    let request = (bucket_id.to_owned(), path.to_string_lossy().into_owned());
+    let operation_id = track!(call_rpc::<schema::ImportBucketRpc, _>(
+        logger, rpc_addr, request
+    ))?;
+    info!(
+        logger,
+        "The frugalos server has started importing the bucket: operation_id={:?}", operation_id
+    );
+    Ok(operation_id)
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスから、長時間操作の状態を取得する。
+pub fn get_operation(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+    operation_id: &str,
+) -> Result<OperationStatus> {
+    track!(call_rpc::<schema::GetOperationRpc, _>(
+        logger,
+        rpc_addr,
+        operation_id.to_owned()
+    ))
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスから、バケツの機能フラグの現在の値を取得する。
+pub fn get_feature_flags(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+    bucket_id: &str,
+) -> Result<FeatureFlagSet> {
+    track!(call_rpc::<schema::GetFeatureFlagsRpc, _>(
+        logger,
+        rpc_addr,
+        bucket_id.to_owned()
+    ))
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスで、バケツの機能フラグを切り替える。
+///
+/// 変更はそのプロセスにのみ適用されるので、クラスタ全体で切り替える場合には各サーバに対して実行すること。
+pub fn set_feature_flag(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+    bucket_id: &str,
+    feature: Feature,
+    enabled: bool,
+) -> Result<FeatureFlagSet> {
+    let request = (bucket_id.to_owned(), feature, enabled);
+    let flags = track!(call_rpc::<schema::SetFeatureFlagRpc, _>(
+        logger, rpc_addr, request
+    ))?;
+    info!(
+        logger,
+        "The feature flag has been changed: {}",
+        dump!(feature, enabled);
+        logging::BUCKET => bucket_id
+    );
+    Ok(flags)
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスで、設定ファイルを再読み込みする。
+pub fn reload_config(logger: &Logger, rpc_addr: SocketAddr) -> Result<ReloadOutcome> {
+    let outcome = track!(call_rpc::<schema::ReloadConfigRpc, _>(logger, rpc_addr, ()))?;
+    info!(
+        logger,
+        "The frugalos server has reloaded the configuration file: {:?}", outcome
+    );
+    Ok(outcome)
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセス上にメンバを持つセグメントのうち、劣化しているものを取得する。
+///
+/// リペアの積み残しが`repair_backlog_threshold`を超えるメンバを持つセグメントも、劣化しているものとみなされる。
+pub fn get_cluster_health(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+    repair_backlog_threshold: usize,
+) -> Result<ClusterHealthReport> {
+    track!(call_rpc::<schema::GetClusterHealthRpc, _>(
+        logger,
+        rpc_addr,
+        repair_backlog_threshold
+    ))
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセス上の、バケツの内容のキャッシュを破棄する。
+///
+/// 結果は破棄したオブジェクトの数。
+pub fn flush_content_cache(logger: &Logger, rpc_addr: SocketAddr, bucket_id: &str) -> Result<u64> {
+    let objects = track!(call_rpc::<schema::FlushContentCacheRpc, _>(
+        logger,
+        rpc_addr,
+        bucket_id.to_owned()
+    ))?;
+    info!(
+        logger,
+        "The frugalos server has flushed the content cache: {}",
+        dump!(objects);
+        logging::BUCKET => bucket_id
+    );
+    Ok(objects)
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスから、オブジェクトの内容を取得する。
+pub fn get_object(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+    bucket_id: &str,
+    object_id: &str,
+    deadline: Duration,
+    expect: Expect,
+    consistency: ReadConsistency,
+) -> Result<Option<(ObjectVersion, Vec<u8>)>> {
+    let (bucket_id, object_id) = (bucket_id.to_owned(), object_id.to_owned());
+    track!(call_client(logger, rpc_addr, move |client| {
+        client.get_object(bucket_id, object_id, deadline, expect, consistency)
+    }))
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスから、オブジェクトのバージョンを取得する。
+///
+/// `check_storage`が`true`の場合には、MDS だけではなくストレージ上の存在も確認する。
+#[allow(clippy::too_many_arguments)]
+pub fn head_object(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+    bucket_id: &str,
+    object_id: &str,
+    deadline: Duration,
+    expect: Expect,
+    consistency: ReadConsistency,
+    check_storage: bool,
+) -> Result<Option<ObjectVersion>> {
+    let (bucket_id, object_id) = (bucket_id.to_owned(), object_id.to_owned());
+    track!(call_client(logger, rpc_addr, move |client| {
+        client.head_object(
+            bucket_id,
+            object_id,
+            deadline,
+            expect,
+            consistency,
+            check_storage,
+        )
+    }))
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスで、オブジェクトを保存する。
+///
+/// 保存されたバージョンと、新規に作成されたかどうかを返す。
+pub fn put_object(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+    bucket_id: &str,
+    object_id: &str,
+    content: Vec<u8>,
+    deadline: Duration,
+    expect: Expect,
+) -> Result<(ObjectVersion, bool)> {
+    let (bucket_id, object_id) = (bucket_id.to_owned(), object_id.to_owned());
+    track!(call_client(logger, rpc_addr, move |client| {
+        client.put_object(bucket_id, object_id, content, deadline, expect)
+    }))
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスで、オブジェクトを削除する。
+///
+/// 削除されたオブジェクトのバージョンを返す。
+pub fn delete_object(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+    bucket_id: &str,
+    object_id: &str,
+    deadline: Duration,
+    expect: Expect,
+) -> Result<Option<ObjectVersion>> {
+    let (bucket_id, object_id) = (bucket_id.to_owned(), object_id.to_owned());
+    track!(call_client(logger, rpc_addr, move |client| {
+        client.delete_object(bucket_id, object_id, deadline, expect)
+    }))
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスから、セグメント内のオブジェクトの一覧を取得する。
+pub fn list_objects(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+    bucket_id: &str,
+    segment: u16,
+) -> Result<Vec<ObjectSummary>> {
+    let bucket_id = bucket_id.to_owned();
+    track!(call_client(logger, rpc_addr, move |client| {
+        client.list_objects(bucket_id, segment)
+    }))
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスで、オブジェクトの断片の配置状況を監査する。
+///
+/// オブジェクトが存在しない場合には`None`を返す。
+pub fn verify_object(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+    bucket_id: &str,
+    object_id: &str,
+) -> Result<Option<ObjectAuditReport>> {
+    let request = (bucket_id.to_owned(), object_id.to_owned());
+    track!(call_rpc::<schema::VerifyObjectRpc, _>(
+        logger, rpc_addr, request
+    ))
+}
+
+/// 指定されたアドレスを使用しているfrugalosプロセスに、オブジェクトの配置を問い合わせる。
+///
+/// `consistency`はオブジェクトのバージョンの取得時に使われる(`None`の場合はバケツのデフォルト値)。
+pub fn get_object_placement(
+    logger: &Logger,
+    rpc_addr: SocketAddr,
+    bucket_id: &str,
+    object_id: &str,
+    consistency: Option<ReadConsistency>,
+) -> Result<ObjectPlacement> {
+    let request = (bucket_id.to_owned(), object_id.to_owned(), consistency);
+    track!(call_rpc::<schema::GetObjectPlacementRpc, _>(
+        logger, rpc_addr, request
+    ))
+}
+
+fn call_client<F, T, V>(logger: &Logger, rpc_addr: SocketAddr, f: F) -> Result<V>
+where
+    F: FnOnce(&libfrugalos::client::frugalos::Client) -> T,
+    T: Future<Item = V, Error = libfrugalos::Error> + Send + 'static,
+    V: Send + 'static,
+{
     let mut executor = track!(ThreadPoolExecutor::with_thread_count(1).map_err(Error::from))?;
     let rpc_service = RpcServiceBuilder::new()
         .logger(logger.clone())
@@ -426,15 +1261,49 @@ pub fn set_repair_config(
     executor.spawn(rpc_service.map_err(|e| panic!("{}", e)));
 
     let client = libfrugalos::client::frugalos::Client::new(rpc_addr, rpc_service_handle);
-    let fiber = executor.spawn_monitor(client.set_repair_config(repair_config));
-    track!(executor
+    let fiber = executor.spawn_monitor(f(&client).map_err(Error::from));
+    let value = track!(executor
         .run_fiber(fiber)
         .unwrap()
         .map_err(|e| e.unwrap_or_else(|| panic!("monitoring channel disconnected"))))?;
+    Ok(value)
+}
 
-    info!(
-        logger,
-        "The frugalos server has set repair_idleness_threshold"
-    );
-    Ok(())
+fn call_segment_gc_rpc<T, V>(logger: &Logger, rpc_addr: SocketAddr) -> Result<V>
+where
+    T: Call<Req = (), Res = libfrugalos::Result<V>>,
+    T::ReqEncoder: Default,
+    T::ResDecoder: Default,
+    V: Send + 'static,
+{
+    track!(call_rpc::<T, V>(logger, rpc_addr, ()))
+}
+
+fn call_rpc<T, V>(logger: &Logger, rpc_addr: SocketAddr, request: T::Req) -> Result<V>
+where
+    T: Call<Res = libfrugalos::Result<V>>,
+    T::Req: Serialize + DeserializeOwned + Send + 'static,
+    T::ReqEncoder: Default,
+    T::ResDecoder: Default,
+    V: Send + 'static,
+{
+    info!(logger, "Calls {}", T::NAME);
+
+    let mut executor = track!(ThreadPoolExecutor::with_thread_count(1).map_err(Error::from))?;
+    let rpc_service = RpcServiceBuilder::new()
+        .logger(logger.clone())
+        .finish(executor.handle());
+    let rpc_service_handle = rpc_service.handle();
+    executor.spawn(rpc_service.map_err(|e| panic!("{}", e)));
+
+    // 管理用の RPC も受け付けられるように、クラスタトークンが設定されていればそれを付けて呼び出す
+    let future = rpc_auth::call::<T>(&rpc_service_handle, rpc_addr, request)
+        .map_err(Error::from)
+        .and_then(|result| result.map_err(Error::from));
+    let fiber = executor.spawn_monitor(future);
+    let value = track!(executor
+        .run_fiber(fiber)
+        .unwrap()
+        .map_err(|e| e.unwrap_or_else(|| panic!("monitoring channel disconnected"))))?;
+    Ok(value)
 }
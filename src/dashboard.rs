@@ -0,0 +1,418 @@
+//! 簡易な UI 向けに、バケツ毎の主要な指標を集計するためのモジュール。
+//!
+//! Prometheus 等を用意しなくてもクラスタの概況を把握できるように、
+//! HTTP のオブジェクト操作のリクエストを直近`WINDOW_SECONDS`秒分だけ保持しておき、
+//! 操作毎のスループットやエラー率、99パーセンタイルのレイテンシをサーバ側で算出する。
+//!
+//! 算出結果は`GET /v1/dashboard`で取得できる。
+use fibers_http_server::{HandleRequest, Req, Res};
+use futures::{Async, Future, Poll};
+use prometrics::metric::Metrics;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use http::{OperationStatistics, RepairBacklog};
+
+/// 指標の集計対象となる期間(秒)。
+pub const WINDOW_SECONDS: u64 = 60;
+
+/// レイテンシのヒストグラムのバケツの上限値(ミリ秒)。
+///
+/// 最後の上限値を超えたリクエストは、スロット内での最大値で代表させる。
+const LATENCY_BOUNDS_MILLIS: &[u64] = &[
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000, 10_000,
+];
+
+/// 集計対象となるオブジェクト操作。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Get,
+    Head,
+    Put,
+    Delete,
+    Append,
+}
+impl Operation {
+    const ALL: &'static [Operation] = &[
+        Operation::Get,
+        Operation::Head,
+        Operation::Put,
+        Operation::Delete,
+        Operation::Append,
+    ];
+
+    /// オブジェクトを対象とするハンドラの HTTP メソッドから、操作の種類を判定する。
+    fn from_method(method: &str) -> Option<Self> {
+        match method {
+            "GET" => Some(Operation::Get),
+            "HEAD" => Some(Operation::Head),
+            "PUT" => Some(Operation::Put),
+            "DELETE" => Some(Operation::Delete),
+            "POST" => Some(Operation::Append),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Operation::Get => "get",
+            Operation::Head => "head",
+            Operation::Put => "put",
+            Operation::Delete => "delete",
+            Operation::Append => "append",
+        }
+    }
+}
+
+/// 一秒分の集計結果。
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Slot {
+    index: u64,
+    requests: u64,
+    errors: u64,
+    histogram: [u64; LATENCY_BOUNDS_MILLIS.len() + 1],
+    max: Duration,
+}
+impl Slot {
+    fn new(index: u64) -> Self {
+        Slot {
+            index,
+            requests: 0,
+            errors: 0,
+            histogram: [0; LATENCY_BOUNDS_MILLIS.len() + 1],
+            max: Duration::from_secs(0),
+        }
+    }
+}
+
+/// バケツと操作の組毎の、直近の集計結果。
+#[derive(Debug, Default)]
+struct Series {
+    slots: VecDeque<Slot>,
+}
+impl Series {
+    fn record(&mut self, now: Duration, elapsed: Duration, status: u16) {
+        let index = now.as_secs();
+        self.expire(index);
+        if self.slots.back().is_none_or(|s| s.index < index) {
+            self.slots.push_back(Slot::new(index));
+        }
+
+        let slot = self.slots.back_mut().expect("Never fails");
+        slot.requests += 1;
+        if status >= 500 {
+            slot.errors += 1;
+        }
+        let millis = elapsed.as_millis() as u64;
+        let i = LATENCY_BOUNDS_MILLIS
+            .iter()
+            .position(|&bound| millis <= bound)
+            .unwrap_or(LATENCY_BOUNDS_MILLIS.len());
+        slot.histogram[i] += 1;
+        slot.max = cmp::max(slot.max, elapsed);
+    }
+
+    /// 集計対象の期間から外れたスロットを捨てる。
+    fn expire(&mut self, current: u64) {
+        while self
+            .slots
+            .front()
+            .is_some_and(|s| s.index + WINDOW_SECONDS <= current)
+        {
+            self.slots.pop_front();
+        }
+    }
+
+    fn statistics(&mut self, now: Duration) -> OperationStatistics {
+        self.expire(now.as_secs());
+
+        let mut requests = 0;
+        let mut errors = 0;
+        let mut histogram = [0; LATENCY_BOUNDS_MILLIS.len() + 1];
+        let mut max = Duration::from_secs(0);
+        for slot in &self.slots {
+            requests += slot.requests;
+            errors += slot.errors;
+            for (x, y) in histogram.iter_mut().zip(slot.histogram.iter()) {
+                *x += y;
+            }
+            max = cmp::max(max, slot.max);
+        }
+        if requests == 0 {
+            return OperationStatistics::default();
+        }
+
+        // 起動直後は、経過時間で割る
+        let seconds = cmp::min(WINDOW_SECONDS, now.as_secs() + 1);
+        OperationStatistics {
+            qps: requests as f64 / seconds as f64,
+            error_rate: errors as f64 / requests as f64,
+            p99_millis: Some(percentile(&histogram, requests, 0.99, max)),
+        }
+    }
+}
+
+/// ヒストグラムから、`q`分位点を含むバケツの上限値(ミリ秒)を求める。
+fn percentile(histogram: &[u64], total: u64, q: f64, max: Duration) -> u64 {
+    let rank = cmp::max(1, (total as f64 * q).ceil() as u64);
+    let mut count = 0;
+    for (i, n) in histogram.iter().enumerate() {
+        count += n;
+        if count >= rank {
+            if let Some(&bound) = LATENCY_BOUNDS_MILLIS.get(i) {
+                return bound;
+            }
+            break;
+        }
+    }
+    max.as_millis() as u64
+}
+
+type SeriesMap = HashMap<(String, Operation), Series>;
+
+/// ダッシュボード用に、オブジェクト操作のリクエストを記録するトラッカー。
+#[derive(Clone)]
+pub struct DashboardTracker {
+    started_at: Instant,
+    series: Arc<Mutex<SeriesMap>>,
+}
+impl DashboardTracker {
+    /// 新しい`DashboardTracker`インスタンスを生成する。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// バケツ`bucket_id`に対するリクエストの結果を記録する。
+    pub fn record(&self, bucket_id: &str, op: Operation, elapsed: Duration, status: u16) {
+        self.record_at(self.started_at.elapsed(), bucket_id, op, elapsed, status);
+    }
+
+    fn record_at(
+        &self,
+        now: Duration,
+        bucket_id: &str,
+        op: Operation,
+        elapsed: Duration,
+        status: u16,
+    ) {
+        if let Ok(mut series) = self.series.lock() {
+            series
+                .entry((bucket_id.to_owned(), op))
+                .or_default()
+                .record(now, elapsed, status);
+        }
+    }
+
+    /// バケツ`bucket_id`の、操作毎の指標を返す。
+    ///
+    /// リクエストがなかった操作についても、値が 0 の指標が含まれる。
+    pub fn statistics(&self, bucket_id: &str) -> BTreeMap<&'static str, OperationStatistics> {
+        self.statistics_at(self.started_at.elapsed(), bucket_id)
+    }
+
+    fn statistics_at(
+        &self,
+        now: Duration,
+        bucket_id: &str,
+    ) -> BTreeMap<&'static str, OperationStatistics> {
+        let mut series = self.series.lock().unwrap_or_else(|e| panic!("{}", e));
+
+        // 存在しないバケツへのリクエスト等で、系列が増え続けないようにする
+        for s in series.values_mut() {
+            s.expire(now.as_secs());
+        }
+        series.retain(|_, s| !s.slots.is_empty());
+
+        Operation::ALL
+            .iter()
+            .map(|&op| {
+                let statistics = series
+                    .get_mut(&(bucket_id.to_owned(), op))
+                    .map(|s| s.statistics(now))
+                    .unwrap_or_default();
+                (op.as_str(), statistics)
+            })
+            .collect()
+    }
+}
+impl Default for DashboardTracker {
+    fn default() -> Self {
+        DashboardTracker {
+            started_at: Instant::now(),
+            series: Arc::default(),
+        }
+    }
+}
+
+/// このノード上のリペアキューの長さを、メトリクスから取得する。
+pub fn repair_backlog() -> RepairBacklog {
+    let mut backlog = RepairBacklog::default();
+    let families = prometrics::default_gatherer()
+        .lock()
+        .unwrap_or_else(|e| panic!("{}", e))
+        .gather();
+    for family in families {
+        if family.name().to_string() != "frugalos_synchronizer_queue_length" {
+            continue;
+        }
+        if let Metrics::Gauge(ref gauges) = *family.metrics() {
+            for gauge in gauges {
+                let value = gauge.value() as u64;
+                match gauge.labels().get("type").map(|l| l.value()) {
+                    Some("repair") => backlog.repair += value,
+                    Some("repair_prep") => backlog.repair_prep += value,
+                    _ => {}
+                }
+            }
+        }
+    }
+    backlog
+}
+
+/// HTTP ハンドラをラップして、処理したリクエストを`DashboardTracker`に記録する。
+///
+/// 対象のハンドラのパスは`/v1/buckets/{bucket_id}/objects/...`の形式である必要がある。
+pub struct WithDashboard<H> {
+    inner: H,
+    tracker: DashboardTracker,
+}
+impl<H: HandleRequest> WithDashboard<H> {
+    /// 新しい`WithDashboard`インスタンスを生成する。
+    pub fn new(inner: H, tracker: DashboardTracker) -> Self {
+        WithDashboard { inner, tracker }
+    }
+}
+impl<H: HandleRequest> HandleRequest for WithDashboard<H> {
+    const METHOD: &'static str = H::METHOD;
+    const PATH: &'static str = H::PATH;
+
+    type ReqBody = H::ReqBody;
+    type ResBody = H::ResBody;
+    type Decoder = H::Decoder;
+    type Encoder = H::Encoder;
+    type Reply = Record<H>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = req
+            .url()
+            .path()
+            .trim_start_matches('/')
+            .split('/')
+            .nth(2)
+            .unwrap_or("")
+            .to_owned();
+        Record {
+            future: self.inner.handle_request(req),
+            started_at: Instant::now(),
+            bucket_id,
+            op: Operation::from_method(H::METHOD),
+            tracker: self.tracker.clone(),
+        }
+    }
+
+    fn handle_request_head(&self, req: &Req<()>) -> Option<Res<Self::ResBody>> {
+        self.inner.handle_request_head(req)
+    }
+}
+
+/// リクエストの処理時間と応答ステータスを記録する`Future`。
+pub struct Record<H: HandleRequest> {
+    future: H::Reply,
+    started_at: Instant,
+    bucket_id: String,
+    op: Option<Operation>,
+    tracker: DashboardTracker,
+}
+impl<H: HandleRequest> Future for Record<H> {
+    type Item = Res<H::ResBody>;
+    type Error = <H::Reply as Future>::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(res) = self.future.poll()? {
+            if let Some(op) = self.op {
+                self.tracker.record(
+                    &self.bucket_id,
+                    op,
+                    self.started_at.elapsed(),
+                    res.status_code(),
+                );
+            }
+            Ok(Async::Ready(res))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn percentile_works() {
+        let mut histogram = [0; LATENCY_BOUNDS_MILLIS.len() + 1];
+        histogram[0] = 98; // <= 1ms
+        histogram[5] = 2; // <= 50ms
+        assert_eq!(percentile(&histogram, 100, 0.99, ms(40)), 50);
+        assert_eq!(percentile(&histogram, 100, 0.5, ms(40)), 1);
+
+        // 上限値を超えたものは、最大値で代表させる
+        histogram[LATENCY_BOUNDS_MILLIS.len()] = 100;
+        assert_eq!(percentile(&histogram, 200, 0.99, ms(12_345)), 12_345);
+    }
+
+    #[test]
+    fn tracker_works() {
+        let tracker = DashboardTracker::new();
+        let now = Duration::from_secs(100);
+        for i in 0..100 {
+            let status = if i < 10 { 500 } else { 200 };
+            tracker.record_at(now, "foo", Operation::Get, ms(i), status);
+        }
+        tracker.record_at(now, "bar", Operation::Put, ms(1), 200);
+
+        let stats = tracker.statistics_at(now, "foo");
+        assert_eq!(stats.len(), Operation::ALL.len());
+        let get = &stats["get"];
+        assert!((get.qps - 100.0 / WINDOW_SECONDS as f64).abs() < 1e-9);
+        assert!((get.error_rate - 0.1).abs() < 1e-9);
+        assert_eq!(get.p99_millis, Some(100));
+        assert_eq!(stats["put"], OperationStatistics::default());
+
+        // 集計期間を過ぎた記録は捨てられる
+        let later = now + Duration::from_secs(WINDOW_SECONDS);
+        assert_eq!(
+            tracker.statistics_at(later, "foo")["get"],
+            OperationStatistics::default()
+        );
+        assert!(tracker.series.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn repair_backlog_works() {
+        let gauge = prometrics::metrics::MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("synchronizer")
+            .gauge("queue_length")
+            .label("type", "repair")
+            .label("node", "repair_backlog_works")
+            .default_registry()
+            .finish()
+            .unwrap();
+        gauge.set(3.0);
+        assert!(repair_backlog().repair >= 3);
+    }
+
+    #[test]
+    fn operation_from_method_works() {
+        assert_eq!(Operation::from_method("GET"), Some(Operation::Get));
+        assert_eq!(Operation::from_method("POST"), Some(Operation::Append));
+        assert_eq!(Operation::from_method("PATCH"), None);
+    }
+}
@@ -0,0 +1,163 @@
+//! デバイスを安全に取り外すための、デバイスの退役を追跡するためのモジュール。
+//!
+//! 退役自体は構成管理用クラスタ上の状態として複製され、対象デバイスをメンバに含むセグメントは
+//! それらを除いた配置へと再配置される(`frugalos_config::DeviceDecommission`を参照)。
+//! 断片のコピーは各セグメントの MDS が進めるので、ここでは退役を要求した後に、
+//! 全ての対象デバイスがどのセグメントからも参照されなくなるまで、その状態を定期的に確認する。
+//!
+//! 操作の進捗は、退役の開始時点で対象デバイスを参照していたセグメントの延べ数に対する、
+//! 参照しなくなったセグメントの数で表される。
+//! この操作をキャンセルしても退役自体は取り消されず、追跡が止まるだけである。
+use fibers::time::timer;
+use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
+use frugalos_config::{self, DeviceDecommission};
+use futures::future::{self, Either, Loop};
+use futures::Future;
+use libfrugalos::entity::device::DeviceId;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use trackable::error::ErrorKindExt;
+
+use operation::OperationHandle;
+use {Error, ErrorKind};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+
+/// 退役の状態を確認する間隔のデフォルト値。
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 退役の結果。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecommissionReport {
+    /// 退役させたデバイスの ID。
+    pub devices: Vec<DeviceId>,
+
+    /// 退役を開始した時刻(UNIXエポックからの秒数)。
+    pub started_at: u64,
+
+    /// 各デバイスの最終的な状態。
+    ///
+    /// 退役中に削除されたデバイスは含まれない。
+    pub statuses: Vec<DeviceDecommission>,
+
+    /// 全てのデバイスを取り外しても良い状態かどうか。
+    pub ready: bool,
+}
+
+/// `devices`の退役を構成管理用クラスタに要求する。
+///
+/// 結果として、要求時点での各デバイスの状態が返される。
+pub fn start(
+    rpc_service: RpcServiceHandle,
+    contact_server: SocketAddr,
+    devices: Vec<DeviceId>,
+) -> BoxFuture<Vec<DeviceDecommission>> {
+    let future =
+        frugalos_config::cluster::decommission_devices(rpc_service, contact_server, devices)
+            .map_err(|e| track!(Error::from(e)));
+    Box::new(future)
+}
+
+/// 退役中のデバイスの状態の一覧を取得する。
+pub fn list(
+    rpc_service: RpcServiceHandle,
+    contact_server: SocketAddr,
+) -> BoxFuture<Vec<DeviceDecommission>> {
+    let future = frugalos_config::cluster::list_decommissions(rpc_service, contact_server)
+        .map_err(|e| track!(Error::from(e)));
+    Box::new(future)
+}
+
+/// `devices`の全てが取り外し可能な状態になるまで、`poll_interval`毎に退役の状態を確認する。
+///
+/// `initial`は`start`が返した、退役の開始時点での状態。
+pub fn watch(
+    rpc_service: RpcServiceHandle,
+    contact_server: SocketAddr,
+    devices: Vec<DeviceId>,
+    initial: Vec<DeviceDecommission>,
+    poll_interval: Duration,
+    operation: OperationHandle,
+) -> BoxFuture<DecommissionReport> {
+    let total = remaining_segments(&devices, &initial);
+    operation.set_total(total);
+
+    let report = DecommissionReport {
+        devices,
+        started_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        statuses: Vec::new(),
+        ready: false,
+    };
+    let future = future::loop_fn((report, initial, 0), move |(mut report, statuses, done)| {
+        // 再配置中にセグメントが増えることはないが、念のため進捗が後退しないようにしておく
+        let remaining = remaining_segments(&report.devices, &statuses);
+        let current = total.saturating_sub(remaining);
+        if current > done {
+            operation.add_done(current - done);
+        }
+        let done = done.max(current);
+
+        // 退役中に削除されたデバイスは一覧に含まれなくなるが、既に取り外されているので完了とみなす
+        report.statuses = select(&report.devices, statuses);
+        report.ready = report.statuses.iter().all(|s| s.ready);
+        if report.ready {
+            return Either::A(future::ok(Loop::Break(report)));
+        }
+        if operation.is_cancelled() {
+            let e = ErrorKind::Other.cause("The decommission tracking has been cancelled");
+            return Either::A(future::err(e.into()));
+        }
+
+        let rpc_service = rpc_service.clone();
+        let future = timer::timeout(poll_interval)
+            .map_err(|e| track!(Error::from(e)))
+            .and_then(move |()| list(rpc_service, contact_server))
+            .map(move |statuses| Loop::Continue((report, statuses, done)));
+        Either::B(future)
+    });
+    Box::new(future)
+}
+
+/// `statuses`のうち`devices`に関するものを取り出す。
+fn select(devices: &[DeviceId], statuses: Vec<DeviceDecommission>) -> Vec<DeviceDecommission> {
+    statuses
+        .into_iter()
+        .filter(|s| devices.contains(&s.device_id))
+        .collect()
+}
+
+/// `devices`を参照しているセグメントの延べ数を返す。
+fn remaining_segments(devices: &[DeviceId], statuses: &[DeviceDecommission]) -> u64 {
+    statuses
+        .iter()
+        .filter(|s| devices.contains(&s.device_id))
+        .map(|s| s.remaining_segments)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(device_id: &str, remaining_segments: u64) -> DeviceDecommission {
+        DeviceDecommission {
+            device_id: device_id.to_owned(),
+            remaining_segments,
+            ready: remaining_segments == 0,
+        }
+    }
+
+    #[test]
+    fn remaining_segments_works() {
+        let devices = vec!["foo".to_owned(), "bar".to_owned()];
+        let statuses = vec![status("foo", 3), status("bar", 0), status("baz", 5)];
+        assert_eq!(remaining_segments(&devices, &statuses), 3);
+        assert_eq!(
+            select(&devices, statuses),
+            vec![status("foo", 3), status("bar", 0)]
+        );
+    }
+}
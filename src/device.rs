@@ -0,0 +1,421 @@
+//! ファイルデバイスの作成時に行う、領域の事前確保と簡易的な性能試験。
+//!
+//! 性能の低いディスクや壊れかけのディスクを、実際に負荷がかかる前に検出することを目的としている。
+//! 試験に失敗したデバイスは起動されない。
+//!
+//! 試験の結果は基準値として構成管理用のクラスタに登録され、
+//! 閾値を満たさなかったデバイスは、以降に構築されるセグメントテーブルの配置先から除外される。
+use fibers::sync::mpsc;
+use fibers::time::timer::{self, Timeout};
+use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
+use frugalos_config::schema::PutDeviceBaselineRpc;
+use frugalos_config::{DeviceBaseline, ServiceHandle as ConfigServiceHandle};
+use frugalos_core::cluster_feature::{self, ClusterFeature};
+use frugalos_core::rpc_auth;
+use futures::{Async, Future, Stream};
+use libc;
+use prometrics::metrics::{Counter, MetricBuilder};
+use slog::Logger;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use {Error, ErrorKind, FrugalosDeviceConfig, Result};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+
+/// 登録に失敗した基準値を再登録するまでの間隔。
+const BASELINE_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 性能試験で一度に読み書きするデータのサイズ。
+const PRECHECK_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// 性能試験の結果。
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrecheckResult {
+    /// 書き込みのスループット(バイト/秒)。
+    pub write_throughput: f64,
+
+    /// 読み込みのスループット(バイト/秒)。
+    pub read_throughput: f64,
+
+    /// 一回の書き込み(同期を含む)にかかった時間の最大値。
+    pub max_write_latency: Duration,
+
+    /// 一回の読み込みにかかった時間の最大値。
+    pub max_read_latency: Duration,
+}
+impl PrecheckResult {
+    /// 結果が設定された閾値を満たしているかどうかを確認する。
+    pub fn check(&self, config: &FrugalosDeviceConfig) -> Result<()> {
+        let max_latency = ::std::cmp::max(self.max_write_latency, self.max_read_latency);
+        track_assert!(
+            max_latency <= config.precheck_max_latency,
+            ErrorKind::Other,
+            "Too slow device: {}",
+            dump!(max_latency, config.precheck_max_latency)
+        );
+        track_assert!(
+            self.write_throughput >= config.precheck_min_write_throughput as f64,
+            ErrorKind::Other,
+            "Too slow device: {}",
+            dump!(self.write_throughput, config.precheck_min_write_throughput)
+        );
+        track_assert!(
+            self.read_throughput >= config.precheck_min_read_throughput as f64,
+            ErrorKind::Other,
+            "Too slow device: {}",
+            dump!(self.read_throughput, config.precheck_min_read_throughput)
+        );
+        Ok(())
+    }
+
+    /// 構成管理用のクラスタに登録する基準値に変換する。
+    pub fn to_baseline(&self, device_id: &str, passed: bool) -> DeviceBaseline {
+        DeviceBaseline {
+            device_id: device_id.to_owned(),
+            write_bytes_per_second: self.write_throughput as u64,
+            read_bytes_per_second: self.read_throughput as u64,
+            max_write_latency_micros: as_micros(self.max_write_latency),
+            max_read_latency_micros: as_micros(self.max_read_latency),
+            passed,
+        }
+    }
+}
+
+/// 新規に作成するデバイスファイル`filepath`の準備を行う。
+///
+/// 設定に応じて、性能試験と領域の事前確保を行う。
+/// 性能試験の結果は、閾値を満たしたかどうかに関わらず`baseline_tx`に送られる。
+/// 性能試験の結果が閾値を満たさない場合にはエラーを返す。
+pub fn prepare_file_device(
+    logger: &Logger,
+    device_id: &str,
+    filepath: &Path,
+    capacity: u64,
+    config: &FrugalosDeviceConfig,
+    baseline_tx: &mpsc::Sender<DeviceBaseline>,
+) -> Result<()> {
+    if let Some(dir) = filepath.parent() {
+        track!(fs::create_dir_all(dir).map_err(Error::from))?;
+    }
+    if config.precheck {
+        let result = track!(precheck(filepath, config.precheck_size))?;
+        info!(
+            logger,
+            "Device precheck finished: {}",
+            dump!(device_id, filepath, result)
+        );
+        record_baseline(device_id, &result);
+        let checked = track!(result.check(config); device_id, filepath);
+        let _ = baseline_tx.send(result.to_baseline(device_id, checked.is_ok()));
+        checked?;
+    }
+    if config.preallocate {
+        track!(preallocate(filepath, capacity))?;
+        info!(
+            logger,
+            "Device file is preallocated: {}",
+            dump!(device_id, filepath, capacity)
+        );
+    }
+    Ok(())
+}
+
+/// `filepath`と同じディレクトリに一時ファイルを作成して、書き込みと読み込みの性能を測る。
+///
+/// 書き込んだ内容が読み込めることも確認する。
+pub fn precheck(filepath: &Path, size: u64) -> Result<PrecheckResult> {
+    let tmp = precheck_file_path(filepath);
+    let result = precheck_file(&tmp, size);
+    let _ = fs::remove_file(&tmp);
+    track!(result)
+}
+
+fn precheck_file_path(filepath: &Path) -> PathBuf {
+    let mut name = filepath
+        .file_name()
+        .map(|s| s.to_os_string())
+        .unwrap_or_default();
+    name.push(".precheck");
+    filepath.with_file_name(name)
+}
+
+fn precheck_file(path: &Path, size: u64) -> Result<PrecheckResult> {
+    let blocks = ::std::cmp::max(1, size as usize / PRECHECK_BLOCK_SIZE);
+    let block = |i: usize| vec![(i % 251) as u8; PRECHECK_BLOCK_SIZE];
+
+    let mut file = track!(OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(path)
+        .map_err(Error::from))?;
+    let mut max_write_latency = Duration::default();
+    let started_at = Instant::now();
+    for i in 0..blocks {
+        let data = block(i);
+        let now = Instant::now();
+        track!(file.write_all(&data).map_err(Error::from))?;
+        track!(file.sync_data().map_err(Error::from))?;
+        max_write_latency = ::std::cmp::max(max_write_latency, now.elapsed());
+    }
+    let write_elapsed = started_at.elapsed();
+
+    // NOTE: ページキャッシュに載っている可能性があるので、読み込み性能は参考値
+    let mut file = track!(File::open(path).map_err(Error::from))?;
+    let mut buf = vec![0; PRECHECK_BLOCK_SIZE];
+    let mut max_read_latency = Duration::default();
+    let started_at = Instant::now();
+    for i in 0..blocks {
+        let now = Instant::now();
+        track!(file.read_exact(&mut buf).map_err(Error::from))?;
+        max_read_latency = ::std::cmp::max(max_read_latency, now.elapsed());
+        track_assert!(
+            buf == block(i),
+            ErrorKind::Other,
+            "Read data differs from written data: path={:?}, block={}",
+            path,
+            i
+        );
+    }
+    let read_elapsed = started_at.elapsed();
+
+    let bytes = (blocks * PRECHECK_BLOCK_SIZE) as f64;
+    Ok(PrecheckResult {
+        write_throughput: bytes / as_secs_f64(write_elapsed),
+        read_throughput: bytes / as_secs_f64(read_elapsed),
+        max_write_latency,
+        max_read_latency,
+    })
+}
+
+fn as_micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())
+}
+
+fn as_secs_f64(d: Duration) -> f64 {
+    let secs = d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1_000_000_000.0;
+    secs.max(0.000_001)
+}
+
+/// `filepath`に、`capacity`バイト分の領域を確保したファイルを作成する。
+pub fn preallocate(filepath: &Path, capacity: u64) -> Result<()> {
+    let file = track!(OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(filepath)
+        .map_err(Error::from))?;
+    track!(fallocate(&file, capacity); filepath)?;
+    track!(file.sync_all().map_err(Error::from))?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn fallocate(file: &File, capacity: u64) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let errno = unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, capacity as libc::off_t) };
+    track_assert_eq!(errno, 0, ErrorKind::Other, "posix_fallocate failed");
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fallocate(file: &File, capacity: u64) -> Result<()> {
+    // NOTE: 実際の領域は確保されない可能性がある
+    track!(file.set_len(capacity).map_err(Error::from))
+}
+
+/// 性能試験の結果をメトリクスとして公開する。
+fn record_baseline(device_id: &str, result: &PrecheckResult) {
+    let mut builder = MetricBuilder::new();
+    builder
+        .namespace("frugalos")
+        .subsystem("device_precheck")
+        .label("device", device_id);
+    let values = [
+        ("write_bytes_per_second", result.write_throughput),
+        ("read_bytes_per_second", result.read_throughput),
+        (
+            "max_write_latency_seconds",
+            as_secs_f64(result.max_write_latency),
+        ),
+        (
+            "max_read_latency_seconds",
+            as_secs_f64(result.max_read_latency),
+        ),
+    ];
+    for &(name, value) in &values {
+        // NOTE: 同じデバイスが再作成された場合には登録に失敗するが、その場合は無視する
+        if let Ok(gauge) = builder
+            .gauge(name)
+            .help("Baseline performance measured when the device was created")
+            .default_registry()
+            .finish()
+        {
+            gauge.set(value);
+        }
+    }
+}
+
+/// デバイスの作成時に測定された性能の基準値を、構成管理用クラスタに登録する。
+///
+/// 登録に失敗した基準値は、一定時間後に再登録される。
+pub struct DeviceBaselineReporter {
+    logger: Logger,
+    rpc_service: RpcServiceHandle,
+    baseline_tx: mpsc::Sender<DeviceBaseline>,
+    baseline_rx: mpsc::Receiver<DeviceBaseline>,
+    reports: Vec<(DeviceBaseline, BoxFuture<DeviceBaseline>)>,
+    failed: Vec<DeviceBaseline>,
+    retry: Option<Timeout>,
+    report_failures: Counter,
+}
+impl DeviceBaselineReporter {
+    /// 新しい`DeviceBaselineReporter`を生成する。
+    pub fn new(logger: Logger, rpc_service: RpcServiceHandle) -> Result<Self> {
+        let report_failures = track!(MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("device_precheck")
+            .counter("report_failures_total")
+            .help("Number of failures of reporting the baselines of devices")
+            .default_registry()
+            .finish())?;
+        let (baseline_tx, baseline_rx) = mpsc::channel();
+        Ok(DeviceBaselineReporter {
+            logger,
+            rpc_service,
+            baseline_tx,
+            baseline_rx,
+            reports: Vec::new(),
+            failed: Vec::new(),
+            retry: None,
+            report_failures,
+        })
+    }
+
+    /// 登録する基準値の送信口を返す。
+    pub fn sender(&self) -> mpsc::Sender<DeviceBaseline> {
+        self.baseline_tx.clone()
+    }
+
+    /// 送られた基準値の登録を開始し、実行中の登録を進める。
+    pub fn poll(&mut self, config_service: &ConfigServiceHandle) -> Result<()> {
+        while let Async::Ready(Some(baseline)) = self.baseline_rx.poll().expect("Never fails") {
+            self.start_report(config_service, baseline);
+        }
+
+        let expired = match self.retry {
+            Some(ref mut retry) => track!(retry.poll().map_err(Error::from))?.is_ready(),
+            None => false,
+        };
+        if expired {
+            self.retry = None;
+            for baseline in mem::replace(&mut self.failed, Vec::new()) {
+                self.start_report(config_service, baseline);
+            }
+        }
+
+        let mut i = 0;
+        while i < self.reports.len() {
+            match self.reports[i].1.poll() {
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(baseline)) => {
+                    info!(self.logger, "Device baseline is reported: {:?}", baseline);
+                    self.reports.swap_remove(i);
+                }
+                Err(e) => {
+                    let (baseline, _) = self.reports.swap_remove(i);
+                    self.report_failures.increment();
+                    warn!(
+                        self.logger,
+                        "Cannot report the baseline of the device {:?}: {}", baseline.device_id, e
+                    );
+                    self.failed.push(baseline);
+                }
+            }
+        }
+
+        if !self.failed.is_empty() && self.retry.is_none() {
+            let mut retry = timer::timeout(BASELINE_RETRY_INTERVAL);
+            track!(retry.poll().map_err(Error::from))?; // タイマーの起床を登録するため
+            self.retry = Some(retry);
+        }
+        Ok(())
+    }
+
+    /// 基準値を、構成管理用クラスタのリーダに登録する。
+    ///
+    /// 古いバージョンのサーバは基準値のコマンドを解釈できないので、
+    /// `device_baselines`機能が有効になるまでは登録しない。
+    fn start_report(&mut self, config_service: &ConfigServiceHandle, baseline: DeviceBaseline) {
+        if !cluster_feature::is_enabled(ClusterFeature::DeviceBaselines) {
+            debug!(
+                self.logger,
+                "The baseline is not reported (the cluster feature is disabled): {:?}", baseline
+            );
+            return;
+        }
+        let rpc_service = self.rpc_service.clone();
+        let request = baseline.clone();
+        let future = config_service
+            .get_leader()
+            .map_err(|e| track!(Error::from(e)))
+            .and_then(move |leader| {
+                rpc_auth::call::<PutDeviceBaselineRpc>(&rpc_service, leader, request)
+                    .map_err(|e| track!(Error::from(e)))
+            })
+            .and_then(|result| track!(result.map_err(Error::from)));
+        self.reports.push((baseline, Box::new(future)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempdir::TempDir;
+    use trackable::result::TestResult;
+
+    #[test]
+    fn precheck_works() -> TestResult {
+        let dir = track_any_err!(TempDir::new("frugalos_device"))?;
+        let filepath = dir.path().join("file0.lusf");
+        let result = track!(precheck(&filepath, 2 * 1024 * 1024))?;
+        assert!(result.write_throughput > 0.0);
+        assert!(!precheck_file_path(&filepath).exists());
+        assert!(!filepath.exists());
+
+        let mut config = FrugalosDeviceConfig::default();
+        track!(result.check(&config))?;
+
+        config.precheck_max_latency = Duration::from_secs(0);
+        config.precheck_min_write_throughput = 0;
+        config.precheck_min_read_throughput = 0;
+        let slow = PrecheckResult {
+            max_write_latency: Duration::from_millis(1),
+            ..result
+        };
+        assert!(slow.check(&config).is_err());
+
+        let baseline = slow.to_baseline("dev0", false);
+        assert_eq!(baseline.device_id, "dev0");
+        assert_eq!(baseline.max_write_latency_micros, 1000);
+        assert_eq!(
+            baseline.write_bytes_per_second,
+            result.write_throughput as u64
+        );
+        assert!(!baseline.passed);
+        Ok(())
+    }
+
+    #[test]
+    fn preallocate_works() -> TestResult {
+        let dir = track_any_err!(TempDir::new("frugalos_device"))?;
+        let filepath = dir.path().join("file0.lusf");
+        track!(preallocate(&filepath, 1024 * 1024))?;
+        assert_eq!(track_any_err!(fs::metadata(&filepath))?.len(), 1024 * 1024);
+        assert!(preallocate(&filepath, 1024 * 1024).is_err());
+        Ok(())
+    }
+}
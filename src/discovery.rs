@@ -0,0 +1,246 @@
+//! ホスト名を使ってサーバのアドレスを解決するためのモジュール。
+//!
+//! クラスタ構成に登録されたサーバのアドレスは Raft のノード ID の一部にもなっているため、
+//! 後から変更することができない。
+//! そこで、設定でホスト名が与えられたサーバについては定期的に名前解決を行い、
+//! IP アドレスが変わっていた場合には `frugalos_raft::set_current_addr` を使って通信先を切り替える。
+//! 新しいアドレスへの接続は、次回の RPC 呼び出し時に確立される。
+use fibers::time::timer::{self, Timeout};
+use fibers_tasque::{AsyncCall, DefaultIoTaskQueue, TaskQueueExt};
+use frugalos_raft;
+use futures::{self, Async, Future, Poll};
+use libfrugalos::entity::server::{Server, ServerId};
+use prometrics::metrics::{Counter, MetricBuilder};
+use slog::Logger;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use {Error, ErrorKind, FrugalosDiscoveryConfig, Result};
+
+/// 名前解決後の、サーバの通信先のアドレスを返す `Future`。
+pub type ResolveAddr = Box<dyn Future<Item = SocketAddr, Error = Error> + Send>;
+
+/// ホスト名を名前解決して IP アドレスを得る。
+///
+/// 複数のアドレスが得られた場合には最初のものを使う。
+pub fn resolve_hostname(hostname: &str) -> Result<IpAddr> {
+    let mut addrs = track!((hostname, 0).to_socket_addrs().map_err(Error::from); hostname)?;
+    let addr = track_assert_some!(
+        addrs.next(),
+        ErrorKind::InvalidInput,
+        "hostname={}",
+        hostname
+    );
+    Ok(addr.ip())
+}
+
+/// 自サーバが RPC サーバとして bind すべきアドレスを返す。
+///
+/// 自サーバのホスト名が設定されている場合には、名前解決して得たアドレスを使う。
+/// その際には、他のノードからの通信と同様に、ローカルのノードへの通信も新しいアドレスに向けられる。
+pub fn resolve_local_addr(config: &FrugalosDiscoveryConfig, local: &Server) -> Result<SocketAddr> {
+    let registered = local.addr();
+    if let Some(hostname) = config.hostnames.get(&local.id) {
+        let ip = track!(resolve_hostname(hostname))?;
+        let current = SocketAddr::new(ip, registered.port());
+        frugalos_raft::set_current_addr(registered, current);
+        Ok(current)
+    } else {
+        Ok(registered)
+    }
+}
+
+/// ホスト名が設定されたサーバのアドレスを定期的に解決し直す `Future`。
+///
+/// この `Future` が終了することはない。
+pub struct ServerDiscovery {
+    logger: Logger,
+    hostnames: BTreeMap<ServerId, String>,
+    // サーバ ID からクラスタ構成に登録されているアドレスへの対応.
+    registered: HashMap<ServerId, SocketAddr>,
+    interval: Duration,
+    timeout: Timeout,
+    resolving: Vec<(ServerId, AsyncCall<Result<IpAddr>>)>,
+    metrics: DiscoveryMetrics,
+}
+impl ServerDiscovery {
+    /// 新しい `ServerDiscovery` を生成する。
+    pub fn new(logger: Logger, config: &FrugalosDiscoveryConfig) -> Result<Self> {
+        let metrics = track!(DiscoveryMetrics::new())?;
+        Ok(ServerDiscovery {
+            logger,
+            hostnames: config.hostnames.clone(),
+            registered: HashMap::new(),
+            interval: config.refresh_interval,
+            timeout: timer::timeout(config.refresh_interval),
+            resolving: Vec::new(),
+            metrics,
+        })
+    }
+
+    /// クラスタ構成に登録されたサーバを追加(あるいは更新)する。
+    pub fn put_server(&mut self, server: &Server) {
+        let addr = server.addr();
+        if let Some(old) = self.registered.insert(server.id.clone(), addr) {
+            if old != addr {
+                // 登録済みのアドレス自体が変わったので、古いアドレスに対する変換は不要になった
+                frugalos_raft::set_current_addr(old, old);
+            }
+        }
+    }
+
+    /// クラスタ構成から削除されたサーバを取り除く。
+    pub fn delete_server(&mut self, server: &ServerId) {
+        if let Some(addr) = self.registered.remove(server) {
+            frugalos_raft::set_current_addr(addr, addr);
+        }
+    }
+
+    /// 定期的な名前解決を待たずに、サーバのアドレスを解決し直す。
+    ///
+    /// 名前解決は I/O 用のスレッドプールで行われ、結果は通信先のアドレスにも反映される。
+    /// ホスト名が設定されていないサーバについては、現在の通信先のアドレスをそのまま返す。
+    pub fn resolve_now(&self, server: &Server) -> ResolveAddr {
+        let registered = server.addr();
+        let hostname = if let Some(hostname) = self.hostnames.get(&server.id) {
+            hostname.clone()
+        } else {
+            return Box::new(futures::finished(frugalos_raft::current_addr(registered)));
+        };
+        let future = DefaultIoTaskQueue
+            .async_call(move || resolve_hostname(&hostname))
+            .then(move |result| {
+                let ip = track!(result.map_err(Error::from)?)?;
+                let current = SocketAddr::new(ip, registered.port());
+                frugalos_raft::set_current_addr(registered, current);
+                Ok(current)
+            });
+        Box::new(future)
+    }
+
+    fn start_resolving(&mut self) {
+        if !self.resolving.is_empty() {
+            // 前回の名前解決が終わっていない
+            return;
+        }
+        for (id, hostname) in &self.hostnames {
+            if !self.registered.contains_key(id) {
+                continue;
+            }
+            let hostname = hostname.clone();
+            let future = DefaultIoTaskQueue.async_call(move || resolve_hostname(&hostname));
+            self.resolving.push((id.clone(), future));
+        }
+    }
+
+    fn handle_resolved(&mut self, server: &ServerId, ip: IpAddr) {
+        let registered = if let Some(&addr) = self.registered.get(server) {
+            addr
+        } else {
+            // 名前解決中にクラスタ構成から削除された
+            return;
+        };
+        let current = SocketAddr::new(ip, registered.port());
+        let previous = frugalos_raft::current_addr(registered);
+        if previous != current {
+            info!(
+                self.logger,
+                "The address of the server is changed: {}",
+                dump!(server, registered, previous, current)
+            );
+            self.metrics.address_changes.increment();
+            frugalos_raft::set_current_addr(registered, current);
+        }
+    }
+}
+impl Future for ServerDiscovery {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while track!(self.timeout.poll().map_err(Error::from))?.is_ready() {
+            self.timeout = timer::timeout(self.interval);
+            self.start_resolving();
+        }
+
+        let mut i = 0;
+        while i < self.resolving.len() {
+            let result = match self.resolving[i].1.poll() {
+                Ok(Async::NotReady) => {
+                    i += 1;
+                    continue;
+                }
+                Ok(Async::Ready(result)) => result,
+                Err(e) => Err(track!(Error::from(e))),
+            };
+            let (server, _) = self.resolving.swap_remove(i);
+            match result {
+                Ok(ip) => self.handle_resolved(&server, ip),
+                Err(e) => {
+                    self.metrics.resolve_failures.increment();
+                    warn!(
+                        self.logger,
+                        "Cannot resolve the hostname of the server: server={}, error={}", server, e
+                    );
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+struct DiscoveryMetrics {
+    address_changes: Counter,
+    resolve_failures: Counter,
+}
+impl DiscoveryMetrics {
+    fn new() -> Result<Self> {
+        let mut builder = MetricBuilder::new();
+        builder.namespace("frugalos").subsystem("discovery");
+        let address_changes = track!(builder
+            .counter("address_changes_total")
+            .help("Number of detected address changes of the servers")
+            .default_registry()
+            .finish())?;
+        let resolve_failures = track!(builder
+            .counter("resolve_failures_total")
+            .help("Number of failures of resolving the hostnames of the servers")
+            .default_registry()
+            .finish())?;
+        Ok(DiscoveryMetrics {
+            address_changes,
+            resolve_failures,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use trackable::result::TestResult;
+
+    #[test]
+    fn resolve_local_addr_works() -> TestResult {
+        // 変換表はプロセス全体で共有されるので、他のテストが使わない(文書用の)アドレスを使う
+        let mut local = Server::new("srv1".to_owned(), "192.0.2.10:14278".parse().unwrap());
+        let mut config = FrugalosDiscoveryConfig::default();
+        assert_eq!(track!(resolve_local_addr(&config, &local))?, local.addr());
+
+        local.id = "srv2".to_owned();
+        config
+            .hostnames
+            .insert("srv2".to_owned(), "localhost".to_owned());
+        let addr = track!(resolve_local_addr(&config, &local))?;
+        assert!(addr.ip().is_loopback());
+        assert_eq!(addr.port(), 14278);
+        assert_eq!(frugalos_raft::current_addr(local.addr()), addr);
+        frugalos_raft::set_current_addr(local.addr(), local.addr());
+
+        assert_eq!(
+            track!(resolve_hostname("127.0.0.1"))?,
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+        );
+        Ok(())
+    }
+}
@@ -12,6 +12,7 @@ use raftlog;
 use serde_yaml;
 use std;
 use std::io;
+use std::time::Duration;
 use trackable::error::TrackableError;
 use trackable::error::{ErrorKind as TrackableErrorKind, ErrorKindExt};
 
@@ -25,10 +26,15 @@ impl From<io::Error> for Error {
 }
 impl From<frugalos_segment::Error> for Error {
     fn from(f: frugalos_segment::Error) -> Self {
-        if let frugalos_segment::ErrorKind::UnexpectedVersion { current } = *f.kind() {
-            ErrorKind::Unexpected(current).takes_over(f).into()
-        } else {
-            ErrorKind::Other.takes_over(f).into()
+        match *f.kind() {
+            frugalos_segment::ErrorKind::UnexpectedVersion { current } => {
+                ErrorKind::Unexpected(current).takes_over(f).into()
+            }
+            frugalos_segment::ErrorKind::QuotaExceeded => {
+                ErrorKind::QuotaExceeded.takes_over(f).into()
+            }
+            frugalos_segment::ErrorKind::ReadOnly => ErrorKind::ReadOnly.takes_over(f).into(),
+            _ => ErrorKind::Other.takes_over(f).into(),
         }
     }
 }
@@ -138,6 +144,23 @@ pub enum ErrorKind {
     InvalidInput,
     NotFound,
     Unexpected(Option<ObjectVersion>),
+
+    /// バケツの割り当て量を超えるため、書き込めない。
+    QuotaExceeded,
+
+    /// バケツが読み込み専用に設定されているため、書き込めない。
+    ReadOnly,
+
+    /// 認証情報が無い、ないし不正である。
+    Unauthenticated,
+
+    /// 操作を行う権限が無い。
+    PermissionDenied,
+
+    /// 負荷が高いため、リクエストを受け付けられない。
+    ///
+    /// 値は、クライアントが再試行するまでに待つべき時間。
+    Busy(Duration),
     Other,
 }
 impl TrackableErrorKind for ErrorKind {}
@@ -0,0 +1,558 @@
+//! オブジェクトの変更(追加と削除)のイベントを、外部のシステムに転送するためのモジュール。
+//!
+//! 設定されたバケツの各セグメントの変更を`Watch`で監視し、`EventSink`に順に渡す。
+//! 転送先への送信に失敗したイベントは、成功するまで間隔を空けて再送される。
+use fibers::time::timer::{self, Timeout};
+use fibers::Spawn;
+use frugalos_core::logging;
+use frugalos_mds::ObjectChangeKind;
+use frugalos_segment::{self, Watch};
+use futures::{Async, Future, Poll, Stream};
+use libfrugalos::entity::bucket::BucketId;
+use libfrugalos::entity::object::{ObjectId, ObjectVersion};
+use prometrics::metrics::{Counter, MetricBuilder};
+use serde_json;
+use slog::Logger;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+
+use client::{BucketHandle, FrugalosClient};
+use {Error, ErrorKind, EventSinkConfig, FrugalosEventSinkConfig, Result};
+
+/// オブジェクトの変更イベント。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ObjectEvent {
+    /// 変更されたオブジェクトが属するバケツ。
+    pub bucket_id: BucketId,
+
+    /// 変更されたオブジェクトが属するセグメント。
+    pub segment: u16,
+
+    /// セグメント内での変更の位置。
+    ///
+    /// 同じセグメントのイベントは、この値の昇順に転送される。
+    pub position: ObjectVersion,
+
+    /// 変更の種類。
+    pub kind: ObjectChangeKind,
+
+    /// 変更されたオブジェクトの ID。
+    pub object_id: ObjectId,
+
+    /// 保存されたオブジェクトのバージョン、ないし削除されたオブジェクトのバージョン。
+    pub version: ObjectVersion,
+}
+
+/// イベントの転送先。
+///
+/// `send`は転送処理を行うスレッドから同期的に呼び出されるので、
+/// 時間の掛かる送信を行う実装は、内部でバッファリングする等して速やかに返ること。
+pub trait EventSink: Send + 'static {
+    /// イベントを転送する。
+    ///
+    /// エラーを返した場合には、同じイベントが後で再度渡される。
+    fn send(&mut self, event: &ObjectEvent) -> Result<()>;
+}
+
+/// イベントをログに出力する`EventSink`。
+#[derive(Debug, Clone)]
+pub struct LogEventSink {
+    logger: Logger,
+}
+impl LogEventSink {
+    /// 新しい`LogEventSink`を生成する。
+    pub fn new(logger: Logger) -> Self {
+        LogEventSink { logger }
+    }
+}
+impl EventSink for LogEventSink {
+    fn send(&mut self, event: &ObjectEvent) -> Result<()> {
+        info!(
+            self.logger,
+            "Object {:?} event: {}",
+            event.kind,
+            dump!(
+                event.bucket_id,
+                event.segment,
+                event.position,
+                event.object_id,
+                event.version
+            )
+        );
+        Ok(())
+    }
+}
+
+/// イベントをファイルに JSON Lines 形式で追記する`EventSink`。
+#[derive(Debug)]
+pub struct FileEventSink {
+    writer: BufWriter<File>,
+}
+impl FileEventSink {
+    /// 出力先のファイルを開いて、新しい`FileEventSink`を生成する。
+    ///
+    /// ファイルが存在しない場合には作成される。
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = track!(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::from))?;
+        Ok(FileEventSink {
+            writer: BufWriter::new(file),
+        })
+    }
+}
+impl EventSink for FileEventSink {
+    fn send(&mut self, event: &ObjectEvent) -> Result<()> {
+        let mut line = track!(serde_json::to_vec(event).map_err(|e| ErrorKind::Other.cause(e)))?;
+        line.push(b'\n');
+        track!(self.writer.write_all(&line).map_err(Error::from))?;
+        track!(self.writer.flush().map_err(Error::from))?;
+        Ok(())
+    }
+}
+
+impl EventSinkConfig {
+    fn build(&self, logger: &Logger) -> Result<Box<dyn EventSink>> {
+        match *self {
+            EventSinkConfig::Log => Ok(Box::new(LogEventSink::new(logger.clone()))),
+            EventSinkConfig::File { ref path } => Ok(Box::new(track!(FileEventSink::new(path))?)),
+        }
+    }
+}
+
+/// 全てのバケツのイベントの転送処理。
+pub struct EventForwarders {
+    logger: Logger,
+    client: FrugalosClient,
+    config: FrugalosEventSinkConfig,
+    forwarders: Vec<EventForwarder>,
+}
+impl EventForwarders {
+    /// 設定に含まれる組み込みの転送先を生成する。
+    pub fn new(
+        logger: Logger,
+        client: FrugalosClient,
+        config: FrugalosEventSinkConfig,
+    ) -> Result<Self> {
+        let mut this = EventForwarders {
+            logger,
+            client,
+            config: config.clone(),
+            forwarders: Vec::new(),
+        };
+        for bucket in config.buckets {
+            let sink = track!(
+                bucket.sink.build(&this.logger),
+                "bucket_id={:?}",
+                bucket.bucket_id
+            )?;
+            track!(this.add(bucket.bucket_id, sink))?;
+        }
+        Ok(this)
+    }
+
+    /// バケツの転送先を追加する。
+    pub fn add(&mut self, bucket_id: BucketId, sink: Box<dyn EventSink>) -> Result<()> {
+        let forwarder = track!(EventForwarder::new(
+            self.logger.clone(),
+            self.client.clone(),
+            bucket_id,
+            sink,
+            &self.config,
+        ))?;
+        self.forwarders.push(forwarder);
+        Ok(())
+    }
+
+    /// 転送処理を開始する。
+    pub fn spawn<S: Spawn>(self, spawner: &S) {
+        for forwarder in self.forwarders {
+            let logger = forwarder.logger.clone();
+            spawner.spawn(forwarder.map_err(move |e| {
+                error!(logger, "Event forwarder terminated abnormally: {}", e);
+            }));
+        }
+    }
+}
+
+#[derive(Debug)]
+struct EventForwarderMetrics {
+    events_total: Counter,
+    send_failures_total: Counter,
+    lost_total: Counter,
+}
+impl EventForwarderMetrics {
+    fn new(bucket_id: &str) -> Result<Self> {
+        let mut builder = MetricBuilder::new();
+        builder
+            .namespace("frugalos")
+            .subsystem("event_sink")
+            .label("bucket", bucket_id);
+        Ok(EventForwarderMetrics {
+            events_total: track!(builder
+                .counter("events_total")
+                .help("Number of events forwarded to the sink")
+                .default_registry()
+                .finish())?,
+            send_failures_total: track!(builder
+                .counter("send_failures_total")
+                .help("Number of failures to send an event to the sink")
+                .default_registry()
+                .finish())?,
+            lost_total: track!(builder
+                .counter("lost_total")
+                .help("Number of times some events could not be forwarded since MDS no longer retained them")
+                .default_registry()
+                .finish())?,
+        })
+    }
+}
+
+/// あるセグメントの監視状態。
+struct SegmentWatch {
+    segment: u16,
+    watch: Option<Watch>,
+
+    // 監視を再開する際に指定する位置
+    position: Option<ObjectVersion>,
+
+    // 転送に失敗したイベント
+    pending: Option<ObjectEvent>,
+
+    retry: Option<Timeout>,
+}
+
+/// あるバケツのイベントを転送する`Future`。
+///
+/// バケツが存在しない間は、作成されるのを待つ。
+struct EventForwarder {
+    logger: Logger,
+    client: FrugalosClient,
+    bucket_id: BucketId,
+    sink: Box<dyn EventSink>,
+    polling_interval: Duration,
+    retry_interval: Duration,
+    bucket: Option<BucketHandle>,
+    bucket_timeout: Option<Timeout>,
+    segments: Vec<SegmentWatch>,
+    metrics: EventForwarderMetrics,
+}
+impl EventForwarder {
+    fn new(
+        logger: Logger,
+        client: FrugalosClient,
+        bucket_id: BucketId,
+        sink: Box<dyn EventSink>,
+        config: &FrugalosEventSinkConfig,
+    ) -> Result<Self> {
+        let metrics = track!(EventForwarderMetrics::new(&bucket_id))?;
+        let logger = logger.new(o!(logging::BUCKET => bucket_id.clone()));
+        Ok(EventForwarder {
+            logger,
+            client,
+            bucket_id,
+            sink,
+            polling_interval: config.polling_interval,
+            retry_interval: config.retry_interval,
+            bucket: None,
+            bucket_timeout: None,
+            segments: Vec::new(),
+            metrics,
+        })
+    }
+
+    fn poll_bucket(&mut self) -> Result<bool> {
+        while self.bucket.is_none() {
+            if let Some(mut timeout) = self.bucket_timeout.take() {
+                if let Async::NotReady = track!(timeout.poll().map_err(Error::from))? {
+                    self.bucket_timeout = Some(timeout);
+                    return Ok(false);
+                }
+            }
+            match self.client.bucket(&self.bucket_id) {
+                Ok(bucket) => {
+                    info!(
+                        self.logger,
+                        "Starts forwarding events: segments={}",
+                        bucket.segment_count()
+                    );
+                    self.segments = (0..bucket.segment_count())
+                        .map(|segment| SegmentWatch {
+                            segment,
+                            watch: None,
+                            position: None,
+                            pending: None,
+                            retry: None,
+                        })
+                        .collect();
+                    self.bucket = Some(bucket);
+                }
+                Err(e) => {
+                    if *e.kind() != ErrorKind::NotFound {
+                        return Err(track!(e));
+                    }
+                    self.bucket_timeout = Some(timer::timeout(self.retry_interval));
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// 再試行の待機が終わっていれば、転送に失敗したイベントを再送する。
+    ///
+    /// 再試行を待機している間は`false`を返す。
+    fn send_pending(&mut self, index: usize) -> Result<bool> {
+        let segment = &mut self.segments[index];
+        loop {
+            if let Some(mut retry) = segment.retry.take() {
+                if let Async::NotReady = track!(retry.poll().map_err(Error::from))? {
+                    segment.retry = Some(retry);
+                    return Ok(false);
+                }
+            }
+            let event = if let Some(event) = segment.pending.take() {
+                event
+            } else {
+                return Ok(true);
+            };
+            if let Err(e) = self.sink.send(&event) {
+                warn!(
+                    self.logger,
+                    "Cannot send an event: {}",
+                    dump!(event.segment, event.position, e)
+                );
+                self.metrics.send_failures_total.increment();
+                segment.pending = Some(event);
+                segment.retry = Some(timer::timeout(self.retry_interval));
+                continue;
+            }
+            self.metrics.events_total.increment();
+            return Ok(true);
+        }
+    }
+
+    fn poll_segment(&mut self, index: usize) -> Result<()> {
+        loop {
+            if !track!(self.send_pending(index))? {
+                return Ok(());
+            }
+            let bucket = self.bucket.as_ref().expect("Never fails");
+            let segment = &mut self.segments[index];
+            if segment.watch.is_none() {
+                let watch = track!(bucket.watch(segment.segment as usize, segment.position))?;
+                segment.watch = Some(watch.polling_interval(self.polling_interval));
+            }
+
+            let result = segment.watch.as_mut().expect("Never fails").poll();
+            match result {
+                Ok(Async::NotReady) => return Ok(()),
+                Ok(Async::Ready(Some(change))) => {
+                    segment.position = segment.watch.as_ref().and_then(|w| w.position());
+                    segment.pending = Some(ObjectEvent {
+                        bucket_id: self.bucket_id.clone(),
+                        segment: segment.segment,
+                        position: change.position,
+                        kind: change.kind,
+                        object_id: change.object_id,
+                        version: change.version,
+                    });
+                }
+                Ok(Async::Ready(None)) => {
+                    track_panic!(ErrorKind::Other, "Watch stream terminated unexpectedly")
+                }
+                Err(e) => {
+                    if let frugalos_segment::ErrorKind::ChangesLost = *e.kind() {
+                        // 失われた変更は取り戻せないので、現在の位置から監視をやり直す
+                        warn!(
+                            self.logger,
+                            "Some events were lost: {}",
+                            dump!(segment.segment, segment.position)
+                        );
+                        self.metrics.lost_total.increment();
+                        segment.position = None;
+                    } else {
+                        debug!(
+                            self.logger,
+                            "Cannot watch changes: {}",
+                            dump!(segment.segment, e)
+                        );
+                    }
+                    segment.watch = None;
+                    segment.retry = Some(timer::timeout(self.retry_interval));
+                }
+            }
+        }
+    }
+}
+impl Future for EventForwarder {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if !track!(self.poll_bucket())? {
+            return Ok(Async::NotReady);
+        }
+        for i in 0..self.segments.len() {
+            track!(self.poll_segment(i))?;
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_immut::AtomicImmut;
+    use fibers::{Executor, InPlaceExecutor};
+    use frugalos_segment::{ContentCacheSizing, MaintenanceSchedule};
+    use futures;
+    use slog::Discard;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+    use trackable::result::TestResult;
+
+    /// 最初の`failures`回の送信に失敗し、送信できたイベントを記録する`EventSink`。
+    struct FlakySink {
+        failures: usize,
+        sent: Arc<Mutex<Vec<ObjectEvent>>>,
+    }
+    impl EventSink for FlakySink {
+        fn send(&mut self, event: &ObjectEvent) -> Result<()> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                track_panic!(ErrorKind::Other, "Unavailable");
+            }
+            self.sent.lock().expect("Never fails").push(event.clone());
+            Ok(())
+        }
+    }
+
+    fn event() -> ObjectEvent {
+        ObjectEvent {
+            bucket_id: "foo".to_owned(),
+            segment: 3,
+            position: ObjectVersion(10),
+            kind: ObjectChangeKind::Put,
+            object_id: "bar".to_owned(),
+            version: ObjectVersion(8),
+        }
+    }
+
+    fn forwarder(bucket_id: &str, sink: FlakySink) -> Result<EventForwarder> {
+        let client = FrugalosClient::new(
+            Arc::new(AtomicImmut::new(HashMap::new())),
+            ContentCacheSizing::default(),
+            MaintenanceSchedule::default(),
+        );
+        let config = FrugalosEventSinkConfig {
+            retry_interval: Duration::from_millis(1),
+            ..Default::default()
+        };
+        track!(EventForwarder::new(
+            Logger::root(Discard, o!()),
+            client,
+            bucket_id.to_owned(),
+            Box::new(sink),
+            &config,
+        ))
+    }
+
+    /// 結果が得られるまで、`f`をファイバー上でポーリングする(タイマーはファイバー上でのみ動作するため)。
+    fn run_in_fiber<T, F>(f: F) -> Result<T>
+    where
+        F: FnMut() -> Poll<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut executor = track!(InPlaceExecutor::new().map_err(Error::from))?;
+        let result = track!(executor
+            .run_future(futures::future::poll_fn(f))
+            .map_err(Error::from))?;
+        track!(result)
+    }
+
+    #[test]
+    fn forwarder_waits_for_bucket() -> TestResult {
+        let sink = FlakySink {
+            failures: 0,
+            sent: Arc::default(),
+        };
+        let mut forwarder = Some(track!(forwarder("event_sink_missing", sink))?);
+        let (forwarder, polled) = track!(run_in_fiber(move || {
+            let mut forwarder = forwarder.take().expect("Never fails");
+            let polled = track!(forwarder.poll())?;
+            Ok(Async::Ready((forwarder, polled)))
+        }))?;
+
+        // バケツが存在しなくてもエラーにはならず、作成されるのを待つ
+        assert!(polled.is_not_ready());
+        assert!(forwarder.bucket.is_none());
+        assert!(forwarder.bucket_timeout.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn forwarder_resends_failed_events() -> TestResult {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sink = FlakySink {
+            failures: 2,
+            sent: Arc::clone(&sent),
+        };
+        let mut forwarder = track!(forwarder("event_sink_retry", sink))?;
+        forwarder.segments.push(SegmentWatch {
+            segment: 3,
+            watch: None,
+            position: None,
+            pending: Some(event()),
+            retry: None,
+        });
+
+        let mut forwarder = Some(forwarder);
+        let forwarder = track!(run_in_fiber(move || {
+            if track!(forwarder.as_mut().expect("Never fails").send_pending(0))? {
+                Ok(Async::Ready(forwarder.take().expect("Never fails")))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }))?;
+
+        // 失敗したイベントは、成功するまで同じものが再送される
+        assert_eq!(*sent.lock().expect("Never fails"), vec![event()]);
+        assert!(forwarder.segments[0].pending.is_none());
+        assert_eq!(forwarder.metrics.send_failures_total.value(), 2.0);
+        assert_eq!(forwarder.metrics.events_total.value(), 1.0);
+        Ok(())
+    }
+
+    #[test]
+    fn file_event_sink_works() -> TestResult {
+        let dir = track_any_err!(TempDir::new("frugalos_test"))?;
+        let path = dir.path().join("events.jsonl");
+
+        let mut event = event();
+        let mut sink = track!(FileEventSink::new(&path))?;
+        track!(sink.send(&event))?;
+
+        // 既存のファイルには追記される
+        let mut sink = track!(FileEventSink::new(&path))?;
+        event.position = ObjectVersion(12);
+        event.kind = ObjectChangeKind::Delete;
+        track!(sink.send(&event))?;
+
+        let content = track_any_err!(fs::read_to_string(&path))?;
+        assert_eq!(
+            content.lines().collect::<Vec<_>>(),
+            vec![
+                r#"{"bucket_id":"foo","segment":3,"position":10,"kind":"Put","object_id":"bar","version":8}"#,
+                r#"{"bucket_id":"foo","segment":3,"position":12,"kind":"Delete","object_id":"bar","version":8}"#,
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,634 @@
+//! オブジェクトの存在フィルタ(Bloom filter)を管理するためのモジュール。
+//!
+//! 設定されたバケツの各セグメントについて、オブジェクトの ID を登録した Bloom filter を保持し、
+//! フィルタに含まれない(= 存在しないことが確実な)オブジェクトの取得要求には、MDS に問い合わせずに応答する。
+//! 存在しないオブジェクトへの要求が多い読み込み主体の負荷で、MDS への往復を減らすことが目的。
+//!
+//! フィルタは、セグメントのオブジェクトの一覧で初期化した後、MDS の変更履歴(`Watch`)に従って更新される。
+//! Bloom filter からは要素を削除できないので、削除されたオブジェクトはフィルタに残り続ける(偽陽性となる)。
+//! 登録数が想定を超えた場合には、容量を増やして一覧から再構築する。
+//!
+//! NOTE: 他のサーバで保存されたオブジェクトは、変更履歴の取得間隔だけ遅れてフィルタに反映されるので、
+//! 直前の書き込みが見えることを期待する`ReadConsistency::Consistent`と`ReadConsistency::Quorum`の要求では
+//! フィルタは使われない。
+use atomic_immut::AtomicImmut;
+use fibers::time::timer::{self, Timeout};
+use fibers::Spawn;
+use frugalos_core::logging;
+use frugalos_core::memory::MemoryTracker;
+use frugalos_mds::ObjectChangeKind;
+use frugalos_segment::{self, Watch};
+use futures::{Async, Future, Poll, Stream};
+use libfrugalos::consistency::ReadConsistency;
+use libfrugalos::entity::bucket::BucketId;
+use libfrugalos::entity::object::{ObjectSummary, ObjectVersion};
+use prometrics::metrics::{Counter, Gauge, MetricBuilder};
+use siphasher::sip::SipHasher13;
+use slog::Logger;
+use std::collections::HashMap;
+use std::f64::consts::LN_2;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use client::{BucketHandle, FrugalosClient};
+use {Error, ErrorKind, FrugalosExistenceFilterConfig, Result};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+
+/// オブジェクトの ID を登録する Bloom filter。
+#[derive(Debug, Clone)]
+struct BloomFilter {
+    bits: Vec<u64>,
+    hash_count: u32,
+    capacity: usize,
+    len: usize,
+}
+impl BloomFilter {
+    /// `capacity`個の要素を登録した時点で、偽陽性率が`false_positive_rate`程度となるフィルタを生成する。
+    fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        let n = capacity.max(1) as f64;
+        let p = false_positive_rate.max(1e-9).min(0.5);
+        let bit_count = (-n * p.ln() / (LN_2 * LN_2)).ceil().max(64.0);
+        let hash_count = (bit_count / n * LN_2).round().max(1.0) as u32;
+        let words = (bit_count as usize + 63) / 64;
+        BloomFilter {
+            bits: vec![0; words],
+            hash_count,
+            capacity,
+            len: 0,
+        }
+    }
+
+    fn insert(&mut self, object_id: &str) {
+        let (h1, h2) = hash_pair(object_id);
+        for i in 0..self.hash_count {
+            let bit = self.bit_index(h1, h2, i);
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    fn may_contain(&self, object_id: &str) -> bool {
+        let (h1, h2) = hash_pair(object_id);
+        (0..self.hash_count).all(|i| {
+            let bit = self.bit_index(h1, h2, i);
+            self.bits[bit / 64] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    /// 登録回数が想定を超えていて、偽陽性率が目標値よりも悪化している場合に`true`を返す。
+    ///
+    /// 同じオブジェクトが複数回登録された場合も、それぞれ数えられる。
+    fn is_overloaded(&self) -> bool {
+        self.len > self.capacity
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    fn bit_index(&self, h1: u64, h2: u64, i: u32) -> usize {
+        let bit_count = self.bits.len() as u64 * 64;
+        (h1.wrapping_add(u64::from(i).wrapping_mul(h2)) % bit_count) as usize
+    }
+}
+
+// Kirsch-Mitzenmacher の手法で、二つのハッシュ値から各ハッシュ関数の値を導出する
+fn hash_pair(object_id: &str) -> (u64, u64) {
+    let mut h1 = SipHasher13::new_with_keys(0, 0);
+    object_id.hash(&mut h1);
+    let mut h2 = SipHasher13::new_with_keys(1, 1);
+    object_id.hash(&mut h2);
+    (h1.finish(), h2.finish() | 1)
+}
+
+/// あるセグメントのフィルタ。
+///
+/// 初期化の完了前や、変更履歴の一部が失われた後は`None`となり、全ての要求が MDS に問い合わせられる。
+#[derive(Debug, Clone, Default)]
+struct SegmentFilter(Arc<Mutex<Option<BloomFilter>>>);
+impl SegmentFilter {
+    fn with<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut Option<BloomFilter>) -> T,
+    {
+        let mut inner = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut inner)
+    }
+}
+
+/// フィルタを使った問い合わせの結果。
+pub(crate) enum ExistenceProbe {
+    /// フィルタが利用できない。
+    Unavailable,
+
+    /// オブジェクトが存在しないことが確実。
+    Absent,
+
+    /// オブジェクトが存在する可能性がある。
+    MaybePresent(Option<Arc<ExistenceFilterMetrics>>),
+}
+impl ExistenceProbe {
+    /// オブジェクトが存在しないことが確実な場合に`true`を返す。
+    pub fn is_absent(&self) -> bool {
+        if let ExistenceProbe::Absent = *self {
+            true
+        } else {
+            false
+        }
+    }
+
+    /// MDS に問い合わせた結果を報告する。
+    ///
+    /// 存在する可能性があると判定したオブジェクトが実際には存在しなかった場合には、偽陽性として数えられる。
+    pub fn observe(&self, found: bool) {
+        if let ExistenceProbe::MaybePresent(Some(ref metrics)) = *self {
+            if !found {
+                metrics.false_positives_total.increment();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct BucketFilters {
+    segments: Arc<Vec<SegmentFilter>>,
+    metrics: Option<Arc<ExistenceFilterMetrics>>,
+}
+
+/// 全てのバケツの存在フィルタ。
+///
+/// `Clone`されたインスタンス間で状態が共有される。
+#[derive(Clone, Default)]
+pub(crate) struct ExistenceFilters {
+    buckets: Arc<AtomicImmut<HashMap<BucketId, BucketFilters>>>,
+}
+impl ExistenceFilters {
+    /// `bucket_id`のバケツの`segment`に割り当てられるオブジェクトが、存在しないことが確実かどうかを調べる。
+    pub fn probe(
+        &self,
+        bucket_id: &str,
+        segment: usize,
+        object_id: &str,
+        consistency: &ReadConsistency,
+    ) -> ExistenceProbe {
+        match *consistency {
+            ReadConsistency::Consistent | ReadConsistency::Quorum => {
+                return ExistenceProbe::Unavailable;
+            }
+            ReadConsistency::Stale | ReadConsistency::Subset(_) => {}
+        }
+        let buckets = self.buckets.load();
+        let bucket = match buckets.get(bucket_id) {
+            None => return ExistenceProbe::Unavailable,
+            Some(bucket) => bucket,
+        };
+        let may_contain = match bucket.segments.get(segment) {
+            None => return ExistenceProbe::Unavailable,
+            Some(filter) => filter.with(|f| f.as_ref().map(|f| f.may_contain(object_id))),
+        };
+        match may_contain {
+            None => ExistenceProbe::Unavailable,
+            Some(true) => {
+                if let Some(ref metrics) = bucket.metrics {
+                    metrics.lookups_total.increment();
+                }
+                ExistenceProbe::MaybePresent(bucket.metrics.clone())
+            }
+            Some(false) => {
+                if let Some(ref metrics) = bucket.metrics {
+                    metrics.lookups_total.increment();
+                    metrics.absent_total.increment();
+                }
+                ExistenceProbe::Absent
+            }
+        }
+    }
+
+    /// このサーバ経由で保存されるオブジェクトを、変更履歴を待たずにフィルタに登録する。
+    pub fn insert(&self, bucket_id: &str, segment: usize, object_id: &str) {
+        let buckets = self.buckets.load();
+        if let Some(filter) = buckets.get(bucket_id).and_then(|b| b.segments.get(segment)) {
+            filter.with(|f| {
+                if let Some(f) = f.as_mut() {
+                    f.insert(object_id);
+                }
+            });
+        }
+    }
+
+    fn register(&self, bucket_id: BucketId, filters: BucketFilters) {
+        let mut buckets = (&*self.buckets.load()).clone();
+        buckets.insert(bucket_id, filters);
+        self.buckets.store(buckets);
+    }
+
+    /// 空のフィルタで初期化済みの状態として、`bucket_id`のバケツを登録する。
+    #[cfg(test)]
+    pub fn register_empty(&self, bucket_id: &str, segment_count: usize) {
+        let segments = (0..segment_count)
+            .map(|_| SegmentFilter(Arc::new(Mutex::new(Some(BloomFilter::new(100, 0.01))))))
+            .collect();
+        self.register(
+            bucket_id.to_owned(),
+            BucketFilters {
+                segments: Arc::new(segments),
+                metrics: None,
+            },
+        );
+    }
+}
+
+/// 設定されたバケツのフィルタの更新処理を開始する。
+pub(crate) fn spawn_updaters<S: Spawn>(
+    logger: &Logger,
+    client: &FrugalosClient,
+    config: &FrugalosExistenceFilterConfig,
+    spawner: &S,
+) -> Result<()> {
+    for bucket_id in &config.buckets {
+        let metrics = track!(ExistenceFilterMetrics::new(bucket_id))?;
+        let updater = ExistenceFilterUpdater::new(
+            logger.new(o!(logging::BUCKET => bucket_id.clone())),
+            client.clone(),
+            bucket_id.clone(),
+            config.clone(),
+            Arc::new(metrics),
+        );
+        let logger = updater.logger.clone();
+        spawner.spawn(updater.map_err(move |e| {
+            error!(
+                logger,
+                "Existence filter updater terminated abnormally: {}", e
+            );
+        }));
+    }
+    Ok(())
+}
+
+/// あるセグメントのフィルタの更新状態。
+struct SegmentSync {
+    segment: u16,
+    filter: SegmentFilter,
+    watch: Option<Watch>,
+
+    // 監視を再開する際に指定する位置
+    position: Option<ObjectVersion>,
+
+    // フィルタの(再)構築中に、一覧の取得と並行して監視で受け取った、追加されたオブジェクトの ID 群
+    //
+    // フィルタの容量は一覧の件数から決めるので、一覧の取得が完了するまではフィルタ自体は作らない.
+    building: Option<Vec<String>>,
+    listing: Option<BoxFuture<Vec<ObjectSummary>>>,
+
+    retry: Option<Timeout>,
+    memory_bytes: usize,
+}
+
+/// あるバケツのフィルタを、変更履歴に従って更新する`Future`。
+///
+/// バケツが存在しない間は、作成されるのを待つ。
+struct ExistenceFilterUpdater {
+    logger: Logger,
+    client: FrugalosClient,
+    bucket_id: BucketId,
+    config: FrugalosExistenceFilterConfig,
+    bucket: Option<BucketHandle>,
+    bucket_timeout: Option<Timeout>,
+    segments: Vec<SegmentSync>,
+    metrics: Arc<ExistenceFilterMetrics>,
+    memory: MemoryTracker,
+}
+impl ExistenceFilterUpdater {
+    fn new(
+        logger: Logger,
+        client: FrugalosClient,
+        bucket_id: BucketId,
+        config: FrugalosExistenceFilterConfig,
+        metrics: Arc<ExistenceFilterMetrics>,
+    ) -> Self {
+        ExistenceFilterUpdater {
+            logger,
+            client,
+            bucket_id,
+            config,
+            bucket: None,
+            bucket_timeout: None,
+            segments: Vec::new(),
+            metrics,
+            memory: MemoryTracker::new("existence_filters"),
+        }
+    }
+
+    fn poll_bucket(&mut self) -> Result<bool> {
+        while self.bucket.is_none() {
+            if let Some(mut timeout) = self.bucket_timeout.take() {
+                if let Async::NotReady = track!(timeout.poll().map_err(Error::from))? {
+                    self.bucket_timeout = Some(timeout);
+                    return Ok(false);
+                }
+            }
+            match self.client.bucket(&self.bucket_id) {
+                Ok(bucket) => {
+                    info!(
+                        self.logger,
+                        "Starts building existence filters: segments={}",
+                        bucket.segment_count()
+                    );
+                    self.segments = (0..bucket.segment_count())
+                        .map(|segment| SegmentSync {
+                            segment,
+                            filter: SegmentFilter::default(),
+                            watch: None,
+                            position: None,
+                            building: None,
+                            listing: None,
+                            retry: None,
+                            memory_bytes: 0,
+                        })
+                        .collect();
+                    let filters = BucketFilters {
+                        segments: Arc::new(
+                            self.segments.iter().map(|s| s.filter.clone()).collect(),
+                        ),
+                        metrics: Some(self.metrics.clone()),
+                    };
+                    self.client
+                        .existence_filters()
+                        .register(self.bucket_id.clone(), filters);
+                    self.bucket = Some(bucket);
+                }
+                Err(e) => {
+                    if *e.kind() != ErrorKind::NotFound {
+                        return Err(track!(e));
+                    }
+                    self.bucket_timeout = Some(timer::timeout(self.config.retry_interval));
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn poll_segment(&mut self, index: usize) -> Result<()> {
+        let bucket = self.bucket.as_ref().expect("Never fails");
+        let segment = &mut self.segments[index];
+        loop {
+            if let Some(mut retry) = segment.retry.take() {
+                if let Async::NotReady = track!(retry.poll().map_err(Error::from))? {
+                    segment.retry = Some(retry);
+                    return Ok(());
+                }
+            }
+            if segment.watch.is_none() {
+                let watch = track!(bucket.watch(segment.segment as usize, segment.position))?;
+                segment.watch = Some(
+                    watch
+                        .polling_interval(self.config.polling_interval)
+                        .max_polling_interval(self.config.max_polling_interval),
+                );
+            }
+
+            // フィルタが未構築の場合や、登録数が想定を超えた場合には(再)構築を開始する
+            if segment.building.is_none() {
+                let needs_build = segment.filter.with(|f| match *f {
+                    None => true,
+                    Some(ref f) => f.is_overloaded(),
+                });
+                if needs_build {
+                    segment.building = Some(Vec::new());
+                }
+            }
+
+            // 一覧の取得は、監視の開始位置が確定してから行う(取りこぼしを防ぐため)
+            if segment.building.is_some()
+                && segment.listing.is_none()
+                && segment.watch.as_ref().and_then(|w| w.position()).is_some()
+            {
+                segment.listing = Some(bucket.request().list(segment.segment as usize));
+            }
+
+            let mut progressed = false;
+            if let Some(mut listing) = segment.listing.take() {
+                match listing.poll() {
+                    Ok(Async::NotReady) => segment.listing = Some(listing),
+                    Ok(Async::Ready(objects)) => {
+                        let added = segment.building.take().expect("Never fails");
+
+                        // 構築直後に再び溢れないように、実際のオブジェクト数の倍の容量を確保する
+                        let len = objects.len() + added.len();
+                        let capacity = self.config.expected_objects_per_segment.max(len * 2);
+                        let mut filter =
+                            BloomFilter::new(capacity, self.config.false_positive_rate);
+                        for object in &objects {
+                            filter.insert(&object.id);
+                        }
+                        for object_id in &added {
+                            filter.insert(object_id);
+                        }
+                        debug!(
+                            self.logger,
+                            "Existence filter is built: {}",
+                            dump!(segment.segment, objects.len(), filter.memory_bytes())
+                        );
+                        segment.memory_bytes = filter.memory_bytes();
+                        self.metrics.rebuilds_total.increment();
+                        segment.filter.with(|f| *f = Some(filter));
+                        progressed = true;
+                    }
+                    Err(e) => {
+                        debug!(
+                            self.logger,
+                            "Cannot list objects: {}",
+                            dump!(segment.segment, e)
+                        );
+                        segment.building = None;
+                        segment.retry = Some(timer::timeout(self.config.retry_interval));
+                        continue;
+                    }
+                }
+            }
+
+            let result = segment.watch.as_mut().expect("Never fails").poll();
+            match result {
+                Ok(Async::NotReady) => {
+                    if !progressed {
+                        return Ok(());
+                    }
+                }
+                Ok(Async::Ready(Some(change))) => {
+                    segment.position = segment.watch.as_ref().and_then(|w| w.position());
+                    if let ObjectChangeKind::Put = change.kind {
+                        segment.filter.with(|f| {
+                            if let Some(f) = f.as_mut() {
+                                f.insert(&change.object_id);
+                            }
+                        });
+                        if let Some(ref mut building) = segment.building {
+                            building.push(change.object_id.clone());
+                        }
+                    }
+                }
+                Ok(Async::Ready(None)) => {
+                    track_panic!(ErrorKind::Other, "Watch stream terminated unexpectedly")
+                }
+                Err(e) => {
+                    if let frugalos_segment::ErrorKind::ChangesLost = *e.kind() {
+                        // 失われた変更に含まれていたオブジェクトを判定できないので、フィルタを破棄して構築し直す
+                        warn!(
+                            self.logger,
+                            "Some changes were lost; the existence filter will be rebuilt: {}",
+                            dump!(segment.segment, segment.position)
+                        );
+                        segment.filter.with(|f| *f = None);
+                        segment.building = None;
+                        segment.listing = None;
+                        segment.position = None;
+                    } else {
+                        debug!(
+                            self.logger,
+                            "Cannot watch changes: {}",
+                            dump!(segment.segment, e)
+                        );
+                    }
+                    segment.watch = None;
+                    segment.retry = Some(timer::timeout(self.config.retry_interval));
+                }
+            }
+        }
+    }
+}
+impl Future for ExistenceFilterUpdater {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if !track!(self.poll_bucket())? {
+            return Ok(Async::NotReady);
+        }
+        for i in 0..self.segments.len() {
+            track!(self.poll_segment(i))?;
+        }
+        let memory_bytes: usize = self.segments.iter().map(|s| s.memory_bytes).sum();
+        self.metrics.memory_bytes.set(memory_bytes as f64);
+
+        // 再構築中に受け取ったオブジェクトの ID 群も、フィルタに登録されるまでは別途メモリを占有する
+        let building_bytes: usize = self
+            .segments
+            .iter()
+            .filter_map(|s| s.building.as_ref())
+            .flat_map(|ids| ids.iter().map(String::len))
+            .sum();
+        self.memory.set((memory_bytes + building_bytes) as u64);
+        Ok(Async::NotReady)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ExistenceFilterMetrics {
+    lookups_total: Counter,
+    absent_total: Counter,
+    false_positives_total: Counter,
+    rebuilds_total: Counter,
+    memory_bytes: Gauge,
+}
+impl ExistenceFilterMetrics {
+    fn new(bucket_id: &str) -> Result<Self> {
+        let mut builder = MetricBuilder::new();
+        builder
+            .namespace("frugalos")
+            .subsystem("existence_filter")
+            .label("bucket", bucket_id);
+        Ok(ExistenceFilterMetrics {
+            lookups_total: track!(builder
+                .counter("lookups_total")
+                .help("Number of requests checked against the existence filter")
+                .default_registry()
+                .finish())?,
+            absent_total: track!(builder
+                .counter("absent_total")
+                .help("Number of requests answered locally since the object was definitely absent")
+                .default_registry()
+                .finish())?,
+            false_positives_total: track!(builder
+                .counter("false_positives_total")
+                .help("Number of requests for absent objects that the existence filter could not reject")
+                .default_registry()
+                .finish())?,
+            rebuilds_total: track!(builder
+                .counter("rebuilds_total")
+                .help("Number of times a segment's existence filter was (re)built from the object list")
+                .default_registry()
+                .finish())?,
+            memory_bytes: track!(builder
+                .gauge("memory_bytes")
+                .help("Memory used by the existence filters of the bucket")
+                .default_registry()
+                .finish())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bloom_filter_works() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            filter.insert(&format!("object-{}", i));
+        }
+        // 偽陰性は発生しない
+        assert!((0..1000).all(|i| filter.may_contain(&format!("object-{}", i))));
+        assert!(!filter.is_overloaded());
+
+        // 偽陽性率は概ね目標値に収まる
+        let false_positives = (0..10_000)
+            .filter(|i| filter.may_contain(&format!("absent-{}", i)))
+            .count();
+        assert!(false_positives < 300, "false_positives={}", false_positives);
+
+        filter.insert("object-1000");
+        assert!(filter.is_overloaded());
+    }
+
+    #[test]
+    fn bloom_filter_memory_bytes_works() {
+        // 1% の偽陽性率では、要素当たり約 9.6 ビットが必要となる
+        let filter = BloomFilter::new(100_000, 0.01);
+        assert_eq!(filter.hash_count, 7);
+        assert!(filter.memory_bytes() > 110_000 && filter.memory_bytes() < 130_000);
+    }
+
+    #[test]
+    fn existence_filters_works() {
+        let filters = ExistenceFilters::default();
+        let stale = ReadConsistency::Stale;
+        assert!(!filters.probe("foo", 0, "bar", &stale).is_absent());
+
+        let segment = SegmentFilter::default();
+        filters.register(
+            "foo".to_owned(),
+            BucketFilters {
+                segments: Arc::new(vec![segment.clone()]),
+                metrics: None,
+            },
+        );
+
+        // 初期化の完了前は、フィルタは使われない
+        assert!(!filters.probe("foo", 0, "bar", &stale).is_absent());
+
+        segment.with(|f| *f = Some(BloomFilter::new(100, 0.01)));
+        assert!(filters.probe("foo", 0, "bar", &stale).is_absent());
+        assert!(!filters
+            .probe("foo", 0, "bar", &ReadConsistency::Consistent)
+            .is_absent());
+        assert!(!filters
+            .probe("foo", 0, "bar", &ReadConsistency::Quorum)
+            .is_absent());
+
+        filters.insert("foo", 0, "bar");
+        assert!(!filters.probe("foo", 0, "bar", &stale).is_absent());
+        assert!(filters.probe("foo", 0, "baz", &stale).is_absent());
+    }
+}
@@ -0,0 +1,671 @@
+//! バケツ内の全オブジェクトを、ローカルファイル(アーカイブ)に書き出したり、そこから復元するためのモジュール。
+//!
+//! アーカイブは、先頭のマジックナンバーとフォーマットのバージョンに続いて、
+//! 長さ付きのフレームを並べた単純な形式となっている:
+//!
+//! ```text
+//! magic (16 bytes) | format version (u32) | header
+//! object* (tag=1 | metadata | content)
+//! trailer (tag=0 | object count (u64))
+//! ```
+//!
+//! ヘッダとメタデータは JSON (長さは u32)で、内容は生のバイト列(長さは u64)で書き込まれる。
+//! 数値は全てビッグエンディアンで表現する。
+//!
+//! アーカイブは、設定(`operation.archive_dir`)で指定されたディレクトリの下にのみ置くことができる。
+//!
+//! 書き出しは一時ファイル(アーカイブのパスに`.exporting`を付けたもの)に対して行われ、
+//! 全てのオブジェクトを書き終えた時点でリネームされる。
+//! 途中で失敗ないしキャンセルされた場合には、一時ファイルは削除される。
+use fibers_tasque::{DefaultIoTaskQueue, TaskQueueExt};
+use futures::future::{self, Loop};
+use futures::Future;
+use libfrugalos::entity::bucket::{BucketId, BucketKind};
+use libfrugalos::entity::object::{ObjectId, ObjectVersion};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use trackable::error::ErrorKindExt;
+
+use client::FrugalosClient;
+use operation::OperationHandle;
+use {Error, ErrorKind, Result};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+
+/// アーカイブの先頭に置かれるマジックナンバー。
+const ARCHIVE_MAGIC: &[u8; 16] = b"FRUGALOS-EXPORT\n";
+
+/// アーカイブのフォーマットのバージョン。
+const ARCHIVE_FORMAT_VERSION: u32 = 1;
+
+const TAG_END: u8 = 0;
+const TAG_OBJECT: u8 = 1;
+
+/// 一つのフレーム(ヘッダないしメタデータ)の最大サイズ。
+///
+/// 壊れたアーカイブを読み込んだ際に、巨大なメモリを確保してしまうことを防ぐ。
+const MAX_FRAME_SIZE: u32 = 1024 * 1024;
+
+/// 書き出し中のアーカイブのパスの末尾に付与する拡張子。
+const TEMPORARY_EXTENSION: &str = ".exporting";
+
+/// 要求で指定されたアーカイブのパス`path`を、アーカイブ用のディレクトリ`archive_dir`の下のパスに解決する。
+///
+/// `path`は`archive_dir`からの相対パスで、`..`等を含まない必要がある。
+/// `archive_dir`が設定されていない場合はエラーとなる。
+pub fn resolve_archive_path(archive_dir: Option<&Path>, path: &Path) -> Result<PathBuf> {
+    let archive_dir = track_assert_some!(
+        archive_dir,
+        ErrorKind::InvalidInput,
+        "`operation.archive_dir` is not configured on the server"
+    );
+    let is_plain = path.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    });
+    track_assert!(
+        is_plain && path.components().next().is_some(),
+        ErrorKind::InvalidInput,
+        "The path must be relative to the archive directory and must not contain `..`: {:?}",
+        path
+    );
+    Ok(archive_dir.join(path))
+}
+
+/// 書き出し中のアーカイブのパスを返す。
+///
+/// 既存の拡張子を置き換えると無関係なファイルと衝突し得るので、末尾に付け加える。
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temp = OsString::from(path.as_os_str());
+    temp.push(TEMPORARY_EXTENSION);
+    PathBuf::from(temp)
+}
+
+/// アーカイブのヘッダ。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveHeader {
+    /// 書き出し元のバケツの ID。
+    pub bucket_id: BucketId,
+
+    /// 書き出し元のバケツの種類。
+    pub bucket_kind: BucketKind,
+
+    /// 書き出し元のバケツのセグメント数。
+    pub segment_count: u16,
+
+    /// 書き出しを開始した時刻(UNIXエポックからの秒数)。
+    pub exported_at: u64,
+}
+
+/// アーカイブに含まれるオブジェクトのメタデータ。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedObject {
+    /// オブジェクトの ID。
+    pub object_id: ObjectId,
+
+    /// 書き出し元でのオブジェクトのバージョン。
+    ///
+    /// 復元先では新しいバージョンが割り当てられる。
+    pub version: ObjectVersion,
+
+    /// 書き出し元でオブジェクトが属していたセグメント。
+    pub segment: u16,
+
+    /// 内容のサイズ(バイト)。
+    pub size: u64,
+}
+
+/// バケツの書き出しの結果。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ExportSummary {
+    /// 書き出したオブジェクトの数。
+    pub objects: u64,
+
+    /// 書き出したオブジェクトの内容の合計サイズ(バイト)。
+    pub bytes: u64,
+
+    /// 一覧の取得後に削除されていたため、書き出さなかったオブジェクトの数。
+    pub skipped: u64,
+}
+
+/// バケツへの復元の結果。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImportSummary {
+    /// 書き出し元のバケツの ID。
+    pub source_bucket_id: BucketId,
+
+    /// 復元したオブジェクトの数。
+    pub objects: u64,
+
+    /// 復元したオブジェクトの内容の合計サイズ(バイト)。
+    pub bytes: u64,
+}
+
+/// アーカイブを書き出すためのオブジェクト。
+///
+/// `finish`が呼ばれずに破棄された場合には、書き出し途中のファイルを削除する。
+struct ArchiveWriter<W: Write> {
+    writer: Option<W>,
+    temp_path: Option<PathBuf>,
+    summary: ExportSummary,
+}
+impl ArchiveWriter<BufWriter<File>> {
+    /// `path`に書き出すアーカイブを作成する。
+    ///
+    /// `path`に既にファイルが存在する場合はエラーとなる。
+    fn create(path: &Path, header: &ArchiveHeader) -> Result<Self> {
+        track_assert!(
+            !path.exists(),
+            ErrorKind::InvalidInput,
+            "The archive already exists: {:?}",
+            path
+        );
+        // 他の書き出しの一時ファイル等を上書きしないように、新規作成のみを許す
+        let temp_path = temporary_path(path);
+        let file = track!(
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&temp_path)
+                .map_err(Error::from),
+            "path={:?}",
+            temp_path
+        )?;
+        let mut this = ArchiveWriter {
+            writer: None,
+            temp_path: Some(temp_path),
+            summary: ExportSummary::default(),
+        };
+        this.writer = Some(BufWriter::new(file));
+        track!(this.write_header(header))?;
+        Ok(this)
+    }
+
+    /// 書き出しを完了し、アーカイブを`path`に移動する。
+    fn finish(mut self, path: &Path) -> Result<ExportSummary> {
+        track!(self.write_trailer())?;
+        let file = track!(self
+            .writer
+            .take()
+            .expect("Never fails")
+            .into_inner()
+            .map_err(|e| Error::from(e.into_error())))?;
+        track!(file.sync_all().map_err(Error::from))?;
+        let temp_path = self.temp_path.take().expect("Never fails");
+        track!(
+            fs::rename(&temp_path, path).map_err(Error::from),
+            "from={:?}, to={:?}",
+            temp_path,
+            path
+        )?;
+        Ok(self.summary.clone())
+    }
+}
+impl<W: Write> ArchiveWriter<W> {
+    #[cfg(test)]
+    fn new(writer: W, header: &ArchiveHeader) -> Result<Self> {
+        let mut this = ArchiveWriter {
+            writer: Some(writer),
+            temp_path: None,
+            summary: ExportSummary::default(),
+        };
+        track!(this.write_header(header))?;
+        Ok(this)
+    }
+
+    fn write_object(&mut self, object: &ArchivedObject, content: &[u8]) -> Result<()> {
+        track_assert_eq!(object.size, content.len() as u64, ErrorKind::Other);
+        let w = self.writer.as_mut().expect("Never fails");
+        track!(w.write_all(&[TAG_OBJECT]).map_err(Error::from))?;
+        track!(write_json_frame(&mut *w, object))?;
+        track!(w.write_all(&object.size.to_be_bytes()).map_err(Error::from))?;
+        track!(w.write_all(content).map_err(Error::from))?;
+        self.summary.objects += 1;
+        self.summary.bytes += object.size;
+        Ok(())
+    }
+
+    fn write_header(&mut self, header: &ArchiveHeader) -> Result<()> {
+        let w = self.writer.as_mut().expect("Never fails");
+        track!(w.write_all(ARCHIVE_MAGIC).map_err(Error::from))?;
+        track!(w
+            .write_all(&ARCHIVE_FORMAT_VERSION.to_be_bytes())
+            .map_err(Error::from))?;
+        track!(write_json_frame(w, header))
+    }
+
+    fn write_trailer(&mut self) -> Result<()> {
+        let w = self.writer.as_mut().expect("Never fails");
+        track!(w.write_all(&[TAG_END]).map_err(Error::from))?;
+        track!(w
+            .write_all(&self.summary.objects.to_be_bytes())
+            .map_err(Error::from))?;
+        track!(w.flush().map_err(Error::from))
+    }
+}
+impl<W: Write> Drop for ArchiveWriter<W> {
+    fn drop(&mut self) {
+        if let Some(ref temp_path) = self.temp_path {
+            self.writer = None;
+            let _ = fs::remove_file(temp_path);
+        }
+    }
+}
+
+/// アーカイブを読み込むためのオブジェクト。
+struct ArchiveReader<R: Read> {
+    reader: CountingReader<R>,
+    objects: u64,
+    finished: bool,
+}
+impl ArchiveReader<BufReader<File>> {
+    /// `path`のアーカイブを開く。
+    fn open(path: &Path) -> Result<(Self, ArchiveHeader)> {
+        let file = track!(File::open(path).map_err(Error::from), "path={:?}", path)?;
+        track!(Self::new(BufReader::new(file)))
+    }
+}
+impl<R: Read> ArchiveReader<R> {
+    fn new(reader: R) -> Result<(Self, ArchiveHeader)> {
+        let mut reader = CountingReader {
+            inner: reader,
+            count: 0,
+        };
+        let mut magic = [0; 16];
+        track!(reader.read_exact(&mut magic).map_err(Error::from))?;
+        track_assert_eq!(
+            &magic,
+            ARCHIVE_MAGIC,
+            ErrorKind::InvalidInput,
+            "Not a frugalos archive"
+        );
+        let version = track!(read_u32(&mut reader))?;
+        track_assert_eq!(
+            version,
+            ARCHIVE_FORMAT_VERSION,
+            ErrorKind::InvalidInput,
+            "Unsupported archive format version"
+        );
+        let header = track!(read_json_frame(&mut reader))?;
+        let this = ArchiveReader {
+            reader,
+            objects: 0,
+            finished: false,
+        };
+        Ok((this, header))
+    }
+
+    /// アーカイブの先頭から読み込んだバイト数を返す。
+    fn position(&self) -> u64 {
+        self.reader.count
+    }
+
+    /// 次のオブジェクトを読み込む。
+    ///
+    /// 全てのオブジェクトを読み終えた場合には`None`を返す。
+    fn next_object(&mut self) -> Result<Option<(ArchivedObject, Vec<u8>)>> {
+        if self.finished {
+            return Ok(None);
+        }
+        let mut tag = [0; 1];
+        track!(self.reader.read_exact(&mut tag).map_err(Error::from))?;
+        match tag[0] {
+            TAG_OBJECT => {
+                let object: ArchivedObject = track!(read_json_frame(&mut self.reader))?;
+                let size = track!(read_u64(&mut self.reader))?;
+                track_assert_eq!(size, object.size, ErrorKind::InvalidInput; object);
+                let mut content = Vec::new();
+                track!((&mut self.reader)
+                    .take(size)
+                    .read_to_end(&mut content)
+                    .map_err(Error::from))?;
+                track_assert_eq!(
+                    content.len() as u64,
+                    size,
+                    ErrorKind::InvalidInput,
+                    "The archive is truncated: {:?}",
+                    object
+                );
+                self.objects += 1;
+                Ok(Some((object, content)))
+            }
+            TAG_END => {
+                let objects = track!(read_u64(&mut self.reader))?;
+                track_assert_eq!(
+                    objects,
+                    self.objects,
+                    ErrorKind::InvalidInput,
+                    "The number of objects does not match the trailer"
+                );
+                self.finished = true;
+                Ok(None)
+            }
+            tag => track_panic!(ErrorKind::InvalidInput, "Unknown tag: {}", tag),
+        }
+    }
+}
+
+/// 読み込んだバイト数を数える`Read`の実装。
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+fn write_json_frame<W: Write, T: Serialize>(mut writer: W, value: &T) -> Result<()> {
+    let bytes = track!(serde_json::to_vec(value).map_err(|e| ErrorKind::Other.cause(e)))?;
+    track_assert!(
+        bytes.len() <= MAX_FRAME_SIZE as usize,
+        ErrorKind::InvalidInput,
+        "Too large frame: {} bytes",
+        bytes.len()
+    );
+    track!(writer
+        .write_all(&(bytes.len() as u32).to_be_bytes())
+        .map_err(Error::from))?;
+    track!(writer.write_all(&bytes).map_err(Error::from))
+}
+
+fn read_json_frame<R: Read, T: DeserializeOwned>(mut reader: R) -> Result<T> {
+    let size = track!(read_u32(&mut reader))?;
+    track_assert!(size <= MAX_FRAME_SIZE, ErrorKind::InvalidInput; size);
+    let mut bytes = vec![0; size as usize];
+    track!(reader.read_exact(&mut bytes).map_err(Error::from))?;
+    let value =
+        track!(serde_json::from_slice(&bytes).map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+    Ok(value)
+}
+
+fn read_u32<R: Read>(mut reader: R) -> Result<u32> {
+    let mut bytes = [0; 4];
+    track!(reader.read_exact(&mut bytes).map_err(Error::from))?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64<R: Read>(mut reader: R) -> Result<u64> {
+    let mut bytes = [0; 8];
+    track!(reader.read_exact(&mut bytes).map_err(Error::from))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// バケツ内の全オブジェクトを、`path`のアーカイブに書き出す。
+///
+/// 全セグメントのオブジェクトの一覧を取得した後に、オブジェクトを一つずつ読み込んで書き出す。
+/// 進捗は、処理したオブジェクトの数として`operation`に報告される。
+/// 書き出しの途中で保存されたオブジェクトは、アーカイブに含まれないことがある。
+pub fn export_bucket(
+    client: &FrugalosClient,
+    bucket_id: &str,
+    path: PathBuf,
+    operation: OperationHandle,
+) -> BoxFuture<ExportSummary> {
+    let bucket = match track!(client.bucket(bucket_id)) {
+        Ok(bucket) => bucket,
+        Err(e) => return Box::new(future::failed(e)),
+    };
+    let header = ArchiveHeader {
+        bucket_id: bucket.id().clone(),
+        bucket_kind: bucket.kind().clone(),
+        segment_count: bucket.segment_count(),
+        exported_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    };
+    let lists = (0..bucket.segment_count())
+        .map(|segment| {
+            bucket
+                .request()
+                .list(segment as usize)
+                .map(move |objects| (segment, objects))
+        })
+        .collect::<Vec<_>>();
+    let future = future::join_all(lists).and_then(move |lists| {
+        let objects = lists
+            .into_iter()
+            .flat_map(|(segment, objects)| objects.into_iter().map(move |o| (segment, o.id)))
+            .collect::<Vec<_>>();
+        operation.set_total(objects.len() as u64);
+        let writer = io_call({
+            let path = path.clone();
+            move || track!(ArchiveWriter::create(&path, &header))
+        });
+        writer.and_then(move |writer| {
+            future::loop_fn(
+                (writer, objects.into_iter()),
+                move |(writer, mut objects)| {
+                    let (segment, object_id) = match objects.next() {
+                        None => return Box::new(future::ok(Loop::Break(writer))) as BoxFuture<_>,
+                        Some(o) => o,
+                    };
+                    if operation.is_cancelled() {
+                        let e = ErrorKind::Other.cause("The export has been cancelled");
+                        return Box::new(future::failed(e.into()));
+                    }
+                    let operation = operation.clone();
+                    let future = bucket
+                        .get(object_id.clone())
+                        .and_then(move |value| {
+                            io_call(move || {
+                                let mut writer = writer;
+                                if let Some(value) = value {
+                                    let object = ArchivedObject {
+                                        object_id,
+                                        version: value.version,
+                                        segment,
+                                        size: value.content.len() as u64,
+                                    };
+                                    track!(writer.write_object(&object, &value.content))?;
+                                } else {
+                                    writer.summary.skipped += 1;
+                                }
+                                Ok(writer)
+                            })
+                        })
+                        .map(move |writer| {
+                            operation.add_done(1);
+                            Loop::Continue((writer, objects))
+                        });
+                    Box::new(future)
+                },
+            )
+            .and_then(move |writer: ArchiveWriter<BufWriter<File>>| {
+                io_call(move || track!(writer.finish(&path)))
+            })
+        })
+    });
+    Box::new(future)
+}
+
+/// `path`のアーカイブに含まれる全オブジェクトを、`bucket_id`のバケツに保存する。
+///
+/// 復元先のバケツは、事前に作成しておく必要がある(書き出し元と同じ種類である必要はない)。
+/// 同じ ID のオブジェクトが既に存在する場合には上書きされる。
+/// 進捗は、アーカイブから読み込んだバイト数として`operation`に報告される。
+pub fn import_bucket(
+    client: &FrugalosClient,
+    bucket_id: &str,
+    path: PathBuf,
+    operation: OperationHandle,
+) -> BoxFuture<ImportSummary> {
+    let bucket = match track!(client.bucket(bucket_id)) {
+        Ok(bucket) => bucket,
+        Err(e) => return Box::new(future::failed(e)),
+    };
+    let reader = io_call(move || {
+        let size = track!(fs::metadata(&path).map_err(Error::from), "path={:?}", path)?.len();
+        let (reader, header) = track!(ArchiveReader::open(&path))?;
+        Ok((reader, header, size))
+    });
+    let future = reader.and_then(move |(reader, header, size)| {
+        operation.set_total(size);
+        let summary = ImportSummary {
+            source_bucket_id: header.bucket_id,
+            objects: 0,
+            bytes: 0,
+        };
+        operation.add_done(reader.position());
+        future::loop_fn((reader, summary), move |(reader, summary)| {
+            if operation.is_cancelled() {
+                let e = ErrorKind::Other.cause("The import has been cancelled");
+                return Box::new(future::failed(e.into())) as BoxFuture<_>;
+            }
+            let bucket = bucket.clone();
+            let operation = operation.clone();
+            let next = io_call(move || {
+                let mut reader = reader;
+                let start = reader.position();
+                let object = track!(reader.next_object())?;
+                Ok((reader, object, start))
+            });
+            let future = next.and_then(move |(reader, object, start)| {
+                operation.add_done(reader.position() - start);
+                let (object, content) = match object {
+                    None => return Box::new(future::ok(Loop::Break(summary))) as BoxFuture<_>,
+                    Some(object) => object,
+                };
+                let mut summary = summary;
+                summary.objects += 1;
+                summary.bytes += object.size;
+                let future = bucket
+                    .put(object.object_id, content)
+                    .map(move |_| Loop::Continue((reader, summary)));
+                Box::new(future)
+            });
+            Box::new(future)
+        })
+    });
+    Box::new(future)
+}
+
+/// ブロッキングするファイル操作を、I/O 用のスレッドプールで実行する。
+fn io_call<F, T>(f: F) -> BoxFuture<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let future = DefaultIoTaskQueue
+        .async_call(f)
+        .map_err(|e| track!(Error::from(e)))
+        .and_then(|result| result);
+    Box::new(future)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tempdir::TempDir;
+    use trackable::result::TestResult;
+
+    fn header() -> ArchiveHeader {
+        ArchiveHeader {
+            bucket_id: "foo".to_owned(),
+            bucket_kind: BucketKind::Dispersed,
+            segment_count: 2,
+            exported_at: 0,
+        }
+    }
+
+    fn object(id: &str, version: u64, content: &[u8]) -> ArchivedObject {
+        ArchivedObject {
+            object_id: id.to_owned(),
+            version: ObjectVersion(version),
+            segment: 1,
+            size: content.len() as u64,
+        }
+    }
+
+    #[test]
+    fn archive_works() -> Result<()> {
+        let mut writer = track!(ArchiveWriter::new(Vec::new(), &header()))?;
+        track!(writer.write_object(&object("bar", 3, b"hello"), b"hello"))?;
+        track!(writer.write_object(&object("baz", 4, b""), b""))?;
+        track!(writer.write_trailer())?;
+        let bytes = writer.writer.take().expect("Never fails");
+        assert_eq!(writer.summary.objects, 2);
+        assert_eq!(writer.summary.bytes, 5);
+
+        let (mut reader, header) = track!(ArchiveReader::new(Cursor::new(&bytes)))?;
+        assert_eq!(header.bucket_id, "foo");
+        assert_eq!(header.segment_count, 2);
+        assert_eq!(
+            track!(reader.next_object())?,
+            Some((object("bar", 3, b"hello"), b"hello".to_vec()))
+        );
+        assert_eq!(
+            track!(reader.next_object())?,
+            Some((object("baz", 4, b""), Vec::new()))
+        );
+        assert_eq!(track!(reader.next_object())?, None);
+        assert_eq!(reader.position(), bytes.len() as u64);
+
+        // 途中で切れたアーカイブ
+        let (mut reader, _) = track!(ArchiveReader::new(Cursor::new(&bytes[..bytes.len() - 20])))?;
+        assert!(reader.next_object().is_ok());
+        assert!(reader.next_object().is_err());
+
+        // アーカイブではないファイル
+        assert!(ArchiveReader::new(Cursor::new(b"hello world")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unfinished_archive_is_removed() -> TestResult {
+        let dir = track_any_err!(TempDir::new("frugalos_test"))?;
+        let path = dir.path().join("foo.archive");
+
+        let writer = track!(ArchiveWriter::create(&path, &header()))?;
+        assert!(temporary_path(&path).exists());
+        std::mem::drop(writer);
+        assert!(!temporary_path(&path).exists());
+        assert!(!path.exists());
+
+        let mut writer = track!(ArchiveWriter::create(&path, &header()))?;
+        track!(writer.write_object(&object("bar", 3, b"hello"), b"hello"))?;
+        let summary = track!(writer.finish(&path))?;
+        assert_eq!(summary.objects, 1);
+        assert!(path.exists());
+        assert!(!temporary_path(&path).exists());
+
+        // 既存のアーカイブは上書きしない
+        assert!(ArchiveWriter::create(&path, &header()).is_err());
+
+        // 拡張子だけが異なる無関係なファイルは上書きしない
+        let other = dir.path().join("bar.exporting");
+        track_any_err!(fs::write(&other, b"unrelated"))?;
+        let writer = track!(ArchiveWriter::create(
+            &dir.path().join("bar.archive"),
+            &header()
+        ))?;
+        std::mem::drop(writer);
+        assert_eq!(track_any_err!(fs::read(&other))?, b"unrelated");
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_archive_path_works() {
+        let dir = Path::new("/var/lib/frugalos/archives");
+        assert_eq!(
+            resolve_archive_path(Some(dir), Path::new("foo/bar.archive")).ok(),
+            Some(dir.join("foo/bar.archive"))
+        );
+        assert!(resolve_archive_path(None, Path::new("bar.archive")).is_err());
+        assert!(resolve_archive_path(Some(dir), Path::new("/etc/passwd")).is_err());
+        assert!(resolve_archive_path(Some(dir), Path::new("../bar.archive")).is_err());
+        assert!(resolve_archive_path(Some(dir), Path::new("foo/../../bar")).is_err());
+        assert!(resolve_archive_path(Some(dir), Path::new("")).is_err());
+    }
+}
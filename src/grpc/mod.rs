@@ -0,0 +1,720 @@
+//! `schema/frugalos_api.proto`で定義される gRPC の API を提供するサーバ。
+//!
+//! `grpc`フィーチャを有効にしてビルドした場合にのみ利用できる。
+//! 各操作は、RPC サーバ(`RpcServer`)と同じ認可・受け付け制御の下で`FrugalosClient`に委譲される。
+//! gRPC のスレッドではリクエストの受け取りと結果の返送のみを行い、処理自体は fibers の executor 上で実行する。
+//!
+//! トークンは`authorization: Bearer <token>`メタデータで提示する。
+use bytecodec::{DecodeExt, EncodeExt};
+use cannyls::deadline::Deadline;
+use fibers::executor::ThreadPoolExecutorHandle;
+use fibers::Spawn;
+use frugalos_core::tracer::SpanExt;
+use futures::{self, Future, IntoFuture, Stream};
+use futures03;
+use futures03::channel::oneshot;
+use futures03::future::Either;
+use futures03::{FutureExt, StreamExt};
+use grpcio::{
+    self, Environment, GrpcSlice, Marshaller, MessageReader, Method, MethodType, RpcContext,
+    RpcStatus, RpcStatusCode, ServerBuilder, ServerStreamingSink, ServiceBuilder, UnarySink,
+    WriteFlags,
+};
+use libfrugalos::entity::bucket::{BucketId, BucketKind};
+use libfrugalos::entity::object::ObjectPrefix;
+use protobuf_codec::message::{MessageDecode, MessageEncode};
+use std::io::Read;
+use std::net::SocketAddr;
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+
+use self::protobuf::BucketSummary;
+use admission::RequestKind;
+use auth::{self, Permission};
+use client::Request;
+use rpc_server::RpcServer;
+use {Error, ErrorKind, FrugalosGrpcServerConfig, Result};
+
+mod protobuf;
+
+// メッセージのデコード・エンコードは、エラーを gRPC のステータスとして返せるようにハンドラ内で行う
+const BYTES: Marshaller<Vec<u8>> = Marshaller {
+    ser: ser_bytes,
+    de: de_bytes,
+};
+
+const GET_OBJECT: Method<Vec<u8>, Vec<u8>> = Method {
+    ty: MethodType::Unary,
+    name: "/frugalos.v1.Objects/GetObject",
+    req_mar: BYTES,
+    resp_mar: BYTES,
+};
+const HEAD_OBJECT: Method<Vec<u8>, Vec<u8>> = Method {
+    ty: MethodType::Unary,
+    name: "/frugalos.v1.Objects/HeadObject",
+    req_mar: BYTES,
+    resp_mar: BYTES,
+};
+const PUT_OBJECT: Method<Vec<u8>, Vec<u8>> = Method {
+    ty: MethodType::Unary,
+    name: "/frugalos.v1.Objects/PutObject",
+    req_mar: BYTES,
+    resp_mar: BYTES,
+};
+const DELETE_OBJECT: Method<Vec<u8>, Vec<u8>> = Method {
+    ty: MethodType::Unary,
+    name: "/frugalos.v1.Objects/DeleteObject",
+    req_mar: BYTES,
+    resp_mar: BYTES,
+};
+const LIST_OBJECTS: Method<Vec<u8>, Vec<u8>> = Method {
+    ty: MethodType::ServerStreaming,
+    name: "/frugalos.v1.Objects/ListObjects",
+    req_mar: BYTES,
+    resp_mar: BYTES,
+};
+const DELETE_OBJECTS_BY_PREFIX: Method<Vec<u8>, Vec<u8>> = Method {
+    ty: MethodType::Unary,
+    name: "/frugalos.v1.Objects/DeleteObjectsByPrefix",
+    req_mar: BYTES,
+    resp_mar: BYTES,
+};
+const LIST_BUCKETS: Method<Vec<u8>, Vec<u8>> = Method {
+    ty: MethodType::Unary,
+    name: "/frugalos.v1.Buckets/ListBuckets",
+    req_mar: BYTES,
+    resp_mar: BYTES,
+};
+const GET_BUCKET: Method<Vec<u8>, Vec<u8>> = Method {
+    ty: MethodType::Unary,
+    name: "/frugalos.v1.Buckets/GetBucket",
+    req_mar: BYTES,
+    resp_mar: BYTES,
+};
+const GET_BUCKET_STATISTICS: Method<Vec<u8>, Vec<u8>> = Method {
+    ty: MethodType::Unary,
+    name: "/frugalos.v1.Buckets/GetBucketStatistics",
+    req_mar: BYTES,
+    resp_mar: BYTES,
+};
+
+// `add_*_handler`に渡すクロージャを作る
+macro_rules! handler {
+    ($handler:expr, $method:ident) => {{
+        let handler = $handler.clone();
+        move |ctx, req, sink| handler.$method(&ctx, req, sink)
+    }};
+}
+
+// デコードに失敗した場合には、`INVALID_ARGUMENT`を返して呼び出しを終える
+macro_rules! try_decode {
+    ($ctx:expr, $sink:expr, $decoder:expr, $bytes:expr) => {
+        match track!(decode($decoder, $bytes)) {
+            Err(e) => {
+                $ctx.spawn($sink.fail(into_rpc_status(e)).map(|_| ()));
+                return;
+            }
+            Ok(request) => request,
+        }
+    };
+}
+
+/// gRPC サーバを作成する。
+///
+/// アドレスへの bind はここで行われるが、リクエストの受け付けは`grpcio::Server::start`の呼び出し後に始まる。
+/// 返り値のサーバが破棄されると、gRPC サーバも停止する。
+pub fn build(
+    bind_addr: SocketAddr,
+    config: &FrugalosGrpcServerConfig,
+    rpc: RpcServer,
+    executor: ThreadPoolExecutorHandle,
+) -> Result<grpcio::Server> {
+    let handler = GrpcHandler { rpc, executor };
+    let objects = ServiceBuilder::new()
+        .add_unary_handler(&GET_OBJECT, handler!(handler, get_object))
+        .add_unary_handler(&HEAD_OBJECT, handler!(handler, head_object))
+        .add_unary_handler(&PUT_OBJECT, handler!(handler, put_object))
+        .add_unary_handler(&DELETE_OBJECT, handler!(handler, delete_object))
+        .add_server_streaming_handler(&LIST_OBJECTS, handler!(handler, list_objects))
+        .add_unary_handler(
+            &DELETE_OBJECTS_BY_PREFIX,
+            handler!(handler, delete_objects_by_prefix),
+        )
+        .build();
+    let buckets = ServiceBuilder::new()
+        .add_unary_handler(&LIST_BUCKETS, handler!(handler, list_buckets))
+        .add_unary_handler(&GET_BUCKET, handler!(handler, get_bucket))
+        .add_unary_handler(
+            &GET_BUCKET_STATISTICS,
+            handler!(handler, get_bucket_statistics),
+        )
+        .build();
+
+    let env = Arc::new(Environment::new(config.completion_queues));
+    let server = track!(ServerBuilder::new(env)
+        .register_service(objects)
+        .register_service(buckets)
+        .bind(bind_addr.ip().to_string(), bind_addr.port())
+        .build()
+        .map_err(|e| ErrorKind::Other.cause(e)))?;
+    Ok(server)
+}
+
+#[derive(Clone)]
+struct GrpcHandler {
+    rpc: RpcServer,
+    executor: ThreadPoolExecutorHandle,
+}
+impl GrpcHandler {
+    fn get_object(&self, ctx: &RpcContext, req: Vec<u8>, sink: UnarySink<Vec<u8>>) {
+        let request = try_decode!(ctx, sink, protobuf::object_request_decoder(), &req);
+        let rpc = self.rpc.with_token(bearer_token(ctx));
+        let rx = self.execute(move || -> Result<_> {
+            track!(rpc.authorize_bucket(&request.bucket_id, Permission::Read))?;
+            let permit = track!(rpc.admit(&request.bucket_id, RequestKind::Get))?;
+            let mut span =
+                rpc.object_span("get_object_grpc", &request.bucket_id, &request.object_id);
+            let future = client_request(&rpc, request.bucket_id, request.deadline)
+                .expect(request.expect)
+                .span(&span)
+                .get(request.object_id, request.consistency);
+            Ok(future.then(move |result| {
+                drop(permit);
+                if let Err(ref e) = result {
+                    span.log_error(e);
+                }
+                result.map(|o| o.map(|o| (o.version, o.content)))
+            }))
+        });
+        reply(ctx, sink, rx, protobuf::get_object_response_encoder());
+    }
+
+    fn head_object(&self, ctx: &RpcContext, req: Vec<u8>, sink: UnarySink<Vec<u8>>) {
+        let request = try_decode!(ctx, sink, protobuf::head_object_request_decoder(), &req);
+        let rpc = self.rpc.with_token(bearer_token(ctx));
+        let rx = self.execute(move || -> Result<_> {
+            track!(rpc.authorize_bucket(&request.bucket_id, Permission::Read))?;
+            let permit = track!(rpc.admit(&request.bucket_id, RequestKind::Get))?;
+            let mut head_request = client_request(&rpc, request.bucket_id, request.deadline);
+            head_request.expect(request.expect);
+            let future = if request.check_storage {
+                head_request.head_storage(request.object_id, request.consistency)
+            } else {
+                head_request.head(request.object_id, request.consistency)
+            };
+            Ok(future.then(move |result| {
+                drop(permit);
+                result
+            }))
+        });
+        reply(ctx, sink, rx, protobuf::object_version_response_encoder());
+    }
+
+    fn put_object(&self, ctx: &RpcContext, req: Vec<u8>, sink: UnarySink<Vec<u8>>) {
+        let request = try_decode!(ctx, sink, protobuf::put_object_request_decoder(), &req);
+        let rpc = self.rpc.with_token(bearer_token(ctx));
+        let rx = self.execute(move || -> Result<_> {
+            track!(rpc.authorize_bucket(&request.bucket_id, Permission::Write))?;
+            let permit = track!(rpc.admit(&request.bucket_id, RequestKind::Put))?;
+            let mut span =
+                rpc.object_span("put_object_grpc", &request.bucket_id, &request.object_id);
+            let future = client_request(&rpc, request.bucket_id, request.deadline)
+                .expect(request.expect)
+                .span(&span)
+                .put(request.object_id, request.content);
+            Ok(future.then(move |result| {
+                drop(permit);
+                if let Err(ref e) = result {
+                    span.log_error(e);
+                }
+                result.map(|(version, created, _)| (version, created))
+            }))
+        });
+        reply(ctx, sink, rx, protobuf::put_object_response_encoder());
+    }
+
+    fn delete_object(&self, ctx: &RpcContext, req: Vec<u8>, sink: UnarySink<Vec<u8>>) {
+        let request = try_decode!(ctx, sink, protobuf::object_request_decoder(), &req);
+        let rpc = self.rpc.with_token(bearer_token(ctx));
+        let rx = self.execute(move || -> Result<_> {
+            track!(rpc.authorize_bucket(&request.bucket_id, Permission::Write))?;
+            let permit = track!(rpc.admit(&request.bucket_id, RequestKind::Put))?;
+            let mut span =
+                rpc.object_span("delete_object_grpc", &request.bucket_id, &request.object_id);
+            let future = client_request(&rpc, request.bucket_id, request.deadline)
+                .expect(request.expect)
+                .span(&span)
+                .delete(request.object_id);
+            Ok(future.then(move |result| {
+                drop(permit);
+                if let Err(ref e) = result {
+                    span.log_error(e);
+                }
+                result
+            }))
+        });
+        reply(ctx, sink, rx, protobuf::object_version_response_encoder());
+    }
+
+    fn list_objects(&self, ctx: &RpcContext, req: Vec<u8>, sink: ServerStreamingSink<Vec<u8>>) {
+        let request = try_decode!(ctx, sink, protobuf::segment_request_decoder(), &req);
+        let rpc = self.rpc.with_token(bearer_token(ctx));
+        let rx = self.execute(move || -> Result<_> {
+            track!(rpc.authorize_bucket(&request.bucket_id, Permission::Read))?;
+            let future = rpc
+                .client()
+                .request(request.bucket_id)
+                .list(request.segment as usize);
+            Ok(future)
+        });
+        reply_stream(ctx, sink, rx, protobuf::object_summary_encoder());
+    }
+
+    fn delete_objects_by_prefix(&self, ctx: &RpcContext, req: Vec<u8>, sink: UnarySink<Vec<u8>>) {
+        let request = try_decode!(ctx, sink, protobuf::prefix_request_decoder(), &req);
+        let rpc = self.rpc.with_token(bearer_token(ctx));
+        let rx = self.execute(move || -> Result<_> {
+            track!(rpc.authorize_bucket(&request.bucket_id, Permission::Write))?;
+            let future = client_request(&rpc, request.bucket_id, request.deadline)
+                .delete_by_prefix(ObjectPrefix(request.prefix));
+            Ok(future.map(|summary| summary.total))
+        });
+        reply(
+            ctx,
+            sink,
+            rx,
+            protobuf::delete_objects_by_prefix_response_encoder(),
+        );
+    }
+
+    fn list_buckets(&self, ctx: &RpcContext, req: Vec<u8>, sink: UnarySink<Vec<u8>>) {
+        try_decode!(ctx, sink, protobuf::list_buckets_request_decoder(), &req);
+        let rpc = self.rpc.with_token(bearer_token(ctx));
+        let rx = self.execute(move || -> Result<_> {
+            track!(rpc.authorize_cluster(Permission::Read))?;
+            let mut bucket_ids = rpc.client().bucket_ids();
+            bucket_ids.sort();
+            Ok(futures::finished(bucket_ids))
+        });
+        reply(ctx, sink, rx, protobuf::list_buckets_response_encoder());
+    }
+
+    fn get_bucket(&self, ctx: &RpcContext, req: Vec<u8>, sink: UnarySink<Vec<u8>>) {
+        let bucket_id = try_decode!(ctx, sink, protobuf::bucket_request_decoder(), &req);
+        let rpc = self.rpc.with_token(bearer_token(ctx));
+        let rx = self.execute(move || -> Result<_> {
+            track!(rpc.authorize_bucket(&bucket_id, Permission::Read))?;
+            let bucket = track!(rpc.client().bucket(&bucket_id))?;
+            let kind = match *bucket.kind() {
+                BucketKind::Metadata => "metadata",
+                BucketKind::Replicated => "replicated",
+                BucketKind::Dispersed => "dispersed",
+            };
+            let summary = BucketSummary {
+                bucket_id,
+                kind,
+                segment_count: u32::from(bucket.segment_count()),
+            };
+            Ok(futures::finished(summary))
+        });
+        reply(ctx, sink, rx, protobuf::bucket_response_encoder());
+    }
+
+    fn get_bucket_statistics(&self, ctx: &RpcContext, req: Vec<u8>, sink: UnarySink<Vec<u8>>) {
+        let bucket_id = try_decode!(ctx, sink, protobuf::bucket_request_decoder(), &req);
+        let rpc = self.rpc.with_token(bearer_token(ctx));
+        let rx = self.execute(move || -> Result<_> {
+            track!(rpc.authorize_bucket(&bucket_id, Permission::Read))?;
+            let segments = track_assert_some!(
+                rpc.client().segment_count(&bucket_id),
+                ErrorKind::NotFound,
+                "No such bucket: {:?}",
+                bucket_id
+            );
+            let client = rpc.client().clone();
+            let future = futures::stream::iter_ok(0..segments)
+                .and_then(move |segment| {
+                    let request = client.request(bucket_id.clone());
+                    request
+                        .object_count(segment as usize)
+                        .map_err(|e| track!(e))
+                })
+                .fold(0, |total, objects| -> Result<_> { Ok(total + objects) });
+            Ok(future)
+        });
+        reply(ctx, sink, rx, protobuf::bucket_statistics_encoder());
+    }
+
+    /// リクエストの処理を executor 上で実行し、結果を受け取るためのチャンネルを返す。
+    ///
+    /// `FrugalosClient`の処理は fibers のタイマ等を使うので、gRPC のスレッドでは実行できない。
+    fn execute<F, B>(&self, f: F) -> oneshot::Receiver<Result<B::Item>>
+    where
+        F: FnOnce() -> Result<B> + Send + 'static,
+        B: IntoFuture<Error = Error> + Send + 'static,
+        B::Future: Send + 'static,
+        B::Item: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let future = futures::lazy(f)
+            .and_then(|future| future)
+            .then(move |result| {
+                let _ = tx.send(result);
+                Ok(())
+            });
+        self.executor.spawn(future);
+        rx
+    }
+}
+
+// デッドラインが省略された場合には、バケツのデフォルト値が使われる
+fn client_request(rpc: &RpcServer, bucket_id: BucketId, deadline: Option<Duration>) -> Request {
+    let mut request = rpc.client().request(bucket_id);
+    request.deadline(deadline.map(Deadline::Within));
+    request
+}
+
+fn reply<T, E>(
+    ctx: &RpcContext,
+    sink: UnarySink<Vec<u8>>,
+    rx: oneshot::Receiver<Result<T>>,
+    mut encoder: E,
+) where
+    T: Send + 'static,
+    E: MessageEncode<Item = T> + Send + 'static,
+{
+    let future = rx.then(move |result| {
+        let result = flatten_result(result)
+            .and_then(|item| track!(encode(&mut encoder, item)).map_err(into_rpc_status));
+        let future = match result {
+            Ok(bytes) => sink.success(bytes),
+            Err(status) => sink.fail(status),
+        };
+        future.map(|_| ())
+    });
+    ctx.spawn(future);
+}
+
+fn reply_stream<T, E>(
+    ctx: &RpcContext,
+    sink: ServerStreamingSink<Vec<u8>>,
+    rx: oneshot::Receiver<Result<Vec<T>>>,
+    mut encoder: E,
+) where
+    T: Send + 'static,
+    E: MessageEncode<Item = T> + Send + 'static,
+{
+    let future = rx.then(move |result| {
+        let result = flatten_result(result).and_then(|items| {
+            let mut messages = Vec::with_capacity(items.len());
+            for item in items {
+                let bytes = track!(encode(&mut encoder, item)).map_err(into_rpc_status)?;
+                messages.push(Ok::<_, grpcio::Error>((bytes, WriteFlags::default())));
+            }
+            Ok(messages)
+        });
+        match result {
+            Ok(messages) => {
+                let future = futures03::stream::iter(messages).forward(sink);
+                Either::Left(future.map(|_| ()))
+            }
+            Err(status) => Either::Right(sink.fail(status).map(|_| ())),
+        }
+    });
+    ctx.spawn(future);
+}
+
+fn flatten_result<T>(
+    result: ::std::result::Result<Result<T>, oneshot::Canceled>,
+) -> ::std::result::Result<T, RpcStatus> {
+    match result {
+        Err(_) => Err(RpcStatus::with_message(
+            RpcStatusCode::UNAVAILABLE,
+            "The request was canceled".to_owned(),
+        )),
+        Ok(result) => result.map_err(into_rpc_status),
+    }
+}
+
+fn decode<D: MessageDecode>(mut decoder: D, bytes: &[u8]) -> Result<D::Item> {
+    let item = track!(decoder
+        .decode_from_bytes(bytes)
+        .map_err(|e| ErrorKind::InvalidInput.takes_over(e)))?;
+    Ok(item)
+}
+
+fn encode<E: MessageEncode>(encoder: &mut E, item: E::Item) -> Result<Vec<u8>> {
+    let bytes = track!(encoder
+        .encode_into_bytes(item)
+        .map_err(|e| ErrorKind::Other.takes_over(e)))?;
+    Ok(bytes)
+}
+
+// `authorization: Bearer <token>`メタデータからトークンを取り出す
+fn bearer_token(ctx: &RpcContext) -> Option<String> {
+    ctx.request_headers()
+        .iter()
+        .find(|&(key, _)| key.eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| str::from_utf8(value).ok())
+        .and_then(auth::parse_bearer_token)
+}
+
+fn into_rpc_status(e: Error) -> RpcStatus {
+    let code = match *e.kind() {
+        ErrorKind::InvalidInput => RpcStatusCode::INVALID_ARGUMENT,
+        ErrorKind::NotFound => RpcStatusCode::NOT_FOUND,
+        ErrorKind::Unexpected(_) => RpcStatusCode::FAILED_PRECONDITION,
+        ErrorKind::QuotaExceeded => RpcStatusCode::RESOURCE_EXHAUSTED,
+        ErrorKind::ReadOnly => RpcStatusCode::FAILED_PRECONDITION,
+        ErrorKind::Unauthenticated => RpcStatusCode::UNAUTHENTICATED,
+        ErrorKind::PermissionDenied => RpcStatusCode::PERMISSION_DENIED,
+        ErrorKind::Busy(_) => RpcStatusCode::UNAVAILABLE,
+        ErrorKind::Other => RpcStatusCode::INTERNAL,
+    };
+    RpcStatus::with_message(code, e.to_string())
+}
+
+#[allow(clippy::ptr_arg)]
+fn ser_bytes(bytes: &Vec<u8>, buf: &mut GrpcSlice) -> grpcio::Result<()> {
+    *buf = GrpcSlice::from(&bytes[..]);
+    Ok(())
+}
+
+fn de_bytes(mut reader: MessageReader) -> grpcio::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(reader.len());
+    reader
+        .read_to_end(&mut bytes)
+        .map_err(|e| grpcio::Error::Codec(Box::new(e)))?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use atomic_immut::AtomicImmut;
+    use fibers::{Executor, ThreadPoolExecutor};
+    use frugalos_segment::{ContentCacheSizing, MaintenanceSchedule};
+    use grpcio::{CallOption, ChannelBuilder, Client, MetadataBuilder};
+    use libfrugalos::consistency::ReadConsistency;
+    use libfrugalos::entity::bucket::{Bucket as BucketConfig, MetadataBucket};
+    use protobuf_codec::field::num::{F1, F2, F3};
+    use protobuf_codec::scalar::{BytesEncoder, StringEncoder, Uint64Encoder};
+    use std::collections::{BTreeMap, HashMap};
+    use std::thread;
+    use trackable::result::TestResult;
+
+    use super::*;
+    use auth::{BucketAcl, BucketAclAuthorizer, SharedAuthorizer};
+    use bucket::{Bucket, RequestDefaults};
+    use client::{BucketDefaults, FrugalosClient};
+
+    /// メタデータ用バケツ`meta`を持つ`RpcServer`を生成する。
+    ///
+    /// `alice`はバケツとクラスタの参照のみを許可され、`bob`はいずれの権限も持たない。
+    /// バケツの存在フィルタは空なので、`Stale`での取得は MDS に問い合わせずに「存在しない」と応答される。
+    fn rpc_server() -> Result<RpcServer> {
+        let meta = BucketConfig::Metadata(MetadataBucket {
+            id: "meta".to_owned(),
+            seqno: 0,
+            device: "root".to_owned(),
+            segment_count: 1,
+            tolerable_faults: 2,
+        });
+        let mut buckets = HashMap::new();
+        buckets.insert(
+            "meta".to_owned(),
+            track!(Bucket::for_test(&meta, RequestDefaults::default()))?,
+        );
+        let client = FrugalosClient::new(
+            Arc::new(AtomicImmut::new(buckets)),
+            ContentCacheSizing::default(),
+            MaintenanceSchedule::default(),
+        )
+        .with_bucket_defaults(BucketDefaults {
+            deadline: Deadline::Within(Duration::from_millis(3000)),
+            consistency: ReadConsistency::Stale,
+        });
+        client.existence_filters().register_empty("meta", 1);
+
+        let mut tokens = BTreeMap::new();
+        tokens.insert("alice".to_owned(), "alice-token".to_owned());
+        tokens.insert("bob".to_owned(), "bob-token".to_owned());
+        let acl = BucketAcl {
+            read: vec!["alice".to_owned()],
+            ..BucketAcl::default()
+        };
+        let authorizer = track!(BucketAclAuthorizer::new(&tokens, BTreeMap::new(), acl))?;
+        Ok(RpcServer::for_test(
+            client,
+            SharedAuthorizer::new(Arc::new(authorizer)),
+        ))
+    }
+
+    /// gRPC サーバを起動して、それに接続したクライアントを返す。
+    fn grpc_client(rpc: RpcServer) -> Result<(grpcio::Server, Client)> {
+        let executor = track!(ThreadPoolExecutor::with_thread_count(1).map_err(Error::from))?;
+        let handle = executor.handle();
+        thread::spawn(move || {
+            let _ = executor.run();
+        });
+
+        let config = FrugalosGrpcServerConfig {
+            completion_queues: 1,
+            ..FrugalosGrpcServerConfig::default()
+        };
+        let mut server = track!(build(([127, 0, 0, 1], 0).into(), &config, rpc, handle))?;
+        server.start();
+        let port = server.bind_addrs().next().expect("Never fails").1;
+
+        let env = Arc::new(Environment::new(1));
+        let channel = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+        Ok((server, Client::new(channel)))
+    }
+
+    fn call(
+        client: &Client,
+        method: &Method<Vec<u8>, Vec<u8>>,
+        token: Option<&str>,
+        request: Vec<u8>,
+    ) -> ::std::result::Result<Vec<u8>, RpcStatusCode> {
+        let mut option = CallOption::default().timeout(Duration::from_secs(10));
+        if let Some(token) = token {
+            let mut metadata = MetadataBuilder::new();
+            metadata
+                .add_str("authorization", &format!("Bearer {}", token))
+                .expect("Never fails");
+            option = option.headers(metadata.build());
+        }
+        client
+            .unary_call(method, &request, option)
+            .map_err(|e| match e {
+                grpcio::Error::RpcFailure(status) => status.code(),
+                e => panic!("Unexpected error: {}", e),
+            })
+    }
+
+    fn bucket_request(bucket_id: &str) -> Vec<u8> {
+        let mut encoder = protobuf_message_encoder![(F1, StringEncoder::new())];
+        encoder
+            .encode_into_bytes(bucket_id.to_owned())
+            .expect("Never fails")
+    }
+
+    fn head_object_request(bucket_id: &str, object_id: &str, deadline_millis: u64) -> Vec<u8> {
+        let mut encoder = protobuf_message_encoder![
+            (F1, StringEncoder::new()),
+            (F2, StringEncoder::new()),
+            (F3, Uint64Encoder::new())
+        ];
+        let item = (bucket_id.to_owned(), object_id.to_owned(), deadline_millis);
+        encoder.encode_into_bytes(item).expect("Never fails")
+    }
+
+    fn put_object_request(bucket_id: &str, object_id: &str, content: &[u8]) -> Vec<u8> {
+        let mut encoder = protobuf_message_encoder![
+            (F1, StringEncoder::new()),
+            (F2, StringEncoder::new()),
+            (F3, BytesEncoder::new())
+        ];
+        let item = (
+            bucket_id.to_owned(),
+            object_id.to_owned(),
+            content.to_owned(),
+        );
+        encoder.encode_into_bytes(item).expect("Never fails")
+    }
+
+    #[test]
+    fn handlers_authorize_requests() -> TestResult {
+        let (_server, client) = track!(grpc_client(track!(rpc_server())?))?;
+
+        // トークンを提示しなかった主体と、未知のトークンを提示した主体は認証されない
+        let result = call(&client, &LIST_BUCKETS, None, Vec::new());
+        assert_eq!(result.err(), Some(RpcStatusCode::UNAUTHENTICATED));
+        let result = call(&client, &LIST_BUCKETS, Some("unknown"), Vec::new());
+        assert_eq!(result.err(), Some(RpcStatusCode::UNAUTHENTICATED));
+
+        let result = call(&client, &LIST_BUCKETS, Some("bob-token"), Vec::new());
+        assert_eq!(result.err(), Some(RpcStatusCode::PERMISSION_DENIED));
+        assert!(call(&client, &LIST_BUCKETS, Some("alice-token"), Vec::new()).is_ok());
+
+        let request = bucket_request("meta");
+        let result = call(&client, &GET_BUCKET, Some("bob-token"), request.clone());
+        assert_eq!(result.err(), Some(RpcStatusCode::PERMISSION_DENIED));
+        assert!(call(&client, &GET_BUCKET, Some("alice-token"), request).is_ok());
+
+        // 参照のみを許可された主体は書き込めない
+        let request = put_object_request("meta", "foo", b"bar");
+        let result = call(&client, &PUT_OBJECT, Some("alice-token"), request);
+        assert_eq!(result.err(), Some(RpcStatusCode::PERMISSION_DENIED));
+        Ok(())
+    }
+
+    #[test]
+    fn handlers_translate_errors_into_statuses() -> TestResult {
+        let (_server, client) = track!(grpc_client(track!(rpc_server())?))?;
+
+        // デコードできないリクエストは、認可の前に拒否される
+        let result = call(&client, &GET_BUCKET, None, vec![0xFF]);
+        assert_eq!(result.err(), Some(RpcStatusCode::INVALID_ARGUMENT));
+
+        let request = bucket_request("unknown");
+        let result = call(&client, &GET_BUCKET, Some("alice-token"), request);
+        assert_eq!(result.err(), Some(RpcStatusCode::NOT_FOUND));
+
+        // 正常に処理されたリクエストは`OK`となる
+        let request = head_object_request("meta", "foo", 0);
+        assert!(call(&client, &HEAD_OBJECT, Some("alice-token"), request).is_ok());
+
+        let code = |kind: ErrorKind| into_rpc_status(Error::from(kind.error())).code();
+        assert_eq!(
+            code(ErrorKind::InvalidInput),
+            RpcStatusCode::INVALID_ARGUMENT
+        );
+        assert_eq!(code(ErrorKind::NotFound), RpcStatusCode::NOT_FOUND);
+        assert_eq!(
+            code(ErrorKind::QuotaExceeded),
+            RpcStatusCode::RESOURCE_EXHAUSTED
+        );
+        assert_eq!(
+            code(ErrorKind::ReadOnly),
+            RpcStatusCode::FAILED_PRECONDITION
+        );
+        assert_eq!(
+            code(ErrorKind::Unauthenticated),
+            RpcStatusCode::UNAUTHENTICATED
+        );
+        assert_eq!(
+            code(ErrorKind::PermissionDenied),
+            RpcStatusCode::PERMISSION_DENIED
+        );
+        assert_eq!(
+            code(ErrorKind::Busy(Duration::from_millis(500))),
+            RpcStatusCode::UNAVAILABLE
+        );
+        assert_eq!(code(ErrorKind::Other), RpcStatusCode::INTERNAL);
+        Ok(())
+    }
+
+    #[test]
+    fn omitted_deadline_falls_back_to_bucket_default() -> TestResult {
+        let rpc = track!(rpc_server())?;
+        let within = |ms| Deadline::Within(Duration::from_millis(ms));
+
+        let bytes = head_object_request("meta", "foo", 250);
+        let request = track!(decode(protobuf::head_object_request_decoder(), &bytes))?;
+        let client_request = client_request(&rpc, request.bucket_id, request.deadline);
+        assert_eq!(client_request.current_deadline(), within(250));
+
+        let bytes = head_object_request("meta", "foo", 0);
+        let request = track!(decode(protobuf::head_object_request_decoder(), &bytes))?;
+        let client_request = client_request(&rpc, request.bucket_id, request.deadline);
+        assert_eq!(client_request.current_deadline(), within(3000));
+
+        // 存在しないバケツに対するリクエストには、クライアント全体のデフォルト値が使われる
+        let client_request = client_request(&rpc, "unknown".to_owned(), None);
+        assert_eq!(client_request.current_deadline(), within(3000));
+        Ok(())
+    }
+}
@@ -0,0 +1,322 @@
+use libfrugalos::consistency::ReadConsistency;
+use libfrugalos::entity::bucket::BucketId;
+use libfrugalos::entity::object::{ObjectId, ObjectSummary, ObjectVersion};
+use libfrugalos::expect::Expect;
+use protobuf_codec::field::branch::{Branch3, Branch4};
+use protobuf_codec::field::num::{F1, F2, F3, F4, F5, F6};
+use protobuf_codec::message::{MessageDecode, MessageEncode};
+use protobuf_codec::scalar::{
+    BoolDecoder, BoolEncoder, BytesDecoder, BytesEncoder, StringDecoder, StringEncoder,
+    Uint32Decoder, Uint32Encoder, Uint64Decoder, Uint64Encoder,
+};
+use std::time::Duration;
+
+/// オブジェクトを対象とするリクエスト(`ObjectRequest`および`HeadObjectRequest`)。
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectRequest {
+    pub bucket_id: BucketId,
+    pub object_id: ObjectId,
+
+    /// `None`の場合は、バケツのデフォルトのデッドラインが使われる。
+    pub deadline: Option<Duration>,
+    pub expect: Expect,
+
+    /// `None`の場合は、バケツのデフォルトの一貫性が使われる。
+    pub consistency: Option<ReadConsistency>,
+
+    /// `HeadObject`の場合のみ意味を持つ。
+    pub check_storage: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PutObjectRequest {
+    pub bucket_id: BucketId,
+    pub object_id: ObjectId,
+    pub content: Vec<u8>,
+    pub deadline: Option<Duration>,
+    pub expect: Expect,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentRequest {
+    pub bucket_id: BucketId,
+    pub segment: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefixRequest {
+    pub bucket_id: BucketId,
+    pub prefix: String,
+    pub deadline: Option<Duration>,
+}
+
+/// `GetBucket`の結果。
+#[derive(Debug, Clone, PartialEq)]
+pub struct BucketSummary {
+    pub bucket_id: BucketId,
+
+    /// "metadata"、"replicated"ないし"dispersed"。
+    pub kind: &'static str,
+    pub segment_count: u32,
+}
+
+//
+// https://github.com/frugalos/frugalos/blob/master/schema/frugalos_api.proto
+//
+pub fn object_request_decoder() -> impl MessageDecode<Item = ObjectRequest> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, StringDecoder::new()),
+        (F3, Uint64Decoder::new()),
+        (F4, expect_decoder(), message),
+        (F5, read_consistency_decoder(), message)
+    ];
+    base.map(|x| ObjectRequest {
+        bucket_id: x.0,
+        object_id: x.1,
+        deadline: deadline_from_millis(x.2),
+        expect: x.3.unwrap_or(Expect::Any),
+        consistency: x.4,
+        check_storage: false,
+    })
+}
+
+pub fn head_object_request_decoder() -> impl MessageDecode<Item = ObjectRequest> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, StringDecoder::new()),
+        (F3, Uint64Decoder::new()),
+        (F4, expect_decoder(), message),
+        (F5, read_consistency_decoder(), message),
+        (F6, BoolDecoder::new())
+    ];
+    base.map(|x| ObjectRequest {
+        bucket_id: x.0,
+        object_id: x.1,
+        deadline: deadline_from_millis(x.2),
+        expect: x.3.unwrap_or(Expect::Any),
+        consistency: x.4,
+        check_storage: x.5,
+    })
+}
+
+pub fn put_object_request_decoder() -> impl MessageDecode<Item = PutObjectRequest> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, StringDecoder::new()),
+        (F3, BytesDecoder::new()),
+        (F4, Uint64Decoder::new()),
+        (F5, expect_decoder(), message)
+    ];
+    base.map(|x| PutObjectRequest {
+        bucket_id: x.0,
+        object_id: x.1,
+        content: x.2,
+        deadline: deadline_from_millis(x.3),
+        expect: x.4.unwrap_or(Expect::Any),
+    })
+}
+
+pub fn segment_request_decoder() -> impl MessageDecode<Item = SegmentRequest> {
+    let base = protobuf_message_decoder![(F1, StringDecoder::new()), (F2, Uint32Decoder::new())];
+    base.map(|x| SegmentRequest {
+        bucket_id: x.0,
+        segment: x.1,
+    })
+}
+
+pub fn prefix_request_decoder() -> impl MessageDecode<Item = PrefixRequest> {
+    let base = protobuf_message_decoder![
+        (F1, StringDecoder::new()),
+        (F2, StringDecoder::new()),
+        (F3, Uint64Decoder::new())
+    ];
+    base.map(|x| PrefixRequest {
+        bucket_id: x.0,
+        prefix: x.1,
+        deadline: deadline_from_millis(x.2),
+    })
+}
+
+pub fn list_buckets_request_decoder() -> impl MessageDecode<Item = ()> {
+    protobuf_message_decoder![]
+}
+
+pub fn bucket_request_decoder() -> impl MessageDecode<Item = BucketId> {
+    protobuf_message_decoder![(F1, StringDecoder::new())]
+}
+
+// `frugalos_mds`の`Precondition`のうち、`Expect`で表現できる部分と同じ形式。
+// 何も指定されていない場合は`Expect::Any`を表す。
+pub fn expect_decoder() -> impl MessageDecode<Item = Expect> {
+    let base = protobuf_message_decoder![(
+        oneof,
+        (F1, versions_decoder(), message),
+        (F2, versions_decoder(), message),
+        (F3, empty_decoder(), message)
+    )];
+    base.map(|x| match x {
+        Some(Branch3::A(versions)) => Expect::IfMatch(versions),
+        Some(Branch3::B(versions)) => Expect::IfNoneMatch(versions),
+        Some(Branch3::C(_)) => Expect::None,
+        None => Expect::Any,
+    })
+}
+
+pub fn read_consistency_decoder() -> impl MessageDecode<Item = ReadConsistency> {
+    let base = protobuf_message_decoder![(
+        required_oneof,
+        (F1, empty_decoder(), message),
+        (F2, empty_decoder(), message),
+        (F3, empty_decoder(), message),
+        (F4, Uint32Decoder::new())
+    )];
+    base.map(|x| match x {
+        Branch4::A(_) => ReadConsistency::Consistent,
+        Branch4::B(_) => ReadConsistency::Stale,
+        Branch4::C(_) => ReadConsistency::Quorum,
+        Branch4::D(n) => ReadConsistency::Subset(n as usize),
+    })
+}
+
+pub fn versions_decoder() -> impl MessageDecode<Item = Vec<ObjectVersion>> {
+    let base = protobuf_message_decoder![(F1, Uint64Decoder::new(), packed)];
+    base.map(|versions: Vec<_>| versions.into_iter().map(ObjectVersion).collect())
+}
+
+pub fn empty_decoder() -> impl MessageDecode<Item = ()> {
+    protobuf_message_decoder![]
+}
+
+// 省略された(ゼロの)デッドラインは、バケツのデフォルト値を使うことを表す
+fn deadline_from_millis(millis: u64) -> Option<Duration> {
+    if millis == 0 {
+        None
+    } else {
+        Some(Duration::from_millis(millis))
+    }
+}
+
+pub fn get_object_response_encoder() -> impl MessageEncode<Item = Option<(ObjectVersion, Vec<u8>)>>
+{
+    let base = protobuf_message_encoder![
+        (F1, BoolEncoder::new()),
+        (F2, Uint64Encoder::new()),
+        (F3, BytesEncoder::new())
+    ];
+    base.map_from(|x: Option<(ObjectVersion, Vec<u8>)>| match x {
+        Some((version, content)) => (true, version.0, content),
+        None => (false, 0, Vec::new()),
+    })
+}
+
+/// `HeadObject`と`DeleteObject`の結果(対象のオブジェクトのバージョン)のエンコーダ。
+pub fn object_version_response_encoder() -> impl MessageEncode<Item = Option<ObjectVersion>> {
+    let base = protobuf_message_encoder![(F1, BoolEncoder::new()), (F2, Uint64Encoder::new())];
+    base.map_from(|x: Option<ObjectVersion>| match x {
+        Some(version) => (true, version.0),
+        None => (false, 0),
+    })
+}
+
+pub fn put_object_response_encoder() -> impl MessageEncode<Item = (ObjectVersion, bool)> {
+    let base = protobuf_message_encoder![(F1, Uint64Encoder::new()), (F2, BoolEncoder::new())];
+    base.map_from(|(version, created): (ObjectVersion, bool)| (version.0, created))
+}
+
+pub fn object_summary_encoder() -> impl MessageEncode<Item = ObjectSummary> {
+    let base = protobuf_message_encoder![(F1, StringEncoder::new()), (F2, Uint64Encoder::new())];
+    base.map_from(|x: ObjectSummary| (x.id, x.version.0))
+}
+
+pub fn delete_objects_by_prefix_response_encoder() -> impl MessageEncode<Item = u64> {
+    protobuf_message_encoder![(F1, Uint64Encoder::new())]
+}
+
+pub fn list_buckets_response_encoder() -> impl MessageEncode<Item = Vec<BucketId>> {
+    protobuf_message_encoder![(F1, StringEncoder::new(), repeated)]
+}
+
+pub fn bucket_response_encoder() -> impl MessageEncode<Item = BucketSummary> {
+    let base = protobuf_message_encoder![
+        (F1, StringEncoder::new()),
+        (F2, StringEncoder::new()),
+        (F3, Uint32Encoder::new())
+    ];
+    base.map_from(|x: BucketSummary| (x.bucket_id, x.kind.to_owned(), x.segment_count))
+}
+
+pub fn bucket_statistics_encoder() -> impl MessageEncode<Item = u64> {
+    protobuf_message_encoder![(F1, Uint64Encoder::new())]
+}
+
+#[cfg(test)]
+mod tests {
+    use bytecodec::{DecodeExt, EncodeExt};
+    use protobuf_codec::message::MessageEncode;
+    use trackable::result::TestResult;
+
+    use super::*;
+
+    // テスト用に、`ObjectRequest`をクライアントと同じ形式でエンコードする
+    fn object_request_encoder() -> impl MessageEncode<Item = (String, String, u64, Expect, u32)> {
+        let versions = || {
+            let base = protobuf_message_encoder![(F1, Uint64Encoder::new(), packed)];
+            base.map_from(|x: Vec<ObjectVersion>| x.into_iter().map(|v| v.0))
+        };
+        let expect = protobuf_message_encoder![(
+            oneof,
+            (F1, versions(), unsized_message),
+            (F2, versions(), unsized_message),
+            (F3, protobuf_message_encoder![], message)
+        )];
+        let expect = expect.map_from(|x: Expect| match x {
+            Expect::IfMatch(versions) => Some(Branch3::A(versions)),
+            Expect::IfNoneMatch(versions) => Some(Branch3::B(versions)),
+            Expect::None => Some(Branch3::C(())),
+            Expect::Any => None,
+        });
+        let consistency = protobuf_message_encoder![(F4, Uint32Encoder::new())];
+        protobuf_message_encoder![
+            (F1, StringEncoder::new()),
+            (F2, StringEncoder::new()),
+            (F3, Uint64Encoder::new()),
+            (F4, expect, required_unsized_message),
+            (F5, consistency, required_message)
+        ]
+    }
+
+    #[test]
+    fn object_request_decoder_works() -> TestResult {
+        let item = (
+            "foo".to_owned(),
+            "bar".to_owned(),
+            0,
+            Expect::IfMatch(vec![ObjectVersion(3), ObjectVersion(5)]),
+            2,
+        );
+        let bytes = track!(object_request_encoder().encode_into_bytes(item))?;
+        let request = track!(object_request_decoder().decode_from_bytes(&bytes))?;
+        assert_eq!(
+            request,
+            ObjectRequest {
+                bucket_id: "foo".to_owned(),
+                object_id: "bar".to_owned(),
+                deadline: None,
+                expect: Expect::IfMatch(vec![ObjectVersion(3), ObjectVersion(5)]),
+                consistency: Some(ReadConsistency::Subset(2)),
+                check_storage: false,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn omitted_fields_are_decoded_as_defaults() -> TestResult {
+        let request = track!(object_request_decoder().decode_from_bytes(&[]))?;
+        assert_eq!(request.deadline, None);
+        assert_eq!(request.expect, Expect::Any);
+        assert_eq!(request.consistency, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,453 @@
+//! デバイス(ディスク)の健全性を監視するためのモジュール。
+//!
+//! 健全性の判定は `DeviceHealthProbe` トレイトとして抽象化されており、
+//! デフォルトでは `smartctl` の結果と sysfs 上のデバイスの状態を用いる。
+//!
+//! 判定結果はメトリクスとして公開される他、`DeviceHealthMonitor` のストリームを通して通知されるので、
+//! 故障しかけているディスクを使っているノードから、リーダ権を退避させる等の対応を取ることができる。
+//!
+//! 故障が差し迫っていると連続して判定されたデバイスは隔離(quarantine)される。
+//! 隔離されたデバイスは退役させる(セグメントを他のデバイスに移し替える)ことで、完全に壊れる前にデータを退避できる。
+use fibers::time::timer::{self, Timeout};
+use fibers_tasque::{self, AsyncCall, TaskQueueExt};
+use futures::{Async, Future, Poll, Stream};
+use prometrics::metrics::{Counter, Gauge, MetricBuilder};
+use slog::Logger;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+
+use {Error, ErrorKind, Result};
+
+/// デバイスの健全性。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceHealthStatus {
+    /// 問題は検出されていない。
+    Healthy,
+
+    /// 劣化の兆候が見られる。
+    Degraded,
+
+    /// 故障しているか、故障が差し迫っている。
+    Failing,
+
+    /// 健全性を判定できなかった。
+    Unknown,
+}
+impl DeviceHealthStatus {
+    /// メトリクスとして公開する際の値を返す。
+    fn as_metric_value(self) -> f64 {
+        match self {
+            DeviceHealthStatus::Healthy => 0.0,
+            DeviceHealthStatus::Degraded => 1.0,
+            DeviceHealthStatus::Failing => 2.0,
+            DeviceHealthStatus::Unknown => -1.0,
+        }
+    }
+}
+
+/// デバイスの健全性を判定するためのトレイト。
+///
+/// `probe` はブロッキングしても良い(I/O 用のタスクキュー上で実行される)。
+pub trait DeviceHealthProbe: Send + Sync + 'static {
+    /// `filepath` に置かれたデバイスファイルが載っているディスクの健全性を判定する。
+    fn probe(&self, filepath: &Path) -> Result<DeviceHealthStatus>;
+}
+
+/// デフォルトの `DeviceHealthProbe` の実装。
+///
+/// sysfs からデバイスファイルが載っているブロックデバイスを特定して、
+/// `smartctl -H` の終了ステータスから健全性を判定する。
+/// `smartctl` が利用できない場合には、sysfs 上のデバイスの状態(`device/state`)のみを確認する。
+#[derive(Debug, Clone)]
+pub struct DefaultDeviceHealthProbe {
+    smartctl: PathBuf,
+}
+impl DefaultDeviceHealthProbe {
+    /// 新しい `DefaultDeviceHealthProbe` を生成する。
+    pub fn new<P: AsRef<Path>>(smartctl: P) -> Self {
+        DefaultDeviceHealthProbe {
+            smartctl: smartctl.as_ref().to_owned(),
+        }
+    }
+}
+impl DeviceHealthProbe for DefaultDeviceHealthProbe {
+    fn probe(&self, filepath: &Path) -> Result<DeviceHealthStatus> {
+        let disk = track!(sysfs_disk_dir(filepath); filepath)?;
+        if let Ok(state) = fs::read_to_string(disk.join("device/state")) {
+            if state.trim() != "running" {
+                return Ok(DeviceHealthStatus::Failing);
+            }
+        }
+
+        let devname = track!(read_devname(&disk); disk)?;
+        match Command::new(&self.smartctl)
+            .arg("-H")
+            .arg(Path::new("/dev").join(devname))
+            .output()
+        {
+            Ok(output) => Ok(status_from_smartctl_exit_code(
+                output.status.code().unwrap_or(1),
+            )),
+            Err(_) => {
+                // smartctl がインストールされていない
+                Ok(DeviceHealthStatus::Unknown)
+            }
+        }
+    }
+}
+
+/// `smartctl` の終了ステータスから健全性を判定する。
+///
+/// 終了ステータスの各ビットの意味は `smartctl(8)` の "RETURN VALUES" を参照。
+fn status_from_smartctl_exit_code(code: i32) -> DeviceHealthStatus {
+    if code & 0b0000_0011 != 0 {
+        // コマンドラインの誤りか、デバイスを開けなかった
+        DeviceHealthStatus::Unknown
+    } else if code & 0b0000_1000 != 0 {
+        // "DISK FAILING"
+        DeviceHealthStatus::Failing
+    } else if code & 0b1101_0000 != 0 {
+        // 閾値を下回った属性があるか、エラーログにエラーが記録されている
+        DeviceHealthStatus::Degraded
+    } else {
+        DeviceHealthStatus::Healthy
+    }
+}
+
+/// `filepath` が載っているディスク(パーティションではない)の sysfs 上のディレクトリを返す。
+fn sysfs_disk_dir(filepath: &Path) -> Result<PathBuf> {
+    let dev = track!(device_number(filepath))?;
+    let (major, minor) = split_device_number(dev);
+    let dir = track!(
+        fs::canonicalize(format!("/sys/dev/block/{}:{}", major, minor)).map_err(Error::from)
+    )?;
+    if dir.join("partition").exists() {
+        let parent = track_assert_some!(dir.parent(), ErrorKind::Other, "dir={:?}", dir);
+        Ok(parent.to_owned())
+    } else {
+        Ok(dir)
+    }
+}
+
+fn read_devname(sysfs_dir: &Path) -> Result<String> {
+    let uevent = track!(fs::read_to_string(sysfs_dir.join("uevent")).map_err(Error::from))?;
+    let devname = uevent
+        .lines()
+        .filter_map(|line| {
+            let mut tokens = line.splitn(2, '=');
+            match (tokens.next(), tokens.next()) {
+                (Some("DEVNAME"), Some(name)) => Some(name.to_owned()),
+                _ => None,
+            }
+        })
+        .next();
+    Ok(track_assert_some!(devname, ErrorKind::Other, "No DEVNAME"))
+}
+
+#[cfg(unix)]
+fn device_number(filepath: &Path) -> Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = track!(fs::metadata(filepath).map_err(Error::from))?;
+    Ok(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device_number(filepath: &Path) -> Result<u64> {
+    track_panic!(ErrorKind::Other, "Unsupported platform: {:?}", filepath)
+}
+
+/// glibc の `gnu_dev_major` および `gnu_dev_minor` と同じ方法で、デバイス番号を分割する。
+fn split_device_number(dev: u64) -> (u64, u64) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    (major, minor)
+}
+
+/// デバイスの健全性の判定結果。
+#[derive(Debug, Clone)]
+pub struct DeviceHealthReport {
+    /// デバイスのシーケンス番号。
+    pub device_no: u32,
+
+    /// デバイスの ID。
+    pub device_id: String,
+
+    /// 判定結果。
+    pub status: DeviceHealthStatus,
+
+    /// デバイスが隔離されているかどうか。
+    pub quarantined: bool,
+}
+
+struct MonitoredDevice {
+    id: String,
+    filepath: PathBuf,
+    status: Gauge,
+    quarantine: Gauge,
+    consecutive_failures: usize,
+    quarantined: bool,
+}
+impl MonitoredDevice {
+    /// 判定結果を反映し、デバイスが新たに隔離された場合には`true`を返す。
+    ///
+    /// `Failing`が`threshold`回連続したデバイスは隔離される。
+    /// 判定できなかった場合(`Unknown`)には、連続回数はそのまま維持される。
+    /// 一度隔離されたデバイスは、プロセスが再起動されるまで隔離されたままとなる。
+    fn update(&mut self, status: DeviceHealthStatus, threshold: usize) -> bool {
+        match status {
+            DeviceHealthStatus::Failing => self.consecutive_failures += 1,
+            DeviceHealthStatus::Unknown => {}
+            _ => self.consecutive_failures = 0,
+        }
+        if self.quarantined || self.consecutive_failures < threshold {
+            return false;
+        }
+        self.quarantined = true;
+        self.quarantine.set(1.0);
+        true
+    }
+}
+
+type ProbeFuture = AsyncCall<(u32, Result<DeviceHealthStatus>)>;
+
+/// ローカルのファイルデバイスの健全性を定期的に判定する `Stream`。
+///
+/// 判定の度に、その結果を `DeviceHealthReport` として通知する。
+/// この `Stream` が終了することはない。
+pub struct DeviceHealthMonitor {
+    logger: Logger,
+    probe: Arc<dyn DeviceHealthProbe>,
+    devices: HashMap<u32, MonitoredDevice>,
+    interval: Duration,
+    quarantine_threshold: usize,
+    timeout: Timeout,
+    probes: Vec<ProbeFuture>,
+    probe_failures: Counter,
+}
+impl DeviceHealthMonitor {
+    /// 新しい `DeviceHealthMonitor` を生成する。
+    ///
+    /// `Failing` と `quarantine_threshold` 回連続して判定されたデバイスは隔離される。
+    pub fn new(
+        logger: Logger,
+        probe: Arc<dyn DeviceHealthProbe>,
+        interval: Duration,
+        quarantine_threshold: usize,
+    ) -> Result<Self> {
+        let probe_failures = track!(MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("device_health")
+            .counter("probe_failures_total")
+            .help("Number of failures of probing the health of devices")
+            .default_registry()
+            .finish())?;
+        Ok(DeviceHealthMonitor {
+            logger,
+            probe,
+            devices: HashMap::new(),
+            interval,
+            quarantine_threshold,
+            timeout: timer::timeout(interval),
+            probes: Vec::new(),
+            probe_failures,
+        })
+    }
+
+    /// 監視対象のデバイスを追加する。
+    pub fn put_device(&mut self, device_no: u32, device_id: &str, filepath: &Path) -> Result<()> {
+        if self.devices.contains_key(&device_no) {
+            return Ok(());
+        }
+        let status = track!(MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("device_health")
+            .gauge("status")
+            .help("Health of the device (0=healthy, 1=degraded, 2=failing, -1=unknown)")
+            .label("device", device_id)
+            .default_registry()
+            .finish())?;
+        status.set(DeviceHealthStatus::Unknown.as_metric_value());
+        let quarantine = track!(MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("device_health")
+            .gauge("quarantined")
+            .help("Whether the device is quarantined (1) or not (0)")
+            .label("device", device_id)
+            .default_registry()
+            .finish())?;
+        self.devices.insert(
+            device_no,
+            MonitoredDevice {
+                id: device_id.to_owned(),
+                filepath: filepath.to_owned(),
+                status,
+                quarantine,
+                consecutive_failures: 0,
+                quarantined: false,
+            },
+        );
+        Ok(())
+    }
+
+    fn start_probes(&mut self) {
+        if !self.probes.is_empty() {
+            // 前回の判定が終わっていないので、今回はスキップする
+            return;
+        }
+        for (&device_no, device) in &self.devices {
+            let probe = self.probe.clone();
+            let filepath = device.filepath.clone();
+            let future = fibers_tasque::DefaultIoTaskQueue
+                .async_call(move || (device_no, track!(probe.probe(&filepath))));
+            self.probes.push(future);
+        }
+    }
+
+    fn handle_result(
+        &mut self,
+        device_no: u32,
+        result: Result<DeviceHealthStatus>,
+    ) -> Option<DeviceHealthReport> {
+        let device = self.devices.get_mut(&device_no)?;
+        let status = match result {
+            Ok(status) => status,
+            Err(e) => {
+                self.probe_failures.increment();
+                debug!(
+                    self.logger,
+                    "Cannot probe the health of the device: device={}, error={}", device.id, e
+                );
+                DeviceHealthStatus::Unknown
+            }
+        };
+        device.status.set(status.as_metric_value());
+        if status == DeviceHealthStatus::Degraded || status == DeviceHealthStatus::Failing {
+            warn!(
+                self.logger,
+                "The device is unhealthy: device={}, status={:?}", device.id, status
+            );
+        }
+        if device.update(status, self.quarantine_threshold) {
+            warn!(
+                self.logger,
+                "The device is quarantined: device={}, consecutive_failures={}",
+                device.id,
+                device.consecutive_failures
+            );
+        }
+        Some(DeviceHealthReport {
+            device_no,
+            device_id: device.id.clone(),
+            status,
+            quarantined: device.quarantined,
+        })
+    }
+}
+impl Stream for DeviceHealthMonitor {
+    type Item = DeviceHealthReport;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while track!(self.timeout.poll().map_err(Error::from))?.is_ready() {
+            self.timeout = timer::timeout(self.interval);
+            self.start_probes();
+        }
+
+        let mut i = 0;
+        while i < self.probes.len() {
+            match track!(self.probes[i].poll().map_err(Error::from))? {
+                Async::NotReady => {
+                    i += 1;
+                }
+                Async::Ready((device_no, result)) => {
+                    let _ = self.probes.swap_remove(i);
+                    if let Some(report) = self.handle_result(device_no, result) {
+                        return Ok(Async::Ready(Some(report)));
+                    }
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trackable::result::TestResult;
+
+    #[test]
+    fn status_from_smartctl_exit_code_works() {
+        assert_eq!(
+            status_from_smartctl_exit_code(0),
+            DeviceHealthStatus::Healthy
+        );
+        assert_eq!(
+            status_from_smartctl_exit_code(2),
+            DeviceHealthStatus::Unknown
+        );
+        assert_eq!(
+            status_from_smartctl_exit_code(8),
+            DeviceHealthStatus::Failing
+        );
+        assert_eq!(
+            status_from_smartctl_exit_code(8 | 16),
+            DeviceHealthStatus::Failing
+        );
+        assert_eq!(
+            status_from_smartctl_exit_code(64),
+            DeviceHealthStatus::Degraded
+        );
+        // 過去に閾値を下回ったことがあるだけなら問題としない
+        assert_eq!(
+            status_from_smartctl_exit_code(32),
+            DeviceHealthStatus::Healthy
+        );
+    }
+
+    #[test]
+    fn quarantine_works() -> TestResult {
+        let mut device = MonitoredDevice {
+            id: "dev0".to_owned(),
+            filepath: PathBuf::from("/tmp/dev0.lusf"),
+            status: track!(MetricBuilder::new()
+                .gauge("status")
+                .finish()
+                .map_err(Error::from))?,
+            quarantine: track!(MetricBuilder::new()
+                .gauge("quarantined")
+                .finish()
+                .map_err(Error::from))?,
+            consecutive_failures: 0,
+            quarantined: false,
+        };
+        assert!(!device.update(DeviceHealthStatus::Failing, 3));
+        assert!(!device.update(DeviceHealthStatus::Healthy, 3));
+        assert_eq!(device.consecutive_failures, 0);
+
+        // 判定できなかった場合は、連続回数に影響しない
+        assert!(!device.update(DeviceHealthStatus::Failing, 3));
+        assert!(!device.update(DeviceHealthStatus::Unknown, 3));
+        assert!(!device.update(DeviceHealthStatus::Failing, 3));
+        assert!(device.update(DeviceHealthStatus::Failing, 3));
+        assert!(device.quarantined);
+        assert_eq!(device.quarantine.value(), 1.0);
+
+        // 一度隔離されたデバイスは、回復しても隔離されたまま
+        assert!(!device.update(DeviceHealthStatus::Failing, 3));
+        assert!(!device.update(DeviceHealthStatus::Healthy, 3));
+        assert!(device.quarantined);
+        Ok(())
+    }
+
+    #[test]
+    fn split_device_number_works() {
+        // 8:1 (e.g., /dev/sda1)
+        assert_eq!(split_device_number(0x801), (8, 1));
+        // 259:0 (e.g., /dev/nvme0n1)
+        assert_eq!(split_device_number(0x10300), (259, 0));
+    }
+}
@@ -1,7 +1,9 @@
 use fibers_http_server::{Res, Status};
+use frugalos_segment::{CacheClass, ContentCacheStats, MemberHealth, MemberStatus, PutDurability};
 use httpcodec::{Header, HeaderField, HeaderFields};
 use libfrugalos::entity::object::ObjectVersion;
 use rustracing::carrier::IterHttpHeaderFields;
+use std::collections::BTreeMap;
 use trackable::error::ErrorKindExt;
 
 use {Error, ErrorKind, Result};
@@ -84,6 +86,30 @@ pub fn make_object_response(
     res
 }
 
+/// オブジェクトの保存時に、一部の断片の書き込みに失敗したかどうかをヘッダに付与する。
+///
+/// 応答の時点で完了を確認できていない書き込みも、失敗したものとして数えられる。
+///
+/// 失敗した断片は後でリペアされるが、重要な書き込みを行うクライアントはこれを見て再検証や再試行を行える。
+pub fn add_durability_headers<T>(res: &mut Res<T>, durability: &PutDurability) {
+    res.header_mut().add_field(unsafe {
+        HeaderField::new_unchecked(
+            "X-Frugalos-Degraded-Durability",
+            if durability.is_degraded() {
+                "true"
+            } else {
+                "false"
+            },
+        )
+    });
+    res.header_mut().add_field(unsafe {
+        HeaderField::new_unchecked(
+            "X-Frugalos-Failed-Fragments",
+            &durability.failed_fragments.to_string(),
+        )
+    });
+}
+
 pub fn not_found() -> Error {
     ErrorKind::Other.cause("Not Found").into()
 }
@@ -93,3 +119,155 @@ pub struct BucketStatistics {
     /// バケツ内のオブジェクト数.
     pub objects: u64,
 }
+
+/// `GET /v1/buckets/{bucket_id}/status`の応答.
+#[derive(Debug, Serialize)]
+pub struct BucketStatus {
+    /// バケツ内のオブジェクト数(一部のセグメントから取得できなかった場合は`null`).
+    pub objects: Option<u64>,
+
+    /// セグメント毎の状態.
+    pub segments: Vec<SegmentSummary>,
+}
+impl BucketStatus {
+    /// セグメント毎の状態から、バケツ全体の状態を作る.
+    pub fn new(segments: Vec<SegmentSummary>) -> Self {
+        let objects = segments.iter().map(|s| s.objects).sum();
+        BucketStatus { objects, segments }
+    }
+}
+
+/// `GET /v1/buckets/{bucket_id}/status`の応答の要素.
+#[derive(Debug, Serialize)]
+pub struct SegmentSummary {
+    /// セグメントの番号.
+    pub segment: u16,
+
+    /// このサーバが MDS のリーダとして認識しているノード(不明な場合は`null`).
+    pub leader: Option<String>,
+
+    /// セグメント内のオブジェクト数(取得できなかった場合は`null`).
+    pub objects: Option<u64>,
+}
+
+/// `GET /v1/buckets/{bucket_id}/segments/{segment}/status`の応答.
+#[derive(Debug, Serialize)]
+pub struct SegmentStatus {
+    #[serde(flatten)]
+    pub summary: SegmentSummary,
+
+    /// セグメントの各メンバの状態.
+    ///
+    /// Raft のリーダやキューの長さ(リペアの積み残し等)は、メンバ毎に問い合わせた結果となる.
+    pub members: Vec<MemberStatus>,
+}
+
+/// `GET /v1/buckets/{bucket_id}/health`の応答の要素.
+#[derive(Debug, Serialize)]
+pub struct SegmentMemberHealth {
+    /// セグメントの番号.
+    pub segment: u16,
+
+    /// このサーバが記録している、ストレージの各メンバの健全性.
+    ///
+    /// 一度も読み込み要求を送っていないメンバは含まれない.
+    pub members: Vec<MemberHealth>,
+}
+
+/// `GET /v1/buckets/{bucket_id}/cache`の応答の要素.
+#[derive(Debug, Serialize)]
+pub struct SegmentCacheStatistics {
+    /// セグメントの番号.
+    pub segment: u16,
+
+    /// ヒット率.
+    pub hit_ratio: f64,
+
+    #[serde(flatten)]
+    pub stats: ContentCacheStats,
+}
+
+/// `DELETE /v1/buckets/{bucket_id}/cache`の応答.
+#[derive(Debug, Serialize)]
+pub struct FlushedContentCache {
+    /// 破棄したオブジェクトの数.
+    pub objects: u64,
+}
+
+/// `/v1/frugalos/content_cache/{class}`の応答.
+#[derive(Debug, Serialize)]
+pub struct ContentCacheCapacity {
+    /// バケツの種類.
+    pub class: CacheClass,
+
+    /// セグメント当たりのキャッシュの容量(バイト).
+    pub capacity_bytes: u64,
+}
+
+/// `/v1/frugalos/maintenance`の応答.
+#[derive(Debug, Serialize)]
+pub struct MaintenanceState {
+    /// 負荷の高いバックグラウンド処理を実行できる状態かどうか.
+    pub in_window: bool,
+
+    /// メンテナンスウィンドウによる制限が解除されているかどうか.
+    #[serde(rename = "override")]
+    pub overridden: bool,
+}
+
+/// `GET /v1/dashboard`の応答.
+#[derive(Debug, Serialize)]
+pub struct Dashboard {
+    /// スループット等の集計対象となった直近の期間(秒).
+    pub window_seconds: u64,
+
+    /// このノード上で処理待ちとなっているリペアの数.
+    ///
+    /// キューはバケツ毎ではなくノード単位で管理されているため、バケツ毎の値は提供しない.
+    pub repair_backlog: RepairBacklog,
+
+    /// バケツ毎の指標.
+    pub buckets: Vec<BucketDashboard>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RepairBacklog {
+    /// リペアキューに積まれているオブジェクトの数.
+    pub repair: u64,
+
+    /// リペアの要否を確認するキューに積まれているオブジェクトの数.
+    pub repair_prep: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BucketDashboard {
+    pub bucket_id: String,
+
+    /// 操作毎(`get`, `head`, `put`, `delete`, `append`)の指標.
+    pub operations: BTreeMap<&'static str, OperationStatistics>,
+
+    /// バケツ内のオブジェクト数(一部のセグメントから取得できなかった場合は`null`).
+    pub objects: Option<u64>,
+
+    /// バケツのセグメント数.
+    pub segments: u16,
+
+    /// MDS のリーダのアドレス毎の、セグメント数.
+    ///
+    /// リーダが不明なセグメントは`unknown`として数えられる.
+    pub leaders: BTreeMap<String, u16>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct OperationStatistics {
+    /// 一秒当たりのリクエスト数.
+    pub qps: f64,
+
+    /// 5xx 系の応答を返したリクエストの割合.
+    pub error_rate: f64,
+
+    /// 99パーセンタイルのレイテンシ(ミリ秒、リクエストがなかった場合は`null`).
+    ///
+    /// ヒストグラムから求めた近似値なので、実際の値以上となる.
+    pub p99_millis: Option<u64>,
+}
@@ -0,0 +1,69 @@
+//! JSON 形式でログを出力するためのモジュール。
+//!
+//! 各行は一つの JSON オブジェクトで、`ts`と`level`と`msg`の他に、ログに付与された全てのフィールドを含む。
+//! バケツやセグメント等の ID は、`frugalos_core::logging`のキーで出力される。
+use slog::{Drain, Logger};
+use slog_async::Async;
+use slog_json::Json;
+use sloggers::types::Severity;
+use std::io::Write;
+
+/// `writer`に JSON 形式でログを出力するロガーを生成する。
+///
+/// `sloggers`のロガーと同様に出力は非同期に行われ、`channel_size`はその待ち行列の長さとなる。
+pub fn build_json_logger<W>(writer: W, level: Severity, channel_size: usize) -> Logger
+where
+    W: Write + Send + 'static,
+{
+    let drain = Json::new(writer).add_default_keys().build().fuse();
+    let drain = Async::new(drain)
+        .chan_size(channel_size)
+        .build()
+        .filter_level(level.as_level())
+        .fuse();
+    Logger::root(drain, o!())
+}
+
+#[cfg(test)]
+mod tests {
+    use frugalos_core::logging;
+    use serde_json::{self, Value};
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().expect("Never fails").write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn build_json_logger_works() {
+        let buf = SharedBuf::default();
+        {
+            let logger = build_json_logger(buf.clone(), Severity::Info, 16);
+            let logger = logger.new(o!(logging::BUCKET => "foo", logging::SEGMENT => 3));
+            debug!(logger, "filtered");
+            info!(logger, "Hello"; logging::OBJECT_ID => "bar");
+        }
+
+        let output = buf.0.lock().expect("Never fails").clone();
+        let lines = String::from_utf8(output).expect("Never fails");
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+
+        let value: Value = serde_json::from_str(lines[0]).expect("Never fails");
+        assert_eq!(value["msg"], "Hello");
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["bucket"], "foo");
+        assert_eq!(value["segment"], 3);
+        assert_eq!(value["object_id"], "bar");
+    }
+}
@@ -0,0 +1,77 @@
+//! サーバ間の MDS のリーダ数の偏りを、定期的に解消するためのモジュール。
+//!
+//! 各サーバは、自サーバ内のリーダ数が期待値を超えている場合に、超過分のリーダ権を他のサーバに譲る。
+//! リーダ権の委譲先は Raft の再選出によって決まるので、一度で均等になるとは限らないが、
+//! 全てのサーバで繰り返し行うことで偏りが緩和される。
+//!
+//! 全てのサーバが同時にリーダ権を譲ると再選出が集中するので、確認の間隔は無作為にばらつかせている。
+use fibers::time::timer::{self, Timeout};
+use frugalos_mds::FrugalosMdsConfig;
+use futures::Future;
+use prometrics::metrics::{Counter, MetricBuilder};
+use rand::{self, Rng};
+use slog::Logger;
+use std::time::Duration;
+
+use {Error, Result};
+
+/// MDS のリーダ数の偏りを定期的に解消する。
+pub struct LeadershipBalancer {
+    logger: Logger,
+    interval: Duration,
+    slack: usize,
+    timeout: Timeout,
+    resignations: Counter,
+}
+impl LeadershipBalancer {
+    /// 新しい`LeadershipBalancer`を生成する。
+    pub fn new(logger: Logger, config: &FrugalosMdsConfig) -> Result<Self> {
+        let resignations = track!(MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("leadership_balancer")
+            .counter("resignations_total")
+            .help("Number of leaderships resigned for balancing")
+            .default_registry()
+            .finish())?;
+        Ok(LeadershipBalancer {
+            logger,
+            interval: config.leadership_balance_interval,
+            slack: config.leadership_balance_slack,
+            timeout: timer::timeout(jittered(config.leadership_balance_interval)),
+            resignations,
+        })
+    }
+
+    /// 確認を行うべき時刻になっていれば、`balance`を呼び出してリーダ数の偏りを解消する。
+    ///
+    /// `balance`には閾値が渡され、リーダ権の委譲を要求したノードの数を返す。
+    pub fn poll<F>(&mut self, balance: F) -> Result<()>
+    where
+        F: FnOnce(usize) -> usize,
+    {
+        let mut tick = false;
+        while track!(self.timeout.poll().map_err(Error::from))?.is_ready() {
+            self.timeout = timer::timeout(jittered(self.interval));
+            tick = true;
+        }
+        if tick {
+            let resigned = balance(self.slack);
+            if resigned > 0 {
+                info!(
+                    self.logger,
+                    "Resigned leaderships for balancing: {}",
+                    dump!(resigned, self.slack)
+                );
+                self.resignations.add_u64(resigned as u64);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `interval`の 0.5 倍から 1.5 倍の範囲の時間を無作為に返す。
+fn jittered(interval: Duration) -> Duration {
+    let millis = interval.as_secs() * 1000 + u64::from(interval.subsec_millis());
+    let half = millis / 2;
+    Duration::from_millis(half + rand::thread_rng().gen_range(0, millis + 1))
+}
@@ -15,22 +15,34 @@ extern crate frugalos_mds;
 extern crate frugalos_raft;
 extern crate frugalos_segment;
 extern crate futures;
+#[cfg(feature = "grpc")]
+extern crate futures03;
+#[cfg(feature = "grpc")]
+extern crate grpcio;
 extern crate httpcodec;
 extern crate jemalloc_ctl;
+extern crate libc;
 extern crate libfrugalos;
 extern crate num_cpus;
+#[cfg(feature = "grpc")]
+#[macro_use]
+extern crate protobuf_codec;
 extern crate prometrics;
 extern crate raftlog;
+extern crate rand;
 extern crate rustracing;
 extern crate rustracing_jaeger;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate serde_yaml;
 extern crate siphasher;
 extern crate url;
 #[macro_use]
 extern crate slog;
+extern crate slog_async;
+extern crate slog_json;
 #[cfg(test)]
 extern crate tempdir;
 #[macro_use]
@@ -38,10 +50,20 @@ extern crate trackable;
 extern crate clap;
 extern crate sloggers;
 
+use frugalos_core::checksum::ChecksumAlgorithm;
+use frugalos_core::cluster_feature::ClusterFeature;
+use frugalos_core::prometheus::PrometheusConfig;
+use frugalos_core::serde_ext::evolution::{ConfigSchema, ConfigWarning};
+use frugalos_core::tracer::otlp::OtlpExporterConfig;
+use frugalos_core::tracer::TailSamplingConfig;
+use libfrugalos::entity::server::ServerId;
+use libfrugalos::repair::{RepairConcurrencyLimit, RepairConfig, RepairIdleness};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
+use trackable::error::ErrorKindExt;
 
 macro_rules! dump {
     ($($e:expr),*) => {
@@ -49,7 +71,18 @@ macro_rules! dump {
     }
 }
 
+pub use admission::{AdmissionLimits, RequestKind};
+pub use auth::{
+    AllowAllAuthorizer, Authorizer, BucketAcl, BucketAclAuthorizer, Permission, Principal,
+    Resource, StaticTokenAuthorizer,
+};
+pub use client::{BucketDefaults, BucketHandle, FrugalosClient};
 pub use error::{Error, ErrorKind};
+pub use event_sink::{EventSink, FileEventSink, LogEventSink, ObjectEvent};
+pub use json_log::build_json_logger;
+pub use operation::{OperationProgress, OperationState, OperationStatus};
+pub use placement::{ObjectPlacement, PlacementMember};
+pub use reload::ReloadOutcome;
 
 pub mod command;
 pub mod daemon;
@@ -57,16 +90,51 @@ pub mod daemon;
 /// The following module is automatically generated by build.rs .
 pub mod build_information;
 
+mod admission;
+mod auth;
+mod availability;
 mod bucket;
+mod capture;
 mod client;
+mod clock;
+mod cluster_health;
 mod codec;
 mod config_server;
+mod dashboard;
+mod decommission;
+mod device;
+mod discovery;
 mod error;
+mod event_sink;
+mod existence;
+mod export;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod health;
 mod http;
+mod json_log;
+mod leadership;
+mod lifecycle;
+mod metrics;
+mod migration;
+mod operation;
+mod placement;
+mod readiness;
+mod rebalancer;
 mod recovery;
+mod reload;
 mod rpc_server;
+mod schema;
 mod server;
 mod service;
+mod slo;
+mod upload;
+mod usage;
+mod warmup;
+mod watchdog;
+#[cfg(feature = "web-ui")]
+mod web_ui;
+mod workload;
 
 /// クレート固有の`Result`型。
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -79,6 +147,20 @@ struct FrugalosConfigWrapper {
     config: FrugalosConfig,
 }
 
+/// 設定ファイルのフォーマットの変遷。
+///
+/// フィールド名を変更したり、フィールドを廃止する場合にはここに追記する。
+const FRUGALOS_CONFIG_SCHEMA: ConfigSchema = ConfigSchema {
+    aliases: &[
+        ("frugalos.log_level", "frugalos.loglevel"),
+        (
+            "frugalos.segment.mds_client.put_content_timeout",
+            "frugalos.segment.mds_client.put_content_timeout_secs",
+        ),
+    ],
+    deprecations: &[],
+};
+
 /// frugalos の設定を表す struct。
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FrugalosConfig {
@@ -91,6 +173,9 @@ pub struct FrugalosConfig {
     /// 出力するログレベルの下限。
     #[serde(default = "default_loglevel")]
     pub loglevel: sloggers::types::Severity,
+    /// ログの出力形式。
+    #[serde(default)]
+    pub log_format: LogFormat,
     /// 同時に処理できるログの最大値。
     #[serde(default = "default_max_concurrent_logs")]
     pub max_concurrent_logs: usize,
@@ -100,9 +185,64 @@ pub struct FrugalosConfig {
     /// HTTP server 向けの設定。
     #[serde(default)]
     pub http_server: FrugalosHttpServerConfig,
+
+    /// gRPC server 向けの設定。
+    #[serde(default)]
+    pub grpc_server: FrugalosGrpcServerConfig,
     /// RPC client 向けの設定。
     #[serde(default)]
     pub rpc_client: FrugalosRpcClientConfig,
+    /// サーバのアドレス解決向けの設定。
+    #[serde(default)]
+    pub discovery: FrugalosDiscoveryConfig,
+    /// ローカルデバイスに関する設定。
+    #[serde(default)]
+    pub device: FrugalosDeviceConfig,
+    /// リクエストの記録に関する設定。
+    #[serde(default)]
+    pub workload_recorder: FrugalosWorkloadRecorderConfig,
+    /// SLO の追跡に関する設定。
+    #[serde(default)]
+    pub slo: FrugalosSloConfig,
+    /// 停滞したバックグラウンドタスクの監視に関する設定。
+    #[serde(default)]
+    pub watchdog: FrugalosWatchdogConfig,
+    /// オブジェクトの変更イベントの転送に関する設定。
+    #[serde(default)]
+    pub event_sink: FrugalosEventSinkConfig,
+    /// オブジェクトの存在フィルタに関する設定。
+    #[serde(default)]
+    pub existence_filter: FrugalosExistenceFilterConfig,
+    /// 長時間操作の管理に関する設定。
+    #[serde(default)]
+    pub operation: FrugalosOperationConfig,
+    /// セグメントの配置の偏りを均す、バックグラウンドでの移動に関する設定。
+    #[serde(default)]
+    pub rebalancer: FrugalosRebalancerConfig,
+    /// リペア処理に関する設定。
+    #[serde(default)]
+    pub repair: FrugalosRepairConfig,
+    /// メモリ使用量の見積もりと予算に関する設定。
+    #[serde(default)]
+    pub memory: FrugalosMemoryConfig,
+    /// 有効にするクラスタ単位の機能の一覧。
+    ///
+    /// 古いバージョンのサーバが解釈できない形式のデータを生成する機能は、ここに列挙されるまで使用できない。
+    /// クラスタ内の全てのサーバを更新した後で、全てのサーバに同じ値を設定すること。
+    #[serde(default)]
+    pub cluster_features: Vec<ClusterFeature>,
+    /// RPC と HTTP の API の認可に関する設定。
+    #[serde(default)]
+    pub auth: FrugalosAuthConfig,
+    /// リクエストの受け付け制御に関する設定。
+    #[serde(default)]
+    pub admission: FrugalosAdmissionConfig,
+    /// 分散トレーシングのスパンの送信先に関する設定。
+    #[serde(default)]
+    pub tracing: FrugalosTracingConfig,
+    /// Prometheus 向けのメトリクスに関する設定。
+    #[serde(default)]
+    pub prometheus: PrometheusConfig,
     /// frugalos_mds 向けの設定。
     #[serde(default)]
     pub mds: frugalos_mds::FrugalosMdsConfig,
@@ -116,17 +256,32 @@ impl FrugalosConfig {
     ///
     /// Return Value:
     ///  * The first component is an obtained value of `FrugalosConfig`.
-    ///  * The second component is unknown fields that appear in the `path` with respect to the definition of `FrugalosConfig`.
-    pub fn from_yaml<P: AsRef<Path>>(path: P) -> Result<(FrugalosConfig, std::vec::Vec<String>)> {
+    ///  * The second component is warnings about the content of the `path`,
+    ///    such as unknown fields with respect to the definition of `FrugalosConfig` and renamed or deprecated fields.
+    pub fn from_yaml<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<(FrugalosConfig, std::vec::Vec<ConfigWarning>)> {
         let file = File::open(path).map_err(|e| track!(Error::from(e)))?;
         let value: serde_yaml::Value =
             serde_yaml::from_reader(file).map_err(|e| track!(Error::from(e)))?;
 
-        let mut unknowns = std::vec::Vec::new();
-        let wrapped: FrugalosConfigWrapper =
-            serde_ignored::deserialize(value, |path| unknowns.push(path.to_string()))
-                .map_err(|e| track!(Error::from(e)))?;
-        Ok((wrapped.config, unknowns))
+        let (wrapped, warnings): (FrugalosConfigWrapper, _) = FRUGALOS_CONFIG_SCHEMA
+            .deserialize(value)
+            .map_err(|e| track!(Error::from(e)))?;
+        track!(wrapped.config.validate())?;
+        Ok((wrapped.config, warnings))
+    }
+
+    /// 設定値の整合性を検証する。
+    ///
+    /// Raft の選挙タイムアウト時間は、環境変数(`FRUGALOS_RAFT_MIN_TIMEOUT`)から取得される。
+    pub fn validate(&self) -> Result<()> {
+        let min_election_timeout = frugalos_raft::Timer::from_env().min_timeout();
+        track!(self
+            .mds
+            .validate(min_election_timeout)
+            .map_err(|e| ErrorKind::InvalidInput.takes_over(e)))?;
+        Ok(())
     }
 }
 
@@ -136,16 +291,82 @@ impl Default for FrugalosConfig {
             data_dir: Default::default(),
             log_file: Default::default(),
             loglevel: default_loglevel(),
+            log_format: Default::default(),
             max_concurrent_logs: default_max_concurrent_logs(),
             daemon: Default::default(),
             http_server: Default::default(),
+            grpc_server: Default::default(),
             rpc_client: Default::default(),
+            discovery: Default::default(),
+            device: Default::default(),
+            workload_recorder: Default::default(),
+            slo: Default::default(),
+            watchdog: Default::default(),
+            event_sink: Default::default(),
+            existence_filter: Default::default(),
+            operation: Default::default(),
+            rebalancer: Default::default(),
+            repair: Default::default(),
+            memory: Default::default(),
+            cluster_features: Default::default(),
+            auth: Default::default(),
+            admission: Default::default(),
+            tracing: Default::default(),
+            prometheus: Default::default(),
             mds: Default::default(),
             segment: Default::default(),
         }
     }
 }
 
+/// リペア処理向けの設定。
+///
+/// `frugalos set-repair-config`で変更できる値の初期値で、設定の再読み込み時にも反映される。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrugalosRepairConfig {
+    /// リペアを開始するまでに、ノードが暇な状態でいなければならない時間。
+    ///
+    /// `None`の場合にはリペアを行わない。
+    #[serde(
+        rename = "idleness_threshold_millis",
+        default,
+        with = "frugalos_core::serde_ext::option_duration_millis"
+    )]
+    pub idleness_threshold: Option<Duration>,
+
+    /// 同時に実行できるリペアの数の上限。
+    #[serde(default)]
+    pub concurrency_limit: u64,
+}
+impl FrugalosRepairConfig {
+    /// `frugalos_segment`に適用するための`RepairConfig`に変換する。
+    pub fn to_repair_config(&self) -> RepairConfig {
+        RepairConfig {
+            repair_concurrency_limit: Some(RepairConcurrencyLimit(self.concurrency_limit)),
+            repair_idleness_threshold: Some(
+                self.idleness_threshold
+                    .map_or(RepairIdleness::Disabled, RepairIdleness::Threshold),
+            ),
+            segment_gc_concurrency_limit: None,
+        }
+    }
+}
+
+/// メモリ使用量の予算に関する設定。
+///
+/// 使用量は、同期用のキュー群・MDS のマシン・キャッシュ等のサブシステム毎に見積もられ、
+/// `frugalos_memory_usage_bytes`メトリクスとして公開される。
+/// 設定の再読み込み時にも反映される。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrugalosMemoryConfig {
+    /// メモリ使用量の見積もりの合計に対する予算(バイト)。
+    ///
+    /// 合計がこれを超えている間は、新たなリペアと segment_gc の開始(ないし再開)が見合わせられる。
+    /// 実行中の処理は中断されない。0 の場合には予算を設けない。
+    #[serde(default)]
+    pub budget_bytes: u64,
+}
+
 /// `FrugalosDaemon` 向けの設定。
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FrugalosDaemonConfig {
@@ -157,6 +378,10 @@ pub struct FrugalosDaemonConfig {
     #[serde(default = "default_sampling_rate")]
     pub sampling_rate: f64,
 
+    /// 遅いリクエストのトレースを、サンプリング確率に関わらず報告するための設定。
+    #[serde(default)]
+    pub tail_sampling: TailSamplingConfig,
+
     /// frugalos 停止時に待つ時間。
     #[serde(
         rename = "stop_waiting_time_millis",
@@ -164,6 +389,57 @@ pub struct FrugalosDaemonConfig {
         with = "frugalos_core::serde_ext::duration_millis"
     )]
     pub stop_waiting_time: Duration,
+
+    /// 他のサーバとの時計のズレを計測する間隔。
+    #[serde(
+        rename = "clock_skew_check_interval_millis",
+        default = "default_clock_skew_check_interval",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub clock_skew_check_interval: Duration,
+
+    /// 時計のズレを警告する閾値。
+    #[serde(
+        rename = "clock_skew_warning_threshold_millis",
+        default = "default_clock_skew_warning_threshold",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub clock_skew_warning_threshold: Duration,
+
+    /// SIGTERM を受け取った際に、graceful shutdown を行うかどうか。
+    ///
+    /// `true` の場合には、readiness を not-ready に切り替えてリーダ権を他のサーバに譲った後に、
+    /// `frugalos stop` と同様の停止処理(スナップショットの取得を含む)を行う。
+    /// Kubernetes の Pod のように、SIGTERM で停止が指示される環境での利用を想定している。
+    #[serde(default)]
+    pub graceful_shutdown_on_sigterm: bool,
+
+    /// graceful shutdown 時に、リーダ権の移譲を待ってから停止処理を開始するまでの時間。
+    #[serde(
+        rename = "leadership_drain_time_millis",
+        default = "default_leadership_drain_time",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub leadership_drain_time: Duration,
+
+    /// graceful shutdown の猶予期間。
+    ///
+    /// SIGTERM の受信からこの時間が経過しても停止処理が完了しない場合には、強制的に終了する。
+    /// Kubernetes の `terminationGracePeriodSeconds` よりも短い値を指定すること。
+    #[serde(
+        rename = "shutdown_grace_period_millis",
+        default = "default_shutdown_grace_period",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub shutdown_grace_period: Duration,
+
+    /// 起動時に、他のサーバへの接続を事前に確立するための設定。
+    #[serde(default)]
+    pub warmup: FrugalosWarmupConfig,
+
+    /// `/v1/frugalos/readiness`による readiness の判定に関する設定。
+    #[serde(default)]
+    pub readiness: FrugalosReadinessConfig,
 }
 
 impl Default for FrugalosDaemonConfig {
@@ -171,7 +447,74 @@ impl Default for FrugalosDaemonConfig {
         Self {
             executor_threads: default_executor_threads(),
             sampling_rate: default_sampling_rate(),
+            tail_sampling: Default::default(),
             stop_waiting_time: default_stop_waiting_time(),
+            clock_skew_check_interval: default_clock_skew_check_interval(),
+            clock_skew_warning_threshold: default_clock_skew_warning_threshold(),
+            graceful_shutdown_on_sigterm: false,
+            leadership_drain_time: default_leadership_drain_time(),
+            shutdown_grace_period: default_shutdown_grace_period(),
+            warmup: Default::default(),
+            readiness: Default::default(),
+        }
+    }
+}
+
+/// 起動時の接続のウォームアップの設定。
+///
+/// ウォームアップ中は readiness が not-ready となるので、
+/// 再起動直後のリクエストが接続確立の遅延を被ることを避けられる。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosWarmupConfig {
+    /// ウォームアップを行うかどうか。
+    #[serde(default = "default_warmup_enabled")]
+    pub enabled: bool,
+
+    /// 新たなサーバの登録が途絶えてから、サーバ一覧が揃ったとみなすまでの時間。
+    #[serde(
+        rename = "settle_time_millis",
+        default = "default_warmup_settle_time",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub settle_time: Duration,
+
+    /// ウォームアップの上限時間。
+    ///
+    /// この時間が経過すると、接続の確立が終わっていないサーバがあっても ready となる。
+    #[serde(
+        rename = "timeout_millis",
+        default = "default_warmup_timeout",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub timeout: Duration,
+}
+
+impl Default for FrugalosWarmupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_warmup_enabled(),
+            settle_time: default_warmup_settle_time(),
+            timeout: default_warmup_timeout(),
+        }
+    }
+}
+
+/// `/v1/frugalos/readiness`による readiness の判定に関する設定。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosReadinessConfig {
+    /// ローカルの MDS ノードのうち、所属するクラスタのリーダが確定しているべきものの割合(`0.0`から`1.0`)。
+    ///
+    /// この割合に満たない間は not-ready となる。
+    /// 一部のセグメントでリーダが不在なだけで全てのサーバが not-ready にならないように、
+    /// デフォルト値は`0.5`となっている。
+    #[serde(default = "default_readiness_mds_leader_ratio")]
+    pub mds_leader_ratio: f64,
+}
+
+impl Default for FrugalosReadinessConfig {
+    fn default() -> Self {
+        Self {
+            mds_leader_ratio: default_readiness_mds_leader_ratio(),
         }
     }
 }
@@ -182,12 +525,84 @@ pub struct FrugalosHttpServerConfig {
     /// bind するアドレス。
     #[serde(default = "default_http_server_bind_addr")]
     pub bind_addr: SocketAddr,
+
+    /// 分割アップロードの設定。
+    #[serde(default)]
+    pub upload: FrugalosUploadConfig,
+
+    /// オブジェクトの取得時に、内容のダイジェストを`X-Frugalos-Content-Digest`ヘッダで返す際のアルゴリズム。
+    ///
+    /// 指定されない場合には、ダイジェストは返さない。
+    /// 書き込み時のヘッダによる内容の検証は、この設定に関わらず全てのアルゴリズムで行われる。
+    #[serde(default)]
+    pub content_digest: Option<ChecksumAlgorithm>,
 }
 
 impl Default for FrugalosHttpServerConfig {
     fn default() -> Self {
         Self {
             bind_addr: default_http_server_bind_addr(),
+            upload: Default::default(),
+            content_digest: None,
+        }
+    }
+}
+
+/// gRPC server 向けの設定。
+///
+/// gRPC の API は`grpc`フィーチャを有効にしてビルドした場合にのみ利用できる。
+/// フィーチャが無効な場合に`bind_addr`を指定すると、起動時にエラーとなる。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosGrpcServerConfig {
+    /// bind するアドレス。
+    ///
+    /// 指定されない場合には、gRPC サーバは起動しない。
+    #[serde(default)]
+    pub bind_addr: Option<SocketAddr>,
+
+    /// gRPC のリクエストを処理するスレッド(completion queue)の数。
+    ///
+    /// リクエストの処理自体は executor 上で行われるので、多くする必要はない。
+    #[serde(default = "default_grpc_server_completion_queues")]
+    pub completion_queues: usize,
+}
+
+impl Default for FrugalosGrpcServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: None,
+            completion_queues: default_grpc_server_completion_queues(),
+        }
+    }
+}
+
+/// 分割アップロード(追記によるオブジェクトの作成)の設定。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosUploadConfig {
+    /// この時間以上、部分の送信や状態の問い合わせが無いアップロードは、放棄されたものと見なす。
+    ///
+    /// 放棄されたアップロードの作成途中のオブジェクトは削除される。
+    #[serde(
+        rename = "abandoned_timeout_millis",
+        default = "default_upload_abandoned_timeout",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub abandoned_timeout: Duration,
+
+    /// 放棄されたアップロードを確認する間隔。
+    #[serde(
+        rename = "gc_interval_millis",
+        default = "default_upload_gc_interval",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub gc_interval: Duration,
+}
+
+impl Default for FrugalosUploadConfig {
+    fn default() -> Self {
+        Self {
+            abandoned_timeout: default_upload_abandoned_timeout(),
+            gc_interval: default_upload_gc_interval(),
         }
     }
 }
@@ -230,6 +645,552 @@ impl Default for FrugalosRpcClientConfig {
     }
 }
 
+/// サーバのアドレス解決向けの設定。
+///
+/// クラスタ構成にはサーバの IP アドレスが登録されているが、ここでホスト名を指定したサーバについては、
+/// 定期的に名前解決を行い、得られたアドレスを使って通信する。
+/// 再起動の度に IP アドレスが変わる環境(クラウドや k8s 等)での利用を想定している。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosDiscoveryConfig {
+    /// サーバ ID からホスト名への対応。
+    ///
+    /// ポート番号にはクラスタ構成に登録されているものが使われる。
+    #[serde(default)]
+    pub hostnames: BTreeMap<ServerId, String>,
+
+    /// 名前解決をやり直す間隔。
+    #[serde(
+        rename = "refresh_interval_millis",
+        default = "default_discovery_refresh_interval",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub refresh_interval: Duration,
+}
+
+impl Default for FrugalosDiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            hostnames: BTreeMap::new(),
+            refresh_interval: default_discovery_refresh_interval(),
+        }
+    }
+}
+
+/// ローカルデバイスに関する設定。
+///
+/// `precheck`および`preallocate`の設定は、ファイルデバイスが新規に作成される場合にのみ使われる。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosDeviceConfig {
+    /// デバイスファイルの作成時に、容量分の領域を事前に確保するかどうか。
+    #[serde(default)]
+    pub preallocate: bool,
+
+    /// デバイスファイルの作成時に、ディスクの簡易的な性能試験を行うかどうか。
+    ///
+    /// 試験の結果が閾値を満たさない場合には、そのデバイスは起動されない。
+    #[serde(default)]
+    pub precheck: bool,
+
+    /// 性能試験で読み書きするデータのサイズ(バイト単位)。
+    #[serde(default = "default_device_precheck_size")]
+    pub precheck_size: u64,
+
+    /// 性能試験で許容する、一回の読み書きにかかる時間の上限。
+    #[serde(
+        rename = "precheck_max_latency_millis",
+        default = "default_device_precheck_max_latency",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub precheck_max_latency: Duration,
+
+    /// 性能試験で要求する、書き込みスループットの下限(バイト/秒)。
+    #[serde(default = "default_device_precheck_min_throughput")]
+    pub precheck_min_write_throughput: u64,
+
+    /// 性能試験で要求する、読み込みスループットの下限(バイト/秒)。
+    #[serde(default = "default_device_precheck_min_throughput")]
+    pub precheck_min_read_throughput: u64,
+
+    /// ファイルデバイスが載っているディスクの健全性を定期的に確認するかどうか。
+    ///
+    /// 故障が差し迫っていると判定されたディスク上のノードは、リーダ権を他のサーバに譲る。
+    #[serde(default)]
+    pub health_check: bool,
+
+    /// ディスクの健全性を確認する間隔。
+    #[serde(
+        rename = "health_check_interval_millis",
+        default = "default_device_health_check_interval",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub health_check_interval: Duration,
+
+    /// ディスクの健全性の確認に使う`smartctl`コマンドのパス。
+    #[serde(default = "default_device_health_check_command")]
+    pub health_check_command: String,
+
+    /// 故障が差し迫っていると連続して判定された回数が、この値に達したディスクを隔離する。
+    #[serde(default = "default_device_health_quarantine_threshold")]
+    pub health_quarantine_threshold: usize,
+
+    /// 隔離したディスク上のデバイスの退役を、構成管理用のクラスタに自動で要求するかどうか。
+    ///
+    /// 退役させたデバイスのセグメントは他のデバイスに再配置されるので、ディスクが完全に壊れる前にデータを退避できる。
+    /// クラスタ機能の`decommission`と`relayout`が有効になっている必要がある。
+    #[serde(default)]
+    pub health_auto_decommission: bool,
+
+    /// ローカルデバイスの使用量を、構成管理用のクラスタに定期的に報告するかどうか。
+    ///
+    /// 報告された使用量は、以降に作成されるバケツのセグメント配置で考慮される。
+    #[serde(default)]
+    pub usage_report: bool,
+
+    /// デバイスの使用量を報告する間隔。
+    #[serde(
+        rename = "usage_report_interval_millis",
+        default = "default_device_usage_report_interval",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub usage_report_interval: Duration,
+}
+
+impl Default for FrugalosDeviceConfig {
+    fn default() -> Self {
+        Self {
+            preallocate: false,
+            precheck: false,
+            precheck_size: default_device_precheck_size(),
+            precheck_max_latency: default_device_precheck_max_latency(),
+            precheck_min_write_throughput: default_device_precheck_min_throughput(),
+            precheck_min_read_throughput: default_device_precheck_min_throughput(),
+            health_check: false,
+            health_check_interval: default_device_health_check_interval(),
+            health_check_command: default_device_health_check_command(),
+            health_quarantine_threshold: default_device_health_quarantine_threshold(),
+            health_auto_decommission: false,
+            usage_report: false,
+            usage_report_interval: default_device_usage_report_interval(),
+        }
+    }
+}
+
+/// リクエストの記録に関する設定。
+///
+/// 記録したファイルは`frugalos bench`で再生することができる。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosWorkloadRecorderConfig {
+    /// 記録先のファイルパス。
+    ///
+    /// 指定されていない場合には記録は行われない。
+    #[serde(default)]
+    pub filepath: Option<PathBuf>,
+
+    /// 記録するリクエストの割合(オブジェクト単位でサンプリングされる)。
+    #[serde(default = "default_workload_sampling_rate")]
+    pub sampling_rate: f64,
+}
+
+impl Default for FrugalosWorkloadRecorderConfig {
+    fn default() -> Self {
+        Self {
+            filepath: None,
+            sampling_rate: default_workload_sampling_rate(),
+        }
+    }
+}
+
+/// SLO(サービスレベル目標)の追跡に関する設定。
+///
+/// 目標が設定されたバケツについて、成功率とエラーバジェットの消費速度(バーンレート)が
+/// メトリクスとして公開される。
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct FrugalosSloConfig {
+    /// バケツ毎の目標。
+    #[serde(default)]
+    pub objectives: Vec<FrugalosSloObjective>,
+}
+
+/// あるバケツに対するオブジェクト操作の目標。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosSloObjective {
+    /// 対象のバケツの ID。
+    pub bucket_id: String,
+
+    /// この時間以内に成功応答を返したリクエストを、レイテンシの目標を満たしたものと見なす。
+    #[serde(
+        rename = "latency_threshold_millis",
+        default = "default_slo_latency_threshold",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub latency_threshold: Duration,
+
+    /// レイテンシの目標を満たすリクエストの割合の目標値。
+    #[serde(default = "default_slo_latency_target")]
+    pub latency_target: f64,
+
+    /// 成功(5xx 以外の)応答を返すリクエストの割合の目標値。
+    #[serde(default = "default_slo_availability_target")]
+    pub availability_target: f64,
+}
+
+/// 停滞したバックグラウンドタスク(リペア、セグメントGC、スナップショットの保存等)の監視に関する設定。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosWatchdogConfig {
+    /// この時間以上進捗が無いタスクを、停滞しているものと見なす。
+    #[serde(
+        rename = "stall_threshold_millis",
+        default = "default_watchdog_stall_threshold",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub stall_threshold: Duration,
+
+    /// タスクの状態を確認する間隔。
+    #[serde(
+        rename = "check_interval_millis",
+        default = "default_watchdog_check_interval",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub check_interval: Duration,
+
+    /// 停滞しているタスクに中断を要求するかどうか。
+    ///
+    /// 中断されたリペアはキューに戻されて、後で再実行される。
+    #[serde(default)]
+    pub cancel_stalled: bool,
+}
+
+impl Default for FrugalosWatchdogConfig {
+    fn default() -> Self {
+        Self {
+            stall_threshold: default_watchdog_stall_threshold(),
+            check_interval: default_watchdog_check_interval(),
+            cancel_stalled: false,
+        }
+    }
+}
+
+/// 長時間操作(接頭辞指定でのオブジェクト削除等)の管理に関する設定。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosOperationConfig {
+    /// 終了した操作の状態を保持する期間。
+    #[serde(
+        rename = "retention_millis",
+        default = "default_operation_retention",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub retention: Duration,
+
+    /// バケツの書き出し(export)と復元(import)で使うアーカイブを置くディレクトリ。
+    ///
+    /// 書き出しと復元の要求で指定されたパスは、このディレクトリからの相対パスとして扱われ、
+    /// ディレクトリの外を指すパスは拒否される。
+    /// 指定されていない場合には、書き出しと復元は行えない。
+    #[serde(default)]
+    pub archive_dir: Option<PathBuf>,
+}
+
+impl Default for FrugalosOperationConfig {
+    fn default() -> Self {
+        Self {
+            retention: default_operation_retention(),
+            archive_dir: None,
+        }
+    }
+}
+
+/// セグメントの配置の偏りを均す、バックグラウンドでの移動(リバランス)に関する設定。
+///
+/// 移動は構成管理用クラスタのリーダとなっているサーバでのみ開始され、一度に一つのバケツ分ずつ、
+/// 再配置として非同期に進められる。
+/// 新たな移動は、構成管理用クラスタに設定されたメンテナンスウィンドウ内でのみ開始される
+/// (開始済みの移動は、ウィンドウの外でも継続する)。
+/// 有効にするには、クラスタ単位の機能`relayout`と`rebalance`も有効にしておく必要がある。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosRebalancerConfig {
+    /// バックグラウンドでの移動を行うかどうか。
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// 新たな移動を開始するかどうかを確認する間隔。
+    ///
+    /// 一つの確認で開始される移動は、高々一つのバケツ分となる。
+    #[serde(
+        rename = "interval_millis",
+        default = "default_rebalancer_interval",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub interval: Duration,
+
+    /// 一度の移動で、一つのバケツで移動対象とする割当の最大割合(`0.0`から`1.0`の範囲)。
+    #[serde(default = "default_rebalancer_max_move_ratio")]
+    pub max_move_ratio: f64,
+
+    /// どのバケツからも参照されていない物理デバイスを、移動先の候補に含めるかどうか。
+    #[serde(default = "default_rebalancer_include_unused_devices")]
+    pub include_unused_devices: bool,
+
+    /// 再配置中のバケツがこの数以上ある間は、新たな移動を開始しない。
+    ///
+    /// 手動での再配置や、デバイスの退役による再配置も数に含まれる。
+    #[serde(default = "default_rebalancer_max_relayouts")]
+    pub max_relayouts: usize,
+}
+
+impl Default for FrugalosRebalancerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: default_rebalancer_interval(),
+            max_move_ratio: default_rebalancer_max_move_ratio(),
+            include_unused_devices: default_rebalancer_include_unused_devices(),
+            max_relayouts: default_rebalancer_max_relayouts(),
+        }
+    }
+}
+
+/// オブジェクトの変更(追加と削除)のイベントを、外部に転送するための設定。
+///
+/// 転送は、この設定を持つサーバ毎に行われる。
+/// 同じイベントが重複して転送されることを避けたい場合には、一台のサーバでのみ設定すること。
+///
+/// 転送はサーバの起動後の変更から開始され、それ以前の変更が転送されることはない。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosEventSinkConfig {
+    /// バケツ毎の転送先。
+    #[serde(default)]
+    pub buckets: Vec<FrugalosBucketEventSink>,
+
+    /// 新しい変更がなかった場合に、次に MDS に問い合わせるまでの間隔。
+    #[serde(
+        rename = "polling_interval_millis",
+        default = "default_event_sink_polling_interval",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub polling_interval: Duration,
+
+    /// 変更の取得や転送に失敗した場合に、再試行するまでの間隔。
+    #[serde(
+        rename = "retry_interval_millis",
+        default = "default_event_sink_retry_interval",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub retry_interval: Duration,
+}
+
+impl Default for FrugalosEventSinkConfig {
+    fn default() -> Self {
+        Self {
+            buckets: Vec::new(),
+            polling_interval: default_event_sink_polling_interval(),
+            retry_interval: default_event_sink_retry_interval(),
+        }
+    }
+}
+
+/// あるバケツのイベントの転送先。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosBucketEventSink {
+    /// 対象のバケツの ID。
+    pub bucket_id: String,
+
+    /// 転送先。
+    pub sink: EventSinkConfig,
+}
+
+/// 組み込みの転送先。
+///
+/// これ以外の転送先(Kafka や NATS 等)を使う場合には、`EventSink`を実装して
+/// `FrugalosDaemon::add_event_sink`で登録する。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventSinkConfig {
+    /// ログに出力する(`LogEventSink`)。
+    Log,
+
+    /// ファイルに JSON Lines 形式で追記する(`FileEventSink`)。
+    File {
+        /// 出力先のファイルパス。
+        path: PathBuf,
+    },
+}
+
+/// オブジェクトの存在フィルタ(Bloom filter)に関する設定。
+///
+/// 対象のバケツでは、セグメント毎にオブジェクトの ID のフィルタを MDS の変更履歴から構築し、
+/// 存在しないことが確実なオブジェクトの取得要求に対しては、MDS に問い合わせずに応答する。
+/// フィルタは変更履歴に遅れて追従するため、`ReadConsistency::Consistent`以外の要求でのみ使われる。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosExistenceFilterConfig {
+    /// フィルタを使用するバケツの ID の一覧。
+    #[serde(default)]
+    pub buckets: Vec<String>,
+
+    /// セグメント当たりに想定するオブジェクト数。
+    ///
+    /// これを超えるオブジェクトが登録された場合には、容量を増やしてフィルタを再構築する。
+    #[serde(default = "default_existence_filter_expected_objects")]
+    pub expected_objects_per_segment: usize,
+
+    /// 偽陽性率の目標値。
+    #[serde(default = "default_existence_filter_false_positive_rate")]
+    pub false_positive_rate: f64,
+
+    /// 新しい変更がなかった場合に、次に MDS に問い合わせるまでの間隔。
+    #[serde(
+        rename = "polling_interval_millis",
+        default = "default_event_sink_polling_interval",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub polling_interval: Duration,
+
+    /// 新しい変更がない状態が続いた場合の、問い合わせ間隔の上限。
+    ///
+    /// 変更がない間は、問い合わせの間隔が`polling_interval`から倍々に延ばされる。
+    /// 他のサーバで保存されたオブジェクトは、最大でこの時間だけ遅れてフィルタに反映される。
+    #[serde(
+        rename = "max_polling_interval_millis",
+        default = "default_existence_filter_max_polling_interval",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub max_polling_interval: Duration,
+
+    /// 変更やオブジェクトの一覧の取得に失敗した場合に、再試行するまでの間隔。
+    #[serde(
+        rename = "retry_interval_millis",
+        default = "default_event_sink_retry_interval",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub retry_interval: Duration,
+}
+
+impl Default for FrugalosExistenceFilterConfig {
+    fn default() -> Self {
+        Self {
+            buckets: Vec::new(),
+            expected_objects_per_segment: default_existence_filter_expected_objects(),
+            false_positive_rate: default_existence_filter_false_positive_rate(),
+            polling_interval: default_event_sink_polling_interval(),
+            max_polling_interval: default_existence_filter_max_polling_interval(),
+            retry_interval: default_event_sink_retry_interval(),
+        }
+    }
+}
+
+/// RPC と HTTP の API の認可に関する設定。
+///
+/// 組み込みの方式以外で認可を行う場合には、`Authorizer`を実装して
+/// `FrugalosDaemon::set_authorizer`で登録する。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrugalosAuthConfig {
+    /// 認可の方式。
+    #[serde(default)]
+    pub authorizer: AuthorizerConfig,
+
+    /// 主体の名前と、その主体が提示するトークンの対応。
+    #[serde(default)]
+    pub tokens: BTreeMap<String, String>,
+
+    /// サーバ間の RPC の認証に使うクラスタトークン。
+    ///
+    /// 設定されている場合には、構成管理の更新系、MDS、セグメントの RPC はこのトークンを伴う呼び出しのみを受け付ける。
+    /// また、このトークンを提示した呼び出しには全ての操作が許可される。
+    /// クラスタ内の全てのサーバで同じ値を設定する必要がある。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cluster_token: Option<String>,
+}
+
+/// 組み込みの認可の方式。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuthorizerConfig {
+    /// 全ての操作を許可する(`AllowAllAuthorizer`)。
+    AllowAll,
+
+    /// 有効なトークンを提示した主体に全ての操作を許可する(`StaticTokenAuthorizer`)。
+    StaticToken,
+
+    /// バケツ毎のアクセス制御リストに従う(`BucketAclAuthorizer`)。
+    BucketAcl {
+        /// バケツ毎の ACL。
+        #[serde(default)]
+        buckets: BTreeMap<String, BucketAcl>,
+
+        /// ACL が設定されていないバケツと、バケツに属さない操作に使われる ACL。
+        #[serde(default)]
+        default: BucketAcl,
+    },
+}
+impl Default for AuthorizerConfig {
+    fn default() -> Self {
+        AuthorizerConfig::AllowAll
+    }
+}
+
+/// オブジェクトの取得と書き込みのリクエストの受け付け制御に関する設定。
+///
+/// 上限を超えたリクエストは、処理を開始せずに`ErrorKind::Busy`で拒否される。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosAdmissionConfig {
+    /// 全てのバケツに対するリクエストの合計に適用される上限。
+    #[serde(default)]
+    pub global: AdmissionLimits,
+
+    /// バケツ毎の上限。
+    #[serde(default)]
+    pub buckets: BTreeMap<String, AdmissionLimits>,
+
+    /// 同時実行数の上限を超えて拒否した場合に、クライアントに再試行を促すまでの時間。
+    ///
+    /// レートの上限を超えた場合には、次のリクエストを受け付けられるまでの時間が使われる。
+    #[serde(
+        rename = "retry_after_millis",
+        default = "default_admission_retry_after",
+        with = "frugalos_core::serde_ext::duration_millis"
+    )]
+    pub retry_after: Duration,
+}
+
+impl Default for FrugalosAdmissionConfig {
+    fn default() -> Self {
+        Self {
+            global: Default::default(),
+            buckets: BTreeMap::new(),
+            retry_after: default_admission_retry_after(),
+        }
+    }
+}
+
+/// 分散トレーシングのスパンの送信先に関する設定。
+///
+/// スパンを記録するかどうかは`daemon.sampling_rate`と`daemon.tail_sampling`で決まる。
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FrugalosTracingConfig {
+    /// スパンの送信方式。
+    #[serde(default)]
+    pub exporter: TracingExporterConfig,
+}
+
+/// スパンの送信方式。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TracingExporterConfig {
+    /// ローカルの Jaeger エージェントに UDP で送信する。
+    Jaeger,
+
+    /// OTLP (OpenTelemetry Protocol) を受け付けるコレクタに送信する。
+    Otlp(OtlpExporterConfig),
+}
+impl Default for TracingExporterConfig {
+    fn default() -> Self {
+        TracingExporterConfig::Jaeger
+    }
+}
+
+fn default_admission_retry_after() -> Duration {
+    Duration::from_secs(1)
+}
+
 fn default_executor_threads() -> usize {
     num_cpus::get()
 }
@@ -242,10 +1203,150 @@ fn default_stop_waiting_time() -> Duration {
     Duration::from_millis(5000)
 }
 
+fn default_clock_skew_check_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_clock_skew_warning_threshold() -> Duration {
+    Duration::from_millis(1000)
+}
+
+fn default_leadership_drain_time() -> Duration {
+    Duration::from_millis(3000)
+}
+
+fn default_shutdown_grace_period() -> Duration {
+    Duration::from_secs(25)
+}
+
+fn default_warmup_enabled() -> bool {
+    true
+}
+
+fn default_warmup_settle_time() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn default_warmup_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_readiness_mds_leader_ratio() -> f64 {
+    0.5
+}
+
+fn default_discovery_refresh_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_device_precheck_size() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_device_precheck_max_latency() -> Duration {
+    Duration::from_millis(1000)
+}
+
+fn default_device_precheck_min_throughput() -> u64 {
+    1024 * 1024
+}
+
+fn default_workload_sampling_rate() -> f64 {
+    0.01
+}
+
+fn default_slo_latency_threshold() -> Duration {
+    Duration::from_millis(1000)
+}
+
+fn default_slo_latency_target() -> f64 {
+    0.99
+}
+
+fn default_slo_availability_target() -> f64 {
+    0.999
+}
+
+fn default_watchdog_stall_threshold() -> Duration {
+    Duration::from_secs(600)
+}
+
+fn default_watchdog_check_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_operation_retention() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+fn default_rebalancer_interval() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
+fn default_rebalancer_max_move_ratio() -> f64 {
+    0.05
+}
+
+fn default_rebalancer_include_unused_devices() -> bool {
+    true
+}
+
+fn default_rebalancer_max_relayouts() -> usize {
+    1
+}
+
+fn default_event_sink_polling_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_event_sink_retry_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_existence_filter_expected_objects() -> usize {
+    100_000
+}
+
+fn default_existence_filter_false_positive_rate() -> f64 {
+    0.01
+}
+
+fn default_existence_filter_max_polling_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_device_health_check_interval() -> Duration {
+    Duration::from_secs(600)
+}
+
+fn default_device_health_check_command() -> String {
+    "smartctl".to_owned()
+}
+
+fn default_device_health_quarantine_threshold() -> usize {
+    3
+}
+
+fn default_device_usage_report_interval() -> Duration {
+    Duration::from_secs(600)
+}
+
+fn default_grpc_server_completion_queues() -> usize {
+    2
+}
+
 fn default_http_server_bind_addr() -> SocketAddr {
     SocketAddr::from(([127, 0, 0, 1], 3000))
 }
 
+fn default_upload_abandoned_timeout() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+fn default_upload_gc_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
 fn default_tcp_connect_timeout() -> Duration {
     Duration::from_secs(5)
 }
@@ -254,6 +1355,25 @@ fn default_tcp_write_timeout() -> Duration {
     Duration::from_secs(5)
 }
 
+/// ログの出力形式。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// 人が読むためのテキスト形式。
+    Text,
+
+    /// 一行に一つの JSON オブジェクトを出力する形式。
+    ///
+    /// ID 等の構造化フィールドは、JSON オブジェクトのキーとして出力される。
+    /// キーの名前は`frugalos_core::logging`に定義されている。
+    Json,
+}
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
 fn default_loglevel() -> sloggers::types::Severity {
     sloggers::types::Severity::Info
 }
@@ -265,7 +1385,11 @@ fn default_max_concurrent_logs() -> usize {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use frugalos_segment::config::MdsRequestPolicy;
+    use frugalos_core::tracer::otlp::OtlpProtocol;
+    use frugalos_segment::config::{
+        BucketEncryptionConfig, ErasureCoderConfig, FragmentFanOut, KeyProviderConfig,
+        MdsRequestPolicy, RetryableErrorKind, SaturationPolicy,
+    };
     use libfrugalos::time::Seconds;
     use std::fs::File;
     use std::io::Write;
@@ -280,16 +1404,124 @@ frugalos:
   data_dir: "/var/lib/frugalos"
   log_file: ~
   loglevel: critical
+  log_format: json
   max_concurrent_logs: 30
   daemon:
     executor_threads: 3
     sampling_rate: 0.1
+    tail_sampling:
+      enabled: true
+      latency_threshold_millis: 2000
     stop_waiting_time_millis: 300
+    clock_skew_check_interval_millis: 30000
+    clock_skew_warning_threshold_millis: 500
+    graceful_shutdown_on_sigterm: true
+    leadership_drain_time_millis: 1000
+    shutdown_grace_period_millis: 20000
+    warmup:
+      settle_time_millis: 500
+      timeout_millis: 10000
+    readiness:
+      mds_leader_ratio: 0.9
   http_server:
     bind_addr: "127.0.0.1:2222"
+    upload:
+      abandoned_timeout_millis: 600000
+    content_digest: sha256
+  grpc_server:
+    bind_addr: "127.0.0.1:3200"
+    completion_queues: 4
   rpc_client:
     tcp_connect_timeout_millis: 8000
     tcp_write_timeout_millis: 10000
+  discovery:
+    hostnames:
+      srv1: "frugalos-0.frugalos.default.svc.cluster.local"
+    refresh_interval_millis: 5000
+  device:
+    preallocate: true
+    precheck: true
+    precheck_size: 1048576
+    precheck_max_latency_millis: 500
+    precheck_min_write_throughput: 2097152
+    precheck_min_read_throughput: 4194304
+    health_check: true
+    health_check_interval_millis: 60000
+    health_check_command: /usr/sbin/smartctl
+    health_quarantine_threshold: 2
+    health_auto_decommission: true
+    usage_report: true
+    usage_report_interval_millis: 30000
+  workload_recorder:
+    filepath: /var/log/frugalos/workload.dat
+    sampling_rate: 0.5
+  slo:
+    objectives:
+      - bucket_id: logs
+        latency_threshold_millis: 200
+        latency_target: 0.95
+      - bucket_id: images
+  watchdog:
+    stall_threshold_millis: 120000
+    check_interval_millis: 10000
+    cancel_stalled: true
+  event_sink:
+    buckets:
+      - bucket_id: logs
+        sink:
+          type: file
+          path: /var/log/frugalos/events.jsonl
+      - bucket_id: images
+        sink:
+          type: log
+    polling_interval_millis: 500
+  existence_filter:
+    buckets: [logs]
+    expected_objects_per_segment: 1000000
+    false_positive_rate: 0.001
+    max_polling_interval_millis: 10000
+  operation:
+    retention_millis: 3600000
+    archive_dir: /var/lib/frugalos/archives
+  rebalancer:
+    enabled: true
+    interval_millis: 60000
+    max_move_ratio: 0.1
+    include_unused_devices: false
+    max_relayouts: 2
+  memory:
+    budget_bytes: 8589934592
+  auth:
+    authorizer:
+      type: bucket_acl
+      buckets:
+        logs:
+          read: ["*"]
+          write: [writer]
+      default:
+        admin: [admin]
+    tokens:
+      admin: admin-token
+      writer: writer-token
+    cluster_token: cluster-secret
+  admission:
+    global:
+      max_inflight_gets: 1000
+      max_inflight_puts: 200
+    buckets:
+      logs:
+        max_requests_per_sec: 50.0
+    retry_after_millis: 2000
+  tracing:
+    exporter:
+      type: otlp
+      endpoint: "http://127.0.0.1:4318"
+      timeout_millis: 5000
+      max_pending_batches: 32
+  prometheus:
+    histogram_buckets:
+      frugalos_segment_request_duration_seconds: [0.01, 0.1, 1.0]
+      frugalos_mds_get_request_duration_seconds: [0.001, 0.01]
   mds:
     commit_timeout_threshold: 20
     large_proposal_queue_threshold: 250
@@ -299,10 +1531,28 @@ frugalos:
     reelection_threshold: 48
     snapshot_threshold_min: 100
     snapshot_threshold_max: 200
+    snapshot_threshold_bytes: 1048576
     staled_object_threshold: 5000
+    tombstone_retention_secs: 86400
+    tombstone_gc_interval_millis: 60000
+    fetch_snapshot_from_peers: true
+    fetch_snapshot_timeout_millis: 30000
+    consistent_read_lease_millis: 300
+    leadership_balance: false
+    leadership_balance_interval_millis: 30000
+    leadership_balance_slack: 2
+    witness_snapshot_threshold: 500
+    node_startup_concurrency: 32
+    node_startup_concurrency_per_device: 4
+    node_startup_timeout_millis: 5000
+    snapshot_chunk_size: 1048576
   segment:
     dispersed_client:
       get_timeout_millis: 4000
+      hedge:
+        enabled: true
+        delay_millis: 30
+      fan_out: all
       cannyls_device_max_queue_len: 64
       cannyls_rpc_max_queue_len: 128
     replicated_client:
@@ -316,24 +1566,195 @@ frugalos:
       default_request_policy:
         type: 'speculative'
         timeout_millis: 3000
-      put_content_timeout_secs: 32"##;
+      put_content_timeout_secs: 32
+      stale_read_max_lag: 100
+      metadata_cache_capacity: 10000
+      metadata_cache_capacity_bytes: 1048576
+      metadata_cache_ttl_millis: 500
+    erasure_coding:
+      replication_threshold_bytes: 1024
+      buckets:
+        archive:
+          replication_threshold_bytes: 4096
+    request_priority:
+      bulk_deadline_millis: 30000
+    encryption:
+      key_provider:
+        type: file
+        dir: /etc/frugalos/keys
+      buckets:
+        secret:
+          key_id: secret-2019
+    compaction:
+      aggressive: true
+      max_concurrency: 8
+    retry:
+      max_attempts: 4
+      initial_backoff_millis: 50
+      jitter: false
+      retry_on: [busy, other]
+    member_health:
+      ejection_threshold: 0.8
+      ejection_duration_millis: 5000
+    circuit_breaker:
+      failure_threshold: 3
+      cooldown_millis: 2000
+    budget:
+      max_decode_bytes: 104857600
+      max_duration_millis: 30000
+    ec_pool:
+      worker_threads: 4
+      on_saturation: reject
+    content_cache:
+      dispersed_capacity_bytes: 67108864
+      max_entry_bytes: 1048576"##;
         let dir = track_any_err!(TempDir::new("frugalos_test"))?;
         let filepath = dir.path().join("frugalos1.yml");
         let mut file = track_any_err!(File::create(filepath.clone()))?;
 
         track_any_err!(file.write(content.as_bytes()))?;
 
-        let (actual, _) = track!(FrugalosConfig::from_yaml(filepath))?;
+        let (actual, warnings) = track!(FrugalosConfig::from_yaml(filepath))?;
+        assert_eq!(warnings, vec![]);
         let mut expected = FrugalosConfig::default();
         expected.data_dir = "/var/lib/frugalos".to_owned();
         expected.max_concurrent_logs = 30;
         expected.loglevel = sloggers::types::Severity::Critical;
+        expected.log_format = LogFormat::Json;
         expected.daemon.sampling_rate = 0.1;
+        expected.daemon.tail_sampling.enabled = true;
+        expected.daemon.tail_sampling.latency_threshold = Duration::from_secs(2);
         expected.daemon.executor_threads = 3;
         expected.daemon.stop_waiting_time = Duration::from_millis(300);
+        expected.daemon.clock_skew_check_interval = Duration::from_millis(30000);
+        expected.daemon.clock_skew_warning_threshold = Duration::from_millis(500);
+        expected.daemon.graceful_shutdown_on_sigterm = true;
+        expected.daemon.leadership_drain_time = Duration::from_millis(1000);
+        expected.daemon.shutdown_grace_period = Duration::from_secs(20);
+        expected.daemon.warmup.settle_time = Duration::from_millis(500);
+        expected.daemon.warmup.timeout = Duration::from_secs(10);
+        expected.daemon.readiness.mds_leader_ratio = 0.9;
         expected.http_server.bind_addr = SocketAddr::from(([127, 0, 0, 1], 2222));
+        expected.http_server.upload.abandoned_timeout = Duration::from_secs(600);
+        expected.http_server.content_digest = Some(ChecksumAlgorithm::Sha256);
+        expected.grpc_server.bind_addr = Some(SocketAddr::from(([127, 0, 0, 1], 3200)));
+        expected.grpc_server.completion_queues = 4;
         expected.rpc_client.tcp_connect_timeout = Duration::from_secs(8);
         expected.rpc_client.tcp_write_timeout = Duration::from_secs(10);
+        expected.discovery.hostnames.insert(
+            "srv1".to_owned(),
+            "frugalos-0.frugalos.default.svc.cluster.local".to_owned(),
+        );
+        expected.discovery.refresh_interval = Duration::from_secs(5);
+        expected.device.preallocate = true;
+        expected.device.precheck = true;
+        expected.device.precheck_size = 1024 * 1024;
+        expected.device.precheck_max_latency = Duration::from_millis(500);
+        expected.device.precheck_min_write_throughput = 2 * 1024 * 1024;
+        expected.device.precheck_min_read_throughput = 4 * 1024 * 1024;
+        expected.device.health_check = true;
+        expected.device.health_check_interval = Duration::from_secs(60);
+        expected.device.health_check_command = "/usr/sbin/smartctl".to_owned();
+        expected.device.health_quarantine_threshold = 2;
+        expected.device.health_auto_decommission = true;
+        expected.device.usage_report = true;
+        expected.device.usage_report_interval = Duration::from_secs(30);
+        expected.workload_recorder.filepath = Some(PathBuf::from("/var/log/frugalos/workload.dat"));
+        expected.workload_recorder.sampling_rate = 0.5;
+        expected.slo.objectives = vec![
+            FrugalosSloObjective {
+                bucket_id: "logs".to_owned(),
+                latency_threshold: Duration::from_millis(200),
+                latency_target: 0.95,
+                availability_target: 0.999,
+            },
+            FrugalosSloObjective {
+                bucket_id: "images".to_owned(),
+                latency_threshold: Duration::from_secs(1),
+                latency_target: 0.99,
+                availability_target: 0.999,
+            },
+        ];
+        expected.watchdog.stall_threshold = Duration::from_secs(120);
+        expected.watchdog.check_interval = Duration::from_secs(10);
+        expected.watchdog.cancel_stalled = true;
+        expected.rebalancer = FrugalosRebalancerConfig {
+            enabled: true,
+            interval: Duration::from_secs(60),
+            max_move_ratio: 0.1,
+            include_unused_devices: false,
+            max_relayouts: 2,
+        };
+        expected.memory.budget_bytes = 8 * 1024 * 1024 * 1024;
+        expected.event_sink.buckets = vec![
+            FrugalosBucketEventSink {
+                bucket_id: "logs".to_owned(),
+                sink: EventSinkConfig::File {
+                    path: PathBuf::from("/var/log/frugalos/events.jsonl"),
+                },
+            },
+            FrugalosBucketEventSink {
+                bucket_id: "images".to_owned(),
+                sink: EventSinkConfig::Log,
+            },
+        ];
+        expected.event_sink.polling_interval = Duration::from_millis(500);
+        expected.existence_filter.buckets = vec!["logs".to_owned()];
+        expected.existence_filter.expected_objects_per_segment = 1_000_000;
+        expected.existence_filter.false_positive_rate = 0.001;
+        expected.existence_filter.max_polling_interval = Duration::from_secs(10);
+        expected.operation.retention = Duration::from_secs(3600);
+        expected.operation.archive_dir = Some(PathBuf::from("/var/lib/frugalos/archives"));
+        let mut acls = BTreeMap::new();
+        acls.insert(
+            "logs".to_owned(),
+            BucketAcl {
+                read: vec!["*".to_owned()],
+                write: vec!["writer".to_owned()],
+                admin: Vec::new(),
+            },
+        );
+        expected.auth.authorizer = AuthorizerConfig::BucketAcl {
+            buckets: acls,
+            default: BucketAcl {
+                read: Vec::new(),
+                write: Vec::new(),
+                admin: vec!["admin".to_owned()],
+            },
+        };
+        expected
+            .auth
+            .tokens
+            .insert("admin".to_owned(), "admin-token".to_owned());
+        expected
+            .auth
+            .tokens
+            .insert("writer".to_owned(), "writer-token".to_owned());
+        expected.auth.cluster_token = Some("cluster-secret".to_owned());
+        expected.admission.global.max_inflight_gets = Some(1000);
+        expected.admission.global.max_inflight_puts = Some(200);
+        expected.admission.buckets.insert(
+            "logs".to_owned(),
+            AdmissionLimits {
+                max_requests_per_sec: Some(50.0),
+                ..Default::default()
+            },
+        );
+        expected.admission.retry_after = Duration::from_secs(2);
+        expected.tracing.exporter = TracingExporterConfig::Otlp(OtlpExporterConfig {
+            endpoint: "http://127.0.0.1:4318".to_owned(),
+            protocol: OtlpProtocol::HttpJson,
+            timeout: Duration::from_secs(5),
+            max_pending_batches: 32,
+        });
+        expected.prometheus.histogram_buckets.insert(
+            "frugalos_segment_request_duration_seconds".to_owned(),
+            vec![0.01, 0.1, 1.0],
+        );
+        expected.prometheus.histogram_buckets.insert(
+            "frugalos_mds_get_request_duration_seconds".to_owned(),
+            vec![0.001, 0.01],
+        );
         expected.mds.commit_timeout_threshold = 20;
         expected.mds.large_proposal_queue_threshold = 250;
         expected.mds.large_leader_waiting_queue_threshold = 400;
@@ -342,8 +1763,25 @@ frugalos:
         expected.mds.reelection_threshold = 48;
         expected.mds.snapshot_threshold_min = 100;
         expected.mds.snapshot_threshold_max = 200;
+        expected.mds.snapshot_threshold_bytes = Some(1024 * 1024);
         expected.mds.staled_object_threshold = 5000;
+        expected.mds.tombstone_retention = Some(Seconds(86400));
+        expected.mds.tombstone_gc_interval = Duration::from_secs(60);
+        expected.mds.fetch_snapshot_from_peers = true;
+        expected.mds.fetch_snapshot_timeout = Duration::from_secs(30);
+        expected.mds.consistent_read_lease = Duration::from_millis(300);
+        expected.mds.leadership_balance = false;
+        expected.mds.leadership_balance_interval = Duration::from_secs(30);
+        expected.mds.leadership_balance_slack = 2;
+        expected.mds.witness_snapshot_threshold = 500;
+        expected.mds.node_startup_concurrency = 32;
+        expected.mds.node_startup_concurrency_per_device = 4;
+        expected.mds.node_startup_timeout = Duration::from_secs(5);
+        expected.mds.snapshot_chunk_size = Some(1024 * 1024);
         expected.segment.dispersed_client.get_timeout = Duration::from_secs(4);
+        expected.segment.dispersed_client.hedge.enabled = true;
+        expected.segment.dispersed_client.hedge.delay = Duration::from_millis(30);
+        expected.segment.dispersed_client.fan_out = FragmentFanOut::All;
         expected
             .segment
             .dispersed_client
@@ -358,10 +1796,51 @@ frugalos:
         expected.segment.replicated_client.cannyls.rpc_max_queue_len = 32;
         expected.segment.mds_client.get_request_policy = MdsRequestPolicy::Conservative;
         expected.segment.mds_client.head_request_policy = MdsRequestPolicy::Conservative;
+        expected.segment.mds_client.stale_read_max_lag = Some(100);
+        expected.segment.mds_client.metadata_cache_capacity = 10000;
+        expected.segment.mds_client.metadata_cache_capacity_bytes = 1_048_576;
+        expected.segment.mds_client.metadata_cache_ttl = Duration::from_millis(500);
         expected.segment.mds_client.default_request_policy = MdsRequestPolicy::Speculative {
             timeout: Duration::from_secs(3),
         };
+        expected
+            .segment
+            .erasure_coding
+            .default
+            .replication_threshold = 1024;
+        expected.segment.erasure_coding.buckets.insert(
+            "archive".to_owned(),
+            ErasureCoderConfig {
+                replication_threshold: 4096,
+            },
+        );
         expected.segment.mds_client.put_content_timeout = Seconds(32);
+        expected.segment.request_priority.bulk_deadline = Duration::from_secs(30);
+        expected.segment.encryption.key_provider = KeyProviderConfig::File {
+            dir: PathBuf::from("/etc/frugalos/keys"),
+        };
+        expected.segment.encryption.buckets.insert(
+            "secret".to_owned(),
+            BucketEncryptionConfig {
+                key_id: "secret-2019".to_owned(),
+            },
+        );
+        expected.segment.compaction.aggressive = true;
+        expected.segment.compaction.max_concurrency = 8;
+        expected.segment.retry.max_attempts = 4;
+        expected.segment.retry.initial_backoff = Duration::from_millis(50);
+        expected.segment.retry.jitter = false;
+        expected.segment.retry.retry_on = vec![RetryableErrorKind::Busy, RetryableErrorKind::Other];
+        expected.segment.member_health.ejection_threshold = 0.8;
+        expected.segment.member_health.ejection_duration = Duration::from_secs(5);
+        expected.segment.circuit_breaker.failure_threshold = 3;
+        expected.segment.circuit_breaker.cooldown = Duration::from_secs(2);
+        expected.segment.budget.max_decode_bytes = 100 * 1024 * 1024;
+        expected.segment.budget.max_duration = Duration::from_secs(30);
+        expected.segment.ec_pool.worker_threads = 4;
+        expected.segment.ec_pool.on_saturation = SaturationPolicy::Reject;
+        expected.segment.content_cache.dispersed_capacity = 64 * 1024 * 1024;
+        expected.segment.content_cache.max_entry_size = 1024 * 1024;
 
         assert_eq!(expected, actual);
 
@@ -403,6 +1882,22 @@ frugalos:
         Ok(())
     }
 
+    #[test]
+    fn read_lease_must_be_shorter_than_election_timeout() -> TestResult {
+        let content = r##"---
+        frugalos:
+          mds:
+            consistent_read_lease_millis: 1000
+        "##;
+        let dir = track_any_err!(TempDir::new("frugalos_test"))?;
+        let filepath = dir.path().join("frugalos_lease.yml");
+        let mut file = track_any_err!(File::create(filepath.clone()))?;
+
+        track_any_err!(file.write(content.as_bytes()))?;
+        assert!(FrugalosConfig::from_yaml(filepath).is_err());
+        Ok(())
+    }
+
     #[test]
     fn frugalos_config_value_must_not_be_unit_type() -> TestResult {
         let content = r##"---
@@ -419,7 +1914,7 @@ frugalos:
 
     #[test]
     fn frugalos_config_from_yaml_reports_unknown_fields() -> TestResult {
-        // NOTE: serde_ignored で認識されないケースについて
+        // NOTE: `#[serde(flatten)]` されたフィールドの中の未知のフィールドも報告される
         // https://github.com/frugalos/frugalos/pull/130#issuecomment-476986133
         let content = r##"---
         frugalos:
@@ -430,14 +1925,22 @@ frugalos:
           segment:
             mds_client:
               put_content_timeout_millis: "MdsClient only has put_content_timeout_secs"
+            mds_clinet:
+              put_content_timeout_secs: 30
         "##;
         let dir = track_any_err!(TempDir::new("frugalos_test"))?;
         let filepath = dir.path().join("frugalos5.yml");
         let mut file = track_any_err!(File::create(filepath.clone()))?;
 
         track_any_err!(file.write(content.as_bytes()))?;
-        let (actual, mut unknowns) = track!(FrugalosConfig::from_yaml(filepath))?;
-        unknowns.sort();
+        let (actual, warnings) = track!(FrugalosConfig::from_yaml(filepath))?;
+        let unknowns = warnings
+            .into_iter()
+            .filter_map(|w| match w {
+                ConfigWarning::UnknownField(path) => Some(path),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
 
         assert_eq!(FrugalosConfig::default(), actual);
         assert_eq!(
@@ -445,10 +1948,49 @@ frugalos:
             vec![
                 "frugalos.daemon.executor_threadz",
                 "frugalos.segment.mds_client.put_content_timeout_millis",
+                "frugalos.segment.mds_clinet",
                 "frugalos.this_is_invalid_field"
             ]
         );
 
         Ok(())
     }
+
+    #[test]
+    fn frugalos_config_from_yaml_accepts_renamed_fields() -> TestResult {
+        let content = r##"---
+        frugalos:
+          log_level: error
+          segment:
+            mds_client:
+              put_content_timeout: 10
+        "##;
+        let dir = track_any_err!(TempDir::new("frugalos_test"))?;
+        let filepath = dir.path().join("frugalos6.yml");
+        let mut file = track_any_err!(File::create(filepath.clone()))?;
+
+        track_any_err!(file.write(content.as_bytes()))?;
+        let (actual, warnings) = track!(FrugalosConfig::from_yaml(filepath))?;
+
+        assert_eq!(actual.loglevel, sloggers::types::Severity::Error);
+        assert_eq!(
+            actual.segment.mds_client.put_content_timeout,
+            libfrugalos::time::Seconds(10)
+        );
+        assert_eq!(
+            warnings,
+            vec![
+                ConfigWarning::RenamedField {
+                    path: "frugalos.log_level".to_owned(),
+                    replacement: "frugalos.loglevel".to_owned(),
+                },
+                ConfigWarning::RenamedField {
+                    path: "frugalos.segment.mds_client.put_content_timeout".to_owned(),
+                    replacement: "frugalos.segment.mds_client.put_content_timeout_secs".to_owned(),
+                },
+            ]
+        );
+
+        Ok(())
+    }
 }
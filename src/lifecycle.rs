@@ -0,0 +1,290 @@
+//! デーモンのライフサイクル(稼働中から停止まで)を管理するためのモジュール。
+//!
+//! Kubernetes 等のオーケストレータとの連携を想定しており、以下を提供する:
+//! - readiness の判定に使われる現在のフェーズ(判定とエンドポイントは`readiness`モジュールを参照)
+//! - graceful shutdown の契機となる SIGTERM の監視
+use fibers::time::timer::{self, Timeout};
+use futures::{self, Async, Future, Poll, Stream};
+use libc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use Error;
+
+/// SIGTERM の受信有無を確認する間隔。
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+static SIGTERM_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+/// デーモンのライフサイクル上のフェーズ。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecyclePhase {
+    /// 起動直後で、他のサーバへの接続を事前に確立している。
+    WarmingUp,
+
+    /// 通常稼働中。
+    Running,
+
+    /// 停止に先立って、リーダ権を他のサーバに譲っている。
+    Draining,
+
+    /// 停止処理中。
+    Stopping,
+}
+impl LifecyclePhase {
+    /// リクエストを受け付けるべき状態かどうかを返す。
+    pub fn is_ready(self) -> bool {
+        self == LifecyclePhase::Running
+    }
+
+    fn from_usize(n: usize) -> Self {
+        match n {
+            0 => LifecyclePhase::Running,
+            1 => LifecyclePhase::Draining,
+            3 => LifecyclePhase::WarmingUp,
+            _ => LifecyclePhase::Stopping,
+        }
+    }
+
+    fn as_usize(self) -> usize {
+        match self {
+            LifecyclePhase::Running => 0,
+            LifecyclePhase::Draining => 1,
+            LifecyclePhase::Stopping => 2,
+            LifecyclePhase::WarmingUp => 3,
+        }
+    }
+}
+
+/// デーモンの現在のフェーズ。
+///
+/// 複製したインスタンスは同じ状態を共有する。
+#[derive(Debug, Clone, Default)]
+pub struct Lifecycle(Arc<AtomicUsize>);
+impl Lifecycle {
+    /// 新しい `Lifecycle` を生成する(初期フェーズは `Running`)。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 現在のフェーズを返す。
+    pub fn phase(&self) -> LifecyclePhase {
+        LifecyclePhase::from_usize(self.0.load(Ordering::SeqCst))
+    }
+
+    /// フェーズを変更する。
+    pub fn set_phase(&self, phase: LifecyclePhase) {
+        self.0.store(phase.as_usize(), Ordering::SeqCst);
+    }
+
+    /// 現在のフェーズが `from` の場合にのみ、フェーズを `to` に変更する。
+    ///
+    /// 変更した場合には `true` を返す。
+    pub fn transit(&self, from: LifecyclePhase, to: LifecyclePhase) -> bool {
+        self.0
+            .compare_exchange(
+                from.as_usize(),
+                to.as_usize(),
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok()
+    }
+}
+
+extern "C" fn handle_sigterm(_signum: libc::c_int) {
+    // NOTE: シグナルハンドラ内ではアトミック変数の更新以外は行わない
+    SIGTERM_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+/// SIGTERM を受信したら完了する `Future`。
+///
+/// 生成時に SIGTERM のハンドラを登録するため、以降は SIGTERM でプロセスが即座に終了することはなくなる。
+#[derive(Debug)]
+pub struct TerminationSignal {
+    timeout: Timeout,
+}
+impl TerminationSignal {
+    /// SIGTERM のハンドラを登録して、新しい `TerminationSignal` を生成する。
+    pub fn install() -> Self {
+        let handler = handle_sigterm as extern "C" fn(libc::c_int);
+        unsafe {
+            libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+        }
+        TerminationSignal {
+            timeout: timer::timeout(SIGNAL_CHECK_INTERVAL),
+        }
+    }
+}
+impl Future for TerminationSignal {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while track!(self.timeout.poll().map_err(Error::from))?.is_ready() {
+            if SIGTERM_RECEIVED.load(Ordering::SeqCst) {
+                return Ok(Async::Ready(()));
+            }
+            self.timeout = timer::timeout(SIGNAL_CHECK_INTERVAL);
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// graceful shutdown の進行に伴って発生するイベント。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownEvent {
+    /// SIGTERM を受信した。
+    Terminated,
+
+    /// リーダ権の移譲を待つ期間が経過した。
+    Drained,
+
+    /// graceful shutdown の猶予期間が経過した。
+    GracePeriodExpired,
+}
+
+/// graceful shutdown の契機となるシグナルとタイマー群を監視するための `Stream`。
+///
+/// 各タイマーは設定されている場合にのみ監視され、イベントを通知した後は解除される。
+/// そのため、何も設定されていない状態では、このストリームがイベントを通知することはない。
+#[derive(Debug)]
+pub struct ShutdownTimers {
+    termination_signal: Option<TerminationSignal>,
+    // リーダ権の移譲を待つためのタイマー (graceful shutdown 時のみ).
+    drain_timer: Option<Timeout>,
+    // graceful shutdown の猶予期間が過ぎたことを検知するためのタイマー.
+    grace_timer: Option<Timeout>,
+}
+impl ShutdownTimers {
+    /// 新しい `ShutdownTimers` インスタンスを生成する。
+    ///
+    /// `termination_signal` が `None` の場合には、SIGTERM は監視されない。
+    pub fn new(termination_signal: Option<TerminationSignal>) -> Self {
+        ShutdownTimers {
+            termination_signal,
+            drain_timer: None,
+            grace_timer: None,
+        }
+    }
+
+    /// ドレインを開始して、移譲の待機期間と猶予期間のタイマーを設定する。
+    pub fn start_draining(&mut self, drain_time: Duration, grace_period: Duration) {
+        self.drain_timer = Some(timer::timeout(drain_time));
+        self.grace_timer = Some(timer::timeout(grace_period));
+    }
+
+    /// `ShutdownEvent::Drained` を `delay` 後に再度通知するようにする。
+    pub fn postpone_drained(&mut self, delay: Duration) {
+        self.drain_timer = Some(timer::timeout(delay));
+    }
+
+    /// ドレインの完了待ちを止める (猶予期間のタイマーは維持される)。
+    pub fn cancel_draining(&mut self) {
+        self.drain_timer = None;
+    }
+}
+impl Stream for ShutdownTimers {
+    type Item = ShutdownEvent;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // NOTE: `Option<F>` を直接 poll すると `None` の場合に即座に完了扱いとなってしまうので、
+        // 設定されているものだけを poll する
+        if let Some(ref mut signal) = self.termination_signal {
+            if track!(signal.poll())?.is_ready() {
+                self.termination_signal = None;
+                return Ok(Async::Ready(Some(ShutdownEvent::Terminated)));
+            }
+        }
+        if let Some(ref mut timer) = self.grace_timer {
+            if track!(timer.poll().map_err(Error::from))?.is_ready() {
+                self.grace_timer = None;
+                return Ok(Async::Ready(Some(ShutdownEvent::GracePeriodExpired)));
+            }
+        }
+        if let Some(ref mut timer) = self.drain_timer {
+            if track!(timer.poll().map_err(Error::from))?.is_ready() {
+                self.drain_timer = None;
+                return Ok(Async::Ready(Some(ShutdownEvent::Drained)));
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers::executor::{Executor, InPlaceExecutor};
+
+    use super::*;
+
+    fn poll_timers(timers: ShutdownTimers) -> (Async<Option<ShutdownEvent>>, ShutdownTimers) {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let future = futures::lazy(move || {
+            let mut timers = timers;
+            timers.poll().map(|event| (event, timers))
+        });
+        executor.run_future(future).unwrap().unwrap()
+    }
+
+    #[test]
+    fn lifecycle_works() {
+        let lifecycle = Lifecycle::new();
+        assert_eq!(lifecycle.phase(), LifecyclePhase::Running);
+        assert!(lifecycle.phase().is_ready());
+
+        let cloned = lifecycle.clone();
+        cloned.set_phase(LifecyclePhase::Draining);
+        assert_eq!(lifecycle.phase(), LifecyclePhase::Draining);
+        assert!(!lifecycle.phase().is_ready());
+
+        cloned.set_phase(LifecyclePhase::Stopping);
+        assert_eq!(lifecycle.phase(), LifecyclePhase::Stopping);
+        assert!(!lifecycle.phase().is_ready());
+    }
+
+    #[test]
+    fn transit_works() {
+        let lifecycle = Lifecycle::new();
+        lifecycle.set_phase(LifecyclePhase::WarmingUp);
+        assert!(!lifecycle.phase().is_ready());
+        assert!(lifecycle.transit(LifecyclePhase::WarmingUp, LifecyclePhase::Running));
+        assert!(lifecycle.phase().is_ready());
+
+        // ウォームアップ中に停止が始まっていれば、稼働中には戻らない
+        lifecycle.set_phase(LifecyclePhase::Draining);
+        assert!(!lifecycle.transit(LifecyclePhase::WarmingUp, LifecyclePhase::Running));
+        assert_eq!(lifecycle.phase(), LifecyclePhase::Draining);
+    }
+
+    #[test]
+    fn idle_shutdown_timers_never_fire() {
+        // 何も設定されていない状態で、停止処理が始まってはいけない
+        let timers = ShutdownTimers::new(None);
+        let (event, timers) = poll_timers(timers);
+        assert_eq!(event, Async::NotReady);
+        let (event, _) = poll_timers(timers);
+        assert_eq!(event, Async::NotReady);
+    }
+
+    #[test]
+    fn shutdown_timers_works() {
+        let mut timers = ShutdownTimers::new(None);
+        timers.start_draining(Duration::from_millis(0), Duration::from_secs(3600));
+        std::thread::sleep(Duration::from_millis(50));
+        let (event, timers) = poll_timers(timers);
+        assert_eq!(event, Async::Ready(Some(ShutdownEvent::Drained)));
+
+        // 通知済みのタイマーは解除されている
+        let (event, mut timers) = poll_timers(timers);
+        assert_eq!(event, Async::NotReady);
+
+        timers.postpone_drained(Duration::from_secs(3600));
+        timers.cancel_draining();
+        let (event, _) = poll_timers(timers);
+        assert_eq!(event, Async::NotReady);
+    }
+}
@@ -2,6 +2,7 @@ extern crate clap;
 extern crate fibers_rpc;
 extern crate frugalos;
 extern crate frugalos_config;
+extern crate frugalos_core;
 extern crate frugalos_segment;
 extern crate hostname;
 extern crate jemallocator;
@@ -13,19 +14,32 @@ extern crate sloggers;
 extern crate trackable;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
+use frugalos_core::serde_ext::evolution::ConfigWarning;
 use libfrugalos::entity::server::Server;
 use libfrugalos::time::Seconds;
 use sloggers::Build;
 use std::env;
+use std::fs::OpenOptions;
 use std::string::ToString;
 use std::time::Duration;
 use trackable::error::{ErrorKindExt, Failure};
 
+use frugalos::command::bench::BenchCommand;
+use frugalos::command::bucket_archive::BucketArchiveCommand;
+use frugalos::command::bucket_relayout::BucketRelayoutCommand;
+use frugalos::command::cluster_health::ClusterHealthCommand;
+use frugalos::command::config::ConfigCommand;
+use frugalos::command::content_cache::ContentCacheCommand;
+use frugalos::command::feature_flags::FeatureFlagsCommand;
+use frugalos::command::migrate_data_dir::MigrateDataDirCommand;
+use frugalos::command::object::ObjectCommand;
+use frugalos::command::repair::RepairCommand;
 use frugalos::command::rpc_addr;
+use frugalos::command::segment_gc::SegmentGcCommand;
 use frugalos::command::set_repair_config::SetRepairConfigCommand;
 use frugalos::command::FrugalosSubcommand;
-use frugalos::FrugalosConfig;
 use frugalos::{Error, ErrorKind, Result};
+use frugalos::{FrugalosConfig, LogFormat};
 
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
@@ -42,6 +56,17 @@ fn main() {
 
     // Subcommand definitions
     let set_repair_config_command = SetRepairConfigCommand;
+    let segment_gc_command = SegmentGcCommand;
+    let migrate_data_dir_command = MigrateDataDirCommand;
+    let bench_command = BenchCommand;
+    let config_command = ConfigCommand;
+    let bucket_archive_command = BucketArchiveCommand;
+    let feature_flags_command = FeatureFlagsCommand;
+    let cluster_health_command = ClusterHealthCommand;
+    let content_cache_command = ContentCacheCommand;
+    let object_command = ObjectCommand;
+    let repair_command = RepairCommand;
+    let bucket_relayout_command = BucketRelayoutCommand;
 
     let matches = App::new("frugalos")
         .version(env!("CARGO_PKG_VERSION"))
@@ -93,6 +118,11 @@ fn main() {
                         .long("stop-waiting-time-millis")
                         .takes_value(true),
                 )
+                .arg(
+                    Arg::with_name("GRACEFUL_SHUTDOWN_ON_SIGTERM")
+                        .long("graceful-shutdown-on-sigterm")
+                        .takes_value(false),
+                )
                 .arg(
                     Arg::with_name("RPC_CONNECT_TIMEOUT_MILLIS")
                         .long("rpc-connect-timeout-millis")
@@ -106,9 +136,28 @@ fn main() {
                 .arg(data_dir_arg())
                 .arg(put_content_timeout_arg()),
         )
-        .subcommand(SubCommand::with_name("stop").arg(rpc_addr::get_arg()))
+        .subcommand(
+            SubCommand::with_name("stop").arg(rpc_addr::get_arg()).arg(
+                Arg::with_name("DRAIN")
+                    .long("drain")
+                    .help("Transfers leaderships and waits for in-flight repairs before stopping")
+                    .takes_value(false),
+            ),
+        )
         .subcommand(SubCommand::with_name("take-snapshot").arg(rpc_addr::get_arg()))
+        .subcommand(SubCommand::with_name("reload-config").arg(rpc_addr::get_arg()))
         .subcommand(set_repair_config_command.get_subcommand())
+        .subcommand(segment_gc_command.get_subcommand())
+        .subcommand(migrate_data_dir_command.get_subcommand())
+        .subcommand(bench_command.get_subcommand())
+        .subcommand(config_command.get_subcommand())
+        .subcommand(bucket_archive_command.get_subcommand())
+        .subcommand(feature_flags_command.get_subcommand())
+        .subcommand(cluster_health_command.get_subcommand())
+        .subcommand(content_cache_command.get_subcommand())
+        .subcommand(object_command.get_subcommand())
+        .subcommand(repair_command.get_subcommand())
+        .subcommand(bucket_relayout_command.get_subcommand())
         .arg(
             Arg::with_name("LOGLEVEL")
                 .short("l")
@@ -116,6 +165,12 @@ fn main() {
                 .takes_value(true)
                 .possible_values(&["debug", "info", "warning", "error", "critical"]),
         )
+        .arg(
+            Arg::with_name("LOG_FORMAT")
+                .long("log_format")
+                .takes_value(true)
+                .possible_values(&["text", "json"]),
+        )
         .arg(
             Arg::with_name("MAX_CONCURRENT_LOGS")
                 .long("max_concurrent_logs")
@@ -128,8 +183,10 @@ fn main() {
         )
         .get_matches();
 
-    let (mut config, unknown_fields): (FrugalosConfig, Vec<String>) =
+    let (mut config, config_warnings): (FrugalosConfig, Vec<ConfigWarning>) =
         track_try_unwrap!(track_any_err!(get_frugalos_config(&matches)));
+    // 再読み込み時の差分の検出には、コマンドライン引数で上書きする前の設定を使う
+    let file_config = config.clone();
 
     // Logger
     config.loglevel = matches
@@ -143,13 +200,28 @@ fn main() {
             _ => unreachable!(),
         })
         .unwrap_or(config.loglevel);
+    config.log_format = matches
+        .value_of("LOG_FORMAT")
+        .map(|v| match v {
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            _ => unreachable!(),
+        })
+        .unwrap_or(config.log_format);
     if let Some(v) = matches.value_of("MAX_CONCURRENT_LOGS") {
         config.max_concurrent_logs = track_try_unwrap!(v.parse().map_err(Error::from));
     }
+    let config_file = matches.value_of("CONFIG_FILE").map(ToOwned::to_owned);
+    // `join`や`stop`等のサブコマンドが発行する RPC にも、設定されたクラスタトークンを付ける
+    frugalos_core::rpc_auth::set_cluster_token(config.auth.cluster_token.clone());
+
+    let log_file = matches
+        .value_of("LOGFILE")
+        .or_else(|| config.log_file.as_ref().and_then(|p| p.to_str()))
+        .map(ToOwned::to_owned);
     let logger_builder;
     {
-        let maybe_log_file = config.log_file.as_ref().and_then(|p| p.to_str());
-        logger_builder = if let Some(filepath) = matches.value_of("LOGFILE").or(maybe_log_file) {
+        logger_builder = if let Some(ref filepath) = log_file {
             let mut builder = sloggers::file::FileLoggerBuilder::new(filepath);
             builder.level(config.loglevel);
             builder.channel_size(config.max_concurrent_logs);
@@ -174,7 +246,7 @@ fn main() {
         set_data_dir(&matches, &mut config);
 
         let mut logger = track_try_unwrap!(logger_builder.build());
-        warn_if_there_are_unknown_fields(&mut logger, &unknown_fields);
+        warn_config_warnings(&mut logger, &config_warnings);
         let logger = logger.new(o!("server" => format!("{}@{}", server_id, server_addr)));
         let server = Server::new(
             server_id.to_string(),
@@ -198,7 +270,7 @@ fn main() {
         set_data_dir(&matches, &mut config);
 
         let mut logger = track_try_unwrap!(logger_builder.build());
-        warn_if_there_are_unknown_fields(&mut logger, &unknown_fields);
+        warn_config_warnings(&mut logger, &config_warnings);
         let logger = logger.new(o!("server" => format!("{}@{}", server_id, server_addr)));
         let server = Server::new(
             server_id.to_string(),
@@ -221,7 +293,7 @@ fn main() {
         let contact_server =
             track_try_unwrap!(contact_server_addr.parse().map_err(Failure::from_error));
         let mut logger = track_try_unwrap!(logger_builder.build());
-        warn_if_there_are_unknown_fields(&mut logger, &unknown_fields);
+        warn_config_warnings(&mut logger, &config_warnings);
         debug!(logger, "config: {:?}", config);
         track_try_unwrap!(frugalos_config::cluster::leave(
             &logger,
@@ -238,7 +310,7 @@ fn main() {
         set_data_dir(&matches, &mut config);
 
         let mut logger = track_try_unwrap!(logger_builder.build());
-        warn_if_there_are_unknown_fields(&mut logger, &unknown_fields);
+        warn_config_warnings(&mut logger, &config_warnings);
         let logger = logger.new(o!("server" => format!("{}@{}", server_id, server_addr)));
         let mut server = Server::new(
             server_id.to_string(),
@@ -252,8 +324,16 @@ fn main() {
         ));
     } else if let Some(matches) = matches.subcommand_matches("start") {
         // START SERVER
-        let mut logger = track_try_unwrap!(logger_builder.build());
-        warn_if_there_are_unknown_fields(&mut logger, &unknown_fields);
+        let mut logger = if config.log_format == LogFormat::Json {
+            track_try_unwrap!(build_daemon_json_logger(
+                log_file.as_ref().map(String::as_str),
+                config.loglevel,
+                config.max_concurrent_logs
+            ))
+        } else {
+            track_try_unwrap!(logger_builder.build())
+        };
+        warn_config_warnings(&mut logger, &config_warnings);
         set_data_dir(&matches, &mut config);
         track_try_unwrap!(track_any_err!(set_daemon_config(
             &matches,
@@ -271,10 +351,13 @@ fn main() {
             &matches,
             &mut config.segment
         )));
-        let daemon = track_try_unwrap!(frugalos::daemon::FrugalosDaemon::new(
+        let mut daemon = track_try_unwrap!(frugalos::daemon::FrugalosDaemon::new(
             &logger,
             config.clone()
         ));
+        if let Some(path) = config_file {
+            daemon.set_config_file(path, file_config);
+        }
         track_try_unwrap!(daemon.run(config.daemon.clone()));
         // NOTE: ログ出力(非同期)用に少し待機
         std::thread::sleep(std::time::Duration::from_millis(100));
@@ -282,10 +365,14 @@ fn main() {
     } else if let Some(matches) = matches.subcommand_matches("stop") {
         // STOP SERVER
         let mut logger = track_try_unwrap!(logger_builder.build());
-        warn_if_there_are_unknown_fields(&mut logger, &unknown_fields);
+        warn_config_warnings(&mut logger, &config_warnings);
         let rpc_addr = rpc_addr::from_matches(&matches);
         let logger = logger.new(o!("rpc_addr" => rpc_addr.to_string()));
-        track_try_unwrap!(frugalos::daemon::stop(&logger, rpc_addr));
+        if matches.is_present("DRAIN") {
+            track_try_unwrap!(frugalos::daemon::drain(&logger, rpc_addr));
+        } else {
+            track_try_unwrap!(frugalos::daemon::stop(&logger, rpc_addr));
+        }
 
         // NOTE: ログ出力(非同期)用に少し待機
         std::thread::sleep(std::time::Duration::from_millis(100));
@@ -293,7 +380,7 @@ fn main() {
     } else if let Some(matches) = matches.subcommand_matches("take-snapshot") {
         // TAKE SNAPSHOT
         let mut logger = track_try_unwrap!(logger_builder.build());
-        warn_if_there_are_unknown_fields(&mut logger, &unknown_fields);
+        warn_config_warnings(&mut logger, &config_warnings);
         let rpc_addr = rpc_addr::from_matches(&matches);
         let logger = logger.new(o!("rpc_addr" => rpc_addr.to_string()));
         track_try_unwrap!(frugalos::daemon::take_snapshot(&logger, rpc_addr));
@@ -301,8 +388,47 @@ fn main() {
         // NOTE: ログ出力(非同期)用に少し待機
         std::thread::sleep(std::time::Duration::from_millis(100));
         debug!(logger, "config: {:?}", config);
+    } else if let Some(matches) = matches.subcommand_matches("reload-config") {
+        // RELOAD CONFIG
+        let mut logger = track_try_unwrap!(logger_builder.build());
+        warn_config_warnings(&mut logger, &config_warnings);
+        let rpc_addr = rpc_addr::from_matches(matches);
+        let logger = logger.new(o!("rpc_addr" => rpc_addr.to_string()));
+        let outcome = track_try_unwrap!(frugalos::daemon::reload_config(&logger, rpc_addr));
+        if !outcome.restart_required.is_empty() {
+            warn!(
+                logger,
+                "Some changed fields require a restart to take effect: {:?}",
+                outcome.restart_required
+            );
+        }
+
+        // NOTE: ログ出力(非同期)用に少し待機
+        std::thread::sleep(std::time::Duration::from_millis(100));
     } else if let Some(matches) = set_repair_config_command.check_matches(&matches) {
-        set_repair_config_command.handle_matches(logger_builder, matches, &unknown_fields);
+        set_repair_config_command.handle_matches(logger_builder, matches, &config_warnings);
+    } else if let Some(matches) = segment_gc_command.check_matches(&matches) {
+        segment_gc_command.handle_matches(logger_builder, matches, &config_warnings);
+    } else if let Some(matches) = migrate_data_dir_command.check_matches(&matches) {
+        migrate_data_dir_command.handle_matches(logger_builder, matches, &config_warnings);
+    } else if let Some(matches) = bench_command.check_matches(&matches) {
+        bench_command.handle_matches(logger_builder, matches, &config_warnings);
+    } else if let Some(matches) = config_command.check_matches(&matches) {
+        config_command.handle_matches(logger_builder, matches, &config_warnings);
+    } else if let Some(matches) = bucket_archive_command.check_matches(&matches) {
+        bucket_archive_command.handle_matches(logger_builder, matches, &config_warnings);
+    } else if let Some(matches) = feature_flags_command.check_matches(&matches) {
+        feature_flags_command.handle_matches(logger_builder, matches, &config_warnings);
+    } else if let Some(matches) = cluster_health_command.check_matches(&matches) {
+        cluster_health_command.handle_matches(logger_builder, matches, &config_warnings);
+    } else if let Some(matches) = content_cache_command.check_matches(&matches) {
+        content_cache_command.handle_matches(logger_builder, matches, &config_warnings);
+    } else if let Some(matches) = object_command.check_matches(&matches) {
+        object_command.handle_matches(logger_builder, matches, &config_warnings);
+    } else if let Some(matches) = repair_command.check_matches(&matches) {
+        repair_command.handle_matches(logger_builder, matches, &config_warnings);
+    } else if let Some(matches) = bucket_relayout_command.check_matches(&matches) {
+        bucket_relayout_command.handle_matches(logger_builder, matches, &config_warnings);
     } else {
         println!("Usage: {}", matches.usage());
         std::process::exit(1);
@@ -371,6 +497,28 @@ fn get_server_seqno(matches: &ArgMatches) -> Result<u32> {
         })
 }
 
+/// `log_file`(未指定の場合は標準エラー出力)に、JSON 形式でログを出力するロガーを生成する。
+fn build_daemon_json_logger(
+    log_file: Option<&str>,
+    level: sloggers::types::Severity,
+    channel_size: usize,
+) -> Result<slog::Logger> {
+    if let Some(path) = log_file {
+        let file = track!(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::from))?;
+        Ok(frugalos::build_json_logger(file, level, channel_size))
+    } else {
+        Ok(frugalos::build_json_logger(
+            std::io::stderr(),
+            level,
+            channel_size,
+        ))
+    }
+}
+
 fn set_data_dir(matches: &ArgMatches, config: &mut FrugalosConfig) {
     if let Some(value) = matches
         .value_of("DATA_DIR")
@@ -389,9 +537,13 @@ fn set_data_dir(matches: &ArgMatches, config: &mut FrugalosConfig) {
 }
 
 /// Gets `FrugalosConfig`.
-fn get_frugalos_config(matches: &ArgMatches) -> Result<(FrugalosConfig, Vec<String>)> {
+fn get_frugalos_config(matches: &ArgMatches) -> Result<(FrugalosConfig, Vec<ConfigWarning>)> {
     matches.value_of("CONFIG_FILE").map_or_else(
-        || Ok((FrugalosConfig::default(), Vec::new())),
+        || {
+            let config = FrugalosConfig::default();
+            track!(config.validate())?;
+            Ok((config, Vec::new()))
+        },
         |v| FrugalosConfig::from_yaml(v).map_err(|e| track!(e)),
     )
 }
@@ -413,6 +565,9 @@ fn set_daemon_config(
             .map(Duration::from_millis)
             .map_err(|e| track!(Error::from(e)))?;
     }
+    if matches.is_present("GRACEFUL_SHUTDOWN_ON_SIGTERM") {
+        config.graceful_shutdown_on_sigterm = true;
+    }
     Ok(())
 }
 
@@ -461,8 +616,8 @@ fn set_segment_config(
     Ok(())
 }
 
-fn warn_if_there_are_unknown_fields(logger: &mut slog::Logger, unknown_fields: &[String]) {
-    frugalos::command::warn_if_there_are_unknown_fields(logger, unknown_fields);
+fn warn_config_warnings(logger: &mut slog::Logger, config_warnings: &[ConfigWarning]) {
+    frugalos::command::warn_config_warnings(logger, config_warnings);
 }
 
 fn make_long_version() -> Result<String> {
@@ -0,0 +1,107 @@
+//! `GET /metrics`で公開するメトリクスを扱うモジュール。
+//!
+//! prometrics のデフォルトのレジストリの内容(プロセスの RSS やファイルディスクリプタ数等を含む)に加えて、
+//! jemalloc の統計情報をゲージとして公開する。
+//! jemalloc の統計情報はスクレイプの度に更新されるので、別途エクスポータを用意する必要はない。
+use fibers_http_server::metrics::MetricsHandler;
+use fibers_http_server::{HandleRequest, Req};
+use jemalloc_ctl;
+use prometrics::metrics::{Gauge, MetricBuilder};
+
+use Result;
+
+/// `GET /metrics`のハンドラ。
+///
+/// レスポンスの生成は`fibers_http_server`の`MetricsHandler`に委譲し、その直前に jemalloc のゲージを更新する。
+pub struct FrugalosMetricsHandler {
+    jemalloc: JemallocMetrics,
+}
+impl FrugalosMetricsHandler {
+    /// 新しい`FrugalosMetricsHandler`を生成する。
+    ///
+    /// jemalloc のゲージはデフォルトのレジストリに登録されるので、プロセス内で一度だけ呼び出すこと。
+    pub fn new() -> Result<Self> {
+        let jemalloc = track!(JemallocMetrics::new())?;
+        Ok(FrugalosMetricsHandler { jemalloc })
+    }
+}
+impl HandleRequest for FrugalosMetricsHandler {
+    const METHOD: &'static str = <MetricsHandler as HandleRequest>::METHOD;
+    const PATH: &'static str = <MetricsHandler as HandleRequest>::PATH;
+
+    type ReqBody = <MetricsHandler as HandleRequest>::ReqBody;
+    type ResBody = <MetricsHandler as HandleRequest>::ResBody;
+    type Decoder = <MetricsHandler as HandleRequest>::Decoder;
+    type Encoder = <MetricsHandler as HandleRequest>::Encoder;
+    type Reply = <MetricsHandler as HandleRequest>::Reply;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        self.jemalloc.update();
+        MetricsHandler.handle_request(req)
+    }
+}
+
+/// jemalloc の統計情報を保持するゲージ群。
+///
+/// 各値の意味は jemalloc の`stats.*`を参照。
+struct JemallocMetrics {
+    allocated_bytes: Gauge,
+    active_bytes: Gauge,
+    metadata_bytes: Gauge,
+    resident_bytes: Gauge,
+    mapped_bytes: Gauge,
+    retained_bytes: Gauge,
+}
+impl JemallocMetrics {
+    fn new() -> Result<Self> {
+        let mut builder = MetricBuilder::new();
+        builder.namespace("frugalos").subsystem("jemalloc");
+        let gauge = |name, help| track!(builder.gauge(name).help(help).default_registry().finish());
+        Ok(JemallocMetrics {
+            allocated_bytes: track!(gauge(
+                "allocated_bytes",
+                "Total number of bytes allocated by the application"
+            ))?,
+            active_bytes: track!(gauge(
+                "active_bytes",
+                "Total number of bytes in active pages allocated by the application"
+            ))?,
+            metadata_bytes: track!(gauge(
+                "metadata_bytes",
+                "Total number of bytes dedicated to jemalloc metadata"
+            ))?,
+            resident_bytes: track!(gauge(
+                "resident_bytes",
+                "Total number of bytes in physically resident data pages mapped by jemalloc"
+            ))?,
+            mapped_bytes: track!(gauge(
+                "mapped_bytes",
+                "Total number of bytes in active extents mapped by jemalloc"
+            ))?,
+            retained_bytes: track!(gauge(
+                "retained_bytes",
+                "Total number of bytes in virtual memory mappings retained by jemalloc"
+            ))?,
+        })
+    }
+
+    fn update(&self) {
+        // 多くの統計情報はキャッシュされており、epoch を進めた時にのみ更新される
+        if jemalloc_ctl::epoch().is_err() {
+            return;
+        }
+        set_gauge(&self.allocated_bytes, jemalloc_ctl::stats::allocated());
+        set_gauge(&self.active_bytes, jemalloc_ctl::stats::active());
+        set_gauge(&self.metadata_bytes, jemalloc_ctl::stats::metadata());
+        set_gauge(&self.resident_bytes, jemalloc_ctl::stats::resident());
+        set_gauge(&self.mapped_bytes, jemalloc_ctl::stats::mapped());
+        set_gauge(&self.retained_bytes, jemalloc_ctl::stats::retained());
+    }
+}
+
+/// 統計情報の取得に失敗した場合には、前回の値を残す。
+fn set_gauge<E>(gauge: &Gauge, value: ::std::result::Result<usize, E>) {
+    if let Ok(value) = value {
+        gauge.set(value as f64);
+    }
+}
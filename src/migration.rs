@@ -0,0 +1,290 @@
+//! サーバのデータディレクトリやデバイスファイルの配置を変更するためのモジュール。
+//!
+//! ノードの ID にはクラスタ構成に登録されたサーバの情報(`local.dat`)が、
+//! デバイスの実体にはクラスタ構成に登録されたファイルパスが、それぞれ対応付けられている。
+//! そのため、これらのファイルを手作業で移動すると対応関係を壊してしまう危険がある。
+//!
+//! このモジュールでは、ファイルをコピーして内容が一致することを検証した上で元のファイルを削除する。
+//! デバイスファイルについては、登録済みのパスから辿れるように、元の位置にシンボリックリンクを残す。
+//!
+//! 移行はサーバを停止した状態で行う必要がある。
+use frugalos_config;
+use slog::Logger;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use recovery;
+use {Error, ErrorKind, Result};
+
+/// コピー中のファイルに付与する拡張子。
+const TEMPORARY_EXTENSION: &str = "migrating";
+
+/// ファイルの移動。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMove {
+    /// 移動元のパス。
+    pub src: PathBuf,
+
+    /// 移動先のパス。
+    pub dest: PathBuf,
+
+    /// 移動後に、移動元のパスに移動先を指すシンボリックリンクを作成するかどうか。
+    pub leave_symlink: bool,
+}
+
+/// データディレクトリの移行計画。
+#[derive(Debug, Default)]
+pub struct MigrationPlan {
+    data_dir: Option<(PathBuf, PathBuf)>,
+    moves: Vec<FileMove>,
+}
+impl MigrationPlan {
+    /// 空の移行計画を生成する。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// データディレクトリ`src`内の frugalos が管理するファイル群を、`dest`に移動する。
+    ///
+    /// デバイスファイル等の、それ以外のファイルは移動しない。
+    pub fn relocate_data_dir<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        src: P,
+        dest: Q,
+    ) -> Result<()> {
+        let (src, dest) = (src.as_ref(), dest.as_ref());
+        track_assert!(
+            self.data_dir.is_none(),
+            ErrorKind::InvalidInput,
+            "The data directory is already relocated"
+        );
+        track_assert!(src.is_dir(), ErrorKind::InvalidInput, "src={:?}", src);
+        track_assert_ne!(src, dest, ErrorKind::InvalidInput);
+
+        let files = frugalos_config::cluster::local_data_files(src)
+            .into_iter()
+            .chain(recovery::recovery_files(src));
+        for file in files.filter(|f| f.exists()) {
+            let name = track_assert_some!(file.file_name(), ErrorKind::Other);
+            let dest = dest.join(name);
+            track!(self.add_move(FileMove {
+                src: file.clone(),
+                dest,
+                leave_symlink: false,
+            }))?;
+        }
+        self.data_dir = Some((src.to_owned(), dest.to_owned()));
+        Ok(())
+    }
+
+    /// クラスタ構成に`registered`というパスで登録されているデバイスファイルを、`dest`に移動する。
+    ///
+    /// 移動後には`registered`に`dest`を指すシンボリックリンクが作成されるので、
+    /// クラスタ構成を変更する必要はない。
+    pub fn relocate_device<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        registered: P,
+        dest: Q,
+    ) -> Result<()> {
+        track!(self.add_move(FileMove {
+            src: registered.as_ref().to_owned(),
+            dest: dest.as_ref().to_owned(),
+            leave_symlink: true,
+        }))
+    }
+
+    /// 計画に含まれるファイルの移動一覧を返す。
+    pub fn moves(&self) -> &[FileMove] {
+        &self.moves
+    }
+
+    /// 移行計画を実行する。
+    ///
+    /// 移動は一つずつ行われ、途中で失敗した場合には、それ以降の移動は行われない。
+    /// (失敗した移動の移動元ファイルは削除されずに残る)
+    pub fn execute(&self, logger: &Logger) -> Result<()> {
+        let server = if let Some((ref src, _)) = self.data_dir {
+            Some(track!(frugalos_config::cluster::load_local_server_info(
+                src
+            ))?)
+        } else {
+            None
+        };
+
+        for m in &self.moves {
+            info!(logger, "[START] Move: {:?}", m);
+            track!(move_file(m); m)?;
+            info!(logger, "[FINISH] Move: {:?}", m);
+        }
+
+        if let (Some(before), Some((_, ref dest))) = (server, self.data_dir.as_ref()) {
+            // ノードの ID の元になるサーバ情報が変わっていないことを確認する
+            let after = track!(frugalos_config::cluster::load_local_server_info(dest))?;
+            track_assert_eq!(before.id, after.id, ErrorKind::Other);
+            track_assert_eq!(before.seqno, after.seqno, ErrorKind::Other);
+            track_assert_eq!(before.addr(), after.addr(), ErrorKind::Other);
+            info!(
+                logger,
+                "The data directory is relocated (update the `data_dir` setting): {}",
+                dump!(dest, after.id)
+            );
+        }
+        Ok(())
+    }
+
+    fn add_move(&mut self, m: FileMove) -> Result<()> {
+        track_assert!(
+            fs::symlink_metadata(&m.src)
+                .map(|x| x.file_type().is_file())
+                .unwrap_or(false),
+            ErrorKind::InvalidInput,
+            "Not a regular file: {:?}",
+            m.src
+        );
+        track_assert!(
+            !m.dest.exists(),
+            ErrorKind::InvalidInput,
+            "Already exists: {:?}",
+            m.dest
+        );
+        let conflicted = self
+            .moves
+            .iter()
+            .any(|x| x.src == m.src || x.dest == m.dest);
+        track_assert!(!conflicted, ErrorKind::InvalidInput, "Conflicted: {:?}", m);
+        self.moves.push(m);
+        Ok(())
+    }
+}
+
+fn move_file(m: &FileMove) -> Result<()> {
+    if let Some(parent) = m.dest.parent() {
+        track!(fs::create_dir_all(parent).map_err(Error::from))?;
+    }
+
+    // 中断された場合に不完全なファイルが移動先に残らないように、一時ファイルにコピーしてから名前を変える
+    let temporary = m.dest.with_extension(TEMPORARY_EXTENSION);
+    track!(fs::copy(&m.src, &temporary).map_err(Error::from))?;
+    track!(File::open(&temporary)
+        .and_then(|f| f.sync_all())
+        .map_err(Error::from))?;
+    if !track!(has_same_contents(&m.src, &temporary))? {
+        let _ = fs::remove_file(&temporary);
+        track_panic!(
+            ErrorKind::Other,
+            "Verification failed: src={:?}, dest={:?}",
+            m.src,
+            temporary
+        );
+    }
+    track!(fs::rename(&temporary, &m.dest).map_err(Error::from))?;
+
+    track!(fs::remove_file(&m.src).map_err(Error::from))?;
+    if m.leave_symlink {
+        track!(symlink(&m.dest, &m.src))?;
+    }
+    Ok(())
+}
+
+fn has_same_contents(a: &Path, b: &Path) -> Result<bool> {
+    let (a, b) = (
+        track!(File::open(a).map_err(Error::from))?,
+        track!(File::open(b).map_err(Error::from))?,
+    );
+    if track!(a.metadata().map_err(Error::from))?.len()
+        != track!(b.metadata().map_err(Error::from))?.len()
+    {
+        return Ok(false);
+    }
+
+    let mut a = BufReader::new(a);
+    let mut b = BufReader::new(b);
+    let mut buf_a = vec![0; 1024 * 1024];
+    let mut buf_b = vec![0; 1024 * 1024];
+    loop {
+        let n = track!(a.read(&mut buf_a).map_err(Error::from))?;
+        if n == 0 {
+            return Ok(true);
+        }
+        track!(b.read_exact(&mut buf_b[..n]).map_err(Error::from))?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> Result<()> {
+    track!(::std::os::unix::fs::symlink(target, link).map_err(Error::from))
+}
+
+#[cfg(not(unix))]
+fn symlink(target: &Path, link: &Path) -> Result<()> {
+    track_panic!(
+        ErrorKind::Other,
+        "Symbolic links are not supported: target={:?}, link={:?}",
+        target,
+        link
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libfrugalos::entity::server::Server;
+    use slog::Discard;
+    use std::io::Write;
+    use tempdir::TempDir;
+    use trackable::result::TestResult;
+
+    fn write_file(path: &Path, contents: &[u8]) -> ::std::io::Result<()> {
+        File::create(path).and_then(|mut f| f.write_all(contents))
+    }
+
+    #[test]
+    fn migration_works() -> TestResult {
+        let logger = Logger::root(Discard, o!());
+        let dir = track_any_err!(TempDir::new("frugalos_migration"))?;
+        let src = dir.path().join("old");
+        let dest = dir.path().join("new");
+        track_any_err!(fs::create_dir_all(&src))?;
+
+        let server = Server::new("srv1".to_owned(), "127.0.0.1:14278".parse().unwrap());
+        track!(frugalos_config::cluster::save_local_server_info(
+            &src, server
+        ))?;
+        let device = src.join("file0.lusf");
+        track_any_err!(write_file(&device, b"device contents"))?;
+        track_any_err!(write_file(&src.join("unrelated.txt"), b"foo"))?;
+
+        let mut plan = MigrationPlan::new();
+        track!(plan.relocate_data_dir(&src, &dest))?;
+        let moved_device = dir.path().join("mnt1/file0.lusf");
+        track!(plan.relocate_device(&device, &moved_device))?;
+        assert_eq!(plan.moves().len(), 2);
+        assert!(plan
+            .relocate_device(&device, dir.path().join("mnt2/file0.lusf"))
+            .is_err());
+
+        track!(plan.execute(&logger))?;
+        assert!(!src.join("local.dat").exists());
+        assert_eq!(
+            track!(frugalos_config::cluster::load_local_server_info(&dest))?.id,
+            "srv1"
+        );
+
+        // デバイスファイルは元のパスからも辿れる
+        let mut buf = Vec::new();
+        track_any_err!(File::open(&device).and_then(|mut f| f.read_to_end(&mut buf)))?;
+        assert_eq!(buf, b"device contents");
+        assert!(track_any_err!(fs::symlink_metadata(&device))?
+            .file_type()
+            .is_symlink());
+        assert!(moved_device.is_file());
+
+        // 管理対象外のファイルはそのまま
+        assert!(src.join("unrelated.txt").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,497 @@
+//! 時間の掛かる操作(長時間操作)を、ID を用いて追跡するための仕組み。
+//!
+//! 長時間操作は、開始されると直ちに操作 ID を返し、以降はバックグラウンドで実行される。
+//! 操作の状態や進捗の確認、キャンセルは、操作の種類によらず共通の API (`/v1/operations`)で行う。
+//!
+//! 操作の状態はこのサーバのメモリ上にのみ保持されるので、サーバが再起動した場合には失われる。
+//! また、終了した操作の状態は`FrugalosOperationConfig::retention`の間だけ保持される。
+use fibers::sync::mpsc;
+use fibers::time::timer::{self, Timeout};
+use futures::stream::FuturesUnordered;
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Stream};
+use serde::Serialize;
+use serde_json;
+use slog::Logger;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use {Error, ErrorKind, FrugalosOperationConfig, Result};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+type OperationFuture = Box<dyn Future<Item = (), Error = ()> + Send + 'static>;
+
+/// 終了した操作の状態を破棄するかどうかを確認する間隔。
+const GC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 操作の状態。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationState {
+    /// 実行中。
+    Running,
+
+    /// 成功した。
+    Succeeded,
+
+    /// 失敗した。
+    Failed,
+
+    /// キャンセルされた。
+    Cancelled,
+}
+
+/// 操作の進捗。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationProgress {
+    /// 完了した作業の量。
+    pub done: u64,
+
+    /// 作業の総量。
+    ///
+    /// まだ分からない場合には`None`となる。
+    pub total: Option<u64>,
+}
+
+/// 操作の状態と進捗。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OperationStatus {
+    /// 操作 ID。
+    pub operation_id: String,
+
+    /// 操作の種類(e.g., `delete_by_prefix`)。
+    pub kind: String,
+
+    /// 操作の対象(e.g., バケツ ID)。
+    pub target: String,
+
+    /// 操作の状態。
+    pub state: OperationState,
+
+    /// 操作の進捗。
+    pub progress: OperationProgress,
+
+    /// 操作の開始時刻(UNIXエポックからの秒数)。
+    pub started_at: u64,
+
+    /// 操作の終了時刻(UNIXエポックからの秒数)。
+    pub finished_at: Option<u64>,
+
+    /// 成功した場合の操作の結果。
+    pub result: Option<serde_json::Value>,
+
+    /// 失敗した場合のエラーの内容。
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Operation {
+    status: OperationStatus,
+    seqno: usize,
+    finished: Option<Instant>,
+
+    // 操作を実行しているタスク(キャンセル時に起こすために使う)
+    task: Option<Task>,
+}
+impl Operation {
+    fn finish(&mut self, state: OperationState, now: Instant) {
+        self.status.state = state;
+        self.status.finished_at = Some(unix_time_secs());
+        self.finished = Some(now);
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+}
+
+/// 長時間操作の一覧。
+#[derive(Clone)]
+pub struct OperationRegistry {
+    config: FrugalosOperationConfig,
+    operations: Arc<Mutex<HashMap<String, Operation>>>,
+    seqno: Arc<AtomicUsize>,
+    operation_tx: mpsc::Sender<OperationFuture>,
+    operation_rx: Arc<Mutex<Option<mpsc::Receiver<OperationFuture>>>>,
+}
+impl OperationRegistry {
+    /// 新しい`OperationRegistry`インスタンスを生成する。
+    ///
+    /// 開始された操作は`runner`が返す`Future`の中で実行される。
+    pub fn new(config: FrugalosOperationConfig) -> Self {
+        let (operation_tx, operation_rx) = mpsc::channel();
+        OperationRegistry {
+            config,
+            operations: Arc::default(),
+            seqno: Arc::default(),
+            operation_tx,
+            operation_rx: Arc::new(Mutex::new(Some(operation_rx))),
+        }
+    }
+
+    /// 新しい操作を開始する。
+    ///
+    /// `f`には、操作の進捗を報告するための`OperationHandle`が渡される。
+    /// `f`が返した`Future`の結果は、JSON に変換されて操作の状態に含まれる。
+    pub fn start<F, T>(&self, kind: &str, target: String, f: F) -> OperationStatus
+    where
+        F: FnOnce(OperationHandle) -> BoxFuture<T>,
+        T: Serialize + Send + 'static,
+    {
+        let (status, future) = self.track(kind, target, f);
+        let _ = self.operation_tx.send(Box::new(future));
+        status
+    }
+
+    /// 操作の状態を返す。
+    pub fn status(&self, operation_id: &str) -> Result<OperationStatus> {
+        let mut operations = self.lock();
+        let operation = track!(get_operation(&mut operations, operation_id))?;
+        Ok(operation.status.clone())
+    }
+
+    /// 保持している全ての操作の状態を、開始された順に返す。
+    pub fn list(&self) -> Vec<OperationStatus> {
+        let operations = self.lock();
+        let mut list = operations.values().collect::<Vec<_>>();
+        list.sort_by_key(|o| o.seqno);
+        list.into_iter().map(|o| o.status.clone()).collect()
+    }
+
+    /// 実行中の操作をキャンセルする。
+    ///
+    /// キャンセルされた操作による変更が元に戻されることはない。
+    pub fn cancel(&self, operation_id: &str) -> Result<OperationStatus> {
+        let mut operations = self.lock();
+        let operation = track!(get_operation(&mut operations, operation_id))?;
+        track_assert_eq!(
+            operation.status.state,
+            OperationState::Running,
+            ErrorKind::InvalidInput,
+            "The operation has already finished: operation_id={:?}",
+            operation_id
+        );
+        operation.finish(OperationState::Cancelled, Instant::now());
+        Ok(operation.status.clone())
+    }
+
+    /// 開始された操作を実行し、終了した操作の状態を定期的に破棄する`Future`を返す。
+    ///
+    /// 一つの`OperationRegistry`につき一度だけ呼び出すことができる。
+    pub fn runner(&self, logger: Logger) -> OperationRunner {
+        let operation_rx = self
+            .operation_rx
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+            .expect("`OperationRegistry::runner` has already been called");
+        OperationRunner {
+            logger,
+            registry: self.clone(),
+            operation_rx,
+            operations: FuturesUnordered::new(),
+            timeout: timer::timeout(GC_INTERVAL),
+        }
+    }
+
+    fn track<F, T>(
+        &self,
+        kind: &str,
+        target: String,
+        f: F,
+    ) -> (OperationStatus, TrackedOperation<T>)
+    where
+        F: FnOnce(OperationHandle) -> BoxFuture<T>,
+        T: Serialize + Send + 'static,
+    {
+        let seqno = self.seqno.fetch_add(1, Ordering::SeqCst);
+        let status = OperationStatus {
+            operation_id: format!("{:x}-{:x}", unix_time_nanos(), seqno),
+            kind: kind.to_owned(),
+            target,
+            state: OperationState::Running,
+            progress: OperationProgress::default(),
+            started_at: unix_time_secs(),
+            finished_at: None,
+            result: None,
+            error: None,
+        };
+        let operation = Operation {
+            status: status.clone(),
+            seqno,
+            finished: None,
+            task: None,
+        };
+        self.lock().insert(status.operation_id.clone(), operation);
+
+        let handle = OperationHandle {
+            registry: self.clone(),
+            operation_id: status.operation_id.clone(),
+        };
+        let future = TrackedOperation {
+            future: f(handle.clone()),
+            handle,
+        };
+        (status, future)
+    }
+
+    /// `now`の時点で保持期間が過ぎている、終了した操作の状態を破棄する。
+    fn remove_expired(&self, now: Instant) -> usize {
+        let retention = self.config.retention;
+        let mut operations = self.lock();
+        let before = operations.len();
+        operations.retain(|_, o| {
+            o.finished
+                .is_none_or(|t| now.saturating_duration_since(t) < retention)
+        });
+        before - operations.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Operation>> {
+        self.operations.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl fmt::Debug for OperationRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OperationRegistry")
+            .field("config", &self.config)
+            .field("operations", &self.lock().len())
+            .finish()
+    }
+}
+
+fn get_operation<'a>(
+    operations: &'a mut HashMap<String, Operation>,
+    id: &str,
+) -> Result<&'a mut Operation> {
+    let operation = track_assert_some!(
+        operations.get_mut(id),
+        ErrorKind::NotFound,
+        "No such operation: {:?}",
+        id
+    );
+    Ok(operation)
+}
+
+/// 実行中の操作から、進捗を報告するためのハンドル。
+#[derive(Clone)]
+pub struct OperationHandle {
+    registry: OperationRegistry,
+    operation_id: String,
+}
+impl OperationHandle {
+    /// 操作 ID を返す。
+    pub fn operation_id(&self) -> &str {
+        &self.operation_id
+    }
+
+    /// 作業の総量を設定する。
+    pub fn set_total(&self, total: u64) {
+        self.update(|o| o.status.progress.total = Some(total));
+    }
+
+    /// 完了した作業の量を加算する。
+    pub fn add_done(&self, n: u64) {
+        self.update(|o| o.status.progress.done += n);
+    }
+
+    /// 操作がキャンセルされているかどうかを返す。
+    ///
+    /// キャンセルされた操作の`Future`は、次にポーリングされる時点で破棄されるが、
+    /// 一つの操作の中で複数の段階を順に実行する場合には、各段階の開始前に確認すると良い。
+    pub fn is_cancelled(&self) -> bool {
+        let operations = self.registry.lock();
+        operations
+            .get(&self.operation_id)
+            .is_none_or(|o| o.status.state == OperationState::Cancelled)
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Operation),
+    {
+        if let Some(operation) = self.registry.lock().get_mut(&self.operation_id) {
+            f(operation);
+        }
+    }
+}
+
+/// 操作の`Future`を実行して、その結果を`OperationRegistry`に記録する`Future`。
+struct TrackedOperation<T> {
+    handle: OperationHandle,
+    future: BoxFuture<T>,
+}
+impl<T: Serialize> Future for TrackedOperation<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        {
+            let mut operations = self.handle.registry.lock();
+            match operations.get_mut(&self.handle.operation_id) {
+                Some(o) if o.status.state == OperationState::Running => {
+                    o.task = Some(task::current());
+                }
+                _ => {
+                    // キャンセルされたので、実行中の処理を破棄する
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+        let result = match self.future.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(item)) => Ok(item),
+            Err(e) => Err(e),
+        };
+        self.handle.update(|o| {
+            o.task = None;
+            if o.status.state != OperationState::Running {
+                return;
+            }
+            match result {
+                Ok(item) => {
+                    o.status.result = serde_json::to_value(&item).ok();
+                    o.finish(OperationState::Succeeded, Instant::now());
+                }
+                Err(e) => {
+                    o.status.error = Some(e.to_string());
+                    o.finish(OperationState::Failed, Instant::now());
+                }
+            }
+        });
+        Ok(Async::Ready(()))
+    }
+}
+
+/// 開始された操作を実行し、終了した操作の状態を定期的に破棄する`Future`。
+///
+/// この`Future`が終了することはない。
+pub struct OperationRunner {
+    logger: Logger,
+    registry: OperationRegistry,
+    operation_rx: mpsc::Receiver<OperationFuture>,
+    operations: FuturesUnordered<OperationFuture>,
+    timeout: Timeout,
+}
+impl Future for OperationRunner {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(Some(future)) = self.operation_rx.poll().expect("Never fails") {
+            self.operations.push(future);
+        }
+        while let Ok(Async::Ready(Some(()))) = self.operations.poll() {}
+        while track!(self.timeout.poll().map_err(Error::from))?.is_ready() {
+            self.timeout = timer::timeout(GC_INTERVAL);
+            let removed = self.registry.remove_expired(Instant::now());
+            if removed > 0 {
+                debug!(self.logger, "Removed {} finished operations", removed);
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+fn unix_time_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures;
+    use trackable::error::ErrorKindExt;
+
+    fn registry() -> OperationRegistry {
+        OperationRegistry::new(FrugalosOperationConfig::default())
+    }
+
+    #[test]
+    fn operation_registry_works() -> Result<()> {
+        let registry = registry();
+        let (status, future) = registry.track("test", "foo".to_owned(), |handle| {
+            handle.set_total(2);
+            handle.add_done(1);
+            Box::new(futures::finished(10u64))
+        });
+        assert_eq!(status.state, OperationState::Running);
+        let id = status.operation_id;
+        assert_eq!(
+            registry.status(&id)?.progress,
+            OperationProgress {
+                done: 1,
+                total: Some(2)
+            }
+        );
+
+        let _ = future.wait();
+        let status = registry.status(&id)?;
+        assert_eq!(status.state, OperationState::Succeeded);
+        assert_eq!(status.result, Some(serde_json::Value::from(10)));
+        assert!(status.finished_at.is_some());
+
+        // 終了した操作はキャンセルできない
+        assert!(registry.cancel(&id).is_err());
+
+        let (failed, future) = registry.track::<_, ()>("test", "bar".to_owned(), |_| {
+            Box::new(futures::failed(ErrorKind::Other.error().into()))
+        });
+        let _ = future.wait();
+        let failed = registry.status(&failed.operation_id)?;
+        assert_eq!(failed.state, OperationState::Failed);
+        assert!(failed.error.is_some());
+        assert_eq!(
+            registry
+                .list()
+                .into_iter()
+                .map(|s| s.target)
+                .collect::<Vec<_>>(),
+            vec!["foo".to_owned(), "bar".to_owned()]
+        );
+
+        // 保持期間が過ぎた操作は破棄される
+        let later = Instant::now() + registry.config.retention + Duration::from_secs(1);
+        assert_eq!(registry.remove_expired(later), 2);
+        assert_eq!(
+            registry.status(&id).err().map(|e| e.kind().clone()),
+            Some(ErrorKind::NotFound)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn operations_can_be_cancelled() -> Result<()> {
+        let registry = registry();
+        let (status, future) = registry.track::<_, ()>("test", "foo".to_owned(), |handle| {
+            assert!(!handle.is_cancelled());
+            Box::new(futures::empty())
+        });
+        let id = status.operation_id;
+        let handle = OperationHandle {
+            registry: registry.clone(),
+            operation_id: id.clone(),
+        };
+
+        let status = registry.cancel(&id)?;
+        assert_eq!(status.state, OperationState::Cancelled);
+        assert!(handle.is_cancelled());
+
+        // キャンセルされた操作の`Future`は、完了を待たずに終了する
+        let _ = future.wait();
+        assert_eq!(registry.status(&id)?.state, OperationState::Cancelled);
+        Ok(())
+    }
+}
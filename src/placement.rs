@@ -0,0 +1,154 @@
+//! オブジェクトの配置を調べるためのモジュール。
+//!
+//! オブジェクトが割り当てられるセグメントと、そのデータ(断片ないし複製)を保持するメンバは、
+//! オブジェクトの ID とバージョンから決定的に計算される。
+//! このモジュールはその計算結果を返すので、「オブジェクトがどこにあるのか」を調べる際に使用できる。
+//!
+//! NOTE: 返されるのは配置の計算結果のみで、実際に各メンバにデータが保存されているかどうかは確認しない。
+//! 保存状況を確認したい場合には、オブジェクトの検査(verify)を使用すること。
+use frugalos_segment::config::ClusterMember;
+use futures::{self, Future};
+use libfrugalos::consistency::ReadConsistency;
+use libfrugalos::entity::bucket::BucketId;
+use libfrugalos::entity::object::ObjectId;
+
+use client::FrugalosClient;
+use Error;
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+
+/// オブジェクトの配置。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectPlacement {
+    /// バケツの ID。
+    pub bucket_id: BucketId,
+
+    /// オブジェクトの ID。
+    pub object_id: ObjectId,
+
+    /// オブジェクトが割り当てられるセグメントの番号。
+    pub segment: u16,
+
+    /// オブジェクトの現在のバージョン。
+    ///
+    /// オブジェクトが存在しない場合には`None`となる。
+    pub version: Option<u64>,
+
+    /// セグメントの各メンバ。
+    pub members: Vec<PlacementMember>,
+}
+
+/// セグメントのメンバと、オブジェクトのデータとの対応。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlacementMember {
+    /// ノード ID (アドレスを含む)。
+    pub node: String,
+
+    /// ノードが使用しているデバイスの ID。
+    pub device: String,
+
+    /// ノードを保持するサーバの RPC アドレス。
+    pub server_addr: String,
+
+    /// このメンバが保持するデータの番号。
+    ///
+    /// `dispersed`のバケツでは断片の番号、`replicated`のバケツでは取得時の優先順位となる。
+    /// データを保持しない場合(オブジェクトが存在しない場合やメタデータ用のバケツの場合を含む)には`None`となる。
+    pub fragment: Option<usize>,
+}
+
+/// `bucket_id`のバケツの`object_id`のオブジェクトの配置を調べる。
+///
+/// オブジェクトのバージョンの取得時には`consistency`が使われる(`None`の場合はバケツのデフォルト値)。
+pub fn locate_object(
+    client: &FrugalosClient,
+    bucket_id: &str,
+    object_id: ObjectId,
+    consistency: Option<ReadConsistency>,
+) -> BoxFuture<ObjectPlacement> {
+    let bucket = match track!(client.bucket(bucket_id)) {
+        Ok(bucket) => bucket,
+        Err(e) => return Box::new(futures::failed(e)),
+    };
+    let segment = bucket.segment_of(&object_id);
+    let future = bucket
+        .request()
+        .head(object_id.clone(), consistency)
+        .and_then(move |version| {
+            let members = track!(bucket.segment_members(segment))?;
+            let data_members = if let Some(version) = version {
+                track!(bucket.data_members(segment, version))?
+            } else {
+                Vec::new()
+            };
+            Ok(ObjectPlacement {
+                bucket_id: bucket.id().clone(),
+                object_id,
+                segment,
+                version: version.map(|v| v.0),
+                members: make_placement_members(&members, &data_members),
+            })
+        });
+    Box::new(future)
+}
+
+fn make_placement_members(
+    members: &[ClusterMember],
+    data_members: &[ClusterMember],
+) -> Vec<PlacementMember> {
+    members
+        .iter()
+        .map(|m| PlacementMember {
+            node: m.node.to_string(),
+            device: m.device.clone(),
+            server_addr: m.node.current_addr().to_string(),
+            fragment: data_members.iter().position(|d| d == m),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use frugalos_raft::{self, NodeId};
+
+    use super::*;
+
+    fn member(local_id: u8, device: &str) -> ClusterMember {
+        let node: NodeId = format!("00000000000{:03x}.0@127.0.0.1:14278", local_id)
+            .parse()
+            .unwrap();
+        ClusterMember {
+            node,
+            device: device.to_owned(),
+        }
+    }
+
+    #[test]
+    fn make_placement_members_works() {
+        let members = vec![member(0, "d0"), member(1, "d1"), member(2, "d2")];
+        let data_members = vec![members[2].clone(), members[0].clone()];
+        let placement = make_placement_members(&members, &data_members);
+        assert_eq!(placement.len(), 3);
+        assert_eq!(placement[0].device, "d0");
+        assert_eq!(placement[0].server_addr, "127.0.0.1:14278");
+        assert_eq!(placement[0].fragment, Some(1));
+        assert_eq!(placement[1].fragment, None);
+        assert_eq!(placement[2].fragment, Some(0));
+
+        // オブジェクトが存在しない場合には、どのメンバもデータを保持しない
+        let placement = make_placement_members(&members, &[]);
+        assert!(placement.iter().all(|m| m.fragment.is_none()));
+
+        // アドレスが変わったサーバについては、現在のアドレスを返す
+        // (変換表はプロセス全体で共有されるので、他のテストが使わない文書用のアドレスを使う)
+        let moved = ClusterMember {
+            node: "000000000000ff.0@192.0.2.20:14278".parse().unwrap(),
+            device: "d3".to_owned(),
+        };
+        let registered = moved.node.addr;
+        frugalos_raft::set_current_addr(registered, "192.0.2.21:14278".parse().unwrap());
+        let placement = make_placement_members(&[moved], &[]);
+        frugalos_raft::set_current_addr(registered, registered);
+        assert_eq!(placement[0].server_addr, "192.0.2.21:14278");
+    }
+}
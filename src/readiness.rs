@@ -0,0 +1,277 @@
+//! ロードバランサやオーケストレータ向けに、`/healthz`と`/v1/frugalos/readiness`(およびその別名の`/readyz`)を提供するためのモジュール。
+//!
+//! `/healthz`はプロセスが HTTP リクエストを処理できる限り成功する(liveness)。
+//! `/v1/frugalos/readiness`は以下の全てを満たす場合にのみ成功する(readiness):
+//! - ライフサイクルのフェーズが`Running`であること(ウォームアップ中や停止処理中ではない)
+//! - ローカルの全てのデバイスの初期化が完了していること
+//! - ローカルの MDS ノードのうち、所属するクラスタのリーダが確定しているものの割合が閾値以上であること
+//!
+//! 再起動したサーバが`/readyz`に成功するまで次のサーバの再起動を待つことで、
+//! ローリングリスタート中に過半数のノードが同時に利用できなくなることを避けられる。
+use bytecodec::json_codec::JsonEncoder;
+use bytecodec::null::NullDecoder;
+use fibers_http_server::{HandleRequest, Reply, Req, Res, Status};
+use frugalos_segment::ServiceHandle as SegmentServiceHandle;
+use futures;
+use httpcodec::{BodyDecoder, BodyEncoder};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use http::ContentTypeJson;
+use lifecycle::{Lifecycle, LifecyclePhase};
+use {ErrorKind, FrugalosReadinessConfig, Result};
+
+/// ローカルのデバイスと MDS ノードの起動状況。
+///
+/// `Service`が更新し、`ReadinessProbe`が参照する。
+/// 複製したインスタンスは同じ状態を共有する。
+#[derive(Debug, Clone, Default)]
+pub struct LocalResources(Arc<LocalResourcesInner>);
+impl LocalResources {
+    /// 新しい`LocalResources`を生成する。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ローカルのデバイスの数と、そのうち初期化が完了しているものの数を設定する。
+    pub fn set_devices(&self, devices: usize, initialized: usize) {
+        self.0.devices.store(devices, Ordering::SeqCst);
+        self.0
+            .initialized_devices
+            .store(initialized, Ordering::SeqCst);
+    }
+
+    /// 起動を要求済みのローカルの MDS ノードの数を設定する。
+    pub fn set_mds_nodes(&self, nodes: usize) {
+        self.0.mds_nodes.store(nodes, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Default)]
+struct LocalResourcesInner {
+    devices: AtomicUsize,
+    initialized_devices: AtomicUsize,
+    mds_nodes: AtomicUsize,
+}
+
+/// readiness の判定結果。
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReadinessReport {
+    /// リクエストを受け付けるべき状態かどうか。
+    pub ready: bool,
+
+    /// ライフサイクルの現在のフェーズ。
+    pub phase: LifecyclePhase,
+
+    /// ローカルのデバイスの数。
+    pub devices: usize,
+
+    /// 初期化が完了しているローカルのデバイスの数。
+    pub initialized_devices: usize,
+
+    /// ローカルの MDS ノードの数。
+    pub mds_nodes: usize,
+
+    /// 所属するクラスタのリーダが確定しているローカルの MDS ノードの数。
+    pub mds_nodes_with_leader: usize,
+}
+
+/// サーバの readiness を判定する。
+#[derive(Clone)]
+pub struct ReadinessProbe {
+    lifecycle: Lifecycle,
+    resources: LocalResources,
+    segment_service: SegmentServiceHandle,
+    mds_leader_ratio: f64,
+}
+impl ReadinessProbe {
+    /// 新しい`ReadinessProbe`を生成する。
+    pub fn new(
+        lifecycle: Lifecycle,
+        resources: LocalResources,
+        segment_service: SegmentServiceHandle,
+        config: &FrugalosReadinessConfig,
+    ) -> Result<Self> {
+        track_assert!(
+            0.0 <= config.mds_leader_ratio && config.mds_leader_ratio <= 1.0,
+            ErrorKind::InvalidInput,
+            "mds_leader_ratio must be in the range [0.0, 1.0]: {}",
+            config.mds_leader_ratio
+        );
+        Ok(ReadinessProbe {
+            lifecycle,
+            resources,
+            segment_service,
+            mds_leader_ratio: config.mds_leader_ratio,
+        })
+    }
+
+    /// 現在の readiness を判定する。
+    pub fn check(&self) -> ReadinessReport {
+        let resources = &self.resources.0;
+        let leader_status = self.segment_service.mds_leader_status();
+
+        // 起動を要求したノードが、まだ MDS のサービスに登録されていない場合もある
+        let mds_nodes = ::std::cmp::max(
+            resources.mds_nodes.load(Ordering::SeqCst),
+            leader_status.nodes,
+        );
+        let mut report = ReadinessReport {
+            ready: false,
+            phase: self.lifecycle.phase(),
+            devices: resources.devices.load(Ordering::SeqCst),
+            initialized_devices: resources.initialized_devices.load(Ordering::SeqCst),
+            mds_nodes,
+            mds_nodes_with_leader: leader_status.nodes_with_leader,
+        };
+        report.ready = is_ready(&report, self.mds_leader_ratio);
+        report
+    }
+}
+
+fn is_ready(report: &ReadinessReport, mds_leader_ratio: f64) -> bool {
+    if !report.phase.is_ready() || report.initialized_devices < report.devices {
+        return false;
+    }
+    if report.mds_nodes == 0 {
+        return true;
+    }
+    report.mds_nodes_with_leader as f64 >= report.mds_nodes as f64 * mds_leader_ratio
+}
+
+/// `/healthz`のレスポンス。
+#[derive(Debug, Clone, Serialize)]
+pub struct Liveness {
+    /// 常に`true`。
+    pub alive: bool,
+
+    /// ライフサイクルの現在のフェーズ。
+    pub phase: LifecyclePhase,
+}
+
+/// liveness を返す HTTP ハンドラ。
+///
+/// 停止処理中も含めて、リクエストを処理できる限りは`200 OK`を返す。
+pub struct HealthzHandler(pub Lifecycle);
+impl HandleRequest for HealthzHandler {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/healthz";
+
+    type ReqBody = ();
+    type ResBody = Liveness;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        let mut res = Res::new(
+            Status::Ok,
+            Liveness {
+                alive: true,
+                phase: self.0.phase(),
+            },
+        );
+        res.header_mut().add_field(ContentTypeJson);
+        Box::new(futures::finished(res))
+    }
+}
+
+/// readiness を返す HTTP ハンドラ。
+///
+/// ready でない場合には`503 Service Unavailable`を返すので、
+/// そのまま Kubernetes の readinessProbe に指定することができる。
+pub struct ReadinessHandler(pub ReadinessProbe);
+impl HandleRequest for ReadinessHandler {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/frugalos/readiness";
+
+    type ReqBody = ();
+    type ResBody = ReadinessReport;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        reply_readiness(&self.0)
+    }
+}
+
+/// `ReadinessHandler`の別名で、`/readyz`で同じ readiness を返す HTTP ハンドラ。
+pub struct ReadyzHandler(pub ReadinessProbe);
+impl HandleRequest for ReadyzHandler {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/readyz";
+
+    type ReqBody = ();
+    type ResBody = ReadinessReport;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        reply_readiness(&self.0)
+    }
+}
+
+fn reply_readiness(probe: &ReadinessProbe) -> Reply<ReadinessReport> {
+    let report = probe.check();
+    let status = if report.ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    let mut res = Res::new(status, report);
+    res.header_mut().add_field(ContentTypeJson);
+    Box::new(futures::finished(res))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(
+        phase: LifecyclePhase,
+        devices: (usize, usize),
+        mds_nodes: (usize, usize),
+    ) -> ReadinessReport {
+        ReadinessReport {
+            ready: false,
+            phase,
+            devices: devices.0,
+            initialized_devices: devices.1,
+            mds_nodes: mds_nodes.0,
+            mds_nodes_with_leader: mds_nodes.1,
+        }
+    }
+
+    #[test]
+    fn is_ready_works() {
+        let running = LifecyclePhase::Running;
+        assert!(is_ready(&report(running, (0, 0), (0, 0)), 1.0));
+        assert!(is_ready(&report(running, (2, 2), (4, 4)), 1.0));
+
+        // ウォームアップ中や停止処理中は ready にならない
+        assert!(!is_ready(
+            &report(LifecyclePhase::WarmingUp, (2, 2), (4, 4)),
+            1.0
+        ));
+        assert!(!is_ready(
+            &report(LifecyclePhase::Draining, (2, 2), (4, 4)),
+            1.0
+        ));
+
+        // 初期化中のデバイスがある
+        assert!(!is_ready(&report(running, (2, 1), (4, 4)), 1.0));
+
+        // リーダが確定していないノードの割合に応じて判定される
+        assert!(!is_ready(&report(running, (2, 2), (4, 3)), 1.0));
+        assert!(is_ready(&report(running, (2, 2), (4, 3)), 0.75));
+        assert!(!is_ready(&report(running, (2, 2), (4, 2)), 0.75));
+        assert!(is_ready(&report(running, (2, 2), (4, 0)), 0.0));
+
+        // デフォルトでは、一部のノードのリーダが不在なだけでは not-ready にならない
+        let default = FrugalosReadinessConfig::default().mds_leader_ratio;
+        assert!(is_ready(&report(running, (2, 2), (4, 3)), default));
+        assert!(!is_ready(&report(running, (2, 2), (4, 1)), default));
+    }
+}
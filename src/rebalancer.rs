@@ -0,0 +1,128 @@
+//! セグメントの配置の偏りを、バックグラウンドで少しずつ均すためのモジュール。
+//!
+//! 一定間隔毎に、ローカルの構成管理サービスに対してリバランスの開始を要求する。
+//! 構成管理サービスは、自身がリーダである場合にのみ、最も偏りの大きいバケツの計画を立てて、
+//! その移動を再配置として提案する(`frugalos_config::BucketRebalance`を参照)。
+//! 断片のコピーは、他の再配置と同様に各セグメントの MDS が進める。
+//!
+//! 新たな移動はメンテナンスウィンドウ内でのみ開始され、再配置中のバケツが多い間は開始されない。
+use fibers::time::timer::{self, Timeout};
+use frugalos_config::{BucketRebalance, RebalanceOptions, ServiceHandle as ConfigServiceHandle};
+use frugalos_core::logging;
+use frugalos_segment::MaintenanceSchedule;
+use futures::{Async, Future};
+use prometrics::metrics::{Counter, MetricBuilder};
+use slog::Logger;
+
+use {Error, FrugalosRebalancerConfig, Result};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+
+/// バックグラウンドでのリバランスを定期的に開始する。
+pub struct Rebalancer {
+    logger: Logger,
+    config: FrugalosRebalancerConfig,
+    maintenance: MaintenanceSchedule,
+    timeout: Timeout,
+    rebalance: Option<BoxFuture<Option<BucketRebalance>>>,
+    metrics: Metrics,
+}
+impl Rebalancer {
+    /// 新しい`Rebalancer`を生成する。
+    pub fn new(
+        logger: Logger,
+        config: FrugalosRebalancerConfig,
+        maintenance: MaintenanceSchedule,
+    ) -> Result<Self> {
+        let metrics = track!(Metrics::new())?;
+        Ok(Rebalancer {
+            logger,
+            timeout: timer::timeout(config.interval),
+            config,
+            maintenance,
+            rebalance: None,
+            metrics,
+        })
+    }
+
+    /// 実行中の要求を進め、次の確認を行うべき時刻になっていれば、リバランスの開始を要求する。
+    ///
+    /// 前回の要求が終わっていない場合や、メンテナンスウィンドウ外の場合には、今回の確認はスキップされる。
+    pub fn poll(&mut self, config_service: &ConfigServiceHandle) -> Result<()> {
+        if let Some(mut rebalance) = self.rebalance.take() {
+            match rebalance.poll() {
+                Ok(Async::NotReady) => self.rebalance = Some(rebalance),
+                Ok(Async::Ready(None)) => {}
+                Ok(Async::Ready(Some(rebalance))) => {
+                    info!(
+                        self.logger,
+                        "Started rebalancing a bucket: moves={}",
+                        rebalance.moves.len();
+                        logging::BUCKET => &rebalance.bucket_id
+                    );
+                    self.metrics.started.increment();
+                    self.metrics.moves.add_u64(rebalance.moves.len() as u64);
+                }
+                Err(e) => {
+                    self.metrics.failures.increment();
+                    warn!(self.logger, "Cannot start rebalancing: {}", e);
+                }
+            }
+        }
+
+        let mut tick = false;
+        while track!(self.timeout.poll().map_err(Error::from))?.is_ready() {
+            self.timeout = timer::timeout(self.config.interval);
+            tick = true;
+        }
+        if !tick || self.rebalance.is_some() {
+            return Ok(());
+        }
+        if !self.maintenance.is_open() {
+            debug!(
+                self.logger,
+                "Skips rebalancing outside of the maintenance windows"
+            );
+            return Ok(());
+        }
+
+        let options = RebalanceOptions {
+            max_move_ratio: self.config.max_move_ratio,
+            include_unused_devices: self.config.include_unused_devices,
+        };
+        let future = config_service
+            .start_rebalance(options, self.config.max_relayouts)
+            .map_err(|e| track!(Error::from(e)));
+        self.rebalance = Some(Box::new(future));
+        Ok(())
+    }
+}
+
+struct Metrics {
+    started: Counter,
+    moves: Counter,
+    failures: Counter,
+}
+impl Metrics {
+    fn new() -> Result<Self> {
+        let mut builder = MetricBuilder::new();
+        builder.namespace("frugalos").subsystem("rebalancer");
+        Ok(Metrics {
+            started: track!(builder
+                .counter("started_total")
+                .help("Number of buckets whose rebalancing has been started")
+                .default_registry()
+                .finish())?,
+            moves: track!(builder
+                .counter("moves_total")
+                .help("Number of segment members moved by rebalancing")
+                .default_registry()
+                .finish())?,
+            failures: track!(builder
+                .counter("failures_total")
+                .help("Number of failures of starting rebalancing")
+                .default_registry()
+                .finish())?,
+        })
+    }
+}
@@ -8,7 +8,7 @@
 use slog::Logger;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use {Error, Result};
 
@@ -19,6 +19,14 @@ const RECOVERY_FILE_NAME: &str = "recovery.yml";
 // 完了済みの設定ファイル名
 const COMPLETED_RECOVERY_FILE_NAME: &str = "recovery.done";
 
+/// データディレクトリ内の、リカバリー要求用のファイル群のパスを返す。
+pub(crate) fn recovery_files<P: AsRef<Path>>(data_dir: P) -> Vec<PathBuf> {
+    vec![
+        data_dir.as_ref().join(RECOVERY_FILE_NAME),
+        data_dir.as_ref().join(COMPLETED_RECOVERY_FILE_NAME),
+    ]
+}
+
 /// 起動時に必要なリカバリー要求を表す。
 pub struct RecoveryRequest;
 
@@ -0,0 +1,439 @@
+//! デーモンを再起動せずに、設定の一部を反映するためのモジュール。
+//!
+//! 再読み込みは SIGHUP の受信時、あるいは RPC によって要求される。
+//! `FrugalosConfig`のうち実行中に反映できるのは`ReloadableConfig`に含まれる項目のみで、
+//! それ以外の項目の変更は`ReloadOutcome::restart_required`として報告されるだけで無視される。
+//!
+//! 差分は前回読み込んだ設定ファイルの内容との間で検出されるので、
+//! コマンドライン引数で上書きされた項目は、設定ファイル側で変更されない限りそのまま維持される。
+use fibers::time::timer::{self, Timeout};
+use frugalos_core::cluster_feature::ClusterFeature;
+use frugalos_core::prometheus::PrometheusConfig;
+use futures::{Async, Future, Poll, Stream};
+use libc;
+use slog::{Drain, Level, OwnedKVList, Record};
+use sloggers::types::Severity;
+use std::result;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use {Error, FrugalosConfig, FrugalosMemoryConfig, FrugalosRepairConfig};
+
+/// SIGHUP の受信有無を確認する間隔。
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+static SIGHUP_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+/// 実行中に反映可能な設定項目。
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReloadableConfig {
+    /// `FrugalosConfig::loglevel`
+    pub loglevel: Severity,
+
+    /// `FrugalosConfig::repair`
+    pub repair: FrugalosRepairConfig,
+
+    /// `FrugalosConfig::memory`
+    pub memory: FrugalosMemoryConfig,
+
+    /// `FrugalosConfig::cluster_features`
+    pub cluster_features: Vec<ClusterFeature>,
+
+    /// `FrugalosConfig::prometheus`
+    ///
+    /// 再読み込み以降に生成されるヒストグラムに反映される(既存のヒストグラムのバケツは変わらない)。
+    pub prometheus: PrometheusConfig,
+
+    /// `FrugalosDaemonConfig::stop_waiting_time`
+    pub stop_waiting_time: Duration,
+
+    /// `FrugalosDaemonConfig::leadership_drain_time`
+    pub leadership_drain_time: Duration,
+
+    /// `FrugalosDaemonConfig::shutdown_grace_period`
+    pub shutdown_grace_period: Duration,
+}
+impl ReloadableConfig {
+    /// `config`から、実行中に反映可能な項目を取り出す。
+    pub fn from_config(config: &FrugalosConfig) -> Self {
+        ReloadableConfig {
+            loglevel: config.loglevel,
+            repair: config.repair.clone(),
+            memory: config.memory.clone(),
+            cluster_features: config.cluster_features.clone(),
+            prometheus: config.prometheus.clone(),
+            stop_waiting_time: config.daemon.stop_waiting_time,
+            leadership_drain_time: config.daemon.leadership_drain_time,
+            shutdown_grace_period: config.daemon.shutdown_grace_period,
+        }
+    }
+
+    /// `config`の該当する項目を上書きする。
+    pub fn apply_to(&self, config: &mut FrugalosConfig) {
+        config.loglevel = self.loglevel;
+        config.repair = self.repair.clone();
+        config.memory = self.memory.clone();
+        config.cluster_features = self.cluster_features.clone();
+        config.prometheus = self.prometheus.clone();
+        config.daemon.stop_waiting_time = self.stop_waiting_time;
+        config.daemon.leadership_drain_time = self.leadership_drain_time;
+        config.daemon.shutdown_grace_period = self.shutdown_grace_period;
+    }
+
+    /// `other`と値が異なる項目の名前を返す。
+    pub fn changed_fields(&self, other: &Self) -> Vec<String> {
+        let mut fields = Vec::new();
+        if self.loglevel != other.loglevel {
+            fields.push("loglevel");
+        }
+        if self.repair != other.repair {
+            fields.push("repair");
+        }
+        if self.memory != other.memory {
+            fields.push("memory");
+        }
+        if self.cluster_features != other.cluster_features {
+            fields.push("cluster_features");
+        }
+        if self.prometheus != other.prometheus {
+            fields.push("prometheus");
+        }
+        if self.stop_waiting_time != other.stop_waiting_time {
+            fields.push("daemon.stop_waiting_time_millis");
+        }
+        if self.leadership_drain_time != other.leadership_drain_time {
+            fields.push("daemon.leadership_drain_time_millis");
+        }
+        if self.shutdown_grace_period != other.shutdown_grace_period {
+            fields.push("daemon.shutdown_grace_period_millis");
+        }
+        fields.into_iter().map(ToOwned::to_owned).collect()
+    }
+
+    /// `previous`から`loaded`への変更を、`self`に適用したものを返す。
+    ///
+    /// `previous`と`loaded`で値が同じ項目は`self`の値のままとなるので、
+    /// コマンドライン引数で上書きされている値は、設定ファイル側で変更されない限り維持される。
+    pub fn merge_changes(&self, previous: &Self, loaded: &Self) -> Self {
+        let mut merged = self.clone();
+        if previous.loglevel != loaded.loglevel {
+            merged.loglevel = loaded.loglevel;
+        }
+        if previous.repair != loaded.repair {
+            merged.repair = loaded.repair.clone();
+        }
+        if previous.memory != loaded.memory {
+            merged.memory = loaded.memory.clone();
+        }
+        if previous.cluster_features != loaded.cluster_features {
+            merged.cluster_features = loaded.cluster_features.clone();
+        }
+        if previous.prometheus != loaded.prometheus {
+            merged.prometheus = loaded.prometheus.clone();
+        }
+        if previous.stop_waiting_time != loaded.stop_waiting_time {
+            merged.stop_waiting_time = loaded.stop_waiting_time;
+        }
+        if previous.leadership_drain_time != loaded.leadership_drain_time {
+            merged.leadership_drain_time = loaded.leadership_drain_time;
+        }
+        if previous.shutdown_grace_period != loaded.shutdown_grace_period {
+            merged.shutdown_grace_period = loaded.shutdown_grace_period;
+        }
+        merged
+    }
+}
+
+/// 設定の再読み込みの結果。
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReloadOutcome {
+    /// 新たな値が反映された項目。
+    pub changed: Vec<String>,
+
+    /// 値が変更されていたが、反映には再起動が必要なため無視された項目。
+    pub restart_required: Vec<String>,
+}
+
+/// `previous`と`new`の間で、実行中に反映できない項目のうち値が異なるものの名前を返す。
+///
+/// 両者とも、設定ファイルから読み込んだままの(コマンドライン引数で上書きする前の)設定であることを想定している。
+pub fn restart_required_fields(previous: &FrugalosConfig, new: &FrugalosConfig) -> Vec<String> {
+    let mut new = new.clone();
+    ReloadableConfig::from_config(previous).apply_to(&mut new);
+
+    let mut fields = Vec::new();
+    if previous.data_dir != new.data_dir {
+        fields.push("data_dir");
+    }
+    if previous.log_file != new.log_file {
+        fields.push("log_file");
+    }
+    if previous.log_format != new.log_format {
+        fields.push("log_format");
+    }
+    if previous.max_conprevious_logs != new.max_conprevious_logs {
+        fields.push("max_conprevious_logs");
+    }
+    if previous.daemon != new.daemon {
+        fields.push("daemon");
+    }
+    if previous.http_server != new.http_server {
+        fields.push("http_server");
+    }
+    if previous.grpc_server != new.grpc_server {
+        fields.push("grpc_server");
+    }
+    if previous.rpc_client != new.rpc_client {
+        fields.push("rpc_client");
+    }
+    if previous.discovery != new.discovery {
+        fields.push("discovery");
+    }
+    if previous.device != new.device {
+        fields.push("device");
+    }
+    if previous.workload_recorder != new.workload_recorder {
+        fields.push("workload_recorder");
+    }
+    if previous.slo != new.slo {
+        fields.push("slo");
+    }
+    if previous.watchdog != new.watchdog {
+        fields.push("watchdog");
+    }
+    if previous.event_sink != new.event_sink {
+        fields.push("event_sink");
+    }
+    if previous.operation != new.operation {
+        fields.push("operation");
+    }
+    if previous.rebalancer != new.rebalancer {
+        fields.push("rebalancer");
+    }
+    if previous.auth != new.auth {
+        fields.push("auth");
+    }
+    if previous.admission != new.admission {
+        fields.push("admission");
+    }
+    if previous.tracing != new.tracing {
+        fields.push("tracing");
+    }
+    if previous.mds != new.mds {
+        fields.push("mds");
+    }
+    if previous.segment != new.segment {
+        fields.push("segment");
+    }
+    fields.into_iter().map(ToOwned::to_owned).collect()
+}
+
+/// 実行中に変更可能なログレベル。
+///
+/// ロガーは起動時のログレベルで生成されており、それより詳細なログは既に捨てられているので、
+/// 起動時よりも詳細なレベルに変更することはできない。
+#[derive(Debug, Clone)]
+pub struct LogLevel {
+    current: Arc<AtomicUsize>,
+    initial: Level,
+}
+impl LogLevel {
+    /// `severity`を起動時のログレベルとして、新しい`LogLevel`インスタンスを生成する。
+    pub fn new(severity: Severity) -> Self {
+        let initial = severity.as_level();
+        LogLevel {
+            current: Arc::new(AtomicUsize::new(initial.as_usize())),
+            initial,
+        }
+    }
+
+    /// ログレベルを変更する。
+    ///
+    /// `severity`が起動時のログレベルよりも詳細な場合には、起動時のログレベルが使われ、`false`が返される。
+    pub fn set(&self, severity: Severity) -> bool {
+        let level = severity.as_level();
+        let applicable = level.is_at_least(self.initial);
+        let level = if applicable { level } else { self.initial };
+        self.current.store(level.as_usize(), Ordering::SeqCst);
+        applicable
+    }
+
+    /// 現在のログレベルより低いレベルのログを捨てる`Drain`を返す。
+    pub fn filter<D: Drain>(&self, drain: D) -> LogLevelFilter<D> {
+        LogLevelFilter {
+            drain,
+            level: self.clone(),
+        }
+    }
+
+    fn get(&self) -> Level {
+        Level::from_usize(self.current.load(Ordering::SeqCst)).unwrap_or(self.initial)
+    }
+}
+
+/// `LogLevel`に従ってログを間引く`Drain`。
+#[derive(Debug)]
+pub struct LogLevelFilter<D> {
+    drain: D,
+    level: LogLevel,
+}
+impl<D: Drain> Drain for LogLevelFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> result::Result<Self::Ok, Self::Err> {
+        if record.level().is_at_least(self.level.get()) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+extern "C" fn handle_sighup(_signum: libc::c_int) {
+    // NOTE: シグナルハンドラ内ではアトミック変数の更新以外は行わない
+    SIGHUP_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+/// SIGHUP を受信する度に要素を返す `Stream`。
+///
+/// 生成時に SIGHUP のハンドラを登録するため、以降は SIGHUP でプロセスが終了することはなくなる。
+#[derive(Debug)]
+pub struct ReloadSignal {
+    timeout: Timeout,
+}
+impl ReloadSignal {
+    /// SIGHUP のハンドラを登録して、新しい `ReloadSignal` を生成する。
+    pub fn install() -> Self {
+        let handler = handle_sighup as extern "C" fn(libc::c_int);
+        unsafe {
+            libc::signal(libc::SIGHUP, handler as libc::sighandler_t);
+        }
+        ReloadSignal {
+            timeout: timer::timeout(SIGNAL_CHECK_INTERVAL),
+        }
+    }
+}
+impl Stream for ReloadSignal {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while track!(self.timeout.poll().map_err(Error::from))?.is_ready() {
+            self.timeout = timer::timeout(SIGNAL_CHECK_INTERVAL);
+            if SIGHUP_RECEIVED.swap(false, Ordering::SeqCst) {
+                return Ok(Async::Ready(Some(())));
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use slog::Logger;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn restart_required_fields_works() {
+        let current = FrugalosConfig::default();
+
+        let mut new = current.clone();
+        new.loglevel = Severity::Debug;
+        new.daemon.stop_waiting_time = Duration::from_secs(30);
+        new.repair.idleness_threshold = Some(Duration::from_secs(5));
+        new.memory.budget_bytes = 1024;
+        new.cluster_features = vec![ClusterFeature::DeleteObjects];
+        new.prometheus
+            .histogram_buckets
+            .insert("foo_seconds".to_owned(), vec![0.1, 1.0]);
+        assert!(restart_required_fields(&current, &new).is_empty());
+        assert_eq!(
+            ReloadableConfig::from_config(&current)
+                .changed_fields(&ReloadableConfig::from_config(&new)),
+            vec![
+                "loglevel".to_owned(),
+                "repair".to_owned(),
+                "memory".to_owned(),
+                "cluster_features".to_owned(),
+                "prometheus".to_owned(),
+                "daemon.stop_waiting_time_millis".to_owned()
+            ]
+        );
+
+        new.daemon.executor_threads += 1;
+        new.segment.mds_client.put_content_timeout.0 += 1;
+        assert_eq!(
+            restart_required_fields(&current, &new),
+            vec!["daemon".to_owned(), "segment".to_owned()]
+        );
+    }
+
+    #[test]
+    fn merge_changes_works() {
+        let previous = ReloadableConfig::from_config(&FrugalosConfig::default());
+
+        // コマンドライン引数で上書きされている
+        let mut running = previous.clone();
+        running.loglevel = Severity::Debug;
+        running.stop_waiting_time = Duration::from_secs(30);
+
+        let mut loaded = previous.clone();
+        loaded.stop_waiting_time = Duration::from_secs(60);
+        loaded.memory.budget_bytes = 1024;
+        assert_eq!(
+            previous.changed_fields(&loaded),
+            vec![
+                "memory".to_owned(),
+                "daemon.stop_waiting_time_millis".to_owned()
+            ]
+        );
+
+        let merged = running.merge_changes(&previous, &loaded);
+        assert_eq!(merged.loglevel, Severity::Debug);
+        assert_eq!(merged.stop_waiting_time, Duration::from_secs(60));
+        assert_eq!(merged.memory.budget_bytes, 1024);
+    }
+
+    #[derive(Clone)]
+    struct CountDrain(Arc<AtomicUsize>);
+    impl Drain for CountDrain {
+        type Ok = ();
+        type Err = ();
+        fn log(&self, _: &Record, _: &OwnedKVList) -> result::Result<(), ()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn log_level_filter_works() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let level = LogLevel::new(Severity::Info);
+        let drain = level.filter(CountDrain(count.clone())).ignore_res();
+        let logger = Logger::root(drain, o!());
+
+        assert!(level.set(Severity::Warning));
+        info!(logger, "foo");
+        warn!(logger, "bar");
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        assert!(level.set(Severity::Info));
+        info!(logger, "foo");
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        assert!(level.set(Severity::Error));
+        warn!(logger, "bar");
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        // 起動時よりも詳細なレベルには変更できない
+        assert!(!level.set(Severity::Debug));
+        debug!(logger, "baz");
+        info!(logger, "foo");
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,68 +1,258 @@
 use cannyls;
 use fibers_rpc::server::{HandleCall, Reply, ServerBuilder as RpcServerBuilder};
+use fibers_rpc::Call;
+use frugalos_core::clock;
+use frugalos_core::rpc_auth::{self, Authenticated};
 use frugalos_core::tracer::{SpanExt, ThreadLocalTracer};
 use futures::Future;
 use libfrugalos;
+use libfrugalos::consistency::ReadConsistency;
+use libfrugalos::entity::bucket::BucketId;
+use libfrugalos::entity::object::ObjectId;
 use libfrugalos::schema::frugalos as rpc;
 use rustracing::tag::{StdTag, Tag};
 use rustracing_jaeger::span::Span;
-use std::time::Duration;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use trackable::error::ErrorKindExt;
 
+use admission::{AdmissionController, AdmissionPermit, RequestKind};
+use auth::{Permission, Resource, SharedAuthorizer};
 use client::FrugalosClient;
-use {Error, ErrorKind};
+use cluster_health;
+use export;
+use frugalos_segment::Feature;
+use operation::OperationRegistry;
+use placement;
+use schema;
+use {Error, ErrorKind, Result};
 
 use daemon::FrugalosDaemonHandle;
 
+// 認可に失敗した場合には、エラーを返して呼び出しを終える
+macro_rules! try_authorize {
+    ($e:expr) => {
+        if let Err(e) = track!($e) {
+            return Reply::done(Err(into_rpc_error(e)));
+        }
+    };
+}
+
+// 受け付けられなかった場合には、エラーを返して呼び出しを終える
+macro_rules! try_admit {
+    ($e:expr) => {
+        match track!($e) {
+            Err(e) => return Reply::done(Err(into_rpc_error(e))),
+            Ok(permit) => permit,
+        }
+    };
+}
+
 #[derive(Debug, Clone)]
 pub struct RpcServer {
     client: FrugalosClient,
+    local_addr: SocketAddr,
     daemon: FrugalosDaemonHandle,
     tracer: ThreadLocalTracer,
+    operations: OperationRegistry,
+    authorizer: SharedAuthorizer,
+    admission: AdmissionController,
+    archive_dir: Option<PathBuf>,
+
+    // トークン付きの呼び出しで提示されたトークン
+    token: Option<String>,
 }
 impl RpcServer {
     pub fn register(
         client: FrugalosClient,
+        local_addr: SocketAddr,
         daemon: FrugalosDaemonHandle,
         builder: &mut RpcServerBuilder,
         tracer: ThreadLocalTracer,
-    ) {
+        operations: OperationRegistry,
+        authorizer: SharedAuthorizer,
+        admission: AdmissionController,
+        archive_dir: Option<PathBuf>,
+    ) -> Self {
         let this = RpcServer {
             client,
+            local_addr,
             daemon,
             tracer,
+            operations,
+            authorizer,
+            admission,
+            archive_dir,
+            token: None,
         };
-        builder.add_call_handler::<rpc::DeleteObjectRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::GetObjectRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::HeadObjectRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::PutObjectRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::ListObjectsRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::StopRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::TakeSnapshotRpc, _>(this.clone());
+        add_call_handler::<rpc::DeleteObjectRpc>(builder, &this);
+        add_call_handler::<rpc::GetObjectRpc>(builder, &this);
+        add_call_handler::<rpc::HeadObjectRpc>(builder, &this);
+        add_call_handler::<rpc::PutObjectRpc>(builder, &this);
+        add_call_handler::<rpc::ListObjectsRpc>(builder, &this);
+        add_call_handler::<rpc::StopRpc>(builder, &this);
+        add_call_handler::<rpc::TakeSnapshotRpc>(builder, &this);
 
-        builder.add_call_handler::<rpc::GetLatestVersionRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::DeleteObjectByVersionRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::DeleteObjectsByRangeRpc, _>(this.clone());
-        builder.add_call_handler::<rpc::DeleteObjectsByPrefixRpc, _>(this.clone());
+        add_call_handler::<rpc::GetLatestVersionRpc>(builder, &this);
+        add_call_handler::<rpc::DeleteObjectByVersionRpc>(builder, &this);
+        add_call_handler::<rpc::DeleteObjectsByRangeRpc>(builder, &this);
+        add_call_handler::<rpc::DeleteObjectsByPrefixRpc>(builder, &this);
+
+        add_call_handler::<schema::GetServerTimeRpc>(builder, &this);
+        add_call_handler::<schema::RepairObjectRpc>(builder, &this);
+        add_call_handler::<schema::VerifyObjectRpc>(builder, &this);
+        add_call_handler::<schema::UndeleteObjectRpc>(builder, &this);
+        add_call_handler::<schema::PutObjectWithDurabilityRpc>(builder, &this);
+        add_call_handler::<schema::ExportBucketRpc>(builder, &this);
+        add_call_handler::<schema::ImportBucketRpc>(builder, &this);
+        add_call_handler::<schema::GetOperationRpc>(builder, &this);
+        add_call_handler::<schema::GetFeatureFlagsRpc>(builder, &this);
+        add_call_handler::<schema::SetFeatureFlagRpc>(builder, &this);
+        add_call_handler::<schema::ReloadConfigRpc>(builder, &this);
+        add_call_handler::<schema::DrainDaemonRpc>(builder, &this);
+        add_call_handler::<schema::GetClusterHealthRpc>(builder, &this);
+        add_call_handler::<schema::GetObjectPlacementRpc>(builder, &this);
+        add_call_handler::<schema::FlushContentCacheRpc>(builder, &this);
+        this
+    }
+
+    /// テスト用に、RPC サーバに登録されていない`RpcServer`を生成する。
+    ///
+    /// 受け付け制御と長時間操作の管理には、デフォルトの設定が使われる。
+    #[cfg(test)]
+    pub(crate) fn for_test(client: FrugalosClient, authorizer: SharedAuthorizer) -> Self {
+        RpcServer {
+            client,
+            local_addr: ([127, 0, 0, 1], 0).into(),
+            daemon: FrugalosDaemonHandle::detached(),
+            tracer: ::frugalos_core::tracer::make_null_tracer(),
+            operations: OperationRegistry::new(Default::default()),
+            authorizer,
+            admission: AdmissionController::new(Default::default()),
+            archive_dir: None,
+            token: None,
+        }
+    }
+
+    /// 提示されたトークンで認可を行う`RpcServer`を返す。
+    ///
+    /// RPC 以外の経路(gRPC)で受け付けたリクエストを、同じ認可・受け付け制御の下で処理するために使う。
+    pub(crate) fn with_token(&self, token: Option<String>) -> Self {
+        let mut this = self.clone();
+        this.token = token;
+        this
+    }
+
+    #[cfg(feature = "grpc")]
+    pub(crate) fn client(&self) -> &FrugalosClient {
+        &self.client
+    }
+
+    /// バケツに対する操作を認可する。
+    ///
+    /// トークンを伴わない呼び出しは、匿名の主体として扱われる。
+    /// クラスタトークンを提示した呼び出しには、全ての操作が許可される。
+    pub(crate) fn authorize_bucket(&self, bucket_id: &str, permission: Permission) -> Result<()> {
+        let resource = Resource::Bucket(bucket_id.to_owned());
+        track!(self.authorize(&resource, permission))
+    }
+
+    /// バケツに属さない操作を認可する。
+    pub(crate) fn authorize_cluster(&self, permission: Permission) -> Result<()> {
+        track!(self.authorize(&Resource::Cluster, permission))
+    }
+
+    fn authorize(&self, resource: &Resource, permission: Permission) -> Result<()> {
+        let token = self.token.as_ref().map(|t| t.as_str());
+        if token.map_or(false, rpc_auth::is_cluster_token) {
+            return Ok(());
+        }
+        track!(self.authorizer.check(token, resource, permission))
+    }
+
+    /// オブジェクトの取得ないし書き込みの受け付けを試みる。
+    ///
+    /// 存在しないバケツに対するリクエストは、受け付け制御の対象としない。
+    pub(crate) fn admit(
+        &self,
+        bucket_id: &BucketId,
+        kind: RequestKind,
+    ) -> Result<Option<AdmissionPermit>> {
+        if self.client.segment_count(bucket_id).is_none() {
+            return Ok(None);
+        }
+        track!(self.admission.admit(bucket_id, kind)).map(Some)
+    }
+
+    /// 書き出しないし復元の要求を、長時間操作を開始する前に検証する。
+    ///
+    /// パスは RPC を受け付けたサーバ上のものなので、作業ディレクトリに依存しないように絶対パスに限る。
+    /// 書き出しないし復元の要求を検証して、アーカイブのパスを返す。
+    fn check_bucket_archive_request(&self, bucket_id: &str, path: &Path) -> Result<PathBuf> {
+        track!(self.client.bucket(bucket_id))?;
+        track!(export::resolve_archive_path(
+            self.archive_dir.as_ref().map(|d| d.as_path()),
+            path
+        ))
     }
 
     fn span_from_object_request(
         &self,
         operation: &'static str,
         request: &rpc::ObjectRequest,
+    ) -> Span {
+        self.object_span(operation, &request.bucket_id, &request.object_id)
+    }
+
+    pub(crate) fn object_span(
+        &self,
+        operation: &'static str,
+        bucket_id: &str,
+        object_id: &str,
     ) -> Span {
         // TODO リクエストからの span を引き継ぐ
         let mut span = self.tracer.span(|t| t.span(operation).start());
-        let bucket_id = request.bucket_id.clone();
-        let object_id = request.object_id.clone();
+        let bucket_id = bucket_id.to_owned();
+        let object_id = object_id.to_owned();
         span.set_tag(|| StdTag::component(module_path!()));
         span.set_tag(|| Tag::new("bucket.id", bucket_id));
         span.set_tag(|| Tag::new("object.id", object_id));
         span
     }
 }
+// トークン付きの呼び出しは、提示されたトークンで認可する点を除いて、従来の呼び出しと同じように処理する
+impl<C> HandleCall<Authenticated<C>> for RpcServer
+where
+    C: Call,
+    C::Req: Serialize + DeserializeOwned + Send + 'static,
+    RpcServer: HandleCall<C>,
+{
+    fn handle_call(&self, (token, request): (String, C::Req)) -> Reply<Authenticated<C>> {
+        let this = self.with_token(Some(token));
+        Reply::future(HandleCall::<C>::handle_call(&this, request))
+    }
+}
+
+// 従来の呼び出しとトークン付きの呼び出しの両方に対して、ハンドラを登録する
+fn add_call_handler<C>(builder: &mut RpcServerBuilder, this: &RpcServer)
+where
+    C: Call,
+    C::Req: Serialize + DeserializeOwned + Send + 'static,
+    C::ReqDecoder: Default,
+    C::ResEncoder: Default,
+    RpcServer: HandleCall<C>,
+{
+    builder.add_call_handler::<C, _>(this.clone());
+    builder.add_call_handler::<Authenticated<C>, _>(this.clone());
+}
+
 impl HandleCall<rpc::DeleteObjectRpc> for RpcServer {
     fn handle_call(&self, request: rpc::ObjectRequest) -> Reply<rpc::DeleteObjectRpc> {
+        try_authorize!(self.authorize_bucket(&request.bucket_id, Permission::Write));
+        let permit = try_admit!(self.admit(&request.bucket_id, RequestKind::Put));
         let mut span = self.span_from_object_request("delete_object_rpc", &request);
         let future = self
             .client
@@ -74,6 +264,7 @@ impl HandleCall<rpc::DeleteObjectRpc> for RpcServer {
         Reply::future(
             future
                 .then(move |result| {
+                    drop(permit);
                     result
                         .map(|version| {
                             version.map(|version| {
@@ -92,6 +283,7 @@ impl HandleCall<rpc::DeleteObjectRpc> for RpcServer {
 }
 impl HandleCall<rpc::DeleteObjectByVersionRpc> for RpcServer {
     fn handle_call(&self, request: rpc::VersionRequest) -> Reply<rpc::DeleteObjectByVersionRpc> {
+        try_authorize!(self.authorize_bucket(&request.bucket_id, Permission::Write));
         let future = self
             .client
             .request(request.bucket_id)
@@ -102,6 +294,7 @@ impl HandleCall<rpc::DeleteObjectByVersionRpc> for RpcServer {
 }
 impl HandleCall<rpc::DeleteObjectsByRangeRpc> for RpcServer {
     fn handle_call(&self, request: rpc::RangeRequest) -> Reply<rpc::DeleteObjectsByRangeRpc> {
+        try_authorize!(self.authorize_bucket(&request.bucket_id, Permission::Write));
         let future = self
             .client
             .request(request.bucket_id)
@@ -112,6 +305,7 @@ impl HandleCall<rpc::DeleteObjectsByRangeRpc> for RpcServer {
 }
 impl HandleCall<rpc::DeleteObjectsByPrefixRpc> for RpcServer {
     fn handle_call(&self, request: rpc::PrefixRequest) -> Reply<rpc::DeleteObjectsByPrefixRpc> {
+        try_authorize!(self.authorize_bucket(&request.bucket_id, Permission::Write));
         let future = self
             .client
             .request(request.bucket_id)
@@ -122,6 +316,8 @@ impl HandleCall<rpc::DeleteObjectsByPrefixRpc> for RpcServer {
 }
 impl HandleCall<rpc::GetObjectRpc> for RpcServer {
     fn handle_call(&self, request: rpc::ObjectRequest) -> Reply<rpc::GetObjectRpc> {
+        try_authorize!(self.authorize_bucket(&request.bucket_id, Permission::Read));
+        let permit = try_admit!(self.admit(&request.bucket_id, RequestKind::Get));
         let mut span = self.span_from_object_request("get_object_rpc", &request);
         let future = self
             .client
@@ -129,10 +325,11 @@ impl HandleCall<rpc::GetObjectRpc> for RpcServer {
             .deadline(into_cannyls_deadline(request.deadline))
             .expect(request.expect)
             .span(&span)
-            .get(request.object_id, request.consistency.unwrap_or_default());
+            .get(request.object_id, request.consistency);
         Reply::future(
             future
                 .then(move |result| {
+                    drop(permit);
                     result
                         .map(|o| {
                             o.map(|o| {
@@ -153,38 +350,47 @@ impl HandleCall<rpc::GetObjectRpc> for RpcServer {
 }
 impl HandleCall<rpc::HeadObjectRpc> for RpcServer {
     fn handle_call(&self, request: rpc::HeadObjectRequest) -> Reply<rpc::HeadObjectRpc> {
-        if request.check_storage {
-            let future = self
-                .client
+        try_authorize!(self.authorize_bucket(&request.bucket_id, Permission::Read));
+        let permit = try_admit!(self.admit(&request.bucket_id, RequestKind::Get));
+        let future = if request.check_storage {
+            self.client
                 .request(request.bucket_id)
                 .deadline(into_cannyls_deadline(request.deadline))
                 .expect(request.expect)
-                .head_storage(request.object_id, request.consistency);
-            Reply::future(future.map_err(into_rpc_error).then(Ok))
+                .head_storage(request.object_id, request.consistency)
         } else {
-            let future = self
-                .client
+            self.client
                 .request(request.bucket_id)
                 .deadline(into_cannyls_deadline(request.deadline))
                 .expect(request.expect)
-                .head(request.object_id, request.consistency);
-            Reply::future(future.map_err(into_rpc_error).then(Ok))
-        }
+                .head(request.object_id, request.consistency)
+        };
+        Reply::future(future.map_err(into_rpc_error).then(move |result| {
+            drop(permit);
+            Ok(result)
+        }))
     }
 }
 impl HandleCall<rpc::PutObjectRpc> for RpcServer {
     fn handle_call(&self, request: rpc::PutObjectRequest) -> Reply<rpc::PutObjectRpc> {
+        try_authorize!(self.authorize_bucket(&request.bucket_id, Permission::Write));
+        let permit = try_admit!(self.admit(&request.bucket_id, RequestKind::Put));
         let future = self
             .client
             .request(request.bucket_id)
             .deadline(into_cannyls_deadline(request.deadline))
             .expect(request.expect)
-            .put(request.object_id, request.content);
-        Reply::future(future.map_err(into_rpc_error).then(Ok))
+            .put(request.object_id, request.content)
+            .map(|(version, created, _)| (version, created));
+        Reply::future(future.map_err(into_rpc_error).then(move |result| {
+            drop(permit);
+            Ok(result)
+        }))
     }
 }
 impl HandleCall<rpc::ListObjectsRpc> for RpcServer {
     fn handle_call(&self, request: rpc::SegmentRequest) -> Reply<rpc::ListObjectsRpc> {
+        try_authorize!(self.authorize_bucket(&request.bucket_id, Permission::Read));
         let future = self
             .client
             .request(request.bucket_id)
@@ -195,6 +401,7 @@ impl HandleCall<rpc::ListObjectsRpc> for RpcServer {
 
 impl HandleCall<rpc::GetLatestVersionRpc> for RpcServer {
     fn handle_call(&self, request: rpc::SegmentRequest) -> Reply<rpc::GetLatestVersionRpc> {
+        try_authorize!(self.authorize_bucket(&request.bucket_id, Permission::Read));
         let future = self
             .client
             .request(request.bucket_id)
@@ -205,22 +412,215 @@ impl HandleCall<rpc::GetLatestVersionRpc> for RpcServer {
 
 impl HandleCall<rpc::StopRpc> for RpcServer {
     fn handle_call(&self, (): ()) -> Reply<rpc::StopRpc> {
+        try_authorize!(self.authorize_cluster(Permission::Admin));
         Reply::future(self.daemon.stop().map_err(into_rpc_error2).then(Ok))
     }
 }
 impl HandleCall<rpc::TakeSnapshotRpc> for RpcServer {
     fn handle_call(&self, (): ()) -> Reply<rpc::TakeSnapshotRpc> {
+        try_authorize!(self.authorize_cluster(Permission::Admin));
         // TODO: cast?
         self.daemon.take_snapshot();
         Reply::done(Ok(()))
     }
 }
+impl HandleCall<schema::DrainDaemonRpc> for RpcServer {
+    fn handle_call(&self, (): ()) -> Reply<schema::DrainDaemonRpc> {
+        try_authorize!(self.authorize_cluster(Permission::Admin));
+        Reply::future(self.daemon.drain().map_err(into_rpc_error).then(Ok))
+    }
+}
+impl HandleCall<schema::ReloadConfigRpc> for RpcServer {
+    fn handle_call(&self, (): ()) -> Reply<schema::ReloadConfigRpc> {
+        try_authorize!(self.authorize_cluster(Permission::Admin));
+        Reply::future(self.daemon.reload_config().map_err(into_rpc_error).then(Ok))
+    }
+}
+impl HandleCall<schema::GetServerTimeRpc> for RpcServer {
+    fn handle_call(&self, (): ()) -> Reply<schema::GetServerTimeRpc> {
+        Reply::done(clock::to_unix_millis(SystemTime::now()))
+    }
+}
+impl HandleCall<schema::RepairObjectRpc> for RpcServer {
+    fn handle_call(
+        &self,
+        (bucket_id, object_id): (BucketId, ObjectId),
+    ) -> Reply<schema::RepairObjectRpc> {
+        try_authorize!(self.authorize_bucket(&bucket_id, Permission::Write));
+        let mut span = self.tracer.span(|t| t.span("repair_object_rpc").start());
+        span.set_tag(|| StdTag::component(module_path!()));
+        span.set_tag(|| Tag::new("bucket.id", bucket_id.clone()));
+        span.set_tag(|| Tag::new("object.id", object_id.clone()));
+        let future = self.client.request(bucket_id).span(&span).repair(object_id);
+        Reply::future(
+            future
+                .map_err(move |e| {
+                    span.log_error(&e);
+                    into_rpc_error(e)
+                })
+                .then(Ok),
+        )
+    }
+}
+impl HandleCall<schema::VerifyObjectRpc> for RpcServer {
+    fn handle_call(
+        &self,
+        (bucket_id, object_id): (BucketId, ObjectId),
+    ) -> Reply<schema::VerifyObjectRpc> {
+        try_authorize!(self.authorize_bucket(&bucket_id, Permission::Read));
+        let future = self.client.request(bucket_id).verify(object_id);
+        Reply::future(future.map_err(into_rpc_error).then(Ok))
+    }
+}
+impl HandleCall<schema::GetObjectPlacementRpc> for RpcServer {
+    fn handle_call(
+        &self,
+        (bucket_id, object_id, consistency): (BucketId, ObjectId, Option<ReadConsistency>),
+    ) -> Reply<schema::GetObjectPlacementRpc> {
+        try_authorize!(self.authorize_bucket(&bucket_id, Permission::Read));
+        let future = placement::locate_object(&self.client, &bucket_id, object_id, consistency);
+        Reply::future(future.map_err(into_rpc_error).then(Ok))
+    }
+}
+impl HandleCall<schema::FlushContentCacheRpc> for RpcServer {
+    fn handle_call(&self, bucket_id: BucketId) -> Reply<schema::FlushContentCacheRpc> {
+        try_authorize!(self.authorize_bucket(&bucket_id, Permission::Admin));
+        let result = self.client.flush_content_cache(&bucket_id).ok_or_else(|| {
+            let e = ErrorKind::NotFound.cause(format!("No such bucket: {:?}", bucket_id));
+            track!(Error::from(e))
+        });
+        Reply::done(result.map_err(into_rpc_error))
+    }
+}
+impl HandleCall<schema::PutObjectWithDurabilityRpc> for RpcServer {
+    fn handle_call(
+        &self,
+        request: rpc::PutObjectRequest,
+    ) -> Reply<schema::PutObjectWithDurabilityRpc> {
+        try_authorize!(self.authorize_bucket(&request.bucket_id, Permission::Write));
+        let permit = try_admit!(self.admit(&request.bucket_id, RequestKind::Put));
+        let future = self
+            .client
+            .request(request.bucket_id)
+            .deadline(into_cannyls_deadline(request.deadline))
+            .expect(request.expect)
+            .put(request.object_id, request.content);
+        Reply::future(future.map_err(into_rpc_error).then(move |result| {
+            drop(permit);
+            Ok(result)
+        }))
+    }
+}
+impl HandleCall<schema::ExportBucketRpc> for RpcServer {
+    fn handle_call(&self, (bucket_id, path): (BucketId, String)) -> Reply<schema::ExportBucketRpc> {
+        try_authorize!(self.authorize_bucket(&bucket_id, Permission::Admin));
+        let path = PathBuf::from(path);
+        let result = track!(self.check_bucket_archive_request(&bucket_id, &path)).map(|path| {
+            let client = self.client.clone();
+            let target = bucket_id.clone();
+            let status = self
+                .operations
+                .start("export_bucket", target, move |handle| {
+                    export::export_bucket(&client, &bucket_id, path, handle)
+                });
+            status.operation_id
+        });
+        Reply::done(result.map_err(into_rpc_error))
+    }
+}
+impl HandleCall<schema::ImportBucketRpc> for RpcServer {
+    fn handle_call(&self, (bucket_id, path): (BucketId, String)) -> Reply<schema::ImportBucketRpc> {
+        try_authorize!(self.authorize_bucket(&bucket_id, Permission::Admin));
+        let path = PathBuf::from(path);
+        let result = track!(self.check_bucket_archive_request(&bucket_id, &path)).map(|path| {
+            let client = self.client.clone();
+            let target = bucket_id.clone();
+            let status = self
+                .operations
+                .start("import_bucket", target, move |handle| {
+                    export::import_bucket(&client, &bucket_id, path, handle)
+                });
+            status.operation_id
+        });
+        Reply::done(result.map_err(into_rpc_error))
+    }
+}
+impl HandleCall<schema::GetFeatureFlagsRpc> for RpcServer {
+    fn handle_call(&self, bucket_id: BucketId) -> Reply<schema::GetFeatureFlagsRpc> {
+        try_authorize!(self.authorize_bucket(&bucket_id, Permission::Read));
+        let result = track!(self.client.feature_flags(&bucket_id)).map(|f| f.snapshot());
+        Reply::done(result.map_err(into_rpc_error))
+    }
+}
+impl HandleCall<schema::SetFeatureFlagRpc> for RpcServer {
+    fn handle_call(
+        &self,
+        (bucket_id, feature, enabled): (BucketId, Feature, bool),
+    ) -> Reply<schema::SetFeatureFlagRpc> {
+        try_authorize!(self.authorize_bucket(&bucket_id, Permission::Admin));
+        let result = track!(self.client.feature_flags(&bucket_id)).map(|flags| {
+            flags.set(feature, enabled);
+            flags.snapshot()
+        });
+        Reply::done(result.map_err(into_rpc_error))
+    }
+}
+impl HandleCall<schema::GetOperationRpc> for RpcServer {
+    fn handle_call(&self, operation_id: String) -> Reply<schema::GetOperationRpc> {
+        try_authorize!(self.authorize_cluster(Permission::Read));
+        let result = track!(self.operations.status(&operation_id));
+        Reply::done(result.map_err(into_rpc_error))
+    }
+}
+impl HandleCall<schema::GetClusterHealthRpc> for RpcServer {
+    fn handle_call(&self, repair_backlog_threshold: usize) -> Reply<schema::GetClusterHealthRpc> {
+        try_authorize!(self.authorize_cluster(Permission::Read));
+        let future = cluster_health::check_local_segments(
+            &self.client,
+            self.local_addr,
+            repair_backlog_threshold,
+        );
+        Reply::future(future.map_err(into_rpc_error).then(Ok))
+    }
+}
+impl HandleCall<schema::UndeleteObjectRpc> for RpcServer {
+    fn handle_call(
+        &self,
+        (bucket_id, object_id): (BucketId, ObjectId),
+    ) -> Reply<schema::UndeleteObjectRpc> {
+        try_authorize!(self.authorize_bucket(&bucket_id, Permission::Write));
+        let mut span = self.tracer.span(|t| t.span("undelete_object_rpc").start());
+        span.set_tag(|| StdTag::component(module_path!()));
+        span.set_tag(|| Tag::new("bucket.id", bucket_id.clone()));
+        span.set_tag(|| Tag::new("object.id", object_id.clone()));
+        let future = self
+            .client
+            .request(bucket_id)
+            .span(&span)
+            .undelete(object_id);
+        Reply::future(
+            future
+                .map_err(move |e| {
+                    span.log_error(&e);
+                    into_rpc_error(e)
+                })
+                .then(Ok),
+        )
+    }
+}
 
 fn into_rpc_error(e: Error) -> libfrugalos::Error {
     let kind = match *e.kind() {
         ErrorKind::InvalidInput => libfrugalos::ErrorKind::InvalidInput,
         ErrorKind::NotFound => libfrugalos::ErrorKind::Other,
         ErrorKind::Unexpected(v) => libfrugalos::ErrorKind::Unexpected(v),
+        // NOTE: `libfrugalos`には割り当て量の超過を表す種類が無いので、`InvalidInput`と区別できるように
+        // `Unavailable`を使う(削除等の後であれば成功し得る)
+        ErrorKind::QuotaExceeded => libfrugalos::ErrorKind::Unavailable,
+        ErrorKind::ReadOnly => libfrugalos::ErrorKind::InvalidInput,
+        ErrorKind::Unauthenticated => libfrugalos::ErrorKind::InvalidInput,
+        ErrorKind::PermissionDenied => libfrugalos::ErrorKind::InvalidInput,
+        ErrorKind::Busy(_) => libfrugalos::ErrorKind::Unavailable,
         ErrorKind::Other => libfrugalos::ErrorKind::Other,
     };
     kind.takes_over(e).into()
@@ -235,6 +635,8 @@ fn into_rpc_error2(e: ::Error) -> libfrugalos::Error {
     kind.takes_over(e).into()
 }
 
+// `libfrugalos`の RPC のリクエストはデッドラインを省略できないので、
+// バケツの属性のデフォルトのデッドライン(`default_deadline_ms`)は RPC には適用されない
 fn into_cannyls_deadline(d: Duration) -> cannyls::deadline::Deadline {
     cannyls::deadline::Deadline::Within(d)
 }
@@ -0,0 +1,297 @@
+//! frugalos のサーバ間でのみ利用される RPC のスキーマ定義。
+//!
+//! 公開 API 系の RPC は `libfrugalos::schema` に定義されている。
+//! ここで定義する RPC の ID は、それらと衝突しないように `0x0200_0000` 以降を利用する。
+//! (`0x0201_0000` 以降は `frugalos_segment::schema` が、`0x0202_0000` 以降は `frugalos_mds::schema` が、
+//! `0x0203_0000` 以降は `frugalos_config::schema` が利用している)
+use bytecodec::bincode_codec::{BincodeDecoder, BincodeEncoder};
+use bytecodec::json_codec::{JsonDecoder, JsonEncoder};
+use fibers_rpc::{Call, ProcedureId};
+use frugalos_segment::config::FeatureFlagSet;
+use frugalos_segment::{Feature, ObjectAuditReport, ObjectRepairSummary, PutDurability};
+use libfrugalos::consistency::ReadConsistency;
+use libfrugalos::entity::bucket::BucketId;
+use libfrugalos::entity::object::{ObjectId, ObjectVersion};
+use libfrugalos::schema::frugalos::PutObjectRequest;
+use libfrugalos::Result;
+
+use cluster_health::ClusterHealthReport;
+use operation::OperationStatus;
+use placement::ObjectPlacement;
+use reload::ReloadOutcome;
+
+/// サーバの現在時刻(UNIX エポックからの経過ミリ秒)を取得する RPC。
+///
+/// サーバ間の時計のズレを検出するために使われる。
+#[derive(Debug)]
+pub struct GetServerTimeRpc;
+impl Call for GetServerTimeRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_0000);
+    const NAME: &'static str = "frugalos.ctrl.get_server_time";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = u64;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// オブジェクトの中身を、それを保持する全ノードで即座に検証・リペアする RPC。
+///
+/// 要求はバケツ ID とオブジェクト ID の組で、オブジェクトが存在しない場合の応答は`None`となる。
+#[derive(Debug)]
+pub struct RepairObjectRpc;
+impl Call for RepairObjectRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_0001);
+    const NAME: &'static str = "frugalos.ctrl.repair_object";
+
+    type Req = (BucketId, ObjectId);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Option<ObjectRepairSummary>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// オブジェクトの断片の配置状況を監査する RPC。
+///
+/// 断片の有無を問い合わせるだけで、リペアは行わない。
+/// 要求はバケツ ID とオブジェクト ID の組で、オブジェクトが存在しない場合の応答は`None`となる。
+#[derive(Debug)]
+pub struct VerifyObjectRpc;
+impl Call for VerifyObjectRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_0002);
+    const NAME: &'static str = "frugalos.ctrl.verify_object";
+
+    type Req = (BucketId, ObjectId);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Option<ObjectAuditReport>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 削除猶予期間中のオブジェクトを復元する RPC。
+///
+/// 要求はバケツ ID とオブジェクト ID の組で、応答は復元されたオブジェクトのバージョン。
+#[derive(Debug)]
+pub struct UndeleteObjectRpc;
+impl Call for UndeleteObjectRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_0003);
+    const NAME: &'static str = "frugalos.ctrl.undelete_object";
+
+    type Req = (BucketId, ObjectId);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<Option<ObjectVersion>>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// オブジェクトを保存し、断片の書き込み状況も合わせて返す RPC。
+///
+/// 要求は`libfrugalos`の`PutObjectRpc`と同じ。
+/// 応答は、保存したオブジェクトのバージョン、新規に作成されたかどうか、および断片の書き込み状況。
+#[derive(Debug)]
+pub struct PutObjectWithDurabilityRpc;
+impl Call for PutObjectWithDurabilityRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_0004);
+    const NAME: &'static str = "frugalos.ctrl.put_object_with_durability";
+
+    type Req = PutObjectRequest;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<(ObjectVersion, bool, PutDurability)>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// バケツ内の全オブジェクトを、RPC を受け付けたサーバのローカルファイルに書き出す RPC。
+///
+/// 要求はバケツ ID と書き出し先のパスの組で、応答は開始された長時間操作の ID。
+/// 進捗は`GetOperationRpc`ないし`/v1/operations/{id}`で確認できる。
+#[derive(Debug)]
+pub struct ExportBucketRpc;
+impl Call for ExportBucketRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_0005);
+    const NAME: &'static str = "frugalos.ctrl.export_bucket";
+
+    type Req = (BucketId, String);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<String>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// `ExportBucketRpc`で書き出したファイルから、別のバケツにオブジェクトを復元する RPC。
+///
+/// 要求は復元先のバケツ ID と、RPC を受け付けたサーバ上のファイルのパスの組。
+/// 応答は開始された長時間操作の ID。
+#[derive(Debug)]
+pub struct ImportBucketRpc;
+impl Call for ImportBucketRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_0006);
+    const NAME: &'static str = "frugalos.ctrl.import_bucket";
+
+    type Req = (BucketId, String);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<String>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// 長時間操作の状態と進捗を取得する RPC。
+///
+/// 状態には任意の JSON 値(操作の結果)が含まれるので、応答は JSON で符号化する。
+#[derive(Debug)]
+pub struct GetOperationRpc;
+impl Call for GetOperationRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_0007);
+    const NAME: &'static str = "frugalos.ctrl.get_operation";
+
+    type Req = String;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<OperationStatus>;
+    type ResDecoder = JsonDecoder<Self::Res>;
+    type ResEncoder = JsonEncoder<Self::Res>;
+}
+
+/// RPC を受け付けたサーバ上での、バケツの機能フラグの現在の値を取得する RPC。
+#[derive(Debug)]
+pub struct GetFeatureFlagsRpc;
+impl Call for GetFeatureFlagsRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_0008);
+    const NAME: &'static str = "frugalos.ctrl.get_feature_flags";
+
+    type Req = BucketId;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<FeatureFlagSet>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// RPC を受け付けたサーバ上で、バケツの機能フラグを切り替える RPC。
+///
+/// 要求はバケツ ID、機能、有効にするかどうかの組で、応答は変更後の全ての機能フラグの値。
+/// 変更はそのサーバのみに適用され、再起動すると設定ファイルの値に戻る。
+#[derive(Debug)]
+pub struct SetFeatureFlagRpc;
+impl Call for SetFeatureFlagRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_0009);
+    const NAME: &'static str = "frugalos.ctrl.set_feature_flag";
+
+    type Req = (BucketId, Feature, bool);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<FeatureFlagSet>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// RPC を受け付けたサーバで、設定ファイルを再読み込みする RPC。
+///
+/// 再起動せずに反映できる項目のみが適用され、応答でそれ以外に変更された項目が報告される。
+#[derive(Debug)]
+pub struct ReloadConfigRpc;
+impl Call for ReloadConfigRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_000A);
+    const NAME: &'static str = "frugalos.ctrl.reload_config";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<ReloadOutcome>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// RPC を受け付けたサーバを、ドレインしてから停止させる RPC。
+///
+/// リーダ権の移譲と実行中のリペアの完了を待ち、スナップショットを取得してから停止する。
+/// 応答は停止処理の完了後に返される。
+#[derive(Debug)]
+pub struct DrainDaemonRpc;
+impl Call for DrainDaemonRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_000B);
+    const NAME: &'static str = "frugalos.ctrl.drain";
+
+    type Req = ();
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<()>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// RPC を受け付けたサーバ上にメンバを持つセグメントのうち、劣化しているものを報告する RPC。
+///
+/// 要求はリペアの積み残しの閾値で、これを超えるメンバを持つセグメントも劣化しているものとみなす。
+#[derive(Debug)]
+pub struct GetClusterHealthRpc;
+impl Call for GetClusterHealthRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_000C);
+    const NAME: &'static str = "frugalos.ctrl.get_cluster_health";
+
+    type Req = usize;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<ClusterHealthReport>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// オブジェクトが割り当てられるセグメントと、そのデータを保持するメンバを返す RPC。
+///
+/// 要求はバケツ ID、オブジェクト ID、およびバージョンの取得時に使用する整合性の組。
+/// 整合性が`None`の場合には、バケツのデフォルト値が使われる。
+#[derive(Debug)]
+pub struct GetObjectPlacementRpc;
+impl Call for GetObjectPlacementRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_000E);
+    const NAME: &'static str = "frugalos.ctrl.get_object_placement";
+
+    type Req = (BucketId, ObjectId, Option<ReadConsistency>);
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<ObjectPlacement>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
+
+/// RPC を受け付けたサーバ上の、バケツの内容のキャッシュを破棄する RPC。
+///
+/// 要求はバケツ ID で、応答は破棄したオブジェクトの数。
+/// 他のサーバのキャッシュは破棄されないので、必要に応じて各サーバに送ること。
+#[derive(Debug)]
+pub struct FlushContentCacheRpc;
+impl Call for FlushContentCacheRpc {
+    const ID: ProcedureId = ProcedureId(0x0200_000F);
+    const NAME: &'static str = "frugalos.ctrl.flush_content_cache";
+
+    type Req = BucketId;
+    type ReqDecoder = BincodeDecoder<Self::Req>;
+    type ReqEncoder = BincodeEncoder<Self::Req>;
+
+    type Res = Result<u64>;
+    type ResDecoder = BincodeDecoder<Self::Res>;
+    type ResEncoder = BincodeEncoder<Self::Res>;
+}
@@ -6,9 +6,17 @@ use fibers_http_server::metrics::WithMetrics;
 use fibers_http_server::{
     HandleRequest, Reply, Req, Res, ServerBuilder as HttpServerBuilder, Status,
 };
-use frugalos_core::tracer::ThreadLocalTracer;
+use frugalos_core::checksum::{Digest, ParseChecksumError};
+use frugalos_core::logging;
+use frugalos_core::task_dump::{self, TaskSnapshot};
+use frugalos_core::tracer::otlp::{self, OtlpReporter};
+use frugalos_core::tracer::{TailSampling, ThreadLocalTracer};
+use frugalos_mds::{Precondition, QuotaUsage, RevisionSelector};
+use frugalos_segment::config::RequestPriority;
+use frugalos_segment::{CacheClass, ObjectRange, ObjectValue};
+use futures::future::Either;
 use futures::{self, Future, Stream};
-use httpcodec::{BodyDecoder, BodyEncoder, HeadBodyEncoder, Header};
+use httpcodec::{BodyDecoder, BodyEncoder, HeadBodyEncoder, Header, HeaderField};
 use libfrugalos::consistency::ReadConsistency;
 use libfrugalos::entity::object::{
     DeleteObjectsByPrefixSummary, ObjectPrefix, ObjectSummary, ObjectVersion,
@@ -16,8 +24,11 @@ use libfrugalos::entity::object::{
 use libfrugalos::expect::Expect;
 use rustracing::tag::{StdTag, Tag};
 use rustracing_jaeger::reporter::JaegerCompactReporter;
-use rustracing_jaeger::span::{SpanContext, SpanReceiver};
+use rustracing_jaeger::span::{FinishedSpan, SpanContext, SpanReceiver};
 use slog::Logger;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::path::Path;
 use std::str;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -26,16 +37,39 @@ use std::time::Duration;
 use trackable::error::ErrorKindExt;
 use url::Url;
 
+use admission::{AdmissionController, RequestKind, WithAdmission};
+use auth::{Permission, Resource, SharedAuthorizer, WithAuth};
+use availability::{self, AvailabilityOptions};
+use capture::{
+    CaptureBundle, CaptureTarget, RequestCaptures, DEFAULT_CAPTURE_DURATION, MAX_CAPTURE_DURATION,
+};
 use client::FrugalosClient;
 use codec::{AsyncEncoder, ObjectResultEncoder};
+use dashboard::{self, DashboardTracker, WithDashboard};
 use http::{
-    make_json_response, make_object_response, not_found, BucketStatistics, HttpResult, TraceHeader,
+    add_durability_headers, make_json_response, make_object_response, not_found, BucketDashboard,
+    BucketStatistics, BucketStatus, ContentCacheCapacity, Dashboard, FlushedContentCache,
+    HttpResult, MaintenanceState, SegmentCacheStatistics, SegmentMemberHealth, SegmentStatus,
+    SegmentSummary, TraceHeader,
 };
-use {Error, ErrorKind, FrugalosConfig, Result};
+use operation::{OperationRegistry, OperationRunner, OperationStatus};
+use placement::{self, ObjectPlacement};
+use slo::{SloRefresher, SloTracker, WithSlo};
+use upload::{self, PartWrite, UploadGc, UploadRegistry, UploadStatus};
+use {Error, ErrorKind, FrugalosConfig, FrugalosTracingConfig, Result, TracingExporterConfig};
 
 // TODO: 冗長化設定等を反映した正確な上限を使用する
 const MAX_PUT_OBJECT_SIZE: usize = 50 * 1024 * 1024;
 
+// ダッシュボードの応答を作る際に、並行して問い合わせるセグメントの数
+const DASHBOARD_OBJECT_COUNT_CONCURRENCY: usize = 16;
+
+// 分割アップロードの状態を保存する、データディレクトリ内のファイルの名前
+const UPLOADS_FILE: &str = "uploads.json";
+
+// オブジェクトの内容のダイジェストを伝えるヘッダの名前
+const CONTENT_DIGEST_HEADER: &str = "X-Frugalos-Content-Digest";
+
 macro_rules! try_badarg {
     ($e:expr) => {
         match track!($e) {
@@ -56,6 +90,12 @@ pub struct Server {
     config: FrugalosConfig,
     client: FrugalosClient,
     tracer: ThreadLocalTracer,
+    uploads: UploadRegistry,
+    operations: OperationRegistry,
+    captures: RequestCaptures,
+    authorizer: SharedAuthorizer,
+    admission: AdmissionController,
+    slo: SloTracker,
 
     // TODO: remove
     large_object_count: Arc<AtomicUsize>,
@@ -66,26 +106,187 @@ impl Server {
         config: FrugalosConfig,
         client: FrugalosClient,
         tracer: ThreadLocalTracer,
-    ) -> Self {
-        Server {
+        operations: OperationRegistry,
+        captures: RequestCaptures,
+        authorizer: SharedAuthorizer,
+        admission: AdmissionController,
+    ) -> Result<Self> {
+        let uploads = track!(UploadRegistry::open(
+            config.http_server.upload.clone(),
+            Path::new(&config.data_dir).join(UPLOADS_FILE),
+        ))?;
+        let slo = track!(SloTracker::new(&config.slo))?;
+        Ok(Server {
             logger,
             config,
             client,
             tracer,
+            uploads,
+            operations,
+            captures,
+            authorizer,
+            admission,
+            slo,
             large_object_count: Arc::default(),
+        })
+    }
+    /// 放棄された分割アップロードを定期的に削除する`Future`を返す。
+    pub fn upload_gc(&self) -> UploadGc {
+        self.uploads.gc(self.logger.clone(), self.client.clone())
+    }
+    /// SLO の各ウィンドウの値を定期的に更新する`Future`を返す。
+    pub fn slo_refresher(&self) -> SloRefresher {
+        self.slo.refresher()
+    }
+    /// 長時間操作を実行する`Future`を返す。
+    pub fn operation_runner(&self) -> OperationRunner {
+        self.operations.runner(self.logger.clone())
+    }
+    fn maintenance_state(&self) -> MaintenanceState {
+        MaintenanceState {
+            in_window: self.client.is_maintenance_window_open(),
+            overridden: self.client.is_maintenance_window_overridden(),
         }
     }
+    fn bucket_read<H: HandleRequest>(&self, handler: H) -> WithAuth<H> {
+        WithAuth::bucket(handler, self.authorizer.clone(), Permission::Read)
+    }
+    fn bucket_write<H: HandleRequest>(&self, handler: H) -> WithAuth<H> {
+        WithAuth::bucket(handler, self.authorizer.clone(), Permission::Write)
+    }
+    fn bucket_admin<H: HandleRequest>(&self, handler: H) -> WithAuth<H> {
+        WithAuth::bucket(handler, self.authorizer.clone(), Permission::Admin)
+    }
+    fn cluster_read<H: HandleRequest>(&self, handler: H) -> WithAuth<H> {
+        WithAuth::cluster(handler, self.authorizer.clone(), Permission::Read)
+    }
+    fn cluster_admin<H: HandleRequest>(&self, handler: H) -> WithAuth<H> {
+        WithAuth::cluster(handler, self.authorizer.clone(), Permission::Admin)
+    }
+    fn admit<H: HandleRequest>(&self, handler: H, kind: RequestKind) -> WithAdmission<H> {
+        WithAdmission::bucket(handler, self.admission.clone(), self.client.clone(), kind)
+    }
+    /// 分割アップロードの対象のバケツに対して受け付け制御を行う。
+    fn admit_upload<H: HandleRequest>(&self, handler: H) -> WithAdmission<H> {
+        let uploads = self.uploads.clone();
+        WithAdmission::new(
+            handler,
+            self.admission.clone(),
+            RequestKind::Put,
+            move |url| {
+                uploads
+                    .status(&get_upload_id(url))
+                    .ok()
+                    .map(|status| status.bucket_id)
+            },
+        )
+    }
+    /// 分割アップロードの対象のバケツに対する権限を要求する。
+    fn upload<H: HandleRequest>(&self, handler: H, permission: Permission) -> WithAuth<H> {
+        let uploads = self.uploads.clone();
+        WithAuth::new(handler, self.authorizer.clone(), permission, move |url| {
+            uploads
+                .status(&get_upload_id(url))
+                .map(|status| Resource::Bucket(status.bucket_id))
+                .unwrap_or(Resource::Cluster)
+        })
+    }
     pub fn register(self, builder: &mut HttpServerBuilder) -> Result<()> {
-        track!(builder.add_handler(ListSegments(self.clone())))?;
-        track!(builder.add_handler(WithMetrics::new(ListObjects(self.clone()))))?;
-        track!(builder.add_handler(WithMetrics::new(GetObject(self.clone()))))?;
-        track!(builder.add_handler(WithMetrics::new(HeadObject(self.clone()))))?;
-        track!(builder.add_handler(WithMetrics::new(DeleteObject(self.clone()))))?;
-        track!(builder.add_handler(WithMetrics::new(DeleteObjectByPrefix(self.clone()))))?;
-        track!(builder.add_handler(WithMetrics::new(PutObject(self.clone()))))?;
-        track!(builder.add_handler(WithMetrics::new(GetBucketStatistics(self.clone()))))?;
+        // オブジェクト操作のみを SLO とダッシュボードの対象とする
+        let slo = self.slo.clone();
+        let dashboard = DashboardTracker::new();
+        track!(builder.add_handler(self.bucket_read(ListSegments(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(
+            self.bucket_read(ListObjects(self.clone()))
+        )))?;
+        track!(builder.add_handler(WithMetrics::new(WithDashboard::new(
+            WithSlo::new(
+                self.admit(self.bucket_read(GetObject(self.clone())), RequestKind::Get),
+                slo.clone()
+            ),
+            dashboard.clone()
+        ))))?;
+        track!(builder.add_handler(WithMetrics::new(WithDashboard::new(
+            WithSlo::new(
+                self.admit(self.bucket_read(HeadObject(self.clone())), RequestKind::Get),
+                slo.clone()
+            ),
+            dashboard.clone()
+        ))))?;
+        track!(builder.add_handler(WithMetrics::new(WithDashboard::new(
+            WithSlo::new(
+                self.admit(
+                    self.bucket_write(DeleteObject(self.clone())),
+                    RequestKind::Put
+                ),
+                slo.clone()
+            ),
+            dashboard.clone()
+        ))))?;
+        track!(builder.add_handler(WithMetrics::new(self.admit(
+            self.bucket_write(DeleteObjectByPrefix(self.clone())),
+            RequestKind::Put
+        ))))?;
+        track!(builder.add_handler(WithMetrics::new(WithDashboard::new(
+            WithSlo::new(
+                self.admit(self.bucket_write(PutObject(self.clone())), RequestKind::Put),
+                slo.clone()
+            ),
+            dashboard.clone()
+        ))))?;
+        track!(builder.add_handler(WithMetrics::new(WithDashboard::new(
+            WithSlo::new(
+                self.admit(
+                    self.bucket_write(AppendObject(self.clone())),
+                    RequestKind::Put
+                ),
+                slo
+            ),
+            dashboard.clone()
+        ))))?;
+        track!(builder.add_handler(WithMetrics::new(self.admit(
+            self.bucket_write(StartUpload(self.clone())),
+            RequestKind::Put
+        ))))?;
+        track!(builder.add_handler(WithMetrics::new(
+            self.upload(GetUpload(self.clone()), Permission::Read)
+        )))?;
+        track!(builder.add_handler(WithMetrics::new(
+            self.admit_upload(self.upload(PutUploadPart(self.clone()), Permission::Write))
+        )))?;
+        track!(builder.add_handler(WithMetrics::new(
+            self.admit_upload(self.upload(CompleteUpload(self.clone()), Permission::Write))
+        )))?;
+        track!(builder.add_handler(WithMetrics::new(
+            self.upload(AbortUpload(self.clone()), Permission::Write)
+        )))?;
+        track!(builder.add_handler(self.cluster_admin(StartAvailabilitySurvey(self.clone()))))?;
+        track!(builder.add_handler(self.cluster_read(ListOperations(self.clone()))))?;
+        track!(builder.add_handler(self.cluster_read(GetOperation(self.clone()))))?;
+        track!(builder.add_handler(self.cluster_admin(CancelOperation(self.clone()))))?;
+        track!(builder.add_handler(self.cluster_admin(StartCapture(self.clone()))))?;
+        track!(builder.add_handler(self.cluster_admin(GetCapture(self.clone()))))?;
+        track!(builder.add_handler(self.cluster_admin(DeleteCapture(self.clone()))))?;
+        track!(builder.add_handler(WithMetrics::new(
+            self.bucket_read(GetBucketStatistics(self.clone()))
+        )))?;
+        track!(builder.add_handler(WithMetrics::new(
+            self.bucket_read(GetBucketUsage(self.clone()))
+        )))?;
+        track!(builder.add_handler(self.bucket_read(GetBucketCacheStatistics(self.clone()))))?;
+        track!(builder.add_handler(self.bucket_admin(DeleteBucketCache(self.clone()))))?;
+        track!(builder.add_handler(self.bucket_read(GetObjectPlacement(self.clone()))))?;
+        track!(builder.add_handler(self.bucket_read(GetBucketStatus(self.clone()))))?;
+        track!(builder.add_handler(self.bucket_read(GetSegmentStatus(self.clone()))))?;
+        track!(builder.add_handler(self.bucket_read(GetBucketMemberHealth(self.clone()))))?;
+        track!(builder.add_handler(self.cluster_read(GetContentCacheCapacity(self.clone()))))?;
+        track!(builder.add_handler(self.cluster_admin(PutContentCacheCapacity(self.clone()))))?;
+        track!(builder.add_handler(self.cluster_read(GetMaintenanceState(self.clone()))))?;
+        track!(builder.add_handler(self.cluster_admin(PutMaintenanceState(self.clone()))))?;
+        track!(builder.add_handler(self.cluster_read(GetDashboard(self.clone(), dashboard))))?;
         track!(builder.add_handler(JemallocStats))?;
-        track!(builder.add_handler(CurrentConfigurations(self.config)))?;
+        track!(builder.add_handler(self.cluster_admin(CurrentConfigurations(self.config.clone()))))?;
+        track!(builder.add_handler(self.cluster_read(TaskDump)))?;
         Ok(())
     }
 }
@@ -127,21 +328,136 @@ impl HandleRequest for ListObjects {
     fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
         let bucket_id = get_bucket_id(req.url());
         let segment_num = try_badarg!(get_segment_num(req.url()));
-        let future = self
-            .0
-            .client
-            .request(bucket_id)
-            .list(segment_num as usize)
-            .then(|result| {
-                let response = match track!(result) {
-                    Ok(list) => make_json_response(Status::Ok, Ok(list)),
-                    Err(ref e) if *e.kind() == ErrorKind::NotFound => {
-                        make_json_response(Status::NotFound, Err(not_found()))
-                    }
-                    Err(e) => make_json_response(Status::InternalServerError, Err(e)),
+        let time_range = try_badarg!(get_time_range(req.url()));
+        let consistency = try_badarg!(get_consistency(req.url()));
+        let request = self.0.client.request(bucket_id);
+        let future = match (time_range, consistency) {
+            (Some(range), _) => request.list_by_time_range(segment_num as usize, range),
+            (None, Some(consistency)) => {
+                request.list_with_consistency(segment_num as usize, consistency)
+            }
+            (None, None) => request.list(segment_num as usize),
+        };
+        let future = future.then(|result| {
+            let response = match track!(result) {
+                Ok(list) => make_json_response(Status::Ok, Ok(list)),
+                Err(ref e) if *e.kind() == ErrorKind::NotFound => {
+                    make_json_response(Status::NotFound, Err(not_found()))
+                }
+                Err(e) => make_json_response(Status::InternalServerError, Err(e)),
+            };
+            Ok(response)
+        });
+        Box::new(future)
+    }
+}
+
+/// オブジェクトが割り当てられるセグメントと、そのデータを保持するメンバを返す。
+struct GetObjectPlacement(Server);
+impl HandleRequest for GetObjectPlacement {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/buckets/*/objects/*/placement";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<ObjectPlacement>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let object_id = get_object_id(req.url());
+        let consistency = try_badarg!(get_consistency(req.url()));
+        let future = placement::locate_object(&self.0.client, &bucket_id, object_id, consistency);
+        let future = future.then(|result| {
+            let response = match track!(result) {
+                Ok(placement) => make_json_response(Status::Ok, Ok(placement)),
+                Err(ref e) if *e.kind() == ErrorKind::NotFound => {
+                    make_json_response(Status::NotFound, Err(not_found()))
+                }
+                Err(e) => make_json_response(Status::InternalServerError, Err(e)),
+            };
+            Ok(response)
+        });
+        Box::new(future)
+    }
+}
+
+struct GetDashboard(Server, DashboardTracker);
+impl HandleRequest for GetDashboard {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/dashboard";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<Dashboard>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        let mut bucket_ids = self.0.client.bucket_ids();
+        bucket_ids.sort();
+        let targets = bucket_ids
+            .iter()
+            .filter_map(|bucket_id| self.0.client.bucket(bucket_id).ok())
+            .flat_map(|bucket| {
+                let segments = bucket.segment_count();
+                (0..segments as usize).map(move |segment| (bucket.clone(), segment))
+            })
+            .collect::<Vec<_>>();
+
+        // 一部のセグメントの MDS が応答しなくても、他の指標は返せるようにする
+        let future = futures::stream::iter_ok::<_, Error>(targets)
+            .map(move |(bucket, segment)| {
+                let bucket_id = bucket.id().clone();
+                bucket
+                    .request()
+                    .object_count(segment)
+                    .then(move |result| Ok((bucket_id, result.ok())))
+            })
+            .buffer_unordered(DASHBOARD_OBJECT_COUNT_CONCURRENCY)
+            .fold(HashMap::new(), |mut counts, (bucket_id, count)| {
+                let total = counts.entry(bucket_id).or_insert(Some(0));
+                *total = match (*total, count) {
+                    (Some(x), Some(y)) => Some(x + y),
+                    _ => None,
                 };
-                Ok(response)
+                Ok::<_, Error>(counts)
             });
+
+        let client = self.0.client.clone();
+        let tracker = self.1.clone();
+        let future = future.then(move |result| {
+            let counts = match track!(result) {
+                Err(e) => return Ok(make_json_response(Status::InternalServerError, Err(e))),
+                Ok(counts) => counts,
+            };
+            let buckets = bucket_ids
+                .into_iter()
+                .map(|bucket_id| {
+                    let mut leaders = BTreeMap::new();
+                    let segment_leaders = client.segment_leaders(&bucket_id).unwrap_or_default();
+                    for leader in &segment_leaders {
+                        let addr = leader
+                            .map_or_else(|| "unknown".to_owned(), |n| n.current_addr().to_string());
+                        *leaders.entry(addr).or_insert(0) += 1;
+                    }
+                    BucketDashboard {
+                        operations: tracker.statistics(&bucket_id),
+                        objects: counts.get(&bucket_id).cloned().unwrap_or(Some(0)),
+                        segments: segment_leaders.len() as u16,
+                        leaders,
+                        bucket_id,
+                    }
+                })
+                .collect();
+            let dashboard = Dashboard {
+                window_seconds: dashboard::WINDOW_SECONDS,
+                repair_backlog: dashboard::repair_backlog(),
+                buckets,
+            };
+            Ok(make_json_response(Status::Ok, Ok(dashboard)))
+        });
         Box::new(future)
     }
 }
@@ -189,6 +505,352 @@ impl HandleRequest for GetBucketStatistics {
     }
 }
 
+struct GetBucketUsage(Server);
+impl HandleRequest for GetBucketUsage {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/buckets/*/usage";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<QuotaUsage>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+
+        let segments = if let Some(segments) = self.0.client.segment_count(&bucket_id) {
+            segments
+        } else {
+            return Box::new(futures::finished(make_json_response(
+                Status::NotFound,
+                Err(not_found()),
+            )));
+        };
+
+        // 割り当て量はセグメント毎に適用されているので、全セグメントの分を合算する
+        let client = self.0.client.clone();
+        let future = futures::stream::iter_ok(0..segments)
+            .and_then(move |segment| {
+                let request = client.request(bucket_id.clone());
+                request.quota_usage(segment as usize).map_err(|e| track!(e))
+            })
+            .fold(None, |total: Option<QuotaUsage>, usage| -> Result<_> {
+                Ok(Some(total.map_or(usage, |total| total + usage)))
+            })
+            .then(|result| match track!(result) {
+                Err(e) => Ok(make_json_response(Status::InternalServerError, Err(e))),
+                Ok(usage) => Ok(make_json_response(
+                    Status::Ok,
+                    Ok(usage.unwrap_or_default()),
+                )),
+            });
+        Box::new(future)
+    }
+}
+
+/// バケツの各セグメントのリーダとオブジェクト数を返す。
+struct GetBucketStatus(Server);
+impl HandleRequest for GetBucketStatus {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/buckets/*/status";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<BucketStatus>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let leaders = if let Some(leaders) = self.0.client.segment_leaders(&bucket_id) {
+            leaders
+        } else {
+            return Box::new(futures::finished(make_json_response(
+                Status::NotFound,
+                Err(not_found()),
+            )));
+        };
+
+        // 一部のセグメントの MDS が応答しなくても、他のセグメントの状態は返せるようにする
+        let request = self.0.client.request(bucket_id);
+        let futures = leaders
+            .into_iter()
+            .enumerate()
+            .map(move |(segment, leader)| {
+                request
+                    .object_count(segment)
+                    .then(move |objects| -> Result<_> {
+                        Ok(SegmentSummary {
+                            segment: segment as u16,
+                            leader: leader.map(|n| n.to_string()),
+                            objects: objects.ok(),
+                        })
+                    })
+            })
+            .collect::<Vec<_>>();
+        let future = futures::future::join_all(futures).then(|result| match track!(result) {
+            Err(e) => Ok(make_json_response(Status::InternalServerError, Err(e))),
+            Ok(segments) => Ok(make_json_response(
+                Status::Ok,
+                Ok(BucketStatus::new(segments)),
+            )),
+        });
+        Box::new(future)
+    }
+}
+
+/// セグメントのリーダとオブジェクト数、および各メンバの状態を返す。
+struct GetSegmentStatus(Server);
+impl HandleRequest for GetSegmentStatus {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/buckets/*/segments/*/status";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<SegmentStatus>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let segment = try_badarg!(get_segment_num(req.url()));
+        let leader = match self
+            .0
+            .client
+            .segment_leaders(&bucket_id)
+            .and_then(|leaders| leaders.get(segment as usize).cloned())
+        {
+            None => {
+                return Box::new(futures::finished(make_json_response(
+                    Status::NotFound,
+                    Err(not_found()),
+                )));
+            }
+            Some(leader) => leader,
+        };
+
+        let request = self.0.client.request(bucket_id);
+        let objects = request.object_count(segment as usize).then(|r| Ok(r.ok()));
+        let future = objects
+            .join(request.member_statuses(segment as usize))
+            .then(move |result| match track!(result) {
+                Err(e) => Ok(make_json_response(Status::InternalServerError, Err(e))),
+                Ok((objects, members)) => {
+                    let status = SegmentStatus {
+                        summary: SegmentSummary {
+                            segment,
+                            leader: leader.map(|n| n.to_string()),
+                            objects,
+                        },
+                        members,
+                    };
+                    Ok(make_json_response(Status::Ok, Ok(status)))
+                }
+            });
+        Box::new(future)
+    }
+}
+
+/// このサーバが記録している、バケツの各セグメントのストレージのメンバの健全性を返す.
+///
+/// 値は内容の読み込み先の選択に使われているもので、サーバ毎に異なる.
+struct GetBucketMemberHealth(Server);
+impl HandleRequest for GetBucketMemberHealth {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/buckets/*/health";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<Vec<SegmentMemberHealth>>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let response = if let Some(health) = self.0.client.member_health(&bucket_id) {
+            let health = health
+                .into_iter()
+                .enumerate()
+                .map(|(i, members)| SegmentMemberHealth {
+                    segment: i as u16,
+                    members,
+                })
+                .collect();
+            make_json_response(Status::Ok, Ok(health))
+        } else {
+            make_json_response(Status::NotFound, Err(not_found()))
+        };
+        Box::new(futures::finished(response))
+    }
+}
+
+struct GetBucketCacheStatistics(Server);
+impl HandleRequest for GetBucketCacheStatistics {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/buckets/*/cache";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<Vec<SegmentCacheStatistics>>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let response = if let Some(stats) = self.0.client.content_cache_stats(&bucket_id) {
+            let stats = stats
+                .into_iter()
+                .enumerate()
+                .map(|(i, stats)| SegmentCacheStatistics {
+                    segment: i as u16,
+                    hit_ratio: stats.hit_ratio(),
+                    stats,
+                })
+                .collect();
+            make_json_response(Status::Ok, Ok(stats))
+        } else {
+            make_json_response(Status::NotFound, Err(not_found()))
+        };
+        Box::new(futures::finished(response))
+    }
+}
+
+/// このサーバ上の、バケツの内容のキャッシュを破棄する.
+///
+/// 他のサーバのキャッシュは破棄されない.
+struct DeleteBucketCache(Server);
+impl HandleRequest for DeleteBucketCache {
+    const METHOD: &'static str = "DELETE";
+    const PATH: &'static str = "/v1/buckets/*/cache";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<FlushedContentCache>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let response = if let Some(objects) = self.0.client.flush_content_cache(&bucket_id) {
+            info!(
+                self.0.logger,
+                "Flushed the content cache: {}", dump!(objects);
+                logging::BUCKET => &bucket_id
+            );
+            make_json_response(Status::Ok, Ok(FlushedContentCache { objects }))
+        } else {
+            make_json_response(Status::NotFound, Err(not_found()))
+        };
+        Box::new(futures::finished(response))
+    }
+}
+
+struct GetContentCacheCapacity(Server);
+impl HandleRequest for GetContentCacheCapacity {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/frugalos/content_cache/*";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<ContentCacheCapacity>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let class = try_badarg!(get_cache_class(req.url()));
+        let capacity = ContentCacheCapacity {
+            class,
+            capacity_bytes: self.0.client.content_cache_capacity(class),
+        };
+        Box::new(futures::finished(make_json_response(
+            Status::Ok,
+            Ok(capacity),
+        )))
+    }
+}
+
+/// バケツの種類毎の、セグメント当たりのキャッシュの容量を変更する.
+///
+/// 変更は再起動すると失われるので、永続化したい場合には設定ファイルも更新すること.
+struct PutContentCacheCapacity(Server);
+impl HandleRequest for PutContentCacheCapacity {
+    const METHOD: &'static str = "PUT";
+    const PATH: &'static str = "/v1/frugalos/content_cache/*";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<ContentCacheCapacity>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let class = try_badarg!(get_cache_class(req.url()));
+        let capacity_bytes = try_badarg!(get_capacity_bytes(req.url()));
+        info!(
+            self.0.logger,
+            "Changes the capacity of content caches: class={:?}, capacity_bytes={}",
+            class,
+            capacity_bytes
+        );
+        self.0
+            .client
+            .set_content_cache_capacity(class, capacity_bytes);
+        let capacity = ContentCacheCapacity {
+            class,
+            capacity_bytes,
+        };
+        Box::new(futures::finished(make_json_response(
+            Status::Ok,
+            Ok(capacity),
+        )))
+    }
+}
+
+struct GetMaintenanceState(Server);
+impl HandleRequest for GetMaintenanceState {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/frugalos/maintenance";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<MaintenanceState>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        let state = self.0.maintenance_state();
+        Box::new(futures::finished(make_json_response(Status::Ok, Ok(state))))
+    }
+}
+
+/// メンテナンスウィンドウによるバックグラウンド処理(リペアや segment_gc)の制限を解除ないし再開する.
+///
+/// 緊急のリペアのために`override=true`で解除した場合には、作業後に`override=false`で元に戻すこと.
+/// 変更は再起動すると失われる.
+struct PutMaintenanceState(Server);
+impl HandleRequest for PutMaintenanceState {
+    const METHOD: &'static str = "PUT";
+    const PATH: &'static str = "/v1/frugalos/maintenance";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<MaintenanceState>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let overridden = try_badarg!(get_override(req.url()));
+        info!(
+            self.0.logger,
+            "Changes the override of maintenance windows: {}", overridden
+        );
+        self.0.client.set_maintenance_window_override(overridden);
+        let state = self.0.maintenance_state();
+        Box::new(futures::finished(make_json_response(Status::Ok, Ok(state))))
+    }
+}
+
 struct GetObject(Server);
 impl HandleRequest for GetObject {
     const METHOD: &'static str = "GET";
@@ -219,48 +881,128 @@ impl HandleRequest for GetObject {
         let logger = self.0.logger.clone();
         let expect = try_badarg!(get_expect(&req.header()));
         let deadline = try_badarg!(get_deadline(&req.url()));
+        let priority = try_badarg!(get_priority(&req.header()));
         let consistency = try_badarg!(get_consistency(&req.url()));
-        let future = self
-            .0
-            .client
-            .request(bucket_id)
+        let range = try_badarg!(get_range(&req.header()));
+        let revision = try_badarg!(get_revision_selector(req.url()));
+        if revision.is_some() && range.is_some() {
+            let e = ErrorKind::InvalidInput.cause("Cannot specify both a revision and a range");
+            return Box::new(futures::finished(Res::new(
+                Status::BadRequest,
+                HttpResult::Err(track!(Error::from(e))),
+            )));
+        }
+        let mut request = self.0.client.request(bucket_id);
+        request
             .deadline(deadline)
+            .priority(priority)
             .expect(expect)
-            .span(&span)
-            .get(object_id, consistency)
-            .then(move |result| {
-                let response = match track!(result) {
-                    Ok(None) => {
-                        span.set_tag(|| StdTag::http_status_code(404));
-                        make_object_response(Status::NotFound, None, Err(not_found()))
-                    }
-                    Ok(Some(object)) => {
+            .span(&span);
+        let content_digest = self.0.config.http_server.content_digest;
+        // NOTE: `Content-Range`ヘッダに含めるために、オブジェクト全体の大きさも合わせて扱う
+        let future = if let Some(selector) = revision {
+            span.set_tag(|| Tag::new("revision", format!("{:?}", selector)));
+            Either::A(
+                request
+                    .get_revision(object_id, selector)
+                    .map(|o| o.map(whole_object_range)),
+            )
+        } else if let Some(ref range) = range {
+            span.set_tag(|| Tag::new("range.start", range.start as i64));
+            span.set_tag(|| Tag::new("range.end", range.end as i64));
+            Either::B(Either::A(request.get_range(
+                object_id,
+                range.clone(),
+                consistency,
+            )))
+        } else {
+            Either::B(Either::B(
+                request
+                    .get(object_id, consistency)
+                    .map(|o| o.map(whole_object_range)),
+            ))
+        };
+        let future = future.then(move |result| {
+            let response = match track!(result) {
+                Ok(None) => {
+                    span.set_tag(|| StdTag::http_status_code(404));
+                    make_object_response(Status::NotFound, None, Err(not_found()))
+                }
+                Ok(Some(object)) if range.is_some() => {
+                    let range = range.expect("Never fails");
+                    if range.start >= object.object_size {
+                        span.set_tag(|| StdTag::http_status_code(416));
+                        let e = ErrorKind::InvalidInput
+                            .cause("Range Not Satisfiable")
+                            .into();
+                        let content_range = format!("bytes */{}", object.object_size);
+                        let mut res =
+                            make_object_response(Status::RangeNotSatisfiable, None, Err(e));
+                        res.header_mut().add_field(unsafe {
+                            HeaderField::new_unchecked("Content-Range", &content_range)
+                        });
+                        res
+                    } else {
                         span.set_tag(|| Tag::new("object.size", object.content.len() as i64));
                         span.set_tag(|| Tag::new("object.version", object.version.0 as i64));
-                        span.set_tag(|| StdTag::http_status_code(200));
-                        make_object_response(Status::Ok, Some(object.version), Ok(object.content))
+                        span.set_tag(|| StdTag::http_status_code(206));
+                        let content_range = format!(
+                            "bytes {}-{}/{}",
+                            range.start,
+                            range.start + object.content.len() as u64 - 1,
+                            object.object_size
+                        );
+                        let mut res = make_object_response(
+                            Status::PartialContent,
+                            Some(object.version),
+                            Ok(object.content),
+                        );
+                        res.header_mut().add_field(unsafe {
+                            HeaderField::new_unchecked("Content-Range", &content_range)
+                        });
+                        res
                     }
-                    // NOTE:
-                    // オブジェクトが存在しない場合と、バケツが存在しない(まだ起動処理中かもしれない)は分ける
-                    //
-                    // Err(ref e) if *e.kind() == frugalos::ErrorKind::NotFound => {
-                    //     span.set_tag(|| StdTag::http_status_code(404));
-                    //     make_object_response(Status::NotFound, None, Err(not_found()))
-                    // }
-                    Err(e) => {
+                }
+                Ok(Some(object)) => {
+                    span.set_tag(|| Tag::new("object.size", object.content.len() as i64));
+                    span.set_tag(|| Tag::new("object.version", object.version.0 as i64));
+                    span.set_tag(|| StdTag::http_status_code(200));
+                    let digest = content_digest.map(|a| a.digest(&object.content));
+                    let mut res =
+                        make_object_response(Status::Ok, Some(object.version), Ok(object.content));
+                    if let Some(digest) = digest {
+                        res.header_mut().add_field(unsafe {
+                            HeaderField::new_unchecked(CONTENT_DIGEST_HEADER, &digest.to_string())
+                        });
+                    }
+                    res
+                }
+                // NOTE:
+                // オブジェクトが存在しない場合と、バケツが存在しない(まだ起動処理中かもしれない)は分ける
+                //
+                // Err(ref e) if *e.kind() == frugalos::ErrorKind::NotFound => {
+                //     span.set_tag(|| StdTag::http_status_code(404));
+                //     make_object_response(Status::NotFound, None, Err(not_found()))
+                // }
+                Err(e) => {
+                    if let ErrorKind::Unexpected(version) = *e.kind() {
+                        span.set_tag(|| StdTag::http_status_code(412));
+                        make_object_response(Status::PreconditionFailed, version, Err(e))
+                    } else {
                         warn!(
                             logger,
-                            "Cannot get object (bucket={:?}, object={:?}): {}",
-                            get_bucket_id(req.url()),
-                            get_object_id(req.url()),
-                            e
+                            "Cannot get object: {}",
+                            e;
+                            logging::BUCKET => get_bucket_id(req.url()),
+                            logging::OBJECT_ID => get_object_id(req.url())
                         );
                         span.set_tag(|| StdTag::http_status_code(500));
                         make_object_response(Status::InternalServerError, None, Err(e))
                     }
-                };
-                Ok(response)
-            });
+                }
+            };
+            Ok(response)
+        });
         Box::new(future)
     }
 }
@@ -295,6 +1037,7 @@ impl HandleRequest for HeadObject {
         let logger = self.0.logger.clone();
         let expect = try_badarg!(get_expect(&req.header()));
         let deadline = try_badarg!(get_deadline(&req.url()));
+        let priority = try_badarg!(get_priority(&req.header()));
         let consistency = try_badarg!(get_consistency(&req.url()));
         let check_storage = try_badarg!(get_check_storage(&req.url()));
         let future = if check_storage {
@@ -302,6 +1045,7 @@ impl HandleRequest for HeadObject {
                 .client
                 .request(bucket_id)
                 .deadline(deadline)
+                .priority(priority)
                 .expect(expect)
                 .span(&span)
                 .head_storage(object_id, consistency)
@@ -310,6 +1054,7 @@ impl HandleRequest for HeadObject {
                 .client
                 .request(bucket_id)
                 .deadline(deadline)
+                .priority(priority)
                 .expect(expect)
                 .span(&span)
                 .head(object_id, consistency)
@@ -332,10 +1077,10 @@ impl HandleRequest for HeadObject {
                 Err(e) => {
                     warn!(
                         logger,
-                        "Cannot get object (bucket={:?}, object={:?}): {}",
-                        get_bucket_id(req.url()),
-                        get_object_id(req.url()),
-                        e
+                        "Cannot get object: {}",
+                        e;
+                        logging::BUCKET => get_bucket_id(req.url()),
+                        logging::OBJECT_ID => get_object_id(req.url())
                     );
                     span.set_tag(|| StdTag::http_status_code(500));
                     make_object_response(Status::InternalServerError, None, Err(e))
@@ -375,14 +1120,16 @@ impl HandleRequest for DeleteObject {
         // TODO: deadline and expect
 
         let logger = self.0.logger.clone();
-        let expect = try_badarg!(get_expect(&req.header()));
+        let precondition = try_badarg!(get_precondition(&req.header()));
         let deadline = try_badarg!(get_deadline(&req.url()));
+        let priority = try_badarg!(get_priority(&req.header()));
         let future = self
             .0
             .client
             .request(bucket_id)
             .deadline(deadline)
-            .expect(expect)
+            .priority(priority)
+            .precondition(precondition)
             .span(&span)
             .delete(object_id)
             .then(move |result| {
@@ -404,13 +1151,16 @@ impl HandleRequest for DeleteObject {
                         if let ErrorKind::Unexpected(version) = *e.kind() {
                             span.set_tag(|| StdTag::http_status_code(412));
                             make_object_response(Status::PreconditionFailed, version, Err(e))
+                        } else if *e.kind() == ErrorKind::ReadOnly {
+                            span.set_tag(|| StdTag::http_status_code(403));
+                            make_object_response(Status::Forbidden, None, Err(e))
                         } else {
                             warn!(
                                 logger,
-                                "Cannot delete object (bucket={:?}, object={:?}): {}",
-                                get_bucket_id(req.url()),
-                                get_object_id(req.url()),
-                                e
+                                "Cannot delete object: {}",
+                                e;
+                                logging::BUCKET => get_bucket_id(req.url()),
+                                logging::OBJECT_ID => get_object_id(req.url())
                             );
                             span.set_tag(|| StdTag::http_status_code(500));
                             make_object_response(Status::InternalServerError, None, Err(e))
@@ -423,13 +1173,17 @@ impl HandleRequest for DeleteObject {
     }
 }
 
+/// 接頭辞を指定してオブジェクトを削除する。
+///
+/// `async=true`が指定された場合には、長時間操作として削除を開始し、その状態を`202 Accepted`で返す。
+/// 削除の完了は`GetOperation`で確認する。
 struct DeleteObjectByPrefix(Server);
 impl HandleRequest for DeleteObjectByPrefix {
     const METHOD: &'static str = "DELETE";
     const PATH: &'static str = "/v1/buckets/*/object_prefixes/*";
 
     type ReqBody = ();
-    type ResBody = HttpResult<DeleteObjectsByPrefixSummary>;
+    type ResBody = HttpResult<DeleteObjectsByPrefixResponse>;
     type Decoder = BodyDecoder<NullDecoder>;
     type Encoder = BodyEncoder<AsyncEncoder<JsonEncoder<Self::ResBody>>>;
     type Reply = Reply<Self::ResBody>;
@@ -437,6 +1191,34 @@ impl HandleRequest for DeleteObjectByPrefix {
     fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
         let bucket_id = get_bucket_id(req.url());
         let object_prefix = get_object_prefix(req.url());
+        let deadline = try_badarg!(get_deadline(req.url()));
+        let priority = try_badarg!(get_priority(&req.header()));
+        if try_badarg!(get_async(req.url())) {
+            if self.0.client.segment_count(&bucket_id).is_none() {
+                return Box::new(futures::finished(make_json_response(
+                    Status::NotFound,
+                    Err(not_found()),
+                )));
+            }
+            let client = self.0.client.clone();
+            let target = format!("{}/{}", bucket_id, object_prefix);
+            let status = self
+                .0
+                .operations
+                .start("delete_by_prefix", target, move |operation| {
+                    client
+                        .request(bucket_id)
+                        .deadline(deadline)
+                        .priority(priority)
+                        .operation(&operation)
+                        .delete_by_prefix(ObjectPrefix(object_prefix))
+                });
+            let response = DeleteObjectsByPrefixResponse::Operation(status);
+            return Box::new(futures::finished(make_json_response(
+                Status::Accepted,
+                Ok(response),
+            )));
+        }
 
         let client_span = SpanContext::extract_from_http_header(&TraceHeader(req.header()))
             .ok()
@@ -451,12 +1233,12 @@ impl HandleRequest for DeleteObjectByPrefix {
         span.set_tag(|| Tag::new("object_prefix", object_prefix.clone()));
 
         let logger = self.0.logger.clone();
-        let deadline = try_badarg!(get_deadline(&req.url()));
         let future = self
             .0
             .client
             .request(bucket_id.clone())
             .deadline(deadline)
+            .priority(priority)
             .span(&span)
             .delete_by_prefix(ObjectPrefix(object_prefix.clone()))
             .then(move |result| {
@@ -464,15 +1246,20 @@ impl HandleRequest for DeleteObjectByPrefix {
                     Ok(summary) => {
                         span.set_tag(|| StdTag::http_status_code(200));
                         span.set_tag(|| Tag::new("total", summary.total.to_string()));
-                        make_json_response(Status::Ok, Ok(summary))
+                        let response = DeleteObjectsByPrefixResponse::Summary(summary);
+                        make_json_response(Status::Ok, Ok(response))
+                    }
+                    Err(ref e) if *e.kind() == ErrorKind::ReadOnly => {
+                        span.set_tag(|| StdTag::http_status_code(403));
+                        make_json_response(Status::Forbidden, Err(e.clone()))
                     }
                     Err(e) => {
                         warn!(
                             logger,
-                            "Cannot delete object (bucket={:?}, object_prefix={:?}): {}",
-                            bucket_id,
-                            object_prefix,
-                            e
+                            "Cannot delete objects by prefix: {}",
+                            e;
+                            logging::BUCKET => &bucket_id,
+                            "object_prefix" => &object_prefix
                         );
                         span.set_tag(|| StdTag::http_status_code(500));
                         make_json_response(Status::InternalServerError, Err(e))
@@ -560,35 +1347,65 @@ impl HandleRequest for PutObject {
 
         // TODO: deadline and expect
         let logger = self.0.logger.clone();
-        let expect = try_badarg!(get_expect(&req.header()));
+        let precondition = try_badarg!(get_precondition(&req.header()));
         let deadline = try_badarg!(get_deadline(&req.url()));
+        let priority = try_badarg!(get_priority(&req.header()));
+        if let Some(digest) = try_badarg!(get_content_digest(&req.header())) {
+            if !digest.matches(&content) {
+                span.set_tag(|| StdTag::http_status_code(400));
+                let e = ErrorKind::InvalidInput.cause(format!(
+                    "The content does not match the digest: expected={}, actual={}",
+                    digest,
+                    digest.algorithm().digest(&content)
+                ));
+                return Box::new(futures::finished(make_object_response(
+                    Status::BadRequest,
+                    None,
+                    Err(track!(Error::from(e))),
+                )));
+            }
+        }
         let future = self
             .0
             .client
             .request(bucket_id)
             .deadline(deadline)
-            .expect(expect)
+            .priority(priority)
+            .precondition(precondition)
             .span(&span)
             .put(object_id, content)
             .then(move |result| {
                 let response = match track!(result) {
-                    Ok((version, created)) => {
+                    Ok((version, created, durability)) => {
                         let status = if created { Status::Created } else { Status::Ok };
                         span.set_tag(|| Tag::new("object.version", version.0 as i64));
                         span.set_tag(|| StdTag::http_status_code(status.code()));
-                        make_object_response(status, Some(version), Ok(Vec::new()))
+                        if durability.is_degraded() {
+                            span.set_tag(|| {
+                                Tag::new("failed_fragments", durability.failed_fragments as i64)
+                            });
+                        }
+                        let mut res = make_object_response(status, Some(version), Ok(Vec::new()));
+                        add_durability_headers(&mut res, &durability);
+                        res
                     }
                     Err(e) => {
                         if let ErrorKind::Unexpected(version) = *e.kind() {
                             span.set_tag(|| StdTag::http_status_code(412));
                             make_object_response(Status::PreconditionFailed, version, Err(e))
+                        } else if *e.kind() == ErrorKind::QuotaExceeded {
+                            span.set_tag(|| StdTag::http_status_code(507));
+                            make_object_response(Status::InsufficientStorage, None, Err(e))
+                        } else if *e.kind() == ErrorKind::ReadOnly {
+                            span.set_tag(|| StdTag::http_status_code(403));
+                            make_object_response(Status::Forbidden, None, Err(e))
                         } else {
                             warn!(
                                 logger,
-                                "Cannot put object (bucket={:?}, object={:?}): {}",
-                                get_bucket_id(req.url()),
-                                get_object_id(req.url()),
-                                e
+                                "Cannot put object: {}",
+                                e;
+                                logging::BUCKET => get_bucket_id(req.url()),
+                                logging::OBJECT_ID => get_object_id(req.url())
                             );
                             span.set_tag(|| StdTag::http_status_code(500));
                             make_object_response(Status::InternalServerError, None, Err(e))
@@ -601,6 +1418,513 @@ impl HandleRequest for PutObject {
     }
 }
 
+struct AppendObject(Server);
+impl HandleRequest for AppendObject {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/v1/buckets/*/objects/*";
+
+    type ReqBody = Vec<u8>;
+    type ResBody = HttpResult<Vec<u8>>;
+    type Decoder = BodyDecoder<RemainingBytesDecoder>;
+    type Encoder = BodyEncoder<ObjectResultEncoder>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let object_id = get_object_id(req.url());
+        let (req, content) = req.take_body();
+        if content.len() > MAX_PUT_OBJECT_SIZE {
+            return Box::new(futures::finished(make_object_response(
+                Status::BadRequest,
+                None,
+                Err(track!(ErrorKind::InvalidInput.cause("Too large body size")).into()),
+            )));
+        }
+
+        let client_span = SpanContext::extract_from_http_header(&TraceHeader(req.header()))
+            .ok()
+            .and_then(|c| c);
+        let mut span = self
+            .0
+            .tracer
+            .span(|t| t.span("append_object").child_of(&client_span).start());
+        span.set_tag(|| StdTag::http_method("POST"));
+        span.set_tag(|| Tag::new("bucket.id", bucket_id.clone()));
+        span.set_tag(|| Tag::new("object.id", object_id.clone()));
+        span.set_tag(|| Tag::new("object.size", content.len().to_string()));
+
+        let logger = self.0.logger.clone();
+        let deadline = try_badarg!(get_deadline(req.url()));
+        let priority = try_badarg!(get_priority(&req.header()));
+        let future = self
+            .0
+            .client
+            .request(bucket_id)
+            .deadline(deadline)
+            .priority(priority)
+            .span(&span)
+            .append(object_id, content)
+            .then(move |result| {
+                let response = match track!(result) {
+                    Ok(version) => {
+                        span.set_tag(|| Tag::new("object.version", version.0 as i64));
+                        span.set_tag(|| StdTag::http_status_code(200));
+                        make_object_response(Status::Ok, Some(version), Ok(Vec::new()))
+                    }
+                    Err(ref e) if *e.kind() == ErrorKind::ReadOnly => {
+                        span.set_tag(|| StdTag::http_status_code(403));
+                        make_object_response(Status::Forbidden, None, Err(e.clone()))
+                    }
+                    Err(e) => {
+                        warn!(
+                            logger,
+                            "Cannot append to object: {}",
+                            e;
+                            logging::BUCKET => get_bucket_id(req.url()),
+                            logging::OBJECT_ID => get_object_id(req.url())
+                        );
+                        span.set_tag(|| StdTag::http_status_code(500));
+                        make_object_response(Status::InternalServerError, None, Err(e))
+                    }
+                };
+                Ok(response)
+            });
+        Box::new(future)
+    }
+}
+
+/// 分割アップロードを開始する。
+///
+/// 応答に含まれるアップロード ID を指定して、`PutUploadPart`で部分を順に送信する。
+/// 既に存在するオブジェクトを対象とした場合には、最初の部分の送信が`412 Precondition Failed`で失敗する。
+struct StartUpload(Server);
+impl HandleRequest for StartUpload {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/v1/buckets/*/objects/*/uploads";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<UploadStatus>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = get_bucket_id(req.url());
+        let object_id = get_object_id(req.url());
+        let response = if self.0.client.segment_count(&bucket_id).is_some() {
+            match track!(self.0.uploads.start(bucket_id, object_id)) {
+                Ok(status) => make_json_response(Status::Created, Ok(status)),
+                Err(e) => make_upload_response(Err(e)),
+            }
+        } else {
+            make_json_response(Status::NotFound, Err(not_found()))
+        };
+        Box::new(futures::finished(response))
+    }
+}
+
+/// 分割アップロードの状態(永続化済みの部分の数とバイト数)を返す。
+///
+/// クライアントは、この結果を元に中断したアップロードを再開する。
+struct GetUpload(Server);
+impl HandleRequest for GetUpload {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/uploads/*";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<UploadStatus>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let upload_id = get_upload_id(req.url());
+        let response = make_upload_response(track!(self.0.uploads.status(&upload_id)));
+        Box::new(futures::finished(response))
+    }
+}
+
+/// 分割アップロードの部分を送信する。
+///
+/// 部分は番号(0 始まり)順に送信する必要がある。永続化済みの部分の再送は無視される。
+struct PutUploadPart(Server);
+impl HandleRequest for PutUploadPart {
+    const METHOD: &'static str = "PUT";
+    const PATH: &'static str = "/v1/uploads/*/parts/*";
+
+    type ReqBody = Vec<u8>;
+    type ResBody = HttpResult<UploadStatus>;
+    type Decoder = BodyDecoder<RemainingBytesDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let upload_id = get_upload_id(req.url());
+        let part = try_badarg!(get_upload_part(req.url()));
+        let (req, content) = req.take_body();
+        if content.len() > MAX_PUT_OBJECT_SIZE {
+            let e = track!(ErrorKind::InvalidInput.cause("Too large body size")).into();
+            return Box::new(futures::finished(make_json_response(
+                Status::BadRequest,
+                Err(e),
+            )));
+        }
+        let deadline = try_badarg!(get_deadline(req.url()));
+        let priority = try_badarg!(get_priority(&req.header()));
+
+        let (bucket_id, object_id, append_to) =
+            match track!(self.0.uploads.begin_part(&upload_id, part)) {
+                Err(e) => return Box::new(futures::finished(make_upload_response(Err(e)))),
+                Ok(PartWrite::Done(status)) => {
+                    return Box::new(futures::finished(make_upload_response(Ok(status))));
+                }
+                Ok(PartWrite::Required {
+                    bucket_id,
+                    object_id,
+                    append_to,
+                }) => (bucket_id, object_id, append_to),
+            };
+        let size = content.len() as u64;
+        let mut request = self.0.client.request(bucket_id);
+        request.deadline(deadline).priority(priority);
+        let future = if let Some(version) = append_to {
+            Either::A(
+                request
+                    .expect(Expect::IfMatch(vec![version]))
+                    .append(object_id, content),
+            )
+        } else {
+            // 既存のオブジェクトを上書きしないように、存在しない場合にのみ作成する
+            Either::B(
+                request
+                    .expect(Expect::None)
+                    .put(object_id, content)
+                    .map(|(version, _, _)| version),
+            )
+        };
+
+        let logger = self.0.logger.clone();
+        let uploads = self.0.uploads.clone();
+        let future = future.then(move |result| {
+            let written = result.as_ref().ok().map(|&version| (version, size));
+            let status = uploads.end_part(&upload_id, written);
+            let result = track!(result).and_then(|_| track!(status));
+            if let Err(ref e) = result {
+                warn!(
+                    logger,
+                    "Cannot write a part of the upload (upload_id={:?}, part={}): {}",
+                    upload_id,
+                    part,
+                    e
+                );
+            }
+            Ok(make_upload_response(result))
+        });
+        Box::new(future)
+    }
+}
+
+/// 分割アップロードを完了する。
+///
+/// 作成されたオブジェクトはそのまま残る。
+struct CompleteUpload(Server);
+impl HandleRequest for CompleteUpload {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/v1/uploads/*/complete";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<UploadStatus>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let upload_id = get_upload_id(req.url());
+        let response = make_upload_response(track!(self.0.uploads.complete(&upload_id)));
+        Box::new(futures::finished(response))
+    }
+}
+
+/// 分割アップロードを中止して、作成途中のオブジェクトを削除する。
+struct AbortUpload(Server);
+impl HandleRequest for AbortUpload {
+    const METHOD: &'static str = "DELETE";
+    const PATH: &'static str = "/v1/uploads/*";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<UploadStatus>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let upload_id = get_upload_id(req.url());
+        let status = match track!(self.0.uploads.abort(&upload_id)) {
+            Err(e) => return Box::new(futures::finished(make_upload_response(Err(e)))),
+            Ok(status) => status,
+        };
+        let future = upload::delete_partial_object(&self.0.client, &status)
+            .then(move |result| Ok(make_upload_response(track!(result).map(|_| status))));
+        Box::new(future)
+    }
+}
+
+/// 接頭辞指定でのオブジェクト削除の応答。
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum DeleteObjectsByPrefixResponse {
+    /// 削除が完了した場合の結果。
+    Summary(DeleteObjectsByPrefixSummary),
+
+    /// 長時間操作として開始した場合の、操作の状態。
+    Operation(OperationStatus),
+}
+
+/// 障害後のオブジェクトの可用性の調査を、長時間操作として開始し、その状態を`202 Accepted`で返す.
+///
+/// クエリの`bucket`(複数指定可)で対象を限定できる. 省略時はメタデータ用以外の全てのバケツが対象となる.
+/// 調査結果(`AvailabilityReport`)は、操作の完了後に`GetOperation`の`result`から取得する.
+struct StartAvailabilitySurvey(Server);
+impl HandleRequest for StartAvailabilitySurvey {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/v1/availability_surveys";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<OperationStatus>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_ids = get_bucket_ids(req.url());
+        let mut options = try_badarg!(get_availability_options(req.url()));
+        options.priority = try_badarg!(get_priority(&req.header()));
+        let buckets = match track!(availability::select_buckets(&self.0.client, &bucket_ids)) {
+            Ok(buckets) => buckets,
+            Err(e) => {
+                let status = if *e.kind() == ErrorKind::NotFound {
+                    Status::NotFound
+                } else {
+                    Status::BadRequest
+                };
+                return Box::new(futures::finished(make_json_response(status, Err(e))));
+            }
+        };
+        let target = if bucket_ids.is_empty() {
+            "*".to_owned()
+        } else {
+            bucket_ids.join(",")
+        };
+        info!(
+            self.0.logger,
+            "Starts an availability survey: buckets={:?}, options={:?}", target, options
+        );
+        let status = self
+            .0
+            .operations
+            .start("availability_survey", target, move |operation| {
+                availability::survey_buckets(buckets, options, operation)
+            });
+        Box::new(futures::finished(make_json_response(
+            Status::Accepted,
+            Ok(status),
+        )))
+    }
+}
+
+/// 長時間操作の一覧を返す。
+struct ListOperations(Server);
+impl HandleRequest for ListOperations {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/operations";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<Vec<OperationStatus>>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        let operations = self.0.operations.list();
+        Box::new(futures::finished(make_json_response(
+            Status::Ok,
+            Ok(operations),
+        )))
+    }
+}
+
+/// 長時間操作の状態と進捗を返す。
+struct GetOperation(Server);
+impl HandleRequest for GetOperation {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/operations/*";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<OperationStatus>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let operation_id = get_operation_id(req.url());
+        let response = make_operation_response(track!(self.0.operations.status(&operation_id)));
+        Box::new(futures::finished(response))
+    }
+}
+
+/// 実行中の長時間操作をキャンセルする。
+struct CancelOperation(Server);
+impl HandleRequest for CancelOperation {
+    const METHOD: &'static str = "DELETE";
+    const PATH: &'static str = "/v1/operations/*";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<OperationStatus>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let operation_id = get_operation_id(req.url());
+        let result = track!(self.0.operations.cancel(&operation_id));
+        if let Ok(ref status) = result {
+            info!(
+                self.0.logger,
+                "Cancelled an operation: operation_id={:?}, kind={:?}, target={:?}",
+                status.operation_id,
+                status.kind,
+                status.target
+            );
+        }
+        Box::new(futures::finished(make_operation_response(result)))
+    }
+}
+
+/// 指定のオブジェクトないしトレースに対するリクエストの記録を開始し、`201 Created`で記録の状態を返す。
+///
+/// クエリの`object_id`と`trace_id`のいずれか一方で対象を指定する.
+/// `duration`(秒)を指定すると、記録期間を変更できる(最大で`MAX_CAPTURE_DURATION`).
+/// 記録はこのサーバで処理されたリクエストのみが対象となる.
+///
+/// 記録されるのはトレースされたリクエストのみなので、サンプリングレートが低い場合には
+/// `sample_all=true`を指定して、記録中は全てのリクエストをトレースさせる必要がある.
+struct StartCapture(Server);
+impl HandleRequest for StartCapture {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/v1/captures";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<CaptureBundle>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let (target, duration, sample_all) = try_badarg!(get_capture_options(req.url()));
+        let result = track!(self.0.captures.start(target, duration, sample_all));
+        let status = match result {
+            Ok(ref bundle) => {
+                info!(
+                    self.0.logger,
+                    "Starts capturing requests: capture_id={:?}, target={:?}, duration={:?}, sample_all={}",
+                    bundle.capture_id,
+                    bundle.target,
+                    duration,
+                    bundle.sample_all
+                );
+                Status::Created
+            }
+            Err(_) => Status::Conflict,
+        };
+        Box::new(futures::finished(make_json_response(status, result)))
+    }
+}
+
+/// 記録の現在の状態と、記録されたリクエストのスパン群を返す。
+struct GetCapture(Server);
+impl HandleRequest for GetCapture {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/captures/*";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<CaptureBundle>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let capture_id = get_capture_id(req.url());
+        let response = make_capture_response(track!(self.0.captures.get(&capture_id)));
+        Box::new(futures::finished(response))
+    }
+}
+
+/// 記録を終了して破棄し、最終的な状態を返す。
+struct DeleteCapture(Server);
+impl HandleRequest for DeleteCapture {
+    const METHOD: &'static str = "DELETE";
+    const PATH: &'static str = "/v1/captures/*";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<CaptureBundle>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let capture_id = get_capture_id(req.url());
+        let result = track!(self.0.captures.remove(&capture_id));
+        if let Ok(ref bundle) = result {
+            info!(
+                self.0.logger,
+                "Stopped capturing requests: capture_id={:?}, traces={}",
+                bundle.capture_id,
+                bundle.traces.len()
+            );
+        }
+        Box::new(futures::finished(make_capture_response(result)))
+    }
+}
+
+fn make_capture_response(result: Result<CaptureBundle>) -> Res<HttpResult<CaptureBundle>> {
+    let status = match result {
+        Ok(_) => Status::Ok,
+        Err(ref e) if *e.kind() == ErrorKind::NotFound => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    };
+    make_json_response(status, result)
+}
+
+fn make_operation_response(result: Result<OperationStatus>) -> Res<HttpResult<OperationStatus>> {
+    let status = match result {
+        Ok(_) => Status::Ok,
+        Err(ref e) => match *e.kind() {
+            ErrorKind::NotFound => Status::NotFound,
+            ErrorKind::InvalidInput => Status::Conflict,
+            _ => Status::InternalServerError,
+        },
+    };
+    make_json_response(status, result)
+}
+
+fn make_upload_response(result: Result<UploadStatus>) -> Res<HttpResult<UploadStatus>> {
+    let status = match result {
+        Ok(_) => Status::Ok,
+        Err(ref e) => match *e.kind() {
+            ErrorKind::NotFound => Status::NotFound,
+            ErrorKind::InvalidInput => Status::Conflict,
+            ErrorKind::Unexpected(_) => Status::PreconditionFailed,
+            ErrorKind::QuotaExceeded => Status::InsufficientStorage,
+            ErrorKind::ReadOnly => Status::Forbidden,
+            ErrorKind::Unauthenticated => Status::Unauthorized,
+            ErrorKind::PermissionDenied => Status::Forbidden,
+            ErrorKind::Busy(_) => Status::ServiceUnavailable,
+            ErrorKind::Other => Status::InternalServerError,
+        },
+    };
+    make_json_response(status, result)
+}
+
 struct JemallocStats;
 impl HandleRequest for JemallocStats {
     const METHOD: &'static str = "GET";
@@ -636,16 +1960,104 @@ impl HandleRequest for CurrentConfigurations {
     type Reply = Reply<Self::ResBody>;
 
     fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
-        let response = make_json_response(Status::Ok, Ok(self.0.clone()));
+        let mut config = self.0.clone();
+        for token in config.auth.tokens.values_mut() {
+            *token = "<redacted>".to_owned();
+        }
+        let response = make_json_response(Status::Ok, Ok(config));
+        Box::new(futures::finished(response))
+    }
+}
+
+/// デーモン内で動作しているタスク群(MDSノードや同期処理等)の現在の状態を返す。
+struct TaskDump;
+impl HandleRequest for TaskDump {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/frugalos/tasks";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<Vec<TaskSnapshot>>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        let response = make_json_response(Status::Ok, Ok(task_dump::dump()));
         Box::new(futures::finished(response))
     }
 }
 
-pub fn spawn_report_spans_thread(rx: SpanReceiver) {
-    let reporter = track_try_unwrap!(JaegerCompactReporter::new("frugalos"));
+/// スパンの送信先。
+pub enum SpanExporter {
+    /// ローカルの Jaeger エージェント。
+    Jaeger(JaegerCompactReporter),
+
+    /// OTLP を受け付けるコレクタ。
+    Otlp(OtlpReporter),
+}
+impl SpanExporter {
+    /// 設定に従って、スパンの送信先を生成する。
+    pub fn new(config: &FrugalosTracingConfig) -> Result<Self> {
+        match config.exporter {
+            TracingExporterConfig::Jaeger => {
+                let reporter = track!(JaegerCompactReporter::new("frugalos")
+                    .map_err(|e| ErrorKind::Other.takes_over(e)))?;
+                Ok(SpanExporter::Jaeger(reporter))
+            }
+            TracingExporterConfig::Otlp(ref c) => {
+                let reporter = track!(
+                    OtlpReporter::new(c).map_err(|e| ErrorKind::InvalidInput.takes_over(e))
+                )?;
+                Ok(SpanExporter::Otlp(reporter))
+            }
+        }
+    }
+
+    fn report(&self, spans: Vec<FinishedSpan>) {
+        match *self {
+            SpanExporter::Jaeger(ref r) => {
+                // 一回の報告は一つの UDP パケットで送られ、大きさの上限を超えると破棄されるので、
+                // スパン毎に報告する
+                for span in spans.chunks(1) {
+                    let _ = r.report(span);
+                }
+            }
+            SpanExporter::Otlp(ref r) => r.report(spans),
+        }
+    }
+}
+
+/// `tail_sampling`が指定された場合には、その判定を経たスパンのみを報告する。
+///
+/// 受信済みのスパンはまとめて`exporter`に渡される。
+pub fn spawn_report_spans_thread(
+    rx: SpanReceiver,
+    captures: RequestCaptures,
+    mut tail_sampling: Option<TailSampling>,
+    exporter: SpanExporter,
+) {
     thread::spawn(move || {
         while let Ok(span) = rx.recv() {
-            let _ = reporter.report(&[span]);
+            let mut received = vec![span];
+            while received.len() < otlp::MAX_SPANS_PER_REQUEST {
+                match rx.try_recv() {
+                    Ok(span) => received.push(span),
+                    Err(_) => break,
+                }
+            }
+
+            let mut spans = Vec::with_capacity(received.len());
+            for span in received {
+                captures.observe(&span);
+                if let Some(ref mut tail_sampling) = tail_sampling {
+                    spans.extend(tail_sampling.observe(span));
+                } else {
+                    spans.push(span);
+                }
+            }
+            if !spans.is_empty() {
+                exporter.report(spans);
+            }
         }
     });
 }
@@ -671,6 +2083,39 @@ fn get_object_id(url: &Url) -> String {
         .to_string()
 }
 
+fn get_upload_id(url: &Url) -> String {
+    url.path_segments()
+        .expect("Never fails")
+        .nth(2)
+        .expect("Never fails")
+        .to_string()
+}
+
+fn get_capture_id(url: &Url) -> String {
+    url.path_segments()
+        .expect("Never fails")
+        .nth(2)
+        .expect("Never fails")
+        .to_string()
+}
+
+fn get_operation_id(url: &Url) -> String {
+    url.path_segments()
+        .expect("Never fails")
+        .nth(2)
+        .expect("Never fails")
+        .to_string()
+}
+
+fn get_upload_part(url: &Url) -> Result<u64> {
+    let part = url
+        .path_segments()
+        .expect("Never fails")
+        .nth(4)
+        .expect("Never fails");
+    track!(part.parse().map_err(Error::from))
+}
+
 fn get_object_prefix(url: &Url) -> String {
     url.path_segments()
         .expect("Never fails")
@@ -690,6 +2135,25 @@ fn get_segment_num(url: &Url) -> Result<u16> {
     Ok(n)
 }
 
+/// `since`と`until`(UNIXエポックからの秒数)で指定された期間を返す.
+///
+/// どちらも指定されていない場合は`None`となる.
+fn get_time_range(url: &Url) -> Result<Option<Range<u64>>> {
+    let mut since = None;
+    let mut until = None;
+    for (k, v) in url.query_pairs() {
+        match k.as_ref() {
+            "since" => since = Some(track!(v.parse().map_err(Error::from))?),
+            "until" => until = Some(track!(v.parse().map_err(Error::from))?),
+            _ => {}
+        }
+    }
+    if since.is_none() && until.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(since.unwrap_or(0)..until.unwrap_or(u64::MAX)))
+}
+
 fn get_expect(header: &Header) -> Result<Expect> {
     for field in header.fields() {
         if field.name().eq_ignore_ascii_case("if-match") {
@@ -711,6 +2175,135 @@ fn get_expect(header: &Header) -> Result<Expect> {
     Ok(Expect::Any)
 }
 
+/// 書き込み系のリクエストのヘッダから、前提条件を取り出す。
+///
+/// `If-Match`と`If-None-Match`に加えて、以下の独自ヘッダに対応している:
+///
+/// - `X-Frugalos-If-Match-Range: "FROM", "TO"`: バージョンが`FROM`以上`TO`未満の場合
+/// - `X-Frugalos-If-Newer-Than: "VERSION"`: バージョンが`VERSION`よりも新しい場合
+/// - `X-Frugalos-If-Unmodified-Since: SECONDS`: UNIXエポックからの秒数で指定した時刻以降に更新されていない場合
+fn get_precondition(header: &Header) -> Result<Precondition> {
+    for field in header.fields() {
+        if let Some(precondition) = track!(parse_precondition_field(field.name(), field.value()))? {
+            return Ok(precondition);
+        }
+    }
+    track!(get_expect(header)).map(Precondition::from)
+}
+
+fn parse_precondition_field(name: &str, value: &str) -> Result<Option<Precondition>> {
+    if name.eq_ignore_ascii_case("x-frugalos-if-match-range") {
+        let versions = track!(parse_etag_values(value))?;
+        track_assert_eq!(
+            versions.len(),
+            2,
+            ErrorKind::InvalidInput,
+            "value={:?}",
+            value
+        );
+        Ok(Some(Precondition::IfMatchRange {
+            from: versions[0],
+            to: versions[1],
+        }))
+    } else if name.eq_ignore_ascii_case("x-frugalos-if-newer-than") {
+        let versions = track!(parse_etag_values(value))?;
+        track_assert_eq!(
+            versions.len(),
+            1,
+            ErrorKind::InvalidInput,
+            "value={:?}",
+            value
+        );
+        Ok(Some(Precondition::IfNewerThan(versions[0])))
+    } else if name.eq_ignore_ascii_case("x-frugalos-if-unmodified-since") {
+        let since = track!(value.trim().parse().map_err(Error::from))?;
+        Ok(Some(Precondition::IfUnmodifiedSince(since)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// `X-Frugalos-Content-Digest`ヘッダから、書き込まれる内容のダイジェストを取り出す。
+///
+/// ダイジェストは`{アルゴリズムの識別子}:{16進数表記}`の形式(e.g., `sha256:ba7816bf...`)で指定する。
+fn get_content_digest(header: &Header) -> Result<Option<Digest>> {
+    for field in header.fields() {
+        if field.name().eq_ignore_ascii_case(CONTENT_DIGEST_HEADER) {
+            return track!(parse_content_digest(field.value())).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+fn parse_content_digest(s: &str) -> Result<Digest> {
+    let digest = track!(s
+        .trim()
+        .parse()
+        .map_err(|e: ParseChecksumError| ErrorKind::InvalidInput.cause(e.to_string())))?;
+    Ok(digest)
+}
+
+/// オブジェクト全体を、その全範囲を表す`ObjectRange`に変換する。
+fn whole_object_range(object: ObjectValue) -> ObjectRange {
+    ObjectRange {
+        version: object.version,
+        object_size: object.content.len() as u64,
+        content: object.content,
+    }
+}
+
+/// `Range`ヘッダから、取得対象のバイト範囲を取り出す。
+///
+/// 単一の範囲(`bytes=START-END`および`bytes=START-`)のみに対応している。
+fn get_range(header: &Header) -> Result<Option<Range<u64>>> {
+    for field in header.fields() {
+        if field.name().eq_ignore_ascii_case("range") {
+            return track!(parse_range_value(field.value())).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+fn parse_range_value(s: &str) -> Result<Range<u64>> {
+    let s = s.trim();
+    track_assert!(
+        s.starts_with("bytes="),
+        ErrorKind::InvalidInput,
+        "Unsupported range unit: {:?}",
+        s
+    );
+    let spec = &s["bytes=".len()..];
+    track_assert!(
+        !spec.contains(','),
+        ErrorKind::InvalidInput,
+        "Multiple ranges are unsupported: {:?}",
+        s
+    );
+    let mut tokens = spec.splitn(2, '-');
+    let start = tokens.next().unwrap_or("").trim();
+    let end = tokens.next().unwrap_or("").trim();
+    track_assert!(
+        !start.is_empty(),
+        ErrorKind::InvalidInput,
+        "Suffix ranges are unsupported: {:?}",
+        s
+    );
+    let start: u64 = track!(start.parse().map_err(Error::from))?;
+    let end = if end.is_empty() {
+        u64::MAX
+    } else {
+        let end: u64 = track!(end.parse().map_err(Error::from))?;
+        track_assert!(
+            start <= end,
+            ErrorKind::InvalidInput,
+            "Invalid range: {:?}",
+            s
+        );
+        end.saturating_add(1)
+    };
+    Ok(start..end)
+}
+
 fn parse_etag_values(s: &str) -> Result<Vec<ObjectVersion>> {
     let mut versions = Vec::new();
     for token in s.split(',') {
@@ -729,14 +2322,36 @@ fn parse_etag_values(s: &str) -> Result<Vec<ObjectVersion>> {
     Ok(versions)
 }
 
-fn get_deadline(url: &Url) -> Result<Deadline> {
+/// `X-Frugalos-Priority`ヘッダから、リクエストの優先度クラスを取り出す。
+///
+/// ヘッダが存在しない場合には`RequestPriority::Normal`が使われる。
+fn get_priority(header: &Header) -> Result<RequestPriority> {
+    for field in header.fields() {
+        if field.name().eq_ignore_ascii_case("x-frugalos-priority") {
+            return track!(parse_priority_value(field.value()));
+        }
+    }
+    Ok(RequestPriority::Normal)
+}
+
+fn parse_priority_value(s: &str) -> Result<RequestPriority> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "realtime" => Ok(RequestPriority::Realtime),
+        "normal" => Ok(RequestPriority::Normal),
+        "bulk" => Ok(RequestPriority::Bulk),
+        _ => track_panic!(ErrorKind::InvalidInput, "Unknown priority: {:?}", s),
+    }
+}
+
+/// クエリの`deadline`(ミリ秒)を取り出す。指定がない場合はバケツのデフォルト値が使われる。
+fn get_deadline(url: &Url) -> Result<Option<Deadline>> {
     for (k, v) in url.query_pairs() {
         if k == "deadline" {
             let n: u64 = track!(v.parse().map_err(Error::from))?;
-            return Ok(Deadline::Within(Duration::from_millis(n)));
+            return Ok(Some(Deadline::Within(Duration::from_millis(n))));
         }
     }
-    Ok(Deadline::Within(Duration::from_secs(5)))
+    Ok(None)
 }
 
 fn get_subset(url: &Url) -> Result<usize> {
@@ -748,7 +2363,8 @@ fn get_subset(url: &Url) -> Result<usize> {
     Ok(1)
 }
 
-fn get_consistency(url: &Url) -> Result<ReadConsistency> {
+/// クエリの`consistency`を取り出す。指定がない場合はバケツのデフォルト値が使われる。
+fn get_consistency(url: &Url) -> Result<Option<ReadConsistency>> {
     for (k, v) in url.query_pairs() {
         if k == "consistency" {
             let consistency = match v.as_ref() {
@@ -763,10 +2379,148 @@ fn get_consistency(url: &Url) -> Result<ReadConsistency> {
                     .cause(format!("Undefined consistency level: {}", v))
                     .into()),
             };
-            return consistency;
+            return consistency.map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// 過去のバージョンの指定を、クエリの`version`(ETag と同様の16進数)ないし`as_of`(UNIXエポックからの秒数)から取り出す。
+fn get_revision_selector(url: &Url) -> Result<Option<RevisionSelector>> {
+    let mut selector = None;
+    for (k, v) in url.query_pairs() {
+        let s = match k.as_ref() {
+            "version" => {
+                let version = track!(u64::from_str_radix(&v, 16).map_err(Error::from))?;
+                RevisionSelector::Version(ObjectVersion(version))
+            }
+            "as_of" => RevisionSelector::AsOf(track!(v.parse().map_err(Error::from))?),
+            _ => continue,
+        };
+        track_assert!(
+            selector.is_none(),
+            ErrorKind::InvalidInput,
+            "Cannot specify both `version` and `as_of`"
+        );
+        selector = Some(s);
+    }
+    Ok(selector)
+}
+
+fn get_cache_class(url: &Url) -> Result<CacheClass> {
+    let class = url
+        .path_segments()
+        .expect("Never fails")
+        .nth(3)
+        .expect("Never fails");
+    match class {
+        "replicated" => Ok(CacheClass::Replicated),
+        "dispersed" => Ok(CacheClass::Dispersed),
+        _ => track_panic!(ErrorKind::InvalidInput, "Unknown cache class: {:?}", class),
+    }
+}
+
+fn get_capacity_bytes(url: &Url) -> Result<u64> {
+    for (k, v) in url.query_pairs() {
+        if k == "capacity_bytes" {
+            return track!(v.parse().map_err(Error::from));
+        }
+    }
+    track_panic!(ErrorKind::InvalidInput, "`capacity_bytes` is required")
+}
+
+fn get_override(url: &Url) -> Result<bool> {
+    for (k, v) in url.query_pairs() {
+        if k == "override" {
+            return track!(v.parse().map_err(Error::from));
+        }
+    }
+    track_panic!(ErrorKind::InvalidInput, "`override` is required")
+}
+
+/// クエリの`bucket`を全て取り出す.
+fn get_bucket_ids(url: &Url) -> Vec<String> {
+    url.query_pairs()
+        .filter(|(k, _)| k == "bucket")
+        .map(|(_, v)| v.into_owned())
+        .collect()
+}
+
+/// クエリの`concurrency`、`max_lost_objects`および`deadline`から、可用性の調査方法を取り出す.
+fn get_availability_options(url: &Url) -> Result<AvailabilityOptions> {
+    let mut options = AvailabilityOptions::default();
+    for (k, v) in url.query_pairs() {
+        match k.as_ref() {
+            "concurrency" => {
+                options.concurrency = track!(v.parse().map_err(Error::from))?;
+                track_assert_ne!(options.concurrency, 0, ErrorKind::InvalidInput);
+            }
+            "max_lost_objects" => {
+                options.max_lost_objects = track!(v.parse().map_err(Error::from))?;
+            }
+            _ => {}
+        }
+    }
+    options.deadline = track!(get_deadline(url))?;
+    Ok(options)
+}
+
+/// クエリの`object_id`ないし`trace_id`と`duration`から、リクエストの記録対象と期間を取り出す.
+fn get_capture_options(url: &Url) -> Result<(CaptureTarget, Duration, bool)> {
+    let mut target = None;
+    let mut duration = DEFAULT_CAPTURE_DURATION;
+    let mut sample_all = false;
+    for (k, v) in url.query_pairs() {
+        match k.as_ref() {
+            "object_id" => {
+                track_assert!(
+                    target.is_none(),
+                    ErrorKind::InvalidInput,
+                    "Duplicate target"
+                );
+                target = Some(CaptureTarget::ObjectId(v.into_owned()));
+            }
+            "trace_id" => {
+                track_assert!(
+                    target.is_none(),
+                    ErrorKind::InvalidInput,
+                    "Duplicate target"
+                );
+                target = Some(track!(CaptureTarget::trace_id(&v))?);
+            }
+            "duration" => {
+                let secs: u64 = track!(v.parse().map_err(Error::from))?;
+                track_assert_ne!(secs, 0, ErrorKind::InvalidInput);
+                duration = Duration::from_secs(secs);
+                track_assert!(
+                    duration <= MAX_CAPTURE_DURATION,
+                    ErrorKind::InvalidInput,
+                    "Too long duration: max={:?}",
+                    MAX_CAPTURE_DURATION
+                );
+            }
+            "sample_all" => {
+                sample_all = track!(v.parse().map_err(Error::from))?;
+            }
+            _ => {}
+        }
+    }
+    let target = track_assert_some!(
+        target,
+        ErrorKind::InvalidInput,
+        "Either `object_id` or `trace_id` must be specified"
+    );
+    Ok((target, duration, sample_all))
+}
+
+fn get_async(url: &Url) -> Result<bool> {
+    for (k, v) in url.query_pairs() {
+        if k == "async" {
+            let b: bool = track!(v.parse().map_err(Error::from))?;
+            return Ok(b);
         }
     }
-    Ok(Default::default())
+    Ok(false)
 }
 
 fn get_check_storage(url: &Url) -> Result<bool> {
@@ -782,6 +2536,7 @@ fn get_check_storage(url: &Url) -> Result<bool> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use frugalos_core::checksum::ChecksumAlgorithm;
     use std::str::FromStr;
     use trackable::result::TestResult;
 
@@ -799,23 +2554,241 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn bucket_status_works() {
+        let summary = |segment, objects| SegmentSummary {
+            segment,
+            leader: None,
+            objects,
+        };
+        let status = BucketStatus::new(vec![summary(0, Some(3)), summary(1, Some(4))]);
+        assert_eq!(status.objects, Some(7));
+
+        // 一つでも取得できなかったセグメントがあれば、合計は分からない
+        let status = BucketStatus::new(vec![summary(0, Some(3)), summary(1, None)]);
+        assert_eq!(status.objects, None);
+        assert_eq!(status.segments.len(), 2);
+
+        let status = SegmentStatus {
+            summary: summary(1, Some(4)),
+            members: Vec::new(),
+        };
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["segment"], 1);
+        assert_eq!(json["objects"], 4);
+        assert!(json["leader"].is_null());
+    }
+
+    #[test]
+    fn get_availability_options_works() -> TestResult {
+        let url = Url::from_str("http://example.com/v1/availability_surveys").unwrap();
+        assert!(get_bucket_ids(&url).is_empty());
+        let options = track!(get_availability_options(&url))?;
+        assert_eq!(options.concurrency, availability::DEFAULT_CONCURRENCY);
+        assert_eq!(options.deadline, None);
+
+        let url = Url::from_str(
+            "http://example.com/?bucket=foo&bucket=bar&concurrency=4&max_lost_objects=10&deadline=500",
+        )
+        .unwrap();
+        assert_eq!(get_bucket_ids(&url), vec!["foo", "bar"]);
+        let options = track!(get_availability_options(&url))?;
+        assert_eq!(options.concurrency, 4);
+        assert_eq!(options.max_lost_objects, 10);
+        assert_eq!(
+            options.deadline,
+            Some(Deadline::Within(Duration::from_millis(500)))
+        );
+
+        let url = Url::from_str("http://example.com/?concurrency=0").unwrap();
+        assert!(get_availability_options(&url).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn get_capture_options_works() -> TestResult {
+        let url = Url::from_str("http://example.com/v1/captures?object_id=foo").unwrap();
+        let (target, duration, sample_all) = track!(get_capture_options(&url))?;
+        assert_eq!(target, CaptureTarget::ObjectId("foo".to_owned()));
+        assert_eq!(duration, DEFAULT_CAPTURE_DURATION);
+        assert!(!sample_all);
+
+        let url =
+            Url::from_str("http://example.com/?trace_id=00ab&duration=30&sample_all=true").unwrap();
+        let (target, duration, sample_all) = track!(get_capture_options(&url))?;
+        assert_eq!(target, CaptureTarget::TraceId("ab".to_owned()));
+        assert_eq!(duration, Duration::from_secs(30));
+        assert!(sample_all);
+
+        for query in &[
+            "",
+            "?object_id=foo&trace_id=ab",
+            "?object_id=foo&duration=0",
+            "?object_id=foo&duration=18446744073709551615",
+            "?object_id=foo&sample_all=yes",
+        ] {
+            let url = Url::from_str(&format!("http://example.com/{}", query)).unwrap();
+            assert!(get_capture_options(&url).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn get_revision_selector_works() -> TestResult {
+        let url = Url::from_str("http://example.com/").unwrap();
+        assert_eq!(track!(get_revision_selector(&url))?, None);
+        let url = Url::from_str("http://example.com/?version=1f").unwrap();
+        assert_eq!(
+            track!(get_revision_selector(&url))?,
+            Some(RevisionSelector::Version(ObjectVersion(31)))
+        );
+        let url = Url::from_str("http://example.com/?as_of=1500000000").unwrap();
+        assert_eq!(
+            track!(get_revision_selector(&url))?,
+            Some(RevisionSelector::AsOf(1_500_000_000))
+        );
+        let url = Url::from_str("http://example.com/?version=1&as_of=1").unwrap();
+        assert!(get_revision_selector(&url).is_err());
+        let url = Url::from_str("http://example.com/?as_of=yesterday").unwrap();
+        assert!(get_revision_selector(&url).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn get_time_range_works() -> TestResult {
+        let url = Url::from_str("http://example.com/").unwrap();
+        assert_eq!(track!(get_time_range(&url))?, None);
+        let url = Url::from_str("http://example.com/?since=10&until=20").unwrap();
+        assert_eq!(track!(get_time_range(&url))?, Some(10..20));
+        let url = Url::from_str("http://example.com/?since=10").unwrap();
+        assert_eq!(track!(get_time_range(&url))?, Some(10..u64::MAX));
+        let url = Url::from_str("http://example.com/?until=yesterday").unwrap();
+        assert!(get_time_range(&url).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn content_cache_parameters_work() -> TestResult {
+        let url = Url::from_str(
+            "http://example.com/v1/frugalos/content_cache/dispersed?capacity_bytes=1024",
+        )
+        .unwrap();
+        assert_eq!(track!(get_cache_class(&url))?, CacheClass::Dispersed);
+        assert_eq!(track!(get_capacity_bytes(&url))?, 1024);
+        let url = Url::from_str("http://example.com/v1/frugalos/content_cache/metadata").unwrap();
+        assert!(get_cache_class(&url).is_err());
+        assert!(get_capacity_bytes(&url).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn get_override_works() -> TestResult {
+        let url =
+            Url::from_str("http://example.com/v1/frugalos/maintenance?override=true").unwrap();
+        assert!(track!(get_override(&url))?);
+        let url = Url::from_str("http://example.com/v1/frugalos/maintenance").unwrap();
+        assert!(get_override(&url).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_priority_value_works() -> TestResult {
+        assert_eq!(
+            track!(parse_priority_value("realtime"))?,
+            RequestPriority::Realtime
+        );
+        assert_eq!(
+            track!(parse_priority_value(" Bulk "))?,
+            RequestPriority::Bulk
+        );
+        assert_eq!(
+            track!(parse_priority_value("normal"))?,
+            RequestPriority::Normal
+        );
+        assert!(parse_priority_value("urgent").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_range_value_works() -> TestResult {
+        assert_eq!(track!(parse_range_value("bytes=0-99"))?, 0..100);
+        assert_eq!(track!(parse_range_value("bytes=100-"))?, 100..u64::MAX);
+        assert!(parse_range_value("bytes=-100").is_err());
+        assert!(parse_range_value("bytes=10-5").is_err());
+        assert!(parse_range_value("bytes=0-1,5-6").is_err());
+        assert!(parse_range_value("items=0-1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_content_digest_works() -> TestResult {
+        let digest = track!(parse_content_digest(
+            " sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad "
+        ))?;
+        assert_eq!(digest.algorithm(), ChecksumAlgorithm::Sha256);
+        assert!(digest.matches(b"abc"));
+        assert!(!digest.matches(b"abd"));
+
+        let digest = track!(parse_content_digest(
+            &ChecksumAlgorithm::Xxh3.digest(b"abc").to_string()
+        ))?;
+        assert!(digest.matches(b"abc"));
+
+        let e = parse_content_digest("md5:900150983cd24fb0d6963f7d28e17f72").unwrap_err();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+        assert!(parse_content_digest("sha256:1234").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_precondition_field_works() -> TestResult {
+        assert_eq!(
+            track!(parse_precondition_field(
+                "X-Frugalos-If-Match-Range",
+                "\"a\", \"1f\""
+            ))?,
+            Some(Precondition::IfMatchRange {
+                from: ObjectVersion(10),
+                to: ObjectVersion(31),
+            })
+        );
+        assert_eq!(
+            track!(parse_precondition_field(
+                "x-frugalos-if-newer-than",
+                "\"3\""
+            ))?,
+            Some(Precondition::IfNewerThan(ObjectVersion(3)))
+        );
+        assert_eq!(
+            track!(parse_precondition_field(
+                "x-frugalos-if-unmodified-since",
+                "1500000000"
+            ))?,
+            Some(Precondition::IfUnmodifiedSince(1_500_000_000))
+        );
+        assert_eq!(track!(parse_precondition_field("if-match", "*"))?, None);
+        assert!(parse_precondition_field("x-frugalos-if-match-range", "\"a\"").is_err());
+        assert!(parse_precondition_field("x-frugalos-if-newer-than", "3").is_err());
+        Ok(())
+    }
+
     #[test]
     fn get_consistency_works() -> TestResult {
         let url = Url::from_str("http://example.com/?consistency=consistent").unwrap();
         let consistency = track!(get_consistency(&url))?;
-        assert_eq!(ReadConsistency::Consistent, consistency);
+        assert_eq!(Some(ReadConsistency::Consistent), consistency);
         let url = Url::from_str("http://example.com/?consistency=stale").unwrap();
         let consistency = track!(get_consistency(&url))?;
-        assert_eq!(ReadConsistency::Stale, consistency);
+        assert_eq!(Some(ReadConsistency::Stale), consistency);
         let url = Url::from_str("http://example.com/?consistency=subset").unwrap();
         let consistency = track!(get_consistency(&url))?;
-        assert_eq!(ReadConsistency::Subset(1), consistency);
+        assert_eq!(Some(ReadConsistency::Subset(1)), consistency);
         let url = Url::from_str("http://example.com/?consistency=quorum").unwrap();
         let consistency = track!(get_consistency(&url))?;
-        assert_eq!(ReadConsistency::Quorum, consistency);
+        assert_eq!(Some(ReadConsistency::Quorum), consistency);
         let url = Url::from_str("http://example.com/").unwrap();
         let consistency = track!(get_consistency(&url))?;
-        assert_eq!(ReadConsistency::Consistent, consistency);
+        assert_eq!(None, consistency);
         Ok(())
     }
 }
@@ -3,19 +3,28 @@ use cannyls;
 use cannyls::device::{Device, DeviceHandle};
 use cannyls_rpc::DeviceId;
 use cannyls_rpc::DeviceRegistryHandle;
-use fibers::sync::oneshot;
+use fibers::sync::{mpsc, oneshot};
 use fibers::Spawn;
 use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
 use fibers_rpc::server::ServerBuilder as RpcServerBuilder;
 use fibers_tasque;
 use fibers_tasque::TaskQueueExt;
-use frugalos_config::{DeviceGroup, Event as ConfigEvent, Service as ConfigService};
+use frugalos_config::{
+    self, DeviceBaseline, DeviceDecommission, DeviceGroup, DeviceUsage, Event as ConfigEvent,
+    MaintenanceWindows, RelayoutedSegment, Service as ConfigService,
+};
+use frugalos_core::cluster_feature::{self, ClusterFeature};
+use frugalos_core::logging;
 use frugalos_core::tracer::ThreadLocalTracer;
-use frugalos_mds;
+use frugalos_mds::{self, Quota};
 use frugalos_raft::{NodeId, Service as RaftService};
 use frugalos_segment;
-use frugalos_segment::FrugalosSegmentConfig;
+use frugalos_segment::config::{ClusterMember, MaintenanceConfig, MaintenanceWindowConfig};
 use frugalos_segment::Service as SegmentService;
+use frugalos_segment::{
+    ContentCacheSizing, ErasureCodingPool, FeatureFlags, FrugalosSegmentConfig,
+    MaintenanceSchedule, NotifyRelayoutFinished, RelayoutTarget,
+};
 use futures::future::Fuse;
 use futures::{Async, Future, Poll, Stream};
 use libfrugalos::entity::bucket::{Bucket as BucketConfig, BucketId};
@@ -23,16 +32,50 @@ use libfrugalos::entity::device::{
     Device as DeviceConfig, FileDevice as FileDeviceConfig, MemoryDevice as MemoryDeviceConfig,
 };
 use libfrugalos::entity::server::{Server, ServerId};
+use libfrugalos::repair::RepairConfig;
 use prometrics::metrics::MetricBuilder;
+use raftlog::cluster::ClusterMembers;
 use slog::Logger;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::Duration;
 use trackable::error::ErrorKindExt;
 
-use bucket::Bucket;
+use bucket::{self, Bucket};
 use client::FrugalosClient;
+use clock::ClockSkewMonitor;
+use decommission;
+use device::{prepare_file_device, DeviceBaselineReporter};
+use discovery::ServerDiscovery;
+use health::{DeviceHealthMonitor, DeviceHealthReport, DeviceHealthStatus};
+use leadership::LeadershipBalancer;
+use lifecycle::Lifecycle;
+use readiness::{LocalResources, ReadinessProbe};
+use rebalancer::Rebalancer;
 use recovery::RecoveryRequest;
-use {Error, ErrorKind, Result};
+use usage::DeviceUsageReporter;
+use warmup::ConnectionWarmup;
+use {
+    Error, ErrorKind, FrugalosDeviceConfig, FrugalosReadinessConfig, FrugalosRebalancerConfig,
+    Result,
+};
+
+type DecommissionFuture = Box<dyn Future<Item = Vec<DeviceDecommission>, Error = Error> + Send>;
+
+/// 構成管理用クラスタで共有されているメンテナンスウィンドウを、セグメントで使う形式に変換する。
+pub fn maintenance_config(windows: &MaintenanceWindows) -> MaintenanceConfig {
+    MaintenanceConfig {
+        windows: windows
+            .windows
+            .iter()
+            .map(|w| MaintenanceWindowConfig {
+                schedule: w.schedule.clone(),
+                duration: Duration::from_secs(u64::from(w.duration_minutes) * 60),
+            })
+            .collect(),
+        utc_offset_minutes: windows.utc_offset_minutes,
+    }
+}
 
 pub struct PhysicalDevice {
     id: DeviceId,
@@ -59,12 +102,45 @@ pub struct Service<S> {
 
     servers: HashMap<ServerId, Server>,
 
+    clock_skew_monitor: ClockSkewMonitor,
+    server_discovery: ServerDiscovery,
+    connection_warmup: ConnectionWarmup,
+    device_health_monitor: Option<DeviceHealthMonitor>,
+    // 隔離されたデバイスについて、自動で要求した退役
+    health_decommissions: Vec<(String, DecommissionFuture)>,
+    decommission_requested: HashSet<String>,
+    device_usage_reporter: Option<DeviceUsageReporter>,
+    device_baseline_reporter: DeviceBaselineReporter,
+    leadership_balancer: Option<LeadershipBalancer>,
+    rebalancer: Option<Rebalancer>,
+
     segment_config: FrugalosSegmentConfig,
+    device_config: FrugalosDeviceConfig,
+    mds_config: frugalos_mds::FrugalosMdsConfig,
+
+    // 全バケツで共有する Erasure Coding 用のスレッドプール
+    ec_pool: ErasureCodingPool,
+    cache_sizing: ContentCacheSizing,
+    maintenance: MaintenanceSchedule,
 
     // 起動済みのノード一覧
     spawned_nodes: HashSet<NodeId>,
 
+    // ローカルデバイス毎の、起動済みのノード一覧
+    device_nodes: HashMap<u32, Vec<NodeId>>,
+
+    // セグメント(バケツ番号とセグメント番号の組)毎の、起動済みのノード一覧
+    segment_nodes: HashMap<(u32, u16), Vec<NodeId>>,
+
+    // 再配置中(ないし再配置済み)のバケツの、変更前の設定
+    relayouts: HashMap<BucketId, BucketConfig>,
+    // バケツの属性で指定された、各セグメントの MDS のウィットネスの数
+    mds_witnesses: HashMap<BucketId, usize>,
+
     recovery_request: Option<RecoveryRequest>,
+
+    // readiness の判定に使う、ローカルのデバイスとノードの起動状況
+    resources: LocalResources,
 }
 impl<S> Service<S>
 where
@@ -81,7 +157,12 @@ where
         rpc_service: RpcServiceHandle,
         mds_config: frugalos_mds::FrugalosMdsConfig,
         segment_config: FrugalosSegmentConfig,
+        device_config: FrugalosDeviceConfig,
         recovery_request: Option<RecoveryRequest>,
+        clock_skew_monitor: ClockSkewMonitor,
+        server_discovery: ServerDiscovery,
+        connection_warmup: ConnectionWarmup,
+        device_health_monitor: Option<DeviceHealthMonitor>,
         tracer: ThreadLocalTracer,
     ) -> Result<Self> {
         let frugalos_segment_service = track!(SegmentService::new(
@@ -90,9 +171,34 @@ where
             rpc_service.clone(),
             rpc,
             raft_service.handle(),
-            mds_config,
+            mds_config.clone(),
             tracer
         ))?;
+        let ec_pool = track!(ErasureCodingPool::new(&segment_config.ec_pool))?;
+        let cache_sizing = ContentCacheSizing::new(&segment_config.content_cache);
+        // NOTE: ウィンドウは構成管理用クラスタから通知されるまでは未設定(常に実行可能)として扱う
+        let maintenance = MaintenanceSchedule::default();
+        let device_usage_reporter = if device_config.usage_report {
+            Some(track!(DeviceUsageReporter::new(
+                logger.clone(),
+                rpc_service.clone(),
+                device_config.usage_report_interval,
+            ))?)
+        } else {
+            None
+        };
+        let device_baseline_reporter = track!(DeviceBaselineReporter::new(
+            logger.clone(),
+            rpc_service.clone()
+        ))?;
+        let leadership_balancer = if mds_config.leadership_balance {
+            Some(track!(LeadershipBalancer::new(
+                logger.clone(),
+                &mds_config
+            ))?)
+        } else {
+            None
+        };
         Ok(Service {
             logger,
             local_server: config_service.local_server().clone(),
@@ -106,13 +212,50 @@ where
             buckets: Arc::new(AtomicImmut::new(HashMap::new())),
             bucket_no_to_id: HashMap::new(),
             servers: HashMap::new(),
+            clock_skew_monitor,
+            server_discovery,
+            connection_warmup,
+            device_health_monitor,
+            health_decommissions: Vec::new(),
+            decommission_requested: HashSet::new(),
+            device_usage_reporter,
+            device_baseline_reporter,
+            leadership_balancer,
+            rebalancer: None,
             spawned_nodes: HashSet::new(),
+            device_nodes: HashMap::new(),
+            segment_nodes: HashMap::new(),
+            relayouts: HashMap::new(),
+            mds_witnesses: HashMap::new(),
             recovery_request,
+            resources: LocalResources::new(),
             segment_config,
+            device_config,
+            mds_config,
+            ec_pool,
+            cache_sizing,
+            maintenance,
         })
     }
     pub fn client(&self) -> FrugalosClient {
-        FrugalosClient::new(self.buckets.clone())
+        FrugalosClient::new(
+            self.buckets.clone(),
+            self.cache_sizing.clone(),
+            self.maintenance.clone(),
+        )
+    }
+    /// このサーバの readiness を判定するための`ReadinessProbe`を返す。
+    pub fn readiness_probe(
+        &self,
+        lifecycle: Lifecycle,
+        config: &FrugalosReadinessConfig,
+    ) -> Result<ReadinessProbe> {
+        track!(ReadinessProbe::new(
+            lifecycle,
+            self.resources.clone(),
+            self.frugalos_segment_service.handle(),
+            config,
+        ))
     }
     pub fn stop(&mut self) {
         self.frugalos_segment_service.stop();
@@ -120,6 +263,32 @@ where
     pub fn take_snapshot(&mut self) {
         self.frugalos_segment_service.take_snapshot();
     }
+    pub fn resign_leaderships(&mut self) {
+        self.frugalos_segment_service.resign_leaderships();
+    }
+    pub fn drain(&mut self) {
+        self.frugalos_segment_service.drain();
+    }
+    pub fn repairs_in_flight(&self) -> u64 {
+        self.frugalos_segment_service.repairs_in_flight()
+    }
+    pub fn set_repair_config(&mut self, repair_config: RepairConfig) {
+        self.frugalos_segment_service
+            .set_repair_config(repair_config);
+    }
+    /// バックグラウンドでのリバランスの設定を反映する。
+    pub fn set_rebalancer_config(&mut self, config: &FrugalosRebalancerConfig) -> Result<()> {
+        self.rebalancer = if config.enabled {
+            Some(track!(Rebalancer::new(
+                self.logger.clone(),
+                config.clone(),
+                self.maintenance.clone(),
+            ))?)
+        } else {
+            None
+        };
+        Ok(())
+    }
     fn handle_config_event(&mut self, event: ConfigEvent) -> Result<()> {
         info!(self.logger, "Configuration Event: {:?}", event);
         match event {
@@ -155,15 +324,71 @@ where
                 segment_no,
                 groups,
             } => {
-                track_assert_eq!(groups.len(), 1, ErrorKind::Other, "Unimplemented");
-                track!(self.handle_patch_segment(bucket_no, segment_no, &groups[0]))?;
+                track!(self.handle_patch_segment(bucket_no, segment_no, &groups))?;
+            }
+            ConfigEvent::PutBucketRelayout(relayout) => {
+                info!(
+                    self.logger,
+                    "Bucket relayout: {}",
+                    dump!(relayout.pending_segments.len());
+                    logging::BUCKET => &relayout.bucket_id
+                );
+                self.relayouts.insert(relayout.bucket_id, relayout.previous);
             }
             ConfigEvent::PutServer(server) => {
+                self.clock_skew_monitor.put_peer(&server);
+                self.server_discovery.put_server(&server);
+                if !self.connection_warmup.is_done() {
+                    let addr = self.server_discovery.resolve_now(&server);
+                    self.connection_warmup.put_server(&server.id, addr);
+                }
                 self.servers.insert(server.id.clone(), server);
             }
             ConfigEvent::DeleteServer(server) => {
+                self.clock_skew_monitor.delete_peer(&server.id);
+                self.server_discovery.delete_server(&server.id);
                 self.servers.remove(&server.id);
             }
+            ConfigEvent::PutBucketAttributes(attributes) => {
+                // NOTE: ウィットネスの数は、以降にセグメントの構成が更新された時に反映される
+                if attributes.mds_witnesses == 0 {
+                    self.mds_witnesses.remove(&attributes.bucket_id);
+                } else {
+                    self.mds_witnesses
+                        .insert(attributes.bucket_id.clone(), attributes.mds_witnesses);
+                }
+                if let Some(bucket) = self.buckets.load().get(&attributes.bucket_id) {
+                    bucket.access().set_read_only(attributes.read_only);
+                    bucket.request_defaults().set(
+                        attributes.default_deadline_ms,
+                        attributes.default_consistency.clone(),
+                    );
+                    let quota = Quota {
+                        max_objects: attributes.max_objects,
+                        max_bytes: attributes.max_bytes,
+                    };
+                    bucket
+                        .quota()
+                        .set(quota.split(bucket.segments().len() as u16));
+                    bucket
+                        .coding()
+                        .set(attributes.erasure_coding.unwrap_or_default());
+                } else {
+                    warn!(
+                        self.logger,
+                        "The attributes refer to unknown bucket: {:?}", attributes
+                    );
+                }
+            }
+            ConfigEvent::PutMaintenanceWindows(windows) => {
+                // NOTE: 不正なウィンドウで他のイベントの処理を止めないように、失敗しても直前のウィンドウを使い続ける
+                if let Err(e) = track!(self.maintenance.update(&maintenance_config(&windows))) {
+                    warn!(
+                        self.logger,
+                        "Cannot apply the maintenance windows {:?}: {}", windows, e
+                    );
+                }
+            }
         }
         Ok(())
     }
@@ -172,11 +397,51 @@ where
         self.bucket_no_to_id
             .insert(bucket_config.seqno(), id.clone());
 
+        // NOTE: バケツの構成が更新された場合でも、実行中に切り替えられた機能フラグは引き継ぐ
+        let features = self
+            .buckets
+            .load()
+            .get(&id)
+            .map(|b| b.features().clone())
+            .unwrap_or_else(|| FeatureFlags::new(self.segment_config.feature_flags.flags(&id)));
+        // 構成管理用クラスタで設定された属性も同様に引き継ぐ
+        let access = self
+            .buckets
+            .load()
+            .get(&id)
+            .map(|b| b.access().clone())
+            .unwrap_or_default();
+        let request_defaults = self
+            .buckets
+            .load()
+            .get(&id)
+            .map(|b| b.request_defaults().clone())
+            .unwrap_or_default();
+        let quota = self
+            .buckets
+            .load()
+            .get(&id)
+            .map(|b| b.quota().clone())
+            .unwrap_or_default();
+        let coding = self
+            .buckets
+            .load()
+            .get(&id)
+            .map(|b| b.coding().clone())
+            .unwrap_or_default();
         let bucket = track!(Bucket::new(
             self.logger.clone(),
             self.rpc_service.clone(),
             &bucket_config,
             self.segment_config.clone(),
+            self.ec_pool.clone(),
+            self.cache_sizing.clone(),
+            self.maintenance.clone(),
+            features,
+            access,
+            request_defaults,
+            quota,
+            coding,
         ))?;
         let mut buckets = (&*self.buckets.load()).clone();
         buckets.insert(id, bucket);
@@ -187,43 +452,89 @@ where
         &mut self,
         bucket_no: u32,
         segment_no: u16,
-        group: &DeviceGroup,
+        groups: &[DeviceGroup],
     ) -> Result<()> {
-        // このグループに対応するRaftクラスタのメンバ群を用意
-        let mut members = Vec::new();
-        for (member_no, device_no) in (0..group.members.len()).zip(group.members.iter()) {
-            let owner = &self.servers[&self.seqno_to_device[device_no].server];
-            let node: NodeId = track!(format!(
-                "00{:06x}{:04x}{:02x}.{:x}@{}:{}",
-                bucket_no, segment_no, member_no, device_no, owner.host, owner.port
-            )
-            .parse())?;
-            members.push(node);
+        // 再配置中のセグメントは、新しい配置と変更前の配置の二つのグループを持つ
+        track_assert!(
+            !groups.is_empty() && groups.len() <= 2,
+            ErrorKind::Other,
+            "Unexpected device groups: {:?}",
+            groups
+        );
+
+        // 各グループに対応するRaftクラスタのメンバ群を用意
+        let mut group_members = Vec::new();
+        for group in groups {
+            group_members.push(track!(self.make_members(bucket_no, segment_no, group))?);
         }
+        let members = &group_members[0];
 
         // バケツの更新
-        if let Some(id) = self.bucket_no_to_id.get(&bucket_no) {
-            // TODO: だいぶコスト高の操作なので、セグメント更新はバッチ的に行った方が良いかも
-            let mut buckets = (&*self.buckets.load()).clone();
-            let segment;
-            {
-                use frugalos_segment::config::ClusterMember;
-                let bucket = buckets.get_mut(id).expect("Never fails");
-                let members = members
-                    .iter()
-                    .zip(group.members.iter())
-                    .map(|(&node, device_no)| ClusterMember {
-                        node,
-                        device: self.seqno_to_device[&device_no].id.clone().into_string(),
-                    })
-                    .collect();
-                track!(bucket.update_segment(segment_no, members))?;
-                segment = bucket.segments()[segment_no as usize].clone();
-            }
-            self.buckets.store(buckets);
+        let id = if let Some(id) = self.bucket_no_to_id.get(&bucket_no).cloned() {
+            id
+        } else {
+            // 既に削除されているバケツのセグメント
+            // (FIXME: タイミング的にここに来ることはなさそうなのでエラーでも良いかも)
+            return Ok(());
+        };
+        // TODO: だいぶコスト高の操作なので、セグメント更新はバッチ的に行った方が良いかも
+        let mut buckets = (&*self.buckets.load()).clone();
+        let segment;
+        let quota;
+        let version_retention;
+        let mds_witnesses = self.mds_witnesses.get(&id).cloned().unwrap_or(0);
+        {
+            use frugalos_segment::config::{ClusterConfig, PreviousLayout};
+            let previous_layout = if let Some(previous_members) = group_members.get(1) {
+                let previous_bucket = track_assert_some!(
+                    self.relayouts.get(&id),
+                    ErrorKind::Other,
+                    "Unknown relayout: {:?}",
+                    id
+                );
+                let cluster_members = self.cluster_members(previous_members, &groups[1]);
+                Some(PreviousLayout {
+                    cluster: ClusterConfig {
+                        members: cluster_members,
+                        mds_witnesses,
+                    },
+                    storage: bucket::storage_config(previous_bucket),
+                })
+            } else {
+                None
+            };
+            let bucket = buckets.get_mut(&id).expect("Never fails");
+            let cluster_members = self.cluster_members(members, &groups[0]);
+            track!(bucket.update_segment(
+                segment_no,
+                cluster_members,
+                mds_witnesses,
+                previous_layout
+            ))?;
+            segment = bucket.segments()[segment_no as usize].clone();
+            quota = bucket.quota().clone();
+            version_retention = self.mds_config.version_retention.get(&id).cloned();
+        }
+        self.buckets.store(buckets);
 
-            // このサーバが扱うべきRaftノードを起動
-            for (node, device_no) in members.iter().zip(group.members.iter()) {
+        // 再配置中は、MDS のメンバの切り替えが完了するまで変更前のメンバ群がRaftクラスタを構成する
+        // (新しい配置のノードは、切り替え時にリーダによって追加される)
+        let (raft_members, relayout) = if let Some(previous_members) = group_members.get(1) {
+            let target = RelayoutTarget {
+                generation: groups[0].generation,
+                members: members.clone(),
+                notify_finished: self.relayout_notifier(&id, segment_no, groups[0].generation),
+            };
+            (previous_members, Some(target))
+        } else {
+            (members, None)
+        };
+        let raft_members: ClusterMembers =
+            raft_members.iter().map(NodeId::to_raft_node_id).collect();
+
+        // このサーバが扱うべきRaftノードを起動
+        for (group, nodes) in groups.iter().zip(group_members.iter()) {
+            for (node, device_no) in nodes.iter().zip(group.members.iter()) {
                 let device_id =
                     if let Some(id) = self.local_devices.get(&device_no).map(LocalDevice::id) {
                         id
@@ -235,17 +546,31 @@ where
                     info!(
                         self.logger,
                         "The node has been spawned already: {}",
-                        dump!(bucket_no, segment_no, device_no, device_id, node)
+                        dump!(bucket_no, device_no, device_id);
+                        logging::BUCKET => &id,
+                        logging::SEGMENT => segment_no,
+                        logging::NODE_ID => node.local_id.to_string()
                     );
                     continue;
                 }
 
                 self.spawned_nodes.insert(node.clone());
+                self.device_nodes
+                    .entry(*device_no)
+                    .or_insert_with(Vec::new)
+                    .push(node.clone());
+                self.segment_nodes
+                    .entry((bucket_no, segment_no))
+                    .or_insert_with(Vec::new)
+                    .push(node.clone());
 
                 info!(
                     self.logger,
                     "Add a node: {}",
-                    dump!(bucket_no, segment_no, device_no, device_id, node)
+                    dump!(bucket_no, device_no, device_id);
+                    logging::BUCKET => &id,
+                    logging::SEGMENT => segment_no,
+                    logging::NODE_ID => node.local_id.to_string()
                 );
 
                 let device_handle = self.local_devices.get_mut(&device_no).unwrap().watch();
@@ -256,26 +581,212 @@ where
                             .map_err(|e| frugalos_segment::ErrorKind::Other.takes_over(e).into())
                     ),
                     segment.clone(),
-                    members.iter().map(NodeId::to_raft_node_id).collect(),
+                    raft_members.clone(),
                     self.recovery_request.is_some(),
+                    quota.clone(),
+                    version_retention,
+                    relayout.clone(),
                 ))?;
             }
+        }
+
+        // 再配置が完了したセグメントでは、変更前の配置のノードは不要になる
+        if relayout.is_none() {
+            track!(self.remove_stale_nodes(&id, bucket_no, segment_no, members))?;
+        }
+        Ok(())
+    }
+    /// `group`に対応するRaftクラスタのメンバ群を返す。
+    fn make_members(
+        &self,
+        bucket_no: u32,
+        segment_no: u16,
+        group: &DeviceGroup,
+    ) -> Result<Vec<NodeId>> {
+        let mut members = Vec::new();
+        for (member_no, device_no) in group.members.iter().enumerate() {
+            let owner = &self.servers[&self.seqno_to_device[device_no].server];
+            let node: NodeId = track!(format!(
+                "00{:06x}{:04x}{:02x}.{:x}@{}:{}",
+                bucket_no,
+                segment_no,
+                group.node_member_no(member_no),
+                device_no,
+                owner.host,
+                owner.port
+            )
+            .parse())?;
+            members.push(node);
+        }
+        Ok(members)
+    }
+    fn cluster_members(&self, members: &[NodeId], group: &DeviceGroup) -> Vec<ClusterMember> {
+        members
+            .iter()
+            .zip(group.members.iter())
+            .map(|(&node, device_no)| ClusterMember {
+                node,
+                device: self.seqno_to_device[&device_no].id.clone().into_string(),
+            })
+            .collect()
+    }
+    /// セグメントの切り替えの完了を、構成管理用クラスタに通知する関数を返す。
+    fn relayout_notifier(
+        &self,
+        bucket_id: &BucketId,
+        segment_no: u16,
+        generation: u8,
+    ) -> NotifyRelayoutFinished {
+        let rpc_service = self.rpc_service.clone();
+        let contact_server = self.local_server.addr();
+        let segment = RelayoutedSegment {
+            bucket_id: bucket_id.clone(),
+            segment_no,
+            generation,
+        };
+        Arc::new(
+            move || -> Box<dyn Future<Item = (), Error = frugalos_segment::Error> + Send> {
+                let future = frugalos_config::cluster::finish_segment_relayout(
+                    rpc_service.clone(),
+                    contact_server,
+                    segment.clone(),
+                )
+                .map_err(|e| {
+                    frugalos_segment::Error::from(frugalos_segment::ErrorKind::Other.takes_over(e))
+                });
+                Box::new(future)
+            },
+        )
+    }
+    /// 再配置によって`members`に含まれなくなった、このサーバ上のノード群を停止して、そのデータを削除する。
+    fn remove_stale_nodes(
+        &mut self,
+        bucket_id: &BucketId,
+        bucket_no: u32,
+        segment_no: u16,
+        members: &[NodeId],
+    ) -> Result<()> {
+        let stale_nodes = if let Some(nodes) = self.segment_nodes.get_mut(&(bucket_no, segment_no))
+        {
+            let (stale, alive): (Vec<_>, Vec<_>) =
+                nodes.drain(..).partition(|n| !members.contains(n));
+            *nodes = alive;
+            stale
         } else {
-            // 既に削除されているバケツのセグメント
-            // (FIXME: タイミング的にここに来ることはなさそうなのでエラーでも良いかも)
+            Vec::new()
         };
-
+        for node in stale_nodes {
+            info!(
+                self.logger,
+                "Remove a node";
+                logging::BUCKET => bucket_id,
+                logging::SEGMENT => segment_no,
+                logging::NODE_ID => node.local_id.to_string()
+            );
+            self.spawned_nodes.remove(&node);
+            for nodes in self.device_nodes.values_mut() {
+                nodes.retain(|n| *n != node);
+            }
+            track!(self.frugalos_segment_service.handle().remove_node(node))?;
+        }
         Ok(())
     }
     fn spawn_device(&mut self, device_config: &DeviceConfig) -> Result<()> {
         let device = LocalDevice::new(
             self.logger.clone(),
             &device_config,
+            &self.device_config,
+            self.device_baseline_reporter.sender(),
             self.frugalos_segment_service.device_registry().handle(),
         );
         self.local_devices.insert(device_config.seqno(), device);
+        if let (Some(monitor), DeviceConfig::File(ref d)) =
+            (self.device_health_monitor.as_mut(), device_config)
+        {
+            track!(monitor.put_device(d.seqno, &d.id, &d.filepath))?;
+        }
         Ok(())
     }
+    fn handle_device_health_report(&mut self, report: &DeviceHealthReport) {
+        if report.quarantined && self.device_config.health_auto_decommission {
+            self.request_decommission(&report.device_id);
+        }
+        if report.status != DeviceHealthStatus::Failing {
+            return;
+        }
+
+        // 故障しかけているディスク上のノードがリーダであり続けると、クラスタ全体の性能や可用性に影響するので、
+        // 他のサーバにリーダ権を譲る (既にフォロワーの場合には何も起こらない)
+        let nodes = self
+            .device_nodes
+            .get(&report.device_no)
+            .map_or(&[][..], |nodes| &nodes[..]);
+        warn!(
+            self.logger,
+            "Resigns the leaderships of the nodes on the failing device: {}",
+            dump!(report.device_id, nodes.len())
+        );
+        for node in nodes {
+            self.frugalos_segment_service
+                .resign_leadership(node.local_id);
+        }
+    }
+    /// 隔離されたデバイスの退役を、構成管理用クラスタに要求する。
+    ///
+    /// 要求に失敗した場合には、次に健全性が判定された時に改めて要求する。
+    fn request_decommission(&mut self, device_id: &str) {
+        if self.decommission_requested.contains(device_id) {
+            return;
+        }
+        for &feature in &[ClusterFeature::Relayout, ClusterFeature::Decommission] {
+            if !cluster_feature::is_enabled(feature) {
+                warn!(
+                    self.logger,
+                    "Cannot decommission the quarantined device (the cluster feature {:?} is disabled): {}",
+                    feature.name(),
+                    device_id
+                );
+                return;
+            }
+        }
+        warn!(
+            self.logger,
+            "Requests the decommission of the quarantined device: {}", device_id
+        );
+        let future = decommission::start(
+            self.rpc_service.clone(),
+            self.local_server.addr(),
+            vec![device_id.to_owned()],
+        );
+        self.decommission_requested.insert(device_id.to_owned());
+        self.health_decommissions
+            .push((device_id.to_owned(), future));
+    }
+    fn poll_health_decommissions(&mut self) {
+        let mut i = 0;
+        while i < self.health_decommissions.len() {
+            match self.health_decommissions[i].1.poll() {
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(statuses)) => {
+                    let (device_id, _) = self.health_decommissions.swap_remove(i);
+                    info!(
+                        self.logger,
+                        "The decommission of the quarantined device is started: {}",
+                        dump!(device_id, statuses)
+                    );
+                }
+                Err(e) => {
+                    let (device_id, _) = self.health_decommissions.swap_remove(i);
+                    warn!(
+                        self.logger,
+                        "Cannot decommission the quarantined device: {}",
+                        dump!(device_id, e)
+                    );
+                    self.decommission_requested.remove(&device_id);
+                }
+            }
+        }
+    }
 }
 impl<S> Future for Service<S>
 where
@@ -295,11 +806,53 @@ where
             track!(self.handle_config_event(event))?;
         }
 
+        track!(self.clock_skew_monitor.poll())?;
+        track!(self.server_discovery.poll())?;
+        track!(self.connection_warmup.poll())?;
+        let mut reports = Vec::new();
+        if let Some(ref mut monitor) = self.device_health_monitor {
+            while let Async::Ready(Some(report)) = track!(monitor.poll())? {
+                reports.push(report);
+            }
+        }
+        for report in reports {
+            self.handle_device_health_report(&report);
+        }
+        self.poll_health_decommissions();
+        if let Some(ref mut reporter) = self.device_usage_reporter {
+            if track!(reporter.poll_tick())? {
+                let usages = self
+                    .local_devices
+                    .values()
+                    .filter_map(LocalDevice::usage)
+                    .collect();
+                reporter.report(&self.config_service.handle(), usages);
+            }
+        }
+        track!(self
+            .device_baseline_reporter
+            .poll(&self.config_service.handle()))?;
+        if let Some(ref mut balancer) = self.leadership_balancer {
+            let segments = &mut self.frugalos_segment_service;
+            track!(balancer.poll(|slack| segments.balance_leaderships(slack)))?;
+        }
+        if let Some(ref mut rebalancer) = self.rebalancer {
+            track!(rebalancer.poll(&self.config_service.handle()))?;
+        }
+
         for device in self.local_devices.values_mut() {
             if let Err(e) = track!(device.poll()) {
                 error!(self.logger, "Device error: {}", e; "device" => device.id().as_str());
             }
         }
+        let initialized = self
+            .local_devices
+            .values()
+            .filter(|d| d.handle.is_some())
+            .count();
+        self.resources
+            .set_devices(self.local_devices.len(), initialized);
+        self.resources.set_mds_nodes(self.spawned_nodes.len());
 
         Ok(Async::NotReady)
     }
@@ -315,20 +868,35 @@ struct LocalDevice {
     watches: Vec<oneshot::Monitored<DeviceHandle, Error>>,
 }
 impl LocalDevice {
-    fn new(logger: Logger, config: &DeviceConfig, device_registry: DeviceRegistryHandle) -> Self {
+    fn new(
+        logger: Logger,
+        config: &DeviceConfig,
+        device_config: &FrugalosDeviceConfig,
+        baseline_tx: mpsc::Sender<DeviceBaseline>,
+        device_registry: DeviceRegistryHandle,
+    ) -> Self {
         info!(logger, "Starts spawning new device: {:?}", config);
         LocalDevice {
-            logger,
+            logger: logger.clone(),
             config: config.clone(),
             device_registry,
             handle: None,
-            future: spawn_device(config).fuse(),
+            future: spawn_device(logger, config, device_config, baseline_tx).fuse(),
             watches: Vec::new(),
         }
     }
     fn id(&self) -> DeviceId {
         DeviceId::new(self.config.id().clone())
     }
+    fn usage(&self) -> Option<DeviceUsage> {
+        let handle = self.handle.as_ref()?;
+        let data_region = handle.metrics().storage()?.data_region();
+        Some(DeviceUsage {
+            device_id: self.config.id().clone(),
+            capacity_bytes: data_region.capacity_bytes(),
+            usage_bytes: data_region.usage_bytes(),
+        })
+    }
     fn watch(&mut self) -> WatchDeviceHandle {
         if let Some(ref d) = self.handle {
             WatchDeviceHandle::Ok(d.clone())
@@ -373,7 +941,12 @@ impl Future for WatchDeviceHandle {
     }
 }
 
-fn spawn_device(device: &DeviceConfig) -> fibers_tasque::AsyncCall<Result<Device>> {
+fn spawn_device(
+    logger: Logger,
+    device: &DeviceConfig,
+    device_config: &FrugalosDeviceConfig,
+    baseline_tx: mpsc::Sender<DeviceBaseline>,
+) -> fibers_tasque::AsyncCall<Result<Device>> {
     use libfrugalos::entity::device::Device;
 
     match *device {
@@ -381,7 +954,7 @@ fn spawn_device(device: &DeviceConfig) -> fibers_tasque::AsyncCall<Result<Device
             fibers_tasque::DefaultIoTaskQueue.async_call(|| track_panic!(ErrorKind::Other))
         }
         Device::Memory(ref d) => spawn_memory_device(d),
-        Device::File(ref d) => spawn_file_device(d),
+        Device::File(ref d) => spawn_file_device(logger, d, device_config.clone(), baseline_tx),
     }
 }
 
@@ -401,8 +974,14 @@ fn spawn_memory_device(device: &MemoryDeviceConfig) -> fibers_tasque::AsyncCall<
     })
 }
 
-fn spawn_file_device(device: &FileDeviceConfig) -> fibers_tasque::AsyncCall<Result<Device>> {
+fn spawn_file_device(
+    logger: Logger,
+    device: &FileDeviceConfig,
+    device_config: FrugalosDeviceConfig,
+    baseline_tx: mpsc::Sender<DeviceBaseline>,
+) -> fibers_tasque::AsyncCall<Result<Device>> {
     use cannyls::nvm::FileNvm;
+    let device_id = device.id.clone();
     let metrics = MetricBuilder::new()
         .label("device", device.id.as_ref())
         .clone();
@@ -411,8 +990,21 @@ fn spawn_file_device(device: &FileDeviceConfig) -> fibers_tasque::AsyncCall<Resu
     let mut storage = cannyls::storage::StorageBuilder::new();
     storage.metrics(metrics.clone());
     fibers_tasque::DefaultIoTaskQueue.async_call(move || {
-        let (nvm, created) =
-            track!(FileNvm::create_if_absent(filepath, capacity).map_err(Error::from))?;
+        let (nvm, created) = if filepath.exists() {
+            let nvm = track!(FileNvm::open(filepath).map_err(Error::from))?;
+            (nvm, false)
+        } else {
+            track!(prepare_file_device(
+                &logger,
+                &device_id,
+                &filepath,
+                capacity,
+                &device_config,
+                &baseline_tx
+            ))?;
+            let nvm = track!(FileNvm::create(filepath, capacity).map_err(Error::from))?;
+            (nvm, true)
+        };
         let storage = if created {
             track!(storage.create(nvm).map_err(Error::from))?
         } else {
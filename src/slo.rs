@@ -0,0 +1,424 @@
+//! バケツ毎のSLO(サービスレベル目標)の達成状況を追跡するためのモジュール。
+//!
+//! HTTP のオブジェクト操作のリクエストを計測して、SLI 毎に以下のメトリクスを公開する:
+//!
+//! - `frugalos_slo_success_ratio`: 直近の各ウィンドウ内で、目標を満たしたリクエストの割合
+//! - `frugalos_slo_burn_rate`: エラーバジェットの消費速度(`1.0`で、期間内にちょうど使い切るペース)
+//!
+//! SLI には、可用性(`availability`: 5xx 以外の応答の割合)と、
+//! レイテンシ(`latency`: 閾値以内に成功応答を返したリクエストの割合)の二種類がある。
+//! 生のヒストグラムではなく、これらの値に対してアラートを設定することを想定している。
+//!
+//! 各ウィンドウの値は、リクエストの有無に関わらず`SloRefresher`によって定期的に更新される。
+use fibers::time::timer::{self, Timeout};
+use fibers_http_server::{HandleRequest, Req, Res};
+use futures::{Async, Future, Poll};
+use prometrics::metrics::{Counter, Gauge, MetricBuilder};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use {Error, FrugalosSloConfig, FrugalosSloObjective, Result};
+
+/// リクエスト数を集計する単位となる時間幅(秒)。
+const SLOT_SECONDS: u64 = 10;
+
+/// 成功率とバーンレートを算出するウィンドウ群(秒)。
+const WINDOWS: &[(&str, u64)] = &[("5m", 5 * 60), ("1h", 60 * 60), ("6h", 6 * 60 * 60)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sli {
+    Availability,
+    Latency,
+}
+impl Sli {
+    fn as_str(self) -> &'static str {
+        match self {
+            Sli::Availability => "availability",
+            Sli::Latency => "latency",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Slot {
+    index: u64,
+    total: u64,
+    available: u64,
+    fast: u64,
+}
+
+struct WindowGauges {
+    seconds: u64,
+    success_ratio: Gauge,
+    burn_rate: Gauge,
+}
+
+struct BucketSlo {
+    objective: FrugalosSloObjective,
+    slots: VecDeque<Slot>,
+    requests_total: Counter,
+    availability_good_total: Counter,
+    latency_good_total: Counter,
+    windows: Vec<(Sli, WindowGauges)>,
+}
+impl BucketSlo {
+    fn new(objective: FrugalosSloObjective) -> Result<Self> {
+        let bucket_id = objective.bucket_id.clone();
+        let mut builder = MetricBuilder::new();
+        builder
+            .namespace("frugalos")
+            .subsystem("slo")
+            .label("bucket", &bucket_id);
+        let requests_total = track!(builder
+            .counter("requests_total")
+            .help("Number of requests subject to the SLO")
+            .default_registry()
+            .finish())?;
+        let good_total = |sli: Sli| {
+            track!(builder
+                .counter("good_requests_total")
+                .help("Number of requests that met the objective")
+                .label("sli", sli.as_str())
+                .default_registry()
+                .finish())
+        };
+        let availability_good_total = good_total(Sli::Availability)?;
+        let latency_good_total = good_total(Sli::Latency)?;
+
+        let mut windows = Vec::new();
+        for &sli in &[Sli::Availability, Sli::Latency] {
+            for &(name, seconds) in WINDOWS {
+                let success_ratio = track!(builder
+                    .gauge("success_ratio")
+                    .help("Ratio of requests that met the objective in the window")
+                    .label("sli", sli.as_str())
+                    .label("window", name)
+                    .default_registry()
+                    .finish())?;
+                let burn_rate = track!(builder
+                    .gauge("burn_rate")
+                    .help("Rate at which the error budget is consumed in the window")
+                    .label("sli", sli.as_str())
+                    .label("window", name)
+                    .default_registry()
+                    .finish())?;
+                success_ratio.set(1.0);
+                let gauges = WindowGauges {
+                    seconds,
+                    success_ratio,
+                    burn_rate,
+                };
+                windows.push((sli, gauges));
+            }
+        }
+        Ok(BucketSlo {
+            objective,
+            slots: VecDeque::new(),
+            requests_total,
+            availability_good_total,
+            latency_good_total,
+            windows,
+        })
+    }
+
+    fn record(&mut self, now: Duration, elapsed: Duration, status: u16) {
+        let index = now.as_secs() / SLOT_SECONDS;
+        if self.slots.back().is_none_or(|s| s.index < index) {
+            // 新しいスロットに移る前に、完了したスロットまでの結果を反映する
+            self.update_gauges(index);
+            self.slots.push_back(Slot {
+                index,
+                ..Slot::default()
+            });
+        }
+
+        let available = status < 500;
+        let fast = available && elapsed <= self.objective.latency_threshold;
+        let slot = self.slots.back_mut().expect("Never fails");
+        slot.total += 1;
+        self.requests_total.increment();
+        if available {
+            slot.available += 1;
+            self.availability_good_total.increment();
+        }
+        if fast {
+            slot.fast += 1;
+            self.latency_good_total.increment();
+        }
+    }
+
+    /// リクエストがない間にも値が古いまま残らないように、現在時刻`now`までに完了したスロットの結果を反映する。
+    fn refresh(&mut self, now: Duration) {
+        self.update_gauges(now.as_secs() / SLOT_SECONDS);
+    }
+
+    fn update_gauges(&mut self, current: u64) {
+        let max_window = WINDOWS.iter().map(|w| w.1).max().unwrap_or(0) / SLOT_SECONDS;
+        while self
+            .slots
+            .front()
+            .is_some_and(|s| s.index + max_window < current)
+        {
+            self.slots.pop_front();
+        }
+        for (sli, gauges) in &self.windows {
+            let from = current.saturating_sub(gauges.seconds / SLOT_SECONDS);
+            let (total, good) = self
+                .slots
+                .iter()
+                .filter(|s| s.index >= from && s.index < current)
+                .fold((0, 0), |(total, good), s| {
+                    let ok = match sli {
+                        Sli::Availability => s.available,
+                        Sli::Latency => s.fast,
+                    };
+                    (total + s.total, good + ok)
+                });
+            let target = match sli {
+                Sli::Availability => self.objective.availability_target,
+                Sli::Latency => self.objective.latency_target,
+            };
+            let ratio = success_ratio(total, good);
+            gauges.success_ratio.set(ratio);
+            gauges.burn_rate.set(burn_rate(ratio, target));
+        }
+    }
+}
+
+/// 成功率を返す(リクエストがなかった場合は`1.0`)。
+fn success_ratio(total: u64, good: u64) -> f64 {
+    if total == 0 {
+        1.0
+    } else {
+        good as f64 / total as f64
+    }
+}
+
+/// 成功率`ratio`と目標値`target`から、エラーバジェットの消費速度を求める。
+fn burn_rate(ratio: f64, target: f64) -> f64 {
+    let budget = 1.0 - target;
+    if budget <= 0.0 {
+        // 失敗を一切許容しない目標の場合
+        if ratio < 1.0 {
+            f64::INFINITY
+        } else {
+            0.0
+        }
+    } else {
+        (1.0 - ratio) / budget
+    }
+}
+
+/// SLO の対象となるリクエストを計測するためのトラッカー。
+#[derive(Clone)]
+pub struct SloTracker {
+    started_at: Instant,
+    buckets: Arc<Mutex<HashMap<String, BucketSlo>>>,
+}
+impl SloTracker {
+    /// 設定から`SloTracker`を生成する。
+    pub fn new(config: &FrugalosSloConfig) -> Result<Self> {
+        let mut buckets = HashMap::new();
+        for objective in &config.objectives {
+            let slo = track!(BucketSlo::new(objective.clone()))?;
+            buckets.insert(objective.bucket_id.clone(), slo);
+        }
+        Ok(SloTracker {
+            started_at: Instant::now(),
+            buckets: Arc::new(Mutex::new(buckets)),
+        })
+    }
+
+    /// バケツ`bucket_id`に対するリクエストの結果を記録する。
+    ///
+    /// バケツに目標が設定されていない場合には何もしない。
+    pub fn record(&self, bucket_id: &str, elapsed: Duration, status: u16) {
+        let now = self.started_at.elapsed();
+        if let Ok(mut buckets) = self.buckets.lock() {
+            if let Some(slo) = buckets.get_mut(bucket_id) {
+                slo.record(now, elapsed, status);
+            }
+        }
+    }
+
+    /// 全てのバケツの各ウィンドウの値を、現在時刻に合わせて更新する。
+    pub fn refresh(&self) {
+        let now = self.started_at.elapsed();
+        if let Ok(mut buckets) = self.buckets.lock() {
+            for slo in buckets.values_mut() {
+                slo.refresh(now);
+            }
+        }
+    }
+
+    /// 各ウィンドウの値を定期的に更新する`Future`を返す。
+    pub fn refresher(&self) -> SloRefresher {
+        SloRefresher {
+            tracker: self.clone(),
+            timeout: timer::timeout(Duration::from_secs(SLOT_SECONDS)),
+        }
+    }
+}
+
+/// `SloTracker`の各ウィンドウの値を、スロットの時間幅毎に更新する`Future`。
+///
+/// リクエストが途絶えた場合にも、ウィンドウから外れた結果が値に残り続けないようにするために使われる。
+/// この`Future`が終了することはない。
+pub struct SloRefresher {
+    tracker: SloTracker,
+    timeout: Timeout,
+}
+impl Future for SloRefresher {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while track!(self.timeout.poll().map_err(Error::from))?.is_ready() {
+            self.timeout = timer::timeout(Duration::from_secs(SLOT_SECONDS));
+            self.tracker.refresh();
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// HTTP ハンドラをラップして、処理したリクエストを`SloTracker`に記録する。
+///
+/// 対象のハンドラのパスは`/v1/buckets/{bucket_id}/...`の形式である必要がある。
+pub struct WithSlo<H> {
+    inner: H,
+    tracker: SloTracker,
+}
+impl<H: HandleRequest> WithSlo<H> {
+    /// 新しい`WithSlo`インスタンスを生成する。
+    pub fn new(inner: H, tracker: SloTracker) -> Self {
+        WithSlo { inner, tracker }
+    }
+}
+impl<H: HandleRequest> HandleRequest for WithSlo<H> {
+    const METHOD: &'static str = H::METHOD;
+    const PATH: &'static str = H::PATH;
+
+    type ReqBody = H::ReqBody;
+    type ResBody = H::ResBody;
+    type Decoder = H::Decoder;
+    type Encoder = H::Encoder;
+    type Reply = Measure<H>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let bucket_id = bucket_id_from_path(req.url().path()).to_owned();
+        Measure {
+            future: self.inner.handle_request(req),
+            started_at: Instant::now(),
+            bucket_id,
+            tracker: self.tracker.clone(),
+        }
+    }
+
+    fn handle_request_head(&self, req: &Req<()>) -> Option<Res<Self::ResBody>> {
+        self.inner.handle_request_head(req)
+    }
+}
+
+/// リクエストの処理時間と応答ステータスを計測する`Future`。
+pub struct Measure<H: HandleRequest> {
+    future: H::Reply,
+    started_at: Instant,
+    bucket_id: String,
+    tracker: SloTracker,
+}
+impl<H: HandleRequest> Future for Measure<H> {
+    type Item = Res<H::ResBody>;
+    type Error = <H::Reply as Future>::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(res) = self.future.poll()? {
+            self.tracker.record(
+                &self.bucket_id,
+                self.started_at.elapsed(),
+                res.status_code(),
+            );
+            Ok(Async::Ready(res))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+fn bucket_id_from_path(path: &str) -> &str {
+    path.trim_start_matches('/').split('/').nth(2).unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gauge(slo: &BucketSlo, sli: Sli, window: &str) -> (f64, f64) {
+        let seconds = WINDOWS
+            .iter()
+            .find(|w| w.0 == window)
+            .expect("Never fails")
+            .1;
+        let (_, gauges) = slo
+            .windows
+            .iter()
+            .find(|(s, g)| *s == sli && g.seconds == seconds)
+            .expect("Never fails");
+        (gauges.success_ratio.value(), gauges.burn_rate.value())
+    }
+
+    #[test]
+    fn burn_rate_works() {
+        assert_eq!(success_ratio(0, 0), 1.0);
+        assert_eq!(success_ratio(100, 99), 0.99);
+        assert_eq!(burn_rate(1.0, 0.999), 0.0);
+        assert!((burn_rate(0.99, 0.999) - 10.0).abs() < 1e-9);
+        assert!((burn_rate(0.999, 0.999) - 1.0).abs() < 1e-9);
+        assert_eq!(burn_rate(0.5, 1.0), f64::INFINITY);
+    }
+
+    #[test]
+    fn bucket_slo_works() -> Result<()> {
+        let objective = FrugalosSloObjective {
+            bucket_id: "slo_test".to_owned(),
+            latency_threshold: Duration::from_millis(100),
+            latency_target: 0.9,
+            availability_target: 0.99,
+        };
+        let mut slo = track!(BucketSlo::new(objective))?;
+        for i in 0..10 {
+            let elapsed = Duration::from_millis(if i < 5 { 10 } else { 200 });
+            let status = if i == 0 { 503 } else { 200 };
+            slo.record(Duration::from_secs(1), elapsed, status);
+        }
+        // 次のスロットに移った時点で、前のスロットまでの結果が反映される
+        slo.record(
+            Duration::from_secs(SLOT_SECONDS),
+            Duration::from_millis(0),
+            200,
+        );
+
+        let (ratio, burn) = gauge(&slo, Sli::Availability, "5m");
+        assert_eq!(ratio, 0.9);
+        assert!((burn - 10.0).abs() < 1e-9);
+        let (ratio, burn) = gauge(&slo, Sli::Latency, "1h");
+        assert_eq!(ratio, 0.4);
+        assert!((burn - 6.0).abs() < 1e-9);
+
+        // リクエストがなくても、ウィンドウから外れた結果は値に反映されなくなる
+        slo.refresh(Duration::from_secs(SLOT_SECONDS * 2 + 5 * 60));
+        assert_eq!(gauge(&slo, Sli::Availability, "5m"), (1.0, 0.0));
+        assert!(gauge(&slo, Sli::Availability, "1h").0 < 1.0);
+
+        // ウィンドウから外れたスロットは捨てられる
+        slo.record(
+            Duration::from_secs(7 * 60 * 60),
+            Duration::from_millis(0),
+            200,
+        );
+        assert_eq!(slo.slots.len(), 1);
+        assert_eq!(gauge(&slo, Sli::Availability, "6h").0, 1.0);
+
+        assert_eq!(bucket_id_from_path("/v1/buckets/foo/objects/bar"), "foo");
+        Ok(())
+    }
+}
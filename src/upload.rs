@@ -0,0 +1,513 @@
+//! 中断された分割アップロードを再開するための仕組み。
+//!
+//! 分割アップロードでは、オブジェクトの内容を先頭から順に部分(part)に分けて送信する。
+//! 最初の部分はオブジェクトが存在しないことを前提条件(`Expect::None`)とする PUT として、
+//! 以降の部分は直前の部分で作成されたバージョンを前提条件とする追記として保存されるので、
+//! 応答が返された時点で、その部分までの内容は永続化されている。
+//! 既存のオブジェクトを分割アップロードで上書きすることはできない。
+//!
+//! クライアントがクラッシュした場合には、アップロード ID を指定して受信済みの部分の数とバイト数を問い合わせ、
+//! その続きから送信を再開することができる。
+//! 一定時間(`FrugalosUploadConfig::abandoned_timeout`)操作が無いアップロードは放棄されたものと見なし、
+//! 作成途中のオブジェクトを削除する。
+//!
+//! アップロードの状態は、変更の度にデータディレクトリ内のファイルに保存されるので、
+//! サーバが再起動した後も、同じサーバに対してアップロードを再開することができる。
+//! 再起動後の最初の操作までの時間は、放棄の判定では経過時間に含まれない。
+use fibers::time::timer::{self, Timeout};
+use frugalos_core::logging;
+use futures::stream::FuturesUnordered;
+use futures::{self, Async, Future, Poll, Stream};
+use libfrugalos::entity::bucket::BucketId;
+use libfrugalos::entity::object::{ObjectId, ObjectVersion};
+use libfrugalos::expect::Expect;
+use serde_json;
+use slog::Logger;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use client::FrugalosClient;
+use {Error, ErrorKind, FrugalosUploadConfig, Result};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+
+/// アップロードの状態。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadStatus {
+    /// アップロード ID。
+    pub upload_id: String,
+
+    /// 作成中のオブジェクトが属するバケツの ID。
+    pub bucket_id: BucketId,
+
+    /// 作成中のオブジェクトの ID。
+    pub object_id: ObjectId,
+
+    /// 永続化済みの部分の数。
+    ///
+    /// 次に送信すべき部分の番号(0 始まり)でもある。
+    pub parts: u64,
+
+    /// 永続化済みのバイト数。
+    ///
+    /// 次に送信すべき部分の、オブジェクト内での開始位置でもある。
+    pub bytes: u64,
+
+    /// 作成中のオブジェクトの現在のバージョン。
+    ///
+    /// まだ一つも部分が永続化されていない場合には`None`となる。
+    pub version: Option<ObjectVersion>,
+}
+
+/// 部分の書き込みの要否。
+#[derive(Debug, PartialEq, Eq)]
+pub enum PartWrite {
+    /// 既に永続化済みの部分なので、書き込む必要はない。
+    Done(UploadStatus),
+
+    /// 書き込む必要がある。
+    ///
+    /// `append_to`が`None`の場合には(存在しないことを前提条件として)オブジェクトを作成し、
+    /// そうでなければ、そのバージョンに追記する。
+    Required {
+        bucket_id: BucketId,
+        object_id: ObjectId,
+        append_to: Option<ObjectVersion>,
+    },
+}
+
+#[derive(Debug)]
+struct Upload {
+    status: UploadStatus,
+    writing: bool,
+    last_activity: Instant,
+}
+
+/// 進行中のアップロードの一覧。
+#[derive(Debug, Clone)]
+pub struct UploadRegistry {
+    config: FrugalosUploadConfig,
+    path: Option<PathBuf>,
+    uploads: Arc<Mutex<HashMap<String, Upload>>>,
+    seqno: Arc<AtomicUsize>,
+}
+impl UploadRegistry {
+    /// 状態をメモリ上にのみ保持する、新しい`UploadRegistry`インスタンスを生成する。
+    pub fn new(config: FrugalosUploadConfig) -> Self {
+        UploadRegistry {
+            config,
+            path: None,
+            uploads: Arc::default(),
+            seqno: Arc::default(),
+        }
+    }
+
+    /// 状態を`path`のファイルに保存する`UploadRegistry`インスタンスを生成する。
+    ///
+    /// ファイルが既に存在する場合には、そこに保存されているアップロードを引き継ぐ。
+    pub fn open<P: AsRef<Path>>(config: FrugalosUploadConfig, path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut uploads = HashMap::new();
+        if path.exists() {
+            let bytes = track!(fs::read(&path).map_err(Error::from), "path={:?}", path)?;
+            let statuses: Vec<UploadStatus> = track!(
+                serde_json::from_slice(&bytes).map_err(|e| ErrorKind::InvalidInput.cause(e))
+            )?;
+            let now = Instant::now();
+            for status in statuses {
+                let upload = Upload {
+                    status,
+                    writing: false,
+                    last_activity: now,
+                };
+                uploads.insert(upload.status.upload_id.clone(), upload);
+            }
+        }
+        Ok(UploadRegistry {
+            config,
+            path: Some(path),
+            uploads: Arc::new(Mutex::new(uploads)),
+            seqno: Arc::default(),
+        })
+    }
+
+    /// 新しいアップロードを開始する。
+    pub fn start(&self, bucket_id: BucketId, object_id: ObjectId) -> Result<UploadStatus> {
+        let status = UploadStatus {
+            upload_id: self.next_upload_id(),
+            bucket_id,
+            object_id,
+            parts: 0,
+            bytes: 0,
+            version: None,
+        };
+        let upload = Upload {
+            status: status.clone(),
+            writing: false,
+            last_activity: Instant::now(),
+        };
+        let mut uploads = self.lock();
+        uploads.insert(status.upload_id.clone(), upload);
+        track!(self.save(&uploads))?;
+        Ok(status)
+    }
+
+    /// アップロードの状態を返す。
+    pub fn status(&self, upload_id: &str) -> Result<UploadStatus> {
+        let mut uploads = self.lock();
+        let upload = track!(get_upload(&mut uploads, upload_id))?;
+        upload.last_activity = Instant::now();
+        Ok(upload.status.clone())
+    }
+
+    /// `part`番目の部分の書き込みを開始する。
+    ///
+    /// 書き込みが必要な場合には、完了後に`end_part`を呼び出すこと。
+    /// 同時に書き込めるのは一つの部分のみで、部分は番号順に書き込まなければならない。
+    pub fn begin_part(&self, upload_id: &str, part: u64) -> Result<PartWrite> {
+        let mut uploads = self.lock();
+        let upload = track!(get_upload(&mut uploads, upload_id))?;
+        upload.last_activity = Instant::now();
+        if part < upload.status.parts {
+            return Ok(PartWrite::Done(upload.status.clone()));
+        }
+        track_assert!(
+            !upload.writing,
+            ErrorKind::InvalidInput,
+            "Another part is being written: upload_id={:?}",
+            upload_id
+        );
+        track_assert_eq!(
+            part,
+            upload.status.parts,
+            ErrorKind::InvalidInput,
+            "Unexpected part number: upload_id={:?}",
+            upload_id
+        );
+        upload.writing = true;
+        Ok(PartWrite::Required {
+            bucket_id: upload.status.bucket_id.clone(),
+            object_id: upload.status.object_id.clone(),
+            append_to: upload.status.version,
+        })
+    }
+
+    /// `begin_part`で開始した部分の書き込みが終了したことを記録する。
+    ///
+    /// `written`は、書き込みに成功した場合の新しいバージョンと部分のバイト数。
+    pub fn end_part(
+        &self,
+        upload_id: &str,
+        written: Option<(ObjectVersion, u64)>,
+    ) -> Result<UploadStatus> {
+        let mut uploads = self.lock();
+        let upload = track!(get_upload(&mut uploads, upload_id))?;
+        upload.writing = false;
+        upload.last_activity = Instant::now();
+        let status = if let Some((version, bytes)) = written {
+            upload.status.parts += 1;
+            upload.status.bytes += bytes;
+            upload.status.version = Some(version);
+            upload.status.clone()
+        } else {
+            return Ok(upload.status.clone());
+        };
+        track!(self.save(&uploads))?;
+        Ok(status)
+    }
+
+    /// アップロードを完了する。
+    ///
+    /// 作成されたオブジェクトはそのまま残り、以後はアップロード ID を使うことはできなくなる。
+    pub fn complete(&self, upload_id: &str) -> Result<UploadStatus> {
+        track!(self.remove(upload_id))
+    }
+
+    /// アップロードを中止する。
+    ///
+    /// 作成途中のオブジェクトの削除は、呼び出し元が(`delete_partial_object`を用いて)行う。
+    pub fn abort(&self, upload_id: &str) -> Result<UploadStatus> {
+        track!(self.remove(upload_id))
+    }
+
+    /// 放棄されたアップロードを定期的に削除する`Future`を返す。
+    pub fn gc(&self, logger: Logger, client: FrugalosClient) -> UploadGc {
+        UploadGc {
+            logger,
+            registry: self.clone(),
+            client,
+            timeout: timer::timeout(self.config.gc_interval),
+            deletions: FuturesUnordered::new(),
+        }
+    }
+
+    fn remove(&self, upload_id: &str) -> Result<UploadStatus> {
+        let mut uploads = self.lock();
+        let writing = track!(get_upload(&mut uploads, upload_id))?.writing;
+        track_assert!(
+            !writing,
+            ErrorKind::InvalidInput,
+            "A part is being written: upload_id={:?}",
+            upload_id
+        );
+        let upload = uploads.remove(upload_id).expect("Never fails");
+        track!(self.save(&uploads))?;
+        Ok(upload.status)
+    }
+
+    /// `now`の時点で放棄されていると見なせるアップロードを取り除いて返す。
+    ///
+    /// 取り除いた結果は保存されないので、続けて`flush`を呼び出すこと。
+    fn take_abandoned(&self, now: Instant) -> Vec<UploadStatus> {
+        let timeout = self.config.abandoned_timeout;
+        let mut uploads = self.lock();
+        let abandoned = uploads
+            .iter()
+            .filter(|(_, u)| {
+                !u.writing && now.saturating_duration_since(u.last_activity) >= timeout
+            })
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        abandoned
+            .into_iter()
+            .filter_map(|id| uploads.remove(&id))
+            .map(|u| u.status)
+            .collect()
+    }
+
+    /// 現在のアップロードの一覧をファイルに保存する。
+    fn flush(&self) -> Result<()> {
+        let uploads = self.lock();
+        track!(self.save(&uploads))
+    }
+
+    /// 現在のアップロードの一覧をファイルに保存する。
+    ///
+    /// 一時ファイルに書き出してから置き換えるので、途中でクラッシュしても以前の内容が残る。
+    fn save(&self, uploads: &HashMap<String, Upload>) -> Result<()> {
+        let path = if let Some(ref path) = self.path {
+            path
+        } else {
+            return Ok(());
+        };
+        let statuses = uploads.values().map(|u| &u.status).collect::<Vec<_>>();
+        let bytes = track!(serde_json::to_vec(&statuses).map_err(|e| ErrorKind::Other.cause(e)))?;
+        let temp_path = path.with_extension("saving");
+        {
+            let mut file = track!(File::create(&temp_path).map_err(Error::from))?;
+            track!(file.write_all(&bytes).map_err(Error::from))?;
+            track!(file.sync_all().map_err(Error::from))?;
+        }
+        track!(
+            fs::rename(&temp_path, path).map_err(Error::from),
+            "from={:?}, to={:?}",
+            temp_path,
+            path
+        )?;
+        Ok(())
+    }
+
+    fn next_upload_id(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let seqno = self.seqno.fetch_add(1, Ordering::SeqCst);
+        format!("{:x}-{:x}", now.as_nanos(), seqno)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Upload>> {
+        self.uploads.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn get_upload<'a>(uploads: &'a mut HashMap<String, Upload>, id: &str) -> Result<&'a mut Upload> {
+    let upload = track_assert_some!(
+        uploads.get_mut(id),
+        ErrorKind::NotFound,
+        "No such upload: {:?}",
+        id
+    );
+    Ok(upload)
+}
+
+/// 中止・放棄されたアップロードで作成途中だったオブジェクトを削除する。
+///
+/// オブジェクトが他の操作によって更新されていた場合には、削除は行わない。
+pub fn delete_partial_object(
+    client: &FrugalosClient,
+    status: &UploadStatus,
+) -> BoxFuture<Option<ObjectVersion>> {
+    if let Some(version) = status.version {
+        client
+            .request(status.bucket_id.clone())
+            .expect(Expect::IfMatch(vec![version]))
+            .delete(status.object_id.clone())
+    } else {
+        Box::new(futures::finished(None))
+    }
+}
+
+/// 放棄されたアップロードを定期的に削除する`Future`。
+///
+/// この`Future`が終了することはない。
+pub struct UploadGc {
+    logger: Logger,
+    registry: UploadRegistry,
+    client: FrugalosClient,
+    timeout: Timeout,
+    deletions: FuturesUnordered<BoxFuture<()>>,
+}
+impl UploadGc {
+    fn collect(&mut self) {
+        let abandoned = self.registry.take_abandoned(Instant::now());
+        if abandoned.is_empty() {
+            return;
+        }
+        if let Err(e) = track!(self.registry.flush()) {
+            warn!(self.logger, "Cannot save the state of uploads: {}", e);
+        }
+        for status in abandoned {
+            info!(
+                self.logger,
+                "Deletes an abandoned upload: {}",
+                dump!(status.upload_id, status.parts);
+                logging::BUCKET => &status.bucket_id,
+                logging::OBJECT_ID => &status.object_id
+            );
+            let logger = self.logger.clone();
+            let future = delete_partial_object(&self.client, &status).then(move |result| {
+                if let Err(e) = result {
+                    warn!(
+                        logger,
+                        "Cannot delete the object of an abandoned upload: upload_id={:?}, {}",
+                        status.upload_id,
+                        e
+                    );
+                }
+                Ok(())
+            });
+            self.deletions.push(Box::new(future));
+        }
+    }
+}
+impl Future for UploadGc {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while track!(self.timeout.poll().map_err(Error::from))?.is_ready() {
+            self.timeout = timer::timeout(self.registry.config.gc_interval);
+            self.collect();
+        }
+        while let Async::Ready(Some(())) = track!(self.deletions.poll())? {}
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    fn registry() -> UploadRegistry {
+        UploadRegistry::new(FrugalosUploadConfig::default())
+    }
+
+    #[test]
+    fn parts_are_written_in_order() -> Result<()> {
+        let registry = registry();
+        let upload = registry.start("bucket".to_owned(), "object".to_owned())?;
+        let id = upload.upload_id.as_str();
+        assert_eq!(upload.parts, 0);
+
+        // 最初の部分は新規作成になる
+        assert_eq!(
+            registry.begin_part(id, 0)?,
+            PartWrite::Required {
+                bucket_id: "bucket".to_owned(),
+                object_id: "object".to_owned(),
+                append_to: None,
+            }
+        );
+        // 書き込み中の部分と、その次の部分は受け付けない
+        assert!(registry.begin_part(id, 0).is_err());
+        assert!(registry.begin_part(id, 1).is_err());
+        let status = registry.end_part(id, Some((ObjectVersion(10), 100)))?;
+        assert_eq!((status.parts, status.bytes), (1, 100));
+
+        // 以降の部分は、直前のバージョンへの追記になる
+        match registry.begin_part(id, 1)? {
+            PartWrite::Required { append_to, .. } => {
+                assert_eq!(append_to, Some(ObjectVersion(10)))
+            }
+            w => panic!("{:?}", w),
+        }
+        // 失敗した場合には、同じ部分を再送できる
+        registry.end_part(id, None)?;
+        assert!(registry.begin_part(id, 1).is_ok());
+        let status = registry.end_part(id, Some((ObjectVersion(11), 50)))?;
+        assert_eq!((status.parts, status.bytes), (2, 150));
+        assert_eq!(status.version, Some(ObjectVersion(11)));
+
+        // 永続化済みの部分の再送は無視される
+        assert_eq!(registry.begin_part(id, 0)?, PartWrite::Done(status.clone()));
+        assert!(registry.begin_part(id, 3).is_err());
+
+        assert_eq!(registry.complete(id)?, status);
+        assert_eq!(
+            registry.status(id).err().map(|e| e.kind().clone()),
+            Some(ErrorKind::NotFound)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn abandoned_uploads_are_collected() -> Result<()> {
+        let registry = registry();
+        let timeout = registry.config.abandoned_timeout;
+        let idle = registry.start("bucket".to_owned(), "idle".to_owned())?;
+        let writing = registry.start("bucket".to_owned(), "writing".to_owned())?;
+        registry.begin_part(&writing.upload_id, 0)?;
+        let later = Instant::now() + timeout + Duration::from_secs(1);
+
+        let abandoned = registry.take_abandoned(later);
+        assert_eq!(abandoned, vec![idle.clone()]);
+        assert!(registry.status(&idle.upload_id).is_err());
+
+        // 書き込み中のアップロードは、中止もできない
+        assert!(registry.abort(&writing.upload_id).is_err());
+        registry.end_part(&writing.upload_id, None)?;
+        assert!(registry.abort(&writing.upload_id).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn uploads_survive_restarts() -> Result<()> {
+        let dir = track!(TempDir::new("frugalos_test").map_err(Error::from))?;
+        let path = dir.path().join("uploads.json");
+        let config = FrugalosUploadConfig::default();
+
+        let registry = track!(UploadRegistry::open(config.clone(), &path))?;
+        let upload = track!(registry.start("bucket".to_owned(), "object".to_owned()))?;
+        let id = upload.upload_id.as_str();
+        track!(registry.begin_part(id, 0))?;
+        let status = track!(registry.end_part(id, Some((ObjectVersion(10), 100))))?;
+
+        // 書き込み中だった部分は、再起動後に再送できる
+        track!(registry.begin_part(id, 1))?;
+        let registry = track!(UploadRegistry::open(config.clone(), &path))?;
+        assert_eq!(track!(registry.status(id))?, status);
+        assert!(registry.begin_part(id, 1).is_ok());
+        track!(registry.end_part(id, None))?;
+
+        track!(registry.complete(id))?;
+        let registry = track!(UploadRegistry::open(config, &path))?;
+        assert!(registry.status(id).is_err());
+        Ok(())
+    }
+}
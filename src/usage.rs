@@ -0,0 +1,103 @@
+//! ローカルデバイスの使用量を、構成管理用のクラスタに定期的に報告するためのモジュール。
+//!
+//! 報告された使用量は構成管理用の Raft クラスタで複製され、以降に作成されるバケツのセグメント配置で、
+//! 空き容量の多いデバイスを優先するために使用される。
+//! 既存のバケツのセグメント配置が変わることはない。
+use fibers::time::timer::{self, Timeout};
+use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
+use fibers_rpc::Call;
+use frugalos_config::schema::PutDeviceUsagesRpc;
+use frugalos_config::{DeviceUsage, ServiceHandle as ConfigServiceHandle};
+use frugalos_core::cluster_feature::{self, ClusterFeature};
+use frugalos_core::rpc_auth;
+use futures::{Async, Future};
+use prometrics::metrics::{Counter, MetricBuilder};
+use slog::Logger;
+use std::time::Duration;
+
+use {Error, Result};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+
+/// ローカルデバイスの使用量を定期的に報告する。
+///
+/// 報告する使用量自体は、呼び出し側が`report`メソッドで渡す。
+pub struct DeviceUsageReporter {
+    logger: Logger,
+    rpc_service: RpcServiceHandle,
+    interval: Duration,
+    timeout: Timeout,
+    report: Option<BoxFuture<()>>,
+    report_failures: Counter,
+}
+impl DeviceUsageReporter {
+    /// 新しい`DeviceUsageReporter`を生成する。
+    pub fn new(logger: Logger, rpc_service: RpcServiceHandle, interval: Duration) -> Result<Self> {
+        let report_failures = track!(MetricBuilder::new()
+            .namespace("frugalos")
+            .subsystem("device_usage")
+            .counter("report_failures_total")
+            .help("Number of failures of reporting the usages of devices")
+            .default_registry()
+            .finish())?;
+        Ok(DeviceUsageReporter {
+            logger,
+            rpc_service,
+            interval,
+            timeout: timer::timeout(interval),
+            report: None,
+            report_failures,
+        })
+    }
+
+    /// 実行中の報告を進め、次の報告を行うべき時刻になっていれば`true`を返す。
+    ///
+    /// 前回の報告が終わっていない場合には、今回の報告はスキップされる。
+    pub fn poll_tick(&mut self) -> Result<bool> {
+        if let Some(mut report) = self.report.take() {
+            match report.poll() {
+                Ok(Async::NotReady) => self.report = Some(report),
+                Ok(Async::Ready(())) => {}
+                Err(e) => {
+                    self.report_failures.increment();
+                    warn!(self.logger, "Cannot report the usages of devices: {}", e);
+                }
+            }
+        }
+
+        let mut tick = false;
+        while track!(self.timeout.poll().map_err(Error::from))?.is_ready() {
+            self.timeout = timer::timeout(self.interval);
+            tick = true;
+        }
+        Ok(tick && self.report.is_none())
+    }
+
+    /// デバイスの使用量を、構成管理用クラスタのリーダに報告する。
+    ///
+    /// 古いバージョンのサーバは使用量のコマンドを解釈できないので、
+    /// `config_extensions`機能が有効になるまでは報告しない。
+    pub fn report(&mut self, config_service: &ConfigServiceHandle, usages: Vec<DeviceUsage>) {
+        if usages.is_empty() || self.report.is_some() {
+            return;
+        }
+        if !cluster_feature::is_enabled(ClusterFeature::ConfigExtensions) {
+            return;
+        }
+        debug!(
+            self.logger,
+            "Reports the usages of devices: {}",
+            dump!(usages.len())
+        );
+        let rpc_service = self.rpc_service.clone();
+        let future = config_service
+            .get_leader()
+            .map_err(|e| track!(Error::from(e)))
+            .and_then(move |leader| {
+                rpc_auth::call::<PutDeviceUsagesRpc>(&rpc_service, leader, usages)
+                    .map_err(|e| track!(Error::from(e)))
+            })
+            .and_then(|result| track!(result.map_err(Error::from)));
+        self.report = Some(Box::new(future));
+    }
+}
@@ -0,0 +1,215 @@
+//! 起動直後に、他のサーバへの RPC 接続を事前に確立しておくためのモジュール。
+//!
+//! RPC の接続は最初の呼び出し時に確立されるので、何もしなければ再起動直後のクライアントのリクエストが
+//! 名前解決と接続確立の遅延を被ることになる。
+//! そこで、クラスタ構成に登録されたサーバ群に対して並行して名前解決と軽量な RPC 呼び出しを行い、
+//! それが終わるまでは readiness を not-ready にしておく。
+//!
+//! デバイスへのアクセス(cannyls の RPC)も同じサーバのアドレスへの接続を共有するので、
+//! サーバ毎に一度接続を確立すれば十分である。
+use fibers::time::timer::{self, Timeout};
+use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
+use fibers_rpc::Call;
+use futures::{Async, Future, Poll};
+use libfrugalos::entity::server::ServerId;
+use prometrics::metrics::{Counter, Gauge, MetricBuilder};
+use slog::Logger;
+use std::time::Instant;
+
+use discovery::ResolveAddr;
+use lifecycle::{Lifecycle, LifecyclePhase};
+use schema::GetServerTimeRpc;
+use {Error, FrugalosWarmupConfig, Result};
+
+type WarmupFuture = Box<dyn Future<Item = ServerId, Error = (ServerId, Error)> + Send>;
+
+/// 起動時の接続のウォームアップを行う `Future`。
+///
+/// ウォームアップが完了すると、ライフサイクルのフェーズを `WarmingUp` から `Running` に変更する。
+/// この `Future` が終了することはない。
+pub struct ConnectionWarmup {
+    logger: Logger,
+    rpc_service: RpcServiceHandle,
+    local_server: ServerId,
+    lifecycle: Lifecycle,
+    config: FrugalosWarmupConfig,
+    started_at: Instant,
+    warming: Vec<WarmupFuture>,
+    settle_timeout: Timeout,
+    settled: bool,
+    deadline: Timeout,
+    servers: usize,
+    failures: usize,
+    done: bool,
+    metrics: WarmupMetrics,
+}
+impl ConnectionWarmup {
+    /// 新しい `ConnectionWarmup` を生成する。
+    ///
+    /// ウォームアップが有効な場合には、ライフサイクルのフェーズを `WarmingUp` にする。
+    pub fn new(
+        logger: Logger,
+        rpc_service: RpcServiceHandle,
+        local_server: ServerId,
+        lifecycle: Lifecycle,
+        config: &FrugalosWarmupConfig,
+    ) -> Result<Self> {
+        let metrics = track!(WarmupMetrics::new())?;
+        if config.enabled {
+            lifecycle.set_phase(LifecyclePhase::WarmingUp);
+        }
+        Ok(ConnectionWarmup {
+            logger,
+            rpc_service,
+            local_server,
+            lifecycle,
+            config: config.clone(),
+            started_at: Instant::now(),
+            warming: Vec::new(),
+            settle_timeout: timer::timeout(config.settle_time),
+            settled: false,
+            deadline: timer::timeout(config.timeout),
+            servers: 0,
+            failures: 0,
+            done: !config.enabled,
+            metrics,
+        })
+    }
+
+    /// ウォームアップが完了しているかどうかを返す。
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// クラスタ構成に登録されたサーバへの接続を開始する。
+    ///
+    /// `addr`は、そのサーバの名前解決後のアドレス。
+    /// 自サーバ、およびウォームアップの完了後に登録されたサーバは無視される。
+    pub fn put_server(&mut self, server: &ServerId, addr: ResolveAddr) {
+        if self.done || *server == self.local_server {
+            return;
+        }
+        let id = server.clone();
+        let rpc_service = self.rpc_service.clone();
+        let future = addr
+            .and_then(move |addr| {
+                GetServerTimeRpc::client(&rpc_service)
+                    .call(addr, ())
+                    .map_err(|e| track!(Error::from(e)))
+            })
+            .then(move |result| match result {
+                Ok(_) => Ok(id),
+                Err(e) => Err((id, e)),
+            });
+        self.warming.push(Box::new(future));
+        self.servers += 1;
+
+        // 続けて他のサーバが登録される可能性があるので、待ち直す
+        self.settle_timeout = timer::timeout(self.config.settle_time);
+        self.settled = false;
+    }
+
+    fn complete(&mut self, expired: bool) {
+        self.done = true;
+        let elapsed = self.started_at.elapsed();
+        self.metrics
+            .duration_seconds
+            .set(prometrics::timestamp::duration_to_seconds(elapsed));
+        if expired {
+            warn!(
+                self.logger,
+                "Connection warm-up timed out: servers={}, failures={}, unfinished={}, elapsed={:?}",
+                self.servers,
+                self.failures,
+                self.warming.len(),
+                elapsed
+            );
+        } else {
+            info!(
+                self.logger,
+                "Connection warm-up completed: servers={}, failures={}, elapsed={:?}",
+                self.servers,
+                self.failures,
+                elapsed
+            );
+        }
+        self.warming.clear();
+        self.lifecycle
+            .transit(LifecyclePhase::WarmingUp, LifecyclePhase::Running);
+    }
+}
+impl Future for ConnectionWarmup {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.done {
+            return Ok(Async::NotReady);
+        }
+
+        let mut i = 0;
+        while i < self.warming.len() {
+            match self.warming[i].poll() {
+                Ok(Async::NotReady) => {
+                    i += 1;
+                    continue;
+                }
+                Ok(Async::Ready(_)) => {
+                    self.metrics.connected.increment();
+                }
+                Err((server, e)) => {
+                    self.failures += 1;
+                    self.metrics.failures.increment();
+                    warn!(
+                        self.logger,
+                        "Cannot warm up the connection to the server: server={}, error={}",
+                        server,
+                        e
+                    );
+                }
+            }
+            let _ = self.warming.swap_remove(i);
+        }
+
+        if !self.settled && track!(self.settle_timeout.poll().map_err(Error::from))?.is_ready() {
+            self.settled = true;
+        }
+        if self.settled && self.warming.is_empty() {
+            self.complete(false);
+        } else if track!(self.deadline.poll().map_err(Error::from))?.is_ready() {
+            self.complete(true);
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+struct WarmupMetrics {
+    connected: Counter,
+    failures: Counter,
+    duration_seconds: Gauge,
+}
+impl WarmupMetrics {
+    fn new() -> Result<Self> {
+        let mut builder = MetricBuilder::new();
+        builder.namespace("frugalos").subsystem("warmup");
+        let connected = track!(builder
+            .counter("connected_servers_total")
+            .help("Number of servers connected during the startup warm-up")
+            .default_registry()
+            .finish())?;
+        let failures = track!(builder
+            .counter("failures_total")
+            .help("Number of servers that could not be connected during the startup warm-up")
+            .default_registry()
+            .finish())?;
+        let duration_seconds = track!(builder
+            .gauge("duration_seconds")
+            .help("Time taken by the startup warm-up")
+            .default_registry()
+            .finish())?;
+        Ok(WarmupMetrics {
+            connected,
+            failures,
+            duration_seconds,
+        })
+    }
+}
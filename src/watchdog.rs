@@ -0,0 +1,215 @@
+//! 停滞しているバックグラウンドタスクを検出するためのモジュール。
+//!
+//! `frugalos_core::task_dump` に報告されている各タスクの状態を定期的に確認して、
+//! リペアやセグメントGC、スナップショットの保存等が一定時間以上進んでいない場合には、
+//! 警告ログとメトリクスで通知する。
+//!
+//! 設定によっては、停滞しているタスクに中断を要求することもできる
+//! (e.g., 中断されたリペアはキューに戻されて、後で再実行される)。
+use fibers::time::timer::{self, Timeout};
+use frugalos_core::task_dump::{self, TaskSnapshot};
+use futures::{Future, Poll};
+use prometrics::metrics::{Counter, Gauge, MetricBuilder};
+use slog::Logger;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use {Error, FrugalosWatchdogConfig, Result};
+
+/// 監視対象となるタスクの種類と状態。
+struct WatchedTask {
+    kind: &'static str,
+    states: &'static [&'static str],
+
+    /// 中断の要求に応じるかどうか。
+    cancellable: bool,
+}
+
+const WATCHED_TASKS: &[WatchedTask] = &[
+    WatchedTask {
+        kind: "repair_queue_executor",
+        states: &["Repair"],
+        cancellable: true,
+    },
+    WatchedTask {
+        kind: "synchronizer",
+        states: &["SegmentGc"],
+        cancellable: true,
+    },
+    WatchedTask {
+        kind: "mds_node",
+        states: &["EncodingSnapshot", "InstallingSnapshot", "DecodingSnapshot"],
+        cancellable: false,
+    },
+];
+
+fn find_watched(task: &TaskSnapshot) -> Option<&'static WatchedTask> {
+    WATCHED_TASKS
+        .iter()
+        .find(|w| w.kind == task.kind && w.states.contains(&task.state.as_str()))
+}
+
+fn is_stalled(task: &TaskSnapshot, threshold: Duration) -> bool {
+    find_watched(task).is_some() && Duration::from_millis(task.progress_elapsed_millis) >= threshold
+}
+
+struct WatchdogMetrics {
+    stalled_tasks: Gauge,
+    stalled_tasks_total: Counter,
+    cancelled_tasks_total: Counter,
+}
+impl WatchdogMetrics {
+    fn new(kind: &str) -> Result<Self> {
+        let mut builder = MetricBuilder::new();
+        builder
+            .namespace("frugalos")
+            .subsystem("watchdog")
+            .label("kind", kind);
+        Ok(WatchdogMetrics {
+            stalled_tasks: track!(builder
+                .gauge("stalled_tasks")
+                .help("Number of background tasks which are currently stalled")
+                .default_registry()
+                .finish())?,
+            stalled_tasks_total: track!(builder
+                .counter("stalled_tasks_total")
+                .help("Number of detected stalls of background tasks")
+                .default_registry()
+                .finish())?,
+            cancelled_tasks_total: track!(builder
+                .counter("cancelled_tasks_total")
+                .help("Number of stalled background tasks cancelled by the watchdog")
+                .default_registry()
+                .finish())?,
+        })
+    }
+}
+
+/// 停滞しているバックグラウンドタスクを定期的に検出する `Future`。
+///
+/// この `Future` が終了することはない。
+pub struct Watchdog {
+    logger: Logger,
+    config: FrugalosWatchdogConfig,
+    timeout: Timeout,
+    metrics: HashMap<&'static str, WatchdogMetrics>,
+
+    // 停滞中として報告済みのタスクの ID 群
+    stalled: HashSet<u64>,
+}
+impl Watchdog {
+    /// 新しい `Watchdog` を生成する。
+    pub fn new(logger: Logger, config: FrugalosWatchdogConfig) -> Result<Self> {
+        let mut metrics = HashMap::new();
+        for watched in WATCHED_TASKS {
+            metrics.insert(watched.kind, track!(WatchdogMetrics::new(watched.kind))?);
+        }
+        Ok(Watchdog {
+            logger,
+            timeout: timer::timeout(config.check_interval),
+            config,
+            metrics,
+            stalled: HashSet::new(),
+        })
+    }
+
+    fn check(&mut self) {
+        let mut stalled = HashSet::new();
+        let mut counts = HashMap::new();
+        for task in task_dump::dump() {
+            if !is_stalled(&task, self.config.stall_threshold) {
+                continue;
+            }
+            let watched = find_watched(&task).expect("Never fails");
+            stalled.insert(task.id);
+            *counts.entry(watched.kind).or_insert(0) += 1;
+            if self.stalled.contains(&task.id) {
+                continue;
+            }
+
+            warn!(
+                self.logger,
+                "A background task has made no progress: kind={}, name={}, state={}, elapsed={:?}",
+                task.kind,
+                task.name,
+                task.state,
+                Duration::from_millis(task.progress_elapsed_millis);
+                "kind" => &task.kind,
+                "name" => &task.name,
+                "state" => &task.state,
+                "progress_elapsed_millis" => task.progress_elapsed_millis
+            );
+            let metrics = &self.metrics[watched.kind];
+            metrics.stalled_tasks_total.increment();
+            if self.config.cancel_stalled
+                && watched.cancellable
+                && task_dump::request_cancel(task.id)
+            {
+                info!(
+                    self.logger,
+                    "Requested cancellation of the stalled task: kind={}, name={}",
+                    task.kind,
+                    task.name
+                );
+                metrics.cancelled_tasks_total.increment();
+            }
+        }
+        for (kind, metrics) in &self.metrics {
+            metrics
+                .stalled_tasks
+                .set(f64::from(counts.get(kind).cloned().unwrap_or(0)));
+        }
+        self.stalled = stalled;
+    }
+}
+impl Future for Watchdog {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while track!(self.timeout.poll().map_err(Error::from))?.is_ready() {
+            self.timeout = timer::timeout(self.config.check_interval);
+            self.check();
+        }
+        Ok(futures::Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn task(kind: &str, state: &str, progress_elapsed_millis: u64) -> TaskSnapshot {
+        TaskSnapshot {
+            id: 0,
+            kind: kind.to_owned(),
+            name: "0".to_owned(),
+            state: state.to_owned(),
+            state_elapsed_millis: progress_elapsed_millis,
+            progress_elapsed_millis,
+            queues: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn is_stalled_works() {
+        let threshold = Duration::from_secs(60);
+        assert!(is_stalled(
+            &task("repair_queue_executor", "Repair", 60_000),
+            threshold
+        ));
+        assert!(!is_stalled(
+            &task("repair_queue_executor", "Repair", 59_999),
+            threshold
+        ));
+        assert!(!is_stalled(
+            &task("repair_queue_executor", "Idle", 60_000),
+            threshold
+        ));
+        assert!(is_stalled(
+            &task("mds_node", "EncodingSnapshot", 60_000),
+            threshold
+        ));
+        assert!(!is_stalled(&task("mds_node", "Leader", 60_000), threshold));
+    }
+}
@@ -0,0 +1,268 @@
+//! クラスタの状態を確認するための、簡易な Web UI。
+//!
+//! `web-ui`フィーチャを有効にしてビルドした場合にのみ、HTTP サーバの`/ui`で提供される。
+//!
+//! UI 自体は静的な HTML(`index.html`)で、表示に必要な情報は既存の HTTP API
+//! (`/v1/buckets`、`/v1/servers`、`/v1/dashboard`等)から取得する。
+//! 以下の安全な操作については、既存の管理用 RPC をローカルのサーバに発行する HTTP API を追加で提供する:
+//!
+//! - `POST /v1/frugalos/snapshot`: スナップショットの取得
+//! - `GET /v1/frugalos/segment_gc`: 各ノードの segment_gc (FullSync) の状態の取得
+//! - `POST /v1/frugalos/segment_gc`: segment_gc の開始
+//! - `PUT /v1/frugalos/repair_config`: リペアの設定の変更(リペアの一時停止・再開に使用する)
+//!
+//! これらの API には他の HTTP API と同じ認可が適用され、参照にはクラスタの参照権限が、
+//! 操作にはクラスタの管理権限が必要となる。
+//! ローカルのサーバへの RPC には、クラスタトークンが設定されていればそれが付けられる。
+//!
+//! 認可が無効な場合でも他のサイトから操作を発行されないように(CSRF 対策)、
+//! 操作の API は`Content-Type: application/json`を伴うリクエストのみを受け付ける。
+//! この`Content-Type`はブラウザにとって単純リクエストとはならないので、
+//! 他のオリジンからのリクエストには事前の CORS プリフライトが必要となり、このサーバはそれを許可しない。
+use bytecodec::bytes::BytesEncoder;
+use bytecodec::json_codec::{JsonDecoder, JsonEncoder};
+use bytecodec::null::NullDecoder;
+use fibers_http_server::{
+    HandleRequest, Reply, Req, Res, ServerBuilder as HttpServerBuilder, Status,
+};
+use fibers_rpc::client::ClientServiceHandle as RpcServiceHandle;
+use fibers_rpc::Call;
+use frugalos_core::rpc_auth;
+use frugalos_segment::schema as segment_schema;
+use frugalos_segment::SegmentGcStatus;
+use futures::{self, Future};
+use httpcodec::{BodyDecoder, BodyEncoder, HeaderField};
+use libfrugalos::repair::RepairConfig;
+use libfrugalos::schema::frugalos::{SetRepairConfigRpc, TakeSnapshotRpc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::net::SocketAddr;
+use trackable::error::ErrorKindExt;
+
+use auth::{Permission, SharedAuthorizer, WithAuth};
+use http::{make_json_response, HttpResult};
+use {Error, ErrorKind, Result};
+
+const INDEX_HTML: &str = include_str!("index.html");
+
+#[derive(Debug)]
+struct ContentTypeHtml;
+impl From<ContentTypeHtml> for HeaderField<'static, 'static> {
+    fn from(_: ContentTypeHtml) -> Self {
+        unsafe { HeaderField::new_unchecked("Content-Type", "text/html; charset=utf-8") }
+    }
+}
+
+#[derive(Clone)]
+pub struct WebUi {
+    rpc_service: RpcServiceHandle,
+    local_addr: SocketAddr,
+    authorizer: SharedAuthorizer,
+}
+impl WebUi {
+    pub fn new(
+        rpc_service: RpcServiceHandle,
+        local_addr: SocketAddr,
+        authorizer: SharedAuthorizer,
+    ) -> Self {
+        WebUi {
+            rpc_service,
+            local_addr,
+            authorizer,
+        }
+    }
+    pub fn register(self, builder: &mut HttpServerBuilder) -> Result<()> {
+        let read = Permission::Read;
+        let admin = Permission::Admin;
+        let auth = || self.authorizer.clone();
+        track!(builder.add_handler(Index))?;
+        track!(builder.add_handler(WithAuth::cluster(
+            RequireJson(TakeSnapshot(self.clone())),
+            auth(),
+            admin
+        )))?;
+        track!(builder.add_handler(WithAuth::cluster(
+            GetSegmentGcStatus(self.clone()),
+            auth(),
+            read
+        )))?;
+        track!(builder.add_handler(WithAuth::cluster(
+            RequireJson(StartSegmentGc(self.clone())),
+            auth(),
+            admin
+        )))?;
+        track!(builder.add_handler(WithAuth::cluster(
+            RequireJson(SetRepairConfig(self.clone())),
+            auth(),
+            admin
+        )))?;
+        Ok(())
+    }
+    fn call_rpc<T, V>(&self, request: T::Req) -> impl Future<Item = V, Error = Error>
+    where
+        T: Call<Res = libfrugalos::Result<V>>,
+        T::Req: Serialize + DeserializeOwned + Send + 'static,
+        T::ReqEncoder: Default,
+        T::ResDecoder: Default,
+        V: Send + 'static,
+    {
+        rpc_auth::call::<T>(&self.rpc_service, self.local_addr, request)
+            .map_err(Error::from)
+            .and_then(|result| result.map_err(Error::from))
+    }
+}
+
+fn reply<T, F>(future: F) -> Reply<HttpResult<T>>
+where
+    F: Future<Item = T, Error = Error> + Send + 'static,
+    T: Send + 'static,
+{
+    let future = future.then(|result| {
+        let (status, body) = match track!(result) {
+            Err(e) => (Status::InternalServerError, Err(e)),
+            Ok(v) => (Status::Ok, Ok(v)),
+        };
+        Ok(make_json_response(status, body))
+    });
+    Box::new(future)
+}
+
+/// 操作用の HTTP ハンドラをラップして、`Content-Type: application/json`のリクエストのみを受け付ける。
+///
+/// HTML のフォーム等から送信できる単純リクエストを拒否することで、CSRF を防ぐ。
+struct RequireJson<H>(H);
+impl<H, T> HandleRequest for RequireJson<H>
+where
+    H: HandleRequest<ResBody = HttpResult<T>>,
+{
+    const METHOD: &'static str = H::METHOD;
+    const PATH: &'static str = H::PATH;
+
+    type ReqBody = H::ReqBody;
+    type ResBody = H::ResBody;
+    type Decoder = H::Decoder;
+    type Encoder = H::Encoder;
+    type Reply = H::Reply;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        self.0.handle_request(req)
+    }
+
+    fn handle_request_head(&self, req: &Req<()>) -> Option<Res<Self::ResBody>> {
+        let is_json = req.header().fields().any(|f| {
+            f.name().eq_ignore_ascii_case("content-type") && is_json_content_type(f.value())
+        });
+        if !is_json {
+            let e = ErrorKind::InvalidInput.cause("`Content-Type: application/json` is required");
+            return Some(make_json_response(Status::Forbidden, Err(track!(e).into())));
+        }
+        self.0.handle_request_head(req)
+    }
+}
+
+fn is_json_content_type(value: &str) -> bool {
+    value
+        .split(';')
+        .next()
+        .map_or(false, |t| t.trim().eq_ignore_ascii_case("application/json"))
+}
+
+struct Index;
+impl HandleRequest for Index {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/ui";
+
+    type ReqBody = ();
+    type ResBody = Vec<u8>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<BytesEncoder<Vec<u8>>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        let mut res = Res::new(Status::Ok, INDEX_HTML.as_bytes().to_owned());
+        res.header_mut().add_field(ContentTypeHtml);
+        Box::new(futures::finished(res))
+    }
+}
+
+struct TakeSnapshot(WebUi);
+impl HandleRequest for TakeSnapshot {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/v1/frugalos/snapshot";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<()>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        reply(self.0.call_rpc::<TakeSnapshotRpc, _>(()))
+    }
+}
+
+struct GetSegmentGcStatus(WebUi);
+impl HandleRequest for GetSegmentGcStatus {
+    const METHOD: &'static str = "GET";
+    const PATH: &'static str = "/v1/frugalos/segment_gc";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<Vec<SegmentGcStatus>>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        reply(
+            self.0
+                .call_rpc::<segment_schema::GetSegmentGcStatusRpc, _>(()),
+        )
+    }
+}
+
+struct StartSegmentGc(WebUi);
+impl HandleRequest for StartSegmentGc {
+    const METHOD: &'static str = "POST";
+    const PATH: &'static str = "/v1/frugalos/segment_gc";
+
+    type ReqBody = ();
+    type ResBody = HttpResult<Vec<String>>;
+    type Decoder = BodyDecoder<NullDecoder>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, _req: Req<Self::ReqBody>) -> Self::Reply {
+        reply(self.0.call_rpc::<segment_schema::StartSegmentGcRpc, _>(()))
+    }
+}
+
+struct SetRepairConfig(WebUi);
+impl HandleRequest for SetRepairConfig {
+    const METHOD: &'static str = "PUT";
+    const PATH: &'static str = "/v1/frugalos/repair_config";
+
+    type ReqBody = RepairConfig;
+    type ResBody = HttpResult<()>;
+    type Decoder = BodyDecoder<JsonDecoder<Self::ReqBody>>;
+    type Encoder = BodyEncoder<JsonEncoder<Self::ResBody>>;
+    type Reply = Reply<Self::ResBody>;
+
+    fn handle_request(&self, req: Req<Self::ReqBody>) -> Self::Reply {
+        let repair_config = req.into_body();
+        reply(self.0.call_rpc::<SetRepairConfigRpc, _>(repair_config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_json_content_type_works() {
+        assert!(is_json_content_type("application/json"));
+        assert!(is_json_content_type("Application/JSON; charset=utf-8"));
+        assert!(!is_json_content_type("text/plain"));
+        assert!(!is_json_content_type("application/x-www-form-urlencoded"));
+        assert!(!is_json_content_type(""));
+    }
+}
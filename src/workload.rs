@@ -0,0 +1,555 @@
+//! 実際のリクエストの流れを記録・再生するためのモジュール。
+//!
+//! サンプリングしたリクエストについて、操作の種類・オブジェクトのサイズ・処理時間等のみを記録する。
+//! オブジェクト ID はプロセス毎にランダムな鍵でハッシュ化されるので、記録から元の ID を知ることはできない。
+//!
+//! 記録したファイルは `frugalos bench` で、テスト用のクラスタに対して再生することができる。
+use fibers::time::timer;
+use fibers::{Executor, Spawn, ThreadPoolExecutor};
+use fibers_rpc::client::ClientServiceBuilder as RpcServiceBuilder;
+use futures::{self, Future, Stream};
+use libfrugalos;
+use libfrugalos::consistency::ReadConsistency;
+use libfrugalos::entity::bucket::BucketId;
+use libfrugalos::entity::object::ObjectId;
+use libfrugalos::expect::Expect;
+use num_cpus;
+use slog::Logger;
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::io::{BufReader, BufWriter, ErrorKind as IoErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use {Error, ErrorKind, FrugalosWorkloadRecorderConfig, Result};
+
+/// 記録ファイルの先頭に置かれるマジックナンバー。
+const MAGIC: &[u8; 8] = b"FRGWKLD1";
+
+/// 一つの記録のエンコード後のサイズ。
+const RECORD_SIZE: usize = 34;
+
+/// 書き込み待ちの記録の最大数。
+///
+/// これを超えた記録は破棄される。
+const MAX_PENDING_RECORDS: usize = 4096;
+
+/// 操作の種類。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OperationKind {
+    /// GET
+    Get,
+
+    /// HEAD
+    Head,
+
+    /// PUT
+    Put,
+
+    /// DELETE
+    Delete,
+}
+impl OperationKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            OperationKind::Get => 0,
+            OperationKind::Head => 1,
+            OperationKind::Put => 2,
+            OperationKind::Delete => 3,
+        }
+    }
+
+    fn from_u8(n: u8) -> Result<Self> {
+        match n {
+            0 => Ok(OperationKind::Get),
+            1 => Ok(OperationKind::Head),
+            2 => Ok(OperationKind::Put),
+            3 => Ok(OperationKind::Delete),
+            _ => track_panic!(ErrorKind::InvalidInput, "Unknown operation: {}", n),
+        }
+    }
+}
+
+/// 一つの操作の記録。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperationRecord {
+    /// 記録開始から、操作が開始されるまでの時間。
+    pub offset: Duration,
+
+    /// 操作の種類。
+    pub kind: OperationKind,
+
+    /// 匿名化されたオブジェクト ID。
+    pub object: u64,
+
+    /// 読み書きしたオブジェクトのサイズ(バイト単位)。
+    pub size: u64,
+
+    /// 操作にかかった時間。
+    pub latency: Duration,
+
+    /// 操作が成功したかどうか。
+    pub ok: bool,
+}
+impl OperationRecord {
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut buf = [0; RECORD_SIZE];
+        buf[0..8].copy_from_slice(&as_micros(self.offset).to_le_bytes());
+        buf[8] = self.kind.to_u8();
+        buf[9] = self.ok as u8;
+        buf[10..18].copy_from_slice(&self.object.to_le_bytes());
+        buf[18..26].copy_from_slice(&self.size.to_le_bytes());
+        buf[26..34].copy_from_slice(&as_micros(self.latency).to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8; RECORD_SIZE]) -> Result<Self> {
+        let u64_at = |i: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&buf[i..i + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        Ok(OperationRecord {
+            offset: Duration::from_micros(u64_at(0)),
+            kind: track!(OperationKind::from_u8(buf[8]))?,
+            ok: buf[9] != 0,
+            object: u64_at(10),
+            size: u64_at(18),
+            latency: Duration::from_micros(u64_at(26)),
+        })
+    }
+}
+
+fn as_micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())
+}
+
+/// 記録を書き出すための構造体。
+#[derive(Debug)]
+pub struct WorkloadWriter<W: Write> {
+    inner: W,
+}
+impl<W: Write> WorkloadWriter<W> {
+    /// 新しい`WorkloadWriter`を生成する。
+    pub fn new(mut inner: W) -> Result<Self> {
+        track!(inner.write_all(MAGIC).map_err(Error::from))?;
+        Ok(WorkloadWriter { inner })
+    }
+
+    /// 記録を一つ書き出す。
+    pub fn write(&mut self, record: &OperationRecord) -> Result<()> {
+        track!(self.inner.write_all(&record.encode()).map_err(Error::from))
+    }
+
+    /// バッファされている記録を書き出す。
+    pub fn flush(&mut self) -> Result<()> {
+        track!(self.inner.flush().map_err(Error::from))
+    }
+}
+
+/// 記録を読み込むための構造体。
+#[derive(Debug)]
+pub struct WorkloadReader<R: Read> {
+    inner: R,
+}
+impl<R: Read> WorkloadReader<R> {
+    /// 新しい`WorkloadReader`を生成する。
+    pub fn new(mut inner: R) -> Result<Self> {
+        let mut magic = [0; 8];
+        track!(inner.read_exact(&mut magic).map_err(Error::from))?;
+        track_assert_eq!(
+            &magic,
+            MAGIC,
+            ErrorKind::InvalidInput,
+            "Not a workload file"
+        );
+        Ok(WorkloadReader { inner })
+    }
+
+    /// 次の記録を読み込む。
+    ///
+    /// 末尾に達した場合には`None`が返される。
+    /// (途中で書き込みが中断された不完全な記録は無視される)
+    pub fn read(&mut self) -> Result<Option<OperationRecord>> {
+        let mut buf = [0; RECORD_SIZE];
+        match self.inner.read_exact(&mut buf) {
+            Err(ref e) if e.kind() == IoErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(track!(Error::from(e))),
+            Ok(()) => track!(OperationRecord::decode(&buf)).map(Some),
+        }
+    }
+}
+
+/// サンプリングしたリクエストを記録するための構造体。
+///
+/// 記録は専用のスレッドでファイルに書き出される。
+/// 書き出しが追いつかない場合には、記録は破棄される。
+#[derive(Clone)]
+pub struct WorkloadRecorder {
+    started_at: Instant,
+    sampling_threshold: u64,
+    hasher: RandomState,
+    tx: SyncSender<OperationRecord>,
+}
+impl WorkloadRecorder {
+    /// 設定に従って`WorkloadRecorder`を生成する。
+    ///
+    /// 記録先のファイルが設定されていない場合には`None`を返す。
+    pub fn from_config(
+        logger: &Logger,
+        config: &FrugalosWorkloadRecorderConfig,
+    ) -> Result<Option<Self>> {
+        let filepath = if let Some(ref filepath) = config.filepath {
+            filepath
+        } else {
+            return Ok(None);
+        };
+        track_assert!(
+            0.0 <= config.sampling_rate && config.sampling_rate <= 1.0,
+            ErrorKind::InvalidInput,
+            "sampling_rate={}",
+            config.sampling_rate
+        );
+        let file = track!(File::create(filepath).map_err(Error::from); filepath)?;
+        let mut writer = track!(WorkloadWriter::new(BufWriter::new(file)))?;
+        info!(
+            logger,
+            "Starts recording the workload: {}",
+            dump!(filepath, config.sampling_rate)
+        );
+
+        let (tx, rx) = mpsc::sync_channel::<OperationRecord>(MAX_PENDING_RECORDS);
+        let logger = logger.clone();
+        thread::spawn(move || {
+            while let Ok(record) = rx.recv() {
+                // 溜まっている記録をまとめて書き出してからフラッシュする
+                let result = track!(writer.write(&record))
+                    .and_then(|()| {
+                        for record in rx.try_iter() {
+                            track!(writer.write(&record))?;
+                        }
+                        Ok(())
+                    })
+                    .and_then(|()| track!(writer.flush()));
+                if let Err(e) = result {
+                    error!(logger, "Cannot write the workload record: {}", e);
+                    return;
+                }
+            }
+        });
+        Ok(Some(WorkloadRecorder {
+            started_at: Instant::now(),
+            sampling_threshold: (config.sampling_rate * u64::MAX as f64) as u64,
+            hasher: RandomState::new(),
+            tx,
+        }))
+    }
+
+    /// 操作を記録する。
+    ///
+    /// サンプリングはオブジェクト単位で行われるので、同じオブジェクトに対する操作は全て記録されるか、全く記録されないかのどちらかとなる。
+    pub fn record(
+        &self,
+        kind: OperationKind,
+        object_id: &ObjectId,
+        size: u64,
+        started_at: Instant,
+        ok: bool,
+    ) {
+        let object = self.hasher.hash_one(object_id);
+        if object > self.sampling_threshold {
+            return;
+        }
+
+        let record = OperationRecord {
+            offset: started_at.saturating_duration_since(self.started_at),
+            kind,
+            object,
+            size,
+            latency: started_at.elapsed(),
+            ok,
+        };
+        // NOTE: リクエスト処理を遅らせないように、書き出しが追いつかない場合は破棄する
+        let _ = self.tx.try_send(record);
+    }
+}
+
+/// 記録の再生方法の指定。
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// 再生速度の倍率。
+    pub speed: f64,
+
+    /// 同時に発行するリクエストの最大数。
+    pub concurrency: usize,
+
+    /// 各リクエストのデッドライン。
+    pub deadline: Duration,
+}
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        ReplayOptions {
+            speed: 1.0,
+            concurrency: 64,
+            deadline: Duration::from_secs(5),
+        }
+    }
+}
+
+/// 操作の種類毎の再生結果。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// 発行したリクエストの数。
+    pub count: u64,
+
+    /// 失敗したリクエストの数。
+    pub errors: u64,
+
+    /// リクエストの処理時間の合計。
+    pub total_latency: Duration,
+
+    /// 記録時のリクエストの処理時間の合計。
+    pub recorded_latency: Duration,
+}
+
+/// 記録の再生結果。
+#[derive(Debug, Clone, Default)]
+pub struct ReplaySummary {
+    /// 操作の種類毎の結果。
+    pub stats: Vec<(OperationKind, ReplayStats)>,
+
+    /// 再生にかかった時間。
+    pub elapsed: Duration,
+}
+impl ReplaySummary {
+    fn add(&mut self, record: &OperationRecord, latency: Duration, ok: bool) {
+        let i = if let Some(i) = self.stats.iter().position(|s| s.0 == record.kind) {
+            i
+        } else {
+            self.stats.push((record.kind, ReplayStats::default()));
+            self.stats.sort_by_key(|s| s.0);
+            self.stats
+                .iter()
+                .position(|s| s.0 == record.kind)
+                .expect("Never fails")
+        };
+        let stats = &mut self.stats[i].1;
+        stats.count += 1;
+        if !ok {
+            stats.errors += 1;
+        }
+        stats.total_latency += latency;
+        stats.recorded_latency += record.latency;
+    }
+}
+
+/// `filepath`に記録された操作を、`rpc_addr`のサーバを経由して`bucket_id`のバケツに対して再生する。
+///
+/// オブジェクト ID には匿名化された ID から生成したものが使われ、PUT の内容はゼロ埋めされたデータとなる。
+/// 本番のクラスタに対しては実行しないこと。
+pub fn replay<P: AsRef<Path>>(
+    logger: &Logger,
+    filepath: P,
+    rpc_addr: SocketAddr,
+    bucket_id: BucketId,
+    options: &ReplayOptions,
+) -> Result<ReplaySummary> {
+    let file = track!(File::open(filepath.as_ref()).map_err(Error::from))?;
+    let mut reader = track!(WorkloadReader::new(BufReader::new(file)))?;
+    let mut records = Vec::new();
+    while let Some(record) = track!(reader.read())? {
+        records.push(record);
+    }
+    info!(
+        logger,
+        "Starts replaying the workload: {}",
+        dump!(records.len(), options)
+    );
+
+    let mut executor =
+        track!(ThreadPoolExecutor::with_thread_count(num_cpus::get()).map_err(Error::from))?;
+    let rpc_service = RpcServiceBuilder::new()
+        .logger(logger.clone())
+        .finish(executor.handle());
+    let client = Arc::new(libfrugalos::client::frugalos::Client::new(
+        rpc_addr,
+        rpc_service.handle(),
+    ));
+    executor.spawn(rpc_service.map_err(|e| panic!("{}", e)));
+
+    let started_at = Instant::now();
+    let speed = options.speed;
+    let deadline = options.deadline;
+    let future = futures::stream::iter_ok::<_, Error>(records)
+        .map(move |record| {
+            let client = client.clone();
+            let bucket_id = bucket_id.clone();
+            futures::lazy(move || {
+                let scheduled = started_at + record.offset.div_f64(speed);
+                let delay = scheduled.saturating_duration_since(Instant::now());
+                timer::timeout(delay)
+                    .map_err(Error::from)
+                    .and_then(move |()| {
+                        let now = Instant::now();
+                        replay_operation(&client, bucket_id, &record, deadline)
+                            .then(move |result| Ok((record, now.elapsed(), result.is_ok())))
+                    })
+            })
+        })
+        .buffer_unordered(options.concurrency)
+        .fold(
+            ReplaySummary::default(),
+            |mut summary, (record, latency, ok)| {
+                summary.add(&record, latency, ok);
+                Ok::<_, Error>(summary)
+            },
+        );
+    let fiber = executor.spawn_monitor(future);
+    let mut summary = track!(executor
+        .run_fiber(fiber)
+        .unwrap()
+        .map_err(|e| e.unwrap_or_else(|| panic!("monitoring channel disconnected"))))?;
+    summary.elapsed = started_at.elapsed();
+    Ok(summary)
+}
+
+fn replay_operation(
+    client: &libfrugalos::client::frugalos::Client,
+    bucket_id: BucketId,
+    record: &OperationRecord,
+    deadline: Duration,
+) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    let object_id = format!("replay-{:016x}", record.object);
+    match record.kind {
+        OperationKind::Get => Box::new(
+            client
+                .get_object(
+                    bucket_id,
+                    object_id,
+                    deadline,
+                    Expect::Any,
+                    ReadConsistency::Consistent,
+                )
+                .map(|_| ())
+                .map_err(Error::from),
+        ),
+        OperationKind::Head => Box::new(
+            client
+                .head_object(
+                    bucket_id,
+                    object_id,
+                    deadline,
+                    Expect::Any,
+                    ReadConsistency::Consistent,
+                    false,
+                )
+                .map(|_| ())
+                .map_err(Error::from),
+        ),
+        OperationKind::Put => {
+            let content = vec![0; record.size as usize];
+            Box::new(
+                client
+                    .put_object(bucket_id, object_id, content, deadline, Expect::Any)
+                    .map(|_| ())
+                    .map_err(Error::from),
+            )
+        }
+        OperationKind::Delete => Box::new(
+            client
+                .delete_object(bucket_id, object_id, deadline, Expect::Any)
+                .map(|_| ())
+                .map_err(Error::from),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workload_file_works() -> Result<()> {
+        let records = vec![
+            OperationRecord {
+                offset: Duration::from_millis(0),
+                kind: OperationKind::Put,
+                object: 10,
+                size: 1024,
+                latency: Duration::from_micros(1500),
+                ok: true,
+            },
+            OperationRecord {
+                offset: Duration::from_millis(3),
+                kind: OperationKind::Get,
+                object: 10,
+                size: 1024,
+                latency: Duration::from_micros(700),
+                ok: false,
+            },
+        ];
+
+        let mut buf = Vec::new();
+        {
+            let mut writer = track!(WorkloadWriter::new(&mut buf))?;
+            for r in &records {
+                track!(writer.write(r))?;
+            }
+        }
+        // 書き込みが中断された記録は無視される
+        buf.extend_from_slice(&[0; RECORD_SIZE - 1]);
+
+        let mut reader = track!(WorkloadReader::new(&buf[..]))?;
+        let mut actual = Vec::new();
+        while let Some(r) = track!(reader.read())? {
+            actual.push(r);
+        }
+        assert_eq!(actual, records);
+
+        assert!(WorkloadReader::new(&b"NOTAFILE"[..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn replay_summary_works() {
+        let record = |kind, ok| OperationRecord {
+            offset: Duration::from_millis(0),
+            kind,
+            object: 0,
+            size: 0,
+            latency: Duration::from_millis(1),
+            ok,
+        };
+        let mut summary = ReplaySummary::default();
+        summary.add(
+            &record(OperationKind::Put, true),
+            Duration::from_millis(2),
+            true,
+        );
+        summary.add(
+            &record(OperationKind::Get, true),
+            Duration::from_millis(3),
+            false,
+        );
+        summary.add(
+            &record(OperationKind::Get, true),
+            Duration::from_millis(3),
+            true,
+        );
+
+        let kinds = summary.stats.iter().map(|s| s.0).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![OperationKind::Get, OperationKind::Put]);
+        assert_eq!(summary.stats[0].1.count, 2);
+        assert_eq!(summary.stats[0].1.errors, 1);
+        assert_eq!(summary.stats[0].1.total_latency, Duration::from_millis(6));
+        assert_eq!(
+            summary.stats[0].1.recorded_latency,
+            Duration::from_millis(2)
+        );
+    }
+}